@@ -20,11 +20,21 @@ enum AccessLevel {
     Voice,
     Write,
     Read,
+    None, // temporary access expired
 }
 
 enum DrawingCanvasState {
     Active,
     Moderated,
+    Archived,
+}
+
+// action of the state endpoint that leads to each state
+const stateActions: Record<string, DrawingCanvasState> = {
+    'moderate': DrawingCanvasState.Moderated,
+    'activate': DrawingCanvasState.Active,
+    'archive': DrawingCanvasState.Archived,
+    'unarchive': DrawingCanvasState.Active,
 }
 
 type CanvasUser = {
@@ -37,7 +47,9 @@ type CanvasUser = {
 export class MultiUserOverlay extends HTMLElement {
     protected readonly userListElement: HTMLUListElement
     protected readonly connectingElement: HTMLDivElement
+    protected readonly syncProgressElement: HTMLProgressElement
     protected readonly assignCanvasState: HTMLSelectElement
+    protected readonly expectedCanvasVersion: HTMLInputElement
     protected readonly toolArea: ToolArea
     protected readonly moderationContainerElement: HTMLDivElement
     protected moderationElement: HTMLDivElement | null = null // lazy loaded
@@ -51,6 +63,10 @@ export class MultiUserOverlay extends HTMLElement {
     protected socket: WebSocket | null = null
     protected eventListenerRemover: () => void = () => {}
     protected users: Map<string, CanvasUser> = new Map()
+    // resolved by the server, the page and the ServerHello of the socket agree
+    protected featureFlags: Record<string, boolean> = JSON.parse(
+        document.querySelector('#canvas-bootstrap')?.textContent || '{}'
+    ).flags ?? {}
 
     constructor() {
         super()
@@ -83,7 +99,14 @@ export class MultiUserOverlay extends HTMLElement {
 
         this.userListElement = document.createElement('ul')
         this.assignCanvasState = document.createElement('select')
+
+        // sent with state changes, server rejects changes based on an outdated canvas
+        this.expectedCanvasVersion = document.createElement('input')
+        this.expectedCanvasVersion.type = 'hidden'
+        this.expectedCanvasVersion.name = 'expected_version'
+        this.expectedCanvasVersion.value = document.querySelector('#canvas-container[data-canvas-version]')?.getAttribute('data-canvas-version') ?? '0'
         this.connectingElement = document.createElement('div')
+        this.syncProgressElement = document.createElement('progress')
         this.moderationContainerElement = document.createElement('div')
     }
 
@@ -131,16 +154,15 @@ export class MultiUserOverlay extends HTMLElement {
         targetAttribute.value = 'info-pop'
         canvasModeration.attributes.setNamedItem(targetAttribute)
         canvasModeration.method = 'POST'
-        canvasModeration.action = `${window.location.pathname}/update`
+        canvasModeration.action = `${window.location.pathname}/state`
 
-        this.assignCanvasState.name = 'state'
-        const states = ['Moderated', 'Active']
-        states.forEach((state) => {
+        this.assignCanvasState.name = 'action'
+        Object.entries(stateActions).forEach(([action, state]) => {
             const option = document.createElement('option')
-            option.id = 'canvas-state' + state
-            option.value = state
-            option.innerText = state
-            option.selected = state === DrawingCanvasState[this.canvasState]
+            option.id = 'canvas-state-' + action
+            option.value = action
+            option.innerText = action
+            option.selected = state === this.canvasState && action !== 'unarchive'
             this.assignCanvasState.appendChild(option)
         })
 
@@ -149,6 +171,7 @@ export class MultiUserOverlay extends HTMLElement {
         addButton.innerText = 'Update Canvas State'
 
         canvasModeration.appendChild(this.assignCanvasState)
+        canvasModeration.appendChild(this.expectedCanvasVersion)
         canvasModeration.appendChild(addButton)
         
         return canvasModeration
@@ -190,7 +213,7 @@ export class MultiUserOverlay extends HTMLElement {
 
     updateCanvasState(state: DrawingCanvasState) {
         for (const option of this.assignCanvasState.options) {
-            option.selected = option.value === DrawingCanvasState[state]
+            option.selected = stateActions[option.value] === state && option.value !== 'unarchive'
         }
 
         this.canvasState = state
@@ -209,7 +232,8 @@ export class MultiUserOverlay extends HTMLElement {
         console.log('Access Level Changed', AccessLevel[accessLevel])
         this.accessLevel = accessLevel
         
-        if (accessLevel === AccessLevel.Read || 
+        if (accessLevel === AccessLevel.Read || accessLevel === AccessLevel.None ||
+            this.canvasState === DrawingCanvasState.Archived ||
             ( accessLevel === AccessLevel.Write && this.canvasState === DrawingCanvasState.Moderated)
         ) {
             this.toolArea.disableToolSelection()
@@ -224,6 +248,20 @@ export class MultiUserOverlay extends HTMLElement {
         }
     }
 
+    /**
+     * Shows feedback of the server, errors and warnings use the error popup
+     */
+    showNotice(level: string, message: string) {
+        const popover = document.querySelector(level === 'Notice' ? '#info-pop' : '#error-pop')
+        if (!(popover instanceof HTMLElement)) return
+
+        popover.innerText = message
+        popover.showPopover()
+        setTimeout(() => {
+            popover.hidePopover()
+        }, 3000)
+    }
+
     updateUserList() {
         this.userListElement.innerHTML = ''
         this.users.forEach((user) => {
@@ -279,59 +317,7 @@ export class MultiUserOverlay extends HTMLElement {
         }
 
         this.socket.onmessage = (wsMessage) => {
-            const rawEvent = JSON.parse(wsMessage.data)
-
-            switch (rawEvent.type) {
-                case 'UserJoined':
-                    console.log('User Joined', rawEvent)
-                    this.users.set(`${rawEvent.userId}-${rawEvent.sessionId}`, {
-                        name: rawEvent.username,
-                        userId: rawEvent.userId,
-                        sessionId: rawEvent.sessionId,
-                        accessLevel: rawEvent.accessLevel
-                    })
-                    this.updateUserList()
-                    break
-                case 'UserLeft':
-                    this.users.delete(`${rawEvent.userId}-${rawEvent.sessionId}`)
-                    this.updateUserList()
-                    break
-                case 'CanvasStateChanged':
-                    console.log('Canvas State Changed', rawEvent)
-                    const state = DrawingCanvasState[rawEvent.state as keyof typeof DrawingCanvasState]
-                    if (state === undefined) {
-                        console.error('Invalid canvas state', rawEvent)
-                        return
-                    }
-                    this.updateCanvasState(state)
-                    break;
-                case 'UserAccessLevelChanged':
-                    console.log('User Access Level Changed', rawEvent)
-                    const accessLevel = AccessLevel[rawEvent.accessLevel as keyof typeof AccessLevel]
-                    if (accessLevel === undefined) {
-                        console.error('Invalid access level', rawEvent)
-                        return
-                    }
-
-                    this.users.forEach((user) => {
-                        if (user.userId === rawEvent.userId) {
-                            user.accessLevel = rawEvent.accessLevel
-                        }
-                    })
-
-                    if (rawEvent.userId === this.userId) {
-                        this.updateAccessLevel(accessLevel)
-                    }
-
-                    this.updateUserList()
-                    break
-                default:
-                    // reparsing is not nice, only revise if performance is an issue
-                    const event = deserializeEvent(wsMessage.data)
-                    event.external = true
-                    // FIXME: Typecheck would be nice here
-                    SHAPE_EVENT_BUS.dispatchEvent(event.type, event)
-            }
+            this.handleServerEvent(JSON.parse(wsMessage.data), wsMessage.data)
         }
 
         this.socket.onerror = (event) => {
@@ -367,6 +353,88 @@ export class MultiUserOverlay extends HTMLElement {
         })
     }
 
+    /**
+     * Applies an event received from the server
+     * Initial state arrives in chunks, their events are applied one by one while showing the progress
+     * @param rawEvent parsed event
+     * @param data unparsed event, shape events are deserialized again to restore special values
+     */
+    handleServerEvent(rawEvent: any, data: string) {
+        switch (rawEvent.type) {
+            case 'UserJoined':
+                console.log('User Joined', rawEvent)
+                this.users.set(`${rawEvent.userId}-${rawEvent.sessionId}`, {
+                    name: rawEvent.username,
+                    userId: rawEvent.userId,
+                    sessionId: rawEvent.sessionId,
+                    accessLevel: rawEvent.accessLevel
+                })
+                this.updateUserList()
+                break
+            case 'UserLeft':
+                this.users.delete(`${rawEvent.userId}-${rawEvent.sessionId}`)
+                this.updateUserList()
+                break
+            case 'CanvasStateChanged':
+                console.log('Canvas State Changed', rawEvent)
+                const state = DrawingCanvasState[rawEvent.state as keyof typeof DrawingCanvasState]
+                if (state === undefined) {
+                    console.error('Invalid canvas state', rawEvent)
+                    return
+                }
+                if (rawEvent.version) {
+                    this.expectedCanvasVersion.value = String(rawEvent.version)
+                }
+                this.updateCanvasState(state)
+                break;
+            case 'UserAccessLevelChanged':
+                console.log('User Access Level Changed', rawEvent)
+                const accessLevel = AccessLevel[rawEvent.accessLevel as keyof typeof AccessLevel]
+                if (accessLevel === undefined) {
+                    console.error('Invalid access level', rawEvent)
+                    return
+                }
+
+                this.users.forEach((user) => {
+                    if (user.userId === rawEvent.userId) {
+                        user.accessLevel = rawEvent.accessLevel
+                    }
+                })
+
+                if (rawEvent.userId === this.userId) {
+                    this.updateAccessLevel(accessLevel)
+                }
+
+                this.updateUserList()
+                break
+            case 'ServerNotice':
+                console.log('Server Notice', rawEvent)
+                this.showNotice(rawEvent.level, rawEvent.message)
+                break
+            case 'ServerHello':
+                this.featureFlags = rawEvent.flags
+                break
+            case 'InitialStateChunk':
+                this.syncProgressElement.max = rawEvent.total
+                this.syncProgressElement.value = rawEvent.seq
+                if (rawEvent.seq < rawEvent.total) {
+                    this.prepend(this.syncProgressElement)
+                } else {
+                    this.syncProgressElement.remove()
+                }
+                for (const event of rawEvent.events) {
+                    this.handleServerEvent(event, JSON.stringify(event))
+                }
+                break
+            default:
+                // reparsing is not nice, only revise if performance is an issue
+                const event = deserializeEvent(data)
+                event.external = true
+                // FIXME: Typecheck would be nice here
+                SHAPE_EVENT_BUS.dispatchEvent(event.type, event)
+        }
+    }
+
     /**
      * Disconnect from the websocket server
      * Called by DOM when CustomElement is removed from the DOM
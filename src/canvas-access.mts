@@ -0,0 +1,88 @@
+// Polls the access endpoint while the no access page of a canvas is shown
+// and reloads the page once the owner granted access, no re-login needed
+// The page also asks for access, the poll shows whether the request is pending or was answered
+
+let pollTimer: number | undefined
+
+const requestStatusTexts: Record<string, string> = {
+    Pending: 'Anfrage gesendet, warte auf Antwort des Besitzers.',
+    Denied: 'Deine Anfrage wurde abgelehnt.',
+    Cancelled: 'Deine Anfrage wurde zurückgezogen.',
+}
+
+const jsonHeaders = { 'X-SPA-Request': 'true', 'Accept': 'application/json' }
+
+function showRequestStatus(noAccess: HTMLElement, status: string | undefined) {
+    const form = noAccess.querySelector('#canvas-access-request')
+    const text = noAccess.querySelector('#canvas-access-request-status')
+    const cancel = noAccess.querySelector('#canvas-access-request-cancel')
+    if (!(form instanceof HTMLFormElement) || !(text instanceof HTMLElement) || !(cancel instanceof HTMLElement)) return
+
+    form.hidden = status === 'Pending'
+    cancel.hidden = status !== 'Pending'
+    text.hidden = status === undefined || !(status in requestStatusTexts)
+    text.textContent = status ? requestStatusTexts[status] ?? '' : ''
+}
+
+async function showError(noAccess: HTMLElement, response: Response) {
+    const text = noAccess.querySelector('#canvas-access-request-status')
+    if (!(text instanceof HTMLElement)) return
+    const body = await response.json().catch(() => undefined)
+    text.hidden = false
+    text.textContent = body?.message ?? 'Die Anfrage ist fehlgeschlagen.'
+}
+
+document.addEventListener('AJAXContentLoaded', () => {
+    window.clearInterval(pollTimer)
+    pollTimer = undefined
+
+    const noAccess = document.querySelector('#canvas-no-access')
+    if (!(noAccess instanceof HTMLElement)) return
+
+    const canvasId = noAccess.dataset.canvasId
+    const interval = Number(noAccess.dataset.pollInterval) || 5000
+
+    noAccess.querySelector('#canvas-access-request')?.addEventListener('submit', async (event) => {
+        event.preventDefault()
+        const form = event.target as HTMLFormElement
+        const response = await fetch(`/canvas/${canvasId}/request-access`, {
+            method: 'POST',
+            headers: jsonHeaders,
+            body: new URLSearchParams(new FormData(form) as unknown as Record<string, string>),
+        })
+        if (!response.ok) return showError(noAccess, response)
+        const request = await response.json()
+        showRequestStatus(noAccess, request.status)
+    })
+
+    noAccess.querySelector('#canvas-access-request-cancel')?.addEventListener('click', async () => {
+        const response = await fetch(`/canvas/${canvasId}/request-access`, {
+            method: 'DELETE',
+            headers: jsonHeaders,
+        })
+        if (!response.ok) return showError(noAccess, response)
+        showRequestStatus(noAccess, 'Cancelled')
+    })
+
+    pollTimer = window.setInterval(async () => {
+        if (!noAccess.isConnected) {
+            // navigated away without another AJAXContentLoaded
+            window.clearInterval(pollTimer)
+            return
+        }
+        const response = await fetch(`/canvas/${canvasId}/access`, {
+            cache: 'no-cache',
+            headers: jsonHeaders,
+        })
+        // rate limited or offline, try again with the next poll
+        if (!response.ok) return
+
+        const access = await response.json()
+        if (access.access_level !== 'None') {
+            window.clearInterval(pollTimer)
+            location.reload()
+            return
+        }
+        showRequestStatus(noAccess, access.access_request)
+    }, interval)
+})
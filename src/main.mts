@@ -43,14 +43,15 @@ document.addEventListener('DOMContentLoaded', async () =>{
  * Fetches the content
  * and replaces the content of the contentElement
  * or displays a popover if the contentElement has the popover attribute
- * if the response is not 200, an error popover is shown
+ * if the response is not 200 and not marked with X-SPA-Render, an error popover is shown
  */
 async function fetchAndLoad(contentElement: HTMLElement, url: string, options?: RequestInit) {
     const base = { method: 'GET', cache: 'no-cache', headers: { 'X-SPA-Request': 'true' } }
     const data = Object.assign(base, options)
     const response = await fetch(url, data)
 
-    if (response.status === 200) {
+    // pages like the no access page of a canvas are rendered with their error status
+    if (response.status === 200 || response.headers.get('X-SPA-Render') === 'true') {
         // handle popovers specially
         if (contentElement.hasAttribute('popover')) {
             response.text()
@@ -10,6 +10,10 @@ export default {
                 home: resolve(__dirname, '.templates/home.html'),
                 register: resolve(__dirname, '.templates/register.html'),
                 canvas: resolve(__dirname, '.templates/canvas.html'),
+                'canvas-no-access': resolve(__dirname, '.templates/canvas-no-access.html'),
+                profile: resolve(__dirname, '.templates/profile.html'),
+                members: resolve(__dirname, '.templates/members.html'),
+                'reset-password': resolve(__dirname, '.templates/reset-password.html'),
             },
         }
     },
@@ -21,6 +25,8 @@ export default {
             '/logout': 'http://localhost:8080',
             '/register': 'http://localhost:8080',
             '/home': 'http://localhost:8080',
+            '/user': 'http://localhost:8080',
+            '/api': 'http://localhost:8080',
             '^/$': 'http://localhost:8080',
         }
     }
@@ -0,0 +1,8 @@
+/// Path of the vite build embedded by the embed-frontend feature, overridden by FRONTEND_DIST_DIR
+fn main() {
+    println!("cargo:rerun-if-env-changed=FRONTEND_DIST_DIR");
+    if std::env::var_os("FRONTEND_DIST_DIR").is_none() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rustc-env=FRONTEND_DIST_DIR={manifest_dir}/../dist");
+    }
+}
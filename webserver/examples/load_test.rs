@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use webserver::canvas::{
+    client::CanvasClient,
+    events::{CanvasEvents, Point2D, Shape},
+};
+
+// Connects N clients to one canvas, one client draws and every other client measures broadcast latency
+// cargo run --example load_test -- <base_url> <auth_tokens> <canvas_id> [clients] [shapes]
+// auth_tokens is a comma separated list of auth cookie values, clients are assigned round robin
+// Every user is limited to ConnectionLimits::max_sessions_per_user, use enough users for the client count
+
+const SHAPE_INTERVAL: Duration = Duration::from_millis(50);
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[actix_web::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 3 {
+        eprintln!("usage: load_test <base_url> <auth_tokens> <canvas_id> [clients] [shapes]");
+        std::process::exit(2);
+    }
+    let base_url = &args[0];
+    let tokens: Vec<&str> = args[1].split(',').collect();
+    let canvas_id = &args[2];
+    let clients: usize = args
+        .get(3)
+        .map_or(10, |n| n.parse().expect("clients is a number"));
+    let shapes: usize = args
+        .get(4)
+        .map_or(100, |n| n.parse().expect("shapes is a number"));
+
+    let mut connected = Vec::with_capacity(clients);
+    for client in 0..clients.max(2) {
+        let token = tokens[client % tokens.len()];
+        match CanvasClient::connect(base_url, token, canvas_id).await {
+            Ok(client) => connected.push(client),
+            Err(e) => {
+                eprintln!("client {client} failed to connect: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let drawer = connected.remove(0);
+    println!("{} clients connected to {canvas_id}", connected.len() + 1);
+
+    // send time of every shape, receivers look up their latency by shape id
+    let sent: Arc<Mutex<HashMap<String, Instant>>> = Arc::default();
+
+    let receivers: Vec<_> = connected
+        .into_iter()
+        .map(|mut client| {
+            let sent = sent.clone();
+            actix_web::rt::spawn(async move {
+                let mut latencies = Vec::with_capacity(shapes);
+                while latencies.len() < shapes {
+                    let event =
+                        match actix_web::rt::time::timeout(RECEIVE_TIMEOUT, client.next_event())
+                            .await
+                        {
+                            Ok(Some(event)) => event,
+                            _ => break,
+                        };
+                    let CanvasEvents::ShapeAdded { shape, .. } = event else {
+                        continue;
+                    };
+                    // the initial state can contain shapes of earlier runs
+                    if let Some(sent_at) = sent.lock().unwrap().get(shape.get_id()) {
+                        latencies.push(sent_at.elapsed());
+                    }
+                }
+                let _ = client.close().await;
+                latencies
+            })
+        })
+        .collect();
+
+    let run = nanoid::nanoid!(6);
+    for shape in 0..shapes {
+        let id = format!("load-{run}-{shape}");
+        sent.lock().unwrap().insert(id.clone(), Instant::now());
+        let line = Shape::Line {
+            id,
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: Point2D { x: 0, y: 0 },
+            to: Point2D {
+                x: shape as i32,
+                y: shape as i32,
+            },
+        };
+        if let Err(e) = drawer.add_shape(line).await {
+            eprintln!("failed to send shape {shape}: {e}");
+            break;
+        }
+        actix_web::rt::time::sleep(SHAPE_INTERVAL).await;
+    }
+
+    let mut latencies = Vec::new();
+    for receiver in receivers {
+        latencies.extend(receiver.await.unwrap_or_default());
+    }
+    let _ = drawer.close().await;
+
+    let expected = shapes * (clients.max(2) - 1);
+    println!("received {} of {expected} broadcasts", latencies.len());
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        latencies[latencies.len() - 1]
+    );
+}
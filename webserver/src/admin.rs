@@ -0,0 +1,788 @@
+use crate::{
+    api::pagination::{self, ListEndpoint, ListParams},
+    authentication::{self, AuthContext, JWTClaims, JWTRefreshCache},
+    canvas::{
+        events::NoticeLevel,
+        orphans::{self, OrphanReport, CANVAS_LOG_DIR},
+        replay::ReplayCache,
+        server::CanvasSocketServerHandle,
+        store::{
+            CanvasId, DeleteCanvasMessage, GetCanvasRecordsMessage, GetOwnedCanvasesMessage,
+            PurgeUserMessage, RestoreCanvasMessage,
+        },
+    },
+    clock::{self, SharedClock},
+    connection::UnmaskedConnection,
+    instance::InstanceInfo,
+    mailbox::ActorGauges,
+    maintenance_mode::{MaintenanceState, MaintenanceWindow},
+    messages::{self, MessageKey},
+    persistence::{EventLogPersistenceJson, EventLogPersistenceStandaloneJson, ReplayIssues},
+    preflight::PreflightReport,
+    templates::RenderMonitor,
+    user,
+    userstore::{DeleteUserMessage, UserId},
+};
+use actix::Recipient;
+use actix_web::{http::StatusCode, web, FromRequest, HttpRequest, HttpResponse, Responder, Result};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Mutex,
+};
+
+// Admin endpoints
+// Admins are configured by user id, usernames are freed by deletion and could be registered by anyone
+// Every mutating admin endpoint is recorded in the AdminActionLog
+// An action is written before it is attempted and again once its outcome is known,
+// a crash mid-action leaves the pending line as evidence
+// Destructive actions additionally require confirm=<target-id> to prevent accidental clicks
+
+pub static ADMIN_ACTION_LOG: &str = "admin_actions.jsonl";
+
+/// User ids allowed to use the admin endpoints
+#[derive(Debug, Clone, Default)]
+pub struct Admins(HashSet<UserId>);
+
+impl Admins {
+    pub fn new(user_ids: impl IntoIterator<Item = UserId>) -> Self {
+        Self(user_ids.into_iter().collect())
+    }
+
+    pub fn is_admin(&self, claims: &JWTClaims) -> bool {
+        self.0.contains(&claims.uid)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ActionOutcome {
+    /// written before the action is attempted, stays pending if the server crashed mid-action
+    Pending,
+    Succeeded,
+    Failed {
+        reason: String,
+    },
+}
+
+/// Single line of the admin action log, the outcome line repeats the request_id of the pending line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminAction {
+    pub timestamp: u64,
+    pub admin_user_id: UserId,
+    pub action: String,
+    pub target: String,
+    pub request_id: String,
+    pub outcome: ActionOutcome,
+}
+
+struct AdminActionLogInner {
+    persistence: EventLogPersistenceStandaloneJson<AdminAction>,
+    /// actions with their latest outcome, oldest first
+    actions: Vec<AdminAction>,
+    /// request_id to index in actions
+    lookup: HashMap<String, usize>,
+}
+
+impl AdminActionLogInner {
+    fn apply(&mut self, action: AdminAction) {
+        match self.lookup.get(&action.request_id) {
+            Some(index) => self.actions[*index].outcome = action.outcome,
+            None => {
+                self.lookup
+                    .insert(action.request_id.clone(), self.actions.len());
+                self.actions.push(action);
+            }
+        }
+    }
+}
+
+/// Append-only log of admin actions, shared between all workers using web::Data
+/// Writes are synchronous, admin actions are rare
+pub struct AdminActionLog {
+    inner: Mutex<AdminActionLogInner>,
+    clock: SharedClock,
+}
+
+impl AdminActionLog {
+    pub fn open(file_path: &str, clock: SharedClock) -> Result<Self, std::io::Error> {
+        let (lines, persistence) =
+            EventLogPersistenceJson::new(file_path)?.into_standalone::<AdminAction>()?;
+
+        let mut inner = AdminActionLogInner {
+            persistence,
+            actions: Vec::new(),
+            lookup: HashMap::new(),
+        };
+        lines.into_iter().for_each(|action| inner.apply(action));
+
+        Ok(Self {
+            inner: Mutex::new(inner),
+            clock,
+        })
+    }
+
+    fn write(&self, action: AdminAction) -> Result<(), std::io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.persistence.save_event(&action)?;
+        inner.apply(action);
+        Ok(())
+    }
+
+    /// Records the attempt, the action must not be attempted if this fails
+    /// The returned guard records the outcome, a guard dropped without finish records a failure
+    pub fn begin(
+        log: &web::Data<AdminActionLog>,
+        admin_user_id: &UserId,
+        action: &str,
+        target: &str,
+    ) -> Result<AdminActionGuard, std::io::Error> {
+        let action = AdminAction {
+            timestamp: log.clock.now_ms(),
+            admin_user_id: admin_user_id.clone(),
+            action: action.to_string(),
+            target: target.to_string(),
+            request_id: nanoid!(),
+            outcome: ActionOutcome::Pending,
+        };
+        log.write(action.clone())?;
+
+        Ok(AdminActionGuard {
+            log: log.clone(),
+            action: Some(action),
+        })
+    }
+
+    /// Actions with their latest outcome, newest first
+    pub fn page(&self, page: usize, per_page: usize) -> (Vec<AdminAction>, usize) {
+        let inner = self.inner.lock().unwrap();
+        let actions = inner
+            .actions
+            .iter()
+            .rev()
+            .skip(page.saturating_sub(1) * per_page)
+            .take(per_page)
+            .cloned()
+            .collect();
+        (actions, inner.actions.len())
+    }
+
+    /// Actions with their position in the log, oldest first, the position is stable as the log is append-only
+    pub fn sequenced(&self) -> Vec<(usize, AdminAction)> {
+        let inner = self.inner.lock().unwrap();
+        inner.actions.iter().cloned().enumerate().collect()
+    }
+}
+
+/// Pending admin action, finish records the outcome as second line
+pub struct AdminActionGuard {
+    log: web::Data<AdminActionLog>,
+    action: Option<AdminAction>,
+}
+
+impl AdminActionGuard {
+    pub fn finish<T, E: Display>(mut self, result: &Result<T, E>) {
+        let outcome = match result {
+            Ok(_) => ActionOutcome::Succeeded,
+            Err(e) => ActionOutcome::Failed {
+                reason: e.to_string(),
+            },
+        };
+        self.record(outcome);
+    }
+
+    fn record(&mut self, outcome: ActionOutcome) {
+        let Some(action) = self.action.take() else {
+            return;
+        };
+        let action = AdminAction {
+            timestamp: self.log.clock.now_ms(),
+            outcome,
+            ..action
+        };
+        if let Err(e) = self.log.write(action) {
+            println!("Failed to record outcome of admin action: {e}");
+        }
+    }
+}
+
+impl Drop for AdminActionGuard {
+    fn drop(&mut self) {
+        self.record(ActionOutcome::Failed {
+            reason: "not completed".to_string(),
+        });
+    }
+}
+
+/// Whether the claims belong to a configured admin
+pub fn is_admin(request: &HttpRequest, claims: &JWTClaims) -> bool {
+    request
+        .app_data::<web::Data<Admins>>()
+        .is_some_and(|admins| admins.is_admin(claims))
+}
+
+/// Context of the requesting admin
+pub fn require_admin(request: &HttpRequest) -> Result<AuthContext> {
+    let admin =
+        AuthContext::of(request).ok_or(messages::unauthorized(MessageKey::AuthenticationFailed))?;
+
+    if !is_admin(request, admin.claims()) {
+        return Err(messages::forbidden(MessageKey::AdminRequired).into());
+    }
+    Ok(admin)
+}
+
+/// Destructive actions have to echo their target
+fn require_confirmation(confirm: Option<&str>, target: &str) -> Result<()> {
+    if confirm != Some(target) {
+        return Err(messages::precondition_required(
+            messages::Message::new(MessageKey::AdminConfirmationRequired).param("target", target),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ConfirmQuery {
+    confirm: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ActionsQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ActionsPage {
+    page: usize,
+    per_page: usize,
+    total: usize,
+    actions: Vec<AdminAction>,
+}
+
+const DEFAULT_ACTIONS_PER_PAGE: usize = 50;
+const MAX_ACTIONS_PER_PAGE: usize = 200;
+
+/// Admin actions with list parameters, paged by cursor as actions are added while an admin pages through them
+struct ActionList;
+
+impl ListEndpoint for ActionList {
+    const DEFAULT_PER_PAGE: usize = DEFAULT_ACTIONS_PER_PAGE;
+    const MAX_PER_PAGE: usize = MAX_ACTIONS_PER_PAGE;
+    const SORT_FIELDS: &'static [&'static str] = &["timestamp"];
+    const DEFAULT_SORT: &'static str = "-timestamp";
+    const CURSOR: bool = true;
+}
+
+/// Review the admin action log, newest first
+/// page and per_page answer as before, the other list parameters answer with the envelope of the list endpoints
+async fn admin_actions_handler(
+    request: HttpRequest,
+    query: web::Query<ActionsQuery>,
+    admin_action_log: web::Data<AdminActionLog>,
+) -> Result<HttpResponse> {
+    require_admin(&request)?;
+
+    if pagination::requested(&request, &["page", "per_page"]) {
+        let list = ListParams::<ActionList>::extract(&request).await?;
+        let page = list.pagination.cursor_page(
+            admin_action_log.sequenced(),
+            list.sort.descending,
+            |(seq, _)| *seq,
+        )?;
+        return Ok(pagination::respond(
+            page.map(|(_, action)| action),
+            &list.fields,
+        ));
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_ACTIONS_PER_PAGE)
+        .clamp(1, MAX_ACTIONS_PER_PAGE);
+    let (actions, total) = admin_action_log.page(page, per_page);
+
+    Ok(HttpResponse::Ok().json(ActionsPage {
+        page,
+        per_page,
+        total,
+        actions,
+    }))
+}
+
+/// Queue estimates of the stores and their persistence, and the stores currently read-only
+async fn admin_actors_handler(
+    request: HttpRequest,
+    actor_gauges: web::Data<ActorGauges>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(actor_gauges.status()))
+}
+
+/// Render times of the server rendered pages per template
+async fn admin_templates_handler(
+    request: HttpRequest,
+    render_monitor: web::Data<RenderMonitor>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(render_monitor.status()))
+}
+
+/// Issues skipped or tolerated while replaying the store eventlogs at startup
+async fn admin_replay_issues_handler(
+    request: HttpRequest,
+    replay_issues: web::Data<ReplayIssues>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(replay_issues.get_ref().clone()))
+}
+
+/// Findings of the startup preflight, the paths the server reads and writes
+async fn admin_preflight_handler(
+    request: HttpRequest,
+    preflight: web::Data<PreflightReport>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(preflight.get_ref().clone()))
+}
+
+#[derive(Serialize)]
+struct AdminCanvasSession<'a> {
+    user_id: &'a UserId,
+    username: &'a str,
+    session_id: &'a str,
+    connection: UnmaskedConnection<'a>,
+}
+
+/// Live sessions on a canvas with their full address and user agent
+async fn admin_canvas_sessions_handler(
+    request: HttpRequest,
+    canvas_id: web::Path<CanvasId>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+
+    let sessions = canvas_server_handle
+        .canvas_sessions(canvas_id.into_inner())
+        .await?;
+    let sessions: Vec<_> = sessions
+        .iter()
+        .map(|session| AdminCanvasSession {
+            user_id: &session.user_id,
+            username: &session.username,
+            session_id: &session.session_id,
+            connection: session.connection.unmasked(),
+        })
+        .collect();
+    Ok(web::Json(serde_json::to_value(sessions)?))
+}
+
+/// Delete a canvas on behalf of its owner, connected sessions are closed
+/// The canvas can be restored until the deletion grace period passed
+async fn admin_delete_canvas_handler(
+    request: HttpRequest,
+    canvas_id: web::Path<CanvasId>,
+    query: web::Query<ConfirmQuery>,
+    admin_action_log: web::Data<AdminActionLog>,
+    delete_canvas_recipient: web::Data<Recipient<DeleteCanvasMessage>>,
+) -> Result<impl Responder> {
+    let admin = require_admin(&request)?;
+    let canvas_id = canvas_id.into_inner();
+    require_confirmation(query.confirm.as_deref(), &canvas_id)?;
+
+    let action = AdminActionLog::begin(
+        &admin_action_log,
+        admin.user_id(),
+        "delete_canvas",
+        &canvas_id,
+    )
+    .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+
+    let result = delete_canvas_recipient
+        .send(DeleteCanvasMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: admin.user_id().clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasDeleteFailed).into())
+        .and_then(|result| result.map_err(actix_web::Error::from));
+    action.finish(&result);
+    result?;
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasDeleted.into(),
+    ))
+}
+
+/// Restore a deleted canvas on behalf of its owner, within the deletion grace period
+async fn admin_restore_canvas_handler(
+    request: HttpRequest,
+    canvas_id: web::Path<CanvasId>,
+    admin_action_log: web::Data<AdminActionLog>,
+    restore_canvas_recipient: web::Data<Recipient<RestoreCanvasMessage>>,
+) -> Result<impl Responder> {
+    let admin = require_admin(&request)?;
+    let canvas_id = canvas_id.into_inner();
+
+    let action = AdminActionLog::begin(
+        &admin_action_log,
+        admin.user_id(),
+        "restore_canvas",
+        &canvas_id,
+    )
+    .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+
+    let result = restore_canvas_recipient
+        .send(RestoreCanvasMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: admin.user_id().clone(),
+            admin: true,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasRestoreFailed).into())
+        .and_then(|result| result.map_err(actix_web::Error::from));
+    action.finish(&result);
+    result?;
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasRestored.into(),
+    ))
+}
+
+#[derive(Serialize)]
+struct DeletedUser {
+    user_id: UserId,
+    /// canvases the user was a member of
+    removed_from: Vec<CanvasId>,
+}
+
+/// Delete an account and remove it from the canvases it is a member of, its sessions are closed
+/// Refused while the user owns canvases, they have to be transferred or deleted first
+#[allow(clippy::too_many_arguments)]
+async fn admin_delete_user_handler(
+    request: HttpRequest,
+    user_id: web::Path<UserId>,
+    query: web::Query<ConfirmQuery>,
+    admin_action_log: web::Data<AdminActionLog>,
+    owned_canvases_recipient: web::Data<Recipient<GetOwnedCanvasesMessage>>,
+    delete_user_recipient: web::Data<Recipient<DeleteUserMessage>>,
+    purge_user_recipient: web::Data<Recipient<PurgeUserMessage>>,
+    jwt_refresh_cache: web::Data<JWTRefreshCache>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    let admin = require_admin(&request)?;
+    let user_id = user_id.into_inner();
+    require_confirmation(query.confirm.as_deref(), &user_id)?;
+
+    let action = AdminActionLog::begin(&admin_action_log, admin.user_id(), "delete_user", &user_id)
+        .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+
+    let result = user::delete_user(
+        &user_id,
+        &owned_canvases_recipient,
+        &delete_user_recipient,
+        &purge_user_recipient,
+        &jwt_refresh_cache,
+        &canvas_server_handle,
+    )
+    .await;
+    action.finish(&result);
+
+    Ok(web::Json(DeletedUser {
+        user_id,
+        removed_from: result?,
+    }))
+}
+
+/// Id of this instance, events in the eventlogs name the instance that wrote them
+async fn admin_instance_handler(request: HttpRequest) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(InstanceInfo::installed()))
+}
+
+/// Current read-only maintenance
+async fn admin_maintenance_handler(
+    request: HttpRequest,
+    maintenance: web::Data<MaintenanceState>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+    Ok(web::Json(maintenance.window()))
+}
+
+/// Switch the read-only maintenance on or off, every connected session is told about the change
+async fn admin_set_maintenance_handler(
+    request: HttpRequest,
+    window: web::Json<MaintenanceWindow>,
+    admin_action_log: web::Data<AdminActionLog>,
+    maintenance: web::Data<MaintenanceState>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    let admin = require_admin(&request)?;
+    let window = window.into_inner();
+    let target = if window.enabled {
+        "enabled"
+    } else {
+        "disabled"
+    };
+
+    let action = AdminActionLog::begin(&admin_action_log, admin.user_id(), "maintenance", target)
+        .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+    maintenance.set(window);
+    canvas_server_handle.notify_all(NoticeLevel::Warning, maintenance.announcement());
+    action.finish(&Ok::<_, String>(()));
+
+    Ok(web::Json(maintenance.window()))
+}
+
+/// Classifies the canvas eventlogs on disk, canvases loaded in the canvas server are skipped
+async fn orphan_report(
+    get_canvas_records_recipient: &Recipient<GetCanvasRecordsMessage>,
+    canvas_server_handle: &CanvasSocketServerHandle,
+) -> Result<OrphanReport> {
+    // loaded first, a canvas loaded in between is known to the store by then
+    let open = canvas_server_handle.loaded_canvases().await?;
+    let records = get_canvas_records_recipient
+        .send(GetCanvasRecordsMessage)
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::StorageScanFailed))?;
+
+    web::block(move || orphans::scan(CANVAS_LOG_DIR, &records, &open))
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::StorageScanFailed))?
+        .map_err(|e| {
+            println!("WARNING: failed to scan the canvas eventlogs: {e}");
+            messages::internal_error(MessageKey::StorageScanFailed).into()
+        })
+}
+
+/// Canvas eventlogs on disk classified against the CanvasStore, unknown ones may point to lost store events
+async fn admin_storage_orphans_handler(
+    request: HttpRequest,
+    get_canvas_records_recipient: web::Data<Recipient<GetCanvasRecordsMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    require_admin(&request)?;
+
+    let report = orphan_report(&get_canvas_records_recipient, &canvas_server_handle).await?;
+    let unknown = report.count(orphans::LogClass::Unknown);
+    if unknown > 0 {
+        println!("WARNING: {unknown} canvas eventlogs without a canvas in the CanvasStore");
+    }
+    Ok(web::Json(report))
+}
+
+#[derive(Deserialize)]
+struct PurgeRequest {
+    files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PurgeResult {
+    /// paths the eventlogs were moved to
+    quarantined: Vec<String>,
+}
+
+/// Move purgeable and unknown canvas eventlogs into the quarantine, confirm with the file names joined by ","
+/// Every file is classified again first, nothing is moved if one of them can't be purged
+async fn admin_storage_purge_handler(
+    request: HttpRequest,
+    purge: web::Json<PurgeRequest>,
+    query: web::Query<ConfirmQuery>,
+    admin_action_log: web::Data<AdminActionLog>,
+    get_canvas_records_recipient: web::Data<Recipient<GetCanvasRecordsMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    replay_cache: web::Data<ReplayCache>,
+) -> Result<impl Responder> {
+    let admin = require_admin(&request)?;
+    let mut files = purge.into_inner().files;
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        return Err(messages::unprocessable_entity(MessageKey::StoragePurgeEmpty).into());
+    }
+    let target = files.join(",");
+    require_confirmation(query.confirm.as_deref(), &target)?;
+
+    let report = orphan_report(&get_canvas_records_recipient, &canvas_server_handle).await?;
+    for file in &files {
+        let class = match report.file(file) {
+            Some(log) if log.class.is_purgeable() => continue,
+            Some(log) => log.class.name(),
+            None if report.skipped_open.contains(file) => "open",
+            None => "missing",
+        };
+        return Err(messages::conflict(
+            messages::Message::new(MessageKey::StoragePurgeRefused)
+                .param("file", file)
+                .param("class", class),
+        )
+        .into());
+    }
+
+    let action = AdminActionLog::begin(
+        &admin_action_log,
+        admin.user_id(),
+        "purge_canvas_logs",
+        &target,
+    )
+    .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+
+    let canvas_ids: Vec<String> = files
+        .iter()
+        .filter_map(|file| orphans::canvas_id_of(file).map(str::to_string))
+        .collect();
+    let now = clock::request_clock(&request).now_ms();
+    let result = web::block(move || {
+        files
+            .iter()
+            .map(|file| orphans::quarantine(CANVAS_LOG_DIR, file, now))
+            .collect::<std::io::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result.map_err(|e| e.to_string()));
+    action.finish(&result);
+    let quarantined = result.map_err(|e| {
+        println!("WARNING: failed to quarantine canvas eventlogs: {e}");
+        messages::internal_error(MessageKey::StoragePurgeFailed)
+    })?;
+    for canvas_id in &canvas_ids {
+        replay_cache.evict(canvas_id);
+    }
+
+    Ok(web::Json(PurgeResult { quarantined }))
+}
+
+pub fn admin_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/api")
+            .wrap(authentication::AuthenticationService)
+            .route("/actions", web::get().to(admin_actions_handler))
+            .route("/actors", web::get().to(admin_actors_handler))
+            .route("/templates", web::get().to(admin_templates_handler))
+            .route("/replay-issues", web::get().to(admin_replay_issues_handler))
+            .route("/preflight", web::get().to(admin_preflight_handler))
+            .route("/instance", web::get().to(admin_instance_handler))
+            .route("/maintenance", web::get().to(admin_maintenance_handler))
+            .route(
+                "/storage/orphans",
+                web::get().to(admin_storage_orphans_handler),
+            )
+            .route(
+                "/storage/purge",
+                web::post().to(admin_storage_purge_handler),
+            )
+            .route(
+                "/maintenance",
+                web::post().to(admin_set_maintenance_handler),
+            )
+            .route(
+                "/canvas/{canvas_id}/sessions",
+                web::get().to(admin_canvas_sessions_handler),
+            )
+            .route(
+                "/canvas/{canvas_id}/delete",
+                web::post().to(admin_delete_canvas_handler),
+            )
+            .route(
+                "/canvas/{canvas_id}/restore",
+                web::post().to(admin_restore_canvas_handler),
+            )
+            .route(
+                "/users/{user_id}/delete",
+                web::post().to(admin_delete_user_handler),
+            ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+
+    fn temp_log() -> (String, web::Data<AdminActionLog>) {
+        let path = std::env::temp_dir()
+            .join(format!("{}-admin_actions.jsonl", nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let log = web::Data::new(AdminActionLog::open(&path, clock::system()).unwrap());
+        (path, log)
+    }
+
+    fn read_lines(path: &str) -> Vec<AdminAction> {
+        EventLogPersistenceJson::open(path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_admins_are_matched_by_user_id() {
+        let admins = Admins::new(["a0000001".to_string()]);
+        let claims = |uid: &str, nam: &str| JWTClaims {
+            uid: uid.to_string(),
+            nam: nam.to_string(),
+            eml: format!("{nam}@example.com"),
+            can: Vec::new(),
+            exp: 0,
+            rfr: String::new(),
+            tv: 0,
+        };
+        assert!(admins.is_admin(&claims("a0000001", "admin")));
+        assert!(admins.is_admin(&claims("a0000001", "renamed")));
+        // the username of an admin grants nothing
+        assert!(!admins.is_admin(&claims("b0000002", "admin")));
+        assert!(!admins.is_admin(&claims("b0000002", "a0000001")));
+    }
+
+    #[test]
+    fn test_actions_are_written_before_and_after() {
+        let (path, log) = temp_log();
+        let admin = "admin".to_string();
+
+        let action = AdminActionLog::begin(&log, &admin, "delete_canvas", "a").unwrap();
+        // the pending line is on disk before the action is attempted
+        assert_eq!(read_lines(&path)[0].outcome, ActionOutcome::Pending);
+        action.finish(&Ok::<(), String>(()));
+
+        let action = AdminActionLog::begin(&log, &admin, "delete_canvas", "b").unwrap();
+        action.finish(&Err::<(), _>("Canvas not found"));
+
+        // a guard dropped on an early return still records an outcome
+        drop(AdminActionLog::begin(&log, &admin, "delete_canvas", "c").unwrap());
+
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 6);
+        for pair in lines.chunks(2) {
+            assert_eq!(pair[0].request_id, pair[1].request_id);
+            assert_eq!(pair[0].outcome, ActionOutcome::Pending);
+        }
+        assert_eq!(lines[1].outcome, ActionOutcome::Succeeded);
+        assert_eq!(
+            lines[3].outcome,
+            ActionOutcome::Failed {
+                reason: "Canvas not found".to_string()
+            }
+        );
+        assert!(matches!(lines[5].outcome, ActionOutcome::Failed { .. }));
+
+        // reopening folds the outcome lines into their action
+        let reopened = AdminActionLog::open(&path, clock::system()).unwrap();
+        let (actions, total) = reopened.page(1, 10);
+        assert_eq!(total, 3);
+        assert_eq!(actions[2].outcome, ActionOutcome::Succeeded);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,3 @@
+/// Conventions shared by the JSON endpoints
+/// New list endpoints take their query parameters and answer with the envelope of pagination.rs
+pub mod pagination;
@@ -0,0 +1,623 @@
+use actix_web::{dev::Payload, Error, FromRequest, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    future::{ready, Ready},
+    marker::PhantomData,
+};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::messages::{self, LocalizedError, Message, MessageKey};
+
+// Query parameters and response envelope shared by the list endpoints
+// ?page=&per_page= selects a page, endpoints opting in continue after ?cursor= instead
+// ?sort=<field> orders by a field the endpoint allows, ?sort=-<field> in descending order
+// ?fields=<a>,<b> reduces every item to these fields
+// Responses are { data, page: { page | next_cursor, per_page, total } }, see respond
+// Invalid parameters are rejected with 422 naming the parameter
+// Endpoints that answered differently before keep their old response unless a new parameter is given, see requested
+
+/// Query parameters of the list endpoints
+pub const LIST_PARAMS: [&str; 5] = ["page", "per_page", "cursor", "sort", "fields"];
+
+/// Limits and orders of a list endpoint, implemented by a marker type per endpoint
+pub trait ListEndpoint {
+    const DEFAULT_PER_PAGE: usize;
+    const MAX_PER_PAGE: usize;
+    /// fields ?sort= accepts
+    const SORT_FIELDS: &'static [&'static str];
+    /// order without ?sort=, one of SORT_FIELDS, prefixed with - for descending order
+    const DEFAULT_SORT: &'static str;
+    /// ?cursor= continues after the last item of the previous page, ?cursor= without value starts
+    const CURSOR: bool = false;
+}
+
+/// Whether the request uses list parameters the endpoint did not ship with
+/// Such endpoints answer with their old response otherwise, so existing clients keep working
+pub fn requested(request: &HttpRequest, shipped: &[&str]) -> bool {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(request.query_string()).is_ok_and(
+        |params| {
+            params.iter().any(|(name, _)| {
+                LIST_PARAMS.contains(&name.as_str()) && !shipped.contains(&name.as_str())
+            })
+        },
+    )
+}
+
+/// List parameters of a query, other parameters of the endpoint are ignored
+/// Numbers are read as text, so errors can name the parameter
+#[derive(Deserialize, IntoParams, Debug, Default)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// page number, starting at 1
+    #[param(value_type = Option<usize>)]
+    page: Option<String>,
+    /// items per page, capped per endpoint
+    #[param(value_type = Option<usize>)]
+    per_page: Option<String>,
+    /// next_cursor of the previous page, only for endpoints paginating by cursor
+    cursor: Option<String>,
+    /// field to sort by, prefixed with - for descending order
+    sort: Option<String>,
+    /// comma separated fields every item is reduced to
+    fields: Option<String>,
+}
+
+impl ListQuery {
+    fn of(request: &HttpRequest) -> Result<Self, LocalizedError> {
+        serde_urlencoded::from_str(request.query_string()).map_err(|_| {
+            messages::unprocessable_entity(
+                Message::new(MessageKey::RequestMalformed).param("reason", "malformed"),
+            )
+        })
+    }
+}
+
+fn invalid(field: &str, message: Message) -> LocalizedError {
+    messages::unprocessable_entity(
+        message
+            .param("reason", "invalid_value")
+            .param("field", field),
+    )
+}
+
+fn parse_number(field: &str, value: &str, max: usize) -> Result<usize, LocalizedError> {
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|number| (1..=max).contains(number))
+        .ok_or_else(|| {
+            invalid(
+                field,
+                Message::new(MessageKey::ListNumberInvalid)
+                    .param("min", 1)
+                    .param("max", max),
+            )
+        })
+}
+
+/// Where the page starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Position {
+    Page(usize),
+    /// None for the first page
+    Cursor(Option<String>),
+}
+
+/// Page the client asked for
+#[derive(Debug)]
+pub struct Pagination<E> {
+    pub per_page: usize,
+    position: Position,
+    endpoint: PhantomData<E>,
+}
+
+impl<E: ListEndpoint> Pagination<E> {
+    fn parse(query: &ListQuery) -> Result<Self, LocalizedError> {
+        let per_page = match &query.per_page {
+            Some(per_page) => parse_number("per_page", per_page, E::MAX_PER_PAGE)?,
+            None => E::DEFAULT_PER_PAGE,
+        };
+        let position = match (&query.page, &query.cursor) {
+            (Some(_), Some(_)) => {
+                return Err(invalid(
+                    "cursor",
+                    Message::new(MessageKey::ListParameterUnsupported),
+                ))
+            }
+            (_, Some(_)) if !E::CURSOR => {
+                return Err(invalid(
+                    "cursor",
+                    Message::new(MessageKey::ListParameterUnsupported),
+                ))
+            }
+            (_, Some(cursor)) => Position::Cursor(Some(cursor.clone()).filter(|c| !c.is_empty())),
+            (Some(page), None) => Position::Page(parse_number("page", page, usize::MAX)?),
+            (None, None) => Position::Page(1),
+        };
+
+        Ok(Self {
+            per_page,
+            position,
+            endpoint: PhantomData,
+        })
+    }
+
+    /// Number of the requested page, 1 for requests paginating by cursor
+    pub fn page_number(&self) -> usize {
+        match self.position {
+            Position::Page(page) => page,
+            Position::Cursor(_) => 1,
+        }
+    }
+
+    /// Items of the requested page, items are already in their order
+    /// Endpoints paginating by cursor use cursor_page instead
+    pub fn page<T>(&self, items: Vec<T>) -> Page<T> {
+        let total = items.len();
+        let start = (self.page_number() - 1).saturating_mul(self.per_page);
+        Page {
+            items: items.into_iter().skip(start).take(self.per_page).collect(),
+            info: PageInfo {
+                position: PagePosition::Number {
+                    page: self.page_number(),
+                },
+                per_page: self.per_page,
+                total: Some(total),
+            },
+        }
+    }
+
+    /// Items after the cursor, ordered by key, which has to be unique
+    /// Items inserted before the cursor while the client pages are not returned, no item is returned twice
+    /// Requests paginating by number are answered like by page
+    pub fn cursor_page<T, K>(
+        &self,
+        mut items: Vec<T>,
+        descending: bool,
+        key: impl Fn(&T) -> K,
+    ) -> Result<Page<T>, LocalizedError>
+    where
+        K: Ord + Serialize + DeserializeOwned,
+    {
+        let order = |ordering: Ordering| match descending {
+            true => ordering.reverse(),
+            false => ordering,
+        };
+        items.sort_by(|a, b| order(key(a).cmp(&key(b))));
+
+        let cursor = match &self.position {
+            Position::Page(_) => return Ok(self.page(items)),
+            Position::Cursor(cursor) => cursor.as_deref().map(decode_cursor::<K>).transpose()?,
+        };
+        let total = items.len();
+        let mut after: Vec<T> = items
+            .into_iter()
+            .filter(|item| {
+                cursor
+                    .as_ref()
+                    .is_none_or(|cursor| order(key(item).cmp(cursor)) == Ordering::Greater)
+            })
+            .take(self.per_page + 1)
+            .collect();
+        let next_cursor = match after.len() > self.per_page {
+            true => {
+                after.truncate(self.per_page);
+                after.last().map(|last| encode_cursor(&key(last)))
+            }
+            false => None,
+        };
+
+        Ok(Page {
+            items: after,
+            info: PageInfo {
+                position: PagePosition::Cursor { next_cursor },
+                per_page: self.per_page,
+                total: Some(total),
+            },
+        })
+    }
+}
+
+fn encode_cursor<K: Serialize>(key: &K) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(key).expect("Cursor can't be serialized"))
+}
+
+fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> Result<K, LocalizedError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| invalid("cursor", Message::new(MessageKey::ListCursorInvalid)))
+}
+
+impl<E: ListEndpoint> FromRequest for Pagination<E> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            ListQuery::of(req)
+                .and_then(|query| Self::parse(&query))
+                .map_err(Error::from),
+        )
+    }
+}
+
+/// Order the client asked for, the field is one of ListEndpoint::SORT_FIELDS
+#[derive(Debug)]
+pub struct SortSpec<E> {
+    pub field: &'static str,
+    pub descending: bool,
+    endpoint: PhantomData<E>,
+}
+
+impl<E: ListEndpoint> SortSpec<E> {
+    fn parse(query: &ListQuery) -> Result<Self, LocalizedError> {
+        let sort = query.sort.as_deref().unwrap_or(E::DEFAULT_SORT);
+        let (name, descending) = match sort.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (sort, false),
+        };
+        let field = E::SORT_FIELDS
+            .iter()
+            .find(|field| **field == name)
+            .ok_or_else(|| {
+                invalid(
+                    "sort",
+                    Message::new(MessageKey::ListSortInvalid)
+                        .param("fields", E::SORT_FIELDS.join(", ")),
+                )
+            })?;
+
+        Ok(Self {
+            field,
+            descending,
+            endpoint: PhantomData,
+        })
+    }
+
+    /// Ordering of two items compared by the field, reversed for descending order
+    pub fn order(&self, ordering: Ordering) -> Ordering {
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+}
+
+impl<E: ListEndpoint> FromRequest for SortSpec<E> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            ListQuery::of(req)
+                .and_then(|query| Self::parse(&query))
+                .map_err(Error::from),
+        )
+    }
+}
+
+/// Sparse fieldset, None keeps every field
+/// Applied to the serialized items, unknown fields are left out like fields that are not set
+#[derive(Debug, Default)]
+pub struct Fields(Option<BTreeSet<String>>);
+
+impl Fields {
+    fn parse(query: &ListQuery) -> Self {
+        let fields: BTreeSet<String> = query
+            .fields
+            .iter()
+            .flat_map(|fields| fields.split(','))
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self(Some(fields).filter(|fields| !fields.is_empty()))
+    }
+
+    pub fn filter(&self, mut item: Value) -> Value {
+        if let (Some(fields), Value::Object(map)) = (&self.0, &mut item) {
+            map.retain(|field, _| fields.contains(field));
+        }
+        item
+    }
+}
+
+impl FromRequest for Fields {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            ListQuery::of(req)
+                .map(|query| Self::parse(&query))
+                .map_err(Error::from),
+        )
+    }
+}
+
+/// Pagination, order and fieldset of a request, parsed at once
+#[derive(Debug)]
+pub struct ListParams<E> {
+    pub pagination: Pagination<E>,
+    pub sort: SortSpec<E>,
+    pub fields: Fields,
+}
+
+impl<E: ListEndpoint> ListParams<E> {
+    /// Envelope of the page of the items, which are already in their order
+    pub fn respond<T: Serialize>(&self, items: Vec<T>) -> HttpResponse {
+        respond(self.pagination.page(items), &self.fields)
+    }
+}
+
+impl<E: ListEndpoint> FromRequest for ListParams<E> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let params = ListQuery::of(req).and_then(|query| {
+            Ok(Self {
+                pagination: Pagination::parse(&query)?,
+                sort: SortSpec::parse(&query)?,
+                fields: Fields::parse(&query),
+            })
+        });
+        ready(params.map_err(Error::from))
+    }
+}
+
+/// Where a page is in its list
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(untagged)]
+pub enum PagePosition {
+    Number {
+        page: usize,
+    },
+    /// next_cursor is null on the last page
+    Cursor {
+        next_cursor: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct PageInfo {
+    #[serde(flatten)]
+    pub position: PagePosition,
+    pub per_page: usize,
+    /// items of the whole list, left out where counting is too expensive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+}
+
+/// Items of a page with their position
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub info: PageInfo,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            info: self.info,
+        }
+    }
+}
+
+/// Response of every list endpoint
+#[derive(Serialize, ToSchema)]
+pub struct Envelope<T> {
+    pub data: Vec<T>,
+    pub page: PageInfo,
+}
+
+/// Envelope of the page, its items reduced to the requested fields
+pub fn respond<T: Serialize>(page: Page<T>, fields: &Fields) -> HttpResponse {
+    // This is a application error, so we can panic
+    let data: Vec<Value> = page
+        .items
+        .iter()
+        .map(|item| fields.filter(serde_json::to_value(item).expect("Item can't be serialized")))
+        .collect();
+    HttpResponse::Ok().json(Envelope {
+        data,
+        page: page.info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::TestRequest, ResponseError};
+    use serde_json::json;
+
+    #[derive(Debug)]
+    struct Numbers;
+
+    impl ListEndpoint for Numbers {
+        const DEFAULT_PER_PAGE: usize = 3;
+        const MAX_PER_PAGE: usize = 10;
+        const SORT_FIELDS: &'static [&'static str] = &["value", "name"];
+        const DEFAULT_SORT: &'static str = "-value";
+        const CURSOR: bool = true;
+    }
+
+    #[derive(Debug)]
+    struct Members;
+
+    impl ListEndpoint for Members {
+        const DEFAULT_PER_PAGE: usize = 50;
+        const MAX_PER_PAGE: usize = 200;
+        const SORT_FIELDS: &'static [&'static str] = &["username"];
+        const DEFAULT_SORT: &'static str = "username";
+    }
+
+    fn query(query: &str) -> ListQuery {
+        ListQuery::of(&TestRequest::with_uri(&format!("/list?{query}")).to_http_request()).unwrap()
+    }
+
+    /// Status of the rejection and whether its message names the parameter
+    fn rejection(error: LocalizedError, field: &str) -> StatusCode {
+        assert!(error.to_string().contains(field), "{error} names {field}");
+        error.status_code()
+    }
+
+    #[test]
+    fn test_invalid_parameters_are_rejected_with_their_name() {
+        for (params, field) in [
+            ("per_page=0", "per_page"),
+            ("per_page=11", "per_page"),
+            ("per_page=ten", "per_page"),
+            ("page=0", "page"),
+            ("page=-1", "page"),
+            ("page=2&cursor=abc", "cursor"),
+            ("cursor=not-a-cursor", "cursor"),
+        ] {
+            let error = Pagination::<Numbers>::parse(&query(params))
+                .and_then(|pagination| pagination.cursor_page(vec![1u64], true, |n| *n))
+                .unwrap_err();
+            assert_eq!(
+                rejection(error, field),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{params}"
+            );
+        }
+        let error = Pagination::<Members>::parse(&query("cursor=")).unwrap_err();
+        assert_eq!(rejection(error, "cursor"), StatusCode::UNPROCESSABLE_ENTITY);
+
+        for sort in ["email", "--value", "-", "Value"] {
+            let error = SortSpec::<Numbers>::parse(&query(&format!("sort={sort}"))).unwrap_err();
+            assert!(error.to_string().contains("value, name"));
+            assert_eq!(rejection(error, "sort"), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        let error = ListQuery::of(&TestRequest::with_uri("/list?page=1&page=2").to_http_request())
+            .unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn test_defaults_and_valid_parameters() {
+        let pagination = Pagination::<Members>::parse(&query("tag=x")).unwrap();
+        assert_eq!((pagination.page_number(), pagination.per_page), (1, 50));
+        let sort = SortSpec::<Members>::parse(&query("")).unwrap();
+        assert_eq!((sort.field, sort.descending), ("username", false));
+
+        let sort = SortSpec::<Numbers>::parse(&query("")).unwrap();
+        assert_eq!((sort.field, sort.descending), ("value", true));
+        assert_eq!(sort.order(1.cmp(&2)), Ordering::Greater);
+        let sort = SortSpec::<Numbers>::parse(&query("sort=name")).unwrap();
+        assert_eq!((sort.field, sort.descending), ("name", false));
+
+        let page = Pagination::<Members>::parse(&query("page=2&per_page=2"))
+            .unwrap()
+            .page((1..=5).collect::<Vec<_>>());
+        assert_eq!(page.items, [3, 4]);
+        assert_eq!(
+            serde_json::to_value(&page.info).unwrap(),
+            json!({ "page": 2, "per_page": 2, "total": 5 })
+        );
+        let page = Pagination::<Members>::parse(&query("page=9"))
+            .unwrap()
+            .page((1..=5).collect::<Vec<_>>());
+        assert!(page.items.is_empty());
+
+        let fields = Fields::parse(&query("fields=id,%20name,,"));
+        assert_eq!(
+            fields.filter(json!({ "id": 1, "name": "a", "secret": true })),
+            json!({ "id": 1, "name": "a" })
+        );
+        assert_eq!(
+            Fields::parse(&query("fields=")).filter(json!({ "id": 1 })),
+            json!({ "id": 1 })
+        );
+    }
+
+    #[test]
+    fn test_only_new_parameters_request_the_envelope() {
+        let request = |uri: &str| TestRequest::with_uri(uri).to_http_request();
+        assert!(!requested(&request("/list"), &[]));
+        assert!(!requested(&request("/list?tag=a"), &[]));
+        assert!(requested(&request("/list?fields=id"), &[]));
+        assert!(!requested(
+            &request("/list?page=2&per_page=5"),
+            &["page", "per_page"]
+        ));
+        assert!(requested(
+            &request("/list?page=2&sort=-timestamp"),
+            &["page", "per_page"]
+        ));
+    }
+
+    /// Deterministic pseudo random numbers, every seed is its own run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    #[test]
+    fn test_cursor_pages_are_stable_under_concurrent_inserts() {
+        for seed in 1..=200u64 {
+            let mut rng = Rng(seed);
+            let descending = seed % 2 == 0;
+            // unique keys with gaps, so inserts land between existing items
+            let mut items: Vec<u64> = (0..rng.below(40) as u64).map(|n| n * 10).collect();
+            let initial = items.clone();
+            let mut cursor: Option<String> = None;
+            let mut seen = Vec::new();
+
+            for _ in 0..1000 {
+                let per_page = 1 + rng.below(Numbers::MAX_PER_PAGE);
+                let params = match &cursor {
+                    Some(cursor) => format!("per_page={per_page}&cursor={cursor}"),
+                    None => format!("per_page={per_page}&cursor="),
+                };
+                let page = Pagination::<Numbers>::parse(&query(&params))
+                    .unwrap()
+                    .cursor_page(items.clone(), descending, |n| *n)
+                    .unwrap();
+                assert!(page.items.len() <= per_page);
+                seen.extend(page.items);
+
+                // other clients insert while this one pages
+                for _ in 0..rng.below(4) {
+                    let key = rng.below(500) as u64;
+                    if !items.contains(&key) {
+                        items.push(key);
+                    }
+                }
+                match page.info.position {
+                    PagePosition::Cursor {
+                        next_cursor: Some(next),
+                    } => cursor = Some(next),
+                    _ => break,
+                }
+            }
+
+            // every item is seen once, in order, and every item present from the start is seen
+            let mut ordered = seen.clone();
+            ordered.sort_by(|a, b| match descending {
+                true => b.cmp(a),
+                false => a.cmp(b),
+            });
+            ordered.dedup();
+            assert_eq!(seen, ordered, "seed {seed}");
+            for item in initial {
+                assert!(seen.contains(&item), "seed {seed} lost {item}");
+            }
+        }
+    }
+}
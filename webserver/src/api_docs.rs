@@ -0,0 +1,162 @@
+use actix_web::{web, HttpResponse, Responder};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as OpenApiDocument,
+    },
+    Modify, OpenApi,
+};
+
+use crate::{canvas, messages::MessageBody, user};
+
+// OpenAPI description of the JSON endpoints
+// Paths and schemas are derived from the handlers and their request and response structs
+// Every service lists its endpoints in its own OpenApi struct, they are merged here
+// Form and page endpoints are left out, they are used by the SPA only
+// The document is served at /api/openapi.json, dev builds serve a Swagger UI at /api/docs/index.html
+
+/// Security scheme names used by the paths
+const COOKIE_SCHEME: &str = "cookie";
+const CANVAS_TOKEN_SCHEME: &str = "canvas_token";
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            COOKIE_SCHEME,
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                user::AUTH_COOKIE_NAME,
+                "JWT set by POST /login",
+            ))),
+        );
+        components.add_security_scheme(
+            CANVAS_TOKEN_SCHEME,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "API token of a single canvas, created by its owner at /canvas/{canvas_id}/tokens",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Drawing Canvas",
+        description = "JSON API of the Drawing Canvas webserver, errors are MessageBody responses if the request accepts JSON"
+    ),
+    components(schemas(MessageBody)),
+    modifiers(&SecuritySchemes),
+    security(("cookie" = [])),
+    tags(
+        (name = "user", description = "The logged in user"),
+        (name = "canvas", description = "Canvases, their members, settings, tokens and exports")
+    )
+)]
+struct ApiDoc;
+
+/// Document with the endpoints of every service
+pub fn openapi() -> OpenApiDocument {
+    let mut openapi = ApiDoc::openapi();
+    openapi.merge(user::UserApi::openapi());
+    openapi.merge(canvas::CanvasApi::openapi());
+    openapi
+}
+
+async fn openapi_handler() -> impl Responder {
+    HttpResponse::Ok().json(openapi())
+}
+
+/// Swagger UI of the document, only in dev builds
+#[cfg(feature = "dev")]
+fn swagger_ui(cfg: &mut web::ServiceConfig) {
+    use utoipa_swagger_ui::{Config, SwaggerUi};
+
+    // NormalizePath trims the trailing slash, the relative assets need the index under the path
+    cfg.route(
+        "/api/docs",
+        web::get().to(|| async {
+            HttpResponse::Found()
+                .insert_header((actix_web::http::header::LOCATION, "/api/docs/index.html"))
+                .finish()
+        }),
+    );
+    cfg.service(SwaggerUi::new("/api/docs/{_:.*}").config(Config::from("/api/openapi.json")));
+}
+
+#[cfg(not(feature = "dev"))]
+fn swagger_ui(_: &mut web::ServiceConfig) {}
+
+pub fn api_docs_service(cfg: &mut web::ServiceConfig) {
+    cfg.route("/api/openapi.json", web::get().to(openapi_handler));
+    swagger_ui(cfg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_covers_the_json_endpoints() {
+        let document = serde_json::to_value(openapi()).unwrap();
+        let paths = &document["paths"];
+
+        for (path, method, schema) in [
+            ("/api/me", "get", "Me"),
+            ("/api/me/activity", "get", "MyActivity"),
+            ("/api/me/preferences", "put", "UserPreferences"),
+            ("/api/canvases", "get", "UserCanvases"),
+            ("/canvas/{canvas_id}/stats", "get", "CanvasStats"),
+            ("/canvas/{canvas_id}/flags", "get", "CanvasFeatureFlags"),
+            ("/canvas/{canvas_id}/read-state", "get", "CanvasReadState"),
+            ("/canvas/{canvas_id}/tokens", "post", "CreatedApiToken"),
+            (
+                "/canvas/{canvas_id}/diagnostics",
+                "get",
+                "CanvasDiagnosticsResponse",
+            ),
+        ] {
+            let operation = &paths[path][method];
+            assert!(operation.is_object(), "{method} {path} is not documented");
+            let success = operation["responses"]
+                .as_object()
+                .unwrap()
+                .iter()
+                .find(|(status, _)| status.starts_with('2'))
+                .map(|(_, response)| response)
+                .unwrap();
+            assert_eq!(
+                success["content"]["application/json"]["schema"]["$ref"],
+                format!("#/components/schemas/{schema}"),
+                "{method} {path}"
+            );
+            assert!(
+                document["components"]["schemas"][schema].is_object(),
+                "{schema} is not part of the components"
+            );
+        }
+
+        // errors share one schema
+        assert_eq!(
+            paths["/canvas/{canvas_id}/stats"]["get"]["responses"]["403"]["content"]
+                ["application/json"]["schema"]["$ref"],
+            "#/components/schemas/MessageBody"
+        );
+
+        let schemes = &document["components"]["securitySchemes"];
+        assert_eq!(schemes["cookie"]["in"], "cookie");
+        assert_eq!(schemes["cookie"]["name"], user::AUTH_COOKIE_NAME);
+        assert_eq!(schemes["canvas_token"]["scheme"], "bearer");
+        // read endpoints accept canvas tokens next to the cookie
+        assert_eq!(
+            paths["/canvas/{canvas_id}/replay"]["get"]["security"],
+            serde_json::json!([{ "cookie": [] }, { "canvas_token": [] }])
+        );
+    }
+}
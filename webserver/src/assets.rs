@@ -0,0 +1,215 @@
+use actix_web::{
+    http::header::{self, CacheControl, CacheDirective, ContentType, EntityTag, IfNoneMatch},
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use mime_guess::mime;
+use rust_embed::{EmbeddedFile, RustEmbed};
+use std::borrow::Cow;
+
+use crate::templates::INDEX;
+
+// Frontend compiled into the binary, only with the embed-frontend feature
+// The vite build named by FRONTEND_DIST_DIR at compile time replaces the dist folder and its templates dir,
+// the binary runs without any frontend files next to it
+// Files under /assets/ have hashed names and are cached forever, everything else is revalidated through its ETag
+
+#[derive(RustEmbed)]
+#[folder = "$FRONTEND_DIST_DIR"]
+struct Frontend;
+
+/// Templates dir inside the vite build
+const TEMPLATES_PREFIX: &str = ".templates/";
+/// Vite puts the hashed bundles here
+const ASSETS_PREFIX: &str = "assets/";
+/// A year, the longest max-age browsers honor
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+/// Embedded file at a path relative to the vite build
+pub fn file(path: &str) -> Option<EmbeddedFile> {
+    Frontend::get(path)
+}
+
+/// Embedded templates as (name, source), named like the templates dir registration names them
+pub fn templates() -> impl Iterator<Item = (String, Cow<'static, [u8]>)> {
+    Frontend::iter().filter_map(|path| {
+        let name = path.strip_prefix(TEMPLATES_PREFIX)?.strip_suffix(".html")?;
+        Some((name.to_string(), Frontend::get(&path)?.data))
+    })
+}
+
+/// Strong ETag of an embedded file, derived from the hash rust-embed computed at compile time
+fn entity_tag(file: &EmbeddedFile) -> EntityTag {
+    let hex: String = file
+        .metadata
+        .sha256_hash()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    EntityTag::new_strong(hex)
+}
+
+/// Hashed assets never change under their name, every other file is revalidated on each use
+fn cache_control(path: &str) -> CacheControl {
+    if path.starts_with(ASSETS_PREFIX) {
+        CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(IMMUTABLE_MAX_AGE),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ])
+    } else {
+        CacheControl(vec![CacheDirective::NoCache])
+    }
+}
+
+/// Content-Type from the extension, text is served as utf-8 like the Files service does
+fn content_type(path: &str) -> ContentType {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let is_text = mime.type_() == mime::TEXT || mime.subtype() == mime::JAVASCRIPT;
+    match mime.get_param(mime::CHARSET) {
+        None if is_text => ContentType(
+            format!("{}; charset=utf-8", mime.essence_str())
+                .parse()
+                .unwrap_or(mime),
+        ),
+        _ => ContentType(mime),
+    }
+}
+
+/// Whether the browser already has this version of the file
+fn is_fresh(request: &HttpRequest, tag: &EntityTag) -> bool {
+    match request.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|other| other.weak_eq(tag)),
+        None => false,
+    }
+}
+
+///
+/// Response for a path relative to the vite build, the path of the request with the leading slash removed
+/// Unknown assets are answered with 404, any other unknown path with index.html so the SPA can route it
+///
+pub fn serve(path: &str, request: &HttpRequest) -> HttpResponse {
+    let path = if path.is_empty() { INDEX } else { path };
+    let (path, file) = match Frontend::get(path) {
+        Some(file) => (path, file),
+        None if path.starts_with(ASSETS_PREFIX) => return HttpResponse::NotFound().finish(),
+        None => match Frontend::get(INDEX) {
+            Some(file) => (INDEX, file),
+            None => return HttpResponse::NotFound().finish(),
+        },
+    };
+
+    let tag = entity_tag(&file);
+    if is_fresh(request, &tag) {
+        return HttpResponse::NotModified()
+            .insert_header(header::ETag(tag))
+            .insert_header(cache_control(path))
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .insert_header(content_type(path))
+        .insert_header(header::ETag(tag))
+        .insert_header(cache_control(path))
+        .body(file.data.into_owned())
+}
+
+async fn embedded_file_handler(path: web::Path<String>, request: HttpRequest) -> HttpResponse {
+    serve(&path, &request)
+}
+
+/// Replaces the Files service of the dist folder, registered last so every other route wins
+pub fn embedded_frontend_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/{path:.*}")
+            .route(web::get().to(embedded_file_handler))
+            .route(web::head().to(embedded_file_handler)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body, http::StatusCode, test::TestRequest};
+
+    // the tests run against any build, e.g. FRONTEND_DIST_DIR=tests/fixtures/dist
+
+    fn header(response: &HttpResponse, name: header::HeaderName) -> &str {
+        response.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_embedded_files_are_served_with_cache_headers() {
+        let request = TestRequest::default().to_http_request();
+        let index = serve(INDEX, &request);
+        assert_eq!(index.status(), StatusCode::OK);
+        assert_eq!(
+            header(&index, header::CONTENT_TYPE),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(header(&index, header::CACHE_CONTROL), "no-cache");
+        let index_tag = header(&index, header::ETAG).to_string();
+
+        // unknown pages are routed by the SPA, unknown assets don't exist
+        let fallback = serve("canvas/unknown", &request);
+        assert_eq!(fallback.status(), StatusCode::OK);
+        assert_eq!(header(&fallback, header::ETAG), index_tag);
+        assert_eq!(
+            serve("assets/missing.js", &request).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let assets: Vec<_> = Frontend::iter()
+            .filter(|path| path.starts_with(ASSETS_PREFIX))
+            .collect();
+        assert!(!assets.is_empty(), "the build has no assets");
+        for path in assets {
+            let response = serve(&path, &request);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                header(&response, header::CACHE_CONTROL),
+                format!("public, max-age={IMMUTABLE_MAX_AGE}, immutable")
+            );
+            assert!(header(&response, header::CONTENT_TYPE).starts_with(
+                mime_guess::from_path(path.as_ref())
+                    .first_or_octet_stream()
+                    .essence_str()
+            ));
+            let expected = file(&path).unwrap().data.into_owned();
+            let body = body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body.as_ref(), expected.as_slice());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_matching_etag_is_not_modified() {
+        let request = TestRequest::default().to_http_request();
+        let tag = header(&serve(INDEX, &request), header::ETAG).to_string();
+
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, tag.clone()))
+            .to_http_request();
+        let response = serve(INDEX, &request);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&response, header::ETAG), tag);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, "\"stale\""))
+            .to_http_request();
+        assert_eq!(serve(INDEX, &request).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_templates_are_named_like_the_templates_dir() {
+        let names: Vec<String> = templates().map(|(name, _)| name).collect();
+        let files = Frontend::iter()
+            .filter(|path| path.starts_with(TEMPLATES_PREFIX) && path.ends_with(".html"))
+            .count();
+        assert_eq!(names.len(), files);
+        for name in names {
+            assert!(!name.starts_with('.') && !name.ends_with(".html"), "{name}");
+        }
+    }
+}
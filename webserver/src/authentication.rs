@@ -1,35 +1,57 @@
+use crate::canvas::guests::{self, GuestAccess, GuestPolicy};
+use crate::canvas::store::AccessLevel;
 use crate::canvas::store::CanvasClaim;
+use crate::canvas::store::CanvasId;
+use crate::canvas::store::GetCanvasMessage;
+use crate::canvas::store::GetUserAccessLevelMessage;
 use crate::canvas::store::GetUserClaimsMessage;
+use crate::canvas::store::ResolveApiTokenMessage;
+use crate::canvas::tokens::{self, TokenPrincipal, TokenRateLimiter};
+use crate::clock::{self, Clock};
+use crate::messages::{self, MessageKey};
 use crate::templates;
 use crate::user;
+use crate::userstore::GetTokenVersionMessage;
 use crate::userstore::GetUserMessage;
 use crate::userstore::SimpleUser;
+use crate::userstore::TouchUserMessage;
 use crate::userstore::UserId;
 use actix::Recipient;
-use actix_web::body::BoxBody;
 use actix_web::body::EitherBody;
-use actix_web::cookie::Cookie;
-use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::error;
+use actix_web::cookie::{Cookie, CookieBuilder, SameSite};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
 use actix_web::web;
 use actix_web::Error;
+use actix_web::FromRequest;
 use actix_web::HttpMessage;
+use actix_web::HttpRequest;
 use futures_util::future::LocalBoxFuture;
 use futures_util::try_join;
-use futures_util::{FutureExt, TryFutureExt};
+use futures_util::FutureExt;
 use serde::{Deserialize, Serialize};
-use std::future::{ready, Ready};
-
-/// Actix Middleware
-/// Used to authenticate users
-/// Checks if a JWT Token is present in the request
-/// validates the token and checks if the token is expired
-/// If the token is expired, it will check if the token is allowed to be refreshed
-/// > this uses a very simple refresh token system, which is not secure
-/// > this needs to be replaced by a proper refresh token system
-/// 
-/// ! JWT are not meant to store session data, but it is required by the exercise
-/// ! I used the JWT heavily. This means it takes 30 seconds for the state of the application to be updated
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+// Actix Middleware
+// Used to authenticate users
+// Checks if a JWT Token is present in the request
+// validates the token and checks if the token is expired
+// If the token is expired, it will check if the token is allowed to be refreshed
+// Tokens issued before the user logged out everywhere are rejected, see GetTokenVersionMessage
+// Requests without the auth cookie may authenticate with a canvas API token instead, see authenticate_api_token
+// Browsers without the auth cookie opening a canvas open for guests are admitted as guest, see admit_guest
+// Websocket handshakes may carry their token as subprotocol or query parameter instead, see websocket_claims
+// > this uses a very simple refresh token system, which is not secure
+// > this needs to be replaced by a proper refresh token system
+//
+// ! JWT are not meant to store session data, but it is required by the exercise
+// ! I used the JWT heavily. This means it takes 30 seconds for the state of the application to be updated
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct JWTClaims {
@@ -39,6 +61,9 @@ pub struct JWTClaims {
     pub can: Vec<CanvasClaim>,
     pub exp: usize,
     pub rfr: String,
+    /// token version of the user at issue time, see GetTokenVersionMessage
+    #[serde(default)]
+    pub tv: u64,
 }
 
 pub struct JWTUser {
@@ -61,6 +86,417 @@ impl From<JWTClaims> for JWTUser {
 
 pub struct RegenerateJWTMarker;
 
+/// Claims embedded into a JWT, the remaining claims are looked up in the CanvasStore when needed
+pub const JWT_CLAIM_LIMIT: usize = 20;
+
+/// Tokens expire quickly and are refreshed by the middleware, see generate_jwt_token
+pub const JWT_LIFETIME_SECS: usize = 15;
+
+/// Subprotocol carrying the token of a websocket handshake, `access_token.<token>`
+pub const WS_TOKEN_PROTOCOL_PREFIX: &str = "access_token.";
+
+/// Query parameter carrying the token of a websocket handshake, only read if WebSocketAuth enables it
+pub const WS_TOKEN_QUERY_PARAM: &str = "auth";
+
+/// Handshake credentials of the websocket route besides the auth cookie and the Authorization header
+/// The subprotocol is always accepted, tokens in the query end up in the access logs of proxies and are opt-in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebSocketAuth {
+    pub query_token: bool,
+}
+
+/// SameSite attribute of the cookies, as configured on the command line
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CookieSameSite {
+    Strict,
+    /// prevents CSRF for POST requests while links from other sites keep the login
+    #[default]
+    Lax,
+    /// cookies are sent with cross-site requests as well, only accepted by browsers together with Secure
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+/// Attributes of every cookie the server sets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookiePolicy {
+    /// only sent over TLS, required once the server is reached through https
+    pub secure: bool,
+    pub same_site: CookieSameSite,
+    /// host only cookies if None
+    pub domain: Option<String>,
+    pub path: String,
+}
+
+impl Default for CookiePolicy {
+    fn default() -> Self {
+        Self {
+            secure: false,
+            same_site: CookieSameSite::Lax,
+            domain: None,
+            path: "/".to_string(),
+        }
+    }
+}
+
+/// Builds the cookies of the server, the only place their attributes are set
+/// Every cookie is HttpOnly, none of them is read by the frontend
+#[derive(Debug, Clone, Default)]
+pub struct CookieFactory {
+    policy: CookiePolicy,
+}
+
+impl CookieFactory {
+    pub fn new(policy: CookiePolicy) -> Self {
+        Self { policy }
+    }
+
+    pub fn policy(&self) -> &CookiePolicy {
+        &self.policy
+    }
+
+    fn build(&self, name: &'static str, value: String) -> CookieBuilder<'static> {
+        let same_site = self.policy.same_site;
+        let builder = Cookie::build(name, value)
+            .http_only(true)
+            .same_site(same_site.into())
+            // browsers drop SameSite=None cookies without Secure
+            .secure(self.policy.secure || same_site == CookieSameSite::None)
+            .path(self.policy.path.clone());
+        match &self.policy.domain {
+            Some(domain) => builder.domain(domain.clone()),
+            None => builder,
+        }
+    }
+
+    /// Cookie carrying the JWT, set on login and whenever the token is refreshed or regenerated
+    pub fn auth_cookie(&self, token: String) -> Cookie<'static> {
+        self.build(user::AUTH_COOKIE_NAME, token).finish()
+    }
+
+    /// Removes the auth cookie, attributes have to match the cookie set before
+    pub fn auth_removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.auth_cookie(String::new());
+        cookie.make_removal();
+        cookie
+    }
+
+    /// Cookie carrying a signed flash, see templates::set_flash
+    pub fn flash_cookie(&self, payload: String) -> Cookie<'static> {
+        self.build(templates::FLASH_COOKIE_NAME, payload)
+            .max_age(templates::FLASH_TTL.try_into().unwrap_or_default())
+            .finish()
+    }
+
+    pub fn flash_removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.flash_cookie(String::new());
+        cookie.make_removal();
+        cookie
+    }
+}
+
+/// Cookie factory registered in the app data, the default policy if none is registered
+pub fn cookie_factory(request: &HttpRequest) -> web::Data<CookieFactory> {
+    request
+        .app_data::<web::Data<CookieFactory>>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Claims of the authenticated user, inserted into the request extensions once and shared by every AuthContext
+struct SharedClaims(Rc<JWTClaims>);
+
+fn insert_claims(req: &HttpRequest, claims: JWTClaims) {
+    req.extensions_mut().insert(SharedClaims(Rc::new(claims)));
+}
+
+///
+/// Authenticated user of a request, extracted from the claims the AuthenticationMiddleware inserted
+/// Answers 401 if the route is not wrapped by the middleware, the claims are shared and never cloned
+/// Access levels of canvases not claimed by the JWT are looked up in the CanvasStore, see canvas_access_level
+///
+#[derive(Clone)]
+pub struct AuthContext {
+    claims: Rc<JWTClaims>,
+    request: HttpRequest,
+}
+
+impl AuthContext {
+    /// Context of the request, None if no claims were inserted
+    pub fn of(request: &HttpRequest) -> Option<Self> {
+        let claims = request.extensions().get::<SharedClaims>()?.0.clone();
+        Some(Self {
+            claims,
+            request: request.clone(),
+        })
+    }
+
+    pub fn user_id(&self) -> &UserId {
+        &self.claims.uid
+    }
+
+    pub fn username(&self) -> &str {
+        &self.claims.nam
+    }
+
+    pub fn email(&self) -> &str {
+        &self.claims.eml
+    }
+
+    pub fn claims(&self) -> &JWTClaims {
+        &self.claims
+    }
+
+    /// Whether the request authenticated with a canvas API token instead of a user session
+    pub fn is_token(&self) -> bool {
+        self.request.extensions().get::<TokenPrincipal>().is_some()
+    }
+
+    /// Rejects API tokens, for HTML pages and for routes creating canvases, tokens or requests
+    /// A token principal is no user, anything it created would belong to nobody
+    pub fn require_user(&self) -> Result<(), Error> {
+        if self.is_token() {
+            return Err(messages::forbidden(MessageKey::ApiTokenScopeDenied).into());
+        }
+        Ok(())
+    }
+
+    /// Claim of the canvas embedded into the JWT, expired claims included
+    pub fn claim_for(&self, canvas_id: &str) -> Option<&CanvasClaim> {
+        self.claims.can.iter().find(|claim| claim.c == canvas_id)
+    }
+
+    /// Access level on the canvas, AccessLevel::None for users that are no member of it
+    pub async fn access_level(&self, canvas_id: &str) -> Result<AccessLevel, Error> {
+        canvas_access_level(&self.request, &self.claims, canvas_id).await
+    }
+
+    /// Access level on the canvas, answers 403 with the denied message if it is below the required one
+    pub async fn require_level(
+        &self,
+        canvas_id: &str,
+        required: AccessLevel,
+        denied: MessageKey,
+    ) -> Result<AccessLevel, Error> {
+        let access_level = self.access_level(canvas_id).await?;
+        if access_level == AccessLevel::None || !access_level.at_least(&required) {
+            return Err(messages::forbidden(denied).into());
+        }
+        Ok(access_level)
+    }
+}
+
+impl FromRequest for AuthContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::of(req).ok_or(messages::unauthorized(MessageKey::AuthenticationFailed).into()))
+    }
+}
+
+/// Access levels looked up in the CanvasStore, cached in the request extensions for the duration of the request
+#[derive(Default)]
+struct CanvasAccessCache(HashMap<CanvasId, AccessLevel>);
+
+/// Access level of the authenticated user on a canvas
+/// Uses the claim of the JWT if present, otherwise asks the CanvasStore
+/// The claim of a guest is ignored, the owner may close the canvas for guests while the cookie is valid
+/// Returns AccessLevel::None for users that are no member of the canvas
+async fn canvas_access_level(
+    request: &HttpRequest,
+    claims: &JWTClaims,
+    canvas_id: &str,
+) -> Result<AccessLevel, Error> {
+    let now = clock::request_clock(request).now_ms();
+    if let Some(claim) = claims.can.iter().find(|claim| {
+        claim.c == canvas_id && !claim.is_expired(now) && !guests::is_guest(&claims.uid)
+    }) {
+        return Ok(claim.r.clone());
+    }
+
+    if let Some(access_level) = request
+        .extensions()
+        .get::<CanvasAccessCache>()
+        .and_then(|cache| cache.0.get(canvas_id).cloned())
+    {
+        return Ok(access_level);
+    }
+
+    let canvas_store = request
+        .app_data::<web::Data<Recipient<GetUserAccessLevelMessage>>>()
+        .ok_or(messages::internal_error(MessageKey::AuthenticationFailed))?;
+    let access_level = canvas_store
+        .send(GetUserAccessLevelMessage {
+            user_id: claims.uid.clone(),
+            canvas_id: canvas_id.to_string(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AuthenticationFailed))?;
+
+    let mut extensions = request.extensions_mut();
+    if let Some(cache) = extensions.get_mut::<CanvasAccessCache>() {
+        cache.0.insert(canvas_id.to_string(), access_level.clone());
+    } else {
+        let mut cache = CanvasAccessCache::default();
+        cache.0.insert(canvas_id.to_string(), access_level.clone());
+        extensions.insert(cache);
+    }
+
+    Ok(access_level)
+}
+
+/// How often an active user is reported to the UserStore
+pub const USER_ACTIVITY_DEBOUNCE: Duration = Duration::from_secs(5 * 60);
+
+/// Debounces activity reports of authenticated requests
+/// Shared between all workers and middleware instances using web::Data
+pub struct UserActivityTracker {
+    touch_user_recipient: Recipient<TouchUserMessage>,
+    debounce: Duration,
+    last_touched: Mutex<HashMap<UserId, Instant>>,
+}
+
+impl UserActivityTracker {
+    pub fn new(touch_user_recipient: Recipient<TouchUserMessage>, debounce: Duration) -> Self {
+        Self {
+            touch_user_recipient,
+            debounce,
+            last_touched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reports the user as active, at most once per debounce interval
+    pub fn touch(&self, user_id: &UserId, now: Instant) {
+        let mut last_touched = self.last_touched.lock().unwrap();
+
+        if last_touched
+            .get(user_id)
+            .is_some_and(|last| now.duration_since(*last) < self.debounce)
+        {
+            return;
+        }
+
+        // forget users that have not been seen for a while, keeps the map bounded by active users
+        last_touched.retain(|_, last| now.duration_since(*last) < self.debounce);
+        last_touched.insert(user_id.clone(), now);
+
+        self.touch_user_recipient.do_send(TouchUserMessage {
+            user_id: user_id.clone(),
+        });
+    }
+}
+
+/// How long a refreshed JWT is handed out to further requests of the same user
+pub const JWT_REFRESH_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Users with a cached refresh, least recently used entries are dropped first
+pub const JWT_REFRESH_CACHE_CAPACITY: usize = 256;
+
+struct RefreshEntry {
+    token: Arc<OnceCell<String>>,
+    created: Instant,
+    last_used: Instant,
+}
+
+/// Coalesces JWT refreshes of parallel requests
+/// The first request of a user refreshes the token, concurrent and following requests within the TTL await and reuse it
+/// Shared between all workers and middleware instances using web::Data
+pub struct JWTRefreshCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<UserId, RefreshEntry>>,
+    hits: AtomicUsize,
+    lookups: AtomicUsize,
+}
+
+impl JWTRefreshCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the cached token of the user or runs refresh, failed refreshes are not cached
+    pub async fn get_or_refresh<F, Fut>(
+        &self,
+        user_id: &UserId,
+        refresh: F,
+    ) -> Result<String, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, Error>>,
+    {
+        let token = self.entry(user_id, Instant::now());
+        token.get_or_try_init(refresh).await.cloned()
+    }
+
+    /// Counts lookups, the hit rate is logged every 100 lookups in debug builds
+    fn record_lookup(&self, hit: bool) {
+        let hits = self.hits.fetch_add(hit as usize, Ordering::Relaxed) + hit as usize;
+        let lookups = self.lookups.fetch_add(1, Ordering::Relaxed) + 1;
+        if cfg!(debug_assertions) && lookups.is_multiple_of(100) {
+            println!("JWT refresh cache hit rate: {hits}/{lookups}");
+        }
+    }
+
+    /// Drops the cached token, the next request refreshes with the current claims
+    pub fn invalidate(&self, user_id: &UserId) {
+        self.entries.lock().unwrap().remove(user_id);
+    }
+
+    fn entry(&self, user_id: &UserId, now: Instant) -> Arc<OnceCell<String>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries
+            .get_mut(user_id)
+            .filter(|entry| now.duration_since(entry.created) < self.ttl)
+        {
+            entry.last_used = now;
+            self.record_lookup(true);
+            return entry.token.clone();
+        }
+        self.record_lookup(false);
+
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| now.duration_since(entry.created) < self.ttl);
+        }
+        if entries.len() >= self.capacity {
+            let least_recently_used = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(user_id, _)| user_id.clone());
+            if let Some(user_id) = least_recently_used {
+                entries.remove(&user_id);
+            }
+        }
+
+        let token = Arc::new(OnceCell::new());
+        entries.insert(
+            user_id.clone(),
+            RefreshEntry {
+                token: token.clone(),
+                created: now,
+                last_used: now,
+            },
+        );
+        token
+    }
+}
+
 // pub struct RefreshClaims {
 //     /// User ID ? not sure if needed here
 //     uid: String,
@@ -81,6 +517,7 @@ pub struct RegenerateJWTMarker;
 pub fn generate_jwt_token(
     user: SimpleUser,
     canvas_claims: Vec<CanvasClaim>,
+    clock: &dyn Clock,
 ) -> Result<String, std::io::Error> {
     // Problem: claims are not stored in the token
     // if the claims change, the token is still valid and won't be invalidated
@@ -92,23 +529,28 @@ pub fn generate_jwt_token(
         nam: user.username,
         eml: user.email,
         can: canvas_claims,
-        exp: chrono::Utc::now().timestamp() as usize + 15, // valid for 15 seconds
+        exp: clock.now_secs() as usize + JWT_LIFETIME_SECS,
         rfr: "refresh".to_string(),
+        tv: user.token_version,
     };
 
+    encode_jwt(&claims)
+}
+
+fn encode_jwt(claims: &JWTClaims) -> Result<String, std::io::Error> {
     jsonwebtoken::encode(
         &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
-        &claims,
+        claims,
         &jsonwebtoken::EncodingKey::from_secret(user::JWT_SECRET.as_bytes()),
     )
-    .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Failed to generate Token"))
+    .map_err(|_| std::io::Error::other("Failed to generate Token"))
 }
 
 pub struct AuthenticationService;
 
 impl<S, B> Transform<S, ServiceRequest> for AuthenticationService
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -119,12 +561,14 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthenticationMiddleware { service }))
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
     }
 }
 
 pub struct AuthenticationMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 /// Helper to generate a JWT form a response and user_id
@@ -145,29 +589,368 @@ async fn recreate_jwt_for_response<B>(
         let (claims, user) = try_join!(
             canvas_store.send(GetUserClaimsMessage {
                 user_id: user_id.clone(),
+                limit: Some(JWT_CLAIM_LIMIT),
+                canvas_id: None,
             }),
             user_store.send(GetUserMessage {
                 username_email: None,
                 user_id: Some(user_id),
             })
         )
-        .map_err(|_| error::ErrorInternalServerError("Failed to refresh token"))?; // mailing error
-                                                                                   // TODO: consider logging alterting system, if this error occurs, something is very wrong
+        .map_err(|_| messages::internal_error(MessageKey::TokenRefreshFailed))?; // mailing error
+                                                                                 // TODO: consider logging alterting system, if this error occurs, something is very wrong
 
-        let user = user.ok_or(error::ErrorInternalServerError("Failed to refresh token"))?;
+        let user = user.ok_or(messages::internal_error(MessageKey::TokenRefreshFailed))?;
         // TODO: consider logging alterting system, if this error occurs, something is very wrong
 
-        generate_jwt_token(user.into(), claims)
-            .map_err(|_| error::ErrorInternalServerError("Failed to refresh token"))
+        let clock = clock::request_clock(res.request());
+        generate_jwt_token(user.into(), claims, clock.as_ref())
+            .map_err(|_| messages::internal_error(MessageKey::TokenRefreshFailed).into())
         // TODO: consider logging alterting system, if this error occurs, something is wrong
     } else {
-        Err(error::ErrorInternalServerError("Failed to refresh token"))
+        Err(messages::internal_error(MessageKey::TokenRefreshFailed).into())
     }
 }
 
-impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+/// Refreshes the JWT through the JWTRefreshCache if one is registered
+/// fresh_claims skips the cache, used if the application changed the claims of the user
+async fn refresh_jwt_for_response<B>(
+    res: &ServiceResponse<B>,
+    user_id: UserId,
+    fresh_claims: bool,
+) -> Result<String, Error> {
+    let Some(cache) = res.request().app_data::<web::Data<JWTRefreshCache>>() else {
+        return recreate_jwt_for_response(res, user_id).await;
+    };
+
+    if fresh_claims {
+        cache.invalidate(&user_id);
+    }
+    cache
+        .get_or_refresh(&user_id, || recreate_jwt_for_response(res, user_id.clone()))
+        .await
+}
+
+/// Redirect to the login page, used for missing, invalid and revoked tokens
+fn redirect_to_login<B>(req: ServiceRequest) -> ServiceResponse<EitherBody<B>> {
+    let redirect_response = templates::redirect_to_static("login", req.request());
+    req.into_response(redirect_response.map_into_right_body())
+}
+
+/// Canvas API token of the Authorization header
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+/// Token of a websocket handshake, the subprotocol is preferred over the query parameter
+fn handshake_token(req: &HttpRequest) -> Option<String> {
+    let protocol = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(WS_TOKEN_PROTOCOL_PREFIX));
+    if let Some(token) = protocol {
+        return Some(token.to_string());
+    }
+
+    let query_token = req
+        .app_data::<web::Data<WebSocketAuth>>()
+        .is_some_and(|auth| auth.query_token);
+    if !query_token {
+        return None;
+    }
+    // the query is parsed here only, the url is never logged
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()?
+        .remove(WS_TOKEN_QUERY_PARAM)
+}
+
+/// Canvas addressed by /canvas/{canvas_id}/.. or /ws/canvas/{canvas_id}, the only paths accepting API tokens
+fn api_token_scope(path: &str) -> Option<&str> {
+    let path = path.strip_prefix("/ws").unwrap_or(path);
+    let canvas_id = path.strip_prefix("/canvas/")?.split('/').next()?;
+    (!canvas_id.is_empty()).then_some(canvas_id)
+}
+
+/// Authenticates the request as the principal of a canvas API token
+/// The principal gets synthetic claims with exactly one claim on the canvas of the token,
+/// tokens are resolved on every request, so revocation and expiry take effect immediately
+async fn authenticate_api_token(req: &HttpRequest, token: &str) -> Result<(), Error> {
+    let (canvas_id, token_id) =
+        tokens::parse(token).ok_or(messages::unauthorized(MessageKey::ApiTokenInvalid))?;
+    match api_token_scope(req.path()) {
+        None => return Err(messages::forbidden(MessageKey::ApiTokenScopeDenied).into()),
+        Some(scope) if scope != canvas_id => {
+            return Err(messages::forbidden(MessageKey::CanvasViewDenied).into())
+        }
+        Some(_) => {}
+    }
+
+    let canvas_store = req
+        .app_data::<web::Data<Recipient<ResolveApiTokenMessage>>>()
+        .ok_or(messages::internal_error(MessageKey::AuthenticationFailed))?;
+    let (api_token, claim) = canvas_store
+        .send(ResolveApiTokenMessage {
+            canvas_id: canvas_id.clone(),
+            token_id: token_id.clone(),
+            secret_hash: tokens::hash(token),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AuthenticationFailed))?
+        .ok_or(messages::unauthorized(MessageKey::ApiTokenInvalid))?;
+
+    if let Some(rate_limiter) = req.app_data::<web::Data<TokenRateLimiter>>() {
+        if !rate_limiter.allow(&token_id, Instant::now()) {
+            return Err(messages::too_many_requests(MessageKey::ApiTokenRateLimited).into());
+        }
+    }
+
+    let claims = JWTClaims {
+        uid: tokens::principal_id(&token_id),
+        nam: api_token.label,
+        eml: String::new(),
+        can: vec![claim],
+        // never refreshed, there is no cookie to replace
+        exp: usize::MAX,
+        rfr: String::new(),
+        tv: 0,
+    };
+    insert_claims(req, claims);
+    req.extensions_mut().insert(TokenPrincipal {
+        token_id,
+        canvas_id,
+    });
+    Ok(())
+}
+
+fn guests_enabled(req: &HttpRequest) -> bool {
+    req.app_data::<web::Data<GuestPolicy>>()
+        .is_some_and(|policy| policy.enabled)
+}
+
+/// Claims and token of a new guest of the canvas page requested
+/// None if guests are disabled, the request is no canvas page or the canvas is closed for guests
+async fn mint_guest(req: &HttpRequest) -> Result<Option<(JWTClaims, String)>, Error> {
+    let Some(policy) = req
+        .app_data::<web::Data<GuestPolicy>>()
+        .filter(|policy| policy.enabled)
+    else {
+        return Ok(None);
+    };
+    let Some(canvas_id) = guests::admission_scope(req.path()) else {
+        return Ok(None);
+    };
+    if req.method() != Method::GET {
+        return Ok(None);
+    }
+
+    let canvas_store = req
+        .app_data::<web::Data<Recipient<GetCanvasMessage>>>()
+        .ok_or(messages::internal_error(MessageKey::AuthenticationFailed))?;
+    let Some(canvas) = canvas_store
+        .send(GetCanvasMessage {
+            canvas_id: canvas_id.to_string(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AuthenticationFailed))?
+        .filter(|canvas| canvas.settings.guest_access != GuestAccess::Closed)
+    else {
+        return Ok(None);
+    };
+
+    let (uid, nam) = guests::generate();
+    let now = clock::request_clock(req).now_secs() as usize;
+    let claims = JWTClaims {
+        uid,
+        nam,
+        eml: String::new(),
+        can: vec![guests::claim(
+            canvas.id,
+            canvas.name,
+            canvas.settings.guest_access,
+        )],
+        exp: now + policy.lifetime.as_secs() as usize,
+        // never refreshed, an expired guest is admitted as new guest
+        rfr: String::new(),
+        tv: 0,
+    };
+    let token = encode_jwt(&claims)
+        .map_err(|_| messages::internal_error(MessageKey::AuthenticationFailed))?;
+    println!("Admitted {} as guest of canvas {}", claims.uid, canvas_id);
+    Ok(Some((claims, token)))
+}
+
+/// Handles the request as a new guest, the response carries the auth cookie of the guest
+/// Redirects to the login page if no guest is admitted, see mint_guest
+async fn admit_guest<S, B>(
+    service: Rc<S>,
+    req: ServiceRequest,
+) -> Result<ServiceResponse<EitherBody<B>>, Error>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let (claims, token) = match mint_guest(req.request()).await {
+        Ok(Some(guest)) => guest,
+        Ok(None) => return Ok(redirect_to_login(req)),
+        Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+    };
+
+    insert_claims(req.request(), claims);
+    let mut res = service.call(req).await?;
+    let auth_cookie = cookie_factory(res.request()).auth_cookie(token);
+    res.response_mut().add_cookie(&auth_cookie)?;
+    Ok(res.map_into_left_body())
+}
+
+///
+/// Handles the request of a guest cookie, guests have no UserStore record and are never refreshed
+/// Guests are limited to their canvas, see guests::permits, opening another canvas admits them as new guest
+/// Expired guests are admitted again, guest cookies are rejected once guests are disabled
+///
+async fn authenticate_guest<S, B>(
+    service: Rc<S>,
+    req: ServiceRequest,
+    claims: JWTClaims,
+) -> Result<ServiceResponse<EitherBody<B>>, Error>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    if !guests_enabled(req.request()) {
+        println!("Rejected guest {}, guest access is disabled", claims.uid);
+        return Ok(redirect_to_login(req));
+    }
+
+    let now = clock::request_clock(req.request()).now_secs() as usize;
+    let canvas_id = claims.can.first().map_or("", |claim| claim.c.as_str());
+    let other_canvas = guests::admission_scope(req.path()).is_some_and(|scope| scope != canvas_id);
+    if claims.exp < now || other_canvas {
+        return admit_guest(service, req).await;
+    }
+    if !guests::permits(canvas_id, req.method(), req.path()) {
+        let error = messages::forbidden(MessageKey::GuestNotAllowed);
+        return Ok(req.error_response(error).map_into_right_body());
+    }
+
+    insert_claims(req.request(), claims);
+    Ok(service.call(req).await?.map_into_left_body())
+}
+
+/// Checks the tv claim against the token version of the UserStore
+/// Tokens of deleted users and tokens issued before a logout everywhere are revoked
+/// Skipped if no UserStore is registered, e.g. in tests of single services
+async fn token_version_current(req: &HttpRequest, claims: &JWTClaims) -> Result<bool, Error> {
+    let Some(user_store) = req.app_data::<web::Data<Recipient<GetTokenVersionMessage>>>() else {
+        return Ok(true);
+    };
+    let token_version = user_store
+        .send(GetTokenVersionMessage {
+            user_id: claims.uid.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AuthenticationFailed))?;
+    Ok(token_version.is_some_and(|token_version| claims.tv >= token_version))
+}
+
+/// Claims of a JWT signed by this server, the expiry is checked by the caller
+fn decode_jwt(token: &str) -> Result<JWTClaims, jsonwebtoken::errors::Error> {
+    let mut validation_rules = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    validation_rules.validate_exp = false; // disable expiration check, we will check it manually
+
+    // jsonwebtoken library not suseptible to algorithm substitution attacks, no need to check alg: none
+    jsonwebtoken::decode::<JWTClaims>(
+        token,
+        &jsonwebtoken::DecodingKey::from_secret(user::JWT_SECRET.as_bytes()),
+        &validation_rules,
+    )
+    .map(|token| token.claims)
+}
+
+///
+/// Claims of a websocket handshake, validated before the upgrade
+/// Handshakes authenticated by the cookie or the Authorization header use the claims of the middleware,
+/// otherwise the token of the subprotocol or the query is validated like the cookie, a JWT or a canvas API token
+/// An expired JWT is rejected instead of refreshed, without cookie there is nothing to replace it with
+/// Guests have no token version, their token is only accepted for the websocket of their canvas
+///
+pub async fn websocket_claims(req: &HttpRequest) -> Result<AuthContext, Error> {
+    if let Some(auth) = AuthContext::of(req) {
+        return Ok(auth);
+    }
+    let token =
+        handshake_token(req).ok_or(messages::unauthorized(MessageKey::AuthenticationFailed))?;
+
+    if token.starts_with(tokens::TOKEN_PREFIX) {
+        authenticate_api_token(req, &token).await?;
+        return AuthContext::of(req)
+            .ok_or(messages::internal_error(MessageKey::AuthenticationFailed).into());
+    }
+
+    let claims = decode_jwt(&token).map_err(|e| {
+        println!("Failed to decode handshake token or invalid token: {:?}", e);
+        messages::unauthorized(MessageKey::HandshakeTokenInvalid)
+    })?;
+    let guest = guests::is_guest(&claims.uid);
+    if guest {
+        if !guests_enabled(req) {
+            return Err(messages::unauthorized(MessageKey::HandshakeTokenInvalid).into());
+        }
+        let canvas_id = claims.can.first().map_or("", |claim| claim.c.as_str());
+        if !guests::permits(canvas_id, req.method(), req.path()) {
+            return Err(messages::forbidden(MessageKey::GuestNotAllowed).into());
+        }
+    } else if !token_version_current(req, &claims).await? {
+        println!("Rejected revoked handshake token of {}", claims.uid);
+        return Err(messages::unauthorized(MessageKey::HandshakeTokenInvalid).into());
+    }
+    let now = clock::request_clock(req).now_secs() as usize;
+    if claims.exp < now {
+        return Err(messages::unauthorized(MessageKey::HandshakeTokenInvalid).into());
+    }
+
+    if let Some(activity_tracker) = req
+        .app_data::<web::Data<UserActivityTracker>>()
+        .filter(|_| !guest)
+    {
+        activity_tracker.touch(&claims.uid, Instant::now());
+    }
+    insert_claims(req, claims);
+    AuthContext::of(req).ok_or(messages::internal_error(MessageKey::AuthenticationFailed).into())
+}
+
+/// Replaces the auth cookie with a refreshed token
+/// Responses that set the auth cookie themselves, e.g. a logout, are left alone
+async fn refresh_auth_cookie<B>(
+    mut res: ServiceResponse<B>,
+    user_id: UserId,
+    fresh_claims: bool,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    if res
+        .response()
+        .cookies()
+        .any(|cookie| cookie.name() == user::AUTH_COOKIE_NAME)
+    {
+        return Ok(res.map_into_left_body());
+    }
+
+    let refreshed_token = match refresh_jwt_for_response(&res, user_id, fresh_claims).await {
+        Ok(refreshed_token) => refreshed_token,
+        // as response, so the error can be localized
+        Err(e) => return Ok(res.error_response(e).map_into_right_body()),
+    };
+
+    let auth_cookie = cookie_factory(res.request()).auth_cookie(refreshed_token);
+    res.response_mut().add_cookie(&auth_cookie)?;
+    // TODO: consider logging alterting system, if this error occurs, something is wrong
+    Ok(res.map_into_left_body())
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -178,107 +961,531 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        // let user_store: &web::Data<Addr<UserStore>> = req.app_data().expect("UserStore not found");
-        let cookie = req.cookie(user::AUTH_COOKIE_NAME);
-
-        let mut validation_rules = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
-        validation_rules.validate_exp = false; // disable expiration check, we will check it manually
-
-        if let Some(cookie) = cookie {
-            // jsonwebtoken library not suseptible to algorithm substitution attacks, no need to check alg: none
-            let jwt_decode = jsonwebtoken::decode::<JWTClaims>(
-                cookie.value(),
-                &jsonwebtoken::DecodingKey::from_secret(user::JWT_SECRET.as_bytes()),
-                &validation_rules,
-            );
+        // the token version is checked before the request is handled, the service is called from the future
+        let service = self.service.clone();
 
-            match jwt_decode {
-                Ok(token) => {
-                    // add claims to request extensions
-                    req.extensions_mut().insert(token.claims.clone());
-
-                    if token.claims.exp < chrono::Utc::now().timestamp() as usize {
-                        if token.claims.rfr == "refresh" {
-                            // Token expired, Refreshing allowed
-
-                            // Explanation: This calles the next middleware in the chain
-                            // then receives the response. This is a future, we can then attach a future to be executed after the response is generated
-                            // then we are able to call another actor and wait its message
-                            // TODO: maybe ask on discord if this is "idiomatic" actix-web/async-rust
-                            self.service
-                                .call(req)
-                                .and_then(|mut res| async move {
-                                    let refreshed_token =
-                                        recreate_jwt_for_response(&res, token.claims.uid).await?;
-
-                                    res.response_mut().add_cookie(
-                                        &Cookie::build(user::AUTH_COOKIE_NAME, refreshed_token)
-                                            .same_site(actix_web::cookie::SameSite::Lax)
-                                            .http_only(true)
-                                            .path("/")
-                                            .finish(),
-                                    )?;
-                                    // TODO: consider logging alterting system, if this error occurs, something is wrong
-                                    Ok(res)
-                                })
-                                .map_ok(ServiceResponse::map_into_left_body::<BoxBody>)
-                                .boxed_local()
-                        } else {
-                            // Token expired, Refresh not allowed
-
-                            Box::pin(async {
-                                let redirect_response =
-                                    templates::redirect_to_static("login", req.request());
-                                Ok(req.into_response(redirect_response.map_into_right_body()))
-                            })
-                        }
-                    } else {
-                        // JWT is valid and not expired
-
-                        self.service
-                            .call(req)
-                            .and_then(|mut res| async {
-                                // check if appliaction requests a jwt token refresh
-                                if res
-                                    .request()
-                                    .extensions()
-                                    .get::<RegenerateJWTMarker>()
-                                    .is_some()
-                                {
-                                    let refreshed_token =
-                                        recreate_jwt_for_response(&res, token.claims.uid).await?;
-
-                                    res.response_mut().add_cookie(
-                                        &Cookie::build(user::AUTH_COOKIE_NAME, refreshed_token)
-                                            .same_site(actix_web::cookie::SameSite::Lax)
-                                            .http_only(true)
-                                            .path("/")
-                                            .finish(),
-                                    )?;
-                                }
-
-                                Ok(res)
-                            })
-                            .map_ok(ServiceResponse::map_into_left_body::<BoxBody>)
-                            .boxed_local()
-                    }
+        async move {
+            let Some(cookie) = req.cookie(user::AUTH_COOKIE_NAME) else {
+                if let Some(token) = bearer_token(&req) {
+                    // as response, so the error can be localized
+                    return match authenticate_api_token(req.request(), &token).await {
+                        Ok(()) => Ok(service.call(req).await?.map_into_left_body()),
+                        Err(e) => Ok(req.error_response(e).map_into_right_body()),
+                    };
                 }
+
+                if req.path().starts_with("/ws/") && handshake_token(req.request()).is_some() {
+                    // validated by the handler before the upgrade, see websocket_claims
+                    return Ok(service.call(req).await?.map_into_left_body());
+                }
+
+                // No JWT Token found, open canvases admit guests
+                return admit_guest(service, req).await;
+            };
+
+            let claims = match decode_jwt(cookie.value()) {
+                Ok(claims) => claims,
                 Err(e) => {
                     println!("Failed to decode token or invalid token: {:?}", e);
-                    Box::pin(async {
-                        let redirect_response =
-                            templates::redirect_to_static("login", req.request());
-                        Ok(req.into_response(redirect_response.map_into_right_body()))
-                    })
+                    return Ok(redirect_to_login(req));
                 }
+            };
+
+            if guests::is_guest(&claims.uid) {
+                return authenticate_guest(service, req, claims).await;
             }
-        } else {
-            // No JWT Token found
 
+            // revoked tokens are neither accepted nor refreshed
+            if !token_version_current(req.request(), &claims).await? {
+                println!("Rejected revoked token of {}", claims.uid);
+                return Ok(redirect_to_login(req));
+            }
+
+            if let Some(activity_tracker) = req.app_data::<web::Data<UserActivityTracker>>() {
+                activity_tracker.touch(&claims.uid, Instant::now());
+            }
+
+            let now = clock::request_clock(req.request()).now_secs() as usize;
+            let (user_id, expired, refreshable) = (
+                claims.uid.clone(),
+                claims.exp < now,
+                claims.rfr == "refresh",
+            );
+            // add claims to request extensions
+            insert_claims(req.request(), claims);
+
+            if expired {
+                if !refreshable {
+                    // Token expired, Refresh not allowed
+                    return Ok(redirect_to_login(req));
+                }
+
+                // Token expired, Refreshing allowed
+                // the request is handled first, the refreshed token is attached to its response
+                let res = service.call(req).await?;
+                refresh_auth_cookie(res, user_id, false).await
+            } else {
+                // JWT is valid and not expired
+
+                let res = service.call(req).await?;
+                // check if appliaction requests a jwt token refresh
+                if res
+                    .request()
+                    .extensions()
+                    .get::<RegenerateJWTMarker>()
+                    .is_some()
+                {
+                    refresh_auth_cookie(res, user_id, true).await
+                } else {
+                    Ok(res.map_into_left_body())
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, Context, Handler};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct TouchCounter(Arc<AtomicUsize>);
+
+    impl Actor for TouchCounter {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<TouchUserMessage> for TouchCounter {
+        type Result = ();
+
+        fn handle(&mut self, _: TouchUserMessage, _: &mut Self::Context) -> Self::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_cookie_attributes_follow_the_policy() {
+        for (secure, same_site, domain) in [
+            (false, CookieSameSite::Lax, None),
+            (true, CookieSameSite::Lax, None),
+            (false, CookieSameSite::Strict, Some("example.com")),
+            (false, CookieSameSite::None, None),
+            (true, CookieSameSite::None, Some("example.com")),
+        ] {
+            let factory = CookieFactory::new(CookiePolicy {
+                secure,
+                same_site,
+                domain: domain.map(str::to_string),
+                path: "/app".to_string(),
+            });
+            let cookies = [
+                factory.auth_cookie("token".to_string()),
+                factory.auth_removal_cookie(),
+                factory.flash_cookie("flash".to_string()),
+                factory.flash_removal_cookie(),
+            ];
+            for cookie in cookies {
+                let case = format!("{cookie} with {:?}", factory.policy());
+                assert_eq!(cookie.http_only(), Some(true), "{case}");
+                assert_eq!(cookie.same_site(), Some(same_site.into()), "{case}");
+                // browsers drop SameSite=None cookies without Secure
+                let expected_secure = secure || same_site == CookieSameSite::None;
+                assert_eq!(cookie.secure(), Some(expected_secure), "{case}");
+                assert_eq!(cookie.domain(), domain, "{case}");
+                assert_eq!(cookie.path(), Some("/app"), "{case}");
+            }
+        }
+
+        let factory = CookieFactory::default();
+        let auth = factory.auth_cookie("token".to_string());
+        assert_eq!(auth.name(), user::AUTH_COOKIE_NAME);
+        assert_eq!(auth.max_age(), None);
+        assert_eq!(auth.path(), Some("/"));
+        let removal = factory.auth_removal_cookie();
+        assert_eq!(removal.value(), "");
+        assert_eq!(
+            removal.max_age(),
+            Some(actix_web::cookie::time::Duration::ZERO)
+        );
+        assert_eq!(
+            factory.flash_cookie("flash".to_string()).max_age(),
+            Some(templates::FLASH_TTL.try_into().unwrap())
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_activity_is_debounced() {
+        let touches = Arc::new(AtomicUsize::new(0));
+        let tracker = UserActivityTracker::new(
+            TouchCounter(touches.clone()).start().recipient(),
+            USER_ACTIVITY_DEBOUNCE,
+        );
+
+        let start = Instant::now();
+        let user_id = "user".to_string();
+        for request in 0..100 {
+            tracker.touch(&user_id, start + Duration::from_millis(request));
+        }
+        tracker.touch(&user_id, start + USER_ACTIVITY_DEBOUNCE);
+
+        // process the mailbox
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(touches.load(Ordering::SeqCst), 2);
+    }
+
+    struct ClaimsCounter(Arc<AtomicUsize>);
+
+    impl Actor for ClaimsCounter {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<GetUserClaimsMessage> for ClaimsCounter {
+        type Result = actix::ResponseFuture<Vec<CanvasClaim>>;
+
+        fn handle(&mut self, _: GetUserClaimsMessage, _: &mut Self::Context) -> Self::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            // keep the refresh in flight while the other requests arrive
             Box::pin(async {
-                let redirect_response = templates::redirect_to_static("login", req.request());
-                Ok(req.into_response(redirect_response.map_into_right_body()))
+                actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+                Vec::new()
+            })
+        }
+    }
+
+    impl Handler<GetUserMessage> for ClaimsCounter {
+        type Result = Option<crate::userstore::User>;
+
+        fn handle(&mut self, msg: GetUserMessage, _: &mut Self::Context) -> Self::Result {
+            Some(crate::userstore::User {
+                id: msg.user_id.unwrap(),
+                email: "user@example.com".to_string(),
+                username: "user".to_string(),
+                password_hash: String::new(),
+                last_login_at: None,
+                last_seen_at: None,
+                token_version: 0,
+                preferences: Default::default(),
             })
         }
     }
+
+    impl Handler<GetTokenVersionMessage> for ClaimsCounter {
+        type Result = Option<u64>;
+
+        fn handle(&mut self, _: GetTokenVersionMessage, _: &mut Self::Context) -> Self::Result {
+            // the user logged out everywhere once
+            Some(1)
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_parallel_refreshes_are_coalesced() {
+        use actix_web::{test, App, HttpResponse};
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = ClaimsCounter(lookups.clone()).start();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    counter.clone().recipient::<GetUserClaimsMessage>(),
+                ))
+                .app_data(web::Data::new(counter.recipient::<GetUserMessage>()))
+                .app_data(web::Data::new(JWTRefreshCache::new(
+                    JWT_REFRESH_CACHE_TTL,
+                    JWT_REFRESH_CACHE_CAPACITY,
+                )))
+                .wrap(AuthenticationService)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/regenerate",
+                    web::get().to(|req: HttpRequest| async move {
+                        req.extensions_mut().insert(RegenerateJWTMarker);
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let token_with_exp = |exp: usize| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+                &JWTClaims {
+                    uid: "user".to_string(),
+                    nam: "user".to_string(),
+                    eml: "user@example.com".to_string(),
+                    can: Vec::new(),
+                    exp,
+                    rfr: "refresh".to_string(),
+                    tv: 0,
+                },
+                &jsonwebtoken::EncodingKey::from_secret(user::JWT_SECRET.as_bytes()),
+            )
+            .unwrap()
+        };
+        let token = token_with_exp(0);
+        let request = |path: &str, token: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .cookie(Cookie::new(user::AUTH_COOKIE_NAME, token.to_string()))
+                .to_request()
+        };
+
+        let responses = futures_util::future::join_all(
+            (0..10).map(|_| test::call_service(&app, request("/", &token))),
+        )
+        .await;
+
+        let tokens: Vec<String> = responses
+            .iter()
+            .map(|res| {
+                res.response()
+                    .cookies()
+                    .find(|cookie| cookie.name() == user::AUTH_COOKIE_NAME)
+                    .expect("refreshed token")
+                    .value()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert!(tokens.iter().all(|refreshed| *refreshed == tokens[0]));
+
+        // changed claims bypass the cache
+        let valid = token_with_exp(clock::SystemClock.now_secs() as usize + 60);
+        test::call_service(&app, request("/regenerate", &valid)).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_token_expires_with_clock() {
+        use actix_web::{test, App, HttpResponse};
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = ClaimsCounter(lookups.clone()).start();
+        let manual_clock = Arc::new(clock::ManualClock::new(1_000_000));
+        let shared_clock: clock::SharedClock = manual_clock.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    counter.clone().recipient::<GetUserClaimsMessage>(),
+                ))
+                .app_data(web::Data::new(counter.recipient::<GetUserMessage>()))
+                .app_data(web::Data::from(shared_clock))
+                .wrap(AuthenticationService)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let user = SimpleUser {
+            id: "user".to_string(),
+            username: "user".to_string(),
+            email: "user@example.com".to_string(),
+            token_version: 0,
+        };
+        let token = generate_jwt_token(user, Vec::new(), manual_clock.as_ref()).unwrap();
+        fn refreshed_token<B>(res: &ServiceResponse<B>) -> Option<String> {
+            res.response()
+                .cookies()
+                .find(|cookie| cookie.name() == user::AUTH_COOKIE_NAME)
+                .map(|cookie| cookie.value().to_string())
+        }
+        let request = || {
+            test::TestRequest::get()
+                .cookie(Cookie::new(user::AUTH_COOKIE_NAME, token.clone()))
+                .to_request()
+        };
+
+        manual_clock.advance(Duration::from_secs(JWT_LIFETIME_SECS as u64));
+        let res = test::call_service(&app, request()).await;
+        assert!(refreshed_token(&res).is_none());
+        assert_eq!(lookups.load(Ordering::SeqCst), 0);
+
+        manual_clock.advance(Duration::from_secs(1));
+        let res = test::call_service(&app, request()).await;
+        let refreshed = refreshed_token(&res).expect("expired token is refreshed");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // the library checks exp against the system time
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<JWTClaims>(
+            &refreshed,
+            &jsonwebtoken::DecodingKey::from_secret(user::JWT_SECRET.as_bytes()),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.exp, 1_000 + 16 + JWT_LIFETIME_SECS);
+    }
+
+    #[actix_web::test]
+    async fn test_revoked_token_is_not_refreshed() {
+        use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = ClaimsCounter(lookups.clone()).start();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    counter.clone().recipient::<GetUserClaimsMessage>(),
+                ))
+                .app_data(web::Data::new(
+                    counter.clone().recipient::<GetTokenVersionMessage>(),
+                ))
+                .app_data(web::Data::new(counter.recipient::<GetUserMessage>()))
+                .service(web::resource("/login").name("login"))
+                .service(
+                    web::resource("/")
+                        .wrap(AuthenticationService)
+                        .route(web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        // expired, so a valid token would be refreshed
+        let request = |tv: u64| {
+            let token = jsonwebtoken::encode(
+                &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+                &JWTClaims {
+                    uid: "user".to_string(),
+                    nam: "user".to_string(),
+                    eml: "user@example.com".to_string(),
+                    can: Vec::new(),
+                    exp: 0,
+                    rfr: "refresh".to_string(),
+                    tv,
+                },
+                &jsonwebtoken::EncodingKey::from_secret(user::JWT_SECRET.as_bytes()),
+            )
+            .unwrap();
+            test::TestRequest::get()
+                .cookie(Cookie::new(user::AUTH_COOKIE_NAME, token))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, request(0)).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(lookups.load(Ordering::SeqCst), 0);
+
+        let res = test::call_service(&app, request(1)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    fn claims_with(can: Vec<CanvasClaim>) -> JWTClaims {
+        JWTClaims {
+            uid: "user".to_string(),
+            nam: "user".to_string(),
+            eml: "user@example.com".to_string(),
+            can,
+            exp: usize::MAX,
+            rfr: "refresh".to_string(),
+            tv: 0,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_auth_context_requires_the_middleware() {
+        use actix_web::{http::StatusCode, test};
+
+        let request = test::TestRequest::default().to_http_request();
+        let error = AuthContext::from_request(&request, &mut Payload::None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        insert_claims(&request, claims_with(Vec::new()));
+        let auth = AuthContext::from_request(&request, &mut Payload::None)
+            .await
+            .unwrap();
+        assert_eq!(auth.user_id(), "user");
+        assert_eq!(auth.username(), "user");
+        assert_eq!(auth.email(), "user@example.com");
+        // every context of the request shares the claims
+        let other = AuthContext::of(&request).unwrap();
+        assert!(Rc::ptr_eq(&auth.claims, &other.claims));
+    }
+
+    #[actix_web::test]
+    async fn test_require_level_compares_at_least() {
+        use actix_web::{http::StatusCode, test};
+
+        let levels = [
+            AccessLevel::Read,
+            AccessLevel::Voice,
+            AccessLevel::Write,
+            AccessLevel::Moderate,
+            AccessLevel::Owner,
+        ];
+        let request = test::TestRequest::default().to_http_request();
+        insert_claims(
+            &request,
+            claims_with(
+                levels
+                    .iter()
+                    .map(|level| CanvasClaim {
+                        n: level.to_string(),
+                        c: level.to_string(),
+                        r: level.clone(),
+                        exp: None,
+                    })
+                    .collect(),
+            ),
+        );
+        // canvases without a claim are looked up in the CanvasStore, the lookup is cached
+        let mut cache = CanvasAccessCache::default();
+        cache.0.insert("None".to_string(), AccessLevel::None);
+        cache.0.insert("Stored".to_string(), AccessLevel::Write);
+        request.extensions_mut().insert(cache);
+        let auth = AuthContext::of(&request).unwrap();
+
+        assert!(auth.claim_for("Owner").is_some());
+        assert!(auth.claim_for("Stored").is_none());
+        for held in levels.iter().chain([&AccessLevel::None]) {
+            for required in &levels {
+                let result = auth
+                    .require_level(
+                        &held.to_string(),
+                        required.clone(),
+                        MessageKey::CanvasViewDenied,
+                    )
+                    .await;
+                match result {
+                    Ok(access_level) => {
+                        assert_eq!(access_level, *held);
+                        assert!(held.rank() >= required.rank(), "{held} passed {required}");
+                    }
+                    Err(e) => {
+                        assert_eq!(e.as_response_error().status_code(), StatusCode::FORBIDDEN);
+                        assert!(
+                            *held == AccessLevel::None || held.rank() < required.rank(),
+                            "{held} refused {required}"
+                        );
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            auth.require_level("Stored", AccessLevel::Write, MessageKey::CanvasViewDenied)
+                .await
+                .unwrap(),
+            AccessLevel::Write
+        );
+        assert!(auth
+            .require_level(
+                "Stored",
+                AccessLevel::Moderate,
+                MessageKey::CanvasViewDenied
+            )
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,282 @@
+use super::{
+    store::{AccessLevel, CanvasId},
+    tokens::TokenRateLimiter,
+};
+use crate::{
+    messages::{self, LocalizedError, Message, MessageKey},
+    userstore::UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+// Requests of users without access to a canvas, asked from the no access page and answered by owners and moderators
+// Requests are kept by the CanvasStore and rebuilt from its eventlog, a user has at most one pending request per canvas
+// Resolved requests stay listed for RESOLVED_REQUEST_RETENTION, the next replay drops older ones
+
+/// Characters of the optional message to the owner
+pub const MAX_ACCESS_REQUEST_MESSAGE_LENGTH: usize = 500;
+
+/// Time resolved requests stay listed, lets requesters see the answer and owners what they answered
+pub const RESOLVED_REQUEST_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Requests per user and canvas and window, cancelling and asking again counts as well
+pub const ACCESS_REQUEST_LIMIT: u32 = 3;
+pub const ACCESS_REQUEST_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum AccessRequestStatus {
+    Pending,
+    Approved,
+    Denied,
+    /// withdrawn by the requester
+    Cancelled,
+}
+
+/// Answer to a pending request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessRequestResolution {
+    /// access was granted with the level
+    Approved(AccessLevel),
+    Denied,
+    Cancelled,
+}
+
+impl AccessRequestResolution {
+    pub fn status(&self) -> AccessRequestStatus {
+        match self {
+            AccessRequestResolution::Approved(_) => AccessRequestStatus::Approved,
+            AccessRequestResolution::Denied => AccessRequestStatus::Denied,
+            AccessRequestResolution::Cancelled => AccessRequestStatus::Cancelled,
+        }
+    }
+
+    pub fn access_level(&self) -> Option<AccessLevel> {
+        match self {
+            AccessRequestResolution::Approved(access_level) => Some(access_level.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct AccessRequest {
+    pub user_id: UserId,
+    pub message: Option<String>,
+    /// unix timestamp in milliseconds
+    pub requested_at: u64,
+    pub status: AccessRequestStatus,
+    /// unix timestamp in milliseconds
+    pub resolved_at: Option<u64>,
+    pub resolved_by: Option<UserId>,
+    /// level granted on approval
+    pub access_level: Option<AccessLevel>,
+}
+
+/// Access requests of a single canvas, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessRequests(Vec<AccessRequest>);
+
+impl AccessRequests {
+    pub fn pending(&self, user_id: &UserId) -> Option<&AccessRequest> {
+        self.0.iter().find(|request| {
+            &request.user_id == user_id && request.status == AccessRequestStatus::Pending
+        })
+    }
+
+    /// Latest request of the user that is still listed, pending or resolved
+    pub fn latest(&self, user_id: &UserId, now: u64) -> Option<&AccessRequest> {
+        self.0
+            .iter()
+            .rev()
+            .find(|request| &request.user_id == user_id && is_listed(request, now))
+    }
+
+    /// Adds a pending request, false if the user has one pending already
+    pub fn request(&mut self, user_id: &UserId, message: Option<String>, timestamp: u64) -> bool {
+        if self.pending(user_id).is_some() {
+            return false;
+        }
+        self.0.push(AccessRequest {
+            user_id: user_id.clone(),
+            message,
+            requested_at: timestamp,
+            status: AccessRequestStatus::Pending,
+            resolved_at: None,
+            resolved_by: None,
+            access_level: None,
+        });
+        true
+    }
+
+    /// Resolves the pending request of the user, None if there is none
+    pub fn resolve(
+        &mut self,
+        user_id: &UserId,
+        resolution: &AccessRequestResolution,
+        initiator_id: &UserId,
+        timestamp: u64,
+    ) -> Option<AccessRequest> {
+        let request = self.0.iter_mut().find(|request| {
+            &request.user_id == user_id && request.status == AccessRequestStatus::Pending
+        })?;
+        request.status = resolution.status();
+        request.resolved_at = Some(timestamp);
+        request.resolved_by = Some(initiator_id.clone());
+        request.access_level = resolution.access_level();
+        Some(request.clone())
+    }
+
+    /// Pending requests and requests resolved within RESOLVED_REQUEST_RETENTION, oldest first
+    pub fn listed(&self, now: u64) -> Vec<AccessRequest> {
+        self.0
+            .iter()
+            .filter(|request| is_listed(request, now))
+            .cloned()
+            .collect()
+    }
+
+    /// Drops requests resolved before RESOLVED_REQUEST_RETENTION
+    pub fn prune(&mut self, now: u64) {
+        self.0.retain(|request| is_listed(request, now));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn is_listed(request: &AccessRequest, now: u64) -> bool {
+    request.resolved_at.is_none_or(|resolved_at| {
+        now.saturating_sub(resolved_at) < RESOLVED_REQUEST_RETENTION.as_millis() as u64
+    })
+}
+
+/// Invisible characters that reorder or hide text, the message is shown to the owner as is
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2069}' | '\u{feff}')
+}
+
+/// Message of the requester on a single line without control and invisible characters, None if nothing is left
+pub fn sanitize_message(message: Option<&str>) -> Result<Option<String>, LocalizedError> {
+    let Some(message) = message else {
+        return Ok(None);
+    };
+    let message = message
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| !c.is_control() && !is_invisible(*c))
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if message.chars().count() > MAX_ACCESS_REQUEST_MESSAGE_LENGTH {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::AccessRequestMessageTooLong)
+                .param("max", MAX_ACCESS_REQUEST_MESSAGE_LENGTH)
+                .param("reason", "invalid_value")
+                .param("field", "message"),
+        ));
+    }
+    Ok(Some(message).filter(|message| !message.is_empty()))
+}
+
+/// Request or answer handed to the Notifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRequestNotice {
+    pub canvas_id: CanvasId,
+    pub canvas_name: String,
+    pub request: AccessRequest,
+}
+
+/// Fixed window rate limit of access requests per user and canvas, shared between all workers using web::Data
+pub struct AccessRequestLimiter(TokenRateLimiter);
+
+impl Default for AccessRequestLimiter {
+    fn default() -> Self {
+        Self::new(ACCESS_REQUEST_LIMIT, ACCESS_REQUEST_WINDOW)
+    }
+}
+
+impl AccessRequestLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self(TokenRateLimiter::new(limit, window))
+    }
+
+    /// Counts the request, false once the user used up the window of the canvas
+    pub fn allow(&self, user_id: &UserId, canvas_id: &CanvasId, now: Instant) -> bool {
+        self.0.allow(&format!("{user_id}/{canvas_id}"), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, ResponseError};
+
+    #[test]
+    fn test_requests_are_deduplicated_while_pending() {
+        let mut requests = AccessRequests::default();
+        let alice = "alice".to_string();
+        let owner = "owner".to_string();
+
+        assert!(requests.request(&alice, Some("hi".to_string()), 10));
+        assert!(!requests.request(&alice, None, 20));
+        assert_eq!(requests.listed(20).len(), 1);
+
+        let denied = requests
+            .resolve(&alice, &AccessRequestResolution::Denied, &owner, 30)
+            .unwrap();
+        assert_eq!(denied.status, AccessRequestStatus::Denied);
+        assert_eq!(denied.resolved_by, Some(owner.clone()));
+        assert!(requests
+            .resolve(&alice, &AccessRequestResolution::Denied, &owner, 40)
+            .is_none());
+
+        // asking again after the answer is a new request
+        assert!(requests.request(&alice, None, 50));
+        assert_eq!(
+            requests.latest(&alice, 50).unwrap().status,
+            AccessRequestStatus::Pending
+        );
+        assert_eq!(requests.listed(50).len(), 2);
+
+        let retention = RESOLVED_REQUEST_RETENTION.as_millis() as u64;
+        requests.prune(30 + retention);
+        assert_eq!(requests.listed(30 + retention).len(), 1);
+        assert!(requests.pending(&alice).is_some());
+    }
+
+    #[test]
+    fn test_messages_are_sanitized_and_capped() {
+        assert_eq!(sanitize_message(None).unwrap(), None);
+        assert_eq!(sanitize_message(Some(" \n\t ")).unwrap(), None);
+        assert_eq!(
+            sanitize_message(Some("  please\nadd\u{202e} me\u{7}  ")).unwrap(),
+            Some("please add me".to_string())
+        );
+
+        let longest = "a".repeat(MAX_ACCESS_REQUEST_MESSAGE_LENGTH);
+        assert_eq!(
+            sanitize_message(Some(&longest)).unwrap(),
+            Some(longest.clone())
+        );
+        let error = sanitize_message(Some(&format!("{longest}a"))).unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error.to_string().contains("500"));
+    }
+
+    #[test]
+    fn test_rate_limit_is_per_user_and_canvas() {
+        let limiter = AccessRequestLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        let alice = "alice".to_string();
+        assert!(limiter.allow(&alice, &"a".to_string(), now));
+        assert!(!limiter.allow(&alice, &"a".to_string(), now));
+        assert!(limiter.allow(&alice, &"b".to_string(), now));
+        assert!(limiter.allow(&"bob".to_string(), &"a".to_string(), now));
+    }
+}
@@ -0,0 +1,258 @@
+use super::{events::CanvasEvents, store::CanvasId};
+use crate::{persistence::EventLogPersistenceJson, userstore::UserId};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader},
+    sync::{Arc, Mutex},
+};
+use utoipa::ToSchema;
+
+// Activity of users on canvases, shown to the users themselves under /api/me/activity
+// Derived from the canvas eventlogs, nothing is persisted for it and canvases are not loaded to compute it
+// Folds are cached per canvas, keyed by the sequence number of the last folded line
+// A log with more or fewer lines than the cached fold is folded again from the start
+
+/// Persisted changes of a single user on a canvas
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct UserActivity {
+    pub shapes_added: u64,
+    /// updates and z changes
+    pub shapes_updated: u64,
+    pub comments_added: u64,
+    /// unix timestamp in seconds of the first attributed event
+    pub first_active_at: Option<u64>,
+    /// unix timestamp in seconds of the last attributed event
+    pub last_active_at: Option<u64>,
+}
+
+impl UserActivity {
+    fn record(&mut self, timestamp: u64) {
+        self.first_active_at = Some(self.first_active_at.map_or(timestamp, |t| t.min(timestamp)));
+        self.last_active_at = Some(self.last_active_at.map_or(timestamp, |t| t.max(timestamp)));
+    }
+
+    /// Adds the activity of another canvas, used for totals
+    pub fn merge(&mut self, other: &UserActivity) {
+        self.shapes_added += other.shapes_added;
+        self.shapes_updated += other.shapes_updated;
+        self.comments_added += other.comments_added;
+        for timestamp in [other.first_active_at, other.last_active_at]
+            .into_iter()
+            .flatten()
+        {
+            self.record(timestamp);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes_added + self.shapes_updated + self.comments_added == 0
+    }
+}
+
+/// Activity of every user on a canvas, up to a line of its eventlog
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanvasActivity {
+    /// number of folded lines
+    pub seq: u64,
+    pub users: HashMap<UserId, UserActivity>,
+}
+
+impl CanvasActivity {
+    fn apply(&mut self, event: &CanvasEvents) {
+        let (user_id, added, updated, comments) = match event {
+            CanvasEvents::ShapeAdded {
+                userId: Some(user_id),
+                ..
+            } => (user_id, 1, 0, 0),
+            CanvasEvents::ShapeUpdated {
+                userId: Some(user_id),
+                ..
+            }
+            | CanvasEvents::ShapeZChanged {
+                userId: Some(user_id),
+                ..
+            } => (user_id, 0, 1, 0),
+            CanvasEvents::CommentAdded {
+                userId: Some(user_id),
+                ..
+            } => (user_id, 0, 0, 1),
+            // removals are not attributed, lines written before attribution existed belong to nobody
+            _ => return,
+        };
+
+        let activity = self.users.entry(user_id.clone()).or_default();
+        activity.shapes_added += added;
+        activity.shapes_updated += updated;
+        activity.comments_added += comments;
+        activity.record(event.timestamp());
+    }
+}
+
+/// Streams the eventlog and folds the activity of all users, the log is never loaded at once
+/// Lines that fail to deserialize are skipped, but still count towards the sequence number
+pub fn fold_log(file_path: &str) -> io::Result<CanvasActivity> {
+    let mut activity = CanvasActivity::default();
+    let persistence = match EventLogPersistenceJson::open(file_path) {
+        Ok(persistence) => persistence,
+        // canvases without events have no eventlog yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(activity),
+        Err(e) => return Err(e),
+    };
+
+    for line in persistence.stream_lines::<CanvasEvents>() {
+        activity.seq += 1;
+        if let Ok(event) = line? {
+            activity.apply(&event);
+        }
+    }
+    Ok(activity)
+}
+
+/// Lines in the eventlog, without decoding them
+pub fn count_lines(file_path: &str) -> io::Result<u64> {
+    match std::fs::File::open(file_path) {
+        Ok(file) => BufReader::new(file)
+            .split(b'\n')
+            .try_fold(0, |count, line| line.map(|_| count + 1)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Latest fold of every canvas
+#[derive(Default)]
+pub struct ActivityCache {
+    entries: Mutex<HashMap<CanvasId, Arc<CanvasActivity>>>,
+}
+
+impl ActivityCache {
+    /// Cached fold, only if it covers exactly latest_seq lines
+    pub fn get(&self, canvas_id: &str, latest_seq: u64) -> Option<Arc<CanvasActivity>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(canvas_id)
+            .filter(|activity| activity.seq == latest_seq)
+            .cloned()
+    }
+
+    pub fn insert(&self, canvas_id: &str, activity: Arc<CanvasActivity>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(canvas_id.to_string(), activity);
+    }
+
+    /// Activity on the canvas, answers from the cache if the log did not change
+    /// latest_seq is known for loaded canvases, the lines of the eventlog are counted otherwise
+    pub fn activity(
+        &self,
+        canvas_id: &str,
+        file_path: &str,
+        latest_seq: Option<u64>,
+    ) -> io::Result<Arc<CanvasActivity>> {
+        let latest_seq = match latest_seq {
+            Some(seq) => seq,
+            None => count_lines(file_path)?,
+        };
+        if let Some(activity) = self.get(canvas_id, latest_seq) {
+            return Ok(activity);
+        }
+
+        let activity = Arc::new(fold_log(file_path)?);
+        self.insert(canvas_id, activity.clone());
+        Ok(activity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(content: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("{}-activity.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    const LOG: &str = r##"{"type":"ShapeAdded","origin":"s1","timestamp":10,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}
+{"type":"ShapeAdded","origin":"s2","timestamp":20,"shape":{"type":"Line","id":"l2","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"bob"}
+{"type":"ShapeAdded","origin":"s1","timestamp":25,"shape":{"type":"Line","id":"l3","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}
+{"type":"ShapeUpdated","origin":"s2","timestamp":30,"shape":{"id":"l1","borderColor":"#fff"},"userId":"bob"}
+{"type":"ShapeZChanged","origin":"s1","timestamp":40,"shapeId":"l1","z":{"isInfinity":true,"value":1},"userId":"alice"}
+not an event
+{"type":"CommentAdded","origin":"s2","timestamp":50,"commentId":"c1","shapeId":"l1","text":"nice","userId":"bob"}
+{"type":"ShapeRemoved","origin":"s1","timestamp":60,"shapeId":"l2"}
+"##;
+
+    #[test]
+    fn test_fold_attributes_events_to_users() {
+        let path = write_log(LOG);
+        let activity = fold_log(&path).unwrap();
+        assert_eq!(activity.seq, 8);
+        assert_eq!(activity.users.len(), 2);
+        assert_eq!(
+            activity.users["alice"],
+            UserActivity {
+                shapes_added: 1,
+                shapes_updated: 1,
+                comments_added: 0,
+                first_active_at: Some(10),
+                last_active_at: Some(40),
+            }
+        );
+        assert_eq!(
+            activity.users["bob"],
+            UserActivity {
+                shapes_added: 1,
+                shapes_updated: 1,
+                comments_added: 1,
+                first_active_at: Some(20),
+                last_active_at: Some(50),
+            }
+        );
+        assert_eq!(count_lines(&path).unwrap(), 8);
+        assert_eq!(fold_log("missing-activity.jsonl").unwrap().seq, 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_cache_is_invalidated_when_the_log_grows() {
+        let path = write_log(LOG);
+        let cache = ActivityCache::default();
+        let first = cache.activity("c1", &path, None).unwrap();
+        assert!(Arc::ptr_eq(
+            &first,
+            &cache.activity("c1", &path, Some(8)).unwrap()
+        ));
+
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(
+            &mut log,
+            br#"{"type":"CommentAdded","origin":"s1","timestamp":70,"commentId":"c2","shapeId":"l1","text":"thanks","userId":"alice"}
+"#,
+        )
+        .unwrap();
+
+        let grown = cache.activity("c1", &path, None).unwrap();
+        assert_eq!(grown.seq, 9);
+        assert_eq!(grown.users["alice"].comments_added, 1);
+        assert_eq!(grown.users["alice"].last_active_at, Some(70));
+        assert!(cache.get("c1", 8).is_none());
+
+        let mut total = UserActivity::default();
+        total.merge(&grown.users["alice"]);
+        total.merge(&grown.users["bob"]);
+        assert_eq!(total.shapes_added, 2);
+        assert_eq!(total.first_active_at, Some(10));
+        assert_eq!(total.last_active_at, Some(70));
+        let _ = std::fs::remove_file(path);
+    }
+}
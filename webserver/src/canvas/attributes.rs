@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Extension attributes of shapes, e.g. stroke width or opacity
+// Every shape carries a map of typed values, the server validates them like the rest of the shape
+// Known keys are checked for their type and range, unknown keys are rejected unless allowed by the ShapeLimits
+
+/// Longest text attribute in characters
+pub const MAX_TEXT_LENGTH: usize = 256;
+
+pub const STROKE_WIDTH: &str = "strokeWidth";
+pub const DASH_PATTERN: &str = "dashPattern";
+pub const OPACITY: &str = "opacity";
+/// rotation around the center of the shape in degrees
+pub const ROTATION: &str = "rotation";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    /// hex color like #rgb, #rrggbb or #rrggbbaa, or rgb(r, g, b) and rgba(r, g, b, a)
+    Color(String),
+}
+
+pub type ShapeAttributes = HashMap<String, AttributeValue>;
+
+/// Reason an attribute map was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeError {
+    /// key is neither known nor are unknown keys allowed
+    Unknown(String),
+    /// value is of the wrong type, out of range or malformed
+    Invalid(String),
+    TooMany {
+        count: usize,
+    },
+    TooLarge {
+        bytes: usize,
+    },
+}
+
+/// Limits of the attributes of a single shape
+#[derive(Debug, Clone)]
+pub struct AttributeLimits {
+    /// keys outside of the known ones are accepted, e.g. while the frontend iterates on a new attribute
+    pub allow_unknown: bool,
+    pub max_attributes: usize,
+    /// size of the serialized map in bytes
+    pub max_bytes: usize,
+}
+
+impl Default for AttributeLimits {
+    fn default() -> Self {
+        Self {
+            allow_unknown: false,
+            max_attributes: 20,
+            max_bytes: 2 * 1024,
+        }
+    }
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value.strip_prefix('#').is_some_and(|digits| {
+        matches!(digits.len(), 3 | 4 | 6 | 8) && digits.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+fn is_rgb_color(value: &str) -> bool {
+    let (channels, arguments) = if let Some(arguments) = value.strip_prefix("rgba(") {
+        (4, arguments)
+    } else if let Some(arguments) = value.strip_prefix("rgb(") {
+        (3, arguments)
+    } else {
+        return false;
+    };
+    let Some(arguments) = arguments.strip_suffix(')') else {
+        return false;
+    };
+
+    let arguments: Vec<&str> = arguments.split(',').map(str::trim).collect();
+    arguments.len() == channels
+        && arguments.iter().enumerate().all(|(index, argument)| {
+            if index == 3 {
+                argument
+                    .parse::<f64>()
+                    .is_ok_and(|alpha| (0.0..=1.0).contains(&alpha))
+            } else {
+                argument.parse::<u8>().is_ok()
+            }
+        })
+}
+
+pub fn is_valid_color(value: &str) -> bool {
+    is_hex_color(value) || is_rgb_color(value)
+}
+
+impl AttributeValue {
+    fn is_valid(&self) -> bool {
+        match self {
+            AttributeValue::Number(number) => number.is_finite(),
+            AttributeValue::Text(text) => text.chars().count() <= MAX_TEXT_LENGTH,
+            AttributeValue::Bool(_) => true,
+            AttributeValue::Color(color) => is_valid_color(color),
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            AttributeValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+/// Checks the type and range of a known key, None if the key is unknown
+fn check_known(key: &str, value: &AttributeValue) -> Option<bool> {
+    let number = value.as_number();
+    match key {
+        STROKE_WIDTH => Some(number.is_some_and(|width| width >= 0.0)),
+        OPACITY => Some(number.is_some_and(|opacity| (0.0..=1.0).contains(&opacity))),
+        ROTATION => Some(number.is_some()),
+        // numbers separated by spaces or commas, as in stroke-dasharray
+        DASH_PATTERN => Some(match value {
+            AttributeValue::Text(pattern) => pattern
+                .split([' ', ','])
+                .filter(|part| !part.is_empty())
+                .all(|part| part.parse::<f64>().is_ok_and(|length| length >= 0.0)),
+            _ => false,
+        }),
+        _ => None,
+    }
+}
+
+pub fn validate_attributes(
+    attributes: &ShapeAttributes,
+    limits: &AttributeLimits,
+) -> Result<(), AttributeError> {
+    if attributes.len() > limits.max_attributes {
+        return Err(AttributeError::TooMany {
+            count: attributes.len(),
+        });
+    }
+
+    for (key, value) in attributes {
+        if !value.is_valid() {
+            return Err(AttributeError::Invalid(key.clone()));
+        }
+        match check_known(key, value) {
+            Some(true) => {}
+            Some(false) => return Err(AttributeError::Invalid(key.clone())),
+            None if limits.allow_unknown => {}
+            None => return Err(AttributeError::Unknown(key.clone())),
+        }
+    }
+
+    let bytes = serde_json::to_vec(attributes).map_or(usize::MAX, |json| json.len());
+    if bytes > limits.max_bytes {
+        return Err(AttributeError::TooLarge { bytes });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(entries: &[(&str, AttributeValue)]) -> ShapeAttributes {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_colors_are_validated() {
+        for color in [
+            "#fff",
+            "#ffff",
+            "#00ff00",
+            "#00ff0080",
+            "rgb(0, 128, 255)",
+            "rgba(0,0,0,0.5)",
+        ] {
+            assert!(is_valid_color(color), "{color}");
+        }
+        for color in [
+            "fff",
+            "#ggg",
+            "#12345",
+            "rgb(0, 0, 256)",
+            "rgb(0, 0)",
+            "rgba(0, 0, 0, 2)",
+            "red",
+            "url(#x)",
+            "\"><script>",
+        ] {
+            assert!(!is_valid_color(color), "{color}");
+        }
+
+        let limits = AttributeLimits {
+            allow_unknown: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_attributes(
+                &attributes(&[("glow", AttributeValue::Color("javascript:".to_string()))]),
+                &limits
+            ),
+            Err(AttributeError::Invalid("glow".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_known_keys_and_budget() {
+        let limits = AttributeLimits::default();
+        let known = attributes(&[
+            (STROKE_WIDTH, AttributeValue::Number(2.5)),
+            (OPACITY, AttributeValue::Number(0.5)),
+            (ROTATION, AttributeValue::Number(-45.0)),
+            (DASH_PATTERN, AttributeValue::Text("4 2".to_string())),
+        ]);
+        assert_eq!(validate_attributes(&known, &limits), Ok(()));
+
+        assert_eq!(
+            validate_attributes(
+                &attributes(&[(OPACITY, AttributeValue::Number(1.5))]),
+                &limits
+            ),
+            Err(AttributeError::Invalid(OPACITY.to_string()))
+        );
+        assert_eq!(
+            validate_attributes(
+                &attributes(&[(ROTATION, AttributeValue::Bool(true))]),
+                &limits
+            ),
+            Err(AttributeError::Invalid(ROTATION.to_string()))
+        );
+        assert_eq!(
+            validate_attributes(
+                &attributes(&[(STROKE_WIDTH, AttributeValue::Number(f64::NAN))]),
+                &limits
+            ),
+            Err(AttributeError::Invalid(STROKE_WIDTH.to_string()))
+        );
+
+        let unknown = attributes(&[("glow", AttributeValue::Bool(true))]);
+        assert_eq!(
+            validate_attributes(&unknown, &limits),
+            Err(AttributeError::Unknown("glow".to_string()))
+        );
+        let permissive = AttributeLimits {
+            allow_unknown: true,
+            ..Default::default()
+        };
+        assert_eq!(validate_attributes(&unknown, &permissive), Ok(()));
+
+        let too_many: ShapeAttributes = (0..21)
+            .map(|index| (format!("key{index}"), AttributeValue::Bool(true)))
+            .collect();
+        assert_eq!(
+            validate_attributes(&too_many, &permissive),
+            Err(AttributeError::TooMany { count: 21 })
+        );
+
+        let too_long = attributes(&[(
+            "note",
+            AttributeValue::Text("a".repeat(MAX_TEXT_LENGTH + 1)),
+        )]);
+        assert_eq!(
+            validate_attributes(&too_long, &permissive),
+            Err(AttributeError::Invalid("note".to_string()))
+        );
+
+        let too_large: ShapeAttributes = (0..10)
+            .map(|index| {
+                (
+                    format!("note{index}"),
+                    AttributeValue::Text("a".repeat(MAX_TEXT_LENGTH)),
+                )
+            })
+            .collect();
+        assert!(matches!(
+            validate_attributes(&too_large, &permissive),
+            Err(AttributeError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use super::store::AccessLevel;
+
+// Backpressure of the eventlog of a canvas, events persisted but not yet synced to disk are its backlog
+// A backlog reaching the high-water mark throttles the canvas, writers and voices are refused until it drains below the low-water mark
+// Owners and moderators keep writing up to the hard limit, events that are never persisted are never refused
+// The defaults are far above what the FlushPolicy lets pile up on a healthy disk
+
+/// Events and bytes of an eventlog not yet synced to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Backlog {
+    pub events: u64,
+    pub bytes: u64,
+}
+
+impl Backlog {
+    /// Whether either count reached the one of the mark
+    fn reaches(&self, mark: &Backlog) -> bool {
+        self.events >= mark.events || self.bytes >= mark.bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackpressureLimits {
+    /// backlog throttling the canvas
+    pub high_water: Backlog,
+    /// backlog ending the throttling, has to be below both counts
+    pub low_water: Backlog,
+    /// backlog refusing owners and moderators as well
+    pub hard_limit: Backlog,
+}
+
+impl Default for BackpressureLimits {
+    fn default() -> Self {
+        Self {
+            high_water: Backlog {
+                events: 10_000,
+                bytes: 32 * 1024 * 1024,
+            },
+            low_water: Backlog {
+                events: 1_000,
+                bytes: 4 * 1024 * 1024,
+            },
+            hard_limit: Backlog {
+                events: 50_000,
+                bytes: 128 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+impl BackpressureLimits {
+    /// Whether the canvas is throttled with the backlog, the state changes at the high- and low-water mark only
+    pub fn throttled(&self, throttled: bool, backlog: &Backlog) -> bool {
+        match throttled {
+            true => backlog.reaches(&self.low_water),
+            false => backlog.reaches(&self.high_water),
+        }
+    }
+
+    /// Whether a persisted event of the access level is refused
+    pub fn refuses(&self, throttled: bool, backlog: &Backlog, access_level: &AccessLevel) -> bool {
+        match access_level {
+            AccessLevel::Owner | AccessLevel::Moderate => backlog.reaches(&self.hard_limit),
+            _ => throttled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> BackpressureLimits {
+        BackpressureLimits {
+            high_water: Backlog {
+                events: 10,
+                bytes: 1_000,
+            },
+            low_water: Backlog {
+                events: 2,
+                bytes: 200,
+            },
+            hard_limit: Backlog {
+                events: 20,
+                bytes: 2_000,
+            },
+        }
+    }
+
+    #[test]
+    fn test_throttling_changes_at_the_marks_only() {
+        let limits = limits();
+        let backlog = |events, bytes| Backlog { events, bytes };
+
+        assert!(!limits.throttled(false, &backlog(9, 999)));
+        assert!(limits.throttled(false, &backlog(10, 0)));
+        assert!(limits.throttled(false, &backlog(0, 1_000)));
+        // between the marks the state is kept
+        assert!(limits.throttled(true, &backlog(5, 500)));
+        assert!(!limits.throttled(false, &backlog(5, 500)));
+        assert!(limits.throttled(true, &backlog(1, 200)));
+        assert!(!limits.throttled(true, &backlog(1, 199)));
+    }
+
+    #[test]
+    fn test_owners_and_moderators_write_up_to_the_hard_limit() {
+        let limits = limits();
+        let backlog = Backlog {
+            events: 15,
+            bytes: 0,
+        };
+        assert!(limits.refuses(true, &backlog, &AccessLevel::Write));
+        assert!(limits.refuses(true, &backlog, &AccessLevel::Voice));
+        assert!(!limits.refuses(true, &backlog, &AccessLevel::Owner));
+        assert!(!limits.refuses(true, &backlog, &AccessLevel::Moderate));
+        assert!(!limits.refuses(false, &backlog, &AccessLevel::Write));
+
+        let full = Backlog {
+            events: 20,
+            bytes: 0,
+        };
+        assert!(limits.refuses(true, &full, &AccessLevel::Owner));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fmt, io};
+
+use super::{
+    events::CanvasEvents,
+    store::{self, CanvasId},
+};
+use crate::persistence::{self, EventLogPersistenceJson};
+
+// Binds the eventlog of a canvas to the canvas
+// The first line of every canvas eventlog is a CanvasLogHeader with the id of the canvas
+// A log copied to the wrong file name or attached to the wrong canvas is refused instead of loaded
+// Logs written before the header existed are migrated, the file name is taken as the claimed id
+
+/// How the eventlog relates to the canvas it is loaded for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogBinding {
+    /// nothing persisted yet, the header is written with the first event
+    Empty,
+    Bound,
+    /// written before headers existed
+    Legacy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingError {
+    /// the header claims another canvas
+    Mismatch {
+        expected: CanvasId,
+        claimed: CanvasId,
+    },
+    /// a legacy log is only migrated if its file name is a canvas id
+    InvalidCanvasId(String),
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingError::Mismatch { expected, claimed } => write!(
+                f,
+                "eventlog of canvas {expected} belongs to canvas {claimed}"
+            ),
+            BindingError::InvalidCanvasId(canvas_id) => {
+                write!(f, "{canvas_id} is not a valid canvas id")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BindingError {}
+
+impl From<BindingError> for io::Error {
+    fn from(error: BindingError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+pub fn header(canvas_id: &str, timestamp: u64) -> CanvasEvents {
+    CanvasEvents::CanvasLogHeader {
+        timestamp,
+        canvasId: canvas_id.to_string(),
+    }
+}
+
+/// Checks the header of a deserialized eventlog
+pub fn check(canvas_id: &str, event_log: &[CanvasEvents]) -> Result<LogBinding, BindingError> {
+    match event_log.first() {
+        None => Ok(LogBinding::Empty),
+        Some(CanvasEvents::CanvasLogHeader { canvasId, .. }) if canvasId == canvas_id => {
+            Ok(LogBinding::Bound)
+        }
+        Some(CanvasEvents::CanvasLogHeader { canvasId, .. }) => Err(BindingError::Mismatch {
+            expected: canvas_id.to_string(),
+            claimed: canvasId.clone(),
+        }),
+        Some(_) => Ok(LogBinding::Legacy),
+    }
+}
+
+///
+/// Binds the eventlog to the canvas, an existing header is replaced and kept on the first line
+/// Without a header one is prepended, every line moves down by one and read receipts are moved along
+///
+pub fn rebind(canvas_id: &str, event_log: &mut Vec<CanvasEvents>, timestamp: u64) {
+    if let Some(CanvasEvents::CanvasLogHeader { .. }) = event_log.first() {
+        event_log[0] = header(canvas_id, timestamp);
+        return;
+    }
+
+    for event in event_log.iter_mut() {
+        if let CanvasEvents::UserCaughtUp { seq, .. } = event {
+            if *seq > 0 {
+                *seq += 1;
+            }
+        }
+    }
+    event_log.insert(0, header(canvas_id, timestamp));
+}
+
+///
+/// Prepends the header to a legacy eventlog, claiming the canvas it is named after
+/// Returns the migrated eventlog
+///
+pub fn migrate_legacy_log(
+    file_path: &str,
+    canvas_id: &str,
+    mut event_log: Vec<CanvasEvents>,
+    timestamp: u64,
+) -> Result<Vec<CanvasEvents>, io::Error> {
+    if !store::is_valid_canvas_id(canvas_id) {
+        return Err(BindingError::InvalidCanvasId(canvas_id.to_string()).into());
+    }
+
+    rebind(canvas_id, &mut event_log, timestamp);
+
+    persistence::rewrite_event_log(file_path, &event_log)?;
+    Ok(event_log)
+}
+
+/// Canvas id claimed by the first line of the eventlog at file_path, None for empty and legacy logs
+pub fn read_claimed_id(file_path: &str) -> Result<Option<CanvasId>, io::Error> {
+    let log = EventLogPersistenceJson::open(file_path)?;
+    let first_line = log.stream_lines::<CanvasEvents>().next().transpose()?;
+    Ok(match first_line {
+        Some(Ok(CanvasEvents::CanvasLogHeader { canvasId, .. })) => Some(canvasId),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined() -> CanvasEvents {
+        serde_json::from_str(
+            r#"{"type":"UserJoined","timestamp":1,"userId":"u1","sessionId":"s1","username":"u1","accessLevel":"Owner"}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mismatched_header_is_rejected() {
+        let log = [header("aaaaaaaaaaaa", 0), joined()];
+        assert_eq!(check("aaaaaaaaaaaa", &log), Ok(LogBinding::Bound));
+        assert_eq!(
+            check("bbbbbbbbbbbb", &log),
+            Err(BindingError::Mismatch {
+                expected: "bbbbbbbbbbbb".to_string(),
+                claimed: "aaaaaaaaaaaa".to_string(),
+            })
+        );
+        assert_eq!(check("bbbbbbbbbbbb", &[]), Ok(LogBinding::Empty));
+        assert_eq!(check("bbbbbbbbbbbb", &[joined()]), Ok(LogBinding::Legacy));
+    }
+
+    #[test]
+    fn test_legacy_log_is_migrated() {
+        let path = std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let receipt = CanvasEvents::UserCaughtUp {
+            timestamp: 2,
+            userId: "u1".to_string(),
+            seq: 1,
+        };
+        let legacy = vec![joined(), receipt];
+
+        let invalid = migrate_legacy_log(&path, "not-a-canvas", Vec::new(), 0);
+        assert!(invalid.is_err());
+        assert!(!std::path::Path::new(&path).exists());
+
+        migrate_legacy_log(&path, "0123456789ab", legacy, 5).unwrap();
+        assert_eq!(
+            read_claimed_id(&path).unwrap().as_deref(),
+            Some("0123456789ab")
+        );
+        let migrated = EventLogPersistenceJson::open(&path)
+            .unwrap()
+            .read_lines::<CanvasEvents>()
+            .unwrap();
+        assert_eq!(migrated.len(), 3);
+        assert!(matches!(
+            migrated[2],
+            Ok(CanvasEvents::UserCaughtUp { seq: 2, .. })
+        ));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,154 @@
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{events::CanvasEvents, store::CanvasId};
+use crate::userstore::UserId;
+
+// Fan-out of persisted canvas events to consumers inside the server, e.g. metrics or webhooks
+// The canvas server publishes a notification after every event it persisted, without its payload,
+// consumers needing the payload read it through the query interface or the eventlog
+// Publishing never waits, a subscriber that falls behind by more than the capacity loses the oldest notifications
+
+/// Notifications kept for the slowest subscriber
+pub const DEFAULT_EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Event persisted by the canvas server
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasEventNotification {
+    pub canvas_id: CanvasId,
+    /// line of the event in the eventlog of the canvas
+    pub seq: u64,
+    /// type of the event, e.g. ShapeAdded
+    pub kind: &'static str,
+    /// user the event names, None for events without one like ShapeRemoved
+    pub user_id: Option<UserId>,
+    /// time the event was persisted, unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+impl CanvasEventNotification {
+    pub fn new(canvas_id: &str, seq: u64, event: &CanvasEvents, timestamp: u64) -> Self {
+        let user_id = match event {
+            CanvasEvents::ShapeAdded { userId, .. }
+            | CanvasEvents::ShapeUpdated { userId, .. }
+            | CanvasEvents::ShapeZChanged { userId, .. }
+            | CanvasEvents::CommentAdded { userId, .. }
+            | CanvasEvents::CommentResolved { userId, .. }
+            | CanvasEvents::CommentReopened { userId, .. }
+            | CanvasEvents::CommentDeleted { userId, .. } => userId.clone(),
+            CanvasEvents::UserJoined { userId, .. }
+            | CanvasEvents::UserLeft { userId, .. }
+            | CanvasEvents::UserAccessLevelChanged { userId, .. }
+            | CanvasEvents::UserCaughtUp { userId, .. }
+            | CanvasEvents::ContributorSeen { userId, .. } => Some(userId.clone()),
+            CanvasEvents::CanvasStateChanged { initiatorId, .. }
+            | CanvasEvents::CanvasSettingsChanged { initiatorId, .. }
+            | CanvasEvents::PaletteChanged { initiatorId, .. } => Some(initiatorId.clone()),
+            _ => None,
+        };
+        Self {
+            canvas_id: canvas_id.to_string(),
+            seq,
+            kind: event.kind(),
+            user_id,
+            timestamp,
+        }
+    }
+}
+
+/// Bounded broadcast channel of the notifications, cloned into every loaded canvas
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<CanvasEventNotification>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Never blocks, without subscribers the notification is dropped
+    pub fn publish(&self, notification: CanvasEventNotification) {
+        let _ = self.sender.send(notification);
+    }
+
+    /// Receiver of the notifications published from now on, dropping it unsubscribes
+    pub fn subscribe(&self) -> broadcast::Receiver<CanvasEventNotification> {
+        self.sender.subscribe()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_BUS_CAPACITY)
+    }
+}
+
+/// Subscriber printing every notification, runs until the canvas server is gone
+pub async fn log_notifications(mut receiver: broadcast::Receiver<CanvasEventNotification>) {
+    loop {
+        match receiver.recv().await {
+            Ok(notification) => println!(
+                "Canvas {} persisted {} at line {} by {}",
+                notification.canvas_id,
+                notification.kind,
+                notification.seq,
+                notification.user_id.as_deref().unwrap_or("the server")
+            ),
+            Err(RecvError::Lagged(lost)) => {
+                println!("WARNING: canvas event log fell behind, {lost} notifications lost")
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broadcast::error::TryRecvError;
+
+    fn notification(seq: u64) -> CanvasEventNotification {
+        CanvasEventNotification {
+            canvas_id: "canvas".to_string(),
+            seq,
+            kind: "ShapeAdded",
+            user_id: None,
+            timestamp: seq,
+        }
+    }
+
+    #[test]
+    fn test_stalled_subscriber_loses_the_oldest_notifications() {
+        let bus = EventBus::new(2);
+        let mut stalled = bus.subscribe();
+        // publishing returns right away although nobody reads
+        for seq in 1..=5 {
+            bus.publish(notification(seq));
+        }
+
+        assert_eq!(stalled.try_recv(), Err(TryRecvError::Lagged(3)));
+        assert_eq!(stalled.try_recv().map(|n| n.seq), Ok(4));
+        assert_eq!(stalled.try_recv().map(|n| n.seq), Ok(5));
+        assert_eq!(stalled.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_dropped_receivers_unsubscribe() {
+        let bus = EventBus::default();
+        bus.publish(notification(1));
+
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        assert_eq!(bus.subscriber_count(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(bus.subscriber_count(), 0);
+        bus.publish(notification(2));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::userstore::UserId;
+
+use super::store::{AccessLevel, Canvas, CanvasClaim, CanvasId};
+
+// Claims of every user with the reverse index of the users holding a claim on a canvas
+// Changes of a canvas touch the claims of its members only, never all users
+// Both maps are only changed through the methods below, which keep them consistent
+
+#[derive(Default, Debug)]
+pub struct ClaimIndex {
+    /// claims of a user, ordered from oldest to most recently granted
+    user_id_lookup: HashMap<UserId, Vec<CanvasClaim>>,
+    /// users holding a claim on the canvas
+    canvas_members: HashMap<CanvasId, HashSet<UserId>>,
+    /// claim vectors changed since the counter was reset, lets tests check which users were touched
+    #[cfg(test)]
+    pub(crate) touched_claim_vectors: usize,
+}
+
+impl ClaimIndex {
+    pub fn get(&self, user_id: &str) -> Option<&Vec<CanvasClaim>> {
+        self.user_id_lookup.get(user_id)
+    }
+
+    /// Users holding a claim on the canvas
+    pub fn members(&self, canvas_id: &str) -> impl Iterator<Item = &UserId> {
+        self.canvas_members.get(canvas_id).into_iter().flatten()
+    }
+
+    fn claims_mut(&mut self, user_id: &UserId) -> &mut Vec<CanvasClaim> {
+        #[cfg(test)]
+        {
+            self.touched_claim_vectors += 1;
+        }
+        self.user_id_lookup.entry(user_id.clone()).or_default()
+    }
+
+    /// Whether the user holds a claim on the canvas in both maps or in neither
+    fn is_consistent(&self, user_id: &UserId, canvas_id: &CanvasId) -> bool {
+        let claimed = self
+            .user_id_lookup
+            .get(user_id)
+            .is_some_and(|claims| claims.iter().any(|claim| claim.c == *canvas_id));
+        let member = self
+            .canvas_members
+            .get(canvas_id)
+            .is_some_and(|members| members.contains(user_id));
+        claimed == member
+    }
+
+    /// Appends the claim as most recently granted, an older claim on the same canvas is dropped
+    pub fn add_claim(&mut self, user_id: &UserId, claim: CanvasClaim) {
+        let canvas_id = claim.c.clone();
+        let claims = self.claims_mut(user_id);
+        claims.retain(|existing| *existing != claim);
+        claims.push(claim);
+        self.canvas_members
+            .entry(canvas_id.clone())
+            .or_default()
+            .insert(user_id.clone());
+        debug_assert!(self.is_consistent(user_id, &canvas_id));
+    }
+
+    /// Changes the access of an existing claim in place, claims the canvas otherwise
+    pub fn set_claim_level(
+        &mut self,
+        user_id: &UserId,
+        canvas: &Canvas,
+        access_level: AccessLevel,
+        expires_at: Option<u64>,
+    ) {
+        let claims = self.claims_mut(user_id);
+        match claims.iter_mut().find(|claim| claim.c == canvas.id) {
+            Some(claim) => {
+                claim.r = access_level;
+                claim.exp = expires_at;
+            }
+            None => claims.push(CanvasClaim {
+                n: canvas.name.clone(),
+                c: canvas.id.clone(),
+                r: access_level,
+                exp: expires_at,
+            }),
+        }
+        self.canvas_members
+            .entry(canvas.id.clone())
+            .or_default()
+            .insert(user_id.clone());
+        debug_assert!(self.is_consistent(user_id, &canvas.id));
+    }
+
+    /// Drops the claim of the user on the canvas, if any
+    pub fn remove_claim(&mut self, user_id: &UserId, canvas_id: &CanvasId) {
+        let Some(members) = self.canvas_members.get_mut(canvas_id) else {
+            return;
+        };
+        if !members.remove(user_id) {
+            return;
+        }
+        if members.is_empty() {
+            self.canvas_members.remove(canvas_id);
+        }
+        self.claims_mut(user_id)
+            .retain(|claim| claim.c != *canvas_id);
+        debug_assert!(self.is_consistent(user_id, canvas_id));
+    }
+
+    /// Drops the claims of every member of the canvas
+    pub fn remove_canvas(&mut self, canvas_id: &CanvasId) {
+        for user_id in self.canvas_members.remove(canvas_id).unwrap_or_default() {
+            self.claims_mut(&user_id)
+                .retain(|claim| claim.c != *canvas_id);
+            debug_assert!(self.is_consistent(&user_id, canvas_id));
+        }
+    }
+
+    /// Renames the canvas in the claims of its members
+    pub fn update_claim_name(&mut self, canvas_id: &CanvasId, name: &str) {
+        let members: Vec<UserId> = self.members(canvas_id).cloned().collect();
+        for user_id in members {
+            if let Some(claim) = self
+                .claims_mut(&user_id)
+                .iter_mut()
+                .find(|claim| claim.c == *canvas_id)
+            {
+                claim.n = name.to_string();
+            }
+        }
+    }
+
+    /// Users holding claims without being known to the UserStore, with the canvases they claim
+    /// Left behind by users deleted before their memberships were removed, see PurgeUserMessage
+    pub fn unknown_claimants(
+        &self,
+        known_users: &HashSet<UserId>,
+    ) -> BTreeMap<UserId, Vec<CanvasId>> {
+        self.user_id_lookup
+            .iter()
+            .filter(|(user_id, claims)| !claims.is_empty() && !known_users.contains(*user_id))
+            .map(|(user_id, claims)| {
+                let mut canvas_ids: Vec<CanvasId> =
+                    claims.iter().map(|claim| claim.c.clone()).collect();
+                canvas_ids.sort();
+                (user_id.clone(), canvas_ids)
+            })
+            .collect()
+    }
+
+    ///
+    /// Checks both maps against each other and against the canvases, every issue is described
+    /// Claims have to name a live canvas, carry the access of the user on it and list the user as member
+    /// Members of a canvas may lack a claim, expired access is not claimed until the sweep removes it
+    ///
+    pub fn invariant_issues(&self, canvases: &HashMap<CanvasId, Canvas>) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        for (user_id, claims) in &self.user_id_lookup {
+            for claim in claims {
+                if !self
+                    .canvas_members
+                    .get(&claim.c)
+                    .is_some_and(|members| members.contains(user_id))
+                {
+                    issues.push(format!(
+                        "Claim of user {user_id} on canvas {} is missing in the member index",
+                        claim.c
+                    ));
+                }
+                match canvases.get(&claim.c) {
+                    None => {
+                        issues.push(format!("User {user_id} claims unknown canvas {}", claim.c))
+                    }
+                    Some(canvas) if canvas.users.get(user_id) != Some(&claim.r) => {
+                        issues.push(format!(
+                            "Claim of user {user_id} on canvas {} disagrees with its access",
+                            claim.c
+                        ))
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+
+        for (canvas_id, members) in &self.canvas_members {
+            for user_id in members {
+                if !self.is_consistent(user_id, canvas_id) {
+                    issues.push(format!(
+                        "Member index lists user {user_id} on canvas {canvas_id} without a claim"
+                    ));
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::store::CanvasState;
+
+    fn canvas(id: &str, users: &[(&str, AccessLevel)]) -> Canvas {
+        Canvas {
+            id: id.to_string(),
+            name: id.to_string(),
+            owner_id: users[0].0.to_string(),
+            state: CanvasState::Active,
+            users: users
+                .iter()
+                .map(|(user_id, access_level)| (user_id.to_string(), access_level.clone()))
+                .collect(),
+            version: 1,
+            expirations: HashMap::new(),
+            settings: Default::default(),
+            tags: Vec::new(),
+            feature_overrides: Default::default(),
+            api_tokens: Default::default(),
+            created_at: 0,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_rename_touches_members_only() {
+        let mut canvases = HashMap::new();
+        let mut index = ClaimIndex::default();
+        for user in 0..10_000 {
+            let user_id = format!("user{user}");
+            let own = canvas(&format!("own{user}"), &[(&user_id, AccessLevel::Owner)]);
+            index.set_claim_level(&user_id, &own, AccessLevel::Owner, None);
+            canvases.insert(own.id.clone(), own);
+        }
+
+        let members = [
+            ("user0", AccessLevel::Owner),
+            ("user1", AccessLevel::Write),
+            ("user2", AccessLevel::Write),
+            ("user3", AccessLevel::Read),
+            ("user4", AccessLevel::Moderate),
+        ];
+        let shared = canvas("shared", &members);
+        for (user_id, access_level) in &members {
+            index.set_claim_level(&user_id.to_string(), &shared, access_level.clone(), None);
+        }
+        canvases.insert(shared.id.clone(), shared);
+        assert!(index.invariant_issues(&canvases).is_empty());
+
+        index.touched_claim_vectors = 0;
+        index.update_claim_name(&"shared".to_string(), "Renamed");
+        assert_eq!(index.touched_claim_vectors, 5);
+        for (user_id, _) in &members {
+            let claim = index.get(user_id).unwrap().iter().find(|c| c.c == "shared");
+            assert_eq!(claim.unwrap().n, "Renamed");
+        }
+
+        index.touched_claim_vectors = 0;
+        index.remove_canvas(&"shared".to_string());
+        canvases.remove("shared");
+        assert_eq!(index.touched_claim_vectors, 5);
+        assert_eq!(index.members("shared").count(), 0);
+        assert!(index.invariant_issues(&canvases).is_empty());
+    }
+
+    #[test]
+    fn test_invariant_issues_report_disagreeing_claims() {
+        let mut index = ClaimIndex::default();
+        let board = canvas(
+            "board",
+            &[("alice", AccessLevel::Owner), ("bob", AccessLevel::Read)],
+        );
+        index.set_claim_level(&"alice".to_string(), &board, AccessLevel::Owner, None);
+        index.set_claim_level(&"bob".to_string(), &board, AccessLevel::Write, None);
+        index.remove_claim(&"alice".to_string(), &"board".to_string());
+        let canvases = HashMap::from([(board.id.clone(), board)]);
+
+        let issues = index.invariant_issues(&canvases);
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].contains("user bob on canvas board disagrees"));
+
+        assert!(index.invariant_issues(&HashMap::new())[0].contains("unknown canvas board"));
+    }
+
+    #[test]
+    fn test_unknown_claimants_are_listed_with_their_canvases() {
+        let mut index = ClaimIndex::default();
+        let board = canvas(
+            "board",
+            &[("alice", AccessLevel::Owner), ("ghost", AccessLevel::Read)],
+        );
+        let sketch = canvas(
+            "sketch",
+            &[("alice", AccessLevel::Owner), ("ghost", AccessLevel::Write)],
+        );
+        for canvas in [&board, &sketch] {
+            for (user_id, access_level) in &canvas.users {
+                index.set_claim_level(user_id, canvas, access_level.clone(), None);
+            }
+        }
+        // a user without claims left is not listed
+        let gone = canvas("gone", &[("bob", AccessLevel::Owner)]);
+        index.set_claim_level(&"bob".to_string(), &gone, AccessLevel::Owner, None);
+        index.remove_canvas(&"gone".to_string());
+
+        let known = HashSet::from(["alice".to_string()]);
+        assert_eq!(
+            index.unknown_claimants(&known),
+            BTreeMap::from([(
+                "ghost".to_string(),
+                vec!["board".to_string(), "sketch".to_string()]
+            )])
+        );
+        index.remove_claim(&"ghost".to_string(), &"board".to_string());
+        index.remove_claim(&"ghost".to_string(), &"sketch".to_string());
+        assert!(index.unknown_claimants(&known).is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use super::{
+    events::{CanvasEvents, Shape},
+    server::Msg,
+    socket_handler::RegisterSession,
+    WS_PROTOCOL,
+};
+use crate::{
+    authentication::WS_TOKEN_PROTOCOL_PREFIX,
+    clock::{Clock, SystemClock},
+};
+use actix_http::ws::{CloseCode, CloseReason};
+use derive_more::{Display, Error};
+use futures_util::{
+    future::{select, Either},
+    SinkExt as _, Stream, StreamExt as _,
+};
+use nanoid::nanoid;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::Value;
+use std::{
+    pin::{pin, Pin},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        client::IntoClientRequest as _,
+        http::{header, uri::Authority, HeaderValue, Request},
+        protocol::{frame::coding::CloseCode as FrameCloseCode, CloseFrame, WebSocketConfig},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+// Client side of the canvas websocket protocol, used by bots, load tests and integration tests
+// Performs the RegisterSession handshake, answers heartbeat pings and decodes every received message
+// Uses the same CanvasEvents and Shape types as the server, protocol changes break the client at compile time
+// Speaks plain ws:// only, TLS is expected to be terminated in front of the server
+
+/// Largest frame accepted from the server, the server never sends continuations
+const MAX_FRAME_BYTES: usize = 2 * 1024 * 1024;
+
+/// Characters of a canvas id sent unescaped in the upgrade path, ids are nanoids
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_');
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Display, Error)]
+pub enum ClientError {
+    #[display("invalid base url {_0}, expected http://host:port")]
+    InvalidUrl(#[error(not(source))] String),
+    #[display("connection failed: {_0}")]
+    Io(std::io::Error),
+    /// the server refused the upgrade, e.g. missing authentication or no access to the canvas
+    #[display("upgrade rejected: {_0}")]
+    Rejected(#[error(not(source))] String),
+    #[display("websocket protocol error: {_0}")]
+    Protocol(tungstenite::Error),
+    #[display("event can't be serialized: {_0}")]
+    Serialize(serde_json::Error),
+    /// the connection is closed, close_reason tells why
+    #[display("connection closed")]
+    Closed,
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Io(e) => ClientError::Io(e),
+            tungstenite::Error::Http(response) => {
+                ClientError::Rejected(response.status().to_string())
+            }
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                ClientError::Closed
+            }
+            e => ClientError::Protocol(e),
+        }
+    }
+}
+
+/// Message to write together with the channel that reports the write
+type Outgoing = (Message, oneshot::Sender<Result<(), tungstenite::Error>>);
+
+/// Connected and registered canvas session
+/// Received events are available using next_event or by using the client as Stream
+pub struct CanvasClient {
+    session_id: String,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    events: mpsc::UnboundedReceiver<CanvasEvents>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+    undecodable: Arc<AtomicUsize>,
+}
+
+impl CanvasClient {
+    /// Connects to base_url (http://host:port) and registers a new session
+    /// auth_token is the value of the auth cookie, as set by the login endpoint, or a canvas API token
+    /// Has to be called inside a tokio runtime, the connection is driven by a spawned task
+    pub async fn connect(
+        base_url: &str,
+        auth_token: &str,
+        canvas_id: &str,
+    ) -> Result<CanvasClient, ClientError> {
+        let request = upgrade_request(base_url, auth_token, canvas_id)?;
+        let config = WebSocketConfig::default().max_frame_size(Some(MAX_FRAME_BYTES));
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async_with_config(request, Some(config), false).await?;
+
+        let session_id = nanoid!();
+        let register: Msg = serde_json::to_string(&RegisterSession {
+            session: session_id.clone(),
+        })
+        .map_err(ClientError::Serialize)?;
+        socket.send(Message::text(register)).await?;
+
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let close_reason = Arc::new(Mutex::new(None));
+        let undecodable = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run_connection(
+            socket,
+            outgoing_rx,
+            events_tx,
+            close_reason.clone(),
+            undecodable.clone(),
+        ));
+
+        Ok(CanvasClient {
+            session_id,
+            outgoing: outgoing_tx,
+            events: events_rx,
+            close_reason,
+            undecodable,
+        })
+    }
+
+    /// Session id used as origin of every sent event
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Sends any event, resolves once the frame is written to the socket
+    pub async fn send(&self, event: CanvasEvents) -> Result<(), ClientError> {
+        let message: Msg = (&event).try_into().map_err(ClientError::Serialize)?;
+        self.write(Message::text(message)).await
+    }
+
+    pub async fn add_shape(&self, shape: Shape) -> Result<(), ClientError> {
+        self.send(CanvasEvents::ShapeAdded {
+            origin: self.session_id.clone(),
+            timestamp: timestamp(),
+            shape,
+            // set by the server
+            userId: None,
+        })
+        .await
+    }
+
+    /// shape is a partial shape, it has to contain the id
+    pub async fn update_shape(&self, shape: Value) -> Result<(), ClientError> {
+        self.send(CanvasEvents::ShapeUpdated {
+            origin: self.session_id.clone(),
+            timestamp: timestamp(),
+            shape,
+            userId: None,
+        })
+        .await
+    }
+
+    pub async fn remove_shape(&self, shape_id: &str) -> Result<(), ClientError> {
+        self.send(CanvasEvents::ShapeRemoved {
+            origin: self.session_id.clone(),
+            timestamp: timestamp(),
+            shapeId: shape_id.to_string(),
+        })
+        .await
+    }
+
+    pub async fn select(&self, shape_id: &str, options: Value) -> Result<(), ClientError> {
+        self.send(CanvasEvents::ShapeSelected {
+            origin: self.session_id.clone(),
+            timestamp: timestamp(),
+            shapeId: shape_id.to_string(),
+            options,
+        })
+        .await
+    }
+
+    pub async fn deselect(&self, shape_id: &str) -> Result<(), ClientError> {
+        self.send(CanvasEvents::ShapeDeselected {
+            origin: self.session_id.clone(),
+            timestamp: timestamp(),
+            shapeId: shape_id.to_string(),
+        })
+        .await
+    }
+
+    /// Next event received from the server, None once the connection is closed
+    /// The server starts with the eventlog of the canvas, followed by broadcasts of other sessions
+    pub async fn next_event(&mut self) -> Option<CanvasEvents> {
+        self.events.recv().await
+    }
+
+    /// Close frame sent by the server, SocketClose::from_code maps the code
+    /// None while connected and if the connection dropped or failed without close frame
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.lock().unwrap().clone()
+    }
+
+    /// Messages of the server that failed to decode and were skipped, e.g. sent by a newer server
+    pub fn undecodable_messages(&self) -> usize {
+        self.undecodable.load(Ordering::Relaxed)
+    }
+
+    /// Closes the session, the server removes it from the canvas
+    pub async fn close(self) -> Result<(), ClientError> {
+        self.write(normal_close()).await
+    }
+
+    async fn write(&self, message: Message) -> Result<(), ClientError> {
+        let (written_tx, written_rx) = oneshot::channel();
+        self.outgoing
+            .send((message, written_tx))
+            .map_err(|_| ClientError::Closed)?;
+        written_rx.await.map_err(|_| ClientError::Closed)??;
+        Ok(())
+    }
+}
+
+impl Stream for CanvasClient {
+    type Item = CanvasEvents;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemClock.now_ms()
+}
+
+/// Upgrade request for the canvas, the host and the canvas id are checked and escaped
+fn upgrade_request(
+    base_url: &str,
+    auth_token: &str,
+    canvas_id: &str,
+) -> Result<Request<()>, ClientError> {
+    let invalid_url = || ClientError::InvalidUrl(base_url.to_string());
+    let host = base_url
+        .strip_prefix("http://")
+        .or_else(|| base_url.strip_prefix("ws://"))
+        .map(|host| host.trim_end_matches('/'))
+        .ok_or_else(invalid_url)?;
+    let host = Authority::from_str(host).map_err(|_| invalid_url())?;
+    // userinfo has no place in a base url
+    if host.as_str().contains('@') {
+        return Err(invalid_url());
+    }
+
+    let canvas_id = utf8_percent_encode(canvas_id, PATH_SEGMENT);
+    let mut request = format!("ws://{host}/ws/canvas/{canvas_id}").into_client_request()?;
+    // the token travels as subprotocol, the canvas protocol is offered as well so the server has one to select
+    let protocols = HeaderValue::from_str(&format!(
+        "{WS_PROTOCOL}, {WS_TOKEN_PROTOCOL_PREFIX}{auth_token}"
+    ))
+    .map_err(|e| ClientError::Protocol(tungstenite::Error::HttpFormat(e.into())))?;
+    request
+        .headers_mut()
+        .insert(header::SEC_WEBSOCKET_PROTOCOL, protocols);
+    Ok(request)
+}
+
+fn normal_close() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: FrameCloseCode::Normal,
+        reason: "".into(),
+    }))
+}
+
+/// Close frame as seen by the server, the client reports the codes the server uses
+fn close_reason(frame: CloseFrame) -> CloseReason {
+    CloseReason {
+        code: CloseCode::from(u16::from(frame.code)),
+        description: (!frame.reason.is_empty()).then(|| frame.reason.to_string()),
+    }
+}
+
+/// Drives the connection until either side closes it
+/// Pings are answered right away, they are the heartbeat of the server
+async fn run_connection(
+    mut socket: Socket,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    events: mpsc::UnboundedSender<CanvasEvents>,
+    close_reason_slot: Arc<Mutex<Option<CloseReason>>>,
+    undecodable: Arc<AtomicUsize>,
+) {
+    loop {
+        let next = {
+            let outgoing = pin!(outgoing.recv());
+            match select(socket.next(), outgoing).await {
+                Either::Left((message, _)) => Either::Left(message),
+                Either::Right((message, _)) => Either::Right(message),
+            }
+        };
+
+        match next {
+            Either::Left(Some(Ok(message))) => match message {
+                // the pong is queued by tungstenite, flushing sends it
+                Message::Ping(_) => {
+                    let _ = socket.flush().await;
+                }
+
+                Message::Text(text) => match serde_json::from_str::<CanvasEvents>(&text) {
+                    // the initial state is unpacked, callers see the eventlog event by event
+                    Ok(CanvasEvents::InitialStateChunk { events: chunk, .. }) => {
+                        for event in chunk {
+                            let _ = events.send(event);
+                        }
+                    }
+                    Ok(event) => {
+                        // the client may be dropped without closing, the session stays open until then
+                        let _ = events.send(event);
+                    }
+                    Err(_) => {
+                        undecodable.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+
+                // the close reply is queued by tungstenite as well
+                Message::Close(frame) => {
+                    *close_reason_slot.lock().unwrap() = frame.map(close_reason);
+                    let _ = socket.flush().await;
+                    break;
+                }
+
+                Message::Pong(_) | Message::Binary(_) | Message::Frame(_) => {}
+            },
+
+            // protocol errors end the connection, close_reason stays None
+            Either::Left(Some(Err(_))) => break,
+
+            // server closed the connection without close frame
+            Either::Left(None) => break,
+
+            Either::Right(Some((message, written))) => {
+                let close = matches!(message, Message::Close(_));
+                let _ = written.send(socket.send(message).await);
+                if close {
+                    break;
+                }
+            }
+
+            // client dropped without closing
+            Either::Right(None) => {
+                let _ = socket.send(normal_close()).await;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_request_escapes_the_canvas_id() {
+        let request = upgrade_request("http://localhost:1234/", "token", "a/../b c?d").unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            "ws://localhost:1234/ws/canvas/a%2F%2E%2E%2Fb%20c%3Fd"
+        );
+        assert_eq!(
+            request.headers()[header::SEC_WEBSOCKET_PROTOCOL],
+            format!("{WS_PROTOCOL}, {WS_TOKEN_PROTOCOL_PREFIX}token")
+        );
+    }
+
+    #[test]
+    fn test_upgrade_request_rejects_invalid_hosts() {
+        for base_url in [
+            "localhost:1234",
+            "https://localhost:1234",
+            "http://",
+            "http://localhost:1234/path",
+            "http://user@localhost:1234",
+            "http://localhost:1234\r\nX-Injected: 1",
+        ] {
+            assert!(
+                matches!(
+                    upgrade_request(base_url, "token", "canvas"),
+                    Err(ClientError::InvalidUrl(_))
+                ),
+                "{base_url}"
+            );
+        }
+        // header values can't carry line breaks
+        assert!(upgrade_request("http://localhost:1234", "a\r\nb", "canvas").is_err());
+    }
+}
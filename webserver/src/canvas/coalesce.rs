@@ -0,0 +1,303 @@
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+
+use super::events::CanvasEvents;
+use crate::userstore::UserId;
+
+// Coalescing of the ShapeUpdated stream of a drag, a 3 second drag would otherwise persist hundreds of lines
+// Updates of a session and shape are broadcast right away but buffered for the window before they are persisted,
+// the updates of one window are persisted as a single merged update
+// Updates are partial shapes applied like Object.assign, so the merged update folds to the same shape
+// A buffer is persisted early whenever the order of the eventlog would otherwise change,
+// e.g. by another event of its shape, its session leaving or the canvas being synced
+
+/// Time the updates of a session and shape are buffered, starting with the first update of the window
+pub const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(150);
+
+/// Updates of a session and shape waiting to be persisted as one
+#[derive(Debug)]
+pub struct PendingUpdate {
+    pub user_id: UserId,
+    pub session_id: String,
+    /// merged partial shape, origin and timestamp of the last update
+    pub event: CanvasEvents,
+    /// operation ids of the merged updates in the order they arrived, the last one is acknowledged
+    pub op_ids: Vec<String>,
+    /// time of the first update of the window, in milliseconds
+    started_at: u64,
+    /// order of the buffers, persisted in the order they were started
+    order: u64,
+}
+
+impl PendingUpdate {
+    /// Operation id acknowledged with the persisted line and the ids it superseded
+    pub fn acknowledged(&self) -> Option<(&String, &[String])> {
+        self.op_ids.split_last()
+    }
+}
+
+/// Buffered updates of a canvas, keyed by session and shape
+#[derive(Debug, Default)]
+pub struct UpdateBuffer {
+    pending: HashMap<(String, String), PendingUpdate>,
+    started: u64,
+}
+
+/// Shape of a ShapeUpdated event
+pub fn updated_shape_id(event: &CanvasEvents) -> Option<&str> {
+    match event {
+        CanvasEvents::ShapeUpdated { shape, .. } => shape.get("id").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+/// Merges a later update of the same shape into the buffered one
+fn merge(buffered: &mut CanvasEvents, update: CanvasEvents) {
+    let (
+        CanvasEvents::ShapeUpdated {
+            origin,
+            timestamp,
+            shape,
+            userId,
+        },
+        CanvasEvents::ShapeUpdated {
+            origin: later_origin,
+            timestamp: later_timestamp,
+            shape: later_shape,
+            userId: later_user_id,
+        },
+    ) = (buffered, update)
+    else {
+        return;
+    };
+    *origin = later_origin;
+    *timestamp = later_timestamp;
+    *userId = later_user_id;
+    if let (Some(target), Value::Object(update)) = (shape.as_object_mut(), later_shape) {
+        target.extend(update);
+    }
+}
+
+impl UpdateBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    ///
+    /// Buffers a ShapeUpdated event, merged into the buffer of its session and shape if its window is still open
+    /// Returns the buffer whose window is over, it has to be persisted before the new one
+    ///
+    pub fn push(
+        &mut self,
+        now_ms: u64,
+        window: Duration,
+        user_id: &UserId,
+        session_id: &str,
+        op_id: Option<String>,
+        event: CanvasEvents,
+    ) -> Option<PendingUpdate> {
+        let shape_id = updated_shape_id(&event)?.to_string();
+        let key = (session_id.to_string(), shape_id);
+        let expired = match self.pending.get(&key) {
+            Some(pending) if now_ms >= pending.started_at + window.as_millis() as u64 => {
+                self.pending.remove(&key)
+            }
+            _ => None,
+        };
+
+        match self.pending.get_mut(&key) {
+            Some(pending) => {
+                merge(&mut pending.event, event);
+                pending.op_ids.extend(op_id);
+            }
+            None => {
+                self.started += 1;
+                let pending = PendingUpdate {
+                    user_id: user_id.clone(),
+                    session_id: session_id.to_string(),
+                    event,
+                    op_ids: op_id.into_iter().collect(),
+                    started_at: now_ms,
+                    order: self.started,
+                };
+                self.pending.insert(key, pending);
+            }
+        }
+        expired
+    }
+
+    /// Removes the buffers matching the filter, in the order they were started
+    fn take_where(
+        &mut self,
+        filter: impl Fn(&str, &str, &PendingUpdate) -> bool,
+    ) -> Vec<PendingUpdate> {
+        let keys: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|((session_id, shape_id), pending)| filter(session_id, shape_id, pending))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut taken: Vec<PendingUpdate> = keys
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .collect();
+        taken.sort_by_key(|pending| pending.order);
+        taken
+    }
+
+    /// Buffers whose window is over
+    pub fn take_due(&mut self, now_ms: u64, window: Duration) -> Vec<PendingUpdate> {
+        let window = window.as_millis() as u64;
+        self.take_where(|_, _, pending| now_ms >= pending.started_at + window)
+    }
+
+    /// Buffers of the shape, the buffer of keep_session stays
+    pub fn take_shape(&mut self, shape_id: &str, keep_session: Option<&str>) -> Vec<PendingUpdate> {
+        self.take_where(|session_id, shape, _| {
+            shape == shape_id && keep_session != Some(session_id)
+        })
+    }
+
+    pub fn take_session(&mut self, session_id: &str) -> Vec<PendingUpdate> {
+        self.take_where(|session, _, _| session == session_id)
+    }
+
+    pub fn take_all(&mut self) -> Vec<PendingUpdate> {
+        self.take_where(|_, _, _| true)
+    }
+
+    /// Time until the next window is over, None without buffers
+    pub fn next_due_in(&self, now_ms: u64, window: Duration) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|pending| pending.started_at + window.as_millis() as u64)
+            .min()
+            .map(|due| Duration::from_millis(due.saturating_sub(now_ms)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn update(timestamp: u64, shape: Value) -> CanvasEvents {
+        CanvasEvents::ShapeUpdated {
+            origin: "session".to_string(),
+            timestamp,
+            shape,
+            userId: None,
+        }
+    }
+
+    #[test]
+    fn test_updates_of_a_window_are_merged() {
+        let window = DEFAULT_COALESCE_WINDOW;
+        let user = "user".to_string();
+        let mut buffer = UpdateBuffer::default();
+
+        let first = json!({ "id": "l1", "from": { "x": 0, "y": 0 }, "borderColor": "#f00" });
+        assert!(buffer
+            .push(
+                0,
+                window,
+                &user,
+                "session",
+                Some("op1".into()),
+                update(1, first)
+            )
+            .is_none());
+        let second = json!({ "id": "l1", "from": { "x": 5, "y": 5 } });
+        assert!(buffer
+            .push(
+                100,
+                window,
+                &user,
+                "session",
+                Some("op2".into()),
+                update(2, second)
+            )
+            .is_none());
+        assert_eq!(
+            buffer.next_due_in(100, window),
+            Some(Duration::from_millis(50))
+        );
+        assert!(buffer.take_due(149, window).is_empty());
+
+        let due = buffer.take_due(150, window);
+        assert_eq!(due.len(), 1);
+        let CanvasEvents::ShapeUpdated {
+            timestamp, shape, ..
+        } = &due[0].event
+        else {
+            panic!("not an update");
+        };
+        assert_eq!(*timestamp, 2);
+        assert_eq!(
+            shape,
+            &json!({ "id": "l1", "from": { "x": 5, "y": 5 }, "borderColor": "#f00" })
+        );
+        let (acknowledged, superseded) = due[0].acknowledged().unwrap();
+        assert_eq!(
+            (acknowledged.as_str(), superseded),
+            ("op2", &["op1".to_string()][..])
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_expired_buffer_is_returned_before_a_new_window() {
+        let window = DEFAULT_COALESCE_WINDOW;
+        let user = "user".to_string();
+        let mut buffer = UpdateBuffer::default();
+
+        buffer.push(
+            0,
+            window,
+            &user,
+            "a",
+            None,
+            update(1, json!({ "id": "l1" })),
+        );
+        buffer.push(
+            10,
+            window,
+            &user,
+            "b",
+            None,
+            update(1, json!({ "id": "l1" })),
+        );
+        buffer.push(
+            20,
+            window,
+            &user,
+            "a",
+            None,
+            update(1, json!({ "id": "l2" })),
+        );
+        let expired = buffer.push(
+            200,
+            window,
+            &user,
+            "a",
+            None,
+            update(2, json!({ "id": "l1" })),
+        );
+        assert_eq!(
+            expired.map(|pending| pending.session_id),
+            Some("a".to_string())
+        );
+
+        // other sessions' buffers of the shape are taken in the order they were started
+        let taken = buffer.take_shape("l1", Some("a"));
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].session_id, "b");
+        let sessions: Vec<_> = buffer
+            .take_session("a")
+            .into_iter()
+            .map(|pending| updated_shape_id(&pending.event).unwrap().to_string())
+            .collect();
+        assert_eq!(sessions, ["l2", "l1"]);
+        assert!(buffer.take_all().is_empty());
+    }
+}
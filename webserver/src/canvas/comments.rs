@@ -0,0 +1,494 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use utoipa::ToSchema;
+
+use super::{
+    events::CanvasEvents,
+    store::{clean_text, AccessLevel},
+    validation::is_valid_shape_id,
+};
+use crate::{
+    messages::{Message, MessageKey},
+    userstore::UserId,
+};
+
+// Feedback anchored to persisted shapes, folded from the comment events of the eventlog
+// The server stamps the userId of every comment event, it is the authority on the author
+// Removing a shape resolves its open comments, resolved comments outlive their shape until they are deleted
+// The live canvas keeps comments out of event_log, sessions receive the open comments with the initial state
+// and ask for the resolved ones with a ResolvedCommentsRequest
+
+/// Longest comment in characters
+pub const MAX_COMMENT_LENGTH: usize = 2_000;
+
+/// Lowest access level that may comment unless configured otherwise
+pub const DEFAULT_COMMENT_LEVEL: AccessLevel = AccessLevel::Voice;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Comment {
+    pub comment_id: String,
+    pub shape_id: String,
+    /// None for comments of logs without a stamped author
+    pub author_id: Option<UserId>,
+    pub text: String,
+    /// stamp of CommentAdded in seconds, stamped by the server
+    pub created_at: u64,
+    pub resolved: bool,
+    /// None if the comment is open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_by: Option<UserId>,
+}
+
+impl Comment {
+    pub fn status(&self) -> CommentStatus {
+        match self.resolved {
+            true => CommentStatus::Resolved,
+            false => CommentStatus::Open,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CommentStatus {
+    Open,
+    Resolved,
+}
+
+/// Why a comment event was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommentRejection {
+    /// the access level of the user is below the configured one
+    PermissionDenied,
+    /// name of the invalid field
+    Invalid(&'static str),
+    /// id of the comment
+    IdTaken(String),
+    /// id of the comment
+    UnknownComment(String),
+    /// id of the shape
+    UnknownShape(String),
+    /// only the author, owners and moderators delete comments
+    NotAuthor,
+}
+
+impl CommentRejection {
+    pub fn message(&self) -> Message {
+        match self {
+            CommentRejection::PermissionDenied => Message::new(MessageKey::EventCommentDenied),
+            CommentRejection::Invalid(field) => Message::new(MessageKey::EventCommentInvalid)
+                .param("field", *field)
+                .param("max", MAX_COMMENT_LENGTH),
+            CommentRejection::IdTaken(id) => {
+                Message::new(MessageKey::EventCommentIdTaken).param("id", id)
+            }
+            CommentRejection::UnknownComment(id) => {
+                Message::new(MessageKey::EventCommentUnknown).param("id", id)
+            }
+            CommentRejection::UnknownShape(id) => {
+                Message::new(MessageKey::EventCommentShapeUnknown).param("id", id)
+            }
+            CommentRejection::NotAuthor => Message::new(MessageKey::EventCommentNotAuthor),
+        }
+    }
+}
+
+/// Whether the level may comment, regardless of the state of the canvas
+pub fn may_comment(level: &AccessLevel, min_level: &AccessLevel) -> bool {
+    *level != AccessLevel::None && level.at_least(min_level)
+}
+
+/// Events folded into the comments, persisted but never part of event_log
+pub fn is_comment_event(event: &CanvasEvents) -> bool {
+    matches!(
+        event,
+        CanvasEvents::CommentAdded { .. }
+            | CanvasEvents::CommentResolved { .. }
+            | CanvasEvents::CommentReopened { .. }
+            | CanvasEvents::CommentDeleted { .. }
+    )
+}
+
+/// Comments of a canvas, keyed by shape id, every shape in the order its comments were added
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Comments {
+    shapes: BTreeMap<String, Vec<Comment>>,
+}
+
+impl Comments {
+    pub fn from_log<'a>(events: impl IntoIterator<Item = &'a CanvasEvents>) -> Self {
+        let mut comments = Self::default();
+        for event in events {
+            comments.apply(event);
+        }
+        comments
+    }
+
+    pub fn get(&self, comment_id: &str) -> Option<&Comment> {
+        self.shapes
+            .values()
+            .flatten()
+            .find(|comment| comment.comment_id == comment_id)
+    }
+
+    fn get_mut(&mut self, comment_id: &str) -> Option<&mut Comment> {
+        self.shapes
+            .values_mut()
+            .flatten()
+            .find(|comment| comment.comment_id == comment_id)
+    }
+
+    /// Comments matching both filters, ordered by shape
+    pub fn filter(&self, shape_id: Option<&str>, status: Option<CommentStatus>) -> Vec<&Comment> {
+        self.shapes
+            .iter()
+            .filter(|(shape, _)| shape_id.is_none_or(|shape_id| shape_id == *shape))
+            .flat_map(|(_, comments)| comments)
+            .filter(|comment| status.is_none_or(|status| comment.status() == status))
+            .collect()
+    }
+
+    /// Applies a single event, events of unknown comments are ignored
+    pub fn apply(&mut self, event: &CanvasEvents) {
+        match event {
+            // the first of colliding comments is kept
+            CanvasEvents::CommentAdded {
+                commentId,
+                shapeId,
+                text,
+                userId,
+                timestamp,
+                ..
+            } => {
+                if self.get(commentId).is_some() {
+                    return;
+                }
+                self.shapes
+                    .entry(shapeId.clone())
+                    .or_default()
+                    .push(Comment {
+                        comment_id: commentId.clone(),
+                        shape_id: shapeId.clone(),
+                        author_id: userId.clone(),
+                        text: text.clone(),
+                        created_at: *timestamp,
+                        resolved: false,
+                        resolved_by: None,
+                    });
+            }
+            CanvasEvents::CommentResolved {
+                commentId, userId, ..
+            } => {
+                if let Some(comment) = self.get_mut(commentId) {
+                    comment.resolved = true;
+                    comment.resolved_by = userId.clone();
+                }
+            }
+            CanvasEvents::CommentReopened { commentId, .. } => {
+                if let Some(comment) = self.get_mut(commentId) {
+                    comment.resolved = false;
+                    comment.resolved_by = None;
+                }
+            }
+            CanvasEvents::CommentDeleted { commentId, .. } => {
+                for comments in self.shapes.values_mut() {
+                    comments.retain(|comment| &comment.comment_id != commentId);
+                }
+                self.shapes.retain(|_, comments| !comments.is_empty());
+            }
+            _ => (),
+        }
+    }
+
+    ///
+    /// Open comments resolved by the event, comments of removed shapes or of every shape on a clear
+    /// Shapes the session is still drawing have no comments, they are never persisted
+    ///
+    pub fn resolved_by_removal(&self, event: &CanvasEvents) -> Vec<String> {
+        let shape_id = match event {
+            CanvasEvents::ShapeRemoved { shapeId, .. } => Some(shapeId.as_str()),
+            CanvasEvents::CanvasCleared { .. } => None,
+            _ => return Vec::new(),
+        };
+        self.filter(shape_id, Some(CommentStatus::Open))
+            .into_iter()
+            .map(|comment| comment.comment_id.clone())
+            .collect()
+    }
+
+    ///
+    /// Events rebuilding the matching comments on a client, resolved comments are followed by their resolution
+    /// origin is the session receiving them, like shapes sent on catch up
+    ///
+    pub fn sync_events(
+        &self,
+        origin: &str,
+        shape_id: Option<&str>,
+        status: CommentStatus,
+    ) -> Vec<CanvasEvents> {
+        let mut events = Vec::new();
+        for comment in self.filter(shape_id, Some(status)) {
+            events.push(CanvasEvents::CommentAdded {
+                origin: origin.to_string(),
+                timestamp: comment.created_at,
+                commentId: comment.comment_id.clone(),
+                shapeId: comment.shape_id.clone(),
+                text: comment.text.clone(),
+                userId: comment.author_id.clone(),
+            });
+            if comment.resolved {
+                events.push(CanvasEvents::CommentResolved {
+                    origin: origin.to_string(),
+                    timestamp: comment.created_at,
+                    commentId: comment.comment_id.clone(),
+                    userId: comment.resolved_by.clone(),
+                });
+            }
+        }
+        events
+    }
+
+    ///
+    /// Checks a comment event of a client against the comments and the persisted shapes
+    /// The text of a new comment is cleaned in place, control characters but line breaks are stripped
+    ///
+    pub fn check(
+        &self,
+        event: &mut CanvasEvents,
+        user_id: &UserId,
+        level: &AccessLevel,
+        min_level: &AccessLevel,
+        shapes: &HashSet<String>,
+    ) -> Result<(), CommentRejection> {
+        if !may_comment(level, min_level) {
+            return Err(CommentRejection::PermissionDenied);
+        }
+
+        match event {
+            CanvasEvents::CommentAdded {
+                commentId,
+                shapeId,
+                text,
+                ..
+            } => {
+                if !is_valid_shape_id(commentId) {
+                    return Err(CommentRejection::Invalid("commentId"));
+                }
+                if self.get(commentId).is_some() {
+                    return Err(CommentRejection::IdTaken(commentId.clone()));
+                }
+                if !shapes.contains(shapeId.as_str()) {
+                    return Err(CommentRejection::UnknownShape(shapeId.clone()));
+                }
+                let cleaned = clean_text(Some(std::mem::take(text)), true)
+                    .filter(|cleaned| cleaned.chars().count() <= MAX_COMMENT_LENGTH)
+                    .ok_or(CommentRejection::Invalid("text"))?;
+                *text = cleaned;
+                Ok(())
+            }
+            CanvasEvents::CommentResolved { commentId, .. }
+            | CanvasEvents::CommentReopened { commentId, .. } => self
+                .get(commentId)
+                .map(|_| ())
+                .ok_or_else(|| CommentRejection::UnknownComment(commentId.clone())),
+            CanvasEvents::CommentDeleted { commentId, .. } => {
+                let comment = self
+                    .get(commentId)
+                    .ok_or_else(|| CommentRejection::UnknownComment(commentId.clone()))?;
+                let moderates = matches!(level, AccessLevel::Owner | AccessLevel::Moderate);
+                if !moderates && comment.author_id.as_ref() != Some(user_id) {
+                    return Err(CommentRejection::NotAuthor);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(comment_id: &str, shape_id: &str, author: &str, text: &str) -> CanvasEvents {
+        CanvasEvents::CommentAdded {
+            origin: "session".to_string(),
+            timestamp: 1,
+            commentId: comment_id.to_string(),
+            shapeId: shape_id.to_string(),
+            text: text.to_string(),
+            userId: Some(author.to_string()),
+        }
+    }
+
+    fn deleted(comment_id: &str) -> CanvasEvents {
+        CanvasEvents::CommentDeleted {
+            origin: "session".to_string(),
+            timestamp: 2,
+            commentId: comment_id.to_string(),
+            userId: None,
+        }
+    }
+
+    fn resolved(comment_id: &str) -> CanvasEvents {
+        CanvasEvents::CommentResolved {
+            origin: "session".to_string(),
+            timestamp: 2,
+            commentId: comment_id.to_string(),
+            userId: Some("bob".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_permission_matrix() {
+        let comments = Comments::from_log(&[added("c1", "s1", "alice", "first")]);
+        let shapes = HashSet::from(["s1".to_string()]);
+        let levels = [
+            AccessLevel::None,
+            AccessLevel::Read,
+            AccessLevel::Voice,
+            AccessLevel::Write,
+            AccessLevel::Moderate,
+            AccessLevel::Owner,
+        ];
+
+        // (may comment with the default level, may delete the comment of alice)
+        let expected = [
+            (false, false),
+            (false, false),
+            (true, false),
+            (true, false),
+            (true, true),
+            (true, true),
+        ];
+        for (level, (comment, delete)) in levels.iter().zip(expected) {
+            let check = |mut event: CanvasEvents| {
+                comments
+                    .check(
+                        &mut event,
+                        &"bob".to_string(),
+                        level,
+                        &DEFAULT_COMMENT_LEVEL,
+                        &shapes,
+                    )
+                    .is_ok()
+            };
+            assert_eq!(
+                check(added("c2", "s1", "bob", "looks off")),
+                comment,
+                "{level}"
+            );
+            assert_eq!(check(resolved("c1")), comment, "{level}");
+            assert_eq!(check(deleted("c1")), delete, "{level}");
+        }
+
+        // the author deletes its own comment, the configured level is raised to Write
+        let mut delete = deleted("c1");
+        let alice = "alice".to_string();
+        assert!(comments
+            .check(
+                &mut delete,
+                &alice,
+                &AccessLevel::Voice,
+                &DEFAULT_COMMENT_LEVEL,
+                &shapes
+            )
+            .is_ok());
+        assert_eq!(
+            comments.check(
+                &mut delete,
+                &alice,
+                &AccessLevel::Voice,
+                &AccessLevel::Write,
+                &shapes
+            ),
+            Err(CommentRejection::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn test_comments_are_checked_and_cleaned() {
+        let comments = Comments::from_log(&[added("c1", "s1", "alice", "first")]);
+        let shapes = HashSet::from(["s1".to_string()]);
+        let check = |mut event: CanvasEvents| {
+            comments
+                .check(
+                    &mut event,
+                    &"bob".to_string(),
+                    &AccessLevel::Write,
+                    &DEFAULT_COMMENT_LEVEL,
+                    &shapes,
+                )
+                .map(|_| event)
+        };
+
+        let Ok(CanvasEvents::CommentAdded { text, .. }) =
+            check(added("c2", "s1", "bob", " two\u{0007}\nlines "))
+        else {
+            panic!("comment rejected");
+        };
+        assert_eq!(text, "two\nlines");
+        assert_eq!(
+            check(added("c2", "gone", "bob", "text")).unwrap_err(),
+            CommentRejection::UnknownShape("gone".to_string())
+        );
+        assert_eq!(
+            check(added("c1", "s1", "bob", "text")).unwrap_err(),
+            CommentRejection::IdTaken("c1".to_string())
+        );
+        assert_eq!(
+            check(added("c 2", "s1", "bob", "text")).unwrap_err(),
+            CommentRejection::Invalid("commentId")
+        );
+        for text in ["  \u{0007} ", &"x".repeat(MAX_COMMENT_LENGTH + 1)] {
+            assert_eq!(
+                check(added("c2", "s1", "bob", text)).unwrap_err(),
+                CommentRejection::Invalid("text")
+            );
+        }
+        assert_eq!(
+            check(resolved("c9")).unwrap_err(),
+            CommentRejection::UnknownComment("c9".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fold_follows_the_status_of_every_comment() {
+        let removed = CanvasEvents::ShapeRemoved {
+            origin: "session".to_string(),
+            timestamp: 3,
+            shapeId: "s1".to_string(),
+        };
+        let mut comments = Comments::from_log(&[
+            added("c1", "s1", "alice", "first"),
+            added("c2", "s1", "alice", "second"),
+            added("c3", "s2", "alice", "third"),
+            resolved("c1"),
+            deleted("c3"),
+        ]);
+        assert_eq!(comments.resolved_by_removal(&removed), vec!["c2"]);
+
+        comments.apply(&CanvasEvents::CommentReopened {
+            origin: "session".to_string(),
+            timestamp: 4,
+            commentId: "c1".to_string(),
+            userId: None,
+        });
+        let open: Vec<&str> = comments
+            .filter(Some("s1"), Some(CommentStatus::Open))
+            .iter()
+            .map(|comment| comment.comment_id.as_str())
+            .collect();
+        assert_eq!(open, vec!["c1", "c2"]);
+        assert!(comments.filter(Some("s2"), None).is_empty());
+
+        comments.apply(&resolved("c2"));
+        let events = comments.sync_events("s", None, CommentStatus::Resolved);
+        assert!(matches!(
+            events.as_slice(),
+            [CanvasEvents::CommentAdded { commentId, .. }, CanvasEvents::CommentResolved { userId: Some(_), .. }]
+                if commentId == "c2"
+        ));
+    }
+}
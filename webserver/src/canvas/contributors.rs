@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::{events::CanvasEvents, receipts::ReadReceipts};
+use crate::userstore::UserId;
+
+// Attribution of the changes of a canvas to the users who made them
+// The first change of a user persists a ContributorSeen line with the display name the user had at that moment
+// The eventlog names its contributors on its own, attribution doesn't depend on UserJoined or on the UserStore
+// A user renamed since its last record gets a new record with its next change, older changes keep the old name
+
+/// Latest recorded name of every contributor
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Contributors {
+    names: BTreeMap<UserId, String>,
+}
+
+impl Contributors {
+    /// Folds a persisted eventlog, records of earlier instances are restored
+    pub fn from_log<'a>(events: impl IntoIterator<Item = &'a CanvasEvents>) -> Self {
+        let mut contributors = Self::default();
+        for event in events {
+            contributors.apply(event);
+        }
+        contributors
+    }
+
+    pub fn apply(&mut self, event: &CanvasEvents) {
+        if let CanvasEvents::ContributorSeen {
+            userId, username, ..
+        } = event
+        {
+            self.names.insert(userId.clone(), username.clone());
+        }
+    }
+
+    /// Events that are attributed to the user sending them
+    pub fn is_contribution(event: &CanvasEvents) -> bool {
+        ReadReceipts::is_change(event)
+    }
+
+    pub fn name(&self, user_id: &UserId) -> Option<&str> {
+        self.names.get(user_id).map(String::as_str)
+    }
+
+    pub fn contains(&self, user_id: &UserId) -> bool {
+        self.names.contains_key(user_id)
+    }
+
+    /// Names contributors unknown to the eventlog, recorded names are kept
+    pub fn insert_unrecorded(&mut self, user_id: UserId, username: String) {
+        self.names.entry(user_id).or_insert(username);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&UserId, &String)> {
+        self.names.iter()
+    }
+
+    ///
+    /// Records that the user contributes under username
+    /// Returns the record to persist before the contribution, None if the user is recorded under that name
+    ///
+    pub fn record(&mut self, user_id: &UserId, username: &str, now: u64) -> Option<CanvasEvents> {
+        if self.name(user_id) == Some(username) {
+            return None;
+        }
+
+        self.names.insert(user_id.clone(), username.to_string());
+        Some(CanvasEvents::ContributorSeen {
+            userId: user_id.clone(),
+            username: username.to_string(),
+            firstSeen: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_are_restored_from_log() {
+        let seen = |user_id: &str, username: &str| CanvasEvents::ContributorSeen {
+            userId: user_id.to_string(),
+            username: username.to_string(),
+            firstSeen: 1,
+        };
+        let mut contributors =
+            Contributors::from_log(&[seen("u1", "alice"), seen("u2", "bob"), seen("u1", "ally")]);
+
+        assert_eq!(contributors.name(&"u1".to_string()), Some("ally"));
+        assert!(contributors.record(&"u2".to_string(), "bob", 5).is_none());
+        assert!(matches!(
+            contributors.record(&"u2".to_string(), "bobby", 5),
+            Some(CanvasEvents::ContributorSeen { firstSeen: 5, .. })
+        ));
+
+        // recorded names win over names looked up later
+        contributors.insert_unrecorded("u1".to_string(), "alice".to_string());
+        contributors.insert_unrecorded("u3".to_string(), "carol".to_string());
+        assert_eq!(contributors.name(&"u1".to_string()), Some("ally"));
+        assert_eq!(contributors.name(&"u3".to_string()), Some("carol"));
+    }
+}
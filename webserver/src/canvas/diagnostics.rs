@@ -0,0 +1,435 @@
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+use utoipa::ToSchema;
+
+// Concurrent edit diagnostics of a loaded canvas, tell owners whether a laggy session is caused by
+// a slow client, the eventlog or the amount of events
+// Counted in the hot path of the canvas server with plain integers, they start over whenever the canvas is loaded
+
+/// Seconds covered by the rolling counters
+pub const DIAGNOSTICS_WINDOW_SECS: usize = 60;
+
+/// Persist latencies kept for the percentiles, the oldest sample is overwritten first
+const PERSIST_SAMPLES: usize = 256;
+
+/// Shapes whose conflicts are counted, conflicts of further shapes only count towards the rolling counter
+const MAX_CONTENDED_SHAPES: usize = 1_000;
+
+/// Contended shapes listed in the report
+const REPORTED_CONTENDED_SHAPES: usize = 10;
+
+/// Minimum time between two alarms of the same canvas
+pub const ALARM_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Thresholds that send owners and moderators a diagnostics alarm, None disables the check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticsAlarm {
+    pub events_per_minute: Option<u64>,
+    /// time since the oldest event not yet synced to disk
+    pub save_lag: Option<Duration>,
+    /// failed sends to a single session, a session whose socket is gone or can't keep up
+    pub send_failures: Option<u64>,
+}
+
+impl Default for DiagnosticsAlarm {
+    fn default() -> Self {
+        Self {
+            events_per_minute: Some(6_000),
+            save_lag: Some(Duration::from_secs(30)),
+            send_failures: Some(100),
+        }
+    }
+}
+
+/// Events per second of the last DIAGNOSTICS_WINDOW_SECS seconds
+#[derive(Debug, Clone)]
+struct RollingCounter {
+    counts: [u64; DIAGNOSTICS_WINDOW_SECS],
+    /// second every bucket counts, buckets of older seconds are reused
+    seconds: [u64; DIAGNOSTICS_WINDOW_SECS],
+}
+
+impl Default for RollingCounter {
+    fn default() -> Self {
+        Self {
+            counts: [0; DIAGNOSTICS_WINDOW_SECS],
+            seconds: [0; DIAGNOSTICS_WINDOW_SECS],
+        }
+    }
+}
+
+impl RollingCounter {
+    fn add(&mut self, now_ms: u64, count: u64) {
+        let second = now_ms / 1000;
+        let bucket = second as usize % DIAGNOSTICS_WINDOW_SECS;
+        if self.seconds[bucket] != second {
+            self.seconds[bucket] = second;
+            self.counts[bucket] = 0;
+        }
+        self.counts[bucket] += count;
+    }
+
+    fn total(&self, now_ms: u64) -> u64 {
+        let second = now_ms / 1000;
+        self.seconds
+            .iter()
+            .zip(self.counts)
+            .filter(|(bucket_second, _)| {
+                **bucket_second <= second
+                    && second - **bucket_second < DIAGNOSTICS_WINDOW_SECS as u64
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Counters of a CanvasInstance
+#[derive(Debug, Clone)]
+pub struct CanvasDiagnostics {
+    received: RollingCounter,
+    broadcast: RollingCounter,
+    rejected: RollingCounter,
+    /// updates of a shape following the update of another user, see provenance.rs
+    conflicts: RollingCounter,
+    /// conflicts per shape since the canvas was loaded
+    contended_shapes: HashMap<String, u64>,
+    /// temporary shapes removed because a session or the canvas drew too many at once
+    temp_shapes_evicted: RollingCounter,
+    /// selections of shapes removed meanwhile, dropped by the sweep
+    selections_swept: RollingCounter,
+    /// times the canvas was throttled because the disk could not keep up, see backpressure.rs
+    throttled: RollingCounter,
+    /// events refused while throttled or beyond the hard limit
+    refused_under_pressure: RollingCounter,
+    /// sessions reached by the broadcasts of the window, divided by broadcast for the average fan-out
+    fan_out: RollingCounter,
+    max_fan_out: usize,
+    /// failed sends per session, high-water mark of the queue without a bounded channel
+    send_failures: HashMap<String, u64>,
+    /// microseconds spent persisting an event
+    persist_samples: [u64; PERSIST_SAMPLES],
+    persist_count: usize,
+    /// millisecond timestamp of the last alarm
+    last_alarm_at: Option<u64>,
+}
+
+impl Default for CanvasDiagnostics {
+    fn default() -> Self {
+        Self {
+            received: RollingCounter::default(),
+            broadcast: RollingCounter::default(),
+            rejected: RollingCounter::default(),
+            conflicts: RollingCounter::default(),
+            contended_shapes: HashMap::new(),
+            temp_shapes_evicted: RollingCounter::default(),
+            selections_swept: RollingCounter::default(),
+            throttled: RollingCounter::default(),
+            refused_under_pressure: RollingCounter::default(),
+            fan_out: RollingCounter::default(),
+            max_fan_out: 0,
+            send_failures: HashMap::new(),
+            persist_samples: [0; PERSIST_SAMPLES],
+            persist_count: 0,
+            last_alarm_at: None,
+        }
+    }
+}
+
+/// Percentiles of the persist latency in microseconds
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub struct LatencyPercentiles {
+    pub samples: usize,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Session with failed sends
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SessionBacklog {
+    pub session_id: String,
+    pub send_failures: u64,
+}
+
+/// Shape edited by different users at the same time
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ShapeContention {
+    pub shape_id: String,
+    pub conflicts: u64,
+}
+
+/// Answer to CanvasQuery::Diagnostics, counts are of the last minute
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DiagnosticsReport {
+    pub window_secs: usize,
+    pub events_received_per_minute: u64,
+    pub events_broadcast_per_minute: u64,
+    pub events_rejected_per_minute: u64,
+    pub average_fan_out: f64,
+    /// largest fan-out since the canvas was loaded
+    pub max_fan_out: usize,
+    /// sessions with failed sends, the slowest first
+    pub slow_sessions: Vec<SessionBacklog>,
+    pub persist_latency: LatencyPercentiles,
+    /// time since the oldest event not yet synced to disk, zero if everything is saved
+    pub save_lag_ms: u64,
+    /// updates of a shape following the update of another user within the conflict window
+    pub conflicts_per_minute: u64,
+    /// shapes with the most conflicts since the canvas was loaded, the most contended first
+    pub contended_shapes: Vec<ShapeContention>,
+    /// times the wall clock stepped back since the canvas was loaded, the stamps of its events kept increasing
+    pub clock_regressions: u64,
+    /// temporary shapes removed beyond the limits of a session or the canvas
+    pub temp_shapes_evicted_per_minute: u64,
+    /// selections dropped because their shape was removed meanwhile
+    pub selections_swept_per_minute: u64,
+    /// shapes currently selected by all sessions
+    pub selected_shapes: usize,
+    /// temporary shapes currently drawn by all sessions
+    pub temp_shapes: usize,
+    /// times the canvas was throttled because its backlog reached the high-water mark
+    pub throttled_per_minute: u64,
+    /// events refused because of the backlog
+    pub refused_under_pressure_per_minute: u64,
+    /// whether writers are currently refused
+    pub throttled: bool,
+    /// events persisted but not yet synced to disk
+    pub backlog_events: u64,
+    pub backlog_bytes: u64,
+}
+
+impl CanvasDiagnostics {
+    pub fn record_received(&mut self, now_ms: u64) {
+        self.received.add(now_ms, 1);
+    }
+
+    pub fn record_rejected(&mut self, now_ms: u64) {
+        self.rejected.add(now_ms, 1);
+    }
+
+    pub fn record_conflict(&mut self, now_ms: u64, shape_id: &str) {
+        self.conflicts.add(now_ms, 1);
+        let tracked = self.contended_shapes.len();
+        match self.contended_shapes.get_mut(shape_id) {
+            Some(conflicts) => *conflicts += 1,
+            None if tracked < MAX_CONTENDED_SHAPES => {
+                self.contended_shapes.insert(shape_id.to_string(), 1);
+            }
+            None => (),
+        }
+    }
+
+    pub fn record_temp_shape_evicted(&mut self, now_ms: u64) {
+        self.temp_shapes_evicted.add(now_ms, 1);
+    }
+
+    pub fn record_selections_swept(&mut self, now_ms: u64, count: usize) {
+        self.selections_swept.add(now_ms, count as u64);
+    }
+
+    pub fn record_throttled(&mut self, now_ms: u64) {
+        self.throttled.add(now_ms, 1);
+    }
+
+    pub fn record_refused_under_pressure(&mut self, now_ms: u64) {
+        self.refused_under_pressure.add(now_ms, 1);
+    }
+
+    pub fn record_broadcast(&mut self, now_ms: u64, fan_out: usize) {
+        self.broadcast.add(now_ms, 1);
+        self.fan_out.add(now_ms, fan_out as u64);
+        self.max_fan_out = self.max_fan_out.max(fan_out);
+    }
+
+    pub fn record_send_failure(&mut self, session_id: &str) {
+        match self.send_failures.get_mut(session_id) {
+            Some(failures) => *failures += 1,
+            None => {
+                self.send_failures.insert(session_id.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn record_persist(&mut self, elapsed: Duration) {
+        self.persist_samples[self.persist_count % PERSIST_SAMPLES] = elapsed.as_micros() as u64;
+        self.persist_count += 1;
+    }
+
+    /// Forgets a closed session
+    pub fn remove_session(&mut self, session_id: &str) {
+        self.send_failures.remove(session_id);
+    }
+
+    fn persist_latency(&self) -> LatencyPercentiles {
+        let samples = self.persist_count.min(PERSIST_SAMPLES);
+        if samples == 0 {
+            return LatencyPercentiles::default();
+        }
+        let mut sorted = self.persist_samples[..samples].to_vec();
+        sorted.sort_unstable();
+        let percentile = |percent: usize| sorted[(samples - 1) * percent / 100];
+        LatencyPercentiles {
+            samples,
+            p50_us: percentile(50),
+            p95_us: percentile(95),
+            p99_us: percentile(99),
+            max_us: sorted[samples - 1],
+        }
+    }
+
+    pub fn report(&self, now_ms: u64, save_lag_ms: u64) -> DiagnosticsReport {
+        let broadcast = self.broadcast.total(now_ms);
+        let mut slow_sessions: Vec<SessionBacklog> = self
+            .send_failures
+            .iter()
+            .map(|(session_id, send_failures)| SessionBacklog {
+                session_id: session_id.clone(),
+                send_failures: *send_failures,
+            })
+            .collect();
+        slow_sessions.sort_by(|a, b| {
+            b.send_failures
+                .cmp(&a.send_failures)
+                .then_with(|| a.session_id.cmp(&b.session_id))
+        });
+
+        let mut contended_shapes: Vec<ShapeContention> = self
+            .contended_shapes
+            .iter()
+            .map(|(shape_id, conflicts)| ShapeContention {
+                shape_id: shape_id.clone(),
+                conflicts: *conflicts,
+            })
+            .collect();
+        contended_shapes.sort_by(|a, b| {
+            b.conflicts
+                .cmp(&a.conflicts)
+                .then_with(|| a.shape_id.cmp(&b.shape_id))
+        });
+        contended_shapes.truncate(REPORTED_CONTENDED_SHAPES);
+
+        DiagnosticsReport {
+            window_secs: DIAGNOSTICS_WINDOW_SECS,
+            events_received_per_minute: self.received.total(now_ms),
+            events_broadcast_per_minute: broadcast,
+            events_rejected_per_minute: self.rejected.total(now_ms),
+            average_fan_out: if broadcast == 0 {
+                0.0
+            } else {
+                self.fan_out.total(now_ms) as f64 / broadcast as f64
+            },
+            max_fan_out: self.max_fan_out,
+            slow_sessions,
+            persist_latency: self.persist_latency(),
+            save_lag_ms,
+            conflicts_per_minute: self.conflicts.total(now_ms),
+            contended_shapes,
+            clock_regressions: 0,
+            temp_shapes_evicted_per_minute: self.temp_shapes_evicted.total(now_ms),
+            selections_swept_per_minute: self.selections_swept.total(now_ms),
+            selected_shapes: 0,
+            temp_shapes: 0,
+            throttled_per_minute: self.throttled.total(now_ms),
+            refused_under_pressure_per_minute: self.refused_under_pressure.total(now_ms),
+            throttled: false,
+            backlog_events: 0,
+            backlog_bytes: 0,
+        }
+    }
+
+    ///
+    /// Report to send as alarm if a threshold is crossed, at most once per ALARM_INTERVAL
+    /// The interval starts with the alarm, a canvas staying above a threshold is reported every interval
+    ///
+    pub fn alarm(
+        &mut self,
+        thresholds: &DiagnosticsAlarm,
+        now_ms: u64,
+        save_lag_ms: u64,
+    ) -> Option<DiagnosticsReport> {
+        if self
+            .last_alarm_at
+            .is_some_and(|last| now_ms < last + ALARM_INTERVAL.as_millis() as u64)
+        {
+            return None;
+        }
+
+        let report = self.report(now_ms, save_lag_ms);
+        let crossed = thresholds
+            .events_per_minute
+            .is_some_and(|limit| report.events_received_per_minute > limit)
+            || thresholds
+                .save_lag
+                .is_some_and(|limit| save_lag_ms > limit.as_millis() as u64)
+            || thresholds.send_failures.is_some_and(|limit| {
+                report
+                    .slow_sessions
+                    .first()
+                    .is_some_and(|session| session.send_failures > limit)
+            });
+        if !crossed {
+            return None;
+        }
+
+        self.last_alarm_at = Some(now_ms);
+        Some(report)
+    }
+}
+
+/// Session id as shown in notices, enough to tell sessions apart without handing out the id
+pub fn mask_session_id(session_id: &str) -> String {
+    let prefix: String = session_id.chars().take(4).collect();
+    format!("{prefix}…")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_roll_with_the_window() {
+        let mut diagnostics = CanvasDiagnostics::default();
+        for ms in (0..30_000).step_by(100) {
+            diagnostics.record_received(1_000_000 + ms);
+        }
+        diagnostics.record_broadcast(1_000_000, 4);
+        diagnostics.record_broadcast(1_010_000, 2);
+        diagnostics.record_rejected(1_020_000);
+
+        let report = diagnostics.report(1_030_000, 0);
+        assert_eq!(report.events_received_per_minute, 300);
+        assert_eq!(report.events_broadcast_per_minute, 2);
+        assert_eq!(report.events_rejected_per_minute, 1);
+        assert_eq!(report.average_fan_out, 3.0);
+        assert_eq!(report.max_fan_out, 4);
+
+        // the events of the first 16 seconds left the window
+        let report = diagnostics.report(1_075_000, 0);
+        assert_eq!(report.events_received_per_minute, 140);
+        assert_eq!(report.events_broadcast_per_minute, 0);
+        assert_eq!(report.max_fan_out, 4);
+        assert_eq!(
+            diagnostics.report(2_000_000, 0).events_received_per_minute,
+            0
+        );
+    }
+
+    #[test]
+    fn test_persist_latency_percentiles() {
+        let mut diagnostics = CanvasDiagnostics::default();
+        assert_eq!(
+            diagnostics.report(0, 0).persist_latency,
+            LatencyPercentiles::default()
+        );
+
+        for micros in 1..=100 {
+            diagnostics.record_persist(Duration::from_micros(micros));
+        }
+        let latency = diagnostics.report(0, 0).persist_latency;
+        assert_eq!(latency.samples, 100);
+        assert_eq!(latency.p50_us, 50);
+        assert_eq!(latency.p95_us, 95);
+        assert_eq!(latency.max_us, 100);
+    }
+}
@@ -0,0 +1,417 @@
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io, time::Duration};
+use utoipa::ToSchema;
+
+use super::{
+    events::CanvasEvents,
+    server::canvas_log_path,
+    store::{CanvasId, DueDigest, GetDueDigestsMessage, RecordCanvasDigestMessage},
+};
+use crate::{
+    clock::SharedClock, notifier::SharedNotifier, persistence::EventLogPersistenceJson,
+    userstore::UserId,
+};
+
+// Activity digests sent to canvas owners, see DigestScheduler
+// The owner picks how often, canvases start without digests
+// A digest covers the lines of the canvas eventlog after the last digested seq, the store persists that seq
+// with a CanvasDigested event, a restart neither repeats a digest nor skips lines
+// Canvases without new lines are checked again on the next run, no digest is sent for them
+
+/// How often the scheduler checks for due digests
+pub const DEFAULT_DIGEST_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How often the owner of a canvas is sent a digest, only the owner may change it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub enum DigestFrequency {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    /// Time between two digests in milliseconds, None if digests are off
+    pub fn period_ms(self) -> Option<u64> {
+        match self {
+            DigestFrequency::Off => None,
+            DigestFrequency::Daily => Some(DAY_MS),
+            DigestFrequency::Weekly => Some(7 * DAY_MS),
+        }
+    }
+}
+
+/// Last digest of a canvas, kept by the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigestMark {
+    /// last line of the canvas eventlog covered by the digest
+    pub seq: u64,
+    /// unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+impl DigestMark {
+    /// Canvases that never had a digest are due right away
+    pub fn is_due(mark: Option<&DigestMark>, frequency: DigestFrequency, now: u64) -> bool {
+        match (frequency.period_ms(), mark) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(period), Some(mark)) => now.saturating_sub(mark.timestamp) >= period,
+        }
+    }
+}
+
+/// Changes of a single user within a digest
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContributorActivity {
+    pub shapes_added: u64,
+    pub shapes_updated: u64,
+    pub comments_added: u64,
+}
+
+/// Summary of the changes of a canvas since the previous digest
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasDigest {
+    pub canvas_id: CanvasId,
+    pub canvas_name: String,
+    /// first and last line of the canvas eventlog covered, both included
+    pub from_seq: u64,
+    pub to_seq: u64,
+    pub shapes_added: u64,
+    pub shapes_updated: u64,
+    /// removals are not attributed in the eventlog, they are only counted
+    pub shapes_removed: u64,
+    pub comments_added: u64,
+    /// access level changes of members while the canvas was open
+    pub membership_changes: u64,
+    /// attributed changes per user, lines written before attribution existed are only counted
+    pub contributors: BTreeMap<UserId, ContributorActivity>,
+}
+
+impl CanvasDigest {
+    fn new(canvas_id: CanvasId, canvas_name: String, from_seq: u64) -> Self {
+        Self {
+            canvas_id,
+            canvas_name,
+            from_seq,
+            to_seq: from_seq - 1,
+            shapes_added: 0,
+            shapes_updated: 0,
+            shapes_removed: 0,
+            comments_added: 0,
+            membership_changes: 0,
+            contributors: BTreeMap::new(),
+        }
+    }
+
+    fn apply(&mut self, event: &CanvasEvents) {
+        let contributor = match event {
+            CanvasEvents::ShapeAdded { userId, .. } => {
+                self.shapes_added += 1;
+                userId.as_ref().map(|user_id| (user_id, 1, 0, 0))
+            }
+            CanvasEvents::ShapeUpdated { userId, .. }
+            | CanvasEvents::ShapeZChanged { userId, .. } => {
+                self.shapes_updated += 1;
+                userId.as_ref().map(|user_id| (user_id, 0, 1, 0))
+            }
+            CanvasEvents::ShapeRemoved { .. } => {
+                self.shapes_removed += 1;
+                None
+            }
+            CanvasEvents::CommentAdded { userId, .. } => {
+                self.comments_added += 1;
+                userId.as_ref().map(|user_id| (user_id, 0, 0, 1))
+            }
+            CanvasEvents::UserAccessLevelChanged { .. } => {
+                self.membership_changes += 1;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some((user_id, added, updated, comments)) = contributor {
+            let activity = self.contributors.entry(user_id.clone()).or_default();
+            activity.shapes_added += added;
+            activity.shapes_updated += updated;
+            activity.comments_added += comments;
+        }
+    }
+
+    /// Anything worth telling the owner about, joins and read receipts alone are not
+    pub fn has_changes(&self) -> bool {
+        self.shapes_added
+            + self.shapes_updated
+            + self.shapes_removed
+            + self.comments_added
+            + self.membership_changes
+            > 0
+    }
+}
+
+/// Result of folding the eventlog of a canvas after its last digest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestFold {
+    /// number of lines in the eventlog
+    pub last_seq: u64,
+    /// None if there were no changes after the last digest
+    pub digest: Option<CanvasDigest>,
+}
+
+///
+/// Streams the eventlog and summarizes the lines after after_seq, the log is never loaded at once
+/// A log shorter than after_seq was compacted, its lines were renumbered and are not summarized again
+/// Lines that fail to deserialize are skipped, but still count towards the sequence number
+///
+pub fn fold_log(due: &DueDigest, file_path: &str) -> io::Result<DigestFold> {
+    let persistence = match EventLogPersistenceJson::open(file_path) {
+        Ok(persistence) => persistence,
+        // canvases without events have no eventlog yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(DigestFold {
+                last_seq: 0,
+                digest: None,
+            })
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut digest = CanvasDigest::new(
+        due.canvas_id.clone(),
+        due.canvas_name.clone(),
+        due.after_seq + 1,
+    );
+    let mut last_seq = 0;
+    for (index, line) in persistence.stream_lines::<CanvasEvents>().enumerate() {
+        last_seq = index as u64 + 1;
+        if last_seq <= due.after_seq {
+            continue;
+        }
+        digest.to_seq = last_seq;
+        if let Ok(event) = line? {
+            digest.apply(&event);
+        }
+    }
+
+    Ok(DigestFold {
+        last_seq,
+        digest: (last_seq > due.after_seq && digest.has_changes()).then_some(digest),
+    })
+}
+
+/// Sends the due digests, resolves to the digests sent
+#[derive(Message)]
+#[rtype(result = "Vec<CanvasDigest>")]
+pub struct RunDigestsMessage {
+    pub now: u64,
+}
+
+///
+/// Checks for due digests every interval, driven by the clock of the server
+/// The digest is recorded in the store before the owner is notified, a failed record never sends a digest twice
+///
+pub struct DigestScheduler {
+    get_due_digests_recipient: Recipient<GetDueDigestsMessage>,
+    record_canvas_digest_recipient: Recipient<RecordCanvasDigestMessage>,
+    notifier: SharedNotifier,
+    clock: SharedClock,
+    interval: Duration,
+}
+
+impl DigestScheduler {
+    pub fn new(
+        get_due_digests_recipient: Recipient<GetDueDigestsMessage>,
+        record_canvas_digest_recipient: Recipient<RecordCanvasDigestMessage>,
+        notifier: SharedNotifier,
+        clock: SharedClock,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            get_due_digests_recipient,
+            record_canvas_digest_recipient,
+            notifier,
+            clock,
+            interval,
+        }
+    }
+}
+
+impl Actor for DigestScheduler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |scheduler, ctx| {
+            ctx.address().do_send(RunDigestsMessage {
+                now: scheduler.clock.now_ms(),
+            });
+        });
+    }
+}
+
+impl Handler<RunDigestsMessage> for DigestScheduler {
+    type Result = AtomicResponse<Self, Vec<CanvasDigest>>;
+
+    // atomic, a run still folding logs when the next one starts would send its digests twice
+    fn handle(&mut self, msg: RunDigestsMessage, _: &mut Self::Context) -> Self::Result {
+        let get_due_digests_recipient = self.get_due_digests_recipient.clone();
+        let record_canvas_digest_recipient = self.record_canvas_digest_recipient.clone();
+        let notifier = self.notifier.clone();
+
+        AtomicResponse::new(Box::pin(
+            async move {
+                let due_digests = match get_due_digests_recipient
+                    .send(GetDueDigestsMessage { now: msg.now })
+                    .await
+                {
+                    Ok(due_digests) => due_digests,
+                    Err(e) => {
+                        println!("WARNING: digests skipped, canvas store unavailable: {e}");
+                        return Vec::new();
+                    }
+                };
+
+                let mut sent = Vec::new();
+                for due in due_digests {
+                    let path = canvas_log_path(&due.canvas_id);
+                    let fold = {
+                        let due = due.clone();
+                        actix_web::rt::task::spawn_blocking(move || fold_log(&due, &path)).await
+                    };
+                    let fold = match fold {
+                        Ok(Ok(fold)) => fold,
+                        Ok(Err(e)) => {
+                            println!("WARNING: digest of canvas {} failed: {e}", due.canvas_id);
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("WARNING: digest of canvas {} failed: {e}", due.canvas_id);
+                            continue;
+                        }
+                    };
+                    // quiet canvases are checked again next run, a compacted log starts over at its end
+                    if fold.digest.is_none() && fold.last_seq >= due.after_seq {
+                        continue;
+                    }
+
+                    let recorded = record_canvas_digest_recipient
+                        .send(RecordCanvasDigestMessage {
+                            canvas_id: due.canvas_id.clone(),
+                            seq: fold.last_seq,
+                            now: msg.now,
+                        })
+                        .await;
+                    match recorded {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            println!(
+                                "WARNING: digest of canvas {} not recorded: {e}",
+                                due.canvas_id
+                            );
+                            continue;
+                        }
+                        Err(e) => {
+                            println!(
+                                "WARNING: digest of canvas {} not recorded: {e}",
+                                due.canvas_id
+                            );
+                            continue;
+                        }
+                    }
+
+                    if let Some(digest) = fold.digest {
+                        notifier.canvas_digest(&due.owner_id, &digest);
+                        sent.push(digest);
+                    }
+                }
+                sent
+            }
+            .into_actor(self),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(id: &str, user_id: Option<&str>) -> String {
+        let user_id = user_id.map_or(String::new(), |user_id| {
+            format!(",\"userId\":\"{user_id}\"")
+        });
+        format!(
+            "{{\"type\":\"ShapeAdded\",\"origin\":\"u\",\"timestamp\":1{user_id},\"shape\":{{\"type\":\"Line\",\"id\":\"{id}\",\"temporary\":false,\"borderColor\":\"#000000\",\"fillColor\":\"#000000\",\"from\":{{\"x\":0,\"y\":0}},\"to\":{{\"x\":1,\"y\":1}}}}}}"
+        )
+    }
+
+    fn due(after_seq: u64) -> DueDigest {
+        DueDigest {
+            canvas_id: "c1".to_string(),
+            canvas_name: "Canvas".to_string(),
+            owner_id: "owner".to_string(),
+            after_seq,
+        }
+    }
+
+    #[test]
+    fn test_digests_are_due_once_per_period() {
+        let mark = DigestMark {
+            seq: 3,
+            timestamp: 1000,
+        };
+        assert!(!DigestMark::is_due(None, DigestFrequency::Off, 0));
+        assert!(DigestMark::is_due(None, DigestFrequency::Daily, 0));
+        assert!(!DigestMark::is_due(
+            Some(&mark),
+            DigestFrequency::Daily,
+            1000 + DAY_MS - 1
+        ));
+        assert!(DigestMark::is_due(
+            Some(&mark),
+            DigestFrequency::Daily,
+            1000 + DAY_MS
+        ));
+        assert!(!DigestMark::is_due(
+            Some(&mark),
+            DigestFrequency::Weekly,
+            1000 + DAY_MS
+        ));
+    }
+
+    #[test]
+    fn test_fold_covers_the_lines_after_the_last_digest() {
+        let path = std::env::temp_dir()
+            .join(format!("{}-digest.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let lines = [
+            added("l1", Some("alice")),
+            added("l2", Some("bob")),
+            r#"{"type":"ShapeRemoved","origin":"u","timestamp":2,"shapeId":"l1"}"#.to_string(),
+            added("l3", None),
+            r#"{"type":"CommentAdded","origin":"u","timestamp":3,"commentId":"k1","shapeId":"l2","text":"nice","userId":"alice"}"#.to_string(),
+        ];
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let fold = fold_log(&due(0), &path).unwrap();
+        let digest = fold.digest.unwrap();
+        assert_eq!((digest.from_seq, digest.to_seq), (1, 5));
+        assert_eq!(digest.shapes_added, 3);
+        assert_eq!(digest.shapes_removed, 1);
+        assert_eq!(digest.contributors.len(), 2);
+        assert_eq!(digest.contributors["alice"].comments_added, 1);
+
+        let digest = fold_log(&due(2), &path).unwrap().digest.unwrap();
+        assert_eq!((digest.from_seq, digest.to_seq), (3, 5));
+        assert_eq!(digest.shapes_added, 1);
+        assert_eq!(digest.contributors.keys().collect::<Vec<_>>(), ["alice"]);
+
+        // nothing new, and a log compacted below the last digest
+        assert_eq!(fold_log(&due(5), &path).unwrap().digest, None);
+        let compacted = fold_log(&due(9), &path).unwrap();
+        assert_eq!((compacted.last_seq, compacted.digest), (5, None));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,460 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+use super::{
+    events::{CanvasEvents, Shape},
+    palette::{self, InvalidPalette, PaletteColor},
+    provenance::Provenance,
+    replay::{CanvasShapeState, CREATED_BY_KEY, CREATED_BY_NAME_KEY},
+    store::{self, CanvasMetadata, CanvasSettings, InvalidMetadata},
+    validation::{self, EventRejection, ShapeLimits},
+};
+use crate::{
+    messages::{Message, MessageKey},
+    userstore::UserId,
+};
+
+// Content of a canvas independent of its eventlog, exported, imported and duplicated as one document
+// from_state folds a replayed canvas into the document, materialize turns it back into the minimal eventlog of a new canvas
+// Shapes carry their z position, a document listing its shapes in another order still stacks them the same
+// Creators, comments and the history of the shapes stay with the original canvas, the new canvas starts its own
+// Canvases have no shape groups yet, they are added with the next version of the document
+
+/// Version written into new documents, documents of a newer version are refused
+pub const DOCUMENT_VERSION: u32 = 1;
+
+/// Origin of the events written by materialize, no session drew the shapes
+pub const DOCUMENT_ORIGIN: &str = "document";
+
+/// Shape of the document with its position
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DocumentShape {
+    /// position from back to front, starting at 0
+    pub z: usize,
+    /// shape as replayed, without its creator
+    #[schema(value_type = Object)]
+    pub shape: Value,
+}
+
+/// Settings of the canvas that belong to its content
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct DocumentSettings {
+    #[serde(default)]
+    pub grid_size: Option<u32>,
+    #[serde(default)]
+    pub snap_enabled: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<PaletteColor>,
+    #[serde(default)]
+    pub enforce_palette: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CanvasDocument {
+    /// DOCUMENT_VERSION of the server that wrote the document
+    pub version: u32,
+    pub shapes: Vec<DocumentShape>,
+    #[serde(default)]
+    pub settings: DocumentSettings,
+    /// left out for canvases without metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CanvasMetadata>,
+    /// last change of every shape keyed by its original id, only exported on request and never imported
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// New id of a shape of the document, in the order the shapes are stacked
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ShapeIdMapping {
+    pub from: String,
+    pub to: String,
+}
+
+/// Events writing the shapes of a document into a fresh eventlog, the header is added by binding::rebind
+#[derive(Debug)]
+pub struct Materialized {
+    pub events: Vec<CanvasEvents>,
+    pub id_mapping: Vec<ShapeIdMapping>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DocumentError {
+    /// version of the document
+    UnsupportedVersion(u32),
+    /// index of the shape in the document that is no shape or reuses the id of another
+    InvalidShape(usize),
+    /// index of the shape in the document and why a client drawing it would be refused
+    RejectedShape(usize, EventRejection),
+    GridSize,
+    Palette(InvalidPalette),
+    Metadata(InvalidMetadata),
+}
+
+impl DocumentError {
+    pub fn message(&self) -> Message {
+        match self {
+            DocumentError::UnsupportedVersion(version) => {
+                Message::new(MessageKey::CanvasDocumentVersion)
+                    .param("version", version)
+                    .param("supported", DOCUMENT_VERSION)
+            }
+            DocumentError::InvalidShape(index) => {
+                Message::new(MessageKey::CanvasDocumentInvalid).param("index", index)
+            }
+            DocumentError::RejectedShape(index, rejection) => {
+                rejection.message().param("index", index)
+            }
+            DocumentError::GridSize => Message::new(MessageKey::CanvasGridSizeInvalid)
+                .param("reason", "invalid_value")
+                .param("field", "grid_size")
+                .param("max", store::MAX_GRID_SIZE),
+            DocumentError::Palette(invalid) => invalid.message(),
+            DocumentError::Metadata(invalid) => Message::new(MessageKey::CanvasMetadataInvalid)
+                .param("reason", "invalid_value")
+                .param("field", invalid.field)
+                .param("max", invalid.max),
+        }
+    }
+}
+
+/// Shape without the keys replay adds for its creator
+fn content(shape: &Value) -> Value {
+    let mut shape = shape.clone();
+    if let Some(object) = shape.as_object_mut() {
+        object.remove(CREATED_BY_KEY);
+        object.remove(CREATED_BY_NAME_KEY);
+    }
+    shape
+}
+
+/// Numbers are compared by value, a radius of 3 is the radius 3.0 the shape serializes
+fn same(known: &Value, value: &Value) -> bool {
+    match (known, value) {
+        (Value::Number(known), Value::Number(value)) => known.as_f64() == value.as_f64(),
+        _ => known == value,
+    }
+}
+
+impl CanvasDocument {
+    /// Folds the replayed shapes and the settings of a canvas into its document, provenance is left out
+    pub fn from_state(state: &CanvasShapeState, settings: &CanvasSettings) -> Self {
+        Self {
+            version: DOCUMENT_VERSION,
+            shapes: state
+                .shapes
+                .iter()
+                .enumerate()
+                .map(|(z, shape)| DocumentShape {
+                    z,
+                    shape: content(shape),
+                })
+                .collect(),
+            settings: DocumentSettings {
+                grid_size: settings.grid_size,
+                snap_enabled: settings.snap_enabled,
+                palette: settings.palette.clone(),
+                enforce_palette: settings.enforce_palette,
+            },
+            metadata: settings.metadata.clone(),
+            provenance: None,
+        }
+    }
+
+    /// Checks the version and normalizes the settings and metadata like the settings and palette endpoints
+    pub fn normalize(mut self) -> Result<Self, DocumentError> {
+        if self.version > DOCUMENT_VERSION {
+            return Err(DocumentError::UnsupportedVersion(self.version));
+        }
+        let grid_size_valid = self
+            .settings
+            .grid_size
+            .map_or(!self.settings.snap_enabled, |grid_size| {
+                (1..=store::MAX_GRID_SIZE).contains(&grid_size)
+            });
+        if !grid_size_valid {
+            return Err(DocumentError::GridSize);
+        }
+        self.settings.palette =
+            palette::normalize_palette(self.settings.palette).map_err(DocumentError::Palette)?;
+        self.metadata = match self.metadata {
+            Some(metadata) => {
+                store::normalize_metadata(metadata).map_err(DocumentError::Metadata)?
+            }
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Settings the new canvas starts with, the fields outside of the document are left at their defaults
+    pub fn canvas_settings(&self) -> CanvasSettings {
+        CanvasSettings {
+            grid_size: self.settings.grid_size,
+            snap_enabled: self.settings.snap_enabled,
+            ..CanvasSettings::default()
+        }
+    }
+
+    ///
+    /// Minimal events adding the shapes to a fresh eventlog, back to front so no z change is needed
+    /// Every shape gets a new id, keys merged in by updates that its type does not know follow in a ShapeUpdated
+    /// The shapes are checked like shapes drawn by the initiator, who is recorded as their creator
+    ///
+    pub fn materialize(
+        &self,
+        initiator_id: &UserId,
+        timestamp: u64,
+        limits: &ShapeLimits,
+    ) -> Result<Materialized, DocumentError> {
+        let mut stacked: Vec<(usize, &DocumentShape)> = self.shapes.iter().enumerate().collect();
+        // stable, shapes sharing a position keep the order of the document
+        stacked.sort_by_key(|(_, shape)| shape.z);
+
+        let mut events = Vec::with_capacity(self.shapes.len());
+        let mut id_mapping = Vec::with_capacity(self.shapes.len());
+        let mut seen = HashSet::new();
+        for (index, document_shape) in stacked {
+            let Value::Object(mut object) = content(&document_shape.shape) else {
+                return Err(DocumentError::InvalidShape(index));
+            };
+            let from = match object.get("id").and_then(Value::as_str) {
+                Some(from) if seen.insert(from.to_string()) => from.to_string(),
+                _ => return Err(DocumentError::InvalidShape(index)),
+            };
+            let to = nanoid::nanoid!();
+            object.insert("id".to_string(), Value::String(to.clone()));
+
+            let shape: Shape = serde_json::from_value(Value::Object(object.clone()))
+                .map_err(|_| DocumentError::InvalidShape(index))?;
+            let known = serde_json::to_value(&shape).unwrap_or_default();
+            let mut unknown: Map<String, Value> = object
+                .into_iter()
+                .filter(|(key, value)| !known.get(key).is_some_and(|known| same(known, value)))
+                .collect();
+
+            let mut shape_events = vec![CanvasEvents::ShapeAdded {
+                origin: DOCUMENT_ORIGIN.to_string(),
+                timestamp,
+                shape,
+                userId: Some(initiator_id.clone()),
+            }];
+            if !unknown.is_empty() {
+                unknown.insert("id".to_string(), Value::String(to.clone()));
+                shape_events.push(CanvasEvents::ShapeUpdated {
+                    origin: DOCUMENT_ORIGIN.to_string(),
+                    timestamp,
+                    shape: Value::Object(unknown),
+                    userId: Some(initiator_id.clone()),
+                });
+            }
+            for event in &shape_events {
+                validation::validate_event(event, limits)
+                    .map_err(|rejection| DocumentError::RejectedShape(index, rejection))?;
+            }
+            events.extend(shape_events);
+            id_mapping.push(ShapeIdMapping { from, to });
+        }
+        Ok(Materialized { events, id_mapping })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{binding, replay, transfer};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// Deterministic pseudo random numbers, every seed is its own canvas
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    fn random_shape(rng: &mut Rng, id: &str) -> Value {
+        let color = ["#000000", "#ff8800", "transparent"][rng.below(3)];
+        let point = |rng: &mut Rng| json!({ "x": rng.below(500), "y": rng.below(500) });
+        let mut shape = match rng.below(3) {
+            0 => json!({ "type": "Line", "from": point(rng), "to": point(rng) }),
+            1 => json!({ "type": "Circle", "center": point(rng), "radius": rng.below(50) }),
+            _ => json!({ "type": "Rectangle", "from": point(rng), "to": point(rng) }),
+        };
+        let object = shape.as_object_mut().unwrap();
+        object.insert("id".to_string(), json!(id));
+        object.insert("temporary".to_string(), json!(false));
+        object.insert("borderColor".to_string(), json!(color));
+        object.insert("fillColor".to_string(), json!(color));
+        shape
+    }
+
+    /// Canvas drawn by random adds, updates, z changes and removals
+    fn random_canvas(seed: u64) -> Vec<CanvasEvents> {
+        let mut rng = Rng(seed);
+        let mut live: Vec<String> = Vec::new();
+        let mut events = vec![binding::header("aaaaaaaaaaaa", 0)];
+        for step in 0..60 {
+            let event = match (live.is_empty(), rng.below(5)) {
+                (true, _) | (false, 0 | 1) => {
+                    let id = format!("s{step}");
+                    live.push(id.clone());
+                    json!({ "type": "ShapeAdded", "origin": "s1", "timestamp": step,
+                        "shape": random_shape(&mut rng, &id), "userId": "u1" })
+                }
+                (false, 2) => {
+                    let id = &live[rng.below(live.len())];
+                    // keys the shape types don't know survive as well
+                    json!({ "type": "ShapeUpdated", "origin": "s1", "timestamp": step,
+                        "shape": { "id": id, "borderColor": "#ff8800", "label": step } })
+                }
+                (false, 3) => {
+                    let id = &live[rng.below(live.len())];
+                    let z = match rng.below(3) {
+                        0 => json!({ "value": 1, "isInfinity": true }),
+                        1 => json!({ "value": -1, "isInfinity": true }),
+                        _ => json!({ "value": rng.below(5) as i64 - 2, "isInfinity": false }),
+                    };
+                    json!({ "type": "ShapeZChanged", "origin": "s1", "timestamp": step,
+                        "shapeId": id, "z": z })
+                }
+                (false, _) => {
+                    let id = live.remove(rng.below(live.len()));
+                    json!({ "type": "ShapeRemoved", "origin": "s1", "timestamp": step, "shapeId": id })
+                }
+            };
+            events.push(serde_json::from_value(event).unwrap());
+        }
+        events
+    }
+
+    fn document_of(path: &str, settings: &CanvasSettings) -> CanvasDocument {
+        let replay = replay::replay_log(path, None).unwrap();
+        CanvasDocument::from_state(&replay.state, settings)
+    }
+
+    /// Document with the shape ids renamed
+    fn renamed(document: &CanvasDocument, ids: &HashMap<String, String>) -> CanvasDocument {
+        let mut document = document.clone();
+        for shape in &mut document.shapes {
+            let id = shape.shape["id"].as_str().unwrap().to_string();
+            shape.shape["id"] = json!(ids[&id]);
+        }
+        document
+    }
+
+    #[test]
+    fn test_documents_round_trip_through_new_eventlogs() {
+        let settings = CanvasSettings {
+            grid_size: Some(20),
+            snap_enabled: true,
+            palette: vec![PaletteColor {
+                name: "Ink".to_string(),
+                color: "#000000".to_string(),
+            }],
+            ..CanvasSettings::default()
+        };
+        let original = std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let copy = format!("{original}.copy");
+
+        for seed in 1..=50 {
+            crate::persistence::rewrite_event_log(&original, &random_canvas(seed)).unwrap();
+            let exported = document_of(&original, &settings);
+            // the export travels as JSON
+            let imported: CanvasDocument =
+                serde_json::from_str(&serde_json::to_string(&exported).unwrap()).unwrap();
+            let imported = imported.normalize().unwrap();
+
+            let materialized = imported
+                .materialize(&"u2".to_string(), 100, &ShapeLimits::default())
+                .unwrap();
+            transfer::write_import(&copy, "bbbbbbbbbbbb", materialized.events, 100).unwrap();
+            let copied_settings = CanvasSettings {
+                palette: imported.settings.palette.clone(),
+                enforce_palette: imported.settings.enforce_palette,
+                metadata: imported.metadata.clone(),
+                ..imported.canvas_settings()
+            };
+            let reexported = document_of(&copy, &copied_settings);
+
+            let ids: HashMap<String, String> = materialized
+                .id_mapping
+                .into_iter()
+                .map(|mapping| (mapping.from, mapping.to))
+                .collect();
+            assert_eq!(ids.len(), exported.shapes.len(), "seed {seed}");
+            assert_eq!(renamed(&exported, &ids), reexported, "seed {seed}");
+        }
+        let _ = std::fs::remove_file(original);
+        let _ = std::fs::remove_file(copy);
+    }
+
+    #[test]
+    fn test_shapes_are_stacked_by_their_position() {
+        let shape = |id: &str| random_shape(&mut Rng(7), id);
+        let document = CanvasDocument {
+            version: DOCUMENT_VERSION,
+            shapes: vec![
+                DocumentShape {
+                    z: 2,
+                    shape: shape("front"),
+                },
+                DocumentShape {
+                    z: 0,
+                    shape: shape("back"),
+                },
+                DocumentShape {
+                    z: 1,
+                    shape: shape("middle"),
+                },
+            ],
+            settings: DocumentSettings::default(),
+            metadata: None,
+            provenance: None,
+        };
+        let materialized = document
+            .materialize(&"u1".to_string(), 1, &ShapeLimits::default())
+            .unwrap();
+        let order: Vec<&str> = materialized
+            .id_mapping
+            .iter()
+            .map(|mapping| mapping.from.as_str())
+            .collect();
+        assert_eq!(order, vec!["back", "middle", "front"]);
+
+        // the shape stacked above the other one is refused
+        let mut reused = document.clone();
+        reused.shapes[2].shape = shape("back");
+        assert_eq!(
+            reused
+                .materialize(&"u1".to_string(), 1, &ShapeLimits::default())
+                .unwrap_err(),
+            DocumentError::InvalidShape(2)
+        );
+        let mut unknown = document.clone();
+        unknown.shapes[0].shape["type"] = json!("Hexagon");
+        assert_eq!(
+            unknown
+                .materialize(&"u1".to_string(), 1, &ShapeLimits::default())
+                .unwrap_err(),
+            DocumentError::InvalidShape(0)
+        );
+        let newer = CanvasDocument {
+            version: DOCUMENT_VERSION + 1,
+            ..document
+        };
+        assert_eq!(
+            newer.normalize().unwrap_err(),
+            DocumentError::UnsupportedVersion(DOCUMENT_VERSION + 1)
+        );
+    }
+}
@@ -3,35 +3,130 @@ use actix_web::{
     http::header::ContentType,
     HttpResponse,
 };
-use derive_more::{Display, Error};
+use derive_more::Error;
+use std::fmt;
 
-#[derive(Debug, Display, Error)]
+use super::store::{CanvasState, CanvasStateAction};
+use crate::messages::{Locale, Message, MessageKey};
+
+#[derive(Debug, Error)]
 pub enum CanvasStoreError {
-    #[display("Canvas nicht gefunden")]
     CanvasNotFound,
-    // #[display("User not found")]
     // UserNotFound,
-    #[display("Zugriff verweigert: {}", _0)]
-    AccessDenied(#[error(ignore)] String),
-    #[display("Daten konnten nicht gespeichert werden")]
-    PersistenceFailed,
+    /// carries the reason the change was denied
+    AccessDenied(#[error(not(source))] MessageKey),
+    /// carries the internal reason, logged but never part of a response
+    PersistenceFailed(#[error(not(source))] String),
+    /// no unused canvas id found, practically unreachable with nanoid
+    IdGenerationFailed,
+    /// canvas was changed since the client loaded it, carries the current values to re-prompt
+    VersionConflict {
+        current_version: u64,
+        state: CanvasState,
+    },
+    /// action can't be taken in the state the canvas is in, see store::transition
+    InvalidTransition {
+        from: CanvasState,
+        action: CanvasStateAction,
+    },
+    /// store is read-only until its persistence caught up, see mailbox::DegradedMode
+    Degraded,
+    /// user has no pending access request on the canvas
+    AccessRequestNotFound,
+    /// user asked for access to a canvas it has access to
+    AlreadyMember,
+    /// owner already has a canvas with the name, carries the name, see CanvasStore::with_unique_names
+    DuplicateName(#[error(not(source))] String),
+}
+
+impl CanvasStoreError {
+    pub fn persistence(reason: impl fmt::Display) -> Self {
+        CanvasStoreError::PersistenceFailed(reason.to_string())
+    }
+
+    /// Machine-readable code, part of JSON responses and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            CanvasStoreError::CanvasNotFound => "canvas_not_found",
+            CanvasStoreError::AccessDenied(_) => "canvas_access_denied",
+            CanvasStoreError::PersistenceFailed(_) => "canvas_persistence_failed",
+            CanvasStoreError::IdGenerationFailed => "canvas_id_generation_failed",
+            CanvasStoreError::VersionConflict { .. } => "canvas_version_conflict",
+            CanvasStoreError::InvalidTransition { .. } => "canvas_invalid_transition",
+            CanvasStoreError::Degraded => "canvas_store_read_only",
+            CanvasStoreError::AccessRequestNotFound => "canvas_access_request_not_found",
+            CanvasStoreError::AlreadyMember => "canvas_already_member",
+            CanvasStoreError::DuplicateName(_) => "canvas_duplicate_name",
+        }
+    }
+
+    /// Internal diagnostic detail, see messages::LocalizeService
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            CanvasStoreError::PersistenceFailed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Localizable message, rendered per request by the LocalizeService
+    pub fn message(&self) -> Message {
+        match self {
+            CanvasStoreError::CanvasNotFound => Message::new(MessageKey::CanvasNotFound),
+            CanvasStoreError::AccessDenied(reason) => Message::new(*reason),
+            CanvasStoreError::PersistenceFailed(_) => Message::new(MessageKey::PersistenceFailed),
+            CanvasStoreError::IdGenerationFailed => Message::new(MessageKey::CanvasCreateFailed),
+            CanvasStoreError::VersionConflict {
+                current_version,
+                state,
+            } => Message::new(MessageKey::CanvasVersionConflict)
+                .param("version", current_version)
+                .param("state", format!("{state:?}")),
+            CanvasStoreError::InvalidTransition { from, action } => {
+                Message::new(MessageKey::CanvasTransitionInvalid)
+                    .param("state", format!("{from:?}"))
+                    .param("action", format!("{action:?}").to_lowercase())
+            }
+            CanvasStoreError::Degraded => Message::new(MessageKey::StoreReadOnly),
+            CanvasStoreError::AccessRequestNotFound => {
+                Message::new(MessageKey::AccessRequestNotFound)
+            }
+            CanvasStoreError::AlreadyMember => Message::new(MessageKey::AccessRequestAlreadyMember),
+            CanvasStoreError::DuplicateName(name) => {
+                Message::new(MessageKey::CanvasNameTaken).param("name", name)
+            }
+        }
+    }
+}
+
+/// Rendered in the default locale without the detail, responses are localized by the LocalizeService
+impl fmt::Display for CanvasStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message().render(Locale::default()))
+    }
 }
 
 impl error::ResponseError for CanvasStoreError {
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
         HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
+            .insert_header(ContentType::plaintext())
             .body(self.to_string())
     }
 
     fn status_code(&self) -> actix_web::http::StatusCode {
         match *self {
-            CanvasStoreError::CanvasNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            CanvasStoreError::CanvasNotFound | CanvasStoreError::AccessRequestNotFound => {
+                actix_web::http::StatusCode::NOT_FOUND
+            }
             // CanvasStoreError::UserNotFound => actix_web::http::StatusCode::NOT_FOUND,
             CanvasStoreError::AccessDenied(_) => actix_web::http::StatusCode::FORBIDDEN,
-            CanvasStoreError::PersistenceFailed => {
+            CanvasStoreError::PersistenceFailed(_) | CanvasStoreError::IdGenerationFailed => {
                 actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
             }
+            CanvasStoreError::VersionConflict { .. }
+            | CanvasStoreError::InvalidTransition { .. }
+            | CanvasStoreError::AlreadyMember
+            | CanvasStoreError::DuplicateName(_) => actix_web::http::StatusCode::CONFLICT,
+            CanvasStoreError::Degraded => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
@@ -2,20 +2,119 @@
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use utoipa::ToSchema;
 
-use crate::userstore::UserId;
+use crate::{
+    messages::{Locale, Message, MessageKey},
+    userstore::UserId,
+};
 
 use super::{
+    attributes::ShapeAttributes,
+    palette::PaletteColor,
     server::Msg,
-    store::{AccessLevel, CanvasState},
+    store::{legacy_voice_behavior_default, AccessLevel, CanvasState},
 };
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Point2D {
     pub x: i32, // We will never use sub-pixel precision, but technically js uses floats
     pub y: i32, // We will never use sub-pixel precision
 }
 
+/// Axis aligned area of the canvas, corners in any order, both inclusive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub from: Point2D,
+    pub to: Point2D,
+}
+
+impl Bounds {
+    /// Corners as (min, max)
+    fn normalized(&self) -> (Point2D, Point2D) {
+        (
+            Point2D {
+                x: self.from.x.min(self.to.x),
+                y: self.from.y.min(self.to.y),
+            },
+            Point2D {
+                x: self.from.x.max(self.to.x),
+                y: self.from.y.max(self.to.y),
+            },
+        )
+    }
+
+    /// Whether the areas share at least a point, touching edges count
+    pub fn intersects(&self, other: &Bounds) -> bool {
+        let (min, max) = self.normalized();
+        let (other_min, other_max) = other.normalized();
+        min.x <= other_max.x && other_min.x <= max.x && min.y <= other_max.y && other_min.y <= max.y
+    }
+
+    /// Smallest area containing both
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        let (min, max) = self.normalized();
+        let (other_min, other_max) = other.normalized();
+        Bounds {
+            from: Point2D {
+                x: min.x.min(other_min.x),
+                y: min.y.min(other_min.y),
+            },
+            to: Point2D {
+                x: max.x.max(other_max.x),
+                y: max.y.max(other_max.y),
+            },
+        }
+    }
+}
+
+impl From<(Point2D, Point2D)> for Bounds {
+    fn from((from, to): (Point2D, Point2D)) -> Self {
+        Bounds { from, to }
+    }
+}
+
+/// Variant of a shape, the type tag of its JSON
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema,
+)]
+pub enum ShapeType {
+    Line,
+    Circle,
+    Rectangle,
+    Triangle,
+    Path,
+}
+
+impl ShapeType {
+    /// Every variant of Shape, kept in sync by a test
+    pub const ALL: [ShapeType; 5] = [
+        ShapeType::Line,
+        ShapeType::Circle,
+        ShapeType::Rectangle,
+        ShapeType::Triangle,
+        ShapeType::Path,
+    ];
+
+    /// Name as in the type tag
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShapeType::Line => "Line",
+            ShapeType::Circle => "Circle",
+            ShapeType::Rectangle => "Rectangle",
+            ShapeType::Triangle => "Triangle",
+            ShapeType::Path => "Path",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|shape_type| shape_type.name() == name)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum Shape {
@@ -24,6 +123,9 @@ pub enum Shape {
         temporary: bool,
         borderColor: String,
         fillColor: String,
+        /// extension attributes, see attributes.rs, missing in logs written before they existed
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: ShapeAttributes,
 
         from: Point2D,
         to: Point2D,
@@ -33,6 +135,9 @@ pub enum Shape {
         temporary: bool,
         borderColor: String,
         fillColor: String,
+        /// extension attributes, see attributes.rs, missing in logs written before they existed
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: ShapeAttributes,
 
         center: Point2D,
         radius: f32,
@@ -42,6 +147,9 @@ pub enum Shape {
         temporary: bool,
         borderColor: String,
         fillColor: String,
+        /// extension attributes, see attributes.rs, missing in logs written before they existed
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: ShapeAttributes,
 
         from: Point2D,
         to: Point2D,
@@ -51,11 +159,27 @@ pub enum Shape {
         temporary: bool,
         borderColor: String,
         fillColor: String,
+        /// extension attributes, see attributes.rs, missing in logs written before they existed
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: ShapeAttributes,
 
         p1: Point2D,
         p2: Point2D,
         p3: Point2D,
     },
+    /// Freehand stroke, persisted strokes are simplified by the server
+    Path {
+        id: String,
+        temporary: bool,
+        borderColor: String,
+        fillColor: String,
+        /// extension attributes, see attributes.rs, missing in logs written before they existed
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        attributes: ShapeAttributes,
+
+        points: Vec<Point2D>,
+        closed: bool,
+    },
 }
 
 impl Shape {
@@ -65,6 +189,7 @@ impl Shape {
             Shape::Circle { id, .. } => id,
             Shape::Rectangle { id, .. } => id,
             Shape::Triangle { id, .. } => id,
+            Shape::Path { id, .. } => id,
         }
     }
 
@@ -74,8 +199,105 @@ impl Shape {
             Shape::Circle { temporary, .. } => *temporary,
             Shape::Rectangle { temporary, .. } => *temporary,
             Shape::Triangle { temporary, .. } => *temporary,
+            Shape::Path { temporary, .. } => *temporary,
+        }
+    }
+
+    pub fn attributes(&self) -> &ShapeAttributes {
+        match self {
+            Shape::Line { attributes, .. }
+            | Shape::Circle { attributes, .. }
+            | Shape::Rectangle { attributes, .. }
+            | Shape::Triangle { attributes, .. }
+            | Shape::Path { attributes, .. } => attributes,
+        }
+    }
+
+    /// Valid values of the type tag, e.g. for the allowed shape types of a canvas
+    pub fn type_names() -> impl Iterator<Item = &'static str> {
+        ShapeType::ALL.iter().map(ShapeType::name)
+    }
+
+    pub fn shape_type(&self) -> ShapeType {
+        match self {
+            Shape::Line { .. } => ShapeType::Line,
+            Shape::Circle { .. } => ShapeType::Circle,
+            Shape::Rectangle { .. } => ShapeType::Rectangle,
+            Shape::Triangle { .. } => ShapeType::Triangle,
+            Shape::Path { .. } => ShapeType::Path,
+        }
+    }
+
+    pub fn border_color(&self) -> &str {
+        match self {
+            Shape::Line { borderColor, .. }
+            | Shape::Circle { borderColor, .. }
+            | Shape::Rectangle { borderColor, .. }
+            | Shape::Triangle { borderColor, .. }
+            | Shape::Path { borderColor, .. } => borderColor,
         }
     }
+
+    pub fn fill_color(&self) -> &str {
+        match self {
+            Shape::Line { fillColor, .. }
+            | Shape::Circle { fillColor, .. }
+            | Shape::Rectangle { fillColor, .. }
+            | Shape::Triangle { fillColor, .. }
+            | Shape::Path { fillColor, .. } => fillColor,
+        }
+    }
+
+    ///
+    /// Smallest axis aligned box containing the shape as (min, max), both corners inclusive
+    /// Circles extend by their radius rounded up, a path without points is the point at the origin
+    ///
+    pub fn bounds(&self) -> (Point2D, Point2D) {
+        let points: Vec<Point2D> = match self {
+            Shape::Line { from, to, .. } | Shape::Rectangle { from, to, .. } => vec![*from, *to],
+            Shape::Triangle { p1, p2, p3, .. } => vec![*p1, *p2, *p3],
+            Shape::Path { points, .. } => points.clone(),
+            Shape::Circle { center, radius, .. } => {
+                // saturating casts, NaN becomes 0
+                let radius = radius.abs().ceil() as i32;
+                return (
+                    Point2D {
+                        x: center.x.saturating_sub(radius),
+                        y: center.y.saturating_sub(radius),
+                    },
+                    Point2D {
+                        x: center.x.saturating_add(radius),
+                        y: center.y.saturating_add(radius),
+                    },
+                );
+            }
+        };
+
+        let Some(first) = points.first() else {
+            let origin = Point2D { x: 0, y: 0 };
+            return (origin, origin);
+        };
+        points.iter().fold((*first, *first), |(min, max), point| {
+            (
+                Point2D {
+                    x: min.x.min(point.x),
+                    y: min.y.min(point.y),
+                },
+                Point2D {
+                    x: max.x.max(point.x),
+                    y: max.y.max(point.y),
+                },
+            )
+        })
+    }
+}
+
+/// Severity of a ServerNotice, lets the client decide how to present it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeLevel {
+    Notice,
+    Warning,
+    Error,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -86,6 +308,9 @@ pub enum CanvasEvents {
         origin: String,
         timestamp: u64,
         shape: Shape,
+        /// creator of the shape, always set by the server, missing in logs written before it was tracked
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
     },
     ShapeRemoved {
         origin: String,
@@ -108,12 +333,20 @@ pub enum CanvasEvents {
         timestamp: u64,
         shapeId: String,
         z: Value, // NOTE: Uses custom serializer in Canvas Appliaction
+        /// editor of the shape, always set by the server, missing in logs written before it was tracked
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
     },
     ShapeUpdated {
         origin: String,
         timestamp: u64,
         shape: Value,
+        /// editor of the shape, always set by the server, missing in logs written before it was tracked
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
     },
+    /// Removes every shape, acts as a barrier when folding the log
+    CanvasCleared { origin: String, timestamp: u64 },
     UserJoined {
         timestamp: u64,
         userId: String,
@@ -135,7 +368,419 @@ pub enum CanvasEvents {
         timestamp: u64,
         state: CanvasState,
         initiatorId: UserId,
+        /// canvas version after the change, clients send it back as expected_version
+        #[serde(default)]
+        version: u64,
+    },
+    /// Grid and snapping settings of the canvas changed, shapes are snapped by the server
+    CanvasSettingsChanged {
+        timestamp: u64,
+        gridSize: Option<u32>,
+        snapEnabled: bool,
+        /// only creators, owners and moderators may change a shape
+        #[serde(default)]
+        shapeOwnershipEnforced: bool,
+        /// Voice draws in active canvases as well, see AccessLevel::can_write_in
+        #[serde(default = "legacy_voice_behavior_default")]
+        legacyVoiceBehavior: bool,
+        /// types of new shapes, empty allows all, clients grey out the other tools
+        #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+        allowedShapeTypes: BTreeSet<ShapeType>,
+        /// owners and moderators may add shapes of every type
+        #[serde(default)]
+        moderatorsBypassShapeTypes: bool,
+        initiatorId: UserId,
+        /// canvas version after the change, clients send it back as expected_version
+        version: u64,
+    },
+    /// Palette of the canvas was replaced, clients update their color pickers, see palette.rs
+    PaletteChanged {
+        timestamp: u64,
+        palette: Vec<PaletteColor>,
+        /// shapes have to use colors of the palette
+        enforcePalette: bool,
+        initiatorId: UserId,
+        /// canvas version after the change, clients send it back as expected_version
+        version: u64,
     },
+    /// Comment anchored to a persisted shape, see comments.rs
+    CommentAdded {
+        origin: String,
+        timestamp: u64,
+        commentId: String,
+        shapeId: String,
+        text: String,
+        /// author of the comment, always set by the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
+    },
+    /// Comment is done, the server resolves the open comments of removed shapes
+    CommentResolved {
+        origin: String,
+        timestamp: u64,
+        commentId: String,
+        /// user resolving the comment, always set by the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
+    },
+    CommentReopened {
+        origin: String,
+        timestamp: u64,
+        commentId: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
+    },
+    /// Only the author, owners and moderators delete a comment
+    CommentDeleted {
+        origin: String,
+        timestamp: u64,
+        commentId: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        userId: Option<UserId>,
+    },
+    /// Client asks for the resolved comments, of a single shape if shapeId is set
+    /// Open comments are part of the initial state, answered on the session of the client only and never persisted
+    ResolvedCommentsRequest {
+        #[serde(default)]
+        timestamp: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        shapeId: Option<String>,
+    },
+    /// First line of every canvas eventlog, binds the log to its canvas, see binding.rs
+    /// Never sent to or accepted from clients
+    CanvasLogHeader { timestamp: u64, canvasId: String },
+    /// User has seen every change of the canvas up to seq, a line of the eventlog
+    /// Persisted by the server as read receipt, never sent to or accepted from clients
+    UserCaughtUp {
+        timestamp: u64,
+        userId: UserId,
+        seq: u64,
+    },
+    /// Name of a user at its first contribution to the canvas, see contributors.rs
+    /// Persisted by the server, never sent to or accepted from clients
+    ContributorSeen {
+        userId: UserId,
+        username: String,
+        /// timestamp in seconds like the other events
+        firstSeen: u64,
+    },
+    /// Feedback of the server, never accepted from clients and never persisted
+    ServerNotice {
+        timestamp: u64,
+        level: NoticeLevel,
+        /// stable message key, e.g. session.cooling_down
+        code: String,
+        message: String,
+        /// operation the notice refers to, lets clients drop acknowledged retries
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        opId: Option<String>,
+        /// free shape id to resend a shape with, if its id was taken
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suggestedId: Option<String>,
+    },
+    /// Client event with opId was persisted, seq is its line in the eventlog of the canvas
+    /// Only sent to the origin session, never accepted from clients and never persisted
+    Ack {
+        timestamp: u64,
+        opId: String,
+        seq: u64,
+        /// operations merged into the persisted line, e.g. the intermediate updates of a drag
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        superseded: Vec<String>,
+    },
+    /// Client event with opId was dropped, code and message as in ServerNotice
+    Nack {
+        timestamp: u64,
+        opId: String,
+        code: String,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suggestedId: Option<String>,
+    },
+    /// First message of a registered session, the feature flags resolved for its user and canvas
+    /// Only sent by the server, never persisted
+    ServerHello {
+        timestamp: u64,
+        sessionId: String,
+        flags: BTreeMap<String, bool>,
+    },
+    /// Part of the initial state of a new session, seq counts from 1 to total
+    /// Only sent by the server, clients can render progressively and show the progress
+    InitialStateChunk {
+        timestamp: u64,
+        seq: u32,
+        total: u32,
+        events: Vec<CanvasEvents>,
+        /// server clock in milliseconds, a first estimate of the offset before a TimeSyncRequest
+        #[serde(default)]
+        serverTimeMs: u64,
+    },
+    /// Client asks for the server time, times are unix timestamps in milliseconds
+    /// Answered on the session of the client only, never broadcast and never persisted
+    TimeSyncRequest { clientTime: u64 },
+    /// NTP-style answer, the client computes its clock offset and the round trip from the four times
+    /// Only sent by the server
+    TimeSyncResponse {
+        clientTime: u64,
+        serverReceiveTime: u64,
+        serverSendTime: u64,
+    },
+    /// Client asks the server to sync the eventlog to disk, e.g. before the laptop is closed
+    /// Answered with SaveStateChanged, never broadcast and never persisted
+    FlushRequest {
+        #[serde(default)]
+        timestamp: u64,
+    },
+    /// Eventlog of the canvas is synced to disk up to the line flushedSeq
+    /// Changes acknowledged with a higher seq are still being saved, never accepted from clients and never persisted
+    SaveStateChanged { timestamp: u64, flushedSeq: u64 },
+    /// Area of the canvas the session shows, shape events outside of it may be skipped, see viewport.rs
+    /// Answered with the shapes that became visible, never broadcast and never persisted
+    ViewportChanged {
+        origin: String,
+        #[serde(default)]
+        timestamp: u64,
+        bounds: Bounds,
+    },
+}
+
+/// Events per InitialStateChunk, a canvas with thousands of shapes is sent in a few frames
+pub const INITIAL_STATE_CHUNK_SIZE: usize = 200;
+
+/// Borrowing twin of CanvasEvents::InitialStateChunk, the eventlog is serialized without cloning it
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "InitialStateChunk")]
+struct InitialStateChunkRef<'a, T> {
+    timestamp: u64,
+    seq: u32,
+    total: u32,
+    events: &'a [T],
+    serverTimeMs: u64,
+}
+
+/// Serializes the eventlog into InitialStateChunk messages
+/// An empty eventlog still produces one chunk, the client knows the initial state is complete
+/// Redacted eventlogs are passed as already redacted values
+pub fn initial_state_chunks<T: Serialize>(
+    timestamp: u64,
+    server_time_ms: u64,
+    events: &[T],
+) -> Result<Vec<Msg>, serde_json::Error> {
+    let mut chunks: Vec<&[T]> = events.chunks(INITIAL_STATE_CHUNK_SIZE).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let total = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .zip(1..)
+        .map(|(events, seq)| {
+            serde_json::to_string(&InitialStateChunkRef {
+                timestamp,
+                seq,
+                total,
+                events,
+                serverTimeMs: server_time_ms,
+            })
+        })
+        .collect()
+}
+
+/// Event as sent by a client
+/// opId identifies an operation across retries, it is not persisted
+#[derive(Deserialize, Debug)]
+pub struct ClientEvent {
+    #[serde(default)]
+    pub opId: Option<String>,
+    #[serde(flatten)]
+    pub event: CanvasEvents,
+}
+
+impl CanvasEvents {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            CanvasEvents::ShapeAdded { timestamp, .. }
+            | CanvasEvents::ShapeRemoved { timestamp, .. }
+            | CanvasEvents::ShapeSelected { timestamp, .. }
+            | CanvasEvents::ShapeDeselected { timestamp, .. }
+            | CanvasEvents::ShapeZChanged { timestamp, .. }
+            | CanvasEvents::ShapeUpdated { timestamp, .. }
+            | CanvasEvents::CanvasCleared { timestamp, .. }
+            | CanvasEvents::UserJoined { timestamp, .. }
+            | CanvasEvents::UserLeft { timestamp, .. }
+            | CanvasEvents::UserAccessLevelChanged { timestamp, .. }
+            | CanvasEvents::CanvasStateChanged { timestamp, .. }
+            | CanvasEvents::CanvasSettingsChanged { timestamp, .. }
+            | CanvasEvents::PaletteChanged { timestamp, .. }
+            | CanvasEvents::CommentAdded { timestamp, .. }
+            | CanvasEvents::CommentResolved { timestamp, .. }
+            | CanvasEvents::CommentReopened { timestamp, .. }
+            | CanvasEvents::CommentDeleted { timestamp, .. }
+            | CanvasEvents::ResolvedCommentsRequest { timestamp, .. }
+            | CanvasEvents::CanvasLogHeader { timestamp, .. }
+            | CanvasEvents::UserCaughtUp { timestamp, .. }
+            | CanvasEvents::ServerNotice { timestamp, .. }
+            | CanvasEvents::Ack { timestamp, .. }
+            | CanvasEvents::Nack { timestamp, .. }
+            | CanvasEvents::ServerHello { timestamp, .. }
+            | CanvasEvents::InitialStateChunk { timestamp, .. }
+            | CanvasEvents::FlushRequest { timestamp }
+            | CanvasEvents::SaveStateChanged { timestamp, .. }
+            | CanvasEvents::ViewportChanged { timestamp, .. } => *timestamp,
+            CanvasEvents::ContributorSeen { firstSeen, .. } => *firstSeen,
+            CanvasEvents::TimeSyncRequest { clientTime } => *clientTime,
+            CanvasEvents::TimeSyncResponse { serverSendTime, .. } => *serverSendTime,
+        }
+    }
+
+    /// Replaces the timestamp, the server stamps the events of clients with its own clock
+    pub fn restamp(&mut self, stamp: u64) {
+        match self {
+            CanvasEvents::ShapeAdded { timestamp, .. }
+            | CanvasEvents::ShapeRemoved { timestamp, .. }
+            | CanvasEvents::ShapeSelected { timestamp, .. }
+            | CanvasEvents::ShapeDeselected { timestamp, .. }
+            | CanvasEvents::ShapeZChanged { timestamp, .. }
+            | CanvasEvents::ShapeUpdated { timestamp, .. }
+            | CanvasEvents::CanvasCleared { timestamp, .. }
+            | CanvasEvents::UserJoined { timestamp, .. }
+            | CanvasEvents::UserLeft { timestamp, .. }
+            | CanvasEvents::UserAccessLevelChanged { timestamp, .. }
+            | CanvasEvents::CanvasStateChanged { timestamp, .. }
+            | CanvasEvents::CanvasSettingsChanged { timestamp, .. }
+            | CanvasEvents::PaletteChanged { timestamp, .. }
+            | CanvasEvents::CommentAdded { timestamp, .. }
+            | CanvasEvents::CommentResolved { timestamp, .. }
+            | CanvasEvents::CommentReopened { timestamp, .. }
+            | CanvasEvents::CommentDeleted { timestamp, .. }
+            | CanvasEvents::ResolvedCommentsRequest { timestamp, .. }
+            | CanvasEvents::CanvasLogHeader { timestamp, .. }
+            | CanvasEvents::UserCaughtUp { timestamp, .. }
+            | CanvasEvents::ServerNotice { timestamp, .. }
+            | CanvasEvents::Ack { timestamp, .. }
+            | CanvasEvents::Nack { timestamp, .. }
+            | CanvasEvents::ServerHello { timestamp, .. }
+            | CanvasEvents::InitialStateChunk { timestamp, .. }
+            | CanvasEvents::FlushRequest { timestamp }
+            | CanvasEvents::SaveStateChanged { timestamp, .. }
+            | CanvasEvents::ViewportChanged { timestamp, .. } => *timestamp = stamp,
+            // times of the time sync and first sightings are not stamps
+            CanvasEvents::ContributorSeen { .. }
+            | CanvasEvents::TimeSyncRequest { .. }
+            | CanvasEvents::TimeSyncResponse { .. } => {}
+        }
+    }
+
+    /// Type of the event as it is serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CanvasEvents::ShapeAdded { .. } => "ShapeAdded",
+            CanvasEvents::ShapeRemoved { .. } => "ShapeRemoved",
+            CanvasEvents::ShapeSelected { .. } => "ShapeSelected",
+            CanvasEvents::ShapeDeselected { .. } => "ShapeDeselected",
+            CanvasEvents::ShapeZChanged { .. } => "ShapeZChanged",
+            CanvasEvents::ShapeUpdated { .. } => "ShapeUpdated",
+            CanvasEvents::CanvasCleared { .. } => "CanvasCleared",
+            CanvasEvents::UserJoined { .. } => "UserJoined",
+            CanvasEvents::UserLeft { .. } => "UserLeft",
+            CanvasEvents::UserAccessLevelChanged { .. } => "UserAccessLevelChanged",
+            CanvasEvents::CanvasStateChanged { .. } => "CanvasStateChanged",
+            CanvasEvents::CanvasSettingsChanged { .. } => "CanvasSettingsChanged",
+            CanvasEvents::PaletteChanged { .. } => "PaletteChanged",
+            CanvasEvents::CommentAdded { .. } => "CommentAdded",
+            CanvasEvents::CommentResolved { .. } => "CommentResolved",
+            CanvasEvents::CommentReopened { .. } => "CommentReopened",
+            CanvasEvents::CommentDeleted { .. } => "CommentDeleted",
+            CanvasEvents::ResolvedCommentsRequest { .. } => "ResolvedCommentsRequest",
+            CanvasEvents::CanvasLogHeader { .. } => "CanvasLogHeader",
+            CanvasEvents::UserCaughtUp { .. } => "UserCaughtUp",
+            CanvasEvents::ContributorSeen { .. } => "ContributorSeen",
+            CanvasEvents::ServerNotice { .. } => "ServerNotice",
+            CanvasEvents::Ack { .. } => "Ack",
+            CanvasEvents::Nack { .. } => "Nack",
+            CanvasEvents::ServerHello { .. } => "ServerHello",
+            CanvasEvents::InitialStateChunk { .. } => "InitialStateChunk",
+            CanvasEvents::TimeSyncRequest { .. } => "TimeSyncRequest",
+            CanvasEvents::TimeSyncResponse { .. } => "TimeSyncResponse",
+            CanvasEvents::FlushRequest { .. } => "FlushRequest",
+            CanvasEvents::SaveStateChanged { .. } => "SaveStateChanged",
+            CanvasEvents::ViewportChanged { .. } => "ViewportChanged",
+        }
+    }
+
+    /// Notice rendered in the default locale, the socket does not know the locale of the client
+    pub fn notice(timestamp: u64, level: NoticeLevel, message: impl Into<Message>) -> Self {
+        Self::notice_for(timestamp, level, message, None)
+    }
+
+    /// Notice acknowledging an operation that was already applied, the client can stop retrying it
+    pub fn duplicate_ack(timestamp: u64, op_id: String) -> Self {
+        Self::notice_for(
+            timestamp,
+            NoticeLevel::Notice,
+            MessageKey::EventDuplicate,
+            Some(op_id),
+        )
+    }
+
+    pub fn ack(timestamp: u64, op_id: String, seq: u64) -> Self {
+        CanvasEvents::Ack {
+            timestamp,
+            opId: op_id,
+            seq,
+            superseded: Vec::new(),
+        }
+    }
+
+    /// Acknowledgement of a persisted line that merged several operations, op_id is the last of them
+    pub fn coalesced_ack(timestamp: u64, op_id: String, seq: u64, superseded: Vec<String>) -> Self {
+        CanvasEvents::Ack {
+            timestamp,
+            opId: op_id,
+            seq,
+            superseded,
+        }
+    }
+
+    /// Rejection of an event with opId, rendered in the default locale like notices
+    pub fn nack(timestamp: u64, op_id: String, message: impl Into<Message>) -> Self {
+        let message = message.into();
+        CanvasEvents::Nack {
+            timestamp,
+            opId: op_id,
+            code: message.key.key().to_string(),
+            message: message.render(Locale::default()),
+            suggestedId: None,
+        }
+    }
+
+    /// Adds the id a rejected shape can be resent with, other events are returned unchanged
+    pub fn with_suggested_id(mut self, shape_id: String) -> Self {
+        if let CanvasEvents::ServerNotice { suggestedId, .. }
+        | CanvasEvents::Nack { suggestedId, .. } = &mut self
+        {
+            *suggestedId = Some(shape_id);
+        }
+        self
+    }
+
+    fn notice_for(
+        timestamp: u64,
+        level: NoticeLevel,
+        message: impl Into<Message>,
+        op_id: Option<String>,
+    ) -> Self {
+        let message = message.into();
+        CanvasEvents::ServerNotice {
+            timestamp,
+            level,
+            code: message.key.key().to_string(),
+            message: message.render(Locale::default()),
+            opId: op_id,
+            suggestedId: None,
+        }
+    }
 }
 
 impl TryInto<Msg> for &CanvasEvents {
@@ -145,3 +790,150 @@ impl TryInto<Msg> for &CanvasEvents {
         serde_json::to_string(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: i32, y: i32) -> Point2D {
+        Point2D { x, y }
+    }
+
+    fn circle(center: Point2D, radius: f32) -> Shape {
+        Shape::Circle {
+            id: "c".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            center,
+            radius,
+        }
+    }
+
+    fn path(points: Vec<Point2D>) -> Shape {
+        Shape::Path {
+            id: "p".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            points,
+            closed: false,
+        }
+    }
+
+    #[test]
+    fn test_shape_bounds() {
+        let rectangle = Shape::Rectangle {
+            id: "r".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: point(10, -5),
+            to: point(-10, 5),
+        };
+        assert_eq!(rectangle.bounds(), (point(-10, -5), point(10, 5)));
+
+        let triangle = Shape::Triangle {
+            id: "t".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            p1: point(0, 0),
+            p2: point(4, 8),
+            p3: point(-2, 3),
+        };
+        assert_eq!(triangle.bounds(), (point(-2, 0), point(4, 8)));
+
+        assert_eq!(
+            circle(point(5, 5), 2.5).bounds(),
+            (point(2, 2), point(8, 8))
+        );
+        assert_eq!(
+            path(vec![point(3, 1), point(-1, 7), point(2, 2)]).bounds(),
+            (point(-1, 1), point(3, 7))
+        );
+    }
+
+    #[test]
+    fn test_degenerate_shape_bounds() {
+        assert_eq!(
+            circle(point(5, 5), 0.0).bounds(),
+            (point(5, 5), point(5, 5))
+        );
+        assert_eq!(
+            circle(point(i32::MAX, 0), 10.0).bounds(),
+            (point(i32::MAX - 10, -10), point(i32::MAX, 10))
+        );
+        assert_eq!(
+            circle(point(1, 1), f32::NAN).bounds(),
+            (point(1, 1), point(1, 1))
+        );
+        assert_eq!(path(vec![point(4, 2)]).bounds(), (point(4, 2), point(4, 2)));
+        assert_eq!(path(Vec::new()).bounds(), (point(0, 0), point(0, 0)));
+    }
+
+    #[test]
+    fn test_shape_type_names_cover_every_variant() {
+        let line = Shape::Line {
+            id: "l".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: point(0, 0),
+            to: point(1, 1),
+        };
+        let rectangle = Shape::Rectangle {
+            id: "r".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: point(0, 0),
+            to: point(1, 1),
+        };
+        let triangle = Shape::Triangle {
+            id: "t".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            p1: point(0, 0),
+            p2: point(1, 1),
+            p3: point(0, 1),
+        };
+        let shapes = [
+            line,
+            circle(point(0, 0), 1.0),
+            rectangle,
+            triangle,
+            path(vec![point(0, 0)]),
+        ];
+
+        // fails to compile once Shape gets a variant, it needs a sample above and an entry in ShapeType::ALL
+        let names: Vec<&str> = shapes
+            .iter()
+            .map(|shape| match shape {
+                Shape::Line { .. } => "Line",
+                Shape::Circle { .. } => "Circle",
+                Shape::Rectangle { .. } => "Rectangle",
+                Shape::Triangle { .. } => "Triangle",
+                Shape::Path { .. } => "Path",
+            })
+            .collect();
+        assert_eq!(names, Shape::type_names().collect::<Vec<_>>());
+
+        for shape in &shapes {
+            let shape_type = shape.shape_type();
+            let json = serde_json::to_value(shape).unwrap();
+            assert_eq!(json["type"], shape_type.name());
+            assert_eq!(serde_json::to_value(shape_type).unwrap(), shape_type.name());
+            assert_eq!(ShapeType::from_name(shape_type.name()), Some(shape_type));
+        }
+        assert_eq!(ShapeType::from_name("line"), None);
+    }
+}
@@ -0,0 +1,413 @@
+use super::{
+    attributes::{AttributeValue, DASH_PATTERN, OPACITY, ROTATION, STROKE_WIDTH},
+    events::{Point2D, Shape},
+    replay::{CanvasShapeState, CREATED_BY_KEY, CREATED_BY_NAME_KEY},
+    store::CanvasMetadata,
+};
+use std::fmt::Write;
+
+// SVG export of a canvas
+// Renders the folded shapes of a replay, shapes are drawn back to front
+// Temporary shapes are never persisted and therefore never exported
+// Elements of shapes with a known creator carry it as data-created-by attribute, its name at the time as data-created-by-name
+// The contributors are listed as Dublin Core metadata of the document, as is the author, license and description of the canvas
+// On request the author and license are shown in a footer below the drawing
+// Known extension attributes of a shape become presentation attributes, unknown ones are not exported
+
+/// Margin around the drawing in pixels
+const EXPORT_MARGIN: i32 = 10;
+
+/// Height of the attribution footer in pixels
+const ATTRIBUTION_HEIGHT: i32 = 20;
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn points_attribute(points: &[Point2D]) -> String {
+    points
+        .iter()
+        .map(|point| format!("{},{}", point.x, point.y))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Points spanning the area covered by the shape, used for the viewBox
+fn shape_extent(shape: &Shape) -> Vec<Point2D> {
+    match shape {
+        Shape::Line { from, to, .. } | Shape::Rectangle { from, to, .. } => vec![*from, *to],
+        Shape::Circle { center, radius, .. } => {
+            let radius = radius.ceil() as i32;
+            vec![
+                Point2D {
+                    x: center.x - radius,
+                    y: center.y - radius,
+                },
+                Point2D {
+                    x: center.x + radius,
+                    y: center.y + radius,
+                },
+            ]
+        }
+        Shape::Triangle { p1, p2, p3, .. } => vec![*p1, *p2, *p3],
+        Shape::Path { points, .. } => points.clone(),
+    }
+}
+
+/// Presentation attributes of the known extension attributes, rotation turns the shape around the center of its extent
+fn extension_attributes(shape: &Shape) -> String {
+    let attributes = shape.attributes();
+    let number = |key: &str| match attributes.get(key) {
+        Some(AttributeValue::Number(number)) => Some(*number),
+        _ => None,
+    };
+
+    let mut rendered = String::new();
+    if let Some(width) = number(STROKE_WIDTH) {
+        let _ = write!(rendered, r#" stroke-width="{width}""#);
+    }
+    if let Some(AttributeValue::Text(pattern)) = attributes.get(DASH_PATTERN) {
+        let _ = write!(
+            rendered,
+            r#" stroke-dasharray="{}""#,
+            escape_attribute(pattern)
+        );
+    }
+    if let Some(opacity) = number(OPACITY) {
+        let _ = write!(rendered, r#" opacity="{opacity}""#);
+    }
+    if let Some(rotation) = number(ROTATION) {
+        let extent = shape_extent(shape);
+        if !extent.is_empty() {
+            let (min_x, max_x) = extent
+                .iter()
+                .fold((i32::MAX, i32::MIN), |(min, max), point| {
+                    (min.min(point.x), max.max(point.x))
+                });
+            let (min_y, max_y) = extent
+                .iter()
+                .fold((i32::MAX, i32::MIN), |(min, max), point| {
+                    (min.min(point.y), max.max(point.y))
+                });
+            let center_x = (min_x as f64 + max_x as f64) / 2.0;
+            let center_y = (min_y as f64 + max_y as f64) / 2.0;
+            let _ = write!(
+                rendered,
+                r#" transform="rotate({rotation} {center_x} {center_y})""#
+            );
+        }
+    }
+    rendered
+}
+
+fn render_shape(shape: &Shape) -> String {
+    match shape {
+        Shape::Line {
+            borderColor,
+            from,
+            to,
+            ..
+        } => format!(
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}"/>"#,
+            from.x,
+            from.y,
+            to.x,
+            to.y,
+            escape_attribute(borderColor)
+        ),
+        Shape::Circle {
+            borderColor,
+            fillColor,
+            center,
+            radius,
+            ..
+        } => format!(
+            r#"<circle cx="{}" cy="{}" r="{}" stroke="{}" fill="{}"/>"#,
+            center.x,
+            center.y,
+            radius,
+            escape_attribute(borderColor),
+            escape_attribute(fillColor)
+        ),
+        Shape::Rectangle {
+            borderColor,
+            fillColor,
+            from,
+            to,
+            ..
+        } => format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" stroke="{}" fill="{}"/>"#,
+            from.x.min(to.x),
+            from.y.min(to.y),
+            (to.x - from.x).abs(),
+            (to.y - from.y).abs(),
+            escape_attribute(borderColor),
+            escape_attribute(fillColor)
+        ),
+        Shape::Triangle {
+            borderColor,
+            fillColor,
+            p1,
+            p2,
+            p3,
+            ..
+        } => format!(
+            r#"<polygon points="{}" stroke="{}" fill="{}"/>"#,
+            points_attribute(&[*p1, *p2, *p3]),
+            escape_attribute(borderColor),
+            escape_attribute(fillColor)
+        ),
+        // open paths are never filled, the canvas application does the same
+        Shape::Path {
+            borderColor,
+            fillColor,
+            points,
+            closed,
+            ..
+        } => format!(
+            r#"<{} points="{}" stroke="{}" fill="{}"/>"#,
+            if *closed { "polygon" } else { "polyline" },
+            points_attribute(points),
+            escape_attribute(borderColor),
+            if *closed {
+                escape_attribute(fillColor)
+            } else {
+                "none".to_string()
+            }
+        ),
+    }
+}
+
+/// Visible credit of the canvas, None if it has neither author nor license
+fn attribution_text(metadata: &CanvasMetadata) -> Option<String> {
+    let parts: Vec<&str> = [
+        metadata.author_display.as_deref(),
+        metadata.license.as_ref().map(|license| license.name()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!parts.is_empty()).then(|| format!("© {}", parts.join(" · ")))
+}
+
+/// SVG document of render_svg_capped, every value written into it was escaped
+/// Only this module creates one, the print page inlines it as markup, see templates::render_timed_with_svg
+#[derive(Debug)]
+pub struct SvgDocument(String);
+
+impl SvgDocument {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Renders the shapes into a standalone SVG document
+/// Shapes that can't be read as a Shape, e.g. broken by a partial update, are skipped
+/// With attribution the author and license are added as footer, if the canvas has any
+pub fn render_svg(
+    state: &CanvasShapeState,
+    metadata: Option<&CanvasMetadata>,
+    attribution: bool,
+) -> String {
+    let (svg, _) = render_svg_capped(state, metadata, attribution, usize::MAX);
+    svg.0
+}
+
+/// Renders the backmost max_shapes shapes like render_svg, returns the SVG and how many shapes were left out
+pub fn render_svg_capped(
+    state: &CanvasShapeState,
+    metadata: Option<&CanvasMetadata>,
+    attribution: bool,
+    max_shapes: usize,
+) -> (SvgDocument, usize) {
+    let omitted = state.shapes.len().saturating_sub(max_shapes);
+    let shapes: Vec<(Shape, String)> = state
+        .shapes
+        .iter()
+        .take(max_shapes)
+        .filter_map(|value| {
+            let shape = serde_json::from_value(value.clone()).ok()?;
+            let mut attributes = extension_attributes(&shape);
+            for (key, attribute) in [
+                (CREATED_BY_KEY, "data-created-by"),
+                (CREATED_BY_NAME_KEY, "data-created-by-name"),
+            ] {
+                if let Some(value) = value.get(key).and_then(|value| value.as_str()) {
+                    let _ = write!(attributes, " {attribute}=\"{}\"", escape_attribute(value));
+                }
+            }
+            Some((shape, attributes))
+        })
+        .collect();
+
+    let extent = shapes.iter().flat_map(|(shape, _)| shape_extent(shape));
+    let (min_x, min_y, max_x, max_y) = extent.fold(
+        (i32::MAX, i32::MAX, i32::MIN, i32::MIN),
+        |(min_x, min_y, max_x, max_y), point| {
+            (
+                min_x.min(point.x),
+                min_y.min(point.y),
+                max_x.max(point.x),
+                max_y.max(point.y),
+            )
+        },
+    );
+    let (min_x, min_y, width, mut height) = if shapes.is_empty() {
+        (0, 0, 0, 0)
+    } else {
+        (
+            min_x - EXPORT_MARGIN,
+            min_y - EXPORT_MARGIN,
+            max_x - min_x + 2 * EXPORT_MARGIN,
+            max_y - min_y + 2 * EXPORT_MARGIN,
+        )
+    };
+
+    let footer = metadata
+        .filter(|_| attribution)
+        .and_then(attribution_text)
+        .map(|text| {
+            let footer = format!(
+                r#"<text x="{}" y="{}" font-family="sans-serif" font-size="12">{}</text>"#,
+                min_x + EXPORT_MARGIN,
+                min_y + height + ATTRIBUTION_HEIGHT - 6,
+                escape_attribute(&text)
+            );
+            height += ATTRIBUTION_HEIGHT;
+            footer
+        });
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{min_x} {min_y} {width} {height}" width="{width}" height="{height}">"#
+    );
+    if !state.contributors.is_empty() || metadata.is_some() {
+        svg.push_str("\n  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">");
+        if let Some(metadata) = metadata {
+            let timestamp = state
+                .timestamp
+                .and_then(|timestamp| chrono::DateTime::from_timestamp_millis(timestamp as i64));
+            for (element, value) in [
+                ("creator", metadata.author_display.clone()),
+                (
+                    "rights",
+                    metadata
+                        .license
+                        .as_ref()
+                        .map(|license| license.name().to_string()),
+                ),
+                ("description", metadata.description.clone()),
+                ("date", timestamp.map(|timestamp| timestamp.to_rfc3339())),
+            ] {
+                if let Some(value) = value {
+                    let _ = write!(
+                        svg,
+                        "\n    <dc:{element}>{}</dc:{element}>",
+                        escape_attribute(&value)
+                    );
+                }
+            }
+        }
+        for (_, username) in state.contributors.iter() {
+            let _ = write!(
+                svg,
+                "\n    <dc:contributor>{}</dc:contributor>",
+                escape_attribute(username)
+            );
+        }
+        svg.push_str("\n  </metadata>");
+    }
+    for (shape, attributes) in &shapes {
+        let element = render_shape(shape);
+        let _ = write!(svg, "\n  {}{attributes}/>", element.trim_end_matches("/>"));
+    }
+    if let Some(footer) = footer {
+        let _ = write!(svg, "\n  {footer}");
+    }
+    svg.push_str("\n</svg>\n");
+    (SvgDocument(svg), omitted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{contributors::Contributors, events::CanvasEvents};
+    use serde_json::json;
+
+    #[test]
+    fn test_render_svg() {
+        let state = CanvasShapeState {
+            shapes: vec![
+                json!({"type": "Rectangle", "id": "r1", "temporary": false, "borderColor": "#000", "fillColor": "#f00", "from": {"x": 20, "y": 20}, "to": {"x": 0, "y": 0}, "createdBy": "alice", "createdByName": "Alice"}),
+                json!({"type": "Path", "id": "p1", "temporary": false, "borderColor": "#00f", "fillColor": "#0f0", "points": [{"x": 0, "y": 0}, {"x": 5, "y": 10}, {"x": 40, "y": 30}], "closed": false}),
+                json!({"type": "Path", "id": "p2", "temporary": false, "borderColor": "\"><script>", "fillColor": "#0f0", "points": [{"x": 0, "y": 0}, {"x": 5, "y": 10}, {"x": 10, "y": 0}], "closed": true}),
+                json!({"id": "broken"}),
+            ],
+            contributors: Contributors::from_log(&[CanvasEvents::ContributorSeen {
+                userId: "alice".to_string(),
+                username: "Alice".to_string(),
+                firstSeen: 0,
+            }]),
+            ..Default::default()
+        };
+
+        let svg = render_svg(&state, None, true);
+        assert!(
+            svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="-10 -10 60 50""#)
+        );
+        assert!(svg.contains(
+            r##"<rect x="0" y="0" width="20" height="20" stroke="#000" fill="#f00" data-created-by="alice" data-created-by-name="Alice"/>"##
+        ));
+        assert!(svg.contains(r##"<polyline points="0,0 5,10 40,30" stroke="#00f" fill="none"/>"##));
+        assert!(svg.contains(
+            r##"<polygon points="0,0 5,10 10,0" stroke="&quot;&gt;&lt;script&gt;" fill="#0f0"/>"##
+        ));
+        assert!(!svg.contains("broken"));
+        assert!(svg.contains("<dc:contributor>Alice</dc:contributor>"));
+
+        // back to front order is kept
+        assert!(svg.find("<rect").unwrap() < svg.find("<polyline").unwrap());
+    }
+
+    #[test]
+    fn test_render_svg_maps_known_attributes() {
+        let state = CanvasShapeState {
+            shapes: vec![
+                json!({"type": "Rectangle", "id": "r1", "temporary": false, "borderColor": "#000", "fillColor": "#f00", "from": {"x": 0, "y": 0}, "to": {"x": 20, "y": 10}, "attributes": {"strokeWidth": {"Number": 3.0}, "opacity": {"Number": 0.5}, "rotation": {"Number": 90.0}, "dashPattern": {"Text": "4 2"}, "glow": {"Bool": true}}}),
+                json!({"type": "Line", "id": "l1", "temporary": false, "borderColor": "#000", "fillColor": "#000", "from": {"x": 0, "y": 0}, "to": {"x": 5, "y": 5}}),
+            ],
+            ..Default::default()
+        };
+
+        let svg = render_svg(&state, None, false);
+        assert!(svg.contains(
+            r##"<rect x="0" y="0" width="20" height="10" stroke="#000" fill="#f00" stroke-width="3" stroke-dasharray="4 2" opacity="0.5" transform="rotate(90 10 5)"/>"##
+        ));
+        assert!(!svg.contains("glow"));
+        assert!(svg.contains(r##"<line x1="0" y1="0" x2="5" y2="5" stroke="#000"/>"##));
+    }
+
+    #[test]
+    fn test_render_svg_capped_keeps_the_backmost_shapes() {
+        let line = |id: &str, x: i32| json!({"type": "Line", "id": id, "temporary": false, "borderColor": "#000", "fillColor": "#000", "from": {"x": x, "y": 0}, "to": {"x": x, "y": 5}});
+        let state = CanvasShapeState {
+            shapes: vec![line("l1", 0), line("l2", 10), line("l3", 20)],
+            ..Default::default()
+        };
+
+        let (svg, omitted) = render_svg_capped(&state, None, false, 2);
+        let svg = svg.as_str();
+        assert_eq!(omitted, 1);
+        assert_eq!(svg.matches("<line").count(), 2);
+        assert!(!svg.contains(r#"x1="20""#));
+        // the viewBox spans the rendered shapes only
+        assert!(svg.contains(r#"viewBox="-10 -10 30 25""#));
+
+        let (svg, omitted) = render_svg_capped(&state, None, false, 3);
+        assert_eq!(omitted, 0);
+        assert_eq!(svg.as_str(), render_svg(&state, None, false));
+    }
+}
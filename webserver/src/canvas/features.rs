@@ -0,0 +1,293 @@
+use ring::digest;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use utoipa::ToSchema;
+
+use super::events::CanvasEvents;
+use crate::userstore::UserId;
+
+// Feature flags to roll protocol features out gradually
+// The server configures every flag as enabled, disabled or enabled for a percentage of the users
+// Owners may override flags marked canvas overridable for their canvas, overrides win over the rollout
+// Flags are resolved per canvas and user when a session registers and sent with the ServerHello,
+// the canvas page gets the same resolution so both transports agree
+
+/// Clients may ask the server to sync the eventlog to disk, see FlushRequest
+pub const FLUSH_REQUESTS: &str = "flush_requests";
+/// Clients may estimate the offset of their clock, see TimeSyncRequest
+pub const TIME_SYNC: &str = "time_sync";
+/// Sessions may declare a viewport and skip shape events outside of it, see ViewportChanged
+pub const VIEWPORT_SUBSCRIPTIONS: &str = "viewport_subscriptions";
+
+/// Longest flag name accepted by the config and the overrides of a canvas
+pub const MAX_FLAG_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rollout {
+    Enabled,
+    Disabled,
+    /// enabled for this share of the users, bucketed by their id
+    Percentage(u8),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagConfig {
+    pub rollout: Rollout,
+    /// owners may switch the flag for their canvas
+    pub canvas_overridable: bool,
+}
+
+/// Flag as written in the config, name=on, name=off or name=<percent>% with an optional :overridable suffix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagSpec {
+    pub name: String,
+    pub config: FlagConfig,
+}
+
+impl FromStr for FlagSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Invalid feature flag {value}, expected name=on, name=off or name=<percent>% with an optional :overridable suffix")
+        };
+        let (name, rollout) = value.split_once('=').ok_or_else(invalid)?;
+        if !is_valid_flag_name(name) {
+            return Err(invalid());
+        }
+        let (rollout, canvas_overridable) = match rollout.strip_suffix(":overridable") {
+            Some(rollout) => (rollout, true),
+            None => (rollout, false),
+        };
+        let rollout = match rollout {
+            "on" => Rollout::Enabled,
+            "off" => Rollout::Disabled,
+            percentage => percentage
+                .strip_suffix('%')
+                .and_then(|percentage| percentage.parse::<u8>().ok())
+                .filter(|percentage| *percentage <= 100)
+                .map(Rollout::Percentage)
+                .ok_or_else(invalid)?,
+        };
+        Ok(FlagSpec {
+            name: name.to_string(),
+            config: FlagConfig {
+                rollout,
+                canvas_overridable,
+            },
+        })
+    }
+}
+
+impl fmt::Display for FlagSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.name)?;
+        match self.config.rollout {
+            Rollout::Enabled => write!(f, "on")?,
+            Rollout::Disabled => write!(f, "off")?,
+            Rollout::Percentage(percentage) => write!(f, "{percentage}%")?,
+        }
+        if self.config.canvas_overridable {
+            write!(f, ":overridable")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn is_valid_flag_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_FLAG_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Flags configured for the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureFlags {
+    flags: BTreeMap<String, FlagConfig>,
+}
+
+impl Default for FeatureFlags {
+    /// The gated protocol features are enabled, as they were before flags existed
+    /// Viewport subscriptions are new, owners enable them for their huge canvases
+    fn default() -> Self {
+        let enabled = FlagConfig {
+            rollout: Rollout::Enabled,
+            canvas_overridable: true,
+        };
+        let disabled = FlagConfig {
+            rollout: Rollout::Disabled,
+            canvas_overridable: true,
+        };
+        Self {
+            flags: BTreeMap::from([
+                (FLUSH_REQUESTS.to_string(), enabled),
+                (TIME_SYNC.to_string(), enabled),
+                (VIEWPORT_SUBSCRIPTIONS.to_string(), disabled),
+            ]),
+        }
+    }
+}
+
+impl FeatureFlags {
+    /// Default flags with the configured ones added or replaced
+    pub fn from_specs(specs: impl IntoIterator<Item = FlagSpec>) -> Self {
+        let mut flags = Self::default();
+        for spec in specs {
+            flags.flags.insert(spec.name, spec.config);
+        }
+        flags
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FlagConfig> {
+        self.flags.get(name)
+    }
+
+    /// First flag of the overrides that is unknown or not canvas overridable
+    pub fn rejected_override<'a>(&self, overrides: &'a BTreeMap<String, bool>) -> Option<&'a str> {
+        overrides
+            .keys()
+            .find(|name| !self.get(name).is_some_and(|flag| flag.canvas_overridable))
+            .map(String::as_str)
+    }
+
+    /// Flags in effect for the user in a canvas with the overrides
+    /// Overrides of flags that are no longer overridable are ignored
+    pub fn resolve(&self, user_id: &UserId, overrides: &BTreeMap<String, bool>) -> ResolvedFlags {
+        ResolvedFlags(
+            self.flags
+                .iter()
+                .map(|(name, flag)| {
+                    let enabled = match overrides.get(name) {
+                        Some(enabled) if flag.canvas_overridable => *enabled,
+                        _ => match flag.rollout {
+                            Rollout::Enabled => true,
+                            Rollout::Disabled => false,
+                            Rollout::Percentage(percentage) => bucket(name, user_id) < percentage,
+                        },
+                    };
+                    (name.clone(), enabled)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Stable bucket of the user for a flag, 0 to 99
+/// Hashed with the flag name, so each rollout picks its own users
+pub fn bucket(flag: &str, user_id: &str) -> u8 {
+    let hash = digest::digest(&digest::SHA256, format!("{flag}:{user_id}").as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash.as_ref()[..8]);
+    (u64::from_be_bytes(prefix) % 100) as u8
+}
+
+/// Flags of a session, unknown flags are disabled
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(transparent)]
+pub struct ResolvedFlags(pub BTreeMap<String, bool>);
+
+impl ResolvedFlags {
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.0.get(flag).copied().unwrap_or(false)
+    }
+}
+
+/// Flag a client event depends on, None for events every client may send
+pub fn gating_flag(event: &CanvasEvents) -> Option<&'static str> {
+    match event {
+        CanvasEvents::FlushRequest { .. } => Some(FLUSH_REQUESTS),
+        CanvasEvents::TimeSyncRequest { .. } => Some(TIME_SYNC),
+        CanvasEvents::ViewportChanged { .. } => Some(VIEWPORT_SUBSCRIPTIONS),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canvas_overrides_win_over_server_config() {
+        let flags = FeatureFlags::from_specs([
+            "snapping=off:overridable".parse().unwrap(),
+            "batching=on".parse().unwrap(),
+        ]);
+        let overrides = BTreeMap::from([
+            ("snapping".to_string(), true),
+            // not overridable, the server config wins
+            ("batching".to_string(), false),
+        ]);
+
+        let resolved = flags.resolve(&"user".to_string(), &overrides);
+        assert!(resolved.is_enabled("snapping"));
+        assert!(resolved.is_enabled("batching"));
+        assert!(resolved.is_enabled(FLUSH_REQUESTS));
+        assert!(!resolved.is_enabled("unknown"));
+
+        let without_overrides = flags.resolve(&"user".to_string(), &BTreeMap::new());
+        assert!(!without_overrides.is_enabled("snapping"));
+
+        assert_eq!(flags.rejected_override(&overrides), Some("batching"));
+        let unknown = BTreeMap::from([("unknown".to_string(), true)]);
+        assert_eq!(flags.rejected_override(&unknown), Some("unknown"));
+        let time_sync = BTreeMap::from([(TIME_SYNC.to_string(), false)]);
+        assert_eq!(flags.rejected_override(&time_sync), None);
+    }
+
+    #[test]
+    fn test_percentage_rollout_is_stable() {
+        let flags = FeatureFlags::from_specs(["resume=30%".parse().unwrap()]);
+        let users: Vec<String> = (0..1000).map(|user| format!("user{user}")).collect();
+
+        let enabled: Vec<bool> = users
+            .iter()
+            .map(|user| flags.resolve(user, &BTreeMap::new()).is_enabled("resume"))
+            .collect();
+        let again: Vec<bool> = users
+            .iter()
+            .map(|user| flags.resolve(user, &BTreeMap::new()).is_enabled("resume"))
+            .collect();
+        assert_eq!(enabled, again);
+
+        let share = enabled.iter().filter(|enabled| **enabled).count();
+        assert!((250..350).contains(&share), "{share} of 1000 users enabled");
+
+        // raising the percentage keeps the users that already had the feature
+        let raised = FeatureFlags::from_specs(["resume=60%".parse().unwrap()]);
+        for (user, enabled) in users.iter().zip(enabled) {
+            if enabled {
+                assert!(raised.resolve(user, &BTreeMap::new()).is_enabled("resume"));
+            }
+        }
+
+        assert_eq!(bucket("resume", "user1"), bucket("resume", "user1"));
+        let none = FeatureFlags::from_specs(["resume=0%".parse().unwrap()]);
+        let all = FeatureFlags::from_specs(["resume=100%".parse().unwrap()]);
+        for user in &users {
+            assert!(!none.resolve(user, &BTreeMap::new()).is_enabled("resume"));
+            assert!(all.resolve(user, &BTreeMap::new()).is_enabled("resume"));
+        }
+    }
+
+    #[test]
+    fn test_flag_specs_parse() {
+        let spec: FlagSpec = "chunked_sync=25%:overridable".parse().unwrap();
+        assert_eq!(spec.name, "chunked_sync");
+        assert_eq!(spec.config.rollout, Rollout::Percentage(25));
+        assert!(spec.config.canvas_overridable);
+        assert_eq!(spec.to_string(), "chunked_sync=25%:overridable");
+
+        for invalid in [
+            "snapping",
+            "=on",
+            "snapping=yes",
+            "snapping=101%",
+            "Snapping=on",
+        ] {
+            assert!(invalid.parse::<FlagSpec>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use super::events::{Point2D, Shape};
+use serde_json::Value;
+
+// Grid snapping of shapes, applied by the server when a canvas has snapping enabled
+// Snapping on the server keeps the eventlog and every client on identical geometry,
+// even if a client ignores the grid settings
+// Coordinates are rounded to the nearest multiple of the grid, exact halves are rounded away from zero
+// like f32::round, so snapping is symmetric around the origin
+
+/// Nearest multiple of grid, clamped to the range of i32
+pub fn snap_coordinate(value: i32, grid: u32) -> i32 {
+    if grid <= 1 {
+        return value;
+    }
+    let (value, grid) = (value as i64, grid as i64);
+
+    let below = value - value.rem_euclid(grid);
+    let distance = value - below;
+    let snapped = match (distance * 2).cmp(&grid) {
+        std::cmp::Ordering::Less => below,
+        std::cmp::Ordering::Greater => below + grid,
+        // exact half, away from zero
+        std::cmp::Ordering::Equal if value >= 0 => below + grid,
+        std::cmp::Ordering::Equal => below,
+    };
+    snapped.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+pub fn snap_point(point: Point2D, grid: u32) -> Point2D {
+    Point2D {
+        x: snap_coordinate(point.x, grid),
+        y: snap_coordinate(point.y, grid),
+    }
+}
+
+/// Nearest multiple of grid, a circle never collapses into a point
+/// A radius of 0 stays 0, a positive radius is at least one grid step
+/// Non finite radii are left alone, they are not ours to fix
+pub fn snap_radius(radius: f32, grid: u32) -> f32 {
+    if grid == 0 || !radius.is_finite() || radius == 0.0 {
+        return radius;
+    }
+    let grid = grid as f32;
+    let snapped = (radius / grid).round() * grid;
+    if snapped == 0.0 {
+        grid.copysign(radius)
+    } else {
+        snapped
+    }
+}
+
+/// Snaps every coordinate of a non temporary shape, returns true if the shape changed
+/// Temporary shapes are previews while drawing, they are passed through untouched
+pub fn snap_shape(shape: &mut Shape, grid: u32) -> bool {
+    if shape.is_temporary() {
+        return false;
+    }
+
+    let mut changed = false;
+    let mut snap = |point: &mut Point2D| {
+        let snapped = snap_point(*point, grid);
+        changed |= snapped != *point;
+        *point = snapped;
+    };
+
+    match shape {
+        Shape::Line { from, to, .. } | Shape::Rectangle { from, to, .. } => {
+            snap(from);
+            snap(to);
+        }
+        Shape::Circle { center, radius, .. } => {
+            snap(center);
+            let snapped = snap_radius(*radius, grid);
+            changed |= snapped != *radius;
+            *radius = snapped;
+        }
+        Shape::Triangle { p1, p2, p3, .. } => {
+            snap(p1);
+            snap(p2);
+            snap(p3);
+        }
+        Shape::Path { points, .. } => points.iter_mut().for_each(snap),
+    }
+    changed
+}
+
+/// Snaps the coordinates contained in a partial shape of ShapeUpdated, returns true if it changed
+/// Fields that don't hold a point or a radius are left alone, the client owns their format
+pub fn snap_partial_shape(shape: &mut Value, grid: u32) -> bool {
+    let Some(fields) = shape.as_object_mut() else {
+        return false;
+    };
+
+    let mut changed = false;
+    for (name, value) in fields.iter_mut() {
+        match name.as_str() {
+            "from" | "to" | "center" | "p1" | "p2" | "p3" => changed |= snap_value(value, grid),
+            "points" => {
+                if let Some(points) = value.as_array_mut() {
+                    for point in points {
+                        changed |= snap_value(point, grid);
+                    }
+                }
+            }
+            "radius" => {
+                let Some(radius) = value.as_f64() else {
+                    continue;
+                };
+                let snapped = snap_radius(radius as f32, grid);
+                if snapped as f64 != radius {
+                    *value = Value::from(snapped);
+                    changed = true;
+                }
+            }
+            _ => (),
+        }
+    }
+    changed
+}
+
+/// Snaps a point given as json value, anything that isn't a Point2D is left alone
+fn snap_value(value: &mut Value, grid: u32) -> bool {
+    let Ok(point) = serde_json::from_value::<Point2D>(value.clone()) else {
+        return false;
+    };
+    let snapped = snap_point(point, grid);
+    if snapped == point {
+        return false;
+    }
+    // unwrap: Point2D always serializes
+    *value = serde_json::to_value(snapped).unwrap();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn point(x: i32, y: i32) -> Point2D {
+        Point2D { x, y }
+    }
+
+    fn line(temporary: bool, from: Point2D, to: Point2D) -> Shape {
+        Shape::Line {
+            id: "line".to_string(),
+            temporary,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from,
+            to,
+        }
+    }
+
+    fn circle(center: Point2D, radius: f32) -> Shape {
+        Shape::Circle {
+            id: "circle".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            center,
+            radius,
+        }
+    }
+
+    #[test]
+    fn test_snap_coordinate_rounds_to_nearest() {
+        assert_eq!(snap_coordinate(0, 10), 0);
+        assert_eq!(snap_coordinate(4, 10), 0);
+        assert_eq!(snap_coordinate(6, 10), 10);
+        assert_eq!(snap_coordinate(10, 10), 10);
+        assert_eq!(snap_coordinate(14, 10), 10);
+        assert_eq!(snap_coordinate(16, 10), 20);
+    }
+
+    #[test]
+    fn test_snap_coordinate_negative() {
+        assert_eq!(snap_coordinate(-4, 10), 0);
+        assert_eq!(snap_coordinate(-6, 10), -10);
+        assert_eq!(snap_coordinate(-10, 10), -10);
+        assert_eq!(snap_coordinate(-14, 10), -10);
+        assert_eq!(snap_coordinate(-16, 10), -20);
+    }
+
+    #[test]
+    fn test_snap_coordinate_exact_half_rounds_away_from_zero() {
+        assert_eq!(snap_coordinate(5, 10), 10);
+        assert_eq!(snap_coordinate(-5, 10), -10);
+        assert_eq!(snap_coordinate(15, 10), 20);
+        assert_eq!(snap_coordinate(-15, 10), -20);
+        // odd grids have no exact half
+        assert_eq!(snap_coordinate(1, 3), 0);
+        assert_eq!(snap_coordinate(2, 3), 3);
+        assert_eq!(snap_coordinate(-2, 3), -3);
+    }
+
+    #[test]
+    fn test_snap_coordinate_trivial_grids_and_bounds() {
+        assert_eq!(snap_coordinate(7, 0), 7);
+        assert_eq!(snap_coordinate(-7, 1), -7);
+        assert_eq!(snap_coordinate(i32::MAX, 10), i32::MAX);
+        assert_eq!(snap_coordinate(i32::MIN, 10), i32::MIN);
+        assert_eq!(snap_coordinate(i32::MAX, u32::MAX), 0);
+    }
+
+    #[test]
+    fn test_snap_radius() {
+        assert_eq!(snap_radius(0.0, 10), 0.0);
+        assert_eq!(snap_radius(12.3, 10), 10.0);
+        assert_eq!(snap_radius(15.0, 10), 20.0);
+        assert_eq!(snap_radius(2.0, 10), 10.0); // never collapses
+        assert_eq!(snap_radius(-2.0, 10), -10.0);
+        assert_eq!(snap_radius(12.6, 1), 13.0);
+        assert_eq!(snap_radius(12.6, 0), 12.6);
+        assert!(snap_radius(f32::NAN, 10).is_nan());
+    }
+
+    #[test]
+    fn test_snap_shape() {
+        let mut shape = line(false, point(3, -6), point(25, 44));
+        assert!(snap_shape(&mut shape, 10));
+        assert!(
+            matches!(shape, Shape::Line { from, to, .. } if from == point(0, -10) && to == point(30, 40))
+        );
+
+        // already on the grid
+        assert!(!snap_shape(&mut shape, 10));
+
+        let mut shape = circle(point(11, 19), 0.0);
+        assert!(snap_shape(&mut shape, 10));
+        assert!(
+            matches!(shape, Shape::Circle { center, radius, .. } if center == point(10, 20) && radius == 0.0)
+        );
+
+        let mut shape = Shape::Path {
+            id: "path".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            points: vec![point(1, 1), point(-4, 9), point(-5, 5)],
+            closed: false,
+        };
+        assert!(snap_shape(&mut shape, 10));
+        assert!(
+            matches!(&shape, Shape::Path { points, .. } if *points == vec![point(0, 0), point(0, 10), point(-10, 10)])
+        );
+    }
+
+    #[test]
+    fn test_temporary_shapes_are_not_snapped() {
+        let mut shape = line(true, point(3, 3), point(7, 7));
+        assert!(!snap_shape(&mut shape, 10));
+        assert!(
+            matches!(shape, Shape::Line { from, to, .. } if from == point(3, 3) && to == point(7, 7))
+        );
+    }
+
+    #[test]
+    fn test_snap_partial_shape() {
+        let mut shape = json!({
+            "id": "triangle",
+            "p1": {"x": 4, "y": 6},
+            "points": [{"x": -6, "y": 0}, "invalid"],
+            "radius": 3.0,
+            "borderColor": "#123"
+        });
+        assert!(snap_partial_shape(&mut shape, 10));
+        assert_eq!(
+            shape,
+            json!({
+                "id": "triangle",
+                "p1": {"x": 0, "y": 10},
+                "points": [{"x": -10, "y": 0}, "invalid"],
+                "radius": 10.0,
+                "borderColor": "#123"
+            })
+        );
+
+        let mut shape = json!({"id": "line", "fillColor": "#fff"});
+        assert!(!snap_partial_shape(&mut shape, 10));
+    }
+}
@@ -0,0 +1,154 @@
+use actix_web::http::Method;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use super::store::{AccessLevel, CanvasClaim, CanvasId};
+use crate::{username::GUEST_NAME_PREFIX, userstore::UserId};
+
+// Guests join canvases the owner opened for them without an account
+// The authentication middleware mints a guest when a browser without auth cookie opens such a canvas,
+// the guest is a JWT with exactly one claim on the canvas, it is never refreshed and there is no UserStore record
+// Guests are no members, their level follows the guest access of the canvas, see Canvas::access_level,
+// nothing about them is persisted in the CanvasStore, so they are gone with the cookie or a restart
+
+/// Prefix of guest user ids, nanoid user ids of accounts are not expected to start with it
+const GUEST_PREFIX: &str = "guest_";
+
+const GUEST_ID_LENGTH: usize = 12;
+
+/// Lifetime of a guest cookie, an expired guest gets a new identity on the next visit
+pub const DEFAULT_GUEST_LIFETIME: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// Resources of their canvas guests may read besides the page, management endpoints are left out
+const GUEST_RESOURCES: [&str; 5] = ["access", "shapes", "replay", "export.svg", "export.json"];
+
+/// Who may join a canvas without an account, only the owner may change it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub enum GuestAccess {
+    #[default]
+    Closed,
+    OpenRead,
+    OpenWrite,
+}
+
+impl GuestAccess {
+    /// Level of guests on the canvas, AccessLevel::None if it is closed
+    pub fn access_level(&self) -> AccessLevel {
+        match self {
+            GuestAccess::Closed => AccessLevel::None,
+            GuestAccess::OpenRead => AccessLevel::Read,
+            GuestAccess::OpenWrite => AccessLevel::Write,
+        }
+    }
+}
+
+/// Guest access of the whole server, registered as app data
+/// Without a registered policy no guests are admitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestPolicy {
+    /// switches guest access off for every canvas, existing guest cookies are rejected as well
+    pub enabled: bool,
+    pub lifetime: Duration,
+}
+
+impl Default for GuestPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            lifetime: DEFAULT_GUEST_LIFETIME,
+        }
+    }
+}
+
+pub fn is_guest(user_id: &str) -> bool {
+    user_id.starts_with(GUEST_PREFIX)
+}
+
+/// New guest id and display name, the name is reserved by the UsernamePolicy so no user can impersonate a guest
+pub fn generate() -> (UserId, String) {
+    let digits = nanoid!(2, &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9']);
+    let number = digits.parse::<u8>().unwrap_or_default() + 1;
+    (
+        format!("{GUEST_PREFIX}{}", nanoid!(GUEST_ID_LENGTH)),
+        format!("{GUEST_NAME_PREFIX} {number}"),
+    )
+}
+
+/// The only claim of a guest
+pub fn claim(canvas_id: CanvasId, canvas_name: String, guest_access: GuestAccess) -> CanvasClaim {
+    CanvasClaim {
+        n: canvas_name,
+        c: canvas_id,
+        r: guest_access.access_level(),
+        exp: None,
+    }
+}
+
+/// Canvas page of /canvas/{canvas_id}, the only page guests are admitted on
+pub fn admission_scope(path: &str) -> Option<&str> {
+    let canvas_id = path.strip_prefix("/canvas/")?;
+    (!canvas_id.is_empty() && !canvas_id.contains('/')).then_some(canvas_id)
+}
+
+/// Guests may only read the page and resources of their canvas and connect to its websocket
+pub fn permits(canvas_id: &str, method: &Method, path: &str) -> bool {
+    if method != Method::GET && method != Method::HEAD {
+        return false;
+    }
+    if path.strip_prefix("/ws/canvas/") == Some(canvas_id) {
+        return true;
+    }
+    let Some(rest) = path
+        .strip_prefix("/canvas/")
+        .and_then(|path| path.strip_prefix(canvas_id))
+    else {
+        return false;
+    };
+    match rest.strip_prefix('/') {
+        None => rest.is_empty(),
+        Some(resource) => GUEST_RESOURCES.contains(&resource.split('/').next().unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guests_are_limited_to_their_canvas() {
+        let (guest_id, name) = generate();
+        assert!(is_guest(&guest_id));
+        assert!(name.starts_with("Guest "));
+        // no account can take the name of a guest
+        assert_eq!(
+            crate::username::UsernamePolicy::default().check(&name),
+            Err(crate::username::UsernameViolation::Reserved)
+        );
+
+        assert!(permits("board", &Method::GET, "/canvas/board"));
+        assert!(permits("board", &Method::GET, "/ws/canvas/board"));
+        assert!(permits("board", &Method::GET, "/canvas/board/export.svg"));
+        assert!(permits(
+            "board",
+            &Method::GET,
+            "/canvas/board/replay/keyframes"
+        ));
+        assert!(!permits(
+            "board",
+            &Method::GET,
+            "/canvas/board/export/events"
+        ));
+        assert!(!permits("board", &Method::GET, "/canvas/board2"));
+        assert!(!permits("board", &Method::GET, "/canvas/board/members"));
+        assert!(!permits("board", &Method::GET, "/canvas/board/tokens"));
+        assert!(!permits("board", &Method::POST, "/canvas/board/settings"));
+        assert!(!permits("board", &Method::GET, "/canvas/other"));
+        assert!(!permits("board", &Method::GET, "/user/canvases"));
+
+        assert_eq!(admission_scope("/canvas/board"), Some("board"));
+        assert_eq!(admission_scope("/canvas/board/members"), None);
+        assert_eq!(admission_scope("/canvas/"), None);
+    }
+}
@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    comments::Comments, contributors::Contributors, events::CanvasEvents, provenance::Provenance,
+    receipts::ReadReceipts, store::CanvasId,
+};
+use crate::{
+    encryption::{self, EventLogKey, LineCodec},
+    userstore::UserId,
+};
+
+// Warm handoff of the loaded canvases between two processes of a deploy
+// On shutdown the CanvasSocketServer writes the derived state of every loaded canvas next to its eventlog,
+// the next process restores the canvas from it instead of folding the whole eventlog
+// A handoff is only restored while it is fresh and the eventlog did not change since, it is removed once read
+// Sessions are not migrated, clients reconnect and the joins and selections of the old sessions are cleaned up like after a crash
+// With an eventlog key installed the handoff is sealed like an eventlog line, the file stem is the associated data,
+// so a handoff neither opens as the handoff of another canvas nor as a line of an eventlog
+
+/// Bumped whenever the envelope changes, handoffs of other versions are discarded
+pub const HANDOFF_VERSION: u32 = 1;
+
+/// Age up to which a handoff is restored, older ones may miss changes made without the server
+pub const DEFAULT_HANDOFF_MAX_AGE: Duration = Duration::from_secs(120);
+
+/// Temporary shapes older than this at restore are abandoned strokes, they are dropped
+pub const TEMP_SHAPE_TTL: Duration = Duration::from_secs(30);
+
+/// Path of the handoff of a canvas, next to its eventlog
+pub fn handoff_path(canvas_id: &str) -> String {
+    format!("./{}.handoff.json", canvas_id)
+}
+
+/// Temporary shape of a session, never persisted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TempShape {
+    pub session_id: String,
+    /// millisecond timestamp of the server, not of the client drawing it
+    pub added_at: u64,
+}
+
+/// Versioned envelope of the hot state of a CanvasInstance
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handoff {
+    pub version: u32,
+    pub canvas_id: CanvasId,
+    /// millisecond timestamp
+    pub written_at: u64,
+    /// sequence number of the last line of the eventlog when the handoff was written
+    pub persisted_events: u64,
+    /// size of the eventlog in bytes when the handoff was written, a different size means it changed since
+    pub log_bytes: u64,
+    /// live events as derived from the eventlog, including the temporary shapes
+    pub event_log: Vec<CanvasEvents>,
+    pub shapes: HashSet<String>,
+    pub shape_creators: HashMap<String, UserId>,
+    /// written before provenance was tracked, such a handoff starts without
+    #[serde(default)]
+    pub provenance: Provenance,
+    /// written before comments existed, such a handoff starts without
+    #[serde(default)]
+    pub comments: Comments,
+    pub temp_shapes: HashMap<String, TempShape>,
+    pub contributors: Contributors,
+    pub receipts: ReadReceipts,
+}
+
+/// Why a handoff was not restored, the canvas is folded from its eventlog instead
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandoffRejection {
+    Version(u32),
+    OtherCanvas(CanvasId),
+    Expired { age: Duration },
+    LogChanged { expected: u64, actual: u64 },
+}
+
+impl Handoff {
+    /// Written with the installed eventlog key, see write_sealed
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        self.write_sealed(path, encryption::installed_key())
+    }
+
+    /// Written to a temporary file first, a crash never leaves a partial handoff behind
+    /// The codec is made for the final path, the temporary file name never becomes the associated data
+    pub fn write_sealed(&self, path: &str, key: Option<Arc<EventLogKey>>) -> io::Result<()> {
+        let codec = LineCodec::new(key, path);
+        let temp_path = format!("{path}.tmp");
+        fs::write(&temp_path, codec.encode(&serde_json::to_vec(self)?))?;
+        fs::rename(temp_path, path)
+    }
+
+    /// Reads the handoff with the installed eventlog key, see take_sealed
+    pub fn take(path: &str) -> io::Result<Option<Handoff>> {
+        Self::take_sealed(path, encryption::installed_key())
+    }
+
+    /// Reads and removes the handoff of the canvas, a handoff is never restored twice
+    /// None if there is none, unreadable handoffs are removed as well
+    /// Plaintext handoffs written before a key was installed are still read
+    pub fn take_sealed(path: &str, key: Option<Arc<EventLogKey>>) -> io::Result<Option<Handoff>> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        fs::remove_file(path)?;
+        let content = String::from_utf8(content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let json = LineCodec::new(key, path).decode(&content)?;
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    /// Checks that the handoff belongs to the canvas, is fresh and describes the eventlog as it is now
+    pub fn check(
+        &self,
+        canvas_id: &str,
+        log_bytes: u64,
+        now: u64,
+        max_age: Duration,
+    ) -> Result<(), HandoffRejection> {
+        if self.version != HANDOFF_VERSION {
+            return Err(HandoffRejection::Version(self.version));
+        }
+        if self.canvas_id != canvas_id {
+            return Err(HandoffRejection::OtherCanvas(self.canvas_id.clone()));
+        }
+        let age = Duration::from_millis(now.saturating_sub(self.written_at));
+        if age > max_age {
+            return Err(HandoffRejection::Expired { age });
+        }
+        if self.log_bytes != log_bytes {
+            return Err(HandoffRejection::LogChanged {
+                expected: self.log_bytes,
+                actual: log_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Drops temporary shapes older than TEMP_SHAPE_TTL together with their live events
+    pub fn drop_expired_temp_shapes(&mut self, now: u64) {
+        let ttl = TEMP_SHAPE_TTL.as_millis() as u64;
+        let expired: HashSet<String> = self
+            .temp_shapes
+            .iter()
+            .filter(|(_, temp_shape)| temp_shape.added_at + ttl <= now)
+            .map(|(shape_id, _)| shape_id.clone())
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+
+        self.temp_shapes
+            .retain(|shape_id, _| !expired.contains(shape_id));
+        self.event_log
+            .retain(|event| shape_id(event).is_none_or(|shape_id| !expired.contains(shape_id)));
+    }
+}
+
+/// Shape an event is about, None for events not about a single shape
+fn shape_id(event: &CanvasEvents) -> Option<&str> {
+    match event {
+        CanvasEvents::ShapeAdded { shape, .. } => Some(shape.get_id()),
+        CanvasEvents::ShapeUpdated { shape, .. } => shape.get("id").and_then(|id| id.as_str()),
+        CanvasEvents::ShapeRemoved { shapeId, .. }
+        | CanvasEvents::ShapeSelected { shapeId, .. }
+        | CanvasEvents::ShapeDeselected { shapeId, .. }
+        | CanvasEvents::ShapeZChanged { shapeId, .. } => Some(shapeId),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handoff(canvas_id: &str) -> Handoff {
+        Handoff {
+            version: HANDOFF_VERSION,
+            canvas_id: canvas_id.to_string(),
+            written_at: 1_000,
+            persisted_events: 3,
+            log_bytes: 300,
+            event_log: Vec::new(),
+            shapes: HashSet::new(),
+            shape_creators: HashMap::new(),
+            provenance: Provenance::default(),
+            comments: Comments::default(),
+            temp_shapes: HashMap::new(),
+            contributors: Contributors::default(),
+            receipts: ReadReceipts::default(),
+        }
+    }
+
+    #[test]
+    fn test_stale_handoffs_are_rejected() {
+        let max_age = Duration::from_secs(60);
+        let handoff = handoff("canvas");
+        assert_eq!(handoff.check("canvas", 300, 2_000, max_age), Ok(()));
+
+        assert_eq!(
+            handoff.check("other", 300, 2_000, max_age),
+            Err(HandoffRejection::OtherCanvas("canvas".to_string()))
+        );
+        assert_eq!(
+            handoff.check("canvas", 300, 62_000, max_age),
+            Err(HandoffRejection::Expired {
+                age: Duration::from_secs(61)
+            })
+        );
+        // a line appended after the handoff was written
+        assert_eq!(
+            handoff.check("canvas", 420, 2_000, max_age),
+            Err(HandoffRejection::LogChanged {
+                expected: 300,
+                actual: 420
+            })
+        );
+
+        let future = Handoff {
+            version: HANDOFF_VERSION + 1,
+            ..handoff
+        };
+        assert_eq!(
+            future.check("canvas", 300, 2_000, max_age),
+            Err(HandoffRejection::Version(HANDOFF_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_sealed_handoffs_hold_no_plaintext() {
+        let key = Some(Arc::new(
+            EventLogKey::new("k1", &[7; encryption::KEY_LENGTH]).unwrap(),
+        ));
+        let dir = std::env::temp_dir();
+        let path = |canvas_id: &str| {
+            dir.join(format!("{canvas_id}.handoff.json"))
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let canvas_id = nanoid::nanoid!(12);
+        let mut written = handoff(&canvas_id);
+        written.shapes.insert("secret-shape".to_string());
+        written
+            .write_sealed(&path(&canvas_id), key.clone())
+            .unwrap();
+
+        let content = fs::read_to_string(path(&canvas_id)).unwrap();
+        for plaintext in [canvas_id.as_str(), "secret-shape", "event_log"] {
+            assert!(!content.contains(plaintext), "{content}");
+        }
+        assert!(!std::path::Path::new(&format!("{}.tmp", path(&canvas_id))).exists());
+
+        // a handoff moved to another canvas does not open
+        let other_id = nanoid::nanoid!(12);
+        fs::write(path(&other_id), &content).unwrap();
+        assert!(Handoff::take_sealed(&path(&other_id), key.clone()).is_err());
+        assert!(Handoff::take_sealed(&path(&canvas_id), None).is_err());
+
+        written
+            .write_sealed(&path(&canvas_id), key.clone())
+            .unwrap();
+        let taken = Handoff::take_sealed(&path(&canvas_id), key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(taken.canvas_id, canvas_id);
+        assert!(taken.shapes.contains("secret-shape"));
+        // both are removed, also the one that could not be opened
+        assert!(!std::path::Path::new(&path(&canvas_id)).exists());
+        assert!(!std::path::Path::new(&path(&other_id)).exists());
+    }
+}
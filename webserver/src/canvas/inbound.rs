@@ -0,0 +1,190 @@
+use serde_json::{Map, Value};
+
+use super::events::ClientEvent;
+use crate::messages::{Message, MessageKey};
+
+// Strict parsing of the events clients send over the websocket
+// Deserializing CanvasEvents ignores fields it does not know, a typo like shapeID instead of shapeId
+// would otherwise silently lose the value, so every field of an inbound event has to be known
+// A field is known if it is part of the parsed event serialized again, fields left out when empty
+// are accepted with an empty value, the parsed event is the same with or without them
+// Eventlogs are read with the lenient derive, lines written by newer versions still load
+
+/// Characters of the payload quoted in the error
+const EXCERPT_LENGTH: usize = 200;
+
+/// Why an inbound message was not accepted, as told to its sender
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundError {
+    /// serde error, or the path of the unknown field
+    pub reason: String,
+    /// byte offset of the error in the payload, None if serde does not report one
+    pub offset: Option<usize>,
+    /// position of the error, both counted from 1
+    pub line: usize,
+    pub column: usize,
+    /// start of the payload
+    pub excerpt: String,
+    /// operation of the message, if it could be read
+    pub op_id: Option<String>,
+}
+
+impl InboundError {
+    fn new(msg: &str, reason: String, line: usize, column: usize) -> Self {
+        Self {
+            reason,
+            offset: None,
+            line,
+            column,
+            excerpt: msg.chars().take(EXCERPT_LENGTH).collect(),
+            op_id: None,
+        }
+    }
+
+    fn unknown_field(msg: &str, path: &str, field: &str) -> Self {
+        // the first occurrence of the key, a value repeating it is a rare enough mismatch
+        let offset = msg.find(&format!("\"{field}\""));
+        let (line, column) = offset.map_or((0, 0), |offset| position(msg, offset));
+        Self {
+            offset,
+            ..Self::new(msg, format!("unknown field `{path}`"), line, column)
+        }
+    }
+
+    /// Feedback for the sender
+    pub fn message(&self) -> Message {
+        Message::new(MessageKey::EventMalformed)
+            .param("reason", &self.reason)
+            .param("line", self.line)
+            .param("column", self.column)
+            .param("excerpt", &self.excerpt)
+    }
+}
+
+/// Line and column of a byte offset, both counted from 1
+fn position(msg: &str, offset: usize) -> (usize, usize) {
+    let before = &msg[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Object(map) => map.is_empty(),
+        Value::Array(values) => values.is_empty(),
+        _ => false,
+    }
+}
+
+/// First field of received that is missing in parsed, nested objects are compared as well
+fn unknown_field(received: &Value, parsed: &Value, path: &str) -> Option<(String, String)> {
+    match (received, parsed) {
+        (Value::Object(received), Value::Object(parsed)) => {
+            unknown_object_field(received, parsed, path)
+        }
+        (Value::Array(received), Value::Array(parsed)) => received
+            .iter()
+            .zip(parsed)
+            .find_map(|(received, parsed)| unknown_field(received, parsed, path)),
+        _ => None,
+    }
+}
+
+fn unknown_object_field(
+    received: &Map<String, Value>,
+    parsed: &Map<String, Value>,
+    path: &str,
+) -> Option<(String, String)> {
+    received.iter().find_map(|(field, value)| {
+        let field_path = if path.is_empty() {
+            field.clone()
+        } else {
+            format!("{path}.{field}")
+        };
+        match parsed.get(field) {
+            Some(parsed) => unknown_field(value, parsed, &field_path),
+            None if is_empty(value) => None,
+            None => Some((field_path, field.clone())),
+        }
+    })
+}
+
+/// Parses a client message, unknown fields are rejected
+pub fn parse_client_event(msg: &str) -> Result<ClientEvent, InboundError> {
+    let received: Value = serde_json::from_str(msg)
+        .map_err(|e| InboundError::new(msg, e.to_string(), e.line(), e.column()))?;
+    let op_id = received
+        .get("opId")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let with_op_id = |error: InboundError| InboundError {
+        op_id: op_id.clone(),
+        ..error
+    };
+
+    // parsed from the text again, so serde reports the position of a wrong value
+    let event: ClientEvent = serde_json::from_str(msg)
+        .map_err(|e| with_op_id(InboundError::new(msg, e.to_string(), e.line(), e.column())))?;
+
+    let parsed = serde_json::to_value(&event.event)
+        .map_err(|e| with_op_id(InboundError::new(msg, e.to_string(), 0, 0)))?;
+    let Value::Object(mut received) = received else {
+        return Ok(event);
+    };
+    received.remove("opId");
+    match unknown_field(&Value::Object(received), &parsed, "") {
+        Some((path, field)) => Err(with_op_id(InboundError::unknown_field(msg, &path, &field))),
+        None => Ok(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::events::CanvasEvents;
+
+    const LINE: &str = r##"{"type":"ShapeAdded","origin":"s","timestamp":1,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":5,"y":5}}}"##;
+
+    #[test]
+    fn test_known_fields_are_accepted() {
+        let event = parse_client_event(LINE).unwrap();
+        assert!(event.opId.is_none());
+        assert!(matches!(event.event, CanvasEvents::ShapeAdded { .. }));
+
+        // fields left out when empty and the operation id
+        let with_optional = LINE.replace(
+            r#""temporary":false"#,
+            r#""temporary":false,"attributes":{}"#,
+        );
+        let with_optional = with_optional.replacen('{', r#"{"opId":"op1","userId":null,"#, 1);
+        let event = parse_client_event(&with_optional).unwrap();
+        assert_eq!(event.opId.as_deref(), Some("op1"));
+
+        // partial shapes of updates are free-form
+        let update = r#"{"type":"ShapeUpdated","origin":"s","timestamp":1,"shape":{"id":"l1","anything":1}}"#;
+        assert!(parse_client_event(update).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected_with_their_position() {
+        let removed = "{\"type\":\"ShapeRemoved\",\"opId\":\"op1\",\"origin\":\"s\",\n\"timestamp\":1,\"shapeId\":\"l1\",\"shapeID\":\"l2\"}";
+        let error = parse_client_event(removed).unwrap_err();
+        assert_eq!(error.reason, "unknown field `shapeID`");
+        assert_eq!(error.op_id.as_deref(), Some("op1"));
+        assert_eq!((error.line, error.column), (2, 30));
+        assert_eq!(error.offset, removed.find("\"shapeID\""));
+
+        let nested = LINE.replace(r#""x":0,"y":0"#, r#""x":0,"y":0,"z":3"#);
+        let error = parse_client_event(&nested).unwrap_err();
+        assert_eq!(error.reason, "unknown field `shape.from.z`");
+        assert!(error
+            .message()
+            .render(Default::default())
+            .contains("shape.from.z"));
+
+        let error = parse_client_event(&"x".repeat(500)).unwrap_err();
+        assert_eq!(error.excerpt.len(), EXCERPT_LENGTH);
+    }
+}
@@ -1,224 +1,3762 @@
 use crate::{
-    authentication::{self, JWTClaims, RegenerateJWTMarker},
-    templates, userstore,
+    admin,
+    api::pagination::{self, ListEndpoint, ListParams, ListQuery},
+    authentication::{self, AuthContext, RegenerateJWTMarker},
+    clock::{self, Clock},
+    connection::ConnectionMeta,
+    forms::{self, FormOrJson},
+    maintenance_mode,
+    messages::{self, Message, MessageBody, MessageKey},
+    notifier::Notifier,
+    persistence::EventLogPersistenceJson,
+    security, spa, templates, userstore,
+};
+use access_requests::{
+    AccessRequest, AccessRequestLimiter, AccessRequestNotice, AccessRequestResolution,
+    AccessRequestStatus,
 };
 use actix_web::{
-    error::{ErrorInternalServerError, ErrorUnauthorized},
-    web, HttpMessage, HttpRequest, HttpResponse, Responder, Result,
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, Result,
 };
+use guests::GuestAccess;
 use handlebars::Handlebars;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use server::CanvasSocketServerHandle;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use store::{
-    AccessLevel, AddUserToCanvasMessage, CanvasState, CreateCanvas, CreateCanvasMessage,
-    UpdateCanvasStateMessage,
+    AccessLevel, AddUserToCanvasMessage, CanvasSettings, CanvasStateAction, CreateCanvas,
+    CreateCanvasMessage, DeleteCanvasMessage, InvalidTags, RestoreCanvasMessage,
+    UpdateCanvasFeatureFlagsMessage, UpdateCanvasPaletteMessage, UpdateCanvasSettingsMessage,
+    UpdateCanvasStateMessage, UpdateCanvasTagsMessage,
 };
+use tokens::TokenRateLimiter;
 use tokio::task::spawn_local;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
+pub mod access_requests;
+pub mod activity;
+pub mod attributes;
+pub mod backpressure;
+pub mod binding;
+pub mod bus;
+pub mod claims;
+pub mod client;
+pub mod coalesce;
+pub mod comments;
+pub mod contributors;
+pub mod diagnostics;
+pub mod digest;
+pub mod document;
 pub mod error;
 pub mod events;
+pub mod export;
+pub mod features;
+pub mod geometry;
+pub mod guests;
+pub mod handoff;
+pub mod inbound;
+pub mod names;
+pub mod orphans;
+pub mod palette;
+pub mod path;
+pub mod print;
+pub mod provenance;
+pub mod quota;
+pub mod receipts;
+pub mod redaction;
+pub mod replay;
+pub mod retention;
+pub mod search;
 pub mod server;
 pub mod socket_handler;
 pub mod store;
+pub mod tokens;
+pub mod transfer;
+pub mod validation;
+pub mod viewport;
+
+// Handler for API endpoints related to canvas management
+
+#[derive(Deserialize, ToSchema)]
+struct CreateCanvasForm {
+    name: String,
+    /// a name the caller already uses gets the first free " (n)" suffix instead of colliding
+    #[serde(default)]
+    auto_rename: bool,
+}
+
+/// Canvas created or renamed, the name may differ from the requested one with auto_rename
+#[derive(Serialize, ToSchema)]
+struct CanvasName {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct RenameCanvasForm {
+    name: String,
+    /// a name the owner already uses gets the first free " (n)" suffix instead of colliding
+    #[serde(default)]
+    auto_rename: bool,
+}
+
+/// States of the form endpoint, it predates archiving
+#[derive(Deserialize, ToSchema)]
+pub(crate) enum LegacyCanvasState {
+    Active,
+    Moderated,
+}
+
+impl LegacyCanvasState {
+    /// Action reaching the submitted state
+    fn action(&self) -> CanvasStateAction {
+        match self {
+            LegacyCanvasState::Active => CanvasStateAction::Activate,
+            LegacyCanvasState::Moderated => CanvasStateAction::Moderate,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateCanvasForm {
+    state: LegacyCanvasState,
+    /// canvas version the client based the change on
+    expected_version: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CanvasStateForm {
+    action: CanvasStateAction,
+    /// canvas version the client based the change on
+    expected_version: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateCanvasSettingsForm {
+    /// grid spacing in pixels, missing hides the grid
+    grid_size: Option<u32>,
+    #[serde(default)]
+    snap_enabled: bool,
+    #[serde(default)]
+    shape_ownership_enforced: bool,
+    /// missing keeps the voice behavior of the canvas
+    legacy_voice_behavior: Option<bool>,
+    /// missing keeps the anonymization, Read sessions see pseudonyms instead of users
+    anonymize_for_readers: Option<bool>,
+    /// missing keeps the guest access, visitors without an account join at its level
+    guest_access: Option<GuestAccess>,
+    /// missing keeps the digest frequency, Off, Daily or Weekly
+    digest: Option<digest::DigestFrequency>,
+    /// the metadata is kept if all of its fields are missing, otherwise it is replaced
+    author_display: Option<String>,
+    /// SPDX identifier, all-rights-reserved or custom, empty removes the license
+    license: Option<String>,
+    /// text of a custom license
+    custom_license: Option<String>,
+    description: Option<String>,
+    /// the retention overrides are kept if both are missing, otherwise they are replaced
+    /// all, last:<count>, days:<days> or none, empty uses the configured retention
+    history_retention: Option<String>,
+    read_receipt_retention: Option<String>,
+    /// missing keeps the restriction, only new shapes of these types may be added, empty allows all
+    allowed_shape_types: Option<TagList>,
+    /// missing keeps the bypass, owners and moderators may add shapes of every type
+    moderators_bypass_shape_types: Option<bool>,
+}
+
+impl UpdateCanvasSettingsForm {
+    /// Submitted values a rejected form is prefilled with
+    fn flash_values(&self) -> templates::Flash {
+        let values = [
+            (
+                "grid_size",
+                self.grid_size.map(|grid_size| grid_size.to_string()),
+            ),
+            ("snap_enabled", Some(self.snap_enabled.to_string())),
+            (
+                "shape_ownership_enforced",
+                Some(self.shape_ownership_enforced.to_string()),
+            ),
+            ("author_display", self.author_display.clone()),
+            ("license", self.license.clone()),
+            ("custom_license", self.custom_license.clone()),
+            ("description", self.description.clone()),
+            ("history_retention", self.history_retention.clone()),
+            (
+                "read_receipt_retention",
+                self.read_receipt_retention.clone(),
+            ),
+        ];
+        values
+            .into_iter()
+            .filter_map(|(field, value)| Some((field, value?)))
+            .fold(templates::Flash::error(""), |flash, (field, value)| {
+                flash.value(field, value)
+            })
+    }
+
+    /// None if the form leaves the metadata as it is, Some(None) if it removes it
+    fn metadata(&mut self) -> Result<Option<Option<store::CanvasMetadata>>> {
+        if self.author_display.is_none()
+            && self.license.is_none()
+            && self.custom_license.is_none()
+            && self.description.is_none()
+        {
+            return Ok(None);
+        }
+
+        let license = match self.license.take().filter(|license| !license.is_empty()) {
+            Some(identifier) => Some(
+                store::CanvasLicense::parse(&identifier, self.custom_license.take()).ok_or_else(
+                    || {
+                        messages::unprocessable_entity(
+                            Message::new(MessageKey::CanvasLicenseUnknown)
+                                .param("reason", "invalid_value")
+                                .param("field", "license")
+                                .param("license", identifier.as_str()),
+                        )
+                    },
+                )?,
+            ),
+            None => None,
+        };
+        let metadata = store::CanvasMetadata {
+            author_display: self.author_display.take(),
+            license,
+            description: self.description.take(),
+        };
+
+        store::normalize_metadata(metadata)
+            .map(Some)
+            .map_err(|invalid| {
+                messages::unprocessable_entity(
+                    Message::new(MessageKey::CanvasMetadataInvalid)
+                        .param("reason", "invalid_value")
+                        .param("field", invalid.field)
+                        .param("max", invalid.max),
+                )
+                .into()
+            })
+    }
+
+    /// None if the form leaves the retention overrides as they are
+    fn retention(&self) -> Result<Option<retention::RetentionOverrides>> {
+        if self.history_retention.is_none() && self.read_receipt_retention.is_none() {
+            return Ok(None);
+        }
+
+        let parse = |value: &Option<String>, field: &str| {
+            value
+                .as_deref()
+                .filter(|value| !value.is_empty())
+                .map(str::parse::<retention::Retention>)
+                .transpose()
+                .map_err(|_| {
+                    messages::unprocessable_entity(
+                        Message::new(MessageKey::CanvasRetentionInvalid)
+                            .param("reason", "invalid_value")
+                            .param("field", field),
+                    )
+                })
+        };
+        Ok(Some(retention::RetentionOverrides {
+            history: parse(&self.history_retention, "history_retention")?,
+            read_receipts: parse(&self.read_receipt_retention, "read_receipt_retention")?,
+        }))
+    }
+
+    /// None if the form leaves the allowed shape types as they are
+    fn allowed_shape_types(&mut self) -> Result<Option<BTreeSet<events::ShapeType>>> {
+        let Some(names) = self.allowed_shape_types.take() else {
+            return Ok(None);
+        };
+
+        let names: Vec<String> = names
+            .into_tags()
+            .into_iter()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let unknown: Vec<&str> = names
+            .iter()
+            .map(String::as_str)
+            .filter(|name| events::ShapeType::from_name(name).is_none())
+            .collect();
+        if !unknown.is_empty() {
+            return Err(messages::unprocessable_entity(
+                Message::new(MessageKey::CanvasShapeTypesInvalid)
+                    .param("reason", "invalid_value")
+                    .param("field", "allowed_shape_types")
+                    .param("types", unknown.join(", "))
+                    .param(
+                        "valid",
+                        events::Shape::type_names().collect::<Vec<_>>().join(", "),
+                    ),
+            )
+            .into());
+        }
+        Ok(Some(
+            names
+                .iter()
+                .filter_map(|name| events::ShapeType::from_name(name))
+                .collect(),
+        ))
+    }
+}
+
+/// Tags as JSON list or, for forms, as comma separated text
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum TagList {
+    List(Vec<String>),
+    Text(String),
+}
+
+impl TagList {
+    fn into_tags(self) -> Vec<String> {
+        match self {
+            TagList::List(tags) => tags,
+            TagList::Text(text) if text.trim().is_empty() => Vec::new(),
+            TagList::Text(text) => text.split(',').map(str::to_string).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateCanvasTagsForm {
+    /// replaces all tags of the canvas, empty removes them
+    tags: TagList,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UpdateCanvasFeatureFlagsForm {
+    /// replaces all overrides of the canvas, empty falls back to the server config
+    overrides: BTreeMap<String, bool>,
+}
+
+/// Palette of a canvas, replaced as a whole, see palette.rs
+#[derive(Deserialize, Serialize, ToSchema)]
+struct CanvasPalette {
+    /// in the order of the color pickers, at most palette::MAX_PALETTE_COLORS
+    colors: Vec<palette::PaletteColor>,
+    /// shapes have to use colors of the palette
+    #[serde(default)]
+    enforce_palette: bool,
+}
+
+impl CanvasPalette {
+    fn of(settings: &CanvasSettings) -> Self {
+        Self {
+            colors: settings.palette.clone(),
+            enforce_palette: settings.enforce_palette,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct CanvasFeatureFlags {
+    /// flags in effect for the requesting user
+    flags: features::ResolvedFlags,
+    /// flags switched by the owner
+    overrides: BTreeMap<String, bool>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateApiTokenForm {
+    label: String,
+    /// Read or Write
+    access_level: store::AccessLevel,
+    /// unix timestamp in milliseconds, tokens without expiry stay valid until revoked
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CreatedApiToken {
+    #[serde(flatten)]
+    details: tokens::ApiToken,
+    /// plaintext token, only part of this response
+    token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AddUserCanvasFrom {
+    access_level: store::AccessLevel,
+    username_email: String,
+    /// temporary access, unix timestamp in milliseconds
+    expires_at: Option<u64>,
+    /// "members" if submitted from the members page, see submitted_from_members_page
+    return_to: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CanvasMember {
+    user_id: userstore::UserId,
+    username: String,
+    access_level: AccessLevel,
+    /// has a live session on the canvas
+    online: bool,
+    expires_at: Option<u64>,
+    /// seconds until temporary access expires
+    remaining_seconds: Option<u64>,
+}
+
+/// Read receipt of a member, sequence numbers are lines of the canvas eventlog
+#[derive(Serialize, ToSchema)]
+struct MemberReadState {
+    user_id: userstore::UserId,
+    username: String,
+    access_level: AccessLevel,
+    /// last change the member has seen, missing if the member never opened the canvas
+    last_seen_seq: Option<u64>,
+    /// unix timestamp in seconds
+    last_seen_at: Option<u64>,
+    up_to_date: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CanvasReadState {
+    /// sequence number of the last change of the drawing
+    current_seq: u64,
+    members: Vec<MemberReadState>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CanvasStats {
+    quotas: Vec<quota::QuotaUsage>,
+    /// issued quota warnings, oldest first
+    warnings: Vec<quota::QuotaWarning>,
+    /// events per category and what the next compaction would reclaim under the retention of the canvas
+    retention: retention::RetentionReport,
+}
+
+/// Diagnostics of a canvas, only the loaded canvas has counters
+#[derive(Serialize, ToSchema)]
+struct CanvasDiagnosticsResponse {
+    loaded: bool,
+    #[serde(flatten)]
+    report: Option<diagnostics::DiagnosticsReport>,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ReplayQuery {
+    /// timestamp in seconds or seq:<number>, replays the whole log if omitted
+    until: Option<String>,
+    /// adds who last changed every shape and when
+    #[serde(default)]
+    provenance: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ExportQuery {
+    /// same as the cutoff of the replay
+    until: Option<String>,
+    /// adds a visible footer crediting author and license to the SVG
+    #[serde(default)]
+    attribution: bool,
+    /// adds who last changed every shape and when to the JSON document
+    #[serde(default)]
+    provenance: bool,
+    /// adds the comments of the shapes to the JSON document
+    #[serde(default)]
+    comments: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct PrintQuery {
+    /// lists the open comments below the drawing
+    #[serde(default)]
+    comments: bool,
+    /// counts the shapes per type
+    #[serde(default)]
+    legend: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReplayResponse<'a> {
+    #[serde(flatten)]
+    state: &'a replay::CanvasShapeState,
+    /// only with ?provenance=true, keyed by shape id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<&'a provenance::Provenance>,
+}
+
+/// Canvas document answered by GET /canvas/{canvas_id}/export.json, imported again by POST /canvas/import
+#[derive(Serialize, ToSchema)]
+struct JsonExport {
+    #[serde(flatten)]
+    document: document::CanvasDocument,
+    /// only with ?comments=true, open and resolved, never imported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comments: Option<Vec<CommentResponse>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct CommentsQuery {
+    /// only the comments of this shape
+    shape_id: Option<String>,
+    /// only open or only resolved comments, both if left out
+    status: Option<comments::CommentStatus>,
+}
+
+/// Comment with the current name of its author, answered by GET /canvas/{canvas_id}/comments
+#[derive(Serialize, ToSchema)]
+struct CommentResponse {
+    #[serde(flatten)]
+    comment: comments::Comment,
+    /// None if the author is unknown
+    author_name: Option<String>,
+}
+
+/// Last change of a shape, answered by GET /canvas/{canvas_id}/shapes/{shape_id}/provenance
+#[derive(Serialize, ToSchema)]
+struct ShapeProvenanceResponse {
+    shape_id: String,
+    #[serde(flatten)]
+    provenance: provenance::ShapeProvenance,
+    /// current name of the editor, None if the editor is unknown
+    last_modified_by_name: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct KeyframesQuery {
+    every: Option<usize>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+struct ShapeSearchQuery {
+    #[serde(rename = "type")]
+    shape_type: Option<events::ShapeType>,
+    /// exact match, e.g. %23ff0000
+    border_color: Option<String>,
+    /// exact match, e.g. %23ff0000
+    fill_color: Option<String>,
+    id_prefix: Option<String>,
+    /// left edge of the area the shapes intersect, x, y, w and h are only given together
+    x: Option<i32>,
+    y: Option<i32>,
+    w: Option<u32>,
+    h: Option<u32>,
+    /// shapes per page, at most 500
+    limit: Option<usize>,
+    /// next_cursor of the previous page
+    cursor: Option<String>,
+}
+
+impl ShapeSearchQuery {
+    fn filter(&self) -> std::result::Result<search::ShapeFilter, Message> {
+        let area = match (self.x, self.y, self.w, self.h) {
+            (None, None, None, None) => None,
+            (Some(x), Some(y), Some(w), Some(h)) => Some((
+                events::Point2D { x, y },
+                events::Point2D {
+                    x: x.saturating_add_unsigned(w),
+                    y: y.saturating_add_unsigned(h),
+                },
+            )),
+            _ => return Err(Message::new(MessageKey::InvalidShapeSearchArea)),
+        };
+        Ok(search::ShapeFilter {
+            shape_type: self.shape_type,
+            border_color: self.border_color.clone(),
+            fill_color: self.fill_color.clone(),
+            id_prefix: self.id_prefix.clone(),
+            area,
+        })
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct EventsExportQuery {
+    /// only lines after this sequence number, for incremental backups
+    #[serde(default)]
+    since_seq: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct EventsImportQuery {
+    name: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ImportedCanvas {
+    canvas_id: store::CanvasId,
+    /// events written to the eventlog of the new canvas
+    events: usize,
+}
+
+/// Name of imported canvases if the import names none
+const IMPORTED_CANVAS_NAME: &str = "Imported canvas";
+
+#[derive(Deserialize, IntoParams)]
+struct DuplicateQuery {
+    /// name of the copy, the name of the canvas with a suffix if left out
+    name: Option<String>,
+}
+
+/// Canvas created from a canvas document
+#[derive(Serialize, ToSchema)]
+struct MaterializedCanvas {
+    canvas_id: store::CanvasId,
+    /// new ids of the shapes of the document, from back to front
+    id_mapping: Vec<document::ShapeIdMapping>,
+}
+
+/// JSON endpoints of the canvas service, see api_docs
+#[derive(OpenApi)]
+#[openapi(paths(
+    canvas_add_user_handler,
+    canvas_add_users_handler,
+    canvas_delete_handler,
+    canvas_restore_handler,
+    canvas_state_handler,
+    canvas_update_handler,
+    canvas_settings_handler,
+    canvas_tags_handler,
+    canvas_rename_handler,
+    canvas_access_handler,
+    canvas_request_access_handler,
+    canvas_cancel_access_request_handler,
+    canvas_access_requests_handler,
+    canvas_resolve_access_request_handler,
+    canvas_flags_handler,
+    canvas_update_flags_handler,
+    canvas_palette_handler,
+    canvas_update_palette_handler,
+    canvas_tokens_handler,
+    canvas_create_token_handler,
+    canvas_revoke_token_handler,
+    canvas_members_handler,
+    canvas_stats_handler,
+    canvas_diagnostics_handler,
+    canvas_read_state_handler,
+    canvas_replay_handler,
+    canvas_shapes_handler,
+    canvas_shape_provenance_handler,
+    canvas_comments_handler,
+    canvas_keyframes_handler,
+    canvas_export_svg_handler,
+    canvas_export_json_handler,
+    canvas_export_events_handler,
+    canvas_print_handler,
+    canvas_import_events_handler,
+    canvas_import_handler,
+    canvas_duplicate_handler,
+))]
+pub(crate) struct CanvasApi;
+
+/// Display the canvas page
+#[allow(clippy::too_many_arguments)]
+async fn canvas_page_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    handlebars: web::Data<Handlebars<'static>>,
+    canvas_id: web::Path<String>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+    record_canvas_visit_recipient: web::Data<actix::Recipient<store::RecordCanvasVisitMessage>>,
+    get_user_recipient: web::Data<actix::Recipient<userstore::GetUserMessage>>,
+    feature_flags: web::Data<features::FeatureFlags>,
+) -> Result<HttpResponse> {
+    auth.require_user()?;
+    let clock = clock::request_clock(&request);
+    // without a claim the CanvasStore decides, access may have been granted after the token was issued
+    let access_level = auth.access_level(&canvas_id).await?;
+    if access_level == AccessLevel::None {
+        return no_access_page(&request, &handlebars, &canvas_id).await;
+    }
+    if auth.claim_for(&canvas_id).is_none()
+        && auth.claims().can.len() < authentication::JWT_CLAIM_LIMIT
+    {
+        // the response carries a token with the claim, the websocket connects with it
+        request.extensions_mut().insert(RegenerateJWTMarker);
+    }
+
+    let canvas = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.into_inner(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::RenderFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    // only orders the home page, the page is rendered even if the visit is lost
+    // guests have no home page, nothing about them is persisted
+    if !guests::is_guest(auth.user_id()) {
+        record_canvas_visit_recipient.do_send(store::RecordCanvasVisitMessage {
+            user_id: auth.user_id().clone(),
+            canvas_id: canvas.id.clone(),
+            timestamp: clock.now_ms(),
+        });
+    }
+
+    // the UI starts with the defaults if the preferences can't be loaded
+    let preferences = match guests::is_guest(auth.user_id()) {
+        true => None,
+        false => get_user_recipient
+            .send(userstore::GetUserMessage {
+                username_email: None,
+                user_id: Some(auth.user_id().clone()),
+            })
+            .await
+            .ok()
+            .flatten()
+            .map(|user| user.preferences),
+    }
+    .unwrap_or_default();
+
+    let mut response = HttpResponse::Ok();
+    let template_data = json!({
+        "canvasId": canvas.id,
+        "userId": auth.user_id(),
+        "canWrite": access_level.can_write_in(&canvas.state, canvas.settings.legacy_voice_behavior),
+        "accessLevel": access_level,
+        "canvasName": canvas.name,
+        "canvasVersion": canvas.version,
+        // read by the page from a script element, see templates::script_json
+        "bootstrap": {
+            "canvasName": canvas.name,
+            "metadata": canvas.settings.metadata,
+            // resolved like the ServerHello of the websocket, so page and socket agree
+            "flags": feature_flags.resolve(auth.user_id(), &canvas.feature_overrides),
+            "palette": CanvasPalette::of(&canvas.settings),
+            // clients grey out the tools of other shape types, empty allows all
+            "allowedShapeTypes": canvas.settings.allowed_shape_types,
+            // applied before the first paint, the version is the If-Match of the next change
+            "preferences": preferences,
+        },
+        "timestamp": clock.now_secs(), // needed to force browser reevaluation of script
+        "serverTimeMs": clock.now_ms(),
+        "nonce": security::csp_nonce(&request),
+        // errors of a settings form submitted without the SPA
+        "flash": templates::take_flash(&request, &mut response),
+    });
+
+    let page = templates::render_timed(&request, &handlebars, "canvas", template_data).await?;
+    Ok(response.content_type(ContentType::html()).body(page))
+}
+
+/// Page shown instead of the canvas, polls the access endpoint and reloads once access was granted
+/// Rendered with 403, the SPA shows it as page because of the RENDER_HEADER
+async fn no_access_page(
+    request: &HttpRequest,
+    handlebars: &web::Data<Handlebars<'static>>,
+    canvas_id: &str,
+) -> Result<HttpResponse> {
+    let template_data = json!({
+        "canvasId": canvas_id,
+        "pollIntervalMs": ACCESS_POLL_INTERVAL.as_millis() as u64,
+        "maxMessageLength": access_requests::MAX_ACCESS_REQUEST_MESSAGE_LENGTH,
+        "nonce": security::csp_nonce(request),
+    });
+    let page =
+        templates::render_timed(request, handlebars, "canvas-no-access", template_data).await?;
+    Ok(HttpResponse::Forbidden()
+        .insert_header((spa::RENDER_HEADER, "true"))
+        .content_type(ContentType::html())
+        .body(page))
+}
+
+/// Time between two polls of the no access page
+pub const ACCESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Polls of the access endpoint per user and window, leaves room for a few open pages
+pub const ACCESS_POLL_LIMIT: u32 = 30;
+
+/// Fixed window rate limit of the access endpoint per user, shared between all workers using web::Data
+pub struct AccessPollLimiter(TokenRateLimiter);
+
+impl Default for AccessPollLimiter {
+    fn default() -> Self {
+        Self(TokenRateLimiter::new(
+            ACCESS_POLL_LIMIT,
+            Duration::from_secs(60),
+        ))
+    }
+}
+
+impl AccessPollLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self(TokenRateLimiter::new(limit, window))
+    }
+}
+
+/// Access level of the caller on a canvas
+#[derive(Serialize, ToSchema)]
+struct CanvasAccess {
+    access_level: AccessLevel,
+    /// latest access request of a caller without access, lets the page show that it was sent or answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_request: Option<AccessRequestStatus>,
+}
+
+/// Current access level of the caller, polled by the no access page until access is granted
+/// A plain store lookup, the token of the caller is left as it is
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/access",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasAccess), (status = 429, description = "polled too often", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_access_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_membership_recipient: web::Data<actix::Recipient<store::GetCanvasMembershipMessage>>,
+) -> Result<impl Responder> {
+    if let Some(limiter) = request.app_data::<web::Data<AccessPollLimiter>>() {
+        if !limiter.0.allow(auth.user_id(), Instant::now()) {
+            return Err(messages::too_many_requests(MessageKey::CanvasAccessPollLimited).into());
+        }
+    }
+
+    let access_level = auth.access_level(&canvas_id).await?;
+    let access_request = match access_level {
+        AccessLevel::None => get_canvas_membership_recipient
+            .send(store::GetCanvasMembershipMessage {
+                canvas_id: canvas_id.into_inner(),
+            })
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?
+            .and_then(|membership| {
+                membership
+                    .access_requests
+                    .into_iter()
+                    .rfind(|request| request.user_id == *auth.user_id())
+            })
+            .map(|request| request.status),
+        _ => None,
+    };
+    Ok(web::Json(CanvasAccess {
+        access_level,
+        access_request,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AccessRequestForm {
+    /// shown to the owner and moderators, at most MAX_ACCESS_REQUEST_MESSAGE_LENGTH characters
+    message: Option<String>,
+}
+
+/// Ask the owner and moderators of a canvas for access, the caller must not have access yet
+/// A pending request is answered as it is, nobody is notified twice
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/request-access",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = AccessRequestForm,
+    responses((status = 200, description = "with Accept: application/json, a message otherwise", body = AccessRequest), (status = 404, description = "unknown canvas", body = MessageBody), (status = 409, description = "the caller has access already", body = MessageBody), (status = 422, description = "message too long", body = MessageBody), (status = 429, description = "requested too often", body = MessageBody))
+)]
+async fn canvas_request_access_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    access_request_form: FormOrJson<AccessRequestForm>,
+    request_canvas_access_recipient: web::Data<actix::Recipient<store::RequestCanvasAccessMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    notifier: web::Data<dyn Notifier>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+    let canvas_id = canvas_id.into_inner();
+
+    if let Some(limiter) = request.app_data::<web::Data<AccessRequestLimiter>>() {
+        if !limiter.allow(auth.user_id(), &canvas_id, Instant::now()) {
+            return Err(messages::too_many_requests(MessageKey::AccessRequestRateLimited).into());
+        }
+    }
+    let message =
+        access_requests::sanitize_message(access_request_form.into_inner().message.as_deref())?;
+
+    let outcome = request_canvas_access_recipient
+        .send(store::RequestCanvasAccessMessage {
+            canvas_id: canvas_id.clone(),
+            user_id: auth.user_id().clone(),
+            message,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    if outcome.created {
+        println!(
+            "Access request: {} asked for access to {canvas_id}",
+            auth.user_id().clone()
+        );
+        let notice = AccessRequestNotice {
+            canvas_id: canvas_id.clone(),
+            canvas_name: outcome.canvas_name.clone(),
+            request: outcome.request.clone(),
+        };
+        for manager in &outcome.managers {
+            notifier.access_requested(manager, &notice);
+        }
+        canvas_server_handle.notify_users(
+            outcome.managers,
+            events::NoticeLevel::Notice,
+            Message::new(MessageKey::AccessRequestReceived)
+                .param("user", auth.username())
+                .param("canvas", &outcome.canvas_name),
+        );
+    }
+
+    if messages::accepts_json(&request) {
+        return Ok(HttpResponse::Ok().json(outcome.request));
+    }
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::AccessRequestSent.into(),
+    ))
+}
+
+/// Withdraw the pending access request of the caller
+#[utoipa::path(
+    delete,
+    path = "/canvas/{canvas_id}/request-access",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = MessageBody), (status = 404, description = "no pending request", body = MessageBody))
+)]
+async fn canvas_cancel_access_request_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    resolve_access_request_recipient: web::Data<
+        actix::Recipient<store::ResolveAccessRequestMessage>,
+    >,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+    resolve_access_request_recipient
+        .send(store::ResolveAccessRequestMessage {
+            canvas_id: canvas_id.into_inner(),
+            initiator_id: auth.user_id().clone(),
+            user_id: auth.user_id().clone(),
+            resolution: AccessRequestResolution::Cancelled,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::AccessRequestCancelled.into(),
+    ))
+}
+
+/// Access request with the name of the requester
+#[derive(Serialize, ToSchema)]
+struct AccessRequestEntry {
+    username: String,
+    #[serde(flatten)]
+    request: AccessRequest,
+}
+
+/// Pending access requests and the ones resolved recently, oldest first, only for owners and moderators
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/access-requests",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = Vec<AccessRequestEntry>), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody))
+)]
+async fn canvas_access_requests_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_membership_recipient: web::Data<actix::Recipient<store::GetCanvasMembershipMessage>>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+) -> Result<impl Responder> {
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::AccessRequestResolveDenied,
+    )
+    .await?;
+
+    let membership = get_canvas_membership_recipient
+        .send(store::GetCanvasMembershipMessage {
+            canvas_id: canvas_id.into_inner(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+    Ok(web::Json(
+        access_request_entries(membership.access_requests, &get_usernames_recipient).await?,
+    ))
+}
+
+async fn access_request_entries(
+    access_requests: Vec<AccessRequest>,
+    get_usernames_recipient: &actix::Recipient<userstore::GetUsernamesMessage>,
+) -> Result<Vec<AccessRequestEntry>> {
+    let usernames = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: access_requests
+                .iter()
+                .map(|request| request.user_id.clone())
+                .collect(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?;
+    Ok(access_requests
+        .into_iter()
+        .map(|request| AccessRequestEntry {
+            username: usernames.get(&request.user_id).cloned().unwrap_or_default(),
+            request,
+        })
+        .collect())
+}
+
+#[derive(Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum AccessRequestDecision {
+    Approve,
+    Deny,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ResolveAccessRequestForm {
+    decision: AccessRequestDecision,
+    /// level granted on approval, Read if left out
+    access_level: Option<AccessLevel>,
+    /// "members" redirects back to the members page
+    return_to: Option<String>,
+}
+
+/// Approve or deny the pending access request of a user
+/// An approval grants the chosen level like adding the user, a level the caller can't grant is rejected
+/// Submitted from the members page it redirects back with the outcome as flash
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/access-requests/{user_id}",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ("user_id" = String, Path, description = "id of the requester")),
+    request_body = ResolveAccessRequestForm,
+    responses((status = 200, description = "with Accept: application/json, a message otherwise", body = AccessRequest), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "no pending request of the user", body = MessageBody))
+)]
+async fn canvas_resolve_access_request_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    path: web::Path<(String, String)>,
+    resolve_form: FormOrJson<ResolveAccessRequestForm>,
+    resolve_access_request_recipient: web::Data<
+        actix::Recipient<store::ResolveAccessRequestMessage>,
+    >,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    notifier: web::Data<dyn Notifier>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    let (canvas_id, requester_id) = path.into_inner();
+    let resolve_form = resolve_form.into_inner();
+    let from_members_page =
+        submitted_from_members_page(&request, resolve_form.return_to.as_deref(), &canvas_id);
+
+    let result = async {
+        let resolution = match resolve_form.decision {
+            AccessRequestDecision::Approve => AccessRequestResolution::Approved(
+                resolve_form.access_level.clone().unwrap_or(AccessLevel::Read),
+            ),
+            AccessRequestDecision::Deny => AccessRequestResolution::Denied,
+        };
+
+        // an approval is checked and granted like AddUserToCanvasMessage, a request that can't be approved stays pending
+        let outcome = resolve_access_request_recipient
+            .send(store::ResolveAccessRequestMessage {
+                canvas_id: canvas_id.clone(),
+                initiator_id: auth.user_id().clone(),
+                user_id: requester_id.clone(),
+                resolution: resolution.clone(),
+            })
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+        if let AccessRequestResolution::Approved(access_level) = resolution {
+            // the requester may have the canvas open in another session already, e.g. as guest
+            if canvas_server_handle
+                .update_user_permissions(canvas_id.clone(), requester_id.clone(), access_level, None)
+                .is_err()
+            {
+                println!(
+                    "Live sessions of {requester_id} in {canvas_id} keep their access level, the canvas server stopped"
+                );
+            }
+        }
+
+        println!(
+            "Access request: {} resolved the request of {requester_id} on {canvas_id} as {:?}",
+            auth.user_id().clone(), outcome.request.status
+        );
+        let key = match outcome.request.status {
+            AccessRequestStatus::Approved => MessageKey::AccessRequestApproved,
+            _ => MessageKey::AccessRequestDenied,
+        };
+        let message = Message::new(key).param("canvas", &outcome.canvas_name);
+        notifier.access_request_resolved(&AccessRequestNotice {
+            canvas_id: canvas_id.clone(),
+            canvas_name: outcome.canvas_name.clone(),
+            request: outcome.request.clone(),
+        });
+        canvas_server_handle.notify_users(vec![requester_id], events::NoticeLevel::Notice, message.clone());
+
+        if from_members_page {
+            let mut response =
+                templates::builder_redirect("canvas_members", &request, [canvas_id.as_str()]);
+            templates::set_flash(
+                &request,
+                &mut response,
+                &templates::Flash::info(message.render(messages::request_locale(&request))),
+            );
+            return Ok(response.finish());
+        }
+        if messages::accepts_json(&request) {
+            return Ok(HttpResponse::Ok().json(outcome.request));
+        }
+        Ok(messages::respond(&request, StatusCode::OK, &message))
+    }
+    .await;
+    if !from_members_page {
+        return result;
+    }
+    templates::redirect_back_on_error(
+        &request,
+        result,
+        "canvas_members",
+        [canvas_id.as_str()],
+        templates::Flash::error(""),
+        |_| None,
+    )
+}
+
+/// The form was submitted from the members page of the canvas, by its return_to field or the Referer
+fn submitted_from_members_page(
+    request: &HttpRequest,
+    return_to: Option<&str>,
+    canvas_id: &str,
+) -> bool {
+    if return_to == Some("members") {
+        return true;
+    }
+
+    let Ok(members_page) = request.url_for("canvas_members", [canvas_id]) else {
+        return false;
+    };
+    request
+        .headers()
+        .get(header::REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| referer.parse::<actix_web::http::Uri>().ok())
+        .is_some_and(|referer| referer.path() == members_page.path())
+}
+
+/// One user of a batch, see canvas_add_users_handler
+#[derive(Deserialize, ToSchema)]
+struct BatchMemberEntry {
+    username_email: String,
+    access_level: AccessLevel,
+}
+
+/// Users as JSON list or, for the members page, one per line
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum AddUsersCanvasForm {
+    Entries(Vec<BatchMemberEntry>),
+    Text {
+        /// one username or email per line, a level after a comma overrides access_level, e.g. alice, Write
+        users: String,
+        access_level: AccessLevel,
+        /// "members" if submitted from the members page, see submitted_from_members_page
+        return_to: Option<String>,
+    },
+}
 
-/// Handler for API endpoints related to canvas management
+impl AddUsersCanvasForm {
+    fn return_to(&self) -> Option<&str> {
+        match self {
+            AddUsersCanvasForm::Entries(_) => None,
+            AddUsersCanvasForm::Text { return_to, .. } => return_to.as_deref(),
+        }
+    }
+
+    /// Blank lines are skipped, lines are counted from 1 in the error
+    fn into_entries(self) -> Result<Vec<BatchMemberEntry>, messages::LocalizedError> {
+        let (users, access_level) = match self {
+            AddUsersCanvasForm::Entries(entries) => return Ok(entries),
+            AddUsersCanvasForm::Text {
+                users,
+                access_level,
+                ..
+            } => (users, access_level),
+        };
+        users
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                let invalid = || {
+                    messages::unprocessable_entity(
+                        Message::new(MessageKey::CanvasUsersBatchInvalid)
+                            .param("reason", "invalid_value")
+                            .param("field", "users")
+                            .param("line", index + 1),
+                    )
+                };
+                let (username_email, level) = match line.split_once(',') {
+                    Some((username_email, level)) => (username_email, Some(level.trim())),
+                    None => (line, None),
+                };
+                let username_email = username_email.trim();
+                if username_email.is_empty() {
+                    return Err(invalid());
+                }
+                let access_level = match level {
+                    Some(level) => serde_json::from_value(serde_json::Value::from(level))
+                        .map_err(|_| invalid())?,
+                    None => access_level.clone(),
+                };
+                Ok(BatchMemberEntry {
+                    username_email: username_email.to_string(),
+                    access_level,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Outcome of one user of a batch, in the order of the request
+#[derive(Serialize, ToSchema)]
+struct BatchMemberOutcome {
+    username_email: String,
+    access_level: AccessLevel,
+    status: store::BatchMemberStatus,
+    /// None if no user was found
+    user_id: Option<userstore::UserId>,
+}
+
+/// Result of a membership change, returned to clients accepting JSON
+#[derive(Serialize, ToSchema)]
+struct MembershipChange {
+    target_user_id: String,
+    username: String,
+    /// None if the target was no member yet
+    previous_level: AccessLevel,
+    new_level: AccessLevel,
+}
+
+/// Add or update a user to a canvas
+/// Submitted from the members page it redirects back with the result or the error as flash
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body(content = AddUserCanvasFrom, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 200, description = "with Accept: application/json", body = MembershipChange), (status = 400, description = "expiry in the past", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown user", body = MessageBody))
+)]
+#[allow(clippy::too_many_arguments)]
+async fn canvas_add_user_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    add_user_to_canvas_receipient: web::Data<actix::Recipient<store::AddUserToCanvasMessage>>,
+    get_user_recipient: web::Data<actix::Recipient<userstore::GetUserMessage>>,
+    add_user_canvas_from: web::Form<AddUserCanvasFrom>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    let canvas_id = canvas_id.into_inner();
+    let add_user_canvas_from = add_user_canvas_from.into_inner();
+    let from_members_page = submitted_from_members_page(
+        &request,
+        add_user_canvas_from.return_to.as_deref(),
+        &canvas_id,
+    );
+    let flash = templates::Flash::error("")
+        .value("username_email", &add_user_canvas_from.username_email)
+        .value(
+            "access_level",
+            add_user_canvas_from.access_level.to_string(),
+        );
+
+    let result = add_user_to_canvas(
+        &request,
+        &auth,
+        canvas_id.clone(),
+        add_user_to_canvas_receipient.get_ref(),
+        get_user_recipient.get_ref(),
+        add_user_canvas_from,
+        canvas_server_handle.get_ref(),
+        clock.now_ms(),
+    )
+    .await;
+    if !from_members_page {
+        return result;
+    }
+    templates::redirect_back_on_error(
+        &request,
+        result,
+        "canvas_members",
+        [canvas_id.as_str()],
+        flash,
+        |key| match key {
+            MessageKey::CanvasUserNotFound => Some("username_email"),
+            MessageKey::InvalidExpiry => Some("expires_at"),
+            _ => None,
+        },
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn add_user_to_canvas(
+    request: &HttpRequest,
+    auth: &AuthContext,
+    canvas_id: String,
+    add_user_to_canvas_receipient: &actix::Recipient<store::AddUserToCanvasMessage>,
+    get_user_recipient: &actix::Recipient<userstore::GetUserMessage>,
+    add_user_canvas_from: AddUserCanvasFrom,
+    canvas_server_handle: &CanvasSocketServerHandle,
+    now: u64,
+) -> Result<HttpResponse> {
+    // the store checks the change in detail, members without any say are turned away before the lookup
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::AccessLevelChangeDenied,
+    )
+    .await?;
+
+    if add_user_canvas_from
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err(messages::bad_request(MessageKey::InvalidExpiry).into());
+    }
+
+    let target_user = get_user_recipient
+        .send(userstore::GetUserMessage {
+            username_email: Some(add_user_canvas_from.username_email.clone()),
+            user_id: None,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUserAddFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasUserNotFound))?;
+
+    println!(
+        "Adding user to canvas: {} added {} as {} to {}",
+        auth.user_id().clone(),
+        target_user.id,
+        add_user_canvas_from.access_level,
+        canvas_id
+    );
+
+    let previous_level = add_user_to_canvas_receipient
+        .send(AddUserToCanvasMessage {
+            initiator_user_id: auth.user_id().clone(),
+            access_level: add_user_canvas_from.access_level.clone(),
+            canvas_id: canvas_id.clone(),
+            target_user_id: target_user.id.clone(),
+            expires_at: add_user_canvas_from.expires_at,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUserAddFailed))??;
+
+    // the change is persisted, sessions of the target pick it up once they reconnect
+    if canvas_server_handle
+        .update_user_permissions(
+            canvas_id.clone(),
+            target_user.id.clone(),
+            add_user_canvas_from.access_level.clone(),
+            add_user_canvas_from.expires_at,
+        )
+        .is_err()
+    {
+        println!(
+            "Live sessions of {} in {canvas_id} keep their access level, the canvas server stopped",
+            target_user.id
+        );
+    }
+
+    let message = if previous_level == AccessLevel::None {
+        Message::new(MessageKey::CanvasUserAdded)
+    } else {
+        Message::new(MessageKey::CanvasUserAccessChanged)
+            .param("previous_access_level", &previous_level)
+    }
+    .param("user", &target_user.username)
+    .param("access_level", &add_user_canvas_from.access_level);
+
+    if submitted_from_members_page(
+        request,
+        add_user_canvas_from.return_to.as_deref(),
+        &canvas_id,
+    ) {
+        let mut response =
+            templates::builder_redirect("canvas_members", request, [canvas_id.as_str()]);
+        templates::set_flash(
+            request,
+            &mut response,
+            &templates::Flash::info(message.render(messages::request_locale(request))),
+        );
+        return Ok(response.finish());
+    }
+
+    if messages::accepts_json(request) {
+        return Ok(HttpResponse::Ok().json(MembershipChange {
+            target_user_id: target_user.id,
+            username: target_user.username,
+            previous_level,
+            new_level: add_user_canvas_from.access_level,
+        }));
+    }
+
+    Ok(messages::respond(request, StatusCode::OK, &message))
+}
+
+/// Add or update many users of a canvas at once, e.g. a whole class
+/// The response reports every entry, entries that are not allowed or name unknown users are skipped
+/// Submitted from the members page it redirects back with a summary or the error as flash
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/users/batch",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = AddUsersCanvasForm,
+    responses((status = 200, body = Vec<BatchMemberOutcome>), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "too many users or an unreadable line", body = MessageBody))
+)]
+async fn canvas_add_users_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    add_users_to_canvas_recipient: web::Data<actix::Recipient<store::AddUsersToCanvasMessage>>,
+    get_users_recipient: web::Data<actix::Recipient<userstore::GetUsersMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    add_users_form: FormOrJson<AddUsersCanvasForm>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    let canvas_id = canvas_id.into_inner();
+    let add_users_form = add_users_form.into_inner();
+    let from_members_page =
+        submitted_from_members_page(&request, add_users_form.return_to(), &canvas_id);
+    let flash = match &add_users_form {
+        AddUsersCanvasForm::Text { users, .. } => templates::Flash::error("").value("users", users),
+        AddUsersCanvasForm::Entries(_) => templates::Flash::error(""),
+    };
+
+    let result = add_users_to_canvas(
+        &auth,
+        canvas_id.clone(),
+        add_users_to_canvas_recipient.get_ref(),
+        get_users_recipient.get_ref(),
+        canvas_server_handle.get_ref(),
+        add_users_form,
+    )
+    .await;
+    let outcomes = match result {
+        Ok(outcomes) => outcomes,
+        Err(error) if from_members_page => {
+            return templates::redirect_back_on_error(
+                &request,
+                Err(error),
+                "canvas_members",
+                [canvas_id.as_str()],
+                flash,
+                |key| match key {
+                    MessageKey::CanvasUsersBatchInvalid | MessageKey::CanvasUsersBatchSize => {
+                        Some("users")
+                    }
+                    _ => None,
+                },
+            )
+        }
+        Err(error) => return Err(error),
+    };
+
+    if !from_members_page || messages::accepts_json(&request) {
+        return Ok(HttpResponse::Ok().json(outcomes));
+    }
+
+    let count = |status| {
+        outcomes
+            .iter()
+            .filter(|outcome| outcome.status == status)
+            .count()
+    };
+    let added = count(store::BatchMemberStatus::Added);
+    let updated = count(store::BatchMemberStatus::Updated);
+    let message = Message::new(MessageKey::CanvasUsersBatchAdded)
+        .param("added", added)
+        .param("updated", updated)
+        .param("failed", outcomes.len() - added - updated);
+    let mut response =
+        templates::builder_redirect("canvas_members", &request, [canvas_id.as_str()]);
+    templates::set_flash(
+        &request,
+        &mut response,
+        &templates::Flash::info(message.render(messages::request_locale(&request))),
+    );
+    Ok(response.finish())
+}
+
+async fn add_users_to_canvas(
+    auth: &AuthContext,
+    canvas_id: String,
+    add_users_to_canvas_recipient: &actix::Recipient<store::AddUsersToCanvasMessage>,
+    get_users_recipient: &actix::Recipient<userstore::GetUsersMessage>,
+    canvas_server_handle: &CanvasSocketServerHandle,
+    add_users_form: AddUsersCanvasForm,
+) -> Result<Vec<BatchMemberOutcome>> {
+    // the store checks every entry, members without any say are turned away before the lookup
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::AccessLevelChangeDenied,
+    )
+    .await?;
+
+    let entries = add_users_form.into_entries()?;
+    if entries.is_empty() || entries.len() > store::MAX_BATCH_MEMBERS {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasUsersBatchSize)
+                .param("reason", "invalid_value")
+                .param("field", "users")
+                .param("max", store::MAX_BATCH_MEMBERS)
+                .param("count", entries.len()),
+        )
+        .into());
+    }
+
+    let users = get_users_recipient
+        .send(userstore::GetUsersMessage {
+            usernames_emails: entries
+                .iter()
+                .map(|entry| entry.username_email.clone())
+                .collect(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUserAddFailed))?;
+
+    let additions = entries
+        .iter()
+        .zip(&users)
+        .filter_map(|(entry, user)| Some((user.as_ref()?.id.clone(), entry.access_level.clone())))
+        .collect::<Vec<_>>();
+    println!(
+        "Adding users to canvas: {} added {} of {} users to {canvas_id}",
+        auth.user_id().clone(),
+        additions.len(),
+        entries.len()
+    );
+
+    let mut statuses = add_users_to_canvas_recipient
+        .send(store::AddUsersToCanvasMessage {
+            initiator_user_id: auth.user_id().clone(),
+            canvas_id: canvas_id.clone(),
+            additions,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUserAddFailed))??
+        .into_iter();
+
+    let outcomes: Vec<BatchMemberOutcome> = entries
+        .into_iter()
+        .zip(users)
+        .map(|(entry, user)| BatchMemberOutcome {
+            // the store answers for the found users only, in their order
+            status: match user {
+                Some(_) => statuses
+                    .next()
+                    .unwrap_or(store::BatchMemberStatus::Forbidden),
+                None => store::BatchMemberStatus::NotFound,
+            },
+            user_id: user.map(|user| user.id),
+            username_email: entry.username_email,
+            access_level: entry.access_level,
+        })
+        .collect();
+
+    // the changes are persisted, live sessions get their new level one user at a time
+    for outcome in &outcomes {
+        let (Some(user_id), store::BatchMemberStatus::Added | store::BatchMemberStatus::Updated) =
+            (&outcome.user_id, outcome.status)
+        else {
+            continue;
+        };
+        if canvas_server_handle
+            .update_user_permissions(
+                canvas_id.clone(),
+                user_id.clone(),
+                outcome.access_level.clone(),
+                None,
+            )
+            .is_err()
+        {
+            println!(
+                "Live sessions of {user_id} in {canvas_id} keep their access level, the canvas server stopped"
+            );
+        }
+    }
+
+    Ok(outcomes)
+}
+
+/// Members of /canvas/{canvas_id}/members with list parameters
+struct MemberList;
+
+impl ListEndpoint for MemberList {
+    const DEFAULT_PER_PAGE: usize = 100;
+    const MAX_PER_PAGE: usize = 500;
+    const SORT_FIELDS: &'static [&'static str] = &["username", "expires_at"];
+    const DEFAULT_SORT: &'static str = "username";
+}
+
+/// Comments of /canvas/{canvas_id}/comments with list parameters
+struct CommentList;
+
+impl ListEndpoint for CommentList {
+    const DEFAULT_PER_PAGE: usize = 50;
+    const MAX_PER_PAGE: usize = 200;
+    const SORT_FIELDS: &'static [&'static str] = &["created_at", "shape_id"];
+    const DEFAULT_SORT: &'static str = "created_at";
+}
+
+/// Access levels the initiator may grant, see CanvasStore::validate_permission_change
+fn grantable_levels(initiator: &AccessLevel) -> &'static [AccessLevel] {
+    match initiator {
+        AccessLevel::Owner => &[
+            AccessLevel::Read,
+            AccessLevel::Voice,
+            AccessLevel::Write,
+            AccessLevel::Moderate,
+        ],
+        AccessLevel::Moderate => &[AccessLevel::Read, AccessLevel::Voice, AccessLevel::Write],
+        _ => &[],
+    }
+}
+
+/// List the members of a canvas, temporary access shows the remaining time
+/// Requests that don't accept JSON get the members page, with controls for owners and moderators
+/// With list parameters the members are answered in the envelope of the list endpoints
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/members",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ListQuery),
+    responses((status = 200, description = "with Accept: application/json, the members page otherwise, an Envelope of CanvasMember with list parameters", body = Vec<CanvasMember>), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody), (status = 422, description = "invalid list parameter, named by the field param", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+#[allow(clippy::too_many_arguments)]
+async fn canvas_members_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    handlebars: web::Data<Handlebars<'static>>,
+    canvas_id: web::Path<String>,
+    get_canvas_membership_recipient: web::Data<actix::Recipient<store::GetCanvasMembershipMessage>>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse> {
+    let viewer_access_level = auth
+        .require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+
+    let canvas_id = canvas_id.into_inner();
+    let membership = get_canvas_membership_recipient
+        .send(store::GetCanvasMembershipMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    let mut usernames = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: membership
+                .members
+                .iter()
+                .map(|member| member.user_id.clone())
+                .chain(
+                    membership
+                        .access_requests
+                        .iter()
+                        .map(|request| request.user_id.clone()),
+                )
+                .collect(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?;
+    let online = canvas_server_handle.online_users(canvas_id.clone()).await?;
+
+    let now = clock.now_ms();
+    let mut members: Vec<CanvasMember> = membership
+        .members
+        .into_iter()
+        .map(|member| CanvasMember {
+            username: usernames.remove(&member.user_id).unwrap_or_default(),
+            online: online.contains(&member.user_id),
+            remaining_seconds: member
+                .expires_at
+                .map(|expires_at| expires_at.saturating_sub(now) / 1000),
+            user_id: member.user_id,
+            access_level: member.access_level,
+            expires_at: member.expires_at,
+        })
+        .collect();
+    members.sort_by(|a, b| a.username.cmp(&b.username));
+
+    if messages::accepts_json(&request) {
+        if !pagination::requested(&request, &[]) {
+            return Ok(HttpResponse::Ok().json(members));
+        }
+        let list = ListParams::<MemberList>::extract(&request).await?;
+        members.sort_by(|a, b| {
+            list.sort.order(match list.sort.field {
+                "expires_at" => a.expires_at.cmp(&b.expires_at),
+                _ => a.username.cmp(&b.username),
+            })
+        });
+        return Ok(list.respond(members));
+    }
+    auth.require_user()?;
+
+    let grantable = grantable_levels(&viewer_access_level);
+    let mut rows: Vec<serde_json::Value> = members
+        .iter()
+        .map(|member| {
+            let manageable = grantable.contains(&member.access_level);
+            let levels: Vec<serde_json::Value> = grantable
+                .iter()
+                .map(|level| json!({ "value": level, "selected": *level == member.access_level }))
+                .collect();
+            json!({
+                "user_id": member.user_id,
+                "username": member.username,
+                "access_level": member.access_level,
+                "online": member.online,
+                "expires_at": member.expires_at
+                    .and_then(|expires_at| chrono::DateTime::from_timestamp_millis(expires_at as i64))
+                    .map(|expires_at| expires_at.to_rfc3339()),
+                "expires_at_ms": member.expires_at,
+                "manageable": manageable,
+                "levels": levels,
+            })
+        })
+        .collect();
+    let more_members = templates::truncate_for_template(&mut rows);
+
+    // answered requests are only part of the JSON listing
+    let access_requests: Vec<serde_json::Value> = match grantable.is_empty() {
+        true => Vec::new(),
+        false => membership
+            .access_requests
+            .iter()
+            .filter(|request| request.status == AccessRequestStatus::Pending)
+            .map(|request| {
+                json!({
+                    "user_id": request.user_id,
+                    "username": usernames.get(&request.user_id),
+                    "message": request.message,
+                    "requested_at": chrono::DateTime::from_timestamp_millis(request.requested_at as i64)
+                        .map(|requested_at| requested_at.to_rfc3339()),
+                })
+            })
+            .collect(),
+    };
+
+    let mut response = HttpResponse::Ok();
+    let template_data = json!({
+        "canvasId": membership.canvas_id,
+        "canvasName": membership.name,
+        "canManage": !grantable.is_empty(),
+        "hasExpirations": members.iter().any(|member| member.expires_at.is_some()),
+        "grantableLevels": grantable,
+        "members": rows,
+        "moreMembers": more_members,
+        "accessRequests": access_requests,
+        "flash": templates::take_flash(&request, &mut response),
+    });
+
+    let page = templates::render_timed(&request, &handlebars, "members", template_data).await?;
+    Ok(response.content_type(ContentType::html()).body(page))
+}
+
+/// Current quota usage with its thresholds, the issued warnings and the retention usage, only visible to owners and moderators
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/stats",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasStats), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody))
+)]
+async fn canvas_stats_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_quota_recipient: web::Data<actix::Recipient<store::GetCanvasQuotaMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    retention_policy: web::Data<retention::RetentionPolicy>,
+    clock: web::Data<dyn Clock>,
+) -> Result<impl Responder> {
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasViewDenied,
+    )
+    .await?;
+
+    let canvas_id = canvas_id.into_inner();
+    let status = get_canvas_quota_recipient
+        .send(store::GetCanvasQuotaMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::QuotaLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    let overrides = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::QuotaLoadFailed))?
+        .map(|canvas| canvas.settings.retention)
+        .unwrap_or_default();
+    let policy = retention_policy.with_overrides(&overrides);
+    let now = clock.now_secs();
+    let log_path = server::canvas_log_path(&canvas_id);
+    let retention = web::block(move || retention_usage(&log_path, &policy, now))
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::QuotaLoadFailed))?
+        .map_err(|_| messages::internal_error(MessageKey::QuotaLoadFailed))?;
+
+    let mut quotas = canvas_server_handle.quota_usage(canvas_id).await?;
+    quotas.push(status.members);
+
+    Ok(web::Json(CanvasStats {
+        quotas,
+        warnings: status.warnings,
+        retention,
+    }))
+}
+
+/// Concurrent edit counters of the last minute, tell owners and moderators why a session feels laggy
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/diagnostics",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasDiagnosticsResponse), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody))
+)]
+async fn canvas_diagnostics_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasViewDenied,
+    )
+    .await?;
+
+    let report = canvas_server_handle
+        .diagnostics(canvas_id.into_inner())
+        .await?;
+    Ok(web::Json(CanvasDiagnosticsResponse {
+        loaded: report.is_some(),
+        report,
+    }))
+}
+
+/// What compacting the eventlog would drop, canvases nobody opened yet have no eventlog
+fn retention_usage(
+    log_path: &str,
+    policy: &retention::RetentionPolicy,
+    now: u64,
+) -> std::io::Result<retention::RetentionReport> {
+    if !std::path::Path::new(log_path).exists() {
+        return Ok(retention::RetentionReport::default());
+    }
+
+    let mut event_log = EventLogPersistenceJson::open(log_path)?
+        .read_lines::<events::CanvasEvents>()?
+        .into_iter()
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let cleanup_events = server::CanvasSocketServer::extract_cleanup_events(&mut event_log, now);
+    event_log.extend(cleanup_events);
+
+    let dropped = server::CanvasSocketServer::compaction_drops(&event_log, policy, now);
+    Ok(retention::RetentionReport::new(&event_log, &dropped))
+}
+
+/// Who has seen the latest change of the canvas, only visible to owners and moderators
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/read-state",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasReadState), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody))
+)]
+async fn canvas_read_state_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_membership_recipient: web::Data<actix::Recipient<store::GetCanvasMembershipMessage>>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasViewDenied,
+    )
+    .await?;
+
+    let canvas_id = canvas_id.into_inner();
+    let membership = get_canvas_membership_recipient
+        .send(store::GetCanvasMembershipMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    let read_state = canvas_server_handle.read_state(canvas_id).await?;
+    let mut usernames = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: membership
+                .members
+                .iter()
+                .map(|member| member.user_id.clone())
+                .collect(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?;
+
+    let mut members: Vec<MemberReadState> = membership
+        .members
+        .into_iter()
+        .map(|member| {
+            let caught_up = read_state.caught_up(&member.user_id);
+            MemberReadState {
+                username: usernames.remove(&member.user_id).unwrap_or_default(),
+                last_seen_seq: caught_up.map(|caught_up| caught_up.seq),
+                last_seen_at: caught_up.map(|caught_up| caught_up.timestamp),
+                up_to_date: read_state.is_up_to_date(&member.user_id),
+                user_id: member.user_id,
+                access_level: member.access_level,
+            }
+        })
+        .collect();
+    members.sort_by(|a, b| a.username.cmp(&b.username));
+
+    Ok(web::Json(CanvasReadState {
+        current_seq: read_state.current_seq,
+        members,
+    }))
+}
+
+/// Change the state of a canvas, the store decides whether the caller may take the action in the current state
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/state",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = CanvasStateForm,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 409, description = "the canvas changed since expected_version or can't take the action in its state", body = MessageBody))
+)]
+async fn canvas_state_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_state_recipient: web::Data<actix::Recipient<store::UpdateCanvasStateMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    state_form: FormOrJson<CanvasStateForm>,
+) -> Result<impl Responder> {
+    let state_form = state_form.into_inner();
+    change_canvas_state(
+        &request,
+        &auth,
+        canvas_id.into_inner(),
+        update_canvas_state_recipient.get_ref(),
+        canvas_server_handle.get_ref(),
+        state_form.action,
+        state_form.expected_version,
+    )
+    .await
+}
+
+/// Update the state of a canvas, kept for one release, the requested state is translated into an action
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/update",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = UpdateCanvasForm,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 409, description = "the canvas changed since expected_version or can't take the action in its state", body = MessageBody)),
+    description = "Deprecated, use /canvas/{canvas_id}/state"
+)]
+async fn canvas_update_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_state_recipient: web::Data<actix::Recipient<store::UpdateCanvasStateMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    update_canvas_form: FormOrJson<UpdateCanvasForm>,
+) -> Result<impl Responder> {
+    let update_canvas_form = update_canvas_form.into_inner();
+    change_canvas_state(
+        &request,
+        &auth,
+        canvas_id.into_inner(),
+        update_canvas_state_recipient.get_ref(),
+        canvas_server_handle.get_ref(),
+        update_canvas_form.state.action(),
+        update_canvas_form.expected_version,
+    )
+    .await
+}
+
+async fn change_canvas_state(
+    request: &HttpRequest,
+    auth: &AuthContext,
+    canvas_id: String,
+    update_canvas_state_recipient: &actix::Recipient<store::UpdateCanvasStateMessage>,
+    canvas_server_handle: &CanvasSocketServerHandle,
+    action: CanvasStateAction,
+    expected_version: u64,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(request)?;
+    // the store checks the access level, conflicts are returned as is, the client needs the current version to retry
+    let (version, state) = update_canvas_state_recipient
+        .send(UpdateCanvasStateMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            action,
+            expected_version,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    // only accepted changes reach the connected clients
+    canvas_server_handle.update_canvas_state(canvas_id, state, auth.user_id().clone(), version);
+
+    Ok(messages::respond(
+        request,
+        StatusCode::OK,
+        &MessageKey::CanvasUpdated.into(),
+    ))
+}
+
+/// Update the grid, snapping, shape ownership, voice settings, metadata, retention and allowed shape types of a canvas
+/// Rejected form submissions redirect back to the canvas page with the errors as flash
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/settings",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = UpdateCanvasSettingsForm,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_settings_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_settings_recipient: web::Data<actix::Recipient<UpdateCanvasSettingsMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    settings_form: FormOrJson<UpdateCanvasSettingsForm>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    let canvas_id = canvas_id.into_inner();
+    let settings_form = settings_form.into_inner();
+    let flash = settings_form.flash_values();
+
+    let result = update_canvas_settings(
+        &request,
+        &auth,
+        canvas_id.clone(),
+        update_canvas_settings_recipient.get_ref(),
+        canvas_server_handle.get_ref(),
+        settings_form,
+    )
+    .await;
+    templates::redirect_back_on_error(
+        &request,
+        result,
+        "canvas",
+        [canvas_id.as_str()],
+        flash,
+        |_| None,
+    )
+}
+
+async fn update_canvas_settings(
+    request: &HttpRequest,
+    auth: &AuthContext,
+    canvas_id: String,
+    update_canvas_settings_recipient: &actix::Recipient<UpdateCanvasSettingsMessage>,
+    canvas_server_handle: &CanvasSocketServerHandle,
+    mut settings_form: UpdateCanvasSettingsForm,
+) -> Result<HttpResponse> {
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasUpdateDenied,
+    )
+    .await?;
+
+    let grid_size_valid = settings_form
+        .grid_size
+        .map_or(!settings_form.snap_enabled, |grid_size| {
+            (1..=store::MAX_GRID_SIZE).contains(&grid_size)
+        });
+    if !grid_size_valid {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasGridSizeInvalid)
+                .param("reason", "invalid_value")
+                .param("field", "grid_size")
+                .param("max", store::MAX_GRID_SIZE),
+        )
+        .into());
+    }
+
+    let metadata = settings_form.metadata()?;
+    let retention = settings_form.retention()?;
+    let allowed_shape_types = settings_form.allowed_shape_types()?;
+
+    let settings = CanvasSettings {
+        grid_size: settings_form.grid_size,
+        snap_enabled: settings_form.snap_enabled,
+        shape_ownership_enforced: settings_form.shape_ownership_enforced,
+        ..CanvasSettings::default()
+    };
+
+    let (version, settings) = update_canvas_settings_recipient
+        .send(UpdateCanvasSettingsMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            settings,
+            legacy_voice_behavior: settings_form.legacy_voice_behavior,
+            metadata,
+            retention,
+            anonymize_for_readers: settings_form.anonymize_for_readers,
+            guest_access: settings_form.guest_access,
+            digest: settings_form.digest,
+            allowed_shape_types,
+            moderators_bypass_shape_types: settings_form.moderators_bypass_shape_types,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    canvas_server_handle.update_canvas_settings(
+        canvas_id,
+        settings,
+        auth.user_id().clone(),
+        version,
+    );
+
+    Ok(messages::respond(
+        request,
+        StatusCode::OK,
+        &MessageKey::CanvasSettingsUpdated.into(),
+    ))
+}
+
+/// Replace the tags of a canvas
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/tags",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = UpdateCanvasTagsForm,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_tags_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_tags_recipient: web::Data<actix::Recipient<UpdateCanvasTagsMessage>>,
+    tags_form: FormOrJson<UpdateCanvasTagsForm>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasUpdateDenied,
+    )
+    .await?;
+
+    let tags = store::normalize_tags(tags_form.into_inner().tags.into_tags()).map_err(|e| {
+        let message = match e {
+            InvalidTags::Invalid(tags) => Message::new(MessageKey::CanvasTagsInvalid)
+                .param("tags", tags.join(", "))
+                .param("max_length", store::MAX_TAG_LENGTH),
+            InvalidTags::TooMany(count) => Message::new(MessageKey::CanvasTagsTooMany)
+                .param("count", count)
+                .param("max", store::MAX_CANVAS_TAGS),
+        };
+        messages::unprocessable_entity(
+            message
+                .param("reason", "invalid_value")
+                .param("field", "tags"),
+        )
+    })?;
+
+    update_canvas_tags_recipient
+        .send(UpdateCanvasTagsMessage {
+            canvas_id: canvas_id.into_inner(),
+            initiator_id: auth.user_id().clone(),
+            tags,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasTagsUpdated.into(),
+    ))
+}
+
+/// Rename a canvas, only its owner can
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/name",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = RenameCanvasForm,
+    responses((status = 200, body = CanvasName), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 409, description = "the owner already has a canvas with the name, only if names are unique", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_rename_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    rename_canvas_recipient: web::Data<actix::Recipient<store::RenameCanvasMessage>>,
+    rename_form: FormOrJson<RenameCanvasForm>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Owner,
+        MessageKey::CanvasUpdateDenied,
+    )
+    .await?;
+
+    let rename_form = rename_form.into_inner();
+    let name = rename_form.name.trim();
+    if name.is_empty() || name.chars().count() > names::MAX_CANVAS_NAME_LENGTH {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasNameInvalid)
+                .param("max", names::MAX_CANVAS_NAME_LENGTH)
+                .param("reason", "invalid_value")
+                .param("field", "name"),
+        )
+        .into());
+    }
+
+    let canvas_id = canvas_id.into_inner();
+    let name = rename_canvas_recipient
+        .send(store::RenameCanvasMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            name: name.to_string(),
+            auto_rename: rename_form.auto_rename,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    // the name is part of the claims of every member
+    request.extensions_mut().insert(RegenerateJWTMarker);
+
+    if messages::accepts_json(&request) {
+        return Ok(HttpResponse::Ok().json(CanvasName {
+            id: canvas_id,
+            name,
+        }));
+    }
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &Message::new(MessageKey::CanvasRenamed).param("name", name),
+    ))
+}
+
+/// Feature flags in effect for the requesting member, for debugging
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/flags",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasFeatureFlags), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_flags_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+    feature_flags: web::Data<features::FeatureFlags>,
+) -> Result<impl Responder> {
+    auth.require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+
+    let canvas = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.into_inner(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    Ok(web::Json(CanvasFeatureFlags {
+        flags: feature_flags.resolve(auth.user_id(), &canvas.feature_overrides),
+        overrides: canvas.feature_overrides,
+    }))
+}
+
+/// Replace the feature flag overrides of a canvas, only its owner may change them
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/flags",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = UpdateCanvasFeatureFlagsForm,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_update_flags_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_feature_flags_recipient: web::Data<
+        actix::Recipient<UpdateCanvasFeatureFlagsMessage>,
+    >,
+    feature_flags: web::Data<features::FeatureFlags>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    flags_form: FormOrJson<UpdateCanvasFeatureFlagsForm>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Owner,
+        MessageKey::CanvasFeatureFlagsDenied,
+    )
+    .await?;
+
+    let overrides = flags_form.into_inner().overrides;
+    if let Some(flag) = feature_flags.rejected_override(&overrides) {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasFeatureFlagInvalid)
+                .param("reason", "invalid_value")
+                .param("field", "overrides")
+                .param("flag", flag),
+        )
+        .into());
+    }
+
+    let canvas_id = canvas_id.into_inner();
+    let version = update_canvas_feature_flags_recipient
+        .send(UpdateCanvasFeatureFlagsMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            overrides: overrides.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    canvas_server_handle.update_canvas_feature_flags(canvas_id, overrides, version);
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasFeatureFlagsUpdated.into(),
+    ))
+}
+
+/// Palette of a canvas, for every member
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/palette",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = CanvasPalette), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_palette_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<impl Responder> {
+    auth.require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+
+    let canvas = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.into_inner(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    Ok(web::Json(CanvasPalette::of(&canvas.settings)))
+}
+
+/// Replace the palette of a canvas, owners and moderators may change it
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/palette",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = CanvasPalette,
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_update_palette_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    update_canvas_palette_recipient: web::Data<actix::Recipient<UpdateCanvasPaletteMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    palette_form: web::Json<CanvasPalette>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Moderate,
+        MessageKey::CanvasUpdateDenied,
+    )
+    .await?;
+
+    let CanvasPalette {
+        colors,
+        enforce_palette,
+    } = palette_form.into_inner();
+    let colors = palette::normalize_palette(colors)
+        .map_err(|invalid| messages::unprocessable_entity(invalid.message()))?;
+
+    let canvas_id = canvas_id.into_inner();
+    let version = update_canvas_palette_recipient
+        .send(UpdateCanvasPaletteMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            palette: colors.clone(),
+            enforce_palette,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    canvas_server_handle.update_canvas_palette(
+        canvas_id,
+        colors,
+        enforce_palette,
+        auth.user_id().clone(),
+        version,
+    );
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasPaletteUpdated.into(),
+    ))
+}
+
+/// Create an API token for the canvas, only its owner may create tokens
+/// The plaintext token is part of the response only, the CanvasStore keeps its hash
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/tokens",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = CreateApiTokenForm,
+    responses((status = 201, body = CreatedApiToken), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid field, named by the field param", body = MessageBody))
+)]
+async fn canvas_create_token_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    create_api_token_recipient: web::Data<actix::Recipient<store::CreateApiTokenMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    clock: web::Data<dyn Clock>,
+    token_form: FormOrJson<CreateApiTokenForm>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+    auth.require_level(&canvas_id, AccessLevel::Owner, MessageKey::ApiTokenDenied)
+        .await?;
+
+    let token_form = token_form.into_inner();
+    let label = token_form.label.trim().to_string();
+    if label.is_empty() || label.chars().count() > tokens::MAX_TOKEN_LABEL_LENGTH {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::ApiTokenLabelInvalid)
+                .param("reason", "invalid_length")
+                .param("field", "label")
+                .param("max", tokens::MAX_TOKEN_LABEL_LENGTH),
+        )
+        .into());
+    }
+    if !tokens::is_grantable(&token_form.access_level) {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::ApiTokenAccessLevelInvalid)
+                .param("reason", "invalid_value")
+                .param("field", "access_level"),
+        )
+        .into());
+    }
+    if token_form
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= clock.now_ms())
+    {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::ApiTokenExpiryInvalid)
+                .param("reason", "invalid_value")
+                .param("field", "expires_at"),
+        )
+        .into());
+    }
+
+    let canvas_id = canvas_id.into_inner();
+    let (token_id, token) = tokens::generate(&canvas_id);
+    let details = create_api_token_recipient
+        .send(store::CreateApiTokenMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            token: tokens::ApiToken {
+                id: token_id,
+                label,
+                access_level: token_form.access_level,
+                created_by: auth.user_id().clone(),
+                // set by the store
+                created_at: 0,
+                expires_at: token_form.expires_at,
+                secret_hash: tokens::hash(&token),
+            },
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    canvas_server_handle.add_api_token(canvas_id, details.clone());
+
+    Ok(HttpResponse::Created().json(CreatedApiToken { details, token }))
+}
+
+/// API tokens of the canvas without their hashes, only visible to its owner
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/tokens",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = Vec<tokens::ApiToken>), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody))
+)]
+async fn canvas_tokens_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    get_api_tokens_recipient: web::Data<actix::Recipient<store::GetApiTokensMessage>>,
+) -> Result<impl Responder> {
+    auth.require_level(&canvas_id, AccessLevel::Owner, MessageKey::ApiTokenDenied)
+        .await?;
+
+    let api_tokens = get_api_tokens_recipient
+        .send(store::GetApiTokensMessage {
+            canvas_id: canvas_id.into_inner(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    Ok(web::Json(api_tokens))
+}
+
+/// Revoke an API token, only the owner of the canvas may revoke tokens
+/// Live sessions authenticated by the token are closed right away
+#[utoipa::path(
+    delete,
+    path = "/canvas/{canvas_id}/tokens/{token_id}",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ("token_id" = String, Path, description = "id of the token")),
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown token", body = MessageBody))
+)]
+async fn canvas_revoke_token_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    path: web::Path<(String, String)>,
+    revoke_api_token_recipient: web::Data<actix::Recipient<store::RevokeApiTokenMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    let (canvas_id, token_id) = path.into_inner();
+    auth.require_level(&canvas_id, AccessLevel::Owner, MessageKey::ApiTokenDenied)
+        .await?;
+
+    let revoked = revoke_api_token_recipient
+        .send(store::RevokeApiTokenMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: auth.user_id().clone(),
+            token_id: token_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+    if !revoked {
+        return Err(messages::not_found(MessageKey::ApiTokenNotFound).into());
+    }
+
+    canvas_server_handle.revoke_api_token(canvas_id, token_id);
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::ApiTokenRevoked.into(),
+    ))
+}
+
+/// Delete a canvas, only its owner may delete it
+/// The canvas is listed as recently deleted on the home page of its owner until it is purged
+#[utoipa::path(
+    delete,
+    path = "/canvas/{canvas_id}",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody))
+)]
+async fn canvas_delete_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    delete_canvas_recipient: web::Data<actix::Recipient<DeleteCanvasMessage>>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_level(
+        &canvas_id,
+        AccessLevel::Owner,
+        MessageKey::CanvasDeleteDenied,
+    )
+    .await?;
+
+    delete_canvas_recipient
+        .send(DeleteCanvasMessage {
+            canvas_id: canvas_id.into_inner(),
+            initiator_id: auth.user_id().clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasDeleteFailed))??;
+
+    // the claim of the deleted canvas is dropped from the JWT
+    request.extensions_mut().insert(RegenerateJWTMarker);
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasDeleted.into(),
+    ))
+}
+
+/// Restore a deleted canvas within the deletion grace period, only its owner may restore it
+/// Deleted canvases are not part of the claims, the CanvasStore checks the ownership
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/restore",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    responses((status = 200, body = MessageBody), (status = 403, description = "not the owner", body = MessageBody), (status = 404, description = "unknown canvas or grace period over", body = MessageBody))
+)]
+async fn canvas_restore_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    restore_canvas_recipient: web::Data<actix::Recipient<RestoreCanvasMessage>>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    restore_canvas_recipient
+        .send(RestoreCanvasMessage {
+            canvas_id: canvas_id.into_inner(),
+            initiator_id: auth.user_id().clone(),
+            admin: false,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasRestoreFailed))??;
+
+    // members get their claims back with their next JWT
+    request.extensions_mut().insert(RegenerateJWTMarker);
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasRestored.into(),
+    ))
+}
+
+/// Create a new canvas
+/// Rejected form submissions redirect back to the home page with the error as flash
+async fn canvas_create_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    create_canvas_from: web::Form<CreateCanvasForm>,
+    create_canvas_receipient: web::Data<actix::Recipient<CreateCanvasMessage>>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+    let flash = templates::Flash::error("").value("name", &create_canvas_from.name);
+    let result = create_canvas(
+        &request,
+        &auth,
+        create_canvas_from.into_inner(),
+        create_canvas_receipient.get_ref(),
+    )
+    .await;
+    templates::redirect_back_on_error(&request, result, "home", [""; 0], flash, |_| None)
+}
+
+async fn create_canvas(
+    request: &HttpRequest,
+    auth: &AuthContext,
+    create_canvas_from: CreateCanvasForm,
+    create_canvas_receipient: &actix::Recipient<CreateCanvasMessage>,
+) -> Result<HttpResponse> {
+    let canvas = create_canvas_receipient
+        .send(CreateCanvasMessage {
+            canvas: CreateCanvas {
+                name: create_canvas_from.name,
+                owner_id: auth.user_id().clone(),
+            },
+            auto_rename: create_canvas_from.auto_rename,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasCreateFailed))??;
+
+    // mark that the JWT should be regenerated
+    request.extensions_mut().insert(RegenerateJWTMarker);
+
+    if messages::accepts_json(request) {
+        return Ok(HttpResponse::Created().json(CanvasName {
+            id: canvas.id,
+            name: canvas.name,
+        }));
+    }
+
+    Ok(templates::redirect_to(
+        "canvas",
+        request,
+        [canvas.id.as_str()],
+    ))
+}
+
+/// Creators that contributed before the eventlog recorded its contributors are named by the UserStore
+/// Names are optional, without the UserStore the shapes still carry the ids of their creators
+async fn name_unrecorded_creators(
+    state: Arc<replay::CanvasShapeState>,
+    get_usernames_recipient: &actix::Recipient<userstore::GetUsernamesMessage>,
+) -> Arc<replay::CanvasShapeState> {
+    let unrecorded = state.unrecorded_creators();
+    if unrecorded.is_empty() {
+        return state;
+    }
 
-#[derive(Deserialize)]
-struct CreateCanvasForm {
-    name: String,
+    let Ok(usernames) = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: unrecorded.into_iter().collect(),
+        })
+        .await
+    else {
+        return state;
+    };
+    let mut state = (*state).clone();
+    for (user_id, username) in usernames {
+        state.contributors.insert_unrecorded(user_id, username);
+    }
+    Arc::new(state)
 }
 
-#[derive(Deserialize)]
-struct UpdateCanvasForm {
-    state: CanvasState,
+/// Salt of the pseudonyms the caller sees instead of users, None if the canvas reveals users to the caller
+/// Read callers of canvases anonymized for readers see what their websocket shows them, see redaction.rs
+fn reader_salt(access_level: &AccessLevel, canvas: Option<&store::Canvas>) -> Option<String> {
+    canvas
+        .filter(|canvas| {
+            redaction::is_redacted_for(access_level, canvas.settings.anonymize_for_readers)
+        })
+        .map(|canvas| canvas.settings.reader_salt.clone().unwrap_or_default())
 }
 
-#[derive(Deserialize)]
-struct AddUserCanvasFrom {
-    access_level: store::AccessLevel,
-    username_email: String,
+/// reader_salt of a canvas that is not at hand
+async fn redaction_salt(
+    access_level: &AccessLevel,
+    canvas_id: &str,
+    get_canvas_recipient: &actix::Recipient<store::GetCanvasMessage>,
+    failed: MessageKey,
+) -> Result<Option<String>> {
+    let canvas = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.to_string(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(failed))?;
+    Ok(reader_salt(access_level, canvas.as_ref()))
 }
 
-/// Display the canvas page
-async fn canvas_page_handler(
-    request: HttpRequest,
-    handlebars: web::Data<Handlebars<'_>>,
+/// Shapes of the canvas as they were at the requested cutoff
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/replay",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ReplayQuery),
+    responses((status = 200, body = ReplayResponse), (status = 400, description = "invalid cutoff", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_replay_handler(
+    auth: AuthContext,
     canvas_id: web::Path<String>,
+    query: web::Query<ReplayQuery>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
 ) -> Result<impl Responder> {
-    let user_data = request.extensions().get::<JWTClaims>().map_or(
-        Err(ErrorInternalServerError("Failed to authenticate")),
-        |claims| Ok(claims.clone()),
-    )?;
+    let access_level = auth
+        .require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+    let salt = redaction_salt(
+        &access_level,
+        &canvas_id,
+        &get_canvas_recipient,
+        MessageKey::ReplayFailed,
+    )
+    .await?;
 
-    let claim = user_data
-        .can
-        .iter()
-        .find(|claim| claim.c == canvas_id.as_str())
-        .ok_or(ErrorUnauthorized("Not authorized to view canvas"))?;
+    let cutoff = query
+        .until
+        .as_deref()
+        .map(str::parse::<replay::ReplayCutoff>)
+        .transpose()
+        .map_err(|_| {
+            messages::bad_request(
+                Message::new(MessageKey::InvalidReplayCutoff)
+                    .param("value", query.until.clone().unwrap_or_default()),
+            )
+        })?;
 
-    let template_data = json!({
-        "userId": user_data.uid,
-        "accessLevel": claim.r.clone(),
-        "canvasName": claim.n.clone(),
-        "timestamp": chrono::Utc::now().timestamp() as u64, // needed to force browser reevaluation of script
-    });
+    // folding reads the eventlog from disk, keep it off the worker thread
+    let canvas_id = canvas_id.into_inner();
+    let state = web::block(move || {
+        replay_cache.replay(&canvas_id, &server::canvas_log_path(&canvas_id), cutoff)
+    })
+    .await
+    .map_err(|_| messages::internal_error(MessageKey::ReplayFailed))?
+    .map_err(|_| messages::internal_error(MessageKey::ReplayFailed))?;
+    let state = match &salt {
+        Some(salt) => Arc::new(redaction::redact_state(&state, salt)),
+        None => name_unrecorded_creators(state, &get_usernames_recipient).await,
+    };
 
-    handlebars
-        .render("canvas", &template_data)
-        .map(web::Html::new)
-        .map_err(|_| ErrorInternalServerError("Failed to render canvas"))
+    Ok(HttpResponse::Ok().json(ReplayResponse {
+        state: &state,
+        provenance: query.provenance.then_some(&state.provenance),
+    }))
 }
 
-/// Add or update a user to a canvas
-async fn canvas_add_user_handler(
-    request: HttpRequest,
+/// Live shapes of the canvas matching the filters, in z-order and paginated
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/shapes",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ShapeSearchQuery),
+    responses((status = 200, body = search::ShapePage), (status = 400, description = "incomplete area or unknown cursor", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_shapes_handler(
+    auth: AuthContext,
     canvas_id: web::Path<String>,
-    add_user_to_canvas_receipient: web::Data<actix::Recipient<store::AddUserToCanvasMessage>>,
-    get_user_recipient: web::Data<actix::Recipient<userstore::GetUserMessage>>,
-    add_user_canvas_from: web::Form<AddUserCanvasFrom>,
-    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    query: web::Query<ShapeSearchQuery>,
+    canvas_server: web::Data<CanvasSocketServerHandle>,
 ) -> Result<impl Responder> {
-    let user_data = request.extensions().get::<JWTClaims>().map_or(
-        Err(ErrorInternalServerError("Failed to authenticate")),
-        |claims| Ok(claims.clone()),
-    )?;
+    auth.require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
 
-    if let Some(target_user) = get_user_recipient
-        .send(userstore::GetUserMessage {
-            username_email: Some(add_user_canvas_from.username_email.clone()),
-            user_id: None,
-        })
-        .await
-        .map_err(|_| ErrorInternalServerError("Failed to change access level"))?
+    let filter = query.filter().map_err(messages::bad_request)?;
+    let canvas_id = canvas_id.into_inner();
+    let matches = match canvas_server
+        .find_shapes(canvas_id.clone(), filter.clone())
+        .await?
     {
-        println!(
-            "Adding user to canvas: {} added {} as {:?} to {}",
-            user_data.uid, target_user.id, add_user_canvas_from.access_level, canvas_id
-        );
+        Some(matches) => matches,
+        // folding reads the eventlog from disk, keep it off the worker thread
+        None => web::block(move || filter.find_in_log(&server::canvas_log_path(&canvas_id)))
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::ShapeSearchFailed))?
+            .map_err(|_| messages::internal_error(MessageKey::ShapeSearchFailed))?,
+    };
 
-        let canvas_id = canvas_id.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(search::DEFAULT_SEARCH_LIMIT)
+        .clamp(1, search::MAX_SEARCH_LIMIT);
+    let page = search::paginate(matches, query.cursor.as_deref(), limit).ok_or_else(|| {
+        messages::bad_request(
+            Message::new(MessageKey::UnknownShapeCursor)
+                .param("cursor", query.cursor.clone().unwrap_or_default()),
+        )
+    })?;
+    Ok(HttpResponse::Ok().json(page))
+}
 
-        add_user_to_canvas_receipient
-            .send(AddUserToCanvasMessage {
-                initiator_user_id: user_data.uid.clone(),
-                access_level: add_user_canvas_from.access_level.clone(),
-                canvas_id: canvas_id.clone(),
-                target_user_id: target_user.id.clone(),
+/// Who last changed the shape and when, read from the eventlog if the canvas is not loaded
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/shapes/{shape_id}/provenance",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ("shape_id" = String, Path, description = "id of the shape")),
+    responses((status = 200, body = ShapeProvenanceResponse), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "the shape is not alive", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_shape_provenance_handler(
+    auth: AuthContext,
+    path: web::Path<(String, String)>,
+    canvas_server: web::Data<CanvasSocketServerHandle>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<impl Responder> {
+    let (canvas_id, shape_id) = path.into_inner();
+    let access_level = auth
+        .require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+    let salt = redaction_salt(
+        &access_level,
+        &canvas_id,
+        &get_canvas_recipient,
+        MessageKey::ShapeProvenanceFailed,
+    )
+    .await?;
+
+    let (provenance, contributors) = match canvas_server
+        .shape_provenance(canvas_id.clone(), shape_id.clone())
+        .await?
+    {
+        Some(provenance) => (
+            provenance,
+            canvas_server
+                .contributors(canvas_id)
+                .await?
+                .unwrap_or_default(),
+        ),
+        // folding reads the eventlog from disk, keep it off the worker thread
+        None => {
+            let state = web::block(move || {
+                replay_cache.replay(&canvas_id, &server::canvas_log_path(&canvas_id), None)
             })
             .await
-            .map_err(|_| ErrorInternalServerError("Failed to add user to canvas"))??;
-        // TODO: actor panic or mailbox full
+            .map_err(|_| messages::internal_error(MessageKey::ShapeProvenanceFailed))?
+            .map_err(|_| messages::internal_error(MessageKey::ShapeProvenanceFailed))?;
+            (
+                state.provenance.get(&shape_id).cloned(),
+                state.contributors.clone(),
+            )
+        }
+    };
+    let provenance = provenance.ok_or_else(|| {
+        messages::not_found(Message::new(MessageKey::ShapeNotFound).param("id", shape_id.clone()))
+    })?;
+    if let Some(salt) = salt {
+        let (provenance, last_modified_by_name) = redaction::redact_provenance(&provenance, &salt);
+        return Ok(HttpResponse::Ok().json(ShapeProvenanceResponse {
+            shape_id,
+            provenance,
+            last_modified_by_name,
+        }));
+    }
 
-        // at this point access level is valid
-        canvas_server_handle.update_user_permissions(
-            canvas_id,
-            target_user.id.clone(),
-            add_user_canvas_from.access_level.clone(),
-        );
+    // editors that contributed before the eventlog recorded its contributors are named by the UserStore
+    let editor = provenance.last_modified_by.clone();
+    let last_modified_by_name = match editor {
+        Some(editor) => match contributors.name(&editor) {
+            Some(name) => Some(name.to_string()),
+            None => get_usernames_recipient
+                .send(userstore::GetUsernamesMessage {
+                    user_ids: vec![editor.clone()],
+                })
+                .await
+                .ok()
+                .and_then(|mut usernames| usernames.remove(&editor)),
+        },
+        None => None,
+    };
 
-        Ok(HttpResponse::Ok().body(format!(
-            "{} als {:?} hinzugefügt",
-            target_user.id, add_user_canvas_from.access_level
-        )))
-    } else {
-        Ok(HttpResponse::NotFound().body("Benutzer nicht gefunden"))
+    Ok(HttpResponse::Ok().json(ShapeProvenanceResponse {
+        shape_id,
+        provenance,
+        last_modified_by_name,
+    }))
+}
+
+/// Comments with the current names of their authors, in the order of the comments
+/// With a salt the authors are named by their pseudonyms, see redaction_salt
+async fn named_comments(
+    comments: Vec<&comments::Comment>,
+    get_usernames_recipient: &actix::Recipient<userstore::GetUsernamesMessage>,
+    salt: Option<&str>,
+) -> Vec<CommentResponse> {
+    if let Some(salt) = salt {
+        return comments
+            .into_iter()
+            .map(|comment| {
+                let (comment, author_name) = redaction::redact_comment(comment, salt);
+                CommentResponse {
+                    comment,
+                    author_name,
+                }
+            })
+            .collect();
     }
+
+    let user_ids: BTreeSet<userstore::UserId> = comments
+        .iter()
+        .filter_map(|comment| comment.author_id.clone())
+        .collect();
+    let usernames = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: user_ids.into_iter().collect(),
+        })
+        .await
+        .unwrap_or_default();
+    comments
+        .into_iter()
+        .map(|comment| CommentResponse {
+            author_name: comment
+                .author_id
+                .as_ref()
+                .and_then(|author_id| usernames.get(author_id).cloned()),
+            comment: comment.clone(),
+        })
+        .collect()
 }
 
-/// Update the state of a canvas
-async fn canvas_update_handler(
+/// Comments of the shapes, read from the eventlog if the canvas is not loaded
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/comments",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), CommentsQuery, ListQuery),
+    responses((status = 200, description = "an Envelope of CommentResponse with list parameters", body = Vec<CommentResponse>), (status = 400, description = "unknown status", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 422, description = "invalid list parameter, named by the field param", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+#[allow(clippy::too_many_arguments)]
+async fn canvas_comments_handler(
     request: HttpRequest,
+    auth: AuthContext,
     canvas_id: web::Path<String>,
-    update_canvas_state_receipient: web::Data<actix::Recipient<store::UpdateCanvasStateMessage>>,
-    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
-    update_canvas_from: web::Form<UpdateCanvasForm>,
+    query: web::Query<CommentsQuery>,
+    list: ListParams<CommentList>,
+    canvas_server: web::Data<CanvasSocketServerHandle>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<impl Responder> {
+    let canvas_id = canvas_id.into_inner();
+    let access_level = auth
+        .require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+    let salt = redaction_salt(
+        &access_level,
+        &canvas_id,
+        &get_canvas_recipient,
+        MessageKey::CommentsFailed,
+    )
+    .await?;
+
+    let comments = match canvas_server.comments(canvas_id.clone()).await? {
+        Some(comments) => comments,
+        // folding reads the eventlog from disk, keep it off the worker thread
+        None => {
+            let state = web::block(move || {
+                replay_cache.replay(&canvas_id, &server::canvas_log_path(&canvas_id), None)
+            })
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::CommentsFailed))?
+            .map_err(|_| messages::internal_error(MessageKey::CommentsFailed))?;
+            state.comments.clone()
+        }
+    };
+
+    let mut matching = comments.filter(query.shape_id.as_deref(), query.status);
+    if !pagination::requested(&request, &[]) {
+        let named = named_comments(matching, &get_usernames_recipient, salt.as_deref()).await;
+        return Ok(HttpResponse::Ok().json(named));
+    }
+    matching.sort_by(|a, b| {
+        list.sort.order(match list.sort.field {
+            "shape_id" => (&a.shape_id, a.created_at).cmp(&(&b.shape_id, b.created_at)),
+            _ => (a.created_at, &a.comment_id).cmp(&(b.created_at, &b.comment_id)),
+        })
+    });
+    let page = list.pagination.page(matching);
+    let named = named_comments(page.items, &get_usernames_recipient, salt.as_deref()).await;
+    Ok(pagination::respond(
+        pagination::Page {
+            items: named,
+            info: page.info,
+        },
+        &list.fields,
+    ))
+}
+
+/// Replayed shapes, the canvas and the salt of the pseudonyms the caller sees, see exported_canvas
+type ExportedCanvas = (
+    Arc<replay::CanvasShapeState>,
+    Option<store::Canvas>,
+    Option<String>,
+);
+
+/// Replayed shapes and the canvas, shared by the export formats and duplication
+/// The shapes are redacted for callers that see pseudonyms, the salt is returned for the comments
+async fn exported_canvas(
+    auth: &AuthContext,
+    canvas_id: String,
+    until: Option<String>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: &actix::Recipient<userstore::GetUsernamesMessage>,
+    get_canvas_recipient: &actix::Recipient<store::GetCanvasMessage>,
+) -> Result<ExportedCanvas> {
+    let access_level = auth
+        .require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
+
+    let cutoff = until
+        .as_deref()
+        .map(str::parse::<replay::ReplayCutoff>)
+        .transpose()
+        .map_err(|_| {
+            messages::bad_request(
+                Message::new(MessageKey::InvalidReplayCutoff)
+                    .param("value", until.clone().unwrap_or_default()),
+            )
+        })?;
+
+    let canvas = get_canvas_recipient
+        .send(store::GetCanvasMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::ExportFailed))?;
+
+    let state = web::block(move || {
+        replay_cache.replay(&canvas_id, &server::canvas_log_path(&canvas_id), cutoff)
+    })
+    .await
+    .map_err(|_| messages::internal_error(MessageKey::ExportFailed))?
+    .map_err(|_| messages::internal_error(MessageKey::ExportFailed))?;
+
+    let salt = reader_salt(&access_level, canvas.as_ref());
+    let state = match &salt {
+        Some(salt) => Arc::new(redaction::redact_state(&state, salt)),
+        None => name_unrecorded_creators(state, get_usernames_recipient).await,
+    };
+
+    Ok((state, canvas, salt))
+}
+
+/// Canvas as SVG document, accepts the same cutoff as the replay
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/export.svg",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ExportQuery),
+    responses((status = 200, body = String, content_type = "image/svg+xml"), (status = 400, description = "invalid cutoff", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_export_svg_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<impl Responder> {
+    let query = query.into_inner();
+    let (state, canvas, _) = exported_canvas(
+        &auth,
+        canvas_id.into_inner(),
+        query.until,
+        replay_cache,
+        &get_usernames_recipient,
+        &get_canvas_recipient,
+    )
+    .await?;
+    let metadata = canvas.and_then(|canvas| canvas.settings.metadata);
+    let svg = export::render_svg(&state, metadata.as_ref(), query.attribution);
+
+    Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+}
+
+/// Canvas as JSON document, the replayed shapes in their order with the settings and metadata of the canvas, see document.rs
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/export.json",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), ExportQuery),
+    responses((status = 200, body = JsonExport), (status = 400, description = "invalid cutoff", body = MessageBody), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_export_json_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
 ) -> Result<impl Responder> {
-    let user_data = request.extensions().get::<JWTClaims>().map_or(
-        Err(ErrorInternalServerError("Failed to authenticate")),
-        |claims| Ok(claims.clone()),
-    )?;
+    let query = query.into_inner();
+    let (state, canvas, salt) = exported_canvas(
+        &auth,
+        canvas_id.into_inner(),
+        query.until,
+        replay_cache,
+        &get_usernames_recipient,
+        &get_canvas_recipient,
+    )
+    .await?;
+
+    let settings = canvas.map(|canvas| canvas.settings).unwrap_or_default();
+    let mut document = document::CanvasDocument::from_state(&state, &settings);
+    if query.provenance {
+        document.provenance = Some(state.provenance.clone());
+    }
+    let comments = match query.comments {
+        true => {
+            let comments = state.comments.filter(None, None);
+            Some(named_comments(comments, &get_usernames_recipient, salt.as_deref()).await)
+        }
+        false => None,
+    };
+
+    Ok(HttpResponse::Ok().json(JsonExport { document, comments }))
+}
+
+/// Formats a unix timestamp in milliseconds for the print page, None if it is out of range
+fn print_date(timestamp_ms: u64) -> Option<String> {
+    chrono::DateTime::from_timestamp_millis(i64::try_from(timestamp_ms).ok()?)
+        .map(|date| date.format("%d.%m.%Y %H:%M UTC").to_string())
+}
+
+///
+/// Printable page of the canvas without scripts, for handouts
+/// The SVG is inlined and cut off after PrintLimits::max_shapes shapes, see print.rs
+///
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/print",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), PrintQuery),
+    responses((status = 200, body = String, content_type = "text/html"), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody))
+)]
+#[allow(clippy::too_many_arguments)]
+async fn canvas_print_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    handlebars: web::Data<Handlebars<'static>>,
+    canvas_id: web::Path<String>,
+    query: web::Query<PrintQuery>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<HttpResponse> {
+    auth.require_user()?;
+    let canvas_id = canvas_id.into_inner();
+    let (state, canvas, salt) = exported_canvas(
+        &auth,
+        canvas_id.clone(),
+        None,
+        replay_cache,
+        &get_usernames_recipient,
+        &get_canvas_recipient,
+    )
+    .await?;
+    let canvas = canvas.ok_or(messages::not_found(MessageKey::CanvasNotFound))?;
+
+    let limits = print::request_print_limits(&request);
+    let (svg, omitted) = export::render_svg_capped(
+        &state,
+        canvas.settings.metadata.as_ref(),
+        true,
+        limits.max_shapes,
+    );
+    let comments = match query.comments {
+        true => {
+            named_comments(
+                state
+                    .comments
+                    .filter(None, Some(comments::CommentStatus::Open)),
+                &get_usernames_recipient,
+                salt.as_deref(),
+            )
+            .await
+        }
+        false => Vec::new(),
+    };
+    let owner_name = get_usernames_recipient
+        .send(userstore::GetUsernamesMessage {
+            user_ids: vec![canvas.owner_id.clone()],
+        })
+        .await
+        .ok()
+        .and_then(|mut usernames| usernames.remove(&canvas.owner_id));
 
-    user_data
-        .can
+    let mut comments: Vec<serde_json::Value> = comments
         .iter()
-        .find(|claim| {
-            claim.c == canvas_id.as_str()
-                && (claim.r == AccessLevel::Owner || claim.r == AccessLevel::Moderate)
+        .map(|comment| {
+            json!({
+                "shape_id": comment.comment.shape_id,
+                "author_name": comment.author_name,
+                "text": comment.comment.text,
+                "created_at": comment.comment.created_at.checked_mul(1000).and_then(print_date),
+            })
         })
-        .ok_or(ErrorUnauthorized("Not authorized to update canvas"))?;
+        .collect();
+    let more_comments = templates::truncate_for_template(&mut comments);
+
+    let template_data = json!({
+        "canvasId": canvas_id,
+        "canvasName": canvas.name,
+        "ownerName": owner_name,
+        "exportedAt": print_date(clock::request_clock(&request).now_ms()),
+        "shapeCount": state.shapes.len(),
+        "omittedShapes": omitted,
+        "legend": query.legend.then(|| print::legend(&state)),
+        "showComments": query.comments,
+        "comments": comments,
+        "moreComments": more_comments,
+    });
+    let page =
+        templates::render_timed_with_svg(&request, &handlebars, "canvas-print", template_data, svg)
+            .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .content_type(ContentType::html())
+        .body(page))
+}
+
+/// Points of interest in the eventlog of the canvas
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/replay/keyframes",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), KeyframesQuery),
+    responses((status = 200, body = Vec<replay::Keyframe>), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody)),
+    security(("cookie" = []), ("canvas_token" = []))
+)]
+async fn canvas_keyframes_handler(
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    query: web::Query<KeyframesQuery>,
+) -> Result<impl Responder> {
+    auth.require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
 
+    let interval = query.every.unwrap_or(replay::DEFAULT_KEYFRAME_INTERVAL);
     let canvas_id = canvas_id.into_inner();
+    let keyframes =
+        web::block(move || replay::keyframes(&server::canvas_log_path(&canvas_id), interval))
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::ReplayFailed))?
+            .map_err(|_| messages::internal_error(MessageKey::ReplayFailed))?;
 
-    update_canvas_state_receipient
-        .send(UpdateCanvasStateMessage {
+    Ok(web::Json(keyframes))
+}
+
+///
+/// Persisted events of the canvas as JSON Lines, for its owner and admins
+/// Loaded canvases are exported up to the line persisted when the export started, lines written meanwhile are left out
+/// Cold canvases are streamed from disk without loading them
+///
+#[utoipa::path(
+    get,
+    path = "/canvas/{canvas_id}/export/events",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), EventsExportQuery),
+    responses((status = 200, description = "eventlog as JSON Lines", body = String, content_type = "application/x-ndjson"), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody), (status = 413, description = "eventlog too large to transfer", body = MessageBody))
+)]
+async fn canvas_export_events_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    query: web::Query<EventsExportQuery>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<impl Responder> {
+    let canvas_id = canvas_id.into_inner();
+    let access_level = auth.access_level(&canvas_id).await?;
+    if access_level != AccessLevel::Owner && !admin::is_admin(&request, auth.claims()) {
+        return Err(messages::forbidden(MessageKey::CanvasEventsExportDenied).into());
+    }
+
+    // admins are not limited to existing canvases by their claims
+    get_canvas_recipient
+        .send(store::GetCanvasMessage {
             canvas_id: canvas_id.clone(),
-            initiator_id: user_data.uid.clone(),
-            state: update_canvas_from.state.clone(),
         })
         .await
-        .map_err(|_| ErrorInternalServerError("Failed to update canvas"))??;
+        .map_err(|_| messages::internal_error(MessageKey::ExportFailed))?
+        .ok_or_else(|| messages::not_found(MessageKey::CanvasNotFound))?;
 
-    canvas_server_handle.update_canvas_state(
-        canvas_id,
-        update_canvas_from.state.clone(),
-        user_data.uid,
-    );
+    let path = server::canvas_log_path(&canvas_id);
+    let log_bytes = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(_) => return Err(messages::internal_error(MessageKey::ExportFailed).into()),
+    };
+    if log_bytes > transfer::TRANSFER_LIMIT as u64 {
+        return Err(messages::payload_too_large(
+            Message::new(MessageKey::CanvasEventsTooLarge).param("limit", transfer::TRANSFER_LIMIT),
+        )
+        .into());
+    }
 
-    Ok(HttpResponse::Ok().body("Canvas aktualisiert"))
+    let until_seq = canvas_server_handle
+        .persisted_seq(canvas_id.clone())
+        .await?;
+    let lines = transfer::stream_event_log(&path, query.since_seq, until_seq)
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::ExportFailed))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Attachment,
+            parameters: vec![header::DispositionParam::Filename(format!(
+                "{canvas_id}-{}.jsonl",
+                clock.now_secs()
+            ))],
+        })
+        .streaming(lines))
 }
 
-/// Create a new canvas
-async fn canvas_create_handler(
+///
+/// Creates a new canvas from an exported eventlog, only for admins
+/// The importing admin owns the new canvas, its eventlog is written before anyone knows its id
+///
+#[utoipa::path(
+    post,
+    path = "/canvas/import-events",
+    tag = "canvas",
+    params(EventsImportQuery),
+    request_body(content = String, description = "exported eventlog as JSON Lines", content_type = "application/x-ndjson"),
+    responses((status = 201, body = ImportedCanvas), (status = 403, description = "not an admin", body = MessageBody), (status = 413, description = "eventlog too large to transfer", body = MessageBody), (status = 422, description = "empty eventlog or unreadable line", body = MessageBody))
+)]
+async fn canvas_import_events_handler(
     request: HttpRequest,
-    create_canvas_from: web::Form<CreateCanvasForm>,
-    create_canvas_receipient: web::Data<actix::Recipient<CreateCanvasMessage>>,
+    query: web::Query<EventsImportQuery>,
+    payload: web::Payload,
+    create_canvas_recipient: web::Data<actix::Recipient<CreateCanvasMessage>>,
+    admin_action_log: web::Data<admin::AdminActionLog>,
+    clock: web::Data<dyn Clock>,
 ) -> Result<impl Responder> {
-    let user_data = request.extensions().get::<JWTClaims>().map_or(
-        Err(ErrorInternalServerError("Failed to authenticate")),
-        |claims| Ok(claims.clone()),
-    )?;
+    maintenance_mode::ensure_writable(&request)?;
+    let admin = admin::require_admin(&request)?;
+
+    let body = payload
+        .to_bytes_limited(transfer::TRANSFER_LIMIT)
+        .await
+        .map_err(|_| forms::too_large(transfer::TRANSFER_LIMIT))?
+        .map_err(|_| forms::malformed())?;
+    let event_log = transfer::parse_import(&body).map_err(|error| match error {
+        transfer::ImportError::Empty => {
+            messages::unprocessable_entity(MessageKey::CanvasImportEmpty)
+        }
+        transfer::ImportError::InvalidLine(line) => messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasImportInvalid).param("line", line),
+        ),
+    })?;
+    let events = event_log.len();
+
+    let name = query
+        .into_inner()
+        .name
+        .unwrap_or_else(|| IMPORTED_CANVAS_NAME.to_string());
+    let action = admin::AdminActionLog::begin(
+        &admin_action_log,
+        admin.user_id(),
+        "import_canvas_events",
+        &name,
+    )
+    .map_err(|_| messages::internal_error(MessageKey::AdminLogFailed))?;
+
+    let result: Result<store::Canvas> = async {
+        let canvas = create_canvas_recipient
+            .send(CreateCanvasMessage {
+                canvas: CreateCanvas {
+                    name,
+                    owner_id: admin.user_id().clone(),
+                },
+                auto_rename: true,
+            })
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::CanvasImportFailed))?
+            .map_err(|_| messages::internal_error(MessageKey::CanvasImportFailed))?;
+
+        let canvas_id = canvas.id.clone();
+        let timestamp = clock.now_secs();
+        web::block(move || {
+            transfer::write_import(
+                &server::canvas_log_path(&canvas_id),
+                &canvas_id,
+                event_log,
+                timestamp,
+            )
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasImportFailed))?
+        .map_err(|_| messages::internal_error(MessageKey::CanvasImportFailed))?;
+        Ok(canvas)
+    }
+    .await;
+    action.finish(&result);
+    let canvas = result?;
+
+    // the importing admin owns the new canvas
+    request.extensions_mut().insert(RegenerateJWTMarker);
 
-    let canvas_save_event = create_canvas_receipient
+    Ok(HttpResponse::Created().json(ImportedCanvas {
+        canvas_id: canvas.id,
+        events,
+    }))
+}
+
+///
+/// Creates a canvas owned by the caller holding the content of the document
+/// The document is checked before the canvas is created, its eventlog is written before anyone knows its id
+/// Recipients and limits are taken from the app data, the import and duplicate handlers share it
+///
+async fn create_from_document(
+    request: &HttpRequest,
+    auth: &AuthContext,
+    name: String,
+    document: document::CanvasDocument,
+    failed: MessageKey,
+) -> Result<MaterializedCanvas> {
+    let (
+        Some(create_canvas_recipient),
+        Some(update_canvas_settings_recipient),
+        Some(update_canvas_palette_recipient),
+        Some(shape_limits),
+    ) = (
+        request.app_data::<web::Data<actix::Recipient<CreateCanvasMessage>>>(),
+        request.app_data::<web::Data<actix::Recipient<UpdateCanvasSettingsMessage>>>(),
+        request.app_data::<web::Data<actix::Recipient<UpdateCanvasPaletteMessage>>>(),
+        request.app_data::<web::Data<validation::ShapeLimits>>(),
+    )
+    else {
+        return Err(messages::internal_error(failed).into());
+    };
+
+    let document = document
+        .normalize()
+        .map_err(|error| messages::unprocessable_entity(error.message()))?;
+    let timestamp = clock::request_clock(request).now_secs();
+    let materialized = document
+        .materialize(auth.user_id(), timestamp, shape_limits)
+        .map_err(|error| messages::unprocessable_entity(error.message()))?;
+
+    let canvas = create_canvas_recipient
         .send(CreateCanvasMessage {
             canvas: CreateCanvas {
-                name: create_canvas_from.name.clone(),
-                owner_id: user_data.uid,
+                name,
+                owner_id: auth.user_id().clone(),
             },
+            // imported and duplicated canvases are named by the server, they never collide
+            auto_rename: true,
         })
-        .await;
+        .await
+        .map_err(|_| messages::internal_error(failed))??;
 
-    let canvas = match canvas_save_event {
-        Ok(Ok(canvas)) => canvas,
-        Ok(Err(_)) => return Ok(HttpResponse::InternalServerError().body("Failed to save canvas")),
-        Err(_) => return Ok(HttpResponse::InternalServerError().body("Failed to save canvas")),
-    };
+    let canvas_id = canvas.id.clone();
+    let events = materialized.events;
+    web::block(move || {
+        transfer::write_import(
+            &server::canvas_log_path(&canvas_id),
+            &canvas_id,
+            events,
+            timestamp,
+        )
+    })
+    .await
+    .map_err(|_| messages::internal_error(failed))?
+    .map_err(|_| messages::internal_error(failed))?;
 
-    // mark that the JWT should be regenerated
+    // nobody is connected to the new canvas yet, the CanvasStore alone learns its settings
+    let settings = document.canvas_settings();
+    if settings != CanvasSettings::default() || document.metadata.is_some() {
+        update_canvas_settings_recipient
+            .send(UpdateCanvasSettingsMessage {
+                canvas_id: canvas.id.clone(),
+                initiator_id: auth.user_id().clone(),
+                settings,
+                legacy_voice_behavior: None,
+                metadata: document.metadata.clone().map(Some),
+                retention: None,
+                anonymize_for_readers: None,
+                guest_access: None,
+                digest: None,
+                allowed_shape_types: None,
+                moderators_bypass_shape_types: None,
+            })
+            .await
+            .map_err(|_| messages::internal_error(failed))??;
+    }
+    if !document.settings.palette.is_empty() || document.settings.enforce_palette {
+        update_canvas_palette_recipient
+            .send(UpdateCanvasPaletteMessage {
+                canvas_id: canvas.id.clone(),
+                initiator_id: auth.user_id().clone(),
+                palette: document.settings.palette,
+                enforce_palette: document.settings.enforce_palette,
+            })
+            .await
+            .map_err(|_| messages::internal_error(failed))??;
+    }
+
+    // the caller owns the new canvas
     request.extensions_mut().insert(RegenerateJWTMarker);
 
-    Ok(templates::redirect_to(
-        "canvas",
+    Ok(MaterializedCanvas {
+        canvas_id: canvas.id,
+        id_mapping: materialized.id_mapping,
+    })
+}
+
+///
+/// Creates a new canvas from a canvas document, as exported by GET /canvas/{canvas_id}/export.json
+/// The shapes get new ids, the response maps the ids of the document to them
+///
+#[utoipa::path(
+    post,
+    path = "/canvas/import",
+    tag = "canvas",
+    params(EventsImportQuery),
+    request_body = document::CanvasDocument,
+    responses((status = 201, body = MaterializedCanvas), (status = 413, description = "document too large to transfer", body = MessageBody), (status = 422, description = "unsupported version, invalid shape or setting", body = MessageBody))
+)]
+async fn canvas_import_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    query: web::Query<EventsImportQuery>,
+    payload: web::Payload,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+
+    let body = payload
+        .to_bytes_limited(transfer::TRANSFER_LIMIT)
+        .await
+        .map_err(|_| forms::too_large(transfer::TRANSFER_LIMIT))?
+        .map_err(|_| forms::malformed())?;
+    let document: document::CanvasDocument =
+        serde_json::from_slice(&body).map_err(|_| forms::malformed())?;
+
+    let name = query
+        .into_inner()
+        .name
+        .unwrap_or_else(|| IMPORTED_CANVAS_NAME.to_string());
+    let created = create_from_document(
         &request,
-        [canvas.id.as_str()],
-    ))
+        &auth,
+        name,
+        document,
+        MessageKey::CanvasImportFailed,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(created))
+}
+
+///
+/// Creates a copy of the canvas owned by the caller, everyone able to view the canvas may copy it
+/// The copy holds the document the JSON export would answer, members, comments and history stay with the original
+///
+#[utoipa::path(
+    post,
+    path = "/canvas/{canvas_id}/duplicate",
+    tag = "canvas",
+    params(("canvas_id" = String, Path, description = "id of the canvas"), DuplicateQuery),
+    responses((status = 201, body = MaterializedCanvas), (status = 403, description = "not allowed for the access level of the caller", body = MessageBody), (status = 404, description = "unknown canvas", body = MessageBody))
+)]
+async fn canvas_duplicate_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    query: web::Query<DuplicateQuery>,
+    replay_cache: web::Data<replay::ReplayCache>,
+    get_usernames_recipient: web::Data<actix::Recipient<userstore::GetUsernamesMessage>>,
+    get_canvas_recipient: web::Data<actix::Recipient<store::GetCanvasMessage>>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+
+    let (state, canvas, _) = exported_canvas(
+        &auth,
+        canvas_id.into_inner(),
+        None,
+        replay_cache,
+        &get_usernames_recipient,
+        &get_canvas_recipient,
+    )
+    .await?;
+    let canvas = canvas.ok_or_else(|| messages::not_found(MessageKey::CanvasNotFound))?;
+
+    let document = document::CanvasDocument::from_state(&state, &canvas.settings);
+    let name = query
+        .into_inner()
+        .name
+        .unwrap_or_else(|| format!("{} (copy)", canvas.name));
+    let created = create_from_document(
+        &request,
+        &auth,
+        name,
+        document,
+        MessageKey::CanvasDuplicateFailed,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(created))
 }
 
+/// Subprotocol of the canvas websocket, selected if the client offers it
+/// Clients sending their token as subprotocol offer it as well, the token is never echoed
+pub const WS_PROTOCOL: &str = "drawing-canvas";
+
 /// Handle websocket connections to a canvas
 async fn canvas_websocket_handler(
     req: HttpRequest,
     stream: web::Payload,
     canvas_server_handle: web::Data<CanvasSocketServerHandle>,
     canvas_id: web::Path<String>,
+    clock: web::Data<dyn Clock>,
 ) -> Result<HttpResponse> {
-    let user_data = req
-        .extensions()
-        .get::<JWTClaims>()
-        .map_or(Err(ErrorUnauthorized("Failed to authenticate")), |claims| {
-            Ok(claims.clone())
-        })?;
+    // before the upgrade, an invalid or expired token is answered with 401
+    let auth = authentication::websocket_claims(&req).await?;
+    auth.require_level(&canvas_id, AccessLevel::Read, MessageKey::CanvasViewDenied)
+        .await?;
 
-    let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    // actix-http does not implement permessage-deflate, the extension is not negotiated
+    // the initial state is sent in InitialStateChunks instead, see CanvasSocketServer::send_initial_state
+    let connection = ConnectionMeta::from_request(&req);
+    let (mut res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    let offers_protocol = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == WS_PROTOCOL);
+    if offers_protocol {
+        res.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            header::HeaderValue::from_static(WS_PROTOCOL),
+        );
+    }
 
     // spawn websocket handler (and don't await it) so that the response is returned immediately
     spawn_local(socket_handler::start_canvas_websocket_connection(
@@ -226,7 +3764,9 @@ async fn canvas_websocket_handler(
         session,
         msg_stream,
         canvas_id.into_inner(),
-        user_data.into(),
+        auth.claims().clone().into(),
+        connection,
+        clock.into_inner(),
     ));
 
     Ok(res)
@@ -237,15 +3777,128 @@ pub fn canvas_service(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/canvas")
             .wrap(authentication::AuthenticationService)
+            .app_data(forms::form_config(forms::CANVAS_BODY_LIMIT))
+            .app_data(forms::json_config(forms::CANVAS_BODY_LIMIT))
             .route("", web::post().to(canvas_create_handler))
+            // registered before /{canvas_id}, which would take the post otherwise
+            .service(
+                web::resource("/import-events").route(web::post().to(canvas_import_events_handler)),
+            )
+            .service(web::resource("/import").route(web::post().to(canvas_import_handler)))
             .service(
                 web::resource("/{canvas_id}")
                     .name("canvas")
                     .route(web::get().to(canvas_page_handler))
-                    .route(web::post().to(canvas_add_user_handler)),
+                    .route(web::post().to(canvas_add_user_handler))
+                    .route(web::delete().to(canvas_delete_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/restore").route(web::post().to(canvas_restore_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/duplicate")
+                    .route(web::post().to(canvas_duplicate_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/state").route(web::post().to(canvas_state_handler)),
             )
             .service(
                 web::resource("/{canvas_id}/update").route(web::post().to(canvas_update_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/settings")
+                    .route(web::post().to(canvas_settings_handler)),
+            )
+            .service(web::resource("/{canvas_id}/tags").route(web::post().to(canvas_tags_handler)))
+            .service(
+                web::resource("/{canvas_id}/name").route(web::post().to(canvas_rename_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/palette")
+                    .route(web::get().to(canvas_palette_handler))
+                    .route(web::post().to(canvas_update_palette_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/access").route(web::get().to(canvas_access_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/request-access")
+                    .route(web::post().to(canvas_request_access_handler))
+                    .route(web::delete().to(canvas_cancel_access_request_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/access-requests")
+                    .route(web::get().to(canvas_access_requests_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/access-requests/{user_id}")
+                    .route(web::post().to(canvas_resolve_access_request_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/flags")
+                    .route(web::get().to(canvas_flags_handler))
+                    .route(web::post().to(canvas_update_flags_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/tokens")
+                    .route(web::get().to(canvas_tokens_handler))
+                    .route(web::post().to(canvas_create_token_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/tokens/{token_id}")
+                    .route(web::delete().to(canvas_revoke_token_handler))
+                    .route(web::post().to(canvas_revoke_token_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/users/batch")
+                    .route(web::post().to(canvas_add_users_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/members")
+                    .name("canvas_members")
+                    .route(web::get().to(canvas_members_handler)),
+            )
+            .service(web::resource("/{canvas_id}/stats").route(web::get().to(canvas_stats_handler)))
+            .service(
+                web::resource("/{canvas_id}/diagnostics")
+                    .route(web::get().to(canvas_diagnostics_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/read-state")
+                    .route(web::get().to(canvas_read_state_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/shapes").route(web::get().to(canvas_shapes_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/comments")
+                    .route(web::get().to(canvas_comments_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/shapes/{shape_id}/provenance")
+                    .route(web::get().to(canvas_shape_provenance_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/replay").route(web::get().to(canvas_replay_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/replay/keyframes")
+                    .route(web::get().to(canvas_keyframes_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/export.svg")
+                    .route(web::get().to(canvas_export_svg_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/export.json")
+                    .route(web::get().to(canvas_export_json_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/export/events")
+                    .route(web::get().to(canvas_export_events_handler)),
+            )
+            .service(
+                web::resource("/{canvas_id}/print").route(web::get().to(canvas_print_handler)),
             ),
     );
     cfg.service(
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+
+use super::store::CanvasId;
+use crate::userstore::UserId;
+
+// Canvas names per owner, lets the CanvasStore detect and resolve names an owner already uses
+// Names are compared trimmed and case insensitive, soft deleted canvases give up their name until restored
+// Kept alongside the ClaimIndex, maintained by create, rename, delete and restore and rebuilt on replay
+
+/// Characters of a canvas name
+pub const MAX_CANVAS_NAME_LENGTH: usize = 100;
+
+/// Suffixes tried by NameIndex::available before giving up, the name is taken as it is then
+const MAX_NAME_SUFFIX: u32 = 1_000;
+
+/// Form names are compared in
+pub fn fold(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Name without a trailing " (n)" suffix, suffixes of a requested name are counted on instead of stacked
+fn base_name(name: &str) -> &str {
+    let name = name.trim();
+    let Some(rest) = name.strip_suffix(')') else {
+        return name;
+    };
+    match rest.rsplit_once(" (") {
+        Some((base, counter))
+            if !base.trim().is_empty()
+                && !counter.is_empty()
+                && counter.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base.trim_end()
+        }
+        _ => name,
+    }
+}
+
+/// Folded names of the canvases of every owner, a name can be used by several canvases of logs written before
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameIndex(HashMap<UserId, HashMap<String, HashSet<CanvasId>>>);
+
+impl NameIndex {
+    pub fn insert(&mut self, owner_id: &UserId, name: &str, canvas_id: &CanvasId) {
+        self.0
+            .entry(owner_id.clone())
+            .or_default()
+            .entry(fold(name))
+            .or_default()
+            .insert(canvas_id.clone());
+    }
+
+    /// Drops the canvas from the name, owners without names are removed
+    pub fn remove(&mut self, owner_id: &UserId, name: &str, canvas_id: &CanvasId) {
+        let Some(names) = self.0.get_mut(owner_id) else {
+            return;
+        };
+        let folded = fold(name);
+        if let Some(canvas_ids) = names.get_mut(&folded) {
+            canvas_ids.remove(canvas_id);
+            if canvas_ids.is_empty() {
+                names.remove(&folded);
+            }
+        }
+        if names.is_empty() {
+            self.0.remove(owner_id);
+        }
+    }
+
+    /// Whether another canvas of the owner uses the name, except is the canvas being renamed
+    pub fn is_taken(&self, owner_id: &UserId, name: &str, except: Option<&CanvasId>) -> bool {
+        self.0
+            .get(owner_id)
+            .and_then(|names| names.get(&fold(name)))
+            .is_some_and(|canvas_ids| canvas_ids.iter().any(|id| Some(id) != except))
+    }
+
+    ///
+    /// The name if it is free, otherwise the first free "name (n)" counting from 2
+    /// Suffixes already in use are skipped, "Board (2)" asked for again becomes "Board (3)"
+    ///
+    pub fn available(&self, owner_id: &UserId, name: &str, except: Option<&CanvasId>) -> String {
+        if !self.is_taken(owner_id, name, except) {
+            return name.to_string();
+        }
+        let base = base_name(name);
+        (2..=MAX_NAME_SUFFIX)
+            .map(|counter| format!("{base} ({counter})"))
+            .find(|candidate| !self.is_taken(owner_id, candidate, except))
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Canvases of the owner using the name
+    pub fn canvases(&self, owner_id: &UserId, name: &str) -> Vec<CanvasId> {
+        let mut canvas_ids: Vec<CanvasId> = self
+            .0
+            .get(owner_id)
+            .and_then(|names| names.get(&fold(name)))
+            .map(|canvas_ids| canvas_ids.iter().cloned().collect())
+            .unwrap_or_default();
+        canvas_ids.sort();
+        canvas_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_collide_trimmed_and_case_insensitive() {
+        let mut index = NameIndex::default();
+        let alice = "alice".to_string();
+        index.insert(&alice, "Projekt", &"c1".to_string());
+
+        assert!(index.is_taken(&alice, "projekt", None));
+        assert!(index.is_taken(&alice, "  PROJEKT ", None));
+        assert!(!index.is_taken(&alice, "Projekt 2", None));
+        assert!(!index.is_taken(&"bob".to_string(), "Projekt", None));
+        // renaming a canvas to its own name is no collision
+        assert!(!index.is_taken(&alice, "projekt", Some(&"c1".to_string())));
+
+        index.remove(&alice, " projekt", &"c1".to_string());
+        assert!(!index.is_taken(&alice, "Projekt", None));
+        assert_eq!(index, NameIndex::default());
+    }
+
+    #[test]
+    fn test_suffix_counts_on_from_existing_suffixes() {
+        let mut index = NameIndex::default();
+        let alice = "alice".to_string();
+        assert_eq!(index.available(&alice, "Untitled", None), "Untitled");
+
+        index.insert(&alice, "Untitled", &"c1".to_string());
+        index.insert(&alice, "untitled (2)", &"c2".to_string());
+        index.insert(&alice, "Untitled (4)", &"c4".to_string());
+        assert_eq!(index.available(&alice, "Untitled", None), "Untitled (3)");
+        assert_eq!(
+            index.available(&alice, "Untitled (2)", None),
+            "Untitled (3)"
+        );
+        assert_eq!(
+            index.available(&alice, "Untitled (5)", None),
+            "Untitled (5)"
+        );
+
+        index.insert(&alice, "Untitled (3)", &"c3".to_string());
+        assert_eq!(index.available(&alice, " untitled ", None), "untitled (5)");
+        // only a trailing counter is a suffix
+        index.insert(&alice, "Plan (v1)", &"c5".to_string());
+        assert_eq!(index.available(&alice, "Plan (v1)", None), "Plan (v1) (2)");
+        assert_eq!(base_name("(2)"), "(2)");
+    }
+}
@@ -0,0 +1,290 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use super::store::{self, CanvasId};
+
+// Eventlogs of canvases in the data directory the CanvasStore no longer or never knew about
+// Left behind by canvases purged while their eventlog could not be removed, creations that failed
+// after the eventlog was written, or copies of another deployment, see GET /admin/api/storage/orphans
+// Purging moves them into QUARANTINE_DIR, sweep_quarantine removes them once the retention passed
+
+/// Directory the canvas eventlogs are written to, see server::canvas_log_path
+pub const CANVAS_LOG_DIR: &str = ".";
+
+/// Subdirectory of the canvas eventlogs purged eventlogs are moved to
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Time a quarantined eventlog is kept before it is removed for good
+pub const DEFAULT_QUARANTINE_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// What the CanvasStore knows about a canvas id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanvasRecord {
+    Active,
+    SoftDeleted {
+        /// millisecond timestamp the store purges the canvas at
+        purge_at: u64,
+    },
+    /// purged, the eventlog should have been removed with it
+    Purged,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LogClass {
+    Active,
+    /// restorable, the store removes the eventlog itself once it purges the canvas
+    SoftDeleted,
+    /// the canvas was purged, the eventlog is leftover
+    Purgeable,
+    /// no record of the canvas at all, may point to a lost CanvasStore event
+    Unknown,
+}
+
+impl LogClass {
+    pub fn of(record: Option<&CanvasRecord>) -> Self {
+        match record {
+            Some(CanvasRecord::Active) => LogClass::Active,
+            Some(CanvasRecord::SoftDeleted { .. }) => LogClass::SoftDeleted,
+            Some(CanvasRecord::Purged) => LogClass::Purgeable,
+            None => LogClass::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogClass::Active => "active",
+            LogClass::SoftDeleted => "soft_deleted",
+            LogClass::Purgeable => "purgeable",
+            LogClass::Unknown => "unknown",
+        }
+    }
+
+    /// Only eventlogs no canvas reads again can be purged
+    pub fn is_purgeable(&self) -> bool {
+        matches!(self, LogClass::Purgeable | LogClass::Unknown)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasLogFile {
+    pub file_name: String,
+    pub canvas_id: CanvasId,
+    pub class: LogClass,
+    pub size_bytes: u64,
+    /// millisecond timestamp, None if the filesystem doesn't record it
+    pub modified_at: Option<u64>,
+    /// soft deleted canvases only
+    pub purge_at: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrphanReport {
+    /// by file name
+    pub files: Vec<CanvasLogFile>,
+    /// eventlogs currently open in the canvas server, they are neither classified nor purged
+    pub skipped_open: Vec<String>,
+}
+
+impl OrphanReport {
+    pub fn file(&self, file_name: &str) -> Option<&CanvasLogFile> {
+        self.files.iter().find(|file| file.file_name == file_name)
+    }
+
+    pub fn count(&self, class: LogClass) -> usize {
+        self.files.iter().filter(|file| file.class == class).count()
+    }
+}
+
+/// Canvas id of an eventlog file name, None for the store eventlogs and any other file
+pub fn canvas_id_of(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_suffix(".jsonl")
+        .filter(|canvas_id| store::is_valid_canvas_id(canvas_id))
+}
+
+/// Classifies the canvas eventlogs in dir against the records of the CanvasStore
+/// Only reads the directory listing and metadata, no eventlog is opened or loaded
+pub fn scan(
+    dir: &str,
+    records: &HashMap<CanvasId, CanvasRecord>,
+    open: &HashSet<CanvasId>,
+) -> io::Result<OrphanReport> {
+    let mut report = OrphanReport::default();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(canvas_id) = canvas_id_of(&file_name) else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        if open.contains(canvas_id) {
+            report.skipped_open.push(file_name);
+            continue;
+        }
+
+        let record = records.get(canvas_id);
+        report.files.push(CanvasLogFile {
+            canvas_id: canvas_id.to_string(),
+            class: LogClass::of(record),
+            size_bytes: metadata.len(),
+            modified_at: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_millis() as u64),
+            purge_at: match record {
+                Some(CanvasRecord::SoftDeleted { purge_at }) => Some(*purge_at),
+                _ => None,
+            },
+            file_name,
+        });
+    }
+    report.files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    report.skipped_open.sort();
+    Ok(report)
+}
+
+/// Moves the eventlog into the quarantine, the name is prefixed with the time it was quarantined at
+/// Returns the path it was moved to
+pub fn quarantine(dir: &str, file_name: &str, now: u64) -> io::Result<String> {
+    let quarantine_dir = Path::new(dir).join(QUARANTINE_DIR);
+    std::fs::create_dir_all(&quarantine_dir)?;
+    let target = quarantine_dir.join(format!("{now}-{file_name}"));
+    std::fs::rename(Path::new(dir).join(file_name), &target)?;
+    Ok(target.to_string_lossy().to_string())
+}
+
+/// Removes quarantined eventlogs older than the retention, returns the removed file names
+/// Files not named by quarantine are left alone
+pub fn sweep_quarantine(dir: &str, retention: Duration, now: u64) -> io::Result<Vec<String>> {
+    let quarantine_dir = Path::new(dir).join(QUARANTINE_DIR);
+    let entries = match std::fs::read_dir(&quarantine_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+        let quarantined_at = file_name
+            .split_once('-')
+            .filter(|(_, original)| canvas_id_of(original).is_some())
+            .and_then(|(quarantined_at, _)| quarantined_at.parse::<u64>().ok());
+        let Some(quarantined_at) = quarantined_at else {
+            continue;
+        };
+        if quarantined_at.saturating_add(retention.as_millis() as u64) <= now {
+            std::fs::remove_file(quarantine_dir.join(&file_name))?;
+            removed.push(file_name);
+        }
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIVE: &str = "aaaaaaaaaaaa";
+    const SOFT_DELETED: &str = "bbbbbbbbbbbb";
+    const PURGED: &str = "cccccccccccc";
+    const UNKNOWN: &str = "dddddddddddd";
+    const OPEN: &str = "eeeeeeeeeeee";
+
+    /// Data directory with an eventlog of each class next to the store eventlogs
+    fn fixture_dir() -> (String, HashMap<CanvasId, CanvasRecord>) {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!(8));
+        std::fs::create_dir(&dir).unwrap();
+        for canvas_id in [ACTIVE, SOFT_DELETED, PURGED, UNKNOWN, OPEN] {
+            std::fs::write(dir.join(format!("{canvas_id}.jsonl")), "{}\n").unwrap();
+        }
+        std::fs::write(dir.join("canvas_eventlog.jsonl"), "").unwrap();
+        std::fs::write(dir.join("user_eventlog.jsonl"), "").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let records = HashMap::from([
+            (ACTIVE.to_string(), CanvasRecord::Active),
+            (OPEN.to_string(), CanvasRecord::Active),
+            (
+                SOFT_DELETED.to_string(),
+                CanvasRecord::SoftDeleted { purge_at: 500 },
+            ),
+            (PURGED.to_string(), CanvasRecord::Purged),
+        ]);
+        (dir.to_string_lossy().to_string(), records)
+    }
+
+    #[test]
+    fn test_eventlogs_are_classified_by_their_store_record() {
+        let (dir, records) = fixture_dir();
+
+        let report = scan(&dir, &records, &HashSet::from([OPEN.to_string()])).unwrap();
+        let classes: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| (file.canvas_id.as_str(), file.class))
+            .collect();
+        assert_eq!(
+            classes,
+            vec![
+                (ACTIVE, LogClass::Active),
+                (SOFT_DELETED, LogClass::SoftDeleted),
+                (PURGED, LogClass::Purgeable),
+                (UNKNOWN, LogClass::Unknown),
+            ]
+        );
+        // open eventlogs are skipped, not classified
+        assert_eq!(report.skipped_open, vec![format!("{OPEN}.jsonl")]);
+        let soft_deleted = report.file(&format!("{SOFT_DELETED}.jsonl")).unwrap();
+        assert_eq!(soft_deleted.purge_at, Some(500));
+        assert_eq!(soft_deleted.size_bytes, 3);
+        assert!(soft_deleted.modified_at.is_some());
+        assert!(!LogClass::Active.is_purgeable() && !LogClass::SoftDeleted.is_purgeable());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_quarantined_eventlogs_are_moved_and_swept_after_the_retention() {
+        let (dir, records) = fixture_dir();
+        let retention = Duration::from_millis(1_000);
+
+        let moved = quarantine(&dir, &format!("{UNKNOWN}.jsonl"), 2_000).unwrap();
+        assert!(moved.ends_with(&format!("{QUARANTINE_DIR}/2000-{UNKNOWN}.jsonl")));
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "{}\n");
+        quarantine(&dir, &format!("{PURGED}.jsonl"), 2_500).unwrap();
+        // the quarantine is not a canvas eventlog itself
+        let report = scan(&dir, &records, &HashSet::new()).unwrap();
+        assert_eq!(report.count(LogClass::Unknown), 0);
+        assert_eq!(report.count(LogClass::Purgeable), 0);
+        assert!(quarantine(&dir, &format!("{UNKNOWN}.jsonl"), 2_000).is_err());
+
+        std::fs::write(Path::new(&dir).join(QUARANTINE_DIR).join("keep.txt"), "").unwrap();
+        assert!(sweep_quarantine(&dir, retention, 2_999).unwrap().is_empty());
+        assert_eq!(
+            sweep_quarantine(&dir, retention, 3_000).unwrap(),
+            vec![format!("2000-{UNKNOWN}.jsonl")]
+        );
+        assert_eq!(
+            sweep_quarantine(&dir, retention, 10_000).unwrap(),
+            vec![format!("2500-{PURGED}.jsonl")]
+        );
+        assert!(Path::new(&dir)
+            .join(QUARANTINE_DIR)
+            .join("keep.txt")
+            .exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
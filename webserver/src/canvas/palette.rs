@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::events::CanvasEvents;
+use crate::messages::{Message, MessageKey};
+
+// Colors collaborators of a canvas pick from, owners and moderators replace the palette as a whole
+// It is part of the canvas settings, so every settings change persists it along
+// With enforce_palette set new shapes and updated colors have to be in the palette,
+// shapes drawn before keep their colors and an empty palette enforces nothing
+
+/// Most colors of a palette
+pub const MAX_PALETTE_COLORS: usize = 32;
+
+/// Longest color name in characters
+pub const MAX_COLOR_NAME_LENGTH: usize = 24;
+
+/// No color, always allowed
+const TRANSPARENT: &str = "transparent";
+
+/// Named color of a palette
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct PaletteColor {
+    pub name: String,
+    /// lowercase #rrggbb, #rgb is expanded
+    pub color: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidPalette {
+    /// number of colors given
+    TooMany(usize),
+    /// name that is empty or too long, as given
+    Name(String),
+    /// color that is no hex color, as given
+    Color(String),
+}
+
+impl InvalidPalette {
+    /// Validation error naming the field and the rejected value
+    pub fn message(&self) -> Message {
+        let (field, value) = match self {
+            InvalidPalette::TooMany(count) => ("colors", count.to_string()),
+            InvalidPalette::Name(name) => ("name", name.clone()),
+            InvalidPalette::Color(color) => ("color", color.clone()),
+        };
+        Message::new(MessageKey::CanvasPaletteInvalid)
+            .param("reason", "invalid_value")
+            .param("field", field)
+            .param("value", value)
+            .param("max", MAX_PALETTE_COLORS)
+            .param("max_name", MAX_COLOR_NAME_LENGTH)
+    }
+}
+
+/// Lowercase #rrggbb of a #rgb or #rrggbb hex color, None for anything else
+pub fn normalize_color(color: &str) -> Option<String> {
+    let digits = color.trim().strip_prefix('#')?;
+    if !digits.chars().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits = match digits.len() {
+        3 => digits.chars().flat_map(|digit| [digit, digit]).collect(),
+        6 => digits.to_string(),
+        _ => return None,
+    };
+    Some(format!("#{}", digits.to_ascii_lowercase()))
+}
+
+/// Trims the names and normalizes the colors, the order is kept
+pub fn normalize_palette(colors: Vec<PaletteColor>) -> Result<Vec<PaletteColor>, InvalidPalette> {
+    if colors.len() > MAX_PALETTE_COLORS {
+        return Err(InvalidPalette::TooMany(colors.len()));
+    }
+    colors
+        .into_iter()
+        .map(|entry| {
+            let name = entry.name.trim();
+            if name.is_empty() || name.chars().count() > MAX_COLOR_NAME_LENGTH {
+                return Err(InvalidPalette::Name(entry.name));
+            }
+            let color = normalize_color(&entry.color).ok_or(InvalidPalette::Color(entry.color))?;
+            Ok(PaletteColor {
+                name: name.to_string(),
+                color,
+            })
+        })
+        .collect()
+}
+
+fn in_palette(palette: &[PaletteColor], color: &str) -> bool {
+    color.trim().eq_ignore_ascii_case(TRANSPARENT)
+        || normalize_color(color)
+            .is_some_and(|color| palette.iter().any(|entry| entry.color == color))
+}
+
+/// First color of the event missing in the palette, as sent
+/// Updates are only checked for the colors they change
+pub fn off_palette_color<'a>(palette: &[PaletteColor], event: &'a CanvasEvents) -> Option<&'a str> {
+    if palette.is_empty() {
+        return None;
+    }
+    let colors: Vec<&str> = match event {
+        CanvasEvents::ShapeAdded { shape, .. } => vec![shape.border_color(), shape.fill_color()],
+        CanvasEvents::ShapeUpdated { shape, .. } => ["borderColor", "fillColor"]
+            .iter()
+            .filter_map(|field| shape.get(field))
+            .map(|color| color.as_str().unwrap_or_default())
+            .collect(),
+        _ => Vec::new(),
+    };
+    colors.into_iter().find(|color| !in_palette(palette, color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::events::{Point2D, Shape};
+    use serde_json::json;
+
+    fn entry(name: &str, color: &str) -> PaletteColor {
+        PaletteColor {
+            name: name.to_string(),
+            color: color.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_palette_entries_are_validated() {
+        assert_eq!(
+            normalize_palette(vec![entry(" Brand ", "#FF8800"), entry("Ink", "#abc")]),
+            Ok(vec![entry("Brand", "#ff8800"), entry("Ink", "#aabbcc")])
+        );
+        assert_eq!(
+            normalize_palette(vec![entry("  ", "#fff")]),
+            Err(InvalidPalette::Name("  ".to_string()))
+        );
+        let long_name = "n".repeat(MAX_COLOR_NAME_LENGTH + 1);
+        assert_eq!(
+            normalize_palette(vec![entry(&long_name, "#fff")]),
+            Err(InvalidPalette::Name(long_name))
+        );
+        for color in ["red", "#ff88", "#gggggg", "ff8800"] {
+            assert_eq!(
+                normalize_palette(vec![entry("Red", color)]),
+                Err(InvalidPalette::Color(color.to_string()))
+            );
+        }
+        let too_many = vec![entry("Ink", "#000"); MAX_PALETTE_COLORS + 1];
+        assert_eq!(
+            normalize_palette(too_many),
+            Err(InvalidPalette::TooMany(MAX_PALETTE_COLORS + 1))
+        );
+    }
+
+    #[test]
+    fn test_shapes_have_to_use_palette_colors() {
+        let palette = vec![entry("Ink", "#000000"), entry("Brand", "#ff8800")];
+        let added = |border: &str, fill: &str| CanvasEvents::ShapeAdded {
+            origin: "session".to_string(),
+            timestamp: 1,
+            shape: Shape::Line {
+                id: "l1".to_string(),
+                temporary: false,
+                borderColor: border.to_string(),
+                fillColor: fill.to_string(),
+                attributes: Default::default(),
+                from: Point2D { x: 0, y: 0 },
+                to: Point2D { x: 1, y: 1 },
+            },
+            userId: None,
+        };
+
+        assert_eq!(off_palette_color(&palette, &added("#000", "#FF8800")), None);
+        assert_eq!(
+            off_palette_color(&palette, &added("#000", "transparent")),
+            None
+        );
+        assert_eq!(
+            off_palette_color(&palette, &added("#000", "red")),
+            Some("red")
+        );
+        assert_eq!(off_palette_color(&[], &added("#000", "red")), None);
+
+        let updated = |shape| CanvasEvents::ShapeUpdated {
+            origin: "session".to_string(),
+            timestamp: 1,
+            shape,
+            userId: None,
+        };
+        // moving a shape with an off palette color is fine
+        let moved = updated(json!({"id": "l1", "from": {"x": 2, "y": 2}}));
+        assert_eq!(off_palette_color(&palette, &moved), None);
+        let recolored = updated(json!({"id": "l1", "fillColor": "#00ff00"}));
+        assert_eq!(off_palette_color(&palette, &recolored), Some("#00ff00"));
+    }
+}
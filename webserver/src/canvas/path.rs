@@ -0,0 +1,102 @@
+use super::events::Point2D;
+
+// Simplification of freehand strokes
+// Clients sample the pointer on every move, a single stroke easily contains hundreds of points
+// Douglas-Peucker drops points that are closer than epsilon to the simplified line
+// The first and last point of a stroke are always kept
+
+/// Distance of point to the line through start and end, falls back to the distance to start if the line is a point
+fn perpendicular_distance(point: Point2D, start: Point2D, end: Point2D) -> f64 {
+    let (px, py) = (point.x as f64, point.y as f64);
+    let (sx, sy) = (start.x as f64, start.y as f64);
+    let (ex, ey) = (end.x as f64, end.y as f64);
+
+    let (dx, dy) = (ex - sx, ey - sy);
+    let length = dx.hypot(dy);
+    if length == 0.0 {
+        return (px - sx).hypot(py - sy);
+    }
+
+    (dy * px - dx * py + ex * sy - ey * sx).abs() / length
+}
+
+/// Douglas-Peucker simplification, returns the points to keep in their original order
+/// Uses an explicit stack, strokes can be long enough to make recursion uncomfortable
+pub fn simplify(points: &[Point2D], epsilon: f64) -> Vec<Point2D> {
+    if points.len() < 3 || epsilon <= 0.0 {
+        return points.to_vec();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut segments = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = segments.pop() {
+        let farthest = (start + 1..end)
+            .map(|index| {
+                (
+                    index,
+                    perpendicular_distance(points[index], points[start], points[end]),
+                )
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        if let Some((index, distance)) = farthest {
+            if distance > epsilon {
+                keep[index] = true;
+                segments.push((start, index));
+                segments.push((index, end));
+            }
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(point, _)| *point)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simplify_noisy_stroke() {
+        // diagonal stroke with +-1px jitter and a sharp corner in the middle
+        let mut stroke: Vec<Point2D> = (0..500)
+            .map(|i| Point2D {
+                x: i,
+                y: i + if i % 2 == 0 { 1 } else { -1 },
+            })
+            .collect();
+        stroke.extend((0..500).map(|i| Point2D {
+            x: 500 + i,
+            y: 500 - i + if i % 3 == 0 { 1 } else { 0 },
+        }));
+
+        let simplified = simplify(&stroke, 2.0);
+        assert!(simplified.len() <= 10, "{} points left", simplified.len());
+        assert_eq!(simplified.first(), stroke.first());
+        assert_eq!(simplified.last(), stroke.last());
+        // the corner survives
+        assert!(simplified
+            .iter()
+            .any(|point| (point.x - 500).abs() <= 2 && (point.y - 500).abs() <= 2));
+    }
+
+    #[test]
+    fn test_simplify_keeps_short_strokes() {
+        let stroke = vec![Point2D { x: 0, y: 0 }, Point2D { x: 1, y: 1 }];
+        assert_eq!(simplify(&stroke, 2.0), stroke);
+
+        let closed = vec![
+            Point2D { x: 0, y: 0 },
+            Point2D { x: 10, y: 10 },
+            Point2D { x: 0, y: 0 },
+        ];
+        assert_eq!(simplify(&closed, 2.0), closed);
+    }
+}
@@ -0,0 +1,110 @@
+use actix_web::{web, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    events::{Shape, ShapeType},
+    replay::CanvasShapeState,
+};
+
+// Printable view of a canvas, GET /canvas/{canvas_id}/print
+// A server rendered page without scripts, the SVG of export.rs is inlined so the page is a single request
+// Huge boards are cut off after PrintLimits::max_shapes shapes, the page tells how many were left out
+
+/// Shapes inlined into the print page unless configured otherwise
+pub const DEFAULT_PRINT_MAX_SHAPES: usize = 5_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintLimits {
+    /// shapes inlined into the SVG, the backmost ones are kept
+    pub max_shapes: usize,
+}
+
+impl Default for PrintLimits {
+    fn default() -> Self {
+        Self {
+            max_shapes: DEFAULT_PRINT_MAX_SHAPES,
+        }
+    }
+}
+
+/// Limits registered in the app data, the defaults if none are registered
+pub fn request_print_limits(request: &HttpRequest) -> PrintLimits {
+    request
+        .app_data::<web::Data<PrintLimits>>()
+        .map_or_else(Default::default, |limits| limits.get_ref().clone())
+}
+
+/// Row of the shape legend
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LegendEntry {
+    pub shape_type: ShapeType,
+    /// name of the type shown on the page
+    pub label: &'static str,
+    pub count: usize,
+}
+
+fn label(shape_type: ShapeType) -> &'static str {
+    match shape_type {
+        ShapeType::Line => "Linie",
+        ShapeType::Circle => "Kreis",
+        ShapeType::Rectangle => "Rechteck",
+        ShapeType::Triangle => "Dreieck",
+        ShapeType::Path => "Pfad",
+    }
+}
+
+/// Shapes per type of the whole canvas, also the ones left out of the SVG, types without shapes are omitted
+pub fn legend(state: &CanvasShapeState) -> Vec<LegendEntry> {
+    let mut counts = [0; ShapeType::ALL.len()];
+    for shape in &state.shapes {
+        let Ok(shape) = Shape::deserialize(shape) else {
+            continue;
+        };
+        if let Some(index) = ShapeType::ALL
+            .iter()
+            .position(|shape_type| *shape_type == shape.shape_type())
+        {
+            counts[index] += 1;
+        }
+    }
+    ShapeType::ALL
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(shape_type, count)| LegendEntry {
+            shape_type,
+            label: label(shape_type),
+            count,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_legend_counts_shapes_per_type() {
+        let state = CanvasShapeState {
+            shapes: vec![
+                json!({"type": "Line", "id": "l1", "temporary": false, "borderColor": "#000", "fillColor": "#000", "from": {"x": 0, "y": 0}, "to": {"x": 5, "y": 5}}),
+                json!({"type": "Circle", "id": "c1", "temporary": false, "borderColor": "#000", "fillColor": "#fff", "center": {"x": 0, "y": 0}, "radius": 3.0}),
+                json!({"type": "Line", "id": "l2", "temporary": false, "borderColor": "#000", "fillColor": "#000", "from": {"x": 0, "y": 0}, "to": {"x": 1, "y": 1}}),
+                json!({"id": "broken"}),
+            ],
+            ..Default::default()
+        };
+
+        let legend = legend(&state);
+        assert_eq!(
+            legend
+                .iter()
+                .map(|entry| (entry.shape_type, entry.count))
+                .collect::<Vec<_>>(),
+            vec![(ShapeType::Line, 2), (ShapeType::Circle, 1)]
+        );
+        assert_eq!(legend[0].label, "Linie");
+        assert!(super::legend(&CanvasShapeState::default()).is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+use utoipa::ToSchema;
+
+use super::{coalesce::updated_shape_id, events::CanvasEvents};
+use crate::userstore::UserId;
+
+// Who last changed a shape and when, folded from the events of the eventlog
+// The server stamps the userId of ShapeAdded, ShapeUpdated and ShapeZChanged, it is the authority on the editor
+// Events of logs written before the editor was stamped still count, but leave the editor unknown
+// The live canvas additionally remembers when the last update arrived, to count updates of different users
+// racing for the same shape
+
+/// Time within which an update of another user counts as conflict
+pub const DEFAULT_CONFLICT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Last change of a shape
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ShapeProvenance {
+    /// None for changes written before the editor was recorded
+    pub last_modified_by: Option<UserId>,
+    /// timestamp of the last change in seconds, as stamped by the server
+    pub last_modified_at: u64,
+    /// updates and z changes since the shape was added
+    pub modifications: u64,
+    /// arrival of the last update on the server in milliseconds, only known for a loaded canvas
+    #[serde(skip)]
+    received_at: Option<u64>,
+}
+
+/// Provenance of the live shapes of a canvas, keyed by shape id
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(transparent)]
+pub struct Provenance {
+    shapes: BTreeMap<String, ShapeProvenance>,
+}
+
+impl Provenance {
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    pub fn get(&self, shape_id: &str) -> Option<&ShapeProvenance> {
+        self.shapes.get(shape_id)
+    }
+
+    /// Editors of the live shapes
+    pub fn editors(&self) -> impl Iterator<Item = &UserId> {
+        self.shapes
+            .values()
+            .filter_map(|shape| shape.last_modified_by.as_ref())
+    }
+
+    /// Replaces every editor, e.g. by its pseudonym, see redaction.rs
+    pub fn map_editors(&mut self, rename: impl Fn(&UserId) -> UserId) {
+        for shape in self.shapes.values_mut() {
+            if let Some(editor) = &mut shape.last_modified_by {
+                *editor = rename(editor);
+            }
+        }
+    }
+
+    /// Applies a single event, changes of unknown shapes are ignored like the fold ignores them
+    pub fn apply(&mut self, event: &CanvasEvents) {
+        match event {
+            // the fold keeps the first of colliding shapes, so does the provenance
+            CanvasEvents::ShapeAdded {
+                shape,
+                userId,
+                timestamp,
+                ..
+            } => {
+                self.shapes
+                    .entry(shape.get_id().to_string())
+                    .or_insert_with(|| ShapeProvenance {
+                        last_modified_by: userId.clone(),
+                        last_modified_at: *timestamp,
+                        modifications: 0,
+                        received_at: None,
+                    });
+            }
+            CanvasEvents::ShapeUpdated {
+                userId, timestamp, ..
+            }
+            | CanvasEvents::ShapeZChanged {
+                userId, timestamp, ..
+            } => {
+                let Some(shape) = Self::changed_shape_id(event)
+                    .and_then(|shape_id| self.shapes.get_mut(shape_id))
+                else {
+                    return;
+                };
+                shape.last_modified_by = userId.clone();
+                shape.last_modified_at = *timestamp;
+                shape.modifications += 1;
+            }
+            CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                self.shapes.remove(shapeId);
+            }
+            CanvasEvents::CanvasCleared { .. } => self.shapes.clear(),
+            _ => (),
+        }
+    }
+
+    ///
+    /// Applies an event that arrived at now_ms
+    /// Returns the shape if the event changes a shape whose previous change came from another user within window
+    ///
+    pub fn record(
+        &mut self,
+        now_ms: u64,
+        window: Duration,
+        event: &CanvasEvents,
+    ) -> Option<String> {
+        let Some(shape_id) = Self::changed_shape_id(event).map(str::to_string) else {
+            self.apply(event);
+            return None;
+        };
+        let editor = match event {
+            CanvasEvents::ShapeUpdated { userId, .. }
+            | CanvasEvents::ShapeZChanged { userId, .. } => userId.as_ref(),
+            _ => None,
+        };
+        let conflicts = self.shapes.get(&shape_id).is_some_and(|previous| {
+            let recent = previous
+                .received_at
+                .is_some_and(|received_at| now_ms < received_at + window.as_millis() as u64);
+            recent && editor.is_some() && previous.last_modified_by.as_ref() != editor
+        });
+
+        self.apply(event);
+        if let Some(shape) = self.shapes.get_mut(&shape_id) {
+            shape.received_at = Some(now_ms);
+        }
+        conflicts.then_some(shape_id)
+    }
+
+    fn changed_shape_id(event: &CanvasEvents) -> Option<&str> {
+        match event {
+            CanvasEvents::ShapeUpdated { .. } => updated_shape_id(event),
+            CanvasEvents::ShapeZChanged { shapeId, .. } => Some(shapeId),
+            _ => None,
+        }
+    }
+}
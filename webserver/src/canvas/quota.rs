@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+use crate::messages::{Message, MessageKey};
+
+// Soft limits of a canvas
+// Owners and moderators are warned once when usage crosses the warning threshold, nothing is rejected
+// The warning is only issued again after usage dropped below the threshold by the hysteresis margin
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+pub enum QuotaKind {
+    Shapes,
+    LogBytes,
+    Members,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaLimits {
+    /// persisted shapes of a canvas
+    pub max_shapes: u64,
+    /// size of the eventlog of a canvas
+    pub max_log_bytes: u64,
+    /// users with access to a canvas, including the owner
+    pub max_members: u64,
+    /// fraction of the limit at which owners and moderators are warned
+    pub warning_ratio: f64,
+    /// fraction of the limit usage has to drop below the warning threshold before warning again
+    pub hysteresis_ratio: f64,
+}
+
+impl Default for QuotaLimits {
+    fn default() -> Self {
+        Self {
+            max_shapes: 10_000,
+            max_log_bytes: 16 * 1024 * 1024,
+            max_members: 100,
+            warning_ratio: 0.8,
+            hysteresis_ratio: 0.05,
+        }
+    }
+}
+
+impl QuotaLimits {
+    pub fn limit(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Shapes => self.max_shapes,
+            QuotaKind::LogBytes => self.max_log_bytes,
+            QuotaKind::Members => self.max_members,
+        }
+    }
+
+    pub fn warning_threshold(&self, kind: QuotaKind) -> u64 {
+        (self.limit(kind) as f64 * self.warning_ratio).ceil() as u64
+    }
+
+    /// usage below this value clears an issued warning
+    fn reset_threshold(&self, kind: QuotaKind) -> u64 {
+        let ratio = (self.warning_ratio - self.hysteresis_ratio).max(0.0);
+        (self.limit(kind) as f64 * ratio).floor() as u64
+    }
+
+    pub fn usage(&self, kind: QuotaKind, usage: u64) -> QuotaUsage {
+        QuotaUsage {
+            kind,
+            usage,
+            warning_threshold: self.warning_threshold(kind),
+            limit: self.limit(kind),
+        }
+    }
+}
+
+/// Usage of a single quota with both thresholds, enough for clients to render a progress bar
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct QuotaUsage {
+    pub kind: QuotaKind,
+    pub usage: u64,
+    pub warning_threshold: u64,
+    pub limit: u64,
+}
+
+impl QuotaUsage {
+    /// Warning shown to owners and moderators
+    pub fn message(&self) -> Message {
+        let key = match self.kind {
+            QuotaKind::Shapes => MessageKey::QuotaShapesWarning,
+            QuotaKind::LogBytes => MessageKey::QuotaLogBytesWarning,
+            QuotaKind::Members => MessageKey::QuotaMembersWarning,
+        };
+        Message::new(key)
+            .param("usage", self.usage)
+            .param("limit", self.limit)
+    }
+}
+
+/// Persisted warning, listed by the stats endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct QuotaWarning {
+    pub timestamp: u64,
+    pub usage: QuotaUsage,
+}
+
+/// Quotas of a canvas an owner was already warned about
+#[derive(Debug, Default, Clone)]
+pub struct QuotaWarnings {
+    warned: HashSet<QuotaKind>,
+}
+
+impl QuotaWarnings {
+    /// Returns true if usage crossed the warning threshold for the first time
+    pub fn check(&mut self, kind: QuotaKind, usage: u64, limits: &QuotaLimits) -> bool {
+        if usage >= limits.warning_threshold(kind) {
+            return self.warned.insert(kind);
+        }
+        if usage < limits.reset_threshold(kind) {
+            self.warned.remove(&kind);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> QuotaLimits {
+        QuotaLimits {
+            max_shapes: 100,
+            ..QuotaLimits::default()
+        }
+    }
+
+    #[test]
+    fn test_crossing_threshold_warns_once() {
+        let limits = limits();
+        let mut warnings = QuotaWarnings::default();
+
+        let warned: Vec<u64> = (0..=100)
+            .filter(|usage| warnings.check(QuotaKind::Shapes, *usage, &limits))
+            .collect();
+        assert_eq!(warned, vec![80]);
+        // quotas are independent
+        assert!(warnings.check(QuotaKind::Members, 80, &limits));
+    }
+
+    #[test]
+    fn test_hysteresis_prevents_flapping() {
+        let limits = limits();
+        let mut warnings = QuotaWarnings::default();
+
+        assert!(warnings.check(QuotaKind::Shapes, 80, &limits));
+        // hovering around the threshold doesn't warn again
+        for usage in [79, 80, 76, 81, 75, 80] {
+            assert!(!warnings.check(QuotaKind::Shapes, usage, &limits));
+        }
+
+        // dropping below the margin clears the warning
+        assert!(!warnings.check(QuotaKind::Shapes, 74, &limits));
+        assert!(warnings.check(QuotaKind::Shapes, 80, &limits));
+    }
+}
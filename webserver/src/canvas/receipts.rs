@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::events::CanvasEvents;
+use crate::userstore::UserId;
+
+// Read receipts of a canvas, owners can see who looked at the latest state
+// A user caught up to a sequence number has seen every change of the drawing up to that line of the eventlog
+// Only changes of the drawing count, joins, selections and the markers themselves don't outdate a member
+// Markers are persisted sparsely as UserCaughtUp, at most once per MARKER_INTERVAL_SECS on sync and once per disconnect
+
+/// Minimum time between two markers of a user written on sync, in seconds like the canvas events
+pub const MARKER_INTERVAL_SECS: u64 = 10 * 60;
+
+/// Sequence number a user has seen and when, timestamp in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaughtUp {
+    pub seq: u64,
+    pub timestamp: u64,
+}
+
+/// Serialized for the warm handoff only, see handoff.rs
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ReadReceipts {
+    /// sequence number of the last change of the drawing
+    latest_change: u64,
+    /// latest catch up of every user, ahead of the persisted marker while the user is connected
+    caught_up: HashMap<UserId, CaughtUp>,
+    /// last persisted marker of every user
+    persisted: HashMap<UserId, CaughtUp>,
+}
+
+impl ReadReceipts {
+    /// Folds a persisted eventlog, markers of earlier instances are restored
+    pub fn from_log<'a>(events: impl IntoIterator<Item = &'a CanvasEvents>) -> Self {
+        let mut receipts = Self::default();
+        for (index, event) in events.into_iter().enumerate() {
+            receipts.apply(index as u64 + 1, event);
+        }
+        receipts
+    }
+
+    /// Events that change what a viewer sees on the canvas
+    pub fn is_change(event: &CanvasEvents) -> bool {
+        matches!(
+            event,
+            CanvasEvents::ShapeAdded { .. }
+                | CanvasEvents::ShapeUpdated { .. }
+                | CanvasEvents::ShapeRemoved { .. }
+                | CanvasEvents::ShapeZChanged { .. }
+                | CanvasEvents::CanvasCleared { .. }
+        )
+    }
+
+    /// Applies an event persisted at seq
+    pub fn apply(&mut self, seq: u64, event: &CanvasEvents) {
+        match event {
+            CanvasEvents::UserCaughtUp {
+                userId,
+                seq,
+                timestamp,
+            } => {
+                let marker = CaughtUp {
+                    seq: *seq,
+                    timestamp: *timestamp,
+                };
+                self.caught_up.insert(userId.clone(), marker);
+                self.persisted.insert(userId.clone(), marker);
+            }
+            event if Self::is_change(event) => self.latest_change = seq,
+            _ => (),
+        }
+    }
+
+    ///
+    /// Records that the user has seen the latest change
+    /// Returns the marker to persist, on sync only if the last marker of the user is older than MARKER_INTERVAL_SECS
+    ///
+    pub fn catch_up(
+        &mut self,
+        user_id: &UserId,
+        now: u64,
+        disconnect: bool,
+    ) -> Option<CanvasEvents> {
+        let caught_up = CaughtUp {
+            seq: self.latest_change,
+            timestamp: now,
+        };
+        self.caught_up.insert(user_id.clone(), caught_up);
+
+        match self.persisted.get(user_id) {
+            Some(marker) if marker.seq >= caught_up.seq => return None,
+            Some(marker) if !disconnect && now < marker.timestamp + MARKER_INTERVAL_SECS => {
+                return None
+            }
+            _ => (),
+        }
+
+        self.persisted.insert(user_id.clone(), caught_up);
+        Some(CanvasEvents::UserCaughtUp {
+            userId: user_id.clone(),
+            seq: caught_up.seq,
+            timestamp: now,
+        })
+    }
+
+    /// Read state at now, connected users see every change as it happens
+    pub fn read_state<'a>(
+        &self,
+        connected: impl IntoIterator<Item = &'a UserId>,
+        now: u64,
+    ) -> ReadState {
+        let mut caught_up = self.caught_up.clone();
+        for user_id in connected {
+            caught_up.insert(
+                user_id.clone(),
+                CaughtUp {
+                    seq: self.latest_change,
+                    timestamp: now,
+                },
+            );
+        }
+
+        ReadState {
+            current_seq: self.latest_change,
+            caught_up,
+        }
+    }
+}
+
+/// Read receipts of all users at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadState {
+    /// sequence number of the last change of the drawing
+    pub current_seq: u64,
+    pub caught_up: HashMap<UserId, CaughtUp>,
+}
+
+impl ReadState {
+    /// None for users that never caught up
+    pub fn caught_up(&self, user_id: &UserId) -> Option<CaughtUp> {
+        self.caught_up.get(user_id).copied()
+    }
+
+    pub fn is_up_to_date(&self, user_id: &UserId) -> bool {
+        self.caught_up(user_id)
+            .is_some_and(|caught_up| caught_up.seq >= self.current_seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::events::{Point2D, Shape};
+
+    fn shape_added(id: &str) -> CanvasEvents {
+        CanvasEvents::ShapeAdded {
+            origin: "session".to_string(),
+            timestamp: 0,
+            shape: Shape::Line {
+                id: id.to_string(),
+                temporary: false,
+                borderColor: "#000".to_string(),
+                fillColor: "#000".to_string(),
+                attributes: Default::default(),
+                from: Point2D { x: 0, y: 0 },
+                to: Point2D { x: 1, y: 1 },
+            },
+            userId: Some("owner".to_string()),
+        }
+    }
+
+    fn user_joined(user_id: &str) -> CanvasEvents {
+        CanvasEvents::UserJoined {
+            timestamp: 0,
+            userId: user_id.to_string(),
+            sessionId: "session".to_string(),
+            username: user_id.to_string(),
+            accessLevel: crate::canvas::store::AccessLevel::Write,
+        }
+    }
+
+    #[test]
+    fn test_repeated_syncs_are_throttled() {
+        let viewer = "viewer".to_string();
+        let mut receipts = ReadReceipts::from_log(&[shape_added("l1")]);
+
+        assert!(receipts.catch_up(&viewer, 100, false).is_some());
+        // nothing changed, nothing to record
+        assert!(receipts
+            .catch_up(&viewer, 100 + MARKER_INTERVAL_SECS, false)
+            .is_none());
+
+        receipts.apply(2, &shape_added("l2"));
+        // changed, but the last marker is too recent
+        assert!(receipts.catch_up(&viewer, 101, false).is_none());
+        assert_eq!(receipts.caught_up[&viewer].seq, 2);
+        // a disconnect is always recorded
+        assert_eq!(
+            receipts
+                .catch_up(&viewer, 102, true)
+                .map(|marker| marker.timestamp()),
+            Some(102)
+        );
+
+        receipts.apply(3, &shape_added("l3"));
+        let marker = receipts.catch_up(&viewer, 102 + MARKER_INTERVAL_SECS, false);
+        assert!(matches!(
+            marker,
+            Some(CanvasEvents::UserCaughtUp { seq: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_state_of_members() {
+        let log = [
+            shape_added("l1"),
+            user_joined("viewer"),
+            CanvasEvents::UserCaughtUp {
+                userId: "viewer".to_string(),
+                seq: 1,
+                timestamp: 10,
+            },
+            shape_added("l2"),
+            CanvasEvents::UserCaughtUp {
+                userId: "late".to_string(),
+                seq: 4,
+                timestamp: 20,
+            },
+            // joins don't outdate anyone
+            user_joined("late"),
+        ];
+        let receipts = ReadReceipts::from_log(&log);
+        let connected = ["live".to_string()];
+        let state = receipts.read_state(&connected, 30);
+
+        assert_eq!(state.current_seq, 4);
+        assert!(!state.is_up_to_date(&"viewer".to_string()));
+        assert!(state.is_up_to_date(&"late".to_string()));
+        assert!(state.is_up_to_date(&"live".to_string()));
+        // member that never connected
+        assert_eq!(state.caught_up(&"never".to_string()), None);
+        assert!(!state.is_up_to_date(&"never".to_string()));
+    }
+}
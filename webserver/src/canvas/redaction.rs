@@ -0,0 +1,257 @@
+use ring::digest;
+use serde_json::Value;
+
+use super::{
+    comments::Comment,
+    contributors::Contributors,
+    events::CanvasEvents,
+    provenance::ShapeProvenance,
+    replay::{CanvasShapeState, CREATED_BY_KEY, CREATED_BY_NAME_KEY},
+    server::Msg,
+    store::AccessLevel,
+};
+
+// Redaction of the events sent to Read sessions of canvases anonymized for readers
+// Applied when broadcasting and in the initial state, the eventlog and every other session keep the full events
+// The HTTP endpoints answering replays, provenance and comments apply it to Read callers the same way
+// User ids, usernames and initiators are replaced by pseudonyms, ServerNotices not addressed to the session are dropped
+// Pseudonyms are derived from a random salt of the canvas, a user has the same pseudonym within a canvas,
+// but the pseudonyms of two canvases can't be matched
+
+pub const PSEUDONYM_PREFIX: &str = "viewer-";
+
+/// Hex digits of the hash in a pseudonym
+const PSEUDONYM_DIGITS: usize = 8;
+
+/// Fields naming a user, replaced by the pseudonym of the user
+const IDENTITY_FIELDS: [&str; 2] = ["userId", "initiatorId"];
+
+/// Stable pseudonym of the user within the canvas of the salt
+pub fn pseudonym(salt: &str, user_id: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, format!("{salt}.{user_id}").as_bytes());
+    let hex: String = hash
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("{PSEUDONYM_PREFIX}{}", &hex[..PSEUDONYM_DIGITS])
+}
+
+/// Whether a session with the access level receives redacted events, owners, moderators and writers never do
+pub fn is_redacted_for(access_level: &AccessLevel, anonymize_for_readers: bool) -> bool {
+    anonymize_for_readers && *access_level == AccessLevel::Read
+}
+
+/// Event as a Read session sees it, None if it is not sent to Read sessions at all
+pub fn redact(event: &CanvasEvents, salt: &str) -> Option<Value> {
+    if matches!(event, CanvasEvents::ServerNotice { .. }) {
+        return None;
+    }
+
+    let mut value = serde_json::to_value(event).ok()?;
+    let object = value.as_object_mut()?;
+    let mut user_pseudonym = None;
+    for field in IDENTITY_FIELDS {
+        if let Some(Value::String(user_id)) = object.get_mut(field) {
+            let replacement = pseudonym(salt, user_id);
+            if field == "userId" {
+                user_pseudonym = Some(replacement.clone());
+            }
+            *user_id = replacement;
+        }
+    }
+    // the name would undo the pseudonym, it is replaced by the pseudonym as well
+    if let Some(Value::String(username)) = object.get_mut("username") {
+        *username = user_pseudonym.unwrap_or_else(|| PSEUDONYM_PREFIX.to_string());
+    }
+    Some(value)
+}
+
+/// Replayed state as a Read caller sees it, creators, contributors and editors are replaced by their pseudonyms
+pub fn redact_state(state: &CanvasShapeState, salt: &str) -> CanvasShapeState {
+    let mut state = state.clone();
+    for shape in &mut state.shapes {
+        let Some(object) = shape.as_object_mut() else {
+            continue;
+        };
+        object.remove(CREATED_BY_NAME_KEY);
+        let created_by = object
+            .get(CREATED_BY_KEY)
+            .and_then(Value::as_str)
+            .map(|user_id| pseudonym(salt, user_id));
+        // the name would undo the pseudonym, it is replaced by the pseudonym as well
+        if let Some(created_by) = created_by {
+            object.insert(
+                CREATED_BY_KEY.to_string(),
+                Value::String(created_by.clone()),
+            );
+            object.insert(CREATED_BY_NAME_KEY.to_string(), Value::String(created_by));
+        }
+    }
+
+    let mut contributors = Contributors::default();
+    for (user_id, _) in state.contributors.iter() {
+        let replacement = pseudonym(salt, user_id);
+        contributors.insert_unrecorded(replacement.clone(), replacement);
+    }
+    state.contributors = contributors;
+    state
+        .provenance
+        .map_editors(|user_id| pseudonym(salt, user_id));
+    state
+}
+
+/// Last change of a shape as a Read caller sees it, the name of the editor is its pseudonym
+pub fn redact_provenance(
+    provenance: &ShapeProvenance,
+    salt: &str,
+) -> (ShapeProvenance, Option<String>) {
+    let mut provenance = provenance.clone();
+    provenance.last_modified_by = provenance
+        .last_modified_by
+        .map(|user_id| pseudonym(salt, &user_id));
+    let name = provenance.last_modified_by.clone();
+    (provenance, name)
+}
+
+/// Comment as a Read caller sees it, the name of the author is its pseudonym
+pub fn redact_comment(comment: &Comment, salt: &str) -> (Comment, Option<String>) {
+    let mut comment = comment.clone();
+    comment.author_id = comment.author_id.map(|user_id| pseudonym(salt, &user_id));
+    comment.resolved_by = comment.resolved_by.map(|user_id| pseudonym(salt, &user_id));
+    let name = comment.author_id.clone();
+    (comment, name)
+}
+
+///
+/// Payloads of a broadcast event, one per recipient class
+/// Each class is serialized once on first use, no matter how many sessions receive it
+///
+pub struct EventPayloads<'a> {
+    event: &'a CanvasEvents,
+    salt: &'a str,
+    full: Option<Option<Msg>>,
+    redacted: Option<Option<Msg>>,
+    serialized: usize,
+}
+
+impl<'a> EventPayloads<'a> {
+    pub fn new(event: &'a CanvasEvents, salt: &'a str) -> Self {
+        Self {
+            event,
+            salt,
+            full: None,
+            redacted: None,
+            serialized: 0,
+        }
+    }
+
+    /// Payload for a session of the class, None if the event is not sent to it or can't be serialized
+    pub fn get(&mut self, redacted: bool) -> Option<&Msg> {
+        let (event, salt) = (self.event, self.salt);
+        let payload = if redacted {
+            &mut self.redacted
+        } else {
+            &mut self.full
+        };
+        if payload.is_none() {
+            self.serialized += 1;
+            let message = if redacted {
+                redact(event, salt).and_then(|value| serde_json::to_string(&value).ok())
+            } else {
+                event.try_into().ok()
+            };
+            *payload = Some(message);
+        }
+        payload.as_ref().and_then(Option::as_ref)
+    }
+
+    /// Payloads serialized so far, at most one per class
+    pub fn serialized(&self) -> usize {
+        self.serialized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{canvas::events::NoticeLevel, messages::MessageKey};
+
+    fn joined(user_id: &str) -> CanvasEvents {
+        CanvasEvents::UserJoined {
+            timestamp: 1,
+            userId: user_id.to_string(),
+            sessionId: "session".to_string(),
+            username: "alice".to_string(),
+            accessLevel: AccessLevel::Write,
+        }
+    }
+
+    #[test]
+    fn test_pseudonyms_are_stable_per_canvas() {
+        let alice = pseudonym("salt-a", "alice");
+        assert!(alice.starts_with(PSEUDONYM_PREFIX));
+        assert_eq!(alice.len(), PSEUDONYM_PREFIX.len() + PSEUDONYM_DIGITS);
+        assert_eq!(pseudonym("salt-a", "alice"), alice);
+        assert_ne!(pseudonym("salt-a", "bob"), alice);
+        assert_ne!(pseudonym("salt-b", "alice"), alice);
+
+        let redacted = redact(&joined("alice"), "salt-a").unwrap();
+        assert_eq!(redacted["userId"], alice);
+        assert_eq!(redacted["username"], alice);
+        assert_eq!(redacted["sessionId"], "session");
+
+        let notice = CanvasEvents::notice(1, NoticeLevel::Warning, MessageKey::CanvasViewDenied);
+        assert_eq!(redact(&notice, "salt-a"), None);
+    }
+
+    #[test]
+    fn test_payloads_are_serialized_once_per_class() {
+        let event = joined("alice");
+        let mut payloads = EventPayloads::new(&event, "salt");
+        for _ in 0..10 {
+            assert!(payloads.get(false).unwrap().contains("\"alice\""));
+        }
+        assert_eq!(payloads.serialized(), 1);
+        for _ in 0..10 {
+            assert!(!payloads.get(true).unwrap().contains("alice"));
+            assert!(payloads.get(false).is_some());
+        }
+        assert_eq!(payloads.serialized(), 2);
+
+        for level in [
+            AccessLevel::Owner,
+            AccessLevel::Moderate,
+            AccessLevel::Write,
+            AccessLevel::Voice,
+        ] {
+            assert!(!is_redacted_for(&level, true));
+        }
+        assert!(is_redacted_for(&AccessLevel::Read, true));
+        assert!(!is_redacted_for(&AccessLevel::Read, false));
+    }
+
+    #[test]
+    fn test_replayed_creators_are_redacted() {
+        let mut state = CanvasShapeState::default();
+        state.apply(
+            1,
+            &CanvasEvents::ContributorSeen {
+                userId: "alice".to_string(),
+                username: "Alice".to_string(),
+                firstSeen: 1,
+            },
+        );
+        state.apply(
+            2,
+            &serde_json::from_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}"##).unwrap(),
+        );
+
+        let alice = pseudonym("salt", "alice");
+        let redacted = redact_state(&state, "salt");
+        assert_eq!(redacted.shapes[0][CREATED_BY_KEY], alice);
+        assert_eq!(redacted.shapes[0][CREATED_BY_NAME_KEY], alice);
+        assert_eq!(redacted.contributors.name(&alice), Some(alice.as_str()));
+        assert!(!serde_json::to_string(&redacted).unwrap().contains("lice"));
+    }
+}
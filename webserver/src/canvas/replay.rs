@@ -0,0 +1,655 @@
+use super::{
+    comments::Comments, contributors::Contributors, events::CanvasEvents, provenance::Provenance,
+    store::CanvasId,
+};
+use crate::persistence::{EventLogPersistenceJson, ReplayIssue, ReplayIssueKind};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    io,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use utoipa::ToSchema;
+
+// Time travel through the eventlog of a canvas
+// Folds the persisted events into the shapes as they were at a cutoff, the same way clients build their state
+// The eventlog is streamed from disk, only the resulting shapes are kept in memory
+// Sequence numbers are line numbers of the eventlog, they are stable as long as the log is not compacted
+
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 100;
+pub const REPLAY_CACHE_ENTRIES_PER_CANVAS: usize = 8;
+
+/// Point in the eventlog up to which events are applied, the cutoff itself is included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplayCutoff {
+    /// events with a timestamp up to this one, unix timestamp in seconds like the events of the server
+    Timestamp(u64),
+    /// events up to this sequence number, starting at 1
+    Sequence(u64),
+}
+
+impl FromStr for ReplayCutoff {
+    type Err = String;
+
+    /// Parses `seq:<number>` as sequence number, a plain number as timestamp
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("seq:") {
+            Some(seq) => seq.parse().map(ReplayCutoff::Sequence),
+            None => value.parse().map(ReplayCutoff::Timestamp),
+        }
+        .map_err(|_| format!("Invalid cutoff {value}, expected <timestamp> or seq:<number>"))
+    }
+}
+
+/// Key of the creator id added to replayed shapes, the Canvas Application ignores unknown keys
+pub const CREATED_BY_KEY: &str = "createdBy";
+/// Key of the name the creator had when adding the shape, only for creators recorded in the eventlog
+pub const CREATED_BY_NAME_KEY: &str = "createdByName";
+
+/// Shapes of a canvas at a point in the eventlog
+#[derive(Serialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct CanvasShapeState {
+    /// shapes ordered from back to front
+    pub shapes: Vec<Value>,
+    /// sequence number of the last applied event
+    pub seq: u64,
+    /// timestamp of the last applied event
+    pub timestamp: Option<u64>,
+    /// events that could not be applied as they are, e.g. a shape added under the id of a live shape
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<ReplayIssue>,
+    /// latest name of every contributor up to the cutoff
+    #[serde(skip_serializing_if = "Contributors::is_empty")]
+    #[schema(value_type = BTreeMap<String, String>)]
+    pub contributors: Contributors,
+    /// last change of every shape, only sent on request
+    #[serde(skip)]
+    pub provenance: Provenance,
+    /// comments of the shapes, only sent on request
+    #[serde(skip)]
+    pub comments: Comments,
+}
+
+impl CanvasShapeState {
+    fn position(&self, shape_id: &str) -> Option<usize> {
+        self.shapes
+            .iter()
+            .position(|shape| shape.get("id").and_then(Value::as_str) == Some(shape_id))
+    }
+
+    /// Creators and editors of the shapes without a record, they changed shapes before contributors were recorded
+    pub fn unrecorded_creators(&self) -> BTreeSet<String> {
+        self.shapes
+            .iter()
+            .filter_map(|shape| shape.get(CREATED_BY_KEY).and_then(Value::as_str))
+            .chain(self.provenance.editors().map(String::as_str))
+            .filter(|user_id| !self.contributors.contains(&user_id.to_string()))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Applies a single event, mirrors the ShapeStore of the Canvas Application
+    /// Events for unknown shapes are ignored
+    pub fn apply(&mut self, seq: u64, event: &CanvasEvents) {
+        match event {
+            // logs written before ids were checked may add a live shape again, the first one is kept
+            CanvasEvents::ShapeAdded { shape, .. } if self.position(shape.get_id()).is_some() => {
+                self.issues.push(ReplayIssue {
+                    skipped: true,
+                    ..ReplayIssue::event(
+                        seq as usize - 1,
+                        ReplayIssueKind::Duplicate,
+                        format!("Shape {} added again", shape.get_id()),
+                    )
+                });
+            }
+
+            CanvasEvents::ShapeAdded { shape, userId, .. } => {
+                let Ok(mut value) = serde_json::to_value(shape) else {
+                    return;
+                };
+                if let (Some(created_by), Some(object)) = (userId, value.as_object_mut()) {
+                    object.insert(
+                        CREATED_BY_KEY.to_string(),
+                        Value::String(created_by.clone()),
+                    );
+                    if let Some(username) = self.contributors.name(created_by) {
+                        object.insert(
+                            CREATED_BY_NAME_KEY.to_string(),
+                            Value::String(username.to_string()),
+                        );
+                    }
+                }
+                self.shapes.push(value);
+            }
+
+            CanvasEvents::ContributorSeen { .. } => self.contributors.apply(event),
+
+            CanvasEvents::ShapeUpdated { shape, .. } => {
+                let index = shape
+                    .get("id")
+                    .and_then(Value::as_str)
+                    .and_then(|shape_id| self.position(shape_id));
+                if let (Some(index), Some(update)) = (index, shape.as_object()) {
+                    if let Some(target) = self.shapes[index].as_object_mut() {
+                        // updates are partial shapes, merge them like Object.assign
+                        for (key, value) in update {
+                            target.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+
+            CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                if let Some(index) = self.position(shapeId) {
+                    self.shapes.remove(index);
+                }
+            }
+
+            CanvasEvents::ShapeZChanged { shapeId, z, .. } => self.change_z(shapeId, z),
+
+            CanvasEvents::CanvasCleared { .. } => self.shapes.clear(),
+
+            _ => (),
+        }
+        self.provenance.apply(event);
+        self.comments.apply(event);
+
+        self.seq = seq;
+        self.timestamp = Some(event.timestamp());
+    }
+
+    /// z is serialized as { isInfinity, value }, infinity sends the shape to the front or back
+    fn change_z(&mut self, shape_id: &str, z: &Value) {
+        let Some(index) = self.position(shape_id) else {
+            return;
+        };
+        let is_infinity = z.get("isInfinity").and_then(Value::as_bool) == Some(true);
+        let layers = z.get("value").and_then(Value::as_f64).unwrap_or(0.0) as i64;
+        if layers == 0 {
+            return;
+        }
+
+        let shape = self.shapes.remove(index);
+        let target = match (is_infinity, layers > 0) {
+            (true, true) => self.shapes.len(),
+            (true, false) => 0,
+            (false, _) => (index as i64 + layers).clamp(0, self.shapes.len() as i64) as usize,
+        };
+        self.shapes.insert(target, shape);
+    }
+}
+
+/// Result of folding the eventlog
+#[derive(Debug)]
+pub struct Replay {
+    pub state: CanvasShapeState,
+    /// the cutoff was reached, appending to the log can't change the state anymore
+    pub settled: bool,
+}
+
+/// Streams the eventlog and folds it up to the cutoff, without cutoff the whole log is applied
+/// Lines that fail to deserialize are skipped, but still count towards the sequence number
+pub fn replay_log(file_path: &str, cutoff: Option<ReplayCutoff>) -> Result<Replay, io::Error> {
+    let mut state = CanvasShapeState::default();
+
+    let persistence = match EventLogPersistenceJson::open(file_path) {
+        Ok(persistence) => persistence,
+        // canvases without events have no eventlog yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Replay {
+                state,
+                settled: false,
+            })
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut passed = false;
+    for (index, line) in persistence.stream_lines::<CanvasEvents>().enumerate() {
+        let seq = index as u64 + 1;
+        let event = match line? {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Skipping invalid event {seq} in {file_path}: {e}");
+                continue;
+            }
+        };
+
+        match cutoff {
+            // events written before the server stamped them may be out of order, later events can still be due
+            Some(ReplayCutoff::Timestamp(until)) => {
+                passed = event.timestamp() > until;
+                if !passed {
+                    state.apply(seq, &event);
+                }
+            }
+            Some(ReplayCutoff::Sequence(until)) if seq > until => {
+                return Ok(Replay {
+                    state,
+                    settled: true,
+                });
+            }
+            _ => state.apply(seq, &event),
+        }
+    }
+
+    // the log ended exactly on the cutoff sequence, or past the cutoff timestamp
+    // the stamps of the server only increase, events appended later are past the cutoff as well
+    let settled =
+        passed || matches!(cutoff, Some(ReplayCutoff::Sequence(until)) if state.seq == until);
+    Ok(Replay { state, settled })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum KeyframeKind {
+    /// every nth event
+    Interval,
+    CanvasCleared,
+    CanvasStateChanged,
+    CanvasSettingsChanged,
+    /// last event of the log
+    Latest,
+}
+
+/// Point of interest in the eventlog, used to drive a scrubber
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Keyframe {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub kind: KeyframeKind,
+}
+
+/// Streams the eventlog and collects keyframes, every interval events and at clears, state and settings changes
+pub fn keyframes(file_path: &str, interval: usize) -> Result<Vec<Keyframe>, io::Error> {
+    let persistence = match EventLogPersistenceJson::open(file_path) {
+        Ok(persistence) => persistence,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let interval = interval.max(1) as u64;
+    let mut keyframes = Vec::new();
+    let mut latest = None;
+
+    for (index, line) in persistence.stream_lines::<CanvasEvents>().enumerate() {
+        let seq = index as u64 + 1;
+        let Ok(event) = line? else {
+            continue;
+        };
+
+        let kind = match event {
+            CanvasEvents::CanvasCleared { .. } => Some(KeyframeKind::CanvasCleared),
+            CanvasEvents::CanvasStateChanged { .. } => Some(KeyframeKind::CanvasStateChanged),
+            CanvasEvents::CanvasSettingsChanged { .. } => Some(KeyframeKind::CanvasSettingsChanged),
+            _ if seq.is_multiple_of(interval) => Some(KeyframeKind::Interval),
+            _ => None,
+        };
+
+        let keyframe = Keyframe {
+            seq,
+            timestamp: event.timestamp(),
+            kind: kind.unwrap_or(KeyframeKind::Latest),
+        };
+        match kind {
+            Some(_) => {
+                keyframes.push(keyframe);
+                latest = None;
+            }
+            None => latest = Some(keyframe),
+        }
+    }
+
+    keyframes.extend(latest);
+    Ok(keyframes)
+}
+
+/// Cached replays of a canvas, most recently used first
+#[derive(Default)]
+struct CanvasReplays {
+    /// largest size the eventlog was seen with, a smaller log was rewritten since
+    log_bytes: u64,
+    replays: VecDeque<(ReplayCutoff, Arc<CanvasShapeState>)>,
+}
+
+/// Remembers the most recent replays of every canvas
+/// Scrubbing through a canvas produces bursts of requests for the same or adjacent cutoffs
+/// Only settled replays are cached, they only change if the eventlog is rewritten or removed
+/// Deleting and purging a canvas evict its entries, a log that shrank was compacted and evicts them as well
+pub struct ReplayCache {
+    entries_per_canvas: usize,
+    entries: Mutex<HashMap<CanvasId, CanvasReplays>>,
+}
+
+impl ReplayCache {
+    pub fn new(entries_per_canvas: usize) -> Self {
+        Self {
+            entries_per_canvas,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached state and marks it as most recently used
+    pub fn get(&self, canvas_id: &str, cutoff: ReplayCutoff) -> Option<Arc<CanvasShapeState>> {
+        let mut entries = self.entries.lock().unwrap();
+        let canvas_entries = &mut entries.get_mut(canvas_id)?.replays;
+        let index = canvas_entries
+            .iter()
+            .position(|(cached_cutoff, _)| *cached_cutoff == cutoff)?;
+        let entry = canvas_entries.remove(index)?;
+        let state = entry.1.clone();
+        canvas_entries.push_front(entry);
+        Some(state)
+    }
+
+    pub fn insert(&self, canvas_id: &str, cutoff: ReplayCutoff, state: Arc<CanvasShapeState>) {
+        self.insert_at(canvas_id, cutoff, state, 0);
+    }
+
+    fn insert_at(
+        &self,
+        canvas_id: &str,
+        cutoff: ReplayCutoff,
+        state: Arc<CanvasShapeState>,
+        log_bytes: u64,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let canvas_entries =
+            entries
+                .entry(canvas_id.to_string())
+                .or_insert_with(|| CanvasReplays {
+                    log_bytes,
+                    replays: VecDeque::new(),
+                });
+        canvas_entries
+            .replays
+            .retain(|(cached_cutoff, _)| *cached_cutoff != cutoff);
+        canvas_entries.replays.push_front((cutoff, state));
+        canvas_entries.replays.truncate(self.entries_per_canvas);
+    }
+
+    /// Drops the replays of the canvas, its eventlog was rewritten or removed
+    pub fn evict(&self, canvas_id: &str) {
+        self.entries.lock().unwrap().remove(canvas_id);
+    }
+
+    /// Replays the eventlog at file_path, answers from the cache if possible
+    pub fn replay(
+        &self,
+        canvas_id: &str,
+        file_path: &str,
+        cutoff: Option<ReplayCutoff>,
+    ) -> Result<Arc<CanvasShapeState>, io::Error> {
+        // a missing log counts as empty, unknown canvases are refused by the handlers before
+        let log_bytes = std::fs::metadata(file_path).map_or(0, |metadata| metadata.len());
+        {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get_mut(canvas_id) {
+                Some(canvas_entries) if canvas_entries.log_bytes > log_bytes => {
+                    entries.remove(canvas_id);
+                }
+                Some(canvas_entries) => canvas_entries.log_bytes = log_bytes,
+                None => (),
+            }
+        }
+
+        if let Some(state) = cutoff.and_then(|cutoff| self.get(canvas_id, cutoff)) {
+            return Ok(state);
+        }
+
+        let replay = replay_log(file_path, cutoff)?;
+        let state = Arc::new(replay.state);
+        if let (Some(cutoff), true) = (cutoff, replay.settled) {
+            self.insert_at(canvas_id, cutoff, state.clone(), log_bytes);
+        }
+        Ok(state)
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(REPLAY_CACHE_ENTRIES_PER_CANVAS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(content: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("{}-replay.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    const LOG: &str = r##"{"type":"ShapeAdded","origin":"s1","timestamp":10,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}
+{"type":"ShapeAdded","origin":"s1","timestamp":20,"shape":{"type":"Line","id":"l2","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}
+{"type":"ShapeUpdated","origin":"s1","timestamp":30,"shape":{"id":"l1","borderColor":"#fff"}}
+{"type":"ShapeZChanged","origin":"s1","timestamp":40,"shapeId":"l1","z":{"isInfinity":true,"value":1}}
+{"type":"ShapeRemoved","origin":"s1","timestamp":50,"shapeId":"l2"}
+{"type":"CanvasCleared","origin":"s1","timestamp":60}
+{"type":"ShapeAdded","origin":"s1","timestamp":70,"shape":{"type":"Line","id":"l3","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}
+"##;
+
+    fn shape_ids(state: &CanvasShapeState) -> Vec<&str> {
+        state
+            .shapes
+            .iter()
+            .map(|shape| shape["id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_around_update_and_removal() {
+        let path = write_log(LOG);
+
+        let before_update = replay_log(&path, Some(ReplayCutoff::Timestamp(29))).unwrap();
+        assert!(before_update.settled);
+        assert_eq!(shape_ids(&before_update.state), vec!["l1", "l2"]);
+        assert_eq!(before_update.state.shapes[0]["borderColor"], "#000");
+
+        let after_update = replay_log(&path, Some(ReplayCutoff::Sequence(3))).unwrap();
+        assert_eq!(after_update.state.shapes[0]["borderColor"], "#fff");
+        assert_eq!(after_update.state.shapes[0]["type"], "Line");
+        assert_eq!(after_update.state.shapes[0][CREATED_BY_KEY], "alice");
+        // logs written before creators were tracked have none
+        assert!(after_update.state.shapes[1].get(CREATED_BY_KEY).is_none());
+
+        // l1 was sent to the front, l2 is still there
+        let before_removal = replay_log(&path, Some(ReplayCutoff::Timestamp(49))).unwrap();
+        assert_eq!(shape_ids(&before_removal.state), vec!["l2", "l1"]);
+
+        let after_removal = replay_log(&path, Some(ReplayCutoff::Timestamp(50))).unwrap();
+        assert_eq!(shape_ids(&after_removal.state), vec!["l1"]);
+        assert_eq!(after_removal.state.seq, 5);
+    }
+
+    #[test]
+    fn test_timestamp_cutoff_skips_events_past_it() {
+        // l2 was stamped by its client in milliseconds, before the server stamped shape events
+        let path =
+            write_log(&LOG.replacen(r#""timestamp":20,"#, r#""timestamp":1700000000000,"#, 1));
+
+        let replay = replay_log(&path, Some(ReplayCutoff::Timestamp(40))).unwrap();
+        assert_eq!(shape_ids(&replay.state), vec!["l1"]);
+        assert_eq!(replay.state.seq, 4);
+        assert!(replay.settled);
+
+        // only the last event tells whether later events can still be due
+        let replay = replay_log(&path, Some(ReplayCutoff::Timestamp(70))).unwrap();
+        assert_eq!(shape_ids(&replay.state), vec!["l3"]);
+        assert!(!replay.settled);
+    }
+
+    #[test]
+    fn test_replay_cutoff_on_clear() {
+        let path = write_log(LOG);
+
+        let on_clear = replay_log(&path, Some(ReplayCutoff::Sequence(6))).unwrap();
+        assert!(on_clear.state.shapes.is_empty());
+        assert_eq!(on_clear.state.timestamp, Some(60));
+
+        let full = replay_log(&path, None).unwrap();
+        assert!(!full.settled);
+        assert_eq!(shape_ids(&full.state), vec!["l3"]);
+
+        let keyframes = keyframes(&path, 3).unwrap();
+        assert_eq!(
+            keyframes
+                .iter()
+                .map(|keyframe| (keyframe.seq, keyframe.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (3, KeyframeKind::Interval),
+                (6, KeyframeKind::CanvasCleared),
+                (7, KeyframeKind::Latest)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_keeps_first_of_colliding_shapes() {
+        let path = write_log(
+            r##"{"type":"ShapeAdded","origin":"s1","timestamp":10,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}
+{"type":"ShapeAdded","origin":"s2","timestamp":20,"shape":{"type":"Circle","id":"l1","temporary":false,"borderColor":"#f00","fillColor":"#f00","center":{"x":3,"y":7},"radius":4.5},"userId":"bob"}
+{"type":"ShapeRemoved","origin":"s1","timestamp":30,"shapeId":"l1"}
+{"type":"ShapeAdded","origin":"s2","timestamp":40,"shape":{"type":"Circle","id":"l1","temporary":false,"borderColor":"#f00","fillColor":"#f00","center":{"x":3,"y":7},"radius":4.5},"userId":"bob"}
+"##,
+        );
+
+        let collided = replay_log(&path, Some(ReplayCutoff::Sequence(2))).unwrap();
+        assert_eq!(shape_ids(&collided.state), vec!["l1"]);
+        assert_eq!(collided.state.shapes[0]["type"], "Line");
+        assert_eq!(collided.state.shapes[0][CREATED_BY_KEY], "alice");
+        assert_eq!(
+            collided.state.issues,
+            vec![ReplayIssue {
+                skipped: true,
+                ..ReplayIssue::event(1, ReplayIssueKind::Duplicate, "Shape l1 added again")
+            }]
+        );
+
+        // once removed the id is free again
+        let full = replay_log(&path, None).unwrap();
+        assert_eq!(full.state.shapes[0]["type"], "Circle");
+        assert_eq!(full.state.shapes[0][CREATED_BY_KEY], "bob");
+        assert_eq!(full.state.issues.len(), 1);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_replay_reconstructs_provenance() {
+        let path = write_log(
+            r##"{"type":"ShapeAdded","origin":"s1","timestamp":1000,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}
+{"type":"ShapeAdded","origin":"s1","timestamp":2000,"shape":{"type":"Line","id":"l2","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"alice"}
+{"type":"ShapeUpdated","origin":"s2","timestamp":3000,"shape":{"id":"l1","borderColor":"#fff"},"userId":"bob"}
+{"type":"ShapeZChanged","origin":"s1","timestamp":4000,"shapeId":"l1","z":{"isInfinity":true,"value":1},"userId":"alice"}
+{"type":"ShapeUpdated","origin":"s3","timestamp":5000,"shape":{"id":"l2","borderColor":"#fff"}}
+{"type":"ShapeUpdated","origin":"s2","timestamp":6000,"shape":{"id":"gone","borderColor":"#fff"},"userId":"bob"}
+{"type":"ShapeUpdated","origin":"s2","timestamp":7000,"shape":{"id":"l1","borderColor":"#f00"},"userId":"bob"}
+"##,
+        );
+
+        let full = replay_log(&path, None).unwrap().state;
+        let l1 = full.provenance.get("l1").unwrap();
+        assert_eq!(l1.last_modified_by.as_deref(), Some("bob"));
+        assert_eq!((l1.last_modified_at, l1.modifications), (7000, 3));
+        // written before editors were stamped, the change counts but the editor is unknown
+        let l2 = full.provenance.get("l2").unwrap();
+        assert_eq!(l2.last_modified_by, None);
+        assert_eq!((l2.last_modified_at, l2.modifications), (5000, 1));
+        assert!(full.provenance.get("gone").is_none());
+        // unrecorded editors are named like unrecorded creators
+        assert_eq!(
+            full.unrecorded_creators(),
+            BTreeSet::from(["alice".to_string(), "bob".to_string()])
+        );
+
+        let at_z_change = replay_log(&path, Some(ReplayCutoff::Sequence(4)))
+            .unwrap()
+            .state;
+        let l1 = at_z_change.provenance.get("l1").unwrap();
+        assert_eq!(l1.last_modified_by.as_deref(), Some("alice"));
+        assert_eq!((l1.last_modified_at, l1.modifications), (4000, 2));
+
+        // the provenance is only sent on request
+        let json = serde_json::to_value(&full).unwrap();
+        assert!(json.get("provenance").is_none());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_replay_cache() {
+        let path = write_log(LOG);
+        let cache = ReplayCache::new(2);
+
+        let cached = cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(2)))
+            .unwrap();
+        // cutoff past the end of the log is not settled and must not be cached
+        cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(100)))
+            .unwrap();
+        assert!(cache.get("c1", ReplayCutoff::Sequence(100)).is_none());
+
+        // a hit does not read the log again
+        std::fs::write(&path, "x".repeat(LOG.len())).unwrap();
+        let hit = cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(2)))
+            .unwrap();
+        assert!(Arc::ptr_eq(&cached, &hit));
+        assert!(cache.get("c2", ReplayCutoff::Sequence(2)).is_none());
+
+        // least recently used entries are evicted
+        let empty = Arc::new(CanvasShapeState::default());
+        cache.insert("c1", ReplayCutoff::Timestamp(1), empty.clone());
+        cache.insert("c1", ReplayCutoff::Timestamp(2), empty);
+        assert!(cache.get("c1", ReplayCutoff::Sequence(2)).is_none());
+        assert!(cache.get("c1", ReplayCutoff::Timestamp(1)).is_some());
+
+        cache.evict("c1");
+        assert!(cache.get("c1", ReplayCutoff::Timestamp(1)).is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_replay_cache_drops_history_of_compacted_logs() {
+        let path = write_log(LOG);
+        let cache = ReplayCache::default();
+        let before = cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(2)))
+            .unwrap();
+        assert_eq!(shape_ids(&before), vec!["l1", "l2"]);
+
+        // compaction dropped l2 together with its removal
+        let compacted: Vec<&str> = LOG
+            .lines()
+            .enumerate()
+            .filter(|(line, _)| ![1, 4].contains(line))
+            .map(|(_, event)| event)
+            .collect();
+        std::fs::write(&path, compacted.join("\n") + "\n").unwrap();
+        let after = cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(2)))
+            .unwrap();
+        assert_eq!(shape_ids(&after), vec!["l1"]);
+
+        // appended events leave the settled replays alone
+        let mut appended = std::fs::read_to_string(&path).unwrap();
+        appended.push_str(LOG.lines().next().unwrap());
+        appended.push('\n');
+        std::fs::write(&path, appended).unwrap();
+        let cached = cache
+            .replay("c1", &path, Some(ReplayCutoff::Sequence(2)))
+            .unwrap();
+        assert!(Arc::ptr_eq(&after, &cached));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use utoipa::ToSchema;
+
+use super::events::CanvasEvents;
+
+// Retention of persisted events that do not affect the drawing, applied when a canvas eventlog is compacted
+// Every event belongs to a category, only the history and the read receipts can be configured
+// Events the shape and comment folds depend on are always kept, the policy has no way to name them
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Group of events sharing one retention, see category
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// shape events and clears, folded by the compaction but never subject to retention
+    Drawing,
+    /// comments and their status, folded by the compaction but never subject to retention
+    Comments,
+    /// log header and contributors, the log and the attribution of shapes depend on them
+    Binding,
+    /// selections, joins and leaves, dangling state is closed before compacting
+    Presence,
+    /// access level, state and settings changes of the canvas
+    History,
+    /// latest UserCaughtUp of every user
+    ReadReceipts,
+    /// server feedback, never persisted
+    Ephemeral,
+}
+
+/// Category of an event, exhaustive so new events have to pick one
+pub fn category(event: &CanvasEvents) -> EventCategory {
+    match event {
+        CanvasEvents::ShapeAdded { .. }
+        | CanvasEvents::ShapeRemoved { .. }
+        | CanvasEvents::ShapeZChanged { .. }
+        | CanvasEvents::ShapeUpdated { .. }
+        | CanvasEvents::CanvasCleared { .. } => EventCategory::Drawing,
+        CanvasEvents::CommentAdded { .. }
+        | CanvasEvents::CommentResolved { .. }
+        | CanvasEvents::CommentReopened { .. }
+        | CanvasEvents::CommentDeleted { .. } => EventCategory::Comments,
+        CanvasEvents::CanvasLogHeader { .. } | CanvasEvents::ContributorSeen { .. } => {
+            EventCategory::Binding
+        }
+        CanvasEvents::ShapeSelected { .. }
+        | CanvasEvents::ShapeDeselected { .. }
+        | CanvasEvents::UserJoined { .. }
+        | CanvasEvents::UserLeft { .. } => EventCategory::Presence,
+        CanvasEvents::UserAccessLevelChanged { .. }
+        | CanvasEvents::CanvasStateChanged { .. }
+        | CanvasEvents::CanvasSettingsChanged { .. }
+        | CanvasEvents::PaletteChanged { .. } => EventCategory::History,
+        CanvasEvents::UserCaughtUp { .. } => EventCategory::ReadReceipts,
+        CanvasEvents::ServerNotice { .. }
+        | CanvasEvents::Ack { .. }
+        | CanvasEvents::Nack { .. }
+        | CanvasEvents::ServerHello { .. }
+        | CanvasEvents::InitialStateChunk { .. }
+        | CanvasEvents::TimeSyncRequest { .. }
+        | CanvasEvents::TimeSyncResponse { .. }
+        | CanvasEvents::FlushRequest { .. }
+        | CanvasEvents::SaveStateChanged { .. }
+        | CanvasEvents::ViewportChanged { .. }
+        | CanvasEvents::ResolvedCommentsRequest { .. } => EventCategory::Ephemeral,
+    }
+}
+
+/// Which events of a category survive a compaction
+/// Written as all, last:<count>, days:<days> or none
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Retention {
+    KeepAll,
+    KeepLast(usize),
+    /// events younger than the number of days
+    KeepFor(u64),
+    DropOnCompact,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Invalid retention {value}, expected all, last:<count>, days:<days> or none")
+        };
+        match value.split_once(':') {
+            None if value == "all" => Ok(Retention::KeepAll),
+            None if value == "none" => Ok(Retention::DropOnCompact),
+            Some(("last", count)) => count
+                .parse()
+                .map(Retention::KeepLast)
+                .map_err(|_| invalid()),
+            Some(("days", days)) => days.parse().map(Retention::KeepFor).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Retention {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::KeepAll => write!(f, "all"),
+            Retention::KeepLast(count) => write!(f, "last:{count}"),
+            Retention::KeepFor(days) => write!(f, "days:{days}"),
+            Retention::DropOnCompact => write!(f, "none"),
+        }
+    }
+}
+
+impl From<Retention> for String {
+    fn from(retention: Retention) -> Self {
+        retention.to_string()
+    }
+}
+
+/// Retention of the configurable categories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub history: Retention,
+    pub read_receipts: Retention,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            history: Retention::KeepLast(500),
+            read_receipts: Retention::KeepFor(30),
+        }
+    }
+}
+
+/// Per canvas deviations from the configured policy, part of the canvas settings
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<Retention>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_receipts: Option<Retention>,
+}
+
+impl RetentionOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl RetentionPolicy {
+    /// Policy of a canvas, its overrides replace the configured retention of their category
+    pub fn with_overrides(&self, overrides: &RetentionOverrides) -> Self {
+        Self {
+            history: overrides.history.unwrap_or(self.history),
+            read_receipts: overrides.read_receipts.unwrap_or(self.read_receipts),
+        }
+    }
+
+    pub fn retention(&self, category: EventCategory) -> Retention {
+        match category {
+            EventCategory::Drawing | EventCategory::Comments | EventCategory::Binding => {
+                Retention::KeepAll
+            }
+            EventCategory::Presence | EventCategory::Ephemeral => Retention::DropOnCompact,
+            EventCategory::History => self.history,
+            EventCategory::ReadReceipts => self.read_receipts,
+        }
+    }
+}
+
+///
+/// Marks the events the policy does not retain as dropped, events already dropped by the fold are not counted
+/// Drawing, comment and binding events are left to the fold
+/// now is the current time in seconds, like the timestamps of the events
+///
+pub fn apply(event_log: &[CanvasEvents], dropped: &mut [bool], policy: &RetentionPolicy, now: u64) {
+    // kept events of every category, newest first, to count keep-last from the end
+    let mut kept: BTreeMap<EventCategory, usize> = BTreeMap::new();
+
+    for (index, event) in event_log.iter().enumerate().rev() {
+        if dropped[index] {
+            continue;
+        }
+
+        let category = category(event);
+        let keep = match policy.retention(category) {
+            Retention::KeepAll => true,
+            Retention::KeepLast(count) => kept.get(&category).copied().unwrap_or(0) < count,
+            Retention::KeepFor(days) => {
+                event.timestamp().saturating_add(days * SECONDS_PER_DAY) >= now
+            }
+            Retention::DropOnCompact => false,
+        };
+
+        if keep {
+            *kept.entry(category).or_default() += 1;
+        } else {
+            dropped[index] = true;
+        }
+    }
+}
+
+/// Events of a category in the eventlog and how many of them the next compaction would drop
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct CategoryUsage {
+    pub events: u64,
+    pub reclaimable_events: u64,
+    /// serialized size of the reclaimable events, including their line breaks
+    pub reclaimable_bytes: u64,
+}
+
+/// Usage per category, ordered by category
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct RetentionReport {
+    pub categories: BTreeMap<EventCategory, CategoryUsage>,
+}
+
+impl RetentionReport {
+    /// Counts the events of the log and the ones marked as dropped by the compaction
+    pub fn new(event_log: &[CanvasEvents], dropped: &[bool]) -> Self {
+        let mut categories: BTreeMap<EventCategory, CategoryUsage> = BTreeMap::new();
+        for (event, dropped) in event_log.iter().zip(dropped) {
+            let usage = categories.entry(category(event)).or_default();
+            usage.events += 1;
+            if *dropped {
+                usage.reclaimable_events += 1;
+                usage.reclaimable_bytes += serde_json::to_vec(event)
+                    .map(|line| line.len() as u64 + 1)
+                    .unwrap_or(0);
+            }
+        }
+        Self { categories }
+    }
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (category, usage) in &self.categories {
+            writeln!(
+                f,
+                "  {category:?}: {} events, {} dropped ({} bytes)",
+                usage.events, usage.reclaimable_events, usage.reclaimable_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caught_up(user_id: &str, timestamp: u64) -> CanvasEvents {
+        CanvasEvents::UserCaughtUp {
+            timestamp,
+            userId: user_id.to_string(),
+            seq: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_retention() {
+        assert_eq!("all".parse(), Ok(Retention::KeepAll));
+        assert_eq!("last:500".parse(), Ok(Retention::KeepLast(500)));
+        assert_eq!("days:30".parse(), Ok(Retention::KeepFor(30)));
+        assert_eq!("none".parse(), Ok(Retention::DropOnCompact));
+        assert!("last:".parse::<Retention>().is_err());
+        assert!("forever".parse::<Retention>().is_err());
+        for retention in [
+            Retention::KeepAll,
+            Retention::KeepLast(3),
+            Retention::KeepFor(7),
+            Retention::DropOnCompact,
+        ] {
+            assert_eq!(retention.to_string().parse(), Ok(retention));
+        }
+    }
+
+    #[test]
+    fn test_overrides_replace_their_category_only() {
+        let policy = RetentionPolicy::default().with_overrides(&RetentionOverrides {
+            history: Some(Retention::DropOnCompact),
+            read_receipts: None,
+        });
+        assert_eq!(
+            policy.retention(EventCategory::History),
+            Retention::DropOnCompact
+        );
+        assert_eq!(
+            policy.retention(EventCategory::ReadReceipts),
+            RetentionPolicy::default().read_receipts
+        );
+        assert_eq!(policy.retention(EventCategory::Drawing), Retention::KeepAll);
+    }
+
+    #[test]
+    fn test_keep_for_drops_older_events() {
+        let now = 100 * SECONDS_PER_DAY;
+        let events = vec![
+            caught_up("a", now - 31 * SECONDS_PER_DAY),
+            caught_up("b", now - 30 * SECONDS_PER_DAY),
+            caught_up("c", now),
+        ];
+        let mut dropped = vec![false; events.len()];
+        apply(&events, &mut dropped, &RetentionPolicy::default(), now);
+        assert_eq!(dropped, vec![true, false, false]);
+
+        let report = RetentionReport::new(&events, &dropped);
+        let usage = &report.categories[&EventCategory::ReadReceipts];
+        assert_eq!((usage.events, usage.reclaimable_events), (3, 1));
+        assert_eq!(
+            usage.reclaimable_bytes,
+            serde_json::to_vec(&events[0]).unwrap().len() as u64 + 1
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::io;
+use utoipa::ToSchema;
+
+use super::{
+    events::{CanvasEvents, Point2D, Shape, ShapeType},
+    replay::{self, CanvasShapeState},
+};
+
+// Shape search within a canvas, e.g. all red rectangles of a large board
+// Loaded canvases are searched through CanvasQuery::FindShapes, others by folding their eventlog
+// The fold streams the eventlog, only the live shapes are kept in memory and only the matches are returned
+// Matches are in z-order, back to front, pages continue after the shape named by the cursor
+// New shapes are added in front, so pages already read stay the same while the canvas is edited
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 100;
+pub const MAX_SEARCH_LIMIT: usize = 500;
+
+/// Filters of a search, a shape has to match all of them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShapeFilter {
+    pub shape_type: Option<ShapeType>,
+    /// exact match
+    pub border_color: Option<String>,
+    /// exact match
+    pub fill_color: Option<String>,
+    pub id_prefix: Option<String>,
+    /// area the bounds of the shape intersect as (min, max), both corners inclusive
+    pub area: Option<(Point2D, Point2D)>,
+}
+
+impl ShapeFilter {
+    pub fn matches(&self, shape: &Shape) -> bool {
+        self.shape_type
+            .is_none_or(|shape_type| shape.shape_type() == shape_type)
+            && self
+                .border_color
+                .as_ref()
+                .is_none_or(|color| shape.border_color() == color)
+            && self
+                .fill_color
+                .as_ref()
+                .is_none_or(|color| shape.fill_color() == color)
+            && self
+                .id_prefix
+                .as_ref()
+                .is_none_or(|prefix| shape.get_id().starts_with(prefix.as_str()))
+            && self.area.is_none_or(|(min, max)| {
+                let (shape_min, shape_max) = shape.bounds();
+                shape_min.x <= max.x
+                    && shape_max.x >= min.x
+                    && shape_min.y <= max.y
+                    && shape_max.y >= min.y
+            })
+    }
+
+    /// Folded shapes that match, temporary shapes and shapes no longer valid after a partial update never do
+    pub fn matching(&self, shapes: Vec<Value>) -> Vec<Value> {
+        shapes
+            .into_iter()
+            .filter(|value| {
+                serde_json::from_value::<Shape>(value.clone())
+                    .is_ok_and(|shape| !shape.is_temporary() && self.matches(&shape))
+            })
+            .collect()
+    }
+
+    /// Matches among the live events of a loaded canvas
+    pub fn find_live(&self, event_log: &[CanvasEvents]) -> Vec<Value> {
+        let mut state = CanvasShapeState::default();
+        for (index, event) in event_log.iter().enumerate() {
+            state.apply(index as u64 + 1, event);
+        }
+        self.matching(state.shapes)
+    }
+
+    /// Matches in the eventlog of a canvas that is not loaded, blocks while reading it
+    pub fn find_in_log(&self, file_path: &str) -> Result<Vec<Value>, io::Error> {
+        replay::replay_log(file_path, None).map(|replay| self.matching(replay.state.shapes))
+    }
+}
+
+/// A page of matches
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ShapePage {
+    /// shapes ordered from back to front
+    pub shapes: Vec<Value>,
+    /// matches of the whole canvas
+    pub total: usize,
+    /// id of the last shape of the page, None on the last page
+    pub next_cursor: Option<String>,
+}
+
+///
+/// Page of the matches after the shape named by the cursor, from the start without cursor
+/// None if the cursor names no match, e.g. because the shape was removed or changed in between
+///
+pub fn paginate(matches: Vec<Value>, cursor: Option<&str>, limit: usize) -> Option<ShapePage> {
+    let total = matches.len();
+    let start = match cursor {
+        Some(cursor) => {
+            matches
+                .iter()
+                .position(|shape| shape.get("id").and_then(Value::as_str) == Some(cursor))?
+                + 1
+        }
+        None => 0,
+    };
+
+    let shapes: Vec<Value> = matches.into_iter().skip(start).take(limit).collect();
+    let next_cursor = if start + shapes.len() < total {
+        shapes
+            .last()
+            .and_then(|shape| shape.get("id"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    } else {
+        None
+    };
+    Some(ShapePage {
+        shapes,
+        total,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rectangle(id: &str, fill: &str, x: i32) -> Value {
+        json!({
+            "type": "Rectangle", "id": id, "temporary": false, "borderColor": "#000", "fillColor": fill,
+            "from": { "x": x, "y": 0 }, "to": { "x": x + 10, "y": 10 },
+        })
+    }
+
+    #[test]
+    fn test_filters_and_pages() {
+        let shapes = vec![
+            rectangle("r1", "#ff0000", 0),
+            json!({
+                "type": "Circle", "id": "c1", "temporary": false, "borderColor": "#000", "fillColor": "#ff0000",
+                "center": { "x": 100, "y": 100 }, "radius": 5.0,
+            }),
+            rectangle("r2", "#00ff00", 50),
+            json!({ "type": "Rectangle", "id": "r3", "temporary": true, "borderColor": "#000", "fillColor": "#ff0000",
+                "from": { "x": 0, "y": 0 }, "to": { "x": 1, "y": 1 } }),
+            rectangle("r4", "#ff0000", 200),
+        ];
+        let ids = |matches: &[Value]| -> Vec<String> {
+            matches
+                .iter()
+                .map(|shape| shape["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let red_rectangles = ShapeFilter {
+            shape_type: Some(ShapeType::Rectangle),
+            fill_color: Some("#ff0000".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&red_rectangles.matching(shapes.clone())), ["r1", "r4"]);
+
+        let near_origin = ShapeFilter {
+            area: Some((Point2D { x: 5, y: 5 }, Point2D { x: 60, y: 20 })),
+            ..Default::default()
+        };
+        assert_eq!(ids(&near_origin.matching(shapes.clone())), ["r1", "r2"]);
+
+        let matches = ShapeFilter::default().matching(shapes);
+        assert_eq!(ids(&matches), ["r1", "c1", "r2", "r4"]);
+        let page = paginate(matches.clone(), None, 3).unwrap();
+        assert_eq!(ids(&page.shapes), ["r1", "c1", "r2"]);
+        assert_eq!((page.total, page.next_cursor.as_deref()), (4, Some("r2")));
+        let page = paginate(matches.clone(), Some("r2"), 3).unwrap();
+        assert_eq!(ids(&page.shapes), ["r4"]);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(paginate(matches, Some("r3"), 3), None);
+    }
+}
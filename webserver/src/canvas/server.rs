@@ -1,41 +1,371 @@
 //! A multi-room chat server.
 
 use actix::Recipient;
+use futures_util::future::{select, Either};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::pin::pin;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     io,
+    net::IpAddr,
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::{
-    mpsc::{self},
-    oneshot,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{self},
+        oneshot,
+    },
+    time::{interval, sleep},
 };
+use utoipa::ToSchema;
 
 use super::{
-    events::CanvasEvents,
-    store::{Canvas, CanvasId, CanvasState, GetCanvasMessage},
+    backpressure::{Backlog, BackpressureLimits},
+    binding::{self, BindingError, LogBinding},
+    bus::{CanvasEventNotification, EventBus},
+    coalesce::{self, PendingUpdate, UpdateBuffer},
+    comments::{self, CommentStatus, Comments},
+    contributors::Contributors,
+    diagnostics::{self, CanvasDiagnostics, DiagnosticsAlarm, DiagnosticsReport},
+    events::{self, Bounds, CanvasEvents, ClientEvent, NoticeLevel, Shape, ShapeType},
+    features::{self, FeatureFlags, ResolvedFlags},
+    geometry,
+    handoff::{self, Handoff, HandoffRejection, TempShape},
+    inbound,
+    palette::{self, PaletteColor},
+    path,
+    provenance::{self, Provenance, ShapeProvenance},
+    quota::{QuotaKind, QuotaLimits, QuotaUsage, QuotaWarnings},
+    receipts::{ReadReceipts, ReadState},
+    redaction::{self, EventPayloads},
+    replay,
+    retention::{self, RetentionPolicy},
+    search::ShapeFilter,
+    socket_handler::SocketClose,
+    store::{
+        Canvas, CanvasId, CanvasSettings, CanvasState, GetCanvasMessage, RecordQuotaWarningMessage,
+    },
+    tokens::{self, ApiToken, TokenId},
+    validation::{self, ShapeLimits},
+    viewport::{CatchUp, LiveShapes, SessionViewport},
 };
 use crate::{
     canvas::store::AccessLevel,
+    clock::{MonotonicStamps, SharedClock},
+    connection::ConnectionMeta,
+    maintenance_mode::MaintenanceState,
+    messages::{self, Message, MessageKey},
     persistence::{EventLogPersistenceJson, EventLogPersistenceStandaloneJson},
     userstore::UserId,
 };
 
-/// Main Server to handle Canvas Events
-/// Loads a canvas from the store and keeps track of all connected users
-/// Handles user permissions for Events
-/// Is abel to recover from a crash and fixes canvas state on load
+// Main Server to handle Canvas Events
+// Loads a canvas from the store and keeps track of all connected users
+// Handles user permissions for Events
+// Is abel to recover from a crash and fixes canvas state on load
 
 pub type Msg = String;
 
+/// Item of the channel of a session, dropping the sender closes the socket as SocketClose::ClosedByServer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionMessage {
+    Text(Msg),
+    /// the server ends the session, the socket closes with the reason
+    Close(SocketClose),
+}
+
+/// Path of the eventlog for a single canvas
+pub fn canvas_log_path(canvas_id: &str) -> String {
+    format!("./{}.jsonl", canvas_id)
+}
+
+/// Limits protecting the server from clients opening too many sessions
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// concurrent sessions of a single user in one canvas
+    pub max_sessions_per_user: usize,
+    /// concurrent sessions in one canvas
+    pub max_sessions_per_canvas: usize,
+    /// concurrent sessions from a single address in one canvas, counted across users
+    pub max_sessions_per_ip: usize,
+    /// instead of refusing a new session, close the oldest session of the user
+    pub evict_oldest_session: bool,
+    /// connect attempts of a user to a canvas allowed within CONNECT_ATTEMPT_WINDOW
+    pub max_connect_attempts: usize,
+    /// how long connects are refused once max_connect_attempts is exceeded
+    pub connect_cooldown: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions_per_user: 5,
+            max_sessions_per_canvas: 500,
+            max_sessions_per_ip: 50,
+            evict_oldest_session: false,
+            max_connect_attempts: 20,
+            connect_cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sliding window used to count connect attempts
+const CONNECT_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+/// When the eventlog of a canvas is synced to disk, whichever threshold is reached first
+/// Canvases are synced as well when they are unloaded and when the server stops
+#[derive(Debug, Clone)]
+pub struct FlushPolicy {
+    /// persisted events not yet synced
+    pub max_pending_events: u64,
+    /// time since the oldest persisted event not yet synced
+    pub max_pending_time: Duration,
+    /// time a session has to wait between two FlushRequests, earlier requests are dropped
+    pub flush_request_interval: Duration,
+    /// ShapeUpdated events of a session and shape within this time are persisted as one, zero persists every update
+    pub update_coalesce_window: Duration,
+    /// backlog of events not yet synced at which writers are refused, see backpressure.rs
+    pub backpressure: BackpressureLimits,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_pending_events: 100,
+            max_pending_time: Duration::from_secs(5),
+            flush_request_interval: Duration::from_secs(1),
+            update_coalesce_window: coalesce::DEFAULT_COALESCE_WINDOW,
+            backpressure: BackpressureLimits::default(),
+        }
+    }
+}
+
+/// How often the run loop checks max_pending_time of the loaded canvases
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time sync requests a session may send within TIME_SYNC_WINDOW_MS, further requests are dropped
+const MAX_TIME_SYNCS_PER_WINDOW: usize = 6;
+const TIME_SYNC_WINDOW_MS: u64 = 60_000;
+
+/// Applied operation ids remembered per canvas, retries of these are acknowledged but not applied again
+const RECENT_OP_IDS: usize = 2_048;
+
+/// Bounded set of recently applied operation ids, the oldest id is forgotten first
+#[derive(Debug, Default)]
+struct RecentOpIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentOpIds {
+    fn contains(&self, op_id: &str) -> bool {
+        self.ids.contains(op_id)
+    }
+
+    fn insert(&mut self, op_id: String) {
+        if !self.ids.insert(op_id.clone()) {
+            return;
+        }
+        self.order.push_back(op_id);
+        if self.order.len() > RECENT_OP_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forgets an operation that was lost after all, a retry is applied again
+    fn remove(&mut self, op_id: &str) {
+        if self.ids.remove(op_id) {
+            self.order.retain(|id| id != op_id);
+        }
+    }
+}
+
+/// Reason a session was refused or closed by the server
+/// Send to the client as ServerNotice before the socket is closed
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRejection {
+    UserSessionLimit,
+    CanvasSessionLimit,
+    IpSessionLimit,
+    CoolingDown,
+    Evicted,
+    CanvasUnavailable,
+    /// the eventlog belongs to another canvas
+    CanvasLogMismatch,
+}
+
+impl SessionRejection {
+    pub fn notice(self, timestamp: u64) -> CanvasEvents {
+        let (level, key) = match self {
+            SessionRejection::UserSessionLimit => {
+                (NoticeLevel::Error, MessageKey::SessionUserLimit)
+            }
+            SessionRejection::CanvasSessionLimit => {
+                (NoticeLevel::Error, MessageKey::SessionCanvasLimit)
+            }
+            SessionRejection::IpSessionLimit => (NoticeLevel::Error, MessageKey::SessionIpLimit),
+            SessionRejection::CoolingDown => (NoticeLevel::Warning, MessageKey::SessionCoolingDown),
+            SessionRejection::Evicted => (NoticeLevel::Warning, MessageKey::SessionEvicted),
+            SessionRejection::CanvasUnavailable => {
+                (NoticeLevel::Error, MessageKey::CanvasLoadFailed)
+            }
+            SessionRejection::CanvasLogMismatch => {
+                (NoticeLevel::Error, MessageKey::CanvasLogMismatch)
+            }
+        };
+        CanvasEvents::notice(timestamp, level, key)
+    }
+}
+
+/// The chat server stopped, commands are not delivered anymore
+#[derive(Debug)]
+pub struct ServerStopped;
+
+/// Answered with 503, handlers asking the chat server pass it on with ?
+impl From<ServerStopped> for actix_web::Error {
+    fn from(_: ServerStopped) -> Self {
+        messages::service_unavailable(MessageKey::CanvasServerStopped).into()
+    }
+}
+
+/// Live websocket session of a user
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct UserSession {
+    pub canvas_id: CanvasId,
+    pub canvas_name: String,
+    pub session_id: String,
+    /// serialized with the address masked
+    pub connection: ConnectionMeta,
+}
+
+/// Live websocket session on a canvas
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasSession {
+    pub user_id: UserId,
+    pub username: String,
+    pub session_id: String,
+    /// serialized with the address masked
+    pub connection: ConnectionMeta,
+}
+
+/// Aggregates over all loaded canvases
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub loaded_canvases: usize,
+    pub sessions: usize,
+}
+
+/// Read only question to the canvas server, see CanvasSocketServer::answer_query
+/// Queries about a canvas are asked with its id, the others without
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanvasQuery {
+    /// users with at least one live session on the canvas
+    Presence,
+    /// live sessions on the canvas, in the order they connected
+    Sessions,
+    /// live sessions of the user across all loaded canvases
+    UserSessions(UserId),
+    /// shape and eventlog usage, read from the eventlog if the canvas is not loaded
+    QuotaUsage,
+    /// who has seen the latest change, read from the eventlog if the canvas is not loaded
+    ReadState,
+    /// recorded names of the contributors
+    Contributors,
+    /// lines in the eventlog, exports stop there so lines written meanwhile are not torn
+    PersistedSeq,
+    /// concurrent edit counters of the last minute
+    Diagnostics,
+    /// live shapes matching the filter, in z-order, the caller folds the eventlog if the canvas is not loaded
+    FindShapes(ShapeFilter),
+    /// last change of a live shape, the caller folds the eventlog if the canvas is not loaded
+    ShapeProvenance {
+        shape_id: String,
+    },
+    /// comments of the shapes, the caller folds the eventlog if the canvas is not loaded
+    Comments,
+    ServerStats,
+    /// ids of the loaded canvases, their eventlogs are open
+    LoadedCanvases,
+}
+
+/// Answer to a CanvasQuery, one variant per query
+#[derive(Debug, Clone, PartialEq)]
+pub enum CanvasQueryResult {
+    Presence(HashSet<UserId>),
+    Sessions(Vec<CanvasSession>),
+    UserSessions(Vec<UserSession>),
+    QuotaUsage(Vec<QuotaUsage>),
+    ReadState(ReadState),
+    Contributors(Contributors),
+    PersistedSeq(u64),
+    Diagnostics(DiagnosticsReport),
+    Shapes(Vec<Value>),
+    /// None for shapes that are not alive
+    ShapeProvenance(Option<ShapeProvenance>),
+    Comments(Comments),
+    ServerStats(ServerStats),
+    LoadedCanvases(HashSet<CanvasId>),
+    /// the query needs a loaded canvas, or was asked without a canvas id
+    CanvasNotLoaded,
+}
+
+/// Connect attempts of a single user to a single canvas
+#[derive(Default)]
+struct ConnectAttempts {
+    attempts: VecDeque<Instant>,
+    cooldown_until: Option<Instant>,
+}
+
+impl ConnectAttempts {
+    /// Records an attempt, returns false if the user has to cool down
+    fn register_attempt(&mut self, now: Instant, limits: &ConnectionLimits) -> bool {
+        if self.cooldown_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        self.cooldown_until = None;
+
+        self.decay(now);
+        self.attempts.push_back(now);
+
+        if self.attempts.len() > limits.max_connect_attempts {
+            self.cooldown_until = Some(now + limits.connect_cooldown);
+            self.attempts.clear();
+            return false;
+        }
+        true
+    }
+
+    /// Drops attempts that left the sliding window
+    fn decay(&mut self, now: Instant) {
+        while self
+            .attempts
+            .front()
+            .is_some_and(|attempt| now.duration_since(*attempt) > CONNECT_ATTEMPT_WINDOW)
+        {
+            self.attempts.pop_front();
+        }
+    }
+
+    fn is_stale(&mut self, now: Instant) -> bool {
+        self.decay(now);
+        self.attempts.is_empty() && self.cooldown_until.is_none_or(|until| now >= until)
+    }
+}
+
 #[derive(Debug)]
-enum Command {
+pub(super) enum Command {
     Connect {
         user_id: UserId,
         username: String,
         canvas_id: CanvasId,
         session_id: WSSessionId,
-        conn_tx: mpsc::UnboundedSender<Msg>,
+        connection: ConnectionMeta,
+        conn_tx: mpsc::UnboundedSender<SessionMessage>,
     },
 
     Disconnect {
@@ -56,12 +386,79 @@ enum Command {
         user_id: UserId,
         canvas_id: CanvasId,
         access_level: AccessLevel,
+        expires_at: Option<u64>,
     },
 
     UpdateCanvasState {
         canvas_id: CanvasId,
         initiator_id: UserId,
         state: CanvasState,
+        version: u64,
+    },
+
+    UpdateCanvasSettings {
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        settings: CanvasSettings,
+        version: u64,
+    },
+
+    UpdateCanvasPalette {
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        palette: Vec<PaletteColor>,
+        enforce_palette: bool,
+        version: u64,
+    },
+
+    UpdateCanvasFeatureFlags {
+        canvas_id: CanvasId,
+        overrides: BTreeMap<String, bool>,
+        version: u64,
+    },
+
+    /// quota tracked by the CanvasStore crossed its warning threshold
+    QuotaWarning {
+        canvas_id: CanvasId,
+        usage: QuotaUsage,
+    },
+
+    /// read only questions, answered without changing the server
+    Query {
+        canvas_id: Option<CanvasId>,
+        query: CanvasQuery,
+        res_tx: oneshot::Sender<CanvasQueryResult>,
+    },
+
+    /// canvas was deleted, sessions are closed
+    CloseCanvas { canvas_id: CanvasId },
+
+    /// user logged out everywhere, every session is closed
+    CloseUserSessions { user_id: UserId },
+
+    /// notice for every session of every loaded canvas, e.g. about maintenance
+    NotifyAll {
+        level: NoticeLevel,
+        message: Message,
+    },
+
+    /// notice for every session of the users, on whatever canvas they are
+    NotifyUsers {
+        user_ids: Vec<UserId>,
+        level: NoticeLevel,
+        message: Message,
+    },
+
+    /// API token was created, a loaded canvas accepts it right away
+    AddApiToken {
+        canvas_id: CanvasId,
+        token: ApiToken,
+    },
+
+    /// API token was revoked, the sessions it authenticated are closed
+    RevokeApiToken {
+        canvas_id: CanvasId,
+        token_id: TokenId,
     },
 }
 
@@ -69,7 +466,7 @@ type WSSessionId = String;
 
 struct CanvasInstance {
     /// tracks connected users
-    users: HashMap<UserId, HashMap<WSSessionId, mpsc::UnboundedSender<Msg>>>,
+    users: HashMap<UserId, HashMap<WSSessionId, mpsc::UnboundedSender<SessionMessage>>>,
     /// tracks selected shapes for each user
     selected_shapes: HashMap<WSSessionId, HashSet<String>>,
 
@@ -81,8 +478,113 @@ struct CanvasInstance {
     /// inner canvas state
     inner: Canvas,
 
-    /// tracks temporary shapes that should not be persisted
-    temp_shapes: HashSet<String>,
+    /// tracks temporary shapes that should not be persisted, with the session drawing them
+    temp_shapes: HashMap<String, TempShape>,
+
+    /// sessions in the order they connected, used to find the oldest session of a user
+    session_order: Vec<WSSessionId>,
+
+    /// origin of every session, only handed out by the session queries, never broadcast
+    connections: HashMap<WSSessionId, ConnectionMeta>,
+
+    /// ids of the persisted shapes, counts towards the shape quota
+    shapes: HashSet<String>,
+
+    /// creator of every live shape, tracked even while ownership is not enforced
+    shape_creators: HashMap<String, UserId>,
+
+    /// last change of every live shape, see provenance.rs
+    provenance: Provenance,
+
+    /// comments of the shapes, their events are not part of event_log, see comments.rs
+    comments: Comments,
+
+    /// size of the eventlog in bytes
+    log_bytes: u64,
+
+    /// lines in the eventlog, the sequence number of the last persisted event
+    persisted_events: u64,
+
+    /// line of the eventlog up to which it is synced to disk
+    flushed_seq: u64,
+
+    /// time of the oldest persisted event not yet synced, in milliseconds
+    pending_since: Option<u64>,
+
+    /// syncing the eventlog failed, owners and moderators were told, cleared by the next successful flush
+    degraded: bool,
+
+    /// size of the eventlog in bytes up to which it is synced to disk
+    flushed_bytes: u64,
+
+    /// the backlog reached the high-water mark and did not drain below the low-water mark yet, see backpressure.rs
+    throttled: bool,
+
+    /// time of the last FlushRequest of every session, in milliseconds
+    flush_requests: HashMap<WSSessionId, u64>,
+
+    /// canvas claimed by the header of the eventlog, checked before every write
+    log_canvas_id: CanvasId,
+
+    quota_warnings: QuotaWarnings,
+
+    /// which change of the canvas every user has seen, markers are not part of event_log
+    receipts: ReadReceipts,
+
+    /// recorded names of the contributors, records are not part of event_log
+    contributors: Contributors,
+
+    /// display name of every connected user, as announced on connect
+    usernames: HashMap<UserId, String>,
+
+    /// operation ids of applied client events, survives reconnects but not an unload of the canvas
+    applied_op_ids: RecentOpIds,
+
+    /// times of the recent time sync requests of every session, in milliseconds
+    time_syncs: HashMap<WSSessionId, VecDeque<u64>>,
+
+    /// feature flags of every session, resolved on connect and when the overrides change
+    session_flags: HashMap<WSSessionId, ResolvedFlags>,
+
+    /// declared viewports of the sessions, sessions without one receive every event, see viewport.rs
+    viewports: HashMap<WSSessionId, SessionViewport>,
+
+    /// shapes with their bounds, only folded while a session has a viewport
+    live_shapes: Option<LiveShapes>,
+
+    /// concurrent edit counters, start over whenever the canvas is loaded
+    diagnostics: CanvasDiagnostics,
+
+    /// updates already broadcast but not yet persisted, see coalesce.rs
+    pending_updates: UpdateBuffer,
+
+    /// timestamps of the events the server persists, increasing even if the clock steps back
+    stamps: MonotonicStamps,
+
+    /// every persisted event is announced on it, see bus.rs
+    event_bus: EventBus,
+
+    clock: SharedClock,
+}
+
+/// Why a canvas could not be loaded
+#[derive(Debug)]
+enum LoadError {
+    Unavailable(String),
+    Binding(BindingError),
+}
+
+impl LoadError {
+    fn unavailable(error: impl ToString) -> Self {
+        LoadError::Unavailable(error.to_string())
+    }
+
+    fn rejection(&self) -> SessionRejection {
+        match self {
+            LoadError::Unavailable(_) => SessionRejection::CanvasUnavailable,
+            LoadError::Binding(_) => SessionRejection::CanvasLogMismatch,
+        }
+    }
 }
 
 /// Canvas Server handles all canvas events for all canvases
@@ -91,6 +593,45 @@ pub struct CanvasSocketServer {
 
     get_canvas_recipient: Arc<Recipient<GetCanvasMessage>>,
 
+    limits: ConnectionLimits,
+
+    shape_limits: ShapeLimits,
+
+    quota_limits: QuotaLimits,
+
+    flush_policy: FlushPolicy,
+
+    feature_flags: FeatureFlags,
+
+    /// persists quota warnings, so owners can see them later
+    record_quota_warning_recipient: Recipient<RecordQuotaWarningMessage>,
+
+    /// connect attempts per canvas and user, kept independent of loaded canvases
+    /// so that a flapping client can't repeatedly load a cold canvas
+    connect_attempts: HashMap<(CanvasId, UserId), ConnectAttempts>,
+    /// when stale connect attempts are swept next, see sweep_connect_attempts
+    next_attempt_sweep: Instant,
+
+    /// age up to which the handoff of a previous process is restored, see handoff.rs
+    handoff_max_age: Duration,
+
+    /// thresholds of the diagnostics alarm sent to owners and moderators
+    diagnostics_alarm: DiagnosticsAlarm,
+
+    /// time within which updates of a shape by different users count as conflict, see provenance.rs
+    conflict_window: Duration,
+
+    /// persisted events of every canvas, subscribed through the handle
+    event_bus: EventBus,
+
+    /// drawing events are rejected while the service is in maintenance, see maintenance_mode.rs
+    maintenance: Arc<MaintenanceState>,
+
+    /// lowest access level that may comment on shapes, see comments.rs
+    comment_level: AccessLevel,
+
+    clock: SharedClock,
+
     /// Command receiver.
     cmd_rx: mpsc::UnboundedReceiver<Command>,
 }
@@ -98,577 +639,7336 @@ pub struct CanvasSocketServer {
 impl CanvasSocketServer {
     pub fn new(
         get_canvas_recipient: Arc<Recipient<GetCanvasMessage>>,
+        record_quota_warning_recipient: Recipient<RecordQuotaWarningMessage>,
+        limits: ConnectionLimits,
+        shape_limits: ShapeLimits,
+        quota_limits: QuotaLimits,
+        flush_policy: FlushPolicy,
+        clock: SharedClock,
     ) -> (Self, CanvasSocketServerHandle) {
         // channel to communicate with ServerHandle
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let event_bus = EventBus::default();
 
         (
             Self {
                 canvases: HashMap::new(),
                 get_canvas_recipient,
+                limits,
+                shape_limits,
+                quota_limits,
+                flush_policy,
+                feature_flags: FeatureFlags::default(),
+                record_quota_warning_recipient,
+                connect_attempts: HashMap::new(),
+                next_attempt_sweep: Instant::now() + CONNECT_ATTEMPT_WINDOW,
+                handoff_max_age: handoff::DEFAULT_HANDOFF_MAX_AGE,
+                diagnostics_alarm: DiagnosticsAlarm::default(),
+                conflict_window: provenance::DEFAULT_CONFLICT_WINDOW,
+                event_bus: event_bus.clone(),
+                maintenance: Arc::default(),
+                comment_level: comments::DEFAULT_COMMENT_LEVEL,
+                clock,
                 cmd_rx,
             },
-            CanvasSocketServerHandle { cmd_tx },
+            CanvasSocketServerHandle { cmd_tx, event_bus },
         )
     }
 
-    fn persist_event(canvas: &mut CanvasInstance, event: &CanvasEvents) {
-        // do not persist temporary shapes
+    /// Flags configured for the server, resolved for every session on connect
+    pub fn with_feature_flags(mut self, feature_flags: FeatureFlags) -> Self {
+        self.feature_flags = feature_flags;
+        self
+    }
+
+    /// Age up to which the handoff of a previous process is restored instead of folding the eventlog
+    pub fn with_handoff_max_age(mut self, handoff_max_age: Duration) -> Self {
+        self.handoff_max_age = handoff_max_age;
+        self
+    }
+
+    /// Thresholds of the diagnostics alarm, see diagnostics.rs
+    pub fn with_diagnostics_alarm(mut self, diagnostics_alarm: DiagnosticsAlarm) -> Self {
+        self.diagnostics_alarm = diagnostics_alarm;
+        self
+    }
+
+    /// Time within which updates of a shape by different users are counted as conflict in the diagnostics
+    pub fn with_conflict_window(mut self, conflict_window: Duration) -> Self {
+        self.conflict_window = conflict_window;
+        self
+    }
+
+    /// Maintenance shared with the handlers, toggled at runtime
+    pub fn with_maintenance(mut self, maintenance: Arc<MaintenanceState>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Lowest access level that may comment, commenting does not depend on the state of the canvas
+    pub fn with_comment_level(mut self, comment_level: AccessLevel) -> Self {
+        self.comment_level = comment_level;
+        self
+    }
+
+    /// Returns the line of the event in the eventlog, None if the event is not persisted
+    fn persist_event(
+        canvas: &mut CanvasInstance,
+        event: &CanvasEvents,
+    ) -> Result<Option<u64>, io::Error> {
+        // do not persist temporary shapes, handle_message claimed their id for the session
         let should_persist = match &event {
+            CanvasEvents::ShapeAdded { shape, .. } if shape.is_temporary() => false,
+
+            // stroke-commit pattern, a temporary shape is finalized by adding it again as non temporary
             CanvasEvents::ShapeAdded { shape, .. } => {
-                if shape.is_temporary() {
-                    canvas.temp_shapes.insert(shape.get_id().to_string());
-                    false
-                } else {
-                    true
-                }
+                canvas.temp_shapes.remove(shape.get_id());
+                true
             }
 
             CanvasEvents::ShapeRemoved { shapeId, .. } => {
-                !canvas.temp_shapes.remove(shapeId) // don't persist if shape was temporary
+                canvas.temp_shapes.remove(shapeId).is_none() // don't persist if shape was temporary
             }
 
             _ => true,
         };
 
-        if should_persist {
-            canvas.persistence.save_event(event).unwrap();
+        if !should_persist {
+            return Ok(None);
         }
-    }
-
-    fn broadcast_event(
-        canvas: &mut CanvasInstance,
-        skip_session: Option<WSSessionId>,
-        event: impl Into<CanvasEvents>,
-    ) {
-        let event = event.into();
 
-        let message: Result<Msg, serde_json::Error> = (&event).try_into();
-        match message {
-            Ok(message) => {
-                let skip_session_id = skip_session.unwrap_or_default(); // there will never be a user with empty id
-                canvas
-                    .users
-                    .iter()
-                    .flat_map(|(_, sockets)| sockets.iter())
-                    .for_each(move |(session_id, tx)| {
-                        if session_id == &skip_session_id {
-                            return;
-                        }
-                        // don't care if we can't send
-                        // heartbeat will disconnect user
-                        let _ = tx.send(message.clone());
-                    });
+        if canvas.log_canvas_id != canvas.inner.id {
+            return Err(BindingError::Mismatch {
+                expected: canvas.inner.id.clone(),
+                claimed: canvas.log_canvas_id.clone(),
             }
+            .into());
+        }
 
+        // a fresh eventlog starts with its header
+        if canvas.persisted_events == 0 {
+            let header = binding::header(&canvas.inner.id, canvas.stamps.stamp_secs());
+            canvas.log_bytes += canvas.persistence.save_event(&header)?;
+            canvas.persisted_events += 1;
+            canvas.pending_since.get_or_insert(canvas.clock.now_ms());
+        }
+
+        let started = Instant::now();
+        let saved = canvas.persistence.save_event(event);
+        canvas.diagnostics.record_persist(started.elapsed());
+        match saved {
+            Ok(bytes) => {
+                canvas.log_bytes += bytes;
+                canvas.persisted_events += 1;
+                canvas.pending_since.get_or_insert(canvas.clock.now_ms());
+                Self::track_shapes(&mut canvas.shapes, event);
+                canvas.receipts.apply(canvas.persisted_events, event);
+                canvas.event_bus.publish(CanvasEventNotification::new(
+                    &canvas.inner.id,
+                    canvas.persisted_events,
+                    event,
+                    canvas.clock.now_ms(),
+                ));
+                Ok(Some(canvas.persisted_events))
+            }
             Err(e) => {
-                println!("Failed to serialize event: {e}");
-                return;
+                println!("Failed to persist event in {}: {e}", canvas.inner.id);
+                Err(e)
             }
         }
+    }
 
-        canvas.event_log.push(event);
+    /// Whether enough events piled up or the oldest of them waited long enough
+    fn flush_due(canvas: &CanvasInstance, policy: &FlushPolicy) -> bool {
+        let Some(pending_since) = canvas.pending_since else {
+            return false;
+        };
+        canvas.persisted_events - canvas.flushed_seq >= policy.max_pending_events
+            || canvas.clock.now_ms().saturating_sub(pending_since)
+                >= policy.max_pending_time.as_millis() as u64
     }
 
-    fn send_initial_state(canvas: &CanvasInstance, user_id: UserId) {
-        if let Some(sockets) = canvas.users.get(&user_id) {
-            for event in &canvas.event_log {
-                let event: String = event.try_into().expect("Event can't be serialized"); // This is a application error, so we can panic
-                for (_, tx) in sockets.iter() {
-                    let _ = tx.send(event.clone());
-                }
-            }
+    /// Events and bytes persisted but not yet synced to disk
+    fn backlog(canvas: &CanvasInstance) -> Backlog {
+        Backlog {
+            events: canvas.persisted_events - canvas.flushed_seq,
+            bytes: canvas.log_bytes.saturating_sub(canvas.flushed_bytes),
         }
     }
 
-    async fn connect(
-        &mut self,
-        tx: mpsc::UnboundedSender<Msg>,
-        canvas_id: CanvasId,
-        user_id: UserId,
-        username: String,
-        session_id: WSSessionId,
-    ) {
-        println!("{username}({user_id}-{session_id}) joined canvas {canvas_id}");
-
-        if !self.canvases.contains_key(&canvas_id) {
-            if let Err(e) = self.load_canvas(&canvas_id).await {
-                println!("Failed to load events: {e}");
-                tx.send("Connection failed".to_string()).unwrap();
-                return;
-            }
+    /// Throttles the canvas at the high-water mark and ends it below the low-water mark, every session is told both
+    fn check_backpressure(canvas: &mut CanvasInstance, limits: &BackpressureLimits) {
+        let backlog = Self::backlog(canvas);
+        let throttled = limits.throttled(canvas.throttled, &backlog);
+        if throttled == canvas.throttled {
+            return;
         }
+        canvas.throttled = throttled;
+        let now = canvas.clock.now_secs();
+        let notice = match throttled {
+            true => {
+                println!(
+                    "WARNING: throttling {}, {} events and {} bytes not yet synced to disk",
+                    canvas.inner.id, backlog.events, backlog.bytes
+                );
+                canvas.diagnostics.record_throttled(canvas.clock.now_ms());
+                CanvasEvents::notice(now, NoticeLevel::Warning, MessageKey::CanvasThrottled)
+            }
+            false => {
+                println!("Throttling of {} ended", canvas.inner.id);
+                CanvasEvents::notice(now, NoticeLevel::Notice, MessageKey::CanvasThrottleEnded)
+            }
+        };
+        Self::notify_canvas(canvas, notice);
+    }
 
-        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
-            canvas
-                .users
-                .entry(user_id.clone())
-                .and_modify(|sessions| {
-                    sessions.insert(session_id.clone(), tx.clone());
-                })
-                .or_insert_with(|| {
-                    let mut user_sessions = HashMap::with_capacity(1);
-                    user_sessions.insert(session_id.clone(), tx.clone());
-                    user_sessions
-                });
+    /// Events that are never persisted, e.g. temporary shapes, they don't add to the backlog
+    fn is_ephemeral(canvas: &CanvasInstance, event: &CanvasEvents) -> bool {
+        match event {
+            CanvasEvents::ShapeAdded { shape, .. } => shape.is_temporary(),
+            CanvasEvents::ShapeRemoved { shapeId, .. } => canvas.temp_shapes.contains_key(shapeId),
+            _ => false,
+        }
+    }
 
-            let access_level = canvas
-                .inner
-                .users
-                .get(&user_id)
-                .cloned()
-                .unwrap_or(AccessLevel::Read);
-            let event = CanvasEvents::UserJoined {
-                userId: user_id.clone(),
-                username,
-                sessionId: session_id.clone(),
-                timestamp: chrono::Utc::now().timestamp() as u64,
-                accessLevel: access_level,
-            };
+    /// Flushes every loaded canvas that reached a threshold of the flush policy
+    /// The save lag of a canvas grows without events, its alarm is checked here as well
+    /// Selections of removed shapes are swept before, their deselections are flushed with the rest
+    fn flush_due_canvases(&mut self) {
+        let window = self.flush_policy.update_coalesce_window;
+        for canvas in self.canvases.values_mut() {
+            let due = canvas
+                .pending_updates
+                .take_due(canvas.clock.now_ms(), window);
+            Self::persist_updates(canvas, due);
+            Self::sweep_selections(canvas);
+            if Self::flush_due(canvas, &self.flush_policy) {
+                let _ = Self::flush_canvas(canvas);
+            }
+            Self::check_backpressure(canvas, &self.flush_policy.backpressure);
+            Self::check_diagnostics(canvas, &self.diagnostics_alarm);
+        }
+    }
 
-            Self::persist_event(canvas, &event);
-            Self::broadcast_event(canvas, Some(session_id), event); // does not contain own join
-            Self::send_initial_state(canvas, user_id); // does contain own join
+    fn save_state(canvas: &CanvasInstance) -> CanvasEvents {
+        CanvasEvents::SaveStateChanged {
+            timestamp: canvas.clock.now_secs(),
+            flushedSeq: canvas.flushed_seq,
         }
     }
 
     ///
-    /// Loads canvas from persistence and applies all events
-    /// Cleans up dangling state from previous sessions
+    /// Syncs the eventlog to disk, every session is told up to which line it is saved
+    /// A failure marks the canvas degraded and is retried by the next check, owners and moderators are told once
     ///
-    async fn load_canvas(&mut self, canvas_id: &str) -> Result<(), String> {
-        let persistence = EventLogPersistenceJson::new(format!("./{}.jsonl", canvas_id).as_str())
-            .map_err(|e| e.to_string())?;
-        let (mut event_log, persistence) = persistence
-            .into_standalone::<CanvasEvents>()
-            .map_err(|e| e.to_string())?;
+    fn flush_canvas(canvas: &mut CanvasInstance) -> io::Result<()> {
+        Self::persist_all_updates(canvas);
+        if canvas.pending_since.is_none() {
+            return Ok(());
+        }
 
-        let canvas = self
-            .get_canvas_recipient
-            .send(GetCanvasMessage {
-                canvas_id: canvas_id.to_string(),
-            })
-            .await
-            .map_err(|e| e.to_string())?
-            .map(Ok)
-            .unwrap_or(Err("Canvas not found".to_string()))?;
+        if let Err(e) = canvas.persistence.flush() {
+            println!("Failed to flush eventlog of {}: {e}", canvas.inner.id);
+            if !canvas.degraded {
+                canvas.degraded = true;
+                Self::notify_moderators(
+                    canvas,
+                    CanvasEvents::notice(
+                        canvas.clock.now_secs(),
+                        NoticeLevel::Error,
+                        MessageKey::CanvasFlushFailed,
+                    ),
+                );
+            }
+            return Err(e);
+        }
 
-        let cleanup_events = Self::extract_cleanup_events(&mut event_log);
+        canvas.flushed_seq = canvas.persisted_events;
+        canvas.flushed_bytes = canvas.log_bytes;
+        canvas.pending_since = None;
+        canvas.degraded = false;
+        Self::notify_canvas(canvas, Self::save_state(canvas));
+        Ok(())
+    }
 
-        let mut canvas = CanvasInstance {
-            selected_shapes: HashMap::new(),
-            temp_shapes: HashSet::new(),
-            inner: canvas,
-            users: HashMap::with_capacity(1),
-            event_log,
-            persistence,
-        };
-
-        cleanup_events.into_iter().for_each(|event| {
-            Self::persist_event(&mut canvas, &event);
-            canvas.event_log.push(event);
-        });
+    /// Flushes the canvas on request of a session, requests within flush_request_interval are dropped
+    /// Without pending events only the requesting session is told the save state
+    fn answer_flush_request(
+        canvas: &mut CanvasInstance,
+        policy: &FlushPolicy,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        op_id: Option<String>,
+    ) {
+        let now = canvas.clock.now_ms();
+        if canvas
+            .flush_requests
+            .get(session_id)
+            .is_some_and(|last| now < last + policy.flush_request_interval.as_millis() as u64)
+        {
+            println!("Dropped flush request of {user_id}, too many requests");
+            return;
+        }
+        canvas.flush_requests.insert(session_id.clone(), now);
 
-        self.canvases.insert(canvas_id.to_string(), canvas);
+        Self::persist_all_updates(canvas);
+        let pending = canvas.pending_since.is_some();
+        let flushed = Self::flush_canvas(canvas);
+        Self::check_backpressure(canvas, &policy.backpressure);
+        match flushed {
+            // the save state was broadcast to every session
+            Ok(()) if pending => (),
+            Ok(()) => Self::notify_session(canvas, user_id, session_id, Self::save_state(canvas)),
+            Err(_) => {
+                let rejection = Self::rejection(
+                    canvas.clock.now_secs(),
+                    op_id,
+                    NoticeLevel::Error,
+                    MessageKey::CanvasFlushFailed,
+                );
+                Self::notify_session(canvas, user_id, session_id, rejection);
+            }
+        }
+    }
 
-        Ok(())
+    /// Events of the server are applied to the running canvas even if persisting failed
+    /// persist_event already logged the failure
+    fn persist_system_event(canvas: &mut CanvasInstance, event: &CanvasEvents) {
+        let _ = Self::persist_event(canvas, event);
     }
 
     ///
-    /// Returns events to cancel unwanted dangling state from previous sessions, like selected shapes and connected users
-    /// 
-    fn extract_cleanup_events(event_log: &mut [CanvasEvents]) -> Vec<CanvasEvents> {
-        let mut selected_shapes: HashMap<String, String> = HashMap::new();
-        let mut joined_users: HashMap<WSSessionId, UserId> = HashMap::new();
-
-        for event in event_log.iter() {
-            match event {
-                CanvasEvents::ShapeSelected {
-                    origin, shapeId, ..
-                } => {
-                    selected_shapes.insert(shapeId.clone(), origin.clone());
+    /// Persists coalesced updates, the last operation of each is acknowledged with the ids it superseded
+    /// The updates were broadcast when they arrived, only event_log receives the merged update
+    ///
+    fn persist_updates(canvas: &mut CanvasInstance, updates: Vec<PendingUpdate>) {
+        for update in updates {
+            let now = canvas.clock.now_secs();
+            match Self::persist_event(canvas, &update.event) {
+                Ok(seq) => {
+                    if let (Some(seq), Some((op_id, superseded))) = (seq, update.acknowledged()) {
+                        let ack = CanvasEvents::coalesced_ack(
+                            now,
+                            op_id.clone(),
+                            seq,
+                            superseded.to_vec(),
+                        );
+                        Self::notify_session(canvas, &update.user_id, &update.session_id, ack);
+                    }
+                    canvas.event_log.push(update.event);
                 }
-
-                CanvasEvents::ShapeDeselected { shapeId, .. }
-                | CanvasEvents::ShapeRemoved { shapeId, .. } => {
-                    selected_shapes.remove(shapeId);
+                Err(_) => {
+                    // every merged operation is lost, they can be retried
+                    for op_id in &update.op_ids {
+                        canvas.applied_op_ids.remove(op_id);
+                    }
+                    let op_ids: Vec<Option<String>> = if update.op_ids.is_empty() {
+                        vec![None]
+                    } else {
+                        update.op_ids.into_iter().map(Some).collect()
+                    };
+                    for op_id in op_ids {
+                        let rejection = Self::rejection(
+                            now,
+                            op_id,
+                            NoticeLevel::Error,
+                            MessageKey::PersistenceFailed,
+                        );
+                        Self::reject(canvas, &update.user_id, &update.session_id, rejection);
+                    }
                 }
+            }
+        }
+    }
 
-                CanvasEvents::UserJoined {
-                    userId, sessionId, ..
-                } => {
-                    joined_users.insert(sessionId.clone(), userId.clone());
-                }
+    fn persist_all_updates(canvas: &mut CanvasInstance) {
+        let updates = canvas.pending_updates.take_all();
+        Self::persist_updates(canvas, updates);
+    }
 
-                CanvasEvents::UserLeft { sessionId, .. } => {
-                    joined_users.remove(sessionId);
-                }
+    /// Persists the buffered updates the event has to follow in the eventlog
+    /// Every event of a shape waits for its updates, except the next update of the same session
+    fn persist_updates_before(
+        canvas: &mut CanvasInstance,
+        session_id: &WSSessionId,
+        event: &CanvasEvents,
+        coalesced: bool,
+    ) {
+        if canvas.pending_updates.is_empty() {
+            return;
+        }
+        let updates = match event {
+            CanvasEvents::CanvasCleared { .. } => canvas.pending_updates.take_all(),
+            CanvasEvents::ShapeSelected { shapeId, .. }
+            | CanvasEvents::ShapeDeselected { shapeId, .. } => {
+                canvas.pending_updates.take_shape(shapeId, None)
+            }
+            event => match Self::changed_shape_id(event) {
+                Some(shape_id) => canvas
+                    .pending_updates
+                    .take_shape(shape_id, coalesced.then_some(session_id.as_str())),
+                None => return,
+            },
+        };
+        Self::persist_updates(canvas, updates);
+    }
 
-                _ => (),
+    /// Whether the event is buffered instead of persisted, updates of temporary shapes are never persisted late
+    fn is_coalesced(canvas: &CanvasInstance, window: Duration, event: &CanvasEvents) -> bool {
+        !window.is_zero()
+            && coalesce::updated_shape_id(event)
+                .is_some_and(|shape_id| !canvas.temp_shapes.contains_key(shape_id))
+    }
+
+    /// Time until the window of the next buffered update is over
+    fn next_update_due_in(&self) -> Option<Duration> {
+        let window = self.flush_policy.update_coalesce_window;
+        self.canvases
+            .values()
+            .filter_map(|canvas| {
+                canvas
+                    .pending_updates
+                    .next_due_in(canvas.clock.now_ms(), window)
+            })
+            .min()
+    }
+
+    /// Keeps the ids of persisted shapes, temporary shapes never reach this
+    fn track_shapes(shapes: &mut HashSet<String>, event: &CanvasEvents) {
+        match event {
+            CanvasEvents::ShapeAdded { shape, .. } => {
+                shapes.insert(shape.get_id().to_string());
             }
+            CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                shapes.remove(shapeId);
+            }
+            CanvasEvents::CanvasCleared { .. } => shapes.clear(),
+            _ => (),
         }
+    }
 
-        let mut cleanup_events = Vec::new();
-        for (shape_id, origin) in selected_shapes.into_iter() {
-            cleanup_events.push(CanvasEvents::ShapeDeselected {
-                origin,
-                shapeId: shape_id,
-                timestamp: chrono::Utc::now().timestamp() as u64,
-            });
+    /// Keeps the creator of every live shape, temporary shapes are included to protect strokes in progress
+    /// Committing a stroke adds the shape again, the first creator is kept
+    fn track_shape_creators(creators: &mut HashMap<String, UserId>, event: &CanvasEvents) {
+        match event {
+            CanvasEvents::ShapeAdded {
+                shape,
+                userId: Some(user_id),
+                ..
+            } => {
+                creators
+                    .entry(shape.get_id().to_string())
+                    .or_insert_with(|| user_id.clone());
+            }
+            CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                creators.remove(shapeId);
+            }
+            CanvasEvents::CanvasCleared { .. } => creators.clear(),
+            _ => (),
         }
+    }
 
-        for (session_id, user_id) in joined_users.into_iter() {
-            cleanup_events.push(CanvasEvents::UserLeft {
-                sessionId: session_id,
-                userId: user_id,
-                timestamp: chrono::Utc::now().timestamp() as u64,
-            });
+    /// Tracks the last change of the persisted shapes, conflicting changes are counted in the diagnostics
+    fn track_provenance(canvas: &mut CanvasInstance, window: Duration, event: &CanvasEvents) {
+        let temporary = match event {
+            CanvasEvents::ShapeAdded { shape, .. } => shape.is_temporary(),
+            _ => Self::changed_shape_id(event)
+                .is_some_and(|shape_id| canvas.temp_shapes.contains_key(shape_id)),
+        };
+        if temporary {
+            return;
         }
 
-        cleanup_events
-    }
-
-    ///
-    /// Creates events to deselect all selected shapes once a user disconnects
-    /// 
-    fn unselect_selected_shapes(canvas: &mut CanvasInstance, session_id: &WSSessionId) {
-        let mut events = Vec::new();
-        if let Some(selected_shapes) = canvas.selected_shapes.get_mut(session_id) {
-            for shape_id in selected_shapes.drain() {
-                events.push(CanvasEvents::ShapeDeselected {
-                    origin: session_id.clone(),
-                    shapeId: shape_id,
-                    timestamp: chrono::Utc::now().timestamp() as u64,
-                });
-            }
+        let now = canvas.clock.now_ms();
+        if let Some(shape_id) = canvas.provenance.record(now, window, event) {
+            canvas.diagnostics.record_conflict(now, &shape_id);
         }
+    }
 
-        for event in events {
-            Self::persist_event(canvas, &event);
-            Self::broadcast_event(canvas, Some(session_id.clone()), event);
+    fn broadcast_event(
+        canvas: &mut CanvasInstance,
+        skip_session: Option<WSSessionId>,
+        event: impl Into<CanvasEvents>,
+    ) {
+        let event = event.into();
+        if Self::send_event(canvas, skip_session, &event) {
+            canvas.event_log.push(event);
         }
     }
 
-    fn disconnect(&mut self, canvas_id: CanvasId, user_id: UserId, session_id: WSSessionId) {
-        println!("{user_id}-{session_id} disconnected from {canvas_id}");
+    /// Sends the event to every session but skip_session without adding it to event_log
+    /// Returns false if the event can't be serialized
+    fn send_event(
+        canvas: &mut CanvasInstance,
+        skip_session: Option<WSSessionId>,
+        event: &CanvasEvents,
+    ) -> bool {
+        let now = canvas.clock.now_ms();
+        let anonymize = canvas.inner.settings.anonymize_for_readers;
+        let salt = canvas
+            .inner
+            .settings
+            .reader_salt
+            .as_deref()
+            .unwrap_or_default();
+        // at most one payload per recipient class, serialized once no matter the fan-out
+        let mut payloads = EventPayloads::new(event, salt);
+        if payloads.get(false).is_none() {
+            println!("Failed to serialize event");
+            return false;
+        }
 
-        if let Some(users_left) = self.canvases.get_mut(&canvas_id).map(|canvas| {
-            Self::unselect_selected_shapes(canvas, &session_id);
+        let touched = canvas
+            .live_shapes
+            .as_mut()
+            .and_then(|live_shapes| live_shapes.apply(event));
 
-            // delete user and session
-            if let Some(session_count) = canvas.users.get_mut(&user_id).map(|sessions| {
-                sessions.remove(&session_id);
-                sessions.len()
-            }) {
-                if session_count == 0 {
-                    canvas.users.remove(&user_id);
+        let skip_session_id = skip_session.unwrap_or_default(); // there will never be a user with empty id
+        let mut fan_out = 0;
+        for (user_id, sessions) in &canvas.users {
+            let redacted =
+                redaction::is_redacted_for(&canvas.inner.access_level(user_id, now), anonymize);
+            for (session_id, tx) in sessions {
+                if session_id == &skip_session_id {
+                    continue;
+                }
+                if canvas
+                    .viewports
+                    .get_mut(session_id)
+                    .is_some_and(|viewport| !viewport.deliver(event, touched.as_ref()))
+                {
+                    continue;
+                }
+                let Some(message) = payloads.get(redacted) else {
+                    continue;
+                };
+                fan_out += 1;
+                // heartbeat will disconnect user, the failure only shows up in the diagnostics
+                if tx.send(SessionMessage::Text(message.clone())).is_err() {
+                    canvas.diagnostics.record_send_failure(session_id);
                 }
             }
+        }
+        canvas.diagnostics.record_broadcast(now, fan_out);
+        true
+    }
 
-            let event = CanvasEvents::UserLeft {
-                userId: user_id.clone(),
-                sessionId: session_id.clone(),
-                timestamp: chrono::Utc::now().timestamp() as u64, // timestamp will never be before 1970
-            };
-
-            Self::persist_event(canvas, &event);
-            Self::broadcast_event(canvas, Some(session_id), event);
-
-            canvas.users.len()
-        }) {
-            if users_left == 0 {
-                println!("No users left in {canvas_id}, unloading canvas");
-                self.canvases.remove(&canvas_id);
+    /// Sends a notice to a single sender, notices are neither persisted nor part of the event log
+    fn send_notice(tx: &mpsc::UnboundedSender<SessionMessage>, notice: &CanvasEvents) {
+        match notice.try_into() {
+            Ok(message) => {
+                let _ = tx.send(SessionMessage::Text(message));
             }
+            Err(e) => println!("Failed to serialize notice: {e}"),
         }
     }
 
-    fn update_user_access_level(
-        &mut self,
-        canvas_id: CanvasId,
-        user_id: UserId,
-        access_level: AccessLevel,
+    /// Sends a notice to a single session of the canvas
+    fn notify_session(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        notice: CanvasEvents,
     ) {
-        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
-            let event = CanvasEvents::UserAccessLevelChanged {
-                userId: user_id.clone(),
-                accessLevel: access_level.clone(),
-                timestamp: chrono::Utc::now().timestamp() as u64,
-            };
+        if let Some(tx) = canvas
+            .users
+            .get(user_id)
+            .and_then(|sessions| sessions.get(session_id))
+        {
+            Self::send_notice(tx, &notice);
+        }
+    }
 
-            canvas
-                .inner
-                .users
-                .entry(user_id)
-                .and_modify(|e| *e = access_level.clone())
-                .or_insert(access_level);
+    /// Sends a notice to the sessions of owners and moderators
+    fn notify_moderators(canvas: &CanvasInstance, notice: CanvasEvents) {
+        let now = canvas.clock.now_ms();
+        canvas
+            .users
+            .iter()
+            .filter(|(user_id, _)| {
+                matches!(
+                    canvas.inner.access_level(user_id, now),
+                    AccessLevel::Owner | AccessLevel::Moderate
+                )
+            })
+            .flat_map(|(_, sessions)| sessions.values())
+            .for_each(|tx| Self::send_notice(tx, &notice));
+    }
 
-            Self::persist_event(canvas, &event);
-            Self::broadcast_event(canvas, None, event);
-        }
+    /// Sends a notice to every session of the canvas
+    fn notify_canvas(canvas: &CanvasInstance, notice: CanvasEvents) {
+        canvas
+            .users
+            .values()
+            .flat_map(HashMap::values)
+            .for_each(|tx| Self::send_notice(tx, &notice));
     }
 
-    fn update_canvas_state(
-        &mut self,
-        canvas_id: CanvasId,
-        state: CanvasState,
-        initiator_id: UserId,
-    ) {
-        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
-            canvas.inner.state = state.clone();
+    /// Persists a read receipt if one is due, receipts are neither broadcast nor part of the event log
+    fn record_catch_up(canvas: &mut CanvasInstance, user_id: &UserId, disconnect: bool) {
+        let now = canvas.stamps.stamp_secs();
+        if let Some(marker) = canvas.receipts.catch_up(user_id, now, disconnect) {
+            Self::persist_system_event(canvas, &marker);
+        }
+    }
 
-            let event = CanvasEvents::CanvasStateChanged {
-                state,
-                timestamp: chrono::Utc::now().timestamp() as u64,
-                initiatorId: initiator_id,
-            };
+    /// Tells the session which feature flags are in effect for it
+    fn send_hello(canvas: &CanvasInstance, user_id: &UserId, session_id: &WSSessionId) {
+        let hello = CanvasEvents::ServerHello {
+            timestamp: canvas.clock.now_secs(),
+            sessionId: session_id.clone(),
+            flags: canvas
+                .session_flags
+                .get(session_id)
+                .cloned()
+                .unwrap_or_default()
+                .0,
+        };
+        Self::notify_session(canvas, user_id, session_id, hello);
+    }
 
-            Self::persist_event(canvas, &event);
-            Self::broadcast_event(canvas, None, event);
+    fn send_initial_state(canvas: &CanvasInstance, user_id: UserId, session_id: &WSSessionId) {
+        // only the new session needs the state, other sessions of the user are already up to date
+        if let Some(tx) = canvas
+            .users
+            .get(&user_id)
+            .and_then(|sessions| sessions.get(session_id))
+        {
+            let (timestamp, server_time_ms) = (canvas.clock.now_secs(), canvas.clock.now_ms());
+            let settings = &canvas.inner.settings;
+            let redacted = redaction::is_redacted_for(
+                &canvas.inner.access_level(&user_id, server_time_ms),
+                settings.anonymize_for_readers,
+            );
+            // resolved comments are sent on request only, see ResolvedCommentsRequest
+            let comments = canvas
+                .comments
+                .sync_events(session_id, None, CommentStatus::Open);
+            let state = canvas.event_log.iter().chain(&comments);
+            // This is a application error, so we can panic
+            let chunks = if redacted {
+                let salt = settings.reader_salt.as_deref().unwrap_or_default();
+                let events: Vec<Value> = state
+                    .filter_map(|event| redaction::redact(event, salt))
+                    .collect();
+                events::initial_state_chunks(timestamp, server_time_ms, &events)
+            } else {
+                let events: Vec<&CanvasEvents> = state.collect();
+                events::initial_state_chunks(timestamp, server_time_ms, &events)
+            }
+            .expect("Event can't be serialized");
+            for chunk in chunks {
+                let _ = tx.send(SessionMessage::Text(chunk));
+            }
         }
     }
 
     ///
-    /// Updates event log and stores event
-    /// Keeps track of selected shapes
+    /// Checks if a new session of the user fits into the canvas
+    /// Returns the session that has to be evicted to make room, if eviction is enabled
     ///
-    fn track_selected_shapes(
-        canvas: &mut CanvasInstance,
-        session_id: &WSSessionId,
-        event: &CanvasEvents,
-    ) {
-        // store selected shapes
-        match event {
-            CanvasEvents::ShapeSelected { shapeId, .. } => {
-                canvas
-                    .selected_shapes
-                    .entry(session_id.clone())
-                    .or_default()
-                    .insert(shapeId.clone());
+    fn check_session_limits(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        ip: Option<&IpAddr>,
+        limits: &ConnectionLimits,
+    ) -> Result<Option<WSSessionId>, SessionRejection> {
+        // checked first, evicting a session of the user doesn't make room for a flooding host
+        if let Some(ip) = ip {
+            let ip_session_count = canvas
+                .connections
+                .values()
+                .filter(|connection| connection.ip.as_ref() == Some(ip))
+                .count();
+            if ip_session_count >= limits.max_sessions_per_ip {
+                return Err(SessionRejection::IpSessionLimit);
             }
+        }
 
-            CanvasEvents::ShapeDeselected { shapeId, .. }
-            | CanvasEvents::ShapeRemoved { shapeId, .. } => {
-                canvas
-                    .selected_shapes
-                    .entry(session_id.clone())
-                    .or_default() // should never be reached
-                    .remove(shapeId);
+        let user_sessions = canvas.users.get(user_id);
+        let user_session_count = user_sessions.map_or(0, HashMap::len);
+
+        if user_session_count >= limits.max_sessions_per_user {
+            if !limits.evict_oldest_session {
+                return Err(SessionRejection::UserSessionLimit);
             }
 
-            _ => (),
+            // session_order contains every connected session
+            return Ok(canvas
+                .session_order
+                .iter()
+                .find(|session_id| user_sessions.is_some_and(|s| s.contains_key(*session_id)))
+                .cloned());
         }
-    }
 
-    ///
-    /// check if event is a system event
-    /// system events will only be send by the server
-    ///
-    fn message_allowed(event: &CanvasEvents) -> bool {
-        !matches!(
-            event,
-            CanvasEvents::UserJoined { .. }
-                | CanvasEvents::UserLeft { .. }
-                | CanvasEvents::UserAccessLevelChanged { .. }
-                | CanvasEvents::CanvasStateChanged { .. }
-        )
-    }
+        let canvas_session_count: usize = canvas.users.values().map(HashMap::len).sum();
+        if canvas_session_count >= limits.max_sessions_per_canvas {
+            return Err(SessionRejection::CanvasSessionLimit);
+        }
 
-    ///
-    /// Validates if user has the permission to send the event
-    /// 
-    fn validate_permissions(canvas: &CanvasInstance, user_id: &UserId) -> bool {
-        canvas
-            .inner
-            .users
-            .get(user_id)
-            .map_or(false, |access_level| {
-                match (access_level, &canvas.inner.state) {
-                    (AccessLevel::Owner, _) => true,
-                    (AccessLevel::Moderate, _) => true,
-                    (AccessLevel::Voice, _) => true,
-                    (AccessLevel::Write, CanvasState::Active) => true, // Write only in active state
-                    (_, _) => false,                                   // anything else can't write
-                }
-            })
+        Ok(None)
     }
 
-    fn handle_message(
+    async fn connect(
         &mut self,
+        tx: mpsc::UnboundedSender<SessionMessage>,
         canvas_id: CanvasId,
         user_id: UserId,
+        username: String,
         session_id: WSSessionId,
-        event: CanvasEvents,
+        connection: ConnectionMeta,
     ) {
-        if Self::message_allowed(&event) {
-            if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
-                if Self::validate_permissions(canvas, &user_id) {
-                    Self::track_selected_shapes(canvas, &session_id, &event);
-                    Self::persist_event(canvas, &event);
-                    Self::broadcast_event(canvas, Some(session_id), event);
+        // rejected sessions are closed by dropping tx after sending the reason
+        if let Err(rejection) = self
+            .try_connect(
+                tx.clone(),
+                canvas_id.clone(),
+                user_id.clone(),
+                username,
+                session_id.clone(),
+                connection,
+            )
+            .await
+        {
+            println!("{user_id}-{session_id} rejected from canvas {canvas_id}: {rejection:?}");
+            Self::send_notice(&tx, &rejection.notice(self.clock.now_secs()));
+        }
+    }
+
+    async fn try_connect(
+        &mut self,
+        tx: mpsc::UnboundedSender<SessionMessage>,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        username: String,
+        session_id: WSSessionId,
+        connection: ConnectionMeta,
+    ) -> Result<(), SessionRejection> {
+        // checked before loading, a flapping client should not repeatedly load a cold canvas
+        if !self
+            .connect_attempts
+            .entry((canvas_id.clone(), user_id.clone()))
+            .or_default()
+            .register_attempt(Instant::now(), &self.limits)
+        {
+            return Err(SessionRejection::CoolingDown);
+        }
+
+        if !self.canvases.contains_key(&canvas_id) {
+            if let Err(e) = self.load_canvas(&canvas_id).await {
+                match &e {
+                    LoadError::Binding(e) => println!("WARNING: refused to load {canvas_id}: {e}"),
+                    LoadError::Unavailable(e) => println!("Failed to load events: {e}"),
                 }
-                // TODO: signal user that he has no permission
+                return Err(e.rejection());
             }
         } else {
-            println!("User {user_id} tried to send system message");
+            self.refresh_member(&canvas_id, &user_id).await;
         }
-    }
 
-    pub async fn run(mut self) -> io::Result<()> {
-        while let Some(cmd) = self.cmd_rx.recv().await {
-            match cmd {
-                Command::Connect {
-                    conn_tx,
-                    canvas_id,
-                    user_id,
-                    username,
-                    session_id,
+        let canvas = self
+            .canvases
+            .get_mut(&canvas_id)
+            .ok_or(SessionRejection::CanvasUnavailable)?;
+
+        if let Some(evicted_session_id) =
+            Self::check_session_limits(canvas, &user_id, connection.ip.as_ref(), &self.limits)?
+        {
+            println!("Evicting {user_id}-{evicted_session_id} from canvas {canvas_id}");
+            Self::notify_session(
+                canvas,
+                &user_id,
+                &evicted_session_id,
+                SessionRejection::Evicted.notice(canvas.clock.now_secs()),
+            );
+            // removing the session drops its sender, which closes the socket
+            Self::remove_session(canvas, &user_id, &evicted_session_id);
+        }
+
+        println!("{username}({user_id}-{session_id}) joined canvas {canvas_id}");
+
+        {
+            canvas.session_order.push(session_id.clone());
+            canvas.connections.insert(session_id.clone(), connection);
+            canvas.usernames.insert(user_id.clone(), username.clone());
+            canvas
+                .users
+                .entry(user_id.clone())
+                .and_modify(|sessions| {
+                    sessions.insert(session_id.clone(), tx.clone());
+                })
+                .or_insert_with(|| {
+                    let mut user_sessions = HashMap::with_capacity(1);
+                    user_sessions.insert(session_id.clone(), tx.clone());
+                    user_sessions
+                });
+
+            // token principals are no members, their level comes from the token
+            let access_level = match canvas.inner.access_level(&user_id, canvas.clock.now_ms()) {
+                AccessLevel::None => AccessLevel::Read,
+                access_level => access_level,
+            };
+            let event = CanvasEvents::UserJoined {
+                userId: user_id.clone(),
+                username,
+                sessionId: session_id.clone(),
+                timestamp: canvas.stamps.stamp_secs(),
+                accessLevel: access_level,
+            };
+
+            let flags = self
+                .feature_flags
+                .resolve(&user_id, &canvas.inner.feature_overrides);
+            canvas.session_flags.insert(session_id.clone(), flags);
+
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, Some(session_id.clone()), event); // does not contain own join
+            Self::send_hello(canvas, &user_id, &session_id);
+            // the initial state is built from event_log, it lacks the updates still buffered
+            Self::persist_all_updates(canvas);
+            Self::send_initial_state(canvas, user_id.clone(), &session_id); // does contain own join
+            Self::record_catch_up(canvas, &user_id, false);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Takes the access level of a user missing from a loaded canvas from the CanvasStore
+    /// The user may have been granted access after the canvas was loaded, e.g. while the page was open
+    ///
+    async fn refresh_member(&mut self, canvas_id: &str, user_id: &UserId) {
+        let is_member = self
+            .canvases
+            .get(canvas_id)
+            .is_none_or(|canvas| canvas.inner.users.contains_key(user_id));
+        // token principals are no members
+        if is_member || tokens::token_id_of(user_id).is_some() {
+            return;
+        }
+
+        let stored = match self
+            .get_canvas_recipient
+            .send(GetCanvasMessage {
+                canvas_id: canvas_id.to_string(),
+            })
+            .await
+        {
+            Ok(Some(stored)) => stored,
+            _ => return,
+        };
+        let Some(access_level) = stored.users.get(user_id) else {
+            return;
+        };
+
+        // the canvas may have been unloaded meanwhile
+        if let Some(canvas) = self.canvases.get_mut(canvas_id) {
+            canvas
+                .inner
+                .users
+                .insert(user_id.clone(), access_level.clone());
+            match stored.expirations.get(user_id) {
+                Some(expires_at) => canvas
+                    .inner
+                    .expirations
+                    .insert(user_id.clone(), *expires_at),
+                None => canvas.inner.expirations.remove(user_id),
+            };
+        }
+    }
+
+    ///
+    /// Loads canvas from persistence and applies all events
+    /// Cleans up dangling state from previous sessions
+    ///
+    async fn load_canvas(&mut self, canvas_id: &str) -> Result<(), LoadError> {
+        // checked first, unknown canvases must not create an eventlog
+        let canvas = self
+            .get_canvas_recipient
+            .send(GetCanvasMessage {
+                canvas_id: canvas_id.to_string(),
+            })
+            .await
+            .map_err(LoadError::unavailable)?
+            .ok_or_else(|| LoadError::unavailable("Canvas not found"))?;
+
+        if let Some(restored) = self.restore_handoff(canvas_id, &canvas) {
+            println!("Restored {canvas_id} from the handoff of the previous process");
+            self.finish_load(canvas_id, restored);
+            return Ok(());
+        }
+
+        let log_path = canvas_log_path(canvas_id);
+        let open_log = || {
+            EventLogPersistenceJson::new(&log_path)
+                .and_then(|log| log.into_standalone::<CanvasEvents>())
+                .map_err(LoadError::unavailable)
+        };
+        let (mut event_log, mut persistence) = open_log()?;
+
+        match binding::check(canvas_id, &event_log).map_err(LoadError::Binding)? {
+            LogBinding::Empty | LogBinding::Bound => (),
+            LogBinding::Legacy => {
+                println!("Adding canvas header to legacy eventlog {log_path}");
+                binding::migrate_legacy_log(&log_path, canvas_id, event_log, self.clock.now_secs())
+                    .map_err(LoadError::unavailable)?;
+                // the log was replaced, the open handle still points to the old file
+                (event_log, persistence) = open_log()?;
+            }
+        }
+
+        // header and markers only count towards the sequence numbers, clients never see them
+        let persisted_events = event_log.len() as u64;
+        let receipts = ReadReceipts::from_log(&event_log);
+        let contributors = Contributors::from_log(&event_log);
+        let comments = Comments::from_log(&event_log);
+        event_log.retain(|event| {
+            !comments::is_comment_event(event)
+                && !matches!(
+                    event,
+                    CanvasEvents::CanvasLogHeader { .. }
+                        | CanvasEvents::UserCaughtUp { .. }
+                        | CanvasEvents::ContributorSeen { .. }
+                )
+        });
+
+        // logs written before ids were checked may add a live shape again, the first one is kept
+        let mut shapes = HashSet::new();
+        let mut shape_creators = HashMap::new();
+        let mut provenance = Provenance::default();
+        event_log.retain(|event| {
+            if let CanvasEvents::ShapeAdded { shape, .. } = event {
+                if shapes.contains(shape.get_id()) {
+                    println!(
+                        "WARNING: eventlog {log_path}: shape {} added again, kept the first one",
+                        shape.get_id()
+                    );
+                    return false;
+                }
+            }
+            Self::track_shapes(&mut shapes, event);
+            Self::track_shape_creators(&mut shape_creators, event);
+            provenance.apply(event);
+            true
+        });
+
+        let log_bytes = persistence.size().map_err(LoadError::unavailable)?;
+        let canvas = CanvasInstance {
+            selected_shapes: HashMap::new(),
+            temp_shapes: HashMap::new(),
+            inner: canvas,
+            users: HashMap::with_capacity(1),
+            persisted_events,
+            log_canvas_id: canvas_id.to_string(),
+            event_log,
+            flushed_seq: persisted_events,
+            pending_since: None,
+            degraded: false,
+            flushed_bytes: log_bytes,
+            throttled: false,
+            flush_requests: HashMap::new(),
+            log_bytes,
+            persistence,
+            session_order: Vec::new(),
+            shapes,
+            shape_creators,
+            provenance,
+            comments,
+            quota_warnings: QuotaWarnings::default(),
+            receipts,
+            contributors,
+            usernames: HashMap::new(),
+            applied_op_ids: RecentOpIds::default(),
+            time_syncs: HashMap::new(),
+            session_flags: HashMap::new(),
+            viewports: HashMap::new(),
+            live_shapes: None,
+            connections: HashMap::new(),
+            diagnostics: CanvasDiagnostics::default(),
+            pending_updates: UpdateBuffer::default(),
+            stamps: MonotonicStamps::new(self.clock.clone()),
+            event_bus: self.event_bus.clone(),
+            clock: self.clock.clone(),
+        };
+        self.finish_load(canvas_id, canvas);
+
+        Ok(())
+    }
+
+    /// Cleans up the sessions of previous processes and starts tracking the loaded canvas
+    fn finish_load(&mut self, canvas_id: &str, mut canvas: CanvasInstance) {
+        let now = canvas.stamps.stamp_secs();
+        let cleanup_events = Self::extract_cleanup_events(&mut canvas.event_log, now);
+        cleanup_events.into_iter().for_each(|event| {
+            Self::persist_system_event(&mut canvas, &event);
+            canvas.event_log.push(event);
+        });
+
+        // usage above the threshold was already warned about by a previous instance
+        for (kind, usage) in Self::instance_usage(&canvas) {
+            canvas.quota_warnings.check(kind, usage, &self.quota_limits);
+        }
+
+        self.canvases.insert(canvas_id.to_string(), canvas);
+    }
+
+    ///
+    /// Restores the canvas from the handoff the previous process wrote on shutdown
+    /// None if there is none or it is stale, the eventlog is folded then
+    ///
+    fn restore_handoff(&self, canvas_id: &str, inner: &Canvas) -> Option<CanvasInstance> {
+        let handoff = match Handoff::take(&handoff::handoff_path(canvas_id)) {
+            Ok(Some(handoff)) => handoff,
+            Ok(None) => return None,
+            Err(e) => {
+                println!("WARNING: discarded unreadable handoff of {canvas_id}: {e}");
+                return None;
+            }
+        };
+        let persistence = match EventLogPersistenceJson::new(&canvas_log_path(canvas_id)) {
+            Ok(log) => log.into_appender(),
+            Err(e) => {
+                println!("WARNING: discarded handoff of {canvas_id}, eventlog unavailable: {e}");
+                return None;
+            }
+        };
+
+        self.restore(inner, handoff, persistence)
+            .inspect_err(|rejection| {
+                println!("Discarded stale handoff of {canvas_id}: {rejection:?}")
+            })
+            .ok()
+    }
+
+    /// Instance of a handoff, checked against the eventlog it was written for
+    fn restore(
+        &self,
+        inner: &Canvas,
+        mut handoff: Handoff,
+        persistence: EventLogPersistenceStandaloneJson<CanvasEvents>,
+    ) -> Result<CanvasInstance, HandoffRejection> {
+        let now = self.clock.now_ms();
+        // an unreadable size never matches the handoff
+        let log_bytes = persistence.size().unwrap_or(u64::MAX);
+        handoff.check(&inner.id, log_bytes, now, self.handoff_max_age)?;
+        handoff.drop_expired_temp_shapes(now);
+
+        Ok(CanvasInstance {
+            selected_shapes: HashMap::new(),
+            temp_shapes: handoff.temp_shapes,
+            users: HashMap::with_capacity(1),
+            persisted_events: handoff.persisted_events,
+            log_canvas_id: handoff.canvas_id,
+            event_log: handoff.event_log,
+            flushed_seq: handoff.persisted_events,
+            pending_since: None,
+            degraded: false,
+            flushed_bytes: log_bytes,
+            throttled: false,
+            flush_requests: HashMap::new(),
+            log_bytes,
+            persistence,
+            session_order: Vec::new(),
+            shapes: handoff.shapes,
+            shape_creators: handoff.shape_creators,
+            provenance: handoff.provenance,
+            comments: handoff.comments,
+            quota_warnings: QuotaWarnings::default(),
+            receipts: handoff.receipts,
+            contributors: handoff.contributors,
+            usernames: HashMap::new(),
+            applied_op_ids: RecentOpIds::default(),
+            time_syncs: HashMap::new(),
+            session_flags: HashMap::new(),
+            viewports: HashMap::new(),
+            live_shapes: None,
+            connections: HashMap::new(),
+            diagnostics: CanvasDiagnostics::default(),
+            pending_updates: UpdateBuffer::default(),
+            inner: inner.clone(),
+            stamps: MonotonicStamps::new(self.clock.clone()),
+            event_bus: self.event_bus.clone(),
+            clock: self.clock.clone(),
+        })
+    }
+
+    /// Hot state of a flushed canvas for the next process, see handoff.rs
+    fn handoff(canvas: CanvasInstance) -> io::Result<Handoff> {
+        Ok(Handoff {
+            version: handoff::HANDOFF_VERSION,
+            written_at: canvas.clock.now_ms(),
+            log_bytes: canvas.persistence.size()?,
+            canvas_id: canvas.log_canvas_id,
+            persisted_events: canvas.persisted_events,
+            event_log: canvas.event_log,
+            shapes: canvas.shapes,
+            shape_creators: canvas.shape_creators,
+            provenance: canvas.provenance,
+            comments: canvas.comments,
+            temp_shapes: canvas.temp_shapes,
+            contributors: canvas.contributors,
+            receipts: canvas.receipts,
+        })
+    }
+
+    ///
+    /// Returns events to cancel unwanted dangling state from previous sessions, like selected shapes and connected users
+    ///
+    pub(crate) fn extract_cleanup_events(
+        event_log: &mut [CanvasEvents],
+        now: u64,
+    ) -> Vec<CanvasEvents> {
+        let mut selected_shapes: HashMap<String, String> = HashMap::new();
+        let mut joined_users: HashMap<WSSessionId, UserId> = HashMap::new();
+
+        for event in event_log.iter() {
+            match event {
+                CanvasEvents::ShapeSelected {
+                    origin, shapeId, ..
+                } => {
+                    selected_shapes.insert(shapeId.clone(), origin.clone());
+                }
+
+                CanvasEvents::ShapeDeselected { shapeId, .. }
+                | CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                    selected_shapes.remove(shapeId);
+                }
+
+                CanvasEvents::CanvasCleared { .. } => selected_shapes.clear(),
+
+                CanvasEvents::UserJoined {
+                    userId, sessionId, ..
                 } => {
-                    self.connect(conn_tx, canvas_id, user_id, username, session_id)
-                        .await;
+                    joined_users.insert(sessionId.clone(), userId.clone());
+                }
+
+                CanvasEvents::UserLeft { sessionId, .. } => {
+                    joined_users.remove(sessionId);
+                }
+
+                _ => (),
+            }
+        }
+
+        let mut cleanup_events = Vec::new();
+        for (shape_id, origin) in selected_shapes.into_iter() {
+            cleanup_events.push(CanvasEvents::ShapeDeselected {
+                origin,
+                shapeId: shape_id,
+                timestamp: now,
+            });
+        }
+
+        for (session_id, user_id) in joined_users.into_iter() {
+            cleanup_events.push(CanvasEvents::UserLeft {
+                sessionId: session_id,
+                userId: user_id,
+                timestamp: now,
+            });
+        }
+
+        cleanup_events
+    }
+
+    ///
+    /// Marks the events the compaction drops
+    /// Drops shapes that were removed or cleared, selections and join/leave events, and all but the latest read receipt of every user
+    /// Deleted comments are dropped with their events, other comments keep their latest status change
+    /// History and read receipts are then thinned out by the retention policy, now is in seconds
+    ///
+    pub(crate) fn compaction_drops(
+        event_log: &[CanvasEvents],
+        policy: &RetentionPolicy,
+        now: u64,
+    ) -> Vec<bool> {
+        // indices of events belonging to shapes that are still alive
+        let mut shape_events: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut receipts: HashMap<UserId, usize> = HashMap::new();
+        // index of the CommentAdded and of the latest status change of every comment
+        let mut comments: HashMap<String, (usize, Option<usize>)> = HashMap::new();
+        let mut dropped = vec![false; event_log.len()];
+
+        for (index, event) in event_log.iter().enumerate() {
+            match event {
+                CanvasEvents::ShapeAdded { shape, .. } => {
+                    shape_events
+                        .entry(shape.get_id().to_string())
+                        .or_default()
+                        .push(index);
+                }
+
+                CanvasEvents::ShapeUpdated { shape, .. } => {
+                    if let Some(shape_id) = shape.get("id").and_then(|id| id.as_str()) {
+                        shape_events
+                            .entry(shape_id.to_string())
+                            .or_default()
+                            .push(index);
+                    }
+                }
+
+                CanvasEvents::ShapeZChanged { shapeId, .. } => {
+                    shape_events.entry(shapeId.clone()).or_default().push(index);
+                }
+
+                CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                    for removed in shape_events.remove(shapeId).unwrap_or_default() {
+                        dropped[removed] = true;
+                    }
+                    dropped[index] = true;
+                }
+
+                // nothing before a clear is visible anymore, so the clear itself is not needed
+                CanvasEvents::CanvasCleared { .. } => {
+                    for removed in shape_events.drain().flat_map(|(_, indices)| indices) {
+                        dropped[removed] = true;
+                    }
+                    dropped[index] = true;
+                }
+
+                CanvasEvents::ShapeSelected { .. }
+                | CanvasEvents::ShapeDeselected { .. }
+                | CanvasEvents::UserJoined { .. }
+                | CanvasEvents::UserLeft { .. } => dropped[index] = true,
+
+                CanvasEvents::UserCaughtUp { userId, .. } => {
+                    if let Some(previous) = receipts.insert(userId.clone(), index) {
+                        dropped[previous] = true;
+                    }
+                }
+
+                // the fold ignores a comment added again, so does the compacted log
+                CanvasEvents::CommentAdded { commentId, .. } => {
+                    if comments.contains_key(commentId) {
+                        dropped[index] = true;
+                    } else {
+                        comments.insert(commentId.clone(), (index, None));
+                    }
+                }
+
+                CanvasEvents::CommentResolved { commentId, .. }
+                | CanvasEvents::CommentReopened { commentId, .. } => {
+                    match comments.get_mut(commentId) {
+                        Some((_, status)) => {
+                            if let Some(previous) = status.replace(index) {
+                                dropped[previous] = true;
+                            }
+                        }
+                        None => dropped[index] = true,
+                    }
+                }
+
+                CanvasEvents::CommentDeleted { commentId, .. } => {
+                    if let Some((added, status)) = comments.remove(commentId) {
+                        dropped[added] = true;
+                        if let Some(status) = status {
+                            dropped[status] = true;
+                        }
+                    }
+                    dropped[index] = true;
+                }
+
+                _ => (),
+            }
+        }
+
+        retention::apply(event_log, &mut dropped, policy, now);
+        dropped
+    }
+
+    ///
+    /// Folds the event log into the smallest log producing the same canvas, see compaction_drops
+    /// Kept read receipts are rebased onto the lines of the compacted log
+    /// Expects a log without dangling state, see extract_cleanup_events
+    ///
+    pub(crate) fn compact_event_log(
+        event_log: Vec<CanvasEvents>,
+        policy: &RetentionPolicy,
+        now: u64,
+    ) -> Vec<CanvasEvents> {
+        let dropped = Self::compaction_drops(&event_log, policy, now);
+
+        // kept_before[line] counts the kept events among the first lines of the original log
+        let mut kept_before = Vec::with_capacity(dropped.len() + 1);
+        kept_before.push(0);
+        for dropped in &dropped {
+            kept_before.push(kept_before.last().copied().unwrap_or(0) + u64::from(!dropped));
+        }
+
+        event_log
+            .into_iter()
+            .zip(dropped)
+            .filter(|(_, dropped)| !dropped)
+            .map(|(mut event, _)| {
+                if let CanvasEvents::UserCaughtUp { seq, .. } = &mut event {
+                    let line = (*seq as usize).min(kept_before.len() - 1);
+                    *seq = kept_before[line];
                 }
+                event
+            })
+            .collect()
+    }
+
+    ///
+    /// Creates events to deselect all selected shapes once a user disconnects
+    ///
+    fn unselect_selected_shapes(canvas: &mut CanvasInstance, session_id: &WSSessionId) {
+        let mut events = Vec::new();
+        if let Some(selected_shapes) = canvas.selected_shapes.get_mut(session_id) {
+            for shape_id in selected_shapes.drain() {
+                events.push(CanvasEvents::ShapeDeselected {
+                    origin: session_id.clone(),
+                    shapeId: shape_id,
+                    timestamp: canvas.stamps.stamp_secs(),
+                });
+            }
+        }
+
+        for event in events {
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, Some(session_id.clone()), event);
+        }
+    }
+
+    ///
+    /// Removes a session from the canvas and announces that it left
+    /// Does nothing if the session is not connected, e.g. it was rejected or evicted before
+    ///
+    fn remove_session(canvas: &mut CanvasInstance, user_id: &UserId, session_id: &WSSessionId) {
+        let Some(sessions) = canvas.users.get_mut(user_id) else {
+            return;
+        };
+        if sessions.remove(session_id).is_none() {
+            return;
+        }
+        let last_session = sessions.is_empty();
+        if last_session {
+            canvas.users.remove(user_id);
+            canvas.usernames.remove(user_id);
+        }
+        canvas.session_order.retain(|s| s != session_id);
+
+        let updates = canvas.pending_updates.take_session(session_id);
+        Self::persist_updates(canvas, updates);
+        Self::unselect_selected_shapes(canvas, session_id);
+        canvas.selected_shapes.remove(session_id);
+        canvas.time_syncs.remove(session_id);
+        canvas.session_flags.remove(session_id);
+        canvas.viewports.remove(session_id);
+        if canvas.viewports.is_empty() {
+            canvas.live_shapes = None;
+        }
+        canvas.flush_requests.remove(session_id);
+        canvas.connections.remove(session_id);
+        canvas.diagnostics.remove_session(session_id);
+        // the user saw every change while connected
+        if last_session {
+            Self::record_catch_up(canvas, user_id, true);
+        }
+
+        let event = CanvasEvents::UserLeft {
+            userId: user_id.clone(),
+            sessionId: session_id.clone(),
+            timestamp: canvas.stamps.stamp_secs(),
+        };
+
+        Self::persist_system_event(canvas, &event);
+        Self::broadcast_event(canvas, Some(session_id.clone()), event);
+    }
+
+    fn disconnect(&mut self, canvas_id: CanvasId, user_id: UserId, session_id: WSSessionId) {
+        println!("{user_id}-{session_id} disconnected from {canvas_id}");
+
+        if let Some(users_left) = self.canvases.get_mut(&canvas_id).map(|canvas| {
+            Self::remove_session(canvas, &user_id, &session_id);
+            canvas.users.len()
+        }) {
+            if users_left == 0 {
+                println!("No users left in {canvas_id}, unloading canvas");
+                if let Some(mut canvas) = self.canvases.remove(&canvas_id) {
+                    let _ = Self::flush_canvas(&mut canvas);
+                }
+
+                // keep counters that still protect the cold canvas from flapping clients
+                let now = Instant::now();
+                self.connect_attempts
+                    .retain(|(id, _), attempts| id != &canvas_id || !attempts.is_stale(now));
+            }
+        }
+    }
+
+    /// Drops counters that neither count attempts nor cool down anymore, once per CONNECT_ATTEMPT_WINDOW
+    /// Counters of canvases that never finished loading are only ever removed here
+    fn sweep_connect_attempts(&mut self, now: Instant) {
+        if now < self.next_attempt_sweep {
+            return;
+        }
+        self.next_attempt_sweep = now + CONNECT_ATTEMPT_WINDOW;
+        self.connect_attempts
+            .retain(|_, attempts| !attempts.is_stale(now));
+    }
+
+    fn update_user_access_level(
+        &mut self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        access_level: AccessLevel,
+        expires_at: Option<u64>,
+    ) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            match expires_at {
+                Some(expires_at) => canvas.inner.expirations.insert(user_id.clone(), expires_at),
+                None => canvas.inner.expirations.remove(&user_id),
+            };
+
+            let event = CanvasEvents::UserAccessLevelChanged {
+                userId: user_id.clone(),
+                accessLevel: access_level.clone(),
+                timestamp: canvas.stamps.stamp_secs(),
+            };
+
+            canvas
+                .inner
+                .users
+                .entry(user_id)
+                .and_modify(|e| *e = access_level.clone())
+                .or_insert(access_level);
+
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, None, event);
+        }
+    }
+
+    fn update_canvas_state(
+        &mut self,
+        canvas_id: CanvasId,
+        state: CanvasState,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            // commands of concurrent requests may arrive out of order, never go back to an older state
+            if version <= canvas.inner.version {
+                println!(
+                    "Ignored stale state update of {canvas_id}: version {version}, current {}",
+                    canvas.inner.version
+                );
+                return;
+            }
+            canvas.inner.state = state.clone();
+            canvas.inner.version = version;
+
+            let event = CanvasEvents::CanvasStateChanged {
+                state,
+                timestamp: canvas.stamps.stamp_secs(),
+                initiatorId: initiator_id,
+                version,
+            };
+
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, None, event);
+        }
+    }
+
+    fn update_canvas_settings(
+        &mut self,
+        canvas_id: CanvasId,
+        settings: CanvasSettings,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            // same ordering guarantee as state updates, both share the canvas version
+            if version <= canvas.inner.version {
+                println!(
+                    "Ignored stale settings update of {canvas_id}: version {version}, current {}",
+                    canvas.inner.version
+                );
+                return;
+            }
+            canvas.inner.version = version;
+
+            let event = CanvasEvents::CanvasSettingsChanged {
+                timestamp: canvas.stamps.stamp_secs(),
+                gridSize: settings.grid_size,
+                snapEnabled: settings.snap_enabled,
+                shapeOwnershipEnforced: settings.shape_ownership_enforced,
+                legacyVoiceBehavior: settings.legacy_voice_behavior,
+                allowedShapeTypes: settings.allowed_shape_types.clone(),
+                moderatorsBypassShapeTypes: settings.moderators_bypass_shape_types,
+                initiatorId: initiator_id,
+                version,
+            };
+            canvas.inner.settings = settings;
+
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, None, event);
+            Self::release_foreign_selections(canvas);
+        }
+    }
+
+    fn update_canvas_palette(
+        &mut self,
+        canvas_id: CanvasId,
+        palette: Vec<PaletteColor>,
+        enforce_palette: bool,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        let Some(canvas) = self.canvases.get_mut(&canvas_id) else {
+            return;
+        };
+        // shares the canvas version with state and settings updates
+        if version <= canvas.inner.version {
+            println!(
+                "Ignored stale palette update of {canvas_id}: version {version}, current {}",
+                canvas.inner.version
+            );
+            return;
+        }
+        canvas.inner.version = version;
+        canvas.inner.settings.palette = palette.clone();
+        canvas.inner.settings.enforce_palette = enforce_palette;
+
+        let event = CanvasEvents::PaletteChanged {
+            timestamp: canvas.stamps.stamp_secs(),
+            palette,
+            enforcePalette: enforce_palette,
+            initiatorId: initiator_id,
+            version,
+        };
+        Self::persist_system_event(canvas, &event);
+        Self::broadcast_event(canvas, None, event);
+    }
+
+    fn update_canvas_feature_flags(
+        &mut self,
+        canvas_id: CanvasId,
+        overrides: BTreeMap<String, bool>,
+        version: u64,
+    ) {
+        let Some(canvas) = self.canvases.get_mut(&canvas_id) else {
+            return;
+        };
+        // shares the canvas version with state and settings updates
+        if version <= canvas.inner.version {
+            println!(
+                "Ignored stale feature flag update of {canvas_id}: version {version}, current {}",
+                canvas.inner.version
+            );
+            return;
+        }
+        canvas.inner.version = version;
+        canvas.inner.feature_overrides = overrides;
+
+        // connected sessions get the new flags right away, so clients and server keep agreeing
+        let sessions: Vec<(UserId, WSSessionId)> = canvas
+            .users
+            .iter()
+            .flat_map(|(user_id, sessions)| {
+                sessions
+                    .keys()
+                    .map(move |session_id| (user_id.clone(), session_id.clone()))
+            })
+            .collect();
+        for (user_id, session_id) in sessions {
+            let flags = self
+                .feature_flags
+                .resolve(&user_id, &canvas.inner.feature_overrides);
+            canvas.session_flags.insert(session_id.clone(), flags);
+            Self::send_hello(canvas, &user_id, &session_id);
+        }
+    }
+
+    /// Sessions of the user in every loaded canvas, in the order they connected
+    fn user_sessions(&self, user_id: &UserId) -> Vec<UserSession> {
+        let mut sessions: Vec<UserSession> = self
+            .canvases
+            .values()
+            .filter_map(|canvas| {
+                canvas
+                    .users
+                    .get(user_id)
+                    .map(|user_sessions| (canvas, user_sessions))
+            })
+            .flat_map(|(canvas, user_sessions)| {
+                canvas
+                    .session_order
+                    .iter()
+                    .filter(|session_id| user_sessions.contains_key(*session_id))
+                    .map(|session_id| UserSession {
+                        canvas_id: canvas.inner.id.clone(),
+                        canvas_name: canvas.inner.name.clone(),
+                        session_id: session_id.clone(),
+                        connection: canvas
+                            .connections
+                            .get(session_id)
+                            .cloned()
+                            .unwrap_or_default(),
+                    })
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.canvas_name.cmp(&b.canvas_name));
+        sessions
+    }
+
+    /// Sessions on the canvas in the order they connected
+    fn canvas_sessions(&self, canvas_id: &CanvasId) -> Vec<CanvasSession> {
+        let Some(canvas) = self.canvases.get(canvas_id) else {
+            return Vec::new();
+        };
+        canvas
+            .session_order
+            .iter()
+            .filter_map(|session_id| {
+                let (user_id, _) = canvas
+                    .users
+                    .iter()
+                    .find(|(_, sessions)| sessions.contains_key(session_id))?;
+                Some(CanvasSession {
+                    user_id: user_id.clone(),
+                    username: Self::username_of(canvas, user_id),
+                    session_id: session_id.clone(),
+                    connection: canvas
+                        .connections
+                        .get(session_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Answers a query from the loaded canvases, only quota usage and read state fall back to the eventlog
+    /// Mutations stay separate commands, a query never changes the server
+    ///
+    fn answer_query(&self, canvas_id: Option<&CanvasId>, query: CanvasQuery) -> CanvasQueryResult {
+        let canvas = canvas_id.and_then(|canvas_id| self.canvases.get(canvas_id));
+        match (query, canvas_id, canvas) {
+            (CanvasQuery::UserSessions(user_id), _, _) => {
+                CanvasQueryResult::UserSessions(self.user_sessions(&user_id))
+            }
+            (CanvasQuery::ServerStats, _, _) => CanvasQueryResult::ServerStats(ServerStats {
+                loaded_canvases: self.canvases.len(),
+                sessions: self
+                    .canvases
+                    .values()
+                    .flat_map(|canvas| canvas.users.values())
+                    .map(HashMap::len)
+                    .sum(),
+            }),
+            (CanvasQuery::LoadedCanvases, _, _) => {
+                CanvasQueryResult::LoadedCanvases(self.canvases.keys().cloned().collect())
+            }
+            (CanvasQuery::QuotaUsage, Some(canvas_id), _) => {
+                CanvasQueryResult::QuotaUsage(self.quota_usage(canvas_id))
+            }
+            (CanvasQuery::ReadState, Some(canvas_id), _) => {
+                CanvasQueryResult::ReadState(self.read_state(canvas_id))
+            }
+            (CanvasQuery::Presence, _, Some(canvas)) => {
+                CanvasQueryResult::Presence(canvas.users.keys().cloned().collect())
+            }
+            (CanvasQuery::Sessions, _, Some(canvas)) => {
+                CanvasQueryResult::Sessions(self.canvas_sessions(&canvas.inner.id))
+            }
+            (CanvasQuery::Contributors, _, Some(canvas)) => {
+                CanvasQueryResult::Contributors(canvas.contributors.clone())
+            }
+            (CanvasQuery::PersistedSeq, _, Some(canvas)) => {
+                CanvasQueryResult::PersistedSeq(canvas.persisted_events)
+            }
+            (CanvasQuery::Diagnostics, _, Some(canvas)) => {
+                CanvasQueryResult::Diagnostics(DiagnosticsReport {
+                    clock_regressions: canvas.stamps.regressions(),
+                    selected_shapes: canvas.selected_shapes.values().map(HashSet::len).sum(),
+                    temp_shapes: canvas.temp_shapes.len(),
+                    throttled: canvas.throttled,
+                    backlog_events: Self::backlog(canvas).events,
+                    backlog_bytes: Self::backlog(canvas).bytes,
+                    ..canvas
+                        .diagnostics
+                        .report(canvas.clock.now_ms(), Self::save_lag_ms(canvas))
+                })
+            }
+            (CanvasQuery::FindShapes(filter), _, Some(canvas)) => {
+                CanvasQueryResult::Shapes(filter.find_live(&canvas.event_log))
+            }
+            (CanvasQuery::ShapeProvenance { shape_id }, _, Some(canvas)) => {
+                CanvasQueryResult::ShapeProvenance(canvas.provenance.get(&shape_id).cloned())
+            }
+            (CanvasQuery::Comments, _, Some(canvas)) => {
+                CanvasQueryResult::Comments(canvas.comments.clone())
+            }
+            _ => CanvasQueryResult::CanvasNotLoaded,
+        }
+    }
+
+    /// Closes every session of the user, the sessions are told why by a notice and the close code
+    fn close_user_sessions(&mut self, user_id: UserId, reason: MessageKey, close: SocketClose) {
+        for session in self.user_sessions(&user_id) {
+            if let Some(canvas) = self.canvases.get(&session.canvas_id) {
+                let notice =
+                    CanvasEvents::notice(canvas.clock.now_secs(), NoticeLevel::Error, reason);
+                Self::notify_session(canvas, &user_id, &session.session_id, notice);
+                if let Some(tx) = canvas
+                    .users
+                    .get(&user_id)
+                    .and_then(|sessions| sessions.get(&session.session_id))
+                {
+                    let _ = tx.send(SessionMessage::Close(close));
+                }
+            }
+            self.disconnect(session.canvas_id, user_id.clone(), session.session_id);
+        }
+    }
+
+    fn notify_users(&self, user_ids: &[UserId], level: NoticeLevel, message: Message) {
+        for user_id in user_ids {
+            for session in self.user_sessions(user_id) {
+                if let Some(canvas) = self.canvases.get(&session.canvas_id) {
+                    let notice =
+                        CanvasEvents::notice(canvas.clock.now_secs(), level, message.clone());
+                    Self::notify_session(canvas, user_id, &session.session_id, notice);
+                }
+            }
+        }
+    }
+
+    /// Canvases load their tokens from the store, a loaded canvas has to learn about new ones
+    fn add_api_token(&mut self, canvas_id: CanvasId, token: ApiToken) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            canvas.inner.api_tokens.insert(token.id.clone(), token);
+        }
+    }
+
+    /// Drops the token and closes the sessions of its principal, they can't reconnect with it
+    fn revoke_api_token(&mut self, canvas_id: CanvasId, token_id: TokenId) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            canvas.inner.api_tokens.remove(&token_id);
+        }
+        self.close_user_sessions(
+            tokens::principal_id(&token_id),
+            MessageKey::SessionTokenRevoked,
+            SocketClose::AuthExpired,
+        );
+    }
+
+    ///
+    /// Updates event log and stores event
+    /// Keeps track of selected shapes
+    ///
+    fn track_selected_shapes(
+        canvas: &mut CanvasInstance,
+        session_id: &WSSessionId,
+        event: &CanvasEvents,
+    ) {
+        // store selected shapes
+        match event {
+            CanvasEvents::ShapeSelected { shapeId, .. } => {
+                canvas
+                    .selected_shapes
+                    .entry(session_id.clone())
+                    .or_default()
+                    .insert(shapeId.clone());
+            }
+
+            CanvasEvents::ShapeDeselected { shapeId, .. }
+            | CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                canvas
+                    .selected_shapes
+                    .entry(session_id.clone())
+                    .or_default() // should never be reached
+                    .remove(shapeId);
+            }
+
+            CanvasEvents::CanvasCleared { .. } => {
+                canvas
+                    .selected_shapes
+                    .values_mut()
+                    .for_each(|selected| selected.clear());
+            }
+
+            _ => (),
+        }
+    }
+
+    ///
+    /// check if event is a system event
+    /// system events will only be send by the server
+    ///
+    fn message_allowed(event: &CanvasEvents) -> bool {
+        !matches!(
+            event,
+            CanvasEvents::UserJoined { .. }
+                | CanvasEvents::UserLeft { .. }
+                | CanvasEvents::UserAccessLevelChanged { .. }
+                | CanvasEvents::CanvasStateChanged { .. }
+                | CanvasEvents::CanvasSettingsChanged { .. }
+                | CanvasEvents::PaletteChanged { .. }
+                | CanvasEvents::CanvasLogHeader { .. }
+                | CanvasEvents::UserCaughtUp { .. }
+                | CanvasEvents::ContributorSeen { .. }
+                | CanvasEvents::ServerNotice { .. }
+                | CanvasEvents::Ack { .. }
+                | CanvasEvents::Nack { .. }
+                | CanvasEvents::ServerHello { .. }
+                | CanvasEvents::InitialStateChunk { .. }
+                | CanvasEvents::TimeSyncResponse { .. }
+                | CanvasEvents::SaveStateChanged { .. }
+        )
+    }
+
+    ///
+    /// Validates if user has the permission to send the event
+    ///
+    fn validate_permissions(canvas: &CanvasInstance, user_id: &UserId) -> bool {
+        // expired temporary access is denied right away, the CanvasStore sweep only catches up later
+        let now = canvas.clock.now_ms();
+        canvas.inner.access_level(user_id, now).can_write_in(
+            &canvas.inner.state,
+            canvas.inner.settings.legacy_voice_behavior,
+        )
+    }
+
+    ///
+    /// Shape changed by the event, selections do not change a shape
+    ///
+    fn changed_shape_id(event: &CanvasEvents) -> Option<&str> {
+        match event {
+            CanvasEvents::ShapeAdded { shape, .. } => Some(shape.get_id()),
+            CanvasEvents::ShapeUpdated { shape, .. } => shape.get("id").and_then(|id| id.as_str()),
+            CanvasEvents::ShapeRemoved { shapeId, .. }
+            | CanvasEvents::ShapeZChanged { shapeId, .. } => Some(shapeId),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Creator of the shape if ownership is enforced and the user may not change it
+    /// Owners and moderators may change every shape, shapes of logs without creators are not protected
+    ///
+    fn foreign_shape_creator<'a>(
+        canvas: &'a CanvasInstance,
+        user_id: &UserId,
+        shape_id: &str,
+    ) -> Option<&'a UserId> {
+        if !canvas.inner.settings.shape_ownership_enforced {
+            return None;
+        }
+        let now = canvas.clock.now_ms();
+        if matches!(
+            canvas.inner.access_level(user_id, now),
+            AccessLevel::Owner | AccessLevel::Moderate
+        ) {
+            return None;
+        }
+        canvas
+            .shape_creators
+            .get(shape_id)
+            .filter(|creator| *creator != user_id)
+    }
+
+    ///
+    /// Type of the added shape if the canvas does not allow it for the user
+    /// Covers previews and the commit of a temporary shape, changes of existing shapes are always allowed
+    ///
+    fn disallowed_shape_type(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        event: &CanvasEvents,
+    ) -> Option<ShapeType> {
+        let CanvasEvents::ShapeAdded { shape, .. } = event else {
+            return None;
+        };
+        let settings = &canvas.inner.settings;
+        let shape_type = shape.shape_type();
+        if settings.allowed_shape_types.is_empty()
+            || settings.allowed_shape_types.contains(&shape_type)
+        {
+            return None;
+        }
+        let now = canvas.clock.now_ms();
+        let bypassed = settings.moderators_bypass_shape_types
+            && matches!(
+                canvas.inner.access_level(user_id, now),
+                AccessLevel::Owner | AccessLevel::Moderate
+            );
+        (!bypassed).then_some(shape_type)
+    }
+
+    ///
+    /// Selections of shapes the user may not change are only for looking at them
+    /// Clients lock shapes selected by others, such a selection must not block the creator
+    /// The sending client keeps its local selection, nothing is persisted or broadcast
+    ///
+    fn is_viewing_selection(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        event: &CanvasEvents,
+    ) -> bool {
+        match event {
+            CanvasEvents::ShapeSelected { shapeId, .. } => {
+                Self::foreign_shape_creator(canvas, user_id, shapeId).is_some()
+            }
+            // only selections that were broadcast have to be released
+            CanvasEvents::ShapeDeselected { shapeId, .. } => {
+                canvas.inner.settings.shape_ownership_enforced
+                    && !canvas
+                        .selected_shapes
+                        .get(session_id)
+                        .is_some_and(|selected| selected.contains(shapeId))
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// Releases selections of shapes their sessions may not change, once ownership is enforced
+    ///
+    fn release_foreign_selections(canvas: &mut CanvasInstance) {
+        let mut released = Vec::new();
+        for (user_id, sessions) in &canvas.users {
+            for session_id in sessions.keys() {
+                let Some(selected) = canvas.selected_shapes.get(session_id) else {
+                    continue;
+                };
+                for shape_id in selected {
+                    if Self::foreign_shape_creator(canvas, user_id, shape_id).is_some() {
+                        released.push((session_id.clone(), shape_id.clone()));
+                    }
+                }
+            }
+        }
+
+        for (session_id, shape_id) in released {
+            if let Some(selected) = canvas.selected_shapes.get_mut(&session_id) {
+                selected.remove(&shape_id);
+            }
+            let event = CanvasEvents::ShapeDeselected {
+                origin: session_id,
+                shapeId: shape_id,
+                timestamp: canvas.stamps.stamp_secs(),
+            };
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, None, event);
+        }
+    }
+
+    ///
+    /// Recorded name of the contributor, otherwise the name announced by its last UserJoined event
+    /// The id if the user neither contributed nor joined since the last compaction
+    ///
+    fn username_of(canvas: &CanvasInstance, user_id: &UserId) -> String {
+        if let Some(username) = canvas.contributors.name(user_id) {
+            return username.to_string();
+        }
+        canvas
+            .event_log
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                CanvasEvents::UserJoined {
+                    userId, username, ..
+                } if userId == user_id => Some(username.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| user_id.clone())
+    }
+
+    ///
+    /// Persists the name of the user before its first contribution under that name
+    /// Temporary shapes are never persisted, see persist_event, so they never make a contributor
+    ///
+    fn record_contributor(canvas: &mut CanvasInstance, user_id: &UserId, event: &CanvasEvents) {
+        let persisted = match event {
+            CanvasEvents::ShapeAdded { shape, .. } => !shape.is_temporary(),
+            CanvasEvents::ShapeRemoved { shapeId, .. } => !canvas.temp_shapes.contains_key(shapeId),
+            _ => true,
+        };
+        if !persisted || !Contributors::is_contribution(event) {
+            return;
+        }
+
+        let username = match canvas.usernames.get(user_id) {
+            Some(username) => username.clone(),
+            None => Self::username_of(canvas, user_id),
+        };
+        let now = canvas.clock.now_secs();
+        if let Some(record) = canvas.contributors.record(user_id, &username, now) {
+            Self::persist_system_event(canvas, &record);
+        }
+    }
+
+    ///
+    /// Simplifies persisted paths, covers both paths added as non temporary and committed strokes
+    /// The sending client keeps its full resolution copy, it is skipped when broadcasting
+    ///
+    pub(crate) fn simplify_paths(event: &mut CanvasEvents, epsilon: f64) {
+        if let CanvasEvents::ShapeAdded {
+            shape:
+                Shape::Path {
+                    temporary: false,
+                    points,
+                    ..
+                },
+            ..
+        } = event
+        {
+            *points = path::simplify(points, epsilon);
+        }
+    }
+
+    ///
+    /// Snaps persisted shapes to the grid of the canvas, temporary shapes and their updates are left alone
+    /// Returns true if the event changed, the sending client then needs the snapped version as well
+    ///
+    fn snap_to_grid(canvas: &CanvasInstance, event: &mut CanvasEvents) -> bool {
+        let Some(grid) = canvas.inner.settings.snap_grid() else {
+            return false;
+        };
+        match event {
+            CanvasEvents::ShapeAdded { shape, .. } => geometry::snap_shape(shape, grid),
+            CanvasEvents::ShapeUpdated { shape, .. } => {
+                let temporary = shape
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .is_some_and(|id| canvas.temp_shapes.contains_key(id));
+                !temporary && geometry::snap_partial_shape(shape, grid)
+            }
+            _ => false,
+        }
+    }
+
+    /// Quotas tracked by the canvas instance, members are tracked by the CanvasStore
+    fn instance_usage(canvas: &CanvasInstance) -> [(QuotaKind, u64); 2] {
+        [
+            (QuotaKind::Shapes, canvas.shapes.len() as u64),
+            (QuotaKind::LogBytes, canvas.log_bytes),
+        ]
+    }
+
+    ///
+    /// Warns owners and moderators once a quota crosses its warning threshold
+    /// The warning is persisted by the CanvasStore, nothing is rejected
+    ///
+    fn check_quotas(
+        canvas: &mut CanvasInstance,
+        limits: &QuotaLimits,
+        record_quota_warning_recipient: &Recipient<RecordQuotaWarningMessage>,
+    ) {
+        for (kind, usage) in Self::instance_usage(canvas) {
+            if !canvas.quota_warnings.check(kind, usage, limits) {
+                continue;
+            }
+
+            let usage = limits.usage(kind, usage);
+            Self::notify_moderators(
+                canvas,
+                CanvasEvents::notice(
+                    canvas.clock.now_secs(),
+                    NoticeLevel::Warning,
+                    usage.message(),
+                ),
+            );
+            record_quota_warning_recipient.do_send(RecordQuotaWarningMessage {
+                canvas_id: canvas.inner.id.clone(),
+                usage,
+            });
+        }
+    }
+
+    /// Usage of canvases that are not loaded is read from the eventlog
+    /// The server loop answers those from a blocking task instead, see Command::Query
+    fn quota_usage(&self, canvas_id: &CanvasId) -> Vec<QuotaUsage> {
+        let Some(canvas) = self.canvases.get(canvas_id) else {
+            return Self::log_quota_usage(canvas_id, &self.quota_limits);
+        };
+        Self::instance_usage(canvas)
+            .into_iter()
+            .map(|(kind, usage)| self.quota_limits.usage(kind, usage))
+            .collect()
+    }
+
+    /// Replays the eventlog of a canvas that is not loaded, blocks on the file
+    fn log_quota_usage(canvas_id: &CanvasId, quota_limits: &QuotaLimits) -> Vec<QuotaUsage> {
+        let path = canvas_log_path(canvas_id);
+        let shapes = replay::replay_log(&path, None)
+            .map(|replay| replay.state.shapes.len() as u64)
+            .unwrap_or_default();
+        let log_bytes = std::fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or_default();
+        [
+            (QuotaKind::Shapes, shapes),
+            (QuotaKind::LogBytes, log_bytes),
+        ]
+        .into_iter()
+        .map(|(kind, usage)| quota_limits.usage(kind, usage))
+        .collect()
+    }
+
+    /// Read receipts of canvases that are not loaded are read from the eventlog
+    fn read_state(&self, canvas_id: &CanvasId) -> ReadState {
+        let now = self.clock.now_secs();
+        if let Some(canvas) = self.canvases.get(canvas_id) {
+            return canvas.receipts.read_state(canvas.users.keys(), now);
+        }
+
+        // a canvas nobody connected to has no eventlog yet
+        let mut receipts = ReadReceipts::default();
+        if let Ok(event_log) = EventLogPersistenceJson::open(&canvas_log_path(canvas_id)) {
+            // lines that fail to deserialize still count towards the sequence number
+            for (index, line) in event_log.stream_lines::<CanvasEvents>().enumerate() {
+                match line {
+                    Ok(Ok(event)) => receipts.apply(index as u64 + 1, &event),
+                    Ok(Err(_)) => (),
+                    Err(_) => break,
+                }
+            }
+        }
+        receipts.read_state([], now)
+    }
+
+    fn handle_message(
+        &mut self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        session_id: WSSessionId,
+        op_id: Option<String>,
+        mut event: CanvasEvents,
+    ) {
+        let Some(canvas) = self.canvases.get_mut(&canvas_id) else {
+            return;
+        };
+        let now = canvas.clock.now_secs();
+
+        if !Self::message_allowed(&event) {
+            println!("User {user_id} tried to send system message");
+            let rejection =
+                Self::rejection(now, op_id, NoticeLevel::Error, MessageKey::EventNotAllowed);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        if let Some(flag) = features::gating_flag(&event).filter(|flag| {
+            !canvas
+                .session_flags
+                .get(&session_id)
+                .is_some_and(|flags| flags.is_enabled(flag))
+        }) {
+            let message = Message::new(MessageKey::EventFeatureDisabled).param("flag", flag);
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // every session may sync its clock, even without write access
+        if let CanvasEvents::TimeSyncRequest { clientTime } = event {
+            Self::answer_time_sync(canvas, &user_id, &session_id, clientTime);
+            return;
+        }
+
+        // any member may make sure the canvas is saved, writing is not required
+        if let CanvasEvents::FlushRequest { .. } = event {
+            Self::answer_flush_request(canvas, &self.flush_policy, &user_id, &session_id, op_id);
+            return;
+        }
+
+        // any member may choose which part of the canvas it receives
+        if let CanvasEvents::ViewportChanged { bounds, .. } = event {
+            Self::change_viewport(canvas, &user_id, &session_id, op_id, bounds);
+            return;
+        }
+
+        // any member may read the resolved comments, like the open ones of the initial state
+        if let CanvasEvents::ResolvedCommentsRequest { shapeId, .. } = &event {
+            let events = canvas.comments.sync_events(
+                &session_id,
+                shapeId.as_deref(),
+                CommentStatus::Resolved,
+            );
+            Self::send_to_session(canvas, &user_id, &session_id, &events);
+            return;
+        }
+
+        // viewers keep their viewport and clock sync, only changes wait for the maintenance to end
+        if self.maintenance.is_enabled() {
+            let rejection = Self::rejection(
+                now,
+                op_id,
+                NoticeLevel::Warning,
+                self.maintenance.rejection(),
+            );
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // the disk can't keep up, changes wait until the backlog drained
+        Self::check_backpressure(canvas, &self.flush_policy.backpressure);
+        if !Self::is_ephemeral(canvas, &event)
+            && self.flush_policy.backpressure.refuses(
+                canvas.throttled,
+                &Self::backlog(canvas),
+                &canvas.inner.access_level(&user_id, canvas.clock.now_ms()),
+            )
+        {
+            canvas
+                .diagnostics
+                .record_refused_under_pressure(canvas.clock.now_ms());
+            let rejection = Self::rejection(
+                now,
+                op_id,
+                NoticeLevel::Warning,
+                MessageKey::EventServerPressure,
+            );
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        if let Err(rejection) = validation::validate_event(&event, &self.shape_limits) {
+            println!("Dropped event of {user_id} in {canvas_id}: {rejection:?}");
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, rejection.message());
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // shapes drawn before the palette was enforced keep their colors until they are recolored
+        if let Some(color) = canvas
+            .inner
+            .settings
+            .enforce_palette
+            .then(|| palette::off_palette_color(&canvas.inner.settings.palette, &event))
+            .flatten()
+        {
+            let message = Message::new(MessageKey::EventColorOffPalette).param("color", color);
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // commenting does not depend on the state of the canvas, it has its own level
+        if comments::is_comment_event(&event) {
+            Self::handle_comment(
+                canvas,
+                &self.comment_level,
+                user_id,
+                session_id,
+                op_id,
+                event,
+            );
+            return;
+        }
+
+        if !Self::validate_permissions(canvas, &user_id) {
+            let rejection = Self::rejection(
+                now,
+                op_id,
+                NoticeLevel::Warning,
+                MessageKey::EventPermissionDenied,
+            );
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        if let Some(shape_type) = Self::disallowed_shape_type(canvas, &user_id, &event) {
+            let allowed = canvas
+                .inner
+                .settings
+                .allowed_shape_types
+                .iter()
+                .map(ShapeType::name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            let message = Message::new(MessageKey::EventShapeTypeNotAllowed)
+                .param("type", shape_type.name())
+                .param("allowed", allowed);
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // the server is the authority on who created or changed a shape
+        if let CanvasEvents::ShapeAdded { userId, .. }
+        | CanvasEvents::ShapeUpdated { userId, .. }
+        | CanvasEvents::ShapeZChanged { userId, .. } = &mut event
+        {
+            *userId = Some(user_id.clone());
+        }
+
+        if let Some(creator) = Self::changed_shape_id(&event)
+            .and_then(|shape_id| Self::foreign_shape_creator(canvas, &user_id, shape_id))
+        {
+            let message = Message::new(MessageKey::EventShapeNotOwned)
+                .param("owner", Self::username_of(canvas, creator));
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        // like temporary shapes, viewing selections are never acknowledged
+        if Self::is_viewing_selection(canvas, &user_id, &session_id, &event) {
+            return;
+        }
+
+        if let Some(op_id) = op_id.as_ref() {
+            // retried by the client, e.g. after the acknowledgement got lost on a flaky connection
+            if canvas.applied_op_ids.contains(op_id) {
+                Self::notify_session(
+                    canvas,
+                    &user_id,
+                    &session_id,
+                    CanvasEvents::duplicate_ack(now, op_id.clone()),
+                );
+                return;
+            }
+        }
+
+        // the event is never persisted, an ambiguous id would corrupt the eventlog
+        if let Some(shape_id) = Self::colliding_shape_id(canvas, &session_id, &event) {
+            let message = Message::new(MessageKey::EventShapeIdTaken).param("id", shape_id);
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message)
+                .with_suggested_id(Self::free_shape_id(canvas));
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+        if let Some(message) =
+            Self::selection_rejection(canvas, &session_id, &event, &self.shape_limits)
+        {
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, message);
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+        if let CanvasEvents::ShapeAdded { shape, .. } = &event {
+            if shape.is_temporary() {
+                // a session redrawing its preview keeps the id, only new previews count
+                if !canvas.temp_shapes.contains_key(shape.get_id()) {
+                    Self::evict_temp_shapes(canvas, &session_id, &self.shape_limits);
+                }
+                let temp_shape = TempShape {
+                    session_id: session_id.clone(),
+                    added_at: canvas.clock.now_ms(),
+                };
+                canvas
+                    .temp_shapes
+                    .insert(shape.get_id().to_string(), temp_shape);
+            }
+        }
+
+        // accepted events carry the server stamp in seconds like its own events, client clocks drift and step back
+        event.restamp(canvas.stamps.stamp_secs());
+        Self::simplify_paths(&mut event, self.shape_limits.path_simplify_epsilon);
+        let snapped = Self::snap_to_grid(canvas, &mut event);
+        Self::record_contributor(canvas, &user_id, &event);
+        // a snapped shape is echoed to its sender, otherwise the sender would keep its own geometry
+        let skip_session = (!snapped).then_some(session_id.clone());
+
+        let window = self.flush_policy.update_coalesce_window;
+        let coalesced = Self::is_coalesced(canvas, window, &event);
+        Self::persist_updates_before(canvas, &session_id, &event, coalesced);
+        if coalesced {
+            // broadcast right away so viewers see a smooth drag, acknowledged once the window is persisted
+            if let Some(op_id) = op_id.as_ref() {
+                canvas.applied_op_ids.insert(op_id.clone());
+            }
+            Self::send_event(canvas, skip_session, &event);
+            Self::track_provenance(canvas, self.conflict_window, &event);
+            let expired = canvas.pending_updates.push(
+                canvas.clock.now_ms(),
+                window,
+                &user_id,
+                &session_id,
+                op_id,
+                event,
+            );
+            Self::persist_updates(canvas, expired.into_iter().collect());
+            Self::check_diagnostics(canvas, &self.diagnostics_alarm);
+            return;
+        }
+
+        let seq = match Self::persist_event(canvas, &event) {
+            Ok(seq) => seq,
+            Err(_) => {
+                // the client still shows the change, only a Nack tells it the change is lost
+                let rejection = Self::rejection(
+                    now,
+                    op_id,
+                    NoticeLevel::Error,
+                    MessageKey::PersistenceFailed,
+                );
+                Self::reject(canvas, &user_id, &session_id, rejection);
+                return;
+            }
+        };
+
+        if let Some(op_id) = op_id {
+            canvas.applied_op_ids.insert(op_id.clone());
+            // temporary shapes are never persisted, so they are never acknowledged
+            if let Some(seq) = seq {
+                Self::notify_session(
+                    canvas,
+                    &user_id,
+                    &session_id,
+                    CanvasEvents::ack(now, op_id, seq),
+                );
+            }
+        }
+
+        Self::track_selected_shapes(canvas, &session_id, &event);
+        Self::track_shape_creators(&mut canvas.shape_creators, &event);
+        Self::track_provenance(canvas, self.conflict_window, &event);
+        let resolved = canvas.comments.resolved_by_removal(&event);
+        Self::broadcast_event(canvas, skip_session, event);
+        Self::resolve_comments(canvas, &user_id, &session_id, resolved);
+        Self::check_quotas(
+            canvas,
+            &self.quota_limits,
+            &self.record_quota_warning_recipient,
+        );
+        Self::check_diagnostics(canvas, &self.diagnostics_alarm);
+    }
+
+    ///
+    /// Applies a comment event of a client, comment events are persisted and broadcast but never part of event_log
+    /// The sending session already shows the change, it only receives the acknowledgement
+    ///
+    fn handle_comment(
+        canvas: &mut CanvasInstance,
+        comment_level: &AccessLevel,
+        user_id: UserId,
+        session_id: WSSessionId,
+        op_id: Option<String>,
+        mut event: CanvasEvents,
+    ) {
+        let now = canvas.clock.now_secs();
+        let level = canvas.inner.access_level(&user_id, canvas.clock.now_ms());
+        if let Err(rejection) =
+            canvas
+                .comments
+                .check(&mut event, &user_id, &level, comment_level, &canvas.shapes)
+        {
+            println!(
+                "Dropped comment of {user_id} in {}: {rejection:?}",
+                canvas.inner.id
+            );
+            let rejection = Self::rejection(now, op_id, NoticeLevel::Warning, rejection.message());
+            Self::reject(canvas, &user_id, &session_id, rejection);
+            return;
+        }
+
+        if let Some(op_id) = op_id.as_ref() {
+            if canvas.applied_op_ids.contains(op_id) {
+                Self::notify_session(
+                    canvas,
+                    &user_id,
+                    &session_id,
+                    CanvasEvents::duplicate_ack(now, op_id.clone()),
+                );
+                return;
+            }
+        }
+
+        // the server is the authority on who commented
+        if let CanvasEvents::CommentAdded { userId, .. }
+        | CanvasEvents::CommentResolved { userId, .. }
+        | CanvasEvents::CommentReopened { userId, .. }
+        | CanvasEvents::CommentDeleted { userId, .. } = &mut event
+        {
+            *userId = Some(user_id.clone());
+        }
+        // created_at of a comment is the server stamp in seconds, not the clock of the client
+        event.restamp(canvas.stamps.stamp_secs());
+
+        let seq = match Self::persist_event(canvas, &event) {
+            Ok(seq) => seq,
+            Err(_) => {
+                let rejection = Self::rejection(
+                    now,
+                    op_id,
+                    NoticeLevel::Error,
+                    MessageKey::PersistenceFailed,
+                );
+                Self::reject(canvas, &user_id, &session_id, rejection);
+                return;
+            }
+        };
+        if let (Some(op_id), Some(seq)) = (op_id, seq) {
+            canvas.applied_op_ids.insert(op_id.clone());
+            Self::notify_session(
+                canvas,
+                &user_id,
+                &session_id,
+                CanvasEvents::ack(now, op_id, seq),
+            );
+        }
+
+        canvas.comments.apply(&event);
+        Self::send_event(canvas, Some(session_id), &event);
+    }
+
+    /// Resolves the open comments of removed shapes on behalf of the user removing them
+    fn resolve_comments(
+        canvas: &mut CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        comment_ids: Vec<String>,
+    ) {
+        for comment_id in comment_ids {
+            let event = CanvasEvents::CommentResolved {
+                origin: session_id.clone(),
+                timestamp: canvas.stamps.stamp_secs(),
+                commentId: comment_id,
+                userId: Some(user_id.clone()),
+            };
+            Self::persist_system_event(canvas, &event);
+            canvas.comments.apply(&event);
+            Self::send_event(canvas, None, &event);
+        }
+    }
+
+    /// Sends events to a single session, redacted if the session reads an anonymized canvas
+    fn send_to_session(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        events: &[CanvasEvents],
+    ) {
+        let Some(tx) = canvas
+            .users
+            .get(user_id)
+            .and_then(|sessions| sessions.get(session_id))
+        else {
+            return;
+        };
+        let settings = &canvas.inner.settings;
+        let redacted = redaction::is_redacted_for(
+            &canvas.inner.access_level(user_id, canvas.clock.now_ms()),
+            settings.anonymize_for_readers,
+        );
+        let salt = settings.reader_salt.as_deref().unwrap_or_default();
+        for event in events {
+            if let Some(message) = EventPayloads::new(event, salt).get(redacted) {
+                let _ = tx.send(SessionMessage::Text(message.clone()));
+            }
+        }
+    }
+
+    /// Sends the server times to the requesting session, requests above the cap are dropped
+    fn answer_time_sync(
+        canvas: &mut CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        client_time: u64,
+    ) {
+        let server_receive_time = canvas.clock.now_ms();
+
+        let requests = canvas.time_syncs.entry(session_id.clone()).or_default();
+        while requests
+            .front()
+            .is_some_and(|time| *time + TIME_SYNC_WINDOW_MS <= server_receive_time)
+        {
+            requests.pop_front();
+        }
+        if requests.len() >= MAX_TIME_SYNCS_PER_WINDOW {
+            println!("Dropped time sync of {user_id}, too many requests");
+            return;
+        }
+        requests.push_back(server_receive_time);
+
+        let response = CanvasEvents::TimeSyncResponse {
+            clientTime: client_time,
+            serverReceiveTime: server_receive_time,
+            serverSendTime: canvas.clock.now_ms(),
+        };
+        Self::notify_session(canvas, user_id, session_id, response);
+    }
+
+    /// Moves the viewport of the session and sends the shapes that became visible since they were skipped
+    fn change_viewport(
+        canvas: &mut CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        op_id: Option<String>,
+        bounds: Bounds,
+    ) {
+        if canvas.live_shapes.is_none() {
+            // the buffered updates were broadcast already, the folded shapes have to include them
+            Self::persist_all_updates(canvas);
+            canvas.live_shapes = Some(LiveShapes::from_log(&canvas.event_log));
+        }
+
+        let now = canvas.clock.now_ms();
+        let viewport = canvas.viewports.entry(session_id.clone()).or_default();
+        if !viewport.change(now, bounds) {
+            println!("Dropped viewport of {user_id}, too many changes");
+            let rejection = Self::rejection(
+                canvas.clock.now_secs(),
+                op_id,
+                NoticeLevel::Warning,
+                MessageKey::EventViewportRateLimited,
+            );
+            Self::reject(canvas, user_id, session_id, rejection);
+            return;
+        }
+
+        let Some(live_shapes) = canvas.live_shapes.as_ref() else {
+            return;
+        };
+        match viewport.catch_up(live_shapes) {
+            CatchUp::FullResync => {
+                Self::persist_all_updates(canvas);
+                Self::send_initial_state(canvas, user_id.clone(), session_id);
+            }
+            CatchUp::Shapes(shape_ids) => {
+                Self::send_caught_up_shapes(canvas, user_id, session_id, &shape_ids)
+            }
+        }
+    }
+
+    /// Sends the current state of shapes the session skipped, as if they were added just now
+    fn send_caught_up_shapes(
+        canvas: &CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        shape_ids: &[String],
+    ) {
+        let (Some(tx), Some(live_shapes)) = (
+            canvas
+                .users
+                .get(user_id)
+                .and_then(|sessions| sessions.get(session_id)),
+            canvas.live_shapes.as_ref(),
+        ) else {
+            return;
+        };
+        let now = canvas.clock.now_ms();
+        let settings = &canvas.inner.settings;
+        let redacted = redaction::is_redacted_for(
+            &canvas.inner.access_level(user_id, now),
+            settings.anonymize_for_readers,
+        );
+        let salt = settings.reader_salt.as_deref().unwrap_or_default();
+
+        let timestamp = canvas.clock.now_secs();
+        for shape_id in shape_ids {
+            let Some((shape, z)) = live_shapes.get(shape_id) else {
+                continue;
+            };
+            let Ok(shape) = Shape::deserialize(shape) else {
+                continue;
+            };
+            let mut events = vec![CanvasEvents::ShapeAdded {
+                origin: session_id.clone(),
+                timestamp,
+                shape,
+                userId: canvas.shape_creators.get(shape_id).cloned(),
+            }];
+            if let Some(z) = z {
+                events.push(CanvasEvents::ShapeZChanged {
+                    origin: session_id.clone(),
+                    timestamp,
+                    shapeId: shape_id.clone(),
+                    z: z.clone(),
+                    userId: None,
+                });
+            }
+            for event in &events {
+                if let Some(message) = EventPayloads::new(event, salt).get(redacted) {
+                    let _ = tx.send(SessionMessage::Text(message.clone()));
+                }
+            }
+        }
+    }
+
+    ///
+    /// Id of a shape added under the id of another shape
+    /// Persisted shapes are never added again, temporary shapes only by the session drawing them
+    /// The session may update its preview or commit it by adding the shape as non temporary
+    ///
+    fn colliding_shape_id<'a>(
+        canvas: &CanvasInstance,
+        session_id: &WSSessionId,
+        event: &'a CanvasEvents,
+    ) -> Option<&'a str> {
+        let CanvasEvents::ShapeAdded { shape, .. } = event else {
+            return None;
+        };
+        let shape_id = shape.get_id();
+        let taken = canvas.shapes.contains(shape_id)
+            || canvas
+                .temp_shapes
+                .get(shape_id)
+                .is_some_and(|temp_shape| &temp_shape.session_id != session_id);
+        taken.then_some(shape_id)
+    }
+
+    /// Shape id not used on the canvas, suggested to clients whose id collided
+    fn free_shape_id(canvas: &CanvasInstance) -> String {
+        loop {
+            let shape_id = nanoid::nanoid!();
+            if !canvas.shapes.contains(&shape_id) && !canvas.temp_shapes.contains_key(&shape_id) {
+                return shape_id;
+            }
+        }
+    }
+
+    ///
+    /// Selections must name a persisted shape and stay within max_selected_shapes of the session
+    /// Nothing validated the ids before, every selection was kept until the session closed
+    ///
+    fn selection_rejection(
+        canvas: &CanvasInstance,
+        session_id: &WSSessionId,
+        event: &CanvasEvents,
+        limits: &ShapeLimits,
+    ) -> Option<Message> {
+        let CanvasEvents::ShapeSelected { shapeId, .. } = event else {
+            return None;
+        };
+        if !canvas.shapes.contains(shapeId) {
+            return Some(Message::new(MessageKey::EventSelectionShapeUnknown).param("id", shapeId));
+        }
+        let selected = canvas.selected_shapes.get(session_id);
+        if selected.is_some_and(|selected| selected.contains(shapeId)) {
+            return None;
+        }
+        if selected.map_or(0, HashSet::len) >= limits.max_selected_shapes {
+            return Some(
+                Message::new(MessageKey::EventSelectionLimit)
+                    .param("max", limits.max_selected_shapes),
+            );
+        }
+        None
+    }
+
+    ///
+    /// Makes room for a new temporary shape of the session, within the limits of the session and the canvas
+    /// The oldest temporary shape is removed for everyone, its session included, like a stroke that was cancelled
+    ///
+    fn evict_temp_shapes(
+        canvas: &mut CanvasInstance,
+        session_id: &WSSessionId,
+        limits: &ShapeLimits,
+    ) {
+        loop {
+            let of_session = canvas
+                .temp_shapes
+                .values()
+                .filter(|temp_shape| &temp_shape.session_id == session_id)
+                .count();
+            let evicted = if of_session >= limits.max_temp_shapes_per_session {
+                Self::oldest_temp_shape(canvas, Some(session_id))
+            } else if canvas.temp_shapes.len() >= limits.max_temp_shapes_per_canvas {
+                Self::oldest_temp_shape(canvas, None)
+            } else {
+                return;
+            };
+            let Some(shape_id) = evicted else {
+                return;
+            };
+            let Some(temp_shape) = canvas.temp_shapes.remove(&shape_id) else {
+                return;
+            };
+
+            let event = CanvasEvents::ShapeRemoved {
+                origin: temp_shape.session_id,
+                timestamp: canvas.stamps.stamp_secs(),
+                shapeId: shape_id,
+            };
+            Self::track_shape_creators(&mut canvas.shape_creators, &event);
+            canvas
+                .diagnostics
+                .record_temp_shape_evicted(canvas.clock.now_ms());
+            Self::broadcast_event(canvas, None, event);
+        }
+    }
+
+    /// Temporary shape drawn first, of the session if given
+    fn oldest_temp_shape(
+        canvas: &CanvasInstance,
+        session_id: Option<&WSSessionId>,
+    ) -> Option<String> {
+        canvas
+            .temp_shapes
+            .iter()
+            .filter(|(_, temp_shape)| session_id.is_none_or(|id| &temp_shape.session_id == id))
+            .min_by(|(a_id, a), (b_id, b)| a.added_at.cmp(&b.added_at).then_with(|| a_id.cmp(b_id)))
+            .map(|(shape_id, _)| shape_id.clone())
+    }
+
+    ///
+    /// Drops selections of shapes that are gone, e.g. removed by another session or lost with a failed write
+    /// Every session is told, the selecting one included, runs with the flush check of the run loop
+    ///
+    fn sweep_selections(canvas: &mut CanvasInstance) {
+        let mut stale = Vec::new();
+        for (session_id, selected) in canvas.selected_shapes.iter_mut() {
+            selected.retain(|shape_id| {
+                let live = canvas.shapes.contains(shape_id);
+                if !live {
+                    stale.push((session_id.clone(), shape_id.clone()));
+                }
+                live
+            });
+        }
+        if stale.is_empty() {
+            return;
+        }
+
+        canvas
+            .diagnostics
+            .record_selections_swept(canvas.clock.now_ms(), stale.len());
+        for (session_id, shape_id) in stale {
+            let event = CanvasEvents::ShapeDeselected {
+                origin: session_id,
+                shapeId: shape_id,
+                timestamp: canvas.stamps.stamp_secs(),
+            };
+            Self::persist_system_event(canvas, &event);
+            Self::broadcast_event(canvas, None, event);
+        }
+    }
+
+    /// Sends the rejection of a client event to its session, counted in the diagnostics
+    fn reject(
+        canvas: &mut CanvasInstance,
+        user_id: &UserId,
+        session_id: &WSSessionId,
+        rejection: CanvasEvents,
+    ) {
+        canvas.diagnostics.record_rejected(canvas.clock.now_ms());
+        Self::notify_session(canvas, user_id, session_id, rejection);
+    }
+
+    /// Time since the oldest event not yet synced to disk
+    fn save_lag_ms(canvas: &CanvasInstance) -> u64 {
+        canvas.pending_since.map_or(0, |pending_since| {
+            canvas.clock.now_ms().saturating_sub(pending_since)
+        })
+    }
+
+    /// Tells owners and moderators once a diagnostics threshold is crossed, at most every ALARM_INTERVAL
+    fn check_diagnostics(canvas: &mut CanvasInstance, thresholds: &DiagnosticsAlarm) {
+        let save_lag_ms = Self::save_lag_ms(canvas);
+        let Some(report) = canvas
+            .diagnostics
+            .alarm(thresholds, canvas.clock.now_ms(), save_lag_ms)
+        else {
+            return;
+        };
+
+        let slowest_session = report.slow_sessions.first().map_or_else(
+            || "-".to_string(),
+            |session| diagnostics::mask_session_id(&session.session_id),
+        );
+        let message = Message::new(MessageKey::CanvasDiagnosticsAlarm)
+            .param("events_per_minute", report.events_received_per_minute)
+            .param("slowest_session", slowest_session)
+            .param("save_lag_ms", report.save_lag_ms);
+        Self::notify_moderators(
+            canvas,
+            CanvasEvents::notice(canvas.clock.now_secs(), NoticeLevel::Warning, message),
+        );
+    }
+
+    /// Clients that track their events by opId get a Nack, others a notice
+    fn rejection(
+        timestamp: u64,
+        op_id: Option<String>,
+        level: NoticeLevel,
+        message: impl Into<Message>,
+    ) -> CanvasEvents {
+        match op_id {
+            Some(op_id) => CanvasEvents::nack(timestamp, op_id, message),
+            None => CanvasEvents::notice(timestamp, level, message),
+        }
+    }
+
+    /// Checks and deserializes a raw client message, the sender is notified about dropped messages
+    fn handle_raw_message(
+        &mut self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        session_id: WSSessionId,
+        msg: Msg,
+    ) {
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            canvas.diagnostics.record_received(canvas.clock.now_ms());
+        }
+
+        let rejection = match validation::validate_message(&msg, &self.shape_limits) {
+            Ok(()) => match inbound::parse_client_event(&msg) {
+                Ok(ClientEvent { opId: op_id, event }) => {
+                    return self.handle_message(canvas_id, user_id, session_id, op_id, event)
+                }
+                Err(error) => {
+                    println!(
+                        "WARNING: rejected message of {user_id} in {canvas_id}: {} at line {} column {}: {}",
+                        error.reason, error.line, error.column, error.excerpt
+                    );
+                    let message = error.message();
+                    Self::rejection(
+                        self.clock.now_secs(),
+                        error.op_id,
+                        NoticeLevel::Warning,
+                        message,
+                    )
+                }
+            },
+            Err(rejection) => {
+                println!("Dropped message of {user_id} in {canvas_id}: {rejection:?}");
+                CanvasEvents::notice(
+                    self.clock.now_secs(),
+                    NoticeLevel::Warning,
+                    rejection.message(),
+                )
+            }
+        };
+
+        if let Some(canvas) = self.canvases.get_mut(&canvas_id) {
+            Self::reject(canvas, &user_id, &session_id, rejection);
+        }
+    }
+
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut flush_check = interval(FLUSH_CHECK_INTERVAL);
+        loop {
+            // buffered updates are persisted once their window is over, not only on the next check
+            let update_due = self.next_update_due_in();
+            let tick = pin!(flush_check.tick());
+            let update_timer = pin!(sleep_for(update_due));
+            let timers = select(tick, update_timer);
+            // the pending recv borrows the server, it is dropped before the tick is handled
+            let next = match select(pin!(self.cmd_rx.recv()), pin!(timers)).await {
+                Either::Left((cmd, _)) => Some(cmd),
+                Either::Right(_) => None,
+            };
+            let cmd = match next {
+                Some(Some(cmd)) => cmd,
+                Some(None) => break,
+                None => {
+                    self.flush_due_canvases();
+                    self.sweep_connect_attempts(Instant::now());
+                    continue;
+                }
+            };
+
+            match cmd {
+                Command::Connect {
+                    conn_tx,
+                    canvas_id,
+                    user_id,
+                    username,
+                    session_id,
+                    connection,
+                } => {
+                    self.connect(
+                        conn_tx, canvas_id, user_id, username, session_id, connection,
+                    )
+                    .await;
+                }
+
+                Command::Disconnect {
+                    canvas_id,
+                    user_id,
+                    session_id,
+                } => {
+                    self.disconnect(canvas_id, user_id, session_id);
+                }
+
+                Command::UpdateUserAccessLevel {
+                    user_id,
+                    canvas_id,
+                    access_level,
+                    expires_at,
+                } => {
+                    self.update_user_access_level(canvas_id, user_id, access_level, expires_at);
+                }
+
+                Command::UpdateCanvasState {
+                    canvas_id,
+                    state,
+                    initiator_id,
+                    version,
+                } => {
+                    self.update_canvas_state(canvas_id, state, initiator_id, version);
+                }
+
+                Command::CloseUserSessions { user_id } => {
+                    // the sessions are told to log in again
+                    self.close_user_sessions(
+                        user_id,
+                        MessageKey::SessionAuthExpired,
+                        SocketClose::AuthExpired,
+                    );
+                }
+
+                Command::AddApiToken { canvas_id, token } => {
+                    self.add_api_token(canvas_id, token);
+                }
+
+                Command::RevokeApiToken {
+                    canvas_id,
+                    token_id,
+                } => {
+                    self.revoke_api_token(canvas_id, token_id);
+                }
+
+                Command::UpdateCanvasSettings {
+                    canvas_id,
+                    initiator_id,
+                    settings,
+                    version,
+                } => {
+                    self.update_canvas_settings(canvas_id, settings, initiator_id, version);
+                }
+
+                Command::UpdateCanvasPalette {
+                    canvas_id,
+                    initiator_id,
+                    palette,
+                    enforce_palette,
+                    version,
+                } => {
+                    self.update_canvas_palette(
+                        canvas_id,
+                        palette,
+                        enforce_palette,
+                        initiator_id,
+                        version,
+                    );
+                }
+
+                Command::UpdateCanvasFeatureFlags {
+                    canvas_id,
+                    overrides,
+                    version,
+                } => {
+                    self.update_canvas_feature_flags(canvas_id, overrides, version);
+                }
+
+                Command::HandleMessage {
+                    canvas_id,
+                    user_id,
+                    session_id,
+                    msg,
+                    res_tx,
+                } => {
+                    self.handle_raw_message(canvas_id, user_id, session_id, msg);
+                    let _ = res_tx.send(()); // notify sender that message was handeled
+                }
+
+                Command::QuotaWarning { canvas_id, usage } => {
+                    if let Some(canvas) = self.canvases.get(&canvas_id) {
+                        Self::notify_moderators(
+                            canvas,
+                            CanvasEvents::notice(
+                                canvas.clock.now_secs(),
+                                NoticeLevel::Warning,
+                                usage.message(),
+                            ),
+                        );
+                    }
+                }
+
+                // replaying the eventlog of a cold canvas would stall every other canvas
+                Command::Query {
+                    canvas_id: Some(canvas_id),
+                    query: CanvasQuery::QuotaUsage,
+                    res_tx,
+                } if !self.canvases.contains_key(&canvas_id) => {
+                    let quota_limits = self.quota_limits.clone();
+                    actix_web::rt::task::spawn_blocking(move || {
+                        let usage = Self::log_quota_usage(&canvas_id, &quota_limits);
+                        let _ = res_tx.send(CanvasQueryResult::QuotaUsage(usage));
+                    });
+                }
+
+                Command::Query {
+                    canvas_id,
+                    query,
+                    res_tx,
+                } => {
+                    let _ = res_tx.send(self.answer_query(canvas_id.as_ref(), query));
+                }
+
+                Command::NotifyAll { level, message } => {
+                    for canvas in self.canvases.values() {
+                        let notice =
+                            CanvasEvents::notice(canvas.clock.now_secs(), level, message.clone());
+                        Self::notify_canvas(canvas, notice);
+                    }
+                }
+
+                Command::NotifyUsers {
+                    user_ids,
+                    level,
+                    message,
+                } => {
+                    self.notify_users(&user_ids, level, message);
+                }
+
+                Command::CloseCanvas { canvas_id } => {
+                    // dropping the instance drops the senders, which closes the sessions
+                    if let Some(mut canvas) = self.canvases.remove(&canvas_id) {
+                        // kept on disk for a restore
+                        let _ = Self::flush_canvas(&mut canvas);
+                        Self::notify_canvas(
+                            &canvas,
+                            CanvasEvents::notice(
+                                canvas.clock.now_secs(),
+                                NoticeLevel::Error,
+                                MessageKey::CanvasDeleted,
+                            ),
+                        );
+                    }
+                }
+            }
+
+            // reaching max_pending_events should not wait for the next check
+            self.flush_due_canvases();
+        }
+
+        // all handles are dropped once the http server stopped
+        for (canvas_id, mut canvas) in self.canvases.drain() {
+            let flushed = Self::flush_canvas(&mut canvas);
+            Self::notify_canvas(
+                &canvas,
+                CanvasEvents::notice(
+                    canvas.clock.now_secs(),
+                    NoticeLevel::Notice,
+                    MessageKey::ServerShutdown,
+                ),
+            );
+
+            // an unsynced eventlog may lose lines the handoff already contains
+            if flushed.is_err() {
+                continue;
+            }
+            let handoff = Self::handoff(canvas)
+                .and_then(|handoff| handoff.write(&handoff::handoff_path(&canvas_id)));
+            if let Err(e) = handoff {
+                println!("WARNING: failed to write handoff of {canvas_id}: {e}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sleeps for the delay, forever without one
+async fn sleep_for(delay: Option<Duration>) {
+    match delay {
+        Some(delay) => sleep(delay).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reduces boilerplate of setting up response channels in WebSocket handlers.
+#[derive(Debug, Clone)]
+pub struct CanvasSocketServerHandle {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+    event_bus: EventBus,
+}
+
+impl CanvasSocketServerHandle {
+    /// Handle without a running server, tests receive the commands instead
+    #[cfg(test)]
+    pub(super) fn detached() -> (Self, mpsc::UnboundedReceiver<Command>) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let event_bus = EventBus::default();
+        (Self { cmd_tx, event_bus }, cmd_rx)
+    }
+
+    /// Register client message sender and obtain connection ID.
+    pub async fn connect(
+        &self,
+        conn_tx: mpsc::UnboundedSender<SessionMessage>,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        username: String,
+        session_id: WSSessionId,
+        connection: ConnectionMeta,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Connect {
+                conn_tx,
+                canvas_id,
+                user_id,
+                username,
+                session_id,
+                connection,
+            })
+            .unwrap();
+    }
+
+    /// version is the canvas version assigned by the CanvasStore for this change
+    pub fn update_canvas_state(
+        &self,
+        canvas_id: CanvasId,
+        state: CanvasState,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::UpdateCanvasState {
+                canvas_id,
+                state,
+                initiator_id,
+                version,
+            })
+            .unwrap();
+    }
+
+    /// expires_at marks temporary access, unix timestamp in milliseconds
+    /// Fails if the chat server stopped, live sessions then keep their previous level
+    pub fn update_user_permissions(
+        &self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        access_level: AccessLevel,
+        expires_at: Option<u64>,
+    ) -> Result<(), ServerStopped> {
+        self.cmd_tx
+            .send(Command::UpdateUserAccessLevel {
+                canvas_id,
+                user_id,
+                access_level,
+                expires_at,
+            })
+            .map_err(|_| ServerStopped)
+    }
+
+    /// Broadcast message to current room.
+    pub async fn broadcast_event(
+        &self,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        session_id: WSSessionId,
+        msg: impl Into<Msg>,
+    ) {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::HandleMessage {
+                msg: msg.into(),
+                canvas_id,
+                user_id,
+                session_id,
+                res_tx,
+            })
+            .unwrap();
+
+        // unwrap: chat server does not drop our response channel
+        res_rx.await.unwrap();
+    }
+
+    pub fn update_canvas_settings(
+        &self,
+        canvas_id: CanvasId,
+        settings: CanvasSettings,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::UpdateCanvasSettings {
+                canvas_id,
+                initiator_id,
+                settings,
+                version,
+            })
+            .unwrap();
+    }
+
+    /// Sessions get the palette as PaletteChanged, enforcing it applies to the next shapes
+    pub fn update_canvas_palette(
+        &self,
+        canvas_id: CanvasId,
+        palette: Vec<PaletteColor>,
+        enforce_palette: bool,
+        initiator_id: UserId,
+        version: u64,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::UpdateCanvasPalette {
+                canvas_id,
+                initiator_id,
+                palette,
+                enforce_palette,
+                version,
+            })
+            .unwrap();
+    }
+
+    /// Resolves the flags of the connected sessions again, they get a new ServerHello
+    pub fn update_canvas_feature_flags(
+        &self,
+        canvas_id: CanvasId,
+        overrides: BTreeMap<String, bool>,
+        version: u64,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::UpdateCanvasFeatureFlags {
+                canvas_id,
+                overrides,
+                version,
+            })
+            .unwrap();
+    }
+
+    /// Warns owners and moderators connected to the canvas about a quota tracked by the CanvasStore
+    pub fn notify_quota_warning(&self, canvas_id: CanvasId, usage: QuotaUsage) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::QuotaWarning { canvas_id, usage })
+            .unwrap();
+    }
+
+    /// Closes all sessions of a deleted canvas
+    pub fn close_canvas(&self, canvas_id: CanvasId) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::CloseCanvas { canvas_id })
+            .unwrap();
+    }
+
+    /// Asks the server a read only question, canvas_id is None for queries not about a canvas
+    /// Fails if the chat server stopped before answering
+    pub async fn query(
+        &self,
+        canvas_id: Option<CanvasId>,
+        query: CanvasQuery,
+    ) -> Result<CanvasQueryResult, ServerStopped> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Query {
+                canvas_id,
+                query,
+                res_tx,
+            })
+            .map_err(|_| ServerStopped)?;
+
+        res_rx.await.map_err(|_| ServerStopped)
+    }
+
+    /// Live sessions of the user across all canvases
+    pub async fn user_sessions(&self, user_id: UserId) -> Result<Vec<UserSession>, ServerStopped> {
+        let result = self.query(None, CanvasQuery::UserSessions(user_id)).await?;
+        Ok(match result {
+            CanvasQueryResult::UserSessions(sessions) => sessions,
+            _ => Vec::new(),
+        })
+    }
+
+    /// Sends the notice to every session of every loaded canvas
+    pub fn notify_all(&self, level: NoticeLevel, message: impl Into<Message>) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::NotifyAll {
+                level,
+                message: message.into(),
+            })
+            .unwrap();
+    }
+
+    /// Sends the notice to every session of the users, users without a session are skipped
+    pub fn notify_users(
+        &self,
+        user_ids: Vec<UserId>,
+        level: NoticeLevel,
+        message: impl Into<Message>,
+    ) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::NotifyUsers {
+                user_ids,
+                level,
+                message: message.into(),
+            })
+            .unwrap();
+    }
+
+    /// Closes all sessions of the user, used once its tokens are revoked
+    pub fn close_user_sessions(&self, user_id: UserId) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::CloseUserSessions { user_id })
+            .unwrap();
+    }
+
+    /// Lets sessions of a loaded canvas authenticate with the new token
+    pub fn add_api_token(&self, canvas_id: CanvasId, token: ApiToken) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::AddApiToken { canvas_id, token })
+            .unwrap();
+    }
+
+    /// Drops the token from a loaded canvas and closes the sessions it authenticated
+    pub fn revoke_api_token(&self, canvas_id: CanvasId, token_id: TokenId) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::RevokeApiToken {
+                canvas_id,
+                token_id,
+            })
+            .unwrap();
+    }
+
+    /// Shape and eventlog usage of the canvas
+    pub async fn quota_usage(&self, canvas_id: CanvasId) -> Result<Vec<QuotaUsage>, ServerStopped> {
+        let result = self.query(Some(canvas_id), CanvasQuery::QuotaUsage).await?;
+        Ok(match result {
+            CanvasQueryResult::QuotaUsage(usage) => usage,
+            _ => Vec::new(),
+        })
+    }
+
+    /// Who has seen the latest change of the canvas
+    pub async fn read_state(&self, canvas_id: CanvasId) -> Result<ReadState, ServerStopped> {
+        let result = self.query(Some(canvas_id), CanvasQuery::ReadState).await?;
+        Ok(match result {
+            CanvasQueryResult::ReadState(read_state) => read_state,
+            _ => ReadState::default(),
+        })
+    }
+
+    /// Canvases whose eventlog is open, none are loaded by asking
+    pub async fn loaded_canvases(&self) -> Result<HashSet<CanvasId>, ServerStopped> {
+        let result = self.query(None, CanvasQuery::LoadedCanvases).await?;
+        Ok(match result {
+            CanvasQueryResult::LoadedCanvases(canvas_ids) => canvas_ids,
+            _ => HashSet::new(),
+        })
+    }
+
+    /// Live sessions on the canvas, for admins investigating abuse, empty if the canvas is not loaded
+    pub async fn canvas_sessions(
+        &self,
+        canvas_id: CanvasId,
+    ) -> Result<Vec<CanvasSession>, ServerStopped> {
+        let result = self.query(Some(canvas_id), CanvasQuery::Sessions).await?;
+        Ok(match result {
+            CanvasQueryResult::Sessions(sessions) => sessions,
+            _ => Vec::new(),
+        })
+    }
+
+    /// Notifications of the events persisted from now on, see bus.rs
+    /// The receiver loses the oldest notifications if it falls behind, dropping it unsubscribes
+    pub fn subscribe(&self) -> broadcast::Receiver<CanvasEventNotification> {
+        self.event_bus.subscribe()
+    }
+
+    /// Lines in the eventlog of the canvas, None if it is not loaded
+    pub async fn persisted_seq(&self, canvas_id: CanvasId) -> Result<Option<u64>, ServerStopped> {
+        let result = self
+            .query(Some(canvas_id), CanvasQuery::PersistedSeq)
+            .await?;
+        Ok(match result {
+            CanvasQueryResult::PersistedSeq(seq) => Some(seq),
+            _ => None,
+        })
+    }
+
+    /// Concurrent edit counters of the canvas, None if it is not loaded
+    pub async fn diagnostics(
+        &self,
+        canvas_id: CanvasId,
+    ) -> Result<Option<DiagnosticsReport>, ServerStopped> {
+        let result = self
+            .query(Some(canvas_id), CanvasQuery::Diagnostics)
+            .await?;
+        Ok(match result {
+            CanvasQueryResult::Diagnostics(report) => Some(report),
+            _ => None,
+        })
+    }
+
+    /// Live shapes of the canvas matching the filter, None if it is not loaded
+    pub async fn find_shapes(
+        &self,
+        canvas_id: CanvasId,
+        filter: ShapeFilter,
+    ) -> Result<Option<Vec<Value>>, ServerStopped> {
+        let result = self
+            .query(Some(canvas_id), CanvasQuery::FindShapes(filter))
+            .await?;
+        Ok(match result {
+            CanvasQueryResult::Shapes(shapes) => Some(shapes),
+            _ => None,
+        })
+    }
+
+    /// Recorded names of the contributors, None if the canvas is not loaded
+    pub async fn contributors(
+        &self,
+        canvas_id: CanvasId,
+    ) -> Result<Option<Contributors>, ServerStopped> {
+        let result = self
+            .query(Some(canvas_id), CanvasQuery::Contributors)
+            .await?;
+        Ok(match result {
+            CanvasQueryResult::Contributors(contributors) => Some(contributors),
+            _ => None,
+        })
+    }
+
+    /// Last change of a live shape, the outer None if the canvas is not loaded
+    pub async fn shape_provenance(
+        &self,
+        canvas_id: CanvasId,
+        shape_id: String,
+    ) -> Result<Option<Option<ShapeProvenance>>, ServerStopped> {
+        let result = self
+            .query(Some(canvas_id), CanvasQuery::ShapeProvenance { shape_id })
+            .await?;
+        Ok(match result {
+            CanvasQueryResult::ShapeProvenance(provenance) => Some(provenance),
+            _ => None,
+        })
+    }
+
+    /// Comments of the shapes, None if the canvas is not loaded
+    pub async fn comments(&self, canvas_id: CanvasId) -> Result<Option<Comments>, ServerStopped> {
+        let result = self.query(Some(canvas_id), CanvasQuery::Comments).await?;
+        Ok(match result {
+            CanvasQueryResult::Comments(comments) => Some(comments),
+            _ => None,
+        })
+    }
+
+    /// Users with at least one live session on the canvas
+    pub async fn online_users(
+        &self,
+        canvas_id: CanvasId,
+    ) -> Result<HashSet<UserId>, ServerStopped> {
+        let result = self.query(Some(canvas_id), CanvasQuery::Presence).await?;
+        Ok(match result {
+            CanvasQueryResult::Presence(users) => users,
+            _ => HashSet::new(),
+        })
+    }
+
+    /// Unregister message sender and broadcast disconnection message to current room.
+    pub fn disconnect(&self, canvas_id: CanvasId, user_id: UserId, session_id: WSSessionId) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Disconnect {
+                canvas_id,
+                user_id,
+                session_id,
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use actix::{Actor, Handler};
+
+    /// CanvasStore stand-in, tests insert their canvases directly into the server
+    struct EmptyCanvasStore;
+
+    impl Actor for EmptyCanvasStore {
+        type Context = actix::Context<Self>;
+    }
+
+    impl Handler<GetCanvasMessage> for EmptyCanvasStore {
+        type Result = Option<Canvas>;
+
+        fn handle(&mut self, _: GetCanvasMessage, _: &mut Self::Context) -> Self::Result {
+            None
+        }
+    }
+
+    impl Handler<RecordQuotaWarningMessage> for EmptyCanvasStore {
+        type Result = Result<(), crate::canvas::error::CanvasStoreError>;
+
+        fn handle(&mut self, _: RecordQuotaWarningMessage, _: &mut Self::Context) -> Self::Result {
+            Ok(())
+        }
+    }
+
+    fn test_server(limits: ConnectionLimits) -> CanvasSocketServer {
+        test_server_with_clock(limits, crate::clock::system())
+    }
+
+    fn test_server_with_clock(limits: ConnectionLimits, clock: SharedClock) -> CanvasSocketServer {
+        let store = EmptyCanvasStore.start();
+        let (mut server, _) = CanvasSocketServer::new(
+            Arc::new(store.clone().recipient()),
+            store.recipient(),
+            limits,
+            ShapeLimits::default(),
+            QuotaLimits::default(),
+            FlushPolicy::default(),
+            clock.clone(),
+        );
+
+        let log_path = std::env::temp_dir().join(format!("{}.jsonl", nanoid::nanoid!(8)));
+        let (event_log, persistence) = EventLogPersistenceJson::new(log_path.to_str().unwrap())
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+
+        server.canvases.insert(
+            "canvas".to_string(),
+            CanvasInstance {
+                users: HashMap::new(),
+                selected_shapes: HashMap::new(),
+                persistence,
+                event_log,
+                inner: Canvas {
+                    id: "canvas".to_string(),
+                    name: "Canvas".to_string(),
+                    owner_id: "owner".to_string(),
+                    state: CanvasState::Active,
+                    users: HashMap::new(),
+                    version: 1,
+                    expirations: HashMap::new(),
+                    settings: CanvasSettings::default(),
+                    tags: Vec::new(),
+                    feature_overrides: Default::default(),
+                    api_tokens: Default::default(),
+                    created_at: 0,
+                    deleted_at: None,
+                },
+                temp_shapes: HashMap::new(),
+                session_order: Vec::new(),
+                shapes: HashSet::new(),
+                shape_creators: HashMap::new(),
+                provenance: Provenance::default(),
+                log_bytes: 0,
+                comments: Comments::default(),
+                persisted_events: 0,
+                flushed_seq: 0,
+                pending_since: None,
+                degraded: false,
+                flushed_bytes: 0,
+                throttled: false,
+                flush_requests: HashMap::new(),
+                log_canvas_id: "canvas".to_string(),
+                quota_warnings: QuotaWarnings::default(),
+                receipts: ReadReceipts::default(),
+                contributors: Contributors::default(),
+                usernames: HashMap::new(),
+                applied_op_ids: RecentOpIds::default(),
+                time_syncs: HashMap::new(),
+                session_flags: HashMap::new(),
+                viewports: HashMap::new(),
+                live_shapes: None,
+                connections: HashMap::new(),
+                diagnostics: CanvasDiagnostics::default(),
+                pending_updates: UpdateBuffer::default(),
+                stamps: MonotonicStamps::new(clock.clone()),
+                event_bus: server.event_bus.clone(),
+                clock,
+            },
+        );
+        server
+    }
+
+    impl SessionMessage {
+        /// Text sent to the client, panics on a close
+        fn text(&self) -> &str {
+            match self {
+                SessionMessage::Text(text) => text,
+                SessionMessage::Close(close) => panic!("unexpected close {close:?}"),
+            }
+        }
+    }
+
+    fn notice_code(message: &SessionMessage) -> Option<String> {
+        let SessionMessage::Text(message) = message else {
+            return None;
+        };
+        match serde_json::from_str(message) {
+            Ok(CanvasEvents::ServerNotice { code, .. }) => Some(code),
+            _ => None,
+        }
+    }
+
+    async fn connect_session(
+        server: &mut CanvasSocketServer,
+        session_id: &str,
+    ) -> (
+        Result<(), SessionRejection>,
+        mpsc::UnboundedReceiver<SessionMessage>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let result = server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                "user".to_string(),
+                "username".to_string(),
+                session_id.to_string(),
+                ConnectionMeta::default(),
+            )
+            .await;
+        (result, rx)
+    }
+
+    #[actix_web::test]
+    async fn test_session_limit_refuses_new_session() {
+        let mut server = test_server(ConnectionLimits::default());
+
+        // multiple tabs below the limit are fine
+        for session in 0..5 {
+            let (result, _) = connect_session(&mut server, &format!("session{session}")).await;
+            assert_eq!(result, Ok(()));
+        }
+
+        let (result, _) = connect_session(&mut server, "session5").await;
+        assert_eq!(result, Err(SessionRejection::UserSessionLimit));
+        assert_eq!(server.canvases["canvas"].users["user"].len(), 5);
+    }
+
+    #[actix_web::test]
+    async fn test_session_limit_evicts_oldest_session() {
+        let mut server = test_server(ConnectionLimits {
+            evict_oldest_session: true,
+            ..Default::default()
+        });
+
+        let (_, mut oldest_rx) = connect_session(&mut server, "session0").await;
+        for session in 1..5 {
+            let (result, _) = connect_session(&mut server, &format!("session{session}")).await;
+            assert_eq!(result, Ok(()));
+        }
+
+        let (result, _) = connect_session(&mut server, "session5").await;
+        assert_eq!(result, Ok(()));
+
+        let sessions = &server.canvases["canvas"].users["user"];
+        assert_eq!(sessions.len(), 5);
+        assert!(!sessions.contains_key("session0"));
+
+        // evicted session receives the reason and is closed afterwards
+        let mut last_message = None;
+        while let Some(message) = oldest_rx.recv().await {
+            last_message = Some(message);
+        }
+        assert_eq!(
+            notice_code(&last_message.unwrap()).unwrap(),
+            "session.evicted"
+        );
+    }
+
+    async fn connect_from(
+        server: &mut CanvasSocketServer,
+        user_id: &str,
+        ip: &str,
+    ) -> Result<(), SessionRejection> {
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert(user_id.to_string(), AccessLevel::Write);
+        let (tx, _) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                user_id.to_string(),
+                user_id.to_string(),
+                format!("{user_id}-{ip}"),
+                ConnectionMeta {
+                    ip: Some(ip.parse().unwrap()),
+                    user_agent: None,
+                },
+            )
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_session_limit_per_ip_counts_all_users() {
+        let mut server = test_server(ConnectionLimits {
+            max_sessions_per_ip: 2,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            connect_from(&mut server, "alice", "203.0.113.9").await,
+            Ok(())
+        );
+        assert_eq!(
+            connect_from(&mut server, "bob", "203.0.113.9").await,
+            Ok(())
+        );
+        // each user is below their own limit, the address is not
+        assert_eq!(
+            connect_from(&mut server, "carol", "203.0.113.9").await,
+            Err(SessionRejection::IpSessionLimit)
+        );
+        assert_eq!(
+            connect_from(&mut server, "carol", "198.51.100.7").await,
+            Ok(())
+        );
+        assert_eq!(server.canvases["canvas"].connections.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_load_sends_notice() {
+        let mut server = test_server(ConnectionLimits::default());
+        let canvas_id = nanoid::nanoid!(8);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server
+            .connect(
+                tx,
+                canvas_id.clone(),
+                "user".to_string(),
+                "username".to_string(),
+                "session".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await;
+
+        let message = rx.recv().await.unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "canvas.load_failed");
+        // rejected session is closed
+        assert!(rx.recv().await.is_none());
+        assert!(!std::path::Path::new(&canvas_log_path(&canvas_id)).exists());
+    }
+
+    #[actix_web::test]
+    async fn test_permission_denial_sends_notice() {
+        let mut server = test_server(ConnectionLimits::default());
+        let (_, mut rx) = connect_session(&mut server, "session").await;
+        while rx.try_recv().is_ok() {} // initial state
+
+        // user has no access level on the canvas
+        let shape_added = r##"{"type":"ShapeAdded","origin":"session","timestamp":1,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":5,"y":5}}}"##;
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            shape_added.to_string(),
+        );
+        let message = rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.permission_denied");
+
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            "{not json".to_string(),
+        );
+        let message = rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.malformed");
+
+        // notices are never accepted from clients
+        let notice: Msg =
+            (&CanvasEvents::notice(0, NoticeLevel::Notice, MessageKey::ServerShutdown))
+                .try_into()
+                .unwrap();
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            notice,
+        );
+        let message = rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.not_allowed");
+        assert!(server.canvases["canvas"]
+            .event_log
+            .iter()
+            .all(|event| !matches!(event, CanvasEvents::ServerNotice { .. })));
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_fields_are_rejected_inbound_but_load_from_the_log() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = use_temp_log(&mut server);
+        let (mut rx, _other_rx) = connect_writer_sessions(&mut server).await;
+        let line = line_added("op1", "l1")
+            .replace(r#""temporary""#, r#""borderColour":"red","temporary""#);
+
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            line.clone(),
+        );
+        let message = rx.try_recv().unwrap();
+        let Ok(CanvasEvents::Nack {
+            opId,
+            code,
+            message,
+            ..
+        }) = serde_json::from_str(message.text())
+        else {
+            panic!("expected a nack, got {message:?}");
+        };
+        assert_eq!((opId.as_str(), code.as_str()), ("op1", "event.malformed"));
+        assert!(message.contains("shape.borderColour"), "{message}");
+        assert!(message.contains(r#"{"type":"ShapeAdded""#), "{message}");
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 0);
+
+        // the eventlog keeps reading lines with fields it does not know
+        let mut persisted: Value = serde_json::from_str(&line).unwrap();
+        persisted.as_object_mut().unwrap().remove("opId");
+        std::fs::write(&log_path, format!("{persisted}\n")).unwrap();
+        let (events, _) = EventLogPersistenceJson::new(&log_path)
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+        assert!(matches!(events[..], [CanvasEvents::ShapeAdded { .. }]));
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_stale_canvas_state_update_is_ignored() {
+        let mut server = test_server(ConnectionLimits::default());
+        let (_, mut rx) = connect_session(&mut server, "session").await;
+
+        server.update_canvas_state(
+            "canvas".to_string(),
+            CanvasState::Moderated,
+            "owner".to_string(),
+            3,
+        );
+        // arrives after the newer change, e.g. from a slower request
+        server.update_canvas_state(
+            "canvas".to_string(),
+            CanvasState::Active,
+            "moderator".to_string(),
+            2,
+        );
+
+        let canvas = &server.canvases["canvas"].inner;
+        assert!(matches!(canvas.state, CanvasState::Moderated));
+        assert_eq!(canvas.version, 3);
+
+        let mut state_changes = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            if let Ok(CanvasEvents::CanvasStateChanged { version, .. }) =
+                serde_json::from_str(message.text())
+            {
+                state_changes.push(version);
+            }
+        }
+        assert_eq!(state_changes, vec![3]);
+    }
+
+    /// Persists the test canvas into a known file, so tests can read the eventlog back
+    fn persist_into_temp_log(server: &mut CanvasSocketServer) -> std::path::PathBuf {
+        let log_path = std::env::temp_dir().join(format!("{}.jsonl", nanoid::nanoid!(8)));
+        let (_, persistence) = EventLogPersistenceJson::new(log_path.to_str().unwrap())
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+        server.canvases.get_mut("canvas").unwrap().persistence = persistence;
+        log_path
+    }
+
+    fn shape_added(shape: &str) -> Msg {
+        format!(r#"{{"type":"ShapeAdded","origin":"drawer","timestamp":1,"shape":{shape}}}"#)
+    }
+
+    fn received_shapes(rx: &mut mpsc::UnboundedReceiver<SessionMessage>) -> Vec<Shape> {
+        let mut shapes = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            if let Ok(CanvasEvents::ShapeAdded { shape, .. }) = serde_json::from_str(message.text())
+            {
+                shapes.push(shape);
+            }
+        }
+        shapes
+    }
+
+    /// Connects a drawing and a watching session of a writer, drains their initial state
+    async fn connect_drawer_and_viewer(
+        server: &mut CanvasSocketServer,
+    ) -> (
+        mpsc::UnboundedReceiver<SessionMessage>,
+        mpsc::UnboundedReceiver<SessionMessage>,
+    ) {
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, mut drawer_rx) = connect_session(server, "drawer").await;
+        let (_, mut viewer_rx) = connect_session(server, "viewer").await;
+        while drawer_rx.try_recv().is_ok() {}
+        while viewer_rx.try_recv().is_ok() {}
+        (drawer_rx, viewer_rx)
+    }
+
+    fn send_as_drawer(server: &mut CanvasSocketServer, msg: Msg) {
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "drawer".to_string(),
+            msg,
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_snap_enabled_canvas_snaps_shapes() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let (mut drawer_rx, mut viewer_rx) = connect_drawer_and_viewer(&mut server).await;
+        server.update_canvas_settings(
+            "canvas".to_string(),
+            CanvasSettings {
+                grid_size: Some(10),
+                snap_enabled: true,
+                ..CanvasSettings::default()
+            },
+            "owner".to_string(),
+            2,
+        );
+
+        // previews are passed through for smooth drawing
+        send_as_drawer(
+            &mut server,
+            shape_added(
+                r##"{"type":"Line","id":"l1","temporary":true,"borderColor":"#000","fillColor":"#000","from":{"x":3,"y":-6},"to":{"x":25,"y":44}}"##,
+            ),
+        );
+        let previews = received_shapes(&mut viewer_rx);
+        assert!(matches!(&previews[..], [Shape::Line { from, .. }] if from.x == 3 && from.y == -6));
+
+        // the committed shape is snapped for every session, including its sender
+        send_as_drawer(
+            &mut server,
+            shape_added(
+                r##"{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":3,"y":-6},"to":{"x":25,"y":44}}"##,
+            ),
+        );
+        let snapped = |shapes: &[Shape]| {
+            matches!(shapes, [Shape::Line { from, to, .. }]
+                if (from.x, from.y, to.x, to.y) == (0, -10, 30, 40))
+        };
+        assert!(snapped(&received_shapes(&mut viewer_rx)));
+        assert!(snapped(&received_shapes(&mut drawer_rx)));
+
+        let persisted: Vec<Shape> = EventLogPersistenceJson::open(log_path.to_str().unwrap())
+            .unwrap()
+            .read_lines::<CanvasEvents>()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event {
+                Ok(CanvasEvents::ShapeAdded { shape, .. }) => Some(shape),
+                _ => None,
+            })
+            .collect();
+        assert!(snapped(&persisted));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_snap_disabled_canvas_keeps_shapes() {
+        let mut server = test_server(ConnectionLimits::default());
+        let (mut drawer_rx, mut viewer_rx) = connect_drawer_and_viewer(&mut server).await;
+        // a grid alone only affects the clients
+        server.update_canvas_settings(
+            "canvas".to_string(),
+            CanvasSettings {
+                grid_size: Some(10),
+                snap_enabled: false,
+                ..CanvasSettings::default()
+            },
+            "owner".to_string(),
+            2,
+        );
+
+        send_as_drawer(
+            &mut server,
+            shape_added(
+                r##"{"type":"Circle","id":"c1","temporary":false,"borderColor":"#000","fillColor":"#000","center":{"x":3,"y":7},"radius":4.5}"##,
+            ),
+        );
+        assert!(matches!(
+            &received_shapes(&mut viewer_rx)[..],
+            [Shape::Circle { center, radius, .. }] if (center.x, center.y, *radius) == (3, 7, 4.5)
+        ));
+        // unchanged shapes are not echoed to their sender
+        assert!(received_shapes(&mut drawer_rx).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_expired_grant_denies_writes() {
+        let clock = Arc::new(ManualClock::new(500));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let canvas = server.canvases.get_mut("canvas").unwrap();
+        canvas
+            .inner
+            .users
+            .insert("workshop".to_string(), AccessLevel::Write);
+        canvas
+            .inner
+            .expirations
+            .insert("workshop".to_string(), 1_000);
+        assert!(CanvasSocketServer::validate_permissions(
+            canvas,
+            &"workshop".to_string()
+        ));
+
+        // denied immediately, without waiting for the CanvasStore to remove the grant
+        clock.advance(Duration::from_millis(500));
+        assert!(!CanvasSocketServer::validate_permissions(
+            canvas,
+            &"workshop".to_string()
+        ));
+    }
+
+    #[test]
+    fn test_connect_cooldown() {
+        let limits = ConnectionLimits::default();
+        let mut attempts = ConnectAttempts::default();
+        let start = Instant::now();
+
+        for attempt in 0..limits.max_connect_attempts {
+            assert!(
+                attempts.register_attempt(start + Duration::from_millis(attempt as u64), &limits)
+            );
+        }
+
+        // flapping client is cooled down
+        assert!(!attempts.register_attempt(start + Duration::from_secs(1), &limits));
+        assert!(!attempts.register_attempt(start + Duration::from_secs(30), &limits));
+        assert!(!attempts.is_stale(start + Duration::from_secs(30)));
+
+        // cooldown expires
+        let after_cooldown = start + Duration::from_secs(1) + limits.connect_cooldown;
+        assert!(attempts.register_attempt(after_cooldown, &limits));
+
+        // attempts decay out of the window
+        assert!(attempts.is_stale(after_cooldown + CONNECT_ATTEMPT_WINDOW * 2));
+    }
+
+    #[actix_web::test]
+    async fn test_attempts_on_canvases_that_never_load_are_swept() {
+        let mut server = test_server(ConnectionLimits::default());
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let rejection = server
+            .try_connect(
+                tx,
+                "missing".to_string(),
+                "alice".to_string(),
+                "alice".to_string(),
+                "s1".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await;
+        assert!(rejection.is_err());
+        assert_eq!(server.connect_attempts.len(), 1);
+
+        // the counter still protects the canvas within the window
+        let now = Instant::now();
+        server.next_attempt_sweep = now;
+        server.sweep_connect_attempts(now);
+        assert_eq!(server.connect_attempts.len(), 1);
+
+        let later = now + CONNECT_ATTEMPT_WINDOW * 2;
+        server.sweep_connect_attempts(later);
+        assert!(server.connect_attempts.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_quota_warning_is_sent_once_to_moderators() {
+        let mut server = test_server(ConnectionLimits::default());
+        server.quota_limits = QuotaLimits {
+            max_shapes: 10,
+            ..QuotaLimits::default()
+        };
+        let canvas = server.canvases.get_mut("canvas").unwrap();
+        canvas
+            .inner
+            .users
+            .insert("owner".to_string(), AccessLevel::Owner);
+        canvas
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+
+        let (owner_tx, mut owner_rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                owner_tx,
+                "canvas".to_string(),
+                "owner".to_string(),
+                "owner".to_string(),
+                "owner-session".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await
+            .unwrap();
+        let (_, mut user_rx) = connect_session(&mut server, "session").await;
+
+        for shape in 0..10 {
+            let shape_added = format!(
+                r##"{{"type":"ShapeAdded","origin":"session","timestamp":1,"shape":{{"type":"Line","id":"l{shape}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":0,"y":0}},"to":{{"x":5,"y":5}}}}}}"##
+            );
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                shape_added,
+            );
+        }
+
+        let notices = |rx: &mut mpsc::UnboundedReceiver<SessionMessage>| {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter_map(|message| notice_code(&message))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(notices(&mut owner_rx), vec!["quota.shapes"]);
+        assert!(notices(&mut user_rx).is_empty());
+
+        // stats report the counters of the instance
+        let canvas = &server.canvases["canvas"];
+        let usage = server.quota_usage(&"canvas".to_string());
+        assert_eq!(
+            usage,
+            vec![
+                server
+                    .quota_limits
+                    .usage(QuotaKind::Shapes, canvas.shapes.len() as u64),
+                server
+                    .quota_limits
+                    .usage(QuotaKind::LogBytes, canvas.persistence.size().unwrap()),
+            ]
+        );
+        assert_eq!(usage[0].usage, 10);
+        assert_eq!(usage[1].usage, canvas.log_bytes);
+    }
+
+    fn line_added(op_id: &str, shape_id: &str) -> Msg {
+        format!(
+            r##"{{"type":"ShapeAdded","opId":"{op_id}","origin":"session","timestamp":1,"shape":{{"type":"Line","id":"{shape_id}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":0,"y":0}},"to":{{"x":5,"y":5}}}}}}"##
+        )
+    }
+
+    fn shapes_added(canvas: &CanvasInstance) -> usize {
+        canvas
+            .event_log
+            .iter()
+            .filter(|event| matches!(event, CanvasEvents::ShapeAdded { .. }))
+            .count()
+    }
+
+    #[actix_web::test]
+    async fn test_retried_operation_is_applied_once() {
+        let mut server = test_server(ConnectionLimits::default());
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, mut origin_rx) = connect_session(&mut server, "session").await;
+        let (_, mut other_rx) = connect_session(&mut server, "other").await;
+        while origin_rx.try_recv().is_ok() {} // initial state
+        while other_rx.try_recv().is_ok() {}
+
+        let mut log_sizes = Vec::new();
+        for _ in 0..2 {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                line_added("op1", "l1"),
+            );
+            log_sizes.push(server.canvases["canvas"].persistence.size().unwrap());
+        }
+
+        // the retry is neither persisted nor added to the log
+        assert_eq!(log_sizes[0], log_sizes[1]);
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 1);
+
+        let broadcasts: Vec<SessionMessage> =
+            std::iter::from_fn(|| other_rx.try_recv().ok()).collect();
+        assert_eq!(broadcasts.len(), 1);
+        assert!(notice_code(&broadcasts[0]).is_none());
+
+        // the origin receives the Ack of the first delivery and the acknowledgement of the retry
+        let message = origin_rx.try_recv().unwrap();
+        assert!(matches!(
+            serde_json::from_str(message.text()),
+            Ok(CanvasEvents::Ack { opId, .. }) if opId == "op1"
+        ));
+        let message = origin_rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.duplicate");
+        let Ok(CanvasEvents::ServerNotice { opId, .. }) = serde_json::from_str(message.text())
+        else {
+            panic!("expected ServerNotice");
+        };
+        assert_eq!(opId.as_deref(), Some("op1"));
+        assert!(origin_rx.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_distinct_operations_on_same_shape_are_applied() {
+        let mut server = test_server(ConnectionLimits::default());
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, mut rx) = connect_session(&mut server, "session").await;
+        while rx.try_recv().is_ok() {} // initial state
+
+        let update = r##"{"type":"ShapeUpdated","opId":"op2","origin":"session","timestamp":1,"shape":{"id":"l1","borderColor":"#fff"}}"##;
+        for message in [line_added("op1", "l1"), update.to_string()] {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                message,
+            );
+        }
+
+        // the update is buffered until its window is over
+        let canvas = server.canvases.get_mut("canvas").unwrap();
+        CanvasSocketServer::persist_all_updates(canvas);
+        assert_eq!(shapes_added(canvas), 1);
+        assert!(canvas
+            .event_log
+            .iter()
+            .any(|event| matches!(event, CanvasEvents::ShapeUpdated { .. })));
+        assert!(std::iter::from_fn(|| rx.try_recv().ok())
+            .all(|message| notice_code(&message).as_deref() != Some("event.duplicate")));
+    }
+
+    fn temp_line_added(shape_id: &str, temporary: bool) -> Msg {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"session","timestamp":1,"shape":{{"type":"Line","id":"{shape_id}","temporary":{temporary},"borderColor":"#000","fillColor":"#000","from":{{"x":0,"y":0}},"to":{{"x":5,"y":5}}}}}}"##
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_colliding_shape_id_is_rejected_with_suggestion() {
+        let mut server = test_server(ConnectionLimits::default());
+        let (mut origin_rx, mut other_rx) = connect_writer_sessions(&mut server).await;
+        let send = |server: &mut CanvasSocketServer, session_id: &str, msg: Msg| {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                session_id.to_string(),
+                msg,
+            )
+        };
+
+        send(&mut server, "session", line_added("op1", "l1"));
+        send(&mut server, "other", line_added("op2", "l1"));
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 1);
+        while origin_rx.try_recv().is_ok() {}
+
+        let nack: Vec<CanvasEvents> = received_events(&mut other_rx)
+            .into_iter()
+            .filter(|event| matches!(event, CanvasEvents::Nack { .. }))
+            .collect();
+        let [CanvasEvents::Nack {
+            opId,
+            code,
+            suggestedId: Some(suggested_id),
+            ..
+        }] = &nack[..]
+        else {
+            panic!("expected Nack with suggestion, got {nack:?}");
+        };
+        assert_eq!(opId, "op2");
+        assert_eq!(code, "event.shape_id_taken");
+        assert!(validation::is_valid_shape_id(suggested_id));
+
+        // resent with the suggestion
+        send(&mut server, "other", line_added("op3", suggested_id));
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 2);
+
+        // a preview can't take the id of a persisted shape or of the preview of another session
+        send(&mut server, "other", temp_line_added("l1", true));
+        send(&mut server, "other", temp_line_added("t1", true));
+        send(&mut server, "session", temp_line_added("t1", true));
+        let notices: Vec<Option<String>> = received_events(&mut other_rx)
+            .into_iter()
+            .chain(received_events(&mut origin_rx))
+            .filter_map(|event| match event {
+                CanvasEvents::ServerNotice {
+                    code, suggestedId, ..
+                } if code == "event.shape_id_taken" => Some(suggestedId),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notices.len(), 2);
+        assert!(notices.iter().all(Option::is_some));
+
+        // the drawing session updates and commits its own preview
+        send(&mut server, "other", temp_line_added("t1", true));
+        send(&mut server, "other", temp_line_added("t1", false));
+        let canvas = &server.canvases["canvas"];
+        assert!(canvas.shapes.contains("t1"));
+        assert!(canvas.temp_shapes.is_empty());
+        assert!(received_events(&mut other_rx)
+            .iter()
+            .all(|event| !matches!(event, CanvasEvents::ServerNotice { .. })));
+    }
+
+    #[actix_web::test]
+    async fn test_time_sync_is_answered_on_own_session_only() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        // readers can't write, but may sync their clock
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Read);
+        let (_, mut origin_rx) = connect_session(&mut server, "session").await;
+        let (_, mut other_rx) = connect_session(&mut server, "other").await;
+        while origin_rx.try_recv().is_ok() {} // initial state
+        while other_rx.try_recv().is_ok() {}
+        let persisted_events = server.canvases["canvas"].persisted_events;
+        let logged_events = server.canvases["canvas"].event_log.len();
+
+        let request = r#"{"type":"TimeSyncRequest","clientTime":42}"#;
+        for _ in 0..=MAX_TIME_SYNCS_PER_WINDOW {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                request.to_string(),
+            );
+        }
+
+        let responses = received_events(&mut origin_rx);
+        // the request above the cap is dropped
+        assert_eq!(responses.len(), MAX_TIME_SYNCS_PER_WINDOW);
+        for response in responses {
+            let CanvasEvents::TimeSyncResponse {
+                clientTime,
+                serverReceiveTime,
+                serverSendTime,
+            } = response
+            else {
+                panic!("expected TimeSyncResponse, got {response:?}");
+            };
+            assert_eq!(clientTime, 42);
+            assert_eq!(serverReceiveTime, 1_000_000);
+            assert!(serverReceiveTime <= serverSendTime);
+        }
+
+        assert!(other_rx.try_recv().is_err());
+        let canvas = &server.canvases["canvas"];
+        assert_eq!(canvas.persisted_events, persisted_events);
+        assert_eq!(canvas.event_log.len(), logged_events);
+
+        // the window moves on
+        clock.advance(Duration::from_millis(TIME_SYNC_WINDOW_MS));
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            request.to_string(),
+        );
+        assert!(matches!(
+            received_events(&mut origin_rx)[..],
+            [CanvasEvents::TimeSyncResponse { serverReceiveTime, .. }]
+                if serverReceiveTime == 1_000_000 + TIME_SYNC_WINDOW_MS
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_gated_event_is_rejected_while_its_flag_is_disabled() {
+        let mut server = test_server(ConnectionLimits::default()).with_feature_flags(
+            FeatureFlags::from_specs(["time_sync=off:overridable".parse().unwrap()]),
+        );
+        let (_, mut rx) = connect_session(&mut server, "session").await;
+        let hello = rx.try_recv().unwrap();
+        let Ok(CanvasEvents::ServerHello { flags, .. }) = serde_json::from_str(hello.text()) else {
+            panic!("expected ServerHello, got {hello:?}");
+        };
+        assert_eq!(flags.get(features::TIME_SYNC), Some(&false));
+        assert_eq!(flags.get(features::FLUSH_REQUESTS), Some(&true));
+        while rx.try_recv().is_ok() {} // initial state
+
+        let request = r#"{"type":"TimeSyncRequest","clientTime":42}"#;
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            request.to_string(),
+        );
+        let message = rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.feature_disabled");
+        assert!(rx.try_recv().is_err());
+
+        // the owner switches the feature on, the session is told right away
+        let version = server.canvases["canvas"].inner.version + 1;
+        server.update_canvas_feature_flags(
+            "canvas".to_string(),
+            BTreeMap::from([(features::TIME_SYNC.to_string(), true)]),
+            version,
+        );
+        assert!(matches!(
+            &received_events(&mut rx)[..],
+            [CanvasEvents::ServerHello { flags, .. }] if flags[features::TIME_SYNC]
+        ));
+
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            request.to_string(),
+        );
+        assert!(matches!(
+            received_events(&mut rx)[..],
+            [CanvasEvents::TimeSyncResponse { clientTime: 42, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_recent_op_ids_are_bounded() {
+        let mut op_ids = RecentOpIds::default();
+        for op_id in 0..=RECENT_OP_IDS {
+            op_ids.insert(op_id.to_string());
+        }
+        assert!(!op_ids.contains("0"));
+        assert!(op_ids.contains("1"));
+        assert_eq!(op_ids.ids.len(), RECENT_OP_IDS);
+    }
+
+    /// Replaces the eventlog of the test canvas with one at a known path
+    fn use_temp_log(server: &mut CanvasSocketServer) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let (_, persistence) = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+        server.canvases.get_mut("canvas").unwrap().persistence = persistence;
+        path
+    }
+
+    async fn connect_writer_sessions(
+        server: &mut CanvasSocketServer,
+    ) -> (
+        mpsc::UnboundedReceiver<SessionMessage>,
+        mpsc::UnboundedReceiver<SessionMessage>,
+    ) {
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, mut origin_rx) = connect_session(server, "session").await;
+        let (_, mut other_rx) = connect_session(server, "other").await;
+        while origin_rx.try_recv().is_ok() {} // initial state
+        while other_rx.try_recv().is_ok() {}
+        (origin_rx, other_rx)
+    }
+
+    #[actix_web::test]
+    async fn test_ack_carries_persisted_seq_and_goes_to_origin_only() {
+        let mut server = test_server(ConnectionLimits::default());
+        let path = use_temp_log(&mut server);
+        let (mut origin_rx, mut other_rx) = connect_writer_sessions(&mut server).await;
+
+        for op_id in ["op1", "op2"] {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                line_added(op_id, op_id),
+            );
+        }
+        // temporary shapes are not persisted and therefore not acknowledged
+        let temporary = r##"{"type":"ShapeAdded","opId":"op3","origin":"session","timestamp":1,"shape":{"type":"Line","id":"t1","temporary":true,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":5,"y":5}}}"##;
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            temporary.to_string(),
+        );
+
+        let persisted: Vec<CanvasEvents> = EventLogPersistenceJson::open(&path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let acks: Vec<(String, u64)> = std::iter::from_fn(|| origin_rx.try_recv().ok())
+            .map(|message| match serde_json::from_str(message.text()) {
+                Ok(CanvasEvents::Ack { opId, seq, .. }) => (opId, seq),
+                _ => panic!("expected Ack, got {message:?}"),
+            })
+            .collect();
+        assert_eq!(acks.len(), 2);
+        for (op_id, seq) in acks {
+            let CanvasEvents::ShapeAdded { shape, .. } = &persisted[seq as usize - 1] else {
+                panic!("expected ShapeAdded at line {seq}");
+            };
+            assert_eq!(shape.get_id(), op_id);
+        }
+
+        // other sessions only receive the broadcasts
+        let broadcasts: Vec<SessionMessage> =
+            std::iter::from_fn(|| other_rx.try_recv().ok()).collect();
+        assert_eq!(broadcasts.len(), 3);
+        assert!(broadcasts.iter().all(|message| matches!(
+            serde_json::from_str(message.text()),
+            Ok(CanvasEvents::ShapeAdded { .. })
+        )));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn test_persisted_events_are_announced_in_seq_order() {
+        let mut server = test_server(ConnectionLimits::default());
+        let path = use_temp_log(&mut server);
+        let mut notifications = server.event_bus.subscribe();
+        let (_origin_rx, _other_rx) = connect_writer_sessions(&mut server).await;
+
+        for op_id in ["op1", "op2"] {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                line_added(op_id, op_id),
+            );
+        }
+
+        let received: Vec<CanvasEventNotification> =
+            std::iter::from_fn(|| notifications.try_recv().ok()).collect();
+        assert!(received.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+        assert_eq!(
+            received.last().map(|notification| notification.seq),
+            Some(server.canvases["canvas"].persisted_events)
+        );
+        let added: Vec<_> = received
+            .iter()
+            .filter(|notification| notification.kind == "ShapeAdded")
+            .collect();
+        assert_eq!(added.len(), 2);
+        assert!(added
+            .iter()
+            .all(|notification| notification.user_id.as_deref() == Some("user")));
+
+        drop(notifications);
+        assert_eq!(server.event_bus.subscriber_count(), 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn test_persistence_failure_sends_nack_without_broadcast() {
+        let mut server = test_server(ConnectionLimits::default());
+        let path = use_temp_log(&mut server);
+        // a read-only handle fails every write
+        let (_, read_only) = EventLogPersistenceJson::open(&path)
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+        server.canvases.get_mut("canvas").unwrap().persistence = read_only;
+        let (mut origin_rx, mut other_rx) = connect_writer_sessions(&mut server).await;
+
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            line_added("op1", "l1"),
+        );
+
+        let message = origin_rx.try_recv().unwrap();
+        let Ok(CanvasEvents::Nack { opId, code, .. }) = serde_json::from_str(message.text()) else {
+            panic!("expected Nack, got {message:?}");
+        };
+        assert_eq!(opId, "op1");
+        assert_eq!(code, "canvas.persistence_failed");
+        assert!(other_rx.try_recv().is_err());
+
+        let canvas = &server.canvases["canvas"];
+        assert_eq!(shapes_added(canvas), 0);
+        // the failed operation can be retried
+        assert!(!canvas.applied_op_ids.contains("op1"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Bytes of a server frame on the wire, the header grows with the payload length
+    fn frame_bytes(payload: &str) -> usize {
+        let header = match payload.len() {
+            0..=125 => 2,
+            126..=65535 => 4,
+            _ => 10,
+        };
+        header + payload.len()
+    }
+
+    #[actix_web::test]
+    async fn test_initial_state_is_sent_in_chunks() {
+        const SHAPES: usize = 5_000;
+        let mut server = test_server(ConnectionLimits::default());
+        server.canvases.get_mut("canvas").unwrap().event_log = (0..SHAPES)
+            .map(|i| {
+                serde_json::from_str(&shape_added(&format!(
+                    r##"{{"type":"Line","id":"l{i}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":{i},"y":0}},"to":{{"x":5,"y":{i}}}}}"##
+                )))
+                .unwrap()
+            })
+            .collect();
+
+        let (result, mut rx) = connect_session(&mut server, "session").await;
+        assert_eq!(result, Ok(()));
+
+        let mut frames = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            frames.push(message);
+        }
+
+        // the hello with the feature flags comes first
+        let hello = frames.remove(0);
+        assert!(matches!(
+            serde_json::from_str(hello.text()),
+            Ok(CanvasEvents::ServerHello { .. })
+        ));
+
+        // one frame per event before chunking, the eventlog now also contains the own join
+        let event_log = &server.canvases["canvas"].event_log;
+        let unchunked_bytes: usize = event_log
+            .iter()
+            .map(|event| {
+                let message: Msg = event.try_into().unwrap();
+                frame_bytes(&message)
+            })
+            .sum();
+        let chunked_bytes: usize = frames.iter().map(|frame| frame_bytes(frame.text())).sum();
+        println!(
+            "initial state of {} events: {} frames / {unchunked_bytes} bytes unchunked, {} frames / {chunked_bytes} bytes chunked",
+            event_log.len(),
+            event_log.len(),
+            frames.len()
+        );
+        assert!(frames.len() <= SHAPES.div_ceil(events::INITIAL_STATE_CHUNK_SIZE) + 1);
+        assert!(chunked_bytes < unchunked_bytes);
+
+        let mut received = Vec::new();
+        for (frame, expected_seq) in frames.iter().zip(1..) {
+            let Ok(CanvasEvents::InitialStateChunk {
+                seq, total, events, ..
+            }) = serde_json::from_str(frame.text())
+            else {
+                panic!("expected InitialStateChunk, got {frame:?}");
+            };
+            assert_eq!(seq, expected_seq);
+            assert_eq!(total as usize, frames.len());
+            assert!(events.len() <= events::INITIAL_STATE_CHUNK_SIZE);
+            received.extend(events);
+        }
+        assert_eq!(received.len(), SHAPES + 1);
+        assert!(matches!(
+            received.last(),
+            Some(CanvasEvents::UserJoined { sessionId, .. }) if sessionId == "session"
+        ));
+
+        // chunks are never accepted from clients
+        let chunk = frames.swap_remove(0);
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            chunk.text().to_string(),
+        );
+        let message = rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.not_allowed");
+        assert_eq!(server.canvases["canvas"].event_log.len(), SHAPES + 1);
+    }
+
+    /// Connects a session of the user with the given access level, the session id is the user id
+    async fn connect_user(
+        server: &mut CanvasSocketServer,
+        user_id: &str,
+        access_level: AccessLevel,
+    ) -> mpsc::UnboundedReceiver<SessionMessage> {
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert(user_id.to_string(), access_level);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                user_id.to_string(),
+                format!("{user_id}-name"),
+                user_id.to_string(),
+                ConnectionMeta::default(),
+            )
+            .await
+            .unwrap();
+        while rx.try_recv().is_ok() {}
+        rx
+    }
+
+    fn send_as(server: &mut CanvasSocketServer, user_id: &str, msg: &str) {
+        server.handle_raw_message(
+            "canvas".to_string(),
+            user_id.to_string(),
+            user_id.to_string(),
+            msg.to_string(),
+        );
+    }
+
+    fn line_added_by(origin: &str, shape_id: &str) -> Msg {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"{origin}","timestamp":1,"shape":{{"type":"Line","id":"{shape_id}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":0,"y":0}},"to":{{"x":5,"y":5}}}}}}"##
+        )
+    }
+
+    fn shape_event(event_type: &str, origin: &str, shape_id: &str) -> Msg {
+        match event_type {
+            "ShapeUpdated" => format!(
+                r##"{{"type":"ShapeUpdated","origin":"{origin}","timestamp":1,"shape":{{"id":"{shape_id}","borderColor":"#f00"}}}}"##
+            ),
+            "ShapeZChanged" => format!(
+                r#"{{"type":"ShapeZChanged","origin":"{origin}","timestamp":1,"shapeId":"{shape_id}","z":1}}"#
+            ),
+            "ShapeSelected" => format!(
+                r#"{{"type":"ShapeSelected","origin":"{origin}","timestamp":1,"shapeId":"{shape_id}","options":{{}}}}"#
+            ),
+            _ => format!(
+                r#"{{"type":"{event_type}","origin":"{origin}","timestamp":1,"shapeId":"{shape_id}"}}"#
+            ),
+        }
+    }
+
+    fn received_events(rx: &mut mpsc::UnboundedReceiver<SessionMessage>) -> Vec<CanvasEvents> {
+        let mut events = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            events.push(serde_json::from_str(message.text()).unwrap());
+        }
+        events
+    }
+
+    fn set_ownership_enforced(server: &mut CanvasSocketServer, enforced: bool, version: u64) {
+        server.update_canvas_settings(
+            "canvas".to_string(),
+            CanvasSettings {
+                shape_ownership_enforced: enforced,
+                ..CanvasSettings::default()
+            },
+            "owner".to_string(),
+            version,
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_shape_ownership_toggle_applies_immediately() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut creator_rx = connect_user(&mut server, "creator", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        received_events(&mut creator_rx);
+
+        send_as(&mut server, "creator", &line_added_by("creator", "l1"));
+        received_events(&mut other_rx);
+        assert_eq!(
+            server.canvases["canvas"].shape_creators["l1"],
+            "creator".to_string()
+        );
+
+        // not enforced, everyone with write access may change the shape
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeUpdated", "other", "l1"),
+        );
+        assert!(matches!(
+            received_events(&mut creator_rx)[..],
+            [CanvasEvents::ShapeUpdated { .. }]
+        ));
+
+        set_ownership_enforced(&mut server, true, 2);
+        received_events(&mut creator_rx);
+        received_events(&mut other_rx);
+        for event_type in ["ShapeUpdated", "ShapeZChanged", "ShapeRemoved"] {
+            send_as(
+                &mut server,
+                "other",
+                &shape_event(event_type, "other", "l1"),
+            );
+            let events = received_events(&mut other_rx);
+            let [CanvasEvents::ServerNotice { code, message, .. }] = &events[..] else {
+                panic!("expected a notice for {event_type}, got {events:?}");
+            };
+            assert_eq!(code, "event.shape_not_owned");
+            assert!(message.contains("creator-name"));
+            assert!(received_events(&mut creator_rx).is_empty());
+        }
+        // taking over the id of a foreign shape is a change as well
+        send_as(&mut server, "other", &line_added_by("other", "l1"));
+        assert_eq!(
+            notice_code(&other_rx.try_recv().unwrap()).unwrap(),
+            "event.shape_not_owned"
+        );
+        assert!(server.canvases["canvas"].shapes.contains("l1"));
+
+        // the creator is not affected
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeUpdated", "creator", "l1"),
+        );
+        assert!(matches!(
+            received_events(&mut other_rx)[..],
+            [CanvasEvents::ShapeUpdated { .. }]
+        ));
+
+        set_ownership_enforced(&mut server, false, 3);
+        received_events(&mut creator_rx);
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeRemoved", "other", "l1"),
+        );
+        assert!(matches!(
+            received_events(&mut creator_rx)[..],
+            [CanvasEvents::ShapeRemoved { .. }]
+        ));
+        assert!(server.canvases["canvas"].shape_creators.is_empty());
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_moderator_overrides_shape_ownership() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut creator_rx = connect_user(&mut server, "creator", AccessLevel::Write).await;
+        let mut moderator_rx = connect_user(&mut server, "moderator", AccessLevel::Moderate).await;
+        set_ownership_enforced(&mut server, true, 2);
+
+        send_as(&mut server, "creator", &line_added_by("creator", "l1"));
+        send_as(&mut server, "creator", &line_added_by("creator", "l2"));
+        received_events(&mut creator_rx);
+        received_events(&mut moderator_rx);
+
+        send_as(
+            &mut server,
+            "moderator",
+            &shape_event("ShapeUpdated", "moderator", "l1"),
+        );
+        send_as(
+            &mut server,
+            "moderator",
+            &shape_event("ShapeRemoved", "moderator", "l2"),
+        );
+        assert!(received_events(&mut moderator_rx).is_empty());
+        assert!(matches!(
+            received_events(&mut creator_rx)[..],
+            [
+                CanvasEvents::ShapeUpdated { .. },
+                CanvasEvents::ShapeRemoved { .. }
+            ]
+        ));
+        assert!(!server.canvases["canvas"].shapes.contains("l2"));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn shape_added_by(origin: &str, shape_type: ShapeType, shape_id: &str, temporary: bool) -> Msg {
+        let geometry = match shape_type {
+            ShapeType::Line | ShapeType::Rectangle => r#""from":{"x":0,"y":0},"to":{"x":5,"y":5}"#,
+            ShapeType::Circle => r#""center":{"x":5,"y":5},"radius":3.0"#,
+            ShapeType::Triangle => r#""p1":{"x":0,"y":0},"p2":{"x":5,"y":0},"p3":{"x":0,"y":5}"#,
+            ShapeType::Path => r#""points":[{"x":0,"y":0},{"x":5,"y":5}],"closed":false"#,
+        };
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"{origin}","timestamp":1,"shape":{{"type":"{}","id":"{shape_id}","temporary":{temporary},"borderColor":"#000","fillColor":"#000",{geometry}}}}}"##,
+            shape_type.name()
+        )
+    }
+
+    fn set_allowed_shape_types(
+        server: &mut CanvasSocketServer,
+        allowed: &[ShapeType],
+        moderators_bypass: bool,
+        version: u64,
+    ) {
+        server.update_canvas_settings(
+            "canvas".to_string(),
+            CanvasSettings {
+                allowed_shape_types: allowed.iter().copied().collect(),
+                moderators_bypass_shape_types: moderators_bypass,
+                ..CanvasSettings::default()
+            },
+            "owner".to_string(),
+            version,
+        );
+    }
+
+    fn assert_notice(rx: &mut mpsc::UnboundedReceiver<SessionMessage>, expected_code: &str) {
+        let events = received_events(rx);
+        let [CanvasEvents::ServerNotice { code, .. }] = &events[..] else {
+            panic!("expected a notice {expected_code}, got {events:?}");
+        };
+        assert_eq!(code, expected_code);
+    }
+
+    #[actix_web::test]
+    async fn test_selections_need_a_live_shape_and_are_capped() {
+        let mut server = test_server(ConnectionLimits::default());
+        server.shape_limits.max_selected_shapes = 2;
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        for shape_id in ["l1", "l2", "l3"] {
+            send_as(&mut server, "writer", &line_added_by("writer", shape_id));
+        }
+        received_events(&mut writer_rx);
+        received_events(&mut other_rx);
+
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeSelected", "writer", "nope"),
+        );
+        assert_notice(&mut writer_rx, "event.selection_shape_unknown");
+        assert!(received_events(&mut other_rx).is_empty());
+        assert!(!server.canvases["canvas"]
+            .selected_shapes
+            .contains_key("writer"));
+
+        for shape_id in ["l1", "l2", "l2"] {
+            send_as(
+                &mut server,
+                "writer",
+                &shape_event("ShapeSelected", "writer", shape_id),
+            );
+        }
+        assert_eq!(received_events(&mut other_rx).len(), 3);
+        assert!(received_events(&mut writer_rx).is_empty());
+
+        // selecting one more is rejected, another session has its own budget
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeSelected", "writer", "l3"),
+        );
+        assert_notice(&mut writer_rx, "event.selection_limit");
+        assert!(received_events(&mut other_rx).is_empty());
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l3"),
+        );
+        assert_eq!(received_events(&mut writer_rx).len(), 1);
+
+        // a deselection makes room again
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeDeselected", "writer", "l1"),
+        );
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeSelected", "writer", "l3"),
+        );
+        assert_eq!(received_events(&mut other_rx).len(), 2);
+        assert_eq!(server.canvases["canvas"].selected_shapes["writer"].len(), 2);
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.events_rejected_per_minute, 2);
+        assert_eq!(report.selected_shapes, 3);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_oldest_temporary_shapes_are_evicted_for_everyone() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        server.shape_limits.max_temp_shapes_per_session = 2;
+        server.shape_limits.max_temp_shapes_per_canvas = 3;
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        received_events(&mut writer_rx);
+        let persisted_events = server.canvases["canvas"].persisted_events;
+
+        // previews are drawn a millisecond apart, the oldest is the first drawn
+        let draw = |server: &mut CanvasSocketServer, session: &str, shape_id: &str| {
+            clock.advance(Duration::from_millis(1));
+            send_as(
+                server,
+                session,
+                &shape_added_by(session, ShapeType::Line, shape_id, true),
+            );
+        };
+        draw(&mut server, "writer", "t1");
+        draw(&mut server, "writer", "t2");
+        // redrawing a preview keeps its id and evicts nothing
+        draw(&mut server, "writer", "t2");
+        assert_eq!(received_events(&mut other_rx).len(), 3);
+        assert!(received_events(&mut writer_rx).is_empty());
+
+        draw(&mut server, "writer", "t3");
+        let events = received_events(&mut writer_rx);
+        assert!(matches!(
+            &events[..],
+            [CanvasEvents::ShapeRemoved { origin, shapeId, .. }] if origin == "writer" && shapeId == "t1"
+        ));
+        let events = received_events(&mut other_rx);
+        assert!(matches!(
+            &events[..],
+            [
+                CanvasEvents::ShapeRemoved { shapeId, .. },
+                CanvasEvents::ShapeAdded { shape, .. }
+            ] if shapeId == "t1" && shape.get_id() == "t3"
+        ));
+
+        // the canvas limit evicts the oldest preview of any session
+        draw(&mut server, "other", "o1");
+        assert!(received_events(&mut other_rx).is_empty());
+        draw(&mut server, "other", "o2");
+        let events = received_events(&mut writer_rx);
+        assert!(matches!(
+            &events[..],
+            [
+                CanvasEvents::ShapeAdded { .. },
+                CanvasEvents::ShapeRemoved { origin, shapeId, .. },
+                CanvasEvents::ShapeAdded { .. }
+            ] if origin == "writer" && shapeId == "t2"
+        ));
+        let mut temp_shapes: Vec<&String> = server.canvases["canvas"].temp_shapes.keys().collect();
+        temp_shapes.sort();
+        assert_eq!(temp_shapes, ["o1", "o2", "t3"]);
+
+        // evictions are never persisted and show up in the diagnostics
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.temp_shapes_evicted_per_minute, 2);
+        assert_eq!(report.temp_shapes, 3);
+        assert_eq!(server.canvases["canvas"].persisted_events, persisted_events);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_sweep_deselects_shapes_removed_by_another_session() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut creator_rx = connect_user(&mut server, "creator", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        send_as(&mut server, "creator", &line_added_by("creator", "l1"));
+        send_as(&mut server, "creator", &line_added_by("creator", "l2"));
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l1"),
+        );
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l2"),
+        );
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeRemoved", "creator", "l1"),
+        );
+        received_events(&mut creator_rx);
+        received_events(&mut other_rx);
+        assert!(server.canvases["canvas"].selected_shapes["other"].contains("l1"));
+
+        server.flush_due_canvases();
+        for rx in [&mut creator_rx, &mut other_rx] {
+            let events = received_events(rx);
+            assert!(matches!(
+                &events[..],
+                [CanvasEvents::ShapeDeselected { origin, shapeId, .. }] if origin == "other" && shapeId == "l1"
+            ));
+        }
+        let selected: Vec<&String> = server.canvases["canvas"].selected_shapes["other"]
+            .iter()
+            .collect();
+        assert_eq!(selected, ["l2"]);
+
+        // nothing left to sweep
+        server.flush_due_canvases();
+        assert!(received_events(&mut other_rx).is_empty());
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.selections_swept_per_minute, 1);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn assert_shape_type_rejected(
+        rx: &mut mpsc::UnboundedReceiver<SessionMessage>,
+        shape_type: ShapeType,
+    ) {
+        let events = received_events(rx);
+        let [CanvasEvents::ServerNotice { code, message, .. }] = &events[..] else {
+            panic!("expected a notice for {shape_type:?}, got {events:?}");
+        };
+        assert_eq!(code, "event.shape_type_not_allowed");
+        assert!(message.contains(shape_type.name()), "{message}");
+    }
+
+    #[actix_web::test]
+    async fn test_allowed_shape_types_restrict_new_shapes() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        // drawn before the restriction
+        send_as(&mut server, "writer", &line_added_by("writer", "l1"));
+        received_events(&mut writer_rx);
+        received_events(&mut other_rx);
+
+        set_allowed_shape_types(
+            &mut server,
+            &[ShapeType::Rectangle, ShapeType::Path],
+            false,
+            2,
+        );
+        let events = received_events(&mut other_rx);
+        let [CanvasEvents::CanvasSettingsChanged {
+            allowedShapeTypes, ..
+        }] = &events[..]
+        else {
+            panic!("expected the settings, got {events:?}");
+        };
+        assert_eq!(
+            allowedShapeTypes.iter().copied().collect::<Vec<_>>(),
+            [ShapeType::Rectangle, ShapeType::Path]
+        );
+        received_events(&mut writer_rx);
+
+        for (i, shape_type) in ShapeType::ALL.into_iter().enumerate() {
+            let shape_id = format!("s{i}");
+            send_as(
+                &mut server,
+                "writer",
+                &shape_added_by("writer", shape_type, &shape_id, false),
+            );
+            if matches!(shape_type, ShapeType::Rectangle | ShapeType::Path) {
+                assert!(matches!(
+                    received_events(&mut other_rx)[..],
+                    [CanvasEvents::ShapeAdded { .. }]
+                ));
+                assert!(server.canvases["canvas"].shapes.contains(&shape_id));
+            } else {
+                assert_shape_type_rejected(&mut writer_rx, shape_type);
+                assert!(received_events(&mut other_rx).is_empty());
+                assert!(!server.canvases["canvas"].shapes.contains(&shape_id));
+            }
+            received_events(&mut writer_rx);
+        }
+
+        // the line drawn before stays and may still be changed
+        for event_type in ["ShapeUpdated", "ShapeRemoved"] {
+            send_as(
+                &mut server,
+                "writer",
+                &shape_event(event_type, "writer", "l1"),
+            );
+            assert_eq!(received_events(&mut other_rx).len(), 1, "{event_type}");
+        }
+
+        // an empty set allows every type again
+        set_allowed_shape_types(&mut server, &[], false, 3);
+        received_events(&mut other_rx);
+        send_as(&mut server, "writer", &line_added_by("writer", "l2"));
+        assert!(matches!(
+            received_events(&mut other_rx)[..],
+            [CanvasEvents::ShapeAdded { .. }]
+        ));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_allowed_shape_types_reject_the_commit_of_a_temporary_shape() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        // a stroke in progress while the owner restricts the canvas
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Circle, "c1", true),
+        );
+        assert!(server.canvases["canvas"].temp_shapes.contains_key("c1"));
+        set_allowed_shape_types(&mut server, &[ShapeType::Rectangle], false, 2);
+        received_events(&mut writer_rx);
+
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Circle, "c1", false),
+        );
+        assert_shape_type_rejected(&mut writer_rx, ShapeType::Circle);
+        assert!(!server.canvases["canvas"].shapes.contains("c1"));
+
+        // previews of other types are rejected as well
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Circle, "c2", true),
+        );
+        assert_shape_type_rejected(&mut writer_rx, ShapeType::Circle);
+        assert!(!server.canvases["canvas"].temp_shapes.contains_key("c2"));
+
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Rectangle, "r1", true),
+        );
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Rectangle, "r1", false),
+        );
+        assert!(server.canvases["canvas"].shapes.contains("r1"));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_moderators_bypass_allowed_shape_types_only_if_configured() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        let mut moderator_rx = connect_user(&mut server, "moderator", AccessLevel::Moderate).await;
+        let mut owner_rx = connect_user(&mut server, "owner", AccessLevel::Owner).await;
+
+        // restricted for everyone by default
+        set_allowed_shape_types(&mut server, &[ShapeType::Rectangle], false, 2);
+        for rx in [&mut writer_rx, &mut moderator_rx, &mut owner_rx] {
+            received_events(rx);
+        }
+        for (user_id, rx) in [("moderator", &mut moderator_rx), ("owner", &mut owner_rx)] {
+            send_as(
+                &mut server,
+                user_id,
+                &shape_added_by(user_id, ShapeType::Line, &format!("{user_id}-1"), false),
+            );
+            assert_shape_type_rejected(rx, ShapeType::Line);
+        }
+
+        set_allowed_shape_types(&mut server, &[ShapeType::Rectangle], true, 3);
+        for rx in [&mut writer_rx, &mut moderator_rx, &mut owner_rx] {
+            received_events(rx);
+        }
+        for user_id in ["moderator", "owner"] {
+            let shape_id = format!("{user_id}-2");
+            send_as(
+                &mut server,
+                user_id,
+                &shape_added_by(user_id, ShapeType::Line, &shape_id, false),
+            );
+            assert!(server.canvases["canvas"].shapes.contains(&shape_id));
+        }
+        received_events(&mut writer_rx);
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Line, "writer-1", false),
+        );
+        assert_shape_type_rejected(&mut writer_rx, ShapeType::Line);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_enforced_palette_rejects_off_palette_colors() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut creator_rx = connect_user(&mut server, "creator", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        // drawn before the palette, red once recolored
+        send_as(&mut server, "creator", &line_added_by("creator", "l1"));
+        received_events(&mut creator_rx);
+        received_events(&mut other_rx);
+
+        let palette = vec![PaletteColor {
+            name: "Ink".to_string(),
+            color: "#000000".to_string(),
+        }];
+        server.update_canvas_palette(
+            "canvas".to_string(),
+            palette.clone(),
+            true,
+            "owner".to_string(),
+            2,
+        );
+        for rx in [&mut creator_rx, &mut other_rx] {
+            assert!(matches!(
+                &received_events(rx)[..],
+                [CanvasEvents::PaletteChanged { palette: received, enforcePalette: true, version: 2, .. }]
+                    if *received == palette
+            ));
+        }
+
+        // #000 is #000000 after normalization
+        send_as(&mut server, "creator", &line_added_by("creator", "l2"));
+        assert!(matches!(
+            received_events(&mut other_rx)[..],
+            [CanvasEvents::ShapeAdded { .. }]
+        ));
+        received_events(&mut creator_rx);
+
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeUpdated", "creator", "l1"),
+        );
+        assert!(received_events(&mut other_rx).is_empty());
+        assert!(matches!(
+            &received_events(&mut creator_rx)[..],
+            [CanvasEvents::ServerNotice { code, message, .. }]
+                if code == "event.color_off_palette" && message.contains("#f00")
+        ));
+
+        // without enforcing the palette is a suggestion only
+        server.update_canvas_palette("canvas".to_string(), palette, false, "owner".to_string(), 3);
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeUpdated", "creator", "l1"),
+        );
+        assert!(matches!(
+            received_events(&mut other_rx)[..],
+            [
+                CanvasEvents::PaletteChanged {
+                    enforcePalette: false,
+                    ..
+                },
+                CanvasEvents::ShapeUpdated { .. }
+            ]
+        ));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn comment_event(event_type: &str, origin: &str, comment_id: &str) -> Msg {
+        match event_type {
+            "CommentAdded" => format!(
+                r#"{{"type":"CommentAdded","origin":"{origin}","timestamp":1,"commentId":"{comment_id}","shapeId":"l1","text":"  looks off "}}"#
+            ),
+            _ => format!(
+                r#"{{"type":"{event_type}","origin":"{origin}","timestamp":1,"commentId":"{comment_id}"}}"#
+            ),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_comments_follow_their_shape() {
+        let clock = Arc::new(ManualClock::new(1_000_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock);
+        let log_path = persist_into_temp_log(&mut server);
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        let mut reader_rx = connect_user(&mut server, "reader", AccessLevel::Read).await;
+        let mut voice_rx = connect_user(&mut server, "voice", AccessLevel::Voice).await;
+
+        send_as(&mut server, "writer", &line_added_by("writer", "l1"));
+        received_events(&mut writer_rx);
+        received_events(&mut reader_rx);
+        received_events(&mut voice_rx);
+
+        send_as(
+            &mut server,
+            "reader",
+            &comment_event("CommentAdded", "reader", "c1"),
+        );
+        let events = received_events(&mut reader_rx);
+        let [CanvasEvents::ServerNotice { code, .. }] = &events[..] else {
+            panic!("expected a notice, got {events:?}");
+        };
+        assert_eq!(code, "event.comment_denied");
+
+        send_as(
+            &mut server,
+            "voice",
+            &comment_event("CommentAdded", "voice", "c1"),
+        );
+        let events = received_events(&mut writer_rx);
+        let [CanvasEvents::CommentAdded {
+            text,
+            userId,
+            timestamp,
+            ..
+        }] = &events[..]
+        else {
+            panic!("expected the comment, got {events:?}");
+        };
+        assert_eq!(text, "looks off");
+        assert_eq!(userId.as_deref(), Some("voice"));
+        // the server stamps the comment instead of the client
+        assert_eq!(*timestamp, 1_000_000);
+
+        // only the author deletes, everyone commenting resolves
+        send_as(
+            &mut server,
+            "writer",
+            &comment_event("CommentDeleted", "writer", "c1"),
+        );
+        let events = received_events(&mut writer_rx);
+        assert!(matches!(
+            &events[..],
+            [CanvasEvents::ServerNotice { code, .. }] if code == "event.comment_not_author"
+        ));
+        send_as(
+            &mut server,
+            "writer",
+            &comment_event("CommentResolved", "writer", "c1"),
+        );
+        received_events(&mut writer_rx);
+        send_as(
+            &mut server,
+            "writer",
+            &comment_event("CommentReopened", "writer", "c1"),
+        );
+        assert!(matches!(
+            received_events(&mut voice_rx)[..],
+            [
+                CanvasEvents::CommentResolved { .. },
+                CanvasEvents::CommentReopened { .. }
+            ]
+        ));
+
+        // removing the shape resolves its open comments for everyone
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeRemoved", "writer", "l1"),
+        );
+        let events = received_events(&mut voice_rx);
+        assert!(matches!(
+            &events[..],
+            [CanvasEvents::ShapeRemoved { .. }, CanvasEvents::CommentResolved { commentId, .. }]
+                if commentId == "c1"
+        ));
+        let comments = server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Comments);
+        let CanvasQueryResult::Comments(comments) = comments else {
+            panic!("expected comments");
+        };
+        assert_eq!(comments.filter(None, Some(CommentStatus::Open)).len(), 0);
+
+        // resolved comments are not part of the initial state, but are sent on request
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("late".to_string(), AccessLevel::Read);
+        let (tx, mut late_rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                "late".to_string(),
+                "late-name".to_string(),
+                "late".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await
+            .unwrap();
+        let initial = received_events(&mut late_rx);
+        assert!(!initial
+            .iter()
+            .any(|event| matches!(event, CanvasEvents::CommentAdded { .. })));
+        send_as(
+            &mut server,
+            "late",
+            r#"{"type":"ResolvedCommentsRequest","shapeId":"l1"}"#,
+        );
+        assert!(matches!(
+            received_events(&mut late_rx)[..],
+            [
+                CanvasEvents::CommentAdded { .. },
+                CanvasEvents::CommentResolved { .. }
+            ]
+        ));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_foreign_selection_does_not_lock_shape() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut creator_rx = connect_user(&mut server, "creator", AccessLevel::Write).await;
+        let mut other_rx = connect_user(&mut server, "other", AccessLevel::Write).await;
+        received_events(&mut creator_rx);
+        send_as(&mut server, "creator", &line_added_by("creator", "l1"));
+        send_as(&mut server, "creator", &line_added_by("creator", "l2"));
+        received_events(&mut other_rx);
+
+        // a selection made before enforcing ownership is released for everyone
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l1"),
+        );
+        assert!(matches!(
+            received_events(&mut creator_rx)[..],
+            [CanvasEvents::ShapeSelected { .. }]
+        ));
+        set_ownership_enforced(&mut server, true, 2);
+        let events = received_events(&mut creator_rx);
+        assert!(matches!(
+            &events[..],
+            [
+                CanvasEvents::CanvasSettingsChanged { shapeOwnershipEnforced: true, .. },
+                CanvasEvents::ShapeDeselected { origin, shapeId, .. }
+            ] if origin == "other" && shapeId == "l1"
+        ));
+        received_events(&mut other_rx);
+
+        // looking at a foreign shape is allowed, but it is kept from the other clients
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l2"),
+        );
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeDeselected", "other", "l2"),
+        );
+        assert!(received_events(&mut other_rx).is_empty());
+        assert!(received_events(&mut creator_rx).is_empty());
+        assert!(server.canvases["canvas"].selected_shapes["other"].is_empty());
+
+        // the creator can still select and change it
+        send_as(
+            &mut server,
+            "other",
+            &shape_event("ShapeSelected", "other", "l2"),
+        );
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeSelected", "creator", "l2"),
+        );
+        send_as(
+            &mut server,
+            "creator",
+            &shape_event("ShapeUpdated", "creator", "l2"),
+        );
+        assert!(matches!(
+            received_events(&mut other_rx)[..],
+            [
+                CanvasEvents::ShapeSelected { .. },
+                CanvasEvents::ShapeUpdated { .. }
+            ]
+        ));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn caught_up_markers(log_path: &std::path::Path, user_id: &str) -> Vec<u64> {
+        EventLogPersistenceJson::open(log_path.to_str().unwrap())
+            .unwrap()
+            .read_lines::<CanvasEvents>()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event.unwrap() {
+                CanvasEvents::UserCaughtUp { userId, seq, .. } if userId == user_id => Some(seq),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_read_receipt_is_written_on_disconnect() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let _owner_rx = connect_user(&mut server, "owner", AccessLevel::Owner).await;
+        let _viewer_rx = connect_user(&mut server, "viewer", AccessLevel::Read).await;
+        // the initial sync of an empty canvas is recorded once
+        assert_eq!(caught_up_markers(&log_path, "viewer"), vec![0]);
+
+        send_as(&mut server, "owner", &line_added_by("owner", "l1"));
+        let change_seq = server.canvases["canvas"].persisted_events;
+        // connected users see every change
+        let read_state = server.read_state(&"canvas".to_string());
+        assert_eq!(read_state.current_seq, change_seq);
+        assert!(read_state.is_up_to_date(&"viewer".to_string()));
+
+        // markers don't reach clients and are no change themselves
+        server.disconnect(
+            "canvas".to_string(),
+            "viewer".to_string(),
+            "viewer".to_string(),
+        );
+        assert_eq!(caught_up_markers(&log_path, "viewer"), vec![0, change_seq]);
+        assert!(!server.canvases["canvas"]
+            .event_log
+            .iter()
+            .any(|event| matches!(event, CanvasEvents::UserCaughtUp { .. })));
+
+        send_as(&mut server, "owner", &line_added_by("owner", "l2"));
+        let read_state = server.read_state(&"canvas".to_string());
+        assert!(!read_state.is_up_to_date(&"viewer".to_string()));
+        assert_eq!(
+            read_state.caught_up(&"viewer".to_string()).map(|c| c.seq),
+            Some(change_seq)
+        );
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[test]
+    fn test_compaction_rebases_latest_read_receipt() {
+        let events: Vec<CanvasEvents> = [
+            r##"{"type":"ShapeAdded","origin":"s1","timestamp":1,"shape":{"type":"Line","id":"gone","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":5,"y":5}}}"##,
+            r##"{"type":"UserCaughtUp","timestamp":2,"userId":"viewer","seq":1}"##,
+            r##"{"type":"ShapeAdded","origin":"s1","timestamp":3,"shape":{"type":"Line","id":"kept","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":5,"y":5}}}"##,
+            r##"{"type":"ShapeRemoved","origin":"s1","timestamp":4,"shapeId":"gone"}"##,
+            r##"{"type":"UserCaughtUp","timestamp":5,"userId":"viewer","seq":3}"##,
+        ]
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+        let compacted =
+            CanvasSocketServer::compact_event_log(events, &RetentionPolicy::default(), 5);
+        assert_eq!(compacted.len(), 2);
+        // the viewer saw "kept", which is the first line now
+        assert!(matches!(
+            compacted[1],
+            CanvasEvents::UserCaughtUp {
+                seq: 1,
+                timestamp: 5,
+                ..
+            }
+        ));
+    }
+
+    fn access_changed(user_id: &str, timestamp: u64) -> CanvasEvents {
+        CanvasEvents::UserAccessLevelChanged {
+            timestamp,
+            userId: user_id.to_string(),
+            accessLevel: AccessLevel::Write,
+        }
+    }
+
+    #[test]
+    fn test_compaction_keeps_last_history_events() {
+        let events: Vec<CanvasEvents> = (0..1_000)
+            .map(|index| access_changed(&format!("user{index}"), index))
+            .collect();
+
+        let policy = RetentionPolicy {
+            history: retention::Retention::KeepLast(500),
+            ..RetentionPolicy::default()
+        };
+        let compacted = CanvasSocketServer::compact_event_log(events, &policy, 1_000);
+        assert_eq!(compacted.len(), 500);
+        // the newest ones survive
+        assert!(matches!(
+            &compacted[0],
+            CanvasEvents::UserAccessLevelChanged { userId, .. } if userId == "user500"
+        ));
+    }
+
+    #[test]
+    fn test_compaction_expires_read_receipts() {
+        let clock = ManualClock::new(1_000_000);
+        let receipt = |user_id: &str, timestamp| CanvasEvents::UserCaughtUp {
+            timestamp,
+            userId: user_id.to_string(),
+            seq: 0,
+        };
+        let events = vec![
+            receipt("gone", clock.now_secs()),
+            receipt("stays", clock.now_secs() + 24 * 60 * 60),
+        ];
+        let policy = RetentionPolicy {
+            read_receipts: retention::Retention::KeepFor(30),
+            ..RetentionPolicy::default()
+        };
+
+        clock.advance(Duration::from_secs(30 * 24 * 60 * 60));
+        let drops = CanvasSocketServer::compaction_drops(&events, &policy, clock.now_secs());
+        assert_eq!(drops, vec![false, false]);
+
+        clock.advance(Duration::from_secs(1));
+        let drops = CanvasSocketServer::compaction_drops(&events, &policy, clock.now_secs());
+        assert_eq!(drops, vec![true, false]);
+    }
+
+    #[test]
+    fn test_compaction_keeps_drawing_under_every_policy() {
+        let mut events: Vec<CanvasEvents> = vec![CanvasEvents::CanvasLogHeader {
+            timestamp: 1,
+            canvasId: "canvas".to_string(),
+        }];
+        events.extend(
+            [
+                line_added_by("s1", "l1"),
+                line_added_by("s1", "l2"),
+                shape_event("ShapeSelected", "s1", "l1"),
+                shape_event("ShapeUpdated", "s1", "l1"),
+                shape_event("ShapeDeselected", "s1", "l1"),
+                shape_event("ShapeZChanged", "s1", "l2"),
+                shape_event("ShapeRemoved", "s1", "l2"),
+                line_added_by("s1", "l3"),
+            ]
+            .iter()
+            .map(|msg| serde_json::from_str::<CanvasEvents>(msg).unwrap()),
+        );
+        events.insert(
+            2,
+            CanvasEvents::ContributorSeen {
+                userId: "alice".to_string(),
+                username: "Alice".to_string(),
+                firstSeen: 1,
+            },
+        );
+        events.insert(4, access_changed("bob", 1));
+        events.push(CanvasEvents::UserCaughtUp {
+            timestamp: 1,
+            userId: "bob".to_string(),
+            seq: 5,
+        });
+
+        let replay = |events: &[CanvasEvents]| {
+            let mut state = replay::CanvasShapeState::default();
+            for (seq, event) in (1..).zip(events) {
+                state.apply(seq, event);
+            }
+            (state.shapes, state.contributors)
+        };
+        let expected = replay(&events);
+        // CanvasEvents is not Clone, every policy compacts its own copy
+        let json: Vec<String> = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+
+        let retentions = [
+            retention::Retention::KeepAll,
+            retention::Retention::KeepLast(0),
+            retention::Retention::KeepLast(1),
+            retention::Retention::KeepFor(0),
+            retention::Retention::KeepFor(30),
+            retention::Retention::DropOnCompact,
+        ];
+        for history in retentions {
+            for read_receipts in retentions {
+                let policy = RetentionPolicy {
+                    history,
+                    read_receipts,
+                };
+                let events = json
+                    .iter()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+                let compacted =
+                    CanvasSocketServer::compact_event_log(events, &policy, 100 * 24 * 60 * 60);
+                assert_eq!(replay(&compacted), expected, "{history} {read_receipts}");
+                assert!(matches!(compacted[0], CanvasEvents::CanvasLogHeader { .. }));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_folds_comments() {
+        let mut events: Vec<CanvasEvents> = vec![CanvasEvents::CanvasLogHeader {
+            timestamp: 1,
+            canvasId: "canvas".to_string(),
+        }];
+        events.extend(
+            [
+                line_added_by("s1", "l1"),
+                comment_event("CommentAdded", "s1", "c1"),
+                comment_event("CommentResolved", "s1", "c1"),
+                comment_event("CommentReopened", "s1", "c1"),
+                comment_event("CommentAdded", "s1", "c2"),
+                comment_event("CommentResolved", "s1", "c2"),
+                comment_event("CommentDeleted", "s1", "c2"),
+                comment_event("CommentResolved", "s1", "c3"),
+                comment_event("CommentAdded", "s1", "c1"),
+            ]
+            .iter()
+            .map(|msg| serde_json::from_str::<CanvasEvents>(msg).unwrap()),
+        );
+
+        let drops = CanvasSocketServer::compaction_drops(&events, &RetentionPolicy::default(), 1);
+        assert_eq!(
+            drops,
+            vec![false, false, false, true, false, true, true, true, true, true]
+        );
+        let kept: Vec<&CanvasEvents> = events
+            .iter()
+            .zip(drops)
+            .filter_map(|(event, dropped)| (!dropped).then_some(event))
+            .collect();
+        assert_eq!(Comments::from_log(kept), Comments::from_log(events.iter()));
+    }
+
+    #[test]
+    fn test_replay_reconstructs_shape_creators() {
+        let events: Vec<CanvasEvents> = [
+            line_added_by("s1", "l1"),
+            line_added_by("s2", "l2"),
+            line_added_by("s2", "l3"),
+            // written before creators were tracked
+            line_added_by("s3", "legacy"),
+            shape_event("ShapeRemoved", "s2", "l3"),
+            shape_event("ShapeUpdated", "s2", "l1"),
+        ]
+        .iter()
+        .map(|msg| serde_json::from_str(msg).unwrap())
+        .zip([Some("alice"), Some("bob"), Some("bob"), None, None, None])
+        .map(|(mut event, creator)| {
+            if let CanvasEvents::ShapeAdded { userId, .. } = &mut event {
+                *userId = creator.map(str::to_string);
+            }
+            event
+        })
+        .collect();
+
+        let fold = |events: &[CanvasEvents]| {
+            let mut creators = HashMap::new();
+            for event in events {
+                CanvasSocketServer::track_shape_creators(&mut creators, event);
+            }
+            creators
+        };
+        let creators = fold(&events);
+        assert_eq!(
+            creators,
+            HashMap::from([
+                ("l1".to_string(), "alice".to_string()),
+                ("l2".to_string(), "bob".to_string()),
+            ])
+        );
+
+        // the creators survive persisting and compacting the log
+        let persisted: Vec<CanvasEvents> = events
+            .iter()
+            .map(|event| serde_json::from_str(&serde_json::to_string(event).unwrap()).unwrap())
+            .collect();
+        let compacted =
+            CanvasSocketServer::compact_event_log(persisted, &RetentionPolicy::default(), 1);
+        assert_eq!(compacted.len(), 4);
+        assert_eq!(fold(&compacted), creators);
+
+        // committing a stroke keeps its creator
+        let mut creators = creators;
+        let mut commit: CanvasEvents = serde_json::from_str(&line_added_by("s2", "l1")).unwrap();
+        if let CanvasEvents::ShapeAdded { userId, .. } = &mut commit {
+            *userId = Some("bob".to_string());
+        }
+        CanvasSocketServer::track_shape_creators(&mut creators, &commit);
+        assert_eq!(creators["l1"], "alice");
+    }
+
+    /// Reconnects the session "session" of the user under username
+    async fn reconnect_session(
+        server: &mut CanvasSocketServer,
+        username: &str,
+    ) -> mpsc::UnboundedReceiver<SessionMessage> {
+        server.disconnect(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+        );
+        let (tx, rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                "user".to_string(),
+                username.to_string(),
+                "session".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await
+            .unwrap();
+        rx
+    }
+
+    #[actix_web::test]
+    async fn test_contributors_are_recorded_once_per_name() {
+        let mut server = test_server(ConnectionLimits::default());
+        let path = use_temp_log(&mut server);
+        let (_origin_rx, mut other_rx) = connect_writer_sessions(&mut server).await;
+        let send = |server: &mut CanvasSocketServer, session_id: &str, shape_id: &str| {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                session_id.to_string(),
+                line_added(shape_id, shape_id),
+            )
+        };
+        send(&mut server, "session", "l1");
+        send(&mut server, "other", "l2");
+        let _origin_rx = reconnect_session(&mut server, "username").await;
+        send(&mut server, "session", "l3");
+        let _origin_rx = reconnect_session(&mut server, "renamed").await;
+        send(&mut server, "session", "l4");
+
+        let persisted: Vec<CanvasEvents> = EventLogPersistenceJson::open(&path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let records: Vec<&str> = persisted
+            .iter()
+            .filter_map(|event| match event {
+                CanvasEvents::ContributorSeen { username, .. } => Some(username.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(records, vec!["username", "renamed"]);
+
+        // records are neither part of the state of clients nor broadcast
+        let canvas = &server.canvases["canvas"];
+        assert!(!canvas
+            .event_log
+            .iter()
+            .chain(received_events(&mut other_rx).iter())
+            .any(|event| matches!(event, CanvasEvents::ContributorSeen { .. })));
+        assert_eq!(Contributors::from_log(&persisted), canvas.contributors);
+
+        // older shapes keep the name of their creator at the time
+        let state = replay::replay_log(&path, None).unwrap().state;
+        let names: Vec<&str> = state
+            .shapes
+            .iter()
+            .filter_map(|shape| shape.get(replay::CREATED_BY_NAME_KEY)?.as_str())
+            .collect();
+        assert_eq!(names, vec!["username", "username", "username", "renamed"]);
+        assert_eq!(
+            state.contributors.name(&"user".to_string()),
+            Some("renamed")
+        );
+        assert!(state.unrecorded_creators().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn test_voice_behavior_switch_applies_immediately() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut voice_rx = connect_user(&mut server, "speaker", AccessLevel::Voice).await;
+
+        // legacy behavior, Voice draws in active canvases
+        send_as(&mut server, "speaker", &line_added_by("speaker", "l1"));
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 1);
+
+        server.update_canvas_settings(
+            "canvas".to_string(),
+            CanvasSettings {
+                legacy_voice_behavior: false,
+                ..CanvasSettings::default()
+            },
+            "owner".to_string(),
+            2,
+        );
+        assert!(matches!(
+            received_events(&mut voice_rx)[..],
+            [CanvasEvents::CanvasSettingsChanged {
+                legacyVoiceBehavior: false,
+                ..
+            }]
+        ));
+
+        send_as(&mut server, "speaker", &line_added_by("speaker", "l2"));
+        assert_eq!(
+            notice_code(&voice_rx.try_recv().unwrap()).unwrap(),
+            "event.permission_denied"
+        );
+        assert_eq!(shapes_added(&server.canvases["canvas"]), 1);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn flushed_seqs(rx: &mut mpsc::UnboundedReceiver<SessionMessage>) -> Vec<u64> {
+        received_events(rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                CanvasEvents::SaveStateChanged { flushedSeq, .. } => Some(flushedSeq),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Flushes what connecting the sessions persisted, so tests start without pending events
+    fn flush_test_canvas(server: &mut CanvasSocketServer) {
+        CanvasSocketServer::flush_canvas(server.canvases.get_mut("canvas").unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_eventlog_flushed_after_pending_time_or_event_count() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        server.flush_policy.max_pending_events = 4;
+        use_temp_log(&mut server);
+        let (mut origin_rx, mut other_rx) = connect_writer_sessions(&mut server).await;
+        flush_test_canvas(&mut server);
+        while other_rx.try_recv().is_ok() {}
+        while origin_rx.try_recv().is_ok() {}
+
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            line_added("op1", "a"),
+        );
+        server.flush_due_canvases();
+        assert!(flushed_seqs(&mut other_rx).is_empty());
+
+        clock.advance(server.flush_policy.max_pending_time);
+        server.flush_due_canvases();
+        let last_ack = received_events(&mut origin_rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                CanvasEvents::Ack { seq, .. } => Some(seq),
+                _ => None,
+            })
+            .next_back();
+        let canvas = &server.canvases["canvas"];
+        assert_eq!(canvas.flushed_seq, canvas.persisted_events);
+        assert_eq!(last_ack, Some(canvas.persisted_events));
+        assert_eq!(flushed_seqs(&mut other_rx), vec![canvas.persisted_events]);
+        assert!(canvas.pending_since.is_none());
+
+        // reaching the event count does not wait for max_pending_time
+        for op_id in ["op2", "op3", "op4", "op5"] {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                "session".to_string(),
+                line_added(op_id, op_id),
+            );
+        }
+        server.flush_due_canvases();
+        let canvas = &server.canvases["canvas"];
+        assert_eq!(flushed_seqs(&mut other_rx), vec![canvas.persisted_events]);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_flush_notifies_moderators_once_and_rejects_request() {
+        let mut server = test_server(ConnectionLimits::default());
+        // syncing /dev/null fails, writes succeed
+        let (_, persistence) = EventLogPersistenceJson::new("/dev/null")
+            .unwrap()
+            .into_standalone::<CanvasEvents>()
+            .unwrap();
+        server.canvases.get_mut("canvas").unwrap().persistence = persistence;
+        let mut moderator_rx = connect_user(&mut server, "moderator", AccessLevel::Moderate).await;
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+
+        send_as(&mut server, "writer", &line_added_by("writer", "a"));
+        while writer_rx.try_recv().is_ok() {}
+        while moderator_rx.try_recv().is_ok() {}
+
+        send_as(
+            &mut server,
+            "writer",
+            r#"{"type":"FlushRequest","opId":"flush","timestamp":1}"#,
+        );
+        assert!(matches!(
+            received_events(&mut writer_rx).as_slice(),
+            [CanvasEvents::Nack { code, .. }] if code == "canvas.flush_failed"
+        ));
+        assert!(server.canvases["canvas"].degraded);
+        assert!(server.canvases["canvas"].pending_since.is_some());
+
+        // the periodic retry fails again without repeating the notice
+        server.flush_policy.max_pending_time = Duration::ZERO;
+        server.flush_due_canvases();
+        let notices: Vec<_> = std::iter::from_fn(|| moderator_rx.try_recv().ok())
+            .filter_map(|message| notice_code(&message))
+            .collect();
+        assert_eq!(notices, vec!["canvas.flush_failed".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn test_flush_request_answers_requester_and_is_rate_limited() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        use_temp_log(&mut server);
+        let mut reader_rx = connect_user(&mut server, "reader", AccessLevel::Read).await;
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        flush_test_canvas(&mut server);
+        while reader_rx.try_recv().is_ok() {}
+        while writer_rx.try_recv().is_ok() {}
+        let flush_request = r#"{"type":"FlushRequest","timestamp":1}"#;
+
+        // nothing pending, only the requester is told
+        send_as(&mut server, "reader", flush_request);
+        let flushed = server.canvases["canvas"].flushed_seq;
+        assert_eq!(flushed_seqs(&mut reader_rx), vec![flushed]);
+        assert!(flushed_seqs(&mut writer_rx).is_empty());
+
+        send_as(&mut server, "writer", &line_added_by("writer", "a"));
+        send_as(&mut server, "reader", flush_request);
+        assert!(flushed_seqs(&mut reader_rx).is_empty());
+
+        clock.advance(server.flush_policy.flush_request_interval);
+        send_as(&mut server, "reader", flush_request);
+        let persisted = server.canvases["canvas"].persisted_events;
+        assert_eq!(flushed_seqs(&mut reader_rx), vec![persisted]);
+        assert_eq!(flushed_seqs(&mut writer_rx), vec![persisted]);
+
+        // clients can't claim a save state
+        let save_state =
+            format!(r#"{{"type":"SaveStateChanged","timestamp":1,"flushedSeq":{persisted}}}"#);
+        send_as(&mut server, "writer", &save_state);
+        let message = writer_rx.try_recv().unwrap();
+        assert_eq!(notice_code(&message).unwrap(), "event.not_allowed");
+    }
+
+    #[actix_web::test]
+    async fn test_query_answers_every_variant() {
+        let mut server = test_server(ConnectionLimits::default());
+        let path = use_temp_log(&mut server);
+        let _alice_rx = connect_user(&mut server, "alice", AccessLevel::Write).await;
+        let _bob_rx = connect_user(&mut server, "bob", AccessLevel::Read).await;
+        send_as(&mut server, "alice", &line_added_by("alice", "l1"));
+        let canvas_id = "canvas".to_string();
+
+        let CanvasQueryResult::Presence(online) =
+            server.answer_query(Some(&canvas_id), CanvasQuery::Presence)
+        else {
+            panic!("expected presence");
+        };
+        assert_eq!(
+            online,
+            HashSet::from(["alice".to_string(), "bob".to_string()])
+        );
+
+        let CanvasQueryResult::Sessions(sessions) =
+            server.answer_query(Some(&canvas_id), CanvasQuery::Sessions)
+        else {
+            panic!("expected sessions");
+        };
+        let users: Vec<&str> = sessions.iter().map(|s| s.user_id.as_str()).collect();
+        assert_eq!(users, vec!["alice", "bob"]);
+
+        let CanvasQueryResult::UserSessions(sessions) =
+            server.answer_query(None, CanvasQuery::UserSessions("bob".to_string()))
+        else {
+            panic!("expected user sessions");
+        };
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].canvas_id, canvas_id);
+
+        assert_eq!(
+            server.answer_query(Some(&canvas_id), CanvasQuery::QuotaUsage),
+            CanvasQueryResult::QuotaUsage(server.quota_usage(&canvas_id))
+        );
+        assert_eq!(
+            server.answer_query(Some(&canvas_id), CanvasQuery::ReadState),
+            CanvasQueryResult::ReadState(server.read_state(&canvas_id))
+        );
+
+        let CanvasQueryResult::Contributors(contributors) =
+            server.answer_query(Some(&canvas_id), CanvasQuery::Contributors)
+        else {
+            panic!("expected contributors");
+        };
+        assert_eq!(contributors.name(&"alice".to_string()), Some("alice-name"));
+        assert_eq!(contributors.name(&"bob".to_string()), None);
+
+        assert_eq!(
+            server.answer_query(Some(&canvas_id), CanvasQuery::PersistedSeq),
+            CanvasQueryResult::PersistedSeq(server.canvases["canvas"].persisted_events)
+        );
+
+        assert_eq!(
+            server.answer_query(None, CanvasQuery::ServerStats),
+            CanvasQueryResult::ServerStats(ServerStats {
+                loaded_canvases: 1,
+                sessions: 2,
+            })
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn test_query_about_unloaded_canvas() {
+        let server = test_server(ConnectionLimits::default());
+        let missing = "missing".to_string();
+
+        for query in [
+            CanvasQuery::Presence,
+            CanvasQuery::Sessions,
+            CanvasQuery::Contributors,
+            CanvasQuery::PersistedSeq,
+            CanvasQuery::Diagnostics,
+            CanvasQuery::Comments,
+        ] {
+            assert_eq!(
+                server.answer_query(Some(&missing), query.clone()),
+                CanvasQueryResult::CanvasNotLoaded
+            );
+            // canvas queries need a canvas id
+            assert_eq!(
+                server.answer_query(None, query),
+                CanvasQueryResult::CanvasNotLoaded
+            );
+        }
+        assert_eq!(
+            server.answer_query(None, CanvasQuery::QuotaUsage),
+            CanvasQueryResult::CanvasNotLoaded
+        );
+
+        // usage and receipts are read from the eventlog, a canvas without one is empty
+        let CanvasQueryResult::QuotaUsage(usage) =
+            server.answer_query(Some(&missing), CanvasQuery::QuotaUsage)
+        else {
+            panic!("expected quota usage");
+        };
+        assert!(usage.iter().all(|usage| usage.usage == 0));
+        assert_eq!(
+            server.answer_query(Some(&missing), CanvasQuery::ReadState),
+            CanvasQueryResult::ReadState(ReadState::default())
+        );
+    }
 
-                Command::Disconnect {
-                    canvas_id,
-                    user_id,
-                    session_id,
-                } => {
-                    self.disconnect(canvas_id, user_id, session_id);
-                }
+    #[actix_web::test]
+    async fn test_query_through_running_server() {
+        let store = EmptyCanvasStore.start();
+        let (server, handle) = CanvasSocketServer::new(
+            Arc::new(store.clone().recipient()),
+            store.recipient(),
+            ConnectionLimits::default(),
+            ShapeLimits::default(),
+            QuotaLimits::default(),
+            FlushPolicy::default(),
+            crate::clock::system(),
+        );
+        actix_web::rt::spawn(server.run());
 
-                Command::UpdateUserAccessLevel {
-                    user_id,
-                    canvas_id,
-                    access_level,
-                } => {
-                    self.update_user_access_level(canvas_id, user_id, access_level);
-                }
+        assert_eq!(
+            handle.query(None, CanvasQuery::ServerStats).await.unwrap(),
+            CanvasQueryResult::ServerStats(ServerStats::default())
+        );
+        assert_eq!(
+            handle
+                .query(Some("missing".to_string()), CanvasQuery::Presence)
+                .await
+                .unwrap(),
+            CanvasQueryResult::CanvasNotLoaded
+        );
+        // the typed wrappers answer unloaded canvases like empty ones
+        assert!(handle
+            .online_users("missing".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(handle
+            .canvas_sessions("missing".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(handle
+            .user_sessions("user".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        // read from the eventlog off the server loop
+        let usage = handle.quota_usage("missing".to_string()).await.unwrap();
+        assert_eq!(usage.len(), 2);
+        assert!(usage.iter().all(|usage| usage.usage == 0));
+    }
 
-                Command::UpdateCanvasState {
-                    canvas_id,
-                    state,
-                    initiator_id,
-                } => {
-                    self.update_canvas_state(canvas_id, state, initiator_id);
-                }
+    #[actix_web::test]
+    async fn test_query_fails_once_server_stopped() {
+        let (handle, commands) = CanvasSocketServerHandle::detached();
+        let (running, mut running_commands) = CanvasSocketServerHandle::detached();
+        drop(commands);
+        assert!(matches!(
+            handle.query(None, CanvasQuery::ServerStats).await,
+            Err(ServerStopped)
+        ));
+        // the typed wrappers pass it on instead of answering like for an unloaded canvas
+        assert!(handle.user_sessions("user".to_string()).await.is_err());
+        assert!(handle.persisted_seq("canvas".to_string()).await.is_err());
 
-                Command::HandleMessage {
-                    canvas_id,
-                    user_id,
-                    session_id,
-                    msg,
-                    res_tx,
-                } => {
-                    if let Ok(event) = serde_json::from_str::<CanvasEvents>(&msg) {
-                        self.handle_message(canvas_id, user_id, session_id, event)
-                    } else {
-                        println!(
-                            "Failed to deserialize message from {user_id} in {canvas_id}: {msg}"
-                        );
-                    }
-                    let _ = res_tx.send(()); // notify sender that message was handeled
-                }
+        // a server stopping with the query pending drops the response channel
+        let pending = running.query(None, CanvasQuery::ServerStats);
+        let answered = async {
+            let command = running_commands.recv().await;
+            assert!(matches!(command, Some(Command::Query { .. })));
+            drop(command);
+        };
+        let (result, _) = futures_util::future::join(pending, answered).await;
+        assert!(matches!(result, Err(ServerStopped)));
+    }
+
+    #[actix_web::test]
+    async fn test_revoked_api_token_closes_its_sessions() {
+        let mut server = test_server(ConnectionLimits::default());
+        let mut owner_rx = connect_user(&mut server, "owner", AccessLevel::Owner).await;
+
+        server.add_api_token(
+            "canvas".to_string(),
+            ApiToken {
+                id: "token".to_string(),
+                label: "ci".to_string(),
+                access_level: AccessLevel::Write,
+                created_by: "owner".to_string(),
+                created_at: 0,
+                expires_at: None,
+                secret_hash: String::new(),
+            },
+        );
+        let principal = tokens::principal_id("token");
+        let (tx, mut token_rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                principal.clone(),
+                "ci".to_string(),
+                principal.clone(),
+                ConnectionMeta::default(),
+            )
+            .await
+            .unwrap();
+        assert!(received_events(&mut owner_rx).iter().any(|event| matches!(
+            event,
+            CanvasEvents::UserJoined {
+                accessLevel: AccessLevel::Write,
+                ..
             }
+        )));
+        while token_rx.try_recv().is_ok() {}
+
+        // the token principal writes with the access level of its token
+        send_as(&mut server, &principal, &line_added_by(&principal, "line"));
+        assert!(received_events(&mut owner_rx)
+            .iter()
+            .any(|event| matches!(event, CanvasEvents::ShapeAdded { .. })));
+
+        server.revoke_api_token("canvas".to_string(), "token".to_string());
+        let message = token_rx.recv().await.unwrap();
+        assert_eq!(
+            notice_code(&message).as_deref(),
+            Some("session.token_revoked")
+        );
+        assert_eq!(
+            token_rx.recv().await,
+            Some(SessionMessage::Close(SocketClose::AuthExpired))
+        );
+        // the sender was dropped, the socket closes
+        assert!(token_rx.recv().await.is_none());
+        assert_eq!(
+            server.canvases["canvas"].inner.access_level(&principal, 0),
+            AccessLevel::None
+        );
+    }
+
+    fn temp_line_added_by(origin: &str, shape_id: &str) -> Msg {
+        line_added_by(origin, shape_id).replace(r#""temporary":false"#, r#""temporary":true"#)
+    }
+
+    /// Unloads the test canvas into a handoff that went through its file, like on shutdown
+    fn hand_off(server: &mut CanvasSocketServer) -> (Handoff, Canvas) {
+        let canvas = server.canvases.remove("canvas").unwrap();
+        let inner = canvas.inner.clone();
+        let path = std::env::temp_dir()
+            .join(format!("{}.handoff.json", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        CanvasSocketServer::handoff(canvas)
+            .unwrap()
+            .write(&path)
+            .unwrap();
+        let handoff = Handoff::take(&path).unwrap().unwrap();
+        // taken handoffs are never restored twice
+        assert!(Handoff::take(&path).unwrap().is_none());
+        (handoff, inner)
+    }
+
+    fn restore_from(
+        server: &CanvasSocketServer,
+        handoff: Handoff,
+        inner: Canvas,
+        log_path: &str,
+    ) -> Result<CanvasInstance, HandoffRejection> {
+        let persistence = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_appender();
+        server.restore(&inner, handoff, persistence)
+    }
+
+    #[actix_web::test]
+    async fn test_handoff_restores_a_populated_canvas() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = use_temp_log(&mut server);
+        let _writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        send_as(&mut server, "writer", &line_added_by("writer", "a"));
+        send_as(&mut server, "writer", &line_added_by("writer", "b"));
+        send_as(&mut server, "writer", &temp_line_added_by("writer", "t"));
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeSelected", "writer", "a"),
+        );
+        flush_test_canvas(&mut server);
+
+        let before = &server.canvases["canvas"];
+        let shapes = before.shapes.clone();
+        let shape_creators = before.shape_creators.clone();
+        let contributors = before.contributors.clone();
+        let persisted_events = before.persisted_events;
+        let live_events = serde_json::to_string(&before.event_log).unwrap();
+
+        let (handoff, inner) = hand_off(&mut server);
+        let restored = restore_from(&server, handoff, inner, &log_path).unwrap();
+        assert_eq!(restored.shapes, shapes);
+        assert_eq!(restored.shape_creators, shape_creators);
+        assert_eq!(restored.contributors, contributors);
+        assert_eq!(restored.persisted_events, persisted_events);
+        assert_eq!(
+            serde_json::to_string(&restored.event_log).unwrap(),
+            live_events
+        );
+        assert_eq!(
+            restored.temp_shapes.keys().collect::<Vec<_>>(),
+            vec![&"t".to_string()]
+        );
+
+        // the session of the previous process left, its selection is released
+        server.finish_load("canvas", restored);
+        let canvas = &server.canvases["canvas"];
+        assert!(canvas.event_log.iter().any(|event| matches!(
+            event,
+            CanvasEvents::ShapeDeselected { shapeId, .. } if shapeId == "a"
+        )));
+        assert!(canvas.event_log.iter().any(|event| matches!(
+            event,
+            CanvasEvents::UserLeft { userId, .. } if userId == "writer"
+        )));
+        let lines = std::fs::read_to_string(&log_path).unwrap().lines().count() as u64;
+        assert_eq!(canvas.persisted_events, lines);
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_stale_or_mismatched_handoff_falls_back_to_the_eventlog() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = use_temp_log(&mut server);
+        let _writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        send_as(&mut server, "writer", &line_added_by("writer", "a"));
+        flush_test_canvas(&mut server);
+        let (handoff, inner) = hand_off(&mut server);
+
+        // another process appended to the eventlog after the handoff was written
+        let mut persistence = EventLogPersistenceJson::new(&log_path)
+            .unwrap()
+            .into_appender::<CanvasEvents>();
+        persistence
+            .save_event(&serde_json::from_str(&line_added_by("writer", "b")).unwrap())
+            .unwrap();
+        let rejection = restore_from(&server, handoff, inner, &log_path);
+        assert!(matches!(
+            rejection,
+            Err(HandoffRejection::LogChanged { .. })
+        ));
+        std::fs::remove_file(log_path).unwrap();
+
+        // the next process started too long after the handoff was written
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = use_temp_log(&mut server);
+        let (handoff, inner) = hand_off(&mut server);
+        clock.advance(server.handoff_max_age + Duration::from_millis(1));
+        let rejection = restore_from(&server, handoff, inner, &log_path);
+        assert!(matches!(rejection, Err(HandoffRejection::Expired { .. })));
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_handoff_drops_temporary_shapes_past_their_ttl() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = use_temp_log(&mut server);
+        let _writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        send_as(&mut server, "writer", &temp_line_added_by("writer", "old"));
+        send_as(
+            &mut server,
+            "writer",
+            &shape_event("ShapeUpdated", "writer", "old"),
+        );
+        clock.advance(handoff::TEMP_SHAPE_TTL);
+        send_as(
+            &mut server,
+            "writer",
+            &temp_line_added_by("writer", "fresh"),
+        );
+        flush_test_canvas(&mut server);
+
+        let (handoff, inner) = hand_off(&mut server);
+        let restored = restore_from(&server, handoff, inner, &log_path).unwrap();
+        assert_eq!(
+            restored.temp_shapes.keys().collect::<Vec<_>>(),
+            vec![&"fresh".to_string()]
+        );
+        let mentions_old = restored
+            .event_log
+            .iter()
+            .any(|event| serde_json::to_string(event).unwrap().contains(r#""old""#));
+        assert!(!mentions_old);
+        std::fs::remove_file(log_path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_diagnostics_count_bursts_and_alarm_once_per_interval() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        server.diagnostics_alarm = DiagnosticsAlarm {
+            events_per_minute: Some(20),
+            save_lag: None,
+            send_failures: None,
+        };
+        let log_path = use_temp_log(&mut server);
+        let mut owner_rx = connect_user(&mut server, "owner", AccessLevel::Owner).await;
+        let mut alice_rx = connect_user(&mut server, "alice", AccessLevel::Write).await;
+        let bob_rx = connect_user(&mut server, "bob", AccessLevel::Read).await;
+        let alarms = |rx: &mut mpsc::UnboundedReceiver<SessionMessage>| {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter_map(|message| notice_code(&message))
+                .filter(|code| code == "canvas.diagnostics_alarm")
+                .count()
+        };
+
+        for shape in 0..15 {
+            send_as(
+                &mut server,
+                "alice",
+                &line_added_by("alice", &format!("a{shape}")),
+            );
         }
+        for shape in 0..5 {
+            send_as(
+                &mut server,
+                "bob",
+                &line_added_by("bob", &format!("b{shape}")),
+            );
+        }
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.events_received_per_minute, 20);
+        assert_eq!(report.events_rejected_per_minute, 5);
+        // the lines reach the owner and bob, the joins were counted as well
+        assert!(report.events_broadcast_per_minute >= 15);
+        assert_eq!(report.max_fan_out, 2);
+        // the lines, the joins and the header of the eventlog
+        assert!(report.persist_latency.samples > 15);
+        assert!(report.slow_sessions.is_empty());
+        assert_eq!(alarms(&mut owner_rx), 0);
 
-        Ok(())
+        // bob's socket is gone, the broadcast to it fails
+        drop(bob_rx);
+        send_as(&mut server, "alice", &line_added_by("alice", "over"));
+        assert_eq!(alarms(&mut owner_rx), 1);
+        assert_eq!(alarms(&mut alice_rx), 0);
+        let report = server.canvases["canvas"]
+            .diagnostics
+            .report(clock.now_ms(), 0);
+        assert_eq!(report.slow_sessions[0].session_id, "bob");
+
+        for shape in 0..10 {
+            send_as(
+                &mut server,
+                "alice",
+                &line_added_by("alice", &format!("c{shape}")),
+            );
+        }
+        assert_eq!(alarms(&mut owner_rx), 0);
+
+        // a burst after the interval alarms again
+        clock.advance(diagnostics::ALARM_INTERVAL);
+        for shape in 0..21 {
+            send_as(
+                &mut server,
+                "alice",
+                &line_added_by("alice", &format!("d{shape}")),
+            );
+        }
+        assert_eq!(alarms(&mut owner_rx), 1);
+
+        let _ = std::fs::remove_file(log_path);
     }
-}
 
-/// Reduces boilerplate of setting up response channels in WebSocket handlers.
-#[derive(Debug, Clone)]
-pub struct CanvasSocketServerHandle {
-    cmd_tx: mpsc::UnboundedSender<Command>,
-}
+    #[actix_web::test]
+    async fn test_updates_of_other_users_within_the_window_conflict() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = use_temp_log(&mut server);
+        let _alice_rx = connect_user(&mut server, "alice", AccessLevel::Write).await;
+        let _bob_rx = connect_user(&mut server, "bob", AccessLevel::Write).await;
+        // the userId of a client is replaced by the server
+        let update = |timestamp: u64| {
+            format!(
+                r##"{{"type":"ShapeUpdated","origin":"s","timestamp":{timestamp},"shape":{{"id":"l1","borderColor":"#f00"}},"userId":"alice"}}"##
+            )
+        };
+        let report = |server: &mut CanvasSocketServer| {
+            let CanvasQueryResult::Diagnostics(report) =
+                server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+            else {
+                panic!("expected diagnostics");
+            };
+            report
+        };
 
-impl CanvasSocketServerHandle {
-    /// Register client message sender and obtain connection ID.
-    pub async fn connect(
-        &self,
-        conn_tx: mpsc::UnboundedSender<Msg>,
-        canvas_id: CanvasId,
-        user_id: UserId,
-        username: String,
-        session_id: WSSessionId,
-    ) {
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::Connect {
-                conn_tx,
-                canvas_id,
-                user_id,
-                username,
-                session_id,
-            })
+        send_as(&mut server, "alice", &line_added_by("alice", "l1"));
+        // adding is no update, the first update never conflicts
+        send_as(&mut server, "bob", &update(10));
+        clock.advance(Duration::from_secs(1));
+        send_as(&mut server, "bob", &update(20));
+        assert_eq!(report(&mut server).conflicts_per_minute, 0);
+
+        clock.advance(Duration::from_secs(1));
+        send_as(&mut server, "alice", &update(30));
+        assert_eq!(report(&mut server).conflicts_per_minute, 1);
+
+        // outside of the window the update of another user is no conflict
+        clock.advance(provenance::DEFAULT_CONFLICT_WINDOW);
+        send_as(&mut server, "bob", &update(40));
+        clock.advance(Duration::from_millis(500));
+        send_as(&mut server, "alice", &update(50));
+
+        let report = report(&mut server);
+        assert_eq!(report.conflicts_per_minute, 2);
+        assert_eq!(report.contended_shapes.len(), 1);
+        assert_eq!(
+            (
+                report.contended_shapes[0].shape_id.as_str(),
+                report.contended_shapes[0].conflicts
+            ),
+            ("l1", 2)
+        );
+
+        let CanvasQueryResult::ShapeProvenance(Some(provenance)) = server.answer_query(
+            Some(&"canvas".to_string()),
+            CanvasQuery::ShapeProvenance {
+                shape_id: "l1".to_string(),
+            },
+        ) else {
+            panic!("expected the provenance of l1");
+        };
+        assert_eq!(provenance.last_modified_by.as_deref(), Some("alice"));
+        // the server stamps the update, not the client
+        assert_eq!(
+            (provenance.last_modified_at, provenance.modifications),
+            (clock.now_secs(), 5)
+        );
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_readers_of_anonymized_canvases_receive_pseudonyms() {
+        let mut server = test_server(ConnectionLimits::default());
+        let canvas = server.canvases.get_mut("canvas").unwrap();
+        canvas.inner.settings.anonymize_for_readers = true;
+        canvas.inner.settings.reader_salt = Some("salt".to_string());
+        let levels = [
+            ("owner", AccessLevel::Owner),
+            ("moderator", AccessLevel::Moderate),
+            ("writer", AccessLevel::Write),
+            ("reader", AccessLevel::Read),
+        ];
+        for (user_id, level) in &levels {
+            canvas
+                .inner
+                .users
+                .insert(user_id.to_string(), level.clone());
+        }
+
+        let mut receivers = Vec::new();
+        for (user_id, _) in &levels {
+            let (tx, rx) = mpsc::unbounded_channel();
+            server
+                .try_connect(
+                    tx,
+                    "canvas".to_string(),
+                    user_id.to_string(),
+                    user_id.to_string(),
+                    format!("{user_id}-session"),
+                    ConnectionMeta::default(),
+                )
+                .await
+                .unwrap();
+            receivers.push(rx);
+        }
+        for rx in &mut receivers {
+            while rx.try_recv().is_ok() {}
+        }
+
+        let canvas = server.canvases.get_mut("canvas").unwrap();
+        CanvasSocketServer::broadcast_event(
+            canvas,
+            None,
+            CanvasEvents::UserJoined {
+                timestamp: 1,
+                userId: "writer".to_string(),
+                sessionId: "writer-session".to_string(),
+                username: "writer".to_string(),
+                accessLevel: AccessLevel::Write,
+            },
+        );
+
+        let pseudonym = redaction::pseudonym("salt", "writer");
+        for ((user_id, level), rx) in levels.iter().zip(&mut receivers) {
+            let message = rx.try_recv().unwrap();
+            let event: Value = serde_json::from_str(message.text()).unwrap();
+            let expected = if *level == AccessLevel::Read {
+                pseudonym.as_str()
+            } else {
+                "writer"
+            };
+            assert_eq!(event["userId"], expected, "{user_id}");
+            assert_eq!(event["username"], expected, "{user_id}");
+        }
+
+        // a new reader session gets the redacted eventlog as well
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server
+            .try_connect(
+                tx,
+                "canvas".to_string(),
+                "reader".to_string(),
+                "reader".to_string(),
+                "reader-session-2".to_string(),
+                ConnectionMeta::default(),
+            )
+            .await
             .unwrap();
+        let initial_state = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| message.text().to_string())
+            .find(|message| message.contains("InitialStateChunk"))
+            .unwrap();
+        assert!(!initial_state.contains("\"writer\""));
+        assert!(initial_state.contains(&pseudonym));
     }
 
-    pub fn update_canvas_state(
-        &self,
-        canvas_id: CanvasId,
-        state: CanvasState,
-        initiator_id: UserId,
-    ) {
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::UpdateCanvasState {
-                canvas_id,
-                state,
-                initiator_id,
+    #[actix_web::test]
+    async fn test_clock_regression_keeps_persisted_timestamps_ordered() {
+        let clock = Arc::new(ManualClock::new(1_000_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = persist_into_temp_log(&mut server);
+        connect_user(&mut server, "alice", AccessLevel::Write).await;
+
+        // the clock steps back by ten seconds
+        clock.set(999_990_000);
+        connect_user(&mut server, "bob", AccessLevel::Write).await;
+        connect_user(&mut server, "carol", AccessLevel::Read).await;
+        clock.advance(Duration::from_secs(15));
+        connect_user(&mut server, "dave", AccessLevel::Read).await;
+
+        let timestamps: Vec<u64> = std::fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<CanvasEvents>(line)
+                    .unwrap()
+                    .timestamp()
             })
+            .collect();
+        // the header, then a join and a read receipt per user
+        assert_eq!(timestamps[..7], [1_000_000; 7]);
+        assert_eq!(timestamps[7..], [1_000_005; 2]);
+
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.clock_regressions, 1);
+
+        // cutting at the step includes everything stamped during the regression
+        let path = log_path.to_str().unwrap();
+        let replay =
+            replay::replay_log(path, Some(replay::ReplayCutoff::Timestamp(1_000_000))).unwrap();
+        assert_eq!(replay.state.seq, 7);
+        let replay =
+            replay::replay_log(path, Some(replay::ReplayCutoff::Timestamp(999_999))).unwrap();
+        assert_eq!(replay.state.seq, 0);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_client_events_are_persisted_with_the_server_stamp() {
+        let clock = Arc::new(ManualClock::new(1_000_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        let log_path = persist_into_temp_log(&mut server);
+        connect_user(&mut server, "alice", AccessLevel::Write).await;
+
+        // the clock steps back, the client sends its own time in milliseconds
+        clock.set(999_990_000);
+        send_as(
+            &mut server,
+            "alice",
+            &line_added_by("alice", "line")
+                .replace(r#""timestamp":1"#, r#""timestamp":1700000000000"#),
+        );
+
+        let added = persisted_events(log_path.to_str().unwrap())
+            .into_iter()
+            .find(|event| matches!(event, CanvasEvents::ShapeAdded { .. }))
             .unwrap();
+        assert_eq!(added.timestamp(), 1_000_000);
+
+        let _ = std::fs::remove_file(log_path);
     }
 
-    pub fn update_user_permissions(
-        &self,
-        canvas_id: CanvasId,
-        user_id: UserId,
-        access_level: AccessLevel,
-    ) {
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::UpdateUserAccessLevel {
-                canvas_id,
-                user_id,
-                access_level,
+    fn persisted_events(path: &str) -> Vec<CanvasEvents> {
+        EventLogPersistenceJson::open(path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn is_update(event: &CanvasEvents) -> bool {
+        matches!(event, CanvasEvents::ShapeUpdated { .. })
+    }
+
+    fn send_as_session(server: &mut CanvasSocketServer, msg: Msg) {
+        server.handle_raw_message(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+            msg,
+        );
+    }
+
+    struct Drag {
+        server: CanvasSocketServer,
+        clock: Arc<ManualClock>,
+        path: String,
+        origin_rx: mpsc::UnboundedReceiver<SessionMessage>,
+        other_rx: mpsc::UnboundedReceiver<SessionMessage>,
+    }
+
+    /// Adds l1 and drags it with 20 updates 5ms apart, the window of the last updates is still open
+    async fn drag_line(window: Duration) -> Drag {
+        let clock = Arc::new(ManualClock::new(1_000_000_000));
+        let mut server = test_server_with_clock(ConnectionLimits::default(), clock.clone());
+        server.flush_policy.update_coalesce_window = window;
+        let path = use_temp_log(&mut server);
+        let (origin_rx, other_rx) = connect_writer_sessions(&mut server).await;
+
+        send_as_session(&mut server, line_added("add", "l1"));
+        for step in 0..20 {
+            clock.advance(Duration::from_millis(5));
+            let update = format!(
+                r##"{{"type":"ShapeUpdated","opId":"u{step}","origin":"session","timestamp":1,"shape":{{"id":"l1","from":{{"x":{step},"y":{step}}}}}}}"##
+            );
+            send_as_session(&mut server, update);
+        }
+        Drag {
+            server,
+            clock,
+            path,
+            origin_rx,
+            other_rx,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rapid_updates_are_persisted_once_per_window() {
+        let window = Duration::from_millis(150);
+        let Drag {
+            mut server,
+            clock,
+            path,
+            mut origin_rx,
+            mut other_rx,
+        } = drag_line(window).await;
+
+        // every update is broadcast right away, none is persisted yet
+        let broadcasts = received_events(&mut other_rx);
+        assert_eq!(
+            broadcasts.iter().filter(|event| is_update(event)).count(),
+            20
+        );
+        assert!(!persisted_events(&path).iter().any(is_update));
+        let acks = received_events(&mut origin_rx);
+        assert!(matches!(&acks[..], [CanvasEvents::Ack { opId, .. }] if opId == "add"));
+
+        clock.advance(window);
+        server.flush_due_canvases();
+        let persisted = persisted_events(&path);
+        let updates: Vec<&CanvasEvents> =
+            persisted.iter().filter(|event| is_update(event)).collect();
+        assert!(updates.len() <= 2, "{} updates persisted", updates.len());
+        let CanvasEvents::ShapeUpdated { shape, .. } = updates.last().unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(shape["from"], serde_json::json!({ "x": 19, "y": 19 }));
+
+        // the last operation is acknowledged with its line, the others as superseded by it
+        let Some(CanvasEvents::Ack {
+            opId,
+            seq,
+            superseded,
+            ..
+        }) = received_events(&mut origin_rx).pop()
+        else {
+            panic!("expected Ack");
+        };
+        assert_eq!(opId, "u19");
+        assert_eq!(seq as usize, persisted.len());
+        assert_eq!(superseded.len(), 19);
+        assert_eq!(superseded[0], "u0");
+
+        // event_log holds what the eventlog holds
+        let canvas = &server.canvases["canvas"];
+        assert_eq!(
+            canvas
+                .event_log
+                .iter()
+                .filter(|event| is_update(event))
+                .count(),
+            updates.len()
+        );
+        assert!(canvas.pending_updates.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn test_coalesced_log_replays_like_every_update() {
+        let mut coalescing = drag_line(Duration::from_millis(150)).await;
+        coalescing.clock.advance(Duration::from_secs(1));
+        coalescing.server.flush_due_canvases();
+        let coalesced_path = coalescing.path;
+        let full_path = drag_line(Duration::ZERO).await.path;
+
+        let coalesced = persisted_events(&coalesced_path);
+        let full = persisted_events(&full_path);
+        assert_eq!(full.iter().filter(|event| is_update(event)).count(), 20);
+        assert!(coalesced.iter().filter(|event| is_update(event)).count() <= 2);
+        assert_eq!(
+            replay::replay_log(&coalesced_path, None)
+                .unwrap()
+                .state
+                .shapes,
+            replay::replay_log(&full_path, None).unwrap().state.shapes
+        );
+        let _ = std::fs::remove_file(coalesced_path);
+        let _ = std::fs::remove_file(full_path);
+    }
+
+    #[actix_web::test]
+    async fn test_buffered_updates_are_persisted_before_other_events_of_the_shape() {
+        let Drag {
+            mut server, path, ..
+        } = drag_line(Duration::from_millis(150)).await;
+        assert!(!persisted_events(&path).iter().any(is_update));
+
+        // the removal follows the drag in the eventlog, without waiting for the window
+        send_as_session(&mut server, shape_event("ShapeRemoved", "session", "l1"));
+        let persisted = persisted_events(&path);
+        let tail: Vec<&str> = persisted
+            .iter()
+            .rev()
+            .take(2)
+            .map(|event| match event {
+                CanvasEvents::ShapeUpdated { .. } => "updated",
+                CanvasEvents::ShapeRemoved { .. } => "removed",
+                _ => "other",
             })
-            .unwrap();
+            .collect();
+        assert_eq!(tail, ["removed", "updated"]);
+        let canvas = &server.canvases["canvas"];
+        assert!(matches!(
+            canvas.event_log.iter().rev().take(2).collect::<Vec<_>>()[..],
+            [
+                CanvasEvents::ShapeRemoved { .. },
+                CanvasEvents::ShapeUpdated { .. }
+            ]
+        ));
+        assert!(replay::replay_log(&path, None)
+            .unwrap()
+            .state
+            .shapes
+            .is_empty());
+        let _ = std::fs::remove_file(path);
+
+        // a session leaving persists its buffers as well
+        let Drag {
+            mut server, path, ..
+        } = drag_line(Duration::from_millis(150)).await;
+        server.disconnect(
+            "canvas".to_string(),
+            "user".to_string(),
+            "session".to_string(),
+        );
+        assert_eq!(
+            persisted_events(&path)
+                .iter()
+                .filter(|event| is_update(event))
+                .count(),
+            1
+        );
+        let _ = std::fs::remove_file(path);
     }
 
-    /// Broadcast message to current room.
-    pub async fn broadcast_event(
-        &self,
-        canvas_id: CanvasId,
-        user_id: UserId,
-        session_id: WSSessionId,
-        msg: impl Into<Msg>,
-    ) {
-        let (res_tx, res_rx) = oneshot::channel();
+    fn line_at(shape_id: &str, x: i32, y: i32) -> Msg {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"drawer","timestamp":1,"shape":{{"type":"Line","id":"{shape_id}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":{x},"y":{y}}},"to":{{"x":{},"y":{}}}}}}}"##,
+            x + 5,
+            y + 5
+        )
+    }
 
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::HandleMessage {
-                msg: msg.into(),
-                canvas_id,
-                user_id,
-                session_id,
-                res_tx,
+    fn viewport(from: i32, to: i32) -> Msg {
+        format!(
+            r#"{{"type":"ViewportChanged","origin":"viewer","bounds":{{"from":{{"x":{from},"y":{from}}},"to":{{"x":{to},"y":{to}}}}}}}"#
+        )
+    }
+
+    fn added_shape_ids(events: &[CanvasEvents]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                CanvasEvents::ShapeAdded { shape, .. } => Some(shape.get_id()),
+                _ => None,
             })
-            .unwrap();
+            .collect()
+    }
 
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+    #[actix_web::test]
+    async fn test_viewport_skips_shapes_and_catches_up() {
+        let mut server = test_server(ConnectionLimits::default()).with_feature_flags(
+            FeatureFlags::from_specs(["viewport_subscriptions=on".parse().unwrap()]),
+        );
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, mut drawer_rx) = connect_session(&mut server, "drawer").await;
+        let (_, mut viewer_rx) = connect_session(&mut server, "viewer").await;
+        let (_, mut undeclared_rx) = connect_session(&mut server, "undeclared").await;
+        let message = |server: &mut CanvasSocketServer, session: &str, msg: Msg| {
+            server.handle_raw_message(
+                "canvas".to_string(),
+                "user".to_string(),
+                session.to_string(),
+                msg,
+            )
+        };
+
+        message(&mut server, "viewer", viewport(0, 100));
+        while drawer_rx.try_recv().is_ok() {}
+        assert!(received_events(&mut viewer_rx)
+            .iter()
+            .all(|event| !matches!(event, CanvasEvents::ServerNotice { .. })));
+        while undeclared_rx.try_recv().is_ok() {}
+
+        for (shape_id, x) in [("near", 10), ("far", 1000), ("farther", 2000)] {
+            message(&mut server, "drawer", line_at(shape_id, x, x));
+        }
+        assert_eq!(added_shape_ids(&received_events(&mut viewer_rx)), ["near"]);
+        assert_eq!(
+            added_shape_ids(&received_events(&mut undeclared_rx)),
+            ["near", "far", "farther"]
+        );
+
+        // moving the viewport sends exactly the shapes that became visible
+        message(&mut server, "viewer", viewport(900, 1100));
+        let caught_up = received_events(&mut viewer_rx);
+        assert_eq!(added_shape_ids(&caught_up), ["far"]);
+        assert_eq!(caught_up.len(), 1);
+        assert!(received_events(&mut undeclared_rx).is_empty());
+
+        // viewports are never persisted
+        let canvas = &server.canvases["canvas"];
+        assert!(canvas
+            .event_log
+            .iter()
+            .all(|event| !matches!(event, CanvasEvents::ViewportChanged { .. })));
+
+        // without the flag the viewport is rejected and everything is delivered
+        let mut server = test_server(ConnectionLimits::default());
+        server
+            .canvases
+            .get_mut("canvas")
+            .unwrap()
+            .inner
+            .users
+            .insert("user".to_string(), AccessLevel::Write);
+        let (_, _drawer_rx) = connect_session(&mut server, "drawer").await;
+        let (_, mut viewer_rx) = connect_session(&mut server, "viewer").await;
+        while viewer_rx.try_recv().is_ok() {}
+        message(&mut server, "viewer", viewport(0, 100));
+        assert_eq!(
+            received_events(&mut viewer_rx)
+                .iter()
+                .filter_map(|event| match event {
+                    CanvasEvents::ServerNotice { code, .. } => Some(code.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            ["event.feature_disabled"]
+        );
+        message(&mut server, "drawer", line_at("far", 1000, 1000));
+        assert_eq!(added_shape_ids(&received_events(&mut viewer_rx)), ["far"]);
     }
 
-    /// Unregister message sender and broadcast disconnection message to current room.
-    pub fn disconnect(&self, canvas_id: CanvasId, user_id: UserId, session_id: WSSessionId) {
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::Disconnect {
-                canvas_id,
-                user_id,
-                session_id,
+    /// Codes of the notices and Nacks and the ids of the Acks received by a session
+    fn answers(rx: &mut mpsc::UnboundedReceiver<SessionMessage>) -> Vec<String> {
+        received_events(rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                CanvasEvents::ServerNotice { code, .. } => Some(code),
+                CanvasEvents::Nack { opId, code, .. } => Some(format!("{opId}: {code}")),
+                CanvasEvents::Ack { opId, .. } => Some(format!("{opId}: ack")),
+                _ => None,
             })
-            .unwrap();
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_backpressure_throttles_writers_until_the_backlog_drained() {
+        let mut server = test_server(ConnectionLimits::default());
+        let log_path = persist_into_temp_log(&mut server);
+        let mut owner_rx = connect_user(&mut server, "owner", AccessLevel::Owner).await;
+        let mut writer_rx = connect_user(&mut server, "writer", AccessLevel::Write).await;
+        flush_test_canvas(&mut server);
+        // the disk falls behind, nothing is synced until the policy is restored
+        server.flush_policy.max_pending_events = u64::MAX;
+        server.flush_policy.max_pending_time = Duration::from_secs(60 * 60);
+        let events = |events| Backlog {
+            events,
+            bytes: u64::MAX,
+        };
+        server.flush_policy.backpressure = BackpressureLimits {
+            high_water: events(4),
+            low_water: events(1),
+            hard_limit: events(7),
+        };
+        while owner_rx.try_recv().is_ok() {}
+        while writer_rx.try_recv().is_ok() {}
+
+        // the first change of each user also persists them as contributor
+        for op_id in ["w1", "w2", "w3", "w4"] {
+            send_as(&mut server, "writer", &line_added(op_id, op_id));
+        }
+        assert_eq!(
+            answers(&mut writer_rx),
+            [
+                "w1: ack",
+                "w2: ack",
+                "w3: ack",
+                "canvas.throttled",
+                "w4: event.server_pressure"
+            ]
+        );
+        assert_eq!(answers(&mut owner_rx), ["canvas.throttled"]);
+
+        // temporary shapes are never persisted, they are not refused
+        send_as(
+            &mut server,
+            "writer",
+            &shape_added_by("writer", ShapeType::Line, "preview", true),
+        );
+        assert!(answers(&mut writer_rx).is_empty());
+        assert!(server.canvases["canvas"]
+            .temp_shapes
+            .contains_key("preview"));
+
+        // owners keep writing up to the hard limit
+        for op_id in ["o1", "o2", "o3"] {
+            send_as(&mut server, "owner", &line_added(op_id, op_id));
+        }
+        assert_eq!(
+            answers(&mut owner_rx),
+            ["o1: ack", "o2: ack", "o3: event.server_pressure"]
+        );
+
+        server.flush_policy.max_pending_events = 1;
+        server.flush_due_canvases();
+        assert!(answers(&mut writer_rx).contains(&"canvas.throttle_ended".to_string()));
+        send_as(&mut server, "writer", &line_added("w4", "w4"));
+        assert_eq!(answers(&mut writer_rx), ["w4: ack"]);
+
+        let CanvasQueryResult::Diagnostics(report) =
+            server.answer_query(Some(&"canvas".to_string()), CanvasQuery::Diagnostics)
+        else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(report.throttled_per_minute, 1);
+        assert_eq!(report.refused_under_pressure_per_minute, 2);
+        assert!(!report.throttled);
+        assert_eq!(report.backlog_events, 1);
+
+        // everything accepted while throttled is persisted in the order it was accepted
+        let persisted: Vec<String> = EventLogPersistenceJson::open(log_path.to_str().unwrap())
+            .unwrap()
+            .read_lines::<CanvasEvents>()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event {
+                Ok(CanvasEvents::ShapeAdded { shape, .. }) => Some(shape.get_id().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(persisted, ["w1", "w2", "w3", "o1", "o2", "w4"]);
+
+        let _ = std::fs::remove_file(log_path);
     }
 }
@@ -1,21 +1,31 @@
+use super::events::{CanvasEvents, NoticeLevel};
+use super::server::{Msg, SessionMessage};
+use super::store::CanvasId;
+use crate::clock::SharedClock;
+use crate::connection::ConnectionMeta;
+use crate::messages::MessageKey;
+use crate::{authentication::JWTUser, canvas::server::CanvasSocketServerHandle};
+use actix_web::web::Bytes;
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, ProtocolError};
+use futures_util::{
+    future::{select, Either},
+    StreamExt as _,
+};
 use std::{
     pin::pin,
     time::{Duration, Instant},
 };
-use actix_ws::AggregatedMessage;
-use futures_util::{
-    future::{select, Either},
-    StreamExt as _,
+use tokio::{
+    sync::mpsc,
+    time::{interval, sleep_until, Interval},
 };
-use tokio::{sync::mpsc, time::interval};
-use crate::{authentication::JWTUser, canvas::server::CanvasSocketServerHandle};
-use super::store::CanvasId;
 
-/// This is the main loop for each WebSocket connection.
-/// It communicates with the main WebsocketCanvasServer using channels.
-/// This is heavily inspired by the actix-websocket chat example.
-/// Uses ping/pong mechanism to detect broken or dangling connections.
-/// Also handles the initial registration of the session.
+// This is the main loop for each WebSocket connection.
+// It communicates with the main WebsocketCanvasServer using channels.
+// This is heavily inspired by the actix-websocket chat example.
+// Uses ping/pong mechanism to detect broken or dangling connections.
+// Also handles the initial registration of the session.
+// The decisions are made by ConnectionStateMachine, the loop only waits for inputs and executes its actions.
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -23,27 +33,302 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(serde::Deserialize)]
+/// How long a new connection may take to register its session, shorter than CLIENT_TIMEOUT
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Invalid frames tolerated before the session is registered
+const MAX_INVALID_HANDSHAKE_FRAMES: usize = 3;
+
+/// Reasons the socket closes a connection, sent to the client as close code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketClose {
+    /// the client did not start with a valid RegisterSession message
+    HandshakeFailed,
+    /// the client did not register within REGISTRATION_TIMEOUT
+    RegistrationTimeout,
+    /// the server dropped the session, it was rejected or evicted
+    ClosedByServer,
+    /// the user logged out everywhere or the API token was revoked, the client has to authenticate again
+    AuthExpired,
+}
+
+impl SocketClose {
+    /// Application specific codes use the 4000-4999 range
+    pub fn code(self) -> CloseCode {
+        match self {
+            SocketClose::HandshakeFailed => CloseCode::Other(4001),
+            SocketClose::RegistrationTimeout => CloseCode::Other(4002),
+            SocketClose::ClosedByServer => CloseCode::Policy,
+            SocketClose::AuthExpired => CloseCode::Other(4003),
+        }
+    }
+
+    /// Reverse of code, used by clients to interpret the close frame
+    pub fn from_code(code: CloseCode) -> Option<Self> {
+        match code {
+            CloseCode::Other(4001) => Some(SocketClose::HandshakeFailed),
+            CloseCode::Other(4002) => Some(SocketClose::RegistrationTimeout),
+            CloseCode::Other(4003) => Some(SocketClose::AuthExpired),
+            CloseCode::Policy => Some(SocketClose::ClosedByServer),
+            _ => None,
+        }
+    }
+}
+
+impl From<SocketClose> for CloseReason {
+    fn from(close: SocketClose) -> Self {
+        let description = match close {
+            SocketClose::HandshakeFailed => "Session handshake failed",
+            SocketClose::RegistrationTimeout => "Session not registered in time",
+            SocketClose::ClosedByServer => "Session closed by server",
+            SocketClose::AuthExpired => "Session logged out",
+        };
+        CloseReason {
+            code: close.code(),
+            description: Some(description.to_string()),
+        }
+    }
+}
+
+/// First message a client sends, carries the session id the client picked
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(tag = "type")]
-struct RegisterSession {
-    session: String,
+pub struct RegisterSession {
+    pub session: String,
+}
+
+impl RegisterSession {
+    /// Canvas events never contain the tag, so only matching messages are deserialized
+    fn matches(text: &str) -> bool {
+        text.contains("RegisterSession") && serde_json::from_str::<RegisterSession>(text).is_ok()
+    }
+}
+
+/// Notices of the socket are sent directly, they are not related to a canvas
+async fn send_notice(session: &mut actix_ws::Session, clock: &SharedClock, key: MessageKey) {
+    let notice: Result<Msg, _> =
+        (&CanvasEvents::notice(clock.now_secs(), NoticeLevel::Error, key)).try_into();
+    if let Ok(notice) = notice {
+        let _ = session.text(notice).await;
+    }
+}
+
+/// Phases of a connection, the handshake comes first and every connection ends in Closing
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConnectionState {
+    /// waiting for the RegisterSession message, the first message a client has to send
+    AwaitingRegistration {
+        invalid_frames: usize,
+    },
+    Active {
+        session_id: String,
+    },
+    Closing(Option<CloseReason>),
+}
+
+/// What the connection loop has to do for an input of the state machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    SendPong(Bytes),
+    SendPing,
+    SendNotice(MessageKey),
+    /// the client registered its session, connect it to the canvas server
+    Connect(String),
+    /// canvas event of the client for the canvas server
+    Forward(String),
+    /// message of the canvas server for the client
+    SendText(Msg),
+    Close(Option<CloseReason>),
+    Nothing,
+}
+
+///
+/// Decides how a connection reacts to client frames, server messages and heartbeat ticks
+/// Time is passed in, the state machine never reads a clock and never touches the socket
+/// Other text frames before registration are answered with a notice until MAX_INVALID_HANDSHAKE_FRAMES is reached
+///
+#[derive(Debug)]
+struct ConnectionStateMachine {
+    state: ConnectionState,
+    registration_deadline: Instant,
+    last_heartbeat: Instant,
+}
+
+impl ConnectionStateMachine {
+    fn new(now: Instant, registration_timeout: Duration) -> Self {
+        ConnectionStateMachine {
+            state: ConnectionState::AwaitingRegistration { invalid_frames: 0 },
+            registration_deadline: now + registration_timeout,
+            last_heartbeat: now,
+        }
+    }
+
+    fn is_registered(&self) -> bool {
+        matches!(self.state, ConnectionState::Active { .. })
+    }
+
+    fn close(&mut self, reason: Option<CloseReason>) -> Vec<Action> {
+        self.state = ConnectionState::Closing(reason.clone());
+        vec![Action::Close(reason)]
+    }
+
+    /// A client frame, None once the client stream ended
+    fn handle_client_frame(
+        &mut self,
+        frame: Option<Result<AggregatedMessage, ProtocolError>>,
+        now: Instant,
+    ) -> Vec<Action> {
+        let msg = match frame {
+            Some(Ok(msg)) => msg,
+            Some(Err(err)) => {
+                println!("{}", err);
+                return self.close(None);
+            }
+            None => return self.close(None),
+        };
+
+        match &mut self.state {
+            ConnectionState::Closing(_) => vec![Action::Nothing],
+
+            ConnectionState::AwaitingRegistration { invalid_frames } => match msg {
+                AggregatedMessage::Ping(bytes) => vec![Action::SendPong(bytes)],
+
+                AggregatedMessage::Pong(_) => vec![Action::Nothing],
+
+                AggregatedMessage::Text(text) => {
+                    if let Ok(message) = serde_json::from_str::<RegisterSession>(&text) {
+                        self.last_heartbeat = now;
+                        self.state = ConnectionState::Active {
+                            session_id: message.session.clone(),
+                        };
+                        return vec![Action::Connect(message.session)];
+                    }
+
+                    *invalid_frames += 1;
+                    if *invalid_frames >= MAX_INVALID_HANDSHAKE_FRAMES {
+                        let mut actions =
+                            vec![Action::SendNotice(MessageKey::SessionNotRegistered)];
+                        actions.extend(self.close(Some(SocketClose::HandshakeFailed.into())));
+                        return actions;
+                    }
+                    vec![Action::SendNotice(MessageKey::SessionNotRegistered)]
+                }
+
+                AggregatedMessage::Binary(_bin) => {
+                    self.close(Some(SocketClose::HandshakeFailed.into()))
+                }
+
+                AggregatedMessage::Close(reason) => self.close(reason),
+            },
+
+            ConnectionState::Active { .. } => match msg {
+                AggregatedMessage::Ping(bytes) => {
+                    self.last_heartbeat = now;
+                    vec![Action::SendPong(bytes)]
+                }
+
+                AggregatedMessage::Pong(_) => {
+                    self.last_heartbeat = now;
+                    vec![Action::Nothing]
+                }
+
+                AggregatedMessage::Text(text) => {
+                    if RegisterSession::matches(&text) {
+                        // not a canvas event, don't forward it to the server
+                        vec![Action::SendNotice(MessageKey::SessionAlreadyRegistered)]
+                    } else {
+                        vec![Action::Forward(text.trim().to_string())]
+                    }
+                }
+
+                AggregatedMessage::Binary(_bin) => {
+                    println!("unexpected binary message");
+                    vec![Action::Nothing]
+                }
+
+                AggregatedMessage::Close(reason) => self.close(reason),
+            },
+        }
+    }
+
+    /// A message of the canvas server, None once the server dropped the session
+    fn handle_server_msg(&mut self, msg: Option<SessionMessage>) -> Vec<Action> {
+        if !self.is_registered() {
+            return vec![Action::Nothing];
+        }
+        match msg {
+            Some(SessionMessage::Text(msg)) => vec![Action::SendText(msg)],
+            // e.g. the user logged out everywhere
+            Some(SessionMessage::Close(close)) => self.close(Some(close.into())),
+            // the session was rejected or evicted
+            None => self.close(Some(SocketClose::ClosedByServer.into())),
+        }
+    }
+
+    /// Heartbeat tick, before registration the tick at the registration deadline
+    fn handle_tick(&mut self, now: Instant) -> Vec<Action> {
+        match &self.state {
+            ConnectionState::AwaitingRegistration { .. } if now >= self.registration_deadline => {
+                self.close(Some(SocketClose::RegistrationTimeout.into()))
+            }
+            ConnectionState::AwaitingRegistration { .. } | ConnectionState::Closing(_) => {
+                vec![Action::Nothing]
+            }
+            ConnectionState::Active { session_id } => {
+                // if no heartbeat ping/pong received recently, close the connection
+                if now.duration_since(self.last_heartbeat) > CLIENT_TIMEOUT {
+                    println!("Session {session_id} timed out");
+                    return self.close(None);
+                }
+                vec![Action::SendPing]
+            }
+        }
+    }
 }
 
 /// Echo text & binary messages received from the client, respond to ping messages, and monitor
 /// connection health to detect network issues and free up resources.
 pub async fn start_canvas_websocket_connection(
     chat_server: CanvasSocketServerHandle,
-    mut session: actix_ws::Session,
+    session: actix_ws::Session,
     msg_stream: actix_ws::MessageStream,
     canvas_id: CanvasId,
     user: JWTUser,
+    connection: ConnectionMeta,
+    clock: SharedClock,
 ) {
-    let mut last_heartbeat = Instant::now();
-    let mut interval = interval(HEARTBEAT_INTERVAL);
+    run_connection(
+        chat_server,
+        session,
+        msg_stream,
+        canvas_id,
+        user,
+        connection,
+        REGISTRATION_TIMEOUT,
+        clock,
+    )
+    .await
+}
 
-    let (message_tx, mut message_rx) = mpsc::unbounded_channel();
-    let mut client_session_id: Option<String> = None;
+/// Inputs of the state machine, whichever future completed first
+enum Input {
+    ClientFrame(Option<Result<AggregatedMessage, ProtocolError>>),
+    ServerMsg(Option<SessionMessage>),
+    Tick,
+}
 
+// split from start_canvas_websocket_connection so tests can shorten the registration timeout
+#[allow(clippy::too_many_arguments)]
+async fn run_connection(
+    chat_server: CanvasSocketServerHandle,
+    mut session: actix_ws::Session,
+    msg_stream: actix_ws::MessageStream,
+    canvas_id: CanvasId,
+    user: JWTUser,
+    connection: ConnectionMeta,
+    registration_timeout: Duration,
+    clock: SharedClock,
+) {
     let msg_stream = msg_stream
         .max_frame_size(128 * 1024)
         .aggregate_continuations()
@@ -51,103 +336,689 @@ pub async fn start_canvas_websocket_connection(
 
     let mut msg_stream = pin!(msg_stream);
 
-    let close_reason = loop {
+    let mut machine = ConnectionStateMachine::new(Instant::now(), registration_timeout);
+    let mut connection = Some(connection);
+    let mut client_session_id = None;
+    // created once the session registered, the server closes the session by dropping message_tx
+    let mut server: Option<(mpsc::UnboundedReceiver<SessionMessage>, Interval)> = None;
+
+    let close_reason = 'connection: loop {
         // most of the futures we process need to be stack-pinned to work with select()
-        let tick = pin!(interval.tick());
-        let msg_rx = pin!(message_rx.recv());
+        let input = match server.as_mut() {
+            None => {
+                let deadline = pin!(sleep_until(machine.registration_deadline.into()));
+                match select(msg_stream.next(), deadline).await {
+                    Either::Left((frame, _)) => Input::ClientFrame(frame),
+                    Either::Right(_) => Input::Tick,
+                }
+            }
+            Some((message_rx, heartbeat)) => {
+                let tick = pin!(heartbeat.tick());
+                let msg_rx = pin!(message_rx.recv());
+                let messages = pin!(select(msg_stream.next(), msg_rx));
+                match select(messages, tick).await {
+                    Either::Left((Either::Left((frame, _)), _)) => Input::ClientFrame(frame),
+                    Either::Left((Either::Right((msg, _)), _)) => Input::ServerMsg(msg),
+                    Either::Right(_) => Input::Tick,
+                }
+            }
+        };
 
-        // TODO: nested select is pretty gross for readability on the match
-        let messages = pin!(select(msg_stream.next(), msg_rx));
+        let actions = match input {
+            Input::ClientFrame(frame) => machine.handle_client_frame(frame, Instant::now()),
+            Input::ServerMsg(msg) => machine.handle_server_msg(msg),
+            Input::Tick => machine.handle_tick(Instant::now()),
+        };
 
-        match select(messages, tick).await {
-            // commands & messages received from client
-            Either::Left((Either::Left((Some(Ok(msg)), _)), _)) => match msg {
-                AggregatedMessage::Ping(bytes) => {
-                    last_heartbeat = Instant::now();
-                    session.pong(&bytes).await.unwrap();
+        for action in actions {
+            match action {
+                Action::SendPong(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break 'connection None;
+                    }
                 }
 
-                AggregatedMessage::Pong(_) => {
-                    last_heartbeat = Instant::now();
+                Action::SendPing => {
+                    let _ = session.ping(b"").await;
                 }
 
-                AggregatedMessage::Text(text) => {
-                    if let Some(client_session_id) = &client_session_id {
-                        // println!("Received message: {user} in {canvas_id}: {msg}");
-                        let msg = text.trim();
+                Action::SendNotice(key) => send_notice(&mut session, &clock, key).await,
+
+                Action::Connect(session_id) => {
+                    let (message_tx, message_rx) = mpsc::unbounded_channel();
+                    chat_server
+                        .connect(
+                            message_tx,
+                            canvas_id.clone(),
+                            user.id.clone(),
+                            user.username.clone(),
+                            session_id.clone(),
+                            connection.take().unwrap_or_default(),
+                        )
+                        .await;
+                    client_session_id = Some(session_id);
+                    server = Some((message_rx, interval(HEARTBEAT_INTERVAL)));
+                }
+
+                Action::Forward(msg) => {
+                    if let Some(session_id) = &client_session_id {
                         chat_server
                             .broadcast_event(
                                 canvas_id.clone(),
                                 user.id.clone(),
-                                client_session_id.clone(),
+                                session_id.clone(),
                                 msg,
                             )
                             .await;
-                    } else {
-                        let message = serde_json::from_str::<RegisterSession>(&text);
-                        client_session_id =
-                            message.map(|message| Some(message.session)).unwrap_or(None);
-                        if let Some(origin) = &client_session_id {
-                            chat_server
-                                .connect(
-                                    message_tx.clone(),
-                                    canvas_id.clone(),
-                                    user.id.clone(),
-                                    user.username.clone(),
-                                    origin.clone(),
-                                )
-                                .await;
-                        } else {
-                            println!("Invalid session message received {text}");
-                            break None;
-                        }
                     }
                 }
 
-                AggregatedMessage::Binary(_bin) => {
-                    println!("unexpected binary message");
+                Action::SendText(msg) => {
+                    if session.text(msg).await.is_err() {
+                        break 'connection None;
+                    }
                 }
 
-                AggregatedMessage::Close(reason) => break reason,
-            },
+                Action::Close(reason) => break 'connection reason,
 
-            // client WebSocket stream error
-            Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
-                println!("{}", err);
-                break None;
+                Action::Nothing => {}
             }
+        }
+    };
 
-            // client WebSocket stream ended
-            Either::Left((Either::Left((None, _)), _)) => break None,
+    if let Some(session_id) = client_session_id {
+        chat_server.disconnect(canvas_id, user.id.clone(), session_id);
+    }
 
-            // chat messages received from other room participants
-            Either::Left((Either::Right((Some(chat_msg), _)), _)) => {
-                session.text(chat_msg).await.unwrap();
-            }
+    // attempt to close connection gracefully
+    let _ = session.close(close_reason).await;
+}
 
-            // all connection's message senders were dropped
-            Either::Left((Either::Right((None, _)), _)) => unreachable!(
-                "all connection message senders were dropped; chat server may have panicked"
-            ),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::server::Command;
+    use actix_http::ws::{OpCode, Parser};
+    use actix_web::{
+        http::header,
+        web::{self, Bytes, BytesMut},
+        FromRequest,
+    };
+    use tokio::sync::oneshot;
 
-            // heartbeat internal tick
-            Either::Right((_inst, _)) => {
-                // if no heartbeat ping/pong received recently, close the connection
-                if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
-                    println!("User {} in {canvas_id} timed out", user.id);
-                    break None;
-                }
+    /// Client frames are masked, as a browser would send them
+    fn text_frame(text: &str) -> Bytes {
+        let mut frame = BytesMut::new();
+        Parser::write_message(&mut frame, text, OpCode::Text, true, true);
+        frame.freeze()
+    }
+
+    struct Connection {
+        /// text frames sent by the server
+        texts: Vec<String>,
+        close: Option<CloseReason>,
+        commands: Vec<Command>,
+    }
+
+    impl Connection {
+        fn notice_codes(&self) -> Vec<String> {
+            self.texts
+                .iter()
+                .filter_map(|text| match serde_json::from_str(text) {
+                    Ok(CanvasEvents::ServerNotice { code, .. }) => Some(code),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn close_code(&self) -> Option<CloseCode> {
+            self.close.as_ref().map(|reason| reason.code)
+        }
+
+        fn forwarded_events(&self) -> usize {
+            self.commands
+                .iter()
+                .filter(|command| matches!(command, Command::HandleMessage { .. }))
+                .count()
+        }
+    }
+
+    /// Runs a connection with the client frames, the client stream stays open
+    /// so the connection can only end by the server closing it
+    async fn connect(frames: Vec<Bytes>, registration_timeout: Duration) -> Connection {
+        connect_with_replies(frames, registration_timeout, None).await
+    }
+
+    /// Like connect, the server answers the first forwarded event with the replies and drops the session
+    async fn connect_with_replies(
+        frames: Vec<Bytes>,
+        registration_timeout: Duration,
+        mut replies: Option<Vec<SessionMessage>>,
+    ) -> Connection {
+        let request = actix_web::test::TestRequest::get()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_request();
+        let (mut client, payload) = actix_http::h1::Payload::create(false);
+        let payload = web::Payload::from_request(&request, &mut payload.into())
+            .await
+            .unwrap();
+        let (response, session, msg_stream) = actix_ws::handle(&request, payload).unwrap();
 
-                // send heartbeat ping
-                let _ = session.ping(b"").await;
+        for frame in frames {
+            client.feed_data(frame);
+        }
+
+        let (handle, mut commands) = CanvasSocketServerHandle::detached();
+        // answers the commands, keeps the session open unless there are replies
+        let server = actix_web::rt::spawn(async move {
+            let mut received = Vec::new();
+            let mut session_tx = None;
+            while let Some(command) = commands.recv().await {
+                let command = match command {
+                    Command::Connect {
+                        conn_tx,
+                        canvas_id,
+                        user_id,
+                        username,
+                        session_id,
+                        connection,
+                    } => {
+                        session_tx = Some(conn_tx);
+                        Command::Connect {
+                            conn_tx: mpsc::unbounded_channel().0,
+                            canvas_id,
+                            user_id,
+                            username,
+                            session_id,
+                            connection,
+                        }
+                    }
+                    Command::HandleMessage {
+                        msg,
+                        canvas_id,
+                        user_id,
+                        session_id,
+                        res_tx,
+                    } => {
+                        let _ = res_tx.send(());
+                        if let Some(replies) = replies.take() {
+                            // dropping the sender closes the session
+                            if let Some(session_tx) = session_tx.take() {
+                                for reply in replies {
+                                    let _ = session_tx.send(reply);
+                                }
+                            }
+                        }
+                        Command::HandleMessage {
+                            msg,
+                            canvas_id,
+                            user_id,
+                            session_id,
+                            res_tx: oneshot::channel().0,
+                        }
+                    }
+                    command => command,
+                };
+                received.push(command);
             }
+            received
+        });
+        let user = JWTUser {
+            id: "user".to_string(),
+            username: "user".to_string(),
+            email: "user@example.com".to_string(),
+            claims: Vec::new(),
         };
-    };
+        actix_web::rt::spawn(run_connection(
+            handle,
+            session,
+            msg_stream,
+            "canvas".to_string(),
+            user,
+            ConnectionMeta::default(),
+            registration_timeout,
+            crate::clock::system(),
+        ));
 
-    if let Some(session_id) = client_session_id {
-        chat_server.disconnect(canvas_id, user.id.clone(), session_id);
+        // the body ends once the connection dropped its session
+        let mut body = BytesMut::from(
+            &actix_web::body::to_bytes(response.into_body())
+                .await
+                .unwrap()[..],
+        );
+        drop(client);
+
+        let mut connection = Connection {
+            texts: Vec::new(),
+            close: None,
+            commands: Vec::new(),
+        };
+        while let Some((_, opcode, payload)) = Parser::parse(&mut body, false, usize::MAX).unwrap()
+        {
+            let payload = payload.unwrap_or_default();
+            match opcode {
+                OpCode::Text => connection
+                    .texts
+                    .push(String::from_utf8(payload.to_vec()).unwrap()),
+                OpCode::Close => connection.close = Parser::parse_close_payload(&payload),
+                _ => {}
+            }
+        }
+        connection.commands = server.await.unwrap();
+        connection
     }
 
-    // attempt to close connection gracefully
-    let _ = session.close(close_reason).await;
+    #[actix_web::test]
+    async fn test_invalid_frames_fail_handshake() {
+        let frames = (0..MAX_INVALID_HANDSHAKE_FRAMES)
+            .map(|_| {
+                text_frame(r#"{"type":"ShapeRemoved","origin":"s1","timestamp":1,"shapeId":"a"}"#)
+            })
+            .collect();
+        let connection = connect(frames, REGISTRATION_TIMEOUT).await;
+
+        assert_eq!(
+            connection.close_code(),
+            Some(SocketClose::HandshakeFailed.code())
+        );
+        assert_eq!(
+            connection.notice_codes(),
+            vec![MessageKey::SessionNotRegistered.key(); MAX_INVALID_HANDSHAKE_FRAMES]
+        );
+        assert!(connection.commands.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_binary_frame_fails_handshake() {
+        let mut frame = BytesMut::new();
+        Parser::write_message(&mut frame, [1, 2, 3], OpCode::Binary, true, true);
+        let connection = connect(
+            vec![
+                frame.freeze(),
+                text_frame(r#"{"type":"RegisterSession","session":"s1"}"#),
+            ],
+            REGISTRATION_TIMEOUT,
+        )
+        .await;
+
+        assert_eq!(
+            connection.close_code(),
+            Some(SocketClose::HandshakeFailed.code())
+        );
+        assert!(connection.commands.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_silent_client_is_closed_after_deadline() {
+        let connection = connect(Vec::new(), Duration::from_millis(50)).await;
+
+        assert_eq!(
+            connection.close_code(),
+            Some(SocketClose::RegistrationTimeout.code())
+        );
+        assert!(connection.commands.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_register_session_is_not_forwarded() {
+        let mut close = BytesMut::new();
+        Parser::write_close(&mut close, Some(CloseCode::Normal.into()), true);
+        let connection = connect(
+            vec![
+                text_frame(r#"{"type":"RegisterSession","session":"s1"}"#),
+                text_frame(r#"{"type":"RegisterSession","session":"s2"}"#),
+                close.freeze(),
+            ],
+            REGISTRATION_TIMEOUT,
+        )
+        .await;
+
+        assert_eq!(connection.close_code(), Some(CloseCode::Normal));
+        assert_eq!(
+            connection.notice_codes(),
+            vec![MessageKey::SessionAlreadyRegistered.key()]
+        );
+        assert!(matches!(
+            connection.commands.as_slice(),
+            [Command::Connect { .. }, Command::Disconnect { .. }]
+        ));
+        assert_eq!(connection.forwarded_events(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_event_round_trip_ends_with_server_close() {
+        let replies = vec![
+            r#"{"type":"ShapeRemoved","origin":"s1","timestamp":1,"shapeId":"a"}"#.to_string(),
+            notice(MessageKey::SessionAuthExpired),
+        ];
+        let mut server_messages: Vec<SessionMessage> =
+            replies.iter().cloned().map(SessionMessage::Text).collect();
+        server_messages.push(SessionMessage::Close(SocketClose::AuthExpired));
+        let connection = connect_with_replies(
+            vec![
+                text_frame(r#"{"type":"RegisterSession","session":"s1"}"#),
+                text_frame(" {\"type\":\"ShapeRemoved\",\"origin\":\"s1\",\"timestamp\":1,\"shapeId\":\"a\"}\n"),
+            ],
+            REGISTRATION_TIMEOUT,
+            Some(server_messages),
+        )
+        .await;
+
+        assert_eq!(connection.texts, replies);
+        assert_eq!(
+            connection.close_code(),
+            Some(SocketClose::AuthExpired.code())
+        );
+        match connection.commands.as_slice() {
+            [Command::Connect { session_id, .. }, Command::HandleMessage { msg, .. }, Command::Disconnect { .. }] =>
+            {
+                assert_eq!(session_id, "s1");
+                assert_eq!(msg, &replies[0]);
+            }
+            commands => panic!("unexpected commands {}", commands.len()),
+        }
+    }
+
+    fn notice(key: MessageKey) -> Msg {
+        (&CanvasEvents::notice(0, NoticeLevel::Error, key))
+            .try_into()
+            .unwrap()
+    }
+
+    fn frame(msg: AggregatedMessage) -> Option<Result<AggregatedMessage, ProtocolError>> {
+        Some(Ok(msg))
+    }
+
+    fn text(text: &'static str) -> Option<Result<AggregatedMessage, ProtocolError>> {
+        frame(AggregatedMessage::Text(text.into()))
+    }
+
+    const REGISTER: &str = r#"{"type":"RegisterSession","session":"s1"}"#;
+    const EVENT: &str = r#"{"type":"ShapeRemoved","origin":"s1","timestamp":1,"shapeId":"a"}"#;
+
+    fn registered(now: Instant) -> ConnectionStateMachine {
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        machine.handle_client_frame(text(REGISTER), now);
+        machine
+    }
+
+    #[test]
+    fn test_register_session_activates_connection() {
+        let now = Instant::now();
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        assert_eq!(
+            machine.state,
+            ConnectionState::AwaitingRegistration { invalid_frames: 0 }
+        );
+
+        let actions = machine.handle_client_frame(text(REGISTER), now);
+        assert_eq!(actions, vec![Action::Connect("s1".to_string())]);
+        assert_eq!(
+            machine.state,
+            ConnectionState::Active {
+                session_id: "s1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_ping_and_pong_before_registration() {
+        let now = Instant::now();
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+
+        let ping = AggregatedMessage::Ping(Bytes::from_static(b"hi"));
+        assert_eq!(
+            machine.handle_client_frame(frame(ping), now),
+            vec![Action::SendPong(Bytes::from_static(b"hi"))]
+        );
+        let pong = AggregatedMessage::Pong(Bytes::new());
+        assert_eq!(
+            machine.handle_client_frame(frame(pong), now),
+            vec![Action::Nothing]
+        );
+        assert!(!machine.is_registered());
+    }
+
+    #[test]
+    fn test_invalid_frames_fail_handshake_at_limit() {
+        let now = Instant::now();
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        let notice = Action::SendNotice(MessageKey::SessionNotRegistered);
+
+        for invalid_frames in 1..MAX_INVALID_HANDSHAKE_FRAMES {
+            assert_eq!(
+                machine.handle_client_frame(text(EVENT), now),
+                vec![notice.clone()]
+            );
+            assert_eq!(
+                machine.state,
+                ConnectionState::AwaitingRegistration { invalid_frames }
+            );
+        }
+
+        let failed: Option<CloseReason> = Some(SocketClose::HandshakeFailed.into());
+        assert_eq!(
+            machine.handle_client_frame(text(EVENT), now),
+            vec![notice, Action::Close(failed.clone())]
+        );
+        assert_eq!(machine.state, ConnectionState::Closing(failed));
+    }
+
+    #[test]
+    fn test_binary_frame_before_registration_fails_handshake() {
+        let now = Instant::now();
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        let binary = AggregatedMessage::Binary(Bytes::from_static(&[1, 2, 3]));
+        assert_eq!(
+            machine.handle_client_frame(frame(binary), now),
+            vec![Action::Close(Some(SocketClose::HandshakeFailed.into()))]
+        );
+    }
+
+    #[test]
+    fn test_client_close_keeps_reason() {
+        let now = Instant::now();
+        let reason = Some(CloseReason::from(CloseCode::Normal));
+
+        let mut awaiting = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        assert_eq!(
+            awaiting.handle_client_frame(frame(AggregatedMessage::Close(reason.clone())), now),
+            vec![Action::Close(reason.clone())]
+        );
+
+        let mut active = registered(now);
+        assert_eq!(
+            active.handle_client_frame(frame(AggregatedMessage::Close(reason.clone())), now),
+            vec![Action::Close(reason.clone())]
+        );
+        assert_eq!(active.state, ConnectionState::Closing(reason));
+    }
+
+    #[test]
+    fn test_stream_error_and_end_close_without_reason() {
+        let now = Instant::now();
+        let mut awaiting = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        assert_eq!(
+            awaiting.handle_client_frame(Some(Err(ProtocolError::Overflow)), now),
+            vec![Action::Close(None)]
+        );
+
+        let mut active = registered(now);
+        assert_eq!(
+            active.handle_client_frame(Some(Err(ProtocolError::Overflow)), now),
+            vec![Action::Close(None)]
+        );
+
+        let mut ended = registered(now);
+        assert_eq!(
+            ended.handle_client_frame(None, now),
+            vec![Action::Close(None)]
+        );
+        assert_eq!(ended.state, ConnectionState::Closing(None));
+    }
+
+    #[test]
+    fn test_registration_deadline() {
+        let now = Instant::now();
+        let mut machine = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        assert_eq!(
+            machine.handle_tick(now + REGISTRATION_TIMEOUT / 2),
+            vec![Action::Nothing]
+        );
+        assert_eq!(
+            machine.handle_tick(now + REGISTRATION_TIMEOUT),
+            vec![Action::Close(Some(SocketClose::RegistrationTimeout.into()))]
+        );
+
+        // registered sessions are not bound to the deadline
+        let mut active = registered(now);
+        active.handle_client_frame(
+            frame(AggregatedMessage::Pong(Bytes::new())),
+            now + REGISTRATION_TIMEOUT,
+        );
+        assert_eq!(
+            active.handle_tick(now + REGISTRATION_TIMEOUT),
+            vec![Action::SendPing]
+        );
+    }
+
+    #[test]
+    fn test_events_are_forwarded_trimmed() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        let padded = AggregatedMessage::Text(format!("  {EVENT}\n").into());
+        assert_eq!(
+            machine.handle_client_frame(frame(padded), now),
+            vec![Action::Forward(EVENT.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_register_session_is_answered_with_notice() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        assert_eq!(
+            machine.handle_client_frame(text(r#"{"type":"RegisterSession","session":"s2"}"#), now),
+            vec![Action::SendNotice(MessageKey::SessionAlreadyRegistered)]
+        );
+        assert_eq!(
+            machine.state,
+            ConnectionState::Active {
+                session_id: "s1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_binary_frame_while_active_is_ignored() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        let binary = AggregatedMessage::Binary(Bytes::from_static(&[1, 2, 3]));
+        assert_eq!(
+            machine.handle_client_frame(frame(binary), now),
+            vec![Action::Nothing]
+        );
+        assert!(machine.is_registered());
+    }
+
+    #[test]
+    fn test_silent_client_times_out() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        assert_eq!(machine.handle_tick(now), vec![Action::SendPing]);
+        assert_eq!(
+            machine.handle_tick(now + CLIENT_TIMEOUT),
+            vec![Action::SendPing]
+        );
+        assert_eq!(
+            machine.handle_tick(now + CLIENT_TIMEOUT + Duration::from_millis(1)),
+            vec![Action::Close(None)]
+        );
+        assert_eq!(machine.state, ConnectionState::Closing(None));
+    }
+
+    #[test]
+    fn test_ping_and_pong_refresh_heartbeat() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        let ping_at = now + HEARTBEAT_INTERVAL;
+        let ping = AggregatedMessage::Ping(Bytes::new());
+        assert_eq!(
+            machine.handle_client_frame(frame(ping), ping_at),
+            vec![Action::SendPong(Bytes::new())]
+        );
+        let pong_at = ping_at + HEARTBEAT_INTERVAL;
+        assert_eq!(
+            machine.handle_client_frame(frame(AggregatedMessage::Pong(Bytes::new())), pong_at),
+            vec![Action::Nothing]
+        );
+        assert_eq!(
+            machine.handle_tick(pong_at + CLIENT_TIMEOUT),
+            vec![Action::SendPing]
+        );
+        assert_eq!(
+            machine.handle_tick(pong_at + CLIENT_TIMEOUT * 2),
+            vec![Action::Close(None)]
+        );
+    }
+
+    #[test]
+    fn test_server_messages_are_sent_to_client() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        assert_eq!(
+            machine.handle_server_msg(Some(SessionMessage::Text(EVENT.to_string()))),
+            vec![Action::SendText(EVENT.to_string())]
+        );
+
+        // nothing is connected before registration
+        let mut awaiting = ConnectionStateMachine::new(now, REGISTRATION_TIMEOUT);
+        assert_eq!(awaiting.handle_server_msg(None), vec![Action::Nothing]);
+        assert!(!awaiting.is_registered());
+    }
+
+    #[test]
+    fn test_server_decides_the_close_reason() {
+        let now = Instant::now();
+        // a notice alone doesn't change how the session closes
+        let mut evicted = registered(now);
+        evicted.handle_server_msg(Some(SessionMessage::Text(notice(
+            MessageKey::SessionAuthExpired,
+        ))));
+        assert_eq!(
+            evicted.handle_server_msg(None),
+            vec![Action::Close(Some(SocketClose::ClosedByServer.into()))]
+        );
+
+        let mut logged_out = registered(now);
+        assert_eq!(
+            logged_out.handle_server_msg(Some(SessionMessage::Close(SocketClose::AuthExpired))),
+            vec![Action::Close(Some(SocketClose::AuthExpired.into()))]
+        );
+        assert_eq!(logged_out.handle_server_msg(None), vec![Action::Nothing]);
+    }
+
+    #[test]
+    fn test_closing_ignores_inputs() {
+        let now = Instant::now();
+        let mut machine = registered(now);
+        machine.handle_client_frame(None, now);
+
+        assert_eq!(
+            machine.handle_client_frame(text(EVENT), now),
+            vec![Action::Nothing]
+        );
+        assert_eq!(
+            machine.handle_server_msg(Some(SessionMessage::Text(EVENT.to_string()))),
+            vec![Action::Nothing]
+        );
+        assert_eq!(
+            machine.handle_tick(now + CLIENT_TIMEOUT * 2),
+            vec![Action::Nothing]
+        );
+        assert_eq!(machine.state, ConnectionState::Closing(None));
+    }
 }
@@ -1,17 +1,41 @@
 use actix::prelude::*;
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use utoipa::ToSchema;
 
 use crate::{
-    persistence::{self, PersistEventMessage},
+    clock::{MonotonicStamps, SharedClock},
+    mailbox::{self, timed_atomic, DegradedMode, HandlerTrace},
+    messages::MessageKey,
+    persistence::{self, PersistEventMessage, ReplayIssue, ReplayIssueKind},
     userstore::UserId,
 };
 
 /// Event Store for Canvas events
 /// Same concept as userstore.rs
-
-use super::error::CanvasStoreError;
+use super::{
+    access_requests::{
+        AccessRequest, AccessRequestResolution, AccessRequestStatus, AccessRequests,
+    },
+    claims::ClaimIndex,
+    digest::{DigestFrequency, DigestMark},
+    error::CanvasStoreError,
+    events::ShapeType,
+    guests::{self, GuestAccess},
+    names::NameIndex,
+    orphans::{self, CanvasRecord},
+    palette::PaletteColor,
+    quota::{QuotaKind, QuotaLimits, QuotaUsage, QuotaWarning, QuotaWarnings},
+    replay::ReplayCache,
+    retention::RetentionOverrides,
+    server::{canvas_log_path, CanvasSocketServerHandle},
+    tokens::{self, ApiToken, TokenId},
+};
 
 /// Constants for the canvas id generation
 /// Splits the alphabet into single chars and creates a str
@@ -39,9 +63,32 @@ macro_rules! define_canvas_id_constants {
 pub const MAX_ID_GENERATION_ITERATIONS: usize = 10;
 pub const CANVAS_ID_LENGTH: usize = 12;
 
+/// How often expired temporary access is removed from the store
+pub const GRANT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often soft deleted canvases past their grace period are purged
+pub const PURGE_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time a soft deleted canvas can be restored before it is purged
+pub const DEFAULT_DELETION_GRACE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Visits of a user to the same canvas within this window are only persisted once
+pub const CANVAS_VISIT_DEBOUNCE: Duration = Duration::from_secs(60 * 60);
+
+/// Canvases listed as recently visited
+pub const RECENT_CANVAS_LIMIT: usize = 10;
+
 define_canvas_id_constants!("1234567890abcdef", 16);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+/// Ids as generated by CreateCanvasMessage, used before trusting a file name as canvas id
+pub fn is_valid_canvas_id(canvas_id: &str) -> bool {
+    canvas_id.len() == CANVAS_ID_LENGTH
+        && canvas_id
+            .chars()
+            .all(|c| CANVAS_ID_ALPHABET_STR.contains(c))
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, ToSchema)]
 #[repr(u8)]
 pub enum AccessLevel {
     Read = b'R',
@@ -52,11 +99,100 @@ pub enum AccessLevel {
     None = b'N', // Meta level, never assigend to a user
 }
 
+/// Name of the level as it is serialized, used in messages
+impl std::fmt::Display for AccessLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AccessLevel::Read => "Read",
+            AccessLevel::Write => "Write",
+            AccessLevel::Moderate => "Moderate",
+            AccessLevel::Owner => "Owner",
+            AccessLevel::Voice => "Voice",
+            AccessLevel::None => "None",
+        })
+    }
+}
+
+/// Parses the name of a level as it is serialized, e.g. Voice
+impl std::str::FromStr for AccessLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "Read" => Ok(AccessLevel::Read),
+            "Write" => Ok(AccessLevel::Write),
+            "Moderate" => Ok(AccessLevel::Moderate),
+            "Owner" => Ok(AccessLevel::Owner),
+            "Voice" => Ok(AccessLevel::Voice),
+            _ => Err(format!(
+                "Invalid access level {value}, expected Read, Voice, Write, Moderate or Owner"
+            )),
+        }
+    }
+}
+
+impl AccessLevel {
+    /// Position of the level in the order None, Read, Voice, Write, Moderate, Owner
+    pub fn rank(&self) -> u8 {
+        match self {
+            AccessLevel::None => 0,
+            AccessLevel::Read => 1,
+            AccessLevel::Voice => 2,
+            AccessLevel::Write => 3,
+            AccessLevel::Moderate => 4,
+            AccessLevel::Owner => 5,
+        }
+    }
+
+    /// Whether the level grants at least the required one, see rank
+    pub fn at_least(&self, required: &AccessLevel) -> bool {
+        self.rank() >= required.rank()
+    }
+
+    ///
+    /// Whether the level may change the shapes of a canvas in the state
+    /// Voice is a speaking grant for moderated canvases, in active canvases it reads like Read
+    /// With the legacy voice behavior Voice draws in every state, see CanvasSettings::legacy_voice_behavior
+    ///
+    pub fn can_write_in(&self, state: &CanvasState, legacy_voice_behavior: bool) -> bool {
+        // no wildcards, a new level or state has to be placed into the matrix
+        match (self, state) {
+            (AccessLevel::Owner, CanvasState::Active) => true,
+            (AccessLevel::Owner, CanvasState::Moderated) => true,
+            (AccessLevel::Moderate, CanvasState::Active) => true,
+            (AccessLevel::Moderate, CanvasState::Moderated) => true,
+            (AccessLevel::Voice, CanvasState::Active) => legacy_voice_behavior,
+            (AccessLevel::Voice, CanvasState::Moderated) => true,
+            (AccessLevel::Write, CanvasState::Active) => true,
+            (AccessLevel::Write, CanvasState::Moderated) => false,
+            (AccessLevel::Read, CanvasState::Active) => false,
+            (AccessLevel::Read, CanvasState::Moderated) => false,
+            (AccessLevel::None, CanvasState::Active) => false,
+            (AccessLevel::None, CanvasState::Moderated) => false,
+            (AccessLevel::Owner, CanvasState::Archived) => false,
+            (AccessLevel::Moderate, CanvasState::Archived) => false,
+            (AccessLevel::Voice, CanvasState::Archived) => false,
+            (AccessLevel::Write, CanvasState::Archived) => false,
+            (AccessLevel::Read, CanvasState::Archived) => false,
+            (AccessLevel::None, CanvasState::Archived) => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CanvasClaim {
     pub n: String,
     pub c: String,
     pub r: AccessLevel,
+    /// expiry of temporary access, unix timestamp in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+impl CanvasClaim {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.exp.is_some_and(|exp| exp <= now)
+    }
 }
 
 impl PartialEq for CanvasClaim {
@@ -74,638 +210,6870 @@ pub struct CreateCanvas {
     pub owner_id: String,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
 pub enum CanvasState {
     Active,
     Moderated,
+    /// read-only for every level until the owner unarchives it
+    Archived,
 }
 
-/// User struct as it is stored in the eventlog
-/// Can be obtained from RegisterUserMessage or GetUserMessage
-#[derive(Deserialize, Serialize, Clone)]
-pub struct Canvas {
-    pub id: String,
-    pub name: String,
-    pub owner_id: String,
-    pub state: CanvasState,
-    pub users: HashMap<UserId, AccessLevel>,
+/// Change of the canvas state, see transition
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CanvasStateAction {
+    Moderate,
+    Activate,
+    Archive,
+    Unarchive,
 }
 
-pub type CanvasId = String;
-
-pub struct CanvasStore {
-    /// Address to the persistence actor, used to save and read events
-    event_persistence_recipient: Recipient<PersistEventMessage<CanvasStoreEvents>>,
-
-    canvases: HashMap<CanvasId, Canvas>,
+///
+/// State the action leads to, decided by the state the canvas is in and the level of the initiator
+/// Owners and moderators switch between Active and Moderated, archiving and unarchiving is up to the owner
+/// Switching to the current state is accepted, clients submit the state selected in a form
+///
+pub fn transition(
+    current: &CanvasState,
+    action: CanvasStateAction,
+    level: &AccessLevel,
+) -> Result<CanvasState, CanvasStoreError> {
+    use CanvasState::*;
+    use CanvasStateAction::*;
 
-    /// Lookup table for users to canvas they have access to
-    user_id_lookup: HashMap<UserId, Vec<CanvasClaim>>,
+    let moderates = matches!(level, AccessLevel::Owner | AccessLevel::Moderate);
+    let owns = matches!(level, AccessLevel::Owner);
+    // no wildcards, a new state or action has to be placed into the matrix
+    match (current, action) {
+        (Active | Moderated, Moderate) if moderates => Ok(Moderated),
+        (Active | Moderated, Activate) if moderates => Ok(Active),
+        (Active | Moderated, Archive) if owns => Ok(Archived),
+        (Archived, Unarchive) if owns => Ok(Active),
+        (Active | Moderated, Moderate | Activate) => Err(CanvasStoreError::AccessDenied(
+            MessageKey::CanvasUpdateDenied,
+        )),
+        (Active | Moderated, Archive) | (Archived, Unarchive) => Err(
+            CanvasStoreError::AccessDenied(MessageKey::CanvasArchiveDenied),
+        ),
+        (Archived, Moderate | Activate | Archive) | (Active | Moderated, Unarchive) => {
+            Err(CanvasStoreError::InvalidTransition {
+                from: current.clone(),
+                action,
+            })
+        }
+    }
 }
 
-impl CanvasStore {
-    pub fn new(
-        event_persistence_recipient: Recipient<PersistEventMessage<CanvasStoreEvents>>,
-        saved_events: Vec<CanvasStoreEvents>,
-    ) -> Result<Self, anyhow::Error> {
-        let mut canvas = HashMap::new();
-        let mut user_id_lookup = HashMap::new();
-
-        // This is missing validation, e.g not more than two owners, no owner at all etc.
-
-        // events are applied in order, so we can just iterate over them
-        for event in saved_events {
-            match event {
-                CanvasStoreEvents::CanvasCreated {
-                    canvas_id,
-                    name,
-                    owner_id,
-                    state,
-                    ..
-                } => {
-                    let claim = CanvasClaim {
-                        n: name.clone(),
-                        c: canvas_id.clone(),
-                        r: AccessLevel::Owner,
-                    };
-
-                    let mut users = HashMap::with_capacity(1);
-                    users.insert(owner_id.clone(), AccessLevel::Owner);
-
-                    canvas.insert(
-                        canvas_id.clone(),
-                        Canvas {
-                            id: canvas_id.clone(),
-                            name,
-                            owner_id: owner_id.clone(),
-                            state,
-                            users,
-                        },
-                    );
-                    user_id_lookup
-                        .entry(owner_id)
-                        .and_modify(|e: &mut Vec<CanvasClaim>| e.push(claim.clone()))
-                        .or_insert(vec![claim]);
-                }
-                CanvasStoreEvents::UserCanvasAdded {
-                    user_id,
-                    canvas_id,
-                    access_level,
-                    ..
-                } => {
-                    let canvas_entry = canvas.entry(canvas_id.clone());
-
-                    let canvas = match canvas_entry {
-                        std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
-                        std::collections::hash_map::Entry::Vacant(_) => anyhow::bail!(
-                            "Canvas {} for user {} does not exist",
-                            canvas_id,
-                            user_id
-                        ),
-                    };
+/// Largest grid accepted by the settings endpoint, in pixels
+pub const MAX_GRID_SIZE: u32 = 1_000;
 
-                    let claim = CanvasClaim {
-                        n: canvas.name.clone(),
-                        c: canvas_id.clone(),
-                        r: access_level.clone(),
-                    };
+/// Per canvas drawing settings, changed by owners and moderators
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CanvasSettings {
+    /// grid spacing in pixels, None hides the grid
+    #[serde(default)]
+    pub grid_size: Option<u32>,
+    /// snap persisted shapes to the grid, see geometry::snap_shape
+    #[serde(default)]
+    pub snap_enabled: bool,
+    /// only the creator of a shape, owners and moderators may change or remove it
+    #[serde(default)]
+    pub shape_ownership_enforced: bool,
+    /// Voice draws in active canvases as well, as before Voice was limited to moderated canvases
+    /// Kept for existing canvases, only the owner may switch it, see AccessLevel::can_write_in
+    #[serde(default = "legacy_voice_behavior_default")]
+    pub legacy_voice_behavior: bool,
+    /// provenance embedded in exports, only the owner may change it
+    /// Left out when unset, canvases without metadata persist and export as before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CanvasMetadata>,
+    /// deviations from the configured retention policy, only the owner may change them
+    #[serde(default, skip_serializing_if = "RetentionOverrides::is_empty")]
+    pub retention: RetentionOverrides,
+    /// Read sessions see pseudonyms instead of users, see redaction.rs, only the owner may change it
+    #[serde(default)]
+    pub anonymize_for_readers: bool,
+    /// salt of the pseudonyms, created once the canvas is first anonymized and kept from then on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reader_salt: Option<String>,
+    /// level of visitors without an account, see guests.rs, only the owner may change it
+    #[serde(default)]
+    pub guest_access: GuestAccess,
+    /// colors collaborators pick from, see palette.rs, replaced through the palette endpoint only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<PaletteColor>,
+    /// shapes have to use colors of the palette, see palette::off_palette_color
+    #[serde(default)]
+    pub enforce_palette: bool,
+    /// how often the owner is sent an activity digest, see digest.rs, only the owner may change it
+    #[serde(default)]
+    pub digest: DigestFrequency,
+    /// types of new shapes, empty allows all, only the owner may change it
+    /// Shapes of other types drawn before stay and may still be changed or removed
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub allowed_shape_types: BTreeSet<ShapeType>,
+    /// owners and moderators may add shapes of every type, off keeps exercises fair for everyone
+    #[serde(default)]
+    pub moderators_bypass_shape_types: bool,
+}
 
-                    user_id_lookup
-                        .entry(user_id.clone())
-                        .and_modify(|e: &mut Vec<CanvasClaim>| {
-                            e.iter().position(|c| c == &claim).map(|i| e.swap_remove(i));
-                            e.push(claim.clone());
-                        })
-                        .or_insert(vec![claim.clone()]);
+pub(crate) fn legacy_voice_behavior_default() -> bool {
+    true
+}
 
-                    canvas.users.insert(user_id, access_level);
-                }
-                _ => (),
-            }
+impl Default for CanvasSettings {
+    fn default() -> Self {
+        Self {
+            grid_size: None,
+            snap_enabled: false,
+            shape_ownership_enforced: false,
+            legacy_voice_behavior: legacy_voice_behavior_default(),
+            metadata: None,
+            retention: RetentionOverrides::default(),
+            anonymize_for_readers: false,
+            reader_salt: None,
+            guest_access: GuestAccess::Closed,
+            palette: Vec::new(),
+            enforce_palette: false,
+            digest: DigestFrequency::Off,
+            allowed_shape_types: BTreeSet::new(),
+            moderators_bypass_shape_types: false,
         }
-
-        Ok(Self {
-            event_persistence_recipient,
-            canvases: canvas,
-            user_id_lookup,
-        })
     }
 }
 
-impl CanvasStore {
-    fn get_access_level(&self, user_id: &UserId, canvas_id: &CanvasId) -> AccessLevel {
-        self.user_id_lookup
-            .get(user_id)
-            .map(|claims| {
-                claims
-                    .iter()
-                    .find(|claim| claim.c == *canvas_id)
-                    .map(|claim| claim.r.clone())
-                    .unwrap_or(AccessLevel::None)
-            })
-            .unwrap_or(AccessLevel::None)
+impl CanvasSettings {
+    /// Grid shapes are snapped to, None if snapping is disabled or there is no grid
+    pub fn snap_grid(&self) -> Option<u32> {
+        self.grid_size
+            .filter(|grid_size| self.snap_enabled && *grid_size > 0)
     }
+}
 
-    fn validate_permission_change(
-        &self,
-        initiator_access_level: &AccessLevel,
-        target_access_level: &AccessLevel,
-        access_level: &AccessLevel,
-    ) -> Result<(), CanvasStoreError> {
-        match (initiator_access_level, target_access_level, access_level) {
-            // owner can't change himself
-            (AccessLevel::Owner, AccessLevel::Owner, _) => Err(CanvasStoreError::AccessDenied(
-                String::from("Owner can't change his own access level"),
-            )),
-            // owner can't elect a new owner
-            (AccessLevel::Owner, _, AccessLevel::Owner) => Err(CanvasStoreError::AccessDenied(
-                String::from("Owner can't assign owner access level"),
-            )),
-
-            // owner can change anything else
-            (AccessLevel::Owner, _, _) => Ok(()),
+/// Longest author display name of a canvas, in characters
+pub const MAX_AUTHOR_DISPLAY_LENGTH: usize = 100;
+/// Longest custom license of a canvas, in characters
+pub const MAX_CUSTOM_LICENSE_LENGTH: usize = 200;
+/// Longest description of a canvas, in bytes
+pub const MAX_DESCRIPTION_BYTES: usize = 1024;
 
-            // moderate can't change owner nor moderator
-            (AccessLevel::Moderate, AccessLevel::Owner | AccessLevel::Moderate, _) => {
-                Err(CanvasStoreError::AccessDenied(String::from(
-                    "Moderate can't change owner or moderate access level",
-                )))
-            }
-            // moderate can't assign owner nor moderate
-            (AccessLevel::Moderate, _, AccessLevel::Owner | AccessLevel::Moderate) => {
-                Err(CanvasStoreError::AccessDenied(String::from(
-                    "Moderate can't assign owner or moderate access level",
-                )))
-            }
+/// License a canvas is published under, common licenses are serialized as their SPDX identifier
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, ToSchema)]
+pub enum CanvasLicense {
+    #[serde(rename = "all-rights-reserved")]
+    AllRightsReserved,
+    #[serde(rename = "CC0-1.0")]
+    Cc0,
+    #[serde(rename = "CC-BY-4.0")]
+    CcBy,
+    #[serde(rename = "CC-BY-SA-4.0")]
+    CcBySa,
+    #[serde(rename = "CC-BY-NC-4.0")]
+    CcByNc,
+    #[serde(rename = "CC-BY-NC-SA-4.0")]
+    CcByNcSa,
+    #[serde(rename = "CC-BY-ND-4.0")]
+    CcByNd,
+    #[serde(rename = "MIT")]
+    Mit,
+    #[serde(rename = "custom")]
+    Custom(String),
+}
 
-            // moderator is allowed to change any users access level that is left
-            (AccessLevel::Moderate, _, _) => Ok(()),
+impl CanvasLicense {
+    /// License by its identifier, custom is the text of a custom license
+    pub fn parse(identifier: &str, custom: Option<String>) -> Option<Self> {
+        let license = match identifier {
+            "all-rights-reserved" => Self::AllRightsReserved,
+            "CC0-1.0" => Self::Cc0,
+            "CC-BY-4.0" => Self::CcBy,
+            "CC-BY-SA-4.0" => Self::CcBySa,
+            "CC-BY-NC-4.0" => Self::CcByNc,
+            "CC-BY-NC-SA-4.0" => Self::CcByNcSa,
+            "CC-BY-ND-4.0" => Self::CcByNd,
+            "MIT" => Self::Mit,
+            "custom" => Self::Custom(custom.unwrap_or_default()),
+            _ => return None,
+        };
+        Some(license)
+    }
 
-            // non moderate or owner can't change anything
-            (_, _, _) => Err(CanvasStoreError::AccessDenied(String::from(
-                "User can't change access level",
-            ))),
+    /// Human readable name, the text of custom licenses
+    pub fn name(&self) -> &str {
+        match self {
+            Self::AllRightsReserved => "All rights reserved",
+            Self::Cc0 => "CC0 1.0",
+            Self::CcBy => "CC BY 4.0",
+            Self::CcBySa => "CC BY-SA 4.0",
+            Self::CcByNc => "CC BY-NC 4.0",
+            Self::CcByNcSa => "CC BY-NC-SA 4.0",
+            Self::CcByNd => "CC BY-ND 4.0",
+            Self::Mit => "MIT",
+            Self::Custom(text) => text,
         }
     }
 }
 
-impl Actor for CanvasStore {
-    type Context = Context<Self>;
+/// Provenance of a canvas, normalized by normalize_metadata
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, ToSchema)]
+pub struct CanvasMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_display: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<CanvasLicense>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[allow(clippy::enum_variant_names)] // Canvas Application uses this naming convention
-pub enum CanvasStoreEvents {
-    /// Created a new canvas
-    CanvasCreated {
-        timestamp: u64,
-        owner_id: UserId,
-        canvas_id: CanvasId,
-        state: CanvasState,
-        name: String,
-    },
-    /// Deletes a canvas
-    CanvasDeleted { timestamp: u64, canvas_id: CanvasId },
-    /// Adds the user to a canvas (this is mirrored in the canvas store, to make lookups easier)
-    UserCanvasAdded {
-        timestamp: u64,
-        user_id: UserId,
-        initiator_user_id: UserId,
-        canvas_id: CanvasId,
-        access_level: AccessLevel,
-    },
-    /// Removes the user from a canvas (this is mirrored in the canvas store, to make lookups easier)
-    UserCanvasRemoved {
-        timestamp: u64,
-        user_id: String,
-        canvas_id: CanvasId,
-    },
-    /// Changes the state of a canvas, moderated, active etc.
-    CanvasStateChanged {
-        timestamp: u64,
-        canvas_id: CanvasId,
-        initiator_id: UserId,
-        state: CanvasState,
-    },
+/// Field of the metadata that is too long and its limit
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidMetadata {
+    pub field: &'static str,
+    pub max: usize,
 }
 
-#[derive(Message)]
-#[rtype(result = "Result<(), std::io::Error>")]
-pub struct UpdateCanvasStateMessage {
-    pub canvas_id: CanvasId,
-    pub initiator_id: UserId,
-    pub state: CanvasState,
+/// Strips control characters and trims, empty text is None
+/// Line breaks are kept if multiline, the description may span lines
+pub(crate) fn clean_text(text: Option<String>, multiline: bool) -> Option<String> {
+    let text: String = text?
+        .chars()
+        .filter(|c| !c.is_control() || (multiline && *c == '\n'))
+        .collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
 }
 
-impl Handler<UpdateCanvasStateMessage> for CanvasStore {
-    type Result = AtomicResponse<Self, Result<(), std::io::Error>>;
+/// Cleans the texts of the metadata and checks their length, metadata without any field is None
+pub fn normalize_metadata(
+    metadata: CanvasMetadata,
+) -> Result<Option<CanvasMetadata>, InvalidMetadata> {
+    let author_display = clean_text(metadata.author_display, false);
+    if author_display
+        .as_ref()
+        .is_some_and(|author| author.chars().count() > MAX_AUTHOR_DISPLAY_LENGTH)
+    {
+        return Err(InvalidMetadata {
+            field: "author_display",
+            max: MAX_AUTHOR_DISPLAY_LENGTH,
+        });
+    }
 
-    fn handle(&mut self, msg: UpdateCanvasStateMessage, _: &mut Self::Context) -> Self::Result {
-        let event = CanvasStoreEvents::CanvasStateChanged {
-            timestamp: chrono::Utc::now().timestamp_millis() as u64,
-            canvas_id: msg.canvas_id.clone(),
-            initiator_id: msg.initiator_id.clone(),
-            state: msg.state.clone(),
-        };
+    let license = match metadata.license {
+        Some(CanvasLicense::Custom(text)) => {
+            let Some(text) = clean_text(Some(text), false) else {
+                return Err(InvalidMetadata {
+                    field: "license",
+                    max: MAX_CUSTOM_LICENSE_LENGTH,
+                });
+            };
+            if text.chars().count() > MAX_CUSTOM_LICENSE_LENGTH {
+                return Err(InvalidMetadata {
+                    field: "license",
+                    max: MAX_CUSTOM_LICENSE_LENGTH,
+                });
+            }
+            Some(CanvasLicense::Custom(text))
+        }
+        license => license,
+    };
 
-        AtomicResponse::new(Box::pin(
-            self.event_persistence_recipient
-                .send(persistence::PersistEventMessage(event))
-                .into_actor(self)
-                .map(move |result, canvasstore, _| {
-                    match result {
-                        Ok(Ok(_)) => {
-                            // insert after persistence
-                            if let Some(canvas) = canvasstore.canvases.get_mut(&msg.canvas_id) {
-                                canvas.state = msg.state;
-                            }
-                            Ok(())
-                        }
-                        Ok(Err(_)) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to persist event",
-                        )),
-                        Err(_) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to persist event",
-                        )),
-                    }
-                }),
-        ))
+    let description = clean_text(metadata.description, true);
+    if description
+        .as_ref()
+        .is_some_and(|description| description.len() > MAX_DESCRIPTION_BYTES)
+    {
+        return Err(InvalidMetadata {
+            field: "description",
+            max: MAX_DESCRIPTION_BYTES,
+        });
     }
-}
 
-#[derive(Message)]
-#[rtype(result = "Result<Canvas, std::io::Error>")]
-pub struct CreateCanvasMessage {
-    pub canvas: CreateCanvas,
+    let metadata = CanvasMetadata {
+        author_display,
+        license,
+        description,
+    };
+    Ok((metadata != CanvasMetadata::default()).then_some(metadata))
 }
 
-impl Handler<CreateCanvasMessage> for CanvasStore {
-    type Result = AtomicResponse<Self, Result<Canvas, std::io::Error>>;
-
-    // This function is atomic, meaning that the actor will not be able to handle any other messages until the response is resolved
-    fn handle(&mut self, msg: CreateCanvasMessage, _: &mut Self::Context) -> Self::Result {
-        let id = (0..MAX_ID_GENERATION_ITERATIONS)
-            .map(|_| nanoid!(CANVAS_ID_LENGTH, &CANVAS_ID_ALPHABET))
-            .find(|id| !self.canvases.contains_key(id))
-            .ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to generate unique user id",
-                )
-            });
-
-        let id = match id {
-            Ok(id) => id,
-            Err(e) => return AtomicResponse::new(Box::pin(async move { Err(e) }.into_actor(self))),
-        };
-
-        let mut users = HashMap::with_capacity(1);
-        users.insert(msg.canvas.owner_id.clone(), AccessLevel::Owner);
+/// Most tags a canvas can carry
+pub const MAX_CANVAS_TAGS: usize = 10;
 
-        let canvas = Canvas {
-            id: id.clone(),
-            name: msg.canvas.name.clone(),
-            owner_id: msg.canvas.owner_id.clone(),
-            state: CanvasState::Active,
-            users,
-        };
+/// Longest tag in characters
+pub const MAX_TAG_LENGTH: usize = 32;
 
-        let event = CanvasStoreEvents::CanvasCreated {
-            timestamp: chrono::Utc::now().timestamp_millis() as u64,
-            owner_id: msg.canvas.owner_id.clone(),
-            canvas_id: id.clone(),
-            state: canvas.state.clone(),
-            name: msg.canvas.name.clone(),
-        };
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidTags {
+    /// number of distinct tags given
+    TooMany(usize),
+    /// tags that are empty or too long, as given
+    Invalid(Vec<String>),
+}
 
-        self.canvases.insert(id.clone(), canvas.clone());
+/// Trims, lowercases and deduplicates tags, the first occurrence keeps its position
+/// Offending tags are reported before the number of tags is checked
+pub fn normalize_tags(tags: impl IntoIterator<Item = String>) -> Result<Vec<String>, InvalidTags> {
+    let mut normalized: Vec<String> = Vec::new();
+    let mut invalid = Vec::new();
 
-        let canvas_claim = CanvasClaim {
-            n: msg.canvas.name,
-            c: id,
-            r: AccessLevel::Owner,
-        };
-        self.user_id_lookup
-            .entry(msg.canvas.owner_id)
-            .and_modify(|e: &mut Vec<CanvasClaim>| e.push(canvas_claim.clone())) // clone here sucks
-            .or_insert(vec![canvas_claim]);
+    for tag in tags {
+        let normalized_tag = tag.trim().to_lowercase();
+        let length = normalized_tag.chars().count();
+        if length == 0 || length > MAX_TAG_LENGTH {
+            invalid.push(tag);
+        } else if !normalized.contains(&normalized_tag) {
+            normalized.push(normalized_tag);
+        }
+    }
 
-        AtomicResponse::new(Box::pin(
-            self.event_persistence_recipient
-                .send(persistence::PersistEventMessage(event))
-                .into_actor(self)
-                .map(|result, canvasstore, _| {
-                    let canvas_for_error = canvas.clone(); // same as userstore this whole future thing already took to long to figure out, just copy user for error handling
-                    match result {
-                        Ok(Ok(_)) => Ok(canvas),
-                        Ok(Err(_)) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to persist create event",
-                        )),
-                        Err(_) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to persist create event",
-                        )),
-                    }
-                    .map_err(|error| {
-                        canvasstore.canvases.remove(&canvas_for_error.id);
-                        canvasstore
-                            .user_id_lookup
-                            .entry(canvas_for_error.owner_id)
-                            .and_modify(|e: &mut Vec<CanvasClaim>| {
-                                e.retain(|c| c.c != canvas_for_error.id)
-                            });
-                        error
-                    })
-                }),
-        ))
+    if !invalid.is_empty() {
+        return Err(InvalidTags::Invalid(invalid));
     }
+    if normalized.len() > MAX_CANVAS_TAGS {
+        return Err(InvalidTags::TooMany(normalized.len()));
+    }
+    Ok(normalized)
 }
 
-#[derive(Message)]
-#[rtype(result = "Vec<CanvasClaim>")]
-pub struct GetUserClaimsMessage {
-    pub user_id: UserId,
+/// User struct as it is stored in the eventlog
+/// Can be obtained from RegisterUserMessage or GetUserMessage
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Canvas {
+    pub id: String,
+    pub name: String,
+    pub owner_id: String,
+    pub state: CanvasState,
+    pub users: HashMap<UserId, AccessLevel>,
+    /// Incremented on every persisted change, used to detect concurrent state updates
+    #[serde(default)]
+    pub version: u64,
+    /// Expiry of temporary access levels in users, unix timestamp in milliseconds
+    #[serde(default)]
+    pub expirations: HashMap<UserId, u64>,
+    #[serde(default)]
+    pub settings: CanvasSettings,
+    /// normalized tags, see normalize_tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// feature flags switched by the owner, win over the rollout of the server, see FeatureFlags
+    #[serde(default)]
+    pub feature_overrides: BTreeMap<String, bool>,
+    /// API tokens of the canvas, never serialized so their hashes stay in the store, see tokens.rs
+    #[serde(skip)]
+    pub api_tokens: HashMap<TokenId, ApiToken>,
+    /// timestamp of CanvasCreated, unix timestamp in milliseconds
+    #[serde(default)]
+    pub created_at: u64,
+    /// set while the canvas is soft deleted, unix timestamp in milliseconds
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
 }
 
-impl Handler<GetUserClaimsMessage> for CanvasStore {
-    type Result = Vec<CanvasClaim>;
+impl Canvas {
+    /// Access level of the user, expired temporary access counts as none
+    /// Token principals have the access level of their token until it expires
+    pub fn access_level(&self, user_id: &UserId, now: u64) -> AccessLevel {
+        if let Some(token_id) = tokens::token_id_of(user_id) {
+            return self
+                .api_tokens
+                .get(token_id)
+                .filter(|token| !token.is_expired(now))
+                .map(|token| token.access_level.clone())
+                .unwrap_or(AccessLevel::None);
+        }
+        if guests::is_guest(user_id) {
+            return self.settings.guest_access.access_level();
+        }
 
-    fn handle(&mut self, msg: GetUserClaimsMessage, _: &mut Self::Context) -> Self::Result {
-        self.user_id_lookup
-            .get(&msg.user_id)
-            .map_or(Vec::new(), Clone::clone)
+        match self.expirations.get(user_id) {
+            Some(expires_at) if *expires_at <= now => AccessLevel::None,
+            _ => self
+                .users
+                .get(user_id)
+                .cloned()
+                .unwrap_or(AccessLevel::None),
+        }
     }
-}
 
-#[derive(Message, Clone)]
-#[rtype(result = "Option<Canvas>")]
-pub struct GetCanvasMessage {
-    pub canvas_id: CanvasId,
+    /// Users with access at now, the owner included
+    pub fn member_count(&self, now: u64) -> usize {
+        self.users
+            .keys()
+            .filter(|user_id| self.access_level(user_id, now) != AccessLevel::None)
+            .count()
+    }
 }
 
-impl Handler<GetCanvasMessage> for CanvasStore {
-    type Result = Option<Canvas>;
+pub type CanvasId = String;
 
-    fn handle(&mut self, msg: GetCanvasMessage, _: &mut Self::Context) -> Self::Result {
-        self.canvases.get(&msg.canvas_id).cloned()
+pub struct CanvasStore {
+    /// Address to the persistence actor, used to save and read events
+    event_persistence_recipient: Recipient<PersistEventMessage<CanvasStoreEvents>>,
+
+    canvases: HashMap<CanvasId, Canvas>,
+
+    /// Soft deleted canvases, restorable until the deletion grace period passed
+    deleted_canvases: HashMap<CanvasId, Canvas>,
+    deletion_grace: Duration,
+
+    /// Ids of the purged canvases, an eventlog left behind by one of them can be purged, see orphans.rs
+    purged_canvases: HashSet<CanvasId>,
+    /// Time purged eventlogs are kept in the quarantine, removed by the purge sweep
+    quarantine_retention: Duration,
+
+    /// Replays served by the handlers, entries of deleted and purged canvases are evicted
+    replay_cache: Arc<ReplayCache>,
+
+    /// Lookup table for users to canvas they have access to
+    claims: ClaimIndex,
+
+    /// Lookup table for tags to the canvases carrying them
+    tag_index: HashMap<String, HashSet<CanvasId>>,
+
+    /// Canvas names per owner, see names.rs
+    name_index: NameIndex,
+
+    /// Creating or renaming a canvas to a name its owner already uses is rejected, see CanvasStoreError::DuplicateName
+    unique_names: bool,
+
+    /// Live sessions are downgraded when temporary access expires, registered after the canvas server started
+    canvas_server_handle: Option<CanvasSocketServerHandle>,
+
+    quota_limits: QuotaLimits,
+
+    /// member quota per canvas, the other quotas are tracked by the canvas server
+    member_quota_warnings: HashMap<CanvasId, QuotaWarnings>,
+
+    /// issued quota warnings per canvas, oldest first
+    quota_warnings: HashMap<CanvasId, Vec<QuotaWarning>>,
+
+    /// last persisted visit per user and canvas, also debounces visits
+    visits: HashMap<UserId, HashMap<CanvasId, u64>>,
+
+    /// pins and sort hints of the home page per user and canvas
+    preferences: HashMap<UserId, HashMap<CanvasId, CanvasPreference>>,
+
+    /// last activity digest per canvas, see digest.rs
+    digests: HashMap<CanvasId, DigestMark>,
+
+    /// pending and recently resolved access requests per canvas, see access_requests.rs
+    access_requests: HashMap<CanvasId, AccessRequests>,
+
+    /// changes are rejected while the persistence is saturated
+    degraded: DegradedMode,
+    mailbox_capacity: usize,
+    /// handling times of the messages, see mailbox::HandlerTrace
+    handler_trace: Arc<HandlerTrace>,
+
+    /// timestamps of the persisted events, increasing even if the clock steps back
+    stamps: MonotonicStamps,
+
+    clock: SharedClock,
+}
+
+/// In memory state of the CanvasStore, built by replaying the eventlog
+#[derive(Default)]
+pub struct CanvasStoreState {
+    pub(crate) canvases: HashMap<CanvasId, Canvas>,
+    pub(crate) deleted_canvases: HashMap<CanvasId, Canvas>,
+    pub(crate) purged_canvases: HashSet<CanvasId>,
+    pub(crate) claims: ClaimIndex,
+    pub(crate) tag_index: HashMap<String, HashSet<CanvasId>>,
+    pub(crate) name_index: NameIndex,
+    pub(crate) quota_warnings: HashMap<CanvasId, Vec<QuotaWarning>>,
+    pub(crate) visits: HashMap<UserId, HashMap<CanvasId, u64>>,
+    pub(crate) preferences: HashMap<UserId, HashMap<CanvasId, CanvasPreference>>,
+    pub(crate) digests: HashMap<CanvasId, DigestMark>,
+    pub(crate) access_requests: HashMap<CanvasId, AccessRequests>,
+}
+
+/// Applies all events in order and returns the resulting state
+/// Inconsistencies do not abort the replay, events referencing unknown canvases are skipped
+/// Used by the CanvasStore on startup and by the maintenance tooling
+/// Claims expired at now are not restored, the grants stay until the sweep removes them
+pub fn replay_events(
+    events: impl IntoIterator<Item = CanvasStoreEvents>,
+    now: u64,
+) -> (CanvasStoreState, Vec<ReplayIssue>) {
+    let mut state = CanvasStoreState::default();
+    let mut issues = Vec::new();
+
+    // events are applied in order, so we can just iterate over them
+    for (index, event) in events.into_iter().enumerate() {
+        match event {
+            CanvasStoreEvents::CanvasCreated {
+                timestamp,
+                canvas_id,
+                name,
+                owner_id,
+                state: canvas_state,
+            } => {
+                if state.canvases.contains_key(&canvas_id) {
+                    issues.push(ReplayIssue::event(
+                        index,
+                        ReplayIssueKind::Duplicate,
+                        format!("Canvas {canvas_id} created more than once"),
+                    ));
+                }
+
+                let claim = CanvasClaim {
+                    n: name.clone(),
+                    c: canvas_id.clone(),
+                    r: AccessLevel::Owner,
+                    exp: None,
+                };
+
+                let mut users = HashMap::with_capacity(1);
+                users.insert(owner_id.clone(), AccessLevel::Owner);
+
+                state.canvases.insert(
+                    canvas_id.clone(),
+                    Canvas {
+                        id: canvas_id.clone(),
+                        name,
+                        owner_id: owner_id.clone(),
+                        state: canvas_state,
+                        users,
+                        version: 1,
+                        expirations: HashMap::new(),
+                        settings: CanvasSettings::default(),
+                        tags: Vec::new(),
+                        feature_overrides: BTreeMap::new(),
+                        api_tokens: HashMap::new(),
+                        created_at: timestamp,
+                        deleted_at: None,
+                    },
+                );
+                state.name_index.insert(&owner_id, &claim.n, &canvas_id);
+                state.claims.add_claim(&owner_id, claim);
+            }
+            CanvasStoreEvents::CanvasRenamed {
+                canvas_id, name, ..
+            } => {
+                let renamed = rename_canvas(
+                    &mut state.canvases,
+                    &mut state.claims,
+                    &mut state.name_index,
+                    &canvas_id,
+                    name,
+                );
+                if !renamed {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Unknown canvas {canvas_id} renamed"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::UserCanvasAdded {
+                user_id,
+                canvas_id,
+                access_level,
+                expires_at,
+                ..
+            } => {
+                let Some(canvas) = state.canvases.get_mut(&canvas_id) else {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Canvas {canvas_id} for user {user_id} does not exist"),
+                    ));
+                    continue;
+                };
+
+                let claim = CanvasClaim {
+                    n: canvas.name.clone(),
+                    c: canvas_id.clone(),
+                    r: access_level.clone(),
+                    exp: expires_at,
+                };
+
+                state.claims.remove_claim(&user_id, &canvas_id);
+                // expired access never becomes a claim, the grant is kept so the sweep persists its removal
+                if !claim.is_expired(now) {
+                    state.claims.add_claim(&user_id, claim);
+                }
+
+                match expires_at {
+                    Some(expires_at) => canvas.expirations.insert(user_id.clone(), expires_at),
+                    None => canvas.expirations.remove(&user_id),
+                };
+                canvas.users.insert(user_id, access_level);
+                canvas.version += 1;
+            }
+            CanvasStoreEvents::UserCanvasRemoved {
+                user_id, canvas_id, ..
+            } => {
+                // deleted users are removed from soft deleted canvases as well, see PurgeUserMessage
+                let removed =
+                    remove_grant(&mut state.canvases, &mut state.claims, &canvas_id, &user_id)
+                        || remove_deleted_grant(&mut state.deleted_canvases, &canvas_id, &user_id);
+                if !removed {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("User {user_id} removed from unknown canvas {canvas_id}"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::CanvasStateChanged {
+                canvas_id,
+                state: canvas_state,
+                ..
+            } => match state.canvases.get_mut(&canvas_id) {
+                Some(canvas) => {
+                    canvas.state = canvas_state;
+                    canvas.version += 1;
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("State changed on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::CanvasSettingsChanged {
+                canvas_id,
+                settings,
+                ..
+            } => match state.canvases.get_mut(&canvas_id) {
+                Some(canvas) => {
+                    canvas.settings = settings;
+                    canvas.version += 1;
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Settings changed on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::CanvasPaletteChanged {
+                canvas_id,
+                palette,
+                enforce_palette,
+                ..
+            } => match state.canvases.get_mut(&canvas_id) {
+                Some(canvas) => {
+                    canvas.settings.palette = palette;
+                    canvas.settings.enforce_palette = enforce_palette;
+                    canvas.version += 1;
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Palette changed on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::CanvasFeatureFlagsChanged {
+                canvas_id,
+                overrides,
+                ..
+            } => match state.canvases.get_mut(&canvas_id) {
+                Some(canvas) => {
+                    canvas.feature_overrides = overrides;
+                    canvas.version += 1;
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Feature flags changed on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::ApiTokenCreated {
+                timestamp,
+                canvas_id,
+                initiator_id,
+                token_id,
+                label,
+                access_level,
+                expires_at,
+                secret_hash,
+            } => match state.canvases.get_mut(&canvas_id) {
+                // tokens do not change the canvas, the version is kept
+                Some(canvas) => {
+                    canvas.api_tokens.insert(
+                        token_id.clone(),
+                        ApiToken {
+                            id: token_id,
+                            label,
+                            access_level,
+                            created_by: initiator_id,
+                            created_at: timestamp,
+                            expires_at,
+                            secret_hash,
+                        },
+                    );
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("API token created on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::ApiTokenRevoked {
+                canvas_id,
+                token_id,
+                ..
+            } => match state.canvases.get_mut(&canvas_id) {
+                Some(canvas) => {
+                    canvas.api_tokens.remove(&token_id);
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("API token revoked on unknown canvas {canvas_id}"),
+                )),
+            },
+            CanvasStoreEvents::CanvasTagsChanged {
+                canvas_id, tags, ..
+            } => {
+                if !set_tags(&mut state.canvases, &mut state.tag_index, &canvas_id, tags) {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Tags changed on unknown canvas {canvas_id}"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::QuotaWarningIssued {
+                timestamp,
+                canvas_id,
+                usage,
+            } => state
+                .quota_warnings
+                .entry(canvas_id)
+                .or_default()
+                .push(QuotaWarning { timestamp, usage }),
+            CanvasStoreEvents::CanvasSoftDeleted {
+                timestamp,
+                canvas_id,
+                ..
+            } => {
+                let deleted = soft_delete_canvas(
+                    &mut state.canvases,
+                    &mut state.deleted_canvases,
+                    &mut state.claims,
+                    &mut state.tag_index,
+                    &mut state.name_index,
+                    &canvas_id,
+                    timestamp,
+                );
+                if !deleted {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Unknown canvas {canvas_id} soft deleted"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::CanvasRestored { canvas_id, .. } => {
+                let restored = restore_canvas(
+                    &mut state.canvases,
+                    &mut state.deleted_canvases,
+                    &mut state.claims,
+                    &mut state.tag_index,
+                    &mut state.name_index,
+                    &canvas_id,
+                    now,
+                );
+                if !restored {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Canvas {canvas_id} restored without being deleted"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::CanvasDeleted { canvas_id, .. } => {
+                // logs written before soft deletion delete live canvases directly
+                let removed = remove_canvas(
+                    &mut state.canvases,
+                    &mut state.claims,
+                    &mut state.tag_index,
+                    &mut state.name_index,
+                    &canvas_id,
+                )
+                .or_else(|| state.deleted_canvases.remove(&canvas_id));
+                state.purged_canvases.insert(canvas_id.clone());
+                if removed.is_none() {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Unknown canvas {canvas_id} deleted"),
+                    ));
+                }
+                state.quota_warnings.remove(&canvas_id);
+                state.digests.remove(&canvas_id);
+                state.access_requests.remove(&canvas_id);
+                remove_canvas_entries(&mut state.visits, &canvas_id);
+                remove_canvas_entries(&mut state.preferences, &canvas_id);
+            }
+            CanvasStoreEvents::CanvasVisited {
+                timestamp,
+                user_id,
+                canvas_id,
+            } => {
+                if !state.canvases.contains_key(&canvas_id) {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("User {user_id} visited unknown canvas {canvas_id}"),
+                    ));
+                    continue;
+                }
+                state
+                    .visits
+                    .entry(user_id)
+                    .or_default()
+                    .insert(canvas_id, timestamp);
+            }
+            CanvasStoreEvents::UserCanvasPreferenceChanged {
+                user_id,
+                preferences,
+                ..
+            } => {
+                for (canvas_id, preference) in preferences {
+                    if !state.canvases.contains_key(&canvas_id) {
+                        issues.push(ReplayIssue::skipped(
+                            index,
+                            format!("User {user_id} pinned unknown canvas {canvas_id}"),
+                        ));
+                        continue;
+                    }
+                    set_preference(&mut state.preferences, &user_id, canvas_id, preference);
+                }
+            }
+            CanvasStoreEvents::CanvasDigested {
+                timestamp,
+                canvas_id,
+                seq,
+            } => {
+                if !state.canvases.contains_key(&canvas_id) {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Digest of unknown canvas {canvas_id}"),
+                    ));
+                    continue;
+                }
+                state
+                    .digests
+                    .insert(canvas_id, DigestMark { seq, timestamp });
+            }
+            CanvasStoreEvents::AccessRequested {
+                timestamp,
+                canvas_id,
+                user_id,
+                message,
+            } => {
+                if !state.canvases.contains_key(&canvas_id) {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("User {user_id} requested access to unknown canvas {canvas_id}"),
+                    ));
+                    continue;
+                }
+                let requested = state
+                    .access_requests
+                    .entry(canvas_id.clone())
+                    .or_default()
+                    .request(&user_id, message, timestamp);
+                if !requested {
+                    issues.push(ReplayIssue::event(
+                        index,
+                        ReplayIssueKind::Duplicate,
+                        format!("User {user_id} requested access to {canvas_id} while a request was pending"),
+                    ));
+                }
+            }
+            CanvasStoreEvents::AccessRequestResolved {
+                timestamp,
+                canvas_id,
+                user_id,
+                initiator_id,
+                status,
+                access_level,
+            } => {
+                let resolution = match (status, access_level) {
+                    (AccessRequestStatus::Approved, Some(access_level)) => {
+                        AccessRequestResolution::Approved(access_level)
+                    }
+                    (AccessRequestStatus::Denied, _) => AccessRequestResolution::Denied,
+                    (AccessRequestStatus::Cancelled, _) => AccessRequestResolution::Cancelled,
+                    (status, _) => {
+                        issues.push(ReplayIssue::skipped(
+                            index,
+                            format!(
+                                "Access request of {user_id} on {canvas_id} resolved as {status:?}"
+                            ),
+                        ));
+                        continue;
+                    }
+                };
+                let resolved = state
+                    .access_requests
+                    .get_mut(&canvas_id)
+                    .and_then(|requests| {
+                        requests.resolve(&user_id, &resolution, &initiator_id, timestamp)
+                    });
+                if resolved.is_none() {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("No pending access request of {user_id} on {canvas_id} to resolve"),
+                    ));
+                }
+            }
+        }
+    }
+
+    // resolved requests age out, they are only listed for a while
+    for requests in state.access_requests.values_mut() {
+        requests.prune(now);
+    }
+    state
+        .access_requests
+        .retain(|_, requests| !requests.is_empty());
+
+    // preferences of canvases the user lost access to are ignored, they are dropped with the next replay
+    for (user_id, preferences) in state.preferences.iter_mut() {
+        preferences.retain(|canvas_id, _| {
+            state
+                .claims
+                .get(user_id)
+                .is_some_and(|claims| claims.iter().any(|claim| &claim.c == canvas_id))
+        });
+    }
+    state
+        .preferences
+        .retain(|_, preferences| !preferences.is_empty());
+
+    // validate invariants that can only be checked on the final state
+    for canvas in state.canvases.values() {
+        let owners = canvas
+            .users
+            .values()
+            .filter(|access_level| **access_level == AccessLevel::Owner)
+            .count();
+        if owners != 1 {
+            issues.push(ReplayIssue::invariant(format!(
+                "Canvas {} has {owners} owners",
+                canvas.id
+            )));
+        }
+    }
+    issues.extend(
+        state
+            .claims
+            .invariant_issues(&state.canvases)
+            .into_iter()
+            .map(ReplayIssue::invariant),
+    );
+
+    (state, issues)
+}
+
+/// Removes the user from the canvas and drops the claim, returns false if the canvas is unknown
+fn remove_grant(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    claims: &mut ClaimIndex,
+    canvas_id: &CanvasId,
+    user_id: &UserId,
+) -> bool {
+    let Some(canvas) = canvases.get_mut(canvas_id) else {
+        return false;
+    };
+    canvas.users.remove(user_id);
+    canvas.expirations.remove(user_id);
+    canvas.version += 1;
+
+    claims.remove_claim(user_id, canvas_id);
+    true
+}
+
+/// Removes the user from a soft deleted canvas, it holds no claims, returns false if the canvas is not deleted
+fn remove_deleted_grant(
+    deleted_canvases: &mut HashMap<CanvasId, Canvas>,
+    canvas_id: &CanvasId,
+    user_id: &UserId,
+) -> bool {
+    let Some(canvas) = deleted_canvases.get_mut(canvas_id) else {
+        return false;
+    };
+    canvas.users.remove(user_id);
+    canvas.expirations.remove(user_id);
+    canvas.version += 1;
+    true
+}
+
+/// Removes the canvas, the claims of all its users, its tags and its name, returns None if the canvas is unknown
+fn remove_canvas(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    claims: &mut ClaimIndex,
+    tag_index: &mut HashMap<String, HashSet<CanvasId>>,
+    name_index: &mut NameIndex,
+    canvas_id: &CanvasId,
+) -> Option<Canvas> {
+    let canvas = canvases.remove(canvas_id)?;
+    claims.remove_canvas(canvas_id);
+    unindex_tags(tag_index, canvas_id, &canvas.tags);
+    name_index.remove(&canvas.owner_id, &canvas.name, canvas_id);
+    Some(canvas)
+}
+
+/// Moves the canvas to the deleted canvases, claims, tags and its name are dropped like on removal
+/// Returns false if the canvas is unknown or already deleted
+fn soft_delete_canvas(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    deleted_canvases: &mut HashMap<CanvasId, Canvas>,
+    claims: &mut ClaimIndex,
+    tag_index: &mut HashMap<String, HashSet<CanvasId>>,
+    name_index: &mut NameIndex,
+    canvas_id: &CanvasId,
+    deleted_at: u64,
+) -> bool {
+    let Some(mut canvas) = remove_canvas(canvases, claims, tag_index, name_index, canvas_id) else {
+        return false;
+    };
+    canvas.deleted_at = Some(deleted_at);
+    canvas.version += 1;
+    deleted_canvases.insert(canvas_id.clone(), canvas);
+    true
+}
+
+/// Moves a soft deleted canvas back, the claims of its users, its tags and its name are rebuilt from the canvas
+/// Access that expired while the canvas was deleted is not restored, see replay_events
+/// The name is restored even if the owner used it meanwhile, only creating and renaming check names
+/// Returns false if the canvas is not deleted
+fn restore_canvas(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    deleted_canvases: &mut HashMap<CanvasId, Canvas>,
+    claims: &mut ClaimIndex,
+    tag_index: &mut HashMap<String, HashSet<CanvasId>>,
+    name_index: &mut NameIndex,
+    canvas_id: &CanvasId,
+    now: u64,
+) -> bool {
+    let Some(mut canvas) = deleted_canvases.remove(canvas_id) else {
+        return false;
+    };
+    canvas.deleted_at = None;
+    canvas.version += 1;
+
+    for (user_id, access_level) in &canvas.users {
+        let claim = CanvasClaim {
+            n: canvas.name.clone(),
+            c: canvas_id.clone(),
+            r: access_level.clone(),
+            exp: canvas.expirations.get(user_id).copied(),
+        };
+        if !claim.is_expired(now) {
+            claims.add_claim(user_id, claim);
+        }
+    }
+    for tag in &canvas.tags {
+        tag_index
+            .entry(tag.clone())
+            .or_default()
+            .insert(canvas_id.clone());
+    }
+    name_index.insert(&canvas.owner_id, &canvas.name, canvas_id);
+    canvases.insert(canvas_id.clone(), canvas);
+    true
+}
+
+/// Renames the canvas in its claims and the name index, returns false if the canvas is unknown
+fn rename_canvas(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    claims: &mut ClaimIndex,
+    name_index: &mut NameIndex,
+    canvas_id: &CanvasId,
+    name: String,
+) -> bool {
+    let Some(canvas) = canvases.get_mut(canvas_id) else {
+        return false;
+    };
+    name_index.remove(&canvas.owner_id, &canvas.name, canvas_id);
+    name_index.insert(&canvas.owner_id, &name, canvas_id);
+    claims.update_claim_name(canvas_id, &name);
+    canvas.name = name;
+    canvas.version += 1;
+    true
+}
+
+/// Replaces the tags of the canvas and updates the tag index, returns false if the canvas is unknown
+fn set_tags(
+    canvases: &mut HashMap<CanvasId, Canvas>,
+    tag_index: &mut HashMap<String, HashSet<CanvasId>>,
+    canvas_id: &CanvasId,
+    tags: Vec<String>,
+) -> bool {
+    let Some(canvas) = canvases.get_mut(canvas_id) else {
+        return false;
+    };
+    unindex_tags(tag_index, canvas_id, &canvas.tags);
+    for tag in &tags {
+        tag_index
+            .entry(tag.clone())
+            .or_default()
+            .insert(canvas_id.clone());
+    }
+    canvas.tags = tags;
+    canvas.version += 1;
+    true
+}
+
+/// Drops the canvas from the index entries of the tags, tags without canvases are removed
+fn unindex_tags(
+    tag_index: &mut HashMap<String, HashSet<CanvasId>>,
+    canvas_id: &CanvasId,
+    tags: &[String],
+) {
+    for tag in tags {
+        if let Some(canvas_ids) = tag_index.get_mut(tag) {
+            canvas_ids.remove(canvas_id);
+            if canvas_ids.is_empty() {
+                tag_index.remove(tag);
+            }
+        }
+    }
+}
+
+/// Drops the visits or preferences of a deleted canvas
+fn remove_canvas_entries<T>(
+    entries: &mut HashMap<UserId, HashMap<CanvasId, T>>,
+    canvas_id: &CanvasId,
+) {
+    for user_entries in entries.values_mut() {
+        user_entries.remove(canvas_id);
+    }
+}
+
+/// Stores the preference of the user, preferences without pin or hint are not kept
+fn set_preference(
+    preferences: &mut HashMap<UserId, HashMap<CanvasId, CanvasPreference>>,
+    user_id: &UserId,
+    canvas_id: CanvasId,
+    preference: CanvasPreference,
+) {
+    let user_preferences = preferences.entry(user_id.clone()).or_default();
+    if preference == CanvasPreference::default() {
+        user_preferences.remove(&canvas_id);
+    } else {
+        user_preferences.insert(canvas_id, preference);
+    }
+}
+
+impl CanvasStore {
+    /// Replays the saved events, the issues found are returned for the caller to decide on, see ReplayMode
+    pub fn new(
+        event_persistence_recipient: Recipient<PersistEventMessage<CanvasStoreEvents>>,
+        saved_events: Vec<CanvasStoreEvents>,
+        quota_limits: QuotaLimits,
+        clock: SharedClock,
+    ) -> (Self, Vec<ReplayIssue>) {
+        let now = clock.now_ms();
+        let (state, issues) = replay_events(saved_events, now);
+
+        // canvases above the threshold were warned about before the restart
+        let member_quota_warnings = state
+            .canvases
+            .values()
+            .map(|canvas| {
+                let mut warnings = QuotaWarnings::default();
+                warnings.check(
+                    QuotaKind::Members,
+                    canvas.member_count(now) as u64,
+                    &quota_limits,
+                );
+                (canvas.id.clone(), warnings)
+            })
+            .collect();
+
+        let store = Self {
+            event_persistence_recipient,
+            canvases: state.canvases,
+            deleted_canvases: state.deleted_canvases,
+            deletion_grace: DEFAULT_DELETION_GRACE,
+            purged_canvases: state.purged_canvases,
+            quarantine_retention: orphans::DEFAULT_QUARANTINE_RETENTION,
+            replay_cache: Arc::default(),
+            claims: state.claims,
+            tag_index: state.tag_index,
+            name_index: state.name_index,
+            unique_names: false,
+            canvas_server_handle: None,
+            quota_limits,
+            member_quota_warnings,
+            quota_warnings: state.quota_warnings,
+            visits: state.visits,
+            preferences: state.preferences,
+            digests: state.digests,
+            access_requests: state.access_requests,
+            degraded: DegradedMode::default(),
+            mailbox_capacity: mailbox::DEFAULT_MAILBOX_CAPACITY,
+            handler_trace: HandlerTrace::new(
+                "canvas_store",
+                mailbox::DEFAULT_SLOW_HANDLER_THRESHOLD,
+            ),
+            stamps: MonotonicStamps::new(clock.clone()),
+            clock,
+        };
+        (store, issues)
+    }
+
+    /// Bounds the mailbox and shares the read-only mode switched by the MailboxProbe of the persistence
+    pub fn with_mailbox(mut self, capacity: usize, degraded: DegradedMode) -> Self {
+        self.mailbox_capacity = capacity;
+        self.degraded = degraded;
+        self
+    }
+
+    /// Shares the trace of the handlers, listed by the ActorGauges
+    pub fn with_handler_trace(mut self, handler_trace: Arc<HandlerTrace>) -> Self {
+        self.handler_trace = handler_trace;
+        self
+    }
+
+    /// Time a soft deleted canvas can be restored, it is purged by the next sweep afterwards
+    pub fn with_deletion_grace(mut self, deletion_grace: Duration) -> Self {
+        self.deletion_grace = deletion_grace;
+        self
+    }
+
+    /// Time purged eventlogs are kept in the quarantine before the purge sweep removes them
+    pub fn with_quarantine_retention(mut self, quarantine_retention: Duration) -> Self {
+        self.quarantine_retention = quarantine_retention;
+        self
+    }
+
+    /// Shares the cache of the replay handlers, deleting and purging a canvas evicts its replays
+    pub fn with_replay_cache(mut self, replay_cache: Arc<ReplayCache>) -> Self {
+        self.replay_cache = replay_cache;
+        self
+    }
+
+    /// Users claiming canvases the UserStore doesn't know, with the claimed canvases, checked on startup
+    pub fn unknown_claimants(
+        &self,
+        known_users: &HashSet<UserId>,
+    ) -> BTreeMap<UserId, Vec<CanvasId>> {
+        self.claims.unknown_claimants(known_users)
+    }
+
+    /// Rejects names an owner already uses instead of only resolving them on request
+    pub fn with_unique_names(mut self, unique_names: bool) -> Self {
+        self.unique_names = unique_names;
+        self
+    }
+
+    ///
+    /// Name a canvas of the owner gets, except is the canvas being renamed
+    /// auto_rename appends the first free counter, otherwise a used name is rejected if names are unique
+    ///
+    fn resolve_name(
+        &self,
+        owner_id: &UserId,
+        name: &str,
+        except: Option<&CanvasId>,
+        auto_rename: bool,
+    ) -> Result<String, CanvasStoreError> {
+        if auto_rename {
+            return Ok(self.name_index.available(owner_id, name, except));
+        }
+        if self.unique_names && self.name_index.is_taken(owner_id, name, except) {
+            return Err(CanvasStoreError::DuplicateName(name.trim().to_string()));
+        }
+        Ok(name.to_string())
+    }
+
+    /// Rejects changes while the store is read-only, see mailbox::DegradedMode
+    fn check_writable(&self) -> Result<(), CanvasStoreError> {
+        if self.degraded.is_active() {
+            return Err(CanvasStoreError::Degraded);
+        }
+        Ok(())
+    }
+}
+
+impl CanvasStore {
+    /// Applies a persisted UserCanvasAdded to the canvas and the claims, the canvas has to exist
+    fn apply_grant(
+        &mut self,
+        canvas_id: &CanvasId,
+        user_id: &UserId,
+        access_level: AccessLevel,
+        expires_at: Option<u64>,
+        ctx: &mut Context<Self>,
+    ) {
+        let canvas = self.canvases.get_mut(canvas_id).unwrap();
+        canvas.version += 1;
+        match expires_at {
+            Some(expires_at) => canvas.expirations.insert(user_id.clone(), expires_at),
+            None => canvas.expirations.remove(user_id),
+        };
+        canvas.users.insert(user_id.clone(), access_level.clone());
+
+        // update lookup cache
+        self.claims
+            .set_claim_level(user_id, canvas, access_level, expires_at);
+
+        self.check_member_quota(canvas_id, ctx);
+    }
+
+    /// Warns owners and moderators once the members of the canvas cross the warning threshold
+    fn check_member_quota(&mut self, canvas_id: &CanvasId, ctx: &mut Context<Self>) {
+        let Some(canvas) = self.canvases.get(canvas_id) else {
+            return;
+        };
+        let members = canvas.member_count(self.clock.now_ms()) as u64;
+
+        let warned = self
+            .member_quota_warnings
+            .entry(canvas_id.clone())
+            .or_default()
+            .check(QuotaKind::Members, members, &self.quota_limits);
+        if !warned {
+            return;
+        }
+
+        let usage = self.quota_limits.usage(QuotaKind::Members, members);
+        if let Some(handle) = &self.canvas_server_handle {
+            handle.notify_quota_warning(canvas_id.clone(), usage.clone());
+        }
+        ctx.address().do_send(RecordQuotaWarningMessage {
+            canvas_id: canvas_id.clone(),
+            usage,
+        });
+    }
+
+    /// Unix timestamp in milliseconds after which the soft deleted canvas is purged
+    fn purge_at(&self, deleted_at: u64) -> u64 {
+        deleted_at.saturating_add(self.deletion_grace.as_millis() as u64)
+    }
+
+    /// Expired temporary access counts as none, even before the sweep removed it
+    fn get_access_level(&self, user_id: &UserId, canvas_id: &CanvasId) -> AccessLevel {
+        let now = self.clock.now_ms();
+        // guests have no claims, their level follows the canvas
+        if guests::is_guest(user_id) {
+            return self
+                .canvases
+                .get(canvas_id)
+                .map_or(AccessLevel::None, |canvas| {
+                    canvas.access_level(user_id, now)
+                });
+        }
+        self.claims
+            .get(user_id)
+            .map(|claims| {
+                claims
+                    .iter()
+                    .find(|claim| claim.c == *canvas_id && !claim.is_expired(now))
+                    .map(|claim| claim.r.clone())
+                    .unwrap_or(AccessLevel::None)
+            })
+            .unwrap_or(AccessLevel::None)
+    }
+
+    fn validate_permission_change(
+        &self,
+        initiator_access_level: &AccessLevel,
+        target_access_level: &AccessLevel,
+        access_level: &AccessLevel,
+    ) -> Result<(), CanvasStoreError> {
+        match (initiator_access_level, target_access_level, access_level) {
+            // owner can't change himself
+            (AccessLevel::Owner, AccessLevel::Owner, _) => Err(CanvasStoreError::AccessDenied(
+                MessageKey::OwnerChangesOwnAccess,
+            )),
+            // owner can't elect a new owner
+            (AccessLevel::Owner, _, AccessLevel::Owner) => Err(CanvasStoreError::AccessDenied(
+                MessageKey::OwnerAssignsOwner,
+            )),
+
+            // owner can change anything else
+            (AccessLevel::Owner, _, _) => Ok(()),
+
+            // moderate can't change owner nor moderator
+            (AccessLevel::Moderate, AccessLevel::Owner | AccessLevel::Moderate, _) => Err(
+                CanvasStoreError::AccessDenied(MessageKey::ModeratorChangesPrivileged),
+            ),
+            // moderate can't assign owner nor moderate
+            (AccessLevel::Moderate, _, AccessLevel::Owner | AccessLevel::Moderate) => Err(
+                CanvasStoreError::AccessDenied(MessageKey::ModeratorAssignsPrivileged),
+            ),
+
+            // moderator is allowed to change any users access level that is left
+            (AccessLevel::Moderate, _, _) => Ok(()),
+
+            // non moderate or owner can't change anything
+            (_, _, _) => Err(CanvasStoreError::AccessDenied(
+                MessageKey::AccessLevelChangeDenied,
+            )),
+        }
+    }
+}
+
+impl Actor for CanvasStore {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
+        ctx.run_interval(GRANT_SWEEP_INTERVAL, |store, ctx| {
+            ctx.address().do_send(SweepExpiredGrantsMessage {
+                now: store.clock.now_ms(),
+            });
+        });
+        ctx.run_interval(PURGE_SWEEP_INTERVAL, |store, ctx| {
+            ctx.address().do_send(PurgeDeletedCanvasesMessage {
+                now: store.clock.now_ms(),
+            });
+            match orphans::sweep_quarantine(
+                orphans::CANVAS_LOG_DIR,
+                store.quarantine_retention,
+                store.clock.now_ms(),
+            ) {
+                Ok(removed) if !removed.is_empty() => {
+                    println!("Removed {} quarantined eventlogs", removed.len())
+                }
+                Ok(_) => (),
+                Err(e) => println!("WARNING: failed to sweep the eventlog quarantine: {e}"),
+            }
+        });
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[allow(clippy::enum_variant_names)] // Canvas Application uses this naming convention
+pub enum CanvasStoreEvents {
+    /// Created a new canvas
+    CanvasCreated {
+        timestamp: u64,
+        owner_id: UserId,
+        canvas_id: CanvasId,
+        state: CanvasState,
+        name: String,
+    },
+    /// Owner renamed the canvas
+    CanvasRenamed {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        name: String,
+    },
+    /// Purges a canvas for good, follows CanvasSoftDeleted once the grace period passed
+    /// Logs written before soft deletion existed delete live canvases with it
+    CanvasDeleted { timestamp: u64, canvas_id: CanvasId },
+    /// Deletes a canvas, it can be restored until it is purged
+    CanvasSoftDeleted {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+    },
+    /// Restores a soft deleted canvas
+    CanvasRestored {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+    },
+    /// Adds the user to a canvas (this is mirrored in the canvas store, to make lookups easier)
+    UserCanvasAdded {
+        timestamp: u64,
+        user_id: UserId,
+        initiator_user_id: UserId,
+        canvas_id: CanvasId,
+        access_level: AccessLevel,
+        /// temporary access, unix timestamp in milliseconds
+        #[serde(default)]
+        expires_at: Option<u64>,
+    },
+    /// Removes the user from a canvas (this is mirrored in the canvas store, to make lookups easier)
+    UserCanvasRemoved {
+        timestamp: u64,
+        user_id: String,
+        canvas_id: CanvasId,
+    },
+    /// Changes the state of a canvas, moderated, active etc.
+    CanvasStateChanged {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        state: CanvasState,
+    },
+    /// Changes the grid and snapping settings of a canvas
+    CanvasSettingsChanged {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        settings: CanvasSettings,
+    },
+    /// Replaces the palette of a canvas, the other settings are kept
+    CanvasPaletteChanged {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        palette: Vec<PaletteColor>,
+        enforce_palette: bool,
+    },
+    /// Replaces the feature flag overrides of a canvas
+    CanvasFeatureFlagsChanged {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        overrides: BTreeMap<String, bool>,
+    },
+    /// Owner created an API token, only the hash of the token is persisted
+    ApiTokenCreated {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        token_id: TokenId,
+        label: String,
+        access_level: AccessLevel,
+        /// unix timestamp in milliseconds
+        expires_at: Option<u64>,
+        secret_hash: String,
+    },
+    /// Owner revoked an API token
+    ApiTokenRevoked {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        token_id: TokenId,
+    },
+    /// Replaces the tags of a canvas
+    CanvasTagsChanged {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        initiator_id: UserId,
+        tags: Vec<String>,
+    },
+    /// A quota of the canvas crossed its warning threshold
+    QuotaWarningIssued {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        usage: QuotaUsage,
+    },
+    /// User opened the canvas page, persisted at most once per CANVAS_VISIT_DEBOUNCE
+    CanvasVisited {
+        timestamp: u64,
+        user_id: UserId,
+        canvas_id: CanvasId,
+    },
+    /// User pinned or ordered canvases of the home page, carries the resulting preference of every changed canvas
+    UserCanvasPreferenceChanged {
+        timestamp: u64,
+        user_id: UserId,
+        preferences: BTreeMap<CanvasId, CanvasPreference>,
+    },
+    /// Owner was sent a digest of the canvas eventlog up to seq, see digest.rs
+    CanvasDigested {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        seq: u64,
+    },
+    /// User without access asked for it, at most one request per user and canvas is pending
+    AccessRequested {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Pending access request was approved, denied or cancelled by the requester
+    /// An approval follows the UserCanvasAdded of the grant, see ResolveAccessRequestMessage
+    AccessRequestResolved {
+        timestamp: u64,
+        canvas_id: CanvasId,
+        user_id: UserId,
+        initiator_id: UserId,
+        status: AccessRequestStatus,
+        /// level granted on approval
+        #[serde(default)]
+        access_level: Option<AccessLevel>,
+    },
+}
+
+/// Changes the state of a canvas, only applied if the initiator may take the action, see transition,
+/// and the canvas is still at expected_version
+/// Resolves to the new version and state of the canvas
+#[derive(Message)]
+#[rtype(result = "Result<(u64, CanvasState), CanvasStoreError>")]
+pub struct UpdateCanvasStateMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub action: CanvasStateAction,
+    pub expected_version: u64,
+}
+
+impl Handler<UpdateCanvasStateMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(u64, CanvasState), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: UpdateCanvasStateMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<UpdateCanvasStateMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let now = self.clock.now_ms();
+        let check = match self.canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) => transition(
+                &canvas.state,
+                msg.action,
+                &canvas.access_level(&msg.initiator_id, now),
+            )
+            .and_then(|state| {
+                // another moderator changed the canvas since the client loaded it
+                if canvas.version != msg.expected_version {
+                    return Err(CanvasStoreError::VersionConflict {
+                        current_version: canvas.version,
+                        state: canvas.state.clone(),
+                    });
+                }
+                Ok(state)
+            }),
+        };
+        let state = match check {
+            Ok(state) => state,
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        };
+
+        let event = CanvasStoreEvents::CanvasStateChanged {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            state: state.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| {
+                        match result {
+                            Ok(Ok(_)) => {
+                                // insert after persistence
+                                // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                                let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                                canvas.state = state.clone();
+                                canvas.version += 1;
+                                Ok((canvas.version, state))
+                            }
+                            Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                            Err(e) => Err(CanvasStoreError::persistence(e)),
+                        }
+                    }),
+            ),
+        )
+    }
+}
+
+/// Replaces the settings of a canvas, the last change wins
+/// Resolves to the new version of the canvas and the settings in effect
+#[derive(Message)]
+#[rtype(result = "Result<(u64, CanvasSettings), CanvasStoreError>")]
+pub struct UpdateCanvasSettingsMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    /// its legacy_voice_behavior, metadata, retention, anonymization, guest access, digest and shape types are ignored, the fields of the message decide
+    pub settings: CanvasSettings,
+    /// None keeps the voice behavior of the canvas, only the owner may change it
+    pub legacy_voice_behavior: Option<bool>,
+    /// None keeps the metadata of the canvas, Some(None) removes it, only the owner may change it
+    /// Has to be normalized by normalize_metadata
+    pub metadata: Option<Option<CanvasMetadata>>,
+    /// None keeps the retention overrides of the canvas, only the owner may change them
+    pub retention: Option<RetentionOverrides>,
+    /// None keeps the anonymization of the canvas, only the owner may change it
+    pub anonymize_for_readers: Option<bool>,
+    /// None keeps the guest access of the canvas, only the owner may change it
+    pub guest_access: Option<GuestAccess>,
+    /// None keeps the digest frequency of the canvas, only the owner may change it
+    pub digest: Option<DigestFrequency>,
+    /// None keeps the allowed shape types of the canvas, only the owner may change them
+    pub allowed_shape_types: Option<BTreeSet<ShapeType>>,
+    /// None keeps the bypass of owners and moderators, only the owner may change it
+    pub moderators_bypass_shape_types: Option<bool>,
+}
+
+impl Handler<UpdateCanvasSettingsMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(u64, CanvasSettings), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: UpdateCanvasSettingsMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<UpdateCanvasSettingsMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let Some(canvas) = self.canvases.get(&msg.canvas_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        };
+
+        let current = canvas.settings.legacy_voice_behavior;
+        let legacy_voice_behavior = msg.legacy_voice_behavior.unwrap_or(current);
+        if legacy_voice_behavior != current && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasVoiceBehaviorDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+
+        let metadata = msg
+            .metadata
+            .unwrap_or_else(|| canvas.settings.metadata.clone());
+        if metadata != canvas.settings.metadata && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasMetadataDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+
+        let retention = msg
+            .retention
+            .unwrap_or_else(|| canvas.settings.retention.clone());
+        if retention != canvas.settings.retention && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasRetentionDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+
+        let current = canvas.settings.anonymize_for_readers;
+        let anonymize_for_readers = msg.anonymize_for_readers.unwrap_or(current);
+        if anonymize_for_readers != current && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasAnonymizeDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+        let guest_access = msg.guest_access.unwrap_or(canvas.settings.guest_access);
+        if guest_access != canvas.settings.guest_access && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasGuestAccessDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+        let digest = msg.digest.unwrap_or(canvas.settings.digest);
+        if digest != canvas.settings.digest && canvas.owner_id != msg.initiator_id {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasDigestDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+        let allowed_shape_types = msg
+            .allowed_shape_types
+            .unwrap_or_else(|| canvas.settings.allowed_shape_types.clone());
+        let moderators_bypass_shape_types = msg
+            .moderators_bypass_shape_types
+            .unwrap_or(canvas.settings.moderators_bypass_shape_types);
+        if (allowed_shape_types != canvas.settings.allowed_shape_types
+            || moderators_bypass_shape_types != canvas.settings.moderators_bypass_shape_types)
+            && canvas.owner_id != msg.initiator_id
+        {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasShapeTypesDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+        // pseudonyms stay the same when the canvas is anonymized again
+        let reader_salt = canvas
+            .settings
+            .reader_salt
+            .clone()
+            .or_else(|| anonymize_for_readers.then(|| nanoid!(24)));
+
+        let settings = CanvasSettings {
+            legacy_voice_behavior,
+            metadata,
+            retention,
+            anonymize_for_readers,
+            reader_salt,
+            guest_access,
+            digest,
+            allowed_shape_types,
+            moderators_bypass_shape_types,
+            // the palette has its own message, see UpdateCanvasPaletteMessage
+            palette: canvas.settings.palette.clone(),
+            enforce_palette: canvas.settings.enforce_palette,
+            ..msg.settings
+        };
+
+        let event = CanvasStoreEvents::CanvasSettingsChanged {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            settings: settings.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                            canvas.settings = settings.clone();
+                            canvas.version += 1;
+                            Ok((canvas.version, settings))
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Replaces the tags of a canvas, tags have to be normalized by normalize_tags
+/// Resolves to the new version of the canvas
+#[derive(Message)]
+#[rtype(result = "Result<u64, CanvasStoreError>")]
+pub struct UpdateCanvasTagsMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub tags: Vec<String>,
+}
+
+impl Handler<UpdateCanvasTagsMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<u64, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: UpdateCanvasTagsMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<UpdateCanvasTagsMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let event = CanvasStoreEvents::CanvasTagsChanged {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            tags: msg.tags.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            set_tags(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.tag_index,
+                                &msg.canvas_id,
+                                msg.tags,
+                            );
+                            Ok(canvasstore.canvases[&msg.canvas_id].version)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Replaces the palette of a canvas, the palette has to be normalized by palette::normalize_palette
+/// Resolves to the new version of the canvas
+#[derive(Message)]
+#[rtype(result = "Result<u64, CanvasStoreError>")]
+pub struct UpdateCanvasPaletteMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub palette: Vec<PaletteColor>,
+    pub enforce_palette: bool,
+}
+
+impl Handler<UpdateCanvasPaletteMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<u64, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: UpdateCanvasPaletteMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<UpdateCanvasPaletteMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let event = CanvasStoreEvents::CanvasPaletteChanged {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            palette: msg.palette.clone(),
+            enforce_palette: msg.enforce_palette,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                            canvas.settings.palette = msg.palette;
+                            canvas.settings.enforce_palette = msg.enforce_palette;
+                            canvas.version += 1;
+                            Ok(canvas.version)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Replaces the feature flag overrides of a canvas, only the owner may change them
+/// Overrides have to be checked against the server flags by FeatureFlags::rejected_override
+/// Resolves to the new version of the canvas
+#[derive(Message)]
+#[rtype(result = "Result<u64, CanvasStoreError>")]
+pub struct UpdateCanvasFeatureFlagsMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub overrides: BTreeMap<String, bool>,
+}
+
+impl Handler<UpdateCanvasFeatureFlagsMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<u64, CanvasStoreError>>;
+
+    fn handle(
+        &mut self,
+        msg: UpdateCanvasFeatureFlagsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        let timer = self
+            .handler_trace
+            .start::<UpdateCanvasFeatureFlagsMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let check = match self.canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) if canvas.owner_id != msg.initiator_id => Err(
+                CanvasStoreError::AccessDenied(MessageKey::CanvasFeatureFlagsDenied),
+            ),
+            Some(_) => Ok(()),
+        };
+        if let Err(e) = check {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let event = CanvasStoreEvents::CanvasFeatureFlagsChanged {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            overrides: msg.overrides.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                            canvas.feature_overrides = msg.overrides;
+                            canvas.version += 1;
+                            Ok(canvas.version)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Adds an API token to a canvas, only the owner may create tokens
+/// The token carries the hash of the plaintext, see tokens::hash
+/// Resolves to the stored token, created_at is set by the store
+#[derive(Message)]
+#[rtype(result = "Result<ApiToken, CanvasStoreError>")]
+pub struct CreateApiTokenMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub token: ApiToken,
+}
+
+impl Handler<CreateApiTokenMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<ApiToken, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: CreateApiTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<CreateApiTokenMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let check = match self.canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) if canvas.owner_id != msg.initiator_id => {
+                Err(CanvasStoreError::AccessDenied(MessageKey::ApiTokenDenied))
+            }
+            Some(_) if !tokens::is_grantable(&msg.token.access_level) => Err(
+                CanvasStoreError::AccessDenied(MessageKey::ApiTokenAccessLevelInvalid),
+            ),
+            Some(_) => Ok(()),
+        };
+        if let Err(e) = check {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let mut token = msg.token;
+        token.created_at = self.stamps.stamp_ms();
+        let event = CanvasStoreEvents::ApiTokenCreated {
+            timestamp: token.created_at,
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id,
+            token_id: token.id.clone(),
+            label: token.label.clone(),
+            access_level: token.access_level.clone(),
+            expires_at: token.expires_at,
+            secret_hash: token.secret_hash.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                            canvas.api_tokens.insert(token.id.clone(), token.clone());
+                            Ok(token)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Revokes an API token of a canvas, only the owner may revoke tokens
+/// Resolves to false if the canvas has no such token
+#[derive(Message)]
+#[rtype(result = "Result<bool, CanvasStoreError>")]
+pub struct RevokeApiTokenMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub token_id: TokenId,
+}
+
+impl Handler<RevokeApiTokenMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<bool, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: RevokeApiTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RevokeApiTokenMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let check = match self.canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) if canvas.owner_id != msg.initiator_id => {
+                Err(CanvasStoreError::AccessDenied(MessageKey::ApiTokenDenied))
+            }
+            Some(canvas) => Ok(canvas.api_tokens.contains_key(&msg.token_id)),
+        };
+        match check {
+            Ok(true) => {}
+            Ok(false) => {
+                return timed_atomic(timer, Box::pin(async move { Ok(false) }.into_actor(self)))
+            }
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        }
+
+        let event = CanvasStoreEvents::ApiTokenRevoked {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id,
+            token_id: msg.token_id.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                            canvas.api_tokens.remove(&msg.token_id);
+                            Ok(true)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// API tokens of a canvas ordered by creation, None if the canvas does not exist
+#[derive(Message)]
+#[rtype(result = "Option<Vec<ApiToken>>")]
+pub struct GetApiTokensMessage {
+    pub canvas_id: CanvasId,
+}
+
+impl Handler<GetApiTokensMessage> for CanvasStore {
+    type Result = Option<Vec<ApiToken>>;
+
+    fn handle(&mut self, msg: GetApiTokensMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetApiTokensMessage>();
+        let canvas = self.canvases.get(&msg.canvas_id)?;
+        let mut tokens: Vec<ApiToken> = canvas.api_tokens.values().cloned().collect();
+        tokens.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        Some(tokens)
+    }
+}
+
+/// Token matching the hash with the single claim of its principal
+/// None for unknown, revoked and expired tokens and deleted canvases
+#[derive(Message)]
+#[rtype(result = "Option<(ApiToken, CanvasClaim)>")]
+pub struct ResolveApiTokenMessage {
+    pub canvas_id: CanvasId,
+    pub token_id: TokenId,
+    pub secret_hash: String,
+}
+
+impl Handler<ResolveApiTokenMessage> for CanvasStore {
+    type Result = Option<(ApiToken, CanvasClaim)>;
+
+    fn handle(&mut self, msg: ResolveApiTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<ResolveApiTokenMessage>();
+        let now = self.clock.now_ms();
+        let canvas = self.canvases.get(&msg.canvas_id)?;
+        let token = canvas
+            .api_tokens
+            .get(&msg.token_id)
+            .filter(|token| token.secret_hash == msg.secret_hash && !token.is_expired(now))?;
+
+        let claim = CanvasClaim {
+            n: canvas.name.clone(),
+            c: canvas.id.clone(),
+            r: token.access_level.clone(),
+            exp: token.expires_at,
+        };
+        Some((token.clone(), claim))
+    }
+}
+
+/// Resolves to the created canvas, its name may differ from the requested one with auto_rename
+#[derive(Message)]
+#[rtype(result = "Result<Canvas, CanvasStoreError>")]
+pub struct CreateCanvasMessage {
+    pub canvas: CreateCanvas,
+    /// a name the owner already uses gets the first free " (n)" suffix, see NameIndex::available
+    pub auto_rename: bool,
+}
+
+impl Handler<CreateCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<Canvas, CanvasStoreError>>;
+
+    // This function is atomic, meaning that the actor will not be able to handle any other messages until the response is resolved
+    fn handle(&mut self, msg: CreateCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<CreateCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let id = (0..MAX_ID_GENERATION_ITERATIONS)
+            .map(|_| nanoid!(CANVAS_ID_LENGTH, &CANVAS_ID_ALPHABET))
+            .find(|id| !self.canvases.contains_key(id) && !self.deleted_canvases.contains_key(id))
+            .ok_or(CanvasStoreError::IdGenerationFailed);
+
+        let id = match id {
+            Ok(id) => id,
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        };
+        let mut msg = msg;
+        msg.canvas.name = match self.resolve_name(
+            &msg.canvas.owner_id,
+            &msg.canvas.name,
+            None,
+            msg.auto_rename,
+        ) {
+            Ok(name) => name,
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        };
+
+        let mut users = HashMap::with_capacity(1);
+        users.insert(msg.canvas.owner_id.clone(), AccessLevel::Owner);
+
+        let timestamp = self.stamps.stamp_ms();
+        let canvas = Canvas {
+            id: id.clone(),
+            name: msg.canvas.name.clone(),
+            owner_id: msg.canvas.owner_id.clone(),
+            state: CanvasState::Active,
+            users,
+            version: 1,
+            expirations: HashMap::new(),
+            settings: CanvasSettings::default(),
+            tags: Vec::new(),
+            feature_overrides: BTreeMap::new(),
+            api_tokens: HashMap::new(),
+            created_at: timestamp,
+            deleted_at: None,
+        };
+
+        let event = CanvasStoreEvents::CanvasCreated {
+            timestamp,
+            owner_id: msg.canvas.owner_id.clone(),
+            canvas_id: id.clone(),
+            state: canvas.state.clone(),
+            name: msg.canvas.name.clone(),
+        };
+
+        self.canvases.insert(id.clone(), canvas.clone());
+        self.name_index
+            .insert(&msg.canvas.owner_id, &msg.canvas.name, &id);
+
+        let canvas_claim = CanvasClaim {
+            n: msg.canvas.name,
+            c: id,
+            r: AccessLevel::Owner,
+            exp: None,
+        };
+        self.claims.add_claim(&msg.canvas.owner_id, canvas_claim);
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(|result, canvasstore, _| {
+                        let canvas_for_error = canvas.clone(); // same as userstore this whole future thing already took to long to figure out, just copy user for error handling
+                        match result {
+                            Ok(Ok(_)) => Ok(canvas),
+                            Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                            Err(e) => Err(CanvasStoreError::persistence(e)),
+                        }
+                        .inspect_err(|_error| {
+                            canvasstore.canvases.remove(&canvas_for_error.id);
+                            canvasstore
+                                .claims
+                                .remove_claim(&canvas_for_error.owner_id, &canvas_for_error.id);
+                            canvasstore.name_index.remove(
+                                &canvas_for_error.owner_id,
+                                &canvas_for_error.name,
+                                &canvas_for_error.id,
+                            );
+                        })
+                    }),
+            ),
+        )
+    }
+}
+
+/// Renames a canvas, only checks the names of its owner, access is checked by the caller
+/// Resolves to the name the canvas got, it may differ from the requested one with auto_rename
+#[derive(Message)]
+#[rtype(result = "Result<String, CanvasStoreError>")]
+pub struct RenameCanvasMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub name: String,
+    pub auto_rename: bool,
+}
+
+impl Handler<RenameCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<String, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: RenameCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RenameCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let Some(canvas) = self.canvases.get(&msg.canvas_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        };
+        let name = match self.resolve_name(
+            &canvas.owner_id,
+            &msg.name,
+            Some(&msg.canvas_id),
+            msg.auto_rename,
+        ) {
+            Ok(name) => name,
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        };
+
+        let event = CanvasStoreEvents::CanvasRenamed {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id,
+            name: name.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                            rename_canvas(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.claims,
+                                &mut canvasstore.name_index,
+                                &msg.canvas_id,
+                                name.clone(),
+                            );
+                            Ok(name)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Claims of a user, ordered from oldest to most recently granted
+/// limit keeps the most recently visited claims, used to keep the JWT small for users with many canvases
+/// Claims never visited follow the visited ones, the most recently granted first
+#[derive(Message)]
+#[rtype(result = "Vec<CanvasClaim>")]
+pub struct GetUserClaimsMessage {
+    pub user_id: UserId,
+    pub limit: Option<usize>,
+    /// only return the claim for this canvas
+    pub canvas_id: Option<CanvasId>,
+}
+
+impl Handler<GetUserClaimsMessage> for CanvasStore {
+    type Result = Vec<CanvasClaim>;
+
+    fn handle(&mut self, msg: GetUserClaimsMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUserClaimsMessage>();
+        // expired claims are dropped once the JWT is regenerated
+        let now = self.clock.now_ms();
+        let Some(claims) = self.claims.get(&msg.user_id) else {
+            return Vec::new();
+        };
+
+        let claims: Vec<CanvasClaim> = claims
+            .iter()
+            .filter(|claim| !claim.is_expired(now))
+            .filter(|claim| {
+                msg.canvas_id
+                    .as_ref()
+                    .is_none_or(|canvas_id| claim.c == *canvas_id)
+            })
+            .cloned()
+            .collect();
+
+        match msg.limit {
+            Some(limit) if claims.len() > limit => {
+                let visits = self.visits.get(&msg.user_id);
+                let mut ranked: Vec<(Option<u64>, usize)> = claims
+                    .iter()
+                    .enumerate()
+                    .map(|(index, claim)| {
+                        let last_visit = visits.and_then(|visits| visits.get(&claim.c)).copied();
+                        (last_visit, index)
+                    })
+                    .collect();
+                ranked.sort_unstable_by(|a, b| b.cmp(a));
+                let kept: HashSet<usize> = ranked
+                    .into_iter()
+                    .take(limit)
+                    .map(|(_, index)| index)
+                    .collect();
+                claims
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| kept.contains(index))
+                    .map(|(_, claim)| claim)
+                    .collect()
+            }
+            _ => claims,
+        }
+    }
+}
+
+/// Access level of a user on a canvas, used when the claim is not part of the JWT
+#[derive(Message)]
+#[rtype(result = "AccessLevel")]
+pub struct GetUserAccessLevelMessage {
+    pub user_id: UserId,
+    pub canvas_id: CanvasId,
+}
+
+impl Handler<GetUserAccessLevelMessage> for CanvasStore {
+    type Result = MessageResult<GetUserAccessLevelMessage>;
+
+    fn handle(&mut self, msg: GetUserAccessLevelMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUserAccessLevelMessage>();
+        MessageResult(self.get_access_level(&msg.user_id, &msg.canvas_id))
+    }
+}
+
+#[derive(Message, Clone)]
+#[rtype(result = "Option<Canvas>")]
+pub struct GetCanvasMessage {
+    pub canvas_id: CanvasId,
+}
+
+impl Handler<GetCanvasMessage> for CanvasStore {
+    type Result = Option<Canvas>;
+
+    fn handle(&mut self, msg: GetCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetCanvasMessage>();
+        self.canvases.get(&msg.canvas_id).cloned()
+    }
+}
+
+/// Canvas owned by a user, ownership is taken from the canvas, not from the claims of the user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OwnedCanvasSummary {
+    pub id: CanvasId,
+    pub name: String,
+    pub state: CanvasState,
+    /// users with access, the owner included
+    pub member_count: usize,
+    /// unix timestamp in milliseconds, 0 for canvases created before it was recorded
+    pub created_at: u64,
+    /// soft deleted, unix timestamp in milliseconds
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+}
+
+/// Canvases owned by the user, soft deleted ones included until they are purged, oldest first
+#[derive(Message)]
+#[rtype(result = "Vec<OwnedCanvasSummary>")]
+pub struct GetOwnedCanvasesMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<GetOwnedCanvasesMessage> for CanvasStore {
+    type Result = MessageResult<GetOwnedCanvasesMessage>;
+
+    fn handle(&mut self, msg: GetOwnedCanvasesMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetOwnedCanvasesMessage>();
+        let now = self.clock.now_ms();
+        let mut owned: Vec<OwnedCanvasSummary> = self
+            .canvases
+            .values()
+            .chain(self.deleted_canvases.values())
+            .filter(|canvas| canvas.owner_id == msg.user_id)
+            .map(|canvas| OwnedCanvasSummary {
+                id: canvas.id.clone(),
+                name: canvas.name.clone(),
+                state: canvas.state.clone(),
+                member_count: canvas.member_count(now),
+                created_at: canvas.created_at,
+                deleted_at: canvas.deleted_at,
+            })
+            .collect();
+        owned.sort_by(|a, b| {
+            a.created_at
+                .cmp(&b.created_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+
+        MessageResult(owned)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasMembershipEntry {
+    pub user_id: UserId,
+    pub access_level: AccessLevel,
+    /// temporary access, unix timestamp in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+/// Members of a canvas without the rest of the canvas
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanvasMembership {
+    pub canvas_id: CanvasId,
+    pub name: String,
+    pub owner_id: UserId,
+    pub members: Vec<CanvasMembershipEntry>,
+    /// pending requests of users without access and recently resolved ones, oldest first
+    #[serde(default)]
+    pub access_requests: Vec<AccessRequest>,
+}
+
+/// Members with access at the time of the request, expired access is left out like in Canvas::access_level
+#[derive(Message, Clone)]
+#[rtype(result = "Option<CanvasMembership>")]
+pub struct GetCanvasMembershipMessage {
+    pub canvas_id: CanvasId,
+}
+
+impl Handler<GetCanvasMembershipMessage> for CanvasStore {
+    type Result = MessageResult<GetCanvasMembershipMessage>;
+
+    fn handle(&mut self, msg: GetCanvasMembershipMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetCanvasMembershipMessage>();
+        let now = self.clock.now_ms();
+        MessageResult(self.canvases.get(&msg.canvas_id).map(|canvas| {
+            let members = canvas
+                .users
+                .keys()
+                .filter_map(|user_id| {
+                    let access_level = canvas.access_level(user_id, now);
+                    (access_level != AccessLevel::None).then(|| CanvasMembershipEntry {
+                        user_id: user_id.clone(),
+                        access_level,
+                        expires_at: canvas.expirations.get(user_id).copied(),
+                    })
+                })
+                .collect();
+            // pending requests of users granted access some other way are answered already
+            let access_requests = self
+                .access_requests
+                .get(&canvas.id)
+                .map(|requests| requests.listed(now))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|request| {
+                    request.status != AccessRequestStatus::Pending
+                        || canvas.access_level(&request.user_id, now) == AccessLevel::None
+                })
+                .collect();
+            CanvasMembership {
+                canvas_id: canvas.id.clone(),
+                name: canvas.name.clone(),
+                owner_id: canvas.owner_id.clone(),
+                members,
+                access_requests,
+            }
+        }))
+    }
+}
+
+/// Grants the target user an access level on the canvas
+/// Resolves to the previous level of the target, AccessLevel::None if it was no member yet
+#[derive(Message, Clone)]
+#[rtype(result = "Result<AccessLevel, CanvasStoreError>")]
+pub struct AddUserToCanvasMessage {
+    pub initiator_user_id: UserId,
+    pub canvas_id: CanvasId,
+    pub target_user_id: UserId,
+    pub access_level: AccessLevel,
+    /// temporary access, unix timestamp in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+impl Handler<AddUserToCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<AccessLevel, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: AddUserToCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<AddUserToCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let target_access_level = self.get_access_level(&msg.target_user_id, &msg.canvas_id);
+        let initiator_access_level = self.get_access_level(&msg.initiator_user_id, &msg.canvas_id);
+
+        if let Err(e) = self.validate_permission_change(
+            &initiator_access_level,
+            &target_access_level,
+            &msg.access_level,
+        ) {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let event = CanvasStoreEvents::UserCanvasAdded {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: msg.target_user_id.clone(),
+            initiator_user_id: msg.initiator_user_id.clone(),
+            canvas_id: msg.canvas_id.clone(),
+            access_level: msg.access_level.clone(),
+            expires_at: msg.expires_at,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, ctx| {
+                        match result {
+                            Ok(Ok(_)) => {
+                                let msg = msg.clone();
+
+                                // perform state update, after event is persisted
+
+                                // canvas is guaranteed to exist, CanvasStore is not multi-threaded,
+                                // AtomicRepsonse is used for exlusive state access
+                                canvasstore.apply_grant(
+                                    &msg.canvas_id,
+                                    &msg.target_user_id,
+                                    msg.access_level,
+                                    msg.expires_at,
+                                    ctx,
+                                );
+                                Ok(target_access_level)
+                            }
+                            Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                            Err(e) => Err(CanvasStoreError::persistence(e)),
+                        }
+                    }),
+            ),
+        )
+    }
+}
+
+/// Most entries of an AddUsersToCanvasMessage
+pub const MAX_BATCH_MEMBERS: usize = 100;
+
+/// Outcome of one entry of a batch membership change
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMemberStatus {
+    /// the user was no member yet
+    Added,
+    /// the access level of a member was changed
+    Updated,
+    /// no user has the username or email
+    NotFound,
+    /// the initiator may not grant the level to the user, see validate_permission_change
+    Forbidden,
+}
+
+/// Grants many users their access level on a canvas at once, e.g. a class of students
+/// Every entry is validated first, then all allowed entries are persisted as UserCanvasAdded or none
+/// Resolves to the status of every entry, in the order of the additions
+#[derive(Message)]
+#[rtype(result = "Result<Vec<BatchMemberStatus>, CanvasStoreError>")]
+pub struct AddUsersToCanvasMessage {
+    pub initiator_user_id: UserId,
+    pub canvas_id: CanvasId,
+    /// target user and level, a user named twice ends up with the later level
+    pub additions: Vec<(UserId, AccessLevel)>,
+}
+
+/// Membership of a user before an entry of a batch was applied
+struct PreviousGrant {
+    user_id: UserId,
+    access_level: Option<AccessLevel>,
+    expires_at: Option<u64>,
+}
+
+impl Handler<AddUsersToCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<Vec<BatchMemberStatus>, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: AddUsersToCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<AddUsersToCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let initiator_access_level = self.get_access_level(&msg.initiator_user_id, &msg.canvas_id);
+        // levels after the earlier entries, a user named twice is validated against the first change
+        let mut batch_levels: HashMap<UserId, AccessLevel> = HashMap::new();
+        let mut statuses = Vec::with_capacity(msg.additions.len());
+        let mut accepted = Vec::new();
+        for (target_user_id, access_level) in msg.additions {
+            let target_access_level = batch_levels
+                .get(&target_user_id)
+                .cloned()
+                .unwrap_or_else(|| self.get_access_level(&target_user_id, &msg.canvas_id));
+            if self
+                .validate_permission_change(
+                    &initiator_access_level,
+                    &target_access_level,
+                    &access_level,
+                )
+                .is_err()
+            {
+                statuses.push(BatchMemberStatus::Forbidden);
+                continue;
+            }
+            statuses.push(if target_access_level == AccessLevel::None {
+                BatchMemberStatus::Added
+            } else {
+                BatchMemberStatus::Updated
+            });
+            batch_levels.insert(target_user_id.clone(), access_level.clone());
+            accepted.push((target_user_id, access_level));
+        }
+
+        // applied before persisting, AtomicResponse keeps other messages from seeing the batch
+        // until it is persisted, a failed write rolls the applied entries back
+        let canvas = self.canvases.get_mut(&msg.canvas_id).unwrap();
+        let version = canvas.version;
+        let mut events = Vec::with_capacity(accepted.len());
+        let mut previous_grants = Vec::with_capacity(accepted.len());
+        for (target_user_id, access_level) in &accepted {
+            previous_grants.push(PreviousGrant {
+                user_id: target_user_id.clone(),
+                access_level: canvas
+                    .users
+                    .insert(target_user_id.clone(), access_level.clone()),
+                expires_at: canvas.expirations.remove(target_user_id),
+            });
+            canvas.version += 1;
+            events.push(CanvasStoreEvents::UserCanvasAdded {
+                timestamp: self.stamps.stamp_ms(),
+                user_id: target_user_id.clone(),
+                initiator_user_id: msg.initiator_user_id.clone(),
+                canvas_id: msg.canvas_id.clone(),
+                access_level: access_level.clone(),
+                expires_at: None,
+            });
+        }
+        // reverts the persisted entries in reverse order, so replay ends with the memberships before the batch
+        let compensations: Vec<CanvasStoreEvents> = previous_grants
+            .iter()
+            .map(|previous| match &previous.access_level {
+                Some(access_level) => CanvasStoreEvents::UserCanvasAdded {
+                    timestamp: self.stamps.stamp_ms(),
+                    user_id: previous.user_id.clone(),
+                    initiator_user_id: msg.initiator_user_id.clone(),
+                    canvas_id: msg.canvas_id.clone(),
+                    access_level: access_level.clone(),
+                    expires_at: previous.expires_at,
+                },
+                None => CanvasStoreEvents::UserCanvasRemoved {
+                    timestamp: self.stamps.stamp_ms(),
+                    user_id: previous.user_id.clone(),
+                    canvas_id: msg.canvas_id.clone(),
+                },
+            })
+            .collect();
+
+        let persistence = self.event_persistence_recipient.clone();
+        let canvas_id = msg.canvas_id.clone();
+        let persist_all = async move {
+            let mut persisted = 0;
+            let mut failure = None;
+            for event in events {
+                match persistence.send(PersistEventMessage(event)).await {
+                    Ok(Ok(())) => persisted += 1,
+                    Ok(Err(e)) => failure = Some(CanvasStoreError::persistence(e)),
+                    Err(e) => failure = Some(CanvasStoreError::persistence(e)),
+                }
+                if failure.is_some() {
+                    break;
+                }
+            }
+            let Some(e) = failure else {
+                return Ok(());
+            };
+            for compensation in compensations.into_iter().take(persisted).rev() {
+                if !matches!(
+                    persistence.send(PersistEventMessage(compensation)).await,
+                    Ok(Ok(()))
+                ) {
+                    println!(
+                        "WARNING: batch of {canvas_id} could not be reverted in the eventlog, replay restores {persisted} of its entries"
+                    );
+                    break;
+                }
+            }
+            Err(e)
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                persist_all
+                    .into_actor(self)
+                    .map(move |result, canvasstore, ctx| {
+                        // canvas is guaranteed to exist, AtomicResponse is used for exclusive state access
+                        let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
+                        if let Err(e) = result {
+                            for previous in previous_grants.into_iter().rev() {
+                                match previous.access_level {
+                                    Some(access_level) => {
+                                        canvas.users.insert(previous.user_id.clone(), access_level)
+                                    }
+                                    None => canvas.users.remove(&previous.user_id),
+                                };
+                                if let Some(expires_at) = previous.expires_at {
+                                    canvas.expirations.insert(previous.user_id, expires_at);
+                                }
+                            }
+                            canvas.version = version;
+                            return Err(e);
+                        }
+
+                        for (target_user_id, access_level) in accepted {
+                            canvasstore.claims.set_claim_level(
+                                &target_user_id,
+                                canvas,
+                                access_level,
+                                None,
+                            );
+                        }
+                        canvasstore.check_member_quota(&msg.canvas_id, ctx);
+                        Ok(statuses)
+                    }),
+            ),
+        )
+    }
+}
+
+/// Registers the canvas server, it is notified when temporary access is removed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RegisterCanvasServerMessage {
+    pub handle: CanvasSocketServerHandle,
+}
+
+impl Handler<RegisterCanvasServerMessage> for CanvasStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterCanvasServerMessage, _: &mut Self::Context) {
+        let _timer = self.handler_trace.start::<RegisterCanvasServerMessage>();
+        self.canvas_server_handle = Some(msg.handle);
+    }
+}
+
+/// Removes all temporary access that expired at now, a UserCanvasRemoved event is persisted per grant
+/// Sent periodically by the CanvasStore itself, resolves to the number of removed grants
+#[derive(Message)]
+#[rtype(result = "Result<usize, CanvasStoreError>")]
+pub struct SweepExpiredGrantsMessage {
+    pub now: u64,
+}
+
+impl Handler<SweepExpiredGrantsMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<usize, CanvasStoreError>>;
+
+    // atomic, an overlapping sweep would persist the same removal twice
+    fn handle(&mut self, msg: SweepExpiredGrantsMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<SweepExpiredGrantsMessage>();
+        // retried on the next interval
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let expired: Vec<(CanvasId, UserId)> = self
+            .canvases
+            .values()
+            .flat_map(|canvas| {
+                canvas
+                    .expirations
+                    .iter()
+                    .filter(|(_, expires_at)| **expires_at <= msg.now)
+                    .map(|(user_id, _)| (canvas.id.clone(), user_id.clone()))
+            })
+            .collect();
+
+        let persisted = expired.iter().map(|(canvas_id, user_id)| {
+            self.event_persistence_recipient
+                .send(persistence::PersistEventMessage(
+                    CanvasStoreEvents::UserCanvasRemoved {
+                        timestamp: self.stamps.stamp_ms(),
+                        user_id: user_id.clone(),
+                        canvas_id: canvas_id.clone(),
+                    },
+                ))
+        });
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                futures_util::future::join_all(persisted)
+                    .into_actor(self)
+                    .map(move |results, canvasstore, ctx| {
+                        let mut removed = 0;
+                        for ((canvas_id, user_id), result) in expired.into_iter().zip(results) {
+                            // failed removals are retried by the next sweep
+                            if !matches!(result, Ok(Ok(_))) {
+                                continue;
+                            }
+                            remove_grant(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.claims,
+                                &canvas_id,
+                                &user_id,
+                            );
+                            // dropping below the hysteresis margin allows warning again
+                            canvasstore.check_member_quota(&canvas_id, ctx);
+                            if let Some(handle) = &canvasstore.canvas_server_handle {
+                                // sessions that outlive a stopped server are closed anyway
+                                let _ = handle.update_user_permissions(
+                                    canvas_id,
+                                    user_id,
+                                    AccessLevel::None,
+                                    None,
+                                );
+                            }
+                            removed += 1;
+                        }
+                        Ok(removed)
+                    }),
+            ),
+        )
+    }
+}
+
+/// Removes a deleted user from every canvas it is a member of, a UserCanvasRemoved event is persisted per canvas
+/// Sent after the UserStore deleted the user, or on startup for claims of users the UserStore doesn't know
+/// Owned canvases are kept, the deletion flow requires them to be transferred or deleted first
+/// Soft deleted canvases lose the user as well, a restored canvas doesn't grant access to a deleted user
+/// Resolves to the canvases the user was removed from, its sessions on them are closed
+#[derive(Message)]
+#[rtype(result = "Result<Vec<CanvasId>, CanvasStoreError>")]
+pub struct PurgeUserMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<PurgeUserMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<Vec<CanvasId>, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: PurgeUserMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<PurgeUserMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        // expired grants are members without a claim, they are removed as well
+        let mut memberships: Vec<CanvasId> = self
+            .canvases
+            .values()
+            .chain(self.deleted_canvases.values())
+            .filter(|canvas| canvas.owner_id != msg.user_id)
+            .filter(|canvas| canvas.users.contains_key(&msg.user_id))
+            .map(|canvas| canvas.id.clone())
+            .collect();
+        memberships.sort();
+
+        let persisted: Vec<_> = memberships
+            .iter()
+            .map(|canvas_id| {
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(
+                        CanvasStoreEvents::UserCanvasRemoved {
+                            timestamp: self.stamps.stamp_ms(),
+                            user_id: msg.user_id.clone(),
+                            canvas_id: canvas_id.clone(),
+                        },
+                    ))
+            })
+            .collect();
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                futures_util::future::join_all(persisted)
+                    .into_actor(self)
+                    .map(move |results, canvasstore, ctx| {
+                        let mut removed = Vec::with_capacity(memberships.len());
+                        let mut failure = None;
+                        for (canvas_id, result) in memberships.into_iter().zip(results) {
+                            match result {
+                                Ok(Ok(_)) => (),
+                                Ok(Err(e)) => {
+                                    failure = Some(CanvasStoreError::persistence(e));
+                                    continue;
+                                }
+                                Err(e) => {
+                                    failure = Some(CanvasStoreError::persistence(e));
+                                    continue;
+                                }
+                            }
+                            if remove_deleted_grant(
+                                &mut canvasstore.deleted_canvases,
+                                &canvas_id,
+                                &msg.user_id,
+                            ) {
+                                removed.push(canvas_id);
+                                continue;
+                            }
+                            remove_grant(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.claims,
+                                &canvas_id,
+                                &msg.user_id,
+                            );
+                            canvasstore.check_member_quota(&canvas_id, ctx);
+                            if let Some(handle) = &canvasstore.canvas_server_handle {
+                                // sessions that outlive a stopped server are closed anyway
+                                let _ = handle.update_user_permissions(
+                                    canvas_id.clone(),
+                                    msg.user_id.clone(),
+                                    AccessLevel::None,
+                                    None,
+                                );
+                            }
+                            removed.push(canvas_id);
+                        }
+                        // the remaining memberships are removed by sending the message again
+                        if let Some(e) = failure {
+                            return Err(e);
+                        }
+                        canvasstore.visits.remove(&msg.user_id);
+                        canvasstore.preferences.remove(&msg.user_id);
+                        Ok(removed)
+                    }),
+            ),
+        )
+    }
+}
+
+/// Soft deletes a canvas, its users lose their access and connected sessions are closed
+/// The canvas and its eventlog are kept until the deletion grace period passed, see RestoreCanvasMessage
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct DeleteCanvasMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+}
+
+impl Handler<DeleteCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: DeleteCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<DeleteCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let timestamp = self.stamps.stamp_ms();
+        // the grace runs from the clock, the stamps of a burst may run a few milliseconds ahead of it
+        let deleted_at = self.clock.now_ms();
+        let event = CanvasStoreEvents::CanvasSoftDeleted {
+            timestamp,
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            soft_delete_canvas(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.deleted_canvases,
+                                &mut canvasstore.claims,
+                                &mut canvasstore.tag_index,
+                                &mut canvasstore.name_index,
+                                &msg.canvas_id,
+                                deleted_at,
+                            );
+                            canvasstore.replay_cache.evict(&msg.canvas_id);
+                            if let Some(handle) = &canvasstore.canvas_server_handle {
+                                handle.close_canvas(msg.canvas_id);
+                            }
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Restores a soft deleted canvas within the deletion grace period, only its owner or an admin may restore it
+/// Members get their access back, access that expired in the meantime stays expired
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct RestoreCanvasMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    /// admins restore canvases on behalf of their owner
+    pub admin: bool,
+}
+
+impl Handler<RestoreCanvasMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: RestoreCanvasMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RestoreCanvasMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let now = self.clock.now_ms();
+        let check = match self.deleted_canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) if !msg.admin && canvas.owner_id != msg.initiator_id => Err(
+                CanvasStoreError::AccessDenied(MessageKey::CanvasRestoreDenied),
+            ),
+            // waiting for the next purge sweep
+            Some(canvas) if self.purge_at(canvas.deleted_at.unwrap_or_default()) <= now => Err(
+                CanvasStoreError::AccessDenied(MessageKey::CanvasRestoreExpired),
+            ),
+            Some(_) => Ok(()),
+        };
+        if let Err(e) = check {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let event = CanvasStoreEvents::CanvasRestored {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            initiator_id: msg.initiator_id,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            restore_canvas(
+                                &mut canvasstore.canvases,
+                                &mut canvasstore.deleted_canvases,
+                                &mut canvasstore.claims,
+                                &mut canvasstore.tag_index,
+                                &mut canvasstore.name_index,
+                                &msg.canvas_id,
+                                now,
+                            );
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Purges the soft deleted canvases whose grace period passed at now, a CanvasDeleted event is persisted per canvas
+/// The eventlog of a purged canvas is removed, sent periodically by the CanvasStore itself
+/// Resolves to the number of purged canvases
+#[derive(Message)]
+#[rtype(result = "Result<usize, CanvasStoreError>")]
+pub struct PurgeDeletedCanvasesMessage {
+    pub now: u64,
+}
+
+impl Handler<PurgeDeletedCanvasesMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<usize, CanvasStoreError>>;
+
+    // atomic, an overlapping sweep would persist the same purge twice
+    fn handle(&mut self, msg: PurgeDeletedCanvasesMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<PurgeDeletedCanvasesMessage>();
+        // retried on the next interval
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let expired: Vec<CanvasId> = self
+            .deleted_canvases
+            .values()
+            .filter(|canvas| self.purge_at(canvas.deleted_at.unwrap_or_default()) <= msg.now)
+            .map(|canvas| canvas.id.clone())
+            .collect();
+
+        let persisted = expired.iter().map(|canvas_id| {
+            self.event_persistence_recipient
+                .send(persistence::PersistEventMessage(
+                    CanvasStoreEvents::CanvasDeleted {
+                        timestamp: self.stamps.stamp_ms(),
+                        canvas_id: canvas_id.clone(),
+                    },
+                ))
+        });
+
+        timed_atomic(timer, Box::pin(
+            futures_util::future::join_all(persisted)
+                .into_actor(self)
+                .map(move |results, canvasstore, _| {
+                    let mut purged = 0;
+                    for (canvas_id, result) in expired.into_iter().zip(results) {
+                        // failed purges are retried by the next sweep
+                        if !matches!(result, Ok(Ok(_))) {
+                            continue;
+                        }
+                        canvasstore.deleted_canvases.remove(&canvas_id);
+                        canvasstore.purged_canvases.insert(canvas_id.clone());
+                        canvasstore.member_quota_warnings.remove(&canvas_id);
+                        canvasstore.quota_warnings.remove(&canvas_id);
+                        canvasstore.digests.remove(&canvas_id);
+                        canvasstore.access_requests.remove(&canvas_id);
+                        canvasstore.replay_cache.evict(&canvas_id);
+                        remove_canvas_entries(&mut canvasstore.visits, &canvas_id);
+                        remove_canvas_entries(&mut canvasstore.preferences, &canvas_id);
+
+                        // the purge is persisted, a leftover eventlog is only unreachable data
+                        let log_path = canvas_log_path(&canvas_id);
+                        if let Err(e) = persistence::remove_event_log(&log_path) {
+                            println!("WARNING: failed to remove eventlog {log_path} of purged canvas: {e}");
+                        }
+                        purged += 1;
+                    }
+                    Ok(purged)
+                }),
+        ))
+    }
+}
+
+/// What the store knows about every canvas id it ever saw, see orphans.rs
+#[derive(Message)]
+#[rtype(result = "HashMap<CanvasId, CanvasRecord>")]
+pub struct GetCanvasRecordsMessage;
+
+impl Handler<GetCanvasRecordsMessage> for CanvasStore {
+    type Result = MessageResult<GetCanvasRecordsMessage>;
+
+    fn handle(&mut self, _: GetCanvasRecordsMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetCanvasRecordsMessage>();
+        let purged = self
+            .purged_canvases
+            .iter()
+            .map(|canvas_id| (canvas_id.clone(), CanvasRecord::Purged));
+        let deleted = self.deleted_canvases.values().map(|canvas| {
+            let purge_at = self.purge_at(canvas.deleted_at.unwrap_or_default());
+            (canvas.id.clone(), CanvasRecord::SoftDeleted { purge_at })
+        });
+        let active = self
+            .canvases
+            .keys()
+            .map(|canvas_id| (canvas_id.clone(), CanvasRecord::Active));
+        // later entries win, a live canvas is never reported as purged
+        MessageResult(purged.chain(deleted).chain(active).collect())
+    }
+}
+
+/// Persists a quota warning, sent by the canvas server and the CanvasStore itself
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct RecordQuotaWarningMessage {
+    pub canvas_id: CanvasId,
+    pub usage: QuotaUsage,
+}
+
+impl Handler<RecordQuotaWarningMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: RecordQuotaWarningMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RecordQuotaWarningMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let timestamp = self.stamps.stamp_ms();
+        let event = CanvasStoreEvents::QuotaWarningIssued {
+            timestamp,
+            canvas_id: msg.canvas_id.clone(),
+            usage: msg.usage.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            canvasstore
+                                .quota_warnings
+                                .entry(msg.canvas_id)
+                                .or_default()
+                                .push(QuotaWarning {
+                                    timestamp,
+                                    usage: msg.usage,
+                                });
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Member usage and issued warnings of a canvas
+pub struct CanvasQuotaStatus {
+    pub members: QuotaUsage,
+    pub warnings: Vec<QuotaWarning>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<CanvasQuotaStatus>")]
+pub struct GetCanvasQuotaMessage {
+    pub canvas_id: CanvasId,
+}
+
+impl Handler<GetCanvasQuotaMessage> for CanvasStore {
+    type Result = Option<CanvasQuotaStatus>;
+
+    fn handle(&mut self, msg: GetCanvasQuotaMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetCanvasQuotaMessage>();
+        let canvas = self.canvases.get(&msg.canvas_id)?;
+        let members = canvas.member_count(self.clock.now_ms()) as u64;
+        Some(CanvasQuotaStatus {
+            members: self.quota_limits.usage(QuotaKind::Members, members),
+            warnings: self
+                .quota_warnings
+                .get(&msg.canvas_id)
+                .cloned()
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Canvas whose owner is due an activity digest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DueDigest {
+    pub canvas_id: CanvasId,
+    pub canvas_name: String,
+    pub owner_id: UserId,
+    /// last seq of the canvas eventlog covered by the previous digest, 0 for the first digest
+    pub after_seq: u64,
+}
+
+/// Canvases whose digest frequency passed since their last digest, sent by the DigestScheduler
+#[derive(Message)]
+#[rtype(result = "Vec<DueDigest>")]
+pub struct GetDueDigestsMessage {
+    pub now: u64,
+}
+
+impl Handler<GetDueDigestsMessage> for CanvasStore {
+    type Result = Vec<DueDigest>;
+
+    fn handle(&mut self, msg: GetDueDigestsMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetDueDigestsMessage>();
+        let mut due: Vec<DueDigest> = self
+            .canvases
+            .values()
+            .filter(|canvas| {
+                DigestMark::is_due(
+                    self.digests.get(&canvas.id),
+                    canvas.settings.digest,
+                    msg.now,
+                )
+            })
+            .map(|canvas| DueDigest {
+                canvas_id: canvas.id.clone(),
+                canvas_name: canvas.name.clone(),
+                owner_id: canvas.owner_id.clone(),
+                after_seq: self.digests.get(&canvas.id).map_or(0, |mark| mark.seq),
+            })
+            .collect();
+        due.sort_by(|a, b| a.canvas_id.cmp(&b.canvas_id));
+        due
+    }
+}
+
+/// Access request with what the handler needs to notify about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRequestOutcome {
+    pub request: AccessRequest,
+    /// false if the user had a request pending already, nothing was persisted then
+    pub created: bool,
+    pub canvas_name: String,
+    /// owner and moderators, they answer requests
+    pub managers: Vec<UserId>,
+}
+
+impl Canvas {
+    /// Owner and moderators at the time, sorted
+    fn managers(&self, now: u64) -> Vec<UserId> {
+        let mut managers: Vec<UserId> = self
+            .users
+            .keys()
+            .filter(|user_id| {
+                matches!(
+                    self.access_level(user_id, now),
+                    AccessLevel::Owner | AccessLevel::Moderate
+                )
+            })
+            .cloned()
+            .collect();
+        managers.sort();
+        managers
+    }
+}
+
+/// Records a request for access by a user without access, deduplicated while one is pending
+#[derive(Message)]
+#[rtype(result = "Result<AccessRequestOutcome, CanvasStoreError>")]
+pub struct RequestCanvasAccessMessage {
+    pub canvas_id: CanvasId,
+    pub user_id: UserId,
+    /// sanitized, see access_requests::sanitize_message
+    pub message: Option<String>,
+}
+
+impl Handler<RequestCanvasAccessMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<AccessRequestOutcome, CanvasStoreError>>;
+
+    // atomic, requests sent twice at once would otherwise both be persisted
+    fn handle(&mut self, msg: RequestCanvasAccessMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RequestCanvasAccessMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let now = self.clock.now_ms();
+        let Some(canvas) = self.canvases.get(&msg.canvas_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        };
+        if canvas.access_level(&msg.user_id, now) != AccessLevel::None {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::AlreadyMember) }.into_actor(self)),
+            );
+        }
+
+        let canvas_name = canvas.name.clone();
+        let managers = canvas.managers(now);
+        let pending = self
+            .access_requests
+            .get(&msg.canvas_id)
+            .and_then(|requests| requests.pending(&msg.user_id));
+        if let Some(request) = pending {
+            let outcome = AccessRequestOutcome {
+                request: request.clone(),
+                created: false,
+                canvas_name,
+                managers,
+            };
+            return timed_atomic(timer, Box::pin(async move { Ok(outcome) }.into_actor(self)));
+        }
+
+        let timestamp = self.stamps.stamp_ms();
+        let event = CanvasStoreEvents::AccessRequested {
+            timestamp,
+            canvas_id: msg.canvas_id.clone(),
+            user_id: msg.user_id.clone(),
+            message: msg.message.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            let requests = canvasstore
+                                .access_requests
+                                .entry(msg.canvas_id)
+                                .or_default();
+                            requests.request(&msg.user_id, msg.message, timestamp);
+                            Ok(AccessRequestOutcome {
+                                // unwrap: just added
+                                request: requests.pending(&msg.user_id).unwrap().clone(),
+                                created: true,
+                                canvas_name,
+                                managers,
+                            })
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Answers the pending request of user_id, owners and moderators approve or deny, the requester cancels
+/// An approval is checked and persisted like an AddUserToCanvasMessage, the grant and the resolution are
+/// persisted together or not at all
+#[derive(Message)]
+#[rtype(result = "Result<AccessRequestOutcome, CanvasStoreError>")]
+pub struct ResolveAccessRequestMessage {
+    pub canvas_id: CanvasId,
+    pub initiator_id: UserId,
+    pub user_id: UserId,
+    pub resolution: AccessRequestResolution,
+}
+
+impl Handler<ResolveAccessRequestMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<AccessRequestOutcome, CanvasStoreError>>;
+
+    fn handle(&mut self, msg: ResolveAccessRequestMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<ResolveAccessRequestMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let now = self.clock.now_ms();
+        let check = match self.canvases.get(&msg.canvas_id) {
+            None => Err(CanvasStoreError::CanvasNotFound),
+            Some(canvas) => {
+                let initiator_access_level = canvas.access_level(&msg.initiator_id, now);
+                let pending = self
+                    .access_requests
+                    .get(&msg.canvas_id)
+                    .is_some_and(|requests| requests.pending(&msg.user_id).is_some());
+                match &msg.resolution {
+                    AccessRequestResolution::Cancelled if msg.initiator_id != msg.user_id => Err(
+                        CanvasStoreError::AccessDenied(MessageKey::AccessRequestCancelDenied),
+                    ),
+                    AccessRequestResolution::Cancelled => Ok(()),
+                    _ if !matches!(
+                        initiator_access_level,
+                        AccessLevel::Owner | AccessLevel::Moderate
+                    ) =>
+                    {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::AccessRequestResolveDenied,
+                        ))
+                    }
+                    AccessRequestResolution::Approved(access_level) => self
+                        .validate_permission_change(
+                            &initiator_access_level,
+                            &canvas.access_level(&msg.user_id, now),
+                            access_level,
+                        ),
+                    AccessRequestResolution::Denied => Ok(()),
+                }
+                .and_then(|_| match pending {
+                    true => Ok((canvas.name.clone(), canvas.managers(now))),
+                    false => Err(CanvasStoreError::AccessRequestNotFound),
+                })
+            }
+        };
+        let (canvas_name, managers) = match check {
+            Ok(checked) => checked,
+            Err(e) => return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self))),
+        };
+
+        // an approval grants the access first, a resolution that fails to persist reverts the grant
+        let grant = match &msg.resolution {
+            AccessRequestResolution::Approved(access_level) => {
+                Some(CanvasStoreEvents::UserCanvasAdded {
+                    timestamp: self.stamps.stamp_ms(),
+                    user_id: msg.user_id.clone(),
+                    initiator_user_id: msg.initiator_id.clone(),
+                    canvas_id: msg.canvas_id.clone(),
+                    access_level: access_level.clone(),
+                    expires_at: None,
+                })
+            }
+            _ => None,
+        };
+        let timestamp = self.stamps.stamp_ms();
+        let event = CanvasStoreEvents::AccessRequestResolved {
+            timestamp,
+            canvas_id: msg.canvas_id.clone(),
+            user_id: msg.user_id.clone(),
+            initiator_id: msg.initiator_id.clone(),
+            status: msg.resolution.status(),
+            access_level: msg.resolution.access_level(),
+        };
+        // persisted last if at all, stamped last like every event after the grant
+        let revert = grant.is_some().then(|| {
+            let canvas = &self.canvases[&msg.canvas_id];
+            match canvas.users.get(&msg.user_id) {
+                Some(previous) => CanvasStoreEvents::UserCanvasAdded {
+                    timestamp: self.stamps.stamp_ms(),
+                    user_id: msg.user_id.clone(),
+                    initiator_user_id: msg.initiator_id.clone(),
+                    canvas_id: msg.canvas_id.clone(),
+                    access_level: previous.clone(),
+                    expires_at: canvas.expirations.get(&msg.user_id).copied(),
+                },
+                None => CanvasStoreEvents::UserCanvasRemoved {
+                    timestamp: self.stamps.stamp_ms(),
+                    user_id: msg.user_id.clone(),
+                    canvas_id: msg.canvas_id.clone(),
+                },
+            }
+        });
+
+        let persistence = self.event_persistence_recipient.clone();
+        let (canvas_id, user_id) = (msg.canvas_id.clone(), msg.user_id.clone());
+        let persist_all = async move {
+            if let Some(grant) = grant {
+                persistence
+                    .send(PersistEventMessage(grant))
+                    .await
+                    .map_err(CanvasStoreError::persistence)?
+                    .map_err(CanvasStoreError::persistence)?;
+            }
+            let resolved = match persistence.send(PersistEventMessage(event)).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                Err(e) => Err(CanvasStoreError::persistence(e)),
+            };
+            if let (Err(_), Some(revert)) = (&resolved, revert) {
+                if !matches!(
+                    persistence.send(PersistEventMessage(revert)).await,
+                    Ok(Ok(()))
+                ) {
+                    println!(
+                        "WARNING: grant of the access request of {user_id} on {canvas_id} could not be reverted in the eventlog, replay restores it"
+                    );
+                }
+            }
+            resolved
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                persist_all
+                    .into_actor(self)
+                    .map(move |result, canvasstore, ctx| {
+                        result?;
+                        if let AccessRequestResolution::Approved(access_level) = &msg.resolution {
+                            canvasstore.apply_grant(
+                                &msg.canvas_id,
+                                &msg.user_id,
+                                access_level.clone(),
+                                None,
+                                ctx,
+                            );
+                        }
+                        canvasstore
+                            .access_requests
+                            .get_mut(&msg.canvas_id)
+                            .and_then(|requests| {
+                                requests.resolve(
+                                    &msg.user_id,
+                                    &msg.resolution,
+                                    &msg.initiator_id,
+                                    timestamp,
+                                )
+                            })
+                            .map(|request| AccessRequestOutcome {
+                                request,
+                                created: false,
+                                canvas_name,
+                                managers,
+                            })
+                            .ok_or(CanvasStoreError::AccessRequestNotFound)
+                    }),
+            ),
+        )
+    }
+}
+
+/// Persists that the owner was sent a digest up to seq, the next digest starts after it
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct RecordCanvasDigestMessage {
+    pub canvas_id: CanvasId,
+    pub seq: u64,
+    /// time of the digest run, the next digest is due a period later
+    pub now: u64,
+}
+
+impl Handler<RecordCanvasDigestMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: RecordCanvasDigestMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RecordCanvasDigestMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let event = CanvasStoreEvents::CanvasDigested {
+            timestamp: self.stamps.stamp_ms(),
+            canvas_id: msg.canvas_id.clone(),
+            seq: msg.seq,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            canvasstore.digests.insert(
+                                msg.canvas_id,
+                                DigestMark {
+                                    seq: msg.seq,
+                                    // the period runs from the clock, the stamps of a burst may run a few milliseconds ahead of it
+                                    timestamp: msg.now,
+                                },
+                            );
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Records that the user opened the canvas, resolves to true if the visit was persisted
+/// Visits within CANVAS_VISIT_DEBOUNCE of the last persisted visit are dropped to keep the eventlog small
+#[derive(Message)]
+#[rtype(result = "Result<bool, CanvasStoreError>")]
+pub struct RecordCanvasVisitMessage {
+    pub user_id: UserId,
+    pub canvas_id: CanvasId,
+    pub timestamp: u64,
+}
+
+impl Handler<RecordCanvasVisitMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<bool, CanvasStoreError>>;
+
+    // atomic, concurrent page loads would otherwise persist the same visit twice
+    fn handle(&mut self, msg: RecordCanvasVisitMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RecordCanvasVisitMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.canvases.contains_key(&msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self)),
+            );
+        }
+
+        let last_visit = self
+            .visits
+            .get(&msg.user_id)
+            .and_then(|visits| visits.get(&msg.canvas_id));
+        if last_visit.is_some_and(|last_visit| {
+            msg.timestamp.saturating_sub(*last_visit) < CANVAS_VISIT_DEBOUNCE.as_millis() as u64
+        }) {
+            return timed_atomic(timer, Box::pin(async move { Ok(false) }.into_actor(self)));
+        }
+
+        let event = CanvasStoreEvents::CanvasVisited {
+            timestamp: msg.timestamp,
+            user_id: msg.user_id.clone(),
+            canvas_id: msg.canvas_id.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, canvasstore, _| match result {
+                        Ok(Ok(_)) => {
+                            canvasstore
+                                .visits
+                                .entry(msg.user_id)
+                                .or_default()
+                                .insert(msg.canvas_id, msg.timestamp);
+                            Ok(true)
+                        }
+                        Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                        Err(e) => Err(CanvasStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Placement of a canvas on the home page of a user, kept across devices
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub struct CanvasPreference {
+    /// listed in the pinned group on top of the home page
+    #[serde(default)]
+    pub pinned: bool,
+    /// position within its groups, canvases with a hint come first, lowest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_hint: Option<u32>,
+}
+
+impl CanvasStore {
+    /// Whether the user holds an unexpired claim on the canvas, only such canvases can be pinned or ordered
+    fn has_claim(&self, user_id: &UserId, canvas_id: &CanvasId) -> bool {
+        let now = self.clock.now_ms();
+        self.claims.get(user_id).is_some_and(|claims| {
+            claims
+                .iter()
+                .any(|claim| &claim.c == canvas_id && !claim.is_expired(now))
+        })
+    }
+
+    /// Persists the changed preferences of the user and applies them afterwards
+    fn persist_preferences(
+        &mut self,
+        user_id: UserId,
+        preferences: BTreeMap<CanvasId, CanvasPreference>,
+    ) -> ResponseActFuture<Self, Result<(), CanvasStoreError>> {
+        let event = CanvasStoreEvents::UserCanvasPreferenceChanged {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: user_id.clone(),
+            preferences: preferences.clone(),
+        };
+
+        Box::pin(
+            self.event_persistence_recipient
+                .send(persistence::PersistEventMessage(event))
+                .into_actor(self)
+                .map(move |result, canvasstore, _| match result {
+                    Ok(Ok(_)) => {
+                        for (canvas_id, preference) in preferences {
+                            set_preference(
+                                &mut canvasstore.preferences,
+                                &user_id,
+                                canvas_id,
+                                preference,
+                            );
+                        }
+                        Ok(())
+                    }
+                    Ok(Err(e)) => Err(CanvasStoreError::persistence(e)),
+                    Err(e) => Err(CanvasStoreError::persistence(e)),
+                }),
+        )
+    }
+}
+
+/// Pins or places a canvas on the home page of the user, fields left None are kept
+/// Only canvases the user holds a claim on can be pinned
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct SetCanvasPreferenceMessage {
+    pub user_id: UserId,
+    pub canvas_id: CanvasId,
+    pub pinned: Option<bool>,
+    pub sort_hint: Option<u32>,
+}
+
+impl Handler<SetCanvasPreferenceMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: SetCanvasPreferenceMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<SetCanvasPreferenceMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+        if !self.has_claim(&msg.user_id, &msg.canvas_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasPreferenceDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+
+        let current = self
+            .preferences
+            .get(&msg.user_id)
+            .and_then(|preferences| preferences.get(&msg.canvas_id))
+            .copied()
+            .unwrap_or_default();
+        let preference = CanvasPreference {
+            pinned: msg.pinned.unwrap_or(current.pinned),
+            sort_hint: msg.sort_hint.or(current.sort_hint),
+        };
+        timed_atomic(
+            timer,
+            self.persist_preferences(msg.user_id, BTreeMap::from([(msg.canvas_id, preference)])),
+        )
+    }
+}
+
+/// Sets the sort hints of several canvases at once, pins are kept
+/// Rejected as a whole if the user holds no claim on one of the canvases
+#[derive(Message)]
+#[rtype(result = "Result<(), CanvasStoreError>")]
+pub struct SetCanvasOrderMessage {
+    pub user_id: UserId,
+    pub order: Vec<(CanvasId, u32)>,
+}
+
+impl Handler<SetCanvasOrderMessage> for CanvasStore {
+    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+
+    fn handle(&mut self, msg: SetCanvasOrderMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<SetCanvasOrderMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+        if !msg
+            .order
+            .iter()
+            .all(|(canvas_id, _)| self.has_claim(&msg.user_id, canvas_id))
+        {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(CanvasStoreError::AccessDenied(
+                            MessageKey::CanvasPreferenceDenied,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
+        }
+
+        let user_preferences = self.preferences.get(&msg.user_id);
+        let preferences = msg
+            .order
+            .into_iter()
+            .map(|(canvas_id, sort_hint)| {
+                let current = user_preferences
+                    .and_then(|preferences| preferences.get(&canvas_id))
+                    .copied()
+                    .unwrap_or_default();
+                let preference = CanvasPreference {
+                    sort_hint: Some(sort_hint),
+                    ..current
+                };
+                (canvas_id, preference)
+            })
+            .collect();
+        timed_atomic(timer, self.persist_preferences(msg.user_id, preferences))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasOrigin {
+    Owned,
+    Shared,
+}
+
+/// Canvas as listed on the home page
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct CanvasSummary {
+    pub id: CanvasId,
+    pub name: String,
+    pub access_level: AccessLevel,
+    pub origin: CanvasOrigin,
+    /// unix timestamp in milliseconds
+    pub last_visited_at: Option<u64>,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_hint: Option<u32>,
+}
+
+/// Soft deleted canvas as listed on the home page of its owner
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct DeletedCanvasSummary {
+    pub id: CanvasId,
+    pub name: String,
+    /// unix timestamp in milliseconds
+    pub deleted_at: u64,
+    /// restorable until, unix timestamp in milliseconds
+    pub purge_at: u64,
+}
+
+/// Canvases of a user, pinned and recent repeat canvases of the other groups
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, ToSchema)]
+pub struct UserCanvases {
+    /// canvases the user pinned, in the order of their sort hints
+    #[serde(default)]
+    pub pinned: Vec<CanvasSummary>,
+    pub owned: Vec<CanvasSummary>,
+    pub shared: Vec<CanvasSummary>,
+    pub recent: Vec<CanvasSummary>,
+    /// owned canvases that can still be restored, most recently deleted first
+    #[serde(default)]
+    pub deleted: Vec<DeletedCanvasSummary>,
+}
+
+impl UserCanvases {
+    /// Groups the claims of a user, the claims of the JWT can be used if the store is not reachable
+    /// Groups are sorted by sort hint, then by last visit, canvases never visited follow by name
+    /// Recent is sorted by last visit only, preferences of canvases without a claim are ignored
+    /// Tags are taken from canvases, summaries of unknown canvases have no tags
+    pub fn group(
+        claims: Vec<CanvasClaim>,
+        visits: Option<&HashMap<CanvasId, u64>>,
+        preferences: Option<&HashMap<CanvasId, CanvasPreference>>,
+        canvases: Option<&HashMap<CanvasId, Canvas>>,
+    ) -> Self {
+        let mut summaries: Vec<CanvasSummary> = claims
+            .into_iter()
+            .map(|claim| {
+                let preference = preferences
+                    .and_then(|preferences| preferences.get(&claim.c))
+                    .copied()
+                    .unwrap_or_default();
+                CanvasSummary {
+                    last_visited_at: visits.and_then(|visits| visits.get(&claim.c).copied()),
+                    tags: canvases
+                        .and_then(|canvases| canvases.get(&claim.c))
+                        .map(|canvas| canvas.tags.clone())
+                        .unwrap_or_default(),
+                    origin: match claim.r {
+                        AccessLevel::Owner => CanvasOrigin::Owned,
+                        _ => CanvasOrigin::Shared,
+                    },
+                    id: claim.c,
+                    name: claim.n,
+                    access_level: claim.r,
+                    pinned: preference.pinned,
+                    sort_hint: preference.sort_hint,
+                }
+            })
+            .collect();
+        let by_visit = |a: &CanvasSummary, b: &CanvasSummary| {
+            b.last_visited_at
+                .cmp(&a.last_visited_at)
+                .then_with(|| a.name.cmp(&b.name))
+        };
+
+        summaries.sort_by(by_visit);
+        let recent = summaries
+            .iter()
+            .filter(|summary| summary.last_visited_at.is_some())
+            .take(RECENT_CANVAS_LIMIT)
+            .cloned()
+            .collect();
+
+        // canvases without hint follow the ones with a hint
+        summaries.sort_by(|a, b| {
+            a.sort_hint
+                .is_none()
+                .cmp(&b.sort_hint.is_none())
+                .then_with(|| a.sort_hint.cmp(&b.sort_hint))
+                .then_with(|| by_visit(a, b))
+        });
+        let pinned = summaries
+            .iter()
+            .filter(|summary| summary.pinned)
+            .cloned()
+            .collect();
+        let (owned, shared) = summaries
+            .into_iter()
+            .partition(|summary| summary.origin == CanvasOrigin::Owned);
+
+        Self {
+            pinned,
+            owned,
+            shared,
+            recent,
+            deleted: Vec::new(),
+        }
+    }
+}
+
+/// Canvases of a user grouped for the home page, expired claims are left out
+/// tag limits the canvases to those carrying the normalized tag
+#[derive(Message)]
+#[rtype(result = "UserCanvases")]
+pub struct GetUserCanvasesMessage {
+    pub user_id: UserId,
+    pub tag: Option<String>,
+}
+
+impl Handler<GetUserCanvasesMessage> for CanvasStore {
+    type Result = MessageResult<GetUserCanvasesMessage>;
+
+    fn handle(&mut self, msg: GetUserCanvasesMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUserCanvasesMessage>();
+        let now = self.clock.now_ms();
+        let claims = self
+            .claims
+            .get(&msg.user_id)
+            .map(|claims| {
+                claims
+                    .iter()
+                    .filter(|claim| !claim.is_expired(now))
+                    .filter(|claim| {
+                        msg.tag.as_ref().is_none_or(|tag| {
+                            self.tag_index
+                                .get(tag)
+                                .is_some_and(|canvas_ids| canvas_ids.contains(&claim.c))
+                        })
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut user_canvases = UserCanvases::group(
+            claims,
+            self.visits.get(&msg.user_id),
+            self.preferences.get(&msg.user_id),
+            Some(&self.canvases),
+        );
+
+        // deleted canvases left the tag index, their tags are matched directly
+        user_canvases.deleted = self
+            .deleted_canvases
+            .values()
+            .filter(|canvas| canvas.owner_id == msg.user_id)
+            .filter(|canvas| msg.tag.as_ref().is_none_or(|tag| canvas.tags.contains(tag)))
+            .filter_map(|canvas| {
+                let deleted_at = canvas.deleted_at?;
+                let purge_at = self.purge_at(deleted_at);
+                (purge_at > now).then(|| DeletedCanvasSummary {
+                    id: canvas.id.clone(),
+                    name: canvas.name.clone(),
+                    deleted_at,
+                    purge_at,
+                })
+            })
+            .collect();
+        user_canvases.deleted.sort_by(|a, b| {
+            b.deleted_at
+                .cmp(&a.deleted_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+
+        MessageResult(user_canvases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use persistence::EventLogPersistenceJson;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::{self, Clock, ManualClock};
+
+    #[actix_web::test]
+    async fn test_access_level_validation() {
+        // Canvas Store Setup
+        // Same constraints as for the user store
+        let canvas_event_log = EventLogPersistenceJson::new("test.jsonl")
+            .expect("Failed to create or load canvas event log");
+        let (_, canvas_event_log) = canvas_event_log
+            .into_actor::<CanvasStoreEvents>()
+            .expect("Failed to read canvas event log");
+        let canvas_event_persistor_recipient = canvas_event_log.start().recipient();
+
+        // NOTE: not used right now in test, can be used later
+        let initial_events = vec![
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                state: CanvasState::Active,
+                name: "Canvas".to_string(),
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "moderator".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Moderate,
+                expires_at: None,
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "reader".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Read,
+                expires_at: None,
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "writer".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Write,
+                expires_at: None,
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "voice".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Voice,
+                expires_at: None,
+            },
+        ];
+
+        let canvas_store = CanvasStore::new(
+            canvas_event_persistor_recipient,
+            initial_events,
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0;
+
+        // note this does not use messages, only checks the validation function
+
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Read,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Read,
+                &AccessLevel::Owner,
+                &AccessLevel::Moderate
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::Owner,
+                &AccessLevel::Write
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::None,
+                &AccessLevel::Owner
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::None,
+                &AccessLevel::Moderate
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::Voice,
+                &AccessLevel::Moderate
+            )
+            .is_err());
+
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Write,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Voice,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_err());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::None,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_err());
+
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Owner,
+                &AccessLevel::Moderate,
+                &AccessLevel::Write
+            )
+            .is_ok());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Owner,
+                &AccessLevel::None,
+                &AccessLevel::Moderate
+            )
+            .is_ok());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::Write,
+                &AccessLevel::Read
+            )
+            .is_ok());
+        assert!(canvas_store
+            .validate_permission_change(
+                &AccessLevel::Moderate,
+                &AccessLevel::Voice,
+                &AccessLevel::None
+            )
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_state_updates_conflict() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path.to_str().unwrap())
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+
+        let initial_events = vec![
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                state: CanvasState::Active,
+                name: "Canvas".to_string(),
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "moderator".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Moderate,
+                expires_at: None,
+            },
+        ];
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            initial_events,
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .start();
+
+        // both moderators loaded the canvas at version 2
+        let update = |initiator_id: &str, action| UpdateCanvasStateMessage {
+            canvas_id: "canvas".to_string(),
+            initiator_id: initiator_id.to_string(),
+            action,
+            expected_version: 2,
+        };
+        let (first, second) = futures_util::future::join(
+            canvas_store.send(update("owner", CanvasStateAction::Moderate)),
+            canvas_store.send(update("moderator", CanvasStateAction::Activate)),
+        )
+        .await;
+
+        assert!(matches!(first.unwrap(), Ok((3, CanvasState::Moderated))));
+        assert!(matches!(
+            second.unwrap(),
+            Err(CanvasStoreError::VersionConflict {
+                current_version: 3,
+                state: CanvasState::Moderated
+            })
+        ));
+
+        // moderators can't archive, the version is only checked for permitted transitions
+        assert!(matches!(
+            canvas_store
+                .send(update("moderator", CanvasStateAction::Archive))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasArchiveDenied
+            ))
+        ));
+
+        let canvas = canvas_store
+            .send(GetCanvasMessage {
+                canvas_id: "canvas".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(canvas.version, 3);
+        assert!(matches!(canvas.state, CanvasState::Moderated));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    /// Canvas with a writer whose access expired long ago
+    fn expired_grant_events() -> Vec<CanvasStoreEvents> {
+        vec![
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                state: CanvasState::Active,
+                name: "Canvas".to_string(),
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "workshop".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                access_level: AccessLevel::Write,
+                expires_at: Some(1_000),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_replay_drops_expired_claims() {
+        let (state, issues) = replay_events(expired_grant_events(), 1_000);
+        assert!(issues.is_empty());
+        assert!(state.claims.get("workshop").is_none_or(Vec::is_empty));
+
+        // grant is kept until the sweep persists its removal
+        let canvas = &state.canvases["canvas"];
+        assert_eq!(
+            canvas.access_level(&"workshop".to_string(), 999),
+            AccessLevel::Write
+        );
+        assert_eq!(
+            canvas.access_level(&"workshop".to_string(), 1_000),
+            AccessLevel::None
+        );
+
+        let mut events = expired_grant_events();
+        events.push(CanvasStoreEvents::UserCanvasRemoved {
+            timestamp: 1_000,
+            user_id: "workshop".to_string(),
+            canvas_id: "canvas".to_string(),
+        });
+        let (state, _) = replay_events(events, 1_000);
+        assert!(!state.canvases["canvas"].users.contains_key("workshop"));
+        assert!(state.canvases["canvas"].expirations.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_sweep_persists_removal_once() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            expired_grant_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .start();
+
+        let (first, second) = futures_util::future::join(
+            canvas_store.send(SweepExpiredGrantsMessage { now: 2_000 }),
+            canvas_store.send(SweepExpiredGrantsMessage { now: 2_000 }),
+        )
+        .await;
+        assert!(matches!(first.unwrap(), Ok(1)));
+        assert!(matches!(second.unwrap(), Ok(0)));
+
+        let (persisted, _) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let removals = persisted
+            .iter()
+            .filter(|event| matches!(event, CanvasStoreEvents::UserCanvasRemoved { .. }))
+            .count();
+        assert_eq!(removals, 1);
+
+        let canvas = canvas_store
+            .send(GetCanvasMessage {
+                canvas_id: "canvas".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!canvas.users.contains_key("workshop"));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_purged_user_keeps_owned_canvases_only() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        // workshop owns a canvas and holds an expired grant next to a live one
+        let mut events = expired_grant_events();
+        events.extend([
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "other".to_string(),
+                state: CanvasState::Active,
+                name: "Other".to_string(),
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "workshop".to_string(),
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "other".to_string(),
+                access_level: AccessLevel::Read,
+                expires_at: None,
+            },
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "workshop".to_string(),
+                canvas_id: "own".to_string(),
+                state: CanvasState::Active,
+                name: "Own".to_string(),
+            },
+        ]);
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            events,
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .start();
+
+        let removed = canvas_store
+            .send(PurgeUserMessage {
+                user_id: "workshop".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed, vec!["canvas".to_string(), "other".to_string()]);
+
+        let claims = canvas_store
+            .send(GetUserClaimsMessage {
+                user_id: "workshop".to_string(),
+                limit: None,
+                canvas_id: None,
+            })
+            .await
+            .unwrap();
+        let claimed: Vec<&str> = claims.iter().map(|claim| claim.c.as_str()).collect();
+        assert_eq!(claimed, ["own"]);
+
+        let (persisted, _) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let removals = persisted
+            .iter()
+            .filter(|event| matches!(event, CanvasStoreEvents::UserCanvasRemoved { .. }))
+            .count();
+        assert_eq!(removals, 2);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_purged_user_is_removed_from_deleted_canvases() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let deleted_canvas_events = || {
+            let mut events = shared_canvas_events();
+            events.push(CanvasStoreEvents::CanvasSoftDeleted {
+                timestamp: 500,
+                canvas_id: "board".to_string(),
+                initiator_id: "bob".to_string(),
+            });
+            events
+        };
+        let canvas_store = start_store_with_clock(
+            log_path,
+            deleted_canvas_events(),
+            Arc::new(ManualClock::new(1_000)),
+        );
+
+        let removed = canvas_store
+            .send(PurgeUserMessage {
+                user_id: "alice".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed, vec!["board".to_string()]);
+
+        // restoring the canvas doesn't bring the deleted user back
+        canvas_store
+            .send(RestoreCanvasMessage {
+                canvas_id: "board".to_string(),
+                initiator_id: "bob".to_string(),
+                admin: false,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let access_level = canvas_store
+            .send(GetUserAccessLevelMessage {
+                user_id: "alice".to_string(),
+                canvas_id: "board".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(access_level, AccessLevel::None);
+
+        // the removal is replayed on the deleted canvas
+        let persisted: Vec<CanvasStoreEvents> = EventLogPersistenceJson::open(log_path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .take(1)
+            .collect();
+        assert!(matches!(
+            &persisted[..],
+            [CanvasStoreEvents::UserCanvasRemoved { user_id, canvas_id, .. }]
+                if user_id == "alice" && canvas_id == "board"
+        ));
+        let mut events = deleted_canvas_events();
+        events.extend(persisted);
+        let (state, issues) = replay_events(events, 1_000);
+        assert!(issues.is_empty());
+        assert!(!state.deleted_canvases["board"].users.contains_key("alice"));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_temporary_access_expires_with_clock() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let clock = Arc::new(ManualClock::new(500));
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            expired_grant_events(),
+            QuotaLimits::default(),
+            clock.clone(),
+        )
+        .0
+        .start();
+
+        let access_level = || GetUserAccessLevelMessage {
+            user_id: "workshop".to_string(),
+            canvas_id: "canvas".to_string(),
+        };
+        let claims = || GetUserClaimsMessage {
+            user_id: "workshop".to_string(),
+            limit: None,
+            canvas_id: None,
+        };
+        assert_eq!(
+            canvas_store.send(access_level()).await.unwrap(),
+            AccessLevel::Write
+        );
+        assert_eq!(canvas_store.send(claims()).await.unwrap().len(), 1);
+
+        // expired before the sweep removed the grant
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(
+            canvas_store.send(access_level()).await.unwrap(),
+            AccessLevel::None
+        );
+        assert!(canvas_store.send(claims()).await.unwrap().is_empty());
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    /// alice owns "sketch" and was added to "board" of bob
+    fn shared_canvas_events() -> Vec<CanvasStoreEvents> {
+        vec![
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "alice".to_string(),
+                canvas_id: "sketch".to_string(),
+                state: CanvasState::Active,
+                name: "Sketch".to_string(),
+            },
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "bob".to_string(),
+                canvas_id: "board".to_string(),
+                state: CanvasState::Active,
+                name: "Board".to_string(),
+            },
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "alice".to_string(),
+                initiator_user_id: "bob".to_string(),
+                canvas_id: "board".to_string(),
+                access_level: AccessLevel::Write,
+                expires_at: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_replay_skips_grant_on_unknown_canvas() {
+        let mut events = shared_canvas_events();
+        events.insert(
+            1,
+            CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: "bob".to_string(),
+                initiator_user_id: "alice".to_string(),
+                canvas_id: "missing".to_string(),
+                access_level: AccessLevel::Read,
+                expires_at: None,
+            },
+        );
+
+        let (state, issues) = replay_events(events, 0);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].event_index, Some(1));
+        assert_eq!(issues[0].kind, ReplayIssueKind::UnknownReference);
+        assert!(issues[0].skipped);
+        // events after the broken one are still applied
+        assert_eq!(state.canvases.len(), 2);
+        assert!(state.canvases["board"].users.contains_key("alice"));
+    }
+
+    fn start_store(log_path: &str, events: Vec<CanvasStoreEvents>) -> Addr<CanvasStore> {
+        start_store_with_clock(log_path, events, clock::system())
+    }
+
+    fn start_store_with_clock(
+        log_path: &str,
+        events: Vec<CanvasStoreEvents>,
+        clock: Arc<dyn Clock>,
+    ) -> Addr<CanvasStore> {
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            events,
+            QuotaLimits::default(),
+            clock,
+        )
+        .0
+        .start()
+    }
+
+    #[actix_web::test]
+    async fn test_canvas_visits_are_debounced() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+
+        let hour = CANVAS_VISIT_DEBOUNCE.as_millis() as u64;
+        let visit = |timestamp| RecordCanvasVisitMessage {
+            user_id: "alice".to_string(),
+            canvas_id: "sketch".to_string(),
+            timestamp,
+        };
+        let mut persisted = Vec::new();
+        for timestamp in [hour, hour + 60_000, 2 * hour - 1, 2 * hour] {
+            persisted.push(canvas_store.send(visit(timestamp)).await.unwrap().unwrap());
+        }
+        assert_eq!(persisted, vec![true, false, false, true]);
+
+        let unknown = canvas_store
+            .send(RecordCanvasVisitMessage {
+                user_id: "alice".to_string(),
+                canvas_id: "unknown".to_string(),
+                timestamp: hour,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(unknown, Err(CanvasStoreError::CanvasNotFound)));
+
+        let (events, _) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let visits: Vec<u64> = events
+            .iter()
+            .filter_map(|event| match event {
+                CanvasStoreEvents::CanvasVisited { timestamp, .. } => Some(*timestamp),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(visits, vec![hour, 2 * hour]);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_claim_limit_keeps_the_most_recently_visited_claims() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let mut events = shared_canvas_events();
+        for canvas_id in ["c1", "c2", "c3"] {
+            events.push(CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "alice".to_string(),
+                canvas_id: canvas_id.to_string(),
+                state: CanvasState::Active,
+                name: canvas_id.to_string(),
+            });
+        }
+        // granted sketch, board, c1, c2, c3, the visits make board and sketch the most recently used
+        for (timestamp, canvas_id) in [(1_000, "c1"), (2_000, "sketch"), (3_000, "board")] {
+            events.push(CanvasStoreEvents::CanvasVisited {
+                timestamp,
+                user_id: "alice".to_string(),
+                canvas_id: canvas_id.to_string(),
+            });
+        }
+        let canvas_store = start_store(log_path, events);
+        let claims = |limit| GetUserClaimsMessage {
+            user_id: "alice".to_string(),
+            limit: Some(limit),
+            canvas_id: None,
+        };
+        let canvas_ids = |claims: Vec<CanvasClaim>| -> Vec<String> {
+            claims.into_iter().map(|claim| claim.c).collect()
+        };
+
+        // kept claims stay in grant order
+        let kept = canvas_store.send(claims(2)).await.unwrap();
+        assert_eq!(canvas_ids(kept), vec!["sketch", "board"]);
+        let kept = canvas_store.send(claims(3)).await.unwrap();
+        assert_eq!(canvas_ids(kept), vec!["sketch", "board", "c1"]);
+        // unvisited claims follow, the most recently granted first
+        let kept = canvas_store.send(claims(4)).await.unwrap();
+        assert_eq!(canvas_ids(kept), vec!["sketch", "board", "c1", "c3"]);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_user_canvases_are_grouped_by_origin() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let user_canvases = || GetUserCanvasesMessage {
+            user_id: "alice".to_string(),
+            tag: None,
+        };
+
+        let canvases = canvas_store.send(user_canvases()).await.unwrap();
+        assert_eq!(canvases.owned.len(), 1);
+        assert_eq!(canvases.owned[0].id, "sketch");
+        assert_eq!(canvases.owned[0].origin, CanvasOrigin::Owned);
+        assert_eq!(canvases.shared.len(), 1);
+        assert_eq!(canvases.shared[0].id, "board");
+        assert_eq!(canvases.shared[0].access_level, AccessLevel::Write);
+        assert!(canvases.recent.is_empty());
+
+        canvas_store
+            .send(RecordCanvasVisitMessage {
+                user_id: "alice".to_string(),
+                canvas_id: "board".to_string(),
+                timestamp: 1_000,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let canvases = canvas_store.send(user_canvases()).await.unwrap();
+        assert_eq!(canvases.recent, canvases.shared);
+        assert_eq!(canvases.shared[0].last_visited_at, Some(1_000));
+        assert_eq!(canvases.owned[0].last_visited_at, None);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[test]
+    fn test_replay_restores_visits() {
+        let mut events = shared_canvas_events();
+        for (timestamp, canvas_id) in [(1_000, "sketch"), (2_000, "board"), (5_000, "sketch")] {
+            events.push(CanvasStoreEvents::CanvasVisited {
+                timestamp,
+                user_id: "alice".to_string(),
+                canvas_id: canvas_id.to_string(),
+            });
+        }
+
+        let (state, issues) = replay_events(events, 0);
+        assert!(issues.is_empty());
+        assert_eq!(state.visits["alice"]["sketch"], 5_000);
+        assert_eq!(state.visits["alice"]["board"], 2_000);
+
+        // most recent visit first, canvases without visit by name
+        let mut claims = state.claims.get("alice").unwrap().clone();
+        claims.push(CanvasClaim {
+            n: "Archive".to_string(),
+            c: "archive".to_string(),
+            r: AccessLevel::Read,
+            exp: None,
+        });
+        let canvases = UserCanvases::group(claims, state.visits.get("alice"), None, None);
+        let recent: Vec<&str> = canvases.recent.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(recent, vec!["sketch", "board"]);
+        let shared: Vec<&str> = canvases.shared.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(shared, vec!["board", "archive"]);
+    }
+
+    fn preference_changed(
+        canvas_id: &str,
+        pinned: bool,
+        sort_hint: Option<u32>,
+    ) -> CanvasStoreEvents {
+        CanvasStoreEvents::UserCanvasPreferenceChanged {
+            timestamp: 0,
+            user_id: "alice".to_string(),
+            preferences: BTreeMap::from([(
+                canvas_id.to_string(),
+                CanvasPreference { pinned, sort_hint },
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_replay_restores_preferences() {
+        let events = || {
+            let mut events = shared_canvas_events();
+            events.push(preference_changed("board", true, Some(2)));
+            events.push(preference_changed("sketch", true, None));
+            events.push(preference_changed("sketch", false, None));
+            events
+        };
+
+        let (state, issues) = replay_events(events(), 0);
+        assert!(issues.is_empty());
+        assert_eq!(
+            state.preferences["alice"],
+            HashMap::from([(
+                "board".to_string(),
+                CanvasPreference {
+                    pinned: true,
+                    sort_hint: Some(2)
+                }
+            )])
+        );
+
+        // alice loses access to board, its preference is dropped
+        let mut events = events();
+        events.push(CanvasStoreEvents::UserCanvasRemoved {
+            timestamp: 0,
+            user_id: "alice".to_string(),
+            canvas_id: "board".to_string(),
+        });
+        events.push(preference_changed("unknown", true, None));
+        let (state, issues) = replay_events(events, 0);
+        assert_eq!(issues.len(), 1);
+        assert!(!state.preferences.contains_key("alice"));
+    }
+
+    #[actix_web::test]
+    async fn test_preferences_order_the_canvas_list() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let mut events = shared_canvas_events();
+        for (canvas_id, name) in [("archive", "Archive"), ("notes", "Notes")] {
+            events.push(CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "alice".to_string(),
+                canvas_id: canvas_id.to_string(),
+                state: CanvasState::Active,
+                name: name.to_string(),
+            });
+        }
+        let canvas_store = start_store(log_path, events);
+        let pin = |canvas_id: &str, sort_hint| SetCanvasPreferenceMessage {
+            user_id: "alice".to_string(),
+            canvas_id: canvas_id.to_string(),
+            pinned: Some(true),
+            sort_hint,
+        };
+
+        canvas_store
+            .send(pin("notes", Some(1)))
+            .await
+            .unwrap()
+            .unwrap();
+        canvas_store
+            .send(pin("board", Some(0)))
+            .await
+            .unwrap()
+            .unwrap();
+        canvas_store
+            .send(SetCanvasOrderMessage {
+                user_id: "alice".to_string(),
+                order: vec![("sketch".to_string(), 5)],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        // bob's canvas list is not alice's to pin, nothing of the order is applied
+        let denied = canvas_store
+            .send(SetCanvasOrderMessage {
+                user_id: "bob".to_string(),
+                order: vec![("board".to_string(), 0), ("sketch".to_string(), 1)],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied,
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasPreferenceDenied
+            ))
+        ));
+        let denied = canvas_store.send(pin("unknown", None)).await.unwrap();
+        assert!(matches!(denied, Err(CanvasStoreError::AccessDenied(_))));
+
+        let canvases = canvas_store
+            .send(GetUserCanvasesMessage {
+                user_id: "alice".to_string(),
+                tag: None,
+            })
+            .await
+            .unwrap();
+        let ids = |summaries: &[CanvasSummary]| -> Vec<String> {
+            summaries.iter().map(|summary| summary.id.clone()).collect()
+        };
+        assert_eq!(ids(&canvases.pinned), ["board", "notes"]);
+        // hinted first, the rest by name
+        assert_eq!(ids(&canvases.owned), ["notes", "sketch", "archive"]);
+        assert_eq!(ids(&canvases.shared), ["board"]);
+        assert!(!canvases.owned[1].pinned);
+        assert_eq!(canvases.owned[1].sort_hint, Some(5));
+
+        // unpinning keeps the hint
+        canvas_store
+            .send(SetCanvasPreferenceMessage {
+                pinned: Some(false),
+                ..pin("notes", None)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let canvases = canvas_store
+            .send(GetUserCanvasesMessage {
+                user_id: "alice".to_string(),
+                tag: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ids(&canvases.pinned), ["board"]);
+        assert_eq!(canvases.owned[0].sort_hint, Some(1));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[test]
+    fn test_replay_restores_settings() {
+        let mut events = expired_grant_events();
+        events.push(CanvasStoreEvents::CanvasSettingsChanged {
+            timestamp: 0,
+            canvas_id: "canvas".to_string(),
+            initiator_id: "owner".to_string(),
+            settings: CanvasSettings {
+                grid_size: Some(20),
+                snap_enabled: true,
+                shape_ownership_enforced: true,
+                allowed_shape_types: BTreeSet::from([ShapeType::Rectangle, ShapeType::Line]),
+                moderators_bypass_shape_types: true,
+                ..CanvasSettings::default()
+            },
+        });
+        // replayed as persisted
+        let events = events
+            .iter()
+            .map(|event| serde_json::from_value(serde_json::to_value(event).unwrap()).unwrap())
+            .collect::<Vec<CanvasStoreEvents>>();
+
+        let (state, issues) = replay_events(events, 0);
+        assert!(issues.is_empty());
+        let canvas = &state.canvases["canvas"];
+        assert_eq!(canvas.settings.snap_grid(), Some(20));
+        assert!(canvas.settings.shape_ownership_enforced);
+        assert_eq!(
+            canvas
+                .settings
+                .allowed_shape_types
+                .iter()
+                .collect::<Vec<_>>(),
+            [&ShapeType::Line, &ShapeType::Rectangle]
+        );
+        assert!(canvas.settings.moderators_bypass_shape_types);
+        assert_eq!(canvas.version, 3);
+
+        // snapping without a grid does nothing
+        let settings = CanvasSettings {
+            grid_size: None,
+            snap_enabled: true,
+            ..CanvasSettings::default()
+        };
+        assert_eq!(settings.snap_grid(), None);
+
+        // settings persisted before the voice behavior existed keep the legacy behavior
+        let settings: CanvasSettings = serde_json::from_str(r#"{"grid_size":null}"#).unwrap();
+        assert!(settings.legacy_voice_behavior);
+        assert!(settings.allowed_shape_types.is_empty());
+    }
+
+    fn class_canvas_events() -> Vec<CanvasStoreEvents> {
+        let added = |user_id: &str, access_level| CanvasStoreEvents::UserCanvasAdded {
+            timestamp: 0,
+            user_id: user_id.to_string(),
+            initiator_user_id: "owner".to_string(),
+            canvas_id: "class".to_string(),
+            access_level,
+            expires_at: None,
+        };
+        vec![
+            CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "class".to_string(),
+                state: CanvasState::Active,
+                name: "Class".to_string(),
+            },
+            added("teacher", AccessLevel::Moderate),
+            added("student", AccessLevel::Read),
+        ]
+    }
+
+    async fn class_levels(canvas_store: &Addr<CanvasStore>, users: &[&str]) -> Vec<AccessLevel> {
+        let mut levels = Vec::new();
+        for user_id in users {
+            levels.push(
+                canvas_store
+                    .send(GetUserAccessLevelMessage {
+                        user_id: user_id.to_string(),
+                        canvas_id: "class".to_string(),
+                    })
+                    .await
+                    .unwrap(),
+            );
+        }
+        levels
+    }
+
+    #[actix_web::test]
+    async fn test_batch_applies_the_allowed_entries_only() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, class_canvas_events());
+
+        let statuses = canvas_store
+            .send(AddUsersToCanvasMessage {
+                initiator_user_id: "teacher".to_string(),
+                canvas_id: "class".to_string(),
+                additions: vec![
+                    ("new".to_string(), AccessLevel::Write),
+                    ("student".to_string(), AccessLevel::Write),
+                    // moderators neither change the owner nor grant Moderate
+                    ("owner".to_string(), AccessLevel::Read),
+                    ("assistant".to_string(), AccessLevel::Moderate),
+                    ("new".to_string(), AccessLevel::Voice),
+                ],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        use BatchMemberStatus::*;
+        assert_eq!(statuses, [Added, Updated, Forbidden, Forbidden, Updated]);
+        assert_eq!(
+            class_levels(&canvas_store, &["new", "student", "owner", "assistant"]).await,
+            [
+                AccessLevel::Voice,
+                AccessLevel::Write,
+                AccessLevel::Owner,
+                AccessLevel::None
+            ]
+        );
+
+        // one event per allowed entry, replay ends with the same levels
+        let (persisted, _) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        assert_eq!(persisted.len(), 3);
+        let mut events = class_canvas_events();
+        events.extend(persisted);
+        let (state, issues) = replay_events(events, 0);
+        assert!(issues.is_empty());
+        assert_eq!(
+            state.canvases["class"].access_level(&"new".to_string(), 0),
+            AccessLevel::Voice
+        );
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    /// Fails the write of one event, the events before and after it are kept
+    struct FailingWriter {
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+        events: usize,
+        fail_at: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf == b"\n" {
+                self.events += 1;
+            } else if self.events == self.fail_at {
+                self.events += 1;
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_failed_batch_is_rolled_back() {
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let persistence = persistence::EventLogPersistenceActorJson::with_writer(FailingWriter {
+            written: written.clone(),
+            events: 0,
+            fail_at: 1,
+        })
+        .start();
+        let (store, _) = CanvasStore::new(
+            persistence.recipient(),
+            class_canvas_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        );
+        let canvas_store = store.start();
+        let version = |canvas_store: Addr<CanvasStore>| async move {
+            canvas_store
+                .send(GetCanvasMessage {
+                    canvas_id: "class".to_string(),
+                })
+                .await
+                .unwrap()
+                .unwrap()
+                .version
+        };
+        let version_before = version(canvas_store.clone()).await;
+
+        // the second event fails after the first one was written
+        let result = canvas_store
+            .send(AddUsersToCanvasMessage {
+                initiator_user_id: "owner".to_string(),
+                canvas_id: "class".to_string(),
+                additions: vec![
+                    ("new".to_string(), AccessLevel::Write),
+                    ("student".to_string(), AccessLevel::Write),
+                ],
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(CanvasStoreError::PersistenceFailed(_))
+        ));
+        assert_eq!(
+            class_levels(&canvas_store, &["new", "student"]).await,
+            [AccessLevel::None, AccessLevel::Read]
+        );
+        assert_eq!(version(canvas_store.clone()).await, version_before);
+
+        // the written entry is reverted in the eventlog as well
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let persisted: Vec<CanvasStoreEvents> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(matches!(
+            &persisted[..],
+            [
+                CanvasStoreEvents::UserCanvasAdded { user_id: added, .. },
+                CanvasStoreEvents::UserCanvasRemoved { user_id: removed, .. }
+            ] if added == "new" && removed == "new"
+        ));
+        let mut events = class_canvas_events();
+        events.extend(persisted);
+        let (state, _) = replay_events(events, 0);
+        assert_eq!(
+            state.canvases["class"].access_level(&"new".to_string(), 0),
+            AccessLevel::None
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_failed_resolution_reverts_the_grant() {
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let persistence = persistence::EventLogPersistenceActorJson::with_writer(FailingWriter {
+            written: written.clone(),
+            events: 0,
+            fail_at: 1,
+        })
+        .start();
+        let requested_events = || {
+            let mut events = class_canvas_events();
+            events.push(CanvasStoreEvents::AccessRequested {
+                timestamp: 0,
+                canvas_id: "class".to_string(),
+                user_id: "new".to_string(),
+                message: None,
+            });
+            events
+        };
+        let (store, _) = CanvasStore::new(
+            persistence.recipient(),
+            requested_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        );
+        let canvas_store = store.start();
+
+        // the grant is written, the resolution after it fails
+        let result = canvas_store
+            .send(ResolveAccessRequestMessage {
+                canvas_id: "class".to_string(),
+                initiator_id: "owner".to_string(),
+                user_id: "new".to_string(),
+                resolution: AccessRequestResolution::Approved(AccessLevel::Write),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(CanvasStoreError::PersistenceFailed(_))
+        ));
+        assert_eq!(
+            class_levels(&canvas_store, &["new"]).await,
+            [AccessLevel::None]
+        );
+        let membership = canvas_store
+            .send(GetCanvasMembershipMessage {
+                canvas_id: "class".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        // the request stays pending and can be approved again
+        assert_eq!(membership.access_requests.len(), 1);
+        assert_eq!(
+            membership.access_requests[0].status,
+            AccessRequestStatus::Pending
+        );
+
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let persisted: Vec<CanvasStoreEvents> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // the revert is stamped after the grant it follows in the eventlog
+        assert!(matches!(
+            &persisted[..],
+            [
+                CanvasStoreEvents::UserCanvasAdded { user_id: added, timestamp: granted_at, .. },
+                CanvasStoreEvents::UserCanvasRemoved { user_id: removed, timestamp: reverted_at, .. }
+            ] if added == "new" && removed == "new" && granted_at < reverted_at
+        ));
+        let mut events = requested_events();
+        events.extend(persisted);
+        let (state, _) = replay_events(events, 0);
+        assert_eq!(
+            state.canvases["class"].access_level(&"new".to_string(), 0),
+            AccessLevel::None
+        );
+    }
+
+    #[test]
+    fn test_replay_applies_the_latest_palette() {
+        let palette_changed =
+            |colors: &[&str], enforce_palette| CanvasStoreEvents::CanvasPaletteChanged {
+                timestamp: 0,
+                canvas_id: "canvas".to_string(),
+                initiator_id: "owner".to_string(),
+                palette: colors
+                    .iter()
+                    .map(|color| PaletteColor {
+                        name: color.to_string(),
+                        color: color.to_string(),
+                    })
+                    .collect(),
+                enforce_palette,
+            };
+        let mut events = expired_grant_events();
+        events.push(palette_changed(&["#000000", "#ff8800"], true));
+        events.push(CanvasStoreEvents::CanvasSettingsChanged {
+            timestamp: 0,
+            canvas_id: "canvas".to_string(),
+            initiator_id: "owner".to_string(),
+            settings: CanvasSettings {
+                grid_size: Some(20),
+                ..CanvasSettings::default()
+            },
+        });
+        events.push(palette_changed(&["#112233"], false));
+
+        let (state, issues) = replay_events(events, 0);
+        assert!(issues.is_empty());
+        let canvas = &state.canvases["canvas"];
+        let colors: Vec<&str> = canvas
+            .settings
+            .palette
+            .iter()
+            .map(|entry| entry.color.as_str())
+            .collect();
+        assert_eq!(colors, ["#112233"]);
+        assert!(!canvas.settings.enforce_palette);
+        assert_eq!(canvas.settings.grid_size, Some(20));
+        assert_eq!(canvas.version, 5);
+
+        // settings persisted without a palette load with an empty one
+        let settings: CanvasSettings = serde_json::from_str(r#"{"grid_size":null}"#).unwrap();
+        assert!(settings.palette.is_empty());
+        assert!(!serde_json::to_string(&settings)
+            .unwrap()
+            .contains("\"palette\""));
+    }
+
+    #[test]
+    fn test_write_permission_matrix() {
+        use AccessLevel::*;
+        use CanvasState::*;
+
+        // (level, state, can write, can write with legacy voice behavior)
+        let matrix = [
+            (Owner, Active, true, true),
+            (Owner, Moderated, true, true),
+            (Moderate, Active, true, true),
+            (Moderate, Moderated, true, true),
+            (Voice, Active, false, true),
+            (Voice, Moderated, true, true),
+            (Write, Active, true, true),
+            (Write, Moderated, false, false),
+            (Read, Active, false, false),
+            (Read, Moderated, false, false),
+            (None, Active, false, false),
+            (None, Moderated, false, false),
+            (Owner, Archived, false, false),
+            (Moderate, Archived, false, false),
+            (Voice, Archived, false, false),
+            (Write, Archived, false, false),
+            (Read, Archived, false, false),
+            (None, Archived, false, false),
+        ];
+        for (level, state, can_write, can_write_legacy) in matrix {
+            assert_eq!(
+                level.can_write_in(&state, false),
+                can_write,
+                "{level:?} in {state:?}"
+            );
+            assert_eq!(
+                level.can_write_in(&state, true),
+                can_write_legacy,
+                "{level:?} in {state:?} with legacy voice behavior"
+            );
+        }
+    }
+
+    #[test]
+    fn test_state_transition_matrix() {
+        use CanvasStateAction::*;
+
+        let denied = Err("denied");
+        let invalid = Err("invalid");
+        // (state, action, result for Owner, Moderate and every other level)
+        let matrix = [
+            (
+                CanvasState::Active,
+                Moderate,
+                [Ok("Moderated"), Ok("Moderated"), denied],
+            ),
+            (
+                CanvasState::Active,
+                Activate,
+                [Ok("Active"), Ok("Active"), denied],
+            ),
+            (
+                CanvasState::Active,
+                Archive,
+                [Ok("Archived"), denied, denied],
+            ),
+            (CanvasState::Active, Unarchive, [invalid, invalid, invalid]),
+            (
+                CanvasState::Moderated,
+                Moderate,
+                [Ok("Moderated"), Ok("Moderated"), denied],
+            ),
+            (
+                CanvasState::Moderated,
+                Activate,
+                [Ok("Active"), Ok("Active"), denied],
+            ),
+            (
+                CanvasState::Moderated,
+                Archive,
+                [Ok("Archived"), denied, denied],
+            ),
+            (
+                CanvasState::Moderated,
+                Unarchive,
+                [invalid, invalid, invalid],
+            ),
+            (CanvasState::Archived, Moderate, [invalid, invalid, invalid]),
+            (CanvasState::Archived, Activate, [invalid, invalid, invalid]),
+            (CanvasState::Archived, Archive, [invalid, invalid, invalid]),
+            (
+                CanvasState::Archived,
+                Unarchive,
+                [Ok("Active"), denied, denied],
+            ),
+        ];
+        let others = [
+            AccessLevel::Voice,
+            AccessLevel::Write,
+            AccessLevel::Read,
+            AccessLevel::None,
+        ];
+
+        for (state, action, expected) in matrix {
+            let levels = [
+                vec![AccessLevel::Owner],
+                vec![AccessLevel::Moderate],
+                others.to_vec(),
+            ];
+            for (levels, expected) in levels.iter().zip(expected) {
+                for level in levels {
+                    let result = match transition(&state, action, level) {
+                        Ok(state) => Ok(format!("{state:?}")),
+                        Err(CanvasStoreError::AccessDenied(_)) => Err("denied"),
+                        Err(CanvasStoreError::InvalidTransition { .. }) => Err("invalid"),
+                        Err(e) => panic!("unexpected {e:?}"),
+                    };
+                    assert_eq!(
+                        result,
+                        expected.map(str::to_string),
+                        "{level:?} takes {action:?} in {state:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_only_owner_switches_voice_behavior() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let update = |initiator_id: &str, legacy_voice_behavior| UpdateCanvasSettingsMessage {
+            canvas_id: "board".to_string(),
+            initiator_id: initiator_id.to_string(),
+            settings: CanvasSettings {
+                grid_size: Some(10),
+                ..CanvasSettings::default()
+            },
+            legacy_voice_behavior,
+            metadata: None,
+            retention: None,
+            anonymize_for_readers: None,
+            guest_access: None,
+            digest: None,
+            allowed_shape_types: None,
+            moderators_bypass_shape_types: None,
+        };
+
+        let denied = canvas_store
+            .send(update("alice", Some(false)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied,
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasVoiceBehaviorDenied
+            ))
+        ));
+
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(false)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!settings.legacy_voice_behavior);
+
+        // other settings can still be changed, the voice behavior is kept
+        let (_, settings) = canvas_store
+            .send(update("alice", Option::None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!settings.legacy_voice_behavior);
+        assert_eq!(settings.grid_size, Some(10));
+        // repeating the current behavior is not a change
+        assert!(canvas_store
+            .send(update("alice", Some(false)))
+            .await
+            .unwrap()
+            .is_ok());
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_only_owner_restricts_shape_types() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let update = |initiator_id: &str, allowed_shape_types, moderators_bypass_shape_types| {
+            UpdateCanvasSettingsMessage {
+                canvas_id: "board".to_string(),
+                initiator_id: initiator_id.to_string(),
+                settings: CanvasSettings::default(),
+                legacy_voice_behavior: None,
+                metadata: None,
+                retention: None,
+                anonymize_for_readers: None,
+                guest_access: None,
+                digest: None,
+                allowed_shape_types,
+                moderators_bypass_shape_types,
+            }
+        };
+        let rectangles = BTreeSet::from([ShapeType::Rectangle]);
+
+        for (allowed, bypass) in [(Some(rectangles.clone()), None), (None, Some(true))] {
+            let denied = canvas_store
+                .send(update("alice", allowed, bypass))
+                .await
+                .unwrap();
+            assert!(matches!(
+                denied,
+                Err(CanvasStoreError::AccessDenied(
+                    MessageKey::CanvasShapeTypesDenied
+                ))
+            ));
+        }
+
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(rectangles.clone()), None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.allowed_shape_types, rectangles);
+        assert!(!settings.moderators_bypass_shape_types);
+
+        // other settings are changed without touching the restriction
+        let (_, settings) = canvas_store
+            .send(update("alice", None, None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.allowed_shape_types, rectangles);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_only_owner_anonymizes_for_readers() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let update = |initiator_id: &str, anonymize_for_readers| UpdateCanvasSettingsMessage {
+            canvas_id: "board".to_string(),
+            initiator_id: initiator_id.to_string(),
+            settings: CanvasSettings::default(),
+            legacy_voice_behavior: None,
+            metadata: None,
+            retention: None,
+            anonymize_for_readers,
+            guest_access: None,
+            digest: None,
+            allowed_shape_types: None,
+            moderators_bypass_shape_types: None,
+        };
+
+        let denied = canvas_store
+            .send(update("alice", Some(true)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied,
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasAnonymizeDenied
+            ))
+        ));
+
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(true)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(settings.anonymize_for_readers);
+        let salt = settings.reader_salt.clone().unwrap();
+
+        // the salt survives turning the anonymization off and on again, pseudonyms stay the same
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(false)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!settings.anonymize_for_readers);
+        let (_, settings) = canvas_store
+            .send(update("alice", Option::None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.reader_salt, Some(salt));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[test]
+    fn test_normalize_metadata() {
+        let metadata = normalize_metadata(CanvasMetadata {
+            author_display: Some(" Ali\u{0}ce ".to_string()),
+            license: Some(CanvasLicense::Custom("\tHouse rules\n".to_string())),
+            description: Some("first line\nsecond\u{1b} line\r".to_string()),
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(metadata.author_display.as_deref(), Some("Alice"));
+        assert_eq!(
+            metadata.license,
+            Some(CanvasLicense::Custom("House rules".to_string()))
+        );
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("first line\nsecond line")
+        );
+
+        // nothing left means no metadata
+        let empty = CanvasMetadata {
+            author_display: Some("\u{7} ".to_string()),
+            ..CanvasMetadata::default()
+        };
+        assert_eq!(normalize_metadata(empty), Ok(None));
+
+        let too_long = CanvasMetadata {
+            description: Some("ä".repeat(MAX_DESCRIPTION_BYTES / 2 + 1)),
+            ..CanvasMetadata::default()
+        };
+        assert_eq!(
+            normalize_metadata(too_long),
+            Err(InvalidMetadata {
+                field: "description",
+                max: MAX_DESCRIPTION_BYTES
+            })
+        );
+        let empty_license = CanvasMetadata {
+            license: Some(CanvasLicense::Custom(" ".to_string())),
+            ..CanvasMetadata::default()
+        };
+        assert!(normalize_metadata(empty_license).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_only_owner_changes_metadata() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let metadata = CanvasMetadata {
+            author_display: Some("Bob".to_string()),
+            license: Some(CanvasLicense::Cc0),
+            description: None,
+        };
+        let update = |initiator_id: &str, metadata| UpdateCanvasSettingsMessage {
+            canvas_id: "board".to_string(),
+            initiator_id: initiator_id.to_string(),
+            settings: CanvasSettings::default(),
+            legacy_voice_behavior: None,
+            metadata,
+            retention: None,
+            anonymize_for_readers: None,
+            guest_access: None,
+            digest: None,
+            allowed_shape_types: None,
+            moderators_bypass_shape_types: None,
+        };
+
+        let denied = canvas_store
+            .send(update("alice", Some(Some(metadata.clone()))))
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied,
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasMetadataDenied
+            ))
+        ));
+
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(Some(metadata.clone()))))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.metadata, Some(metadata.clone()));
+
+        // other settings keep the metadata
+        let (_, settings) = canvas_store
+            .send(update("alice", None))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.metadata, Some(metadata));
+
+        let (_, settings) = canvas_store
+            .send(update("bob", Some(None)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.metadata, None);
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    fn tags_changed(canvas_id: &str, tags: &[&str]) -> CanvasStoreEvents {
+        CanvasStoreEvents::CanvasTagsChanged {
+            timestamp: 0,
+            canvas_id: canvas_id.to_string(),
+            initiator_id: "alice".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn tagged(state: &HashMap<String, HashSet<CanvasId>>, tag: &str) -> Vec<CanvasId> {
+        let mut canvas_ids: Vec<CanvasId> = state
+            .get(tag)
+            .map(|canvas_ids| canvas_ids.iter().cloned().collect())
+            .unwrap_or_default();
+        canvas_ids.sort();
+        canvas_ids
+    }
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = ["  Work ", "school", "WORK", "Schule-2024"].map(str::to_string);
+        assert_eq!(
+            normalize_tags(tags),
+            Ok(vec![
+                "work".to_string(),
+                "school".to_string(),
+                "schule-2024".to_string()
+            ])
+        );
+        assert_eq!(normalize_tags(Vec::new()), Ok(Vec::new()));
+
+        let too_long = "x".repeat(MAX_TAG_LENGTH + 1);
+        let tags = ["ok".to_string(), " ".to_string(), too_long.clone()];
+        assert_eq!(
+            normalize_tags(tags),
+            Err(InvalidTags::Invalid(vec![" ".to_string(), too_long]))
+        );
+        // length counts characters, not bytes
+        assert!(normalize_tags(["ä".repeat(MAX_TAG_LENGTH)]).is_ok());
+
+        // duplicates count once
+        let tags = (0..=MAX_CANVAS_TAGS).map(|i| format!("tag{i}"));
+        assert_eq!(
+            normalize_tags(tags.chain(["TAG0".to_string()])),
+            Err(InvalidTags::TooMany(MAX_CANVAS_TAGS + 1))
+        );
+    }
+
+    #[test]
+    fn test_replay_keeps_tag_index_consistent() {
+        let mut events = shared_canvas_events();
+        events.push(tags_changed("sketch", &["work", "draft"]));
+        events.push(tags_changed("board", &["work"]));
+        // last write wins
+        events.push(tags_changed("sketch", &["draft", "final"]));
+
+        let (state, issues) = replay_events(events, 0);
+        assert!(issues.is_empty());
+        assert_eq!(state.canvases["sketch"].tags, vec!["draft", "final"]);
+        assert_eq!(state.canvases["sketch"].version, 3);
+        assert_eq!(tagged(&state.tag_index, "work"), vec!["board"]);
+        assert_eq!(tagged(&state.tag_index, "draft"), vec!["sketch"]);
+        assert_eq!(tagged(&state.tag_index, "final"), vec!["sketch"]);
+
+        let mut events = shared_canvas_events();
+        events.push(tags_changed("sketch", &["work"]));
+        events.push(tags_changed("board", &["work", "draft"]));
+        events.push(CanvasStoreEvents::CanvasDeleted {
+            timestamp: 0,
+            canvas_id: "board".to_string(),
+        });
+        events.push(tags_changed("gone", &["work"]));
+
+        let index = events.len() - 1;
+        let (state, issues) = replay_events(events, 0);
+        assert_eq!(
+            issues,
+            vec![ReplayIssue::skipped(
+                index,
+                "Tags changed on unknown canvas gone"
+            )]
+        );
+        assert_eq!(tagged(&state.tag_index, "work"), vec!["sketch"]);
+        // tags without canvases are dropped from the index
+        assert!(!state.tag_index.contains_key("draft"));
+    }
+
+    #[actix_web::test]
+    async fn test_user_canvases_filtered_by_tag() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let mut events = shared_canvas_events();
+        events.push(tags_changed("sketch", &["work"]));
+        let canvas_store = start_store(log_path, events);
+        let user_canvases = |tag: &str| GetUserCanvasesMessage {
+            user_id: "alice".to_string(),
+            tag: Some(tag.to_string()),
+        };
+
+        let canvases = canvas_store.send(user_canvases("work")).await.unwrap();
+        assert_eq!(canvases.owned.len(), 1);
+        assert_eq!(canvases.owned[0].tags, vec!["work"]);
+        assert!(canvases.shared.is_empty());
+
+        canvas_store
+            .send(UpdateCanvasTagsMessage {
+                canvas_id: "board".to_string(),
+                initiator_id: "bob".to_string(),
+                tags: vec!["work".to_string(), "team".to_string()],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        canvas_store
+            .send(UpdateCanvasTagsMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "alice".to_string(),
+                tags: Vec::new(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let canvases = canvas_store.send(user_canvases("work")).await.unwrap();
+        assert!(canvases.owned.is_empty());
+        assert_eq!(canvases.shared.len(), 1);
+        assert_eq!(canvases.shared[0].tags, vec!["work", "team"]);
+
+        canvas_store
+            .send(DeleteCanvasMessage {
+                canvas_id: "board".to_string(),
+                initiator_id: "bob".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let canvases = canvas_store.send(user_canvases("team")).await.unwrap();
+        assert!(canvases.shared.is_empty());
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_owned_canvases_and_membership_after_replay() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let created =
+            |canvas_id: &str, owner_id: &str, timestamp| CanvasStoreEvents::CanvasCreated {
+                timestamp,
+                owner_id: owner_id.to_string(),
+                canvas_id: canvas_id.to_string(),
+                state: CanvasState::Active,
+                name: canvas_id.to_uppercase(),
+            };
+        let added =
+            |canvas_id: &str, user_id: &str, access_level| CanvasStoreEvents::UserCanvasAdded {
+                timestamp: 0,
+                user_id: user_id.to_string(),
+                initiator_user_id: "alice".to_string(),
+                canvas_id: canvas_id.to_string(),
+                access_level,
+                expires_at: None,
+            };
+        let events = vec![
+            created("draft", "alice", 300),
+            created("sketch", "alice", 100),
+            created("gone", "alice", 200),
+            created("board", "bob", 400),
+            added("sketch", "bob", AccessLevel::Write),
+            added("sketch", "carol", AccessLevel::Read),
+            // alice is a member of board, not its owner
+            added("board", "alice", AccessLevel::Moderate),
+            added("gone", "bob", AccessLevel::Read),
+            CanvasStoreEvents::CanvasDeleted {
+                timestamp: 500,
+                canvas_id: "gone".to_string(),
+            },
+            created("trash", "alice", 250),
+            added("trash", "carol", AccessLevel::Read),
+            CanvasStoreEvents::CanvasSoftDeleted {
+                timestamp: 600,
+                canvas_id: "trash".to_string(),
+                initiator_id: "alice".to_string(),
+            },
+        ];
+        let canvas_store = start_store(log_path, events);
+
+        let owned = canvas_store
+            .send(GetOwnedCanvasesMessage {
+                user_id: "alice".to_string(),
+            })
+            .await
+            .unwrap();
+        // soft deleted canvases are owned until they are purged, purged ones are gone
+        let owned: Vec<(&str, usize, u64, Option<u64>)> = owned
+            .iter()
+            .map(|canvas| {
+                (
+                    canvas.id.as_str(),
+                    canvas.member_count,
+                    canvas.created_at,
+                    canvas.deleted_at,
+                )
+            })
+            .collect();
+        assert_eq!(
+            owned,
+            vec![
+                ("sketch", 3, 100, None),
+                ("trash", 2, 250, Some(600)),
+                ("draft", 1, 300, None)
+            ]
+        );
+
+        let membership = canvas_store
+            .send(GetCanvasMembershipMessage {
+                canvas_id: "sketch".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut members: Vec<(String, AccessLevel)> = membership
+            .members
+            .into_iter()
+            .map(|member| (member.user_id, member.access_level))
+            .collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            members,
+            vec![
+                ("alice".to_string(), AccessLevel::Owner),
+                ("bob".to_string(), AccessLevel::Write),
+                ("carol".to_string(), AccessLevel::Read),
+            ]
+        );
+        let gone = canvas_store
+            .send(GetCanvasMembershipMessage {
+                canvas_id: "gone".to_string(),
+            })
+            .await
+            .unwrap();
+        assert!(gone.is_none());
+
+        let created = canvas_store
+            .send(CreateCanvasMessage {
+                canvas: CreateCanvas {
+                    name: "Fresh".to_string(),
+                    owner_id: "bob".to_string(),
+                },
+                auto_rename: false,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let owned = canvas_store
+            .send(GetOwnedCanvasesMessage {
+                user_id: "bob".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(owned.len(), 2);
+        assert_eq!(owned[1].id, created.id);
+        assert_eq!(owned[1].created_at, created.created_at);
+        assert!(created.created_at > 0);
+
+        let _ = std::fs::remove_file(log_path);
     }
-}
 
-#[derive(Message, Clone)]
-#[rtype(result = "Result<(), CanvasStoreError>")]
-pub struct AddUserToCanvasMessage {
-    pub initiator_user_id: UserId,
-    pub canvas_id: CanvasId,
-    pub target_user_id: UserId,
-    pub access_level: AccessLevel,
-}
+    #[actix_web::test]
+    async fn test_access_request_approval_survives_replay() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_store = start_store(log_path, shared_canvas_events());
+        let request = |user_id: &str| RequestCanvasAccessMessage {
+            canvas_id: "sketch".to_string(),
+            user_id: user_id.to_string(),
+            message: Some("please".to_string()),
+        };
+        let resolve = |initiator_id: &str, resolution| ResolveAccessRequestMessage {
+            canvas_id: "sketch".to_string(),
+            initiator_id: initiator_id.to_string(),
+            user_id: "carol".to_string(),
+            resolution,
+        };
 
-impl Handler<AddUserToCanvasMessage> for CanvasStore {
-    type Result = AtomicResponse<Self, Result<(), CanvasStoreError>>;
+        let first = canvas_store.send(request("carol")).await.unwrap().unwrap();
+        assert!(first.created);
+        assert_eq!(first.managers, vec!["alice".to_string()]);
+        // deduplicated while pending, nothing is persisted
+        let second = canvas_store.send(request("carol")).await.unwrap().unwrap();
+        assert!(!second.created);
+        assert_eq!(second.request, first.request);
+        assert!(matches!(
+            canvas_store.send(request("alice")).await.unwrap(),
+            Err(CanvasStoreError::AlreadyMember)
+        ));
 
-    fn handle(&mut self, msg: AddUserToCanvasMessage, _: &mut Self::Context) -> Self::Result {
-        if self.canvases.get(&msg.canvas_id).is_none() {
-            return AtomicResponse::new(Box::pin(
-                async move { Err(CanvasStoreError::CanvasNotFound) }.into_actor(self),
-            ));
-        }
+        // bob is no member of sketch, carol can only cancel
+        assert!(matches!(
+            canvas_store
+                .send(resolve("bob", AccessRequestResolution::Denied))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::AccessRequestResolveDenied
+            ))
+        ));
+        assert!(matches!(
+            canvas_store
+                .send(resolve(
+                    "carol",
+                    AccessRequestResolution::Approved(AccessLevel::Write)
+                ))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::AccessRequestResolveDenied
+            ))
+        ));
+        // approvals are checked like grants
+        assert!(matches!(
+            canvas_store
+                .send(resolve(
+                    "alice",
+                    AccessRequestResolution::Approved(AccessLevel::Owner)
+                ))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::OwnerAssignsOwner
+            ))
+        ));
 
-        let target_access_level = self.get_access_level(&msg.target_user_id, &msg.canvas_id);
-        let initiator_access_level = self.get_access_level(&msg.initiator_user_id, &msg.canvas_id);
+        let approved = canvas_store
+            .send(resolve(
+                "alice",
+                AccessRequestResolution::Approved(AccessLevel::Write),
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(approved.request.status, AccessRequestStatus::Approved);
+        assert_eq!(approved.request.access_level, Some(AccessLevel::Write));
+        // the approval grants the access itself
+        assert_eq!(
+            canvas_store
+                .send(GetUserAccessLevelMessage {
+                    user_id: "carol".to_string(),
+                    canvas_id: "sketch".to_string(),
+                })
+                .await
+                .unwrap(),
+            AccessLevel::Write
+        );
+        assert!(matches!(
+            canvas_store
+                .send(resolve("alice", AccessRequestResolution::Denied))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessRequestNotFound)
+        ));
 
-        if let Err(e) = self.validate_permission_change(
-            &initiator_access_level,
-            &target_access_level,
-            &msg.access_level,
-        ) {
-            return AtomicResponse::new(Box::pin(async move { Err(e) }.into_actor(self)));
-        }
+        // a second requester cancels
+        let dave = canvas_store.send(request("dave")).await.unwrap().unwrap();
+        assert!(dave.created);
+        canvas_store
+            .send(ResolveAccessRequestMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "dave".to_string(),
+                user_id: "dave".to_string(),
+                resolution: AccessRequestResolution::Cancelled,
+            })
+            .await
+            .unwrap()
+            .unwrap();
 
-        let event = CanvasStoreEvents::UserCanvasAdded {
-            timestamp: chrono::Utc::now().timestamp_millis() as u64,
-            user_id: msg.target_user_id.clone(),
-            initiator_user_id: msg.initiator_user_id.clone(),
-            canvas_id: msg.canvas_id.clone(),
-            access_level: msg.access_level.clone(),
+        let persisted: Vec<CanvasStoreEvents> = EventLogPersistenceJson::open(log_path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(persisted.len(), 5);
+        let mut events = shared_canvas_events();
+        events.extend(persisted);
+        let (state, issues) = replay_events(events, approved.request.resolved_at.unwrap());
+        assert!(issues.is_empty());
+        assert_eq!(
+            state.canvases["sketch"].users.get("carol"),
+            Some(&AccessLevel::Write)
+        );
+        let requests = state.access_requests["sketch"].listed(0);
+        let statuses: Vec<(&str, AccessRequestStatus)> = requests
+            .iter()
+            .map(|request| (request.user_id.as_str(), request.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("carol", AccessRequestStatus::Approved),
+                ("dave", AccessRequestStatus::Cancelled)
+            ]
+        );
+        assert_eq!(requests[0].message.as_deref(), Some("please"));
+        assert_eq!(requests[0].resolved_by.as_deref(), Some("alice"));
+
+        // resolved requests age out with the next replay
+        let later = approved.request.resolved_at.unwrap()
+            + super::super::access_requests::RESOLVED_REQUEST_RETENTION.as_millis() as u64
+            + 1_000;
+        let mut events = shared_canvas_events();
+        events.extend(
+            EventLogPersistenceJson::open(log_path)
+                .unwrap()
+                .read_lines::<CanvasStoreEvents>()
+                .unwrap()
+                .into_iter()
+                .map(Result::unwrap),
+        );
+        let (state, _) = replay_events(events, later);
+        assert!(!state.access_requests.contains_key("sketch"));
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_unique_names_are_resolved_per_owner() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            shared_canvas_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .with_unique_names(true)
+        .start();
+        let create = |owner_id: &str, name: &str, auto_rename| CreateCanvasMessage {
+            canvas: CreateCanvas {
+                name: name.to_string(),
+                owner_id: owner_id.to_string(),
+            },
+            auto_rename,
         };
 
-        AtomicResponse::new(Box::pin(
-            self.event_persistence_recipient
-                .send(persistence::PersistEventMessage(event))
-                .into_actor(self)
-                .map(move |result, canvasstore, _| {
-                    match result {
-                        Ok(Ok(_)) => {
-                            let msg = msg.clone();
+        assert!(matches!(
+            canvas_store.send(create("alice", " SKETCH ", false)).await.unwrap(),
+            Err(CanvasStoreError::DuplicateName(name)) if name == "SKETCH"
+        ));
+        // names are unique per owner only
+        let other = canvas_store
+            .send(create("bob", "Sketch", false))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(other.name, "Sketch");
+        let copy = canvas_store
+            .send(create("alice", "Sketch", true))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(copy.name, "Sketch (2)");
 
-                            // perform state update, after event is persisted
+        let rename = |name: &str, auto_rename| RenameCanvasMessage {
+            canvas_id: copy.id.clone(),
+            initiator_id: "alice".to_string(),
+            name: name.to_string(),
+            auto_rename,
+        };
+        assert!(matches!(
+            canvas_store.send(rename("sketch", false)).await.unwrap(),
+            Err(CanvasStoreError::DuplicateName(_))
+        ));
+        // keeping its own name is no collision
+        assert_eq!(
+            canvas_store
+                .send(rename("Sketch (2)", false))
+                .await
+                .unwrap()
+                .unwrap(),
+            "Sketch (2)"
+        );
+        assert_eq!(
+            canvas_store
+                .send(rename("Plan", false))
+                .await
+                .unwrap()
+                .unwrap(),
+            "Plan"
+        );
 
-                            // canvas is guaranteed to exist, CanvasStore is not multi-threaded,
-                            // AtomicRepsonse is used for exlusive state access
-                            let canvas = canvasstore.canvases.get_mut(&msg.canvas_id).unwrap();
-                            canvas
-                                .users
-                                .entry(msg.target_user_id.clone())
-                                .and_modify(|a| *a = msg.access_level.clone())
-                                .or_insert(msg.access_level.clone());
+        // deleted canvases give up their name until restored
+        canvas_store
+            .send(DeleteCanvasMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "alice".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        canvas_store
+            .send(rename("Sketch", false))
+            .await
+            .unwrap()
+            .unwrap();
+        canvas_store
+            .send(RestoreCanvasMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "alice".to_string(),
+                admin: false,
+            })
+            .await
+            .unwrap()
+            .unwrap();
 
-                            // update lookup cache, oof
-                            canvasstore
-                                .user_id_lookup
-                                .entry(msg.target_user_id)
-                                .and_modify(|claims| {
-                                    if let Some(claim) =
-                                        claims.iter_mut().find(|claim| claim.c == msg.canvas_id)
-                                    {
-                                        claim.r = msg.access_level.clone();
-                                    } else {
-                                        claims.push(CanvasClaim {
-                                            n: canvas.name.clone(),
-                                            c: msg.canvas_id.clone(),
-                                            r: msg.access_level.clone(),
-                                        });
-                                    }
-                                })
-                                .or_insert(vec![CanvasClaim {
-                                    n: canvas.name.clone(),
-                                    c: msg.canvas_id,
-                                    r: msg.access_level,
-                                }]);
+        let events: Vec<CanvasStoreEvents> = EventLogPersistenceJson::open(log_path)
+            .unwrap()
+            .read_lines()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let mut replayed = shared_canvas_events();
+        replayed.extend(events);
+        let (state, issues) = replay_events(replayed, 0);
+        assert!(issues.is_empty());
+        assert_eq!(state.canvases[&copy.id].name, "Sketch");
+        // a restored canvas keeps its name even though it collides now
+        assert_eq!(state.name_index.canvases(&"alice".to_string(), "sketch"), {
+            let mut ids = vec!["sketch".to_string(), copy.id.clone()];
+            ids.sort();
+            ids
+        });
+        assert!(state
+            .name_index
+            .canvases(&"alice".to_string(), "Plan")
+            .is_empty());
+        assert_eq!(
+            state.name_index.canvases(&"bob".to_string(), "sketch"),
+            vec![other.id.clone()]
+        );
 
-                            Ok(())
-                        }
-                        Ok(Err(_)) => Err(CanvasStoreError::PersistenceFailed),
-                        Err(_) => Err(CanvasStoreError::PersistenceFailed),
-                    }
-                }),
-        ))
+        let _ = std::fs::remove_file(log_path);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use persistence::EventLogPersistenceJson;
+    #[actix_web::test]
+    async fn test_canvas_deletion_lifecycle() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let canvas_id = nanoid!(CANVAS_ID_LENGTH, &CANVAS_ID_ALPHABET);
+        let canvas_log = canvas_log_path(&canvas_id);
+        std::fs::write(&canvas_log, "").unwrap();
 
-    use super::*;
+        let mut events = shared_canvas_events();
+        events.push(CanvasStoreEvents::CanvasCreated {
+            timestamp: 0,
+            owner_id: "alice".to_string(),
+            canvas_id: canvas_id.clone(),
+            state: CanvasState::Active,
+            name: "Doomed".to_string(),
+        });
+        events.push(CanvasStoreEvents::UserCanvasAdded {
+            timestamp: 0,
+            user_id: "bob".to_string(),
+            initiator_user_id: "alice".to_string(),
+            canvas_id: canvas_id.clone(),
+            access_level: AccessLevel::Write,
+            expires_at: None,
+        });
+        events.push(tags_changed(&canvas_id, &["work"]));
+        let initial_events = serde_json::to_string(&events).unwrap();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let canvas_store = start_store_with_clock(log_path, events, clock.clone());
+
+        let delete = || DeleteCanvasMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: "alice".to_string(),
+        };
+        let restore = |initiator_id: &str| RestoreCanvasMessage {
+            canvas_id: canvas_id.clone(),
+            initiator_id: initiator_id.to_string(),
+            admin: false,
+        };
+        let bob_access = || GetUserAccessLevelMessage {
+            user_id: "bob".to_string(),
+            canvas_id: canvas_id.clone(),
+        };
+        let alice_canvases = || GetUserCanvasesMessage {
+            user_id: "alice".to_string(),
+            tag: None,
+        };
+
+        canvas_store.send(delete()).await.unwrap().unwrap();
+        let canvas = canvas_store
+            .send(GetCanvasMessage {
+                canvas_id: canvas_id.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(canvas.is_none());
+        assert_eq!(
+            canvas_store.send(bob_access()).await.unwrap(),
+            AccessLevel::None
+        );
+        let canvases = canvas_store.send(alice_canvases()).await.unwrap();
+        assert!(canvases.owned.iter().all(|canvas| canvas.id != canvas_id));
+        assert_eq!(canvases.deleted.len(), 1);
+        let deleted = &canvases.deleted[0];
+        assert_eq!(
+            deleted.purge_at - deleted.deleted_at,
+            DEFAULT_DELETION_GRACE.as_millis() as u64
+        );
+        // deleting twice finds nothing to delete
+        assert!(matches!(
+            canvas_store.send(delete()).await.unwrap(),
+            Err(CanvasStoreError::CanvasNotFound)
+        ));
+
+        // members can't restore, the owner can
+        assert!(matches!(
+            canvas_store.send(restore("bob")).await.unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasRestoreDenied
+            ))
+        ));
+        canvas_store.send(restore("alice")).await.unwrap().unwrap();
+        assert_eq!(
+            canvas_store.send(bob_access()).await.unwrap(),
+            AccessLevel::Write
+        );
+        let tagged = canvas_store
+            .send(GetUserCanvasesMessage {
+                user_id: "bob".to_string(),
+                tag: Some("work".to_string()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(tagged.shared[0].id, canvas_id);
+
+        canvas_store.send(delete()).await.unwrap().unwrap();
+
+        // delete, restore and delete again replay to a soft deleted canvas
+        let mut replayed: Vec<CanvasStoreEvents> = serde_json::from_str(&initial_events).unwrap();
+        replayed.extend(
+            EventLogPersistenceJson::open(log_path)
+                .unwrap()
+                .read_lines::<CanvasStoreEvents>()
+                .unwrap()
+                .into_iter()
+                .map(Result::unwrap),
+        );
+        let (state, issues) = replay_events(replayed, 0);
+        assert!(issues.is_empty());
+        assert!(!state.canvases.contains_key(&canvas_id));
+        assert!(state.deleted_canvases[&canvas_id].deleted_at.is_some());
+        assert!(state
+            .claims
+            .get("bob")
+            .unwrap()
+            .iter()
+            .all(|claim| claim.c != canvas_id));
+        assert!(!state.tag_index.contains_key("work"));
+
+        let purge = || PurgeDeletedCanvasesMessage {
+            now: clock.now_ms(),
+        };
+        assert_eq!(canvas_store.send(purge()).await.unwrap().unwrap(), 0);
+        assert!(std::path::Path::new(&canvas_log).exists());
+
+        clock.advance(DEFAULT_DELETION_GRACE);
+        assert_eq!(canvas_store.send(purge()).await.unwrap().unwrap(), 1);
+        assert!(!std::path::Path::new(&canvas_log).exists());
+        assert!(matches!(
+            canvas_store.send(restore("alice")).await.unwrap(),
+            Err(CanvasStoreError::CanvasNotFound)
+        ));
+        let canvases = canvas_store.send(alice_canvases()).await.unwrap();
+        assert!(canvases.deleted.is_empty());
+        // a leftover eventlog of the canvas would be purgeable
+        let records = canvas_store.send(GetCanvasRecordsMessage).await.unwrap();
+        assert_eq!(records.get(&canvas_id), Some(&CanvasRecord::Purged));
+
+        let _ = std::fs::remove_file(log_path);
+    }
 
     #[actix_web::test]
-    async fn test_access_level_validation() {
-        // Canvas Store Setup
-        // Same constraints as for the user store
-        let canvas_event_log = EventLogPersistenceJson::new("test.jsonl")
-            .expect("Failed to create or load canvas event log");
-        let (_, canvas_event_log) = canvas_event_log
+    async fn test_deleting_and_purging_evicts_cached_replays() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
             .into_actor::<CanvasStoreEvents>()
-            .expect("Failed to read canvas event log");
-        let canvas_event_persistor_recipient = canvas_event_log.start().recipient();
+            .unwrap();
+        let replay_cache = Arc::new(ReplayCache::default());
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            shared_canvas_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .with_replay_cache(replay_cache.clone())
+        .start();
 
-        // NOTE: not used right now in test, can be used later
-        let initial_events = vec![
-            CanvasStoreEvents::CanvasCreated {
-                timestamp: 0,
-                owner_id: "owner".to_string(),
-                canvas_id: "canvas".to_string(),
-                state: CanvasState::Active,
-                name: "Canvas".to_string(),
-            },
-            CanvasStoreEvents::UserCanvasAdded {
-                timestamp: 0,
-                user_id: "moderator".to_string(),
-                initiator_user_id: "owner".to_string(),
-                canvas_id: "canvas".to_string(),
-                access_level: AccessLevel::Moderate,
-            },
-            CanvasStoreEvents::UserCanvasAdded {
-                timestamp: 0,
-                user_id: "reader".to_string(),
-                initiator_user_id: "owner".to_string(),
-                canvas_id: "canvas".to_string(),
-                access_level: AccessLevel::Read,
-            },
-            CanvasStoreEvents::UserCanvasAdded {
-                timestamp: 0,
-                user_id: "writer".to_string(),
-                initiator_user_id: "owner".to_string(),
-                canvas_id: "canvas".to_string(),
-                access_level: AccessLevel::Write,
-            },
-            CanvasStoreEvents::UserCanvasAdded {
-                timestamp: 0,
-                user_id: "voice".to_string(),
-                initiator_user_id: "owner".to_string(),
-                canvas_id: "canvas".to_string(),
-                access_level: AccessLevel::Voice,
-            },
+        let cutoff = crate::canvas::replay::ReplayCutoff::Sequence(1);
+        let cache = |canvas_id: &str| replay_cache.insert(canvas_id, cutoff, Arc::default());
+        cache("sketch");
+        cache("board");
+        canvas_store
+            .send(DeleteCanvasMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "alice".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(replay_cache.get("sketch", cutoff).is_none());
+        assert!(replay_cache.get("board", cutoff).is_some());
+
+        // replayed while the canvas was restorable
+        cache("sketch");
+        let purged = canvas_store
+            .send(PurgeDeletedCanvasesMessage { now: u64::MAX / 2 })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert!(replay_cache.get("sketch", cutoff).is_none());
+        assert!(replay_cache.get("board", cutoff).is_some());
+
+        let _ = std::fs::remove_file(log_path);
+    }
+
+    #[actix_web::test]
+    async fn test_member_quota_warning_clears_once_members_are_removed() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        // warns at 4 members, clears below 3
+        let quota_limits = QuotaLimits {
+            max_members: 5,
+            ..QuotaLimits::default()
+        };
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            shared_canvas_events(),
+            quota_limits,
+            clock::system(),
+        )
+        .0
+        .start();
+
+        let set_access = |user_id: &str, access_level| AddUserToCanvasMessage {
+            initiator_user_id: "alice".to_string(),
+            canvas_id: "sketch".to_string(),
+            target_user_id: user_id.to_string(),
+            access_level,
+            expires_at: None,
+        };
+        let warnings = || async {
+            canvas_store
+                .send(GetCanvasQuotaMessage {
+                    canvas_id: "sketch".to_string(),
+                })
+                .await
+                .unwrap()
+                .unwrap()
+                .warnings
+                .len()
+        };
+        let steps = [
+            ("u1", AccessLevel::Read, 0),
+            ("u2", AccessLevel::Read, 0),
+            ("u3", AccessLevel::Read, 1),
+            // 3 members stay within the hysteresis
+            ("u3", AccessLevel::None, 1),
+            ("u3", AccessLevel::Read, 1),
+            ("u3", AccessLevel::None, 1),
+            ("u2", AccessLevel::None, 1),
+            // cleared, crossing again warns again
+            ("u2", AccessLevel::Read, 1),
+            ("u3", AccessLevel::Read, 2),
         ];
+        for (user_id, access_level, expected) in steps {
+            canvas_store
+                .send(set_access(user_id, access_level))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(warnings().await, expected, "after {user_id}");
+        }
 
-        let canvas_store = CanvasStore::new(canvas_event_persistor_recipient, initial_events)
-            .expect("Failed to parse persisted event log");
+        let _ = std::fs::remove_file(log_path);
+    }
 
-        // note this does not use messages, only checks the validation function
+    #[actix_web::test]
+    async fn test_restore_is_refused_after_grace_period() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            shared_canvas_events(),
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .with_deletion_grace(Duration::ZERO)
+        .start();
 
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Read,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Read,
-                &AccessLevel::Owner,
-                &AccessLevel::Moderate
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::Owner,
-                &AccessLevel::Write
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::None,
-                &AccessLevel::Owner
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::None,
-                &AccessLevel::Moderate
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::Voice,
-                &AccessLevel::Moderate
-            )
-            .is_err());
+        canvas_store
+            .send(DeleteCanvasMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "alice".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        // admins are bound to the grace period as well
+        let restored = canvas_store
+            .send(RestoreCanvasMessage {
+                canvas_id: "sketch".to_string(),
+                initiator_id: "admin".to_string(),
+                admin: true,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            restored,
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::CanvasRestoreExpired
+            ))
+        ));
 
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Write,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Voice,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_err());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::None,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_err());
+        let _ = std::fs::remove_file(log_path);
+    }
 
+    #[actix_web::test]
+    async fn test_api_tokens_are_stored_hashed() {
+        let log_path = std::env::temp_dir().join(format!("{}-canvas.jsonl", nanoid!(8)));
+        let log_path = log_path.to_str().unwrap();
+        let (_, canvas_event_log) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let clock = Arc::new(ManualClock::new(1_000));
+        let canvas_store = CanvasStore::new(
+            canvas_event_log.start().recipient(),
+            shared_canvas_events(),
+            QuotaLimits::default(),
+            clock.clone(),
+        )
+        .0
+        .start();
+
+        let (token_id, token) = tokens::generate("sketch");
+        let api_token = |access_level: AccessLevel| ApiToken {
+            id: token_id.clone(),
+            label: "ci".to_string(),
+            access_level,
+            created_by: "alice".to_string(),
+            created_at: 0,
+            expires_at: Some(2_000),
+            secret_hash: tokens::hash(&token),
+        };
+        let create = |initiator_id: &str, access_level: AccessLevel| CreateApiTokenMessage {
+            canvas_id: "sketch".to_string(),
+            initiator_id: initiator_id.to_string(),
+            token: api_token(access_level),
+        };
+        let resolve = |token: &str| ResolveApiTokenMessage {
+            canvas_id: "sketch".to_string(),
+            token_id: token_id.clone(),
+            secret_hash: tokens::hash(token),
+        };
+
+        assert!(matches!(
+            canvas_store
+                .send(create("bob", AccessLevel::Write))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(MessageKey::ApiTokenDenied))
+        ));
+        assert!(matches!(
+            canvas_store
+                .send(create("alice", AccessLevel::Moderate))
+                .await
+                .unwrap(),
+            Err(CanvasStoreError::AccessDenied(
+                MessageKey::ApiTokenAccessLevelInvalid
+            ))
+        ));
+        let created = canvas_store
+            .send(create("alice", AccessLevel::Write))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(created.created_at, 1_000);
+
+        let log = std::fs::read_to_string(log_path).unwrap();
+        let secret = token.rsplit('.').next().unwrap();
+        assert!(!log.contains(secret));
+        assert!(log.contains(&tokens::hash(&token)));
+
+        let (_, claim) = canvas_store.send(resolve(&token)).await.unwrap().unwrap();
+        assert_eq!(claim.c, "sketch");
+        assert_eq!(claim.r, AccessLevel::Write);
         assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Owner,
-                &AccessLevel::Moderate,
-                &AccessLevel::Write
-            )
-            .is_ok());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Owner,
-                &AccessLevel::None,
-                &AccessLevel::Moderate
-            )
-            .is_ok());
-        assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::Write,
-                &AccessLevel::Read
-            )
-            .is_ok());
+            .send(resolve(&format!("{token}x")))
+            .await
+            .unwrap()
+            .is_none());
+
+        // replay restores the token, the principal has the access level of the token until it expires
+        let (persisted, _) = EventLogPersistenceJson::new(log_path)
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let (state, issues) =
+            replay_events(shared_canvas_events().into_iter().chain(persisted), 1_000);
+        assert!(issues.is_empty());
+        let canvas = &state.canvases["sketch"];
+        let principal = tokens::principal_id(&token_id);
+        assert_eq!(canvas.access_level(&principal, 1_999), AccessLevel::Write);
+        assert_eq!(canvas.access_level(&principal, 2_000), AccessLevel::None);
+        // the hash never leaves the store
+        assert!(!serde_json::to_string(canvas)
+            .unwrap()
+            .contains(&tokens::hash(&token)));
+
+        clock.advance(Duration::from_millis(1_000));
+        assert!(canvas_store.send(resolve(&token)).await.unwrap().is_none());
+
+        let revoke = || RevokeApiTokenMessage {
+            canvas_id: "sketch".to_string(),
+            initiator_id: "alice".to_string(),
+            token_id: token_id.clone(),
+        };
+        assert!(canvas_store.send(revoke()).await.unwrap().unwrap());
+        assert!(!canvas_store.send(revoke()).await.unwrap().unwrap());
         assert!(canvas_store
-            .validate_permission_change(
-                &AccessLevel::Moderate,
-                &AccessLevel::Voice,
-                &AccessLevel::None
-            )
-            .is_ok());
+            .send(GetApiTokensMessage {
+                canvas_id: "sketch".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+
+        let _ = std::fs::remove_file(log_path);
     }
 }
@@ -0,0 +1,216 @@
+use nanoid::nanoid;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+use super::store::{AccessLevel, CanvasId};
+use crate::{recovery, userstore::UserId};
+
+// API tokens let scripts and bots use a single canvas without a user account
+// A token reads dct_{canvas id}.{token id}.{secret}, the CanvasStore only keeps the hash of it,
+// the plaintext is shown once to the owner that created it
+// The hash is a plain SHA-256 without a key, neither the JWT secret nor the recovery key is involved,
+// so persisted hashes stay valid when either changes, the random secret leaves nothing to guess
+// Requests with a token act as the principal token:{token id}, which has exactly one claim on the canvas
+// of the token and is only accepted by the JSON endpoints and the websocket of that canvas
+
+pub type TokenId = String;
+
+pub const TOKEN_PREFIX: &str = "dct_";
+
+/// Prefix of the user id of token principals, nanoid user ids never contain a colon
+const PRINCIPAL_PREFIX: &str = "token:";
+
+const TOKEN_ID_LENGTH: usize = 12;
+const SECRET_LENGTH: usize = 32;
+
+pub const MAX_TOKEN_LABEL_LENGTH: usize = 64;
+
+/// Requests a single token may send per window, websocket messages are limited by the canvas server
+pub const TOKEN_RATE_LIMIT: u32 = 120;
+pub const TOKEN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Token as stored in the CanvasStore, the hash is never serialized
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ApiToken {
+    pub id: TokenId,
+    pub label: String,
+    /// Read or Write, see is_grantable
+    pub access_level: AccessLevel,
+    pub created_by: UserId,
+    /// unix timestamp in milliseconds
+    pub created_at: u64,
+    /// unix timestamp in milliseconds, None never expires
+    pub expires_at: Option<u64>,
+    #[serde(skip)]
+    pub secret_hash: String,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Tokens are meant for automation, they never moderate or own a canvas
+pub fn is_grantable(access_level: &AccessLevel) -> bool {
+    matches!(access_level, AccessLevel::Read | AccessLevel::Write)
+}
+
+/// New token id and the plaintext token for the canvas
+pub fn generate(canvas_id: &str) -> (TokenId, String) {
+    let token_id = nanoid!(TOKEN_ID_LENGTH);
+    let token = format!(
+        "{TOKEN_PREFIX}{canvas_id}.{token_id}.{}",
+        nanoid!(SECRET_LENGTH)
+    );
+    (token_id, token)
+}
+
+/// Unkeyed SHA-256 of the whole token, persisted instead of the token, see recovery::hash_token
+pub fn hash(token: &str) -> String {
+    recovery::hash_token(token)
+}
+
+/// Canvas and id of a token, None if it is no canvas token
+pub fn parse(token: &str) -> Option<(CanvasId, TokenId)> {
+    let mut parts = token.strip_prefix(TOKEN_PREFIX)?.splitn(3, '.');
+    let (canvas_id, token_id, secret) = (parts.next()?, parts.next()?, parts.next()?);
+    if canvas_id.is_empty() || token_id.is_empty() || secret.is_empty() {
+        return None;
+    }
+    Some((canvas_id.to_string(), token_id.to_string()))
+}
+
+/// User id the token acts as
+pub fn principal_id(token_id: &str) -> UserId {
+    format!("{PRINCIPAL_PREFIX}{token_id}")
+}
+
+/// Token id of a principal, None for regular users
+pub fn token_id_of(user_id: &str) -> Option<&str> {
+    user_id.strip_prefix(PRINCIPAL_PREFIX)
+}
+
+/// Inserted into the request extensions next to the synthetic JWTClaims of a token
+/// Handlers serving HTML or creating anything reject requests carrying it, see AuthContext::require_user
+pub struct TokenPrincipal {
+    pub token_id: TokenId,
+    pub canvas_id: CanvasId,
+}
+
+/// Fixed window rate limit per token
+/// Shared between all workers and middleware instances using web::Data
+pub struct TokenRateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Default)]
+struct Windows {
+    /// start of the current window and the requests counted in it
+    requests: HashMap<TokenId, (Instant, u32)>,
+    /// when passed windows are swept next, None before the first request
+    next_sweep: Option<Instant>,
+}
+
+impl Default for TokenRateLimiter {
+    fn default() -> Self {
+        Self::new(TOKEN_RATE_LIMIT, TOKEN_RATE_WINDOW)
+    }
+}
+
+impl TokenRateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: Mutex::new(Windows::default()),
+        }
+    }
+
+    /// Counts the request, false once the token used up its window
+    pub fn allow(&self, token_id: &str, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+
+        // forget tokens whose window passed once per window, keeps the map bounded by active tokens
+        if windows
+            .next_sweep
+            .is_none_or(|next_sweep| now >= next_sweep)
+        {
+            windows.next_sweep = Some(now + self.window);
+            let window = self.window;
+            windows
+                .requests
+                .retain(|_, (started, _)| now.duration_since(*started) < window);
+        }
+
+        let (started, requests) = windows
+            .requests
+            .entry(token_id.to_string())
+            .or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *requests = 0;
+        }
+        *requests += 1;
+        *requests <= self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_parsed() {
+        let (token_id, token) = generate("canvas");
+        assert_eq!(parse(&token), Some(("canvas".to_string(), token_id)));
+        assert_ne!(hash(&token), token);
+
+        assert_eq!(parse("canvas.token.secret"), None);
+        assert_eq!(parse("dct_canvas.token"), None);
+        assert_eq!(parse("dct_.token.secret"), None);
+
+        let principal = principal_id("abc");
+        assert_eq!(token_id_of(&principal), Some("abc"));
+        assert_eq!(token_id_of("abc"), None);
+    }
+
+    #[test]
+    fn test_rate_limit_resets_with_the_window() {
+        let limiter = TokenRateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limiter.allow("token", start));
+        assert!(limiter.allow("token", start + Duration::from_secs(1)));
+        assert!(!limiter.allow("token", start + Duration::from_secs(2)));
+        // tokens are limited separately
+        assert!(limiter.allow("other", start + Duration::from_secs(2)));
+
+        assert!(limiter.allow("token", start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_passed_windows_are_swept_once_per_window() {
+        let limiter = TokenRateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let tracked = || limiter.windows.lock().unwrap().requests.len();
+
+        assert!(limiter.allow("token", start));
+        assert!(limiter.allow("other", start + Duration::from_secs(30)));
+        assert!(limiter.allow("third", start + Duration::from_secs(61)));
+        assert_eq!(tracked(), 2);
+
+        // the window of other passed, it is kept until the next sweep is due
+        assert!(limiter.allow("fourth", start + Duration::from_secs(100)));
+        assert_eq!(tracked(), 3);
+        assert!(limiter.allow("fourth", start + Duration::from_secs(121)));
+        assert_eq!(tracked(), 1);
+    }
+}
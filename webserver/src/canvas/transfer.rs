@@ -0,0 +1,228 @@
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream};
+use std::{borrow::Cow, io};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+use super::{
+    binding,
+    events::CanvasEvents,
+    retention::{self, EventCategory},
+};
+use crate::{encryption::LineCodec, persistence};
+
+// Raw eventlog transfer for backup tooling
+// Exports stream the persisted lines of a canvas eventlog as they are, sequence numbers are line numbers
+// Encrypted lines are exported decrypted, their envelopes are bound to the eventlog and useless anywhere else
+// Imports are deserialized line by line and written as the eventlog of a new canvas
+
+/// Largest eventlog that is exported or imported, every export can be imported again
+pub const TRANSFER_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Position of the next line to stream
+struct LineCursor {
+    /// None once the eventlog is exhausted or failed
+    reader: Option<BufReader<File>>,
+    seq: u64,
+    codec: LineCodec,
+}
+
+///
+/// Streams the complete lines of the eventlog after since_seq, up to and including until_seq
+/// A line without its line break is still being written and ends the stream, like the end of the file
+/// A missing eventlog streams nothing, canvases nobody connected to have none yet
+///
+pub async fn stream_event_log(
+    file_path: &str,
+    since_seq: u64,
+    until_seq: Option<u64>,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let reader = match File::open(file_path).await {
+        Ok(file) => Some(BufReader::new(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let cursor = LineCursor {
+        reader,
+        seq: 0,
+        codec: LineCodec::installed(file_path),
+    };
+
+    Ok(stream::unfold(cursor, move |mut cursor| async move {
+        loop {
+            if until_seq.is_some_and(|until_seq| cursor.seq >= until_seq) {
+                return None;
+            }
+            let reader = cursor.reader.as_mut()?;
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line).await {
+                Err(e) => {
+                    cursor.reader = None;
+                    return Some((Err(e), cursor));
+                }
+                Ok(_) if !line.ends_with(b"\n") => return None,
+                Ok(_) => {
+                    cursor.seq += 1;
+                    if cursor.seq > since_seq {
+                        let line = match cursor.codec.decode_raw(&line[..line.len() - 1]) {
+                            Ok(Cow::Borrowed(_)) => Ok(Bytes::from(line)),
+                            Ok(Cow::Owned(mut decoded)) => {
+                                decoded.push(b'\n');
+                                Ok(Bytes::from(decoded))
+                            }
+                            Err(e) => {
+                                cursor.reader = None;
+                                Err(e.into())
+                            }
+                        };
+                        return Some((line, cursor));
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportError {
+    Empty,
+    /// line, starting at 1, that is no persisted canvas event
+    InvalidLine(usize),
+}
+
+/// Deserializes an uploaded eventlog, blank lines are skipped and events that are never persisted are refused
+pub fn parse_import(body: &[u8]) -> Result<Vec<CanvasEvents>, ImportError> {
+    let mut event_log = Vec::new();
+    for (index, line) in body.split(|byte| *byte == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let event: CanvasEvents =
+            serde_json::from_slice(line).map_err(|_| ImportError::InvalidLine(index + 1))?;
+        if retention::category(&event) == EventCategory::Ephemeral {
+            return Err(ImportError::InvalidLine(index + 1));
+        }
+        event_log.push(event);
+    }
+    if event_log.is_empty() {
+        return Err(ImportError::Empty);
+    }
+    Ok(event_log)
+}
+
+///
+/// Writes the imported events as eventlog of the new canvas, the header of the upload is replaced
+/// Nothing else is remapped, shapes, users and sessions keep their ids
+///
+pub fn write_import(
+    file_path: &str,
+    canvas_id: &str,
+    mut event_log: Vec<CanvasEvents>,
+    timestamp: u64,
+) -> io::Result<()> {
+    binding::rebind(canvas_id, &mut event_log, timestamp);
+    persistence::rewrite_event_log(file_path, &event_log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::io::Write;
+
+    fn joined(session_id: &str) -> String {
+        format!(
+            r#"{{"type":"UserJoined","timestamp":1,"userId":"u1","sessionId":"{session_id}","username":"u1","accessLevel":"Owner"}}"#
+        )
+    }
+
+    async fn exported(file_path: &str, since_seq: u64, until_seq: Option<u64>) -> Vec<String> {
+        stream_event_log(file_path, since_seq, until_seq)
+            .await
+            .unwrap()
+            .map(|line| String::from_utf8(line.unwrap().to_vec()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_stream_stops_at_snapshot_while_written() {
+        let path = std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        let mut file = std::fs::File::create(&path).unwrap();
+        for session in 0..3 {
+            writeln!(file, "{}", joined(&format!("s{session}"))).unwrap();
+        }
+
+        // the writer keeps appending after the snapshot of three persisted lines
+        let writer = std::thread::spawn(move || {
+            for session in 3..500 {
+                writeln!(file, "{}", joined(&format!("s{session}"))).unwrap();
+            }
+            // torn tail of a line that is still being written
+            write!(file, "{{\"type\":\"UserJoined\"").unwrap();
+        });
+        let lines = exported(&path, 0, Some(3)).await;
+        writer.join().unwrap();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], format!("{}\n", joined("s2")));
+
+        // without a snapshot every complete line is streamed, the torn tail never is
+        let lines = exported(&path, 0, None).await;
+        assert_eq!(lines.len(), 500);
+        assert!(lines.iter().all(|line| line.ends_with("}\n")));
+
+        let lines = exported(&path, 498, None).await;
+        assert_eq!(
+            lines,
+            vec![
+                format!("{}\n", joined("s498")),
+                format!("{}\n", joined("s499"))
+            ]
+        );
+
+        assert!(exported("missing.jsonl", 0, None).await.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_import_is_bound_to_new_canvas() {
+        let upload = format!(
+            "{}\n\n{}\n",
+            serde_json::to_string(&binding::header("aaaaaaaaaaaa", 0)).unwrap(),
+            joined("s1")
+        );
+        let event_log = parse_import(upload.as_bytes()).unwrap();
+        assert_eq!(event_log.len(), 2);
+
+        let path = std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        write_import(&path, "bbbbbbbbbbbb", event_log, 5).unwrap();
+        assert_eq!(
+            binding::read_claimed_id(&path).unwrap().as_deref(),
+            Some("bbbbbbbbbbbb")
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(parse_import(b"\n \n").unwrap_err(), ImportError::Empty);
+        let garbage = format!("{}\nnot json\n", joined("s1"));
+        assert_eq!(
+            parse_import(garbage.as_bytes()).unwrap_err(),
+            ImportError::InvalidLine(2)
+        );
+        let ack = r#"{"type":"Ack","timestamp":1,"opId":"op","seq":1}"#;
+        assert_eq!(
+            parse_import(ack.as_bytes()).unwrap_err(),
+            ImportError::InvalidLine(1)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use super::{
+    attributes::{self, AttributeError, AttributeLimits, ShapeAttributes},
+    events::{CanvasEvents, Shape},
+};
+use crate::messages::{Message, MessageKey};
+use serde_json::Value;
+
+// Validation of client supplied canvas events
+// Checks run before an event is persisted or broadcast, invalid events are dropped
+// Limits keep a single client from bloating the eventlog and the initial state of every other client
+
+/// Limits applied to shapes send by clients
+#[derive(Debug, Clone)]
+pub struct ShapeLimits {
+    /// size of a single raw event message in bytes
+    pub max_event_bytes: usize,
+    /// points of a single path, checked before simplification
+    pub max_path_points: usize,
+    /// Douglas-Peucker epsilon in pixels used when persisting paths, 0 disables simplification
+    pub path_simplify_epsilon: f64,
+    pub attributes: AttributeLimits,
+    /// shapes a session may have selected at the same time, further selections are rejected
+    pub max_selected_shapes: usize,
+    /// temporary shapes a session may draw at the same time, the oldest one is removed beyond
+    pub max_temp_shapes_per_session: usize,
+    /// temporary shapes of all sessions of a canvas, the oldest one is removed beyond
+    pub max_temp_shapes_per_canvas: usize,
+}
+
+impl Default for ShapeLimits {
+    fn default() -> Self {
+        Self {
+            max_event_bytes: 64 * 1024,
+            max_path_points: 2_000,
+            path_simplify_epsilon: 1.5,
+            attributes: AttributeLimits::default(),
+            max_selected_shapes: 50,
+            max_temp_shapes_per_session: 20,
+            max_temp_shapes_per_canvas: 200,
+        }
+    }
+}
+
+/// Shape ids are generated by clients, they are kept short and safe to embed in urls and markup
+pub const MAX_SHAPE_ID_LENGTH: usize = 64;
+
+pub fn is_valid_shape_id(shape_id: &str) -> bool {
+    !shape_id.is_empty()
+        && shape_id.len() <= MAX_SHAPE_ID_LENGTH
+        && shape_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Reason a client event was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventRejection {
+    TooLarge { bytes: usize },
+    TooManyPoints { points: usize },
+    InvalidShapeId,
+    InvalidAttributes(AttributeError),
+}
+
+impl EventRejection {
+    /// Feedback for the client that sent the event
+    pub fn message(&self) -> Message {
+        match self {
+            EventRejection::TooLarge { bytes } => {
+                Message::new(MessageKey::EventTooLarge).param("bytes", bytes)
+            }
+            EventRejection::TooManyPoints { points } => {
+                Message::new(MessageKey::EventTooManyPoints).param("points", points)
+            }
+            EventRejection::InvalidShapeId => Message::new(MessageKey::EventShapeIdInvalid)
+                .param("max_length", MAX_SHAPE_ID_LENGTH),
+            EventRejection::InvalidAttributes(error) => match error {
+                AttributeError::Unknown(key) => {
+                    Message::new(MessageKey::EventAttributeUnknown).param("key", key)
+                }
+                AttributeError::Invalid(key) => {
+                    Message::new(MessageKey::EventAttributeInvalid).param("key", key)
+                }
+                AttributeError::TooMany { count } => {
+                    Message::new(MessageKey::EventAttributesTooLarge).param("count", count)
+                }
+                AttributeError::TooLarge { bytes } => {
+                    Message::new(MessageKey::EventAttributesTooLarge).param("bytes", bytes)
+                }
+            },
+        }
+    }
+}
+
+/// Checks the raw message before it is deserialized
+pub fn validate_message(msg: &str, limits: &ShapeLimits) -> Result<(), EventRejection> {
+    if msg.len() > limits.max_event_bytes {
+        return Err(EventRejection::TooLarge { bytes: msg.len() });
+    }
+    Ok(())
+}
+
+/// Checks the shapes contained in an event
+pub fn validate_event(event: &CanvasEvents, limits: &ShapeLimits) -> Result<(), EventRejection> {
+    if let CanvasEvents::ShapeAdded { shape, .. } = event {
+        if !is_valid_shape_id(shape.get_id()) {
+            return Err(EventRejection::InvalidShapeId);
+        }
+        attributes::validate_attributes(shape.attributes(), &limits.attributes)
+            .map_err(EventRejection::InvalidAttributes)?;
+    }
+
+    // updates replace the whole map, it has to be valid on its own
+    if let CanvasEvents::ShapeUpdated { shape, .. } = event {
+        if let Some(value) = shape.get("attributes") {
+            let attributes: ShapeAttributes =
+                serde_json::from_value(value.clone()).map_err(|_| {
+                    EventRejection::InvalidAttributes(AttributeError::Invalid(
+                        "attributes".to_string(),
+                    ))
+                })?;
+            attributes::validate_attributes(&attributes, &limits.attributes)
+                .map_err(EventRejection::InvalidAttributes)?;
+        }
+    }
+
+    let points = match event {
+        CanvasEvents::ShapeAdded {
+            shape: Shape::Path { points, .. },
+            ..
+        } => points.len(),
+        // updates are partial shapes, only the points matter here
+        CanvasEvents::ShapeUpdated { shape, .. } => shape
+            .get("points")
+            .and_then(Value::as_array)
+            .map_or(0, Vec::len),
+        _ => 0,
+    };
+
+    if points > limits.max_path_points {
+        return Err(EventRejection::TooManyPoints { points });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::events::Point2D;
+    use serde_json::json;
+
+    fn path_added(points: usize) -> CanvasEvents {
+        CanvasEvents::ShapeAdded {
+            origin: "session".to_string(),
+            timestamp: 0,
+            shape: Shape::Path {
+                id: "path".to_string(),
+                temporary: false,
+                borderColor: "#000".to_string(),
+                fillColor: "transparent".to_string(),
+                attributes: Default::default(),
+                points: (0..points as i32).map(|i| Point2D { x: i, y: i }).collect(),
+                closed: false,
+            },
+            userId: None,
+        }
+    }
+
+    #[test]
+    fn test_oversized_paths_are_rejected() {
+        let limits = ShapeLimits::default();
+
+        assert_eq!(validate_event(&path_added(2_000), &limits), Ok(()));
+        assert_eq!(
+            validate_event(&path_added(2_001), &limits),
+            Err(EventRejection::TooManyPoints { points: 2_001 })
+        );
+
+        let update = CanvasEvents::ShapeUpdated {
+            origin: "session".to_string(),
+            timestamp: 0,
+            shape: json!({ "id": "path", "points": vec![json!({"x": 0, "y": 0}); 2_001] }),
+            userId: None,
+        };
+        assert!(validate_event(&update, &limits).is_err());
+
+        let message = serde_json::to_string(&path_added(10_000)).unwrap();
+        assert!(matches!(
+            validate_message(&message, &limits),
+            Err(EventRejection::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_path_serde_round_trip() {
+        let message = r##"{"type":"ShapeAdded","origin":"s1","timestamp":1,"shape":{"type":"Path","id":"p1","temporary":true,"borderColor":"#000","fillColor":"transparent","points":[{"x":0,"y":0},{"x":5,"y":3}],"closed":true}}"##;
+
+        let event: CanvasEvents = serde_json::from_str(message).unwrap();
+        let CanvasEvents::ShapeAdded { shape, .. } = &event else {
+            panic!("expected ShapeAdded");
+        };
+        assert_eq!(shape.get_id(), "p1");
+        assert!(shape.is_temporary());
+
+        let round_trip: Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(round_trip, serde_json::from_str::<Value>(message).unwrap());
+    }
+
+    #[test]
+    fn test_shape_attributes_round_trip() {
+        let attributes = r##""attributes":{"strokeWidth":{"Number":2.5},"dashPattern":{"Text":"4 2"},"glow":{"Color":"#ff000080"},"hidden":{"Bool":false}}"##;
+        for geometry in [
+            r#""type":"Line","from":{"x":0,"y":0},"to":{"x":5,"y":3}"#,
+            r#""type":"Circle","center":{"x":0,"y":0},"radius":4.5"#,
+            r#""type":"Rectangle","from":{"x":0,"y":0},"to":{"x":5,"y":3}"#,
+            r#""type":"Triangle","p1":{"x":0,"y":0},"p2":{"x":5,"y":3},"p3":{"x":1,"y":7}"#,
+            r#""type":"Path","points":[{"x":0,"y":0},{"x":5,"y":3}],"closed":false"#,
+        ] {
+            let message = format!(
+                r##"{{"type":"ShapeAdded","origin":"s1","timestamp":1,"shape":{{{geometry},"id":"s1","temporary":false,"borderColor":"#000","fillColor":"#000",{attributes}}}}}"##
+            );
+            let event: CanvasEvents = serde_json::from_str(&message).unwrap();
+            let CanvasEvents::ShapeAdded { shape, .. } = &event else {
+                panic!("expected ShapeAdded");
+            };
+            assert_eq!(shape.attributes().len(), 4, "{geometry}");
+
+            let round_trip: Value = serde_json::to_value(&event).unwrap();
+            assert_eq!(round_trip, serde_json::from_str::<Value>(&message).unwrap());
+        }
+
+        // lines written before attributes existed read as an empty map and are written back unchanged
+        let legacy = r##"{"type":"ShapeAdded","origin":"s1","timestamp":1,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}"##;
+        let event: CanvasEvents = serde_json::from_str(legacy).unwrap();
+        assert_eq!(serde_json::to_string(&event).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_shape_attributes_are_validated() {
+        let limits = ShapeLimits::default();
+        let mut event = path_added(2);
+        if let CanvasEvents::ShapeAdded {
+            shape: Shape::Path { attributes, .. },
+            ..
+        } = &mut event
+        {
+            attributes.insert(
+                "opacity".to_string(),
+                attributes::AttributeValue::Number(0.5),
+            );
+        }
+        assert_eq!(validate_event(&event, &limits), Ok(()));
+
+        if let CanvasEvents::ShapeAdded {
+            shape: Shape::Path { attributes, .. },
+            ..
+        } = &mut event
+        {
+            attributes.insert(
+                "glow".to_string(),
+                attributes::AttributeValue::Color("red;".to_string()),
+            );
+        }
+        assert_eq!(
+            validate_event(&event, &limits),
+            Err(EventRejection::InvalidAttributes(AttributeError::Invalid(
+                "glow".to_string()
+            )))
+        );
+
+        let update = |attributes: Value| CanvasEvents::ShapeUpdated {
+            origin: "session".to_string(),
+            timestamp: 0,
+            shape: json!({ "id": "path", "attributes": attributes }),
+            userId: None,
+        };
+        assert_eq!(
+            validate_event(&update(json!({ "rotation": { "Number": 45.0 } })), &limits),
+            Ok(())
+        );
+        assert_eq!(
+            validate_event(&update(json!({ "glow": { "Bool": true } })), &limits),
+            Err(EventRejection::InvalidAttributes(AttributeError::Unknown(
+                "glow".to_string()
+            )))
+        );
+        // the untyped update can't sneak in values of an unknown type
+        assert!(validate_event(&update(json!({ "opacity": 0.5 })), &limits).is_err());
+    }
+
+    #[test]
+    fn test_shape_ids_are_validated() {
+        assert!(is_valid_shape_id("l1"));
+        assert!(is_valid_shape_id("V1StGXR8_Z5jdHi6B-myT"));
+        assert!(is_valid_shape_id(&"a".repeat(MAX_SHAPE_ID_LENGTH)));
+        assert!(!is_valid_shape_id(""));
+        assert!(!is_valid_shape_id(&"a".repeat(MAX_SHAPE_ID_LENGTH + 1)));
+        assert!(!is_valid_shape_id("<script>"));
+        assert!(!is_valid_shape_id("id with spaces"));
+    }
+}
@@ -0,0 +1,406 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::{
+    coalesce::updated_shape_id,
+    events::{Bounds, CanvasEvents, Shape},
+};
+
+// Viewport scoped delivery of shape events, on huge boards most sessions only show a small part of the canvas
+// Sessions opt in by sending ViewportChanged, sessions that never declared a viewport receive every event
+// Shape events outside of the viewport of a session are skipped and the shape is remembered as unsent,
+// once the viewport moves onto it the session gets the current shape as ShapeAdded, clients replace a known id
+// Events without computable geometry, e.g. removals, selections or updates of unknown shapes, are always delivered
+// The live shapes and their bounds are only folded while a session of the canvas has a viewport
+
+/// Viewport changes a session may send within VIEWPORT_WINDOW_MS
+/// A change above the cap is rejected and the session receives every event until its next accepted change
+pub const MAX_VIEWPORT_CHANGES_PER_WINDOW: usize = 20;
+pub const VIEWPORT_WINDOW_MS: u64 = 1_000;
+
+/// Unsent shapes remembered per session, beyond it the next viewport change resends the whole state
+pub const MAX_UNSENT_SHAPES: usize = 10_000;
+
+/// Shape of an event whose delivery depends on the viewport
+pub fn filtered_shape_id(event: &CanvasEvents) -> Option<&str> {
+    match event {
+        CanvasEvents::ShapeAdded { shape, .. } => Some(shape.get_id()),
+        CanvasEvents::ShapeUpdated { .. } => updated_shape_id(event),
+        CanvasEvents::ShapeZChanged { shapeId, .. } => Some(shapeId),
+        _ => None,
+    }
+}
+
+/// Bounds of a shape as sent by the clients, None if the merged shape is no longer a valid shape
+fn shape_bounds(shape: &Value) -> Option<Bounds> {
+    Shape::deserialize(shape)
+        .ok()
+        .map(|shape| shape.bounds().into())
+}
+
+#[derive(Debug)]
+struct LiveShape {
+    shape: Value,
+    bounds: Option<Bounds>,
+    /// last z change, sent after the shape on catch-up
+    z: Option<Value>,
+    /// order the shapes were added in, catch-up keeps the stacking of the eventlog
+    order: u64,
+}
+
+/// Current state of the shapes of a canvas with their bounds
+#[derive(Debug, Default)]
+pub struct LiveShapes {
+    shapes: HashMap<String, LiveShape>,
+    added: u64,
+}
+
+impl LiveShapes {
+    /// Folds the eventlog, buffered updates have to be persisted before
+    pub fn from_log(event_log: &[CanvasEvents]) -> Self {
+        let mut live = Self::default();
+        for event in event_log {
+            live.apply(event);
+        }
+        live
+    }
+
+    ///
+    /// Applies an event sent to the sessions, returns the area a filtered event touches
+    /// An update touches the shape before and after the change, a shape leaving a viewport is still delivered
+    /// None if the event is not filtered or the geometry of its shape is unknown
+    ///
+    pub fn apply(&mut self, event: &CanvasEvents) -> Option<Bounds> {
+        match event {
+            CanvasEvents::ShapeAdded { shape, .. } => {
+                let bounds = Bounds::from(shape.bounds());
+                let value = serde_json::to_value(shape).ok()?;
+                self.added += 1;
+                let live = LiveShape {
+                    shape: value,
+                    bounds: Some(bounds),
+                    z: None,
+                    order: self.added,
+                };
+                self.shapes.insert(shape.get_id().to_string(), live);
+                Some(bounds)
+            }
+            CanvasEvents::ShapeUpdated { shape: update, .. } => {
+                let live = self.shapes.get_mut(updated_shape_id(event)?)?;
+                let before = live.bounds;
+                if let (Some(target), Some(update)) =
+                    (live.shape.as_object_mut(), update.as_object())
+                {
+                    target.extend(
+                        update
+                            .iter()
+                            .map(|(key, value)| (key.clone(), value.clone())),
+                    );
+                }
+                live.bounds = shape_bounds(&live.shape);
+                Some(before?.union(&live.bounds?))
+            }
+            CanvasEvents::ShapeZChanged { shapeId, z, .. } => {
+                let live = self.shapes.get_mut(shapeId)?;
+                live.z = Some(z.clone());
+                live.bounds
+            }
+            CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                self.shapes.remove(shapeId);
+                None
+            }
+            CanvasEvents::CanvasCleared { .. } => {
+                self.shapes.clear();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, shape_id: &str) -> bool {
+        self.shapes.contains_key(shape_id)
+    }
+
+    /// Current shape and last z change
+    pub fn get(&self, shape_id: &str) -> Option<(&Value, Option<&Value>)> {
+        self.shapes
+            .get(shape_id)
+            .map(|live| (&live.shape, live.z.as_ref()))
+    }
+
+    /// Shapes among shape_ids within the bounds, in the order they were added
+    fn visible<'a>(
+        &self,
+        bounds: &Bounds,
+        shape_ids: impl Iterator<Item = &'a String>,
+    ) -> Vec<String> {
+        let mut visible: Vec<(u64, &String)> = shape_ids
+            .filter_map(|shape_id| {
+                let live = self.shapes.get(shape_id)?;
+                live.bounds
+                    .is_some_and(|shape| shape.intersects(bounds))
+                    .then_some((live.order, shape_id))
+            })
+            .collect();
+        visible.sort_unstable();
+        visible
+            .into_iter()
+            .map(|(_, shape_id)| shape_id.clone())
+            .collect()
+    }
+}
+
+/// What a session needs after its viewport changed
+#[derive(Debug, PartialEq, Eq)]
+pub enum CatchUp {
+    /// unsent shapes that became visible, in the order they were added
+    Shapes(Vec<String>),
+    /// too many shapes were skipped to remember them, the whole state is sent again
+    FullResync,
+}
+
+/// Viewport of a session and the shapes it was not sent
+#[derive(Debug, Default)]
+pub struct SessionViewport {
+    /// None until declared or after a rejected change, the session then receives every event
+    bounds: Option<Bounds>,
+    /// shapes whose current state the session was not sent
+    unsent: HashSet<String>,
+    /// more than MAX_UNSENT_SHAPES were skipped, unsent is no longer complete
+    overflowed: bool,
+    /// times of the recent changes, in milliseconds
+    changes: VecDeque<u64>,
+}
+
+impl SessionViewport {
+    pub fn bounds(&self) -> Option<&Bounds> {
+        self.bounds.as_ref()
+    }
+
+    ///
+    /// Whether the event is sent to the session, touched is the area the event changes
+    /// A skipped shape is remembered as unsent, only a ShapeAdded brings the session up to date again
+    ///
+    pub fn deliver(&mut self, event: &CanvasEvents, touched: Option<&Bounds>) -> bool {
+        let Some(shape_id) = filtered_shape_id(event) else {
+            match event {
+                CanvasEvents::ShapeRemoved { shapeId, .. } => {
+                    self.unsent.remove(shapeId);
+                }
+                CanvasEvents::CanvasCleared { .. } => self.unsent.clear(),
+                _ => (),
+            }
+            return true;
+        };
+
+        match (&self.bounds, touched) {
+            (Some(bounds), Some(touched)) if !bounds.intersects(touched) => {
+                if !self.overflowed {
+                    self.unsent.insert(shape_id.to_string());
+                    if self.unsent.len() > MAX_UNSENT_SHAPES {
+                        self.unsent.clear();
+                        self.overflowed = true;
+                    }
+                }
+                false
+            }
+            _ => {
+                if let CanvasEvents::ShapeAdded { .. } = event {
+                    self.unsent.remove(shape_id);
+                }
+                true
+            }
+        }
+    }
+
+    ///
+    /// Moves the viewport, false if the session changed it too often
+    /// A rejected change drops the viewport, the session receives every event until its next accepted change
+    ///
+    pub fn change(&mut self, now_ms: u64, bounds: Bounds) -> bool {
+        while self
+            .changes
+            .front()
+            .is_some_and(|time| *time + VIEWPORT_WINDOW_MS <= now_ms)
+        {
+            self.changes.pop_front();
+        }
+        if self.changes.len() >= MAX_VIEWPORT_CHANGES_PER_WINDOW {
+            self.bounds = None;
+            return false;
+        }
+        self.changes.push_back(now_ms);
+        self.bounds = Some(bounds);
+        true
+    }
+
+    /// Shapes to send after an accepted change, they are no longer unsent
+    pub fn catch_up(&mut self, live: &LiveShapes) -> CatchUp {
+        if std::mem::take(&mut self.overflowed) {
+            self.unsent.clear();
+            return CatchUp::FullResync;
+        }
+        // removals skip the session that sent them
+        self.unsent.retain(|shape_id| live.contains(shape_id));
+        let Some(bounds) = self.bounds else {
+            return CatchUp::Shapes(Vec::new());
+        };
+        let visible = live.visible(&bounds, self.unsent.iter());
+        for shape_id in &visible {
+            self.unsent.remove(shape_id);
+        }
+        CatchUp::Shapes(visible)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::events::Point2D;
+    use serde_json::json;
+
+    fn bounds(from: (i32, i32), to: (i32, i32)) -> Bounds {
+        Bounds {
+            from: Point2D {
+                x: from.0,
+                y: from.1,
+            },
+            to: Point2D { x: to.0, y: to.1 },
+        }
+    }
+
+    fn rectangle(id: &str, from: (i32, i32), to: (i32, i32)) -> CanvasEvents {
+        serde_json::from_value(json!({
+            "type": "ShapeAdded",
+            "origin": "session",
+            "timestamp": 1,
+            "shape": {
+                "type": "Rectangle",
+                "id": id,
+                "temporary": false,
+                "borderColor": "#000",
+                "fillColor": "#fff",
+                "from": { "x": from.0, "y": from.1 },
+                "to": { "x": to.0, "y": to.1 },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn moved(id: &str, from: (i32, i32), to: (i32, i32)) -> CanvasEvents {
+        CanvasEvents::ShapeUpdated {
+            origin: "session".to_string(),
+            timestamp: 2,
+            shape: json!({ "id": id, "from": { "x": from.0, "y": from.1 }, "to": { "x": to.0, "y": to.1 } }),
+            userId: None,
+        }
+    }
+
+    #[test]
+    fn test_bounds_intersect_with_corners_in_any_order() {
+        let viewport = bounds((100, 100), (0, 0));
+        assert!(viewport.intersects(&bounds((50, 50), (60, 60))));
+        assert!(viewport.intersects(&bounds((100, 100), (200, 200))));
+        assert!(viewport.intersects(&bounds((-50, 50), (500, 60))));
+        assert!(!viewport.intersects(&bounds((101, 0), (200, 100))));
+        assert!(!viewport.intersects(&bounds((0, -10), (100, -1))));
+        assert_eq!(
+            bounds((10, 10), (0, 0)).union(&bounds((5, 20), (30, 15))),
+            bounds((0, 0), (30, 20))
+        );
+    }
+
+    #[test]
+    fn test_events_outside_of_the_viewport_are_skipped() {
+        let mut live = LiveShapes::default();
+        let mut session = SessionViewport::default();
+        assert!(session.change(0, bounds((0, 0), (100, 100))));
+
+        let inside = rectangle("inside", (10, 10), (20, 20));
+        let touched = live.apply(&inside);
+        assert!(session.deliver(&inside, touched.as_ref()));
+        let outside = rectangle("outside", (500, 500), (600, 600));
+        let touched = live.apply(&outside);
+        assert!(!session.deliver(&outside, touched.as_ref()));
+
+        // a shape dragged out of the viewport is still delivered, dragged back in as well
+        let drag_out = moved("inside", (300, 300), (310, 310));
+        let touched = live.apply(&drag_out);
+        assert!(session.deliver(&drag_out, touched.as_ref()));
+        let drag_in = moved("outside", (50, 50), (60, 60));
+        let touched = live.apply(&drag_in);
+        assert!(session.deliver(&drag_in, touched.as_ref()));
+
+        // without geometry the event is delivered
+        let unknown = moved("unknown", (900, 900), (910, 910));
+        assert_eq!(live.apply(&unknown), None);
+        assert!(session.deliver(&unknown, None));
+        let removed = CanvasEvents::ShapeRemoved {
+            origin: "session".to_string(),
+            timestamp: 3,
+            shapeId: "outside".to_string(),
+        };
+        assert_eq!(live.apply(&removed), None);
+        assert!(session.deliver(&removed, None));
+    }
+
+    #[test]
+    fn test_catch_up_sends_exactly_the_missing_shapes() {
+        let mut live = LiveShapes::default();
+        let mut session = SessionViewport::default();
+        assert!(session.change(0, bounds((0, 0), (100, 100))));
+
+        for event in [
+            rectangle("a", (10, 10), (20, 20)),
+            rectangle("b", (500, 10), (520, 20)),
+            rectangle("c", (1000, 10), (1020, 20)),
+            rectangle("d", (510, 50), (530, 60)),
+        ] {
+            let touched = live.apply(&event);
+            session.deliver(&event, touched.as_ref());
+        }
+        let update = moved("d", (520, 50), (540, 60));
+        let touched = live.apply(&update);
+        assert!(!session.deliver(&update, touched.as_ref()));
+
+        assert!(session.change(10, bounds((400, 0), (600, 100))));
+        assert_eq!(
+            session.catch_up(&live),
+            CatchUp::Shapes(vec!["b".to_string(), "d".to_string()])
+        );
+        // the moved shape is sent as it is now
+        assert_eq!(
+            live.get("d").unwrap().0["from"],
+            json!({ "x": 520, "y": 50 })
+        );
+
+        // shapes already sent are not sent again
+        assert!(session.change(20, bounds((0, 0), (1100, 100))));
+        assert_eq!(
+            session.catch_up(&live),
+            CatchUp::Shapes(vec!["c".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_sessions_without_viewport_receive_everything() {
+        let mut live = LiveShapes::default();
+        let mut session = SessionViewport::default();
+        let far_away = rectangle("far", (5000, 5000), (5100, 5100));
+        let touched = live.apply(&far_away);
+        assert!(session.deliver(&far_away, touched.as_ref()));
+
+        // changing too often drops the viewport instead of leaving the session stale
+        for change in 0..MAX_VIEWPORT_CHANGES_PER_WINDOW as u64 {
+            assert!(session.change(change, bounds((0, 0), (10, 10))));
+        }
+        assert!(!session.change(50, bounds((0, 0), (10, 10))));
+        assert_eq!(session.bounds(), None);
+        let update = moved("far", (5000, 5000), (5200, 5200));
+        let touched = live.apply(&update);
+        assert!(session.deliver(&update, touched.as_ref()));
+
+        assert!(session.change(VIEWPORT_WINDOW_MS, bounds((0, 0), (10, 10))));
+    }
+}
@@ -0,0 +1,186 @@
+use actix_web::{web, HttpRequest};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// Source of wall clock time
+// Stores, the canvas server, handlers and the JWT middleware take their timestamps from the same Clock
+// Tests use a ManualClock to check expiry without sleeping
+// Durations measured with Instant (heartbeats, cooldowns, caches) stay on the monotonic clock
+
+pub trait Clock: Debug + Send + Sync {
+    /// Unix timestamp in milliseconds
+    fn now_ms(&self) -> u64;
+
+    /// Unix timestamp in seconds
+    fn now_secs(&self) -> u64 {
+        self.now_ms() / 1000
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        chrono::Utc::now().timestamp_millis() as u64 // timestamp will never be before 1970
+    }
+}
+
+/// Clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now_ms: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            now_ms: AtomicU64::new(now_ms),
+        }
+    }
+
+    /// Starts at the current time, tokens and eventlogs of the test stay plausible
+    pub fn starting_now() -> Self {
+        Self::new(SystemClock.now_ms())
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_ms
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+///
+/// Timestamps of persisted events, strictly increasing milliseconds even if the wall clock steps back
+/// An NTP step or a resumed VM starts a regression episode, stamps continue at last + 1 until the clock catches up
+/// Every episode is counted and logged once, the sequence number of an event stays the authoritative order
+///
+#[derive(Debug)]
+pub struct MonotonicStamps {
+    clock: SharedClock,
+    last_stamp_ms: Option<u64>,
+    last_clock_ms: u64,
+    regressing: bool,
+    regressions: u64,
+}
+
+impl MonotonicStamps {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            last_stamp_ms: None,
+            last_clock_ms: 0,
+            regressing: false,
+            regressions: 0,
+        }
+    }
+
+    /// Next stamp in milliseconds, later than every previous stamp
+    pub fn stamp_ms(&mut self) -> u64 {
+        let now = self.clock.now_ms();
+        let Some(last_stamp) = self.last_stamp_ms else {
+            self.last_stamp_ms = Some(now);
+            self.last_clock_ms = now;
+            return now;
+        };
+
+        if now < self.last_clock_ms && !self.regressing {
+            self.regressing = true;
+            self.regressions += 1;
+            println!(
+                "WARNING: clock stepped back by {}ms, timestamps continue from the last stamp",
+                self.last_clock_ms - now
+            );
+        }
+        self.last_clock_ms = now;
+
+        let stamp = if now > last_stamp {
+            self.regressing = false;
+            now
+        } else {
+            last_stamp + 1
+        };
+        self.last_stamp_ms = Some(stamp);
+        stamp
+    }
+
+    /// Next stamp in seconds, never earlier than a previous stamp
+    pub fn stamp_secs(&mut self) -> u64 {
+        self.stamp_ms() / 1000
+    }
+
+    /// Regression episodes since the stamps were created
+    pub fn regressions(&self) -> u64 {
+        self.regressions
+    }
+}
+
+/// Clock registered in the app data, the system clock if none is registered
+pub fn request_clock(request: &HttpRequest) -> SharedClock {
+    request
+        .app_data::<web::Data<dyn Clock>>()
+        .map_or_else(system, |clock| clock.clone().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(1_500);
+        assert_eq!(clock.now_secs(), 1);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now_ms(), 3_500);
+        clock.set(0);
+        assert_eq!(clock.now_ms(), 0);
+    }
+
+    #[test]
+    fn test_stamps_survive_a_clock_regression() {
+        let clock = Arc::new(ManualClock::new(100_000));
+        let mut stamps = MonotonicStamps::new(clock.clone());
+        let mut stamped = vec![stamps.stamp_ms(), stamps.stamp_ms()];
+
+        // ten seconds back, stamped every 100ms until the clock catches up again
+        clock.set(90_000);
+        for _ in 0..150 {
+            stamped.push(stamps.stamp_ms());
+            clock.advance(Duration::from_millis(100));
+        }
+        assert!(stamped.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(stamped[2], 100_002);
+        assert_eq!(*stamped.last().unwrap(), 104_900);
+        assert_eq!(stamps.regressions(), 1);
+
+        // a second step back is a new episode, a step within an episode is not
+        clock.set(50_000);
+        stamps.stamp_ms();
+        clock.set(40_000);
+        stamps.stamp_ms();
+        assert_eq!(stamps.regressions(), 2);
+        assert!(stamps.stamp_secs() >= 104);
+    }
+}
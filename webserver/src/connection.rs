@@ -0,0 +1,195 @@
+use actix_web::{http::header, web, HttpRequest};
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use utoipa::ToSchema;
+
+// Metadata of the HTTP connection a websocket session was opened from
+// Used to trace abuse back to its origin, it is never part of the canvas events sent to clients
+// Behind a reverse proxy the peer address is the proxy, the client is taken from X-Forwarded-For
+// X-Forwarded-For is only trusted if the peer is a configured trusted proxy, anyone can send the header
+
+/// User agents are cut off after this many chars
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Reverse proxies whose X-Forwarded-For header is trusted
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(HashSet<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self(proxies.into_iter().collect())
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+
+    ///
+    /// Address of the client, the first address from the right of the forwarding chain that is not a trusted proxy
+    /// The header is ignored unless the peer itself is trusted, unparsable entries end the chain at the last known hop
+    ///
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?;
+        let Some(forwarded_for) = forwarded_for.filter(|_| self.is_trusted(&peer)) else {
+            return Some(peer);
+        };
+
+        let mut client = peer;
+        for hop in forwarded_for.rsplit(',') {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = hop;
+            if !self.is_trusted(&hop) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Origin of a websocket session
+/// Serialized with the address masked to its network, admins use unmasked to see the full address
+#[derive(Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct ConnectionMeta {
+    /// masked to its network, e.g. 203.0.113.0/24
+    #[schema(value_type = Option<String>)]
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl ConnectionMeta {
+    /// Without configured trusted proxies the peer address of the request is used
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let forwarded_for = request
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok());
+        let ip = request
+            .app_data::<web::Data<TrustedProxies>>()
+            .map(|proxies| proxies.get_ref().clone())
+            .unwrap_or_default()
+            .client_ip(
+                request.peer_addr().map(|address| address.ip()),
+                forwarded_for,
+            );
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+        Self { ip, user_agent }
+    }
+
+    pub fn unmasked(&self) -> UnmaskedConnection<'_> {
+        UnmaskedConnection {
+            ip: self.ip,
+            user_agent: self.user_agent.as_deref(),
+        }
+    }
+}
+
+impl Serialize for ConnectionMeta {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Masked<'a> {
+            ip: Option<String>,
+            user_agent: Option<&'a str>,
+        }
+
+        Masked {
+            ip: self.ip.map(mask_ip),
+            user_agent: self.user_agent.as_deref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Connection with the full address, only handed out to admins
+#[derive(Serialize, Debug)]
+pub struct UnmaskedConnection<'a> {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<&'a str>,
+}
+
+/// Network of the address, /24 for IPv4 and /48 for IPv6
+pub fn mask_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_forwarded_for_is_only_trusted_from_proxies() {
+        let untrusted = TrustedProxies::default();
+        let proxied = TrustedProxies::new([ip("10.0.0.1"), ip("10.0.0.2")]);
+        let chain = Some("198.51.100.7, 203.0.113.9, 10.0.0.2");
+
+        // a client can't claim another address by sending the header itself
+        assert_eq!(
+            untrusted.client_ip(Some(ip("10.0.0.1")), chain),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            proxied.client_ip(Some(ip("192.0.2.1")), chain),
+            Some(ip("192.0.2.1"))
+        );
+
+        // the rightmost untrusted hop, entries left of it are forgeable
+        assert_eq!(
+            proxied.client_ip(Some(ip("10.0.0.1")), chain),
+            Some(ip("203.0.113.9"))
+        );
+        assert_eq!(
+            proxied.client_ip(Some(ip("10.0.0.1")), None),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            proxied.client_ip(Some(ip("10.0.0.1")), Some("unknown, 10.0.0.2")),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(
+            proxied.client_ip(Some(ip("10.0.0.1")), Some(" 2001:db8::1 ")),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(proxied.client_ip(None, chain), None);
+    }
+
+    #[test]
+    fn test_addresses_are_masked_to_their_network() {
+        assert_eq!(mask_ip(ip("203.0.113.9")), "203.0.113.0/24");
+        assert_eq!(mask_ip(ip("2001:db8:85a3:8d3::1")), "2001:db8:85a3::/48");
+
+        let connection = ConnectionMeta {
+            ip: Some(ip("203.0.113.9")),
+            user_agent: Some("Firefox".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&connection).unwrap(),
+            serde_json::json!({ "ip": "203.0.113.0/24", "user_agent": "Firefox" })
+        );
+        assert_eq!(
+            serde_json::to_value(connection.unmasked()).unwrap(),
+            serde_json::json!({ "ip": "203.0.113.9", "user_agent": "Firefox" })
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use actix_web::{web, HttpResponse, Responder};
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::mpsc;
+
+// Live reload coordination for the dev frontend, only compiled with the dev feature
+// A watcher thread polls the template dir and bumps the templates generation once changes settled
+// Pages rendered from templates subscribe to /api/dev/reload-events and refresh on every reload event
+
+/// How often the template dir is scanned
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Time without further changes before a reload is announced, editors often write a file several times
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Features the server was built with, reported by /api/dev/info
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "dev")]
+    "dev",
+];
+
+/// Templates generation and the open reload event streams
+pub struct ReloadEvents {
+    template_dir: String,
+    generation: AtomicU64,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<u64>>>,
+}
+
+impl ReloadEvents {
+    pub fn new(template_dir: String) -> Self {
+        Self {
+            template_dir,
+            generation: AtomicU64::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Receives the new generation after every change of the templates
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<u64> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Increments the generation and tells every subscriber, closed streams are forgotten
+    pub fn bump(&self) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(generation).is_ok());
+        generation
+    }
+}
+
+/// Modification time and size of every file below the dir, unreadable entries are skipped
+fn snapshot(dir: &Path, files: &mut BTreeMap<PathBuf, (SystemTime, u64)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            snapshot(&entry.path(), files);
+        } else if let Ok(modified) = metadata.modified() {
+            files.insert(entry.path(), (modified, metadata.len()));
+        }
+    }
+}
+
+///
+/// Polls the template dir on a thread and bumps the generation once changes settled for the debounce time
+/// The thread ends after the ReloadEvents were dropped
+///
+pub fn spawn_template_watcher(
+    reload_events: &Arc<ReloadEvents>,
+    poll_interval: Duration,
+    debounce: Duration,
+) -> std::thread::JoinHandle<()> {
+    let reload_events: Weak<ReloadEvents> = Arc::downgrade(reload_events);
+    std::thread::spawn(move || {
+        let Some(dir) = reload_events
+            .upgrade()
+            .map(|events| PathBuf::from(&events.template_dir))
+        else {
+            return;
+        };
+
+        let mut known = BTreeMap::new();
+        snapshot(&dir, &mut known);
+        let mut changed_at: Option<Instant> = None;
+
+        loop {
+            std::thread::sleep(poll_interval);
+            let Some(reload_events) = reload_events.upgrade() else {
+                return;
+            };
+
+            let mut current = BTreeMap::new();
+            snapshot(&dir, &mut current);
+            if current != known {
+                known = current;
+                changed_at = Some(Instant::now());
+            }
+
+            if changed_at.is_some_and(|changed_at| changed_at.elapsed() >= debounce) {
+                changed_at = None;
+                let generation = reload_events.bump();
+                println!("Templates changed, generation {generation}");
+            }
+        }
+    })
+}
+
+#[derive(Serialize)]
+struct DevInfo {
+    version: &'static str,
+    features: &'static [&'static str],
+    template_dir: String,
+    templates_generation: u64,
+}
+
+/// Build and template state of the backend the dev frontend talks to
+async fn dev_info_handler(reload_events: web::Data<ReloadEvents>) -> impl Responder {
+    web::Json(DevInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        features: FEATURES,
+        template_dir: reload_events.template_dir.clone(),
+        templates_generation: reload_events.generation(),
+    })
+}
+
+/// Server sent events, a reload event with the new generation whenever the templates changed
+async fn reload_events_handler(reload_events: web::Data<ReloadEvents>) -> impl Responder {
+    let receiver = reload_events.subscribe();
+
+    // the comment flushes the headers, the client knows it is subscribed
+    let connected = stream::once(async { Ok(web::Bytes::from_static(b": connected\n\n")) });
+    let reloads = stream::unfold(receiver, |mut receiver| async move {
+        let generation = receiver.recv().await?;
+        let event = format!("event: reload\ndata: {{\"templates_generation\":{generation}}}\n\n");
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), receiver))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(connected.chain(reloads))
+}
+
+pub fn dev_service(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/dev")
+            .route("/info", web::get().to(dev_info_handler))
+            .route("/reload-events", web::get().to(reload_events_handler)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::MessageBody, test, App};
+
+    async fn next_chunk(body: &mut (impl MessageBody + Unpin)) -> String {
+        let chunk = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx));
+        let chunk = actix_web::rt::time::timeout(Duration::from_secs(5), chunk)
+            .await
+            .expect("no event within 5 seconds")
+            .unwrap()
+            .unwrap_or_else(|_| panic!("body failed"));
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_template_change_is_pushed_as_reload_event() {
+        let dir = std::env::temp_dir().join(format!("{}-templates", nanoid::nanoid!(8)));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("canvas.html"), "<p>before</p>").unwrap();
+
+        let reload_events = Arc::new(ReloadEvents::new(dir.to_string_lossy().to_string()));
+        spawn_template_watcher(
+            &reload_events,
+            Duration::from_millis(20),
+            Duration::from_millis(50),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(reload_events.clone()))
+                .configure(dev_service),
+        )
+        .await;
+
+        let info: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/api/dev/info").to_request(),
+        )
+        .await;
+        assert_eq!(info["templates_generation"], 0);
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(info["features"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("dev")));
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/dev/reload-events")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let mut body = res.into_body();
+        assert_eq!(next_chunk(&mut body).await, ": connected\n\n");
+
+        // a burst of saves is announced once
+        for content in ["<p>a</p>", "<p>ab</p>", "<p>abc</p>"] {
+            std::fs::write(dir.join("canvas.html"), content).unwrap();
+        }
+        assert_eq!(
+            next_chunk(&mut body).await,
+            "event: reload\ndata: {\"templates_generation\":1}\n\n"
+        );
+        assert_eq!(reload_events.generation(), 1);
+
+        std::fs::write(dir.join("members.html"), "<p>new</p>").unwrap();
+        assert_eq!(
+            next_chunk(&mut body).await,
+            "event: reload\ndata: {\"templates_generation\":2}\n\n"
+        );
+
+        let info: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/api/dev/info").to_request(),
+        )
+        .await;
+        assert_eq!(info["templates_generation"], 2);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,459 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use crate::instance;
+
+// Optional encryption at rest of the eventlogs
+// With a key installed every appended line is sealed with XChaCha20-Poly1305 into a one line envelope,
+// the eventlogs stay line oriented and append only
+// Lines are detected as envelope or plaintext one by one, a deployment enabling encryption keeps reading its old lines
+// The stream id, the file name of the eventlog, is the associated data, a line copied into another eventlog fails to open
+// The key is installed once at startup, every eventlog opened afterwards uses it
+
+/// Length of the key, base64 encoded in the configuration
+pub const KEY_LENGTH: usize = 32;
+
+/// Lines containing the field are parsed as envelope, any other line is plaintext
+const ENVELOPE_MARKER: &str = "\"ciphertext_b64\":";
+
+static INSTALLED_KEY: OnceLock<Arc<EventLogKey>> = OnceLock::new();
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    InvalidKey {
+        key_id: String,
+    },
+    AlreadyInstalled {
+        key_id: String,
+    },
+    MissingKey {
+        stream_id: String,
+        key_id: String,
+    },
+    WrongKey {
+        stream_id: String,
+        key_id: String,
+        configured: String,
+    },
+    /// altered line or a line copied from another eventlog
+    Tampered {
+        stream_id: String,
+        key_id: String,
+    },
+    Malformed {
+        stream_id: String,
+    },
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKey { key_id } => write!(
+                f,
+                "eventlog key {key_id} must be {KEY_LENGTH} bytes encoded as base64"
+            ),
+            Self::AlreadyInstalled { key_id } => write!(
+                f,
+                "eventlog key {key_id} is not installed, another key already is"
+            ),
+            Self::MissingKey { stream_id, key_id } => write!(
+                f,
+                "{stream_id} has lines encrypted with key {key_id}, but no eventlog key is configured"
+            ),
+            Self::WrongKey {
+                stream_id,
+                key_id,
+                configured,
+            } => write!(
+                f,
+                "{stream_id} has lines encrypted with key {key_id}, but key {configured} is configured"
+            ),
+            Self::Tampered { stream_id, key_id } => write!(
+                f,
+                "line of {stream_id} failed to decrypt with key {key_id}, it was altered or belongs to another eventlog"
+            ),
+            Self::Malformed { stream_id } => {
+                write!(f, "line of {stream_id} is a malformed envelope")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+/// Startup and loading fail with the message, it names the key id to configure
+impl From<EncryptionError> for std::io::Error {
+    fn from(error: EncryptionError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+/// Line as written to an encrypted eventlog
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope<'a> {
+    key_id: Cow<'a, str>,
+    nonce: String,
+    ciphertext_b64: String,
+}
+
+/// Data encryption key of the deployment
+#[derive(Clone)]
+pub struct EventLogKey {
+    key_id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for EventLogKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key itself
+        f.debug_struct("EventLogKey")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EventLogKey {
+    pub fn new(key_id: impl Into<String>, key: &[u8]) -> Result<Self, EncryptionError> {
+        let key_id = key_id.into();
+        let cipher = XChaCha20Poly1305::new_from_slice(key)
+            .ok()
+            .filter(|_| key.len() == KEY_LENGTH)
+            .ok_or_else(|| EncryptionError::InvalidKey {
+                key_id: key_id.clone(),
+            })?;
+        Ok(Self { key_id, cipher })
+    }
+
+    /// Key as given in the configuration, base64 encoded
+    pub fn from_base64(key_id: impl Into<String>, encoded: &str) -> Result<Self, EncryptionError> {
+        let key_id = key_id.into();
+        let key = BASE64
+            .decode(encoded.trim())
+            .map_err(|_| EncryptionError::InvalidKey {
+                key_id: key_id.clone(),
+            })?;
+        Self::new(key_id, &key)
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn seal(&self, stream_id: &str, plaintext: &[u8]) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: stream_id.as_bytes(),
+                },
+            )
+            .expect("eventlog lines are far below the XChaCha20-Poly1305 message limit");
+        let envelope = Envelope {
+            key_id: Cow::Borrowed(&self.key_id),
+            nonce: BASE64.encode(nonce),
+            ciphertext_b64: BASE64.encode(ciphertext),
+        };
+        serde_json::to_string(&envelope).expect("envelopes only contain strings")
+    }
+
+    fn open(&self, stream_id: &str, envelope: &Envelope) -> Result<Vec<u8>, EncryptionError> {
+        let tampered = || EncryptionError::Tampered {
+            stream_id: stream_id.to_string(),
+            key_id: envelope.key_id.to_string(),
+        };
+        let nonce = BASE64.decode(&envelope.nonce).map_err(|_| tampered())?;
+        if nonce.len() != XNonce::default().len() {
+            return Err(tampered());
+        }
+        let ciphertext = BASE64
+            .decode(&envelope.ciphertext_b64)
+            .map_err(|_| tampered())?;
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: stream_id.as_bytes(),
+                },
+            )
+            .map_err(|_| tampered())
+    }
+}
+
+/// Installs the key used by every eventlog opened afterwards, once per process
+pub fn install(key: EventLogKey) -> Result<(), EncryptionError> {
+    let key_id = key.key_id.clone();
+    INSTALLED_KEY
+        .set(Arc::new(key))
+        .map_err(|_| EncryptionError::AlreadyInstalled { key_id })
+}
+
+pub fn installed_key() -> Option<Arc<EventLogKey>> {
+    INSTALLED_KEY.get().cloned()
+}
+
+/// Stream id of an eventlog, its file name without the extension
+/// Stays the same when the eventlog is rewritten through a temporary file or the data dir is moved
+pub fn stream_id(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file_path.to_string())
+}
+
+/// Encodes and decodes the lines of one eventlog, shared by all readers and writers of the persistence
+/// The default codec writes plaintext
+/// Appended events are stamped with the instance id first, see instance.rs
+#[derive(Debug, Clone, Default)]
+pub struct LineCodec {
+    key: Option<Arc<EventLogKey>>,
+    stream_id: String,
+    instance_id: Option<Arc<str>>,
+}
+
+impl LineCodec {
+    pub fn new(key: Option<Arc<EventLogKey>>, file_path: &str) -> Self {
+        Self {
+            key,
+            stream_id: stream_id(file_path),
+            instance_id: None,
+        }
+    }
+
+    /// Codec of the eventlog at file_path with the installed key and instance id
+    pub fn installed(file_path: &str) -> Self {
+        Self::new(installed_key(), file_path).with_instance_id(instance::installed())
+    }
+
+    pub fn with_key(mut self, key: Option<Arc<EventLogKey>>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn with_instance_id(mut self, instance_id: Option<Arc<str>>) -> Self {
+        self.instance_id = instance_id;
+        self
+    }
+
+    /// Line to append for a serialized event, without the line break
+    pub fn encode<'a>(&self, json: &'a [u8]) -> Cow<'a, [u8]> {
+        let json = match &self.instance_id {
+            Some(instance_id) => instance::stamp(json, instance_id),
+            None => Cow::Borrowed(json),
+        };
+        match &self.key {
+            Some(key) => Cow::Owned(key.seal(&self.stream_id, &json).into_bytes()),
+            None => json,
+        }
+    }
+
+    /// Serialized event of a line, plaintext lines are returned as they are
+    pub fn decode<'a>(&self, line: &'a str) -> Result<Cow<'a, str>, EncryptionError> {
+        if !line.contains(ENVELOPE_MARKER) {
+            return Ok(Cow::Borrowed(line));
+        }
+        // an event mentioning the field, e.g. in the text of a shape, is no envelope
+        let Ok(envelope) = serde_json::from_str::<Envelope>(line) else {
+            return Ok(Cow::Borrowed(line));
+        };
+        let key = match &self.key {
+            Some(key) if key.key_id == envelope.key_id => key,
+            Some(key) => {
+                return Err(EncryptionError::WrongKey {
+                    stream_id: self.stream_id.clone(),
+                    key_id: envelope.key_id.into_owned(),
+                    configured: key.key_id.clone(),
+                })
+            }
+            None => {
+                return Err(EncryptionError::MissingKey {
+                    stream_id: self.stream_id.clone(),
+                    key_id: envelope.key_id.into_owned(),
+                })
+            }
+        };
+        let plaintext = key.open(&self.stream_id, &envelope)?;
+        String::from_utf8(plaintext)
+            .map(Cow::Owned)
+            .map_err(|_| EncryptionError::Malformed {
+                stream_id: self.stream_id.clone(),
+            })
+    }
+
+    /// Raw line as exported, lines that are no envelope are passed through untouched
+    pub fn decode_raw<'a>(&self, line: &'a [u8]) -> Result<Cow<'a, [u8]>, EncryptionError> {
+        let Ok(text) = std::str::from_utf8(line) else {
+            return Ok(Cow::Borrowed(line));
+        };
+        match self.decode(text)? {
+            Cow::Borrowed(_) => Ok(Cow::Borrowed(line)),
+            Cow::Owned(decoded) => Ok(Cow::Owned(decoded.into_bytes())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{EventLogPersistenceJson, PersistEventMessage};
+    use actix::Actor;
+    use serde_json::{json, Value};
+
+    fn temp_log_path() -> String {
+        std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn key(key_id: &str, byte: u8) -> Option<Arc<EventLogKey>> {
+        Some(Arc::new(
+            EventLogKey::new(key_id, &[byte; KEY_LENGTH]).unwrap(),
+        ))
+    }
+
+    fn read(path: &str, key: Option<Arc<EventLogKey>>) -> std::io::Result<Vec<Value>> {
+        EventLogPersistenceJson::open(path)?
+            .with_key(key)
+            .read_lines::<Value>()?
+            .into_iter()
+            .map(|line| line.map_err(std::io::Error::from))
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_round_trip() {
+        assert_eq!(
+            EventLogKey::new("short", &[1; 16]).unwrap_err(),
+            EncryptionError::InvalidKey {
+                key_id: "short".to_string()
+            }
+        );
+        let encoded = BASE64.encode([7; KEY_LENGTH]);
+        assert!(EventLogKey::from_base64("k1", &encoded).is_ok());
+
+        let path = temp_log_path();
+        let event = json!({ "type": "ShapeAdded", "id": "s1", "secret": "diagram" });
+        let mut appender = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .with_key(key("k1", 7))
+            .into_appender::<Value>();
+        appender.save_event(&event).unwrap();
+        appender.save_event(&event).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(!content.contains("diagram"));
+        let envelope: Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(envelope["key_id"], "k1");
+        // every line has its own nonce
+        assert_ne!(content.lines().next(), content.lines().nth(1));
+
+        assert_eq!(read(&path, key("k1", 7)).unwrap(), [event.clone(), event]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_and_spliced_lines_fail() {
+        let key = key("k1", 7);
+        let codec = LineCodec::new(key.clone(), "./canvas-a.jsonl");
+        let line = String::from_utf8(codec.encode(br#"{"type":"x"}"#).into_owned()).unwrap();
+        assert_eq!(codec.decode(&line).unwrap(), r#"{"type":"x"}"#);
+
+        let mut envelope: Value = serde_json::from_str(&line).unwrap();
+        let mut ciphertext = BASE64
+            .decode(envelope["ciphertext_b64"].as_str().unwrap())
+            .unwrap();
+        ciphertext[0] ^= 1;
+        envelope["ciphertext_b64"] = BASE64.encode(ciphertext).into();
+        let tampered = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(
+            codec.decode(&tampered).unwrap_err(),
+            EncryptionError::Tampered {
+                stream_id: "canvas-a".to_string(),
+                key_id: "k1".to_string()
+            }
+        );
+
+        // the line is bound to its eventlog
+        let other = LineCodec::new(key, "./canvas-b.jsonl");
+        assert!(matches!(
+            other.decode(&line),
+            Err(EncryptionError::Tampered { .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_mixed_plaintext_and_encrypted_lines_load() {
+        let path = temp_log_path();
+        let plaintext = json!({ "type": "CanvasCreated", "id": "c1" });
+        std::fs::write(&path, format!("{plaintext}\n")).unwrap();
+
+        // enabling encryption on an existing eventlog only encrypts the new lines
+        let (events, actor) = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .with_key(key("k1", 7))
+            .into_actor::<Value>()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0], plaintext);
+        let encrypted = json!({ "type": "CanvasDeleted", "id": "c1" });
+        actor
+            .start()
+            .send(PersistEventMessage(encrypted.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.lines().nth(1).unwrap().contains(ENVELOPE_MARKER));
+        assert!(!content.lines().next().unwrap().contains(ENVELOPE_MARKER));
+        assert_eq!(read(&path, key("k1", 7)).unwrap(), [plaintext, encrypted]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_or_missing_key_names_the_key_id() {
+        let path = temp_log_path();
+        let mut appender = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .with_key(key("k1", 7))
+            .into_appender::<Value>();
+        appender.save_event(&json!({ "type": "x" })).unwrap();
+        let stream_id = stream_id(&path);
+
+        let error = read(&path, None).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{stream_id} has lines encrypted with key k1, but no eventlog key is configured"
+            )
+        );
+        assert_eq!(
+            read(&path, key("k2", 7)).unwrap_err().to_string(),
+            format!("{stream_id} has lines encrypted with key k1, but key k2 is configured")
+        );
+        // same id, different key material
+        assert!(read(&path, key("k1", 8))
+            .unwrap_err()
+            .to_string()
+            .contains("failed to decrypt with key k1"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use crate::messages::{self, Message, MessageKey};
+use actix_web::{
+    dev::Payload,
+    error::{JsonPayloadError, UrlencodedError},
+    web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::{future::LocalBoxFuture, FutureExt, TryFutureExt};
+use serde::de::DeserializeOwned;
+
+// Limits and error handling of request bodies
+// Oversized bodies are rejected with 413, bodies that can't be deserialized with 422
+// Errors name the offending field and the accepted values, raw serde messages are never returned
+// as they echo user input and leak internal type names
+
+/// Body limit of the login and register forms, also the default of every other scope
+pub const AUTH_BODY_LIMIT: usize = 16 * 1024;
+
+/// Body limit of the canvas management forms
+pub const CANVAS_BODY_LIMIT: usize = 16 * 1024;
+
+/// Form fields holding an enum, serde only names the variants in its errors
+/// Kept in sync with the enums by test_enum_fields_match_enums
+const ENUM_FIELDS: &[(&str, &[&str])] = &[
+    ("state", &["Active", "Moderated"]),
+    ("action", &["moderate", "activate", "archive", "unarchive"]),
+    (
+        "access_level",
+        &["Read", "Write", "Moderate", "Owner", "Voice", "None"],
+    ),
+];
+
+pub fn form_config(limit: usize) -> web::FormConfig {
+    web::FormConfig::default()
+        .limit(limit)
+        .error_handler(|error, _| form_error(error).into())
+}
+
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|error, _| json_error(error).into())
+}
+
+fn form_error(error: UrlencodedError) -> messages::LocalizedError {
+    match error {
+        UrlencodedError::Overflow { limit, .. } => too_large(limit),
+        UrlencodedError::Parse(error) => invalid_field(&error.to_string()),
+        _ => malformed(),
+    }
+}
+
+fn json_error(error: JsonPayloadError) -> messages::LocalizedError {
+    match error {
+        JsonPayloadError::Overflow { limit }
+        | JsonPayloadError::OverflowKnownLength { limit, .. } => too_large(limit),
+        JsonPayloadError::Deserialize(error) if error.is_data() => {
+            invalid_field(&error.to_string())
+        }
+        _ => malformed(),
+    }
+}
+
+pub fn too_large(limit: usize) -> messages::LocalizedError {
+    messages::payload_too_large(
+        Message::new(MessageKey::RequestTooLarge)
+            .param("reason", "too_large")
+            .param("limit", limit),
+    )
+}
+
+pub fn malformed() -> messages::LocalizedError {
+    messages::bad_request(Message::new(MessageKey::RequestMalformed).param("reason", "malformed"))
+}
+
+/// Identifiers quoted in backticks, serde quotes field and variant names this way
+fn quoted(text: &str) -> Vec<&str> {
+    text.split('`').skip(1).step_by(2).collect()
+}
+
+/// Maps a serde error to the field it is about
+/// Only names taken from our own types end up in the message, never the rejected input
+fn invalid_field(serde_message: &str) -> messages::LocalizedError {
+    if let Some(rest) = serde_message.strip_prefix("missing field ") {
+        if let Some(field) = quoted(rest).first() {
+            return messages::unprocessable_entity(
+                Message::new(MessageKey::RequestFieldMissing)
+                    .param("reason", "missing")
+                    .param("field", field),
+            );
+        }
+    }
+
+    if serde_message.starts_with("unknown variant ") {
+        // the first quoted name is the rejected input
+        let expected = serde_message
+            .split_once(", expected ")
+            .map(|(_, expected)| quoted(expected))
+            .unwrap_or_default();
+        let field = ENUM_FIELDS
+            .iter()
+            .find(|(_, variants)| *variants == expected.as_slice())
+            .map(|(field, _)| *field);
+        if let Some(field) = field {
+            return messages::unprocessable_entity(
+                Message::new(MessageKey::RequestFieldInvalid)
+                    .param("reason", "invalid_value")
+                    .param("field", field)
+                    .param("expected", expected.join(", ")),
+            );
+        }
+    }
+
+    messages::unprocessable_entity(
+        Message::new(MessageKey::RequestMalformed).param("reason", "malformed"),
+    )
+}
+
+/// Form or JSON body, chosen by the Content-Type of the request
+/// Unlike web::Either the error of the matching extractor is returned, not the one of the form
+pub struct FormOrJson<T>(pub T);
+
+impl<T> FormOrJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for FormOrJson<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if req.content_type().ends_with("json") {
+            web::Json::<T>::from_request(req, payload)
+                .map_ok(|json| FormOrJson(json.into_inner()))
+                .boxed_local()
+        } else {
+            web::Form::<T>::from_request(req, payload)
+                .map_ok(|form| FormOrJson(form.into_inner()))
+                .boxed_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{
+        store::{AccessLevel, CanvasStateAction},
+        LegacyCanvasState,
+    };
+    use actix_web::http::StatusCode;
+
+    fn unknown_variant_error(field: &str) -> String {
+        let value = serde_json::json!("unknown");
+        match field {
+            "state" => serde_json::from_value::<LegacyCanvasState>(value).err(),
+            "action" => serde_json::from_value::<CanvasStateAction>(value).err(),
+            "access_level" => serde_json::from_value::<AccessLevel>(value).err(),
+            _ => None,
+        }
+        .unwrap_or_else(|| panic!("{field} is not an enum field"))
+        .to_string()
+    }
+
+    #[test]
+    fn test_enum_fields_match_enums() {
+        // serde lists every variant, the table has to list the same ones to match an error
+        for (field, variants) in ENUM_FIELDS {
+            let error = unknown_variant_error(field);
+            let (_, expected) = error.split_once(", expected ").unwrap();
+            assert_eq!(quoted(expected), *variants);
+        }
+    }
+
+    #[test]
+    fn test_invalid_variant_names_field_without_input() {
+        let error = invalid_field("unknown variant `<script>`, expected `Active` or `Moderated`");
+        assert_eq!(
+            actix_web::ResponseError::status_code(&error),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let message = error.to_string();
+        assert!(message.contains("state"));
+        assert!(message.contains("Active, Moderated"));
+        assert!(!message.contains("<script>"));
+    }
+
+    #[test]
+    fn test_other_serde_errors_are_not_echoed() {
+        let message = invalid_field("invalid digit found in string").to_string();
+        assert!(!message.contains("digit"));
+        let message = invalid_field("unknown variant `x`, expected `A` or `B`").to_string();
+        assert!(!message.contains('`'));
+    }
+}
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use crate::persistence::EventLogPersistenceJson;
+
+// Identity of a deployment, so events written by different servers can be told apart
+// The id is generated on the first startup and stored in the data dir, moving the data dir keeps it
+// Every line appended to an eventlog carries the id of the instance that wrote it, see LineCodec
+// Loaders accept lines of any instance, the id is informational until servers sync with each other
+
+/// File in the data dir holding the id, next to the eventlogs
+pub const INSTANCE_ID_FILE: &str = "./instance_id";
+
+/// Field of the persisted events naming the instance that wrote them
+pub const INSTANCE_FIELD: &str = "instanceId";
+
+static INSTALLED_ID: OnceLock<Arc<str>> = OnceLock::new();
+
+/// Instance as shown to admins
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstanceInfo {
+    /// None if no instance id is installed, e.g. in tests
+    pub instance_id: Option<String>,
+}
+
+impl InstanceInfo {
+    pub fn installed() -> Self {
+        Self {
+            instance_id: installed().map(|id| id.to_string()),
+        }
+    }
+}
+
+/// Ids are embedded into the lines unescaped, only nanoid characters are allowed
+fn is_valid(instance_id: &str) -> bool {
+    !instance_id.is_empty()
+        && instance_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Reads the id stored at file_path, a missing file is created with a new id
+pub fn load_or_create(file_path: &str) -> io::Result<String> {
+    match std::fs::read_to_string(file_path) {
+        Ok(content) => {
+            let id = content.trim();
+            if !is_valid(id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{file_path} holds no instance id, remove it to generate a new one"),
+                ));
+            }
+            Ok(id.to_string())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let id = nanoid::nanoid!(12);
+            // create_new, two processes starting at once don't overwrite each other
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(file_path)?;
+            writeln!(file, "{id}")?;
+            file.sync_all()?;
+            println!("Generated instance id {id} in {file_path}");
+            Ok(id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Installs the id stamped into every line appended afterwards, once per process
+pub fn install(instance_id: &str) -> io::Result<()> {
+    if !is_valid(instance_id) {
+        return Err(io::Error::other(format!(
+            "invalid instance id {instance_id}"
+        )));
+    }
+    INSTALLED_ID
+        .set(Arc::from(instance_id))
+        .map_err(|_| io::Error::other("instance id is already installed"))
+}
+
+pub fn installed() -> Option<Arc<str>> {
+    INSTALLED_ID.get().cloned()
+}
+
+/// Serialized event with the instance field in front, anything but a non empty object is returned as is
+pub fn stamp<'a>(json: &'a [u8], instance_id: &str) -> Cow<'a, [u8]> {
+    match json.strip_prefix(b"{") {
+        Some(rest) if rest != b"}" => {
+            let mut stamped = format!("{{\"{INSTANCE_FIELD}\":\"{instance_id}\",").into_bytes();
+            stamped.extend_from_slice(rest);
+            Cow::Owned(stamped)
+        }
+        _ => Cow::Borrowed(json),
+    }
+}
+
+#[derive(Deserialize)]
+struct Stamp {
+    #[serde(rename = "instanceId")]
+    instance_id: Option<String>,
+}
+
+/// Instance that wrote the serialized event, None for lines written before instances were stamped
+pub fn writer_of(json: &serde_json::Value) -> Option<&str> {
+    json.get(INSTANCE_FIELD)?.as_str()
+}
+
+/// Instance that appended the last line of the eventlog, None for a missing, empty or unstamped eventlog
+pub fn last_writer(file_path: &str) -> io::Result<Option<String>> {
+    if !Path::new(file_path).exists() {
+        return Ok(None);
+    }
+    let log = EventLogPersistenceJson::open(file_path)?;
+    let mut last = None;
+    for line in log.stream_lines::<Stamp>() {
+        last = line?.ok().and_then(|stamp| stamp.instance_id);
+    }
+    Ok(last)
+}
+
+/// Eventlogs last appended to by another instance, e.g. a second server sharing the data dir by mistake
+pub fn foreign_writers(
+    file_paths: &[&str],
+    instance_id: &str,
+) -> io::Result<Vec<(String, String)>> {
+    let mut foreign = Vec::new();
+    for file_path in file_paths {
+        if let Some(writer) = last_writer(file_path)?.filter(|writer| writer != instance_id) {
+            foreign.push((file_path.to_string(), writer));
+        }
+    }
+    Ok(foreign)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{}-{name}", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn test_instance_id_survives_restarts() {
+        let path = temp_path("instance_id");
+        let id = load_or_create(&path).unwrap();
+        assert_eq!(id.len(), 12);
+        assert_eq!(load_or_create(&path).unwrap(), id);
+
+        std::fs::write(&path, "\n").unwrap();
+        assert_eq!(
+            load_or_create(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_appended_lines_name_their_instance() {
+        let path = temp_path("canvas.jsonl");
+        let mut log = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .into_appender::<Value>();
+        log.save_event(&json!({ "type": "ShapeRemoved", "shapeId": "l1" }))
+            .unwrap();
+        let mut log = EventLogPersistenceJson::new(&path)
+            .unwrap()
+            .with_instance_id(Some(Arc::from("a")))
+            .into_appender::<Value>();
+        log.save_event(&json!({ "type": "ShapeRemoved", "shapeId": "l2" }))
+            .unwrap();
+
+        let lines: Vec<Value> = EventLogPersistenceJson::open(&path)
+            .unwrap()
+            .read_lines::<Value>()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(writer_of(&lines[0]), None);
+        assert_eq!(
+            lines[1],
+            json!({ "instanceId": "a", "type": "ShapeRemoved", "shapeId": "l2" })
+        );
+        assert_eq!(last_writer(&path).unwrap().as_deref(), Some("a"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_logs_of_another_instance_are_reported() {
+        let shared = temp_path("user_eventlog.jsonl");
+        let own = temp_path("canvas_eventlog.jsonl");
+        let missing = temp_path("missing.jsonl");
+        std::fs::write(
+            &shared,
+            "{\"instanceId\":\"a\",\"type\":\"A\"}\n{\"instanceId\":\"b\",\"type\":\"B\"}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &own,
+            "{\"type\":\"A\"}\n{\"instanceId\":\"a\",\"type\":\"B\"}\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            foreign_writers(&[&shared, &own, &missing], "a").unwrap(),
+            vec![(shared.clone(), "b".to_string())]
+        );
+        assert!(foreign_writers(&[&shared], "b").unwrap().is_empty());
+        let _ = std::fs::remove_file(shared);
+        let _ = std::fs::remove_file(own);
+    }
+}
@@ -0,0 +1,780 @@
+use actix::prelude::*;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    middleware::{self},
+    web, App, HttpRequest, Responder,
+};
+use argon2::Params;
+use canvas::{
+    activity::ActivityCache,
+    diagnostics::DiagnosticsAlarm,
+    features::FeatureFlags,
+    quota::QuotaLimits,
+    replay::ReplayCache,
+    retention::RetentionPolicy,
+    server::{CanvasSocketServer, CanvasSocketServerHandle, ConnectionLimits, FlushPolicy},
+    store::{
+        AddUserToCanvasMessage, AddUsersToCanvasMessage, CanvasStore, CreateApiTokenMessage,
+        CreateCanvasMessage, DeleteCanvasMessage, GetApiTokensMessage, GetCanvasMembershipMessage,
+        GetCanvasMessage, GetCanvasQuotaMessage, GetOwnedCanvasesMessage,
+        GetUserAccessLevelMessage, GetUserCanvasesMessage, GetUserClaimsMessage,
+        PurgeDeletedCanvasesMessage, PurgeUserMessage, RecordCanvasVisitMessage,
+        RegisterCanvasServerMessage, RequestCanvasAccessMessage, ResolveAccessRequestMessage,
+        ResolveApiTokenMessage, RestoreCanvasMessage, RevokeApiTokenMessage, SetCanvasOrderMessage,
+        SetCanvasPreferenceMessage, UpdateCanvasFeatureFlagsMessage, UpdateCanvasPaletteMessage,
+        UpdateCanvasSettingsMessage, UpdateCanvasStateMessage, UpdateCanvasTagsMessage,
+    },
+    tokens::TokenRateLimiter,
+    validation::ShapeLimits,
+};
+use handlebars::{DirectorySourceOptions, Handlebars};
+use persistence::{EventLogPersistenceJson, ReplayIssue, ReplayIssues, ReplayMode};
+use std::{future::Future, path::Path, time::Duration};
+use userstore::{
+    BumpTokenVersionMessage, CompletePasswordResetMessage, DeleteUserMessage,
+    GetTokenVersionMessage, GetUserMessage, GetUsernamesMessage, GetUsersMessage,
+    IssuePasswordResetMessage, RecordLoginMessage, RegisterUserMessage, SetUserPreferencesMessage,
+    TouchUserMessage, UpdatePasswordHashMessage, UserStore,
+};
+
+pub mod admin;
+pub mod api;
+pub mod api_docs;
+#[cfg(feature = "embed-frontend")]
+pub mod assets;
+pub mod authentication;
+pub mod canvas;
+pub mod clock;
+pub mod connection;
+#[cfg(feature = "dev")]
+pub mod dev;
+pub mod encryption;
+pub mod forms;
+pub mod instance;
+pub mod mailbox;
+pub mod maintenance;
+pub mod maintenance_mode;
+pub mod messages;
+pub mod notifier;
+pub mod password;
+pub mod persistence;
+pub mod preferences;
+pub mod preflight;
+pub mod recovery;
+pub mod security;
+pub mod seed;
+pub mod spa;
+pub mod templates;
+pub mod user;
+pub mod username;
+pub mod userstore;
+
+// Drawing Canvas webserver
+// bootstrap creates the stores and the canvas server from a ServerConfig
+// build_app composes the actix App from the resulting AppState
+// Binaries only need to run the HttpServer and the canvas server future
+
+#[cfg(feature = "dev")]
+static HANDLEBARS_DEV: bool = true;
+#[cfg(not(feature = "dev"))]
+static HANDLEBARS_DEV: bool = false;
+
+pub static USER_EVENT_LOG: &str = "user_eventlog.jsonl";
+pub static CANVAS_EVENT_LOG: &str = "canvas_eventlog.jsonl";
+
+/// Configuration of the stores, templates and limits used by bootstrap
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub user_event_log: String,
+    pub canvas_event_log: String,
+    pub template_dir: String,
+    /// vite build served as the frontend, unused with the embed-frontend feature
+    pub dist_dir: String,
+    /// missing directories of the eventlogs are created instead of refusing the startup, see preflight.rs
+    pub create_data_dirs: bool,
+    pub password_hash_config: password::PasswordHashConfig,
+    pub connection_limits: ConnectionLimits,
+    pub shape_limits: ShapeLimits,
+    pub print_limits: canvas::print::PrintLimits,
+    pub quota_limits: QuotaLimits,
+    /// when the canvas eventlogs are synced to disk
+    pub flush_policy: FlushPolicy,
+    /// retention of the events not affecting the drawing when a canvas eventlog is compacted
+    pub retention_policy: RetentionPolicy,
+    /// time a deleted canvas can be restored before it is purged
+    pub deletion_grace: Duration,
+    /// time purged eventlogs stay in the quarantine, see canvas::orphans
+    pub quarantine_retention: Duration,
+    /// reject canvas names an owner already uses, see canvas::names
+    pub unique_canvas_names: bool,
+    pub admin_action_log: String,
+    /// user ids allowed to use the admin endpoints
+    pub admins: Vec<String>,
+    /// origin browsers reach the server at, names the websocket in the Content-Security-Policy
+    /// and is the base of links sent to users, the Host header of a request is never trusted for them
+    pub public_origin: security::PublicOrigin,
+    /// reverse proxies whose X-Forwarded-For header names the client of a websocket session
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+    /// locale used if the Accept-Language header of a request contains no supported language
+    pub default_locale: messages::Locale,
+    /// time source of the stores, the canvas server and the handlers
+    pub clock: clock::SharedClock,
+    /// delivers password reset links, logs them by default
+    pub notifier: notifier::SharedNotifier,
+    /// mailbox capacity and saturation detection of the stores and their persistence
+    pub mailbox: mailbox::MailboxConfig,
+    /// whether issues in the store eventlogs abort the startup
+    pub replay_mode: ReplayMode,
+    /// rollout of the protocol features, canvas owners may override some of them
+    pub feature_flags: FeatureFlags,
+    /// age up to which a canvas is restored from the handoff of the previous process, see canvas::handoff
+    pub handoff_max_age: Duration,
+    /// thresholds that send owners and moderators a diagnostics alarm, see canvas::diagnostics
+    pub diagnostics_alarm: DiagnosticsAlarm,
+    /// time within which updates of a shape by different users count as conflict, see canvas::provenance
+    pub conflict_window: Duration,
+    /// handshake credentials of the websocket route besides the auth cookie, see authentication::websocket_claims
+    pub websocket_auth: authentication::WebSocketAuth,
+    /// attributes of the cookies the server sets, see authentication::CookieFactory
+    pub cookie_policy: authentication::CookiePolicy,
+    /// admission of visitors without an account to canvases open for guests, see canvas::guests
+    pub guest_policy: canvas::guests::GuestPolicy,
+    /// read-only mode the server starts in, admins toggle it at runtime, see maintenance_mode.rs
+    pub maintenance: maintenance_mode::MaintenanceWindow,
+    /// lowest access level that may comment on shapes, see canvas::comments
+    pub comment_level: canvas::store::AccessLevel,
+    /// time between two checks for due activity digests, see canvas::digest
+    pub digest_interval: Duration,
+    /// reserved words and characters of new usernames
+    pub username_policy: username::UsernamePolicy,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            user_event_log: USER_EVENT_LOG.to_string(),
+            canvas_event_log: CANVAS_EVENT_LOG.to_string(),
+            template_dir: templates::default_templates_dir().to_string(),
+            dist_dir: templates::DIST_DIR.to_string(),
+            create_data_dirs: false,
+            password_hash_config: password::PasswordHashConfig::default(),
+            connection_limits: ConnectionLimits::default(),
+            shape_limits: ShapeLimits::default(),
+            print_limits: canvas::print::PrintLimits::default(),
+            quota_limits: QuotaLimits::default(),
+            flush_policy: FlushPolicy::default(),
+            retention_policy: RetentionPolicy::default(),
+            deletion_grace: canvas::store::DEFAULT_DELETION_GRACE,
+            quarantine_retention: canvas::orphans::DEFAULT_QUARANTINE_RETENTION,
+            unique_canvas_names: false,
+            admin_action_log: admin::ADMIN_ACTION_LOG.to_string(),
+            admins: Vec::new(),
+            public_origin: security::PublicOrigin::default(),
+            trusted_proxies: Vec::new(),
+            default_locale: messages::Locale::default(),
+            clock: clock::system(),
+            notifier: notifier::log(),
+            mailbox: mailbox::MailboxConfig::default(),
+            replay_mode: ReplayMode::default(),
+            feature_flags: FeatureFlags::default(),
+            handoff_max_age: canvas::handoff::DEFAULT_HANDOFF_MAX_AGE,
+            diagnostics_alarm: DiagnosticsAlarm::default(),
+            conflict_window: canvas::provenance::DEFAULT_CONFLICT_WINDOW,
+            websocket_auth: authentication::WebSocketAuth::default(),
+            cookie_policy: authentication::CookiePolicy::default(),
+            guest_policy: canvas::guests::GuestPolicy::default(),
+            maintenance: maintenance_mode::MaintenanceWindow::default(),
+            comment_level: canvas::comments::DEFAULT_COMMENT_LEVEL,
+            digest_interval: canvas::digest::DEFAULT_DIGEST_INTERVAL,
+            username_policy: username::UsernamePolicy::default(),
+        }
+    }
+}
+
+/// Everything the handlers need, shared between all workers
+/// all actors are represented by their recipient to allow for easy swapping of implementations
+#[derive(Clone)]
+pub struct AppState {
+    handlebars: web::Data<Handlebars<'static>>,
+    register_user_recipient: web::Data<Recipient<RegisterUserMessage>>,
+    get_user_recipient: web::Data<Recipient<GetUserMessage>>,
+    get_usernames_recipient: web::Data<Recipient<GetUsernamesMessage>>,
+    get_users_recipient: web::Data<Recipient<GetUsersMessage>>,
+    update_password_hash_recipient: web::Data<Recipient<UpdatePasswordHashMessage>>,
+    record_login_recipient: web::Data<Recipient<RecordLoginMessage>>,
+    get_token_version_recipient: web::Data<Recipient<GetTokenVersionMessage>>,
+    bump_token_version_recipient: web::Data<Recipient<BumpTokenVersionMessage>>,
+    delete_user_recipient: web::Data<Recipient<DeleteUserMessage>>,
+    issue_password_reset_recipient: web::Data<Recipient<IssuePasswordResetMessage>>,
+    complete_password_reset_recipient: web::Data<Recipient<CompletePasswordResetMessage>>,
+    set_user_preferences_recipient: web::Data<Recipient<SetUserPreferencesMessage>>,
+    user_activity_tracker: web::Data<authentication::UserActivityTracker>,
+    jwt_refresh_cache: web::Data<authentication::JWTRefreshCache>,
+    create_canvas_recipient: web::Data<Recipient<CreateCanvasMessage>>,
+    get_user_claims_recipient: web::Data<Recipient<GetUserClaimsMessage>>,
+    get_user_canvases_recipient: web::Data<Recipient<GetUserCanvasesMessage>>,
+    record_canvas_visit_recipient: web::Data<Recipient<RecordCanvasVisitMessage>>,
+    set_canvas_preference_recipient: web::Data<Recipient<SetCanvasPreferenceMessage>>,
+    set_canvas_order_recipient: web::Data<Recipient<SetCanvasOrderMessage>>,
+    add_user_to_canvas_recipient: web::Data<Recipient<AddUserToCanvasMessage>>,
+    add_users_to_canvas_recipient: web::Data<Recipient<AddUsersToCanvasMessage>>,
+    request_canvas_access_recipient: web::Data<Recipient<RequestCanvasAccessMessage>>,
+    resolve_access_request_recipient: web::Data<Recipient<ResolveAccessRequestMessage>>,
+    update_canvas_state_recipient: web::Data<Recipient<UpdateCanvasStateMessage>>,
+    update_canvas_settings_recipient: web::Data<Recipient<UpdateCanvasSettingsMessage>>,
+    update_canvas_tags_recipient: web::Data<Recipient<UpdateCanvasTagsMessage>>,
+    rename_canvas_recipient: web::Data<Recipient<canvas::store::RenameCanvasMessage>>,
+    update_canvas_feature_flags_recipient: web::Data<Recipient<UpdateCanvasFeatureFlagsMessage>>,
+    update_canvas_palette_recipient: web::Data<Recipient<UpdateCanvasPaletteMessage>>,
+    get_canvas_recipient: web::Data<Recipient<GetCanvasMessage>>,
+    get_canvas_records_recipient: web::Data<Recipient<canvas::store::GetCanvasRecordsMessage>>,
+    get_canvas_membership_recipient: web::Data<Recipient<GetCanvasMembershipMessage>>,
+    get_owned_canvases_recipient: web::Data<Recipient<GetOwnedCanvasesMessage>>,
+    purge_user_recipient: web::Data<Recipient<PurgeUserMessage>>,
+    get_user_access_level_recipient: web::Data<Recipient<GetUserAccessLevelMessage>>,
+    get_canvas_quota_recipient: web::Data<Recipient<GetCanvasQuotaMessage>>,
+    delete_canvas_recipient: web::Data<Recipient<DeleteCanvasMessage>>,
+    restore_canvas_recipient: web::Data<Recipient<RestoreCanvasMessage>>,
+    create_api_token_recipient: web::Data<Recipient<CreateApiTokenMessage>>,
+    get_api_tokens_recipient: web::Data<Recipient<GetApiTokensMessage>>,
+    revoke_api_token_recipient: web::Data<Recipient<RevokeApiTokenMessage>>,
+    resolve_api_token_recipient: web::Data<Recipient<ResolveApiTokenMessage>>,
+    run_digests_recipient: web::Data<Recipient<canvas::digest::RunDigestsMessage>>,
+    purge_deleted_canvases_recipient: web::Data<Recipient<PurgeDeletedCanvasesMessage>>,
+    token_rate_limiter: web::Data<TokenRateLimiter>,
+    access_poll_limiter: web::Data<canvas::AccessPollLimiter>,
+    access_request_limiter: web::Data<canvas::access_requests::AccessRequestLimiter>,
+    preferences_limiter: web::Data<preferences::PreferencesLimiter>,
+    admins: web::Data<admin::Admins>,
+    trusted_proxies: web::Data<connection::TrustedProxies>,
+    websocket_auth: web::Data<authentication::WebSocketAuth>,
+    cookie_factory: web::Data<authentication::CookieFactory>,
+    guest_policy: web::Data<canvas::guests::GuestPolicy>,
+    maintenance: web::Data<maintenance_mode::MaintenanceState>,
+    retention_policy: web::Data<RetentionPolicy>,
+    shape_limits: web::Data<ShapeLimits>,
+    print_limits: web::Data<canvas::print::PrintLimits>,
+    feature_flags: web::Data<FeatureFlags>,
+    render_monitor: web::Data<templates::RenderMonitor>,
+    admin_action_log: web::Data<admin::AdminActionLog>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    replay_cache: web::Data<ReplayCache>,
+    activity_cache: web::Data<ActivityCache>,
+    actor_gauges: web::Data<mailbox::ActorGauges>,
+    replay_issues: web::Data<ReplayIssues>,
+    preflight: web::Data<preflight::PreflightReport>,
+    security_headers: security::SecurityHeadersService,
+    public_origin: web::Data<security::PublicOrigin>,
+    dist_dir: String,
+    argon_params: Params,
+    default_locale: messages::Locale,
+    clock: web::Data<dyn clock::Clock>,
+    notifier: web::Data<dyn notifier::Notifier>,
+    #[cfg(feature = "dev")]
+    reload_events: web::Data<dev::ReloadEvents>,
+}
+
+impl AppState {
+    /// Handle to the running canvas server, allows embedding binaries to send canvas commands
+    pub fn canvas_server_handle(&self) -> CanvasSocketServerHandle {
+        self.canvas_server_handle.get_ref().clone()
+    }
+
+    /// Issues found while replaying the store eventlogs, only non empty in tolerant replay mode
+    pub fn replay_issues(&self) -> &ReplayIssues {
+        self.replay_issues.get_ref()
+    }
+
+    /// Sends the activity digests due now without waiting for the digest interval, resolves to the digests sent
+    pub async fn run_digests(&self) -> Result<Vec<canvas::digest::CanvasDigest>, MailboxError> {
+        self.run_digests_recipient
+            .send(canvas::digest::RunDigestsMessage {
+                now: self.clock.now_ms(),
+            })
+            .await
+    }
+
+    /// Purges the canvases deleted longer than the deletion grace ago without waiting for the purge interval
+    /// Resolves to the number of purged canvases
+    pub async fn purge_deleted_canvases(
+        &self,
+    ) -> Result<Result<usize, canvas::error::CanvasStoreError>, MailboxError> {
+        self.purge_deleted_canvases_recipient
+            .send(PurgeDeletedCanvasesMessage {
+                now: self.clock.now_ms(),
+            })
+            .await
+    }
+}
+
+/// Creates the stores, actors and the canvas server
+/// Has to be called from within an actix system, the returned future runs the canvas server and has to be spawned
+pub fn bootstrap(
+    config: ServerConfig,
+) -> std::io::Result<(AppState, impl Future<Output = std::io::Result<()>>)> {
+    // a broken setup is refused before any eventlog is created in a surprising place
+    let preflight = preflight::run(&preflight::PreflightPaths {
+        template_dir: config.template_dir.clone(),
+        dist_dir: config.dist_dir.clone(),
+        event_logs: vec![
+            config.user_event_log.clone(),
+            config.canvas_event_log.clone(),
+            config.admin_action_log.clone(),
+        ],
+        create_data_dirs: config.create_data_dirs,
+        dev_index: templates::dev_index_file().map(str::to_string),
+    });
+    print!("{preflight}");
+    if let Some(refusal) = preflight.refusal() {
+        return Err(refusal);
+    }
+
+    // Every store and persistence actor is fronted by a MailboxProbe, see mailbox.rs
+    let actor_gauges = mailbox::ActorGauges::default();
+    let mailbox_config = &config.mailbox;
+
+    // a second server appending to the same eventlogs corrupts the state of both
+    if let Some(instance_id) = instance::installed() {
+        println!("Instance id: {instance_id}");
+        let store_logs = [
+            config.user_event_log.as_str(),
+            config.canvas_event_log.as_str(),
+        ];
+        for (file_path, writer) in instance::foreign_writers(&store_logs, &instance_id)? {
+            println!(
+                "WARNING: {file_path} was last written by instance {writer}, not by this instance {instance_id}"
+            );
+            println!("WARNING: if another server still uses this data dir stop one of them, the eventlogs diverge otherwise");
+        }
+    }
+
+    // User Store
+    // User event store setup, creates persistence actor and user store actor
+    // persistence can be swapped out for a different implementation
+    // user store can later be replaced by a database
+    let (saved_events, user_event_log) =
+        EventLogPersistenceJson::new(&config.user_event_log)?.into_actor()?;
+    let user_store_degraded = mailbox::DegradedMode::default();
+    actor_gauges.register_store("user_store", user_store_degraded.clone());
+    let user_event_persistor_recipient = actor_gauges
+        .monitor(
+            "user_persistence",
+            user_event_log
+                .mailbox_capacity(mailbox_config.capacity)
+                .start(),
+            mailbox_config,
+            Some(user_store_degraded.clone()),
+        )
+        .recipient();
+    let (user_store, user_issues) = UserStore::new(
+        user_event_persistor_recipient,
+        saved_events,
+        config.clock.clone(),
+    );
+    let user_store = user_store.with_username_policy(config.username_policy.clone());
+    let username_violations = user_store.username_violations();
+    let user_ids = user_store.user_ids();
+    if !username_violations.is_empty() {
+        println!(
+            "WARNING: {} usernames violate the username policy, they are kept but could not be registered today",
+            username_violations.len()
+        );
+        for (user_id, username, violation) in &username_violations {
+            println!("  {user_id} {username:?}: {violation}");
+        }
+    }
+    let user_store_addr = actor_gauges.monitor(
+        "user_store",
+        user_store
+            .with_mailbox(mailbox_config.capacity, user_store_degraded)
+            .with_handler_trace(actor_gauges.trace_handlers("user_store", mailbox_config))
+            .start(),
+        mailbox_config,
+        None,
+    );
+
+    // Canvas Store Setup
+    // Same constraints as for the user store
+    let (saved_events, canvas_event_log) =
+        EventLogPersistenceJson::new(&config.canvas_event_log)?.into_actor()?;
+    let canvas_store_degraded = mailbox::DegradedMode::default();
+    actor_gauges.register_store("canvas_store", canvas_store_degraded.clone());
+    let canvas_event_persistor_recipient = actor_gauges
+        .monitor(
+            "canvas_persistence",
+            canvas_event_log
+                .mailbox_capacity(mailbox_config.capacity)
+                .start(),
+            mailbox_config,
+            Some(canvas_store_degraded.clone()),
+        )
+        .recipient();
+    let replay_cache = std::sync::Arc::new(ReplayCache::default());
+    let (canvas_store, mut canvas_issues) = CanvasStore::new(
+        canvas_event_persistor_recipient,
+        saved_events,
+        config.quota_limits.clone(),
+        config.clock.clone(),
+    );
+    // users deleted before their memberships were removed, or a user eventlog of another deployment
+    let unknown_claimants = canvas_store.unknown_claimants(&user_ids);
+    canvas_issues.extend(unknown_claimants.iter().map(|(user_id, canvas_ids)| {
+        ReplayIssue::invariant(format!(
+            "Unknown user {user_id} claims canvases {}",
+            canvas_ids.join(", ")
+        ))
+    }));
+    let replay_issues = ReplayIssues {
+        user: user_issues,
+        canvas: canvas_issues,
+    };
+    if !replay_issues.is_empty() {
+        match config.replay_mode {
+            ReplayMode::Strict => {
+                // the started actors are stopped together with the system
+                return Err(std::io::Error::other(format!(
+                    "Eventlogs are inconsistent, start in tolerant replay mode to skip broken events\n{replay_issues}"
+                )));
+            }
+            ReplayMode::Tolerant => {
+                println!("WARNING: eventlogs are inconsistent, started with the events that could be applied");
+                print!("{replay_issues}");
+                println!("WARNING: listed at /admin/api/replay-issues, run verify for details");
+            }
+        }
+    }
+    let canvas_store_addr = actor_gauges.monitor(
+        "canvas_store",
+        canvas_store
+            .with_mailbox(mailbox_config.capacity, canvas_store_degraded)
+            .with_handler_trace(actor_gauges.trace_handlers("canvas_store", mailbox_config))
+            .with_deletion_grace(config.deletion_grace)
+            .with_quarantine_retention(config.quarantine_retention)
+            .with_replay_cache(replay_cache.clone())
+            .with_unique_names(config.unique_canvas_names)
+            .start(),
+        mailbox_config,
+        None,
+    );
+    // strict replay refused to start above, tolerant replay repairs the claims
+    if !unknown_claimants.is_empty() {
+        println!(
+            "WARNING: removing the claims of {} users unknown to the UserStore",
+            unknown_claimants.len()
+        );
+    }
+    for user_id in unknown_claimants.into_keys() {
+        canvas_store_addr.do_send(PurgeUserMessage { user_id });
+    }
+
+    // Argon Setup, inspired by OWASP Password Storage Cheat Sheet
+    // parameters can be configured or calibrated for the host, see password.rs
+    let argon_params = config
+        .password_hash_config
+        .build_params()
+        .map_err(|_| std::io::Error::other("Failed to create argon2 params"))?;
+    println!(
+        "Argon2 params: m={}KiB t={} p={}",
+        argon_params.m_cost(),
+        argon_params.t_cost(),
+        argon_params.p_cost()
+    );
+
+    // Templating
+    // Handlebar stores compiled templates, so it needs to be shared between threads
+    println!("Template dir: {}", config.template_dir);
+    let handlebars = {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_dev_mode(HANDLEBARS_DEV);
+        templates::register_helpers(&mut handlebars);
+        // DirectorySourceOptions is non_exhaustive, so we need to use the default method and then modify the fields we want
+        // for some reason using struct expansion and ..Default::default() does not work
+        let mut source_options = DirectorySourceOptions::default();
+        source_options.tpl_extension = ".html".to_owned();
+        // a binary with the frontend embedded only reads a templates dir that exists, it overrides the embedded one
+        if !cfg!(feature = "embed-frontend") || Path::new(&config.template_dir).is_dir() {
+            handlebars
+                .register_templates_directory(&config.template_dir, source_options)
+                .map_err(|e| std::io::Error::other(format!("Failed to register templates: {e}")))?;
+        }
+        templates::register_embedded_templates(&mut handlebars)
+            .map_err(|e| std::io::Error::other(format!("Failed to register templates: {e}")))?;
+        web::Data::new(handlebars)
+    };
+
+    // Admin action log, written synchronously by the admin endpoints
+    let admin_action_log = web::Data::new(admin::AdminActionLog::open(
+        &config.admin_action_log,
+        config.clock.clone(),
+    )?);
+
+    // Maintenance, shared by the handlers and the canvas server
+    if config.maintenance.enabled {
+        println!("WARNING: starting in read-only maintenance");
+    }
+    let maintenance =
+        std::sync::Arc::new(maintenance_mode::MaintenanceState::new(config.maintenance));
+
+    // Websocket Handler
+    let (canvas_server, canvas_server_handle) = CanvasSocketServer::new(
+        std::sync::Arc::new(canvas_store_addr.clone().recipient::<GetCanvasMessage>()),
+        canvas_store_addr.clone().recipient(),
+        config.connection_limits,
+        config.shape_limits.clone(),
+        config.quota_limits,
+        config.flush_policy,
+        config.clock.clone(),
+    );
+    let canvas_server = canvas_server
+        .with_feature_flags(config.feature_flags.clone())
+        .with_handoff_max_age(config.handoff_max_age)
+        .with_diagnostics_alarm(config.diagnostics_alarm.clone())
+        .with_conflict_window(config.conflict_window)
+        .with_maintenance(maintenance.clone())
+        .with_comment_level(config.comment_level);
+    canvas_store_addr.do_send(RegisterCanvasServerMessage {
+        handle: canvas_server_handle.clone(),
+    });
+
+    // Activity digests of the canvas owners, checked every digest interval
+    let digest_scheduler = canvas::digest::DigestScheduler::new(
+        canvas_store_addr.clone().recipient(),
+        canvas_store_addr.clone().recipient(),
+        config.notifier.clone(),
+        config.clock.clone(),
+        config.digest_interval,
+    )
+    .start();
+
+    let state = AppState {
+        handlebars,
+        register_user_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        get_user_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        get_usernames_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        get_users_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        update_password_hash_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        record_login_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        get_token_version_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        bump_token_version_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        delete_user_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        issue_password_reset_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        complete_password_reset_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        set_user_preferences_recipient: web::Data::new(user_store_addr.clone().recipient()),
+        user_activity_tracker: web::Data::new(authentication::UserActivityTracker::new(
+            user_store_addr.recipient::<TouchUserMessage>(),
+            authentication::USER_ACTIVITY_DEBOUNCE,
+        )),
+        jwt_refresh_cache: web::Data::new(authentication::JWTRefreshCache::new(
+            authentication::JWT_REFRESH_CACHE_TTL,
+            authentication::JWT_REFRESH_CACHE_CAPACITY,
+        )),
+        create_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_user_claims_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_user_canvases_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        record_canvas_visit_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        set_canvas_preference_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        set_canvas_order_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        add_user_to_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        add_users_to_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        request_canvas_access_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        resolve_access_request_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        update_canvas_state_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        update_canvas_settings_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        update_canvas_tags_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        rename_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        update_canvas_feature_flags_recipient: web::Data::new(
+            canvas_store_addr.clone().recipient(),
+        ),
+        update_canvas_palette_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_canvas_records_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_canvas_membership_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_owned_canvases_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        purge_user_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        purge_deleted_canvases_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_user_access_level_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_canvas_quota_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        delete_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        restore_canvas_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        create_api_token_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        get_api_tokens_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        revoke_api_token_recipient: web::Data::new(canvas_store_addr.clone().recipient()),
+        resolve_api_token_recipient: web::Data::new(canvas_store_addr.recipient()),
+        run_digests_recipient: web::Data::new(digest_scheduler.recipient()),
+        token_rate_limiter: web::Data::new(TokenRateLimiter::default()),
+        access_poll_limiter: web::Data::new(canvas::AccessPollLimiter::default()),
+        access_request_limiter: web::Data::new(
+            canvas::access_requests::AccessRequestLimiter::default(),
+        ),
+        preferences_limiter: web::Data::new(preferences::PreferencesLimiter::default()),
+        admins: web::Data::new(admin::Admins::new(config.admins)),
+        trusted_proxies: web::Data::new(connection::TrustedProxies::new(config.trusted_proxies)),
+        websocket_auth: web::Data::new(config.websocket_auth),
+        cookie_factory: web::Data::new(authentication::CookieFactory::new(config.cookie_policy)),
+        guest_policy: web::Data::new(config.guest_policy),
+        maintenance: web::Data::from(maintenance),
+        retention_policy: web::Data::new(config.retention_policy),
+        shape_limits: web::Data::new(config.shape_limits),
+        print_limits: web::Data::new(config.print_limits),
+        feature_flags: web::Data::new(config.feature_flags),
+        render_monitor: web::Data::new(templates::RenderMonitor::default()),
+        admin_action_log,
+        canvas_server_handle: web::Data::new(canvas_server_handle),
+        replay_cache: web::Data::from(replay_cache),
+        activity_cache: web::Data::new(ActivityCache::default()),
+        actor_gauges: web::Data::new(actor_gauges),
+        replay_issues: web::Data::new(replay_issues),
+        preflight: web::Data::new(preflight),
+        security_headers: security::SecurityHeadersService::new(&config.public_origin),
+        public_origin: web::Data::new(config.public_origin),
+        dist_dir: config.dist_dir,
+        argon_params,
+        default_locale: config.default_locale,
+        clock: web::Data::from(config.clock),
+        notifier: web::Data::from(config.notifier),
+        #[cfg(feature = "dev")]
+        reload_events: {
+            let reload_events = std::sync::Arc::new(dev::ReloadEvents::new(config.template_dir));
+            dev::spawn_template_watcher(&reload_events, dev::POLL_INTERVAL, dev::DEBOUNCE);
+            web::Data::from(reload_events)
+        },
+    };
+
+    Ok((state, canvas_server.run()))
+}
+
+/// Live reload endpoints, they don't exist in release builds, see dev.rs
+#[cfg(feature = "dev")]
+fn dev_service(cfg: &mut web::ServiceConfig, state: &AppState) {
+    cfg.app_data(state.reload_events.clone());
+    dev::dev_service(cfg);
+}
+
+#[cfg(not(feature = "dev"))]
+fn dev_service(_: &mut web::ServiceConfig, _: &AppState) {}
+
+async fn root_request_handler(
+    request: HttpRequest,
+    // handlebars: web::Data<Handlebars<'_>>
+) -> actix_web::Result<impl Responder> {
+    templates::serve_index(&request).await
+}
+
+/// Composes the App from the shared state, called once per worker by the HttpServer factory
+/// `HttpServer::new(move || build_app(&state))`
+pub fn build_app(
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    // Uses some inspiration taken from https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id for configuration
+    // pepper/secret not used
+    // save in state to avoid re-creating the argon2 instance for every request and possibly mixing configurations
+    // created for every worker thread
+    let argon2 = web::Data::new(password::argon2_with_params(state.argon_params.clone()));
+
+    App::new()
+        // .wrap(Logger::default())
+        .app_data(state.handlebars.clone())
+        .app_data(state.register_user_recipient.clone())
+        .app_data(state.get_user_recipient.clone())
+        .app_data(state.get_usernames_recipient.clone())
+        .app_data(state.get_users_recipient.clone())
+        .app_data(state.update_password_hash_recipient.clone())
+        .app_data(state.record_login_recipient.clone())
+        .app_data(state.get_token_version_recipient.clone())
+        .app_data(state.bump_token_version_recipient.clone())
+        .app_data(state.delete_user_recipient.clone())
+        .app_data(state.issue_password_reset_recipient.clone())
+        .app_data(state.complete_password_reset_recipient.clone())
+        .app_data(state.set_user_preferences_recipient.clone())
+        .app_data(state.user_activity_tracker.clone())
+        .app_data(state.jwt_refresh_cache.clone())
+        .app_data(state.create_canvas_recipient.clone())
+        .app_data(state.get_user_claims_recipient.clone())
+        .app_data(state.get_user_canvases_recipient.clone())
+        .app_data(state.record_canvas_visit_recipient.clone())
+        .app_data(state.set_canvas_preference_recipient.clone())
+        .app_data(state.set_canvas_order_recipient.clone())
+        .app_data(state.add_user_to_canvas_recipient.clone())
+        .app_data(state.add_users_to_canvas_recipient.clone())
+        .app_data(state.request_canvas_access_recipient.clone())
+        .app_data(state.resolve_access_request_recipient.clone())
+        .app_data(state.update_canvas_state_recipient.clone())
+        .app_data(state.update_canvas_settings_recipient.clone())
+        .app_data(state.update_canvas_tags_recipient.clone())
+        .app_data(state.rename_canvas_recipient.clone())
+        .app_data(state.update_canvas_feature_flags_recipient.clone())
+        .app_data(state.update_canvas_palette_recipient.clone())
+        .app_data(state.get_canvas_recipient.clone())
+        .app_data(state.get_canvas_records_recipient.clone())
+        .app_data(state.get_canvas_membership_recipient.clone())
+        .app_data(state.get_owned_canvases_recipient.clone())
+        .app_data(state.purge_user_recipient.clone())
+        .app_data(state.get_user_access_level_recipient.clone())
+        .app_data(state.get_canvas_quota_recipient.clone())
+        .app_data(state.delete_canvas_recipient.clone())
+        .app_data(state.restore_canvas_recipient.clone())
+        .app_data(state.create_api_token_recipient.clone())
+        .app_data(state.get_api_tokens_recipient.clone())
+        .app_data(state.revoke_api_token_recipient.clone())
+        .app_data(state.resolve_api_token_recipient.clone())
+        .app_data(state.token_rate_limiter.clone())
+        .app_data(state.access_poll_limiter.clone())
+        .app_data(state.access_request_limiter.clone())
+        .app_data(state.preferences_limiter.clone())
+        .app_data(state.admins.clone())
+        .app_data(state.trusted_proxies.clone())
+        .app_data(state.websocket_auth.clone())
+        .app_data(state.cookie_factory.clone())
+        .app_data(state.guest_policy.clone())
+        .app_data(state.maintenance.clone())
+        .app_data(state.retention_policy.clone())
+        .app_data(state.shape_limits.clone())
+        .app_data(state.print_limits.clone())
+        .app_data(state.feature_flags.clone())
+        .app_data(state.render_monitor.clone())
+        .app_data(state.admin_action_log.clone())
+        .app_data(state.canvas_server_handle.clone())
+        .app_data(state.replay_cache.clone())
+        .app_data(state.activity_cache.clone())
+        .app_data(state.actor_gauges.clone())
+        .app_data(state.replay_issues.clone())
+        .app_data(state.preflight.clone())
+        .app_data(state.clock.clone())
+        .app_data(state.notifier.clone())
+        .app_data(state.public_origin.clone())
+        .app_data(argon2)
+        // scopes with other limits override these
+        .app_data(forms::form_config(forms::AUTH_BODY_LIMIT))
+        .app_data(forms::json_config(forms::AUTH_BODY_LIMIT))
+        .configure(user::user_service)
+        .configure(canvas::canvas_service)
+        .configure(admin::admin_service)
+        .configure(api_docs::api_docs_service)
+        .configure(|cfg| dev_service(cfg, state))
+        .route("/", web::get().to(root_request_handler))
+        .wrap(messages::LocalizeService::new(state.default_locale))
+        .wrap(spa::SPAService)
+        .wrap(middleware::NormalizePath::trim())
+        .wrap(state.security_headers.clone())
+        .configure(|cfg| frontend_service(cfg, state))
+}
+
+/// Frontend files of the vite build, from the binary with the embed-frontend feature
+#[cfg(feature = "embed-frontend")]
+fn frontend_service(cfg: &mut web::ServiceConfig, _: &AppState) {
+    assets::embedded_frontend_service(cfg);
+}
+
+#[cfg(not(feature = "embed-frontend"))]
+fn frontend_service(cfg: &mut web::ServiceConfig, state: &AppState) {
+    cfg.service(actix_files::Files::new("/", &state.dist_dir).index_file("index.html"));
+}
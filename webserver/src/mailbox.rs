@@ -0,0 +1,706 @@
+use actix::dev::{MessageResponse, OneshotSender, ToEnvelope};
+use actix::prelude::*;
+use serde::Serialize;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Bounded mailboxes and backlog monitoring of the stores and their persistence actors
+// actix does not expose the length of a mailbox, so monitored actors are fronted by a MailboxProbe
+// The probe forwards every message unchanged and counts it until the actor answered,
+// queued is the number of messages waiting in the mailbox plus the one being handled
+// Saturated persistence switches its store into a read-only DegradedMode until the backlog drained
+// The stores time their handlers with a HandlerTrace, from receipt until the response resolved,
+// a handler awaiting a slow persistence write is reported with the type of its message
+
+/// actix starts every actor with 16, a single busy canvas fills that with one burst of shape events
+/// 256 absorbs bursts of many sessions while the disk is briefly slow, senders wait once it is full
+/// The saturation threshold at 3/4 leaves 64 messages to warn and degrade before senders block,
+/// a larger mailbox only hides a stalled disk for longer while every queued event is held in memory
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 256;
+
+/// Handling time of a store message above which a warning is logged
+pub const DEFAULT_SLOW_HANDLER_THRESHOLD: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// mailbox capacity of the monitored actors, senders wait while it is full
+    pub capacity: usize,
+    pub report_interval: Duration,
+    /// a report above this number of queued messages counts as saturated
+    pub saturation_threshold: usize,
+    /// consecutive saturated reports until the saturation is reported
+    pub saturation_reports: u32,
+    /// switch the store into read-only mode while its persistence is saturated
+    pub degrade_on_saturation: bool,
+    /// store messages handled slower than this are logged, see HandlerTrace
+    pub slow_handler_threshold: Duration,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_MAILBOX_CAPACITY,
+            report_interval: Duration::from_secs(5),
+            saturation_threshold: DEFAULT_MAILBOX_CAPACITY * 3 / 4,
+            saturation_reports: 3,
+            degrade_on_saturation: true,
+            slow_handler_threshold: DEFAULT_SLOW_HANDLER_THRESHOLD,
+        }
+    }
+}
+
+/// Snapshot of a MailboxGauge, listed by /admin/api/actors
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MailboxStatus {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub queued: usize,
+    /// most messages queued at once since the start
+    pub peak: usize,
+    pub handled: u64,
+    pub saturated: bool,
+}
+
+/// Queue estimate of a single actor, shared between its probe and the admin endpoint
+pub struct MailboxGauge {
+    name: &'static str,
+    capacity: usize,
+    queued: AtomicUsize,
+    peak: AtomicUsize,
+    handled: AtomicU64,
+    saturated_reports: AtomicU32,
+    saturated: AtomicBool,
+}
+
+impl MailboxGauge {
+    pub fn new(name: &'static str, capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            capacity,
+            queued: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            handled: AtomicU64::new(0),
+            saturated_reports: AtomicU32::new(0),
+            saturated: AtomicBool::new(false),
+        })
+    }
+
+    fn received(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(queued, Ordering::SeqCst);
+    }
+
+    fn completed(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        self.handled.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> MailboxStatus {
+        MailboxStatus {
+            name: self.name,
+            capacity: self.capacity,
+            queued: self.queued(),
+            peak: self.peak.load(Ordering::SeqCst),
+            handled: self.handled.load(Ordering::SeqCst),
+            saturated: self.saturated.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Records a self-report, returns Some(true) once saturated and Some(false) once drained
+    /// The backlog counts as drained at half the threshold, a queue hovering around it does not flap
+    fn report(&self, config: &MailboxConfig) -> Option<bool> {
+        let queued = self.queued();
+        if queued > config.saturation_threshold {
+            let reports = self.saturated_reports.fetch_add(1, Ordering::SeqCst) + 1;
+            if reports >= config.saturation_reports && !self.saturated.swap(true, Ordering::SeqCst)
+            {
+                return Some(true);
+            }
+            return None;
+        }
+
+        self.saturated_reports.store(0, Ordering::SeqCst);
+        if queued <= config.saturation_threshold / 2 && self.saturated.swap(false, Ordering::SeqCst)
+        {
+            return Some(false);
+        }
+        None
+    }
+}
+
+/// Read-only mode of a store, consulted by its mutating handlers
+#[derive(Clone, Default)]
+pub struct DegradedMode(Arc<AtomicBool>);
+
+impl DegradedMode {
+    pub fn is_active(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, active: bool) {
+        self.0.store(active, Ordering::SeqCst);
+    }
+}
+
+/// Fronts an actor and counts the messages forwarded to it until they are answered
+/// Recipients of the probe can be used in place of recipients of the actor
+pub struct MailboxProbe<A: Actor> {
+    addr: Addr<A>,
+    gauge: Arc<MailboxGauge>,
+    config: MailboxConfig,
+    /// store switched into read-only mode while this actor is saturated
+    degraded: Option<DegradedMode>,
+}
+
+impl<A: Actor> Actor for MailboxProbe<A> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // forwarding is immediate, the probe only buffers while the actor is full itself
+        ctx.set_mailbox_capacity(self.config.capacity);
+        ctx.run_interval(self.config.report_interval, |probe, _| probe.report());
+    }
+}
+
+impl<A: Actor> MailboxProbe<A> {
+    fn report(&self) {
+        let queued = self.gauge.queued();
+        match self.gauge.report(&self.config) {
+            Some(true) => {
+                println!(
+                    "WARNING: mailbox of {} is saturated, {queued} messages queued for {} reports",
+                    self.gauge.name, self.config.saturation_reports
+                );
+                if let Some(degraded) = self.degraded.as_ref() {
+                    if self.config.degrade_on_saturation {
+                        println!("WARNING: {} switched to read-only mode", self.gauge.name);
+                        degraded.set(true);
+                    }
+                }
+            }
+            Some(false) => {
+                println!(
+                    "Mailbox of {} drained, {queued} messages queued",
+                    self.gauge.name
+                );
+                if let Some(degraded) = self.degraded.as_ref() {
+                    degraded.set(false);
+                }
+            }
+            None if queued > 0 => {
+                println!("Mailbox of {}: {queued} messages queued", self.gauge.name)
+            }
+            None => (),
+        }
+    }
+}
+
+/// Answer of a forwarded message, the sender of the probe waits for the answer of the actor
+/// If the actor is gone the answer is dropped, the sender sees a MailboxError
+pub struct Forwarded<M: Message>(Pin<Box<dyn Future<Output = Option<M::Result>>>>);
+
+impl<A: Actor, M: Message + 'static> MessageResponse<A, M> for Forwarded<M> {
+    fn handle(self, _: &mut A::Context, tx: Option<OneshotSender<M::Result>>) {
+        actix::spawn(async move {
+            if let (Some(result), Some(tx)) = (self.0.await, tx) {
+                let _ = tx.send(result);
+            }
+        });
+    }
+}
+
+impl<A, M> Handler<M> for MailboxProbe<A>
+where
+    A: Actor + Handler<M>,
+    A::Context: ToEnvelope<A, M>,
+    M: Message + Send + 'static,
+    M::Result: Send,
+{
+    type Result = Forwarded<M>;
+
+    fn handle(&mut self, msg: M, _: &mut Self::Context) -> Self::Result {
+        self.gauge.received();
+        let gauge = self.gauge.clone();
+        // enqueued right away, forwarded messages keep their order
+        let request = self.addr.send(msg);
+        Forwarded(Box::pin(async move {
+            let result = request.await;
+            gauge.completed();
+            result.ok()
+        }))
+    }
+}
+
+/// Slowest message type seen by a HandlerTrace
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SlowHandler {
+    pub message: &'static str,
+    pub duration_ms: u64,
+}
+
+/// Snapshot of a HandlerTrace, listed by /admin/api/actors
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HandlerStatus {
+    pub name: &'static str,
+    /// messages handled whose response is not resolved yet
+    pub in_flight: usize,
+    pub peak_in_flight: usize,
+    pub handled: u64,
+    /// messages handled slower than the threshold
+    pub slow: u64,
+    pub last_slow: Option<SlowHandler>,
+}
+
+/// Handling times and in-flight messages of a store
+/// Shared between the store, which starts a HandlerTimer per message, and the admin endpoint
+pub struct HandlerTrace {
+    name: &'static str,
+    slow_threshold: Duration,
+    in_flight: AtomicUsize,
+    peak_in_flight: AtomicUsize,
+    handled: AtomicU64,
+    slow: AtomicU64,
+    last_slow: Mutex<Option<SlowHandler>>,
+}
+
+impl HandlerTrace {
+    pub fn new(name: &'static str, slow_threshold: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            slow_threshold,
+            in_flight: AtomicUsize::new(0),
+            peak_in_flight: AtomicUsize::new(0),
+            handled: AtomicU64::new(0),
+            slow: AtomicU64::new(0),
+            last_slow: Mutex::new(None),
+        })
+    }
+
+    /// Starts timing a message of type M, the timer stops once it is dropped
+    pub fn start<M>(self: &Arc<Self>) -> HandlerTimer {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        let message = std::any::type_name::<M>();
+        HandlerTimer {
+            trace: self.clone(),
+            message: message.rsplit("::").next().unwrap_or(message),
+            started: Instant::now(),
+        }
+    }
+
+    fn finished(&self, message: &'static str, elapsed: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        if elapsed <= self.slow_threshold {
+            return;
+        }
+
+        self.slow.fetch_add(1, Ordering::SeqCst);
+        println!(
+            "WARNING: {} handled {message} in {}ms, {} other messages in flight",
+            self.name,
+            elapsed.as_millis(),
+            self.in_flight.load(Ordering::SeqCst)
+        );
+        *self.last_slow.lock().unwrap() = Some(SlowHandler {
+            message,
+            duration_ms: elapsed.as_millis() as u64,
+        });
+    }
+
+    pub fn status(&self) -> HandlerStatus {
+        HandlerStatus {
+            name: self.name,
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            peak_in_flight: self.peak_in_flight.load(Ordering::SeqCst),
+            handled: self.handled.load(Ordering::SeqCst),
+            slow: self.slow.load(Ordering::SeqCst),
+            last_slow: self.last_slow.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Running timer of a message, reports the handling time to its HandlerTrace when dropped
+pub struct HandlerTimer {
+    trace: Arc<HandlerTrace>,
+    message: &'static str,
+    started: Instant,
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        self.trace.finished(self.message, self.started.elapsed());
+    }
+}
+
+///
+/// AtomicResponse stopping the timer once the future resolved
+/// The persistence awaited inside the future counts towards the handling time of the message
+///
+pub fn timed_atomic<A: Actor, R: 'static>(
+    timer: HandlerTimer,
+    future: ResponseActFuture<A, R>,
+) -> AtomicResponse<A, R> {
+    AtomicResponse::new(Box::pin(future.map(move |result, _, _| {
+        drop(timer);
+        result
+    })))
+}
+
+/// Gauges and read-only modes of all monitored actors
+#[derive(Default)]
+pub struct ActorGauges {
+    gauges: Mutex<Vec<Arc<MailboxGauge>>>,
+    degraded: Mutex<Vec<(&'static str, DegradedMode)>>,
+    handlers: Mutex<Vec<Arc<HandlerTrace>>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActorsStatus {
+    pub actors: Vec<MailboxStatus>,
+    /// stores currently in read-only mode
+    pub degraded: Vec<&'static str>,
+    pub handlers: Vec<HandlerStatus>,
+}
+
+impl ActorGauges {
+    /// Starts a probe in front of the actor, its gauge is listed under name
+    /// degraded is switched on while the actor is saturated, if the config allows it
+    pub fn monitor<A: Actor>(
+        &self,
+        name: &'static str,
+        addr: Addr<A>,
+        config: &MailboxConfig,
+        degraded: Option<DegradedMode>,
+    ) -> Addr<MailboxProbe<A>> {
+        let gauge = MailboxGauge::new(name, config.capacity);
+        self.gauges.lock().unwrap().push(gauge.clone());
+        MailboxProbe {
+            addr,
+            gauge,
+            config: config.clone(),
+            degraded,
+        }
+        .start()
+    }
+
+    /// Trace for the handlers of a store, listed under name
+    pub fn trace_handlers(&self, name: &'static str, config: &MailboxConfig) -> Arc<HandlerTrace> {
+        let trace = HandlerTrace::new(name, config.slow_handler_threshold);
+        self.handlers.lock().unwrap().push(trace.clone());
+        trace
+    }
+
+    /// Lists the read-only mode of a store
+    pub fn register_store(&self, name: &'static str, degraded: DegradedMode) {
+        self.degraded.lock().unwrap().push((name, degraded));
+    }
+
+    pub fn status(&self) -> ActorsStatus {
+        ActorsStatus {
+            actors: self
+                .gauges
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|gauge| gauge.status())
+                .collect(),
+            degraded: self
+                .degraded
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, degraded)| degraded.is_active())
+                .map(|(name, _)| *name)
+                .collect(),
+            handlers: self
+                .handlers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|trace| trace.status())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::error::CanvasStoreError;
+    use crate::canvas::quota::QuotaLimits;
+    use crate::canvas::store::{
+        CanvasState, CanvasStateAction, CanvasStore, CanvasStoreEvents, CreateCanvas,
+        CreateCanvasMessage, UpdateCanvasStateMessage,
+    };
+    use crate::clock;
+    use crate::persistence::{EventLogPersistenceActorJson, PersistEventMessage};
+    use crate::userstore::{GetUserMessage, RegisterUser, RegisterUserMessage, UserStore};
+    use std::io::Write;
+
+    /// Writer of a congested disk, every event takes delay to be written
+    struct SlowWriter {
+        delay: Duration,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // events end with a newline, so this sleeps once per event
+            if buf == b"\n" {
+                std::thread::sleep(self.delay);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_config() -> MailboxConfig {
+        MailboxConfig {
+            capacity: 64,
+            report_interval: Duration::from_millis(10),
+            saturation_threshold: 4,
+            saturation_reports: 2,
+            degrade_on_saturation: true,
+            slow_handler_threshold: DEFAULT_SLOW_HANDLER_THRESHOLD,
+        }
+    }
+
+    fn update_state(expected_version: u64) -> UpdateCanvasStateMessage {
+        UpdateCanvasStateMessage {
+            canvas_id: "canvas".to_string(),
+            initiator_id: "owner".to_string(),
+            action: CanvasStateAction::Moderate,
+            expected_version,
+        }
+    }
+
+    #[test]
+    fn test_report_detects_sustained_saturation() {
+        let config = test_config();
+        let gauge = MailboxGauge::new("test", config.capacity);
+        for _ in 0..5 {
+            gauge.received();
+        }
+
+        // a single saturated report is not enough
+        assert_eq!(gauge.report(&config), None);
+        assert_eq!(gauge.report(&config), Some(true));
+        assert_eq!(gauge.report(&config), None);
+        assert!(gauge.status().saturated);
+
+        // below the threshold but above half of it, still saturated
+        gauge.completed();
+        gauge.completed();
+        assert_eq!(gauge.report(&config), None);
+        assert!(gauge.status().saturated);
+
+        gauge.completed();
+        assert_eq!(gauge.report(&config), Some(false));
+        assert_eq!(
+            gauge.status(),
+            MailboxStatus {
+                name: "test",
+                capacity: 64,
+                queued: 2,
+                peak: 5,
+                handled: 3,
+                saturated: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_interrupted_saturation_is_not_reported() {
+        let config = test_config();
+        let gauge = MailboxGauge::new("test", config.capacity);
+        for _ in 0..5 {
+            gauge.received();
+        }
+        assert_eq!(gauge.report(&config), None);
+
+        // the streak starts over once the queue shrinks
+        gauge.completed();
+        assert_eq!(gauge.report(&config), None);
+        gauge.received();
+        assert_eq!(gauge.report(&config), None);
+        assert!(!gauge.status().saturated);
+    }
+
+    #[actix_web::test]
+    async fn test_slow_persistence_degrades_store_until_drained() {
+        let config = test_config();
+        let gauges = ActorGauges::default();
+        let degraded = DegradedMode::default();
+        gauges.register_store("canvas_store", degraded.clone());
+
+        // blocking writes must not stall the probe, like a disk in another thread
+        let arbiter = Arbiter::new();
+        let persistence = EventLogPersistenceActorJson::start_in_arbiter(&arbiter.handle(), |_| {
+            EventLogPersistenceActorJson::with_writer(SlowWriter {
+                delay: Duration::from_millis(20),
+            })
+        });
+        let persistence = gauges.monitor(
+            "canvas_persistence",
+            persistence,
+            &config,
+            Some(degraded.clone()),
+        );
+
+        let store = CanvasStore::new(
+            persistence.clone().recipient(),
+            vec![CanvasStoreEvents::CanvasCreated {
+                timestamp: 0,
+                owner_id: "owner".to_string(),
+                canvas_id: "canvas".to_string(),
+                state: CanvasState::Active,
+                name: "Canvas".to_string(),
+            }],
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .with_mailbox(config.capacity, degraded.clone())
+        .start();
+
+        // a burst of events, written one every 20ms
+        let burst: Vec<_> = (0..20)
+            .map(|i| {
+                persistence.send(PersistEventMessage(CanvasStoreEvents::CanvasVisited {
+                    timestamp: i,
+                    user_id: "owner".to_string(),
+                    canvas_id: "canvas".to_string(),
+                }))
+            })
+            .collect();
+
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(degraded.is_active());
+        let status = gauges.status();
+        assert_eq!(status.degraded, vec!["canvas_store"]);
+        assert!(status.actors[0].saturated);
+        assert!(status.actors[0].queued > config.saturation_threshold);
+
+        // reads keep working, changes are rejected
+        assert!(matches!(
+            store.send(update_state(1)).await.unwrap(),
+            Err(CanvasStoreError::Degraded)
+        ));
+
+        for result in futures_util::future::join_all(burst).await {
+            assert!(result.unwrap().is_ok());
+        }
+        for _ in 0..50 {
+            if !degraded.is_active() {
+                break;
+            }
+            actix_web::rt::time::sleep(config.report_interval).await;
+        }
+        assert!(!degraded.is_active());
+        assert!(gauges.status().degraded.is_empty());
+        assert_eq!(store.send(update_state(1)).await.unwrap().unwrap().0, 2);
+
+        let status = &gauges.status().actors[0];
+        assert_eq!(status.queued, 0);
+        assert!(status.peak > config.saturation_threshold);
+        assert_eq!(status.handled, 21);
+        arbiter.stop();
+    }
+
+    #[actix_web::test]
+    async fn test_slow_persistence_is_traced_per_message() {
+        let config = MailboxConfig::default();
+        let gauges = ActorGauges::default();
+
+        // every event takes twice the threshold to be written
+        let arbiter = Arbiter::new();
+        let slow_persistence = || {
+            EventLogPersistenceActorJson::start_in_arbiter(&arbiter.handle(), |_| {
+                EventLogPersistenceActorJson::with_writer(SlowWriter {
+                    delay: Duration::from_millis(500),
+                })
+            })
+        };
+        let user_store =
+            UserStore::new(slow_persistence().recipient(), Vec::new(), clock::system())
+                .0
+                .with_handler_trace(gauges.trace_handlers("user_store", &config))
+                .start();
+        let canvas_store = CanvasStore::new(
+            slow_persistence().recipient(),
+            Vec::new(),
+            QuotaLimits::default(),
+            clock::system(),
+        )
+        .0
+        .with_handler_trace(gauges.trace_handlers("canvas_store", &config))
+        .start();
+
+        let registered = user_store.send(RegisterUserMessage {
+            user: RegisterUser {
+                email: "alice@example.com".to_string(),
+                username: "alice".to_string(),
+                password_hash: "hash".to_string(),
+            },
+        });
+        let created = canvas_store.send(CreateCanvasMessage {
+            canvas: CreateCanvas {
+                name: "Canvas".to_string(),
+                owner_id: "alice".to_string(),
+            },
+            auto_rename: false,
+        });
+
+        // the persistence is awaited inside the AtomicResponse, the store waits for it
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        let in_flight: Vec<_> = gauges
+            .status()
+            .handlers
+            .iter()
+            .map(|handlers| handlers.in_flight)
+            .collect();
+        assert_eq!(in_flight, [1, 1]);
+
+        let (registered, created) = futures_util::join!(registered, created);
+        assert!(registered.unwrap().is_ok());
+        assert!(created.unwrap().is_ok());
+        // reads are not slow
+        assert!(user_store
+            .send(GetUserMessage {
+                username_email: Some("alice".to_string()),
+                user_id: None,
+            })
+            .await
+            .unwrap()
+            .is_some());
+
+        let handlers = gauges.status().handlers;
+        for (handlers, message, handled) in [
+            (&handlers[0], "RegisterUserMessage", 2),
+            (&handlers[1], "CreateCanvasMessage", 1),
+        ] {
+            assert_eq!(handlers.in_flight, 0);
+            assert_eq!(handlers.peak_in_flight, 1);
+            assert_eq!(handlers.handled, handled);
+            assert_eq!(handlers.slow, 1);
+            let last_slow = handlers.last_slow.as_ref().unwrap();
+            assert_eq!(last_slow.message, message);
+            assert!(last_slow.duration_ms >= 500);
+        }
+        arbiter.stop();
+    }
+}
@@ -1,157 +1,492 @@
-use actix::prelude::*;
-use actix_web::{
-    middleware::{self},
-    web, App, HttpRequest, HttpServer, Responder,
-};
-use argon2::Argon2;
-use canvas::{
-    server::CanvasSocketServer,
-    store::{
-        AddUserToCanvasMessage, CanvasStore, CreateCanvasMessage, GetCanvasMessage,
-        GetUserClaimsMessage, UpdateCanvasStateMessage,
+use actix_web::HttpServer;
+use clap::{Args, Parser, Subcommand};
+use futures_util::try_join;
+use std::time::Duration;
+use webserver::{
+    authentication::{CookiePolicy, CookieSameSite, WebSocketAuth},
+    canvas::{
+        bus,
+        comments::DEFAULT_COMMENT_LEVEL,
+        diagnostics::DiagnosticsAlarm,
+        digest::DEFAULT_DIGEST_INTERVAL,
+        features::{FeatureFlags, FlagSpec},
+        guests::{GuestPolicy, DEFAULT_GUEST_LIFETIME},
+        handoff::DEFAULT_HANDOFF_MAX_AGE,
+        orphans::{CANVAS_LOG_DIR, DEFAULT_QUARANTINE_RETENTION},
+        print::PrintLimits,
+        provenance::DEFAULT_CONFLICT_WINDOW,
+        retention::{Retention, RetentionPolicy},
+        store::{AccessLevel, DEFAULT_DELETION_GRACE},
+        validation::ShapeLimits,
     },
+    encryption::{self, EventLogKey},
+    instance,
+    mailbox::MailboxConfig,
+    maintenance,
+    maintenance_mode::MaintenanceWindow,
+    password,
+    persistence::ReplayMode,
+    recovery,
+    security::PublicOrigin,
+    seed, templates,
+    username::UsernamePolicy,
+    ServerConfig, CANVAS_EVENT_LOG, USER_EVENT_LOG,
 };
-use futures_util::try_join;
-use handlebars::{DirectorySourceOptions, Handlebars};
-use persistence::EventLogPersistenceJson;
-use userstore::{GetUserMessage, RegisterUserMessage, UserStore};
-
-mod authentication;
-mod canvas;
-mod persistence;
-mod spa;
-mod templates;
-mod user;
-mod userstore;
-
-#[cfg(feature = "dev")]
-static TEMPLATE_DIR: &str = "../.templates";
-#[cfg(not(feature = "dev"))]
-static TEMPLATE_DIR: &str = "../dist/.templates";
-
-#[cfg(feature = "dev")]
-static HANDLEBARS_DEV: bool = true;
-#[cfg(not(feature = "dev"))]
-static HANDLEBARS_DEV: bool = false;
-
-async fn root_request_handler(
-    request: HttpRequest,
-    // handlebars: web::Data<Handlebars<'_>>
-) -> actix_web::Result<impl Responder> {
-    templates::serve_index(&request).await
+
+#[derive(Parser)]
+#[command(about = "Drawing Canvas webserver and eventlog maintenance tooling")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// serve arguments, used if no subcommand is given
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(flatten)]
+    eventlog_key: EventLogKeyArgs,
+}
+
+/// Encryption at rest of the eventlogs, used by the server and the maintenance tooling alike
+#[derive(Args)]
+struct EventLogKeyArgs {
+    /// Base64 encoded 32 byte key, appended eventlog lines are encrypted, existing plaintext lines stay readable
+    #[arg(
+        long,
+        env = "CANVAS_EVENTLOG_KEY",
+        hide_env_values = true,
+        requires = "eventlog_key_id",
+        global = true
+    )]
+    eventlog_key: Option<String>,
+
+    /// Id of the eventlog key, stored with every encrypted line to name the key it needs
+    #[arg(long, env = "CANVAS_EVENTLOG_KEY_ID", global = true)]
+    eventlog_key_id: Option<String>,
+}
+
+impl EventLogKeyArgs {
+    fn install(&self) -> std::io::Result<()> {
+        let (Some(key), Some(key_id)) = (&self.eventlog_key, &self.eventlog_key_id) else {
+            return Ok(());
+        };
+        let key = EventLogKey::from_base64(key_id, key)?;
+        encryption::install(key)?;
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    password_hash_config: password::PasswordHashConfig,
+
+    /// User id allowed to use the admin endpoints, can be repeated
+    #[arg(long = "admin", env = "CANVAS_ADMINS", value_delimiter = ',')]
+    admins: Vec<String>,
+
+    /// Origin browsers reach the server at, e.g. https://canvas.example.org, http://localhost:1234 by default
+    #[arg(long, env = "CANVAS_PUBLIC_ORIGIN")]
+    public_origin: Option<PublicOrigin>,
+
+    /// Secret signing password reset links and flash cookies, at least 32 characters, random per start if omitted
+    #[arg(long, env = "CANVAS_RECOVERY_KEY", hide_env_values = true)]
+    recovery_key: Option<String>,
+
+    /// Reverse proxy whose X-Forwarded-For header is trusted, can be repeated
+    #[arg(
+        long = "trusted-proxy",
+        env = "CANVAS_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<std::net::IpAddr>,
+
+    /// Accept the token of a websocket handshake as ?auth= query parameter, proxies may log it with the url
+    #[arg(long, env = "CANVAS_WS_QUERY_AUTH")]
+    ws_query_auth: bool,
+
+    /// Print every persisted canvas event, see canvas::bus
+    #[arg(long, env = "CANVAS_LOG_EVENTS")]
+    log_canvas_events: bool,
+
+    /// Never admit visitors without an account as guests, even on canvases open for guests
+    #[arg(long, env = "CANVAS_DISABLE_GUESTS")]
+    disable_guests: bool,
+
+    /// Seconds a guest stays signed in, afterwards the guest is admitted with a new identity
+    #[arg(long, env = "CANVAS_GUEST_LIFETIME_SECS")]
+    guest_lifetime_secs: Option<u64>,
+
+    /// Start in read-only maintenance, e.g. for a planned migration, admins end it at runtime
+    #[arg(long, env = "CANVAS_MAINTENANCE")]
+    maintenance: bool,
+
+    /// Shown to users during the maintenance, e.g. the expected end
+    #[arg(long, env = "CANVAS_MAINTENANCE_MESSAGE")]
+    maintenance_message: Option<String>,
+
+    /// Directory of the handlebars templates, the vite build of them by default
+    #[arg(long, env = "CANVAS_TEMPLATE_DIR")]
+    template_dir: Option<String>,
+
+    /// Directory of the vite build served as the frontend, ignored with the embed-frontend feature
+    #[arg(long, env = "CANVAS_DIST_DIR")]
+    dist_dir: Option<String>,
+
+    /// Create missing directories of the eventlogs instead of refusing to start
+    #[arg(long, env = "CANVAS_CREATE_DATA_DIRS")]
+    create_data_dirs: bool,
+
+    /// Seed file with users and canvases created at startup if missing, see seed.example.toml
+    #[arg(long, env = "CANVAS_SEED")]
+    seed: Option<String>,
+
+    /// Abort the startup on inconsistent eventlogs or skip the broken events, strict in dev builds
+    #[arg(long, value_enum, env = "CANVAS_REPLAY_MODE")]
+    replay_mode: Option<ReplayMode>,
+
+    /// Days a deleted canvas can be restored before it is purged
+    #[arg(long, env = "CANVAS_DELETION_GRACE_DAYS")]
+    deletion_grace_days: Option<u64>,
+
+    /// Days eventlogs purged by an admin stay in the quarantine before they are removed
+    #[arg(long, env = "CANVAS_QUARANTINE_RETENTION_DAYS")]
+    quarantine_retention_days: Option<u64>,
+
+    /// Reject creating or renaming a canvas to a name its owner already uses, compared case insensitively
+    #[arg(long, env = "CANVAS_UNIQUE_NAMES")]
+    unique_canvas_names: bool,
+
+    /// Feature flag as name=on, name=off or name=<percent>%, :overridable lets owners switch it, can be repeated
+    #[arg(
+        long = "feature-flag",
+        env = "CANVAS_FEATURE_FLAGS",
+        value_delimiter = ','
+    )]
+    feature_flags: Vec<FlagSpec>,
+
+    /// Seconds a handoff written on shutdown stays usable, a deploy restarting later folds the eventlogs again
+    #[arg(long, env = "CANVAS_HANDOFF_MAX_AGE_SECS")]
+    handoff_max_age_secs: Option<u64>,
+
+    /// Events per minute on a single canvas that alarm its owners and moderators, 0 disables the check
+    #[arg(long, env = "CANVAS_ALARM_EVENTS_PER_MINUTE")]
+    alarm_events_per_minute: Option<u64>,
+
+    /// Seconds a canvas may fall behind syncing its eventlog before its owners and moderators are alarmed, 0 disables the check
+    #[arg(long, env = "CANVAS_ALARM_SAVE_LAG_SECS")]
+    alarm_save_lag_secs: Option<u64>,
+
+    /// Seconds within which updates of a shape by different users count as conflict in the canvas diagnostics
+    #[arg(long, env = "CANVAS_CONFLICT_WINDOW_SECS")]
+    conflict_window_secs: Option<u64>,
+
+    /// Seconds between two checks for due activity digests of canvas owners, daily by default
+    #[arg(long, env = "CANVAS_DIGEST_INTERVAL_SECS")]
+    digest_interval_secs: Option<u64>,
+
+    /// Word no new user may take as username besides the built-in ones, can be repeated
+    #[arg(
+        long = "reserved-username",
+        env = "CANVAS_RESERVED_USERNAMES",
+        value_delimiter = ','
+    )]
+    reserved_usernames: Vec<String>,
+
+    /// Allow letters and digits of any script in new usernames, not only ASCII ones
+    #[arg(long, env = "CANVAS_UNICODE_USERNAMES")]
+    unicode_usernames: bool,
+
+    /// Lowest access level that may comment on shapes: Read, Voice, Write, Moderate or Owner
+    #[arg(long, env = "CANVAS_COMMENT_LEVEL")]
+    comment_level: Option<AccessLevel>,
+
+    /// Milliseconds a store may take to handle a message before a warning names the message
+    #[arg(long, env = "CANVAS_SLOW_HANDLER_MS")]
+    slow_handler_ms: Option<u64>,
+
+    /// Accept shape attributes outside of the known ones, within the attribute budget of a shape
+    #[arg(long, env = "CANVAS_ALLOW_UNKNOWN_SHAPE_ATTRIBUTES")]
+    allow_unknown_shape_attributes: bool,
+
+    /// Shapes a session may have selected at the same time
+    #[arg(long, env = "CANVAS_MAX_SELECTED_SHAPES")]
+    max_selected_shapes: Option<usize>,
+
+    /// Temporary shapes a session may draw at the same time, its oldest one is removed beyond
+    #[arg(long, env = "CANVAS_MAX_TEMP_SHAPES")]
+    max_temp_shapes: Option<usize>,
+
+    /// Shapes shown by the print page of a canvas, larger canvases are cut off with a notice
+    #[arg(long, env = "CANVAS_PRINT_MAX_SHAPES")]
+    print_max_shapes: Option<usize>,
+
+    #[command(flatten)]
+    retention: RetentionArgs,
+
+    #[command(flatten)]
+    cookies: CookieArgs,
+}
+
+/// Attributes of the cookies the server sets
+#[derive(Args)]
+struct CookieArgs {
+    /// Mark cookies Secure, required once the server is reached through https
+    #[arg(long, env = "CANVAS_COOKIE_SECURE")]
+    cookie_secure: bool,
+
+    /// SameSite attribute of the cookies, none implies Secure
+    #[arg(long, value_enum, env = "CANVAS_COOKIE_SAME_SITE")]
+    cookie_same_site: Option<CookieSameSite>,
+
+    /// Domain attribute of the cookies, host only cookies if not set
+    #[arg(long, env = "CANVAS_COOKIE_DOMAIN")]
+    cookie_domain: Option<String>,
+
+    /// Path attribute of the cookies
+    #[arg(long, env = "CANVAS_COOKIE_PATH")]
+    cookie_path: Option<String>,
+}
+
+impl CookieArgs {
+    fn policy(self) -> CookiePolicy {
+        let default = CookiePolicy::default();
+        CookiePolicy {
+            secure: self.cookie_secure,
+            same_site: self.cookie_same_site.unwrap_or(default.same_site),
+            domain: self.cookie_domain,
+            path: self.cookie_path.unwrap_or(default.path),
+        }
+    }
+}
+
+/// Retention applied when canvas eventlogs are compacted, canvases can override it in their settings
+#[derive(Args)]
+struct RetentionArgs {
+    /// Access, state and settings changes kept: all, last:<count>, days:<days> or none
+    #[arg(long, env = "CANVAS_HISTORY_RETENTION")]
+    history_retention: Option<Retention>,
+
+    /// Read receipts kept: all, last:<count>, days:<days> or none
+    #[arg(long, env = "CANVAS_READ_RECEIPT_RETENTION")]
+    read_receipt_retention: Option<Retention>,
+}
+
+impl RetentionArgs {
+    fn policy(&self) -> RetentionPolicy {
+        let default = RetentionPolicy::default();
+        RetentionPolicy {
+            history: self.history_retention.unwrap_or(default.history),
+            read_receipts: self.read_receipt_retention.unwrap_or(default.read_receipts),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start the webserver (default)
+    Serve {
+        #[command(flatten)]
+        args: Box<ServeArgs>,
+    },
+    /// Print event counts and timestamps of an eventlog, lists lines that fail to deserialize
+    Inspect {
+        logfile: String,
+        /// Kind of eventlog, guessed from the file name if omitted
+        #[arg(long, value_enum)]
+        kind: Option<maintenance::LogKind>,
+    },
+    /// Replay the user and canvas eventlogs and check the store invariants
+    Verify,
+    /// Add the canvas header to canvas eventlogs written before it existed, the server must not be running
+    MigrateCanvasLogs,
+    /// Compact the eventlog of a canvas, the server must not be running
+    CompactCanvas {
+        canvas_id: String,
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // User Store
-    // User event store setup, creates persistence actor and user store actor
-    // persistence can be swapped out for a different implementation
-    // user store can later be replaced by a database
-    // all actors are represented by their recipient to allow for easy swapping of implementations
-    let user_event_log = EventLogPersistenceJson::new("user_eventlog.jsonl")
-        .expect("Failed to create or load user event log");
-    let (saved_events, user_event_log) = user_event_log
-        .into_actor()
-        .expect("Failed to read user event log");
-    let user_event_persistor_recipient = user_event_log.start().recipient();
-    let user_store_addr = UserStore::new(user_event_persistor_recipient, saved_events).start();
-
-    let register_user_receipient =
-        web::Data::new(user_store_addr.clone().recipient::<RegisterUserMessage>());
-    let get_user_receipient = web::Data::new(user_store_addr.recipient::<GetUserMessage>());
-
-    // Canvas Store Setup
-    // Same constraints as for the user store
-    let canvas_event_log = EventLogPersistenceJson::new("canvas_eventlog.jsonl")
-        .expect("Failed to create or load canvas event log");
-    let (saved_events, canvas_event_log) = canvas_event_log
-        .into_actor()
-        .expect("Failed to read canvas event log");
-    let canvas_event_persistor_recipient = canvas_event_log.start().recipient();
-    let canvas_store_addr = CanvasStore::new(canvas_event_persistor_recipient, saved_events)
-        .expect("Failed to parse persisted event log")
-        .start();
-
-    let create_canvas_receipient =
-        web::Data::new(canvas_store_addr.clone().recipient::<CreateCanvasMessage>());
-    let get_user_claims_receipient = web::Data::new(
-        canvas_store_addr
-            .clone()
-            .recipient::<GetUserClaimsMessage>(),
-    );
-    let add_user_to_canvas_receipient = web::Data::new(
-        canvas_store_addr
-            .clone()
-            .recipient::<AddUserToCanvasMessage>(),
-    );
-    let get_canvas_recipient =
-        web::Data::new(canvas_store_addr.clone().recipient::<GetCanvasMessage>());
-    let update_canvas_state_recipient = web::Data::new(
-        canvas_store_addr
-            .clone()
-            .recipient::<UpdateCanvasStateMessage>(),
-    );
-
-    // Argon Setup, inspired by OWASP Password Storage Cheat Sheet
-    let argon_params = argon2::Params::new(19 * 1024, 3, 2, None).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::Other, "Failed to create argon2 params")
-    })?;
-
-    // Templating
-    // Handlebar stores compiled templates, so it needs to be shared between threads
-    println!("Template dir: {}", TEMPLATE_DIR);
-    let handlebars = {
-        let mut handlebars = Handlebars::new();
-        handlebars.set_dev_mode(HANDLEBARS_DEV);
-        // DirectorySourceOptions is non_exhaustive, so we need to use the default method and then modify the fields we want
-        // for some reason using struct expansion and ..Default::default() does not work
-        let mut source_options = DirectorySourceOptions::default();
-        source_options.tpl_extension = ".html".to_owned();
-        handlebars
-            .register_templates_directory(TEMPLATE_DIR, source_options)
-            .expect("Failed to register templates");
-        web::Data::new(handlebars)
-    };
+    let cli = Cli::parse();
+    // before any eventlog is opened
+    cli.eventlog_key.install()?;
+    let command = cli.command.unwrap_or(Command::Serve {
+        args: Box::new(cli.serve),
+    });
 
-    // Websocket Handler
-    let (canvas_server, canvas_server_handle) =
-        CanvasSocketServer::new(get_canvas_recipient.into_inner());
-    let canvas_server = tokio::spawn(canvas_server.run());
+    match command {
+        Command::Serve { args } => {
+            install_instance_id()?;
+            serve(*args).await
+        }
+        Command::Inspect { logfile, kind } => {
+            let kind = kind.unwrap_or_else(|| maintenance::LogKind::infer(&logfile));
+            print!("{}", maintenance::inspect_log(&logfile, kind)?);
+            Ok(())
+        }
+        Command::Verify => {
+            let report = maintenance::verify_logs(USER_EVENT_LOG, CANVAS_EVENT_LOG, CANVAS_LOG_DIR)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            print!("{report}");
+            if !report.issues.is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::MigrateCanvasLogs => {
+            install_instance_id()?;
+            let report = maintenance::migrate_canvas_logs(".")?;
+            print!("{report}");
+            if !report.refused.is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::CompactCanvas {
+            canvas_id,
+            retention,
+        } => {
+            install_instance_id()?;
+            let report =
+                maintenance::compact_canvas(CANVAS_EVENT_LOG, &canvas_id, &retention.policy())?;
+            print!("{report}");
+            Ok(())
+        }
+    }
+}
 
-    // https://tokio.rs/tokio/tutorial/shared-state#on-using-stdsyncmutex
+/// Commands writing eventlogs stamp their lines, inspecting and verifying never creates the id
+fn install_instance_id() -> std::io::Result<()> {
+    let instance_id = instance::load_or_create(instance::INSTANCE_ID_FILE)?;
+    instance::install(&instance_id)
+}
+
+async fn serve(args: ServeArgs) -> std::io::Result<()> {
+    // read before bootstrap, a broken seed file should not leave half started actors behind
+    let seed_file = args.seed.as_deref().map(seed::SeedFile::load).transpose()?;
+    match &args.recovery_key {
+        Some(recovery_key) => recovery::install_key(recovery_key)?,
+        None => println!(
+            "WARNING: no --recovery-key configured, password reset links stop working after a restart"
+        ),
+    }
 
-    let http_server = HttpServer::new(move || {
-        // Uses some inspiration taken from https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id for configuration
-        // pepper/secret not used
-        // save in state to avoid re-creating the argon2 instance for every request and possibly mixing configurations
-        // created for every worker thread
-        let argon2 = web::Data::new(Argon2::new(
-            argon2::Algorithm::Argon2id,
-            argon2::Version::V0x13,
-            argon_params.clone(),
+    let default_alarm = DiagnosticsAlarm::default();
+    let default_mailbox = MailboxConfig::default();
+    let mut shape_limits = ShapeLimits::default();
+    shape_limits.attributes.allow_unknown = args.allow_unknown_shape_attributes;
+    if let Some(max_selected_shapes) = args.max_selected_shapes {
+        shape_limits.max_selected_shapes = max_selected_shapes;
+    }
+    if let Some(max_temp_shapes) = args.max_temp_shapes {
+        shape_limits.max_temp_shapes_per_session = max_temp_shapes;
+    }
+    let config = ServerConfig {
+        template_dir: args
+            .template_dir
+            .unwrap_or_else(|| templates::default_templates_dir().to_string()),
+        dist_dir: args
+            .dist_dir
+            .unwrap_or_else(|| templates::DIST_DIR.to_string()),
+        create_data_dirs: args.create_data_dirs,
+        password_hash_config: args.password_hash_config,
+        admins: args.admins,
+        public_origin: args.public_origin.unwrap_or_default(),
+        trusted_proxies: args.trusted_proxies,
+        websocket_auth: WebSocketAuth {
+            query_token: args.ws_query_auth,
+        },
+        cookie_policy: args.cookies.policy(),
+        guest_policy: GuestPolicy {
+            enabled: !args.disable_guests,
+            lifetime: args
+                .guest_lifetime_secs
+                .map_or(DEFAULT_GUEST_LIFETIME, Duration::from_secs),
+        },
+        maintenance: MaintenanceWindow {
+            enabled: args.maintenance,
+            message: args.maintenance_message,
+        },
+        replay_mode: args.replay_mode.unwrap_or_default(),
+        retention_policy: args.retention.policy(),
+        deletion_grace: args
+            .deletion_grace_days
+            .map_or(DEFAULT_DELETION_GRACE, |days| {
+                Duration::from_secs(days * 24 * 60 * 60)
+            }),
+        quarantine_retention: args
+            .quarantine_retention_days
+            .map_or(DEFAULT_QUARANTINE_RETENTION, |days| {
+                Duration::from_secs(days * 24 * 60 * 60)
+            }),
+        unique_canvas_names: args.unique_canvas_names,
+        feature_flags: FeatureFlags::from_specs(args.feature_flags),
+        handoff_max_age: args
+            .handoff_max_age_secs
+            .map_or(DEFAULT_HANDOFF_MAX_AGE, Duration::from_secs),
+        diagnostics_alarm: DiagnosticsAlarm {
+            events_per_minute: args
+                .alarm_events_per_minute
+                .map_or(default_alarm.events_per_minute, |limit| {
+                    (limit > 0).then_some(limit)
+                }),
+            save_lag: args
+                .alarm_save_lag_secs
+                .map_or(default_alarm.save_lag, |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                }),
+            ..default_alarm
+        },
+        conflict_window: args
+            .conflict_window_secs
+            .map_or(DEFAULT_CONFLICT_WINDOW, Duration::from_secs),
+        comment_level: args.comment_level.unwrap_or(DEFAULT_COMMENT_LEVEL),
+        digest_interval: args
+            .digest_interval_secs
+            .map_or(DEFAULT_DIGEST_INTERVAL, Duration::from_secs),
+        username_policy: UsernamePolicy::default()
+            .reserve(&args.reserved_usernames)
+            .with_unicode(args.unicode_usernames),
+        shape_limits,
+        print_limits: args
+            .print_max_shapes
+            .map_or_else(PrintLimits::default, |max_shapes| PrintLimits {
+                max_shapes,
+            }),
+        mailbox: MailboxConfig {
+            slow_handler_threshold: args.slow_handler_ms.map_or(
+                default_mailbox.slow_handler_threshold,
+                Duration::from_millis,
+            ),
+            ..default_mailbox
+        },
+        ..ServerConfig::default()
+    };
+    let shape_limits = config.shape_limits.clone();
+    let (state, canvas_server) = webserver::bootstrap(config)?;
+    if args.log_canvas_events {
+        tokio::spawn(bus::log_notifications(
+            state.canvas_server_handle().subscribe(),
         ));
+    }
+
+    if let Some(seed_file) = seed_file {
+        let report = seed::seed(&state, seed_file, &shape_limits).await?;
+        println!("Applied seed file {}", args.seed.unwrap_or_default());
+        print!("{report}");
+    }
+    let canvas_server = tokio::spawn(canvas_server);
+
+    // https://tokio.rs/tokio/tutorial/shared-state#on-using-stdsyncmutex
 
-        App::new()
-            // .wrap(Logger::default())
-            .app_data(handlebars.clone())
-            .app_data(register_user_receipient.clone())
-            .app_data(get_user_receipient.clone())
-            .app_data(create_canvas_receipient.clone())
-            .app_data(get_user_claims_receipient.clone())
-            .app_data(add_user_to_canvas_receipient.clone())
-            .app_data(update_canvas_state_recipient.clone())
-            .app_data(web::Data::new(canvas_server_handle.clone()))
-            .app_data(argon2)
-            .configure(user::user_service)
-            .configure(canvas::canvas_service)
-            .route("/", web::get().to(root_request_handler))
-            .wrap(spa::SPAService)
-            .wrap(middleware::NormalizePath::trim())
-            .service(actix_files::Files::new("/", "../dist").index_file("index.html"))
-    })
-    .bind(("127.0.0.1", 1234))?
-    .workers(3)
-    .run();
+    let http_server = HttpServer::new(move || webserver::build_app(&state))
+        .bind(("127.0.0.1", 1234))?
+        .workers(3)
+        .run();
 
     println!("Starting server at localhost:1234");
 
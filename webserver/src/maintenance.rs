@@ -0,0 +1,636 @@
+use crate::{
+    canvas::{
+        binding::{self, LogBinding},
+        events::CanvasEvents,
+        orphans::{self, CanvasRecord, LogClass, OrphanReport},
+        retention::{RetentionOverrides, RetentionPolicy, RetentionReport},
+        server::{self, CanvasSocketServer},
+        store::{self, CanvasStoreEvents, DEFAULT_DELETION_GRACE},
+    },
+    clock::{Clock, SystemClock},
+    instance,
+    persistence::{self, EventLogPersistenceJson, ReplayIssue, ReplayIssues},
+    userstore::{self, UserStoreEvents},
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+// Offline maintenance tooling for the eventlogs
+// Reuses the persistence readers and the replay logic of the stores
+// None of these functions should be run against logs of a running server
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogKind {
+    /// user_eventlog.jsonl
+    User,
+    /// canvas_eventlog.jsonl
+    CanvasStore,
+    /// eventlog of a single canvas
+    Canvas,
+}
+
+impl LogKind {
+    /// Guesses the kind of log from its file name, per canvas logs are named after the canvas id
+    pub fn infer(file_path: &str) -> Self {
+        let file_name = std::path::Path::new(file_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        match file_name {
+            "user_eventlog.jsonl" => LogKind::User,
+            "canvas_eventlog.jsonl" => LogKind::CanvasStore,
+            _ => LogKind::Canvas,
+        }
+    }
+
+    /// Checks if the raw event is a valid event of this log kind
+    fn validate(&self, event: Value) -> Result<(), serde_json::Error> {
+        match self {
+            LogKind::User => serde_json::from_value::<UserStoreEvents>(event).map(|_| ()),
+            LogKind::CanvasStore => serde_json::from_value::<CanvasStoreEvents>(event).map(|_| ()),
+            LogKind::Canvas => serde_json::from_value::<CanvasEvents>(event).map(|_| ()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InspectReport {
+    pub event_counts: BTreeMap<String, usize>,
+    pub first_timestamp: Option<u64>,
+    pub last_timestamp: Option<u64>,
+    /// line number (starting at 1) and deserialization error
+    pub invalid_lines: Vec<(usize, String)>,
+    /// canvas eventlogs only, missing header or header claiming another canvas than the file name
+    pub header_issue: Option<String>,
+    /// lines per instance that wrote them, more than one instance may point to a shared data dir
+    pub instance_counts: BTreeMap<String, usize>,
+}
+
+/// Instance of the lines written before the instance id was stamped
+pub const UNSTAMPED: &str = "<unstamped>";
+
+impl fmt::Display for InspectReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Events:")?;
+        for (event_type, count) in &self.event_counts {
+            writeln!(f, "  {event_type}: {count}")?;
+        }
+        match (self.first_timestamp, self.last_timestamp) {
+            (Some(first), Some(last)) => writeln!(f, "Timestamps: {first} - {last}")?,
+            _ => writeln!(f, "Timestamps: none")?,
+        }
+        writeln!(f, "Invalid lines: {}", self.invalid_lines.len())?;
+        for (line, error) in &self.invalid_lines {
+            writeln!(f, "  line {line}: {error}")?;
+        }
+        if let Some(header_issue) = &self.header_issue {
+            writeln!(f, "Header: {header_issue}")?;
+        }
+        writeln!(f, "Instances:")?;
+        for (instance_id, count) in &self.instance_counts {
+            writeln!(f, "  {instance_id}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts events per type and collects all lines that are not valid events of the given kind
+pub fn inspect_log(file_path: &str, kind: LogKind) -> Result<InspectReport, std::io::Error> {
+    let lines = EventLogPersistenceJson::open(file_path)?.read_lines::<Value>()?;
+
+    let mut report = InspectReport::default();
+    for (index, line) in lines.into_iter().enumerate() {
+        let line_number = index + 1;
+
+        let event = match line {
+            Ok(event) => event,
+            Err(e) => {
+                report.invalid_lines.push((line_number, e.to_string()));
+                continue;
+            }
+        };
+
+        let event_type = event
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("<untyped>")
+            .to_string();
+        let timestamp = event.get("timestamp").and_then(Value::as_u64);
+        let instance_id = instance::writer_of(&event).unwrap_or(UNSTAMPED);
+        *report
+            .instance_counts
+            .entry(instance_id.to_string())
+            .or_default() += 1;
+
+        if let Err(e) = kind.validate(event) {
+            report.invalid_lines.push((line_number, e.to_string()));
+            continue;
+        }
+
+        *report.event_counts.entry(event_type).or_default() += 1;
+        if let Some(timestamp) = timestamp {
+            report.first_timestamp.get_or_insert(timestamp);
+            report.last_timestamp = Some(timestamp);
+        }
+    }
+
+    if kind == LogKind::Canvas {
+        report.header_issue = canvas_header_issue(file_path)?;
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub users: usize,
+    pub canvases: usize,
+    pub issues: ReplayIssues,
+    /// canvas eventlogs of the data directory, see canvas::orphans
+    pub eventlogs: OrphanReport,
+}
+
+impl VerifyReport {
+    pub fn issue_count(&self) -> usize {
+        self.issues.user.len() + self.issues.canvas.len()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Users: {}", self.users)?;
+        writeln!(f, "Canvases: {}", self.canvases)?;
+        writeln!(f, "Canvas eventlogs: {}", self.eventlogs.files.len())?;
+        for class in [
+            LogClass::Active,
+            LogClass::SoftDeleted,
+            LogClass::Purgeable,
+            LogClass::Unknown,
+        ] {
+            writeln!(f, "  {}: {}", class.name(), self.eventlogs.count(class))?;
+        }
+        for file in self
+            .eventlogs
+            .files
+            .iter()
+            .filter(|file| file.class.is_purgeable())
+        {
+            writeln!(
+                f,
+                "  {} {}: {} bytes",
+                file.class.name(),
+                file.file_name,
+                file.size_bytes
+            )?;
+        }
+        writeln!(f, "Issues: {}", self.issue_count())?;
+        for line in self.issues.to_string().lines() {
+            writeln!(f, "  {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Canvas eventlogs are named after their canvas, the header has to claim the same canvas
+fn canvas_header_issue(file_path: &str) -> Result<Option<String>, std::io::Error> {
+    let canvas_id = std::path::Path::new(file_path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let is_empty = std::fs::metadata(file_path)?.len() == 0;
+
+    Ok(match binding::read_claimed_id(file_path)? {
+        Some(claimed) if claimed == canvas_id => None,
+        Some(claimed) => Some(format!(
+            "claims canvas {claimed}, file is named {canvas_id}"
+        )),
+        None if is_empty => None,
+        None => Some("missing, run migrate-canvas-logs".to_string()),
+    })
+}
+
+/// Replays the user and canvas store eventlogs and checks the invariants between them
+/// Canvas eventlogs are looked up in canvas_dir
+pub fn verify_logs(
+    user_log: &str,
+    canvas_log: &str,
+    canvas_dir: &str,
+) -> Result<VerifyReport, anyhow::Error> {
+    let user_events = EventLogPersistenceJson::open(user_log)?
+        .read_lines::<UserStoreEvents>()?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let canvas_events = EventLogPersistenceJson::open(canvas_log)?
+        .read_lines::<CanvasStoreEvents>()?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (user_state, user_issues) = userstore::replay_events(user_events);
+    let (canvas_state, mut canvas_issues) =
+        store::replay_events(canvas_events, SystemClock.now_ms());
+
+    // soft deleted canvases keep their users and eventlogs until they are purged
+    let known_canvases = || {
+        canvas_state
+            .canvases
+            .values()
+            .chain(canvas_state.deleted_canvases.values())
+    };
+
+    // every user referenced by a canvas has to exist
+    for canvas in known_canvases() {
+        for user_id in canvas.users.keys() {
+            if !user_state.users_id_lookup.contains_key(user_id) {
+                canvas_issues.push(ReplayIssue::invariant(format!(
+                    "Canvas {} references unknown user {user_id}",
+                    canvas.id
+                )));
+            }
+        }
+    }
+
+    // eventlogs of canvases nobody opened yet don't exist
+    for canvas in known_canvases() {
+        let log_path = Path::new(canvas_dir)
+            .join(format!("{}.jsonl", canvas.id))
+            .to_string_lossy()
+            .to_string();
+        if !Path::new(&log_path).exists() {
+            continue;
+        }
+        if let Some(header_issue) = canvas_header_issue(&log_path)? {
+            canvas_issues.push(ReplayIssue::invariant(format!(
+                "Header of eventlog {log_path}: {header_issue}"
+            )));
+        }
+    }
+
+    // offline no canvas is open, every eventlog is classified
+    let records: HashMap<_, _> = canvas_state
+        .purged_canvases
+        .iter()
+        .map(|canvas_id| (canvas_id.clone(), CanvasRecord::Purged))
+        .chain(canvas_state.deleted_canvases.values().map(|canvas| {
+            let purge_at = canvas
+                .deleted_at
+                .unwrap_or_default()
+                .saturating_add(DEFAULT_DELETION_GRACE.as_millis() as u64);
+            (canvas.id.clone(), CanvasRecord::SoftDeleted { purge_at })
+        }))
+        .chain(
+            canvas_state
+                .canvases
+                .keys()
+                .map(|canvas_id| (canvas_id.clone(), CanvasRecord::Active)),
+        )
+        .collect();
+    let eventlogs = orphans::scan(canvas_dir, &records, &HashSet::new())?;
+    for file in eventlogs
+        .files
+        .iter()
+        .filter(|file| file.class == LogClass::Unknown)
+    {
+        canvas_issues.push(ReplayIssue::invariant(format!(
+            "Eventlog {} has no canvas in the CanvasStore",
+            file.file_name
+        )));
+    }
+
+    Ok(VerifyReport {
+        users: user_state.users_id_lookup.len(),
+        canvases: canvas_state.canvases.len(),
+        eventlogs,
+        issues: ReplayIssues {
+            user: user_issues,
+            canvas: canvas_issues,
+        },
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct MigrateReport {
+    pub migrated: Vec<String>,
+    pub already_bound: usize,
+    /// file and reason, these logs are left untouched
+    pub refused: Vec<(String, String)>,
+}
+
+impl fmt::Display for MigrateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Migrated: {}", self.migrated.len())?;
+        for file in &self.migrated {
+            writeln!(f, "  {file}")?;
+        }
+        writeln!(f, "Already bound: {}", self.already_bound)?;
+        writeln!(f, "Refused: {}", self.refused.len())?;
+        for (file, reason) in &self.refused {
+            writeln!(f, "  {file}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the canvas header to every legacy canvas eventlog in dir
+/// Only files named after a valid canvas id are canvas eventlogs, the store logs are skipped
+pub fn migrate_canvas_logs(dir: &str) -> Result<MigrateReport, std::io::Error> {
+    let mut report = MigrateReport::default();
+
+    let mut paths: Vec<_> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.sort();
+
+    for path in paths {
+        let (Some(canvas_id), Some("jsonl")) = (
+            path.file_stem().and_then(|stem| stem.to_str()),
+            path.extension().and_then(|extension| extension.to_str()),
+        ) else {
+            continue;
+        };
+        if !store::is_valid_canvas_id(canvas_id) {
+            continue;
+        }
+        let file_path = path.to_string_lossy().to_string();
+
+        let event_log = match EventLogPersistenceJson::open(&file_path)?
+            .read_lines::<CanvasEvents>()?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(event_log) => event_log,
+            Err(e) => {
+                report.refused.push((file_path, e.to_string()));
+                continue;
+            }
+        };
+
+        match binding::check(canvas_id, &event_log) {
+            Ok(LogBinding::Bound | LogBinding::Empty) => report.already_bound += 1,
+            Ok(LogBinding::Legacy) => {
+                binding::migrate_legacy_log(
+                    &file_path,
+                    canvas_id,
+                    event_log,
+                    SystemClock.now_secs(),
+                )?;
+                report.migrated.push(file_path);
+            }
+            Err(e) => report.refused.push((file_path, e.to_string())),
+        }
+    }
+
+    Ok(report)
+}
+
+#[derive(Debug)]
+pub struct CompactReport {
+    pub events_before: usize,
+    pub events_after: usize,
+    pub policy: RetentionPolicy,
+    /// events per category before compacting and the dropped ones
+    pub retention: RetentionReport,
+}
+
+impl fmt::Display for CompactReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Compacted {} events into {}",
+            self.events_before, self.events_after
+        )?;
+        writeln!(
+            f,
+            "Retention: history {}, read receipts {}",
+            self.policy.history, self.policy.read_receipts
+        )?;
+        write!(f, "{}", self.retention)
+    }
+}
+
+/// Retention overrides of a canvas in the canvas store log, soft deleted canvases included
+fn canvas_retention_overrides(
+    canvas_log: &str,
+    canvas_id: &str,
+) -> Result<RetentionOverrides, std::io::Error> {
+    let canvas_events = EventLogPersistenceJson::open(canvas_log)?
+        .read_lines::<CanvasStoreEvents>()?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let (canvas_state, _) = store::replay_events(canvas_events, SystemClock.now_ms());
+
+    Ok(canvas_state
+        .canvases
+        .get(canvas_id)
+        .or_else(|| canvas_state.deleted_canvases.get(canvas_id))
+        .map(|canvas| canvas.settings.retention.clone())
+        .unwrap_or_default())
+}
+
+/// Compacts the eventlog of a canvas and atomically replaces it
+/// The retention overrides of the canvas apply on top of the configured policy
+pub fn compact_canvas(
+    canvas_log: &str,
+    canvas_id: &str,
+    policy: &RetentionPolicy,
+) -> Result<CompactReport, std::io::Error> {
+    let policy = policy.with_overrides(&canvas_retention_overrides(canvas_log, canvas_id)?);
+    compact_canvas_log(&server::canvas_log_path(canvas_id), &policy)
+}
+
+pub fn compact_canvas_log(
+    file_path: &str,
+    policy: &RetentionPolicy,
+) -> Result<CompactReport, std::io::Error> {
+    let mut event_log = EventLogPersistenceJson::open(file_path)?
+        .read_lines::<CanvasEvents>()?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let events_before = event_log.len();
+    let now = SystemClock.now_secs();
+
+    // close dangling state the same way the server would on load
+    let cleanup_events = CanvasSocketServer::extract_cleanup_events(&mut event_log, now);
+    event_log.extend(cleanup_events);
+
+    let retention = RetentionReport::new(
+        &event_log,
+        &CanvasSocketServer::compaction_drops(&event_log, policy, now),
+    );
+    let event_log = CanvasSocketServer::compact_event_log(event_log, policy, now);
+    persistence::rewrite_event_log(file_path, &event_log)?;
+
+    Ok(CompactReport {
+        events_before,
+        events_after: event_log.len(),
+        policy: policy.clone(),
+        retention,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_fixture(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("{}-{name}", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    const USER_LOG: &str = r#"{"type":"UserRegistered","timestamp":1,"user_id":"u1","user":{"id":"u1","email":"a@a","username":"a","password_hash":"x"}}
+{"type":"UserRegistered","timestamp":2,"user_id":"u2","user":{"id":"u2","email":"b@b","username":"b","password_hash":"x"}}
+"#;
+
+    const CANVAS_STORE_LOG: &str = r#"{"type":"CanvasCreated","timestamp":3,"owner_id":"u1","canvas_id":"c1","state":"Active","name":"Canvas"}
+{"type":"UserCanvasAdded","timestamp":4,"user_id":"u3","initiator_user_id":"u1","canvas_id":"c1","access_level":"Write"}
+"#;
+
+    #[test]
+    fn test_inspect_log() {
+        let path = write_fixture(
+            "inspect.jsonl",
+            &format!("{USER_LOG}not json\n{{\"type\":\"UserDeleted\",\"timestamp\":9}}\n"),
+        );
+
+        let report = inspect_log(&path, LogKind::User).unwrap();
+        assert_eq!(report.event_counts.get("UserRegistered"), Some(&2));
+        assert_eq!(report.first_timestamp, Some(1));
+        assert_eq!(report.last_timestamp, Some(2));
+        assert_eq!(
+            report
+                .invalid_lines
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+    }
+
+    #[test]
+    fn test_inspect_groups_lines_by_instance() {
+        let stamped = |instance_id: &str, timestamp: u64| {
+            format!(
+                r#"{{"instanceId":"{instance_id}","type":"UserDeleted","timestamp":{timestamp},"user_id":"u1"}}"#
+            )
+        };
+        let path = write_fixture(
+            "user_eventlog.jsonl",
+            &format!(
+                "{USER_LOG}{}\n{}\n{}\n",
+                stamped("a", 5),
+                stamped("b", 6),
+                stamped("a", 7)
+            ),
+        );
+
+        let report = inspect_log(&path, LogKind::User).unwrap();
+        assert!(report.invalid_lines.is_empty());
+        assert_eq!(
+            report.instance_counts,
+            BTreeMap::from([
+                (UNSTAMPED.to_string(), 2),
+                ("a".to_string(), 2),
+                ("b".to_string(), 1)
+            ])
+        );
+        assert!(report
+            .to_string()
+            .contains("Instances:\n  <unstamped>: 2\n  a: 2\n  b: 1\n"));
+    }
+
+    #[test]
+    fn test_verify_logs() {
+        let user_log = write_fixture("user_eventlog.jsonl", USER_LOG);
+        let canvas_log = write_fixture("canvas_eventlog.jsonl", CANVAS_STORE_LOG);
+
+        let canvas_dir = std::env::temp_dir().join(nanoid::nanoid!(8));
+        std::fs::create_dir(&canvas_dir).unwrap();
+        let canvas_dir = canvas_dir.to_string_lossy().to_string();
+
+        let report = verify_logs(&user_log, &canvas_log, &canvas_dir).unwrap();
+        assert_eq!(report.users, 2);
+        assert_eq!(report.canvases, 1);
+        assert_eq!(report.issue_count(), 1);
+        assert!(report.issues.canvas[0].details.contains("unknown user u3"));
+        assert!(report.eventlogs.files.is_empty());
+
+        // an eventlog without a canvas is an issue of its own
+        std::fs::write(Path::new(&canvas_dir).join("0123456789ab.jsonl"), "").unwrap();
+        let report = verify_logs(&user_log, &canvas_log, &canvas_dir).unwrap();
+        assert_eq!(report.issue_count(), 2);
+        assert_eq!(report.eventlogs.count(LogClass::Unknown), 1);
+        assert!(report
+            .to_string()
+            .contains("unknown 0123456789ab.jsonl: 0 bytes"));
+
+        let _ = std::fs::remove_dir_all(canvas_dir);
+    }
+
+    #[test]
+    fn test_migrate_canvas_logs() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!(8));
+        std::fs::create_dir(&dir).unwrap();
+        let joined = r#"{"type":"UserJoined","timestamp":1,"userId":"u1","sessionId":"s1","username":"a","accessLevel":"Owner"}"#;
+        std::fs::write(dir.join("0123456789ab.jsonl"), format!("{joined}\n")).unwrap();
+        std::fs::write(
+            dir.join("bbbbbbbbbbbb.jsonl"),
+            format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":0,\"canvasId\":\"aaaaaaaaaaaa\"}}\n{joined}\n"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("user_eventlog.jsonl"), USER_LOG).unwrap();
+
+        let report = migrate_canvas_logs(dir.to_str().unwrap()).unwrap();
+        assert_eq!(report.migrated.len(), 1);
+        assert_eq!(report.refused.len(), 1);
+        assert!(report.refused[0]
+            .1
+            .contains("belongs to canvas aaaaaaaaaaaa"));
+
+        let legacy = dir.join("0123456789ab.jsonl");
+        let report = inspect_log(legacy.to_str().unwrap(), LogKind::Canvas).unwrap();
+        assert_eq!(report.event_counts.get("CanvasLogHeader"), Some(&1));
+        assert_eq!(report.header_issue, None);
+        let report = inspect_log(
+            dir.join("bbbbbbbbbbbb.jsonl").to_str().unwrap(),
+            LogKind::Canvas,
+        )
+        .unwrap();
+        assert!(report.header_issue.is_some());
+
+        // running it again changes nothing
+        let report = migrate_canvas_logs(dir.to_str().unwrap()).unwrap();
+        assert!(report.migrated.is_empty());
+        assert_eq!(report.already_bound, 1);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_compact_canvas_log() {
+        let path = write_fixture(
+            "canvas.jsonl",
+            r##"{"type":"UserJoined","timestamp":1,"userId":"u1","sessionId":"s1","username":"a","accessLevel":"Owner"}
+{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}
+{"type":"ShapeAdded","origin":"s1","timestamp":3,"shape":{"type":"Line","id":"l2","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}
+{"type":"ShapeSelected","origin":"s1","timestamp":4,"shapeId":"l1","options":{}}
+{"type":"ShapeRemoved","origin":"s1","timestamp":5,"shapeId":"l1"}
+"##,
+        );
+
+        let report = compact_canvas_log(&path, &RetentionPolicy::default()).unwrap();
+        assert_eq!(report.events_before, 5);
+        assert_eq!(report.events_after, 1);
+
+        let report = inspect_log(&path, LogKind::Canvas).unwrap();
+        assert_eq!(report.event_counts.get("ShapeAdded"), Some(&1));
+        assert!(report.invalid_lines.is_empty());
+    }
+}
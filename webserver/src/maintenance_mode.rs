@@ -0,0 +1,138 @@
+use crate::messages::{self, Message, MessageKey};
+use actix_web::{
+    error::InternalError,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    web, HttpRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+// Read-only mode of the whole service for backups, migrations or incidents, toggled at runtime by admins
+// Logins, pages and reads keep working, mutating handlers call ensure_writable first
+// and the canvas server rejects drawing events, viewers keep their viewport and clock sync
+// Unlike maintenance.rs nothing here touches the eventlogs
+
+/// Clients are asked to try again after this time
+pub const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Maintenance as configured at startup and set by the admin endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct MaintenanceWindow {
+    pub enabled: bool,
+    /// shown to users next to the maintenance notice, e.g. the expected end
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// Current maintenance, shared by the handlers and the canvas server
+#[derive(Debug, Default)]
+pub struct MaintenanceState {
+    enabled: AtomicBool,
+    message: RwLock<Option<String>>,
+}
+
+impl MaintenanceState {
+    pub fn new(window: MaintenanceWindow) -> Self {
+        Self {
+            enabled: AtomicBool::new(window.enabled),
+            message: RwLock::new(window.message),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn window(&self) -> MaintenanceWindow {
+        MaintenanceWindow {
+            enabled: self.is_enabled(),
+            message: self.message.read().unwrap().clone(),
+        }
+    }
+
+    /// The message is updated before the switch, a rejection never misses the message of its window
+    pub fn set(&self, window: MaintenanceWindow) {
+        *self.message.write().unwrap() = window.message;
+        self.enabled.store(window.enabled, Ordering::Relaxed);
+    }
+
+    /// Why changes are rejected, with the message of the admin if there is one
+    pub fn rejection(&self) -> Message {
+        match self.message.read().unwrap().as_deref() {
+            Some(note) if !note.is_empty() => {
+                Message::new(MessageKey::MaintenanceReadOnlyNote).param("note", note)
+            }
+            _ => Message::new(MessageKey::MaintenanceReadOnly),
+        }
+    }
+
+    /// Notice for connected sessions about the current mode
+    pub fn announcement(&self) -> Message {
+        if self.is_enabled() {
+            self.rejection()
+        } else {
+            Message::new(MessageKey::MaintenanceEnded)
+        }
+    }
+}
+
+/// Rejects a mutating request with 503 while in maintenance, mutating handlers call this first
+/// The response is negotiated here, so it carries Retry-After past the LocalizeService
+pub fn ensure_writable(request: &HttpRequest) -> actix_web::Result<()> {
+    let Some(maintenance) = request.app_data::<web::Data<MaintenanceState>>() else {
+        return Ok(());
+    };
+    if !maintenance.is_enabled() {
+        return Ok(());
+    }
+
+    let message = maintenance.rejection();
+    let mut response = messages::respond(request, StatusCode::SERVICE_UNAVAILABLE, &message);
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(RETRY_AFTER.as_secs()),
+    );
+    Err(InternalError::from_response(message.key.key(), response).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::TestRequest, HttpResponse};
+
+    #[actix_web::test]
+    async fn test_rejection_names_the_message_and_retry_after() {
+        let maintenance = web::Data::new(MaintenanceState::default());
+        let request = TestRequest::post()
+            .app_data(maintenance.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_http_request();
+        assert!(ensure_writable(&request).is_ok());
+
+        maintenance.set(MaintenanceWindow {
+            enabled: true,
+            message: Some("back at 14:00".to_string()),
+        });
+        let response: HttpResponse = ensure_writable(&request).unwrap_err().error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "300");
+        assert_eq!(
+            maintenance.announcement().params,
+            vec![("note", "back at 14:00".to_string())]
+        );
+
+        maintenance.set(MaintenanceWindow::default());
+        assert!(ensure_writable(&request).is_ok());
+        assert_eq!(maintenance.announcement().key, MessageKey::MaintenanceEnded);
+    }
+}
@@ -0,0 +1,1355 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error,
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use derive_more::Display;
+use futures_util::{future::LocalBoxFuture, FutureExt, TryFutureExt};
+use nanoid::nanoid;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::{ready, Ready},
+    str::FromStr,
+};
+
+use utoipa::ToSchema;
+
+use crate::{canvas::error::CanvasStoreError, userstore::UserStoreError};
+
+// User visible messages
+// Every message has a key and a translation for each locale, missing translations are a compile error
+// Errors carry the key and its parameters, the LocalizeService renders them in the locale of the request
+// Responses to requests accepting JSON contain the key next to the localized message
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    De,
+    En,
+}
+
+impl FromStr for Locale {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "de" => Ok(Locale::De),
+            "en" => Ok(Locale::En),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Defines MessageKey and its catalog
+/// Each entry maps a variant to its key and the translations, parameters are written as {name}
+macro_rules! define_messages {
+    ($($variant:ident => $key:literal { en: $en:literal, de: $de:literal $(,)? },)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum MessageKey {
+            $($variant,)*
+        }
+
+        impl MessageKey {
+            pub fn key(self) -> &'static str {
+                match self {
+                    $(MessageKey::$variant => $key,)*
+                }
+            }
+
+            pub fn template(self, locale: Locale) -> &'static str {
+                match (self, locale) {
+                    $(
+                        (MessageKey::$variant, Locale::En) => $en,
+                        (MessageKey::$variant, Locale::De) => $de,
+                    )*
+                }
+            }
+        }
+    };
+}
+
+define_messages! {
+    AuthenticationFailed => "auth.failed" {
+        en: "Failed to authenticate",
+        de: "Authentifizierung fehlgeschlagen",
+    },
+    ApiTokenInvalid => "auth.api_token_invalid" {
+        en: "Invalid, expired or revoked API token",
+        de: "Ungültiges, abgelaufenes oder widerrufenes API-Token",
+    },
+    ApiTokenScopeDenied => "auth.api_token_scope_denied" {
+        en: "API tokens can only be used for the JSON endpoints and the websocket of their canvas",
+        de: "API-Tokens können nur für die JSON-Endpunkte und den Websocket ihres Canvas verwendet werden",
+    },
+    GuestNotAllowed => "auth.guest_not_allowed" {
+        en: "Guests can only view and draw on the canvas they joined, register to do more",
+        de: "Gäste können nur den Canvas ansehen und bearbeiten, dem sie beigetreten sind, registriere dich für mehr",
+    },
+    ApiTokenRateLimited => "auth.api_token_rate_limited" {
+        en: "Too many requests with this API token, try again in a minute",
+        de: "Zu viele Anfragen mit diesem API-Token, bitte in einer Minute erneut versuchen",
+    },
+    HandshakeTokenInvalid => "auth.handshake_token_invalid" {
+        en: "Invalid, expired or revoked access token, please log in again",
+        de: "Ungültiges, abgelaufenes oder widerrufenes Zugriffstoken, bitte erneut anmelden",
+    },
+    TokenRefreshFailed => "auth.refresh_failed" {
+        en: "Failed to refresh the session, please log in again",
+        de: "Sitzung konnte nicht erneuert werden, bitte erneut anmelden",
+    },
+    LoginFailed => "login.failed" {
+        en: "Failed to login, try again later",
+        de: "Anmeldung fehlgeschlagen, bitte später erneut versuchen",
+    },
+    InvalidCredentials => "login.invalid_credentials" {
+        en: "Invalid password or username",
+        de: "Ungültiger Benutzername oder Passwort",
+    },
+    UnknownUser => "login.unknown_user" {
+        en: "User does not exist",
+        de: "Benutzer existiert nicht",
+    },
+    PasswordsDoNotMatch => "register.passwords_mismatch" {
+        en: "Passwords do not match",
+        de: "Passwörter stimmen nicht überein",
+    },
+    EmailTaken => "register.email_taken" {
+        en: "A user with this email already exists",
+        de: "Ein Benutzer mit dieser E-Mail existiert bereits",
+    },
+    UsernameTaken => "register.username_taken" {
+        en: "This username is already taken",
+        de: "Dieser Benutzername ist bereits vergeben",
+    },
+    UsernameLength => "register.username_length" {
+        en: "Usernames have {min} to {max} characters",
+        de: "Benutzernamen haben {min} bis {max} Zeichen",
+    },
+    UsernameReserved => "register.username_reserved" {
+        en: "This username is reserved",
+        de: "Dieser Benutzername ist reserviert",
+    },
+    UsernameCharacters => "register.username_characters" {
+        en: "Usernames may only contain letters, digits, dashes, underscores and dots, and must start and end with a letter or digit",
+        de: "Benutzernamen dürfen nur Buchstaben, Ziffern, Bindestriche, Unterstriche und Punkte enthalten und müssen mit einem Buchstaben oder einer Ziffer beginnen und enden",
+    },
+    UsernameConfusable => "register.username_confusable" {
+        en: "This username looks too much like the name of another user",
+        de: "Dieser Benutzername ist dem Namen eines anderen Benutzers zu ähnlich",
+    },
+    RegistrationFailed => "register.failed" {
+        en: "Failed to register, try again later",
+        de: "Registrierung fehlgeschlagen, bitte später erneut versuchen",
+    },
+    LogoutFailed => "logout.failed" {
+        en: "Failed to log out on all devices, try again later",
+        de: "Abmeldung auf allen Geräten fehlgeschlagen, bitte später erneut versuchen",
+    },
+    PasswordResetRequested => "password_reset.requested" {
+        en: "If the account exists, a link to reset the password is on its way",
+        de: "Falls das Konto existiert, ist ein Link zum Zurücksetzen des Passworts unterwegs",
+    },
+    PasswordResetInvalid => "password_reset.invalid" {
+        en: "The link to reset the password is invalid or expired, request a new one",
+        de: "Der Link zum Zurücksetzen des Passworts ist ungültig oder abgelaufen, fordere einen neuen an",
+    },
+    PasswordResetFailed => "password_reset.failed" {
+        en: "Failed to reset the password, try again later",
+        de: "Passwort konnte nicht zurückgesetzt werden, bitte später erneut versuchen",
+    },
+    UserLoadFailed => "user.load_failed" {
+        en: "Failed to load user",
+        de: "Benutzer konnte nicht geladen werden",
+    },
+    PreferencesInvalid => "user.preferences_invalid" {
+        en: "Invalid preference {field}: {reason}",
+        de: "Ungültige Einstellung {field}: {reason}",
+    },
+    PreferencesTooLarge => "user.preferences_too_large" {
+        en: "Preferences can have at most {max} bytes",
+        de: "Einstellungen dürfen höchstens {max} Bytes groß sein",
+    },
+    PreferencesConflict => "user.preferences_conflict" {
+        en: "Preferences were changed elsewhere, reload them and try again",
+        de: "Die Einstellungen wurden woanders geändert, lade sie neu und versuche es erneut",
+    },
+    PreferencesRateLimited => "user.preferences_rate_limited" {
+        en: "Preferences saved too often, try again in a minute",
+        de: "Einstellungen zu oft gespeichert, bitte in einer Minute erneut versuchen",
+    },
+    AccountDeletionConfirmationRequired => "account.deletion_confirmation_required" {
+        en: "Confirm the deletion of the account by passing confirm={username}",
+        de: "Bestätige das Löschen des Kontos mit confirm={username}",
+    },
+    AccountOwnsCanvases => "account.owns_canvases" {
+        en: "The account still owns {count} canvases, transfer or delete them first, deleted canvases count until they are purged",
+        de: "Das Konto besitzt noch {count} Canvases, übertrage oder lösche sie zuerst, gelöschte Canvases zählen bis sie endgültig entfernt sind",
+    },
+    AccountDeletionFailed => "account.deletion_failed" {
+        en: "Failed to delete the account, try again later",
+        de: "Konto konnte nicht gelöscht werden, bitte später erneut versuchen",
+    },
+    RenderFailed => "page.render_failed" {
+        en: "Failed to render page",
+        de: "Seite konnte nicht angezeigt werden",
+    },
+    CanvasNotFound => "canvas.not_found" {
+        en: "Canvas not found",
+        de: "Canvas nicht gefunden",
+    },
+    CanvasViewDenied => "canvas.view_denied" {
+        en: "Not authorized to view canvas",
+        de: "Keine Berechtigung, diesen Canvas anzusehen",
+    },
+    CanvasAccessPollLimited => "canvas.access_poll_limited" {
+        en: "Access checked too often, try again in a minute",
+        de: "Zugriff zu oft geprüft, bitte in einer Minute erneut versuchen",
+    },
+    AccessRequestSent => "canvas.access_request_sent" {
+        en: "Your request was sent, the canvas opens once it is approved",
+        de: "Deine Anfrage wurde gesendet, der Canvas öffnet sich, sobald sie angenommen wurde",
+    },
+    AccessRequestRateLimited => "canvas.access_request_rate_limited" {
+        en: "Access to this canvas was requested too often, try again later",
+        de: "Zugriff auf diesen Canvas wurde zu oft angefragt, bitte später erneut versuchen",
+    },
+    AccessRequestMessageTooLong => "canvas.access_request_message_too_long" {
+        en: "The message can have at most {max} characters",
+        de: "Die Nachricht darf höchstens {max} Zeichen lang sein",
+    },
+    AccessRequestNotFound => "canvas.access_request_not_found" {
+        en: "There is no pending access request",
+        de: "Es gibt keine offene Zugriffsanfrage",
+    },
+    AccessRequestAlreadyMember => "canvas.access_request_already_member" {
+        en: "You already have access to this canvas",
+        de: "Du hast bereits Zugriff auf diesen Canvas",
+    },
+    AccessRequestResolveDenied => "canvas.access_request_resolve_denied" {
+        en: "Only owners and moderators can answer access requests",
+        de: "Nur Besitzer und Moderatoren können Zugriffsanfragen beantworten",
+    },
+    AccessRequestCancelDenied => "canvas.access_request_cancel_denied" {
+        en: "Only the requester can cancel an access request",
+        de: "Nur der Anfragende kann eine Zugriffsanfrage zurückziehen",
+    },
+    AccessRequestReceived => "canvas.access_request_received" {
+        en: "{user} asks for access to {canvas}",
+        de: "{user} bittet um Zugriff auf {canvas}",
+    },
+    AccessRequestApproved => "canvas.access_request_approved" {
+        en: "Your access request for {canvas} was approved",
+        de: "Deine Zugriffsanfrage für {canvas} wurde angenommen",
+    },
+    AccessRequestDenied => "canvas.access_request_denied" {
+        en: "Your access request for {canvas} was denied",
+        de: "Deine Zugriffsanfrage für {canvas} wurde abgelehnt",
+    },
+    AccessRequestCancelled => "canvas.access_request_cancelled" {
+        en: "The access request was cancelled",
+        de: "Die Zugriffsanfrage wurde zurückgezogen",
+    },
+    CanvasUpdateDenied => "canvas.update_denied" {
+        en: "Not authorized to update canvas",
+        de: "Keine Berechtigung, diesen Canvas zu ändern",
+    },
+    CanvasArchiveDenied => "canvas.archive_denied" {
+        en: "Only the owner can archive or unarchive the canvas",
+        de: "Nur der Besitzer kann den Canvas archivieren oder aus dem Archiv holen",
+    },
+    OwnerChangesOwnAccess => "canvas.access_denied.owner_self" {
+        en: "Access denied: the owner can't change their own access level",
+        de: "Zugriff verweigert: Der Besitzer kann seine eigene Berechtigung nicht ändern",
+    },
+    OwnerAssignsOwner => "canvas.access_denied.assign_owner" {
+        en: "Access denied: the owner can't assign the owner access level",
+        de: "Zugriff verweigert: Der Besitzer kann keinen weiteren Besitzer ernennen",
+    },
+    ModeratorChangesPrivileged => "canvas.access_denied.moderator_change" {
+        en: "Access denied: moderators can't change owners or moderators",
+        de: "Zugriff verweigert: Moderatoren können Besitzer und Moderatoren nicht ändern",
+    },
+    ModeratorAssignsPrivileged => "canvas.access_denied.moderator_assign" {
+        en: "Access denied: moderators can't assign the owner or moderate access level",
+        de: "Zugriff verweigert: Moderatoren können keine Besitzer oder Moderatoren ernennen",
+    },
+    AccessLevelChangeDenied => "canvas.access_denied" {
+        en: "Access denied: not allowed to change access levels",
+        de: "Zugriff verweigert: Keine Berechtigung, Berechtigungen zu ändern",
+    },
+    PersistenceFailed => "canvas.persistence_failed" {
+        en: "Failed to save data",
+        de: "Daten konnten nicht gespeichert werden",
+    },
+    CanvasCreateFailed => "canvas.create_failed" {
+        en: "Failed to save canvas",
+        de: "Canvas konnte nicht gespeichert werden",
+    },
+    CanvasUpdateFailed => "canvas.update_failed" {
+        en: "Failed to update canvas",
+        de: "Canvas konnte nicht aktualisiert werden",
+    },
+    CanvasUpdated => "canvas.updated" {
+        en: "Canvas updated",
+        de: "Canvas aktualisiert",
+    },
+    CanvasSettingsUpdated => "canvas.settings_updated" {
+        en: "Canvas settings updated",
+        de: "Canvas-Einstellungen aktualisiert",
+    },
+    CanvasNameTaken => "canvas.name_taken" {
+        en: "You already have a canvas named {name}",
+        de: "Du hast bereits ein Canvas mit dem Namen {name}",
+    },
+    CanvasNameInvalid => "canvas.name_invalid" {
+        en: "Canvas names have 1 to {max} characters",
+        de: "Canvas-Namen haben 1 bis {max} Zeichen",
+    },
+    CanvasRenamed => "canvas.renamed" {
+        en: "Canvas renamed to {name}",
+        de: "Canvas in {name} umbenannt",
+    },
+    CanvasTagsUpdated => "canvas.tags_updated" {
+        en: "Canvas tags updated",
+        de: "Canvas-Tags aktualisiert",
+    },
+    CanvasTagsInvalid => "canvas.tags_invalid" {
+        en: "Tags have to be between 1 and {max_length} characters long: {tags}",
+        de: "Tags müssen zwischen 1 und {max_length} Zeichen lang sein: {tags}",
+    },
+    CanvasTagsTooMany => "canvas.tags_too_many" {
+        en: "At most {max} tags are allowed, {count} were given",
+        de: "Höchstens {max} Tags sind erlaubt, {count} wurden angegeben",
+    },
+    CanvasPreferenceUpdated => "canvas.preference_updated" {
+        en: "Canvas list updated",
+        de: "Canvas-Liste aktualisiert",
+    },
+    CanvasPreferenceDenied => "canvas.preference_denied" {
+        en: "Only canvases you are a member of can be pinned or ordered",
+        de: "Nur Canvas, in denen du Mitglied bist, können angeheftet oder sortiert werden",
+    },
+    CanvasOrderInvalid => "canvas.order_invalid" {
+        en: "Every canvas may appear once and at most {max} canvases can be ordered at once",
+        de: "Jede Canvas darf nur einmal vorkommen und höchstens {max} Canvas können auf einmal sortiert werden",
+    },
+    CanvasGridSizeInvalid => "canvas.grid_size_invalid" {
+        en: "Grid size has to be between 1 and {max}, snapping requires a grid",
+        de: "Rastergröße muss zwischen 1 und {max} liegen, Einrasten benötigt ein Raster",
+    },
+    CanvasVoiceBehaviorDenied => "canvas.voice_behavior_denied" {
+        en: "Only the owner can change what Voice may do on this canvas",
+        de: "Nur der Besitzer kann ändern, was Voice auf diesem Canvas darf",
+    },
+    CanvasAnonymizeDenied => "canvas.anonymize_denied" {
+        en: "Only the owner can change whether readers see who contributed",
+        de: "Nur der Besitzer kann ändern, ob Leser sehen, wer beigetragen hat",
+    },
+    CanvasGuestAccessDenied => "canvas.guest_access_denied" {
+        en: "Only the owner can change whether guests may join",
+        de: "Nur der Besitzer kann ändern, ob Gäste beitreten dürfen",
+    },
+    CanvasDigestDenied => "canvas.digest_denied" {
+        en: "Only the owner can change how often activity digests are sent",
+        de: "Nur der Besitzer kann ändern, wie oft Aktivitätsübersichten verschickt werden",
+    },
+    CanvasShapeTypesDenied => "canvas.shape_types_denied" {
+        en: "Only the owner can change which shape types may be drawn",
+        de: "Nur der Besitzer kann ändern, welche Formen gezeichnet werden dürfen",
+    },
+    CanvasShapeTypesInvalid => "canvas.shape_types_invalid" {
+        en: "Unknown shape types {types}, valid are {valid}",
+        de: "Unbekannte Formen {types}, gültig sind {valid}",
+    },
+    CanvasMetadataDenied => "canvas.metadata_denied" {
+        en: "Only the owner can change the author, license and description of this canvas",
+        de: "Nur der Besitzer kann Autor, Lizenz und Beschreibung dieses Canvas ändern",
+    },
+    CanvasRetentionDenied => "canvas.retention_denied" {
+        en: "Only the owner can change how long the history of this canvas is kept",
+        de: "Nur der Besitzer kann ändern, wie lange der Verlauf dieses Canvas aufbewahrt wird",
+    },
+    CanvasRetentionInvalid => "canvas.retention_invalid" {
+        en: "{field} has to be all, last:<count>, days:<days> or none",
+        de: "{field} muss all, last:<Anzahl>, days:<Tage> oder none sein",
+    },
+    CanvasFeatureFlagsDenied => "canvas.feature_flags_denied" {
+        en: "Only the owner can change the features of this canvas",
+        de: "Nur der Besitzer kann die Funktionen dieses Canvas ändern",
+    },
+    CanvasPaletteInvalid => "canvas.palette_invalid" {
+        en: "A palette holds up to {max} colors, named with 1 to {max_name} characters and given as hex like #ff8800",
+        de: "Eine Palette enthält bis zu {max} Farben mit Namen aus 1 bis {max_name} Zeichen und Hexwerten wie #ff8800",
+    },
+    CanvasPaletteUpdated => "canvas.palette_updated" {
+        en: "Canvas palette updated",
+        de: "Canvas-Palette aktualisiert",
+    },
+    CanvasFeatureFlagInvalid => "canvas.feature_flag_invalid" {
+        en: "The feature {flag} can't be changed for a canvas",
+        de: "Die Funktion {flag} kann für einen Canvas nicht geändert werden",
+    },
+    CanvasFeatureFlagsUpdated => "canvas.feature_flags_updated" {
+        en: "Canvas features updated",
+        de: "Canvas-Funktionen aktualisiert",
+    },
+    ApiTokenDenied => "canvas.api_token_denied" {
+        en: "Only the owner can manage the API tokens of this canvas",
+        de: "Nur der Besitzer kann die API-Tokens dieses Canvas verwalten",
+    },
+    ApiTokenAccessLevelInvalid => "canvas.api_token_access_level_invalid" {
+        en: "API tokens can only read or write",
+        de: "API-Tokens können nur lesen oder schreiben",
+    },
+    ApiTokenLabelInvalid => "canvas.api_token_label_invalid" {
+        en: "{field} has to be between 1 and {max} characters long",
+        de: "{field} muss zwischen 1 und {max} Zeichen lang sein",
+    },
+    ApiTokenExpiryInvalid => "canvas.api_token_expiry_invalid" {
+        en: "{field} has to be in the future",
+        de: "{field} muss in der Zukunft liegen",
+    },
+    ApiTokenNotFound => "canvas.api_token_not_found" {
+        en: "API token not found",
+        de: "API-Token nicht gefunden",
+    },
+    ApiTokenRevoked => "canvas.api_token_revoked" {
+        en: "API token revoked",
+        de: "API-Token widerrufen",
+    },
+    CanvasMetadataInvalid => "canvas.metadata_invalid" {
+        en: "{field} has to be between 1 and {max} characters long",
+        de: "{field} muss zwischen 1 und {max} Zeichen lang sein",
+    },
+    CanvasLicenseUnknown => "canvas.license_unknown" {
+        en: "Unknown license: {license}",
+        de: "Unbekannte Lizenz: {license}",
+    },
+    CanvasVersionConflict => "canvas.version_conflict" {
+        en: "Canvas was changed in the meantime, it is now {state} (version {version})",
+        de: "Canvas wurde zwischenzeitlich geändert, er ist jetzt {state} (Version {version})",
+    },
+    CanvasTransitionInvalid => "canvas.transition_invalid" {
+        en: "Canvas is {state}, {action} is not possible",
+        de: "Canvas ist {state}, {action} ist nicht möglich",
+    },
+    CanvasUserAdded => "canvas.user_added" {
+        en: "{user} added as {access_level}",
+        de: "{user} als {access_level} hinzugefügt",
+    },
+    CanvasUserAddFailed => "canvas.user_add_failed" {
+        en: "Failed to add user to canvas",
+        de: "Benutzer konnte nicht hinzugefügt werden",
+    },
+    InvalidExpiry => "canvas.invalid_expiry" {
+        en: "Temporary access has to expire in the future",
+        de: "Temporärer Zugriff muss in der Zukunft ablaufen",
+    },
+    CanvasUserAccessChanged => "canvas.user_access_changed" {
+        en: "{user} changed from {previous_access_level} to {access_level}",
+        de: "{user} von {previous_access_level} zu {access_level} geändert",
+    },
+    CanvasUsersBatchAdded => "canvas.users_batch_added" {
+        en: "{added} users added, {updated} changed, {failed} not possible",
+        de: "{added} Benutzer hinzugefügt, {updated} geändert, {failed} nicht möglich",
+    },
+    CanvasUsersBatchInvalid => "canvas.users_batch_invalid" {
+        en: "Line {line} could not be read, expected a username or email and optionally an access level like alice, Write",
+        de: "Zeile {line} konnte nicht gelesen werden, erwartet wird ein Benutzername oder eine Email und optional eine Berechtigung wie alice, Write",
+    },
+    CanvasUsersBatchSize => "canvas.users_batch_size" {
+        en: "Between 1 and {max} users can be added at once, {count} were given",
+        de: "Es können 1 bis {max} Benutzer auf einmal hinzugefügt werden, angegeben wurden {count}",
+    },
+    CanvasUserNotFound => "canvas.user_not_found" {
+        en: "User not found",
+        de: "Benutzer nicht gefunden",
+    },
+    InvalidReplayCutoff => "canvas.replay.invalid_cutoff" {
+        en: "Invalid cutoff {value}, expected <timestamp> or seq:<number>",
+        de: "Ungültiger Zeitpunkt {value}, erwartet <timestamp> oder seq:<number>",
+    },
+    ReplayFailed => "canvas.replay_failed" {
+        en: "Failed to replay canvas",
+        de: "Canvas konnte nicht wiederhergestellt werden",
+    },
+    ExportFailed => "canvas.export_failed" {
+        en: "Failed to export canvas",
+        de: "Canvas konnte nicht exportiert werden",
+    },
+    InvalidShapeSearchArea => "canvas.shapes.invalid_area" {
+        en: "The search area needs x, y, w and h",
+        de: "Der Suchbereich braucht x, y, w und h",
+    },
+    UnknownShapeCursor => "canvas.shapes.unknown_cursor" {
+        en: "The shape {cursor} no longer matches the search, start again from the first page",
+        de: "Die Form {cursor} passt nicht mehr zur Suche, beginne wieder mit der ersten Seite",
+    },
+    ShapeSearchFailed => "canvas.shapes.search_failed" {
+        en: "Failed to search the shapes of the canvas",
+        de: "Die Formen des Canvas konnten nicht durchsucht werden",
+    },
+    ShapeNotFound => "canvas.shapes.not_found" {
+        en: "The shape {id} does not exist",
+        de: "Die Form {id} existiert nicht",
+    },
+    ShapeProvenanceFailed => "canvas.shapes.provenance_failed" {
+        en: "Failed to look up the last change of the shape",
+        de: "Die letzte Änderung der Form konnte nicht ermittelt werden",
+    },
+    CommentsFailed => "canvas.comments_failed" {
+        en: "Failed to load the comments of the canvas",
+        de: "Die Kommentare des Canvas konnten nicht geladen werden",
+    },
+    CanvasEventsExportDenied => "canvas.events_export_denied" {
+        en: "Only the owner can export the events of this canvas",
+        de: "Nur der Besitzer kann die Events dieses Canvas exportieren",
+    },
+    CanvasEventsTooLarge => "canvas.events_too_large" {
+        en: "The eventlog of this canvas is larger than {limit} bytes and can't be exported",
+        de: "Das Eventlog dieses Canvas ist größer als {limit} Bytes und kann nicht exportiert werden",
+    },
+    CanvasImportEmpty => "canvas.import_empty" {
+        en: "The import contains no events",
+        de: "Der Import enthält keine Events",
+    },
+    CanvasImportInvalid => "canvas.import_invalid" {
+        en: "Line {line} of the import is no persisted canvas event",
+        de: "Zeile {line} des Imports ist kein gespeichertes Canvas-Event",
+    },
+    CanvasImportFailed => "canvas.import_failed" {
+        en: "Failed to import canvas",
+        de: "Canvas konnte nicht importiert werden",
+    },
+    CanvasDocumentVersion => "canvas.document_version" {
+        en: "Documents of version {version} are not supported, this server reads up to version {supported}",
+        de: "Dokumente der Version {version} werden nicht unterstützt, dieser Server liest bis Version {supported}",
+    },
+    CanvasDocumentInvalid => "canvas.document_invalid" {
+        en: "Shape {index} of the document is no shape or reuses the id of another shape",
+        de: "Form {index} des Dokuments ist keine Form oder nutzt die ID einer anderen Form",
+    },
+    CanvasDuplicateFailed => "canvas.duplicate_failed" {
+        en: "Failed to duplicate canvas",
+        de: "Canvas konnte nicht dupliziert werden",
+    },
+    SessionUserLimit => "session.user_limit" {
+        en: "Too many open sessions, close another tab of this canvas",
+        de: "Zu viele offene Sitzungen, bitte einen anderen Tab dieses Canvas schließen",
+    },
+    SessionCanvasLimit => "session.canvas_limit" {
+        en: "Canvas is full, try again later",
+        de: "Canvas ist voll, bitte später erneut versuchen",
+    },
+    CanvasFlushFailed => "canvas.flush_failed" {
+        en: "Changes to this canvas could not be saved to disk, they may be lost if the server stops",
+        de: "Änderungen an diesem Canvas konnten nicht auf die Festplatte geschrieben werden, sie können verloren gehen, wenn der Server stoppt",
+    },
+    CanvasThrottled => "canvas.throttled" {
+        en: "The server can't save changes fast enough, changes of writers are paused until it caught up",
+        de: "Der Server kann Änderungen nicht schnell genug speichern, Änderungen von Schreibenden sind pausiert, bis er aufgeholt hat",
+    },
+    CanvasThrottleEnded => "canvas.throttle_ended" {
+        en: "The server caught up, changes are accepted again",
+        de: "Der Server hat aufgeholt, Änderungen werden wieder angenommen",
+    },
+    CanvasDiagnosticsAlarm => "canvas.diagnostics_alarm" {
+        en: "This canvas is under load: {events_per_minute} events per minute, slowest session {slowest_session}, saving {save_lag_ms}ms behind",
+        de: "Dieser Canvas ist ausgelastet: {events_per_minute} Events pro Minute, langsamste Sitzung {slowest_session}, Speichern {save_lag_ms}ms im Rückstand",
+    },
+    SessionIpLimit => "session.ip_limit" {
+        en: "Too many connections from your network to this canvas",
+        de: "Zu viele Verbindungen aus deinem Netzwerk zu diesem Canvas",
+    },
+    SessionCoolingDown => "session.cooling_down" {
+        en: "Too many connection attempts, try again in a minute",
+        de: "Zu viele Verbindungsversuche, bitte in einer Minute erneut versuchen",
+    },
+    SessionEvicted => "session.evicted" {
+        en: "Session closed, the canvas was opened in another tab",
+        de: "Sitzung geschlossen, der Canvas wurde in einem anderen Tab geöffnet",
+    },
+    SessionAuthExpired => "session.auth_expired" {
+        en: "Logged out on all devices, please log in again",
+        de: "Auf allen Geräten abgemeldet, bitte erneut anmelden",
+    },
+    SessionTokenRevoked => "session.token_revoked" {
+        en: "Session closed, its API token was revoked",
+        de: "Sitzung geschlossen, ihr API-Token wurde widerrufen",
+    },
+    SessionNotRegistered => "session.not_registered" {
+        en: "Connection not registered, the first message must be RegisterSession",
+        de: "Verbindung nicht registriert, die erste Nachricht muss RegisterSession sein",
+    },
+    SessionAlreadyRegistered => "session.already_registered" {
+        en: "Connection is already registered",
+        de: "Verbindung ist bereits registriert",
+    },
+    CanvasLoadFailed => "canvas.load_failed" {
+        en: "Canvas could not be loaded",
+        de: "Canvas konnte nicht geladen werden",
+    },
+    CanvasServerStopped => "canvas.server_stopped" {
+        en: "Live canvases are unavailable at the moment, please try again shortly",
+        de: "Live-Canvases sind im Moment nicht verfügbar, bitte versuche es gleich erneut",
+    },
+    CanvasLogMismatch => "canvas.log_mismatch" {
+        en: "Canvas could not be loaded, its history belongs to another canvas",
+        de: "Canvas konnte nicht geladen werden, sein Verlauf gehört zu einem anderen Canvas",
+    },
+    EventPermissionDenied => "event.permission_denied" {
+        en: "Not allowed to draw on this canvas",
+        de: "Keine Berechtigung, auf diesem Canvas zu zeichnen",
+    },
+    EventShapeNotOwned => "event.shape_not_owned" {
+        en: "Only {owner} or a moderator can change this shape",
+        de: "Nur {owner} oder ein Moderator kann diese Form ändern",
+    },
+    EventColorOffPalette => "event.color_off_palette" {
+        en: "Shape rejected, the color {color} is not part of the canvas palette",
+        de: "Form abgelehnt, die Farbe {color} ist nicht Teil der Canvas-Palette",
+    },
+    EventShapeTypeNotAllowed => "event.shape_type_not_allowed" {
+        en: "Shape rejected, {type} may not be drawn on this canvas, allowed are {allowed}",
+        de: "Form abgelehnt, {type} darf auf diesem Canvas nicht gezeichnet werden, erlaubt sind {allowed}",
+    },
+    EventCommentDenied => "event.comment_denied" {
+        en: "Your access level does not allow comments on this canvas",
+        de: "Deine Zugriffsstufe erlaubt keine Kommentare auf diesem Canvas",
+    },
+    EventCommentInvalid => "event.comment_invalid" {
+        en: "Comment rejected, {field} is empty, invalid or longer than {max} characters",
+        de: "Kommentar abgelehnt, {field} ist leer, ungültig oder länger als {max} Zeichen",
+    },
+    EventCommentIdTaken => "event.comment_id_taken" {
+        en: "Comment rejected, the id {id} is already taken",
+        de: "Kommentar abgelehnt, die ID {id} ist bereits vergeben",
+    },
+    EventCommentUnknown => "event.comment_unknown" {
+        en: "The comment {id} does not exist",
+        de: "Der Kommentar {id} existiert nicht",
+    },
+    EventCommentShapeUnknown => "event.comment_shape_unknown" {
+        en: "Comment rejected, the shape {id} does not exist",
+        de: "Kommentar abgelehnt, die Form {id} existiert nicht",
+    },
+    EventCommentNotAuthor => "event.comment_not_author" {
+        en: "Only the author or a moderator can delete this comment",
+        de: "Nur der Autor oder ein Moderator kann diesen Kommentar löschen",
+    },
+    EventShapeIdTaken => "event.shape_id_taken" {
+        en: "Shape rejected, the id {id} is already taken",
+        de: "Form abgelehnt, die ID {id} ist bereits vergeben",
+    },
+    EventSelectionShapeUnknown => "event.selection_shape_unknown" {
+        en: "Selection rejected, the shape {id} does not exist",
+        de: "Auswahl abgelehnt, die Form {id} existiert nicht",
+    },
+    EventServerPressure => "event.server_pressure" {
+        en: "Change rejected, the server can't save changes fast enough right now, try again shortly",
+        de: "Änderung abgelehnt, der Server kann Änderungen gerade nicht schnell genug speichern, versuche es gleich erneut",
+    },
+    EventSelectionLimit => "event.selection_limit" {
+        en: "Selection rejected, at most {max} shapes can be selected at once",
+        de: "Auswahl abgelehnt, du kannst höchstens {max} Formen gleichzeitig auswählen",
+    },
+    EventShapeIdInvalid => "event.shape_id_invalid" {
+        en: "Shape rejected, ids consist of up to {max_length} letters, digits, - and _",
+        de: "Form abgelehnt, IDs bestehen aus bis zu {max_length} Buchstaben, Ziffern, - und _",
+    },
+    EventTooLarge => "event.too_large" {
+        en: "Change rejected, it is too large ({bytes} bytes)",
+        de: "Änderung abgelehnt, sie ist zu groß ({bytes} Bytes)",
+    },
+    EventTooManyPoints => "event.too_many_points" {
+        en: "Stroke rejected, it has too many points ({points})",
+        de: "Strich abgelehnt, er hat zu viele Punkte ({points})",
+    },
+    EventAttributeUnknown => "event.attribute_unknown" {
+        en: "Shape rejected, the attribute {key} is not supported",
+        de: "Form abgelehnt, das Attribut {key} wird nicht unterstützt",
+    },
+    EventAttributeInvalid => "event.attribute_invalid" {
+        en: "Shape rejected, the attribute {key} has an invalid value",
+        de: "Form abgelehnt, das Attribut {key} hat einen ungültigen Wert",
+    },
+    EventAttributesTooLarge => "event.attributes_too_large" {
+        en: "Shape rejected, it has too many attributes",
+        de: "Form abgelehnt, sie hat zu viele Attribute",
+    },
+    EventMalformed => "event.malformed" {
+        en: "Change could not be read, {reason} at line {line} column {column}: {excerpt}",
+        de: "Änderung konnte nicht gelesen werden, {reason} in Zeile {line} Spalte {column}: {excerpt}",
+    },
+    EventDuplicate => "event.duplicate" {
+        en: "Change was already applied",
+        de: "Änderung wurde bereits übernommen",
+    },
+    EventNotAllowed => "event.not_allowed" {
+        en: "Clients can't send system events",
+        de: "System-Ereignisse können nicht gesendet werden",
+    },
+    EventFeatureDisabled => "event.feature_disabled" {
+        en: "Change rejected, the feature {flag} is not enabled for you on this canvas",
+        de: "Änderung abgelehnt, die Funktion {flag} ist für dich auf diesem Canvas nicht aktiviert",
+    },
+    EventViewportRateLimited => "event.viewport_rate_limited" {
+        en: "Viewport changed too often, every change of the canvas is sent until the next change",
+        de: "Ansicht zu oft geändert, bis zur nächsten Änderung werden alle Änderungen des Canvas gesendet",
+    },
+    QuotaShapesWarning => "quota.shapes" {
+        en: "Canvas is close to its shape limit ({usage} of {limit})",
+        de: "Canvas erreicht bald die maximale Anzahl an Formen ({usage} von {limit})",
+    },
+    QuotaLogBytesWarning => "quota.log_bytes" {
+        en: "Canvas history is close to its size limit ({usage} of {limit} bytes)",
+        de: "Canvas-Verlauf erreicht bald die maximale Größe ({usage} von {limit} Bytes)",
+    },
+    QuotaMembersWarning => "quota.members" {
+        en: "Canvas is close to its member limit ({usage} of {limit})",
+        de: "Canvas erreicht bald die maximale Anzahl an Mitgliedern ({usage} von {limit})",
+    },
+    QuotaLoadFailed => "quota.load_failed" {
+        en: "Failed to load canvas usage",
+        de: "Canvas-Nutzung konnte nicht geladen werden",
+    },
+    CanvasDeleted => "canvas.deleted" {
+        en: "Canvas was deleted",
+        de: "Canvas wurde gelöscht",
+    },
+    CanvasDeleteFailed => "canvas.delete_failed" {
+        en: "Failed to delete canvas",
+        de: "Canvas konnte nicht gelöscht werden",
+    },
+    CanvasDeleteDenied => "canvas.delete_denied" {
+        en: "Only the owner can delete the canvas",
+        de: "Nur der Besitzer kann den Canvas löschen",
+    },
+    CanvasRestored => "canvas.restored" {
+        en: "Canvas was restored",
+        de: "Canvas wurde wiederhergestellt",
+    },
+    CanvasRestoreFailed => "canvas.restore_failed" {
+        en: "Failed to restore canvas",
+        de: "Canvas konnte nicht wiederhergestellt werden",
+    },
+    CanvasRestoreDenied => "canvas.restore_denied" {
+        en: "Only the owner can restore the canvas",
+        de: "Nur der Besitzer kann den Canvas wiederherstellen",
+    },
+    CanvasRestoreExpired => "canvas.restore_expired" {
+        en: "Canvas was deleted too long ago to be restored",
+        de: "Canvas wurde vor zu langer Zeit gelöscht, um wiederhergestellt zu werden",
+    },
+    AdminRequired => "admin.required" {
+        en: "Only admins are allowed to do this",
+        de: "Nur Administratoren dürfen das",
+    },
+    AdminConfirmationRequired => "admin.confirmation_required" {
+        en: "Confirm the action by passing confirm={target}",
+        de: "Aktion mit confirm={target} bestätigen",
+    },
+    AdminLogFailed => "admin.log_failed" {
+        en: "Failed to record the admin action, nothing was changed",
+        de: "Admin-Aktion konnte nicht protokolliert werden, es wurde nichts geändert",
+    },
+    StorageScanFailed => "admin.storage_scan_failed" {
+        en: "Failed to list the canvas eventlogs",
+        de: "Canvas-Eventlogs konnten nicht aufgelistet werden",
+    },
+    StoragePurgeEmpty => "admin.storage_purge_empty" {
+        en: "Name at least one eventlog to purge",
+        de: "Nenne mindestens ein Eventlog zum Bereinigen",
+    },
+    StoragePurgeRefused => "admin.storage_purge_refused" {
+        en: "The eventlog {file} is {class} and can't be purged",
+        de: "Das Eventlog {file} ist {class} und kann nicht bereinigt werden",
+    },
+    StoragePurgeFailed => "admin.storage_purge_failed" {
+        en: "Failed to move the eventlogs into the quarantine",
+        de: "Eventlogs konnten nicht in die Quarantäne verschoben werden",
+    },
+    ServerShutdown => "server.shutdown" {
+        en: "Server is shutting down",
+        de: "Server wird heruntergefahren",
+    },
+    RequestTooLarge => "request.too_large" {
+        en: "Request is too large, at most {limit} bytes are accepted",
+        de: "Anfrage ist zu groß, höchstens {limit} Bytes sind erlaubt",
+    },
+    RequestFieldMissing => "request.field_missing" {
+        en: "Field {field} is missing",
+        de: "Feld {field} fehlt",
+    },
+    RequestFieldInvalid => "request.field_invalid" {
+        en: "Invalid value for {field}, accepted values are {expected}",
+        de: "Ungültiger Wert für {field}, erlaubt sind {expected}",
+    },
+    StoreReadOnly => "store.read_only" {
+        en: "Changes are paused until the server caught up, please try again shortly",
+        de: "Änderungen sind pausiert, bis der Server aufgeholt hat, bitte versuche es gleich erneut",
+    },
+    MaintenanceReadOnly => "maintenance.read_only" {
+        en: "Read-only maintenance, changes are not possible at the moment",
+        de: "Wartungsmodus, Änderungen sind im Moment nicht möglich",
+    },
+    MaintenanceReadOnlyNote => "maintenance.read_only_note" {
+        en: "Read-only maintenance, changes are not possible at the moment: {note}",
+        de: "Wartungsmodus, Änderungen sind im Moment nicht möglich: {note}",
+    },
+    MaintenanceEnded => "maintenance.ended" {
+        en: "Maintenance is over, changes are possible again",
+        de: "Wartung beendet, Änderungen sind wieder möglich",
+    },
+    ListNumberInvalid => "list.number_invalid" {
+        en: "{field} has to be a number from {min} to {max}",
+        de: "{field} muss eine Zahl von {min} bis {max} sein",
+    },
+    ListParameterUnsupported => "list.parameter_unsupported" {
+        en: "{field} is not supported by this list",
+        de: "{field} wird von dieser Liste nicht unterstützt",
+    },
+    ListCursorInvalid => "list.cursor_invalid" {
+        en: "{field} is invalid, start again from the first page",
+        de: "{field} ist ungültig, beginne wieder mit der ersten Seite",
+    },
+    ListSortInvalid => "list.sort_invalid" {
+        en: "The list can be sorted by {fields}, prefixed with - for descending order",
+        de: "Die Liste kann nach {fields} sortiert werden, mit vorangestelltem - absteigend",
+    },
+    RequestMalformed => "request.malformed" {
+        en: "Request could not be read",
+        de: "Anfrage konnte nicht gelesen werden",
+    },
+}
+
+/// Message key with its interpolation parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub key: MessageKey,
+    pub params: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(key: MessageKey) -> Self {
+        Self {
+            key,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn param(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.params.push((name, value.to_string()));
+        self
+    }
+
+    /// Translates the message and replaces the {name} placeholders
+    pub fn render(&self, locale: Locale) -> String {
+        self.params.iter().fold(
+            self.key.template(locale).to_string(),
+            |message, (name, value)| message.replace(&format!("{{{name}}}"), value),
+        )
+    }
+}
+
+impl From<MessageKey> for Message {
+    fn from(key: MessageKey) -> Self {
+        Message::new(key)
+    }
+}
+
+/// Error with a localizable message, rendered in the default locale unless the LocalizeService is used
+#[derive(Debug, Display, derive_more::Error)]
+#[display("{}", message.render(Locale::default()))]
+pub struct LocalizedError {
+    status: StatusCode,
+    #[error(not(source))]
+    message: Message,
+}
+
+impl LocalizedError {
+    pub fn new(status: StatusCode, message: impl Into<Message>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl error::ResponseError for LocalizedError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status)
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+pub fn bad_request(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::BAD_REQUEST, message)
+}
+
+pub fn unauthorized(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::UNAUTHORIZED, message)
+}
+
+pub fn forbidden(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::FORBIDDEN, message)
+}
+
+pub fn not_found(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::NOT_FOUND, message)
+}
+
+pub fn conflict(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::CONFLICT, message)
+}
+
+pub fn payload_too_large(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::PAYLOAD_TOO_LARGE, message)
+}
+
+pub fn too_many_requests(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::TOO_MANY_REQUESTS, message)
+}
+
+pub fn unprocessable_entity(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::UNPROCESSABLE_ENTITY, message)
+}
+
+pub fn precondition_required(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::PRECONDITION_REQUIRED, message)
+}
+
+pub fn service_unavailable(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::SERVICE_UNAVAILABLE, message)
+}
+
+pub fn internal_error(message: impl Into<Message>) -> LocalizedError {
+    LocalizedError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+}
+
+/// Picks the preferred supported locale of an Accept-Language header
+/// Region subtags are ignored (de-AT is de), unknown languages and * fall back to the default
+pub fn negotiate(accept_language: Option<&str>, default: Locale) -> Locale {
+    let Some(accept_language) = accept_language else {
+        return default;
+    };
+
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let quality = parts
+                .find_map(|part| part.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // stable sort, equal qualities keep the order of the header
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    ranges
+        .into_iter()
+        .find_map(|(tag, _)| match tag {
+            "*" => Some(default),
+            tag => tag.split('-').next().and_then(|lang| lang.parse().ok()),
+        })
+        .unwrap_or(default)
+}
+
+/// Locale negotiated by the LocalizeService, negotiates with the default locale if the service is not used
+pub fn request_locale(request: &HttpRequest) -> Locale {
+    if let Some(locale) = request.extensions().get::<Locale>() {
+        return *locale;
+    }
+    negotiate(
+        request
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok()),
+        Locale::default(),
+    )
+}
+
+pub fn accepts_json(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+/// JSON body of localized responses, confirmations and errors alike
+#[derive(Serialize, ToSchema)]
+pub struct MessageBody {
+    /// stable message key, e.g. canvas.not_found
+    pub key: &'static str,
+    /// rendered in the negotiated locale
+    pub message: String,
+    pub params: BTreeMap<&'static str, String>,
+    /// code of store errors, see CanvasStoreError::code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+}
+
+/// Response containing the localized message, as JSON with the key if the request accepts JSON
+pub fn respond(request: &HttpRequest, status: StatusCode, message: &Message) -> HttpResponse {
+    render_response(request, status, message, None)
+}
+
+/// JSON responses to store errors carry the code of the error next to the key
+fn render_response(
+    request: &HttpRequest,
+    status: StatusCode,
+    message: &Message,
+    code: Option<&'static str>,
+) -> HttpResponse {
+    let localized = message.render(request_locale(request));
+
+    if accepts_json(request) {
+        // params allow clients to act on the message, e.g. re-prompt with the current version
+        HttpResponse::build(status).json(MessageBody {
+            key: message.key.key(),
+            message: localized,
+            params: message
+                .params
+                .iter()
+                .map(|(name, value)| (*name, value.clone()))
+                .collect(),
+            code,
+        })
+    } else {
+        HttpResponse::build(status)
+            .insert_header(ContentType::plaintext())
+            .body(localized)
+    }
+}
+
+/// Error that can be localized, store errors add their code and internal detail
+struct ErrorReport {
+    message: Message,
+    code: Option<&'static str>,
+    detail: Option<String>,
+}
+
+fn error_report(error: &Error) -> Option<ErrorReport> {
+    if let Some(error) = error.as_error::<LocalizedError>() {
+        return Some(ErrorReport {
+            message: error.message.clone(),
+            code: None,
+            detail: None,
+        });
+    }
+    if let Some(error) = error.as_error::<CanvasStoreError>() {
+        return Some(ErrorReport {
+            message: error.message(),
+            code: Some(error.code()),
+            detail: error.detail().map(str::to_string),
+        });
+    }
+    error.as_error::<UserStoreError>().map(|error| ErrorReport {
+        message: error.message(),
+        code: Some(error.code()),
+        detail: error.detail().map(str::to_string),
+    })
+}
+
+/// Localizable message of the error, None for errors without one
+pub fn error_message(error: &Error) -> Option<Message> {
+    error_report(error).map(|report| report.message)
+}
+
+/// Actix Middleware
+/// Negotiates the locale of every request and stores it in the request extensions
+/// Re-renders localizable errors in the negotiated locale
+/// Internal server errors are logged with their detail under a request id, the response only names the id
+pub struct LocalizeService {
+    default_locale: Locale,
+}
+
+impl LocalizeService {
+    pub fn new(default_locale: Locale) -> Self {
+        Self { default_locale }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LocalizeService
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = LocalizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LocalizeMiddleware {
+            service,
+            default_locale: self.default_locale,
+        }))
+    }
+}
+
+pub struct LocalizeMiddleware<S> {
+    service: S,
+    default_locale: Locale,
+}
+
+impl<S, B> Service<ServiceRequest> for LocalizeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let locale = negotiate(
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok()),
+            self.default_locale,
+        );
+        req.extensions_mut().insert(locale);
+
+        // errors of inner middleware have to be turned into responses to be localized
+        self.service
+            .call(req)
+            .map_ok(|res| {
+                let Some(report) = res.response().error().and_then(error_report) else {
+                    return res.map_into_left_body();
+                };
+
+                let status = res.status();
+                let mut message = report.message;
+                if status == StatusCode::INTERNAL_SERVER_ERROR {
+                    let request_id = nanoid!(10);
+                    println!(
+                        "ERROR: {} on {}, request {request_id}: {}",
+                        report.code.unwrap_or(message.key.key()),
+                        res.request().path(),
+                        report.detail.as_deref().unwrap_or("no detail"),
+                    );
+                    message = message.param("request", &request_id);
+                }
+                let response = render_response(res.request(), status, &message, report.code);
+                res.into_response(response).map_into_right_body()
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::TestRequest, web, App};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(Some("en-US,en;q=0.9"), Locale::De), Locale::En);
+        assert_eq!(
+            negotiate(Some("fr;q=1.0, en;q=0.5, de;q=0.8"), Locale::En),
+            Locale::De
+        );
+        assert_eq!(negotiate(Some("de-AT"), Locale::En), Locale::De);
+        // q=0 means not acceptable
+        assert_eq!(negotiate(Some("en;q=0, de;q=0.1"), Locale::En), Locale::De);
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_default() {
+        assert_eq!(negotiate(None, Locale::En), Locale::En);
+        assert_eq!(negotiate(Some("fr-FR, ja"), Locale::De), Locale::De);
+        assert_eq!(negotiate(Some("*"), Locale::En), Locale::En);
+        assert_eq!(negotiate(Some(";;,q=,"), Locale::De), Locale::De);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let message = Message::new(MessageKey::CanvasUserAdded)
+            .param("user", "alice")
+            .param("access_level", "Write");
+        assert_eq!(message.render(Locale::En), "alice added as Write");
+        assert_eq!(message.render(Locale::De), "alice als Write hinzugefügt");
+
+        // unknown placeholders are left alone
+        assert_eq!(
+            Message::new(MessageKey::InvalidReplayCutoff).render(Locale::En),
+            "Invalid cutoff {value}, expected <timestamp> or seq:<number>"
+        );
+    }
+
+    async fn failing_handler() -> Result<HttpResponse, Error> {
+        Err(CanvasStoreError::CanvasNotFound.into())
+    }
+
+    #[actix_web::test]
+    async fn test_errors_are_localized_per_request() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .route("/", web::get().to(failing_handler))
+                .wrap(LocalizeService::new(Locale::De)),
+        )
+        .await;
+
+        let body = actix_web::test::call_and_read_body(&app, TestRequest::get().to_request()).await;
+        assert_eq!(body, "Canvas nicht gefunden");
+
+        let res = actix_web::test::call_service(
+            &app,
+            TestRequest::get()
+                .insert_header((header::ACCEPT_LANGUAGE, "en"))
+                .insert_header((header::ACCEPT, "application/json"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(body["key"], "canvas.not_found");
+        assert_eq!(body["message"], "Canvas not found");
+    }
+
+    const DETAIL: &str = "/var/lib/canvas/users.jsonl: expected value at line 1 column 1";
+
+    fn canvas_store_errors() -> Vec<CanvasStoreError> {
+        vec![
+            CanvasStoreError::CanvasNotFound,
+            CanvasStoreError::AccessDenied(MessageKey::AccessLevelChangeDenied),
+            CanvasStoreError::persistence(DETAIL),
+            CanvasStoreError::IdGenerationFailed,
+            CanvasStoreError::VersionConflict {
+                current_version: 2,
+                state: crate::canvas::store::CanvasState::Active,
+            },
+            CanvasStoreError::InvalidTransition {
+                from: crate::canvas::store::CanvasState::Archived,
+                action: crate::canvas::store::CanvasStateAction::Moderate,
+            },
+            CanvasStoreError::Degraded,
+            CanvasStoreError::AccessRequestNotFound,
+            CanvasStoreError::AlreadyMember,
+            CanvasStoreError::DuplicateName("Board".to_string()),
+        ]
+    }
+
+    fn user_store_errors() -> Vec<UserStoreError> {
+        vec![
+            UserStoreError::UserNotFound,
+            UserStoreError::EmailTaken,
+            UserStoreError::UsernameTaken,
+            UserStoreError::UsernameRejected(crate::username::UsernameViolation::Confusable),
+            UserStoreError::IdGenerationFailed,
+            UserStoreError::persistence(DETAIL),
+            UserStoreError::PasswordResetInvalid,
+            UserStoreError::Degraded,
+            UserStoreError::PreferencesConflict,
+        ]
+    }
+
+    // no wildcard arms, new variants fail to compile until they are listed above
+    fn expected_canvas_status(error: &CanvasStoreError) -> StatusCode {
+        match error {
+            CanvasStoreError::CanvasNotFound => StatusCode::NOT_FOUND,
+            CanvasStoreError::AccessDenied(_) => StatusCode::FORBIDDEN,
+            CanvasStoreError::PersistenceFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CanvasStoreError::IdGenerationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            CanvasStoreError::VersionConflict { .. } => StatusCode::CONFLICT,
+            CanvasStoreError::InvalidTransition { .. } => StatusCode::CONFLICT,
+            CanvasStoreError::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+            CanvasStoreError::AccessRequestNotFound => StatusCode::NOT_FOUND,
+            CanvasStoreError::AlreadyMember => StatusCode::CONFLICT,
+            CanvasStoreError::DuplicateName(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    fn expected_user_status(error: &UserStoreError) -> StatusCode {
+        match error {
+            UserStoreError::UserNotFound => StatusCode::NOT_FOUND,
+            UserStoreError::EmailTaken => StatusCode::CONFLICT,
+            UserStoreError::UsernameTaken => StatusCode::CONFLICT,
+            UserStoreError::UsernameRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserStoreError::IdGenerationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            UserStoreError::PersistenceFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UserStoreError::PasswordResetInvalid => StatusCode::BAD_REQUEST,
+            UserStoreError::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+            UserStoreError::PreferencesConflict => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+
+    async fn store_error_handler(index: web::Path<usize>) -> Result<HttpResponse, Error> {
+        let mut errors: Vec<Error> = canvas_store_errors().into_iter().map(Error::from).collect();
+        errors.extend(user_store_errors().into_iter().map(Error::from));
+        Err(errors.swap_remove(index.into_inner()))
+    }
+
+    #[actix_web::test]
+    async fn test_store_errors_respond_with_code_but_without_detail() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .route("/{index}", web::get().to(store_error_handler))
+                .wrap(LocalizeService::new(Locale::En)),
+        )
+        .await;
+
+        let expected: Vec<(StatusCode, &str)> = canvas_store_errors()
+            .iter()
+            .map(|error| (expected_canvas_status(error), error.code()))
+            .chain(
+                user_store_errors()
+                    .iter()
+                    .map(|error| (expected_user_status(error), error.code())),
+            )
+            .collect();
+
+        let mut codes: Vec<&str> = expected.iter().map(|(_, code)| *code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), expected.len());
+
+        for (index, (status, code)) in expected.into_iter().enumerate() {
+            let res = actix_web::test::call_service(
+                &app,
+                TestRequest::get()
+                    .uri(&format!("/{index}"))
+                    .insert_header((header::ACCEPT, "application/json"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(res.status(), status, "{code}");
+            let body = actix_web::test::read_body(res).await;
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(!body.contains(DETAIL), "{code} leaks its detail: {body}");
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["code"], code);
+            if status == StatusCode::INTERNAL_SERVER_ERROR {
+                assert!(body["params"]["request"].is_string(), "{code}");
+            }
+
+            let body = actix_web::test::call_and_read_body(
+                &app,
+                TestRequest::get().uri(&format!("/{index}")).to_request(),
+            )
+            .await;
+            assert!(!String::from_utf8(body.to_vec()).unwrap().contains(DETAIL));
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    canvas::{access_requests::AccessRequestNotice, digest::CanvasDigest},
+    userstore::UserId,
+};
+
+// Delivery of messages to users outside of the application, e.g. password reset links, activity digests or access requests
+// The server has no mail transport, the default LogNotifier prints the messages to the server log
+// Deployments plug in their own delivery through ServerConfig, tests record the messages
+
+pub trait Notifier: Debug + Send + Sync {
+    /// Delivers the link to reset the password of the user, the only place the reset token is passed to
+    fn password_reset(&self, user_id: &UserId, email: &str, reset_link: &str);
+
+    /// Delivers the activity digest of a canvas to its owner, see canvas::digest
+    fn canvas_digest(&self, owner_id: &UserId, digest: &CanvasDigest);
+
+    /// Tells an owner or moderator about a new access request, see canvas::access_requests
+    fn access_requested(&self, recipient_id: &UserId, notice: &AccessRequestNotice);
+
+    /// Tells the requester that the request was approved or denied
+    fn access_request_resolved(&self, notice: &AccessRequestNotice);
+}
+
+pub type SharedNotifier = Arc<dyn Notifier>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn password_reset(&self, user_id: &UserId, email: &str, reset_link: &str) {
+        println!("Password reset for {user_id} <{email}>: {reset_link}");
+    }
+
+    fn canvas_digest(&self, owner_id: &UserId, digest: &CanvasDigest) {
+        println!(
+            "Digest of canvas {} for {owner_id}: {} added, {} updated, {} removed, {} comments, {} membership changes by {} contributors (events {}-{})",
+            digest.canvas_id,
+            digest.shapes_added,
+            digest.shapes_updated,
+            digest.shapes_removed,
+            digest.comments_added,
+            digest.membership_changes,
+            digest.contributors.len(),
+            digest.from_seq,
+            digest.to_seq
+        );
+    }
+
+    fn access_requested(&self, recipient_id: &UserId, notice: &AccessRequestNotice) {
+        println!(
+            "Access request of {} for canvas {} sent to {recipient_id}",
+            notice.request.user_id, notice.canvas_id
+        );
+    }
+
+    fn access_request_resolved(&self, notice: &AccessRequestNotice) {
+        println!(
+            "Access request of {} for canvas {} resolved: {:?}",
+            notice.request.user_id, notice.canvas_id, notice.request.status
+        );
+    }
+}
+
+/// Notification kept by the RecordingNotifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordResetNotification {
+    pub user_id: UserId,
+    pub email: String,
+    pub reset_link: String,
+}
+
+/// Keeps every notification instead of delivering it
+#[derive(Debug, Default)]
+pub struct RecordingNotifier {
+    password_resets: Mutex<Vec<PasswordResetNotification>>,
+    canvas_digests: Mutex<Vec<(UserId, CanvasDigest)>>,
+    access_requests: Mutex<Vec<(UserId, AccessRequestNotice)>>,
+}
+
+impl RecordingNotifier {
+    pub fn password_resets(&self) -> Vec<PasswordResetNotification> {
+        self.password_resets.lock().unwrap().clone()
+    }
+
+    /// Owner and digest of every delivered digest, oldest first
+    pub fn canvas_digests(&self) -> Vec<(UserId, CanvasDigest)> {
+        self.canvas_digests.lock().unwrap().clone()
+    }
+
+    /// Recipient and notice of every request and answer, oldest first, answers go to the requester
+    pub fn access_requests(&self) -> Vec<(UserId, AccessRequestNotice)> {
+        self.access_requests.lock().unwrap().clone()
+    }
+}
+
+impl Notifier for RecordingNotifier {
+    fn password_reset(&self, user_id: &UserId, email: &str, reset_link: &str) {
+        self.password_resets
+            .lock()
+            .unwrap()
+            .push(PasswordResetNotification {
+                user_id: user_id.clone(),
+                email: email.to_string(),
+                reset_link: reset_link.to_string(),
+            });
+    }
+
+    fn canvas_digest(&self, owner_id: &UserId, digest: &CanvasDigest) {
+        self.canvas_digests
+            .lock()
+            .unwrap()
+            .push((owner_id.clone(), digest.clone()));
+    }
+
+    fn access_requested(&self, recipient_id: &UserId, notice: &AccessRequestNotice) {
+        self.access_requests
+            .lock()
+            .unwrap()
+            .push((recipient_id.clone(), notice.clone()));
+    }
+
+    fn access_request_resolved(&self, notice: &AccessRequestNotice) {
+        self.access_requests
+            .lock()
+            .unwrap()
+            .push((notice.request.user_id.clone(), notice.clone()));
+    }
+}
+
+pub fn log() -> SharedNotifier {
+    Arc::new(LogNotifier)
+}
@@ -0,0 +1,108 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::time::{Duration, Instant};
+
+// Argon2 configuration
+// Defaults are taken from the OWASP Password Storage Cheat Sheet
+// https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id
+// Optionally the iteration count can be calibrated on startup to match a target hashing duration
+
+pub const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+pub const DEFAULT_ITERATIONS: u32 = 3;
+pub const DEFAULT_PARALLELISM: u32 = 2;
+
+/// Upper bound for calibrated iterations, protects against a broken measurement
+const MAX_CALIBRATED_ITERATIONS: u32 = 20;
+
+#[derive(clap::Args, Default, Debug, Clone)]
+pub struct PasswordHashConfig {
+    /// Argon2 memory cost in KiB
+    #[arg(long = "argon2-memory-kib", env = "ARGON2_MEMORY_KIB")]
+    pub memory_kib: Option<u32>,
+    /// Argon2 iterations, ignored if calibration is enabled
+    #[arg(long = "argon2-iterations", env = "ARGON2_ITERATIONS")]
+    pub iterations: Option<u32>,
+    /// Argon2 parallelism
+    #[arg(long = "argon2-parallelism", env = "ARGON2_PARALLELISM")]
+    pub parallelism: Option<u32>,
+    /// Benchmark hashing on startup and pick the iterations to match this duration
+    #[arg(long = "argon2-calibrate-ms", env = "ARGON2_CALIBRATE_MS")]
+    pub calibrate_ms: Option<u64>,
+}
+
+impl PasswordHashConfig {
+    /// Builds the argon2 params, runs the calibration if configured
+    pub fn build_params(&self) -> Result<Params, argon2::Error> {
+        let memory_kib = self.memory_kib.unwrap_or(DEFAULT_MEMORY_KIB);
+        let parallelism = self.parallelism.unwrap_or(DEFAULT_PARALLELISM);
+
+        let iterations = match self.calibrate_ms {
+            Some(target) => {
+                calibrate_iterations(memory_kib, parallelism, Duration::from_millis(target))?
+            }
+            None => self.iterations.unwrap_or(DEFAULT_ITERATIONS),
+        };
+
+        Params::new(memory_kib, iterations, parallelism, None)
+    }
+}
+
+/// Creates the argon2 instance used for hashing and verifying
+/// pepper/secret not used
+pub fn argon2_with_params(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Measures a single iteration and extrapolates the iterations needed to reach the target duration
+/// Hashing time grows roughly linear with the iterations
+fn calibrate_iterations(
+    memory_kib: u32,
+    parallelism: u32,
+    target: Duration,
+) -> Result<u32, argon2::Error> {
+    let argon = argon2_with_params(Params::new(memory_kib, 1, parallelism, None)?);
+    let salt = SaltString::generate(&mut OsRng);
+
+    let start = Instant::now();
+    argon
+        .hash_password(b"calibration", &salt)
+        .map_err(|_| argon2::Error::AlgorithmInvalid)?;
+    let single_iteration = start.elapsed().max(Duration::from_micros(1));
+
+    let iterations = (target.as_secs_f64() / single_iteration.as_secs_f64()).round() as u32;
+    let iterations = iterations.clamp(1, MAX_CALIBRATED_ITERATIONS);
+
+    println!(
+        "Argon2 calibration: one iteration took {single_iteration:?}, using {iterations} iterations (m={memory_kib}KiB, p={parallelism}) for a target of {target:?}"
+    );
+
+    Ok(iterations)
+}
+
+/// Checks if a stored hash was created with weaker settings than the current configuration
+pub fn needs_rehash(hash: &PasswordHash<'_>, current: &Params) -> bool {
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+
+    match Params::try_from(hash) {
+        Ok(params) => {
+            params.m_cost() < current.m_cost()
+                || params.t_cost() < current.t_cost()
+                || params.p_cost() < current.p_cost()
+        }
+        // unreadable parameters, rehash to get a clean hash
+        Err(_) => true,
+    }
+}
+
+/// Hash password to PHC string ($argon2id$v=19$...)
+pub fn hash_password(argon: &Argon2<'_>, password: &[u8]) -> Result<String, std::io::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon
+        .hash_password(password, &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| std::io::Error::other("Failed to hash password"))
+}
@@ -3,30 +3,66 @@ use actix::Actor;
 use actix::{Handler, Message};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::fmt;
 use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use utoipa::ToSchema;
 
-/// Simple File based Event persistence
-/// Can either turn into a standalone actor holding a file handle
-/// or into an actor that can be used in the whole system
+use crate::encryption::{EventLogKey, LineCodec};
+use crate::mailbox;
+
+// Simple File based Event persistence
+// Can either turn into a standalone actor holding a file handle
+// or into an actor that can be used in the whole system
+// Lines are encoded by a LineCodec, encrypted if an eventlog key is installed, see encryption.rs
 
 pub struct EventLogPersistenceActorJson {
     // this could use tokio::fs::File, but synchronous file access is easier :)
-    file: std::fs::File,
+    writer: Box<dyn Write>,
+    codec: LineCodec,
+    mailbox_capacity: usize,
 }
 
 pub struct EventLogPersistenceStandaloneJson<T> {
     file: std::fs::File,
+    codec: LineCodec,
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl Actor for EventLogPersistenceActorJson {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
+    }
+}
+
+impl EventLogPersistenceActorJson {
+    /// Appends events to any writer, e.g. a slow one simulating a congested disk in tests
+    pub fn with_writer(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            codec: LineCodec::default(),
+            mailbox_capacity: mailbox::DEFAULT_MAILBOX_CAPACITY,
+        }
+    }
+
+    pub fn line_codec(mut self, codec: LineCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = capacity;
+        self
+    }
 }
 
 pub struct EventLogPersistenceJson {
     // this could use tokio::fs::File, but synchronous file access is easier :)
     file: std::fs::File,
+    codec: LineCodec,
 }
 
 impl EventLogPersistenceJson {
@@ -39,7 +75,57 @@ impl EventLogPersistenceJson {
 
         // consider locking file
         // https://docs.rs/file-guard/latest/file_guard/
-        Ok(Self { file })
+        Ok(Self {
+            file,
+            codec: LineCodec::installed(file_path),
+        })
+    }
+
+    /// Opens an existing eventlog for reading only
+    /// Used by maintenance tooling, which should never create or append to a log
+    pub fn open(file_path: &str) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().read(true).open(file_path)?;
+        Ok(Self {
+            file,
+            codec: LineCodec::installed(file_path),
+        })
+    }
+
+    /// Replaces the installed eventlog key, None reads and writes plaintext
+    pub fn with_key(mut self, key: Option<Arc<EventLogKey>>) -> Self {
+        self.codec = self.codec.with_key(key);
+        self
+    }
+
+    /// Replaces the installed instance id, None appends unstamped lines
+    pub fn with_instance_id(mut self, instance_id: Option<Arc<str>>) -> Self {
+        self.codec = self.codec.with_instance_id(instance_id);
+        self
+    }
+
+    /// Lazily read and deserialize the eventlog line by line
+    /// Only a single line is held in memory, allows folding logs that are too large to load at once
+    /// A line that fails to decrypt is an io error naming the key id, it can't be skipped like a broken event
+    pub fn stream_lines<T>(
+        &self,
+    ) -> impl Iterator<Item = Result<Result<T, serde_json::Error>, std::io::Error>> + '_
+    where
+        T: DeserializeOwned,
+    {
+        BufReader::new(&self.file).lines().map(|raw_line| {
+            let line = raw_line?;
+            let line = self.codec.decode(&line)?;
+            Ok(serde_json::from_str::<T>(&line))
+        })
+    }
+
+    /// Synchonously read and deserialize every line of the eventlog
+    /// Lines that fail to deserialize are returned as errors, so callers can decide how to handle them
+    pub fn read_lines<T>(&self) -> Result<Vec<Result<T, serde_json::Error>>, std::io::Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.stream_lines().collect()
     }
 
     /// Synchonously read and deserialize all lines from the saved eventlog
@@ -48,19 +134,14 @@ impl EventLogPersistenceJson {
     where
         T: DeserializeOwned,
     {
-        let buffered_reader = BufReader::new(&self.file);
-
         // read all events from the eventlog
-        let events = buffered_reader
-            .lines()
-            .map(|raw_line| raw_line.map(|line| serde_json::from_str::<T>(&line)))
-            .collect::<Result<Vec<Result<T, serde_json::Error>>, std::io::Error>>()?;
+        let events = self.read_lines::<T>()?;
 
         Ok((
             events
                 .into_iter()
                 .collect::<Result<Vec<T>, serde_json::Error>>()?,
-            EventLogPersistenceActorJson { file: self.file },
+            EventLogPersistenceActorJson::with_writer(self.file).line_codec(self.codec),
         ))
     }
 
@@ -72,13 +153,8 @@ impl EventLogPersistenceJson {
     where
         T: DeserializeOwned,
     {
-        let buffered_reader = BufReader::new(&self.file);
-
         // read all events from the eventlog
-        let events = buffered_reader
-            .lines()
-            .map(|raw_line| raw_line.map(|line| serde_json::from_str::<T>(&line)))
-            .collect::<Result<Vec<Result<T, serde_json::Error>>, std::io::Error>>()?;
+        let events = self.read_lines::<T>()?;
 
         Ok((
             events
@@ -86,20 +162,79 @@ impl EventLogPersistenceJson {
                 .collect::<Result<Vec<T>, serde_json::Error>>()?,
             EventLogPersistenceStandaloneJson {
                 file: self.file,
+                codec: self.codec,
                 _phantom: std::marker::PhantomData,
             },
         ))
     }
 }
 
+impl EventLogPersistenceJson {
+    /// Appends to the eventlog without reading it, for callers that restored its state otherwise
+    pub fn into_appender<T>(self) -> EventLogPersistenceStandaloneJson<T> {
+        EventLogPersistenceStandaloneJson {
+            file: self.file,
+            codec: self.codec,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+/// Replaces the eventlog at file_path with the given events
+/// Events are written to a temporary file first, which is then renamed over the original
+/// A crash during the write leaves the original eventlog untouched
+pub fn rewrite_event_log<T>(file_path: &str, events: &[T]) -> Result<(), std::io::Error>
+where
+    T: Serialize,
+{
+    let temp_path = format!("{file_path}.tmp");
+    // bound to the eventlog, not the temporary file, rewriting encrypts every line with the installed key
+    let codec = LineCodec::installed(file_path);
+    {
+        let mut temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        for event in events {
+            let line = serde_json::to_vec(event)?;
+            temp_file.write_all(&codec.encode(&line))?;
+            temp_file.write_all(b"\n")?;
+        }
+        temp_file.sync_all()?;
+    }
+    std::fs::rename(temp_path, file_path)
+}
+
+/// Removes the eventlog at file_path, a missing eventlog counts as removed
+pub fn remove_event_log(file_path: &str) -> Result<(), std::io::Error> {
+    match std::fs::remove_file(file_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 impl<T> EventLogPersistenceStandaloneJson<T>
 where
     T: Serialize,
 {
-    pub fn save_event(&mut self, event: &T) -> Result<(), std::io::Error> {
-        serde_json::to_writer(&self.file, event).unwrap();
-        self.file.write_all(&[b'\n'])?;
-        Ok(())
+    /// Returns the number of bytes appended to the eventlog
+    pub fn save_event(&mut self, event: &T) -> Result<u64, std::io::Error> {
+        let event = serde_json::to_vec(event)?;
+        let mut line = self.codec.encode(&event).into_owned();
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(line.len() as u64)
+    }
+
+    /// Syncs the appended events to disk, save_event only hands them to the operating system
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.file.sync_data()
+    }
+
+    /// Current size of the eventlog in bytes
+    pub fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.metadata()?.len())
     }
 }
 
@@ -118,8 +253,117 @@ where
     fn handle(&mut self, msg: PersistEventMessage<T>, _: &mut Self::Context) -> Self::Result {
         // in error case, consider writing to a different file
         // in a production environment this would need to be handled more gracefully and thoughtfully
-        serde_json::to_writer(&self.file, &msg.0).unwrap();
-        self.file.write_all(&[b'\n'])?;
+        let event = serde_json::to_vec(&msg.0).unwrap();
+        self.writer.write_all(&self.codec.encode(&event))?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum ReplayIssueKind {
+    /// event references a canvas or user that does not exist
+    UnknownReference,
+    /// entity created again or taking the email or name of another one
+    Duplicate,
+    /// invariant of the replayed state is violated
+    Invariant,
+}
+
+/// Problem found while replaying an eventlog, replays record them instead of failing
+/// Whether issues are fatal is decided by bootstrap, see ReplayMode
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ReplayIssue {
+    /// index of the event in the eventlog starting at 0, None for invariants of the final state
+    pub event_index: Option<usize>,
+    pub kind: ReplayIssueKind,
+    pub details: String,
+    /// event was not applied
+    pub skipped: bool,
+}
+
+impl ReplayIssue {
+    pub fn event(event_index: usize, kind: ReplayIssueKind, details: impl Into<String>) -> Self {
+        Self {
+            event_index: Some(event_index),
+            kind,
+            details: details.into(),
+            skipped: false,
+        }
+    }
+
+    pub fn skipped(event_index: usize, details: impl Into<String>) -> Self {
+        Self {
+            skipped: true,
+            ..Self::event(event_index, ReplayIssueKind::UnknownReference, details)
+        }
+    }
+
+    pub fn invariant(details: impl Into<String>) -> Self {
+        Self {
+            event_index: None,
+            kind: ReplayIssueKind::Invariant,
+            details: details.into(),
+            skipped: false,
+        }
+    }
+}
+
+impl fmt::Display for ReplayIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(event_index) = self.event_index {
+            // line numbers start at 1, like in the output of inspect
+            write!(f, "line {}: ", event_index + 1)?;
+        }
+        write!(f, "{}", self.details)?;
+        if self.skipped {
+            write!(f, " (skipped)")?;
+        }
+        Ok(())
+    }
+}
+
+/// How bootstrap treats issues found while replaying the store eventlogs
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayMode {
+    /// any issue aborts the startup
+    Strict,
+    /// the store starts with the events that could be applied, issues are reported
+    Tolerant,
+}
+
+impl Default for ReplayMode {
+    /// developers should notice a broken log, a production server should stay available
+    fn default() -> Self {
+        if cfg!(feature = "dev") {
+            ReplayMode::Strict
+        } else {
+            ReplayMode::Tolerant
+        }
+    }
+}
+
+/// Issues found while replaying the store eventlogs at startup, listed on the admin endpoint
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct ReplayIssues {
+    pub user: Vec<ReplayIssue>,
+    pub canvas: Vec<ReplayIssue>,
+}
+
+impl ReplayIssues {
+    pub fn is_empty(&self) -> bool {
+        self.user.is_empty() && self.canvas.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayIssues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.user {
+            writeln!(f, "User eventlog: {issue}")?;
+        }
+        for issue in &self.canvas {
+            writeln!(f, "Canvas eventlog: {issue}")?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,188 @@
+use crate::canvas::tokens::TokenRateLimiter;
+use crate::messages::{Message, MessageKey};
+use crate::userstore::UserId;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+// UI preferences of a user, GET/PUT /api/me/preferences
+// A flat map of ui. keys to primitives, stored as a whole by UserPreferencesChanged events
+// The server only checks the shape and size, the meaning of the keys is up to the frontend
+
+/// Prefix of every preference key
+pub const PREFERENCE_KEY_PREFIX: &str = "ui.";
+/// Characters of a key, including the prefix
+pub const MAX_PREFERENCE_KEY_LENGTH: usize = 64;
+/// Characters of a string value, colors and tool names fit easily
+pub const MAX_PREFERENCE_STRING_LENGTH: usize = 256;
+/// Serialized size of all preferences of a user
+pub const MAX_PREFERENCES_BYTES: usize = 8 * 1024;
+
+/// Writes per user and window, a few tabs saving now and then fit, a client saving on every change does not
+pub const PREFERENCES_WRITE_LIMIT: u32 = 6;
+pub const PREFERENCES_WRITE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Preferences of a user as stored and answered
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default, PartialEq)]
+pub struct UserPreferences {
+    #[schema(value_type = Object)]
+    pub preferences: Map<String, Value>,
+    /// counts the changes, sent back in If-Match to not overwrite changes of another tab
+    pub version: u64,
+}
+
+/// Why preferences were rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreferencesViolation {
+    NotAnObject,
+    TooLarge,
+    Key(String),
+    Value(String),
+}
+
+impl PreferencesViolation {
+    pub fn message(&self) -> Message {
+        match self {
+            PreferencesViolation::NotAnObject => Message::new(MessageKey::PreferencesInvalid)
+                .param("field", "preferences")
+                .param("reason", "not_an_object"),
+            PreferencesViolation::TooLarge => {
+                Message::new(MessageKey::PreferencesTooLarge).param("max", MAX_PREFERENCES_BYTES)
+            }
+            PreferencesViolation::Key(key) => Message::new(MessageKey::PreferencesInvalid)
+                .param("field", key)
+                .param("reason", "invalid_key"),
+            PreferencesViolation::Value(key) => Message::new(MessageKey::PreferencesInvalid)
+                .param("field", key)
+                .param("reason", "invalid_value"),
+        }
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_PREFERENCE_KEY_LENGTH
+        && key.len() > PREFERENCE_KEY_PREFIX.len()
+        && key.starts_with(PREFERENCE_KEY_PREFIX)
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn valid_value(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(_) | Value::Number(_) => true,
+        Value::String(value) => value.chars().count() <= MAX_PREFERENCE_STRING_LENGTH,
+        Value::Array(_) | Value::Object(_) => false,
+    }
+}
+
+/// Checks the preferences sent by a client, they replace all stored ones
+pub fn validate(preferences: Value) -> Result<Map<String, Value>, PreferencesViolation> {
+    let Value::Object(preferences) = preferences else {
+        return Err(PreferencesViolation::NotAnObject);
+    };
+    // sorted, the first invalid key is named consistently
+    let mut keys: Vec<&String> = preferences.keys().collect();
+    keys.sort_unstable();
+    for key in keys {
+        if !valid_key(key) {
+            return Err(PreferencesViolation::Key(key.clone()));
+        }
+        if !valid_value(&preferences[key]) {
+            return Err(PreferencesViolation::Value(key.clone()));
+        }
+    }
+    let size = serde_json::to_vec(&preferences).map_or(usize::MAX, |bytes| bytes.len());
+    if size > MAX_PREFERENCES_BYTES {
+        return Err(PreferencesViolation::TooLarge);
+    }
+    Ok(preferences)
+}
+
+/// Fixed window rate limit of preference writes per user, shared between all workers using web::Data
+pub struct PreferencesLimiter(TokenRateLimiter);
+
+impl Default for PreferencesLimiter {
+    fn default() -> Self {
+        Self::new(PREFERENCES_WRITE_LIMIT, PREFERENCES_WRITE_WINDOW)
+    }
+}
+
+impl PreferencesLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self(TokenRateLimiter::new(limit, window))
+    }
+
+    /// Counts the write, false once the user used up the window
+    pub fn allow(&self, user_id: &UserId, now: Instant) -> bool {
+        self.0.allow(user_id, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_keys_and_values_are_validated() {
+        let valid = json!({
+            "ui.grid": true,
+            "ui.theme": "dark",
+            "ui.last_colors.0": "#ff0000",
+            "ui.stroke-width": 2.5,
+            "ui.tool": null,
+        });
+        assert_eq!(validate(valid.clone()).unwrap().len(), 5);
+        assert!(validate(json!({})).unwrap().is_empty());
+
+        assert_eq!(validate(json!([])), Err(PreferencesViolation::NotAnObject));
+        assert_eq!(
+            validate(json!({"theme": "dark"})),
+            Err(PreferencesViolation::Key("theme".to_string()))
+        );
+        assert_eq!(
+            validate(json!({"ui.": 1})),
+            Err(PreferencesViolation::Key("ui.".to_string()))
+        );
+        assert_eq!(
+            validate(json!({"ui.a b": 1})),
+            Err(PreferencesViolation::Key("ui.a b".to_string()))
+        );
+        let long_key = format!("ui.{}", "k".repeat(MAX_PREFERENCE_KEY_LENGTH));
+        assert_eq!(
+            validate(json!({ long_key.clone(): 1 })),
+            Err(PreferencesViolation::Key(long_key))
+        );
+        assert_eq!(
+            validate(json!({"ui.colors": ["#000"]})),
+            Err(PreferencesViolation::Value("ui.colors".to_string()))
+        );
+        assert_eq!(
+            validate(json!({"ui.nested": {"a": 1}})),
+            Err(PreferencesViolation::Value("ui.nested".to_string()))
+        );
+        assert_eq!(
+            validate(json!({"ui.note": "x".repeat(MAX_PREFERENCE_STRING_LENGTH + 1)})),
+            Err(PreferencesViolation::Value("ui.note".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_total_size_is_limited() {
+        let value = "x".repeat(MAX_PREFERENCE_STRING_LENGTH);
+        let many: Map<String, Value> = (0..40)
+            .map(|index| (format!("ui.key{index}"), Value::String(value.clone())))
+            .collect();
+        assert_eq!(
+            validate(Value::Object(many)),
+            Err(PreferencesViolation::TooLarge)
+        );
+
+        let few: Map<String, Value> = (0..20)
+            .map(|index| (format!("ui.key{index}"), Value::String(value.clone())))
+            .collect();
+        assert!(validate(Value::Object(few)).is_ok());
+    }
+}
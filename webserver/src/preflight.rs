@@ -0,0 +1,444 @@
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Checks of the directories the server reads and writes, run by bootstrap before any store is opened
+// Every finding names the absolute path it is about, all of them are printed as one summary
+// Only findings that keep the server from working refuse the startup, a missing frontend build degrades the pages
+
+/// Probe file written into every data dir, removed right after
+const PROBE_FILE: &str = ".preflight-probe";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Ok,
+    /// the server starts, some pages or files are missing
+    Warning,
+    /// the server refuses to start
+    Fatal,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    /// checked part of the setup, e.g. templates
+    pub component: &'static str,
+    /// absolute path, resolved against the working directory
+    pub path: String,
+    pub severity: Severity,
+    /// what was found, and what to fix for warnings and fatal findings
+    pub message: String,
+}
+
+/// Findings of a preflight, shown to admins at GET /admin/api/preflight
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreflightReport {
+    pub findings: Vec<Finding>,
+}
+
+impl PreflightReport {
+    fn push(&mut self, component: &'static str, path: &Path, severity: Severity, message: String) {
+        self.findings.push(Finding {
+            component,
+            path: absolute(path).display().to_string(),
+            severity,
+            message,
+        });
+    }
+
+    pub fn fatal(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Fatal)
+    }
+
+    /// Error refusing the startup, listing what to fix, None if nothing is fatal
+    pub fn refusal(&self) -> Option<io::Error> {
+        let fatal: Vec<String> = self
+            .fatal()
+            .map(|finding| format!("  - {}", finding.message))
+            .collect();
+        (!fatal.is_empty()).then(|| {
+            io::Error::other(format!(
+                "Refusing to start, fix the following:\n{}",
+                fatal.join("\n")
+            ))
+        })
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Preflight:")?;
+        for finding in &self.findings {
+            let severity = match finding.severity {
+                Severity::Ok => "ok",
+                Severity::Warning => "WARNING",
+                Severity::Fatal => "FATAL",
+            };
+            writeln!(
+                f,
+                "  {severity:<7} {:<9} {}",
+                finding.component, finding.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Paths checked by run, taken from the ServerConfig
+#[derive(Debug, Clone)]
+pub struct PreflightPaths {
+    pub template_dir: String,
+    pub dist_dir: String,
+    /// eventlogs of the stores and other files the server appends to, their directories are probed
+    pub event_logs: Vec<String>,
+    /// missing directories of the eventlogs are created instead of refusing the startup
+    pub create_data_dirs: bool,
+    /// vite entry served for dev=1, only checked in dev builds
+    pub dev_index: Option<String>,
+}
+
+/// Path resolved against the working directory, the path itself if there is none
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Directory of a file path, the working directory for bare file names
+fn parent_dir(file_path: &str) -> PathBuf {
+    match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Creates, appends to and deletes a file in the directory
+fn probe(dir: &Path) -> io::Result<()> {
+    let path = dir.join(PROBE_FILE);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&path)?;
+    file.write_all(b"probe\n")?;
+    drop(file);
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+    file.write_all(b"probe\n")?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(&path)
+}
+
+fn check_templates(report: &mut PreflightReport, template_dir: &str) {
+    let path = Path::new(template_dir);
+    if path.is_dir() {
+        report.push(
+            "templates",
+            path,
+            Severity::Ok,
+            format!("templates dir '{}' found", absolute(path).display()),
+        );
+    } else if cfg!(feature = "embed-frontend") {
+        report.push(
+            "templates",
+            path,
+            Severity::Ok,
+            format!(
+                "templates dir '{}' not found, the embedded templates are served",
+                absolute(path).display()
+            ),
+        );
+    } else {
+        report.push(
+            "templates",
+            path,
+            Severity::Fatal,
+            format!(
+                "templates dir '{}' not found; set --template-dir or build with the embed-frontend feature",
+                absolute(path).display()
+            ),
+        );
+    }
+}
+
+fn check_dist(report: &mut PreflightReport, dist_dir: &str) {
+    let path = Path::new(dist_dir);
+    if cfg!(feature = "embed-frontend") {
+        report.push(
+            "dist",
+            path,
+            Severity::Ok,
+            "the frontend build embedded in the binary is served".to_string(),
+        );
+    } else if !path.is_dir() {
+        report.push(
+            "dist",
+            path,
+            Severity::Warning,
+            format!(
+                "dist dir '{}' not found, the frontend answers 404; run npm run build or set --dist-dir",
+                absolute(path).display()
+            ),
+        );
+    } else if !path.join("index.html").is_file() {
+        report.push(
+            "dist",
+            path,
+            Severity::Warning,
+            format!(
+                "dist dir '{}' has no index.html, the frontend answers 404; run npm run build",
+                absolute(path).display()
+            ),
+        );
+    } else {
+        report.push(
+            "dist",
+            path,
+            Severity::Ok,
+            format!("dist dir '{}' found", absolute(path).display()),
+        );
+    }
+}
+
+fn check_data_dir(report: &mut PreflightReport, dir: &Path, create: bool) {
+    let display = absolute(dir).display().to_string();
+    if !dir.is_dir() {
+        if !create {
+            report.push(
+                "data",
+                dir,
+                Severity::Fatal,
+                format!(
+                    "data dir '{display}' not found; create it or start with --create-data-dirs"
+                ),
+            );
+            return;
+        }
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            report.push(
+                "data",
+                dir,
+                Severity::Fatal,
+                format!("data dir '{display}' could not be created: {e}"),
+            );
+            return;
+        }
+        println!("Created data dir {display}");
+    }
+
+    match probe(dir) {
+        Ok(()) => report.push(
+            "data",
+            dir,
+            Severity::Ok,
+            format!("data dir '{display}' is writable"),
+        ),
+        Err(e) => report.push(
+            "data",
+            dir,
+            Severity::Fatal,
+            format!("data dir '{display}' is not writable: {e}; fix its permissions or start from another directory"),
+        ),
+    }
+}
+
+fn check_dev_index(report: &mut PreflightReport, dev_index: &str) {
+    let path = Path::new(dev_index);
+    if path.is_file() {
+        report.push(
+            "dev",
+            path,
+            Severity::Ok,
+            format!("vite entry '{}' found", absolute(path).display()),
+        );
+    } else {
+        report.push(
+            "dev",
+            path,
+            Severity::Warning,
+            format!(
+                "vite entry '{}' not found, dev=1 answers 404; start the server from the webserver dir",
+                absolute(path).display()
+            ),
+        );
+    }
+}
+
+/// Checks every path, directories of the eventlogs are created if configured
+pub fn run(paths: &PreflightPaths) -> PreflightReport {
+    let mut report = PreflightReport::default();
+    check_templates(&mut report, &paths.template_dir);
+    check_dist(&mut report, &paths.dist_dir);
+
+    // canvas eventlogs are written to the working directory, see canvas::server::canvas_log_path
+    let mut data_dirs = vec![PathBuf::from(".")];
+    for file_path in &paths.event_logs {
+        let dir = parent_dir(file_path);
+        if !data_dirs
+            .iter()
+            .any(|known| absolute(known) == absolute(&dir))
+        {
+            data_dirs.push(dir);
+        }
+    }
+    for dir in &data_dirs {
+        check_data_dir(&mut report, dir, paths.create_data_dirs);
+    }
+
+    if let Some(dev_index) = &paths.dev_index {
+        check_dev_index(&mut report, dev_index);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("preflight-{}", nanoid::nanoid!(8)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Paths of a complete setup in dir
+    fn complete(dir: &Path) -> PreflightPaths {
+        let templates = dir.join("templates");
+        let dist = dir.join("dist");
+        let data = dir.join("data");
+        for created in [&templates, &dist, &data] {
+            std::fs::create_dir_all(created).unwrap();
+        }
+        std::fs::write(dist.join("index.html"), "").unwrap();
+        std::fs::write(dir.join("index.html"), "").unwrap();
+        PreflightPaths {
+            template_dir: templates.display().to_string(),
+            dist_dir: dist.display().to_string(),
+            event_logs: vec![data.join("user_eventlog.jsonl").display().to_string()],
+            create_data_dirs: false,
+            dev_index: Some(dir.join("index.html").display().to_string()),
+        }
+    }
+
+    fn finding<'a>(report: &'a PreflightReport, component: &str) -> &'a Finding {
+        report
+            .findings
+            .iter()
+            .rev()
+            .find(|finding| finding.component == component)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_complete_setup_has_no_findings_to_fix() {
+        let dir = temp_dir();
+        let report = run(&complete(&dir));
+        assert!(report
+            .findings
+            .iter()
+            .all(|finding| finding.severity == Severity::Ok));
+        assert!(report.refusal().is_none());
+        // the probe leaves nothing behind
+        assert!(!dir.join("data").join(PROBE_FILE).exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_each_missing_component_is_reported() {
+        let dir = temp_dir();
+        let paths = complete(&dir);
+
+        std::fs::remove_dir_all(&paths.template_dir).unwrap();
+        let report = run(&paths);
+        let templates = finding(&report, "templates");
+        if cfg!(feature = "embed-frontend") {
+            assert_eq!(templates.severity, Severity::Ok);
+        } else {
+            assert_eq!(templates.severity, Severity::Fatal);
+            assert_eq!(
+                templates.message,
+                format!(
+                    "templates dir '{}' not found; set --template-dir or build with the embed-frontend feature",
+                    paths.template_dir
+                )
+            );
+            let refusal = report.refusal().unwrap().to_string();
+            assert!(refusal.contains(&templates.message), "{refusal}");
+        }
+        std::fs::create_dir_all(&paths.template_dir).unwrap();
+
+        std::fs::remove_file(Path::new(&paths.dist_dir).join("index.html")).unwrap();
+        let report = run(&paths);
+        if !cfg!(feature = "embed-frontend") {
+            assert_eq!(finding(&report, "dist").severity, Severity::Warning);
+            assert!(finding(&report, "dist")
+                .message
+                .contains("has no index.html"));
+        }
+        std::fs::remove_dir_all(&paths.dist_dir).unwrap();
+        let report = run(&paths);
+        if !cfg!(feature = "embed-frontend") {
+            assert_eq!(
+                finding(&report, "dist").message,
+                format!(
+                    "dist dir '{}' not found, the frontend answers 404; run npm run build or set --dist-dir",
+                    paths.dist_dir
+                )
+            );
+        }
+        // a missing frontend never refuses the startup
+        assert!(report.refusal().is_none());
+
+        let data = dir.join("data");
+        std::fs::remove_dir_all(&data).unwrap();
+        let report = run(&paths);
+        let missing = finding(&report, "data");
+        assert_eq!(missing.severity, Severity::Fatal);
+        assert_eq!(
+            missing.message,
+            format!(
+                "data dir '{}' not found; create it or start with --create-data-dirs",
+                data.display()
+            )
+        );
+        let report = run(&PreflightPaths {
+            create_data_dirs: true,
+            ..paths.clone()
+        });
+        assert_eq!(finding(&report, "data").severity, Severity::Ok);
+        assert!(data.is_dir());
+
+        std::fs::remove_file(dir.join("index.html")).unwrap();
+        let report = run(&paths);
+        assert_eq!(finding(&report, "dev").severity, Severity::Warning);
+        assert!(report.refusal().is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_data_dir_is_fatal() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir();
+        let paths = complete(&dir);
+        let data = dir.join("data");
+        std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o555)).unwrap();
+        // root writes anyway, the probe has nothing to find then
+        if probe(&data).is_err() {
+            let report = run(&paths);
+            let finding = finding(&report, "data");
+            assert_eq!(finding.severity, Severity::Fatal);
+            assert!(
+                finding.message.contains("is not writable"),
+                "{}",
+                finding.message
+            );
+        }
+        std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
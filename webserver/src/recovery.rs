@@ -0,0 +1,121 @@
+use nanoid::nanoid;
+use ring::{digest, hmac, rand::SystemRandom};
+use std::{io, sync::OnceLock, time::Duration};
+
+use crate::userstore::UserId;
+
+// Single use tokens to reset a forgotten password
+// A token reads {user id}.{random id}.{HMAC of both}, the HMAC rejects forged tokens before the store is asked
+// The UserStore only keeps the SHA-256 hash and the expiry of the latest token of a user,
+// the token itself is only known to the Notifier that delivers the reset link
+// The HMAC key is configured separately from the JWT secret, without one every start picks a random key
+
+/// How long a reset link can be used
+pub const RESET_TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+
+const RANDOM_ID_LENGTH: usize = 32;
+
+/// Separates reset tokens from other values signed with the same secret
+const HMAC_CONTEXT: &str = "password-reset";
+
+/// Shorter secrets could be guessed offline from a single reset link
+pub const MIN_KEY_LENGTH: usize = 32;
+
+static INSTALLED_KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Installs the secret of every HMAC signed afterwards, once per process
+pub fn install_key(secret: &str) -> io::Result<()> {
+    if secret.len() < MIN_KEY_LENGTH {
+        return Err(io::Error::other(format!(
+            "recovery key has to be at least {MIN_KEY_LENGTH} bytes long"
+        )));
+    }
+    INSTALLED_KEY
+        .set(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+        .map_err(|_| io::Error::other("recovery key is already installed"))
+}
+
+fn key() -> &'static hmac::Key {
+    INSTALLED_KEY.get_or_init(|| {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("system random number generator failed")
+    })
+}
+
+/// Hex encoded HMAC of the value, the context separates values signed for different purposes
+pub(crate) fn sign(context: &str, value: &str) -> String {
+    hex(hmac::sign(key(), format!("{context}.{value}").as_bytes()).as_ref())
+}
+
+/// Whether the hex encoded tag was returned by sign for the value
+pub(crate) fn verify(context: &str, value: &str, tag: &str) -> bool {
+    if tag.len() != 64 || !tag.is_ascii() {
+        return false;
+    }
+    let Ok(tag) = (0..tag.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&tag[index..index + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+    else {
+        return false;
+    };
+
+    // constant time comparison
+    hmac::verify(key(), format!("{context}.{value}").as_bytes(), &tag).is_ok()
+}
+
+fn signed_part(user_id: &str, random_id: &str) -> String {
+    format!("{user_id}.{random_id}")
+}
+
+/// New reset token for the user
+pub fn generate_token(user_id: &str) -> String {
+    let random_id = nanoid!(RANDOM_ID_LENGTH);
+    let tag = sign(HMAC_CONTEXT, &signed_part(user_id, &random_id));
+    format!("{user_id}.{random_id}.{tag}")
+}
+
+/// User the token was issued for, None if the token was not issued by this server
+pub fn verify_token(token: &str) -> Option<UserId> {
+    let mut parts = token.splitn(3, '.');
+    let (user_id, random_id, tag) = (parts.next()?, parts.next()?, parts.next()?);
+    if !verify(HMAC_CONTEXT, &signed_part(user_id, random_id), tag) {
+        return None;
+    }
+    Some(user_id.to_string())
+}
+
+/// Hex encoded SHA-256 of the token, persisted instead of it
+/// Unlike sign it uses no key, persisted hashes survive a change of the recovery key
+pub fn hash_token(token: &str) -> String {
+    hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forged_tokens_are_rejected() {
+        let token = generate_token("0123abcd");
+        assert_eq!(verify_token(&token).as_deref(), Some("0123abcd"));
+        assert_ne!(hash_token(&token), hash_token(&generate_token("0123abcd")));
+
+        // the user id is signed, a token can't be moved to another user
+        let moved = token.replacen("0123abcd", "0123abce", 1);
+        assert_eq!(verify_token(&moved), None);
+        assert_eq!(verify_token("0123abcd.random.abc"), None);
+        assert_eq!(verify_token(""), None);
+        let multibyte = format!("0123abcd.random.{}", "ä".repeat(32));
+        assert_eq!(verify_token(&multibyte), None);
+    }
+
+    #[test]
+    fn test_short_keys_are_rejected() {
+        assert!(install_key("too-short").is_err());
+    }
+}
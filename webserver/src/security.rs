@@ -0,0 +1,283 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
+    http::header::{self, HeaderValue},
+    Error, HttpMessage, HttpRequest,
+};
+use futures_util::{future::LocalBoxFuture, FutureExt, TryFutureExt};
+use nanoid::nanoid;
+use std::{
+    fmt,
+    future::{ready, Ready},
+    str::FromStr,
+};
+
+// Actix Middleware
+// Sets security related headers on every response
+// Generates a nonce for every request, templates can use it to mark their script tags as trusted
+// The nonce is stored in the request extensions and can be obtained using csp_nonce
+// The websocket endpoint in the policy comes from the configured PublicOrigin, never from the Host header
+
+pub const CSP_NONCE_LENGTH: usize = 22;
+
+/// Scheme, host and port the server is reached at by browsers, e.g. https://canvas.example.org
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicOrigin {
+    secure: bool,
+    /// host with the optional port, only made of characters that are valid in a header value
+    authority: String,
+}
+
+impl PublicOrigin {
+    /// Origin of the websocket endpoint, ws:// or wss:// matching the scheme
+    pub fn websocket(&self) -> String {
+        let scheme = if self.secure { "wss" } else { "ws" };
+        format!("{scheme}://{}", self.authority)
+    }
+}
+
+impl Default for PublicOrigin {
+    fn default() -> Self {
+        Self {
+            secure: false,
+            authority: "localhost:1234".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for PublicOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.secure { "https" } else { "http" };
+        write!(f, "{scheme}://{}", self.authority)
+    }
+}
+
+impl FromStr for PublicOrigin {
+    type Err = String;
+
+    fn from_str(origin: &str) -> Result<Self, Self::Err> {
+        let (secure, authority) = if let Some(authority) = origin.strip_prefix("https://") {
+            (true, authority)
+        } else if let Some(authority) = origin.strip_prefix("http://") {
+            (false, authority)
+        } else {
+            return Err(format!("{origin} has to start with http:// or https://"));
+        };
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+        let valid = !authority.is_empty()
+            && authority
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid {
+            return Err(format!(
+                "{origin} is not a plain scheme://host[:port] origin"
+            ));
+        }
+        Ok(Self {
+            secure,
+            authority: authority.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Per request nonce used in the Content-Security-Policy
+#[derive(Clone, Debug)]
+pub struct CspNonce(pub String);
+
+/// Returns the nonce of the current request
+/// Empty if the request did not pass through the SecurityHeadersService
+pub fn csp_nonce(request: &HttpRequest) -> String {
+    request
+        .extensions()
+        .get::<CspNonce>()
+        .map(|nonce| nonce.0.clone())
+        .unwrap_or_default()
+}
+
+// vite injects inline scripts and uses its own websocket for hot module reloading
+// a nonce would disable 'unsafe-inline', so the dev policy does not use one
+#[cfg(feature = "dev")]
+fn content_security_policy(_nonce: &str, websocket: &str) -> String {
+    format!(
+        "default-src 'self'; script-src 'self' 'unsafe-inline' 'unsafe-eval'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; connect-src 'self' ws: wss: {websocket}; frame-ancestors 'none'; base-uri 'self'; form-action 'self'"
+    )
+}
+
+#[cfg(not(feature = "dev"))]
+fn content_security_policy(nonce: &str, websocket: &str) -> String {
+    format!(
+        "default-src 'self'; script-src 'self' 'nonce-{nonce}'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; connect-src 'self' {websocket}; frame-ancestors 'none'; base-uri 'self'; form-action 'self'"
+    )
+}
+
+#[derive(Clone)]
+pub struct SecurityHeadersService {
+    websocket: String,
+}
+
+impl SecurityHeadersService {
+    pub fn new(origin: &PublicOrigin) -> Self {
+        Self {
+            websocket: origin.websocket(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeadersService
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware {
+            service,
+            websocket: self.websocket.clone(),
+        }))
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    websocket: String,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // default nanoid alphabet is base64url, which is a valid nonce charset
+        let nonce = nanoid!(CSP_NONCE_LENGTH);
+        // both parts are restricted to header safe characters, a response without policy is never sent
+        let Ok(policy) = HeaderValue::from_str(&content_security_policy(&nonce, &self.websocket))
+        else {
+            return ready(Err(ErrorInternalServerError(
+                "Invalid Content-Security-Policy",
+            )))
+            .boxed_local();
+        };
+        req.extensions_mut().insert(CspNonce(nonce));
+
+        self.service
+            .call(req)
+            .map_ok(move |mut res| {
+                let headers = res.headers_mut();
+                headers.insert(header::CONTENT_SECURITY_POLICY, policy);
+                headers.insert(
+                    header::X_CONTENT_TYPE_OPTIONS,
+                    HeaderValue::from_static("nosniff"),
+                );
+                headers.insert(
+                    header::REFERRER_POLICY,
+                    HeaderValue::from_static("same-origin"),
+                );
+                headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+                res
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test::TestRequest, web, App, Responder};
+    use handlebars::Handlebars;
+    use serde_json::json;
+
+    async fn render_handler(
+        request: HttpRequest,
+        handlebars: web::Data<Handlebars<'_>>,
+    ) -> impl Responder {
+        let template_data = json!({ "nonce": csp_nonce(&request) });
+        web::Html::new(handlebars.render("page", &template_data).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_security_headers_and_nonce() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("page", r#"<script nonce="{{nonce}}"></script>"#)
+            .unwrap();
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(handlebars))
+                .route("/", web::get().to(render_handler))
+                .wrap(SecurityHeadersService::new(
+                    &"https://canvas.example.org".parse().unwrap(),
+                )),
+        )
+        .await;
+
+        let res = actix_web::test::call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header((header::HOST, "attacker.example"))
+                .to_request(),
+        )
+        .await;
+        let headers = res.headers().clone();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(headers.get(header::REFERRER_POLICY).unwrap(), "same-origin");
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+
+        let policy = headers
+            .get(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(policy.contains("frame-ancestors 'none'"));
+        assert!(policy.contains("wss://canvas.example.org"));
+        assert!(!policy.contains("attacker.example"));
+
+        let body = actix_web::test::read_body(res).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let nonce = body
+            .strip_prefix(r#"<script nonce=""#)
+            .and_then(|rest| rest.strip_suffix(r#""></script>"#))
+            .unwrap();
+        assert_eq!(nonce.len(), CSP_NONCE_LENGTH);
+
+        #[cfg(not(feature = "dev"))]
+        assert!(policy.contains(&format!("'nonce-{nonce}'")));
+    }
+
+    #[test]
+    fn test_public_origin_is_a_plain_origin() {
+        let origin: PublicOrigin = "https://Canvas.example.org:8443/".parse().unwrap();
+        assert_eq!(origin.to_string(), "https://canvas.example.org:8443");
+        assert_eq!(origin.websocket(), "wss://canvas.example.org:8443");
+        assert_eq!(PublicOrigin::default().websocket(), "ws://localhost:1234");
+
+        for invalid in [
+            "canvas.example.org",
+            "ftp://canvas.example.org",
+            "https://",
+            "https://canvas.example.org/app",
+            "https://canvas.example.org; script-src *",
+        ] {
+            assert!(invalid.parse::<PublicOrigin>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::{
+    canvas::{
+        binding::{self, LogBinding},
+        events::{CanvasEvents, Shape},
+        server::{canvas_log_path, CanvasSocketServer},
+        store::{
+            AccessLevel, AddUserToCanvasMessage, CanvasId, CreateCanvas, CreateCanvasMessage,
+            GetCanvasMembershipMessage, GetOwnedCanvasesMessage,
+        },
+        validation::{self, ShapeLimits},
+    },
+    password,
+    persistence::EventLogPersistenceJson,
+    userstore::{GetUserMessage, RegisterUser, RegisterUserMessage, UserId},
+    AppState,
+};
+use serde::Deserialize;
+use std::{collections::HashSet, fmt};
+
+// Deterministic fixtures for demos and tests
+// A seed file describes users, canvases with their members and initial shapes
+// Seeding drives the regular store handlers, seeded state replays like organically created state
+// Entries are matched by username and by canvas name and owner, seeding a second time changes nothing
+// Seed files are TOML, see seed.example.toml
+
+/// origin of seeded shape events, in place of the session id of a client
+pub const SEED_ORIGIN: &str = "seed";
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedFile {
+    #[serde(default)]
+    pub users: Vec<SeedUser>,
+    #[serde(default)]
+    pub canvases: Vec<SeedCanvas>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedUser {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedCanvas {
+    pub name: String,
+    /// username of the owner
+    pub owner: String,
+    #[serde(default)]
+    pub members: Vec<SeedMember>,
+    /// shapes in the format of the Canvas Application, ids have to be unique within the canvas
+    #[serde(default)]
+    pub shapes: Vec<Shape>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedMember {
+    pub username: String,
+    pub access_level: AccessLevel,
+}
+
+impl SeedFile {
+    pub fn load(file_path: &str) -> Result<Self, std::io::Error> {
+        let content = std::fs::read_to_string(file_path)?;
+        toml::from_str(&content).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid seed file {file_path}: {e}"),
+            )
+        })
+    }
+}
+
+/// Entries created by a seeding run, entries that already existed are only counted
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SeedReport {
+    pub users_created: usize,
+    pub users_existing: usize,
+    pub canvases_created: usize,
+    pub canvases_existing: usize,
+    pub members_added: usize,
+    pub shapes_added: usize,
+}
+
+impl SeedReport {
+    /// Nothing was created, the seed was applied before
+    pub fn is_unchanged(&self) -> bool {
+        self.users_created == 0
+            && self.canvases_created == 0
+            && self.members_added == 0
+            && self.shapes_added == 0
+    }
+}
+
+impl fmt::Display for SeedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Users: {} created, {} existing",
+            self.users_created, self.users_existing
+        )?;
+        writeln!(
+            f,
+            "Canvases: {} created, {} existing",
+            self.canvases_created, self.canvases_existing
+        )?;
+        writeln!(f, "Members added: {}", self.members_added)?;
+        writeln!(f, "Shapes added: {}", self.shapes_added)
+    }
+}
+
+/// Seed entry that could not be applied, entries before it were applied
+#[derive(Debug)]
+pub struct SeedError {
+    pub entry: String,
+    pub reason: String,
+}
+
+impl SeedError {
+    fn new(entry: impl Into<String>, reason: impl fmt::Display) -> Self {
+        Self {
+            entry: entry.into(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Seeding failed at {}: {}", self.entry, self.reason)
+    }
+}
+
+impl std::error::Error for SeedError {}
+
+impl From<SeedError> for std::io::Error {
+    fn from(error: SeedError) -> Self {
+        std::io::Error::other(error.to_string())
+    }
+}
+
+/// Applies the seed to the stores of a bootstrapped AppState
+/// Has to run before the server accepts connections, shapes are appended to the canvas eventlogs directly
+pub async fn seed(
+    state: &AppState,
+    seed: SeedFile,
+    shape_limits: &ShapeLimits,
+) -> Result<SeedReport, SeedError> {
+    let mut report = SeedReport::default();
+
+    for user in seed.users {
+        seed_user(state, user, &mut report).await?;
+    }
+
+    for canvas in seed.canvases {
+        seed_canvas(state, canvas, shape_limits, &mut report).await?;
+    }
+
+    Ok(report)
+}
+
+async fn find_user_id(state: &AppState, username: &str) -> Result<Option<UserId>, SeedError> {
+    let user = state
+        .get_user_recipient
+        .send(GetUserMessage {
+            username_email: Some(username.to_string()),
+            user_id: None,
+        })
+        .await
+        .map_err(|e| SeedError::new(format!("user \"{username}\""), e))?;
+    Ok(user.map(|user| user.id))
+}
+
+async fn require_user_id(
+    state: &AppState,
+    username: &str,
+    entry: &str,
+) -> Result<UserId, SeedError> {
+    find_user_id(state, username)
+        .await?
+        .ok_or_else(|| SeedError::new(entry, format!("unknown user \"{username}\"")))
+}
+
+async fn seed_user(
+    state: &AppState,
+    user: SeedUser,
+    report: &mut SeedReport,
+) -> Result<(), SeedError> {
+    let entry = format!("user \"{}\"", user.username);
+    if find_user_id(state, &user.username).await?.is_some() {
+        report.users_existing += 1;
+        return Ok(());
+    }
+
+    let argon = password::argon2_with_params(state.argon_params.clone());
+    let password_hash =
+        actix_web::web::block(move || password::hash_password(&argon, user.password.as_bytes()))
+            .await
+            .map_err(|e| SeedError::new(&entry, e))?
+            .map_err(|e| SeedError::new(&entry, format!("failed to hash password: {e}")))?;
+
+    state
+        .register_user_recipient
+        .send(RegisterUserMessage {
+            user: RegisterUser {
+                email: user.email,
+                username: user.username,
+                password_hash,
+            },
+        })
+        .await
+        .map_err(|e| SeedError::new(&entry, e))?
+        .map_err(|e| SeedError::new(&entry, e))?;
+
+    report.users_created += 1;
+    Ok(())
+}
+
+async fn seed_canvas(
+    state: &AppState,
+    canvas: SeedCanvas,
+    shape_limits: &ShapeLimits,
+    report: &mut SeedReport,
+) -> Result<(), SeedError> {
+    let entry = format!("canvas \"{}\" of \"{}\"", canvas.name, canvas.owner);
+    let owner_id = require_user_id(state, &canvas.owner, &entry).await?;
+
+    let owned = state
+        .get_owned_canvases_recipient
+        .send(GetOwnedCanvasesMessage {
+            user_id: owner_id.clone(),
+        })
+        .await
+        .map_err(|e| SeedError::new(&entry, e))?;
+
+    let canvas_id = match owned
+        .into_iter()
+        .find(|summary| summary.deleted_at.is_none() && summary.name == canvas.name)
+    {
+        Some(summary) => {
+            report.canvases_existing += 1;
+            summary.id
+        }
+        None => {
+            let created = state
+                .create_canvas_recipient
+                .send(CreateCanvasMessage {
+                    canvas: CreateCanvas {
+                        name: canvas.name.clone(),
+                        owner_id: owner_id.clone(),
+                    },
+                    auto_rename: false,
+                })
+                .await
+                .map_err(|e| SeedError::new(&entry, e))?
+                .map_err(|e| SeedError::new(&entry, e))?;
+            report.canvases_created += 1;
+            created.id
+        }
+    };
+
+    for member in canvas.members {
+        seed_member(state, &canvas_id, &owner_id, &entry, member, report).await?;
+    }
+
+    seed_shapes(
+        state,
+        &canvas_id,
+        &owner_id,
+        &entry,
+        canvas.shapes,
+        shape_limits,
+        report,
+    )
+}
+
+/// Members are only added if missing, access levels changed since the last seeding are kept
+async fn seed_member(
+    state: &AppState,
+    canvas_id: &CanvasId,
+    owner_id: &UserId,
+    canvas_entry: &str,
+    member: SeedMember,
+    report: &mut SeedReport,
+) -> Result<(), SeedError> {
+    let entry = format!("member \"{}\" of {canvas_entry}", member.username);
+    let user_id = require_user_id(state, &member.username, &entry).await?;
+
+    let membership = state
+        .get_canvas_membership_recipient
+        .send(GetCanvasMembershipMessage {
+            canvas_id: canvas_id.clone(),
+        })
+        .await
+        .map_err(|e| SeedError::new(&entry, e))?
+        .ok_or_else(|| SeedError::new(&entry, "canvas vanished while seeding"))?;
+    if membership
+        .members
+        .iter()
+        .any(|member| member.user_id == user_id)
+    {
+        return Ok(());
+    }
+
+    state
+        .add_user_to_canvas_recipient
+        .send(AddUserToCanvasMessage {
+            initiator_user_id: owner_id.clone(),
+            canvas_id: canvas_id.clone(),
+            target_user_id: user_id,
+            access_level: member.access_level,
+            expires_at: None,
+        })
+        .await
+        .map_err(|e| SeedError::new(&entry, e))?
+        .map_err(|e| SeedError::new(&entry, e))?;
+
+    report.members_added += 1;
+    Ok(())
+}
+
+/// Appends a ShapeAdded event for every shape never added to the canvas, created by the owner
+/// Shapes removed after seeding stay removed, their ShapeAdded event is still in the log
+fn seed_shapes(
+    state: &AppState,
+    canvas_id: &CanvasId,
+    owner_id: &UserId,
+    canvas_entry: &str,
+    shapes: Vec<Shape>,
+    shape_limits: &ShapeLimits,
+    report: &mut SeedReport,
+) -> Result<(), SeedError> {
+    if shapes.is_empty() {
+        return Ok(());
+    }
+
+    let log_path = canvas_log_path(canvas_id);
+    let open_log = || {
+        EventLogPersistenceJson::new(&log_path)
+            .and_then(|log| log.into_standalone::<CanvasEvents>())
+            .map_err(|e| SeedError::new(canvas_entry, format!("failed to open eventlog: {e}")))
+    };
+    let (mut event_log, mut persistence) = open_log()?;
+
+    // same binding rules as loading the canvas in the canvas server
+    match binding::check(canvas_id, &event_log).map_err(|e| SeedError::new(canvas_entry, e))? {
+        LogBinding::Bound => (),
+        LogBinding::Empty => {
+            persistence
+                .save_event(&binding::header(canvas_id, state.clock.now_secs()))
+                .map_err(|e| SeedError::new(canvas_entry, e))?;
+        }
+        LogBinding::Legacy => {
+            binding::migrate_legacy_log(&log_path, canvas_id, event_log, state.clock.now_secs())
+                .map_err(|e| SeedError::new(canvas_entry, e))?;
+            (event_log, persistence) = open_log()?;
+        }
+    }
+
+    let mut added: HashSet<String> = event_log
+        .iter()
+        .filter_map(|event| match event {
+            CanvasEvents::ShapeAdded { shape, .. } => Some(shape.get_id().to_string()),
+            _ => None,
+        })
+        .collect();
+
+    for shape in shapes {
+        let entry = format!("shape \"{}\" of {canvas_entry}", shape.get_id());
+        if shape.is_temporary() {
+            return Err(SeedError::new(
+                entry,
+                "temporary shapes are never persisted",
+            ));
+        }
+        if !added.insert(shape.get_id().to_string()) {
+            continue;
+        }
+
+        let mut event = CanvasEvents::ShapeAdded {
+            origin: SEED_ORIGIN.to_string(),
+            timestamp: state.clock.now_ms(),
+            shape,
+            userId: Some(owner_id.clone()),
+        };
+        validation::validate_event(&event, shape_limits)
+            .map_err(|rejection| SeedError::new(&entry, format!("{rejection:?}")))?;
+        CanvasSocketServer::simplify_paths(&mut event, shape_limits.path_simplify_epsilon);
+
+        persistence
+            .save_event(&event)
+            .map_err(|e| SeedError::new(&entry, e))?;
+        report.shapes_added += 1;
+    }
+
+    Ok(())
+}
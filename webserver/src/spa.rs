@@ -8,11 +8,14 @@ use futures_util::{future::LocalBoxFuture, FutureExt, TryFutureExt};
 use regex::Regex;
 use std::future::{ready, Ready};
 
-/// Actix Middleware
-/// Handles SPA logic
-/// Every request that is not for a websocket or asset is redirected to / to serve the SPA
-/// Once the SPA is loaded, the SPA will add a header to the request to indicate that it is a SPA request
-/// This is not a perfect solution, but it works for this demo application
+// Actix Middleware
+// Handles SPA logic
+// Every request that is not for a websocket, asset or api is redirected to / to serve the SPA
+// Once the SPA is loaded, the SPA will add a header to the request to indicate that it is a SPA request
+// This is not a perfect solution, but it works for this demo application
+
+/// Marks a response the SPA renders as page although its status is not 200, e.g. the no access page of a canvas
+pub const RENDER_HEADER: &str = "X-SPA-Render";
 
 pub struct SPAService;
 
@@ -38,14 +41,29 @@ where
             .as_str(),
         )
         .expect("Failed to generate canvas Websocket Regex");
+        let standalone = Regex::new(
+            format!(
+                "^/canvas/[{}]{{{}}}/print/?$",
+                store::CANVAS_ID_ALPHABET_STR,
+                store::CANVAS_ID_LENGTH
+            )
+            .as_str(),
+        )
+        .expect("Failed to generate standalone page Regex");
 
-        ready(Ok(SPAMiddleware { service, regex }))
+        ready(Ok(SPAMiddleware {
+            service,
+            regex,
+            standalone,
+        }))
     }
 }
 
 pub struct SPAMiddleware<S> {
     service: S,
     regex: Regex,
+    /// pages opened without the SPA, e.g. the print page of a canvas
+    standalone: Regex,
 }
 
 impl<S, B> Service<ServiceRequest> for SPAMiddleware<S>
@@ -61,7 +79,7 @@ where
     forward_ready!(service);
 
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
-        if req.path().starts_with("/assets/") {
+        if req.path().starts_with("/assets/") || req.path().starts_with("/api/") {
             // println!("Request {:?} for assets, forwarding", req.uri());
             return self
                 .service
@@ -70,7 +88,7 @@ where
                 .boxed_local();
         }
 
-        if self.regex.is_match(req.path()) {
+        if self.regex.is_match(req.path()) || self.standalone.is_match(req.path()) {
             // println!("Request {:?} for websocket, forwarding", req.uri());
             return self
                 .service
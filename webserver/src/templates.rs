@@ -1,37 +1,449 @@
 use actix_files::NamedFile;
-use actix_web::{http::header, HttpRequest, HttpResponse, HttpResponseBuilder, Result};
+use actix_web::{
+    error::InternalError,
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Result,
+};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    RenderErrorReason, Renderable, StringOutput, Template, TemplateError,
+};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-/// Module to handle rendering
+use crate::{
+    authentication,
+    canvas::export::SvgDocument,
+    clock,
+    messages::{self, MessageKey},
+    recovery,
+};
 
+// Module to handle rendering
+
+/// Vite build served as the frontend, see ServerConfig::dist_dir
+pub const DIST_DIR: &str = "../dist";
+// in prod mode the dist folder is served, vite bundles all modules in /dist/ html requests /dist/ "compiled" js
+const INDEX_FILE: &str = "../dist/index.html";
+const TEMPLATES_DIR: &str = "../dist/.templates";
 // in dev mode vite handles module loading on the fly, requests will include /src/ files
 #[cfg(feature = "dev")]
-pub static INDEX_FILE: &str = "../index.html";
-// in prod mode the dist folder is served, vite bundles all modules in /dist/ html requests /dist/ "compiled" js
-#[cfg(not(feature = "dev"))]
-pub static INDEX_FILE: &str = "../dist/index.html";
+const DEV_INDEX_FILE: &str = "../index.html";
+#[cfg(feature = "dev")]
+const DEV_TEMPLATES_DIR: &str = "../.templates";
+
+/// Name of the SPA entry, resolved next to the templates dir instead of inside it
+pub const INDEX: &str = "index.html";
+
+/// Query parameter of a dev build asking for the frontend sources instead of the vite build
+#[derive(Deserialize)]
+struct DevQuery {
+    dev: Option<String>,
+}
 
+/// True for dev=1, other parameters of the query are ignored
+fn parse_dev_flag(query: &str) -> bool {
+    web::Query::<DevQuery>::from_query(query).is_ok_and(|query| query.dev.as_deref() == Some("1"))
+}
+
+/// Whether the request asked for the frontend sources, always false in release builds
+pub fn dev_sources_requested(request: &HttpRequest) -> bool {
+    cfg!(feature = "dev") && parse_dev_flag(request.query_string())
+}
+
+/// Index file and templates dir to serve from
 #[cfg(feature = "dev")]
-pub static TEMPLATES_DIR: &str = "../.templates/";
+fn frontend_paths(dev_sources: bool) -> (&'static str, &'static str) {
+    if dev_sources {
+        (DEV_INDEX_FILE, DEV_TEMPLATES_DIR)
+    } else {
+        (INDEX_FILE, TEMPLATES_DIR)
+    }
+}
+
+/// Index file and templates dir to serve from, release builds never read the sources
 #[cfg(not(feature = "dev"))]
-pub static TEMPLATES_DIR: &str = "../dist/.templates/";
+fn frontend_paths(_: bool) -> (&'static str, &'static str) {
+    (INDEX_FILE, TEMPLATES_DIR)
+}
+
+/// Vite entry of the frontend sources, None in release builds which never serve them
+pub fn dev_index_file() -> Option<&'static str> {
+    cfg!(feature = "dev").then(|| frontend_paths(true).0)
+}
+
+/// Templates dir registered at startup, the sources in dev builds so their changes are reloaded
+pub fn default_templates_dir() -> &'static str {
+    frontend_paths(cfg!(feature = "dev")).1
+}
+
+///
+/// Path of a frontend file for the request, every page and template is resolved here
+/// Dev builds serve the sources for dev=1 and the vite build otherwise, release builds always serve the vite build
+///
+pub fn resolve_template_path(name: &str, request: &HttpRequest) -> PathBuf {
+    let (index_file, templates_dir) = frontend_paths(dev_sources_requested(request));
+    if name == INDEX {
+        PathBuf::from(index_file)
+    } else {
+        Path::new(templates_dir).join(name)
+    }
+}
+
+/// Query that keeps the dev flag on links, form actions and redirects, empty unless the request asked for the sources
+pub fn dev_query(request: &HttpRequest) -> &'static str {
+    if dev_sources_requested(request) {
+        "?dev=1"
+    } else {
+        ""
+    }
+}
+
+/// Templates compiled into the binary, used if the templates dir lacks them
+/// Server rendered pages work without a frontend build that includes them
+static EMBEDDED_TEMPLATES: &[(&str, &str)] = &[
+    ("login", include_str!("../../.templates/login.html")),
+    ("register", include_str!("../../.templates/register.html")),
+    ("members", include_str!("../../.templates/members.html")),
+    (
+        "reset-password",
+        include_str!("../../.templates/reset-password.html"),
+    ),
+    (
+        "canvas-print",
+        include_str!("../../.templates/canvas-print.html"),
+    ),
+];
+
+/// Registers the embedded templates missing from the templates dir
+/// With the embed-frontend feature the templates of the embedded vite build come first
+pub fn register_embedded_templates(handlebars: &mut Handlebars) -> Result<(), TemplateError> {
+    #[cfg(feature = "embed-frontend")]
+    for (name, template) in crate::assets::templates() {
+        if !handlebars.has_template(&name) {
+            handlebars.register_template_string(&name, String::from_utf8_lossy(&template))?;
+        }
+    }
+    for (name, template) in EMBEDDED_TEMPLATES {
+        if !handlebars.has_template(name) {
+            handlebars.register_template_string(name, template)?;
+        }
+    }
+    Ok(())
+}
+
+/// JSON of the value that is safe inside a script element
+/// <, >, & and the line separators are written as \u escapes, JSON.parse returns the original strings
+pub fn script_json(value: &serde_json::Value) -> String {
+    let json = serde_json::to_string(value).unwrap_or_else(|_| "null".to_string());
+    let mut escaped = String::with_capacity(json.len());
+    for character in json.chars() {
+        match character {
+            '<' => escaped.push_str("\\u003c"),
+            '>' => escaped.push_str("\\u003e"),
+            '&' => escaped.push_str("\\u0026"),
+            '\u{2028}' => escaped.push_str("\\u2028"),
+            '\u{2029}' => escaped.push_str("\\u2029"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// {{json value}} writes the script_json of the value, for the content of script elements only
+/// The output is not HTML escaped, user data outside of script elements goes through {{ }}
+fn json_helper(
+    helper: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let json = helper
+        .param(0)
+        .map_or_else(|| "null".to_string(), |param| script_json(param.value()));
+    out.write(&json)?;
+    Ok(())
+}
+
+/// Registers the helpers every template may use
+pub fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("json", Box::new(json_helper));
+}
+
+/// {{inline-svg}} writes the SVG document a page is rendered with as markup, see render_timed_with_svg
+/// The document is no template data, values of the page can't end up in it
+struct InlineSvg(SvgDocument);
+
+impl HelperDef for InlineSvg {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        out.write(self.0.as_str())?;
+        Ok(())
+    }
+}
+
+/// Renders slower than this are logged as a warning
+pub const SLOW_RENDER_THRESHOLD: Duration = Duration::from_millis(100);
+/// Renders taking longer are abandoned, the request is answered with FALLBACK_PAGE
+pub const RENDER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Upper bounds of the render time buckets in milliseconds, the last bucket is unbounded
+pub const RENDER_BUCKETS_MS: [u64; 6] = [1, 5, 25, 100, 500, 2000];
+/// Longest list handed to a template, the view shows how many entries were left out
+pub const TEMPLATE_LIST_LIMIT: usize = 200;
+
+/// Answer of a timed out render, does not depend on the templates dir or any helper
+static FALLBACK_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="de">
+<head><meta charset="utf-8"><title>Drawing Canvas</title></head>
+<body>
+<h1>Die Seite konnte nicht angezeigt werden</h1>
+<p>Bitte versuche es gleich erneut. Anfrage: <code>{request_id}</code></p>
+</body>
+</html>
+"#;
+
+/// Render times of a single template
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderHistogram {
+    pub count: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    /// renders per bucket of RENDER_BUCKETS_MS, the last entry counts the slower ones
+    pub buckets: [u64; RENDER_BUCKETS_MS.len() + 1],
+    /// abandoned renders, not counted in the buckets
+    pub timeouts: u64,
+}
+
+impl RenderHistogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        self.count += 1;
+        self.total_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+        let bucket = RENDER_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(RENDER_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+/// Times the renders of every template and enforces the render timeout, listed by /admin/api/templates
+pub struct RenderMonitor {
+    slow_threshold: Duration,
+    timeout: Duration,
+    histograms: Mutex<BTreeMap<&'static str, RenderHistogram>>,
+}
+
+impl Default for RenderMonitor {
+    fn default() -> Self {
+        Self::new(SLOW_RENDER_THRESHOLD, RENDER_TIMEOUT)
+    }
+}
+
+impl RenderMonitor {
+    pub fn new(slow_threshold: Duration, timeout: Duration) -> Self {
+        Self {
+            slow_threshold,
+            timeout,
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(&self, template: &'static str, elapsed: Duration) {
+        if elapsed > self.slow_threshold {
+            println!(
+                "WARNING: rendering {template} took {}ms",
+                elapsed.as_millis()
+            );
+        }
+        self.histograms
+            .lock()
+            .unwrap()
+            .entry(template)
+            .or_default()
+            .record(elapsed);
+    }
+
+    fn record_timeout(&self, template: &'static str) {
+        self.histograms
+            .lock()
+            .unwrap()
+            .entry(template)
+            .or_default()
+            .timeouts += 1;
+    }
+
+    pub fn status(&self) -> BTreeMap<&'static str, RenderHistogram> {
+        self.histograms.lock().unwrap().clone()
+    }
+}
+
+/// Monitor registered in the app data, an unshared default one if none is registered
+pub fn request_render_monitor(request: &HttpRequest) -> Arc<RenderMonitor> {
+    request
+        .app_data::<web::Data<RenderMonitor>>()
+        .map_or_else(Default::default, |monitor| monitor.clone().into_inner())
+}
+
+/// Source of the template for a request asking for the frontend sources, None renders the registered template
+fn dev_template_source(template: &str, request: &HttpRequest) -> Option<String> {
+    if !dev_sources_requested(request) {
+        return None;
+    }
+    std::fs::read_to_string(resolve_template_path(&format!("{template}.html"), request)).ok()
+}
+
+/// Renders the source if given, the registered template otherwise
+/// With an SVG document the template may inline it by {{inline-svg}}, the helper only exists for this render
+fn render_page(
+    handlebars: &Handlebars,
+    template: &str,
+    source: Option<String>,
+    data: &serde_json::Value,
+    svg: Option<SvgDocument>,
+) -> Result<String, RenderError> {
+    let Some(svg) = svg else {
+        return match source {
+            Some(source) => handlebars.render_template(&source, data),
+            None => handlebars.render(template, data),
+        };
+    };
+
+    let compiled;
+    let template = match source {
+        Some(source) => {
+            compiled = Template::compile(&source)?;
+            &compiled
+        }
+        None => handlebars
+            .get_template(template)
+            .ok_or_else(|| RenderErrorReason::TemplateNotFound(template.to_string()))?,
+    };
+    let context = Context::wraps(data)?;
+    let mut render_context = RenderContext::new(template.name.as_ref());
+    render_context.register_local_helper("inline-svg", Box::new(InlineSvg(svg)));
+    let mut output = StringOutput::new();
+    template.render(handlebars, &context, &mut render_context, &mut output)?;
+    Ok(output.into_string()?)
+}
+
+///
+/// Renders the template on a blocking thread, the worker stays free while a template misbehaves
+/// A render exceeding the timeout is answered with the fallback page and a request id to find it in the log,
+/// the blocking thread can't be cancelled and finishes the render in the background
+///
+pub async fn render_timed(
+    request: &HttpRequest,
+    handlebars: &web::Data<Handlebars<'static>>,
+    template: &'static str,
+    data: serde_json::Value,
+) -> Result<String> {
+    render_timed_page(request, handlebars, template, data, None).await
+}
+
+/// Renders the template like render_timed, {{inline-svg}} writes the SVG document as markup
+pub async fn render_timed_with_svg(
+    request: &HttpRequest,
+    handlebars: &web::Data<Handlebars<'static>>,
+    template: &'static str,
+    data: serde_json::Value,
+    svg: SvgDocument,
+) -> Result<String> {
+    render_timed_page(request, handlebars, template, data, Some(svg)).await
+}
+
+async fn render_timed_page(
+    request: &HttpRequest,
+    handlebars: &web::Data<Handlebars<'static>>,
+    template: &'static str,
+    data: serde_json::Value,
+    svg: Option<SvgDocument>,
+) -> Result<String> {
+    let monitor = request_render_monitor(request);
+    let handlebars = handlebars.clone().into_inner();
+    let source = dev_template_source(template, request);
+    let started = Instant::now();
+    let render = web::block(move || render_page(&handlebars, template, source, &data, svg));
+
+    match actix_web::rt::time::timeout(monitor.timeout, render).await {
+        Ok(Ok(Ok(page))) => {
+            monitor.record(template, started.elapsed());
+            Ok(page)
+        }
+        Ok(Ok(Err(e))) => {
+            monitor.record(template, started.elapsed());
+            println!("Failed to render {template}: {e}");
+            Err(messages::internal_error(MessageKey::RenderFailed).into())
+        }
+        Ok(Err(_)) => Err(messages::internal_error(MessageKey::RenderFailed).into()),
+        Err(_) => {
+            monitor.record_timeout(template);
+            let request_id = nanoid!(10);
+            println!(
+                "WARNING: rendering {template} timed out after {}ms, request {request_id}",
+                monitor.timeout.as_millis()
+            );
+            let fallback = HttpResponse::InternalServerError()
+                .content_type(ContentType::html())
+                .body(FALLBACK_PAGE.replace("{request_id}", &request_id));
+            Err(InternalError::from_response(
+                format!("rendering {template} timed out, request {request_id}"),
+                fallback,
+            )
+            .into())
+        }
+    }
+}
 
-pub async fn serve_index(_: &HttpRequest) -> Result<NamedFile> {
-    Ok(NamedFile::open_async(INDEX_FILE).await?)
+/// Keeps the first TEMPLATE_LIST_LIMIT entries, returns how many were left out
+/// Bounds the context of a template, a user with thousands of canvases still gets a fast page
+pub fn truncate_for_template<T>(items: &mut Vec<T>) -> usize {
+    let more = items.len().saturating_sub(TEMPLATE_LIST_LIMIT);
+    items.truncate(TEMPLATE_LIST_LIMIT);
+    more
 }
 
-pub async fn serve_template(template: &str, _: &HttpRequest) -> Result<NamedFile> {
-    Ok(NamedFile::open_async(TEMPLATES_DIR.to_owned() + template).await?)
+pub async fn serve_index(request: &HttpRequest) -> Result<HttpResponse> {
+    #[cfg(feature = "embed-frontend")]
+    if !dev_sources_requested(request) {
+        return Ok(crate::assets::serve(INDEX, request));
+    }
+    Ok(serve_template(INDEX, request).await?.into_response(request))
+}
+
+pub async fn serve_template(template: &str, request: &HttpRequest) -> Result<NamedFile> {
+    Ok(NamedFile::open_async(resolve_template_path(template, request)).await?)
 }
 
 pub fn builder_redirect_to_static(route_name: &str, req: &HttpRequest) -> HttpResponseBuilder {
-    // TODO: also copy the query string and implement some kind of redirect after login logic
+    // TODO: implement some kind of redirect after login logic
     // TODO: add error handling
     let url = req
         .url_for_static(route_name)
         .expect("Failed to generate route url");
 
     HttpResponse::Found()
-        .append_header((header::LOCATION, url.path())) // manual redirect
+        .append_header((
+            header::LOCATION,
+            format!("{}{}", url.path(), dev_query(req)),
+        )) // manual redirect
         .take()
 }
 
@@ -49,7 +461,10 @@ where
         .expect("Failed to generate route url");
 
     HttpResponse::Found()
-        .append_header((header::LOCATION, url.path())) // manual redirect
+        .append_header((
+            header::LOCATION,
+            format!("{}{}", url.path(), dev_query(req)),
+        )) // manual redirect
         .take()
 }
 
@@ -64,3 +479,455 @@ where
 pub fn redirect_to_static(route_name: &str, req: &HttpRequest) -> HttpResponse {
     builder_redirect_to_static(route_name, req).finish()
 }
+
+/// One-shot message for the next rendered page, e.g. the result of a form submitted without the SPA
+/// Stored in a signed cookie that the page rendering it removes again
+pub const FLASH_COOKIE_NAME: &str = "flash";
+
+/// A flash not shown within this time is ignored, it belongs to a page the browser never loaded
+pub const FLASH_TTL: Duration = Duration::from_secs(60);
+
+/// Separates flash cookies from other values signed with the same secret
+const FLASH_HMAC_CONTEXT: &str = "flash";
+
+/// Form fields that are never sent back to the browser
+const SENSITIVE_FIELDS: [&str; 2] = ["password", "token"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashKind {
+    Info,
+    Error,
+}
+
+/// Flash of a page, templates show the message and re-render a failed form with its errors and values
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Flash {
+    pub kind: FlashKind,
+    pub message: String,
+    /// errors shown next to the named field
+    #[serde(default)]
+    pub field_errors: BTreeMap<String, String>,
+    /// submitted values the form is prefilled with, never passwords
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+}
+
+impl Flash {
+    pub fn info(message: impl Into<String>) -> Self {
+        Self {
+            kind: FlashKind::Info,
+            message: message.into(),
+            field_errors: BTreeMap::new(),
+            values: BTreeMap::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            kind: FlashKind::Error,
+            ..Self::info(message)
+        }
+    }
+
+    /// Keeps the submitted value, sensitive fields are dropped
+    pub fn value(mut self, field: &str, value: impl Into<String>) -> Self {
+        if !SENSITIVE_FIELDS
+            .iter()
+            .any(|sensitive| field.contains(sensitive))
+        {
+            self.values.insert(field.to_string(), value.into());
+        }
+        self
+    }
+
+    pub fn field_error(mut self, field: &str, error: impl Into<String>) -> Self {
+        self.field_errors.insert(field.to_string(), error.into());
+        self
+    }
+}
+
+/// Sets the flash shown by the next page rendered for this browser
+/// The cookie reads {HMAC}.{expiry}.{flash as JSON}, the HMAC rejects flashes not set by this server
+pub fn set_flash(request: &HttpRequest, response: &mut HttpResponseBuilder, flash: &Flash) {
+    let Ok(flash) = serde_json::to_string(flash) else {
+        return;
+    };
+    let expires_at = clock::request_clock(request).now_ms() + FLASH_TTL.as_millis() as u64;
+    let signed = format!("{expires_at}.{flash}");
+    let tag = recovery::sign(FLASH_HMAC_CONTEXT, &signed);
+
+    // percent encoded, messages contain spaces and umlauts
+    response.append_header((
+        header::SET_COOKIE,
+        authentication::cookie_factory(request)
+            .flash_cookie(format!("{tag}.{signed}"))
+            .encoded()
+            .to_string(),
+    ));
+}
+
+/// Reads the flash of the request and removes it with the response, it is shown only once
+/// Forged, expired and unreadable flashes are removed as well
+pub fn take_flash(request: &HttpRequest, response: &mut HttpResponseBuilder) -> Option<Flash> {
+    let cookie = request.cookie(FLASH_COOKIE_NAME)?;
+
+    response.cookie(authentication::cookie_factory(request).flash_removal_cookie());
+
+    let (tag, signed) = cookie.value().split_once('.')?;
+    if !recovery::verify(FLASH_HMAC_CONTEXT, signed, tag) {
+        return None;
+    }
+    let (expires_at, flash) = signed.split_once('.')?;
+    if expires_at.parse::<u64>().ok()? <= clock::request_clock(request).now_ms() {
+        return None;
+    }
+    serde_json::from_str(flash).ok()
+}
+
+/// Post/redirect/get for forms submitted without expecting JSON
+/// A rejected submission redirects back to the form page, the error and the submitted values are its flash
+/// JSON requests and internal errors keep the error response, field_of names the field an error belongs to
+pub fn redirect_back_on_error<U, I>(
+    request: &HttpRequest,
+    result: Result<HttpResponse>,
+    route_name: &str,
+    elements: U,
+    flash: Flash,
+    field_of: impl Fn(MessageKey) -> Option<&'static str>,
+) -> Result<HttpResponse>
+where
+    U: IntoIterator<Item = I>,
+    I: AsRef<str>,
+{
+    let error = match result {
+        Ok(response) => return Ok(response),
+        Err(error) => error,
+    };
+    if messages::accepts_json(request)
+        || request.content_type() == "application/json"
+        || error.as_response_error().status_code() == StatusCode::INTERNAL_SERVER_ERROR
+    {
+        return Err(error);
+    }
+    let Some(message) = messages::error_message(&error) else {
+        return Err(error);
+    };
+
+    let localized = message.render(messages::request_locale(request));
+    let flash = match field_of(message.key) {
+        Some(field) => flash.field_error(field, localized.clone()),
+        None => flash,
+    };
+    let mut flash = Flash {
+        kind: FlashKind::Error,
+        message: localized,
+        ..flash
+    };
+    // errors naming their field in the params, e.g. invalid settings
+    if let Some((_, field)) = message.params.iter().find(|(name, _)| *name == "field") {
+        flash
+            .field_errors
+            .insert(field.clone(), flash.message.clone());
+    }
+
+    let mut response = builder_redirect(route_name, request, elements);
+    set_flash(request, &mut response, &flash);
+    Ok(response.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use actix_web::{body::MessageBody, cookie::Cookie};
+    use serde_json::json;
+
+    fn slow_helper(
+        _: &Helper,
+        _: &Handlebars,
+        _: &Context,
+        _: &mut RenderContext,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        std::thread::sleep(Duration::from_millis(300));
+        Ok(())
+    }
+
+    fn monitored_request(monitor: &web::Data<RenderMonitor>) -> HttpRequest {
+        actix_web::test::TestRequest::default()
+            .app_data(monitor.clone())
+            .to_http_request()
+    }
+
+    fn test_handlebars() -> web::Data<Handlebars<'static>> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_helper("slow", Box::new(slow_helper));
+        handlebars
+            .register_template_string("slow", "{{slow}}")
+            .unwrap();
+        handlebars
+            .register_template_string("fast", "Hello {{name}}")
+            .unwrap();
+        web::Data::new(handlebars)
+    }
+
+    #[actix_web::test]
+    async fn test_slow_render_falls_back_after_timeout() {
+        let handlebars = test_handlebars();
+        let monitor = web::Data::new(RenderMonitor::new(
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+        ));
+        let request = monitored_request(&monitor);
+
+        let started = Instant::now();
+        let error = render_timed(&request, &handlebars, "slow", json!({}))
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.into_body().try_into_bytes().unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Anfrage: <code>"));
+        assert!(!body.contains("{request_id}"));
+
+        let status = monitor.status();
+        assert_eq!(status["slow"].timeouts, 1);
+        assert_eq!(status["slow"].count, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_render_times_are_recorded_per_template() {
+        let handlebars = test_handlebars();
+        let monitor = web::Data::new(RenderMonitor::default());
+        let request = monitored_request(&monitor);
+
+        for _ in 0..3 {
+            let page = render_timed(&request, &handlebars, "fast", json!({ "name": "Ada" }))
+                .await
+                .unwrap();
+            assert_eq!(page, "Hello Ada");
+        }
+        let error = render_timed(&request, &handlebars, "missing", json!({}))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let status = monitor.status();
+        assert_eq!(status["fast"].count, 3);
+        assert_eq!(status["fast"].buckets.iter().sum::<u64>(), 3);
+        assert_eq!(status["fast"].timeouts, 0);
+        assert_eq!(status["missing"].count, 1);
+        assert!(!status.contains_key("slow"));
+    }
+
+    #[test]
+    fn test_script_json_cannot_close_the_script_element() {
+        let name = "</script><!-- & \u{2028}\u{2029}";
+        let json = script_json(&json!({ "name": name }));
+        assert_eq!(
+            json,
+            r#"{"name":"\u003c/script\u003e\u003c!-- \u0026 \u2028\u2029"}"#
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["name"], name);
+
+        let mut handlebars = Handlebars::new();
+        register_helpers(&mut handlebars);
+        let page = handlebars
+            .render_template(
+                "<script>{{json data}}</script>{{data.name}}",
+                &json!({ "data": { "name": "<b>" } }),
+            )
+            .unwrap();
+        assert_eq!(
+            page,
+            r#"<script>{"name":"\u003cb\u003e"}</script>&lt;b&gt;"#
+        );
+    }
+
+    #[test]
+    fn test_only_the_svg_document_is_inlined() {
+        let mut handlebars = Handlebars::new();
+        register_helpers(&mut handlebars);
+        handlebars
+            .register_template_string("page", "{{inline-svg}}{{svg}}")
+            .unwrap();
+        let (svg, _) =
+            crate::canvas::export::render_svg_capped(&Default::default(), None, false, 1);
+        let expected = svg.as_str().to_string();
+
+        // a value looking like an exported document is escaped like any other
+        let data = json!({ "svg": expected });
+        let page = render_page(&handlebars, "page", None, &data, Some(svg)).unwrap();
+        assert_eq!(
+            page,
+            format!("{expected}{}", handlebars::html_escape(&expected))
+        );
+        // without a document the page has no helper to inline one
+        let page = render_page(&handlebars, "page", None, &json!({}), None).unwrap();
+        assert_eq!(page, "");
+    }
+
+    #[test]
+    fn test_templates_never_embed_unescaped_values() {
+        let sources_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join(".templates");
+        let sources = std::fs::read_dir(sources_dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap());
+        // user data is either escaped by {{ }} or written by the json helper
+        for source in sources.chain(EMBEDDED_TEMPLATES.iter().map(|(_, t)| t.to_string())) {
+            assert!(!source.contains("{{{"), "{source}");
+            assert!(!source.contains("{{&"), "{source}");
+        }
+    }
+
+    fn flash_request(clock: &Arc<ManualClock>, cookie: Option<Cookie<'static>>) -> HttpRequest {
+        let clock: web::Data<dyn Clock> = web::Data::from(clock.clone() as Arc<dyn Clock>);
+        let request = actix_web::test::TestRequest::default().app_data(clock);
+        match cookie {
+            Some(cookie) => request.cookie(cookie),
+            None => request,
+        }
+        .to_http_request()
+    }
+
+    #[test]
+    fn test_flash_is_signed_and_short_lived() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let flash = Flash::error("Passwords do not match")
+            .value("username", "ada")
+            .value("password1", "secret")
+            .field_error("password2", "Passwords do not match");
+        assert!(!flash.values.contains_key("password1"));
+
+        let mut response = HttpResponse::Found();
+        set_flash(&flash_request(&clock, None), &mut response, &flash);
+        let cookie = response
+            .finish()
+            .cookies()
+            .find(|cookie| cookie.name() == FLASH_COOKIE_NAME)
+            .unwrap()
+            .into_owned();
+
+        let take = |cookie: Cookie<'static>| {
+            take_flash(
+                &flash_request(&clock, Some(cookie)),
+                &mut HttpResponse::Ok(),
+            )
+        };
+        assert_eq!(take(cookie.clone()), Some(flash));
+
+        let forged = cookie.value().replacen("ada", "eve", 1);
+        assert_eq!(take(Cookie::new(FLASH_COOKIE_NAME, forged)), None);
+
+        clock.advance(FLASH_TTL);
+        assert_eq!(take(cookie), None);
+    }
+
+    #[test]
+    fn test_template_lists_are_truncated() {
+        let mut items: Vec<usize> = (0..TEMPLATE_LIST_LIMIT + 5).collect();
+        assert_eq!(truncate_for_template(&mut items), 5);
+        assert_eq!(items.len(), TEMPLATE_LIST_LIMIT);
+
+        let mut few = vec![1, 2];
+        assert_eq!(truncate_for_template(&mut few), 0);
+        assert_eq!(few, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_dev_flag_is_parsed_from_the_query() {
+        for query in ["dev=1", "lang=de&dev=1", "a=1&dev=1&b=%20"] {
+            assert!(parse_dev_flag(query), "{query}");
+        }
+        for query in ["", "dev", "dev=0", "dev=true", "developer=1", "lang=de"] {
+            assert!(!parse_dev_flag(query), "{query}");
+        }
+    }
+
+    #[cfg(not(feature = "dev"))]
+    #[test]
+    fn test_release_builds_ignore_the_dev_flag() {
+        let request =
+            actix_web::test::TestRequest::with_uri("/login?lang=de&dev=1").to_http_request();
+        assert!(!dev_sources_requested(&request));
+        assert_eq!(dev_query(&request), "");
+        assert_eq!(
+            resolve_template_path(INDEX, &request),
+            Path::new(INDEX_FILE)
+        );
+        assert_eq!(
+            resolve_template_path("login.html", &request),
+            Path::new(TEMPLATES_DIR).join("login.html")
+        );
+        assert_eq!(default_templates_dir(), TEMPLATES_DIR);
+    }
+
+    #[cfg(feature = "dev")]
+    #[test]
+    fn test_dev_builds_serve_the_sources_for_the_dev_flag() {
+        let request =
+            actix_web::test::TestRequest::with_uri("/login?lang=de&dev=1").to_http_request();
+        assert_eq!(dev_query(&request), "?dev=1");
+        assert_eq!(
+            resolve_template_path(INDEX, &request),
+            Path::new(DEV_INDEX_FILE)
+        );
+        assert_eq!(
+            resolve_template_path("login.html", &request),
+            Path::new(DEV_TEMPLATES_DIR).join("login.html")
+        );
+
+        let request = actix_web::test::TestRequest::with_uri("/login?lang=de").to_http_request();
+        assert_eq!(
+            resolve_template_path(INDEX, &request),
+            Path::new(INDEX_FILE)
+        );
+    }
+
+    fn rust_sources(dir: &Path, sources: &mut Vec<(PathBuf, String)>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                rust_sources(&path, sources);
+            } else if path.extension().is_some_and(|extension| extension == "rs") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                sources.push((path, source));
+            }
+        }
+    }
+
+    #[test]
+    fn test_frontend_files_resolve_through_one_helper() {
+        let mut sources = Vec::new();
+        rust_sources(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut sources,
+        );
+
+        // split so this test doesn't count itself
+        let dev_dir = concat!("\"../", ".templates");
+        let named_file = concat!("NamedFile::", "open");
+        let count = |needle: &str| {
+            sources
+                .iter()
+                .map(|(_, source)| source.matches(needle).count())
+                .sum::<usize>()
+        };
+        assert_eq!(count(dev_dir), 1);
+        assert_eq!(count(named_file), 1);
+        assert!(sources
+            .iter()
+            .filter(|(_, source)| source.contains(named_file))
+            .all(|(path, _)| path.ends_with("templates.rs")));
+    }
+}
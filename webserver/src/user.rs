@@ -1,21 +1,49 @@
-use crate::authentication::{self, JWTClaims};
-use crate::canvas::store::GetUserClaimsMessage;
+use crate::api::pagination::{self, ListEndpoint, ListParams, ListQuery};
+use crate::authentication::{self, AuthContext, JWTRefreshCache};
+use crate::canvas::activity::{ActivityCache, UserActivity};
+use crate::canvas::error::CanvasStoreError;
+use crate::canvas::server::{self, CanvasSocketServerHandle, UserSession};
+use crate::canvas::store::{
+    AccessLevel, CanvasId, GetOwnedCanvasesMessage, GetUserCanvasesMessage, GetUserClaimsMessage,
+    PurgeUserMessage, SetCanvasOrderMessage, SetCanvasPreferenceMessage, UserCanvases,
+};
+use crate::clock;
+use crate::forms::FormOrJson;
+use crate::maintenance_mode;
+use crate::messages::{self, Message, MessageBody, MessageKey};
+use crate::notifier::Notifier;
+use crate::password;
+use crate::preferences::{self, PreferencesLimiter, UserPreferences};
+use crate::recovery;
+use crate::security;
 use crate::templates;
-use crate::userstore::{GetUserMessage, RegisterUser, RegisterUserMessage};
+use crate::userstore::{
+    BumpTokenVersionMessage, CompletePasswordResetMessage, DeleteUserMessage, GetUserMessage,
+    IssuePasswordResetMessage, RecordLoginMessage, RegisterUser, RegisterUserMessage,
+    SetUserPreferencesMessage, UpdatePasswordHashMessage, User, UserId, UserStoreError,
+};
 use actix::Recipient;
-use actix_web::{cookie::Cookie, error, get, post, web, HttpResponse, Responder, Result};
+use actix_web::{
+    get,
+    http::{
+        header::{ContentType, EntityTag, IfMatch, ETAG},
+        StatusCode,
+    },
+    post, web, HttpResponse, Responder, Result,
+};
 use actix_web::{HttpMessage, HttpRequest};
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{PasswordHash, PasswordVerifier},
     Argon2,
 };
 use handlebars::Handlebars;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
-/// API Handler for all endpoints related to user management
+// API Handler for all endpoints related to user management
 
-pub const JWT_SECRET: &str = "secret";
+pub(crate) const JWT_SECRET: &str = "secret";
 pub const AUTH_COOKIE_NAME: &str = "auth-token";
 
 #[derive(Deserialize)]
@@ -32,66 +60,200 @@ struct RegisterForm {
     password2: String,
 }
 
+#[derive(Deserialize)]
+struct PasswordResetRequestForm {
+    username_email: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordResetQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordResetForm {
+    token: String,
+    password1: String,
+    password2: String,
+}
+
+/// Renders a form page, a flash left by its failed submission shows the errors and prefills the form
+async fn form_page(
+    request: &HttpRequest,
+    handlebars: &web::Data<Handlebars<'static>>,
+    template: &'static str,
+) -> Result<HttpResponse> {
+    let mut response = HttpResponse::Ok();
+    let template_data = json!({
+        "flash": templates::take_flash(request, &mut response),
+        "dev_query": templates::dev_query(request),
+    });
+
+    let page = templates::render_timed(request, handlebars, template, template_data).await?;
+    Ok(response.content_type(ContentType::html()).body(page))
+}
+
 #[get("/login", name = "login")]
-async fn login_page(request: HttpRequest) -> Result<impl Responder> {
-    templates::serve_template("login.html", &request).await
+async fn login_page(
+    request: HttpRequest,
+    handlebars: web::Data<Handlebars<'static>>,
+) -> Result<impl Responder> {
+    form_page(&request, &handlebars, "login").await
 }
 
+/// Rejected logins redirect back to the login page with the error as flash
 #[post("/login")]
 async fn login(
     request: HttpRequest,
     login_form: web::Form<LoginForm>,
     user_store_addr: web::Data<Recipient<GetUserMessage>>,
     canvas_claims_addr: web::Data<Recipient<GetUserClaimsMessage>>,
-    argon: web::Data<Argon2<'_>>,
+    update_password_hash_addr: web::Data<Recipient<UpdatePasswordHashMessage>>,
+    record_login_addr: web::Data<Recipient<RecordLoginMessage>>,
+    argon: web::Data<Argon2<'static>>,
 ) -> Result<impl Responder> {
+    let flash = templates::Flash::error("").value("username_email", &login_form.username_email);
+    let result = login_user(
+        &request,
+        login_form.into_inner(),
+        &user_store_addr,
+        &canvas_claims_addr,
+        &update_password_hash_addr,
+        &record_login_addr,
+        &argon,
+    )
+    .await;
+    templates::redirect_back_on_error(&request, result, "login", [""; 0], flash, |key| match key {
+        MessageKey::UnknownUser => Some("username_email"),
+        MessageKey::InvalidCredentials => Some("password"),
+        _ => None,
+    })
+}
+
+async fn login_user(
+    request: &HttpRequest,
+    login_form: LoginForm,
+    user_store_addr: &Recipient<GetUserMessage>,
+    canvas_claims_addr: &Recipient<GetUserClaimsMessage>,
+    update_password_hash_addr: &Recipient<UpdatePasswordHashMessage>,
+    record_login_addr: &Recipient<RecordLoginMessage>,
+    argon: &Argon2<'static>,
+) -> Result<HttpResponse> {
     let user = user_store_addr
         .send(GetUserMessage {
             username_email: Some(login_form.username_email.clone()),
             user_id: None,
         })
         .await
-        .map_err(|_| error::ErrorInternalServerError("Failed to login, try again later"))?;
+        .map_err(|_| messages::internal_error(MessageKey::LoginFailed))?;
 
     if let Some(user) = user {
         let parsed_hash = PasswordHash::new(&user.password_hash)
-            .map_err(|_| error::ErrorInternalServerError("Failed to login, try again later"))?;
+            .map_err(|_| messages::internal_error(MessageKey::LoginFailed))?;
 
         let password_check = argon.verify_password(login_form.password.as_bytes(), &parsed_hash);
 
         if password_check.is_ok() {
+            if record_login_addr
+                .send(RecordLoginMessage {
+                    user_id: user.id.clone(),
+                })
+                .await
+                .map_or(true, |result| result.is_err())
+            {
+                // losing a login timestamp is not worth failing the login
+                println!("Failed to record login of {}", user.id);
+            }
+
             let claims = canvas_claims_addr
                 .send(GetUserClaimsMessage {
                     user_id: user.id.clone(),
+                    limit: Some(authentication::JWT_CLAIM_LIMIT),
+                    canvas_id: None,
                 })
                 .await
-                .map_err(|_| error::ErrorInternalServerError("Failed to login, try again later"))?;
+                .map_err(|_| messages::internal_error(MessageKey::LoginFailed))?;
             //TODO: consider logging alterting system, if this error occurs, something is very wrong
 
-            let jwt_token = authentication::generate_jwt_token(user.into(), claims)?;
-            let mut redirect_response = templates::builder_redirect_to_static("home", &request);
-            return Ok(redirect_response
-                .cookie(
-                    Cookie::build(AUTH_COOKIE_NAME, jwt_token)
-                        .same_site(actix_web::cookie::SameSite::Lax) // prevents CSRF for POST requests
-                        .http_only(true) // prevents some XSS attacks
-                        .path("/")
-                        .finish(),
-                )
-                .finish());
+            let rehash_required = password::needs_rehash(&parsed_hash, argon.params());
+            let user_id = user.id.clone();
+            let verified_hash = user.password_hash.clone();
+
+            let jwt_token = authentication::generate_jwt_token(
+                user.into(),
+                claims,
+                clock::request_clock(request).as_ref(),
+            )
+            .map_err(|_| messages::internal_error(MessageKey::LoginFailed))?;
+            let mut redirect_response = templates::builder_redirect_to_static("home", request);
+            let response = redirect_response
+                .cookie(authentication::cookie_factory(request).auth_cookie(jwt_token))
+                .finish();
+
+            // upgrade hashes created with weaker parameters, the password is only known right now
+            // runs in the background so the login response is not delayed
+            if rehash_required {
+                actix_web::rt::spawn(rehash_password(
+                    argon.clone(),
+                    login_form.password,
+                    user_id,
+                    verified_hash,
+                    update_password_hash_addr.clone(),
+                ));
+            }
+
+            return Ok(response);
         }
 
-        Ok(HttpResponse::Forbidden().body("Invalid password or username"))
+        Err(messages::forbidden(MessageKey::InvalidCredentials).into())
     } else {
-        Ok(HttpResponse::BadRequest().body("User does not exist"))
+        Err(messages::bad_request(MessageKey::UnknownUser).into())
+    }
+}
+
+/// Hashes the password with the current parameters and stores the new hash
+/// Nothing is stored if the verified hash was replaced in the meantime, e.g. by a password reset
+async fn rehash_password(
+    argon: Argon2<'static>,
+    password: String,
+    user_id: UserId,
+    verified_hash: String,
+    update_password_hash_addr: Recipient<UpdatePasswordHashMessage>,
+) {
+    let password_hash =
+        web::block(move || password::hash_password(&argon, password.as_bytes())).await;
+
+    let password_hash = match password_hash {
+        Ok(Ok(password_hash)) => password_hash,
+        _ => {
+            println!("Failed to rehash password of {user_id}");
+            return;
+        }
+    };
+
+    match update_password_hash_addr
+        .send(UpdatePasswordHashMessage {
+            user_id: user_id.clone(),
+            expected_hash: verified_hash,
+            password_hash,
+        })
+        .await
+    {
+        Ok(Ok(true)) => println!("Upgraded password hash of {user_id}"),
+        Ok(Ok(false)) => println!("Password of {user_id} changed meanwhile, dropped the rehash"),
+        _ => println!("Failed to store rehashed password of {user_id}"),
     }
 }
 
 #[get("/register", name = "register")]
-async fn register_page(request: HttpRequest) -> Result<impl Responder> {
-    templates::serve_template("register.html", &request).await
+async fn register_page(
+    request: HttpRequest,
+    handlebars: web::Data<Handlebars<'static>>,
+) -> Result<impl Responder> {
+    form_page(&request, &handlebars, "register").await
 }
 
+/// Rejected registrations redirect back to the register page with the errors as flash
 #[post("/register")]
 async fn register(
     request: HttpRequest,
@@ -99,17 +261,42 @@ async fn register(
     user_store_addr: web::Data<Recipient<RegisterUserMessage>>,
     argon: web::Data<Argon2<'_>>,
 ) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    let flash = templates::Flash::error("")
+        .value("username", &register_form.username)
+        .value("email", &register_form.email);
+    let result = register_user(&request, &register_form, &user_store_addr, &argon).await;
+    templates::redirect_back_on_error(
+        &request,
+        result,
+        "register",
+        [""; 0],
+        flash,
+        |key| match key {
+            MessageKey::PasswordsDoNotMatch => Some("password2"),
+            MessageKey::UsernameTaken
+            | MessageKey::UsernameLength
+            | MessageKey::UsernameReserved
+            | MessageKey::UsernameCharacters
+            | MessageKey::UsernameConfusable => Some("username"),
+            MessageKey::EmailTaken => Some("email"),
+            _ => None,
+        },
+    )
+}
+
+async fn register_user(
+    request: &HttpRequest,
+    register_form: &RegisterForm,
+    user_store_addr: &Recipient<RegisterUserMessage>,
+    argon: &Argon2<'_>,
+) -> Result<HttpResponse> {
     if register_form.password1 != register_form.password2 {
-        return Ok(HttpResponse::BadRequest().body("Passwords do not match"));
+        return Err(messages::bad_request(MessageKey::PasswordsDoNotMatch).into());
     }
 
-    let salt = SaltString::generate(&mut OsRng);
-
-    // Hash password to PHC string ($argon2id$v=19$...)
-    let password_hash = argon
-        .hash_password(register_form.password1.clone().as_bytes(), &salt)
-        .map_err(|_| error::ErrorInternalServerError("Registration Failed"))?
-        .to_string();
+    let password_hash = password::hash_password(argon, register_form.password1.as_bytes())
+        .map_err(|_| messages::internal_error(MessageKey::RegistrationFailed))?;
 
     let _ = user_store_addr
         .send(RegisterUserMessage {
@@ -120,60 +307,840 @@ async fn register(
             },
         })
         .await
-        .map_err(|_| error::ErrorInternalServerError("Failed to register, try again later"))??;
-    // TODO: better error handling if user already exists
+        .map_err(|_| messages::internal_error(MessageKey::RegistrationFailed))??;
 
-    Ok(templates::redirect_to_static("login", &request))
+    Ok(templates::redirect_to_static("login", request))
+}
+
+/// Redirect to the login page, removing the auth cookie of this browser
+fn logout_response(request: &HttpRequest) -> HttpResponse {
+    let mut redirect_response = templates::builder_redirect_to_static("login", request);
+    redirect_response.cookie(authentication::cookie_factory(request).auth_removal_cookie());
+    redirect_response.finish()
 }
 
 #[post("/logout")]
 async fn logout_handler(request: HttpRequest) -> impl Responder {
-    let mut redirect_response = templates::builder_redirect_to_static("login", &request);
-    // redirect_response.
-    let mut cookie = Cookie::build(AUTH_COOKIE_NAME, "")
-        .same_site(actix_web::cookie::SameSite::Lax)
-        .http_only(true)
-        .path("/")
-        .finish();
-    cookie.make_removal();
-
-    redirect_response.cookie(cookie);
-    redirect_response.finish()
+    logout_response(&request)
+}
+
+/// Revokes every JWT of the user and closes its canvas sessions, every device has to log in again
+async fn logout_all_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    bump_token_version_addr: web::Data<Recipient<BumpTokenVersionMessage>>,
+    jwt_refresh_cache: web::Data<JWTRefreshCache>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    let user_id = auth.user_id();
+
+    bump_token_version_addr
+        .send(BumpTokenVersionMessage {
+            user_id: user_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::LogoutFailed))??;
+
+    // a token refreshed just before the bump must not be handed out anymore
+    jwt_refresh_cache.invalidate(user_id);
+    canvas_server_handle.close_user_sessions(user_id.clone());
+
+    Ok(logout_response(&request))
+}
+
+/// Deletes the user and removes it from every canvas it is a member of, see delete_account_handler
+/// Refused while the user owns canvases, soft deleted ones included, they have to be transferred or deleted first
+/// The UserStore deletes first, memberships a failed purge leaves behind are removed on the next start
+/// Resolves to the canvases the user was removed from
+pub(crate) async fn delete_user(
+    user_id: &UserId,
+    owned_canvases_addr: &Recipient<GetOwnedCanvasesMessage>,
+    delete_user_addr: &Recipient<DeleteUserMessage>,
+    purge_user_addr: &Recipient<PurgeUserMessage>,
+    jwt_refresh_cache: &JWTRefreshCache,
+    canvas_server_handle: &CanvasSocketServerHandle,
+) -> Result<Vec<CanvasId>> {
+    let owned = owned_canvases_addr
+        .send(GetOwnedCanvasesMessage {
+            user_id: user_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AccountDeletionFailed))?;
+    if !owned.is_empty() {
+        return Err(messages::conflict(
+            Message::new(MessageKey::AccountOwnsCanvases).param("count", owned.len()),
+        )
+        .into());
+    }
+
+    delete_user_addr
+        .send(DeleteUserMessage {
+            user_id: user_id.clone(),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::AccountDeletionFailed))??;
+    jwt_refresh_cache.invalidate(user_id);
+    canvas_server_handle.close_user_sessions(user_id.clone());
+
+    // the account is gone either way, answering with an error would only invite a retry
+    let purged = purge_user_addr
+        .send(PurgeUserMessage {
+            user_id: user_id.clone(),
+        })
+        .await
+        .unwrap_or_else(|e| Err(CanvasStoreError::persistence(e)));
+    Ok(purged.unwrap_or_else(|e| {
+        println!(
+            "WARNING: memberships of deleted user {user_id} are removed on the next start: {e}"
+        );
+        Vec::new()
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct DeleteAccountQuery {
+    /// username of the account, confirms the deletion
+    confirm: Option<String>,
+}
+
+/// Deletes the account of the logged in user and removes it from the canvases it is a member of
+/// Every session is closed and the auth cookie of this browser is removed
+#[utoipa::path(
+    delete,
+    path = "/api/me",
+    tag = "user",
+    params(DeleteAccountQuery),
+    responses(
+        (status = 204, description = "account deleted"),
+        (status = 401, body = MessageBody, description = "not logged in"),
+        (status = 409, body = MessageBody, description = "the account still owns canvases"),
+        (status = 428, body = MessageBody, description = "confirm does not name the username")
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+async fn delete_account_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    query: web::Query<DeleteAccountQuery>,
+    owned_canvases_addr: web::Data<Recipient<GetOwnedCanvasesMessage>>,
+    delete_user_addr: web::Data<Recipient<DeleteUserMessage>>,
+    purge_user_addr: web::Data<Recipient<PurgeUserMessage>>,
+    jwt_refresh_cache: web::Data<JWTRefreshCache>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    auth.require_user()?;
+    if query.confirm.as_deref() != Some(auth.username()) {
+        return Err(messages::precondition_required(
+            Message::new(MessageKey::AccountDeletionConfirmationRequired)
+                .param("username", auth.username()),
+        )
+        .into());
+    }
+
+    delete_user(
+        auth.user_id(),
+        &owned_canvases_addr,
+        &delete_user_addr,
+        &purge_user_addr,
+        &jwt_refresh_cache,
+        &canvas_server_handle,
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent()
+        .cookie(authentication::cookie_factory(&request).auth_removal_cookie())
+        .finish())
+}
+
+/// Issues a reset token and hands the reset link to the Notifier
+/// The response is the same whether the account exists or not
+#[post("/user/request-password-reset")]
+async fn request_password_reset(
+    request: HttpRequest,
+    form: web::Form<PasswordResetRequestForm>,
+    issue_password_reset_addr: web::Data<Recipient<IssuePasswordResetMessage>>,
+    notifier: web::Data<dyn Notifier>,
+    public_origin: web::Data<security::PublicOrigin>,
+) -> Result<impl Responder> {
+    // the same for every account, it tells nothing about the account
+    maintenance_mode::ensure_writable(&request)?;
+    let issued = issue_password_reset_addr
+        .send(IssuePasswordResetMessage {
+            username_email: form.into_inner().username_email,
+            ttl: recovery::RESET_TOKEN_TTL,
+        })
+        .await;
+
+    match issued {
+        Ok(Ok(Some(issued))) => {
+            // never from the Host header, a forged one would send the token to another host
+            let reset_link = format!(
+                "{}/user/reset-password?token={}",
+                public_origin.get_ref(),
+                issued.token
+            );
+            notifier.password_reset(&issued.user_id, &issued.email, &reset_link);
+        }
+        Ok(Ok(None)) => (),
+        // only logged, an error response would tell that the account exists
+        Ok(Err(e)) => println!("Failed to issue password reset, {}: {e:?}", e.code()),
+        Err(e) => println!("Failed to issue password reset: {e}"),
+    }
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::PasswordResetRequested.into(),
+    ))
+}
+
+/// Form to choose a new password, linked to by the reset link
+#[get("/user/reset-password", name = "reset_password")]
+async fn reset_password_page(
+    request: HttpRequest,
+    query: web::Query<PasswordResetQuery>,
+    handlebars: web::Data<Handlebars<'static>>,
+) -> Result<impl Responder> {
+    if recovery::verify_token(&query.token).is_none() {
+        return Err(messages::bad_request(MessageKey::PasswordResetInvalid).into());
+    }
+
+    let template_data = json!({ "token": query.token });
+    templates::render_timed(&request, &handlebars, "reset-password", template_data)
+        .await
+        .map(web::Html::new)
+}
+
+/// Sets the new password, the token is consumed and every device has to log in again
+#[post("/user/reset-password")]
+async fn reset_password(
+    request: HttpRequest,
+    form: web::Form<PasswordResetForm>,
+    complete_password_reset_addr: web::Data<Recipient<CompletePasswordResetMessage>>,
+    argon: web::Data<Argon2<'static>>,
+    jwt_refresh_cache: web::Data<JWTRefreshCache>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    let form = form.into_inner();
+    if form.password1 != form.password2 {
+        return Err(messages::bad_request(MessageKey::PasswordsDoNotMatch).into());
+    }
+    // forged tokens are rejected before a password is hashed for them
+    if recovery::verify_token(&form.token).is_none() {
+        return Err(messages::bad_request(MessageKey::PasswordResetInvalid).into());
+    }
+
+    let argon = argon.into_inner();
+    let password = form.password1;
+    let password_hash = web::block(move || password::hash_password(&argon, password.as_bytes()))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or_else(|| messages::internal_error(MessageKey::PasswordResetFailed))?;
+
+    let user_id = complete_password_reset_addr
+        .send(CompletePasswordResetMessage {
+            token: form.token,
+            password_hash,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::PasswordResetFailed))??;
+
+    // same as logging out everywhere, tokens refreshed just before must not be handed out anymore
+    jwt_refresh_cache.invalidate(&user_id);
+    canvas_server_handle.close_user_sessions(user_id);
+
+    Ok(logout_response(&request))
+}
+
+/// Live websocket sessions of the logged in user across all canvases
+#[utoipa::path(
+    get,
+    path = "/user/sessions",
+    tag = "user",
+    responses(
+        (status = 200, body = Vec<UserSession>),
+        (status = 401, body = MessageBody, description = "not logged in")
+    )
+)]
+async fn sessions_handler(
+    auth: AuthContext,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+) -> Result<impl Responder> {
+    Ok(web::Json(
+        canvas_server_handle
+            .user_sessions(auth.user_id().clone())
+            .await?,
+    ))
+}
+
+/// Canvas of the user as listed by /api/me
+#[derive(Serialize, ToSchema)]
+struct CanvasListEntry {
+    name: String,
+    id: String,
+    access_level: AccessLevel,
+}
+
+/// All canvases of the user, the JWT only carries the most recent claims
+async fn canvas_list(
+    user_id: &UserId,
+    canvas_claims_addr: &Recipient<GetUserClaimsMessage>,
+) -> Result<Vec<CanvasListEntry>> {
+    let claims = canvas_claims_addr
+        .send(GetUserClaimsMessage {
+            user_id: user_id.clone(),
+            limit: None,
+            canvas_id: None,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?;
+
+    Ok(claims
+        .into_iter()
+        .map(|claim| CanvasListEntry {
+            name: claim.n,
+            id: claim.c,
+            access_level: claim.r,
+        })
+        .collect())
+}
+
+/// Canvases of the user grouped by origin and recency
+/// Falls back to the claims of the JWT, without visits and tags, if the CanvasStore can't be reached
+async fn user_canvases(
+    auth: &AuthContext,
+    user_canvases_addr: &Recipient<GetUserCanvasesMessage>,
+    tag: Option<String>,
+) -> UserCanvases {
+    let filtered = tag.is_some();
+    user_canvases_addr
+        .send(GetUserCanvasesMessage {
+            user_id: auth.user_id().clone(),
+            tag,
+        })
+        .await
+        .unwrap_or_else(|_| {
+            // tags are unknown without the store, no canvas matches a filter
+            let claims = if filtered {
+                Vec::new()
+            } else {
+                auth.claims().can.clone()
+            };
+            UserCanvases::group(claims, None, None, None)
+        })
 }
 
 async fn home_request_handler(
     request: HttpRequest,
-    handlebars: web::Data<Handlebars<'_>>,
+    auth: AuthContext,
+    handlebars: web::Data<Handlebars<'static>>,
+    user_canvases_addr: web::Data<Recipient<GetUserCanvasesMessage>>,
 ) -> actix_web::Result<impl Responder> {
-    let user_data = request.extensions().get::<JWTClaims>().map_or(
-        Err(error::ErrorUnauthorized("Failed to authenticate")),
-        |claims| Ok(claims.clone()),
-    )?;
-
-    let canvas: Vec<_> = user_data
-        .can
-        .iter()
-        .map(|claim| {
-            json!({
-                "name": claim.n,
-                "id": claim.c,
-                "access_level": claim.r,
-            })
-        })
-        .collect();
+    let mut canvas = user_canvases(&auth, &user_canvases_addr, None).await;
+    // recent is already bounded by RECENT_CANVAS_LIMIT
+    let more = json!({
+        "owned": templates::truncate_for_template(&mut canvas.owned),
+        "shared": templates::truncate_for_template(&mut canvas.shared),
+        "deleted": templates::truncate_for_template(&mut canvas.deleted),
+    });
 
+    let mut response = HttpResponse::Ok();
     let template_data = json!({
-        "id": user_data.uid,
-        "name": user_data.nam,
+        "id": auth.user_id(),
+        "name": auth.username(),
         "canvas": canvas,
+        "more": more,
+        "nonce": security::csp_nonce(&request),
+        // errors of a canvas creation submitted without the SPA
+        "flash": templates::take_flash(&request, &mut response),
     });
 
-    handlebars
-        .render("home", &template_data)
+    let page = templates::render_timed(&request, &handlebars, "home", template_data).await?;
+    Ok(response.content_type(ContentType::html()).body(page))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct CanvasListQuery {
+    tag: Option<String>,
+}
+
+/// Canvases of /api/canvases with list parameters, owned and shared ones without repeats
+struct CanvasList;
+
+impl ListEndpoint for CanvasList {
+    const DEFAULT_PER_PAGE: usize = 50;
+    const MAX_PER_PAGE: usize = 200;
+    const SORT_FIELDS: &'static [&'static str] = &["name", "last_visited_at"];
+    const DEFAULT_SORT: &'static str = "name";
+}
+
+/// Canvases of the logged in user as JSON, grouped like on the home page
+/// ?tag= limits the list to canvases carrying the tag, it is normalized like the tags themselves
+/// With list parameters the owned and shared canvases are answered as a single list in the envelope of the list endpoints
+#[utoipa::path(
+    get,
+    path = "/api/canvases",
+    tag = "user",
+    params(CanvasListQuery, ListQuery),
+    responses(
+        (status = 200, body = UserCanvases, description = "an Envelope of CanvasSummary with list parameters"),
+        (status = 401, body = MessageBody, description = "not logged in"),
+        (status = 422, body = MessageBody, description = "invalid list parameter, named by the field param")
+    )
+)]
+async fn canvases_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    user_canvases_addr: web::Data<Recipient<GetUserCanvasesMessage>>,
+    query: web::Query<CanvasListQuery>,
+    list: ListParams<CanvasList>,
+) -> Result<HttpResponse> {
+    let tag = query
+        .into_inner()
+        .tag
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty());
+    let canvases = user_canvases(&auth, &user_canvases_addr, tag).await;
+
+    if !pagination::requested(&request, &[]) {
+        return Ok(HttpResponse::Ok().json(canvases));
+    }
+    // pinned and recent repeat canvases of the other groups
+    let mut summaries = canvases.owned;
+    summaries.extend(canvases.shared);
+    summaries.sort_by(|a, b| {
+        list.sort.order(match list.sort.field {
+            "last_visited_at" => a.last_visited_at.cmp(&b.last_visited_at),
+            _ => a.name.cmp(&b.name),
+        })
+    });
+    Ok(list.respond(summaries))
+}
+
+/// Canvases a single order request may place
+pub const MAX_CANVAS_ORDER: usize = 1000;
+
+#[derive(Deserialize, ToSchema)]
+struct CanvasPinForm {
+    pinned: bool,
+    /// position within the pinned group, kept if left out
+    sort_hint: Option<u32>,
+}
+
+/// Pins or unpins a canvas on the home page of the logged in user
+#[utoipa::path(
+    post,
+    path = "/api/canvases/{canvas_id}/pin",
+    tag = "user",
+    params(("canvas_id" = String, Path, description = "id of the canvas")),
+    request_body = CanvasPinForm,
+    responses(
+        (status = 200, body = MessageBody),
+        (status = 401, body = MessageBody, description = "not logged in"),
+        (status = 403, body = MessageBody, description = "not a member of the canvas")
+    )
+)]
+async fn canvas_pin_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    canvas_id: web::Path<String>,
+    set_canvas_preference_addr: web::Data<Recipient<SetCanvasPreferenceMessage>>,
+    pin_form: FormOrJson<CanvasPinForm>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    let pin = pin_form.into_inner();
+    set_canvas_preference_addr
+        .send(SetCanvasPreferenceMessage {
+            user_id: auth.user_id().clone(),
+            canvas_id: canvas_id.into_inner(),
+            pinned: Some(pin.pinned),
+            sort_hint: pin.sort_hint,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasPreferenceUpdated.into(),
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CanvasSortHint {
+    canvas_id: String,
+    sort_hint: u32,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CanvasOrderRequest {
+    order: Vec<CanvasSortHint>,
+}
+
+/// Sets the sort hints of several canvases of the logged in user at once
+/// Every canvas may appear once, the request is rejected as a whole if the user is not a member of one of them
+#[utoipa::path(
+    post,
+    path = "/api/canvases/order",
+    tag = "user",
+    request_body = CanvasOrderRequest,
+    responses(
+        (status = 200, body = MessageBody),
+        (status = 401, body = MessageBody, description = "not logged in"),
+        (status = 403, body = MessageBody, description = "not a member of one of the canvases"),
+        (status = 422, body = MessageBody, description = "duplicate canvas or too many canvases")
+    )
+)]
+async fn canvas_order_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    set_canvas_order_addr: web::Data<Recipient<SetCanvasOrderMessage>>,
+    order_request: web::Json<CanvasOrderRequest>,
+) -> Result<impl Responder> {
+    maintenance_mode::ensure_writable(&request)?;
+    let order: Vec<(String, u32)> = order_request
+        .into_inner()
+        .order
+        .into_iter()
+        .map(|entry| (entry.canvas_id, entry.sort_hint))
+        .collect();
+    let mut canvas_ids: Vec<&String> = order.iter().map(|(canvas_id, _)| canvas_id).collect();
+    canvas_ids.sort_unstable();
+    canvas_ids.dedup();
+    if canvas_ids.len() != order.len() || order.len() > MAX_CANVAS_ORDER {
+        return Err(messages::unprocessable_entity(
+            Message::new(MessageKey::CanvasOrderInvalid)
+                .param("max", MAX_CANVAS_ORDER)
+                .param("reason", "invalid_value")
+                .param("field", "order"),
+        )
+        .into());
+    }
+
+    if !order.is_empty() {
+        set_canvas_order_addr
+            .send(SetCanvasOrderMessage {
+                user_id: auth.user_id().clone(),
+                order,
+            })
+            .await
+            .map_err(|_| messages::internal_error(MessageKey::CanvasUpdateFailed))??;
+    }
+
+    Ok(messages::respond(
+        &request,
+        StatusCode::OK,
+        &MessageKey::CanvasPreferenceUpdated.into(),
+    ))
+}
+
+/// Formats a millisecond timestamp as ISO 8601
+fn format_timestamp(timestamp: Option<u64>) -> Option<String> {
+    timestamp
+        .and_then(|timestamp| chrono::DateTime::from_timestamp_millis(timestamp as i64))
+        .map(|timestamp| timestamp.to_rfc3339())
+}
+
+/// Loads the authenticated user from the UserStore
+async fn authenticated_user(
+    auth: &AuthContext,
+    user_store_addr: &Recipient<GetUserMessage>,
+) -> Result<User> {
+    user_store_addr
+        .send(GetUserMessage {
+            username_email: None,
+            user_id: Some(auth.user_id().clone()),
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?
+        .ok_or(messages::not_found(MessageKey::UnknownUser).into())
+}
+
+#[derive(Serialize, ToSchema)]
+struct Profile {
+    id: UserId,
+    username: String,
+    email: String,
+    /// ISO 8601
+    last_login_at: Option<String>,
+    /// ISO 8601
+    last_seen_at: Option<String>,
+}
+
+impl Profile {
+    fn of(user: &User) -> Self {
+        Self {
+            id: user.id.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            last_login_at: format_timestamp(user.last_login_at),
+            last_seen_at: format_timestamp(user.last_seen_at),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Me {
+    #[serde(flatten)]
+    profile: Profile,
+    canvas: Vec<CanvasListEntry>,
+    /// lets views without a socket estimate the offset of the client clock
+    server_time_ms: u64,
+}
+
+/// Profile of the logged in user as JSON
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    tag = "user",
+    responses(
+        (status = 200, body = Me),
+        (status = 401, body = MessageBody, description = "not logged in")
+    )
+)]
+async fn me_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    user_store_addr: web::Data<Recipient<GetUserMessage>>,
+    canvas_claims_addr: web::Data<Recipient<GetUserClaimsMessage>>,
+) -> Result<impl Responder> {
+    let user = authenticated_user(&auth, &user_store_addr).await?;
+
+    Ok(web::Json(Me {
+        profile: Profile::of(&user),
+        canvas: canvas_list(&user.id, &canvas_claims_addr).await?,
+        server_time_ms: clock::request_clock(&request).now_ms(),
+    }))
+}
+
+/// Activity of the user on one of their canvases
+#[derive(Serialize, ToSchema)]
+struct CanvasActivityEntry {
+    id: String,
+    name: String,
+    #[serde(flatten)]
+    activity: UserActivity,
+}
+
+#[derive(Serialize, ToSchema)]
+struct MyActivity {
+    /// canvases the user changed and still has access to, most recently active first
+    canvas: Vec<CanvasActivityEntry>,
+    /// sum over the listed canvases
+    total: UserActivity,
+}
+
+/// Folds the activity of the user from the eventlogs of their canvases
+/// Canvases are not loaded, the eventlogs are only read again after they changed
+async fn my_activity(
+    user_id: &UserId,
+    canvas_claims_addr: &Recipient<GetUserClaimsMessage>,
+    canvas_server_handle: &CanvasSocketServerHandle,
+    activity_cache: &web::Data<ActivityCache>,
+) -> Result<MyActivity> {
+    let claims = canvas_claims_addr
+        .send(GetUserClaimsMessage {
+            user_id: user_id.clone(),
+            limit: None,
+            canvas_id: None,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))?;
+
+    let mut canvas = Vec::new();
+    for claim in claims {
+        let latest_seq = canvas_server_handle.persisted_seq(claim.c.clone()).await?;
+        let cache = activity_cache.clone();
+        let canvas_id = claim.c.clone();
+        let folded = web::block(move || {
+            cache.activity(&canvas_id, &server::canvas_log_path(&canvas_id), latest_seq)
+        })
+        .await;
+        let activity = match folded {
+            Ok(Ok(activity)) => activity,
+            Ok(Err(e)) => {
+                println!("WARNING: Skipping activity of canvas {}: {e}", claim.c);
+                continue;
+            }
+            Err(_) => return Err(messages::internal_error(MessageKey::UserLoadFailed).into()),
+        };
+        if let Some(activity) = activity.users.get(user_id).filter(|a| !a.is_empty()) {
+            canvas.push(CanvasActivityEntry {
+                id: claim.c,
+                name: claim.n,
+                activity: activity.clone(),
+            });
+        }
+    }
+
+    canvas.sort_by_key(|entry| std::cmp::Reverse(entry.activity.last_active_at));
+    let mut total = UserActivity::default();
+    for entry in &canvas {
+        total.merge(&entry.activity);
+    }
+    Ok(MyActivity { canvas, total })
+}
+
+/// Shapes and comments the logged in user added to their canvases
+#[utoipa::path(
+    get,
+    path = "/api/me/activity",
+    tag = "user",
+    responses(
+        (status = 200, body = MyActivity),
+        (status = 401, body = MessageBody, description = "not logged in")
+    )
+)]
+async fn my_activity_handler(
+    auth: AuthContext,
+    canvas_claims_addr: web::Data<Recipient<GetUserClaimsMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    activity_cache: web::Data<ActivityCache>,
+) -> Result<impl Responder> {
+    Ok(web::Json(
+        my_activity(
+            auth.user_id(),
+            &canvas_claims_addr,
+            &canvas_server_handle,
+            &activity_cache,
+        )
+        .await?,
+    ))
+}
+
+/// Preferences answered with their version as ETag, sent back in If-Match
+fn preferences_response(preferences: UserPreferences) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((
+            ETAG,
+            EntityTag::new_strong(preferences.version.to_string()).to_string(),
+        ))
+        .json(preferences)
+}
+
+/// Version a PUT expects, None without If-Match or with If-Match: *
+fn expected_preferences_version(request: &HttpRequest) -> Result<Option<u64>, UserStoreError> {
+    match request.get_header::<IfMatch>() {
+        None | Some(IfMatch::Any) => Ok(None),
+        Some(IfMatch::Items(tags)) => tags
+            .iter()
+            .find_map(|tag| tag.tag().parse().ok())
+            .map(Some)
+            // never issued by this server, can't match the stored version
+            .ok_or(UserStoreError::PreferencesConflict),
+    }
+}
+
+/// UI preferences of the logged in user, the ETag is their version
+#[utoipa::path(
+    get,
+    path = "/api/me/preferences",
+    tag = "user",
+    responses(
+        (status = 200, body = UserPreferences),
+        (status = 401, body = MessageBody, description = "not logged in")
+    )
+)]
+async fn preferences_handler(
+    auth: AuthContext,
+    user_store_addr: web::Data<Recipient<GetUserMessage>>,
+) -> Result<HttpResponse> {
+    let user = authenticated_user(&auth, &user_store_addr).await?;
+    Ok(preferences_response(user.preferences))
+}
+
+/// Replaces the UI preferences of the logged in user
+/// The body is a flat object of ui. keys to primitives and short strings
+/// With If-Match the preferences are only replaced if they still have that version
+#[utoipa::path(
+    put,
+    path = "/api/me/preferences",
+    tag = "user",
+    request_body(content = Object, description = "all preferences, replacing the stored ones"),
+    params(("If-Match" = Option<String>, Header, description = "ETag of the preferences the change is based on")),
+    responses(
+        (status = 200, body = UserPreferences),
+        (status = 401, body = MessageBody, description = "not logged in"),
+        (status = 412, body = MessageBody, description = "preferences changed since the version of If-Match"),
+        (status = 422, body = MessageBody, description = "invalid key or value, named by the field param, or too large"),
+        (status = 429, body = MessageBody, description = "preferences saved too often")
+    )
+)]
+async fn update_preferences_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    set_user_preferences_addr: web::Data<Recipient<SetUserPreferencesMessage>>,
+    preferences: web::Json<serde_json::Value>,
+) -> Result<HttpResponse> {
+    maintenance_mode::ensure_writable(&request)?;
+    let preferences = preferences::validate(preferences.into_inner())
+        .map_err(|violation| messages::unprocessable_entity(violation.message()))?;
+    let expected_version = expected_preferences_version(&request)?;
+    if let Some(limiter) = request.app_data::<web::Data<PreferencesLimiter>>() {
+        if !limiter.allow(auth.user_id(), std::time::Instant::now()) {
+            return Err(messages::too_many_requests(MessageKey::PreferencesRateLimited).into());
+        }
+    }
+
+    let preferences = set_user_preferences_addr
+        .send(SetUserPreferencesMessage {
+            user_id: auth.user_id().clone(),
+            preferences,
+            expected_version,
+        })
+        .await
+        .map_err(|_| messages::internal_error(MessageKey::UserLoadFailed))??;
+    Ok(preferences_response(preferences))
+}
+
+/// Number of canvases listed on the profile page, the full list is in /api/me/activity
+const PROFILE_ACTIVITY_CANVASES: usize = 5;
+
+/// Profile page of the logged in user
+async fn profile_page_handler(
+    request: HttpRequest,
+    auth: AuthContext,
+    user_store_addr: web::Data<Recipient<GetUserMessage>>,
+    canvas_claims_addr: web::Data<Recipient<GetUserClaimsMessage>>,
+    canvas_server_handle: web::Data<CanvasSocketServerHandle>,
+    activity_cache: web::Data<ActivityCache>,
+    handlebars: web::Data<Handlebars<'static>>,
+) -> Result<impl Responder> {
+    let user = authenticated_user(&auth, &user_store_addr).await?;
+    let mut activity = my_activity(
+        &user.id,
+        &canvas_claims_addr,
+        &canvas_server_handle,
+        &activity_cache,
+    )
+    .await?;
+    activity.canvas.truncate(PROFILE_ACTIVITY_CANVASES);
+
+    let mut profile = serde_json::to_value(Profile::of(&user)).unwrap_or_default();
+    profile["activity"] = serde_json::to_value(activity).unwrap_or_default();
+    templates::render_timed(&request, &handlebars, "profile", profile)
+        .await
         .map(web::Html::new)
-        .map_err(|_| error::ErrorInternalServerError("Failed to render home"))
 }
 
+/// JSON endpoints of the user service, see api_docs
+#[derive(OpenApi)]
+#[openapi(paths(
+    me_handler,
+    delete_account_handler,
+    my_activity_handler,
+    preferences_handler,
+    update_preferences_handler,
+    canvases_handler,
+    canvas_pin_handler,
+    canvas_order_handler,
+    sessions_handler
+))]
+pub(crate) struct UserApi;
+
 /// register user service with actix-web
 pub fn user_service(cfg: &mut web::ServiceConfig) {
     cfg.service(login)
@@ -181,10 +1148,211 @@ pub fn user_service(cfg: &mut web::ServiceConfig) {
         .service(register)
         .service(register_page)
         .service(logout_handler)
+        .service(request_password_reset)
+        .service(reset_password_page)
+        .service(reset_password)
         .service(
             web::resource("/home")
                 .name("home")
                 .wrap(authentication::AuthenticationService) // requires authentication
                 .route(web::get().to(home_request_handler)),
+        )
+        .service(
+            web::resource("/user/profile")
+                .name("profile")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(profile_page_handler)),
+        )
+        .service(
+            web::resource("/user/logout-all")
+                .wrap(authentication::AuthenticationService)
+                .route(web::post().to(logout_all_handler)),
+        )
+        .service(
+            web::resource("/user/sessions")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(sessions_handler)),
+        )
+        .service(
+            web::resource("/api/me")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(me_handler))
+                .route(web::delete().to(delete_account_handler)),
+        )
+        .service(
+            web::resource("/api/me/activity")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(my_activity_handler)),
+        )
+        .service(
+            web::resource("/api/me/preferences")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(preferences_handler))
+                .route(web::put().to(update_preferences_handler)),
+        )
+        .service(
+            web::resource("/api/canvases")
+                .wrap(authentication::AuthenticationService)
+                .route(web::get().to(canvases_handler)),
+        )
+        .service(
+            web::resource("/api/canvases/order")
+                .wrap(authentication::AuthenticationService)
+                .route(web::post().to(canvas_order_handler)),
+        )
+        .service(
+            web::resource("/api/canvases/{canvas_id}/pin")
+                .wrap(authentication::AuthenticationService)
+                .route(web::post().to(canvas_pin_handler)),
         );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::store::{CanvasStore, CanvasStoreEvents};
+    use crate::clock::{ManualClock, SharedClock};
+    use crate::persistence::EventLogPersistenceJson;
+    use crate::userstore::{User, UserStore, UserStoreEvents};
+    use actix::Actor;
+    use actix_web::{http::StatusCode, test, App};
+    use argon2::Params;
+    use std::{sync::Arc, time::Duration};
+
+    fn temp_log_path() -> String {
+        std::env::temp_dir()
+            .join(format!("{}.jsonl", nanoid::nanoid!(8)))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_login_upgrades_weak_hash_and_records_activity() {
+        let weak_argon = password::argon2_with_params(Params::new(1024, 1, 1, None).unwrap());
+        let weak_hash = password::hash_password(&weak_argon, b"password").unwrap();
+
+        let user_event = UserStoreEvents::UserRegistered {
+            timestamp: 0,
+            user_id: "user".to_string(),
+            user: User {
+                id: "user".to_string(),
+                email: "user@example.com".to_string(),
+                username: "user".to_string(),
+                password_hash: weak_hash.clone(),
+                last_login_at: None,
+                last_seen_at: None,
+                token_version: 0,
+                preferences: Default::default(),
+            },
+        };
+        let (_, user_log) = EventLogPersistenceJson::new(&temp_log_path())
+            .unwrap()
+            .into_actor::<UserStoreEvents>()
+            .unwrap();
+        // 2023-11-14T22:13:20Z, login and activity are recorded with the time of the clock
+        let clock: SharedClock = Arc::new(ManualClock::new(1_700_000_000_000));
+        let user_store = UserStore::new(
+            user_log.start().recipient(),
+            vec![user_event],
+            clock.clone(),
+        )
+        .0
+        .start();
+
+        let (_, canvas_log) = EventLogPersistenceJson::new(&temp_log_path())
+            .unwrap()
+            .into_actor::<CanvasStoreEvents>()
+            .unwrap();
+        let canvas_store = CanvasStore::new(
+            canvas_log.start().recipient(),
+            vec![],
+            crate::canvas::quota::QuotaLimits::default(),
+            clock.clone(),
+        )
+        .0
+        .start();
+
+        let strong_params = Params::new(2048, 2, 1, None).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    user_store.clone().recipient::<GetUserMessage>(),
+                ))
+                .app_data(web::Data::new(
+                    user_store.clone().recipient::<UpdatePasswordHashMessage>(),
+                ))
+                .app_data(web::Data::new(
+                    user_store.clone().recipient::<RecordLoginMessage>(),
+                ))
+                .app_data(web::Data::new(
+                    canvas_store.recipient::<GetUserClaimsMessage>(),
+                ))
+                .app_data(web::Data::new(password::argon2_with_params(
+                    strong_params.clone(),
+                )))
+                .app_data(web::Data::from(clock))
+                .configure(user_service),
+        )
+        .await;
+
+        let login_request = || {
+            test::TestRequest::post()
+                .uri("/login")
+                .set_form([("username_email", "user"), ("password", "password")])
+                .to_request()
+        };
+
+        let res = test::call_service(&app, login_request()).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+
+        // rehash happens in the background after the response
+        let mut upgraded_hash = None;
+        for _ in 0..100 {
+            let user = user_store
+                .send(GetUserMessage {
+                    username_email: None,
+                    user_id: Some("user".to_string()),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            if user.password_hash != weak_hash {
+                upgraded_hash = Some(user.password_hash);
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let upgraded_hash = upgraded_hash.expect("password hash was not upgraded");
+        assert!(!password::needs_rehash(
+            &PasswordHash::new(&upgraded_hash).unwrap(),
+            &strong_params
+        ));
+
+        // fresh hash still verifies
+        let res = test::call_service(&app, login_request()).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+
+        let auth_cookie = res
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == AUTH_COOKIE_NAME)
+            .unwrap()
+            .into_owned();
+
+        let profile: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/me")
+                .cookie(auth_cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(profile["username"], "user");
+        // events persisted within the same millisecond are stamped a millisecond apart
+        for field in ["last_login_at", "last_seen_at"] {
+            let stamp = profile[field].as_str().unwrap();
+            assert!(stamp.starts_with("2023-11-14T22:13:20"), "{field}: {stamp}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use derive_more::{Display, Error};
+use std::collections::HashSet;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton as confusable_skeleton;
+
+use crate::messages::{Message, MessageKey};
+
+// Username policy of new registrations, protects against impersonation and names that break pages or routes
+// Usernames are NFKC normalized, reserved words are matched by their confusable skeleton, so lookalikes are reserved as well
+// The UserStore rejects usernames whose skeleton equals the one of an existing user, see UserStore::users_skeleton_lookup
+// Names in the eventlog are kept as they are, violations of older names are only reported on startup
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Words no user may register, compared case insensitively and by skeleton
+pub const DEFAULT_RESERVED_USERNAMES: [&str; 16] = [
+    "admin",
+    "administrator",
+    "api",
+    "canvas",
+    "home",
+    "login",
+    "logout",
+    "moderator",
+    "register",
+    "root",
+    "server",
+    "static",
+    "support",
+    "system",
+    "user",
+    "ws",
+];
+
+/// Display names of guests start with it, no account can take such a name, see canvas::guests::generate
+pub const GUEST_NAME_PREFIX: &str = "Guest";
+
+/// Reserved prefixes, compared case insensitively and by skeleton
+pub const DEFAULT_RESERVED_PREFIXES: [&str; 1] = [GUEST_NAME_PREFIX];
+
+/// Punctuation allowed within a username, never at its start or end
+const INNER_PUNCTUATION: [char; 3] = ['-', '_', '.'];
+
+/// Why a username is rejected, the code is part of JSON responses so clients can localize it
+#[derive(Debug, Display, Error, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameViolation {
+    #[display("username must have {MIN_USERNAME_LENGTH} to {MAX_USERNAME_LENGTH} characters")]
+    Length,
+    #[display("username is reserved")]
+    Reserved,
+    /// characters outside the allowed class or punctuation at the start or end
+    #[display("username contains characters that are not allowed")]
+    Characters,
+    #[display("username looks like the name of another user")]
+    Confusable,
+}
+
+impl UsernameViolation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UsernameViolation::Length => "username_length",
+            UsernameViolation::Reserved => "username_reserved",
+            UsernameViolation::Characters => "username_characters",
+            UsernameViolation::Confusable => "username_confusable",
+        }
+    }
+
+    pub fn message(&self) -> Message {
+        match self {
+            UsernameViolation::Length => Message::new(MessageKey::UsernameLength)
+                .param("min", MIN_USERNAME_LENGTH)
+                .param("max", MAX_USERNAME_LENGTH),
+            UsernameViolation::Reserved => Message::new(MessageKey::UsernameReserved),
+            UsernameViolation::Characters => Message::new(MessageKey::UsernameCharacters),
+            UsernameViolation::Confusable => Message::new(MessageKey::UsernameConfusable),
+        }
+    }
+}
+
+/// NFKC form of the username, the form it is stored in
+pub fn normalize(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// Lowercased confusable skeleton, usernames with the same skeleton look alike
+pub fn skeleton(username: &str) -> String {
+    let lowercase = normalize(username).to_lowercase();
+    confusable_skeleton(&lowercase)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Checks of a single username, the collision with existing usernames is checked by the UserStore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsernamePolicy {
+    /// skeletons of the reserved words
+    reserved: HashSet<String>,
+    /// skeletons of the reserved prefixes
+    reserved_prefixes: Vec<String>,
+    /// usernames exempt from the reserved words
+    allowed: HashSet<String>,
+    /// letters and digits of any script, only ASCII ones otherwise
+    pub allow_unicode: bool,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESERVED_USERNAMES)
+    }
+}
+
+impl UsernamePolicy {
+    pub fn new<S: AsRef<str>>(reserved: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            reserved: reserved
+                .into_iter()
+                .map(|word| skeleton(word.as_ref()))
+                .collect(),
+            reserved_prefixes: DEFAULT_RESERVED_PREFIXES
+                .iter()
+                .map(|p| skeleton(p))
+                .collect(),
+            allowed: HashSet::new(),
+            allow_unicode: false,
+        }
+    }
+
+    /// Reserves additional words
+    pub fn reserve<S: AsRef<str>>(mut self, words: impl IntoIterator<Item = S>) -> Self {
+        self.reserved
+            .extend(words.into_iter().map(|word| skeleton(word.as_ref())));
+        self
+    }
+
+    /// Lets these exact usernames be registered although they are reserved
+    pub fn allow<S: AsRef<str>>(mut self, usernames: impl IntoIterator<Item = S>) -> Self {
+        self.allowed
+            .extend(usernames.into_iter().map(|name| normalize(name.as_ref())));
+        self
+    }
+
+    pub fn with_unicode(mut self, allow_unicode: bool) -> Self {
+        self.allow_unicode = allow_unicode;
+        self
+    }
+
+    fn is_reserved(&self, username: &str) -> bool {
+        if self.allowed.contains(username) {
+            return false;
+        }
+        let skeleton = skeleton(username);
+        self.reserved.contains(&skeleton)
+            || self
+                .reserved_prefixes
+                .iter()
+                .any(|prefix| skeleton.starts_with(prefix.as_str()))
+    }
+
+    fn is_allowed_char(&self, c: char) -> bool {
+        INNER_PUNCTUATION.contains(&c)
+            || match self.allow_unicode {
+                true => c.is_alphanumeric(),
+                false => c.is_ascii_alphanumeric(),
+            }
+    }
+
+    /// Normalized username if it may be registered
+    pub fn check(&self, username: &str) -> Result<String, UsernameViolation> {
+        let username = normalize(username);
+        if self.is_reserved(&username) {
+            return Err(UsernameViolation::Reserved);
+        }
+        let length = username.chars().count();
+        if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+            return Err(UsernameViolation::Length);
+        }
+        let starts_or_ends_with_punctuation = username
+            .chars()
+            .next()
+            .into_iter()
+            .chain(username.chars().last())
+            .any(|c| INNER_PUNCTUATION.contains(&c));
+        if starts_or_ends_with_punctuation || !username.chars().all(|c| self.is_allowed_char(c)) {
+            return Err(UsernameViolation::Characters);
+        }
+        Ok(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_words_and_prefixes() {
+        let policy = UsernamePolicy::default();
+        for username in [
+            "admin", "Admin", "SYSTEM", "ws", "guest", "guest_1", "Guest 12",
+        ] {
+            assert_eq!(
+                policy.check(username),
+                Err(UsernameViolation::Reserved),
+                "{username}"
+            );
+        }
+        assert_eq!(policy.check("administer"), Ok("administer".to_string()));
+
+        let policy = policy.reserve(["teacher"]).allow(["admin"]);
+        assert_eq!(policy.check("Teacher"), Err(UsernameViolation::Reserved));
+        assert_eq!(policy.check("admin"), Ok("admin".to_string()));
+        assert_eq!(policy.check("Admin"), Err(UsernameViolation::Reserved));
+    }
+
+    #[test]
+    fn test_usernames_are_normalized_before_they_are_checked() {
+        let policy = UsernamePolicy::default();
+        // fullwidth letters are NFKC equivalent to ASCII ones
+        assert_eq!(policy.check("ａｌｉｃｅ"), Ok("alice".to_string()));
+        assert_eq!(policy.check("ａｄｍｉｎ"), Err(UsernameViolation::Reserved));
+        // cyrillic lookalikes of a reserved word
+        assert_eq!(policy.check("аdmin"), Err(UsernameViolation::Reserved));
+        assert_eq!(skeleton("аlice"), skeleton("alice"));
+        assert_eq!(skeleton("ALICE"), skeleton("alice"));
+        assert_ne!(skeleton("alice"), skeleton("alicia"));
+    }
+
+    #[test]
+    fn test_unsafe_characters_are_rejected() {
+        let policy = UsernamePolicy::default();
+        for username in [
+            "../x",
+            "<script>",
+            "a b",
+            ".alice",
+            "alice-",
+            "_bob",
+            "bob\u{200b}",
+        ] {
+            assert_eq!(
+                policy.check(username),
+                Err(UsernameViolation::Characters),
+                "{username}"
+            );
+        }
+        for username in ["al", &"a".repeat(MAX_USERNAME_LENGTH + 1)] {
+            assert_eq!(policy.check(username), Err(UsernameViolation::Length));
+        }
+        assert_eq!(policy.check("jürgen"), Err(UsernameViolation::Characters));
+        assert_eq!(
+            policy.with_unicode(true).check("jürgen"),
+            Ok("jürgen".to_string())
+        );
+        assert_eq!(
+            UsernamePolicy::default().check("a.b-c_d"),
+            Ok("a.b-c_d".to_string())
+        );
+    }
+}
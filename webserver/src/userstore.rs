@@ -1,14 +1,27 @@
 use crate::canvas::store::{AccessLevel, CanvasId};
-use crate::persistence::{self, PersistEventMessage};
+use crate::clock::{MonotonicStamps, SharedClock};
+use crate::mailbox::{self, timed_atomic, DegradedMode, HandlerTrace};
+use crate::messages::{Locale, Message, MessageKey};
+use crate::persistence::{self, PersistEventMessage, ReplayIssue, ReplayIssueKind};
+use crate::preferences::UserPreferences;
+use crate::recovery;
+use crate::username::{self, UsernamePolicy, UsernameViolation};
 use actix::prelude::*;
+use actix_web::{error, http::header::ContentType, http::StatusCode, HttpResponse};
+use derive_more::Error;
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
 
-/// Event Store to persist user events
-/// Uses underlying persistence actor to save events
-/// This is a simple "database" that is fully loaded in memory once created
-/// This can be replaced by a real database later. This Store would then just be a gateway to the database
+// Event Store to persist user events
+// Uses underlying persistence actor to save events
+// This is a simple "database" that is fully loaded in memory once created
+// This can be replaced by a real database later. This Store would then just be a gateway to the database
 
 pub const USER_ID_ALPHABET: [char; 16] = [
     '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f',
@@ -32,6 +45,18 @@ pub struct User {
     pub email: String,
     pub username: String,
     pub password_hash: String,
+    /// millisecond timestamp of the last login, rebuilt from UserLoggedIn events
+    #[serde(skip)]
+    pub last_login_at: Option<u64>,
+    /// millisecond timestamp of the last authenticated request, only kept in memory
+    #[serde(skip)]
+    pub last_seen_at: Option<u64>,
+    /// JWTs with an older tv claim are rejected, rebuilt from UserTokenVersionBumped events
+    #[serde(skip)]
+    pub token_version: u64,
+    /// UI preferences, rebuilt from UserPreferencesChanged events
+    #[serde(skip)]
+    pub preferences: UserPreferences,
 }
 
 /// Simpler User can be used in the Application to "hide" the password hash
@@ -39,6 +64,7 @@ pub struct SimpleUser {
     pub id: UserId,
     pub username: String,
     pub email: String,
+    pub token_version: u64,
 }
 
 impl From<User> for SimpleUser {
@@ -47,6 +73,115 @@ impl From<User> for SimpleUser {
             id: user.id,
             username: user.username,
             email: user.email,
+            token_version: user.token_version,
+        }
+    }
+}
+
+/// Latest password reset token issued for a user, older tokens are replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordReset {
+    /// SHA-256 of the token, the token itself is never stored
+    pub token_hash: String,
+    /// millisecond timestamp
+    pub expires_at: u64,
+    pub consumed: bool,
+}
+
+impl PasswordReset {
+    fn accepts(&self, token_hash: &str, now_ms: u64) -> bool {
+        !self.consumed && now_ms < self.expires_at && self.token_hash == token_hash
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UserStoreError {
+    UserNotFound,
+    EmailTaken,
+    UsernameTaken,
+    /// username violates the UsernamePolicy or looks like the name of another user
+    UsernameRejected(#[error(not(source))] UsernameViolation),
+    /// no unused user id found, practically unreachable with nanoid
+    IdGenerationFailed,
+    /// carries the internal reason, logged but never part of a response
+    PersistenceFailed(#[error(not(source))] String),
+    /// unknown, forged, expired or used password reset token
+    PasswordResetInvalid,
+    /// store is read-only until its persistence caught up, see mailbox::DegradedMode
+    Degraded,
+    /// preferences changed since the version the client sent
+    PreferencesConflict,
+}
+
+impl UserStoreError {
+    pub fn persistence(reason: impl fmt::Display) -> Self {
+        UserStoreError::PersistenceFailed(reason.to_string())
+    }
+
+    /// Machine-readable code, part of JSON responses and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            UserStoreError::UserNotFound => "user_not_found",
+            UserStoreError::EmailTaken => "user_email_taken",
+            UserStoreError::UsernameTaken => "user_username_taken",
+            UserStoreError::UsernameRejected(violation) => violation.code(),
+            UserStoreError::IdGenerationFailed => "user_id_generation_failed",
+            UserStoreError::PersistenceFailed(_) => "user_persistence_failed",
+            UserStoreError::PasswordResetInvalid => "user_password_reset_invalid",
+            UserStoreError::Degraded => "user_store_read_only",
+            UserStoreError::PreferencesConflict => "user_preferences_conflict",
+        }
+    }
+
+    /// Internal diagnostic detail, see messages::LocalizeService
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            UserStoreError::PersistenceFailed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Localizable message, rendered per request by the LocalizeService
+    pub fn message(&self) -> Message {
+        match self {
+            UserStoreError::UserNotFound => Message::new(MessageKey::UnknownUser),
+            UserStoreError::EmailTaken => Message::new(MessageKey::EmailTaken),
+            UserStoreError::UsernameTaken => Message::new(MessageKey::UsernameTaken),
+            UserStoreError::UsernameRejected(violation) => violation.message(),
+            UserStoreError::IdGenerationFailed => Message::new(MessageKey::RegistrationFailed),
+            UserStoreError::PersistenceFailed(_) => Message::new(MessageKey::PersistenceFailed),
+            UserStoreError::PasswordResetInvalid => Message::new(MessageKey::PasswordResetInvalid),
+            UserStoreError::Degraded => Message::new(MessageKey::StoreReadOnly),
+            UserStoreError::PreferencesConflict => Message::new(MessageKey::PreferencesConflict),
+        }
+    }
+}
+
+/// Rendered in the default locale without the detail, responses are localized by the LocalizeService
+impl fmt::Display for UserStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message().render(Locale::default()))
+    }
+}
+
+impl error::ResponseError for UserStoreError {
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            UserStoreError::UserNotFound => StatusCode::NOT_FOUND,
+            UserStoreError::EmailTaken | UserStoreError::UsernameTaken => StatusCode::CONFLICT,
+            UserStoreError::UsernameRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserStoreError::IdGenerationFailed | UserStoreError::PersistenceFailed(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            UserStoreError::PasswordResetInvalid => StatusCode::BAD_REQUEST,
+            UserStoreError::Degraded => StatusCode::SERVICE_UNAVAILABLE,
+            UserStoreError::PreferencesConflict => StatusCode::PRECONDITION_FAILED,
         }
     }
 }
@@ -64,51 +199,345 @@ pub struct UserStore {
     // another possible solution would be to use Arc or Rc (as this actor is single-threaded and only one exists)
     users_email_lookup: HashMap<String, UserId>,
     users_username_lookup: HashMap<String, UserId>,
+    /// confusable skeleton of every username, see username::skeleton
+    users_skeleton_lookup: HashMap<String, UserId>,
+    password_resets: HashMap<UserId, PasswordReset>,
+    /// checked on registration, usernames in the eventlog are kept even if they violate it
+    username_policy: UsernamePolicy,
+
+    /// changes are rejected while the persistence is saturated
+    degraded: DegradedMode,
+    mailbox_capacity: usize,
+    /// handling times of the messages, see mailbox::HandlerTrace
+    handler_trace: Arc<HandlerTrace>,
+
+    /// timestamps of the persisted events, increasing even if the clock steps back
+    stamps: MonotonicStamps,
+
+    clock: SharedClock,
 }
 
-impl UserStore {
-    pub fn new(
-        event_persistence_recipient: Recipient<PersistEventMessage<UserStoreEvents>>,
-        saved_events: Vec<UserStoreEvents>,
-    ) -> Self {
-        let mut users_id_lookup = HashMap::new();
-        let mut users_email_lookup = HashMap::new();
-        let mut users_username_lookup = HashMap::new();
-
-        // events are applied in order, so we can just iterate over them
-        for event in saved_events {
-            match event {
-                UserStoreEvents::UserRegistered { user_id, user, .. } => {
-                    users_email_lookup.insert(user.email.clone(), user_id.clone());
-                    users_username_lookup.insert(user.username.clone(), user_id.clone());
-                    users_id_lookup.insert(user_id, user);
+/// In memory state of the UserStore, built by replaying the eventlog
+#[derive(Default)]
+pub struct UserStoreState {
+    pub(crate) users_id_lookup: HashMap<UserId, User>,
+    pub(crate) users_email_lookup: HashMap<String, UserId>,
+    pub(crate) users_username_lookup: HashMap<String, UserId>,
+    pub(crate) users_skeleton_lookup: HashMap<String, UserId>,
+    pub(crate) password_resets: HashMap<UserId, PasswordReset>,
+}
+
+/// Drops the skeleton of the username, unless it belongs to another user
+fn remove_skeleton(skeleton_lookup: &mut HashMap<String, UserId>, username: &str, user_id: &str) {
+    let skeleton = username::skeleton(username);
+    if skeleton_lookup
+        .get(&skeleton)
+        .is_some_and(|id| id == user_id)
+    {
+        skeleton_lookup.remove(&skeleton);
+    }
+}
+
+impl UserStoreState {
+    /// Usernames registered before the policy or a reserved word existed, they are kept as they are
+    pub fn username_violations(
+        &self,
+        policy: &UsernamePolicy,
+    ) -> Vec<(UserId, String, UsernameViolation)> {
+        let mut violations: Vec<_> = self
+            .users_id_lookup
+            .values()
+            .filter_map(|user| {
+                let skeleton_owner = self
+                    .users_skeleton_lookup
+                    .get(&username::skeleton(&user.username));
+                let violation = match policy.check(&user.username) {
+                    Err(violation) => violation,
+                    Ok(normalized) if normalized != user.username => UsernameViolation::Characters,
+                    Ok(_) if skeleton_owner != Some(&user.id) => UsernameViolation::Confusable,
+                    Ok(_) => return None,
+                };
+                Some((user.id.clone(), user.username.clone(), violation))
+            })
+            .collect();
+        violations.sort_by(|a, b| a.0.cmp(&b.0));
+        violations
+    }
+}
+
+/// Applies all events in order and returns the resulting state
+/// Inconsistencies do not abort the replay, events referencing unknown users are skipped
+/// Used by the UserStore on startup and by the maintenance tooling
+pub fn replay_events(
+    events: impl IntoIterator<Item = UserStoreEvents>,
+) -> (UserStoreState, Vec<ReplayIssue>) {
+    let mut state = UserStoreState::default();
+    let mut issues = Vec::new();
+
+    // events are applied in order, so we can just iterate over them
+    for (index, event) in events.into_iter().enumerate() {
+        match event {
+            UserStoreEvents::UserRegistered { user_id, user, .. } => {
+                if state.users_id_lookup.contains_key(&user_id) {
+                    issues.push(ReplayIssue::event(
+                        index,
+                        ReplayIssueKind::Duplicate,
+                        format!("User {user_id} registered more than once"),
+                    ));
                 }
-                UserStoreEvents::UserChanged { user_id, user, .. } => {
-                    users_email_lookup.insert(user.email.clone(), user_id.clone());
-                    users_username_lookup.insert(user.username.clone(), user_id.clone());
-                    users_id_lookup.insert(user_id, user);
+                if state
+                    .users_email_lookup
+                    .get(&user.email)
+                    .is_some_and(|id| id != &user_id)
+                {
+                    issues.push(ReplayIssue::event(
+                        index,
+                        ReplayIssueKind::Duplicate,
+                        format!("User {user_id} registered with email of another user"),
+                    ));
                 }
-                UserStoreEvents::UserDeleted { user_id, .. } => {
-                    if let Some(user) = users_id_lookup.remove(&user_id) {
-                        users_email_lookup.remove(&user.email);
-                        users_username_lookup.remove(&user.username);
+                if state
+                    .users_username_lookup
+                    .get(&user.username)
+                    .is_some_and(|id| id != &user_id)
+                {
+                    issues.push(ReplayIssue::event(
+                        index,
+                        ReplayIssueKind::Duplicate,
+                        format!(
+                            "User {user_id} registered with username {} of another user",
+                            user.username
+                        ),
+                    ));
+                }
+                state
+                    .users_email_lookup
+                    .insert(user.email.clone(), user_id.clone());
+                state
+                    .users_username_lookup
+                    .insert(user.username.clone(), user_id.clone());
+                // the first user keeps a skeleton shared by grandfathered usernames
+                state
+                    .users_skeleton_lookup
+                    .entry(username::skeleton(&user.username))
+                    .or_insert_with(|| user_id.clone());
+                state.users_id_lookup.insert(user_id, user);
+            }
+            UserStoreEvents::UserChanged {
+                user_id, mut user, ..
+            } => {
+                let Some(previous) = state.users_id_lookup.get(&user_id) else {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Changed user {user_id} does not exist"),
+                    ));
+                    continue;
+                };
+                // drop lookups for the old email and username
+                state.users_email_lookup.remove(&previous.email);
+                state.users_username_lookup.remove(&previous.username);
+                remove_skeleton(
+                    &mut state.users_skeleton_lookup,
+                    &previous.username,
+                    &user_id,
+                );
+                // activity is not part of the event
+                user.last_login_at = previous.last_login_at;
+                user.last_seen_at = previous.last_seen_at;
+                user.token_version = previous.token_version;
+                user.preferences = previous.preferences.clone();
+                state
+                    .users_email_lookup
+                    .insert(user.email.clone(), user_id.clone());
+                state
+                    .users_username_lookup
+                    .insert(user.username.clone(), user_id.clone());
+                state
+                    .users_skeleton_lookup
+                    .entry(username::skeleton(&user.username))
+                    .or_insert_with(|| user_id.clone());
+                state.users_id_lookup.insert(user_id, user);
+            }
+            UserStoreEvents::UserDeleted { user_id, .. } => {
+                if let Some(user) = state.users_id_lookup.remove(&user_id) {
+                    state.users_email_lookup.remove(&user.email);
+                    state.users_username_lookup.remove(&user.username);
+                    remove_skeleton(&mut state.users_skeleton_lookup, &user.username, &user_id);
+                    state.password_resets.remove(&user_id);
+                } else {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Deleted user {user_id} does not exist"),
+                    ));
+                }
+            }
+            UserStoreEvents::UserLoggedIn { user_id, timestamp } => {
+                // logins are frequent, only update the timestamp in place
+                match state.users_id_lookup.get_mut(&user_id) {
+                    Some(user) => {
+                        let last_login_at =
+                            user.last_login_at.map_or(timestamp, |t| t.max(timestamp));
+                        user.last_login_at = Some(last_login_at);
+                        user.last_seen_at = Some(last_login_at);
                     }
+                    None => issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Unknown user {user_id} logged in"),
+                    )),
                 }
-                _ => (),
             }
+            UserStoreEvents::UserTokenVersionBumped {
+                user_id,
+                token_version,
+                ..
+            } => match state.users_id_lookup.get_mut(&user_id) {
+                // never go back, a late event must not revive revoked tokens
+                Some(user) => user.token_version = user.token_version.max(token_version),
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Token version of unknown user {user_id} bumped"),
+                )),
+            },
+            UserStoreEvents::UserPreferencesChanged {
+                user_id,
+                preferences,
+                ..
+            } => match state.users_id_lookup.get_mut(&user_id) {
+                Some(user) => {
+                    user.preferences = UserPreferences {
+                        preferences,
+                        version: user.preferences.version + 1,
+                    }
+                }
+                None => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Preferences of unknown user {user_id} changed"),
+                )),
+            },
+            UserStoreEvents::PasswordResetRequested {
+                user_id,
+                token_hash,
+                expires_at,
+                ..
+            } => {
+                if !state.users_id_lookup.contains_key(&user_id) {
+                    issues.push(ReplayIssue::skipped(
+                        index,
+                        format!("Password reset of unknown user {user_id} requested"),
+                    ));
+                    continue;
+                }
+                // a newer token replaces the outstanding one
+                state.password_resets.insert(
+                    user_id,
+                    PasswordReset {
+                        token_hash,
+                        expires_at,
+                        consumed: false,
+                    },
+                );
+            }
+            UserStoreEvents::PasswordResetCompleted {
+                user_id,
+                token_hash,
+                ..
+            } => match state.password_resets.get_mut(&user_id) {
+                Some(reset) if reset.token_hash == token_hash => reset.consumed = true,
+                _ => issues.push(ReplayIssue::skipped(
+                    index,
+                    format!("Completed password reset of user {user_id} was not requested"),
+                )),
+            },
+            _ => (),
         }
+    }
 
-        Self {
+    (state, issues)
+}
+
+impl UserStore {
+    /// Replays the saved events, the issues found are returned for the caller to decide on, see ReplayMode
+    pub fn new(
+        event_persistence_recipient: Recipient<PersistEventMessage<UserStoreEvents>>,
+        saved_events: Vec<UserStoreEvents>,
+        clock: SharedClock,
+    ) -> (Self, Vec<ReplayIssue>) {
+        let (state, issues) = replay_events(saved_events);
+
+        let store = Self {
             event_persistence_recipient,
-            users_id_lookup,
-            users_username_lookup,
-            users_email_lookup,
+            users_id_lookup: state.users_id_lookup,
+            users_username_lookup: state.users_username_lookup,
+            users_email_lookup: state.users_email_lookup,
+            users_skeleton_lookup: state.users_skeleton_lookup,
+            password_resets: state.password_resets,
+            username_policy: UsernamePolicy::default(),
+            degraded: DegradedMode::default(),
+            mailbox_capacity: mailbox::DEFAULT_MAILBOX_CAPACITY,
+            handler_trace: HandlerTrace::new("user_store", mailbox::DEFAULT_SLOW_HANDLER_THRESHOLD),
+            stamps: MonotonicStamps::new(clock.clone()),
+            clock,
+        };
+        (store, issues)
+    }
+
+    /// Bounds the mailbox and shares the read-only mode switched by the MailboxProbe of the persistence
+    pub fn with_mailbox(mut self, capacity: usize, degraded: DegradedMode) -> Self {
+        self.mailbox_capacity = capacity;
+        self.degraded = degraded;
+        self
+    }
+
+    /// Policy new usernames are checked against, the default one otherwise
+    pub fn with_username_policy(mut self, username_policy: UsernamePolicy) -> Self {
+        self.username_policy = username_policy;
+        self
+    }
+
+    /// Usernames in the eventlog violating the policy, listed on startup
+    pub fn username_violations(&self) -> Vec<(UserId, String, UsernameViolation)> {
+        let state = UserStoreState {
+            users_id_lookup: self.users_id_lookup.clone(),
+            users_skeleton_lookup: self.users_skeleton_lookup.clone(),
+            ..UserStoreState::default()
+        };
+        state.username_violations(&self.username_policy)
+    }
+
+    /// Ids of all users, the CanvasStore checks its claims against them on startup
+    pub fn user_ids(&self) -> HashSet<UserId> {
+        self.users_id_lookup.keys().cloned().collect()
+    }
+
+    /// Shares the trace of the handlers, listed by the ActorGauges
+    pub fn with_handler_trace(mut self, handler_trace: Arc<HandlerTrace>) -> Self {
+        self.handler_trace = handler_trace;
+        self
+    }
+
+    /// Rejects changes while the store is read-only, see mailbox::DegradedMode
+    fn check_writable(&self) -> Result<(), UserStoreError> {
+        if self.degraded.is_active() {
+            return Err(UserStoreError::Degraded);
         }
+        Ok(())
+    }
+
+    /// User of an email or, if no email matches, of a username
+    fn find_user(&self, username_email: &str) -> Option<&User> {
+        self.users_email_lookup
+            .get(username_email)
+            .or_else(|| self.users_username_lookup.get(username_email))
+            .and_then(|id| self.users_id_lookup.get(id))
     }
 }
 
 impl Actor for UserStore {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
+    }
 }
 
 /// Events that will be used to persist the internal state of the UserStore
@@ -143,42 +572,92 @@ pub enum UserStoreEvents {
         user_id: UserId,
         canvas_id: CanvasId,
     },
+    /// User logged in successfully
+    UserLoggedIn { timestamp: u64, user_id: UserId },
+    /// User logged out everywhere, JWTs issued before are no longer accepted
+    UserTokenVersionBumped {
+        timestamp: u64,
+        user_id: UserId,
+        token_version: u64,
+    },
+    /// UI preferences of the user replaced as a whole, each change counts up their version
+    UserPreferencesChanged {
+        timestamp: u64,
+        user_id: UserId,
+        preferences: serde_json::Map<String, serde_json::Value>,
+    },
+    /// Password reset token issued, replaces older tokens of the user
+    PasswordResetRequested {
+        timestamp: u64,
+        user_id: UserId,
+        token_hash: String,
+        expires_at: u64,
+    },
+    /// Password reset token used, the new password is persisted as UserChanged
+    PasswordResetCompleted {
+        timestamp: u64,
+        user_id: UserId,
+        token_hash: String,
+    },
 }
 
 #[derive(Message)]
-#[rtype(result = "Result<User, std::io::Error>")]
+#[rtype(result = "Result<User, UserStoreError>")]
 pub struct RegisterUserMessage {
     pub user: RegisterUser,
 }
 
 impl Handler<RegisterUserMessage> for UserStore {
-    type Result = AtomicResponse<Self, Result<User, std::io::Error>>;
+    type Result = AtomicResponse<Self, Result<User, UserStoreError>>;
 
     // Handles registration of a new user
     // This function is atomic, meaning that the actor will not be able to handle any other messages until the response is resolved
     fn handle(&mut self, msg: RegisterUserMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RegisterUserMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
         if self.users_email_lookup.contains_key(&msg.user.email) {
-            return AtomicResponse::new(Box::pin(
-                async move {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "User already exists",
-                    ))
-                }
-                .into_actor(self),
-            ));
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::EmailTaken) }.into_actor(self)),
+            );
         }
 
-        if self.users_username_lookup.contains_key(&msg.user.username) {
-            return AtomicResponse::new(Box::pin(
-                async move {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Username already taken",
-                    ))
-                }
-                .into_actor(self),
-            ));
+        let username = match self.username_policy.check(&msg.user.username) {
+            Ok(username) => username,
+            Err(violation) => {
+                return timed_atomic(
+                    timer,
+                    Box::pin(
+                        async move { Err(UserStoreError::UsernameRejected(violation)) }
+                            .into_actor(self),
+                    ),
+                )
+            }
+        };
+
+        if self.users_username_lookup.contains_key(&username) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::UsernameTaken) }.into_actor(self)),
+            );
+        }
+
+        let skeleton = username::skeleton(&username);
+        if self.users_skeleton_lookup.contains_key(&skeleton) {
+            return timed_atomic(
+                timer,
+                Box::pin(
+                    async move {
+                        Err(UserStoreError::UsernameRejected(
+                            UsernameViolation::Confusable,
+                        ))
+                    }
+                    .into_actor(self),
+                ),
+            );
         }
 
         let mut iteration = 0;
@@ -188,27 +667,28 @@ impl Handler<RegisterUserMessage> for UserStore {
             iteration += 1;
             if iteration > 10 {
                 // not sure if this is the nicest way
-                return AtomicResponse::new(Box::pin(
-                    async move {
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to generate unique user id",
-                        ))
-                    }
-                    .into_actor(self),
-                ));
+                return timed_atomic(
+                    timer,
+                    Box::pin(
+                        async move { Err(UserStoreError::IdGenerationFailed) }.into_actor(self),
+                    ),
+                );
             }
         }
 
         let user = User {
             id: id.clone(),
             email: msg.user.email,
-            username: msg.user.username,
+            username,
             password_hash: msg.user.password_hash,
+            last_login_at: None,
+            last_seen_at: None,
+            token_version: 0,
+            preferences: UserPreferences::default(),
         };
 
         let event = UserStoreEvents::UserRegistered {
-            timestamp: chrono::Utc::now().timestamp_millis() as u64,
+            timestamp: self.stamps.stamp_ms(),
             user_id: id.clone(),
             user: user.clone(),
         };
@@ -217,39 +697,38 @@ impl Handler<RegisterUserMessage> for UserStore {
         // this is done to prevent any race conditions creating multiple users with the same id / username / email
         self.users_username_lookup
             .insert(user.username.clone(), id.clone());
+        self.users_skeleton_lookup.insert(skeleton, id.clone());
         self.users_email_lookup
             .insert(user.email.clone(), id.clone());
         self.users_id_lookup.insert(id, user.clone());
 
         // atomic response means that the actor will not be able to handle any other messages until the response is resolved
-        AtomicResponse::new(Box::pin(
-            self.event_persistence_recipient
-                .send(persistence::PersistEventMessage(event))
-                .into_actor(self)
-                .map(|c, userstore, _| {
-                    let user_for_error = user.clone(); // this whole future thing already took to long to figure out, just copy user for error handling
-                    match c {
-                        Ok(Ok(_)) => Ok(user),
-                        Ok(Err(_)) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to save user registration event",
-                        )),
-                        Err(_) => Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Failed to save user registration event",
-                        )),
-                    }
-                    .map_err(|error| {
-                        // undo changes if event could not be saved
-                        userstore
-                            .users_username_lookup
-                            .remove(&user_for_error.username);
-                        userstore.users_email_lookup.remove(&user_for_error.email);
-                        userstore.users_id_lookup.remove(&user_for_error.id);
-                        error
-                    })
-                }),
-        ))
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(|c, userstore, _| {
+                        let skeleton = username::skeleton(&user.username);
+                        let user_for_error = user.clone(); // this whole future thing already took to long to figure out, just copy user for error handling
+                        match c {
+                            Ok(Ok(_)) => Ok(user),
+                            Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                            Err(e) => Err(UserStoreError::persistence(e)),
+                        }
+                        .inspect_err(|_error| {
+                            // undo changes if event could not be saved
+                            userstore
+                                .users_username_lookup
+                                .remove(&user_for_error.username);
+                            userstore.users_skeleton_lookup.remove(&skeleton);
+                            userstore.users_email_lookup.remove(&user_for_error.email);
+                            userstore.users_id_lookup.remove(&user_for_error.id);
+                        })
+                    }),
+            ),
+        )
     }
 }
 
@@ -264,31 +743,870 @@ impl Handler<GetUserMessage> for UserStore {
     type Result = Option<User>;
 
     fn handle(&mut self, msg: GetUserMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUserMessage>();
         if let Some(user_id) = msg.user_id {
             return self.users_id_lookup.get(&user_id).cloned();
         }
 
         msg.username_email
-            .map(|username_email| {
-                self.users_email_lookup
-                    .get(&username_email) // check using email
-                    .map(|id| {
-                        self.users_id_lookup
-                            .get(id)
-                            .map(|user| Some(user.clone()))
-                            .unwrap_or(None)
-                    })
-                    .unwrap_or_else(|| // not found using email
-                self.users_username_lookup
-                    .get(&username_email) // now check the username
-                    .map(|id|
-                        self.users_id_lookup
-                            .get(id)
-                            .map(|user| Some(user.clone()))
-                            .unwrap_or(None)
-                    )
-                    .unwrap_or(None)) // not found
+            .and_then(|username_email| self.find_user(&username_email))
+            .cloned()
+    }
+}
+
+/// Users of many usernames or emails in one round trip, in the order asked, None for unknown ones
+#[derive(Message)]
+#[rtype(result = "Vec<Option<User>>")]
+pub struct GetUsersMessage {
+    pub usernames_emails: Vec<String>,
+}
+
+impl Handler<GetUsersMessage> for UserStore {
+    type Result = MessageResult<GetUsersMessage>;
+
+    fn handle(&mut self, msg: GetUsersMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUsersMessage>();
+        MessageResult(
+            msg.usernames_emails
+                .iter()
+                .map(|username_email| self.find_user(username_email).cloned())
+                .collect(),
+        )
+    }
+}
+
+/// Usernames of many users in one round trip, unknown users are missing from the result
+#[derive(Message)]
+#[rtype(result = "HashMap<UserId, String>")]
+pub struct GetUsernamesMessage {
+    pub user_ids: Vec<UserId>,
+}
+
+impl Handler<GetUsernamesMessage> for UserStore {
+    type Result = MessageResult<GetUsernamesMessage>;
+
+    fn handle(&mut self, msg: GetUsernamesMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetUsernamesMessage>();
+        MessageResult(
+            msg.user_ids
+                .into_iter()
+                .filter_map(|user_id| {
+                    let username = self.users_id_lookup.get(&user_id)?.username.clone();
+                    Some((user_id, username))
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<bool, UserStoreError>")]
+pub struct UpdatePasswordHashMessage {
+    pub user_id: UserId,
+    /// hash the new one was derived from, the update is dropped if the password changed meanwhile
+    pub expected_hash: String,
+    pub password_hash: String,
+}
+
+impl Handler<UpdatePasswordHashMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<bool, UserStoreError>>;
+
+    // Replaces the password hash of a user, e.g. after rehashing with stronger parameters
+    // Compare and swap, answers false without a change if the stored hash is not the expected one
+    // A reset or password change between the login and the rehash would otherwise be undone
+    // The state is only changed once the UserChanged event is persisted
+    fn handle(&mut self, msg: UpdatePasswordHashMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<UpdatePasswordHashMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let Some(user) = self.users_id_lookup.get(&msg.user_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::UserNotFound) }.into_actor(self)),
+            );
+        };
+        if user.password_hash != msg.expected_hash {
+            return timed_atomic(timer, Box::pin(async move { Ok(false) }.into_actor(self)));
+        }
+
+        let user = User {
+            password_hash: msg.password_hash,
+            ..user.clone()
+        };
+
+        let event = UserStoreEvents::UserChanged {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: user.id.clone(),
+            user: user.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            userstore.users_id_lookup.insert(user.id.clone(), user);
+                            Ok(true)
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), UserStoreError>")]
+pub struct RecordLoginMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<RecordLoginMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<(), UserStoreError>>;
+
+    fn handle(&mut self, msg: RecordLoginMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<RecordLoginMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let timestamp = self.stamps.stamp_ms();
+        let event = UserStoreEvents::UserLoggedIn {
+            timestamp,
+            user_id: msg.user_id.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            if let Some(user) = userstore.users_id_lookup.get_mut(&msg.user_id) {
+                                user.last_login_at = Some(timestamp);
+                                user.last_seen_at = Some(timestamp);
+                            }
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Current token version of the user, None if the user does not exist
+/// Asked by the AuthenticationMiddleware on every authenticated request
+#[derive(Message)]
+#[rtype(result = "Option<u64>")]
+pub struct GetTokenVersionMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<GetTokenVersionMessage> for UserStore {
+    type Result = Option<u64>;
+
+    fn handle(&mut self, msg: GetTokenVersionMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<GetTokenVersionMessage>();
+        self.users_id_lookup
+            .get(&msg.user_id)
+            .map(|user| user.token_version)
+    }
+}
+
+/// Invalidates every JWT of the user, resolves to the new token version
+#[derive(Message)]
+#[rtype(result = "Result<u64, UserStoreError>")]
+pub struct BumpTokenVersionMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<BumpTokenVersionMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<u64, UserStoreError>>;
+
+    fn handle(&mut self, msg: BumpTokenVersionMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<BumpTokenVersionMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let Some(user) = self.users_id_lookup.get(&msg.user_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::UserNotFound) }.into_actor(self)),
+            );
+        };
+
+        let token_version = user.token_version + 1;
+        let event = UserStoreEvents::UserTokenVersionBumped {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: msg.user_id.clone(),
+            token_version,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            if let Some(user) = userstore.users_id_lookup.get_mut(&msg.user_id) {
+                                user.token_version = token_version;
+                            }
+                            Ok(token_version)
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Deletes the user, its JWTs are rejected from now on as its token version is gone
+/// Memberships are kept in the CanvasStore, the deletion flow removes them with PurgeUserMessage
+#[derive(Message)]
+#[rtype(result = "Result<(), UserStoreError>")]
+pub struct DeleteUserMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<DeleteUserMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<(), UserStoreError>>;
+
+    fn handle(&mut self, msg: DeleteUserMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<DeleteUserMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        if !self.users_id_lookup.contains_key(&msg.user_id) {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::UserNotFound) }.into_actor(self)),
+            );
+        }
+
+        let event = UserStoreEvents::UserDeleted {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: msg.user_id.clone(),
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            if let Some(user) = userstore.users_id_lookup.remove(&msg.user_id) {
+                                userstore.users_email_lookup.remove(&user.email);
+                                userstore.users_username_lookup.remove(&user.username);
+                                remove_skeleton(
+                                    &mut userstore.users_skeleton_lookup,
+                                    &user.username,
+                                    &msg.user_id,
+                                );
+                            }
+                            userstore.password_resets.remove(&msg.user_id);
+                            Ok(())
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Replaces the UI preferences of the user, resolves to the stored ones
+/// With an expected version the change fails with PreferencesConflict if they changed in between
+/// The preferences are validated by the caller, see preferences::validate
+#[derive(Message)]
+#[rtype(result = "Result<UserPreferences, UserStoreError>")]
+pub struct SetUserPreferencesMessage {
+    pub user_id: UserId,
+    pub preferences: serde_json::Map<String, serde_json::Value>,
+    pub expected_version: Option<u64>,
+}
+
+impl Handler<SetUserPreferencesMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<UserPreferences, UserStoreError>>;
+
+    fn handle(&mut self, msg: SetUserPreferencesMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<SetUserPreferencesMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let Some(user) = self.users_id_lookup.get(&msg.user_id) else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::UserNotFound) }.into_actor(self)),
+            );
+        };
+        if msg
+            .expected_version
+            .is_some_and(|version| version != user.preferences.version)
+        {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::PreferencesConflict) }.into_actor(self)),
+            );
+        }
+
+        let preferences = UserPreferences {
+            preferences: msg.preferences,
+            version: user.preferences.version + 1,
+        };
+        let event = UserStoreEvents::UserPreferencesChanged {
+            timestamp: self.stamps.stamp_ms(),
+            user_id: msg.user_id.clone(),
+            preferences: preferences.preferences.clone(),
+        };
+
+        // atomic, a second change waits and is compared against this version
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            if let Some(user) = userstore.users_id_lookup.get_mut(&msg.user_id) {
+                                user.preferences = preferences.clone();
+                            }
+                            Ok(preferences)
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Token delivered to the user, only returned once
+pub struct IssuedPasswordReset {
+    pub user_id: UserId,
+    pub email: String,
+    pub token: String,
+}
+
+/// Issues a password reset token for the user, None if no user has the username or email
+#[derive(Message)]
+#[rtype(result = "Result<Option<IssuedPasswordReset>, UserStoreError>")]
+pub struct IssuePasswordResetMessage {
+    pub username_email: String,
+    pub ttl: Duration,
+}
+
+impl Handler<IssuePasswordResetMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<Option<IssuedPasswordReset>, UserStoreError>>;
+
+    fn handle(&mut self, msg: IssuePasswordResetMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<IssuePasswordResetMessage>();
+        let user_id = self
+            .users_email_lookup
+            .get(&msg.username_email)
+            .or_else(|| self.users_username_lookup.get(&msg.username_email));
+        let Some(user) = user_id.and_then(|user_id| self.users_id_lookup.get(user_id)) else {
+            return timed_atomic(timer, Box::pin(async move { Ok(None) }.into_actor(self)));
+        };
+
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let token = recovery::generate_token(&user.id);
+        let timestamp = self.stamps.stamp_ms();
+        let reset = PasswordReset {
+            token_hash: recovery::hash_token(&token),
+            expires_at: timestamp + msg.ttl.as_millis() as u64,
+            consumed: false,
+        };
+        let event = UserStoreEvents::PasswordResetRequested {
+            timestamp,
+            user_id: user.id.clone(),
+            token_hash: reset.token_hash.clone(),
+            expires_at: reset.expires_at,
+        };
+        let issued = IssuedPasswordReset {
+            user_id: user.id.clone(),
+            email: user.email.clone(),
+            token,
+        };
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                self.event_persistence_recipient
+                    .send(persistence::PersistEventMessage(event))
+                    .into_actor(self)
+                    .map(move |result, userstore, _| match result {
+                        Ok(Ok(_)) => {
+                            userstore
+                                .password_resets
+                                .insert(issued.user_id.clone(), reset);
+                            Ok(Some(issued))
+                        }
+                        Ok(Err(e)) => Err(UserStoreError::persistence(e)),
+                        Err(e) => Err(UserStoreError::persistence(e)),
+                    }),
+            ),
+        )
+    }
+}
+
+/// Sets the password of the user the reset token was issued for, resolves to the user id
+/// The token is consumed and every JWT of the user is revoked
+/// Unknown, forged, expired and used tokens fail with PasswordResetInvalid
+#[derive(Message)]
+#[rtype(result = "Result<UserId, UserStoreError>")]
+pub struct CompletePasswordResetMessage {
+    pub token: String,
+    pub password_hash: String,
+}
+
+impl Handler<CompletePasswordResetMessage> for UserStore {
+    type Result = AtomicResponse<Self, Result<UserId, UserStoreError>>;
+
+    fn handle(&mut self, msg: CompletePasswordResetMessage, _: &mut Self::Context) -> Self::Result {
+        let timer = self.handler_trace.start::<CompletePasswordResetMessage>();
+        if let Err(e) = self.check_writable() {
+            return timed_atomic(timer, Box::pin(async move { Err(e) }.into_actor(self)));
+        }
+
+        let token_hash = recovery::hash_token(&msg.token);
+        let timestamp = self.stamps.stamp_ms();
+        let user = recovery::verify_token(&msg.token)
+            .filter(|user_id| {
+                self.password_resets
+                    .get(user_id)
+                    .is_some_and(|reset| reset.accepts(&token_hash, timestamp))
             })
-            .unwrap_or_default()
+            .and_then(|user_id| self.users_id_lookup.get(&user_id));
+        let Some(user) = user else {
+            return timed_atomic(
+                timer,
+                Box::pin(async move { Err(UserStoreError::PasswordResetInvalid) }.into_actor(self)),
+            );
+        };
+
+        let user = User {
+            password_hash: msg.password_hash,
+            ..user.clone()
+        };
+        let token_version = user.token_version + 1;
+        // consuming the token comes first, a failure afterwards never leaves it usable
+        let events = [
+            UserStoreEvents::PasswordResetCompleted {
+                timestamp,
+                user_id: user.id.clone(),
+                token_hash,
+            },
+            UserStoreEvents::UserChanged {
+                timestamp,
+                user_id: user.id.clone(),
+                user: user.clone(),
+            },
+            UserStoreEvents::UserTokenVersionBumped {
+                timestamp,
+                user_id: user.id.clone(),
+                token_version,
+            },
+        ];
+        let event_persistence_recipient = self.event_persistence_recipient.clone();
+
+        timed_atomic(
+            timer,
+            Box::pin(
+                async move {
+                    let mut persisted = 0;
+                    for event in events {
+                        let failure = match event_persistence_recipient
+                            .send(persistence::PersistEventMessage(event))
+                            .await
+                        {
+                            Ok(Ok(_)) => {
+                                persisted += 1;
+                                continue;
+                            }
+                            Ok(Err(e)) => UserStoreError::persistence(e),
+                            Err(e) => UserStoreError::persistence(e),
+                        };
+                        return (persisted, Some(failure));
+                    }
+                    (persisted, None)
+                }
+                .into_actor(self)
+                .map(move |(persisted, failure), userstore, _| {
+                    // apply what was persisted, replay arrives at the same state
+                    let user_id = user.id.clone();
+                    if persisted >= 1 {
+                        if let Some(reset) = userstore.password_resets.get_mut(&user_id) {
+                            reset.consumed = true;
+                        }
+                    }
+                    if persisted >= 2 {
+                        userstore.users_id_lookup.insert(user_id.clone(), user);
+                    }
+                    if let Some(failure) = failure {
+                        return Err(failure);
+                    }
+                    if let Some(user) = userstore.users_id_lookup.get_mut(&user_id) {
+                        user.token_version = token_version;
+                    }
+                    Ok(user_id)
+                }),
+            ),
+        )
+    }
+}
+
+/// Marks the user as active, only kept in memory
+/// Send by the AuthenticationMiddleware, debounced by UserActivityTracker
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TouchUserMessage {
+    pub user_id: UserId,
+}
+
+impl Handler<TouchUserMessage> for UserStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: TouchUserMessage, _: &mut Self::Context) -> Self::Result {
+        let _timer = self.handler_trace.start::<TouchUserMessage>();
+        if let Some(user) = self.users_id_lookup.get_mut(&msg.user_id) {
+            user.last_seen_at = Some(self.clock.now_ms());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered(user_id: &str) -> UserStoreEvents {
+        UserStoreEvents::UserRegistered {
+            timestamp: 0,
+            user_id: user_id.to_string(),
+            user: User {
+                id: user_id.to_string(),
+                email: format!("{user_id}@example.com"),
+                username: user_id.to_string(),
+                password_hash: String::new(),
+                last_login_at: None,
+                last_seen_at: None,
+                token_version: 0,
+                preferences: UserPreferences::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_replay_sets_latest_login() {
+        let events = vec![
+            registered("user"),
+            UserStoreEvents::UserLoggedIn {
+                timestamp: 10,
+                user_id: "user".to_string(),
+            },
+            UserStoreEvents::UserLoggedIn {
+                timestamp: 30,
+                user_id: "user".to_string(),
+            },
+            // written late, must not move the last login back
+            UserStoreEvents::UserLoggedIn {
+                timestamp: 20,
+                user_id: "user".to_string(),
+            },
+            // activity survives a full overwrite of the user
+            UserStoreEvents::UserChanged {
+                timestamp: 40,
+                user_id: "user".to_string(),
+                user: match registered("user") {
+                    UserStoreEvents::UserRegistered { user, .. } => user,
+                    _ => unreachable!(),
+                },
+            },
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert!(issues.is_empty());
+
+        let user = &state.users_id_lookup["user"];
+        assert_eq!(user.last_login_at, Some(30));
+        assert_eq!(user.last_seen_at, Some(30));
+    }
+
+    #[test]
+    fn test_replay_keeps_latest_token_version() {
+        let bumped = |token_version| UserStoreEvents::UserTokenVersionBumped {
+            timestamp: 0,
+            user_id: "user".to_string(),
+            token_version,
+        };
+        let events = vec![
+            registered("user"),
+            bumped(1),
+            bumped(2),
+            // written late, must not revive tokens of version 1
+            bumped(1),
+            UserStoreEvents::UserChanged {
+                timestamp: 40,
+                user_id: "user".to_string(),
+                user: match registered("user") {
+                    UserStoreEvents::UserRegistered { user, .. } => user,
+                    _ => unreachable!(),
+                },
+            },
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert!(issues.is_empty());
+        assert_eq!(state.users_id_lookup["user"].token_version, 2);
+    }
+
+    #[test]
+    fn test_replay_restores_password_resets() {
+        let requested = |token_hash: &str, user_id: &str| UserStoreEvents::PasswordResetRequested {
+            timestamp: 0,
+            user_id: user_id.to_string(),
+            token_hash: token_hash.to_string(),
+            expires_at: 100,
+        };
+        let completed = |token_hash: &str, user_id: &str| UserStoreEvents::PasswordResetCompleted {
+            timestamp: 10,
+            user_id: user_id.to_string(),
+            token_hash: token_hash.to_string(),
+        };
+        let events = vec![
+            registered("user"),
+            registered("other"),
+            requested("first", "user"),
+            completed("first", "user"),
+            // a newer token replaces the consumed one
+            requested("second", "user"),
+            requested("outstanding", "other"),
+            // completing a replaced token is an issue, the outstanding one stays usable
+            completed("first", "other"),
+            requested("ghost", "ghost"),
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert_eq!(issues.len(), 2);
+        assert!(issues.iter().all(|issue| issue.skipped));
+
+        let user_reset = &state.password_resets["user"];
+        assert_eq!(user_reset.token_hash, "second");
+        assert!(user_reset.accepts("second", 99));
+        assert!(!user_reset.accepts("second", 100));
+        assert!(!user_reset.accepts("first", 0));
+        assert!(state.password_resets["other"].accepts("outstanding", 0));
+        assert!(!state.password_resets.contains_key("ghost"));
+
+        let (state, _) = replay_events(vec![
+            registered("user"),
+            requested("first", "user"),
+            completed("first", "user"),
+        ]);
+        assert!(state.password_resets["user"].consumed);
+    }
+
+    #[test]
+    fn test_replay_records_issues_of_unknown_users() {
+        let events = vec![
+            registered("user"),
+            UserStoreEvents::UserDeleted {
+                timestamp: 10,
+                user_id: "ghost".to_string(),
+            },
+            registered("user"),
+            UserStoreEvents::UserLoggedIn {
+                timestamp: 20,
+                user_id: "ghost".to_string(),
+            },
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert_eq!(state.users_id_lookup.len(), 1);
+        let summary: Vec<_> = issues
+            .iter()
+            .map(|issue| (issue.event_index, issue.kind, issue.skipped))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(1), ReplayIssueKind::UnknownReference, true),
+                (Some(2), ReplayIssueKind::Duplicate, false),
+                (Some(3), ReplayIssueKind::UnknownReference, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_grandfathers_usernames_violating_the_policy() {
+        let renamed = |user_id: &str, username: &str| {
+            let UserStoreEvents::UserRegistered { mut user, .. } = registered(user_id) else {
+                unreachable!()
+            };
+            user.username = username.to_string();
+            UserStoreEvents::UserRegistered {
+                timestamp: 0,
+                user_id: user_id.to_string(),
+                user,
+            }
+        };
+        let events = vec![
+            registered("alice"),
+            renamed("mallory", "ALICE"),
+            renamed("root", "admin"),
+            renamed("dots", "../x"),
+            registered("bob"),
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert!(issues.is_empty());
+        assert_eq!(state.users_id_lookup.len(), 5);
+        assert_eq!(state.users_username_lookup["ALICE"], "mallory");
+        assert_eq!(
+            state.users_skeleton_lookup[&username::skeleton("alice")],
+            "alice"
+        );
+
+        let violations: Vec<_> = state
+            .username_violations(&UsernamePolicy::default())
+            .into_iter()
+            .map(|(user_id, _, violation)| (user_id, violation))
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                ("dots".to_string(), UsernameViolation::Characters),
+                ("mallory".to_string(), UsernameViolation::Confusable),
+                ("root".to_string(), UsernameViolation::Reserved),
+            ]
+        );
+
+        // the skeleton is freed once its user is gone
+        let (state, _) = replay_events(vec![
+            registered("alice"),
+            UserStoreEvents::UserDeleted {
+                timestamp: 10,
+                user_id: "alice".to_string(),
+            },
+        ]);
+        assert!(state.users_skeleton_lookup.is_empty());
+    }
+
+    #[test]
+    fn test_replay_rebuilds_preferences_and_their_version() {
+        let changed = |user_id: &str, theme: &str| UserStoreEvents::UserPreferencesChanged {
+            timestamp: 0,
+            user_id: user_id.to_string(),
+            preferences: serde_json::json!({ "ui.theme": theme })
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        let events = vec![
+            registered("user"),
+            registered("other"),
+            changed("user", "light"),
+            changed("user", "dark"),
+            // preferences survive a full overwrite of the user
+            UserStoreEvents::UserChanged {
+                timestamp: 40,
+                user_id: "user".to_string(),
+                user: match registered("user") {
+                    UserStoreEvents::UserRegistered { user, .. } => user,
+                    _ => unreachable!(),
+                },
+            },
+            changed("ghost", "dark"),
+        ];
+
+        let (state, issues) = replay_events(events);
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].skipped);
+
+        let preferences = &state.users_id_lookup["user"].preferences;
+        assert_eq!(preferences.version, 2);
+        assert_eq!(preferences.preferences["ui.theme"], "dark");
+        assert_eq!(
+            state.users_id_lookup["other"].preferences,
+            UserPreferences::default()
+        );
+
+        // users persisted before preferences existed load with none
+        let user: User = serde_json::from_str(
+            r#"{"id":"old","email":"old@example.com","username":"old","password_hash":""}"#,
+        )
+        .unwrap();
+        assert_eq!(user.preferences, UserPreferences::default());
+    }
+
+    #[actix::test]
+    async fn test_password_hash_update_is_dropped_once_the_password_changed() {
+        let log = std::env::temp_dir().join(format!("{}.jsonl", nanoid::nanoid!(8)));
+        let (_, persistence) = persistence::EventLogPersistenceJson::new(&log.to_string_lossy())
+            .unwrap()
+            .into_actor::<UserStoreEvents>()
+            .unwrap();
+        let UserStoreEvents::UserRegistered { mut user, .. } = registered("user") else {
+            unreachable!()
+        };
+        user.password_hash = "reset".to_string();
+        let events = vec![UserStoreEvents::UserRegistered {
+            timestamp: 0,
+            user_id: "user".to_string(),
+            user,
+        }];
+        let store = UserStore::new(
+            persistence.start().recipient(),
+            events,
+            crate::clock::system(),
+        )
+        .0
+        .start();
+
+        let rehash = |expected_hash: &str| UpdatePasswordHashMessage {
+            user_id: "user".to_string(),
+            expected_hash: expected_hash.to_string(),
+            password_hash: "rehashed".to_string(),
+        };
+        let stored_hash = || async {
+            store
+                .send(GetUserMessage {
+                    username_email: None,
+                    user_id: Some("user".to_string()),
+                })
+                .await
+                .unwrap()
+                .unwrap()
+                .password_hash
+        };
+
+        // derived from the password before the reset
+        assert!(!store.send(rehash("")).await.unwrap().unwrap());
+        assert_eq!(stored_hash().await, "reset");
+
+        assert!(store.send(rehash("reset")).await.unwrap().unwrap());
+        assert_eq!(stored_hash().await, "rehashed");
+        let _ = std::fs::remove_file(log);
     }
 }
@@ -0,0 +1,4840 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{Service, ServiceResponse},
+    http::{header, StatusCode},
+    test, web,
+};
+use std::{sync::Arc, time::Duration};
+use webserver::{
+    authentication::{
+        CookiePolicy, CookieSameSite, WebSocketAuth, JWT_LIFETIME_SECS, WS_TOKEN_PROTOCOL_PREFIX,
+    },
+    build_app,
+    canvas::{
+        access_requests::AccessRequestStatus,
+        binding,
+        client::{CanvasClient, ClientError},
+        events::{CanvasEvents, Point2D, Shape},
+        guests::GuestPolicy,
+        print, replay,
+        server::canvas_log_path,
+        socket_handler::SocketClose,
+        WS_PROTOCOL,
+    },
+    clock::{Clock, ManualClock},
+    notifier::RecordingNotifier,
+    password::{self, PasswordHashConfig},
+    persistence::ReplayMode,
+    recovery,
+    seed::{self, SeedFile, SeedReport},
+    templates, user, AppState, ServerConfig,
+};
+
+// End to end tests against the composed App
+// Every test uses its own eventlogs, canvas eventlogs are removed again once the test finished
+
+fn temp_log_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("{}-{name}", nanoid::nanoid!(8)))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        user_event_log: temp_log_path("user_eventlog.jsonl"),
+        canvas_event_log: temp_log_path("canvas_eventlog.jsonl"),
+        admin_action_log: temp_log_path("admin_actions.jsonl"),
+        template_dir: "../.templates".to_string(),
+        // hashing with the production parameters is too slow for unoptimized test builds
+        password_hash_config: PasswordHashConfig {
+            memory_kib: Some(1024),
+            iterations: Some(1),
+            parallelism: Some(1),
+            calibrate_ms: None,
+        },
+        ..ServerConfig::default()
+    }
+}
+
+/// user id of the admin account written by with_admin
+const ADMIN_ID: &str = "a0000001";
+
+/// Registers the admin account in the user eventlog before the server starts, admins are configured by user id
+fn with_admin(config: ServerConfig) -> ServerConfig {
+    let argon = password::argon2_with_params(config.password_hash_config.build_params().unwrap());
+    let event = serde_json::json!({
+        "type": "UserRegistered",
+        "timestamp": 1,
+        "user_id": ADMIN_ID,
+        "user": {
+            "id": ADMIN_ID,
+            "email": "admin@example.com",
+            "username": "admin",
+            "password_hash": password::hash_password(&argon, b"password").unwrap(),
+        },
+    });
+    let log = std::path::Path::new(&config.user_event_log);
+    std::fs::create_dir_all(log.parent().unwrap()).unwrap();
+    std::fs::write(log, format!("{event}\n")).unwrap();
+    ServerConfig {
+        admins: vec![ADMIN_ID.to_string()],
+        ..config
+    }
+}
+
+/// Auth cookie set by the response, checked against the cookie policy of test_config
+fn auth_cookie<B>(res: &ServiceResponse<B>) -> Cookie<'static> {
+    let cookie = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == user::AUTH_COOKIE_NAME)
+        .expect("response sets no auth cookie")
+        .into_owned();
+    assert_eq!(cookie.http_only(), Some(true), "{cookie}");
+    assert_eq!(cookie.same_site(), Some(SameSite::Lax), "{cookie}");
+    cookie
+}
+
+/// Requests not marked as SPA requests are rewritten to /
+fn spa_request() -> test::TestRequest {
+    test::TestRequest::default().insert_header(("X-SPA-Request", "true"))
+}
+
+async fn register_and_login<S, B>(app: &S, username: &str) -> Cookie<'static>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let email = format!("{username}@example.com");
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/register")
+            .set_form([
+                ("username", username),
+                ("email", email.as_str()),
+                ("password1", "password"),
+                ("password2", "password"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    login(app, username).await
+}
+
+async fn login<S, B>(app: &S, username: &str) -> Cookie<'static>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/login")
+            .set_form([("username_email", username), ("password", "password")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    auth_cookie(&res)
+}
+
+/// Writes a user to the user eventlog of the config, bypassing the username policy of the registration
+fn append_grandfathered_user(config: &ServerConfig, username: &str) {
+    let argon = password::argon2_with_params(argon2::Params::new(1024, 1, 1, None).unwrap());
+    let user_id = nanoid::nanoid!(8, &webserver::userstore::USER_ID_ALPHABET);
+    let event = serde_json::json!({
+        "type": "UserRegistered",
+        "timestamp": 0,
+        "user_id": user_id,
+        "user": {
+            "id": user_id,
+            "email": format!("{user_id}@example.com"),
+            "username": username,
+            "password_hash": password::hash_password(&argon, b"password").unwrap(),
+        },
+    });
+    append_lines(&config.user_event_log, &[&event.to_string()]);
+}
+
+/// Creates a canvas and returns its id and the regenerated auth cookie containing the new claim
+async fn create_canvas<S, B>(app: &S, cookie: Cookie<'static>) -> (String, Cookie<'static>)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    create_named_canvas(app, cookie, "Integration").await
+}
+
+async fn create_named_canvas<S, B>(
+    app: &S,
+    cookie: Cookie<'static>,
+    name: &str,
+) -> (String, Cookie<'static>)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/canvas")
+            .cookie(cookie)
+            .set_form([("name", name)])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let canvas_id = location.trim_start_matches("/canvas/").to_string();
+    (canvas_id, auth_cookie(&res))
+}
+
+fn websocket_request(canvas_id: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri(&format!("/ws/canvas/{canvas_id}"))
+        .insert_header((header::UPGRADE, "websocket"))
+        .insert_header((header::CONNECTION, "Upgrade"))
+        .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+        .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+}
+
+/// Serves the app on a random local port, for clients that need a real socket
+fn serve(state: &AppState) -> String {
+    let state = state.clone();
+    let server = actix_web::HttpServer::new(move || build_app(&state))
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{address}")
+}
+
+/// The canvas server creates the eventlog once the first session connects
+async fn remove_canvas_log(canvas_id: &str) {
+    let path = canvas_log_path(canvas_id);
+    for _ in 0..50 {
+        if std::fs::remove_file(&path).is_ok() {
+            return;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[actix_web::test]
+async fn test_login_create_canvas_and_connect() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+    let body = test::read_body(res).await;
+    let page = std::str::from_utf8(&body).unwrap();
+    assert!(page.contains("Integration"));
+    assert!(page.contains("data-server-time-ms=\""));
+
+    let me: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/me")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert!(me["server_time_ms"].as_u64().is_some_and(|time| time > 0));
+
+    // the page visit lists the canvas as recently visited
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(canvases["owned"][0]["id"], canvas_id.as_str());
+    assert_eq!(canvases["owned"][0]["origin"], "owned");
+    assert_eq!(canvases["recent"][0]["id"], canvas_id.as_str());
+    assert!(canvases["shared"].as_array().unwrap().is_empty());
+
+    let res = test::call_service(
+        &app,
+        websocket_request(&canvas_id).cookie(cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_canvas_requires_access() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, _) = create_canvas(&app, owner_cookie).await;
+
+    let other_cookie = register_and_login(&app, "other").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(other_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/replay"))
+            .cookie(other_cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // websocket without a session
+    let res = test::call_service(&app, websocket_request(&canvas_id).to_request()).await;
+    assert!(res.status().is_client_error() || res.status().is_redirection());
+}
+
+#[actix_web::test]
+async fn test_access_without_claim_in_token() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+
+    // the token of the member was issued before the member was added
+    let member_cookie = register_and_login(&app, "member").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner_cookie)
+            .set_form([("username_email", "member"), ("access_level", "Write")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(member_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/update"))
+            .method(actix_web::http::Method::POST)
+            .cookie(member_cookie)
+            .set_form([("state", "Moderated"), ("expected_version", "2")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let stranger_cookie = register_and_login(&app, "stranger").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(stranger_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(
+        &app,
+        websocket_request(&canvas_id)
+            .cookie(stranger_cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn test_access_granted_while_the_page_is_open() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+    let member_cookie = register_and_login(&app, "member").await;
+
+    // the canvas is loaded by the owner before the member is added
+    let res = test::call_service(
+        &app,
+        websocket_request(&canvas_id)
+            .cookie(owner_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(member_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(res.headers().get("X-SPA-Render").unwrap(), "true");
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(body.contains("id=\"canvas-no-access\""), "{body}");
+
+    let access_request = || {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access"))
+            .cookie(member_cookie.clone())
+            .to_request()
+    };
+    let access: serde_json::Value = test::call_and_read_body_json(&app, access_request()).await;
+    assert_eq!(access["access_level"], "None");
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner_cookie)
+            .set_form([("username_email", "member"), ("access_level", "Write")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // polling leaves the token alone, the page hands out the claim
+    let res = test::call_service(&app, access_request()).await;
+    assert!(res
+        .response()
+        .cookies()
+        .all(|cookie| cookie.name() != user::AUTH_COOKIE_NAME));
+    let access: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(access["access_level"], "Write");
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(member_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let refreshed_cookie = auth_cookie(&res);
+
+    for cookie in [member_cookie, refreshed_cookie] {
+        let res = test::call_service(
+            &app,
+            websocket_request(&canvas_id).cookie(cookie).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
+
+#[actix_web::test]
+async fn test_access_polling_is_rate_limited() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, _) = create_canvas(&app, owner_cookie).await;
+    let member_cookie = register_and_login(&app, "member").await;
+
+    let access_request = || {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access"))
+            .cookie(member_cookie.clone())
+            .to_request()
+    };
+    for _ in 0..webserver::canvas::ACCESS_POLL_LIMIT {
+        let res = test::call_service(&app, access_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = test::call_service(&app, access_request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_access_requests_are_deduplicated_answered_and_rate_limited() {
+    let notifier = Arc::new(RecordingNotifier::default());
+    let (state, canvas_server) = webserver::bootstrap(ServerConfig {
+        notifier: notifier.clone(),
+        ..test_config()
+    })
+    .unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+    let requester_cookie = register_and_login(&app, "requester").await;
+
+    let request_access = |message: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/request-access"))
+            .cookie(requester_cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("message", message)])
+            .to_request()
+    };
+    let access = || {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access"))
+            .cookie(requester_cookie.clone())
+            .to_request()
+    };
+
+    // asking twice while pending keeps the first request and notifies the owner once
+    let first: serde_json::Value =
+        test::call_and_read_body_json(&app, request_access("please  let\nme in")).await;
+    assert_eq!(first["status"], "Pending");
+    assert_eq!(first["message"], "please let me in");
+    let second: serde_json::Value =
+        test::call_and_read_body_json(&app, request_access("again")).await;
+    assert_eq!(second, first);
+    let requester_id = first["user_id"].as_str().unwrap().to_string();
+    let sent = notifier.access_requests();
+    assert_eq!(sent.len(), 1);
+    assert_ne!(sent[0].0, requester_id);
+    assert_eq!(sent[0].1.request.user_id, requester_id);
+
+    let status: serde_json::Value = test::call_and_read_body_json(&app, access()).await;
+    assert_eq!(status["access_level"], "None");
+    assert_eq!(status["access_request"], "Pending");
+
+    // only owners and moderators see and answer requests
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access-requests"))
+            .cookie(requester_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let listed: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access-requests"))
+            .cookie(owner_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(listed[0]["username"], "requester");
+    assert_eq!(listed[0]["user_id"], requester_id.as_str());
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/members"))
+            .cookie(owner_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("id=\"canvas-access-requests\""), "{page}");
+    assert!(page.contains("please let me in"));
+
+    let resolve = |cookie: &Cookie<'static>, decision: &str, access_level: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!(
+                "/canvas/{canvas_id}/access-requests/{requester_id}"
+            ))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("decision", decision), ("access_level", access_level)])
+            .to_request()
+    };
+    let res = test::call_service(&app, resolve(&requester_cookie, "approve", "Write")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let denied: serde_json::Value =
+        test::call_and_read_body_json(&app, resolve(&owner_cookie, "deny", "Read")).await;
+    assert_eq!(denied["status"], "Denied");
+    let sent = notifier.access_requests();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1].0, requester_id);
+    assert_eq!(sent[1].1.request.status, AccessRequestStatus::Denied);
+    let status: serde_json::Value = test::call_and_read_body_json(&app, access()).await;
+    assert_eq!(status["access_request"], "Denied");
+    let res = test::call_service(&app, resolve(&owner_cookie, "deny", "Read")).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // asking again after the answer, the approval grants the requested level
+    let third: serde_json::Value = test::call_and_read_body_json(&app, request_access("")).await;
+    assert_eq!(third["status"], "Pending");
+    assert!(third["message"].is_null());
+    let approved: serde_json::Value =
+        test::call_and_read_body_json(&app, resolve(&owner_cookie, "approve", "Write")).await;
+    assert_eq!(approved["status"], "Approved");
+    assert_eq!(approved["access_level"], "Write");
+    let status: serde_json::Value = test::call_and_read_body_json(&app, access()).await;
+    assert_eq!(status["access_level"], "Write");
+    let sent = notifier.access_requests();
+    assert_eq!(
+        sent.last().unwrap().1.request.status,
+        AccessRequestStatus::Approved
+    );
+
+    // three requests per window
+    let res = test::call_service(&app, request_access("")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn test_adding_members_reports_the_change() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+    let member_cookie = register_and_login(&app, "member").await;
+
+    let add_request = |cookie: &Cookie<'static>, username: &str, access_level: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .set_form([("username_email", username), ("access_level", access_level)])
+    };
+
+    // strangers are turned away before the target is looked up, an unknown target is not reported
+    let res = test::call_service(
+        &app,
+        add_request(&member_cookie, "nobody", "Write")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.access_denied");
+
+    let res = test::call_service(
+        &app,
+        add_request(&owner_cookie, "nobody", "Write")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.user_not_found");
+
+    let change: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        add_request(&owner_cookie, "member", "Write")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(change["username"], "member");
+    assert_eq!(change["previous_level"], "None");
+    assert_eq!(change["new_level"], "Write");
+    assert!(change["target_user_id"]
+        .as_str()
+        .is_some_and(|id| !id.is_empty()));
+
+    let res = test::call_service(
+        &app,
+        add_request(&owner_cookie, "member", "Read").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = test::read_body(res).await;
+    assert_eq!(body, "member changed from Write to Read");
+
+    // rejections of the store keep their own message
+    let res = test::call_service(
+        &app,
+        add_request(&owner_cookie, "owner", "Write")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.access_denied.owner_self");
+}
+
+#[actix_web::test]
+async fn test_failed_register_redirects_back_with_errors_and_values() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/register")
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .set_form([
+                ("username", "mallory"),
+                ("email", "mallory@example.com"),
+                ("password1", "password"),
+                ("password2", "different"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(res.headers().get(header::LOCATION).unwrap(), "/register");
+    let flash = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == templates::FLASH_COOKIE_NAME)
+        .expect("response sets no flash cookie")
+        .into_owned();
+    assert!(!flash.value().contains("different"));
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri("/register")
+            .cookie(flash.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let removal = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == templates::FLASH_COOKIE_NAME)
+        .expect("flash cookie is not removed");
+    assert_eq!(removal.value(), "");
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains(r#"name="username" placeholder="Username" value="mallory""#));
+    assert!(page.contains(r#"value="mallory@example.com""#));
+    assert!(page.contains("Passwords do not match"));
+    assert!(!page.contains("different"));
+
+    // shown once, the browser dropped the removed cookie
+    let res = test::call_service(&app, spa_request().uri("/register").to_request()).await;
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(!page.contains("mallory"));
+    assert!(!page.contains("Passwords do not match"));
+
+    // clients accepting JSON keep the error body
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/register")
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([
+                ("username", "mallory"),
+                ("email", "mallory@example.com"),
+                ("password1", "password"),
+                ("password2", "different"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_cookies_follow_the_configured_policy() {
+    let (state, canvas_server) = webserver::bootstrap(ServerConfig {
+        cookie_policy: CookiePolicy {
+            secure: true,
+            same_site: CookieSameSite::Strict,
+            domain: Some("canvas.example.com".to_string()),
+            ..CookiePolicy::default()
+        },
+        ..test_config()
+    })
+    .unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let register = |password2: &'static str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/register")
+            .set_form([
+                ("username", "alice"),
+                ("email", "alice@example.com"),
+                ("password1", "password"),
+                ("password2", password2),
+            ])
+            .to_request()
+    };
+    let failed = test::call_service(&app, register("different")).await;
+    assert_eq!(
+        test::call_service(&app, register("password"))
+            .await
+            .status(),
+        StatusCode::FOUND
+    );
+    let login = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/login")
+            .set_form([("username_email", "alice"), ("password", "password")])
+            .to_request(),
+    )
+    .await;
+    let auth = login
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == user::AUTH_COOKIE_NAME)
+        .unwrap()
+        .into_owned();
+    let logout = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/logout")
+            .cookie(auth)
+            .to_request(),
+    )
+    .await;
+
+    // every cookie the server sets carries the same attributes
+    let cookies: Vec<_> = [&failed, &login, &logout]
+        .into_iter()
+        .flat_map(|res| res.response().cookies())
+        .collect();
+    let names: Vec<_> = cookies.iter().map(|cookie| cookie.name()).collect();
+    assert_eq!(
+        names,
+        [
+            templates::FLASH_COOKIE_NAME,
+            user::AUTH_COOKIE_NAME,
+            user::AUTH_COOKIE_NAME
+        ]
+    );
+    for cookie in cookies {
+        assert_eq!(cookie.http_only(), Some(true), "{cookie}");
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict), "{cookie}");
+        assert_eq!(cookie.secure(), Some(true), "{cookie}");
+        assert_eq!(cookie.domain(), Some("canvas.example.com"), "{cookie}");
+    }
+}
+
+#[actix_web::test]
+async fn test_members_page_lists_members_and_shows_flash_once() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+    let member_cookie = register_and_login(&app, "member").await;
+    let members_page = format!("/canvas/{canvas_id}/members");
+
+    // submitted from the members page, redirects back with the result
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner_cookie.clone())
+            .set_form([
+                ("username_email", "member"),
+                ("access_level", "Write"),
+                ("return_to", "members"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(res.headers().get(header::LOCATION).unwrap(), &members_page);
+    let flash = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == templates::FLASH_COOKIE_NAME)
+        .expect("response sets no flash cookie")
+        .into_owned();
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&members_page)
+            .cookie(owner_cookie.clone())
+            .cookie(flash)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let removal = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == templates::FLASH_COOKIE_NAME)
+        .expect("flash cookie is not removed");
+    assert_eq!(removal.value(), "");
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("member als Write hinzugefügt"));
+    assert!(page.contains("<td>owner</td>"));
+    assert!(page.contains("<td>member</td>"));
+    assert!(page.contains(r#"<option value="Write" selected>"#));
+    assert!(page.contains("Entfernen"));
+    // level and removal of the member, adding a user, the owner can't be changed
+    assert_eq!(
+        page.matches(&format!(r#"action="/canvas/{canvas_id}""#))
+            .count(),
+        3
+    );
+
+    // shown once, the browser dropped the removed cookie
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&members_page)
+            .cookie(owner_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(!page.contains(r#"class="flash""#));
+
+    // the Referer marks the members page as well
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner_cookie.clone())
+            .insert_header((header::REFERER, format!("http://localhost{members_page}")))
+            .set_form([("username_email", "member"), ("access_level", "Read")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    // members see the table without controls
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&members_page)
+            .cookie(member_cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("<td>member</td>"));
+    assert!(!page.contains("<form"));
+
+    let members: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&members_page)
+            .cookie(member_cookie)
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(members[0]["username"], "member");
+    assert_eq!(members[0]["access_level"], "Read");
+    assert_eq!(members[0]["online"], false);
+    assert_eq!(members[1]["username"], "owner");
+}
+
+/// Breaks out of script elements and attributes if it is embedded unescaped
+const XSS_PROBE: &str =
+    "</script><script>alert(1)</script><img src=x onerror=\"alert(2)\">&amp;\u{2028}";
+
+/// Contents of the script element with the id, None if the page has none
+fn script_content<'a>(page: &'a str, id: &str) -> Option<&'a str> {
+    let start = page.find(&format!(r#"<script type="application/json" id="{id}">"#))?;
+    let content = &page[start..];
+    let content = &content[content.find('>')? + 1..];
+    Some(&content[..content.find("</script>")?])
+}
+
+#[actix_web::test]
+async fn test_user_controlled_names_are_escaped_in_pages() {
+    // the username policy rejects such names today, users registered before keep them
+    let config = test_config();
+    append_grandfathered_user(&config, XSS_PROBE);
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = login(&app, XSS_PROBE).await;
+    let (canvas_id, cookie) = create_named_canvas(&app, cookie, XSS_PROBE).await;
+    let page = |uri: String| spa_request().uri(&uri).cookie(cookie.clone()).to_request();
+
+    for uri in [
+        "/home".to_string(),
+        format!("/canvas/{canvas_id}"),
+        format!("/canvas/{canvas_id}/members"),
+    ] {
+        let body = test::call_and_read_body(&app, page(uri.clone())).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        for fragment in ["<script>alert", "<img src=x", "</script><script>"] {
+            assert!(!body.contains(fragment), "{uri} embeds {fragment}");
+        }
+        assert!(
+            body.contains("&lt;/script&gt;"),
+            "{uri} lacks the escaped name"
+        );
+    }
+
+    // the page parses the bootstrap data back to the original name
+    let body = test::call_and_read_body(&app, page(format!("/canvas/{canvas_id}"))).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let bootstrap = script_content(&body, "canvas-bootstrap").unwrap();
+    assert!(!bootstrap.contains('<') && !bootstrap.contains('\u{2028}'));
+    let bootstrap: serde_json::Value = serde_json::from_str(bootstrap).unwrap();
+    assert_eq!(bootstrap["canvasName"], XSS_PROBE);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_concurrent_canvas_state_updates() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "warden").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    let body = test::read_body(res).await;
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains(r#"data-canvas-version="1""#));
+
+    // two moderators toggle the state based on the same page load
+    let update = |state: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/update"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!({ "state": state, "expected_version": 1 }))
+            .to_request()
+    };
+    let (first, second) = futures_util::future::join(
+        test::call_service(&app, update("Moderated")),
+        test::call_service(&app, update("Active")),
+    )
+    .await;
+
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+
+    let conflict = if first.status() == StatusCode::CONFLICT {
+        first
+    } else {
+        second
+    };
+    let body: serde_json::Value = test::read_body_json(conflict).await;
+    assert_eq!(body["key"], "canvas.version_conflict");
+    assert_eq!(body["params"]["version"], "2");
+}
+
+#[actix_web::test]
+async fn test_canvas_state_follows_the_transition_rules() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner = register_and_login(&app, "owner").await;
+    let (canvas_id, owner) = create_canvas(&app, owner).await;
+    let moderator = register_and_login(&app, "warden").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner.clone())
+            .set_form([("username_email", "warden"), ("access_level", "Moderate")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let change = |cookie: &Cookie<'static>, uri: &str, body: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/{uri}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(body)
+            .to_request()
+    };
+    let action = |action: &str, version: u64| serde_json::json!({ "action": action, "expected_version": version });
+
+    // (caller, action, expected status, message key of a refusal)
+    let steps = [
+        (&moderator, "moderate", StatusCode::OK, None),
+        (
+            &moderator,
+            "archive",
+            StatusCode::FORBIDDEN,
+            Some("canvas.archive_denied"),
+        ),
+        (&owner, "archive", StatusCode::OK, None),
+        (
+            &owner,
+            "activate",
+            StatusCode::CONFLICT,
+            Some("canvas.transition_invalid"),
+        ),
+        (
+            &moderator,
+            "unarchive",
+            StatusCode::FORBIDDEN,
+            Some("canvas.archive_denied"),
+        ),
+        (&owner, "unarchive", StatusCode::OK, None),
+        (
+            &owner,
+            "unarchive",
+            StatusCode::CONFLICT,
+            Some("canvas.transition_invalid"),
+        ),
+    ];
+    // the grant of the moderator was the second change
+    let mut version = 2;
+    for (cookie, name, status, key) in steps {
+        let res = test::call_service(&app, change(cookie, "state", action(name, version))).await;
+        assert_eq!(res.status(), status, "{name} at version {version}");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        match key {
+            Some(key) => assert_eq!(body["key"], key, "{name}"),
+            None => version += 1,
+        }
+    }
+    // the form endpoint translates the submitted state, it knows no archiving
+    let res = test::call_service(
+        &app,
+        change(
+            &moderator,
+            "update",
+            serde_json::json!({ "state": "Moderated", "expected_version": version }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        change(&owner, "state", action("archive", version + 1)),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        change(
+            &owner,
+            "update",
+            serde_json::json!({ "state": "Active", "expected_version": version + 2 }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.transition_invalid");
+    assert_eq!(body["params"]["state"], "Archived");
+    assert_eq!(body["params"]["action"], "activate");
+
+    let res = test::call_service(&app, change(&owner, "state", action("delete", 1))).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["params"]["field"], "action");
+}
+
+#[actix_web::test]
+async fn test_canvas_tags_filter_the_canvas_list() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "tagger").await;
+    let (tagged_id, cookie) = create_canvas(&app, cookie).await;
+    let (untagged_id, cookie) = create_canvas(&app, cookie).await;
+
+    let tags_request = |tags: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{tagged_id}/tags"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+            .set_json(serde_json::json!({ "tags": tags }))
+            .to_request()
+    };
+
+    let too_long = "x".repeat(33);
+    let res = test::call_service(&app, tags_request(serde_json::json!(["ok", "", too_long]))).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.tags_invalid");
+    assert_eq!(body["params"]["field"], "tags");
+    assert_eq!(body["params"]["tags"], format!(", {too_long}"));
+
+    let res = test::call_service(
+        &app,
+        tags_request(serde_json::json!((0..11)
+            .map(|i| format!("tag{i}"))
+            .collect::<Vec<_>>())),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.tags_too_many");
+
+    let res = test::call_service(
+        &app,
+        tags_request(serde_json::json!([" Work", "work", "Draft"])),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let list = |query: &str| {
+        spa_request()
+            .uri(&format!("/api/canvases{query}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let canvases: serde_json::Value = test::call_and_read_body_json(&app, list("?tag=WORK")).await;
+    let owned = canvases["owned"].as_array().unwrap();
+    assert_eq!(owned.len(), 1);
+    assert_eq!(owned[0]["id"], tagged_id.as_str());
+    assert_eq!(owned[0]["tags"], serde_json::json!(["work", "draft"]));
+
+    let canvases: serde_json::Value = test::call_and_read_body_json(&app, list("")).await;
+    let mut owned: Vec<&str> = canvases["owned"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|canvas| canvas["id"].as_str().unwrap())
+        .collect();
+    owned.sort();
+    let mut expected = vec![tagged_id.as_str(), untagged_id.as_str()];
+    expected.sort();
+    assert_eq!(owned, expected);
+
+    let canvases: serde_json::Value =
+        test::call_and_read_body_json(&app, list("?tag=unknown")).await;
+    assert!(canvases["owned"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_canvas_order_is_validated_against_claims() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let stranger_cookie = register_and_login(&app, "stranger").await;
+    let (foreign_id, _) = create_canvas(&app, stranger_cookie).await;
+    let cookie = register_and_login(&app, "orderer").await;
+    let (first_id, cookie) = create_canvas(&app, cookie).await;
+    let (second_id, cookie) = create_canvas(&app, cookie).await;
+
+    let order_request = |order: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/api/canvases/order")
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!({ "order": order }))
+            .to_request()
+    };
+    let entry = |canvas_id: &str, sort_hint: u32| serde_json::json!({ "canvas_id": canvas_id, "sort_hint": sort_hint });
+
+    let res = test::call_service(
+        &app,
+        order_request(serde_json::json!([
+            entry(&first_id, 0),
+            entry(&first_id, 1)
+        ])),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.order_invalid");
+    assert_eq!(body["params"]["field"], "order");
+
+    let res = test::call_service(
+        &app,
+        order_request(serde_json::json!([
+            entry(&first_id, 0),
+            entry(&foreign_id, 1)
+        ])),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.preference_denied");
+
+    let res = test::call_service(
+        &app,
+        order_request(serde_json::json!([
+            entry(&second_id, 0),
+            entry(&first_id, 1)
+        ])),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/api/canvases/{first_id}/pin"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!({ "pinned": true }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    let ids = |group: &str| -> Vec<String> {
+        canvases[group]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|canvas| canvas["id"].as_str().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(ids("pinned"), [first_id.as_str()]);
+    assert_eq!(ids("owned"), [second_id, first_id]);
+}
+
+#[actix_web::test]
+async fn test_batch_adds_users_and_reports_every_entry() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    register_and_login(&app, "pupil1").await;
+    register_and_login(&app, "pupil2").await;
+    let cookie = register_and_login(&app, "teacher").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let batch = |body: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/users/batch"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(body)
+            .to_request()
+    };
+
+    let res = test::call_service(
+        &app,
+        batch(serde_json::json!([
+            { "username_email": "pupil1", "access_level": "Write" },
+            { "username_email": "pupil2@example.com", "access_level": "Read" },
+            { "username_email": "nobody", "access_level": "Read" },
+            { "username_email": "pupil2", "access_level": "Owner" },
+        ])),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let outcomes: serde_json::Value = test::read_body_json(res).await;
+    let statuses: Vec<&str> = outcomes
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|outcome| outcome["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["added", "added", "not_found", "forbidden"]);
+    assert!(outcomes[2]["user_id"].is_null());
+
+    // the forbidden entry is not applied, pupil2 stays a reader
+    let members: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/members"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    let level_of = |username: &str| {
+        members
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|member| member["username"] == username)
+            .map(|member| member["access_level"].clone())
+    };
+    assert_eq!(level_of("pupil1"), Some("Write".into()));
+    assert_eq!(level_of("pupil2"), Some("Read".into()));
+
+    // lines of the members page, a level after the comma overrides the default
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/users/batch"))
+            .cookie(cookie.clone())
+            .set_form([
+                ("users", "pupil1, Read\n\npupil2"),
+                ("access_level", "Voice"),
+                ("return_to", "members"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+
+    let too_many: Vec<serde_json::Value> = (0..101)
+        .map(|n| serde_json::json!({ "username_email": format!("pupil{n}"), "access_level": "Read" }))
+        .collect();
+    let res = test::call_service(&app, batch(serde_json::Value::from(too_many))).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.users_batch_size");
+    assert_eq!(body["params"]["count"], "101");
+
+    let members: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/members"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    let levels: Vec<(&str, &str)> = members
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|member| {
+            (
+                member["username"].as_str().unwrap(),
+                member["access_level"].as_str().unwrap(),
+            )
+        })
+        .filter(|(username, _)| username.starts_with("pupil"))
+        .collect();
+    assert!(levels.contains(&("pupil1", "Read")));
+    assert!(levels.contains(&("pupil2", "Voice")));
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_list_endpoints_answer_in_the_envelope_with_list_parameters() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    register_and_login(&app, "listed1").await;
+    register_and_login(&app, "listed2").await;
+    let cookie = register_and_login(&app, "lister").await;
+    let (first_id, cookie) = create_canvas(&app, cookie).await;
+    let (second_id, cookie) = create_canvas(&app, cookie).await;
+
+    let get_json = |uri: String| {
+        spa_request()
+            .uri(&uri)
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request()
+    };
+
+    // without list parameters the shipped bodies stay as they are
+    let canvases: serde_json::Value =
+        test::call_and_read_body_json(&app, get_json("/api/canvases".to_string())).await;
+    assert_eq!(canvases["owned"].as_array().unwrap().len(), 2);
+    assert!(canvases.get("data").is_none());
+
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        get_json("/api/canvases?per_page=1&fields=id".to_string()),
+    )
+    .await;
+    let data = canvases["data"].as_array().unwrap();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0].as_object().unwrap().len(), 1);
+    assert!([first_id.as_str(), second_id.as_str()].contains(&data[0]["id"].as_str().unwrap()));
+    assert_eq!(canvases["page"]["page"], 1);
+    assert_eq!(canvases["page"]["per_page"], 1);
+    assert_eq!(canvases["page"]["total"], 2);
+
+    for (query, field) in [
+        ("per_page=0", "per_page"),
+        ("page=x", "page"),
+        ("sort=owner", "sort"),
+        ("cursor=abc", "cursor"),
+    ] {
+        let res = test::call_service(&app, get_json(format!("/api/canvases?{query}"))).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{query}");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["params"]["field"], field, "{query}");
+    }
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{first_id}/users/batch"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!([
+                { "username_email": "listed2", "access_level": "Read" },
+                { "username_email": "listed1", "access_level": "Read" },
+            ]))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let members: serde_json::Value =
+        test::call_and_read_body_json(&app, get_json(format!("/canvas/{first_id}/members"))).await;
+    assert_eq!(members.as_array().unwrap().len(), 3);
+
+    let members: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        get_json(format!(
+            "/canvas/{first_id}/members?sort=-username&fields=username"
+        )),
+    )
+    .await;
+    assert_eq!(
+        members["data"],
+        serde_json::json!([
+            { "username": "lister" },
+            { "username": "listed2" },
+            { "username": "listed1" },
+        ])
+    );
+    assert_eq!(members["page"]["total"], 3);
+
+    remove_canvas_log(&first_id).await;
+    remove_canvas_log(&second_id).await;
+}
+
+#[actix_web::test]
+async fn test_canvas_palette_is_replaced_as_a_whole() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "colorist").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let post = |uri: &str, body: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/{uri}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(body)
+            .to_request()
+    };
+    let palette = || {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/palette"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    let res = test::call_service(
+        &app,
+        post(
+            "palette",
+            serde_json::json!({ "colors": [{ "name": "Brand", "color": "orange" }] }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.palette_invalid");
+    assert_eq!(body["params"]["field"], "color");
+    assert_eq!(body["params"]["value"], "orange");
+
+    let res = test::call_service(
+        &app,
+        post(
+            "palette",
+            serde_json::json!({
+                "colors": [{ "name": " Brand ", "color": "#FF8800" }, { "name": "Ink", "color": "#000" }],
+                "enforce_palette": true,
+            }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, palette()).await;
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "colors": [{ "name": "Brand", "color": "#ff8800" }, { "name": "Ink", "color": "#000000" }],
+            "enforce_palette": true,
+        })
+    );
+
+    // other settings keep the palette
+    let res = test::call_service(
+        &app,
+        post("settings", serde_json::json!({ "grid_size": 20 })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = test::call_and_read_body_json(&app, palette()).await;
+    assert_eq!(body["colors"].as_array().unwrap().len(), 2);
+
+    let page = test::call_and_read_body(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert!(String::from_utf8(page.to_vec())
+        .unwrap()
+        .contains(r##""palette":{"colors":[{"##));
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_owner_restricts_the_shape_types_of_a_canvas() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "tutor").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let settings_request = |settings: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/settings"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(settings)
+            .to_request()
+    };
+
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "allowed_shape_types": ["Rectangle", "Hexagon"] })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.shape_types_invalid");
+    assert_eq!(body["params"]["field"], "allowed_shape_types");
+    assert_eq!(body["params"]["types"], "Hexagon");
+
+    // forms send the names comma separated
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "allowed_shape_types": "Path, Rectangle" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let page = test::call_and_read_body(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert!(String::from_utf8(page.to_vec())
+        .unwrap()
+        .contains(r#""allowedShapeTypes":["Rectangle","Path"]"#));
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_canvas_metadata_is_embedded_in_exports() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "curator").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let settings_request = |settings: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/settings"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(settings)
+            .to_request()
+    };
+    let export = |path: &str| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/{path}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let export_svg = |path: &str| {
+        let request = export(path);
+        let app = &app;
+        async move {
+            let body = test::call_and_read_body(app, request).await;
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    // canvases without metadata export as before
+    let svg = export_svg("export.svg?attribution=true").await;
+    assert!(!svg.contains("<metadata"));
+    assert!(!svg.contains("<text"));
+    let json: serde_json::Value = test::call_and_read_body_json(&app, export("export.json")).await;
+    assert!(json.get("metadata").is_none());
+
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "license": "WTFPL" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.license_unknown");
+
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({
+            "author_display": "Curator\u{7} Team",
+            "license": "CC-BY-4.0",
+            "description": "</dc:description><script>alert(1)</script> & more",
+        })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // settings without metadata fields keep it
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "grid_size": 20 })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let svg = export_svg("export.svg").await;
+    assert!(svg.contains("<dc:creator>Curator Team</dc:creator>"));
+    assert!(svg.contains("<dc:rights>CC BY 4.0</dc:rights>"));
+    assert!(svg.contains(
+        "<dc:description>&lt;/dc:description&gt;&lt;script&gt;alert(1)&lt;/script&gt; &amp; more</dc:description>"
+    ));
+    assert!(!svg.contains("<script>"));
+    // the footer is only added on request
+    assert!(!svg.contains("<text"));
+    let svg = export_svg("export.svg?attribution=true").await;
+    assert!(svg.contains(">© Curator Team · CC BY 4.0</text>"));
+
+    let json: serde_json::Value = test::call_and_read_body_json(&app, export("export.json")).await;
+    assert_eq!(
+        json["metadata"],
+        serde_json::json!({
+            "author_display": "Curator Team",
+            "license": "CC-BY-4.0",
+            "description": "</dc:description><script>alert(1)</script> & more",
+        })
+    );
+    assert!(json["shapes"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_shape_provenance_is_only_sent_on_request() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "historian").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2000,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"creator"}
+{"type":"ShapeUpdated","origin":"s2","timestamp":3000,"shape":{"id":"l1","borderColor":"#fff"},"userId":"editor"}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let get = |path: &str| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/{path}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    // without the flag the payload is the fold as before
+    let body = test::call_and_read_body(&app, get("replay")).await;
+    let fold = replay::replay_log(&canvas_log_path(&canvas_id), None).unwrap();
+    assert_eq!(body, serde_json::to_vec(&fold.state).unwrap());
+    let json: serde_json::Value = test::call_and_read_body_json(&app, get("export.json")).await;
+    assert!(json.get("provenance").is_none());
+
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("replay?provenance=true")).await;
+    assert_eq!(
+        json["provenance"]["l1"],
+        serde_json::json!({ "last_modified_by": "editor", "last_modified_at": 3000, "modifications": 1 })
+    );
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("export.json?provenance=true")).await;
+    assert_eq!(json["provenance"]["l1"]["last_modified_by"], "editor");
+
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("shapes/l1/provenance")).await;
+    assert_eq!(json["shape_id"], "l1");
+    assert_eq!(json["last_modified_at"], 3000);
+    // the editor is no user of the UserStore
+    assert!(json["last_modified_by_name"].is_null());
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/shapes/missing/provenance"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.shapes.not_found");
+
+    let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+}
+
+#[actix_web::test]
+async fn test_readers_of_anonymized_canvases_see_pseudonyms_over_http() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let reader = register_and_login(&app, "onlooker").await;
+    let cookie = register_and_login(&app, "curator").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let post = |path: &str, body: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/{path}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(body)
+            .to_request()
+    };
+    let res = test::call_service(
+        &app,
+        post(
+            "settings",
+            serde_json::json!({ "anonymize_for_readers": true }),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        post(
+            "users/batch",
+            serde_json::json!([{ "username_email": "onlooker", "access_level": "Read" }]),
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ContributorSeen","userId":"creator","username":"Creator Name","firstSeen":1}
+{"type":"ShapeAdded","origin":"s1","timestamp":2000,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"creator"}
+{"type":"ShapeUpdated","origin":"s2","timestamp":3000,"shape":{"id":"l1","borderColor":"#fff"},"userId":"editor"}
+{"type":"CommentAdded","origin":"s1","timestamp":3,"commentId":"c1","shapeId":"l1","text":"too thin","userId":"creator"}
+{"type":"CommentResolved","origin":"s2","timestamp":4,"commentId":"c1","userId":"editor"}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let paths = [
+        "replay?provenance=true",
+        "shapes/l1/provenance",
+        "comments",
+        "export.json?provenance=true&comments=true",
+        "export.svg",
+    ];
+    for path in paths {
+        let get = |cookie: &Cookie<'static>| {
+            spa_request()
+                .uri(&format!("/canvas/{canvas_id}/{path}"))
+                .cookie(cookie.clone())
+                .to_request()
+        };
+        let res = test::call_service(&app, get(&reader)).await;
+        assert_eq!(res.status(), StatusCode::OK, "{path}");
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        for identity in ["creator", "Creator Name", "editor"] {
+            assert!(
+                !body.contains(identity),
+                "{path} reveals {identity}: {body}"
+            );
+        }
+        assert!(body.contains("viewer-"), "{path}: {body}");
+
+        // the owner still sees who changed what
+        let body = test::call_and_read_body(&app, get(&cookie)).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("creator") || body.contains("editor"),
+            "{path}"
+        );
+    }
+
+    let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+}
+
+#[actix_web::test]
+async fn test_comments_are_filtered_by_shape_and_status() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "reviewer").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"creator"}
+{"type":"CommentAdded","origin":"s1","timestamp":3,"commentId":"c1","shapeId":"l1","text":"too thin","userId":"creator"}
+{"type":"CommentAdded","origin":"s1","timestamp":4,"commentId":"c2","shapeId":"l1","text":"wrong color","userId":"creator"}
+{"type":"CommentAdded","origin":"s1","timestamp":5,"commentId":"c3","shapeId":"l2","text":"gone","userId":"creator"}
+{"type":"CommentResolved","origin":"s2","timestamp":6,"commentId":"c2","userId":"editor"}
+{"type":"CommentDeleted","origin":"s1","timestamp":7,"commentId":"c3","userId":"creator"}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let get = |path: &str| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/{path}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let ids = |json: serde_json::Value| -> Vec<String> {
+        json.as_array()
+            .unwrap()
+            .iter()
+            .map(|comment| comment["comment_id"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let json: serde_json::Value = test::call_and_read_body_json(&app, get("comments")).await;
+    assert_eq!(ids(json), vec!["c1", "c2"]);
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("comments?shape_id=l1&status=resolved")).await;
+    assert_eq!(json[0]["resolved_by"], "editor");
+    assert_eq!(ids(json), vec!["c2"]);
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("comments?status=open")).await;
+    assert_eq!(ids(json), vec!["c1"]);
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("comments?shape_id=l2")).await;
+    assert_eq!(ids(json), Vec::<String>::new());
+
+    let res = test::call_service(&app, get("comments?status=pending")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let json: serde_json::Value = test::call_and_read_body_json(&app, get("export.json")).await;
+    assert!(json.get("comments").is_none());
+    let json: serde_json::Value =
+        test::call_and_read_body_json(&app, get("export.json?comments=true")).await;
+    assert_eq!(json["comments"][0]["text"], "too thin");
+
+    let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+}
+
+#[actix_web::test]
+async fn test_print_page_inlines_the_drawing() {
+    let (state, canvas_server) = webserver::bootstrap(ServerConfig {
+        print_limits: print::PrintLimits { max_shapes: 2 },
+        ..test_config()
+    })
+    .unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner = register_and_login(&app, "teacher").await;
+    let (canvas_id, owner) = create_named_canvas(&app, owner, "Handout").await;
+    let reader = register_and_login(&app, "pupil").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner)
+            .set_form([("username_email", "pupil"), ("access_level", "Read")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":10,"y":10}},"userId":"creator"}
+{"type":"ShapeAdded","origin":"s1","timestamp":3,"shape":{"type":"Circle","id":"c1","temporary":false,"borderColor":"#00f","fillColor":"#fff","center":{"x":5,"y":5},"radius":2}}
+{"type":"CommentAdded","origin":"s1","timestamp":4,"commentId":"k1","shapeId":"l1","text":"<b>bitte nachzeichnen</b>","userId":"creator"}
+{"type":"CommentAdded","origin":"s1","timestamp":5,"commentId":"k2","shapeId":"l1","text":"erledigt","userId":"creator"}
+{"type":"CommentResolved","origin":"s1","timestamp":6,"commentId":"k2","userId":"creator"}
+{"type":"CommentAdded","origin":"s1","timestamp":18446744073709552,"commentId":"k3","shapeId":"c1","text":"aus ferner Zukunft","userId":"creator"}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), &log).unwrap();
+
+    // opened as a plain page, not through the SPA
+    let print = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/canvas/{canvas_id}/print{query}"))
+            .cookie(reader.clone())
+            .to_request()
+    };
+    let res = test::call_service(&app, print("?legend=true")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CACHE_CONTROL).unwrap(),
+        "no-store"
+    );
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains("<h1>Handout</h1>"), "{page}");
+    assert!(page.contains("Besitzer: teacher"));
+    assert!(page.contains(r#"<svg xmlns="http://www.w3.org/2000/svg""#));
+    assert!(page.contains(r##"<line x1="0" y1="0" x2="10" y2="10" stroke="#000""##));
+    assert!(page.contains(r##"<circle cx="5" cy="5" r="2" stroke="#00f" fill="#fff""##));
+    assert!(page.contains("id=\"print-legend\""));
+    assert!(!page.contains("<script"));
+    assert!(!page.contains("print-truncated"));
+    assert!(!page.contains("print-comments"));
+
+    let res = test::call_service(&app, print("?comments=true")).await;
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(
+        page.contains("&lt;b&gt;bitte nachzeichnen&lt;/b&gt;"),
+        "{page}"
+    );
+    // resolved comments are not listed
+    assert!(!page.contains("erledigt"));
+    // a date out of range is left out
+    assert!(page.contains("Unbekannt · Form c1"), "{page}");
+    assert!(!page.contains("print-legend"));
+
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":7,"shape":{"type":"Line","id":"l2","temporary":false,"borderColor":"#0f0","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":3,"y":3}}}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), &log).unwrap();
+    let res = test::call_service(&app, print("?legend=true")).await;
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(
+        page.contains("1 von 3 Formen werden nicht angezeigt"),
+        "{page}"
+    );
+    assert!(!page.contains(r##"stroke="#0f0""##));
+    // the legend counts the whole canvas
+    assert!(page.contains("<td>Linie</td><td>2</td>"));
+
+    let stranger = register_and_login(&app, "stranger").await;
+    let res = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("/canvas/{canvas_id}/print"))
+            .cookie(stranger)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+}
+
+#[actix_web::test]
+async fn test_exported_documents_import_and_duplicate_in_order() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "copyist").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}},"userId":"creator"}
+{"type":"ShapeAdded","origin":"s1","timestamp":3,"shape":{"type":"Circle","id":"c1","temporary":false,"borderColor":"#000","fillColor":"#000","center":{"x":5,"y":5},"radius":2}}
+{"type":"ShapeUpdated","origin":"s1","timestamp":4,"shape":{"id":"l1","note":"kept"}}
+{"type":"ShapeZChanged","origin":"s1","timestamp":5,"shapeId":"l1","z":{"value":1,"isInfinity":true}}
+"##);
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let post = |uri: &str, cookie: &Cookie<'static>| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(uri)
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+    };
+    let res = test::call_service(
+        &app,
+        post(&format!("/canvas/{canvas_id}/palette"), &cookie)
+            .set_json(serde_json::json!({ "colors": [{ "name": "Ink", "color": "#000" }] }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let export = |canvas_id: &str, cookie: &Cookie<'static>| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/export.json"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let exported: serde_json::Value =
+        test::call_and_read_body_json(&app, export(&canvas_id, &cookie)).await;
+    assert_eq!(exported["version"], 1);
+    assert_eq!(exported["shapes"][0]["shape"]["id"], "c1");
+    assert_eq!(exported["shapes"][1]["z"], 1);
+    assert_eq!(exported["shapes"][1]["shape"]["note"], "kept");
+    assert!(exported["shapes"][1]["shape"].get("createdBy").is_none());
+
+    // the ids of the document name the shapes of the copy
+    let renamed = |document: &serde_json::Value, mapping: &serde_json::Value| {
+        let mut document = document.clone();
+        for shape in document["shapes"].as_array_mut().unwrap() {
+            let to = mapping
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|entry| entry["from"] == shape["shape"]["id"])
+                .unwrap()["to"]
+                .clone();
+            shape["shape"]["id"] = to;
+        }
+        document
+    };
+
+    let res = test::call_service(
+        &app,
+        post("/canvas/import?name=Imported", &cookie)
+            .set_json(&exported)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let cookie = auth_cookie(&res);
+    let imported: serde_json::Value = test::read_body_json(res).await;
+    let imported_id = imported["canvas_id"].as_str().unwrap().to_string();
+    assert_eq!(imported["id_mapping"][0]["from"], "c1");
+    let reexported: serde_json::Value =
+        test::call_and_read_body_json(&app, export(&imported_id, &cookie)).await;
+    assert_eq!(renamed(&exported, &imported["id_mapping"]), reexported);
+
+    let res = test::call_service(
+        &app,
+        post(&format!("/canvas/{canvas_id}/duplicate"), &cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let cookie = auth_cookie(&res);
+    let duplicated: serde_json::Value = test::read_body_json(res).await;
+    let duplicate_id = duplicated["canvas_id"].as_str().unwrap().to_string();
+    let copied: serde_json::Value =
+        test::call_and_read_body_json(&app, export(&duplicate_id, &cookie)).await;
+    assert_eq!(renamed(&exported, &duplicated["id_mapping"]), copied);
+
+    let mut newer = exported.clone();
+    newer["version"] = serde_json::json!(2);
+    let res = test::call_service(
+        &app,
+        post("/canvas/import", &cookie)
+            .set_json(&newer)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.document_version");
+
+    for canvas_id in [canvas_id, imported_id, duplicate_id] {
+        let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+    }
+}
+
+#[actix_web::test]
+async fn test_canvas_stats_report_what_compaction_reclaims() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "archivist").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    log.push_str(r##"{"type":"ShapeAdded","origin":"s1","timestamp":2,"shape":{"type":"Line","id":"l1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":0},"to":{"x":1,"y":1}}}"##);
+    log.push('\n');
+    for timestamp in 3..6 {
+        log.push_str(&format!(
+            "{{\"type\":\"UserAccessLevelChanged\",\"timestamp\":{timestamp},\"userId\":\"u{timestamp}\",\"accessLevel\":\"Read\"}}\n"
+        ));
+    }
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let settings_request = |settings: serde_json::Value| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/settings"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(settings)
+            .to_request()
+    };
+    let stats = || {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/stats"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    // the configured policy keeps the last 500 history events
+    let body: serde_json::Value = test::call_and_read_body_json(&app, stats()).await;
+    assert_eq!(body["retention"]["categories"]["history"]["events"], 3);
+    assert_eq!(
+        body["retention"]["categories"]["history"]["reclaimable_events"],
+        0
+    );
+
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "history_retention": "forever" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "canvas.retention_invalid");
+
+    let res = test::call_service(
+        &app,
+        settings_request(serde_json::json!({ "history_retention": "last:1" })),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body: serde_json::Value = test::call_and_read_body_json(&app, stats()).await;
+    let categories = &body["retention"]["categories"];
+    assert_eq!(categories["history"]["reclaimable_events"], 2);
+    assert!(categories["history"]["reclaimable_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(categories["drawing"]["events"], 1);
+    assert_eq!(categories["drawing"]["reclaimable_events"], 0);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_canvas_diagnostics_are_limited_to_moderators() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let owner_cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, owner_cookie) = create_canvas(&app, owner_cookie).await;
+    let member_cookie = register_and_login(&app, "member").await;
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(owner_cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("username_email", "member"), ("access_level", "Write")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let diagnostics = |cookie: &Cookie<'static>| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/diagnostics"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    // nobody is connected, the canvas is not loaded and has nothing to report
+    let res = test::call_service(&app, diagnostics(&owner_cookie)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["loaded"], false);
+
+    let res = test::call_service(&app, diagnostics(&member_cookie)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_shape_search_filters_and_pages() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "seeker").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let stranger_cookie = register_and_login(&app, "stranger").await;
+
+    let mut log =
+        format!("{{\"type\":\"CanvasLogHeader\",\"timestamp\":1,\"canvasId\":\"{canvas_id}\"}}\n");
+    for shape in [
+        r##"{"type":"Rectangle","id":"r1","temporary":false,"borderColor":"#000","fillColor":"#ff0000","from":{"x":0,"y":0},"to":{"x":10,"y":10}}"##,
+        r##"{"type":"Circle","id":"c1","temporary":false,"borderColor":"#000","fillColor":"#ff0000","center":{"x":100,"y":100},"radius":5.0}"##,
+        r##"{"type":"Rectangle","id":"r2","temporary":false,"borderColor":"#00f","fillColor":"#00ff00","from":{"x":50,"y":0},"to":{"x":60,"y":10}}"##,
+        r##"{"type":"Line","id":"line-1","temporary":false,"borderColor":"#000","fillColor":"#000","from":{"x":0,"y":50},"to":{"x":20,"y":50}}"##,
+    ] {
+        log.push_str(&format!(
+            "{{\"type\":\"ShapeAdded\",\"origin\":\"s1\",\"timestamp\":2,\"shape\":{shape}}}\n"
+        ));
+    }
+    std::fs::write(canvas_log_path(&canvas_id), log).unwrap();
+
+    let search = |query: &str, cookie: &Cookie<'static>| {
+        let request = spa_request()
+            .uri(&format!("/canvas/{canvas_id}/shapes?{query}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let app = &app;
+        async move {
+            let res = test::call_service(app, request).await;
+            let status = res.status();
+            let body: serde_json::Value = test::read_body_json(res).await;
+            (status, body)
+        }
+    };
+    let ids = |body: &serde_json::Value| -> Vec<String> {
+        body["shapes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|shape| shape["id"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // the canvas is not loaded, the eventlog is folded
+    for (query, expected) in [
+        ("", vec!["r1", "c1", "r2", "line-1"]),
+        ("type=Rectangle", vec!["r1", "r2"]),
+        ("fillColor=%23ff0000", vec!["r1", "c1"]),
+        ("borderColor=%2300f", vec!["r2"]),
+        ("idPrefix=line", vec!["line-1"]),
+        ("x=90&y=90&w=5&h=5", vec!["c1"]),
+        (
+            "type=Rectangle&fillColor=%23ff0000&x=5&y=5&w=100&h=100",
+            vec!["r1"],
+        ),
+        ("type=Triangle", vec![]),
+    ] {
+        let (status, body) = search(query, &cookie).await;
+        assert_eq!(status, StatusCode::OK, "{query}");
+        assert_eq!(ids(&body), expected, "{query}");
+        assert_eq!(body["total"], expected.len(), "{query}");
+    }
+
+    let (status, body) = search("x=1&y=1", &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["key"], "canvas.shapes.invalid_area");
+    let (status, body) = search("cursor=missing", &cookie).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["key"], "canvas.shapes.unknown_cursor");
+    let (status, _) = search("", &stranger_cookie).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // the first page is read before a shape is added, the loaded canvas answers the next one
+    let (_, first_page) = search("limit=2", &cookie).await;
+    assert_eq!(ids(&first_page), ["r1", "c1"]);
+    assert_eq!(first_page["total"], 4);
+    assert_eq!(first_page["next_cursor"], "c1");
+
+    let base_url = serve(&state);
+    let drawer = CanvasClient::connect(&base_url, cookie.value(), &canvas_id)
+        .await
+        .unwrap();
+    drawer
+        .add_shape(Shape::Rectangle {
+            id: "r3".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#ff0000".to_string(),
+            attributes: Default::default(),
+            from: Point2D { x: 0, y: 0 },
+            to: Point2D { x: 1, y: 1 },
+        })
+        .await
+        .unwrap();
+    let second_page = actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let (_, page) = search("limit=2&cursor=c1", &cookie).await;
+            if page["total"] == 5 {
+                return page;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(ids(&second_page), ["r2", "line-1"]);
+    assert_eq!(second_page["next_cursor"], "line-1");
+    let (_, last_page) = search("limit=2&cursor=line-1", &cookie).await;
+    assert_eq!(ids(&last_page), ["r3"]);
+    assert!(last_page["next_cursor"].is_null());
+
+    drawer.close().await.unwrap();
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_invalid_bodies_are_rejected_with_structured_errors() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let json_request = || {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .insert_header((header::ACCEPT, "application/json"))
+            .insert_header((header::ACCEPT_LANGUAGE, "en"))
+    };
+
+    let oversized = "a".repeat(webserver::forms::AUTH_BODY_LIMIT);
+    let res = test::call_service(
+        &app,
+        json_request()
+            .uri("/register")
+            .set_form([
+                ("username", oversized.as_str()),
+                ("email", "big@example.com"),
+                ("password1", "password"),
+                ("password2", "password"),
+            ])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "request.too_large");
+    assert_eq!(body["params"]["reason"], "too_large");
+    assert_eq!(body["params"]["limit"], "16384");
+
+    let cookie = register_and_login(&app, "owner").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    register_and_login(&app, "member").await;
+
+    let res = test::call_service(
+        &app,
+        json_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .set_form([("username_email", "member"), ("access_level", "Admin")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "request.field_invalid");
+    assert_eq!(body["params"]["field"], "access_level");
+    assert_eq!(
+        body["params"]["expected"],
+        "Read, Write, Moderate, Owner, Voice, None"
+    );
+    assert!(!body["message"].as_str().unwrap().contains("Admin"));
+
+    // forms and JSON bodies report the same error
+    for request in [
+        json_request().set_form([("state", "Archived"), ("expected_version", "1")]),
+        json_request().set_json(serde_json::json!({ "state": "Archived", "expected_version": 1 })),
+    ] {
+        let res = test::call_service(
+            &app,
+            request
+                .uri(&format!("/canvas/{canvas_id}/update"))
+                .cookie(cookie.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["key"], "request.field_invalid");
+        assert_eq!(body["params"]["reason"], "invalid_value");
+        assert_eq!(body["params"]["field"], "state");
+        assert_eq!(body["params"]["expected"], "Active, Moderated");
+        assert_eq!(
+            body["message"],
+            "Invalid value for state, accepted values are Active, Moderated"
+        );
+    }
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/update"))
+            .cookie(cookie)
+            .set_form([("expected_version", "1")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = test::read_body(res).await;
+    assert_eq!(body, "Feld state fehlt");
+}
+
+#[actix_web::test]
+async fn test_admin_canvas_deletion_is_confirmed_and_logged() {
+    let (state, canvas_server) = webserver::bootstrap(with_admin(test_config())).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let admin = login(&app, "admin").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+
+    let delete = |cookie: &Cookie<'static>, confirm: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!(
+                "/admin/api/canvas/{canvas_id}/delete?confirm={confirm}"
+            ))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    let res = test::call_service(&app, delete(&alice, &canvas_id)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // the target has to be echoed
+    let res = test::call_service(&app, delete(&admin, "")).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+    let res = test::call_service(&app, delete(&admin, "other")).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let res = test::call_service(&app, delete(&admin, &canvas_id)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&app, delete(&admin, &canvas_id)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let actions = |query: &str| {
+        spa_request()
+            .uri(&format!("/admin/api/actions?{query}"))
+            .cookie(admin.clone())
+            .to_request()
+    };
+
+    // unconfirmed attempts never reach the log, newest action first
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, actions("page=1&per_page=1")).await;
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["actions"].as_array().unwrap().len(), 1);
+    assert_eq!(page["actions"][0]["outcome"]["status"], "failed");
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, actions("page=2&per_page=1")).await;
+    assert_eq!(page["actions"][0]["action"], "delete_canvas");
+    assert_eq!(page["actions"][0]["target"], canvas_id.as_str());
+    assert_eq!(page["actions"][0]["outcome"]["status"], "succeeded");
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, actions("page=3&per_page=1")).await;
+    assert!(page["actions"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_admin_purges_orphaned_canvas_eventlogs_into_the_quarantine() {
+    let (state, canvas_server) = webserver::bootstrap(with_admin(test_config())).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let admin = login(&app, "admin").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, _) = create_canvas(&app, alice).await;
+    let active = format!("{canvas_id}.jsonl");
+    std::fs::write(&active, "").unwrap();
+    // a canvas id the store never saw
+    let unknown = format!(
+        "{}.jsonl",
+        nanoid::nanoid!(12, &webserver::canvas::store::CANVAS_ID_ALPHABET)
+    );
+    std::fs::write(&unknown, "{}\n").unwrap();
+
+    let report: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/admin/api/storage/orphans")
+            .cookie(admin.clone())
+            .to_request(),
+    )
+    .await;
+    let class_of = |file: &str| {
+        report["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|log| log["file_name"] == file)
+            .map(|log| log["class"].clone())
+    };
+    assert_eq!(class_of(&active), Some(serde_json::json!("active")));
+    assert_eq!(class_of(&unknown), Some(serde_json::json!("unknown")));
+    let unknown_log = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|log| log["file_name"] == unknown.as_str())
+        .unwrap();
+    assert_eq!(unknown_log["size_bytes"], 3);
+
+    let purge = |files: &[&str], confirm: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/admin/api/storage/purge?confirm={confirm}"))
+            .insert_header((header::ACCEPT, "application/json"))
+            .cookie(admin.clone())
+            .set_json(serde_json::json!({ "files": files }))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, purge(&[], "")).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = test::call_service(&app, purge(&[&unknown], "")).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    // nothing is moved if one of the files is in use
+    let mut both = [active.as_str(), unknown.as_str()];
+    both.sort();
+    let res = test::call_service(&app, purge(&both, &both.join(","))).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "admin.storage_purge_refused");
+    assert_eq!(body["params"]["file"], active.as_str());
+    assert_eq!(body["params"]["class"], "active");
+    assert!(std::path::Path::new(&unknown).exists());
+
+    let res = test::call_service(&app, purge(&["notes.txt"], "notes.txt")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["params"]["class"], "missing");
+
+    let result: serde_json::Value =
+        test::call_and_read_body_json(&app, purge(&[&unknown], &unknown)).await;
+    let quarantined = result["quarantined"][0].as_str().unwrap().to_string();
+    assert!(quarantined.ends_with(&format!("-{unknown}")));
+    assert_eq!(std::fs::read_to_string(&quarantined).unwrap(), "{}\n");
+    assert!(!std::path::Path::new(&unknown).exists());
+    assert!(std::path::Path::new(&active).exists());
+
+    let page: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/admin/api/actions?page=1&per_page=1")
+            .cookie(admin.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(page["actions"][0]["action"], "purge_canvas_logs");
+    assert_eq!(page["actions"][0]["target"], unknown.as_str());
+    assert_eq!(page["actions"][0]["outcome"]["status"], "succeeded");
+
+    let _ = std::fs::remove_file(quarantined);
+    let _ = std::fs::remove_file(active);
+    let _ = std::fs::remove_dir("quarantine");
+}
+
+#[actix_web::test]
+async fn test_owner_deletes_and_restores_canvas() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let bob = register_and_login(&app, "bob").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+
+    let delete = |cookie: &Cookie<'static>| {
+        spa_request()
+            .method(actix_web::http::Method::DELETE)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let restore = |cookie: &Cookie<'static>| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/restore"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let page = |cookie: &Cookie<'static>| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+    let canvases = |cookie: &Cookie<'static>| {
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    let res = test::call_service(&app, delete(&bob)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(&app, delete(&alice)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let alice = auth_cookie(&res);
+    let res = test::call_service(&app, page(&alice)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let listed: serde_json::Value = test::call_and_read_body_json(&app, canvases(&alice)).await;
+    assert!(listed["owned"].as_array().unwrap().is_empty());
+    assert_eq!(listed["deleted"][0]["id"], canvas_id.as_str());
+
+    let res = test::call_service(&app, restore(&bob)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, restore(&alice)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let alice = auth_cookie(&res);
+
+    let res = test::call_service(&app, page(&alice)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let listed: serde_json::Value = test::call_and_read_body_json(&app, canvases(&alice)).await;
+    assert_eq!(listed["owned"][0]["id"], canvas_id.as_str());
+    assert!(listed["deleted"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_client_draw_round_trip() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let base_url = serve(&state);
+
+    let rejected = CanvasClient::connect(&base_url, "invalid", &canvas_id).await;
+    assert!(matches!(rejected, Err(ClientError::Rejected(_))));
+
+    let drawer = CanvasClient::connect(&base_url, cookie.value(), &canvas_id)
+        .await
+        .unwrap();
+    let mut viewer = CanvasClient::connect(&base_url, cookie.value(), &canvas_id)
+        .await
+        .unwrap();
+
+    // the initial state of the viewer contains its own join, after that it receives broadcasts
+    let viewer_session = viewer.session_id().to_string();
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = viewer.next_event().await {
+            if matches!(event, CanvasEvents::UserJoined { sessionId, .. } if sessionId == viewer_session)
+            {
+                return;
+            }
+        }
+        panic!("viewer closed before joining");
+    })
+    .await
+    .unwrap();
+
+    drawer
+        .add_shape(Shape::Line {
+            id: "l1".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: Point2D { x: 0, y: 0 },
+            to: Point2D { x: 5, y: 5 },
+        })
+        .await
+        .unwrap();
+
+    let (origin, shape_id) = actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = viewer.next_event().await {
+            if let CanvasEvents::ShapeAdded { origin, shape, .. } = event {
+                return (origin, shape.get_id().to_string());
+            }
+        }
+        panic!("viewer closed before receiving the shape");
+    })
+    .await
+    .unwrap();
+    assert_eq!(origin, drawer.session_id());
+    assert_eq!(shape_id, "l1");
+
+    drawer.close().await.unwrap();
+    viewer.close().await.unwrap();
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_logout_everywhere_revokes_tokens_and_closes_sessions() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let other_device = login(&app, "alice").await;
+    let base_url = serve(&state);
+
+    let mut client = CanvasClient::connect(&base_url, cookie.value(), &canvas_id)
+        .await
+        .unwrap();
+    let session_id = client.session_id().to_string();
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if matches!(event, CanvasEvents::UserJoined { sessionId, .. } if sessionId == session_id)
+            {
+                return;
+            }
+        }
+        panic!("client closed before joining");
+    })
+    .await
+    .unwrap();
+
+    let sessions: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/user/sessions")
+            .cookie(other_device.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    assert_eq!(sessions[0]["canvas_id"], canvas_id.as_str());
+    assert_eq!(sessions[0]["session_id"], session_id.as_str());
+    // the owner only sees the network of the connection
+    assert_eq!(sessions[0]["connection"]["ip"], "127.0.0.0/24");
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/user/logout-all")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert!(auth_cookie(&res).value().is_empty());
+
+    // the socket is closed with the auth expired close code
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while client.next_event().await.is_some() {}
+    })
+    .await
+    .unwrap();
+    let close_code = client.close_reason().map(|reason| reason.code);
+    assert_eq!(close_code, Some(SocketClose::AuthExpired.code()));
+
+    // tokens of every device are revoked, including the refresh path
+    for revoked in [cookie, other_device] {
+        let res = test::call_service(
+            &app,
+            spa_request().uri("/api/me").cookie(revoked).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+    }
+
+    // a fresh login works right away
+    let cookie = login(&app, "alice").await;
+    let res = test::call_service(
+        &app,
+        spa_request().uri("/api/me").cookie(cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_live_state_is_unavailable_once_the_canvas_server_stopped() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    drop(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri("/user/sessions")
+            .cookie(cookie)
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+/// Id of the logged in user as listed by /api/me
+async fn user_id_of<S, B>(app: &S, cookie: &Cookie<'static>) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let me: serde_json::Value = test::call_and_read_body_json(
+        app,
+        spa_request()
+            .uri("/api/me")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    me["id"].as_str().unwrap().to_string()
+}
+
+/// Usernames of the members of the canvas
+async fn member_names<S, B>(app: &S, cookie: &Cookie<'static>, canvas_id: &str) -> Vec<String>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let members: serde_json::Value = test::call_and_read_body_json(
+        app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/members"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    members
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|member| member["username"].as_str().unwrap_or_default().to_string())
+        .collect()
+}
+
+/// Adds the users to the canvas as writers
+async fn add_writers<S, B>(app: &S, cookie: &Cookie<'static>, canvas_id: &str, usernames: &[&str])
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let entries: Vec<serde_json::Value> = usernames
+        .iter()
+        .map(|username| serde_json::json!({ "username_email": username, "access_level": "Write" }))
+        .collect();
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/users/batch"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(entries)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_account_deletion_removes_the_user_from_every_canvas() {
+    let config = ServerConfig {
+        deletion_grace: Duration::ZERO,
+        ..with_admin(test_config())
+    };
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let admin = login(&app, "admin").await;
+    let bob = register_and_login(&app, "bob").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (first_id, alice) = create_canvas(&app, alice).await;
+    let (second_id, alice) = create_canvas(&app, alice).await;
+    for canvas_id in [&first_id, &second_id] {
+        add_writers(&app, &alice, canvas_id, &["bob"]).await;
+    }
+    let (owned_id, bob) = create_canvas(&app, bob).await;
+
+    let delete_account = |cookie: &Cookie<'static>, confirm: &str| {
+        spa_request()
+            .method(actix_web::http::Method::DELETE)
+            .uri(&format!("/api/me?confirm={confirm}"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request()
+    };
+
+    // the username has to be echoed
+    let res = test::call_service(&app, delete_account(&bob, "alice")).await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "account.deletion_confirmation_required");
+
+    // owned canvases have to be transferred or deleted first, for admins as well
+    let res = test::call_service(&app, delete_account(&bob, "bob")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "account.owns_canvases");
+    assert_eq!(body["params"]["count"], "1");
+    let alice_id = user_id_of(&app, &alice).await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!(
+                "/admin/api/users/{alice_id}/delete?confirm={alice_id}"
+            ))
+            .cookie(admin.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(user_id_of(&app, &alice).await, alice_id);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::DELETE)
+            .uri(&format!("/canvas/{owned_id}"))
+            .cookie(bob.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let bob = auth_cookie(&res);
+
+    // a deleted canvas is still owned until it is purged, a restore would bring it back ownerless
+    let res = test::call_service(&app, delete_account(&bob, "bob")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["params"]["count"], "1");
+    assert_eq!(state.purge_deleted_canvases().await.unwrap().unwrap(), 1);
+
+    let res = test::call_service(&app, delete_account(&bob, "bob")).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert!(auth_cookie(&res).value().is_empty());
+
+    for canvas_id in [&first_id, &second_id] {
+        assert_eq!(member_names(&app, &alice, canvas_id).await, ["alice"]);
+    }
+    let removals = std::fs::read_to_string(&config.canvas_event_log)
+        .unwrap()
+        .lines()
+        .filter(|line| line.contains("UserCanvasRemoved"))
+        .count();
+    assert_eq!(removals, 2);
+
+    // the token of the deleted account is revoked and the name can't log in anymore
+    let res = test::call_service(&app, spa_request().uri("/api/me").cookie(bob).to_request()).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_ne!(
+        login_status(&app, "bob", "password").await,
+        StatusCode::FOUND
+    );
+}
+
+#[actix_web::test]
+async fn test_claims_of_unknown_users_are_reported_and_removed_on_startup() {
+    let config = test_config();
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    let bob = register_and_login(&app, "bob").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+    add_writers(&app, &alice, &canvas_id, &["bob"]).await;
+
+    // deleted by a server that crashed before the CanvasStore removed the membership
+    let bob_id = user_id_of(&app, &bob).await;
+    append_lines(
+        &config.user_event_log,
+        &[
+            &serde_json::json!({"type": "UserDeleted", "timestamp": 1, "user_id": bob_id})
+                .to_string(),
+        ],
+    );
+
+    let strict = ServerConfig {
+        replay_mode: ReplayMode::Strict,
+        ..config.clone()
+    };
+    let error = webserver::bootstrap(strict).err().unwrap();
+    assert!(error.to_string().contains(&format!(
+        "Unknown user {bob_id} claims canvases {canvas_id}"
+    )));
+
+    let tolerant = ServerConfig {
+        replay_mode: ReplayMode::Tolerant,
+        ..config.clone()
+    };
+    let (state, canvas_server) = webserver::bootstrap(tolerant).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    assert_eq!(state.replay_issues().canvas.len(), 1);
+    let app = test::init_service(build_app(&state)).await;
+    let alice = login(&app, "alice").await;
+    assert_eq!(member_names(&app, &alice, &canvas_id).await, ["alice"]);
+
+    // the repair is persisted, the next start is clean
+    let strict = ServerConfig {
+        replay_mode: ReplayMode::Strict,
+        ..config
+    };
+    assert!(webserver::bootstrap(strict).is_ok());
+}
+
+/// Requests a password reset, returns the response status and body
+async fn request_password_reset<S, B>(app: &S, username_email: &str) -> (StatusCode, web::Bytes)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/user/request-password-reset")
+            // reset links must not follow the Host header
+            .insert_header((header::HOST, "attacker.example"))
+            .set_form([("username_email", username_email)])
+            .to_request(),
+    )
+    .await;
+    let status = res.status();
+    (status, test::read_body(res).await)
+}
+
+async fn reset_password<S, B>(app: &S, token: &str, password: &str) -> StatusCode
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/user/reset-password")
+            .set_form([
+                ("token", token),
+                ("password1", password),
+                ("password2", password),
+            ])
+            .to_request(),
+    )
+    .await
+    .status()
+}
+
+async fn login_status<S, B>(app: &S, username: &str, password: &str) -> StatusCode
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/login")
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("username_email", username), ("password", password)])
+            .to_request(),
+    )
+    .await
+    .status()
+}
+
+/// Token of the latest reset link the notifier received
+fn latest_reset_token(notifier: &RecordingNotifier) -> String {
+    let notification = notifier
+        .password_resets()
+        .pop()
+        .expect("no reset link sent");
+    let (_, token) = notification.reset_link.split_once("token=").unwrap();
+    token.to_string()
+}
+
+#[actix_web::test]
+async fn test_password_reset_revokes_sessions() {
+    let notifier = Arc::new(RecordingNotifier::default());
+    let (state, canvas_server) = webserver::bootstrap(ServerConfig {
+        notifier: notifier.clone(),
+        public_origin: "https://canvas.example.org".parse().unwrap(),
+        ..test_config()
+    })
+    .unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+
+    // the response does not tell whether the account exists
+    let existing = request_password_reset(&app, "alice@example.com").await;
+    let unknown = request_password_reset(&app, "nobody@example.com").await;
+    assert_eq!(existing.0, StatusCode::OK);
+    assert_eq!(existing, unknown);
+    let sent = notifier.password_resets();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].email, "alice@example.com");
+    assert!(sent[0]
+        .reset_link
+        .starts_with("https://canvas.example.org/user/reset-password?token="));
+    let token = latest_reset_token(&notifier);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/user/reset-password?token={token}"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(page.contains(&token));
+
+    assert_eq!(
+        reset_password(&app, &token, "new-password").await,
+        StatusCode::FOUND
+    );
+
+    // sessions from before the reset are revoked, only the new password works
+    let res = test::call_service(
+        &app,
+        spa_request().uri("/api/me").cookie(cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(
+        login_status(&app, "alice", "password").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        login_status(&app, "alice", "new-password").await,
+        StatusCode::FOUND
+    );
+
+    // tokens are single use
+    assert_eq!(
+        reset_password(&app, &token, "another-password").await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        login_status(&app, "alice", "new-password").await,
+        StatusCode::FOUND
+    );
+}
+
+#[actix_web::test]
+async fn test_password_reset_tokens_expire_and_are_replaced() {
+    let notifier = Arc::new(RecordingNotifier::default());
+    let clock = Arc::new(ManualClock::starting_now());
+    let config = ServerConfig {
+        notifier: notifier.clone(),
+        clock: clock.clone(),
+        ..test_config()
+    };
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    register_and_login(&app, "alice").await;
+
+    request_password_reset(&app, "alice").await;
+    let expired = latest_reset_token(&notifier);
+    clock.advance(recovery::RESET_TOKEN_TTL + Duration::from_secs(1));
+    assert_eq!(
+        reset_password(&app, &expired, "new-password").await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // a newer token replaces the outstanding one
+    request_password_reset(&app, "alice").await;
+    let replaced = latest_reset_token(&notifier);
+    request_password_reset(&app, "alice").await;
+    let outstanding = latest_reset_token(&notifier);
+    assert_eq!(
+        reset_password(&app, &replaced, "new-password").await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // forged tokens are refused before the store is asked
+    let last = if outstanding.ends_with('0') { "1" } else { "0" };
+    let forged = format!("{}{last}", &outstanding[..outstanding.len() - 1]);
+    assert_eq!(
+        reset_password(&app, &forged, "new-password").await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // outstanding tokens survive a restart, the replayed store accepts them
+    // both instances share the eventlogs of the config
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    assert_eq!(
+        reset_password(&app, &replaced, "new-password").await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        reset_password(&app, &outstanding, "new-password").await,
+        StatusCode::FOUND
+    );
+    assert_eq!(
+        login_status(&app, "alice", "new-password").await,
+        StatusCode::FOUND
+    );
+}
+
+fn count_lines(path: &str) -> usize {
+    std::fs::read_to_string(path).unwrap().lines().count()
+}
+
+fn demo_seed() -> SeedFile {
+    serde_json::from_value(serde_json::json!({
+        "users": [
+            { "username": "alice", "email": "alice@example.com", "password": "password" },
+            { "username": "bob", "email": "bob@example.com", "password": "password" }
+        ],
+        "canvases": [{
+            "name": "Demo",
+            "owner": "alice",
+            "members": [{ "username": "bob", "access_level": "Write" }],
+            "shapes": [
+                {
+                    "type": "Line", "id": "l1", "temporary": false,
+                    "borderColor": "#000", "fillColor": "#000",
+                    "from": { "x": 0, "y": 0 }, "to": { "x": 5, "y": 5 }
+                },
+                {
+                    "type": "Circle", "id": "c1", "temporary": false,
+                    "borderColor": "#000", "fillColor": "#fff",
+                    "center": { "x": 20, "y": 20 }, "radius": 10.0
+                }
+            ]
+        }]
+    }))
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn test_seeding_is_idempotent_and_seeded_users_can_log_in() {
+    // the sample has to stay loadable
+    let sample = SeedFile::load("seed.example.toml").unwrap();
+    assert_eq!(sample.users.len(), 3);
+    let shapes: Vec<usize> = sample
+        .canvases
+        .iter()
+        .map(|canvas| canvas.shapes.len())
+        .collect();
+    assert_eq!(shapes, vec![3, 1]);
+
+    let config = test_config();
+    let shape_limits = config.shape_limits.clone();
+
+    let (state, _) = webserver::bootstrap(config.clone()).unwrap();
+    let report = seed::seed(&state, demo_seed(), &shape_limits)
+        .await
+        .unwrap();
+    assert_eq!(
+        report,
+        SeedReport {
+            users_created: 2,
+            users_existing: 0,
+            canvases_created: 1,
+            canvases_existing: 0,
+            members_added: 1,
+            shapes_added: 2,
+        }
+    );
+    let user_log_lines = count_lines(&config.user_event_log);
+    let canvas_log_lines = count_lines(&config.canvas_event_log);
+
+    // a restarted server replays the seeded state, seeding again changes nothing
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let report = seed::seed(&state, demo_seed(), &shape_limits)
+        .await
+        .unwrap();
+    assert!(report.is_unchanged());
+    assert_eq!(report.users_existing, 2);
+    assert_eq!(report.canvases_existing, 1);
+    assert_eq!(count_lines(&config.user_event_log), user_log_lines);
+    assert_eq!(count_lines(&config.canvas_event_log), canvas_log_lines);
+
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = login(&app, "alice").await;
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(canvases["owned"][0]["name"], "Demo");
+    let canvas_id = canvases["owned"][0]["id"].as_str().unwrap().to_string();
+
+    let cookie = login(&app, "bob").await;
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(canvases["shared"][0]["id"], canvas_id.as_str());
+    assert_eq!(canvases["shared"][0]["access_level"], "Write");
+
+    let shapes: Vec<String> = std::fs::read_to_string(canvas_log_path(&canvas_id))
+        .unwrap()
+        .lines()
+        .map(
+            |line| match serde_json::from_str::<CanvasEvents>(line).unwrap() {
+                CanvasEvents::CanvasLogHeader { canvasId, .. } => format!("header of {canvasId}"),
+                CanvasEvents::ShapeAdded { shape, .. } => shape.get_id().to_string(),
+                event => panic!("unexpected seeded event {event:?}"),
+            },
+        )
+        .collect();
+    assert_eq!(
+        shapes,
+        [format!("header of {canvas_id}"), "l1".into(), "c1".into()]
+    );
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_seeding_reports_the_failed_entry() {
+    let (state, _) = webserver::bootstrap(test_config()).unwrap();
+    let seed_file: SeedFile = serde_json::from_value(serde_json::json!({
+        "users": [{ "username": "alice", "email": "alice@example.com", "password": "password" }],
+        "canvases": [{
+            "name": "Demo",
+            "owner": "alice",
+            "members": [{ "username": "nobody", "access_level": "Read" }]
+        }]
+    }))
+    .unwrap();
+
+    let error = seed::seed(&state, seed_file, &Default::default())
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.entry,
+        r#"member "nobody" of canvas "Demo" of "alice""#
+    );
+    assert_eq!(error.reason, r#"unknown user "nobody""#);
+}
+
+#[actix_web::test]
+async fn test_broken_setup_is_refused_before_any_eventlog_is_created() {
+    let missing = std::env::temp_dir().join(format!("missing-{}", nanoid::nanoid!(8)));
+    let config = ServerConfig {
+        template_dir: missing.join("templates").display().to_string(),
+        user_event_log: missing
+            .join("data")
+            .join("user_eventlog.jsonl")
+            .display()
+            .to_string(),
+        ..test_config()
+    };
+    let error = webserver::bootstrap(config.clone())
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(error.starts_with("Refusing to start"), "{error}");
+    assert!(error.contains(&format!(
+        "data dir '{}' not found; create it or start with --create-data-dirs",
+        missing.join("data").display()
+    )));
+    if !cfg!(feature = "embed-frontend") {
+        assert!(error.contains(&format!(
+            "templates dir '{}' not found; set --template-dir",
+            missing.join("templates").display()
+        )));
+    }
+    assert!(!missing.join("data").exists());
+
+    std::fs::create_dir_all(missing.join("templates")).unwrap();
+    let (state, _) = webserver::bootstrap(with_admin(ServerConfig {
+        create_data_dirs: true,
+        dist_dir: missing.join("dist").display().to_string(),
+        ..config
+    }))
+    .unwrap();
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = login(&app, "admin").await;
+    let report: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/admin/api/preflight")
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    let findings = report["findings"].as_array().unwrap();
+    let data = findings
+        .iter()
+        .find(|finding| finding["path"] == missing.join("data").display().to_string())
+        .unwrap();
+    assert_eq!(data["severity"], "ok");
+    if !cfg!(feature = "embed-frontend") {
+        let dist = findings
+            .iter()
+            .find(|finding| finding["component"] == "dist")
+            .unwrap();
+        assert_eq!(dist["severity"], "warning");
+    }
+    let _ = std::fs::remove_dir_all(missing);
+}
+
+#[actix_web::test]
+async fn test_tolerant_replay_skips_broken_events() {
+    let config = with_admin(test_config());
+    let (state, _) = webserver::bootstrap(config.clone()).unwrap();
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = register_and_login(&app, "alice").await;
+    let (first_id, cookie) = create_canvas(&app, cookie).await;
+    let (second_id, _) = create_canvas(&app, cookie).await;
+
+    // grant on a canvas that never existed, written by a crashed or buggy server
+    let mut canvas_log = std::fs::read_to_string(&config.canvas_event_log).unwrap();
+    canvas_log.push_str(
+        r#"{"type":"UserCanvasAdded","timestamp":1,"user_id":"x","initiator_user_id":"x","canvas_id":"missing","access_level":"Read"}"#,
+    );
+    canvas_log.push('\n');
+    std::fs::write(&config.canvas_event_log, canvas_log).unwrap();
+
+    let strict = ServerConfig {
+        replay_mode: ReplayMode::Strict,
+        ..config.clone()
+    };
+    let error = webserver::bootstrap(strict).err().unwrap();
+    assert!(error
+        .to_string()
+        .contains("Canvas missing for user x does not exist"));
+
+    let tolerant = ServerConfig {
+        replay_mode: ReplayMode::Tolerant,
+        ..config
+    };
+    let (state, _) = webserver::bootstrap(tolerant).unwrap();
+    let issues = &state.replay_issues().canvas;
+    assert_eq!(issues.len(), 1);
+    assert!(issues[0].skipped);
+    assert!(state.replay_issues().user.is_empty());
+
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = login(&app, "alice").await;
+    let canvases: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    let mut owned: Vec<&str> = canvases["owned"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|canvas| canvas["id"].as_str().unwrap())
+        .collect();
+    owned.sort();
+    let mut expected = vec![first_id.as_str(), second_id.as_str()];
+    expected.sort();
+    assert_eq!(owned, expected);
+
+    let cookie = login(&app, "admin").await;
+    let listed: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri("/admin/api/replay-issues")
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(listed["canvas"][0]["kind"], "UnknownReference");
+    assert!(listed["user"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn test_canvas_logs_are_bound_to_their_canvas() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (fresh_id, cookie) = create_canvas(&app, cookie).await;
+    let (copied_id, cookie) = create_canvas(&app, cookie).await;
+    let base_url = serve(&state);
+
+    // the first event of a fresh canvas is preceded by the header
+    let mut client = CanvasClient::connect(&base_url, cookie.value(), &fresh_id)
+        .await
+        .unwrap();
+    let session_id = client.session_id().to_string();
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if matches!(event, CanvasEvents::UserJoined { sessionId, .. } if sessionId == session_id)
+            {
+                return;
+            }
+        }
+        panic!("client closed before joining");
+    })
+    .await
+    .unwrap();
+    let fresh_log = std::fs::read_to_string(canvas_log_path(&fresh_id)).unwrap();
+    let header: CanvasEvents = serde_json::from_str(fresh_log.lines().next().unwrap()).unwrap();
+    assert!(
+        matches!(header, CanvasEvents::CanvasLogHeader { canvasId, .. } if canvasId == fresh_id)
+    );
+    client.close().await.unwrap();
+
+    // a log copied to another canvas is refused
+    std::fs::write(canvas_log_path(&copied_id), fresh_log).unwrap();
+    let mut client = CanvasClient::connect(&base_url, cookie.value(), &copied_id)
+        .await
+        .unwrap();
+    let code = actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if let CanvasEvents::ServerNotice { code, .. } = event {
+                return code;
+            }
+        }
+        panic!("client closed without notice");
+    })
+    .await
+    .unwrap();
+    assert_eq!(code, "canvas.log_mismatch");
+
+    remove_canvas_log(&fresh_id).await;
+    remove_canvas_log(&copied_id).await;
+}
+
+#[actix_web::test]
+async fn test_canvas_events_export_and_import_round_trip() {
+    let (state, canvas_server) = webserver::bootstrap(with_admin(test_config())).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let admin = login(&app, "admin").await;
+    let alice = register_and_login(&app, "alice").await;
+    let bob = register_and_login(&app, "bob").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+
+    // a cold canvas, nobody connected since its eventlog was written
+    let line = |shape_id: &str, x: i32| {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"s1","timestamp":{x},"userId":"alice","shape":{{"type":"Line","id":"{shape_id}","temporary":false,"borderColor":"#000","fillColor":"#000","from":{{"x":0,"y":0}},"to":{{"x":{x},"y":5}}}}}}"##
+        )
+    };
+    let lines = [
+        format!(r#"{{"type":"CanvasLogHeader","timestamp":0,"canvasId":"{canvas_id}"}}"#),
+        line("l1", 1),
+        line("l2", 2),
+        r#"{"type":"ShapeRemoved","origin":"s1","timestamp":3,"shapeId":"l1"}"#.to_string(),
+        line("l3", 4),
+    ];
+    std::fs::write(canvas_log_path(&canvas_id), lines.join("\n") + "\n").unwrap();
+
+    let export = |cookie: &Cookie<'static>, query: &str| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/export/events{query}"))
+            .cookie(cookie.clone())
+            .to_request()
+    };
+
+    let res = test::call_service(&app, export(&bob, "")).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(&app, export(&alice, "")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/x-ndjson"
+    );
+    let disposition = res
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(disposition.starts_with(&format!("attachment; filename=\"{canvas_id}-")));
+    let exported = test::read_body(res).await;
+    assert_eq!(
+        String::from_utf8(exported.to_vec()).unwrap(),
+        lines.join("\n") + "\n"
+    );
+
+    // incremental backups only fetch the lines after the last one they have
+    let increment = test::call_and_read_body(&app, export(&admin, "?since_seq=3")).await;
+    assert_eq!(
+        String::from_utf8(increment.to_vec()).unwrap(),
+        format!("{}\n{}\n", lines[3], lines[4])
+    );
+
+    let import = |cookie: &Cookie<'static>, body: web::Bytes| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/canvas/import-events?name=Restored")
+            .cookie(cookie.clone())
+            .insert_header((header::CONTENT_TYPE, "application/x-ndjson"))
+            .set_payload(body)
+            .to_request()
+    };
+
+    let res = test::call_service(&app, import(&alice, exported.clone())).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, import(&admin, web::Bytes::from("{\"type\":\n"))).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let res = test::call_service(&app, import(&admin, exported)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let imported: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(imported["events"], 5);
+    let imported_id = imported["canvas_id"].as_str().unwrap().to_string();
+    assert_ne!(imported_id, canvas_id);
+
+    let original = replay::replay_log(&canvas_log_path(&canvas_id), None).unwrap();
+    let copy = replay::replay_log(&canvas_log_path(&imported_id), None).unwrap();
+    assert_eq!(copy.state.shapes, original.state.shapes);
+    assert_eq!(copy.state.shapes.len(), 2);
+    assert_eq!(
+        binding::read_claimed_id(&canvas_log_path(&imported_id))
+            .unwrap()
+            .as_deref(),
+        Some(imported_id.as_str())
+    );
+
+    let _ = std::fs::remove_file(canvas_log_path(&canvas_id));
+    let _ = std::fs::remove_file(canvas_log_path(&imported_id));
+}
+
+/// Creates an API token for the canvas, returns the response status and body
+async fn create_api_token<S, B>(
+    app: &S,
+    cookie: &Cookie<'static>,
+    canvas_id: &str,
+    body: serde_json::Value,
+) -> (StatusCode, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/tokens"))
+            .cookie(cookie.clone())
+            .set_json(body)
+            .to_request(),
+    )
+    .await;
+    let status = res.status();
+    let body = test::read_body(res).await;
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+/// Status of a request authenticated with the API token only
+async fn bearer_status<S, B>(app: &S, token: &str, uri: &str) -> StatusCode
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    test::call_service(
+        app,
+        spa_request()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await
+    .status()
+}
+
+#[actix_web::test]
+async fn test_canvas_api_tokens_are_scoped_revocable_and_expire() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let config = ServerConfig {
+        clock: clock.clone(),
+        ..test_config()
+    };
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let (other_canvas_id, cookie) = create_canvas(&app, cookie).await;
+
+    let (status, _) = create_api_token(
+        &app,
+        &cookie,
+        &canvas_id,
+        serde_json::json!({ "label": "ci", "access_level": "Moderate" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (status, created) = create_api_token(
+        &app,
+        &cookie,
+        &canvas_id,
+        serde_json::json!({ "label": "ci", "access_level": "Write" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let token = created["token"].as_str().unwrap().to_string();
+    let token_id = created["id"].as_str().unwrap().to_string();
+    assert!(created.get("secret_hash").is_none());
+
+    // only the JSON endpoints and the websocket of the canvas accept the token
+    let flags = format!("/canvas/{canvas_id}/flags");
+    assert_eq!(bearer_status(&app, &token, &flags).await, StatusCode::OK);
+    assert_eq!(
+        bearer_status(&app, &token, &format!("/canvas/{other_canvas_id}/flags")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        bearer_status(&app, &token, "/api/me").await,
+        StatusCode::FORBIDDEN
+    );
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    // HTML pages and routes creating canvases are for users only
+    assert_eq!(
+        bearer_status(&app, &token, &format!("/canvas/{canvas_id}/print")).await,
+        StatusCode::FORBIDDEN
+    );
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/duplicate"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        bearer_status(&app, &format!("{token}x"), &flags).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let listed: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/tokens"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["label"], "ci");
+    assert!(listed[0].get("secret_hash").is_none());
+
+    // revocation closes the sessions of the token
+    let base_url = serve(&state);
+    let mut client = CanvasClient::connect(&base_url, &token, &canvas_id)
+        .await
+        .unwrap();
+    let session_id = client.session_id().to_string();
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if matches!(event, CanvasEvents::UserJoined { sessionId, .. } if sessionId == session_id)
+            {
+                return;
+            }
+        }
+        panic!("client closed before joining");
+    })
+    .await
+    .unwrap();
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::DELETE)
+            .uri(&format!("/canvas/{canvas_id}/tokens/{token_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while client.next_event().await.is_some() {}
+    })
+    .await
+    .unwrap();
+    let close_code = client.close_reason().map(|reason| reason.code);
+    assert_eq!(close_code, Some(SocketClose::AuthExpired.code()));
+    assert_eq!(
+        bearer_status(&app, &token, &flags).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // expired tokens are rejected without being revoked
+    let (status, created) = create_api_token(
+        &app,
+        &cookie,
+        &canvas_id,
+        serde_json::json!({
+            "label": "nightly",
+            "access_level": "Read",
+            "expires_at": clock.now_ms() + 60_000,
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let expiring = created["token"].as_str().unwrap().to_string();
+    assert_eq!(bearer_status(&app, &expiring, &flags).await, StatusCode::OK);
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(
+        bearer_status(&app, &expiring, &flags).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+/// Opens the canvas for visitors without an account at the given level
+async fn open_for_guests<S, B>(app: &S, cookie: &Cookie<'static>, canvas_id: &str, level: &str)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/settings"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!({ "guest_access": level }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+async fn guest_status<S, B>(
+    app: &S,
+    guest: &Cookie<'static>,
+    method: actix_web::http::Method,
+    uri: &str,
+) -> StatusCode
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let res = test::call_service(
+        app,
+        spa_request()
+            .method(method)
+            .uri(uri)
+            .cookie(guest.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request(),
+    )
+    .await;
+    res.status()
+}
+
+#[actix_web::test]
+async fn test_guests_join_and_draw_on_open_canvases() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let page = format!("/canvas/{canvas_id}");
+
+    // closed canvases send visitors to the login as before
+    let res = test::call_service(&app, spa_request().uri(&page).to_request()).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert!(res
+        .response()
+        .cookies()
+        .all(|cookie| cookie.name() != user::AUTH_COOKIE_NAME));
+
+    open_for_guests(&app, &cookie, &canvas_id, "OpenWrite").await;
+    let res = test::call_service(&app, spa_request().uri(&page).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let guest = auth_cookie(&res);
+
+    // guests only see their canvas, everything else is management
+    let get = actix_web::http::Method::GET;
+    let post = actix_web::http::Method::POST;
+    let access: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/access"))
+            .cookie(guest.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(access["access_level"], "Write");
+    for (method, uri) in [
+        (get.clone(), format!("/canvas/{canvas_id}/members")),
+        (get.clone(), format!("/canvas/{canvas_id}/tokens")),
+        (get.clone(), "/api/me".to_string()),
+        (post.clone(), format!("/canvas/{canvas_id}/settings")),
+        (post.clone(), format!("/canvas/{canvas_id}")),
+        (post, "/canvas".to_string()),
+    ] {
+        assert_eq!(
+            guest_status(&app, &guest, method, &uri).await,
+            StatusCode::FORBIDDEN,
+            "{uri}"
+        );
+    }
+
+    let base_url = serve(&state);
+    let drawer = CanvasClient::connect(&base_url, guest.value(), &canvas_id)
+        .await
+        .unwrap();
+    drawer
+        .add_shape(Shape::Line {
+            id: "l1".to_string(),
+            temporary: false,
+            borderColor: "#000".to_string(),
+            fillColor: "#000".to_string(),
+            attributes: Default::default(),
+            from: Point2D { x: 0, y: 0 },
+            to: Point2D { x: 5, y: 5 },
+        })
+        .await
+        .unwrap();
+
+    // the provenance names the guest as creator
+    let mut provenance = serde_json::Value::Null;
+    for _ in 0..50 {
+        let res = test::call_service(
+            &app,
+            spa_request()
+                .uri(&format!("/canvas/{canvas_id}/shapes/l1/provenance"))
+                .cookie(cookie.clone())
+                .insert_header((header::ACCEPT, "application/json"))
+                .to_request(),
+        )
+        .await;
+        if res.status() == StatusCode::OK {
+            provenance = test::read_body_json(res).await;
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    let creator = provenance["last_modified_by"].as_str().unwrap();
+    assert!(creator.starts_with("guest_"), "{creator}");
+
+    drawer.close().await.unwrap();
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_guests_are_not_persisted_and_can_be_disabled() {
+    let config = test_config();
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let page = format!("/canvas/{canvas_id}");
+    open_for_guests(&app, &cookie, &canvas_id, "OpenRead").await;
+    let res = test::call_service(&app, spa_request().uri(&page).to_request()).await;
+    let guest = auth_cookie(&res);
+    let get = actix_web::http::Method::GET;
+    assert_eq!(
+        guest_status(&app, &guest, get.clone(), &page).await,
+        StatusCode::OK
+    );
+
+    let members = |cookie: Cookie<'static>| {
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}/members"))
+            .cookie(cookie)
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request()
+    };
+    let listed: serde_json::Value =
+        test::call_and_read_body_json(&app, members(cookie.clone())).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+
+    // the replayed store knows the guest access of the canvas but not the guest
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    let listed: serde_json::Value =
+        test::call_and_read_body_json(&app, members(cookie.clone())).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert!(!listed.to_string().contains("guest_"));
+    assert_eq!(
+        guest_status(&app, &guest, get.clone(), &page).await,
+        StatusCode::OK
+    );
+
+    // disabled guests neither keep their cookie nor are admitted anew
+    let config = ServerConfig {
+        guest_policy: GuestPolicy {
+            enabled: false,
+            ..GuestPolicy::default()
+        },
+        ..config
+    };
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    assert_eq!(
+        guest_status(&app, &guest, get, &page).await,
+        StatusCode::FOUND
+    );
+    let res = test::call_service(&app, spa_request().uri(&page).to_request()).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert!(res
+        .response()
+        .cookies()
+        .all(|cookie| cookie.name() != user::AUTH_COOKIE_NAME));
+}
+
+fn protocol_request(canvas_id: &str, token: &str) -> test::TestRequest {
+    websocket_request(canvas_id).insert_header((
+        header::SEC_WEBSOCKET_PROTOCOL,
+        format!("{WS_PROTOCOL}, {WS_TOKEN_PROTOCOL_PREFIX}{token}"),
+    ))
+}
+
+#[actix_web::test]
+async fn test_websocket_handshake_tokens() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let config = ServerConfig {
+        clock: clock.clone(),
+        ..test_config()
+    };
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let stranger = register_and_login(&app, "stranger").await;
+
+    // the canvas protocol is selected, the token is never echoed
+    let res = test::call_service(
+        &app,
+        protocol_request(&canvas_id, cookie.value()).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(
+        res.headers().get(header::SEC_WEBSOCKET_PROTOCOL).unwrap(),
+        WS_PROTOCOL
+    );
+
+    let (status, created) = create_api_token(
+        &app,
+        &cookie,
+        &canvas_id,
+        serde_json::json!({ "label": "bot", "access_level": "Read" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let api_token = created["token"].as_str().unwrap();
+    let res = test::call_service(&app, protocol_request(&canvas_id, api_token).to_request()).await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    // the cookie is tried first, the token of the subprotocol is ignored
+    let res = test::call_service(
+        &app,
+        protocol_request(&canvas_id, cookie.value())
+            .cookie(stranger)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // the query is ignored unless enabled
+    let res = test::call_service(
+        &app,
+        websocket_request(&canvas_id)
+            .uri(&format!("/ws/canvas/{canvas_id}?auth={}", cookie.value()))
+            .to_request(),
+    )
+    .await;
+    assert_ne!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    // expired tokens are rejected before the upgrade, only the cookie is refreshed
+    clock.advance(Duration::from_secs(JWT_LIFETIME_SECS as u64 + 1));
+    let res = test::call_service(
+        &app,
+        protocol_request(&canvas_id, cookie.value()).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(
+        &app,
+        websocket_request(&canvas_id).cookie(cookie).to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+#[actix_web::test]
+async fn test_websocket_query_token_is_opt_in() {
+    let config = ServerConfig {
+        websocket_auth: WebSocketAuth { query_token: true },
+        ..test_config()
+    };
+    let (state, canvas_server) = webserver::bootstrap(config).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_canvas(&app, cookie).await;
+    let query_request = || {
+        websocket_request(&canvas_id)
+            .uri(&format!("/ws/canvas/{canvas_id}?auth={}", cookie.value()))
+    };
+
+    let res = test::call_service(&app, query_request().to_request()).await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert!(res.headers().get(header::SEC_WEBSOCKET_PROTOCOL).is_none());
+
+    // the subprotocol is preferred over the query
+    let res = test::call_service(
+        &app,
+        query_request()
+            .insert_header((
+                header::SEC_WEBSOCKET_PROTOCOL,
+                format!("{WS_TOKEN_PROTOCOL_PREFIX}invalid"),
+            ))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    remove_canvas_log(&canvas_id).await;
+}
+
+/// Waits for the next notice of the client and returns its code
+async fn next_notice_code(client: &mut CanvasClient) -> String {
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if let CanvasEvents::ServerNotice { code, .. } = event {
+                return code;
+            }
+        }
+        panic!("client closed before receiving a notice");
+    })
+    .await
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn test_maintenance_rejects_changes_until_it_is_disabled() {
+    let (state, canvas_server) = webserver::bootstrap(with_admin(test_config())).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let admin = login(&app, "admin").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+    let base_url = serve(&state);
+    let mut client = CanvasClient::connect(&base_url, alice.value(), &canvas_id)
+        .await
+        .unwrap();
+    let session_id = client.session_id().to_string();
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = client.next_event().await {
+            if matches!(event, CanvasEvents::UserJoined { sessionId, .. } if sessionId == session_id)
+            {
+                return;
+            }
+        }
+        panic!("client closed before joining");
+    })
+    .await
+    .unwrap();
+
+    let set_maintenance = |cookie: &Cookie<'static>, enabled: bool| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/admin/api/maintenance")
+            .cookie(cookie.clone())
+            .set_json(serde_json::json!({"enabled": enabled, "message": "backup until 14:00"}))
+            .to_request()
+    };
+    let create = || {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/canvas")
+            .insert_header((header::ACCEPT, "application/json"))
+            .cookie(alice.clone())
+            .set_form([("name", "During maintenance")])
+            .to_request()
+    };
+    let line = |id: &str| Shape::Line {
+        id: id.to_string(),
+        temporary: false,
+        borderColor: "#000".to_string(),
+        fillColor: "#000".to_string(),
+        attributes: Default::default(),
+        from: Point2D { x: 0, y: 0 },
+        to: Point2D { x: 5, y: 5 },
+    };
+
+    let res = test::call_service(&app, set_maintenance(&alice, true)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = test::call_service(&app, set_maintenance(&admin, true)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        next_notice_code(&mut client).await,
+        "maintenance.read_only_note"
+    );
+
+    // the open session stays connected, its changes are rejected
+    client.add_shape(line("l1")).await.unwrap();
+    assert_eq!(
+        next_notice_code(&mut client).await,
+        "maintenance.read_only_note"
+    );
+
+    let res = test::call_service(&app, create()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "300");
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "maintenance.read_only_note");
+    assert_eq!(body["params"]["note"], "backup until 14:00");
+
+    // reading and logging in keep working
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(alice.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri("/api/canvases")
+            .cookie(alice.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    login(&app, "alice").await;
+
+    let res = test::call_service(&app, set_maintenance(&admin, false)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(next_notice_code(&mut client).await, "maintenance.ended");
+
+    // without reconnecting
+    client.add_shape(line("l2")).await.unwrap();
+    let shapes = actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let shapes: serde_json::Value = test::call_and_read_body_json(
+                &app,
+                spa_request()
+                    .uri(&format!("/canvas/{canvas_id}/shapes"))
+                    .cookie(alice.clone())
+                    .to_request(),
+            )
+            .await;
+            if shapes["total"] == 1 {
+                return shapes;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(shapes["shapes"][0]["id"], "l2");
+    // JSON clients get the created canvas and its final name instead of the redirect
+    let res = test::call_service(&app, create()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    client.close().await.unwrap();
+    remove_canvas_log(&canvas_id).await;
+}
+
+fn append_lines(path: &str, lines: &[&str]) {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap();
+    for line in lines {
+        writeln!(file, "{line}").unwrap();
+    }
+}
+
+#[actix_web::test]
+async fn test_digests_cover_new_events_once_per_period() {
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    let added = |id: &str| {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"o","timestamp":1,"userId":"someone","shape":{{"type":"Line","id":"{id}","temporary":false,"borderColor":"#000000","fillColor":"#000000","from":{{"x":0,"y":0}},"to":{{"x":1,"y":1}}}}}}"##
+        )
+    };
+    let clock = Arc::new(ManualClock::starting_now());
+    let notifier = Arc::new(RecordingNotifier::default());
+    let config = ServerConfig {
+        clock: clock.clone(),
+        notifier: notifier.clone(),
+        ..test_config()
+    };
+    let (state, canvas_server) = webserver::bootstrap(config.clone()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let cookie = register_and_login(&app, "alice").await;
+    let (daily_id, cookie) = create_canvas(&app, cookie).await;
+    let (quiet_id, cookie) = create_canvas(&app, cookie).await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{daily_id}/settings"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(serde_json::json!({ "digest": "Daily" }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let daily_log = canvas_log_path(&daily_id);
+    let quiet_log = canvas_log_path(&quiet_id);
+    append_lines(&daily_log, &[&added("l1"), &added("l2")]);
+    append_lines(&quiet_log, &[&added("l1")]);
+    let first_seq = count_lines(&daily_log) as u64;
+
+    // the first digest covers everything so far, canvases with digests off are left out
+    let sent = state.run_digests().await.unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].canvas_id, daily_id);
+    assert_eq!((sent[0].from_seq, sent[0].to_seq), (1, first_seq));
+    assert_eq!(sent[0].shapes_added, 2);
+    assert_eq!(sent[0].contributors["someone"].shapes_added, 2);
+
+    // not due again within the day, even with new events
+    append_lines(&daily_log, &[&added("l3")]);
+    assert!(state.run_digests().await.unwrap().is_empty());
+
+    clock.advance(DAY);
+    let sent = state.run_digests().await.unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        (sent[0].from_seq, sent[0].to_seq),
+        (first_seq + 1, first_seq + 1)
+    );
+
+    // a quiet day sends nothing
+    clock.advance(DAY);
+    assert!(state.run_digests().await.unwrap().is_empty());
+    assert_eq!(notifier.canvas_digests().len(), 2);
+
+    // the last digested seq survives a restart, nothing is sent twice
+    let (state, _) = webserver::bootstrap(config).unwrap();
+    assert!(state.run_digests().await.unwrap().is_empty());
+    append_lines(&daily_log, &[&added("l4")]);
+    let sent = state.run_digests().await.unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        (sent[0].from_seq, sent[0].to_seq),
+        (first_seq + 2, first_seq + 2)
+    );
+    let owners: Vec<String> = notifier
+        .canvas_digests()
+        .into_iter()
+        .map(|(owner_id, _)| owner_id)
+        .collect();
+    assert_eq!(owners.len(), 3);
+    assert!(owners.iter().all(|owner_id| owner_id == &owners[0]));
+
+    let _ = std::fs::remove_file(daily_log);
+    let _ = std::fs::remove_file(quiet_log);
+}
+
+#[actix_web::test]
+async fn test_my_activity_lists_canvases_the_user_has_a_claim_on() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+
+    let bob = register_and_login(&app, "bob").await;
+    let alice = register_and_login(&app, "alice").await;
+    let (canvas_id, alice) = create_canvas(&app, alice).await;
+    let user_id = |cookie: Cookie<'static>| {
+        let app = &app;
+        async move {
+            let me: serde_json::Value = test::call_and_read_body_json(
+                app,
+                spa_request().uri("/api/me").cookie(cookie).to_request(),
+            )
+            .await;
+            me["id"].as_str().unwrap().to_string()
+        }
+    };
+    let activity = |cookie: Cookie<'static>| {
+        let app = &app;
+        async move {
+            let activity: serde_json::Value = test::call_and_read_body_json(
+                app,
+                spa_request()
+                    .uri("/api/me/activity")
+                    .cookie(cookie)
+                    .to_request(),
+            )
+            .await;
+            activity
+        }
+    };
+    let added = |id: &str, user_id: &str, timestamp: u64| {
+        format!(
+            r##"{{"type":"ShapeAdded","origin":"o","timestamp":{timestamp},"userId":"{user_id}","shape":{{"type":"Line","id":"{id}","temporary":false,"borderColor":"#000000","fillColor":"#000000","from":{{"x":0,"y":0}},"to":{{"x":1,"y":1}}}}}}"##
+        )
+    };
+
+    let alice_id = user_id(alice.clone()).await;
+    let bob_id = user_id(bob.clone()).await;
+    let log = canvas_log_path(&canvas_id);
+    append_lines(
+        &log,
+        &[
+            &added("l1", &alice_id, 10),
+            &added("l2", &bob_id, 20),
+            &added("l3", &alice_id, 30),
+        ],
+    );
+
+    let mine = activity(alice.clone()).await;
+    assert_eq!(mine["canvas"][0]["id"], canvas_id.as_str());
+    assert_eq!(mine["canvas"][0]["shapes_added"], 2);
+    assert_eq!(mine["canvas"][0]["first_active_at"], 10);
+    assert_eq!(mine["canvas"][0]["last_active_at"], 30);
+    assert_eq!(mine["total"]["shapes_added"], 2);
+
+    // bob drew on the canvas, but has no claim on it
+    let theirs = activity(bob.clone()).await;
+    assert!(theirs["canvas"].as_array().unwrap().is_empty());
+    assert_eq!(theirs["total"]["shapes_added"], 0);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(alice.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("username_email", "bob"), ("access_level", "Write")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let theirs = activity(bob).await;
+    assert_eq!(theirs["canvas"][0]["shapes_added"], 1);
+
+    // new lines are picked up on the next request
+    append_lines(&log, &[&added("l4", &alice_id, 40)]);
+    let mine = activity(alice.clone()).await;
+    assert_eq!(mine["canvas"][0]["shapes_added"], 3);
+    assert_eq!(mine["canvas"][0]["last_active_at"], 40);
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri("/user/profile")
+            .cookie(alice)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let _ = std::fs::remove_file(log);
+}
+
+#[actix_web::test]
+async fn test_registration_follows_the_username_policy() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    register_and_login(&app, "alice").await;
+
+    let register = |username: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/register")
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([
+                ("username", username),
+                ("email", &format!("{}@example.com", nanoid::nanoid!(6))),
+                ("password1", "password"),
+                ("password2", "password"),
+            ])
+            .to_request()
+    };
+
+    let rejected = StatusCode::UNPROCESSABLE_ENTITY;
+    for (username, status, code) in [
+        ("system", rejected, "username_reserved"),
+        ("Guest 7", rejected, "username_reserved"),
+        ("<script>", rejected, "username_characters"),
+        ("../home", rejected, "username_characters"),
+        // cyrillic a, only ASCII letters are allowed by default
+        ("\u{430}lice", rejected, "username_characters"),
+        ("ALICE", rejected, "username_confusable"),
+        ("a1ice", rejected, "username_confusable"),
+        // fullwidth letters are normalized to alice
+        ("\u{ff41}lice", StatusCode::CONFLICT, "user_username_taken"),
+    ] {
+        let res = test::call_service(&app, register(username)).await;
+        assert_eq!(res.status(), status, "{username}");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], code, "{username}");
+    }
+
+    // admins are configured by user id, their usernames stay reserved
+    let res = test::call_service(&app, register("admin")).await;
+    assert_eq!(res.status(), rejected);
+}
+
+#[actix_web::test]
+async fn test_canvas_names_are_unique_per_owner_if_configured() {
+    let (state, canvas_server) = webserver::bootstrap(ServerConfig {
+        unique_canvas_names: true,
+        ..test_config()
+    })
+    .unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = register_and_login(&app, "alice").await;
+    let (canvas_id, cookie) = create_named_canvas(&app, cookie, "Projekt").await;
+
+    let create = |auto_rename: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri("/canvas")
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("name", "projekt"), ("auto_rename", auto_rename)])
+            .to_request()
+    };
+    let res = test::call_service(&app, create("false")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "canvas_duplicate_name");
+
+    let res = test::call_service(&app, create("true")).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let created: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(created["name"], "projekt (2)");
+
+    let rename = |name: &str| {
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/name"))
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("name", name)])
+            .to_request()
+    };
+    let res = test::call_service(&app, rename("Projekt (2)")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = test::call_service(&app, rename(" ")).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let res = test::call_service(&app, rename(" Entwurf ")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let renamed: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(renamed["name"], "Entwurf");
+
+    // members can't rename
+    let bob = register_and_login(&app, "bob").await;
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .method(actix_web::http::Method::POST)
+            .uri(&format!("/canvas/{canvas_id}/name"))
+            .cookie(bob)
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_form([("name", "Meins")])
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn test_preferences_are_validated_versioned_and_rate_limited() {
+    let (state, canvas_server) = webserver::bootstrap(test_config()).unwrap();
+    actix_web::rt::spawn(canvas_server);
+    let app = test::init_service(build_app(&state)).await;
+    let cookie = register_and_login(&app, "painter").await;
+
+    let put = |body: serde_json::Value, if_match: Option<&str>| {
+        let mut request = spa_request()
+            .method(actix_web::http::Method::PUT)
+            .uri("/api/me/preferences")
+            .cookie(cookie.clone())
+            .insert_header((header::ACCEPT, "application/json"))
+            .set_json(body);
+        if let Some(if_match) = if_match {
+            request = request.insert_header((header::IF_MATCH, if_match));
+        }
+        request.to_request()
+    };
+
+    let res = test::call_service(&app, put(serde_json::json!({ "theme": "dark" }), None)).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["key"], "user.preferences_invalid");
+    assert_eq!(body["params"]["field"], "theme");
+    let too_large: serde_json::Map<String, serde_json::Value> = (0..40)
+        .map(|index| (format!("ui.key{index}"), "x".repeat(250).into()))
+        .collect();
+    let res = test::call_service(&app, put(too_large.into(), None)).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let res = test::call_service(
+        &app,
+        put(
+            serde_json::json!({ "ui.theme": "dark", "ui.grid": true }),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"1\"");
+    let res = test::call_service(
+        &app,
+        put(serde_json::json!({ "ui.theme": "light" }), Some("\"1\"")),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // a second tab still on version 1 does not overwrite the change
+    let res = test::call_service(
+        &app,
+        put(serde_json::json!({ "ui.grid": false }), Some("\"1\"")),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "user_preferences_conflict");
+
+    let res = test::call_service(
+        &app,
+        spa_request()
+            .uri("/api/me/preferences")
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"2\"");
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(
+        body,
+        serde_json::json!({ "preferences": { "ui.theme": "light" }, "version": 2 })
+    );
+
+    // the canvas page starts with the preferences
+    let (canvas_id, cookie) = create_canvas(&app, cookie.clone()).await;
+    let page = test::call_and_read_body(
+        &app,
+        spa_request()
+            .uri(&format!("/canvas/{canvas_id}"))
+            .cookie(cookie.clone())
+            .to_request(),
+    )
+    .await;
+    let page = String::from_utf8(page.to_vec()).unwrap();
+    let bootstrap: serde_json::Value =
+        serde_json::from_str(script_content(&page, "canvas-bootstrap").unwrap()).unwrap();
+    assert_eq!(bootstrap["preferences"]["preferences"]["ui.theme"], "light");
+    assert_eq!(bootstrap["preferences"]["version"], 2);
+
+    // the conflicting write counted as well
+    let mut statuses = Vec::new();
+    for _ in 0..webserver::preferences::PREFERENCES_WRITE_LIMIT - 2 {
+        let res = test::call_service(&app, put(serde_json::json!({}), None)).await;
+        statuses.push(res.status());
+    }
+    assert_eq!(statuses.pop(), Some(StatusCode::TOO_MANY_REQUESTS));
+    assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+
+    remove_canvas_log(&canvas_id).await;
+}